@@ -0,0 +1,89 @@
+// Small-message decryption: a fresh buffer per layer vs reused scratch buffers
+// A counting allocator reports allocations per decrypt for each path
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use hybridguard::{DecryptScratch, HybridGuard, KeyManager};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts allocations and the bytes they asked for
+#[cfg_attr(feature = "memory-profile", allow(dead_code))]
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+// The `memory-profile` feature installs its own allocator
+#[cfg(not(feature = "memory-profile"))]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+const ROUNDS: usize = 100;
+
+/// Mean allocations and bytes allocated per call of `f`
+fn allocations_per_call(mut f: impl FnMut()) -> (f64, f64) {
+    f();
+    let (calls, bytes) = (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED.load(Ordering::Relaxed));
+    for _ in 0..ROUNDS {
+        f();
+    }
+    let calls = ALLOCATIONS.load(Ordering::Relaxed) - calls;
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - bytes;
+    (calls as f64 / ROUNDS as f64, bytes as f64 / ROUNDS as f64)
+}
+
+fn bench_decrypt_scratch(c: &mut Criterion) {
+    let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x42; 32]).unwrap()).build().unwrap();
+    let mut group = c.benchmark_group("decrypt-small");
+
+    for size in SIZES {
+        let encrypted = hg.encrypt(&vec![0x5A; size]).unwrap();
+        let mut scratch = DecryptScratch::new();
+
+        if cfg!(not(feature = "memory-profile")) {
+            let (fresh, fresh_bytes) = allocations_per_call(|| {
+                black_box(hg.decrypt(&encrypted).unwrap());
+            });
+            let (reused, reused_bytes) = allocations_per_call(|| {
+                black_box(hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap());
+            });
+            println!(
+                "decrypt {} B: {:.1} allocations ({:.0} B) per call, with scratch {:.1} ({:.0} B)",
+                size, fresh, fresh_bytes, reused, reused_bytes
+            );
+        }
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("fresh-{}", size), |b| {
+            b.iter(|| hg.decrypt(black_box(&encrypted)).unwrap())
+        });
+        group.bench_function(format!("scratch-{}", size), |b| {
+            b.iter(|| hg.decrypt_with_scratch(black_box(&encrypted), &mut scratch).unwrap().len())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decrypt_scratch);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+// Keystream throughput: legacy hash-per-block constructions vs SHAKE-256 XOF
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use hybridguard::crypto::keystream;
+
+const LEN: usize = 16 * 1024 * 1024;
+
+fn bench_keystreams(c: &mut Criterion) {
+    let secret = [0x42u8; 32];
+    let mut group = c.benchmark_group("keystream-16MiB");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.sample_size(10);
+    
+    group.bench_function("legacy-sha3-counter", |b| {
+        b.iter(|| keystream::legacy_sha3(black_box(&secret), b"", LEN))
+    });
+    group.bench_function("legacy-sha256-counter", |b| {
+        b.iter(|| keystream::legacy_sha256(black_box(&secret), LEN))
+    });
+    group.bench_function("shake256-xof", |b| {
+        b.iter(|| keystream::xof(black_box(&secret), b"bench", LEN))
+    });
+    group.bench_function("shake256-xor-in-place", |b| {
+        let mut data = vec![0u8; LEN];
+        b.iter(|| keystream::xor_in_place(black_box(&secret), b"bench", &mut data))
+    });
+    
+    group.finish();
+}
+
+criterion_group!(benches, bench_keystreams);
+criterion_main!(benches);
@@ -0,0 +1,137 @@
+// Example out-of-tree layer for the HybridGuard plugin ABI (version 1)
+// Output is a 4-byte check value derived from the key followed by the input
+// XORed with a key-derived byte stream. It shows the calling convention and
+// nothing else: it is not a cipher anyone should rely on.
+//
+// Build with `cargo build --manifest-path plugins/example-layer/Cargo.toml`
+// and load the resulting library with `--plugin` or `with_plugin`.
+
+use std::ffi::c_void;
+use std::slice;
+
+const ABI_VERSION: u32 = 1;
+const NAME: &str = "Example-XOR";
+const VERSION: u16 = 1;
+const CHECK_LEN: usize = 4;
+
+const OK: i32 = 0;
+const BAD_ARGUMENT: i32 = 1;
+const BUFFER_TOO_SMALL: i32 = 2;
+const WRONG_KEY: i32 = 3;
+
+/// Mirrors `HgLayerInfo` in hybridguard's `layers::plugin`
+#[repr(C)]
+pub struct HgLayerInfo {
+    pub name: *const u8,
+    pub name_len: usize,
+    pub version: u16,
+    pub overhead: u32,
+}
+
+/// The layer keeps no state; the handle only proves create was called
+struct Layer;
+
+fn stream_byte(key: &[u8], i: usize) -> u8 {
+    key[i % key.len()] ^ (i as u8).wrapping_mul(31)
+}
+
+fn check_value(key: &[u8]) -> [u8; CHECK_LEN] {
+    let mut check = [0x5au8; CHECK_LEN];
+    for (i, byte) in key.iter().enumerate() {
+        check[i % CHECK_LEN] = check[i % CHECK_LEN].rotate_left(3) ^ byte;
+    }
+    check
+}
+
+#[no_mangle]
+pub extern "C" fn hg_layer_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn hg_layer_create() -> *mut c_void {
+    Box::into_raw(Box::new(Layer)) as *mut c_void
+}
+
+/// # Safety
+/// `handle` must come from `hg_layer_create` and `info` must be writable
+#[no_mangle]
+pub unsafe extern "C" fn hg_layer_descriptor(handle: *mut c_void, info: *mut HgLayerInfo) -> i32 {
+    if handle.is_null() || info.is_null() {
+        return BAD_ARGUMENT;
+    }
+    *info = HgLayerInfo { name: NAME.as_ptr(), name_len: NAME.len(), version: VERSION, overhead: CHECK_LEN as u32 };
+    OK
+}
+
+/// # Safety
+/// Every pointer must be valid for the length passed beside it
+#[no_mangle]
+pub unsafe extern "C" fn hg_layer_encrypt(
+    handle: *mut c_void,
+    data: *const u8,
+    data_len: usize,
+    key: *const u8,
+    key_len: usize,
+    out: *mut u8,
+    out_cap: usize,
+    out_len: *mut usize,
+) -> i32 {
+    if handle.is_null() || key_len == 0 || out_len.is_null() {
+        return BAD_ARGUMENT;
+    }
+    if out_cap < data_len + CHECK_LEN {
+        return BUFFER_TOO_SMALL;
+    }
+    let key = slice::from_raw_parts(key, key_len);
+    let data = if data_len == 0 { &[][..] } else { slice::from_raw_parts(data, data_len) };
+    let out = slice::from_raw_parts_mut(out, out_cap);
+
+    out[..CHECK_LEN].copy_from_slice(&check_value(key));
+    for (i, byte) in data.iter().enumerate() {
+        out[CHECK_LEN + i] = byte ^ stream_byte(key, i);
+    }
+    *out_len = data_len + CHECK_LEN;
+    OK
+}
+
+/// # Safety
+/// Every pointer must be valid for the length passed beside it
+#[no_mangle]
+pub unsafe extern "C" fn hg_layer_decrypt(
+    handle: *mut c_void,
+    data: *const u8,
+    data_len: usize,
+    key: *const u8,
+    key_len: usize,
+    out: *mut u8,
+    out_cap: usize,
+    out_len: *mut usize,
+) -> i32 {
+    if handle.is_null() || key_len == 0 || out_len.is_null() || data_len < CHECK_LEN {
+        return BAD_ARGUMENT;
+    }
+    if out_cap < data_len - CHECK_LEN {
+        return BUFFER_TOO_SMALL;
+    }
+    let key = slice::from_raw_parts(key, key_len);
+    let data = slice::from_raw_parts(data, data_len);
+    if data[..CHECK_LEN] != check_value(key) {
+        return WRONG_KEY;
+    }
+    let out = slice::from_raw_parts_mut(out, out_cap);
+    for (i, byte) in data[CHECK_LEN..].iter().enumerate() {
+        out[i] = byte ^ stream_byte(key, i);
+    }
+    *out_len = data_len - CHECK_LEN;
+    OK
+}
+
+/// # Safety
+/// `handle` must come from `hg_layer_create` and not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn hg_layer_destroy(handle: *mut c_void) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle as *mut Layer));
+    }
+}
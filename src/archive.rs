@@ -0,0 +1,509 @@
+// Directory archives, reproducible byte for byte
+//
+// Layout (all integers little-endian):
+//   magic "HGAR", u16 format version, u32 index length,
+//   bincode index (one entry per directory, file and symbolic link),
+//   then the contents of each file, in index order
+//
+// Paths are '/'-separated raw bytes relative to the archived directory, so
+// neither an absolute path nor the directory's own name is recorded. In
+// deterministic mode (the default) entries are sorted by path bytes and modes
+// normalized to 0755 or 0644, so an unchanged tree archives to the same bytes
+// however and whenever it was created, and encrypting it again in a
+// convergent mode gives the same ciphertext. Times and owners are only
+// recorded when asked for; `source_date_epoch` pins or clamps the times.
+
+use crate::crypto::container::{le_u16, le_u32, u16_at, u32_at};
+use crate::error::{HybridGuardError, Result};
+use crate::pathname;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes at the start of every archive
+pub const MAGIC: [u8; 4] = *b"HGAR";
+
+/// Archive format written by this build
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Bytes before the bincode index: magic, version, index length
+const PREFIX_LEN: usize = 4 + 2 + 4;
+
+/// Largest index accepted, enough for about a million entries
+const MAX_INDEX_LEN: usize = 256 * 1024 * 1024;
+
+/// How `write` records a directory tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    /// Sort entries by path bytes and normalize modes, so the same tree
+    /// always gives the same archive
+    pub deterministic: bool,
+    /// Record modification times
+    pub preserve_times: bool,
+    /// Record owner and group IDs (Unix)
+    pub preserve_owner: bool,
+    /// Record this time (Unix seconds) for every entry, or with
+    /// `preserve_times` clamp later times to it, like `SOURCE_DATE_EPOCH`
+    pub source_date_epoch: Option<i64>,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self { deterministic: true, preserve_times: false, preserve_owner: false, source_date_epoch: None }
+    }
+}
+
+/// What an archive entry is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Directory,
+    /// Its `len` bytes of content follow the index
+    File { len: u64 },
+    /// Target as raw bytes; never followed while archiving
+    Symlink { target: Vec<u8> },
+}
+
+/// One directory, file or symbolic link in an archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    /// Relative path as '/'-separated raw bytes
+    pub path: Vec<u8>,
+    pub kind: EntryKind,
+    /// Permission bits
+    pub mode: u32,
+    /// Unix seconds; None unless times are recorded
+    pub mtime: Option<i64>,
+    /// User and group IDs; None unless owners are recorded
+    pub owner: Option<(u32, u32)>,
+}
+
+impl ArchiveEntry {
+    /// The path relative to the archive root, as a path on this platform
+    /// Refuses empty, `.` and `..` components, so no entry escapes the root
+    pub fn relative_path(&self) -> Result<PathBuf> {
+        let mut path = PathBuf::new();
+        for component in self.path.split(|byte| *byte == b'/') {
+            if component.is_empty() || component == b"." || component == b".." || component.contains(&0) {
+                return Err(HybridGuardError::Integrity(format!(
+                    "archive entry '{}' is not a plain relative path",
+                    String::from_utf8_lossy(&self.path)
+                )));
+            }
+            path.push(pathname::from_raw_bytes(component.to_vec())?);
+        }
+        Ok(path)
+    }
+}
+
+/// Archive the tree under `root` into `out`, returning its index
+/// Fails with `SourceChangedDuringRead` when a file's length changes
+/// between indexing and copying
+pub fn write<W: Write>(root: &Path, options: &ArchiveOptions, mut out: W) -> Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    collect(root, &[], options, &mut entries)?;
+    if options.deterministic {
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    let index = bincode::serialize(&entries).map_err(|e| HybridGuardError::Encryption(format!("archive index: {}", e)))?;
+    if index.len() > MAX_INDEX_LEN {
+        return Err(HybridGuardError::LimitExceeded { which: "archive index".to_string(), size: index.len(), limit: MAX_INDEX_LEN });
+    }
+    out.write_all(&MAGIC)?;
+    out.write_all(&le_u16(FORMAT_VERSION))?;
+    out.write_all(&le_u32(index.len() as u32))?;
+    out.write_all(&index)?;
+
+    for entry in &entries {
+        let EntryKind::File { len } = entry.kind else {
+            continue;
+        };
+        let path = root.join(entry.relative_path()?);
+        // One byte more than indexed shows a file that grew
+        let copied = io::copy(&mut File::open(&path)?.take(len + 1), &mut out)?;
+        if copied != len {
+            return Err(HybridGuardError::SourceChangedDuringRead(format!(
+                "{} is {} bytes, but was {} when indexed",
+                path.display(),
+                copied,
+                len
+            )));
+        }
+    }
+    out.flush()?;
+    Ok(entries)
+}
+
+/// Length of the archive `write` wrote with this index
+pub fn encoded_len(entries: &[ArchiveEntry]) -> Result<u64> {
+    let index = bincode::serialized_size(entries).map_err(|e| HybridGuardError::Encryption(format!("archive index: {}", e)))?;
+    let contents: u64 = entries
+        .iter()
+        .map(|entry| match entry.kind {
+            EntryKind::File { len } => len,
+            _ => 0,
+        })
+        .sum();
+    Ok(PREFIX_LEN as u64 + index + contents)
+}
+
+/// Unpack an archive into `dest`, which must be missing or empty
+/// Symbolic links are created last, so no entry is written through one
+pub fn extract<R: Read>(mut input: R, dest: &Path) -> Result<Vec<ArchiveEntry>> {
+    let entries = read_index(&mut input)?;
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(HybridGuardError::InvalidInput(format!("{} is not empty", dest.display())));
+    }
+
+    let mut directories = Vec::new();
+    let mut links = Vec::new();
+    for entry in &entries {
+        let path = dest.join(entry.relative_path()?);
+        match &entry.kind {
+            EntryKind::Directory => {
+                fs::create_dir(&path)?;
+                directories.push((path, entry));
+            }
+            EntryKind::File { len } => {
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+                let copied = io::copy(&mut (&mut input).take(*len), &mut file)?;
+                if copied != *len {
+                    return Err(HybridGuardError::Integrity(format!("archive ends inside {}", path.display())));
+                }
+                restore_metadata(&path, Some(&file), entry)?;
+            }
+            EntryKind::Symlink { target } => links.push((path, target, entry)),
+        }
+    }
+    if input.read(&mut [0u8; 1])? != 0 {
+        return Err(HybridGuardError::Integrity("archive has data after its last file".to_string()));
+    }
+
+    for (path, target, entry) in links {
+        symlink(&pathname::from_raw_bytes(target.clone())?, &path)?;
+        restore_owner(&path, entry)?;
+    }
+    // Deepest first, since filling a directory changes its time
+    for (path, entry) in directories.into_iter().rev() {
+        restore_metadata(&path, None, entry)?;
+    }
+    Ok(entries)
+}
+
+/// Read and check the prefix and index of an archive
+pub fn read_index<R: Read>(input: &mut R) -> Result<Vec<ArchiveEntry>> {
+    let truncated = || HybridGuardError::Integrity("archive is truncated".to_string());
+    let mut prefix = [0u8; PREFIX_LEN];
+    input.read_exact(&mut prefix).map_err(|_| truncated())?;
+    if prefix[..4] != MAGIC {
+        return Err(HybridGuardError::UnsupportedFormat("not a HybridGuard archive".to_string()));
+    }
+    let version = u16_at(&prefix, 4);
+    if version != FORMAT_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "archive format v{} is not supported (this build reads v{})",
+            version, FORMAT_VERSION
+        )));
+    }
+    let index_len = u32_at(&prefix, 6) as usize;
+    if index_len > MAX_INDEX_LEN {
+        return Err(HybridGuardError::Integrity("archive index is too large".to_string()));
+    }
+
+    let mut index = vec![0u8; index_len];
+    input.read_exact(&mut index).map_err(|_| truncated())?;
+    bincode::deserialize(&index).map_err(|_| HybridGuardError::Integrity("archive index is unreadable".to_string()))
+}
+
+/// Index the entries below `dir`, whose archive path is `prefix`, parents
+/// before their children
+fn collect(dir: &Path, prefix: &[u8], options: &ArchiveOptions, entries: &mut Vec<ArchiveEntry>) -> Result<()> {
+    for child in fs::read_dir(dir)? {
+        let child = child?;
+        let mut path = prefix.to_vec();
+        if !path.is_empty() {
+            path.push(b'/');
+        }
+        path.extend(pathname::raw_bytes(Path::new(&child.file_name())));
+
+        // Not followed: a link is recorded as a link
+        let metadata = fs::symlink_metadata(child.path())?;
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else if metadata.file_type().is_symlink() {
+            EntryKind::Symlink { target: pathname::raw_bytes(&fs::read_link(child.path())?) }
+        } else if metadata.is_file() {
+            EntryKind::File { len: metadata.len() }
+        } else {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "{} is not a file, directory or symbolic link",
+                child.path().display()
+            )));
+        };
+        let is_dir = kind == EntryKind::Directory;
+        entries.push(ArchiveEntry {
+            mode: mode(&metadata, &kind, options.deterministic),
+            mtime: mtime(&metadata, options)?,
+            owner: owner(&metadata, options.preserve_owner),
+            kind,
+            path: path.clone(),
+        });
+        if is_dir {
+            collect(&child.path(), &path, options, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Permission bits to record; normalized ones depend only on the kind and the
+/// owner's execute bit
+fn mode(metadata: &Metadata, kind: &EntryKind, deterministic: bool) -> u32 {
+    let actual = permission_bits(metadata, kind);
+    if !deterministic {
+        return actual;
+    }
+    match kind {
+        EntryKind::Directory => 0o755,
+        EntryKind::Symlink { .. } => 0o777,
+        EntryKind::File { .. } if actual & 0o100 != 0 => 0o755,
+        EntryKind::File { .. } => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn permission_bits(metadata: &Metadata, _kind: &EntryKind) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(metadata: &Metadata, kind: &EntryKind) -> u32 {
+    match (kind, metadata.permissions().readonly()) {
+        (EntryKind::File { .. }, true) => 0o444,
+        (EntryKind::File { .. }, false) => 0o644,
+        _ => 0o755,
+    }
+}
+
+fn mtime(metadata: &Metadata, options: &ArchiveOptions) -> Result<Option<i64>> {
+    if !options.preserve_times {
+        return Ok(options.source_date_epoch);
+    }
+    let actual = unix_secs(metadata.modified()?);
+    Ok(Some(options.source_date_epoch.map_or(actual, |epoch| actual.min(epoch))))
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    }
+}
+
+fn from_unix_secs(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+#[cfg(unix)]
+fn owner(metadata: &Metadata, preserve: bool) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    preserve.then(|| (metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner(_metadata: &Metadata, _preserve: bool) -> Option<(u32, u32)> {
+    None
+}
+
+/// Apply an entry's mode, time and owner to what was just extracted
+fn restore_metadata(path: &Path, file: Option<&File>, entry: &ArchiveEntry) -> Result<()> {
+    set_mode(path, entry.mode)?;
+    // Elsewhere directories cannot be opened to set their time
+    if let (Some(mtime), true) = (entry.mtime, file.is_some() || cfg!(unix)) {
+        let opened;
+        let file = match file {
+            Some(file) => file,
+            None => {
+                opened = File::open(path)?;
+                &opened
+            }
+        };
+        file.set_modified(from_unix_secs(mtime))?;
+    }
+    restore_owner(path, entry)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    if mode & 0o200 == 0 && path.is_file() {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// Owners are restored where the process may set them, e.g. as root
+#[cfg(unix)]
+fn restore_owner(path: &Path, entry: &ArchiveEntry) -> Result<()> {
+    let Some((uid, gid)) = entry.owner else {
+        return Ok(());
+    };
+    match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_owner(_path: &Path, _entry: &ArchiveEntry) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(target, path)?)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, path: &Path) -> Result<()> {
+    Err(HybridGuardError::UnsupportedFormat(format!(
+        "{} is a symbolic link, which this platform cannot extract",
+        path.display()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build the same tree in `order`, with every mtime set to `mtime`
+    fn tree(root: &Path, order: &[&str], mtime: i64) {
+        for name in order {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            match *name {
+                name if name.ends_with('/') => fs::create_dir_all(&path).unwrap(),
+                name => fs::write(&path, format!("contents of {}", name)).unwrap(),
+            }
+        }
+        for name in order {
+            File::open(root.join(name)).unwrap().set_modified(from_unix_secs(mtime)).unwrap();
+        }
+    }
+
+    const NAMES: [&str; 6] = ["b.txt", "a/", "a/z.bin", "a/m/", "a/m/deep.txt", "a.txt"];
+
+    #[test]
+    fn test_same_tree_gives_identical_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        tree(&first, &NAMES, 1_600_000_000);
+        let reversed: Vec<&str> = NAMES.iter().rev().copied().collect();
+        tree(&second, &reversed, 1_700_000_000);
+
+        let options = ArchiveOptions::default();
+        let (mut one, mut two) = (Vec::new(), Vec::new());
+        let index = write(&first, &options, &mut one).unwrap();
+        write(&second, &options, &mut two).unwrap();
+        assert_eq!(one, two);
+        assert_eq!(encoded_len(&index).unwrap(), one.len() as u64);
+        let paths: Vec<&[u8]> = index.iter().map(|entry| entry.path.as_slice()).collect();
+        assert_eq!(paths, [&b"a"[..], b"a.txt", b"a/m", b"a/m/deep.txt", b"a/z.bin", b"b.txt"]);
+        assert!(index.iter().all(|entry| entry.mtime.is_none() && entry.owner.is_none()));
+
+        // A pinned time is the same for both trees too
+        let pinned = ArchiveOptions { preserve_times: true, source_date_epoch: Some(1_500_000_000), ..options };
+        let (mut one, mut two) = (Vec::new(), Vec::new());
+        write(&first, &pinned, &mut one).unwrap();
+        write(&second, &pinned, &mut two).unwrap();
+        assert_eq!(one, two);
+
+        let out = dir.path().join("out");
+        let extracted = extract(one.as_slice(), &out).unwrap();
+        assert_eq!(extracted.len(), NAMES.len());
+        for name in NAMES.iter().filter(|name| !name.ends_with('/')) {
+            assert_eq!(fs::read_to_string(out.join(name)).unwrap(), format!("contents of {}", name));
+            assert_eq!(fs::metadata(out.join(name)).unwrap().modified().unwrap(), from_unix_secs(1_500_000_000));
+        }
+        assert!(out.join("a/m").is_dir());
+        assert_eq!(fs::metadata(out.join("a")).unwrap().modified().unwrap(), from_unix_secs(1_500_000_000));
+    }
+
+    #[test]
+    fn test_times_are_kept_when_asked_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("tree");
+        tree(&root, &NAMES, 1_600_000_000);
+
+        let options = ArchiveOptions { deterministic: false, preserve_times: true, ..ArchiveOptions::default() };
+        let mut archive = Vec::new();
+        let index = write(&root, &options, &mut archive).unwrap();
+        assert!(index.iter().all(|entry| entry.mtime == Some(1_600_000_000)));
+
+        // Later times clamp to the epoch, earlier ones stay
+        let clamped = ArchiveOptions { source_date_epoch: Some(1_650_000_000), ..options };
+        let later = write(&root, &clamped, io::sink()).unwrap();
+        assert!(later.iter().all(|entry| entry.mtime == Some(1_600_000_000)));
+        let clamped = ArchiveOptions { source_date_epoch: Some(1_550_000_000), ..options };
+        assert!(write(&root, &clamped, io::sink()).unwrap().iter().all(|entry| entry.mtime == Some(1_550_000_000)));
+
+        let out = dir.path().join("out");
+        extract(archive.as_slice(), &out).unwrap();
+        assert_eq!(fs::metadata(out.join("a/m/deep.txt")).unwrap().modified().unwrap(), from_unix_secs(1_600_000_000));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_modes_normalize_and_links_are_not_followed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("tree");
+        tree(&root, &["run.sh", "notes.txt"], 0);
+        fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(root.join("notes.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink("notes.txt", root.join("link")).unwrap();
+
+        let mut archive = Vec::new();
+        let index = write(&root, &ArchiveOptions::default(), &mut archive).unwrap();
+        let modes: Vec<u32> = index.iter().map(|entry| entry.mode).collect();
+        assert_eq!(modes, [0o777, 0o644, 0o755]);
+        assert_eq!(index[0].kind, EntryKind::Symlink { target: b"notes.txt".to_vec() });
+
+        let out = dir.path().join("out");
+        extract(archive.as_slice(), &out).unwrap();
+        assert_eq!(fs::read_link(out.join("link")).unwrap(), Path::new("notes.txt"));
+        assert_eq!(fs::metadata(out.join("run.sh")).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_entries_cannot_escape_the_destination() {
+        let escape = ArchiveEntry { path: b"../evil".to_vec(), kind: EntryKind::File { len: 0 }, mode: 0o644, mtime: None, owner: None };
+        let index = bincode::serialize(&vec![escape]).unwrap();
+        let mut archive = MAGIC.to_vec();
+        archive.extend_from_slice(&le_u16(FORMAT_VERSION));
+        archive.extend_from_slice(&le_u32(index.len() as u32));
+        archive.extend_from_slice(&index);
+
+        let dir = tempfile::tempdir().unwrap();
+        let error = extract(archive.as_slice(), &dir.path().join("out")).unwrap_err();
+        assert!(matches!(error, HybridGuardError::Integrity(_)), "{}", error);
+        assert!(!dir.path().join("evil").exists());
+        assert!(matches!(extract(&b"HGSP"[..], &dir.path().join("other")), Err(HybridGuardError::Integrity(_))));
+    }
+}
@@ -0,0 +1,122 @@
+// Batch encryption and decryption with per-item results
+// Items are processed independently, across threads with the `parallel`
+// feature, and a failing item never stops the rest of the batch
+
+use crate::error::{HybridGuardError, Result};
+use std::time::{Duration, Instant};
+
+/// Outcome counts and timing of one batch call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Wall-clock time for the whole batch
+    pub elapsed: Duration,
+}
+
+impl BatchSummary {
+    pub fn total(&self) -> usize {
+        self.succeeded + self.failed
+    }
+}
+
+/// Apply `f` to every item, tagging each error with the index of its item;
+/// `max_parallelism` caps the worker threads (None uses every core)
+pub(crate) fn run<I, T, F>(items: &[I], max_parallelism: Option<usize>, f: F) -> (Vec<Result<T>>, BatchSummary)
+where
+    I: Sync,
+    T: Send,
+    F: Fn(&I) -> Result<T> + Sync,
+{
+    let start = Instant::now();
+    let process = |(index, item): (usize, &I)| {
+        f(item).map_err(|e| HybridGuardError::BatchItem { index, source: Box::new(e) })
+    };
+
+    #[cfg(feature = "parallel")]
+    let results: Vec<Result<T>> = {
+        use rayon::prelude::*;
+        let parallel = || items.par_iter().enumerate().map(process).collect();
+        match max_parallelism {
+            None => parallel(),
+            // A capped batch gets its own pool; if one cannot be built, run on this thread
+            Some(limit) => match rayon::ThreadPoolBuilder::new().num_threads(limit.max(1)).build() {
+                Ok(pool) => pool.install(parallel),
+                Err(_) => items.iter().enumerate().map(process).collect(),
+            },
+        }
+    };
+    #[cfg(not(feature = "parallel"))]
+    let results: Vec<Result<T>> = {
+        let _ = max_parallelism;
+        items.iter().enumerate().map(process).collect()
+    };
+
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    let summary = BatchSummary {
+        succeeded: results.len() - failed,
+        failed,
+        elapsed: start.elapsed(),
+    };
+    (results, summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    /// Distinct threads that processed 64 slow items under `limit`
+    fn worker_threads(limit: Option<usize>) -> usize {
+        let seen = Mutex::new(HashSet::<ThreadId>::new());
+        let items: Vec<usize> = (0..64).collect();
+        let (results, summary) = run(&items, limit, |&item| {
+            seen.lock().unwrap().insert(thread::current().id());
+            thread::sleep(Duration::from_millis(2));
+            Ok(item)
+        });
+        assert_eq!(summary.succeeded, 64);
+        assert!(results.into_iter().map(Result::unwrap).eq(0..64));
+        seen.into_inner().unwrap().len()
+    }
+
+    #[test]
+    fn test_failures_carry_the_index_of_their_item() {
+        let items: Vec<usize> = (0..32).collect();
+        let (results, summary) = run(&items, None, |&item| match item % 10 {
+            7 => Err(HybridGuardError::InvalidInput(format!("item {}", item))),
+            _ => Ok(item),
+        });
+        assert_eq!((summary.succeeded, summary.failed), (29, 3));
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(item) => assert_eq!(item, index),
+                Err(HybridGuardError::BatchItem { index: reported, source }) => {
+                    assert_eq!(reported, index);
+                    assert!(matches!(*source, HybridGuardError::InvalidInput(ref detail) if *detail == format!("item {}", index)));
+                }
+                Err(other) => panic!("item {} failed without its index: {}", index, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_parallelism_caps_worker_threads() {
+        for limit in [1, 2, 3] {
+            let threads = worker_threads(Some(limit));
+            assert!(threads <= limit, "{} threads under a cap of {}", threads, limit);
+        }
+        // Zero is treated as one
+        assert_eq!(worker_threads(Some(0)), 1);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_capped_batch_still_runs_in_parallel() {
+        if thread::available_parallelism().map_or(1, |n| n.get()) > 1 {
+            assert!(worker_threads(Some(2)) > 1);
+        }
+    }
+}
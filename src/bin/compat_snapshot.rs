@@ -0,0 +1,47 @@
+// compat-snapshot: add this version's interop fixtures to the compatibility corpus
+// Run once per release, before tagging it:
+//   cargo run --features fixtures --bin compat-snapshot
+// then commit the new tests/compat/<version>/ directory and corpus.json.
+
+use clap::Parser;
+use hybridguard::compat;
+use hybridguard::error::HybridGuardError;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(name = "compat-snapshot")]
+#[command(version)]
+#[command(about = "Add this version's interop fixtures to the compatibility corpus", long_about = None)]
+struct Args {
+    /// Corpus directory holding corpus.json
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/compat"))]
+    corpus: PathBuf,
+
+    /// Regenerate this version's set if the corpus already holds one
+    #[arg(long)]
+    replace: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let added = std::fs::create_dir_all(&args.corpus)
+        .map_err(HybridGuardError::from)
+        .and_then(|()| compat::snapshot(&args.corpus, args.replace));
+    match added {
+        Ok(set) => {
+            println!(
+                "✅ Added {} fixtures of version {} (container format up to v{}) to {}",
+                set.fixtures,
+                set.version,
+                set.container_version,
+                args.corpus.display()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            ExitCode::from(e.code())
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Time and output budgets for automation
+// A budget rides on a run's `CancellationToken`, so it is checked wherever
+// cancellation is: between layers and before every streaming chunk. Nothing
+// is interrupted mid-write; a run past its time stops at the next check and
+// a write that would pass the output cap is refused before it happens, both
+// with `BudgetExceeded`, and the run cleans up as a cancelled one does
+
+use crate::cancel::CancellationToken;
+use crate::error::{HybridGuardError, Result};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Limits a run must stay within; `None` leaves that side unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Wall-clock time from the start of the run
+    pub max_duration: Option<Duration>,
+    /// Bytes of output (ciphertext or plaintext) the run may write
+    pub max_output_bytes: Option<u64>,
+}
+
+impl Budget {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_duration.is_none() && self.max_output_bytes.is_none()
+    }
+}
+
+/// How much of its budget a run used, reported on success and failure alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BudgetUsage {
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_duration_ms: Option<u64>,
+    pub output_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+}
+
+/// A budget being spent, shared by every clone of the token carrying it
+#[derive(Debug)]
+pub(crate) struct Meter {
+    budget: Budget,
+    started: Instant,
+    output: AtomicU64,
+}
+
+impl Meter {
+    pub(crate) fn start(budget: Budget) -> Self {
+        Self { budget, started: Instant::now(), output: AtomicU64::new(0) }
+    }
+
+    /// Fail once the run has taken longer than its budget
+    pub(crate) fn check_time(&self) -> Result<()> {
+        let Some(limit) = self.budget.max_duration else {
+            return Ok(());
+        };
+        let elapsed = self.started.elapsed();
+        if elapsed > limit {
+            return Err(exceeded("run time (ms)", limit.as_millis() as u64, elapsed.as_millis() as u64));
+        }
+        Ok(())
+    }
+
+    /// Take `bytes` from the output budget, or fail without taking any
+    pub(crate) fn take_output(&self, bytes: u64) -> Result<()> {
+        let total = self.output.fetch_add(bytes, Ordering::SeqCst).saturating_add(bytes);
+        match self.budget.max_output_bytes {
+            Some(limit) if total > limit => {
+                self.output.fetch_sub(bytes, Ordering::SeqCst);
+                Err(exceeded("output bytes", limit, total))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn usage(&self) -> BudgetUsage {
+        BudgetUsage {
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            max_duration_ms: self.budget.max_duration.map(|limit| limit.as_millis() as u64),
+            output_bytes: self.output.load(Ordering::SeqCst),
+            max_output_bytes: self.budget.max_output_bytes,
+        }
+    }
+}
+
+fn exceeded(which: &str, limit: u64, actual: u64) -> HybridGuardError {
+    HybridGuardError::BudgetExceeded { which: which.to_string(), limit, actual }
+}
+
+/// `Write` adapter that takes every write from `cancel`'s output budget
+/// A write the budget cannot cover fails before reaching `inner`
+pub struct MeteredWriter<'a, W: Write> {
+    inner: W,
+    cancel: &'a CancellationToken,
+}
+
+impl<'a, W: Write> MeteredWriter<'a, W> {
+    pub fn new(inner: W, cancel: &'a CancellationToken) -> Self {
+        Self { inner, cancel }
+    }
+}
+
+impl<W: Write> Write for MeteredWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.cancel.take_output(buf.len() as u64)?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Parse a duration: seconds, or a number with an s, m, h or d suffix
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let invalid = || HybridGuardError::InvalidInput(format!("invalid duration '{}', expected seconds or a duration such as 90s, 10m or 2h", text));
+    let text = text.trim();
+    let (number, unit) = match text.char_indices().last() {
+        Some((at, unit)) if unit.is_ascii_alphabetic() => {
+            let unit = match unit.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => return Err(invalid()),
+            };
+            (&text[..at], unit)
+        }
+        _ => (text, 1),
+    };
+    let secs = number.parse::<u64>().map_err(|_| invalid())?.checked_mul(unit).ok_or_else(invalid)?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parse a byte count: bytes, or a number with a K, M, G or T suffix (powers of 1024)
+pub fn parse_bytes(text: &str) -> Result<u64> {
+    let invalid = || HybridGuardError::InvalidInput(format!("invalid size '{}', expected bytes or a size such as 512M or 50G", text));
+    let text = text.trim();
+    let (number, shift) = match text.char_indices().last() {
+        Some((at, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(invalid()),
+            };
+            (&text[..at], shift)
+        }
+        _ => (text, 0),
+    };
+    number.parse::<u64>().map_err(|_| invalid())?.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_budgets() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("2H").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("m").is_err());
+        assert_eq!(parse_bytes("50G").unwrap(), 50 << 30);
+        assert_eq!(parse_bytes("4096").unwrap(), 4096);
+        assert!(parse_bytes("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_output_budget_refuses_the_write_that_passes_it() {
+        let meter = Meter::start(Budget { max_duration: None, max_output_bytes: Some(10) });
+        meter.take_output(6).unwrap();
+        let error = meter.take_output(5).unwrap_err();
+        assert!(matches!(error, HybridGuardError::BudgetExceeded { limit: 10, actual: 11, .. }), "{}", error);
+        // The refused write took nothing, so a smaller one still fits
+        meter.take_output(4).unwrap();
+        assert_eq!(meter.usage().output_bytes, 10);
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let meter = Meter::start(Budget { max_duration: Some(Duration::ZERO), max_output_bytes: None });
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(matches!(meter.check_time(), Err(HybridGuardError::BudgetExceeded { limit: 0, .. })));
+        assert!(Meter::start(Budget::default()).check_time().is_ok());
+        assert_eq!(meter.usage().max_duration_ms, Some(0));
+    }
+}
@@ -0,0 +1,166 @@
+// Self-extracting bundles: a copy of the hybridguard binary with an encrypted
+// directory archive appended
+// Layout: the binary unchanged, a fresh key file sealed with the bundle
+// password under Argon2id, the container holding the archive, then the
+// trailer `container::read_bundle_trailer` finds at the end of the file. The
+// binary checks its own file for the trailer at startup, so running the bundle
+// asks for the password and extracts instead of parsing the usual commands.
+
+use crate::archive::{self, ArchiveEntry, ArchiveOptions};
+use crate::cancel::CancellationToken;
+use crate::crypto::container::{self, BundleTrailer};
+use crate::crypto::kdf::{KdfParams, MIN_MEMORY_KIB};
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::protector::PasswordProtector;
+use crate::staging::{Contents, StagedFile};
+use crate::KeyManager;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use zeroize::Zeroize;
+
+/// Argon2id cost of the bundle password: the OWASP minimum, since the bundle
+/// may be opened on a slower machine than the one that made it
+pub const STRETCHING: KdfParams = KdfParams { memory_kib: MIN_MEMORY_KIB, iterations: 2, parallelism: 1 };
+
+/// What `create` wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BundleSummary {
+    /// Archive entries: directories, files and links
+    pub entries: usize,
+    pub plaintext_len: u64,
+    /// Bytes of the bundle, the copied binary included
+    pub bundle_len: u64,
+}
+
+/// Archive the directory `input`, encrypt it under a fresh key sealed with
+/// `password`, and write it after a copy of the binary `stub` to `output`
+/// The output is executable on Unix
+pub fn create(
+    stub: &Path,
+    input: &Path,
+    password: &str,
+    output: &Path,
+    options: &WriteOptions,
+    cancel: &CancellationToken,
+) -> Result<BundleSummary> {
+    if !input.is_dir() {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "{} is not a directory; a self-extracting bundle holds a directory tree",
+            input.display()
+        )));
+    }
+    let mut archive = Vec::new();
+    let entries = archive::write(input, &ArchiveOptions::default(), &mut archive)?;
+    let plaintext_len = archive.len() as u64;
+
+    // The bundle's own keys, so no existing key file is ever shipped
+    let key_manager = KeyManager::from_master_key(&rand::random())?;
+    let sealed = key_manager.to_protected_bytes(&PasswordProtector::new(password).with_stretching(STRETCHING))?;
+    let encryptor = HybridGuardEncryptor::new();
+    let (file_keys, wrapped) = encryptor.new_file_keys(&key_manager)?;
+    let encrypted = encryptor.encrypt_cancellable(&archive, &file_keys, cancel);
+    archive.zeroize();
+    let payload = encrypted?.with_wrapped_key(wrapped, &file_keys).with_key_id(key_manager.key_id()).to_bytes()?;
+
+    let mut staged = StagedFile::create(output, None, Contents::Ciphertext)?.with_write_options(options.clone());
+    let key_offset = io::copy(&mut File::open(stub)?, staged.file())?;
+    let trailer = BundleTrailer {
+        key_offset,
+        key_len: sealed.len() as u64,
+        payload_offset: key_offset + sealed.len() as u64,
+        payload_len: payload.len() as u64,
+    };
+    staged.file().write_all(&sealed)?;
+    staged.file().write_all(&payload)?;
+    staged.file().write_all(&trailer.to_bytes())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        staged.file().set_permissions(std::fs::Permissions::from_mode(0o755))?;
+    }
+    staged.commit()?;
+
+    Ok(BundleSummary {
+        entries: entries.len(),
+        plaintext_len,
+        bundle_len: trailer.payload_offset + trailer.payload_len + container::BUNDLE_TRAILER_LEN as u64,
+    })
+}
+
+/// The trailer of the bundle at `path`, or None if it is not one
+pub fn detect(path: &Path) -> Result<Option<BundleTrailer>> {
+    container::read_bundle_trailer(&mut File::open(path)?)
+}
+
+/// Open the bundle at `path` with `password` and extract its archive into
+/// `dest`, which must be missing or empty
+pub fn extract(path: &Path, password: &str, dest: &Path, cancel: &CancellationToken) -> Result<Vec<ArchiveEntry>> {
+    let mut file = File::open(path)?;
+    let trailer = container::read_bundle_trailer(&mut file)?
+        .ok_or_else(|| HybridGuardError::InvalidInput(format!("{} is not a self-extracting bundle", path.display())))?;
+
+    let sealed = read_range(&mut file, trailer.key_offset, trailer.key_len)?;
+    let key_manager = KeyManager::from_protected_bytes(&sealed, &PasswordProtector::new(password))?;
+    let encrypted = container::decode(&read_range(&mut file, trailer.payload_offset, trailer.payload_len)?)?;
+    let keys = key_manager.keys_for(&encrypted)?;
+    let mut archive = HybridGuardEncryptor::new().decrypt_cancellable(&encrypted, &keys, cancel)?;
+    let entries = archive::extract(&archive[..], dest);
+    archive.zeroize();
+    entries
+}
+
+fn read_range(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(HybridGuardError::Decryption("bundle is shorter than its trailer says".to_string()));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let stub = dir.path().join("stub");
+        fs::write(&stub, b"#!/bin/sh\nexit 0\n").unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir_all(input.join("nested")).unwrap();
+        fs::write(input.join("a.txt"), b"alpha").unwrap();
+        fs::write(input.join("nested/b.txt"), b"bravo").unwrap();
+
+        let bundle = dir.path().join("bundle");
+        let cancel = CancellationToken::new();
+        let summary = create(&stub, &input, "plinth-Ocelot-47-marmalade", &bundle, &WriteOptions::bulk(), &cancel).unwrap();
+        assert_eq!(summary.entries, 3);
+        assert_eq!(summary.bundle_len, fs::metadata(&bundle).unwrap().len());
+        assert!(fs::read(&bundle).unwrap().starts_with(b"#!/bin/sh\nexit 0\n"));
+        assert_eq!(detect(&bundle).unwrap().unwrap().key_offset, 17);
+        assert_eq!(detect(&stub).unwrap(), None);
+
+        let wrong = extract(&bundle, "wrong", &dir.path().join("wrong"), &cancel);
+        assert!(matches!(wrong, Err(HybridGuardError::InvalidPassword)));
+
+        let dest = dir.path().join("out");
+        extract(&bundle, "plinth-Ocelot-47-marmalade", &dest, &cancel).unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(dest.join("nested/b.txt")).unwrap(), b"bravo");
+    }
+
+    #[test]
+    fn test_only_directories_are_bundled() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"data").unwrap();
+        let result = create(&file, &file, "pw", &dir.path().join("bundle"), &WriteOptions::bulk(), &CancellationToken::new());
+        assert!(matches!(result, Err(HybridGuardError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,138 @@
+// Cooperative cancellation
+// Long operations poll a shared flag between layers and between streaming
+// chunks, so a GUI Cancel button or Ctrl-C stops them within one chunk.
+// A token may also carry a `Budget`, checked at the same points
+
+use crate::budget::{Budget, BudgetUsage, Meter};
+use crate::error::{HybridGuardError, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use zeroize::Zeroize;
+
+/// Cheap clonable flag that asks running operations to stop
+/// Every clone shares the same flag; cancelling is permanent
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Shared>,
+    /// Budget of the run this token was handed to, if any
+    budget: Option<Arc<Meter>>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    polls: AtomicU64,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation holding a clone of this token to stop
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Times operations have checked this token, one per layer or chunk
+    /// After `cancel`, at most one more check happens before they return
+    pub fn polls(&self) -> u64 {
+        self.inner.polls.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `Cancelled` once the token has been triggered, or with
+    /// `BudgetExceeded` once its run has taken longer than its budget
+    pub fn check(&self) -> Result<()> {
+        self.inner.polls.fetch_add(1, Ordering::SeqCst);
+        if self.is_cancelled() {
+            return Err(HybridGuardError::Cancelled);
+        }
+        match &self.budget {
+            Some(meter) => meter.check_time(),
+            None => Ok(()),
+        }
+    }
+
+    /// A token sharing this one's flag whose run must stay within `budget`,
+    /// timed from now; its clones share the budget too
+    pub fn with_budget(&self, budget: Budget) -> Self {
+        Self { inner: Arc::clone(&self.inner), budget: Some(Arc::new(Meter::start(budget))) }
+    }
+
+    /// Take `bytes` about to be written from the output budget; fails with
+    /// `BudgetExceeded`, taking nothing, when they do not fit
+    pub fn take_output(&self, bytes: u64) -> Result<()> {
+        match &self.budget {
+            Some(meter) => meter.take_output(bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// How much of its budget the run has used; None without a budget
+    pub fn budget_usage(&self) -> Option<BudgetUsage> {
+        self.budget.as_ref().map(|meter| meter.usage())
+    }
+}
+
+/// Check `token`, if any, and zeroize `buffers` before reporting cancellation
+pub(crate) fn poll(token: Option<&CancellationToken>, buffers: &mut [&mut Vec<u8>]) -> Result<()> {
+    let Some(token) = token else {
+        return Ok(());
+    };
+    let checked = token.check();
+    if checked.is_err() {
+        for buffer in buffers.iter_mut() {
+            buffer.zeroize();
+        }
+    }
+    checked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_the_flag() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(clone.check().is_ok());
+        token.cancel();
+        assert!(clone.is_cancelled());
+        assert!(matches!(clone.check(), Err(HybridGuardError::Cancelled)));
+        assert_eq!(token.polls(), 2);
+    }
+
+    #[test]
+    fn test_poll_zeroizes_on_cancel() {
+        let token = CancellationToken::new();
+        let mut buffer = vec![0xAAu8; 64];
+        poll(Some(&token), &mut [&mut buffer]).unwrap();
+        assert_eq!(buffer, vec![0xAAu8; 64]);
+        assert!(poll(None, &mut [&mut buffer]).is_ok());
+
+        token.cancel();
+        assert!(poll(Some(&token), &mut [&mut buffer]).is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_budget_is_checked_with_the_flag() {
+        let token = CancellationToken::new();
+        let budgeted = token.with_budget(Budget { max_duration: Some(std::time::Duration::ZERO), max_output_bytes: Some(4) });
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(matches!(budgeted.clone().check(), Err(HybridGuardError::BudgetExceeded { .. })));
+        assert!(token.check().is_ok());
+        assert!(matches!(budgeted.take_output(5), Err(HybridGuardError::BudgetExceeded { .. })));
+        assert!(token.take_output(5).is_ok() && token.budget_usage().is_none());
+
+        // Cancelling either cancels both
+        budgeted.cancel();
+        assert!(matches!(token.check(), Err(HybridGuardError::Cancelled)));
+        assert_eq!(budgeted.budget_usage().unwrap().output_bytes, 0);
+    }
+}
@@ -0,0 +1,277 @@
+// Secrets handed over by a service manager instead of typed or put in the environment
+// `--password-fd N` and `--key-file-fd N` read a descriptor the parent left
+// open to its end, then close it so no producer or child inherits it. Under
+// systemd's LoadCredential= the files hybridguard.password and hybridguard.keys
+// in $CREDENTIALS_DIRECTORY are used when present. An explicit flag wins over
+// a credential, and a credential over the prompt or the default keys. Errors
+// name the descriptor or file, never anything read from it
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use hybridguard::crypto::secret::SecretBytes;
+use hybridguard::error::HybridGuardError;
+use hybridguard::pathname::JsonPath;
+use serde::Serialize;
+
+use super::keys::PasswordSource;
+
+/// Variable systemd sets to the directory of a service's credentials
+pub const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+/// Credential holding the key file password
+pub const PASSWORD_CREDENTIAL: &str = "hybridguard.password";
+
+/// Credential holding the key file
+pub const KEYS_CREDENTIAL: &str = "hybridguard.keys";
+
+/// Most bytes read from a descriptor; key files are a few KB
+const MAX_SECRET_LEN: u64 = 1 << 20;
+
+/// The systemd credentials directory of this run, if any
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    dir: Option<PathBuf>,
+}
+
+impl Credentials {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    /// `$CREDENTIALS_DIRECTORY`, unless it is unset or empty
+    pub fn from_env() -> Self {
+        Self::new(std::env::var_os(CREDENTIALS_DIRECTORY).filter(|dir| !dir.is_empty()).map(PathBuf::from))
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Path of the credential `name`, when the directory holds it
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let path = self.dir.as_ref()?.join(name);
+        path.is_file().then_some(path)
+    }
+}
+
+/// Where encrypt and decrypt take their keys from; the first that applies wins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeysOrigin {
+    /// `-k PATH`
+    File(PathBuf),
+    /// `--key-file-fd N`
+    Fd(i32),
+    /// `$CREDENTIALS_DIRECTORY/hybridguard.keys`
+    Credential(PathBuf),
+    /// Fresh keys from the default password
+    DefaultPassword,
+}
+
+impl KeysOrigin {
+    /// `-k` and `--key-file-fd` both name the keys, so only one may be given
+    pub fn resolve(key_file: Option<PathBuf>, key_file_fd: Option<i32>, credentials: &Credentials) -> Result<Self, HybridGuardError> {
+        match (key_file, key_file_fd) {
+            (Some(_), Some(fd)) => Err(HybridGuardError::InvalidInput(format!("-k and --key-file-fd {} both name the keys; give one", fd))),
+            (Some(path), None) => Ok(KeysOrigin::File(path)),
+            (None, Some(fd)) => Ok(KeysOrigin::Fd(fd)),
+            (None, None) => Ok(credentials.find(KEYS_CREDENTIAL).map_or(KeysOrigin::DefaultPassword, KeysOrigin::Credential)),
+        }
+    }
+}
+
+impl fmt::Display for KeysOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeysOrigin::File(path) => write!(f, "{}", path.display()),
+            KeysOrigin::Fd(fd) => write!(f, "--key-file-fd {}", fd),
+            KeysOrigin::Credential(path) => write!(f, "systemd credential {}", path.display()),
+            KeysOrigin::DefaultPassword => f.write_str("fresh keys from the default password"),
+        }
+    }
+}
+
+/// Where the key file password comes from; the first that applies wins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordOrigin {
+    /// `--password-fd N`
+    Fd(i32),
+    /// `$CREDENTIALS_DIRECTORY/hybridguard.password`
+    Credential(PathBuf),
+    /// Asked for on stdin
+    Prompt,
+}
+
+impl PasswordOrigin {
+    pub fn resolve(password_fd: Option<i32>, credentials: &Credentials) -> Self {
+        match password_fd {
+            Some(fd) => PasswordOrigin::Fd(fd),
+            None => credentials.find(PASSWORD_CREDENTIAL).map_or(PasswordOrigin::Prompt, PasswordOrigin::Credential),
+        }
+    }
+
+    /// The password handed over, or None when it is to be asked for
+    pub fn read(&self) -> Result<Option<GivenPassword>, HybridGuardError> {
+        let bytes = match self {
+            PasswordOrigin::Fd(fd) => read_fd("--password-fd", *fd)?,
+            PasswordOrigin::Credential(path) => SecretBytes::new(std::fs::read(path)?),
+            PasswordOrigin::Prompt => return Ok(None),
+        };
+        GivenPassword::from_bytes(&bytes, self).map(Some)
+    }
+}
+
+impl fmt::Display for PasswordOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordOrigin::Fd(fd) => write!(f, "--password-fd {}", fd),
+            PasswordOrigin::Credential(path) => write!(f, "systemd credential {}", path.display()),
+            PasswordOrigin::Prompt => f.write_str("prompt on stdin"),
+        }
+    }
+}
+
+/// A key file password handed over whole; never asked for again, so a wrong
+/// one fails the run at once
+pub struct GivenPassword(String);
+
+impl GivenPassword {
+    /// The password in `bytes`, trimmed as a typed one is
+    fn from_bytes(bytes: &[u8], origin: &PasswordOrigin) -> Result<Self, HybridGuardError> {
+        let text = std::str::from_utf8(bytes).map_err(|_| HybridGuardError::InvalidInput(format!("{}: the password is not UTF-8", origin)))?;
+        let password = text.trim();
+        if password.is_empty() {
+            return Err(HybridGuardError::InvalidInput(format!("{}: the password is empty", origin)));
+        }
+        Ok(Self(password.to_string()))
+    }
+}
+
+impl PasswordSource for GivenPassword {
+    fn read(&self, _prompt: &str) -> io::Result<Option<String>> {
+        Ok(Some(self.0.clone()))
+    }
+
+    fn interactive(&self) -> bool {
+        false
+    }
+}
+
+/// Read the inherited descriptor `fd` to its end and close it; `flag` names
+/// it in errors
+#[cfg(unix)]
+pub fn read_fd(flag: &str, fd: i32) -> Result<SecretBytes, HybridGuardError> {
+    use std::io::Read;
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    if (0..=2).contains(&fd) {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "{} {}: descriptors 0 to 2 are stdin, stdout and stderr; pass the secret on 3 or above",
+            flag, fd
+        )));
+    }
+    // Taking ownership of a descriptor that is not open would close someone else's later
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(HybridGuardError::InvalidInput(format!("{} {}: not an open file descriptor", flag, fd)));
+    }
+    // SAFETY: `fd` is open, and nothing else in this process knows it
+    let file = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    let mut bytes = Vec::new();
+    let read = file.take(MAX_SECRET_LEN + 1).read_to_end(&mut bytes);
+    let bytes = SecretBytes::new(bytes);
+    if let Err(e) = read {
+        return Err(HybridGuardError::Io(io::Error::new(e.kind(), format!("{} {}: {}", flag, fd, e))));
+    }
+    if bytes.is_empty() {
+        return Err(HybridGuardError::InvalidInput(format!("{} {}: nothing to read; was it already read or closed?", flag, fd)));
+    }
+    if bytes.len() as u64 > MAX_SECRET_LEN {
+        return Err(HybridGuardError::InvalidInput(format!("{} {}: more than {} bytes", flag, fd, MAX_SECRET_LEN)));
+    }
+    Ok(bytes)
+}
+
+/// File descriptors are only inherited this way on Unix
+#[cfg(not(unix))]
+pub fn read_fd(flag: &str, fd: i32) -> Result<SecretBytes, HybridGuardError> {
+    Err(HybridGuardError::InvalidInput(format!("{} {}: secrets are only passed by descriptor on Unix", flag, fd)))
+}
+
+/// What `config show` prints: where each secret would come from, without reading any
+#[derive(Debug, Serialize)]
+pub struct SecretSources {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials_directory: Option<JsonPath>,
+    pub keys: String,
+    /// None when no --protector password is set, so no password is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_file_password: Option<String>,
+}
+
+impl SecretSources {
+    pub fn new(credentials: &Credentials, keys: &KeysOrigin, password: Option<&PasswordOrigin>) -> Self {
+        Self {
+            credentials_directory: credentials.dir().map(JsonPath::new),
+            keys: keys.to_string(),
+            key_file_password: password.map(ToString::to_string),
+        }
+    }
+}
+
+impl fmt::Display for SecretSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.credentials_directory {
+            Some(dir) => writeln!(f, "  Credentials:        {}", dir)?,
+            None => writeln!(f, "  Credentials:        ${} not set", CREDENTIALS_DIRECTORY)?,
+        }
+        writeln!(f, "  Keys:               {}", self.keys)?;
+        write!(f, "  Key file password:  {}", self.key_file_password.as_deref().unwrap_or("not used (no --protector password)"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_win_over_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = Credentials::new(Some(dir.path().to_path_buf()));
+        assert_eq!(KeysOrigin::resolve(None, None, &credentials).unwrap(), KeysOrigin::DefaultPassword);
+        assert_eq!(PasswordOrigin::resolve(None, &credentials), PasswordOrigin::Prompt);
+
+        std::fs::write(dir.path().join(KEYS_CREDENTIAL), b"{}").unwrap();
+        std::fs::write(dir.path().join(PASSWORD_CREDENTIAL), b"correct horse\n").unwrap();
+        let keys = dir.path().join(KEYS_CREDENTIAL);
+        assert_eq!(KeysOrigin::resolve(None, None, &credentials).unwrap(), KeysOrigin::Credential(keys));
+        assert_eq!(KeysOrigin::resolve(None, Some(5), &credentials).unwrap(), KeysOrigin::Fd(5));
+        let explicit = PathBuf::from("ops.keys");
+        assert_eq!(KeysOrigin::resolve(Some(explicit.clone()), None, &credentials).unwrap(), KeysOrigin::File(explicit.clone()));
+        assert!(KeysOrigin::resolve(Some(explicit), Some(5), &credentials).is_err());
+        assert_eq!(PasswordOrigin::resolve(Some(7), &credentials), PasswordOrigin::Fd(7));
+
+        // The credential's trailing newline is not part of the password
+        let origin = PasswordOrigin::resolve(None, &credentials);
+        let password = origin.read().unwrap().unwrap();
+        assert_eq!(password.read("").unwrap().as_deref(), Some("correct horse"));
+        assert!(!password.interactive());
+    }
+
+    #[test]
+    fn test_bad_passwords_are_not_echoed() {
+        let origin = PasswordOrigin::Fd(3);
+        let error = GivenPassword::from_bytes(b"\xffhunter2", &origin).err().unwrap().to_string();
+        assert!(error.contains("--password-fd 3") && !error.contains("hunter2"), "{}", error);
+        assert!(GivenPassword::from_bytes(b" \n", &origin).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_closed_descriptors_are_refused() {
+        let error = read_fd("--key-file-fd", 1 << 20).err().unwrap();
+        assert!(matches!(&error, HybridGuardError::InvalidInput(m) if m.contains("not an open file descriptor")), "{}", error);
+        assert!(read_fd("--key-file-fd", 1).is_err());
+        assert!(read_fd("--key-file-fd", -1).is_err());
+    }
+}
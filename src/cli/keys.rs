@@ -0,0 +1,508 @@
+// Key files as the global --fix-permissions and --protector flags say
+// Every command that reads or writes a key file goes through `KeyFiles`, so a
+// protected key file works wherever a plain one does. Writes and backups run
+// under the keystore lock; reads take none, since key files are replaced by
+// rename. A wrong key file password is caught when the file is unsealed,
+// before any key is used, and a person at a terminal is asked again up to
+// --password-attempts times; piped input and --json-progress runs fail at once.
+// A key file re-saved without --kdf-params keeps the Argon2id parameters of
+// the file it replaces, or else of the sealed file loaded this run, so
+// re-saving never drops the stretching
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use hybridguard::crypto::kdf::KdfParams;
+use hybridguard::error::HybridGuardError;
+use hybridguard::fsutil::WriteOptions;
+use hybridguard::message;
+use hybridguard::messages::Catalog;
+use hybridguard::key_manager::backup::{self, BackupPolicy, KeyBackup};
+use hybridguard::key_manager::doctor::{self, KeyFileDiagnosis};
+use hybridguard::key_manager::lock::{KeystoreLock, LockOptions};
+use hybridguard::key_manager::permissions::{self, LoosePermissions};
+use hybridguard::key_manager::protector::{self, HmacFileProtector, KeyFileProtector, PasswordProtector, ProtectorSpec};
+use hybridguard::key_manager::provenance::{self, KeyOwner, SignatureStatus};
+use hybridguard::key_manager::Capability;
+use hybridguard::pathname::JsonPath;
+use hybridguard::timing::{Clock, SystemClock};
+use hybridguard::{KeyManager, Warnings};
+use serde::Serialize;
+
+use super::reporter::{Reporter, Verbosity};
+
+/// Tries at a key file password before giving up, by default
+pub const DEFAULT_PASSWORD_ATTEMPTS: u32 = 3;
+
+/// Where key file passwords are typed; tests substitute scripted answers
+pub trait PasswordSource {
+    /// The line typed after `prompt`, None at the end of input
+    fn read(&self, prompt: &str) -> io::Result<Option<String>>;
+
+    /// Whether a person is typing, who may be asked again after a typo
+    fn interactive(&self) -> bool;
+}
+
+/// Passwords read from stdin, with prompts on stderr
+pub struct StdinPasswords;
+
+impl PasswordSource for StdinPasswords {
+    fn read(&self, prompt: &str) -> io::Result<Option<String>> {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+        let mut password = String::new();
+        if io::stdin().read_line(&mut password)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(password.trim().to_string()))
+    }
+
+    fn interactive(&self) -> bool {
+        io::stdin().is_terminal()
+    }
+}
+
+/// How key files are opened and saved
+#[derive(Clone)]
+pub struct KeyFiles {
+    pub loose: LoosePermissions,
+    /// None reads and writes plain key files
+    pub protector: Option<ProtectorSpec>,
+    /// Argon2id parameters the password protector seals with
+    stretching: Option<KdfParams>,
+    /// Parameters of the last stretched key file unsealed, kept for re-saving it
+    loaded_stretching: RefCell<Option<KdfParams>>,
+    /// Asked for once per run, however many key files are opened or saved,
+    /// and again only after it failed to unseal one
+    password: RefCell<Option<String>>,
+    passwords: Rc<dyn PasswordSource>,
+    /// Tries at the password before a load fails; 1 never asks again
+    password_attempts: u32,
+    /// Backups taken before a key file is overwritten; None takes none
+    backups: Option<(BackupPolicy, Reporter)>,
+    /// How saved key files are finished
+    write: WriteOptions,
+    /// How long writers wait for the keystore lock
+    lock: LockOptions,
+    /// Where warnings about loaded key files go; None logs them
+    warnings: Option<Warnings>,
+    /// Wording of the password prompts
+    catalog: &'static Catalog,
+}
+
+impl KeyFiles {
+    pub fn new(loose: LoosePermissions, protector: Option<ProtectorSpec>) -> Self {
+        Self {
+            loose,
+            protector,
+            stretching: None,
+            loaded_stretching: RefCell::new(None),
+            password: RefCell::new(None),
+            passwords: Rc::new(StdinPasswords),
+            password_attempts: DEFAULT_PASSWORD_ATTEMPTS,
+            backups: None,
+            write: WriteOptions::critical(),
+            lock: LockOptions::new(),
+            warnings: None,
+            catalog: Reporter::new(Verbosity::Normal).catalog(),
+        }
+    }
+
+    /// Ask for a wrong key file password up to `attempts` times in all, when
+    /// a person is typing it
+    pub fn with_password_attempts(mut self, attempts: u32) -> Self {
+        self.password_attempts = attempts.max(1);
+        self
+    }
+
+    /// Read passwords from `source` instead of stdin
+    pub fn with_password_source(mut self, source: Rc<dyn PasswordSource>) -> Self {
+        self.passwords = source;
+        self
+    }
+
+    /// Wait for the keystore lock as `options` say
+    pub fn with_lock(mut self, options: LockOptions) -> Self {
+        self.lock = options;
+        self
+    }
+
+    /// Finish saved key files as `options` ask instead of fsyncing them
+    pub fn with_write_options(mut self, options: WriteOptions) -> Self {
+        self.write = options;
+        self
+    }
+
+    /// How saved key files are finished
+    pub fn write_options(&self) -> &WriteOptions {
+        &self.write
+    }
+
+    /// Back up existing key files under `policy` before replacing them
+    pub fn with_backups(mut self, policy: BackupPolicy, reporter: Reporter) -> Self {
+        self.backups = Some((policy, reporter));
+        self
+    }
+
+    /// Where backups go, when they are taken
+    pub fn backup_policy(&self) -> Option<&BackupPolicy> {
+        self.backups.as_ref().map(|(policy, _)| policy)
+    }
+
+    /// Raise warnings about loaded key files, such as loose permissions, in `warnings`
+    pub fn with_warnings(mut self, warnings: Warnings) -> Self {
+        self.warnings = Some(warnings);
+        self
+    }
+
+    /// Word the password prompts from `catalog`
+    pub fn with_catalog(mut self, catalog: &'static Catalog) -> Self {
+        self.catalog = catalog;
+        self
+    }
+
+    /// Seal with the password protector stretched by Argon2id under `params`
+    pub fn with_stretching(mut self, params: KdfParams) -> Self {
+        self.stretching = Some(params);
+        self
+    }
+
+    /// Load the key file at `path`, warning if its self-signature is missing or invalid
+    pub fn load(&self, path: &Path) -> Result<KeyManager, HybridGuardError> {
+        let bytes = std::fs::read(path)?;
+        permissions::check_private_with(path, self.loose, self.warnings.as_ref())?;
+        let key_manager = self.parse(&bytes)?;
+        provenance::warn_if_unsigned(path, &key_manager);
+        Ok(key_manager)
+    }
+
+    /// Parse key file contents, unsealing them with the protector if one is set
+    /// A wrong password is asked for again while attempts remain; unsealing
+    /// fails on it before any layer key exists, so nothing else has run
+    pub fn parse(&self, bytes: &[u8]) -> Result<KeyManager, HybridGuardError> {
+        let mut attempt = 1;
+        loop {
+            let parsed = match self.open_protector(None)? {
+                Some(protector) => {
+                    let parsed = KeyManager::from_protected_bytes(bytes, protector.as_ref());
+                    if let (Ok(_), Some(params)) = (&parsed, protector::stretching(bytes)) {
+                        *self.loaded_stretching.borrow_mut() = Some(params);
+                    }
+                    parsed
+                }
+                None => KeyManager::from_bytes(bytes),
+            };
+            match parsed {
+                Err(HybridGuardError::InvalidPassword) if attempt < self.password_attempts && self.passwords.interactive() => {
+                    *self.password.borrow_mut() = Some(self.ask_password(Some(attempt))?);
+                    attempt += 1;
+                }
+                parsed => return parsed,
+            }
+        }
+    }
+
+    /// Diagnose key file contents, unsealing them with the protector if one is set
+    pub fn diagnose(&self, bytes: &[u8]) -> Result<KeyFileDiagnosis, HybridGuardError> {
+        match self.open_protector(None)? {
+            Some(protector) => doctor::diagnose_protected(bytes, protector.as_ref()),
+            None => Ok(doctor::diagnose(bytes)),
+        }
+    }
+
+    /// What `key list` shows of the key file at `path`; a file that cannot be
+    /// read or parsed is listed with the reason
+    pub fn describe(&self, path: &Path) -> KeyListing {
+        let mut listing = KeyListing {
+            path: JsonPath::new(path),
+            key_id: None,
+            created_at: None,
+            owner: None,
+            capabilities: Vec::new(),
+            signature: None,
+            unreadable: None,
+        };
+        let parsed = std::fs::read(path).map_err(HybridGuardError::from).and_then(|bytes| self.parse(&bytes));
+        match parsed {
+            Ok(key_manager) => {
+                listing.key_id = Some(key_manager.key_id().to_string());
+                listing.created_at = Some(key_manager.created_at().to_string());
+                listing.owner = key_manager.owner().cloned();
+                listing.capabilities = key_manager.capabilities().to_vec();
+                listing.signature = key_manager.self_signature().cloned();
+            }
+            Err(e) => listing.unreadable = Some(e.to_string()),
+        }
+        listing
+    }
+
+    /// Save `key_manager` to `path`, sealed with the protector if one is set
+    /// A key file already at `path` is backed up first
+    pub fn save(&self, key_manager: &KeyManager, path: &Path) -> Result<(), HybridGuardError> {
+        // Ask for the password before other runs are kept waiting on it
+        self.open_protector(None)?;
+        self.lock(path)?.save(key_manager, path)
+    }
+
+    /// Copy the key file at `path`, if any, aside before it is replaced
+    pub fn back_up(&self, path: &Path) -> Result<Option<KeyBackup>, HybridGuardError> {
+        self.lock(path)?.back_up(path)
+    }
+
+    /// Take the lock of the keystore holding `key_file`, for a change of several steps
+    pub fn lock(&self, key_file: &Path) -> Result<LockedKeystore<'_>, HybridGuardError> {
+        let lock = KeystoreLock::for_key_file(key_file, &self.lock)?;
+        Ok(LockedKeystore { key_files: self, _lock: lock })
+    }
+
+    /// Argon2id parameters to seal `path` with: --kdf-params, else those of
+    /// the key file there, else those of the key file loaded this run
+    fn stretching_for(&self, path: &Path) -> Option<KdfParams> {
+        self.stretching
+            .or_else(|| std::fs::read(path).ok().and_then(|bytes| protector::stretching(&bytes)))
+            .or(*self.loaded_stretching.borrow())
+    }
+
+    /// The configured protector, sealing with `stretching` and prompting for
+    /// its password if it needs one
+    fn open_protector(&self, stretching: Option<KdfParams>) -> Result<Option<Box<dyn KeyFileProtector>>, HybridGuardError> {
+        Ok(match &self.protector {
+            None => None,
+            Some(ProtectorSpec::Password) => {
+                let protector = PasswordProtector::new(&self.password()?);
+                Some(Box::new(match stretching {
+                    Some(params) => protector.with_stretching(params),
+                    None => protector,
+                }))
+            }
+            Some(ProtectorSpec::HmacFile(path)) => Some(Box::new(HmacFileProtector::load(path)?)),
+        })
+    }
+
+    fn password(&self) -> Result<String, HybridGuardError> {
+        if let Some(password) = self.password.borrow().as_ref() {
+            return Ok(password.clone());
+        }
+        let password = self.ask_password(None)?;
+        *self.password.borrow_mut() = Some(password.clone());
+        Ok(password)
+    }
+
+    /// Prompt for the password, saying which attempt failed when one did
+    fn ask_password(&self, failed: Option<u32>) -> Result<String, HybridGuardError> {
+        let mut prompt = String::new();
+        if let Some(failed) = failed {
+            let left = self.password_attempts - failed;
+            prompt = message!(self.catalog, "prompt-key-password-wrong", attempt = failed, attempts = self.password_attempts, left = left);
+            prompt.push('\n');
+        }
+        prompt.push_str(&self.catalog.text("prompt-key-password", &[]));
+        match self.passwords.read(&prompt)? {
+            Some(password) => Ok(password),
+            None => Err(HybridGuardError::InvalidInput("no key file password given".to_string())),
+        }
+    }
+}
+
+/// One key file as `key list` shows it
+#[derive(Debug, Serialize)]
+pub struct KeyListing {
+    pub path: JsonPath,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<KeyOwner>,
+    pub capabilities: Vec<Capability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureStatus>,
+    /// Why the file could not be read, e.g. it is sealed or damaged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unreadable: Option<String>,
+}
+
+impl fmt::Display for KeyListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.path)?;
+        if let Some(reason) = &self.unreadable {
+            return write!(f, "  Unreadable:   {}", reason);
+        }
+        writeln!(f, "  Key ID:       {}", self.key_id.as_deref().unwrap_or_default())?;
+        writeln!(f, "  Created:      {}", self.created_at.as_deref().unwrap_or_default())?;
+        if let Some(owner) = &self.owner {
+            writeln!(f, "  Owner:        {}", owner)?;
+        }
+        let capabilities: Vec<String> = self.capabilities.iter().map(|capability| format!("{:?}", capability).to_lowercase()).collect();
+        writeln!(f, "  Capabilities: {}", capabilities.join(", "))?;
+        match &self.signature {
+            Some(signature) => write!(f, "  Signature:    {}", signature),
+            None => write!(f, "  Signature:    not checked"),
+        }
+    }
+}
+
+/// Key files `key list` covers: each file given, and the `*.keys` files
+/// directly in each directory given, in name order
+pub fn list_paths(paths: &[PathBuf]) -> Result<Vec<PathBuf>, HybridGuardError> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.path().extension().is_some_and(|extension| extension == "keys") && entry.file_type()?.is_file() {
+                found.push(entry.path());
+            }
+        }
+        found.sort();
+        files.extend(found);
+    }
+    Ok(files)
+}
+
+/// A keystore locked for writing, unlocked on drop
+pub struct LockedKeystore<'a> {
+    key_files: &'a KeyFiles,
+    _lock: KeystoreLock,
+}
+
+impl LockedKeystore<'_> {
+    /// `KeyFiles::save` under this lock
+    pub fn save(&self, key_manager: &KeyManager, path: &Path) -> Result<(), HybridGuardError> {
+        self.back_up(path)?;
+        let key_files = self.key_files;
+        match key_files.open_protector(key_files.stretching_for(path))? {
+            Some(protector) => key_manager.save_protected_with(path, protector.as_ref(), &key_files.write),
+            None => key_manager.save_with(path, &key_files.write),
+        }
+    }
+
+    /// Replace the key file at `path` with `bytes`, already checked to be one
+    pub fn write(&self, path: &Path, bytes: &[u8]) -> Result<(), HybridGuardError> {
+        Ok(permissions::replace_private_with(path, bytes, &self.key_files.write)?)
+    }
+
+    /// `KeyFiles::back_up` under this lock
+    pub fn back_up(&self, path: &Path) -> Result<Option<KeyBackup>, HybridGuardError> {
+        let Some((policy, reporter)) = &self.key_files.backups else {
+            return Ok(None);
+        };
+        let backup = backup::back_up(path, policy, SystemClock::new().unix_secs())?;
+        if let Some(backup) = &backup {
+            reporter.progress(message!(reporter, "backup-taken", path = path.display(), backup = backup.path.display()));
+            if !backup.protected {
+                reporter.warn(message!(reporter, "backup-plaintext", backup = backup.path.display()));
+            }
+        }
+        Ok(backup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Answers prompts from a script and records them
+    struct ScriptedPasswords {
+        answers: RefCell<VecDeque<&'static str>>,
+        prompts: RefCell<Vec<String>>,
+        interactive: bool,
+    }
+
+    impl ScriptedPasswords {
+        fn new(answers: &[&'static str], interactive: bool) -> Rc<Self> {
+            Rc::new(Self { answers: RefCell::new(answers.iter().copied().collect()), prompts: RefCell::default(), interactive })
+        }
+    }
+
+    impl PasswordSource for ScriptedPasswords {
+        fn read(&self, prompt: &str) -> io::Result<Option<String>> {
+            self.prompts.borrow_mut().push(prompt.to_string());
+            Ok(self.answers.borrow_mut().pop_front().map(str::to_string))
+        }
+
+        fn interactive(&self) -> bool {
+            self.interactive
+        }
+    }
+
+    fn sealed() -> (KeyManager, Vec<u8>) {
+        let key_manager = KeyManager::from_master_key(&[0x2F; 32]).unwrap();
+        let bytes = key_manager.to_protected_bytes(&PasswordProtector::new("correct horse")).unwrap();
+        (key_manager, bytes)
+    }
+
+    fn key_files(source: Rc<ScriptedPasswords>) -> KeyFiles {
+        KeyFiles::new(LoosePermissions::Warn, Some(ProtectorSpec::Password)).with_password_source(source)
+    }
+
+    #[test]
+    fn test_wrong_passwords_are_asked_again() {
+        let (expected, bytes) = sealed();
+        let source = ScriptedPasswords::new(&["correct hrose", "Correct horse", "correct horse"], true);
+        let loaded = key_files(source.clone()).parse(&bytes).unwrap();
+        assert_eq!(loaded.key_id(), expected.key_id());
+
+        let prompts = source.prompts.borrow();
+        assert_eq!(prompts.len(), 3);
+        assert!(!prompts[0].contains("Wrong"));
+        assert!(prompts[1].contains("attempt 1 of 3); 2 attempt(s) left"), "{}", prompts[1]);
+        assert!(prompts[2].contains("attempt 2 of 3); 1 attempt(s) left"), "{}", prompts[2]);
+    }
+
+    #[test]
+    fn test_prompts_come_from_the_catalog() {
+        let (_, bytes) = sealed();
+        let source = ScriptedPasswords::new(&["one", "correct horse"], true);
+        let catalog = Catalog::parse("prompt-key-password = Schlüsseldatei-Passwort: \nprompt-key-password-wrong = Falsch ({left} übrig)").unwrap();
+        key_files(source.clone()).with_catalog(Box::leak(Box::new(catalog.with_emoji(false)))).parse(&bytes).unwrap();
+
+        let prompts = source.prompts.borrow();
+        assert_eq!(prompts[0], "Schlüsseldatei-Passwort:");
+        assert_eq!(prompts[1], "Falsch (2 übrig)\nSchlüsseldatei-Passwort:");
+    }
+
+    #[test]
+    fn test_attempts_run_out() {
+        let (_, bytes) = sealed();
+        let source = ScriptedPasswords::new(&["one", "two", "correct horse"], true);
+        let result = key_files(source.clone()).with_password_attempts(2).parse(&bytes);
+        assert!(matches!(result, Err(HybridGuardError::InvalidPassword)));
+        assert_eq!(source.prompts.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_resaving_keeps_the_stretching() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key_manager, path) = (KeyManager::from_master_key(&[0x30; 32]).unwrap(), dir.path().join("stretched.keys"));
+        let params = KdfParams { memory_kib: hybridguard::crypto::kdf::MIN_MEMORY_KIB, iterations: 1, parallelism: 1 };
+        key_manager.save_protected(&path, &PasswordProtector::new("correct horse").with_stretching(params)).unwrap();
+
+        // Over the same file, and to a new one after loading it
+        let key_files = key_files(ScriptedPasswords::new(&["correct horse"], false));
+        let loaded = key_files.load(&path).unwrap();
+        key_files.save(&loaded, &path).unwrap();
+        let copy = dir.path().join("copy.keys");
+        key_files.save(&loaded, &copy).unwrap();
+        for path in [&path, &copy] {
+            assert_eq!(protector::stretching(&std::fs::read(path).unwrap()), Some(params), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_piped_passwords_are_not_asked_again() {
+        let (_, bytes) = sealed();
+        let source = ScriptedPasswords::new(&["wrong", "correct horse"], false);
+        let result = key_files(source.clone()).parse(&bytes);
+        assert!(matches!(result, Err(HybridGuardError::InvalidPassword)));
+        assert_eq!(source.prompts.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,230 @@
+// File handling for `hybridguard migrate`
+// Outputs are staged in a temporary file and renamed into place, so an
+// interrupted run never leaves a partial output. The staged file is read
+// back and decrypted before the rename, so an in-place migration never
+// replaces the original with a bad copy; inputs are only removed with
+// --delete-old
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use hybridguard::crypto::sniff::{self, FileKind};
+use hybridguard::error::HybridGuardError;
+use hybridguard::fsutil::WriteOptions;
+use hybridguard::message;
+use hybridguard::migrate::{self, Migrated};
+use hybridguard::rekey;
+use hybridguard::scan::{Format, ScanHit};
+use hybridguard::staging::{self, Contents, StagedFile};
+use hybridguard::KeyManager;
+
+use super::reporter::Reporter;
+
+/// Suffix of temporary outputs left behind by older versions
+const LEGACY_TEMP_SUFFIX: &str = ".hg-migrate.tmp";
+
+/// Flags shared by single-file and recursive migration
+pub struct MigrateOptions {
+    /// Overwrite existing outputs
+    pub force: bool,
+    /// Remove each input once its replacement is verified
+    pub delete_old: bool,
+    /// Where outputs are staged (default: beside each output)
+    pub temp_dir: Option<PathBuf>,
+    /// How each output is finished before and after its rename
+    pub write: WriteOptions,
+}
+
+/// What happened to one file
+pub enum Outcome {
+    /// Re-encrypted from the given container format version
+    Migrated { from_format: u16 },
+    /// Already in the current format; nothing written
+    Current,
+    /// Not a HybridGuard file; nothing written
+    NotCiphertext,
+}
+
+/// Totals of a recursive migration
+#[derive(Default)]
+pub struct Summary {
+    pub migrated: usize,
+    pub current: usize,
+    pub not_ciphertext: usize,
+    pub failed: Vec<(PathBuf, HybridGuardError)>,
+}
+
+impl Summary {
+    pub fn record(&mut self, input: &Path, result: Result<Outcome, HybridGuardError>, reporter: &Reporter) {
+        match result {
+            Ok(Outcome::Migrated { from_format }) => {
+                reporter.progress(message!(reporter, "migrate-entry-done", input = input.display(), from = from_format));
+                self.migrated += 1;
+            }
+            Ok(Outcome::Current) => self.current += 1,
+            Ok(Outcome::NotCiphertext) => self.not_ciphertext += 1,
+            Err(e) => {
+                reporter.error(message!(reporter, "migrate-entry-failed", input = input.display(), error = e.localized(reporter.catalog())));
+                self.failed.push((input.to_path_buf(), e));
+            }
+        }
+    }
+
+    /// One-line totals for the default output
+    pub fn line(&self, reporter: &Reporter) -> String {
+        message!(
+            reporter,
+            "migrate-summary",
+            migrated = self.migrated,
+            current = self.current,
+            not_ciphertext = self.not_ciphertext,
+            failed = self.failed.len()
+        )
+    }
+
+    /// The first failure, if any file failed
+    pub fn into_result(self) -> Result<(), HybridGuardError> {
+        match self.failed.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Migrate one file to `output`, which may equal `input` when replacing in place
+pub fn migrate_file(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    options: &MigrateOptions,
+) -> Result<Outcome, HybridGuardError> {
+    let bytes = fs::read(input).map_err(|e| path_error(input, e))?;
+    if sniff::identify(&bytes) != FileKind::HybridGuard {
+        return Ok(Outcome::NotCiphertext);
+    }
+    if migrate::is_current(&bytes)? {
+        return Ok(Outcome::Current);
+    }
+
+    let in_place = input == output;
+    if in_place && !options.delete_old {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "{}: output would replace the input (pass --delete-old to migrate in place)",
+            input.display()
+        )));
+    }
+    if !in_place && output.exists() && !options.force {
+        return Err(HybridGuardError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists (pass --force to overwrite)", output.display()),
+        )));
+    }
+
+    let migrated = migrate::migrate(&bytes, key_manager)?;
+    write_verified(output, &migrated, key_manager, options).map_err(|e| match e {
+        HybridGuardError::Integrity(reason) => HybridGuardError::Integrity(format!("{}; keeping {}", reason, input.display())),
+        other => other,
+    })?;
+
+    if options.delete_old && !in_place {
+        fs::remove_file(input).map_err(|e| path_error(input, e))?;
+    }
+
+    Ok(Outcome::Migrated { from_format: migrated.from_format })
+}
+
+/// Re-encrypt the file `hit` found in place under `to`, from `from`'s keys,
+/// keeping its format
+/// Files already current and under `to` are left alone; the replacement is
+/// checked to decrypt before it is renamed over the original
+pub fn rekey_file(
+    hit: &ScanHit,
+    from: &KeyManager,
+    to: &KeyManager,
+    options: &MigrateOptions,
+) -> Result<Outcome, HybridGuardError> {
+    if hit.key_id.as_deref() == Some(to.key_id()) {
+        // Only a binary container can be under the new key in an older format
+        if hit.format != Format::Container || migrate::is_current(&fs::read(&hit.path).map_err(|e| path_error(&hit.path, e))?)? {
+            return Ok(Outcome::Current);
+        }
+    }
+
+    let from_format = rekey::rekey_file(&hit.path, hit.format, from, to, options.temp_dir.as_deref(), &options.write)?;
+    Ok(Outcome::Migrated { from_format })
+}
+
+/// Migrate every file under `input`, mirroring the tree into `output`
+/// Passing the same directory for both replaces files in place (needs --delete-old)
+pub fn migrate_tree(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    options: &MigrateOptions,
+    reporter: &Reporter,
+) -> Result<Summary, HybridGuardError> {
+    let mut files = Vec::new();
+    collect_files(input, &mut files)?;
+    files.sort();
+
+    let mut summary = Summary::default();
+    for file in files {
+        let relative = file.strip_prefix(input).unwrap_or(&file);
+        let target = output.join(relative);
+        let result = create_parent(&target).and_then(|()| migrate_file(&file, &target, key_manager, options));
+        summary.record(&file, result, reporter);
+    }
+    Ok(summary)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), HybridGuardError> {
+    for entry in fs::read_dir(dir).map_err(|e| path_error(dir, e))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if !is_temporary(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn create_parent(path: &Path) -> Result<(), HybridGuardError> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            fs::create_dir_all(parent).map_err(|e| path_error(parent, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_temporary(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(staging::TEMP_SUFFIX) || name.ends_with(LEGACY_TEMP_SUFFIX)
+}
+
+/// Stage the new container in a temporary file and read it back, then
+/// finish the file and rename it over `path` once the copy checks out
+fn write_verified(path: &Path, migrated: &Migrated, key_manager: &KeyManager, options: &MigrateOptions) -> Result<(), HybridGuardError> {
+    let bytes = &migrated.bytes;
+    let mut staged = StagedFile::create(path, options.temp_dir.as_deref(), Contents::Ciphertext)
+        .map_err(|e| path_error(path, e))?
+        .with_write_options(options.write.clone());
+    let mut written = Vec::with_capacity(bytes.len());
+    let file = staged.file();
+    file.write_all(bytes)
+        .and_then(|()| file.flush())
+        .and_then(|()| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.read_to_end(&mut written))
+        .map_err(|e| path_error(path, e))?;
+    migrated.check_copy(&written, key_manager).map_err(|e| match e {
+        HybridGuardError::Integrity(reason) => HybridGuardError::Integrity(format!("{}: {}", path.display(), reason)),
+        other => other,
+    })?;
+    staged.commit().map_err(|e| path_error(path, e))
+}
+
+fn path_error(path: &Path, e: io::Error) -> HybridGuardError {
+    HybridGuardError::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
@@ -0,0 +1,12 @@
+// Binary-only modules of the hybridguard CLI
+
+pub mod credentials;
+pub mod keys;
+pub mod migrate;
+pub mod pipe;
+pub mod pipeline;
+pub mod plan;
+pub mod preflight;
+pub mod reporter;
+pub mod resource;
+pub mod stats;
@@ -0,0 +1,144 @@
+// Decrypting from a pipe
+// `decrypt --input -` reads stdin once, front to back. Armored text is decoded
+// a line at a time by `ArmorReader`, and a chunked ciphertext, armored or not,
+// is authenticated and decrypted as it arrives by `chunked::decrypt_stream`,
+// so memory stays bounded whatever the input size. A single container needs
+// all of its bytes before any layer runs; on a pipe it is refused unless
+// `--spool-to-temp` copies the stream to a scratch file the usual decrypt reads.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use hybridguard::crypto::encoding::{self, ArmorReader, Encoding};
+use hybridguard::error::HybridGuardError;
+use hybridguard::fsutil::WriteOptions;
+use hybridguard::staging::{Contents, StagedFile};
+use hybridguard::streaming::chunked::{self, ChunkedStats};
+use hybridguard::{CancellationToken, KeyManager};
+
+/// Input path that stands for stdin
+pub const STDIN: &str = "-";
+
+/// Leading bytes that tell armor, JSON and binary apart
+const SNIFF_LEN: usize = 64;
+
+/// What became of the piped input
+pub enum Piped {
+    /// A chunked ciphertext, decrypted straight into the output
+    Decrypted(ChunkedStats),
+    /// Any other input, copied to a scratch file for the usual decrypt
+    Spooled(Spool),
+}
+
+/// A scratch file, such as a copy of stdin, removed when dropped
+pub struct Spool {
+    path: PathBuf,
+    /// Owner-only directory holding it, removed with it
+    dir: Option<PathBuf>,
+}
+
+impl Spool {
+    /// An empty owner-only file in the system temp directory
+    fn create() -> io::Result<(Self, File)> {
+        let path = std::env::temp_dir().join(scratch_name());
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&path)?;
+        Ok((Self { path, dir: None }, file))
+    }
+
+    /// A path, in a new owner-only directory in the system temp directory,
+    /// for a scratch file the caller writes, e.g. the archive `decrypt
+    /// --extract` decrypts before unpacking it
+    pub fn reserve() -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(scratch_name());
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+        Ok(Self { path: dir.join("spool"), dir: Some(dir) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        if let Some(dir) = &self.dir {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+fn scratch_name() -> String {
+    format!("hybridguard-spool-{}-{:016x}", std::process::id(), rand::random::<u64>())
+}
+
+/// Decrypt `source` into `output` if it is a chunked ciphertext, armored or
+/// not; otherwise spool it when `spool` allows, or refuse it
+/// The plaintext is staged and only appears at `output` once authenticated
+pub fn decrypt<R: Read>(
+    source: R,
+    output: &Path,
+    key_manager: &KeyManager,
+    spool: bool,
+    write: &WriteOptions,
+    cancel: &CancellationToken,
+) -> Result<Piped, HybridGuardError> {
+    let mut source = BufReader::new(source);
+    let head = read_head(&mut source, SNIFF_LEN)?;
+    let raw = Cursor::new(head.clone()).chain(source);
+    if encoding::detect(&head) != Encoding::Armor {
+        return finish(raw, &head, output, key_manager, spool, write, cancel);
+    }
+    let mut armor = ArmorReader::new(raw);
+    let head = read_head(&mut armor, chunked::MAGIC.len())?;
+    finish(Cursor::new(head.clone()).chain(armor), &head, output, key_manager, spool, write, cancel)
+}
+
+/// `stream` starts with `head`, already decoded from any armor
+fn finish<R: Read>(
+    mut stream: R,
+    head: &[u8],
+    output: &Path,
+    key_manager: &KeyManager,
+    spool: bool,
+    write: &WriteOptions,
+    cancel: &CancellationToken,
+) -> Result<Piped, HybridGuardError> {
+    if chunked::is_chunked(head) {
+        let mut staged = StagedFile::create(output, None, Contents::Plaintext)?.with_write_options(write.clone());
+        let stats = chunked::decrypt_stream(&mut stream, staged.file(), key_manager, cancel)?;
+        staged.commit()?;
+        return Ok(Piped::Decrypted(stats));
+    }
+    if !spool {
+        return Err(HybridGuardError::UnsupportedFormat(
+            "stdin holds a single container, which is only decrypted whole; save it to a file first or pass \
+             --spool-to-temp (chunked ciphertexts, from `encrypt --checkpoint`, stream from a pipe)"
+                .to_string(),
+        ));
+    }
+    let (spooled, mut file) = Spool::create()?;
+    io::copy(&mut stream, &mut file)?;
+    Ok(Piped::Spooled(spooled))
+}
+
+/// Up to `len` leading bytes of `source`, however few each read returns
+fn read_head<R: Read>(source: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(len);
+    source.take(len as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
@@ -0,0 +1,253 @@
+// Supervising the producer of `encrypt --source-cmd`
+// The command runs under `sh -c` in a process group of its own, with stdin
+// closed, stdout copied into a sink as it arrives, up to --max-input-bytes,
+// and stderr kept to its last few KiB. The caller commits nothing until
+// `run_into` returns, which it only does successfully once the producer has
+// exited with status 0: a non-zero exit, a signal or running past
+// --source-timeout fails the run with the status and the stderr tail, and no
+// output is written. When our side fails first (the input limit, a read
+// error, Ctrl-C, --max-duration) the whole group is sent SIGTERM, then
+// SIGKILL after a grace period, so a dump never keeps writing into a pipe
+// nobody reads.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::CancellationToken;
+use serde::Serialize;
+
+/// Input name the producer's output is planned and reported under
+pub const SOURCE_INPUT: &str = "-";
+
+/// Bytes of the producer's stderr kept for the error message
+pub const STDERR_TAIL: usize = 2048;
+
+/// How long a terminated producer has to exit before it is killed
+const GRACE: Duration = Duration::from_secs(2);
+
+/// How often the deadline and Ctrl-C are checked while waiting
+const POLL: Duration = Duration::from_millis(20);
+
+/// A producer command and how long it may run
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceCommand {
+    pub command: String,
+    /// Seconds from start to exit before the producer is terminated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+/// Why the wait for the producer ended early
+enum Stop {
+    /// The producer ran past its timeout
+    Deadline,
+    /// Our side failed; the error is the run's
+    Ours(HybridGuardError),
+}
+
+impl SourceCommand {
+    pub fn new(command: String, timeout_secs: Option<u64>) -> Self {
+        Self { command, timeout_secs }
+    }
+
+    /// Run the producer to completion, copying its standard output into
+    /// `sink` as it comes, and return how many bytes it wrote
+    /// Fails with `SourceFailed` when the producer does, and with our own
+    /// error, after terminating the producer, when reading or writing does;
+    /// whatever reached `sink` is then to be thrown away
+    pub fn run_into(&self, sink: &mut (dyn Write + Send), limit: u64, cancel: &CancellationToken) -> Result<u64, HybridGuardError> {
+        let mut child = self.spawn()?;
+        let deadline = self.timeout_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let tail = thread::spawn(move || stderr_tail(stderr));
+
+        // Terminating the producer closes its stdout, so the copy always ends
+        let finished = thread::scope(|scope| {
+            let (sender, received) = mpsc::channel();
+            scope.spawn(move || sender.send(copy_limited(stdout, sink, limit)));
+            // All of stdout first, then the exit status; both before the deadline
+            let finished = wait_for(deadline, cancel, || received.recv_timeout(POLL).ok())
+                .and_then(|copied| copied.map_err(Stop::Ours))
+                .and_then(|copied| {
+                    let status = wait_for(deadline, cancel, || child.try_wait().transpose())?;
+                    status.map(|status| (copied, status)).map_err(|e| Stop::Ours(e.into()))
+                });
+            if finished.is_err() {
+                terminate(&mut child);
+            }
+            finished
+        });
+        let status = match finished {
+            Ok((copied, status)) if status.success() => return Ok(copied),
+            Ok((_, status)) => describe(status),
+            Err(Stop::Deadline) => {
+                format!("ran longer than --source-timeout {}s and was terminated", self.timeout_secs.unwrap_or_default())
+            }
+            Err(Stop::Ours(e)) => return Err(e),
+        };
+        let tail = tail.join().unwrap_or_default();
+        Err(self.failed(status, &tail))
+    }
+
+    fn spawn(&self) -> Result<Child, HybridGuardError> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&self.command).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        // Its own group, so Ctrl-C reaches us alone and `terminate` reaches every process it started
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        command.spawn().map_err(|e| self.failed(format!("could not be started: {}", e), ""))
+    }
+
+    fn failed(&self, status: String, stderr: &str) -> HybridGuardError {
+        let stderr = stderr.trim_end();
+        if stderr.is_empty() {
+            return HybridGuardError::SourceFailed(format!("`{}` {}", self.command, status));
+        }
+        HybridGuardError::SourceFailed(format!("`{}` {}; its stderr ended with:\n{}", self.command, status, stderr))
+    }
+}
+
+/// Poll `ready` until it gives a value, the deadline passes or `cancel` stops the run
+fn wait_for<T>(deadline: Option<Instant>, cancel: &CancellationToken, mut ready: impl FnMut() -> Option<T>) -> Result<T, Stop> {
+    loop {
+        if let Some(value) = ready() {
+            return Ok(value);
+        }
+        // Ctrl-C, or a --max-duration that ran out while the producer worked
+        if let Err(e) = cancel.check() {
+            return Err(Stop::Ours(e));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Stop::Deadline);
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Copy all of `stdout` into `sink`, refused once it passes `limit`
+fn copy_limited(stdout: ChildStdout, sink: &mut (dyn Write + Send), limit: u64) -> Result<u64, HybridGuardError> {
+    let copied = io::copy(&mut stdout.take(limit.saturating_add(1)), sink)?;
+    if copied > limit {
+        return Err(HybridGuardError::LimitExceeded {
+            which: "source command output (read so far)".to_string(),
+            size: copied as usize,
+            limit: limit as usize,
+        });
+    }
+    sink.flush()?;
+    Ok(copied)
+}
+
+/// The last `STDERR_TAIL` bytes written to `stderr`, from the start of a line
+fn stderr_tail(mut stderr: ChildStderr) -> String {
+    let (mut tail, mut cut) = (Vec::new(), false);
+    let mut buffer = [0u8; 4096];
+    while let Ok(read) = stderr.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        tail.extend_from_slice(&buffer[..read]);
+        if tail.len() > STDERR_TAIL {
+            tail.drain(..tail.len() - STDERR_TAIL);
+            cut = true;
+        }
+    }
+    let tail = String::from_utf8_lossy(&tail);
+    match tail.split_once('\n') {
+        Some((_, rest)) if cut => rest.to_string(),
+        _ => tail.into_owned(),
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!("was killed by signal {}", signal);
+        }
+    }
+    match status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => "exited abnormally".to_string(),
+    }
+}
+
+/// Stop the producer and everything it started: SIGTERM to its group, then
+/// SIGKILL once the grace period is over or the producer has exited
+fn terminate(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        let group = -(child.id() as libc::pid_t);
+        // SAFETY: kill only reads its arguments; a negative pid names the producer's group
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        let until = Instant::now() + GRACE;
+        while Instant::now() < until && matches!(child.try_wait(), Ok(None)) {
+            thread::sleep(POLL);
+        }
+        // SAFETY: as above; stragglers that outlived the leader still hold its pipes
+        unsafe { libc::kill(group, libc::SIGKILL) };
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn run(command: &str, timeout_secs: Option<u64>) -> Result<Vec<u8>, HybridGuardError> {
+        let mut data = Vec::new();
+        let copied = SourceCommand::new(command.to_string(), timeout_secs).run_into(&mut data, 1 << 20, &CancellationToken::new())?;
+        assert_eq!(copied, data.len() as u64);
+        Ok(data)
+    }
+
+    #[test]
+    fn test_output_of_a_successful_producer() {
+        assert_eq!(run("printf 'COPY 1\\n'; printf 'done' >&2", None).unwrap(), b"COPY 1\n");
+    }
+
+    #[test]
+    fn test_failing_producer_reports_status_and_stderr() {
+        let error = run("printf partial; echo 'pg_dump: error: connection lost' >&2; exit 3", None).unwrap_err();
+        let message = error.to_string();
+        assert!(matches!(error, HybridGuardError::SourceFailed(_)));
+        assert!(message.contains("exited with status 3"), "{}", message);
+        assert!(message.ends_with("pg_dump: error: connection lost"), "{}", message);
+    }
+
+    #[test]
+    fn test_hanging_producer_is_terminated() {
+        let started = Instant::now();
+        let error = run("printf partial; sleep 30", Some(1)).unwrap_err();
+        assert!(error.to_string().contains("--source-timeout 1s"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_oversized_output_stops_the_producer() {
+        let started = Instant::now();
+        let source = SourceCommand::new("yes; sleep 30".to_string(), None);
+        let error = source.run_into(&mut io::sink(), 4096, &CancellationToken::new()).unwrap_err();
+        assert!(matches!(error, HybridGuardError::LimitExceeded { .. }), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_stderr_tail_starts_at_a_line() {
+        let error = run("i=0; while [ $i -lt 500 ]; do echo \"line $i\" >&2; i=$((i+1)); done; exit 1", None).unwrap_err();
+        let message = error.to_string();
+        let tail = message.split_once("ended with:\n").unwrap().1;
+        assert!(tail.len() < STDERR_TAIL && tail.starts_with("line "), "{}", tail);
+        assert!(tail.ends_with("line 499"));
+    }
+}
@@ -0,0 +1,900 @@
+// Planning phase for encrypt and decrypt
+// Every check that can fail without touching output happens here; the real
+// run executes the same plan, so `--dry-run` reports exactly what would happen
+
+use colored::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use hybridguard::archive::ArchiveOptions;
+use hybridguard::crypto::metadata::{Metadata, MetadataMap};
+use hybridguard::crypto::secret::SecretBytes;
+use hybridguard::crypto::{container, encoding, sniff, EncryptedData};
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::error::HybridGuardError;
+use hybridguard::pathname;
+use hybridguard::policy::LabelPolicy;
+use hybridguard::sandbox::SandboxReport;
+use hybridguard::sparse;
+use hybridguard::storage::erasure::{self, Redundancy};
+use hybridguard::streaming::chunked;
+use hybridguard::streaming::shaping::{self, ShapingPolicy};
+use hybridguard::streaming::split::{self, SplitSet};
+use hybridguard::KeyManager;
+
+use crate::cli::credentials::{self, KeysOrigin};
+use crate::cli::keys::KeyFiles;
+use crate::cli::pipeline::SourceCommand;
+use crate::cli::preflight::{self, FsProbe, InputKind, PreflightReport};
+use crate::cli::resource::ResourceReport;
+
+/// Extension appended to encrypted outputs when only a directory is given
+pub const ENCRYPTED_EXTENSION: &str = "hg";
+
+/// Password the CLI falls back to when no key file is given
+const DEFAULT_PASSWORD: &str = "default-password";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Encrypt,
+    Decrypt,
+}
+
+/// Where the keys for a run come from
+pub enum KeySource {
+    /// Load from a key file
+    File(PathBuf, KeyFiles),
+    /// Load from the key file systemd passed as a credential
+    Credential(PathBuf, KeyFiles),
+    /// Parse a key file read from an inherited descriptor, read once up front
+    Inherited(i32, SecretBytes, KeyFiles),
+    /// Fresh keys from the default password (the historical CLI behaviour)
+    Default,
+}
+
+impl KeySource {
+    /// The keys `origin` names; a descriptor is read to its end here
+    pub fn new(origin: KeysOrigin, key_files: KeyFiles) -> Result<Self, HybridGuardError> {
+        Ok(match origin {
+            KeysOrigin::File(path) => KeySource::File(path, key_files),
+            KeysOrigin::Fd(fd) => KeySource::Inherited(fd, credentials::read_fd("--key-file-fd", fd)?, key_files),
+            KeysOrigin::Credential(path) => KeySource::Credential(path, key_files),
+            KeysOrigin::DefaultPassword => KeySource::Default,
+        })
+    }
+
+    pub fn resolve(&self) -> Result<KeyManager, HybridGuardError> {
+        match self {
+            KeySource::File(path, key_files) | KeySource::Credential(path, key_files) => key_files.load(path),
+            KeySource::Inherited(_, bytes, key_files) => key_files.parse(bytes),
+            KeySource::Default => KeyManager::generate(DEFAULT_PASSWORD),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            KeySource::File(path, _) => path.display().to_string(),
+            KeySource::Credential(path, _) => KeysOrigin::Credential(path.clone()).to_string(),
+            KeySource::Inherited(fd, ..) => KeysOrigin::Fd(*fd).to_string(),
+            KeySource::Default => KeysOrigin::DefaultPassword.to_string(),
+        }
+    }
+}
+
+/// How encrypted outputs are written (encrypt only)
+#[derive(Default)]
+pub struct EncryptOptions {
+    pub redundancy: Option<Redundancy>,
+    pub sparse: bool,
+    pub checkpoint: Option<CheckpointPlan>,
+    pub shape: Option<ShapingPolicy>,
+    pub label: Option<String>,
+    pub tag_content_type: bool,
+    /// Application metadata recorded in every output, public and sealed
+    pub metadata: MetadataMap,
+    pub private_metadata: MetadataMap,
+    /// Accept FIFOs and character devices as inputs
+    pub allow_special: bool,
+    /// Refuse inputs longer than this; required for FIFOs and devices
+    pub max_input_bytes: Option<u64>,
+    /// Write chunked output as parts of at most this many bytes and a manifest
+    pub split_size: Option<u64>,
+    /// Encrypt what this command writes to stdout instead of the inputs
+    pub source: Option<SourceCommand>,
+    /// Days after generation past which the keys raise warning HG002
+    pub key_lifetime: Option<u64>,
+    /// How directory inputs are archived before they are encrypted
+    pub archive: ArchiveOptions,
+}
+
+/// Resumable chunked output
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckpointPlan {
+    #[serde(serialize_with = "pathname::serialize")]
+    pub path: PathBuf,
+    /// Streaming chunks per segment; a checkpoint is written after each segment
+    pub every: u64,
+    /// Whether the checkpoint already exists, so the run picks up where it stopped
+    pub resume: bool,
+}
+
+impl CheckpointPlan {
+    pub fn new(path: PathBuf, every: u64) -> Self {
+        let resume = path.exists();
+        Self { path, every, resume }
+    }
+}
+
+/// A reason a file cannot be processed
+pub struct Problem {
+    error: HybridGuardError,
+}
+
+impl Problem {
+    pub fn new(error: HybridGuardError) -> Self {
+        Self { error }
+    }
+
+    pub fn error(&self) -> &HybridGuardError {
+        &self.error
+    }
+}
+
+impl Serialize for Problem {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("Problem", 2)?;
+        state.serialize_field("message", &self.error.to_string())?;
+        state.serialize_field("exit_code", &self.error.code())?;
+        state.end()
+    }
+}
+
+/// What will happen to one input file
+#[derive(Serialize)]
+pub struct FilePlan {
+    #[serde(serialize_with = "pathname::serialize")]
+    pub input: PathBuf,
+    #[serde(serialize_with = "pathname::serialize")]
+    pub output: PathBuf,
+    pub input_size: Option<u64>,
+    /// Exact encrypted file size (encrypt only)
+    pub estimated_output_size: Option<u64>,
+    /// Key ID recorded in the ciphertext header (decrypt only)
+    pub header_key_id: Option<String>,
+    /// Whether the header key ID matches the loaded keys, when both are known
+    pub key_matches: Option<bool>,
+    /// Shards that failed their checksum and were rebuilt (decrypt only)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub damaged_shards: Vec<usize>,
+    /// Whether the input is a hole-preserving sparse ciphertext (decrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sparse: bool,
+    /// Whether the input is a segmented chunked ciphertext (decrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub chunked: bool,
+    /// Whether the input is a constant-rate shaped stream (decrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub shaped: bool,
+    /// Whether the input is a split set's manifest or one of its parts (decrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub split: bool,
+    /// Whether the input is a FIFO or character device, read once and
+    /// encrypted as a chunked ciphertext (encrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    /// Whether the input is a directory, archived as it is read and
+    /// encrypted as a chunked ciphertext (encrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub archive: bool,
+    /// Policy label recorded in the container (decrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Content-type tag an older build left in the clear (decrypt only);
+    /// newer ones seal it, and it is only read once the file decrypts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Label policy requirements the file fails that `--override-policy` lets pass
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overridden: Vec<String>,
+    /// Blocking problems; any one of them stops the whole run
+    pub problems: Vec<Problem>,
+    /// Whether the input parsed as a single container; it is read again
+    /// when it runs, so a batch holds one container in memory at a time
+    #[serde(skip)]
+    pub container: bool,
+}
+
+impl FilePlan {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn block(&mut self, error: HybridGuardError) {
+        self.problems.push(Problem { error });
+    }
+
+    /// Read and parse the input container again (decrypt only)
+    pub fn load(&self) -> Result<EncryptedData, HybridGuardError> {
+        read_container(&self.input).map(|read| read.encrypted)
+    }
+}
+
+/// Everything a run will do, computed without writing anything
+#[derive(Serialize)]
+pub struct Plan {
+    pub operation: Operation,
+    pub keys: String,
+    pub key_id: Option<String>,
+    /// Erasure coding applied to encrypted outputs
+    pub redundancy: Option<Redundancy>,
+    /// Encrypt only data extents and record holes (encrypt only)
+    pub sparse: bool,
+    /// Write resumable chunked output (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<CheckpointPlan>,
+    /// Emit one fixed-size frame per interval (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shape: Option<ShapingPolicy>,
+    /// Policy label recorded in every output (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Record each plaintext's sniffed content type (encrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub tag_content_type: bool,
+    /// Application metadata recorded in every output (encrypt only); not
+    /// shown, as private values must not reach a report
+    #[serde(skip)]
+    pub metadata: MetadataMap,
+    #[serde(skip)]
+    pub private_metadata: MetadataMap,
+    /// Accept FIFOs and character devices as inputs (encrypt only)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub allow_special: bool,
+    /// Longest input read (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_input_bytes: Option<u64>,
+    /// Largest part of split output (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split_size: Option<u64>,
+    /// Producer whose output is encrypted (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceCommand>,
+    /// Days after generation past which the keys raise HG002 (encrypt only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_lifetime: Option<u64>,
+    /// How directory inputs are archived (encrypt only)
+    #[serde(skip)]
+    pub archive: ArchiveOptions,
+    pub files: Vec<FilePlan>,
+    /// Problems that affect the whole run, such as unusable keys
+    pub problems: Vec<Problem>,
+    /// Permission and free space checks, unless skipped with --no-preflight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preflight: Option<PreflightReport>,
+    /// Priority and thread limits in effect, when any were asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceReport>,
+    /// Confinement `decrypt --sandbox` would apply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<SandboxReport>,
+    #[serde(skip)]
+    pub key_manager: Option<KeyManager>,
+}
+
+impl Plan {
+    /// Build the plan for `inputs`; `output` is a file for a single input,
+    /// otherwise a directory
+    pub fn build(
+        operation: Operation,
+        inputs: &[PathBuf],
+        output: &Path,
+        keys: &KeySource,
+        force: bool,
+        options: EncryptOptions,
+    ) -> Self {
+        let EncryptOptions {
+            redundancy,
+            sparse,
+            checkpoint,
+            shape,
+            label,
+            tag_content_type,
+            metadata,
+            private_metadata,
+            allow_special,
+            max_input_bytes,
+            split_size,
+            source,
+            key_lifetime,
+            archive,
+        } = options;
+        // Same size as what each output records, for the estimates
+        let stand_in = Metadata::stand_in(metadata.clone(), &private_metadata);
+        let mut plan = Plan {
+            operation,
+            keys: keys.describe(),
+            key_id: None,
+            redundancy,
+            sparse,
+            checkpoint,
+            shape,
+            label,
+            tag_content_type,
+            metadata,
+            private_metadata,
+            allow_special,
+            max_input_bytes,
+            split_size,
+            source,
+            key_lifetime,
+            archive,
+            files: Vec::new(),
+            problems: Vec::new(),
+            preflight: None,
+            resources: None,
+            sandbox: None,
+            key_manager: None,
+        };
+
+        let key_manager = match keys.resolve() {
+            Ok(km) => Some(km),
+            Err(e) => {
+                plan.problems.push(Problem::new(e));
+                None
+            }
+        };
+        plan.key_id = match keys {
+            KeySource::File(..) | KeySource::Credential(..) | KeySource::Inherited(..) => key_manager.as_ref().map(|km| km.key_id().to_string()),
+            KeySource::Default => None,
+        };
+
+        if plan.checkpoint.is_some() && inputs.len() != 1 {
+            plan.problems.push(Problem {
+                error: HybridGuardError::InvalidInput("--checkpoint takes exactly one input".to_string()),
+            });
+        }
+        let stand_in = match stand_in {
+            Ok(stand_in) => stand_in,
+            Err(e) => {
+                plan.problems.push(Problem::new(e));
+                None
+            }
+        };
+        if plan.split_size.is_some() && inputs.len() != 1 {
+            plan.problems.push(Problem {
+                error: HybridGuardError::InvalidInput("--split-size takes exactly one input".to_string()),
+            });
+        }
+        // A resumed run continues the output it already started
+        let force = force || plan.checkpoint.as_ref().is_some_and(|c| c.resume);
+
+        let multiple = inputs.len() > 1 || output.is_dir();
+        if plan.source.is_some() && multiple {
+            plan.problems.push(Problem::new(HybridGuardError::InvalidInput(
+                "--source-cmd writes one file; give --output a file name".to_string(),
+            )));
+        }
+        let mut seen_outputs = HashSet::new();
+        for input in inputs {
+            let target = output_path(operation, input, output, multiple);
+            let mut file = FilePlan {
+                input: input.clone(),
+                output: target,
+                input_size: None,
+                estimated_output_size: None,
+                header_key_id: None,
+                key_matches: None,
+                damaged_shards: Vec::new(),
+                sparse: false,
+                chunked: false,
+                shaped: false,
+                split: false,
+                stream: false,
+                archive: false,
+                label: None,
+                content_type: None,
+                overridden: Vec::new(),
+                problems: Vec::new(),
+                container: false,
+            };
+
+            match operation {
+                Operation::Encrypt => {
+                    let key_id = key_manager.as_ref().map(|km| km.key_id());
+                    let checked = match &plan.source {
+                        Some(_) => Ok(InputKind::Stream("source command")),
+                        None => preflight::check_input(&file.input, allow_special, max_input_bytes),
+                    };
+                    match checked {
+                        Err(e) => file.block(e),
+                        // Read once at run time, so nothing is known about it beforehand
+                        Ok(InputKind::Stream(_)) => file.stream = true,
+                        Ok(InputKind::Directory) => match directory_conflict(&plan) {
+                            Some(option) => file.block(HybridGuardError::InvalidInput(format!(
+                                "{} is a directory, which is archived and encrypted as a chunked file; {} does not apply to it",
+                                file.input.display(),
+                                option
+                            ))),
+                            None => file.archive = true,
+                        },
+                        Ok(_) => {
+                            if let Some(checkpoint) = &plan.checkpoint {
+                                plan_chunked_encrypt(&mut file, key_id, checkpoint.every)
+                            } else if let Some(split_size) = split_size {
+                                plan_split_encrypt(&mut file, key_id, split_size)
+                            } else if sparse {
+                                plan_sparse_encrypt(&mut file, key_id)
+                            } else if plan.shape.is_some() {
+                                plan_shaped_encrypt(&mut file)
+                            } else {
+                                plan_encrypt(&mut file, key_id, plan.label.as_deref(), plan.tag_content_type, stand_in.as_ref(), redundancy)
+                            }
+                        }
+                    }
+                }
+                Operation::Decrypt => plan_decrypt(&mut file, plan.key_id.as_deref()),
+            }
+            // A split set is written as numbered parts and lands with its manifest
+            let written = match split_size {
+                Some(_) => split::manifest_path(&file.output),
+                None => file.output.clone(),
+            };
+            check_output(&mut file, &written, force, &mut seen_outputs);
+            plan.files.push(file);
+        }
+
+        plan.key_manager = key_manager;
+        plan
+    }
+
+    /// Check permissions and free space for every file against `probe`
+    pub fn with_preflight(mut self, probe: &dyn FsProbe) -> Self {
+        self.preflight = Some(preflight::check(&self.files, probe));
+        self
+    }
+
+    /// Check every parsed file against a label policy (decrypt only)
+    /// Violations block their file unless `overriding`, when they are kept
+    /// for the audit log instead; only a policy with an audit log can be overridden
+    pub fn with_policy(mut self, policy: &LabelPolicy, now: u64, overriding: bool) -> Self {
+        if overriding && policy.audit_log.is_none() {
+            self.problems.push(Problem::new(HybridGuardError::InvalidInput(
+                "the label policy names no audit_log, so its violations cannot be overridden".to_string(),
+            )));
+        }
+        for file in &mut self.files {
+            if !file.container {
+                continue;
+            }
+            let violations = match file.load() {
+                Ok(encrypted) => policy.check(&encrypted, now),
+                Err(e) => {
+                    file.block(e);
+                    continue;
+                }
+            };
+            if overriding {
+                file.overridden = violations.into_iter().map(|violation| violation.requirement).collect();
+            } else {
+                for violation in violations {
+                    file.block(violation.into());
+                }
+            }
+        }
+        self
+    }
+
+    pub fn with_resources(mut self, resources: Option<ResourceReport>) -> Self {
+        self.resources = resources;
+        self
+    }
+
+    pub fn with_sandbox(mut self, sandbox: SandboxReport) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Whether any problem would stop the run
+    pub fn is_blocked(&self) -> bool {
+        !self.problems.is_empty()
+            || self.files.iter().any(|f| !f.is_ok())
+            || self.preflight.as_ref().is_some_and(|p| !p.is_ok())
+    }
+
+    /// The first blocking problem, turned into the run's error
+    pub fn into_error(self) -> Option<HybridGuardError> {
+        let preflight = self.preflight.map(|p| p.problems).unwrap_or_default();
+        self.problems
+            .into_iter()
+            .chain(self.files.into_iter().flat_map(|f| f.problems))
+            .chain(preflight)
+            .map(|p| p.error)
+            .next()
+    }
+
+    pub fn print(&self) {
+        println!("{}", format!("📋 Dry run: {:?} plan", self.operation).bold());
+        println!("   Keys: {}", self.keys);
+        if let Some(key_id) = &self.key_id {
+            println!("   Key ID: {}", key_id);
+        }
+        if let Some(redundancy) = self.redundancy {
+            println!("   Redundancy: {} shards", redundancy);
+        }
+        if self.sparse {
+            println!("   Sparse: holes are recorded, not encrypted");
+        }
+        if let Some(shape) = &self.shape {
+            println!("   Shape: one {} byte frame every {:?}; the output size depends on how long the run takes", shape.chunk_size, shape.interval);
+        }
+        if let Some(label) = &self.label {
+            println!("   Label: {}", label);
+        }
+        if self.tag_content_type {
+            println!("   Content type: sniffed and recorded in each output");
+        }
+        if let Some(limit) = self.max_input_bytes {
+            println!("   Input limit: {}", preflight::human(limit));
+        }
+        if self.allow_special {
+            println!("   Special inputs: FIFOs and character devices are read once, up to the limit, spooled and encrypted chunked");
+        }
+        if let Some(source) = &self.source {
+            let timeout = source.timeout_secs.map(|secs| format!(", terminated after {}s", secs)).unwrap_or_default();
+            println!("   Source: `{}` run with sh -c, its output spooled up to the limit and encrypted chunked{}", source.command, timeout);
+        }
+        if let Some(split_size) = self.split_size {
+            println!("   Split: parts of at most {} plus an authenticated manifest", preflight::human(split_size));
+        }
+        if let Some(days) = self.key_lifetime {
+            println!("   Key lifetime: {} days; older keys raise warning HG002", days);
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            let action = if checkpoint.resume { "resume from" } else { "write" };
+            println!("   Checkpoint: {} {} every {} chunk(s)", action, checkpoint.path.display(), checkpoint.every);
+        }
+        for problem in &self.problems {
+            println!("   {} {}", "✗".red(), problem.error);
+        }
+        if let Some(preflight) = &self.preflight {
+            preflight.print();
+        }
+        if let Some(resources) = &self.resources {
+            resources.print();
+        }
+        if let Some(sandbox) = &self.sandbox {
+            let filter = if sandbox.sandbox { "seccomp filter" } else { "none available" };
+            println!("   Sandbox: {}, strict limits", filter);
+            for reason in &sandbox.skipped {
+                println!("     Not applied: {}", reason);
+            }
+        }
+        println!();
+
+        for file in &self.files {
+            let mark = if file.is_ok() { "✓".green() } else { "✗".red() };
+            println!("{} {} → {}", mark, file.input.display(), file.output.display());
+            if let Some(size) = file.input_size {
+                println!("     Input: {} bytes", size);
+            }
+            if let Some(size) = file.estimated_output_size {
+                println!("     Output: {} bytes", size);
+            }
+            if let Some(key_id) = &file.header_key_id {
+                let status = match file.key_matches {
+                    Some(true) => "matches".green(),
+                    Some(false) => "does not match".red(),
+                    None => "not checked".normal(),
+                };
+                println!("     Key ID: {} ({})", key_id, status);
+            }
+            if let Some(label) = &file.label {
+                println!("     Label: {}", label);
+            }
+            if let Some(content_type) = &file.content_type {
+                println!("     Content type: {}", content_type);
+            }
+            for requirement in &file.overridden {
+                println!("     {}", format!("Policy overridden: needs {}", requirement).yellow());
+            }
+            if !file.damaged_shards.is_empty() {
+                println!(
+                    "     {}",
+                    format!("Damaged shards rebuilt: {}", erasure::list(&file.damaged_shards)).yellow()
+                );
+            }
+            for problem in &file.problems {
+                println!("     {}", problem.error.to_string().red());
+            }
+        }
+
+        let blocked = self.files.iter().filter(|f| !f.is_ok()).count();
+        println!();
+        println!("{} file(s), {} blocked. Nothing was written.", self.files.len(), blocked);
+    }
+
+    pub fn to_json(&self) -> Result<String, HybridGuardError> {
+        serde_json::to_string_pretty(self).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))
+    }
+}
+
+/// Output path for one input
+/// The first option given that a directory input, archived and encrypted
+/// as a chunked file, cannot take
+fn directory_conflict(plan: &Plan) -> Option<&'static str> {
+    [
+        (plan.redundancy.is_some(), "--redundancy"),
+        (plan.sparse, "--sparse"),
+        (plan.checkpoint.is_some(), "--checkpoint"),
+        (plan.shape.is_some(), "--shape"),
+        (plan.split_size.is_some(), "--split-size"),
+        (plan.label.is_some(), "--label"),
+        (plan.tag_content_type, "--tag-content-type"),
+        (!plan.metadata.is_empty() || !plan.private_metadata.is_empty(), "--meta"),
+    ]
+    .into_iter()
+    .find_map(|(given, option)| given.then_some(option))
+}
+
+fn output_path(operation: Operation, input: &Path, output: &Path, into_directory: bool) -> PathBuf {
+    if !into_directory {
+        return output.to_path_buf();
+    }
+    let name = input.file_name().map(PathBuf::from).unwrap_or_else(|| PathBuf::from("output"));
+    let name = match operation {
+        Operation::Encrypt => {
+            let mut name = name.into_os_string();
+            name.push(".");
+            name.push(ENCRYPTED_EXTENSION);
+            PathBuf::from(name)
+        }
+        Operation::Decrypt if name.extension().is_some_and(|e| e == ENCRYPTED_EXTENSION) => name.with_extension(""),
+        Operation::Decrypt => {
+            let mut name = name.into_os_string();
+            name.push(".dec");
+            PathBuf::from(name)
+        }
+    };
+    output.join(name)
+}
+
+fn plan_encrypt(
+    file: &mut FilePlan,
+    key_id: Option<&str>,
+    label: Option<&str>,
+    tag_content_type: bool,
+    metadata: Option<&Metadata>,
+    redundancy: Option<Redundancy>,
+) {
+    let size = match fs::metadata(&file.input) {
+        Ok(meta) => meta.len(),
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+    file.input_size = Some(size);
+    // The tag is sniffed from the head alone, so the estimate stays exact
+    let content_type = match tag_content_type.then(|| read_head(&file.input, sniff::CONTENT_SNIFF_LEN)).transpose() {
+        Ok(head) => head.map(|head| sniff::content_type(&head)),
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+
+    let encryptor = HybridGuardEncryptor::new();
+    let estimate = encryptor.estimate_output_size(size as usize).and_then(|ct_len| {
+        let container_len = container::encoded_len(ct_len, key_id, label, content_type.map(|t| t.name()), metadata)?;
+        Ok(match redundancy {
+            Some(redundancy) => erasure::encoded_len(container_len, redundancy),
+            None => container_len,
+        } as u64)
+    });
+    match estimate {
+        Ok(estimate) => file.estimated_output_size = Some(estimate),
+        Err(e) => file.block(e),
+    }
+}
+
+fn plan_sparse_encrypt(file: &mut FilePlan, key_id: Option<&str>) {
+    match fs::metadata(&file.input) {
+        Ok(meta) => file.input_size = Some(meta.len()),
+        Err(e) => return file.block(read_error(&file.input, e)),
+    }
+    match sparse::estimate_output_size(&file.input, key_id.unwrap_or_default()) {
+        Ok(estimate) => file.estimated_output_size = Some(estimate),
+        Err(e) => file.block(e),
+    }
+}
+
+/// Shaped output grows with the run's duration, so it has no size estimate
+fn plan_shaped_encrypt(file: &mut FilePlan) {
+    match fs::metadata(&file.input) {
+        Ok(meta) => file.input_size = Some(meta.len()),
+        Err(e) => file.block(read_error(&file.input, e)),
+    }
+}
+
+fn plan_chunked_encrypt(file: &mut FilePlan, key_id: Option<&str>, every: u64) {
+    let size = match fs::metadata(&file.input) {
+        Ok(meta) => meta.len(),
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+    file.input_size = Some(size);
+    match chunked::estimate_output_size(size, every, key_id.unwrap_or_default()) {
+        Ok(estimate) => file.estimated_output_size = Some(estimate),
+        Err(e) => file.block(e),
+    }
+}
+
+fn plan_split_encrypt(file: &mut FilePlan, key_id: Option<&str>, split_size: u64) {
+    let size = match fs::metadata(&file.input) {
+        Ok(meta) => meta.len(),
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+    file.input_size = Some(size);
+    match split::estimate_output_size(size, split_size, key_id.unwrap_or_default()) {
+        Ok(estimate) => file.estimated_output_size = Some(estimate),
+        Err(e) => file.block(e),
+    }
+}
+
+fn plan_decrypt(file: &mut FilePlan, key_id: Option<&str>) {
+    if split::is_manifest_path(&file.input) {
+        return plan_split_decrypt(file, key_id);
+    }
+    // Sparse, chunked and shaped ciphertexts are streamed at decrypt time; only their header is read here
+    match read_magic(&file.input) {
+        Ok(magic) if split::is_part(&magic) => return plan_split_decrypt(file, key_id),
+        Ok(magic) if sparse::is_sparse(&magic) => return plan_sparse_decrypt(file, key_id),
+        Ok(magic) if chunked::is_chunked(&magic) => return plan_chunked_decrypt(file, key_id),
+        Ok(magic) if shaping::is_shaped(&magic) => return plan_shaped_decrypt(file, key_id),
+        Ok(_) => {}
+        Err(e) => return file.block(read_error(&file.input, e)),
+    }
+
+    file.input_size = fs::metadata(&file.input).ok().map(|m| m.len());
+    let read = match read_container(&file.input) {
+        Ok(read) => read,
+        Err(e) => return file.block(e),
+    };
+    // Damaged shards are reported, not fatal
+    file.damaged_shards = read.damaged_shards;
+    check_key(file, key_id, read.encrypted.key_id().map(str::to_string));
+    file.label = read.encrypted.label().map(str::to_string);
+    file.content_type = read.encrypted.clear_content_type().map(str::to_string);
+    file.container = true;
+}
+
+/// A container read from disk, with the shards rebuilt to read it
+struct ReadContainer {
+    encrypted: EncryptedData,
+    damaged_shards: Vec<usize>,
+}
+
+/// Read and parse the container at `path`, rebuilding sharded ones first
+fn read_container(path: &Path) -> Result<ReadContainer, HybridGuardError> {
+    let mut bytes = fs::read(path).map_err(|e| read_error(path, e))?;
+    let mut damaged_shards = Vec::new();
+    if erasure::is_sharded(&bytes) {
+        let recovered = erasure::decode(&bytes)?;
+        damaged_shards = recovered.damaged;
+        bytes = recovered.payload;
+    }
+
+    // Reject files that are clearly not HybridGuard before deserializing
+    if let Some(hint) = sniff::identify(&bytes).rejection_hint() {
+        return Err(HybridGuardError::UnsupportedFormat(format!("{}: {}", path.display(), hint)));
+    }
+    Ok(ReadContainer { encrypted: encoding::decode(&bytes)?, damaged_shards })
+}
+
+fn plan_sparse_decrypt(file: &mut FilePlan, key_id: Option<&str>) {
+    file.sparse = true;
+    let source = match File::open(&file.input) {
+        Ok(source) => source,
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+    file.input_size = source.metadata().ok().map(|m| m.len());
+    match sparse::read_header(&mut io::BufReader::new(source)) {
+        Ok(header) => check_key(file, key_id, Some(header.key_id)),
+        Err(e) => file.block(e),
+    }
+}
+
+fn plan_chunked_decrypt(file: &mut FilePlan, key_id: Option<&str>) {
+    file.chunked = true;
+    let source = match File::open(&file.input) {
+        Ok(source) => source,
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+    file.input_size = source.metadata().ok().map(|m| m.len());
+    match chunked::read_header(&mut io::BufReader::new(source)) {
+        Ok(header) => check_key(file, key_id, Some(header.key_id)),
+        Err(e) => file.block(e),
+    }
+}
+
+/// Every part must be there, in place and of the recorded size; their
+/// contents and the manifest tag are checked when the run starts
+fn plan_split_decrypt(file: &mut FilePlan, key_id: Option<&str>) {
+    file.split = true;
+    let set = match SplitSet::open(&file.input) {
+        Ok(set) => set,
+        Err(e) => return file.block(e),
+    };
+    file.input_size = Some(set.manifest().parts.iter().map(|part| part.size).sum());
+    check_key(file, key_id, Some(set.manifest().key_id.clone()));
+    match set.survey() {
+        Ok(issues) if issues.is_empty() => {}
+        Ok(issues) => file.block(set.incomplete(&issues)),
+        Err(e) => file.block(e),
+    }
+}
+
+fn plan_shaped_decrypt(file: &mut FilePlan, key_id: Option<&str>) {
+    file.shaped = true;
+    let source = match File::open(&file.input) {
+        Ok(source) => source,
+        Err(e) => return file.block(read_error(&file.input, e)),
+    };
+    file.input_size = source.metadata().ok().map(|m| m.len());
+    match shaping::read_header(io::BufReader::new(source)) {
+        Ok(header) => check_key(file, key_id, Some(header.key_id)),
+        Err(e) => file.block(e),
+    }
+}
+
+fn read_magic(path: &Path) -> io::Result<Vec<u8>> {
+    let mut magic = [0u8; 4];
+    let n = File::open(path)?.read(&mut magic)?;
+    Ok(magic[..n].to_vec())
+}
+
+/// Record the header key ID and block the file when it differs from the loaded keys
+fn check_key(file: &mut FilePlan, key_id: Option<&str>, found: Option<String>) {
+    if let (Some(expected), Some(found)) = (key_id, found.as_deref()) {
+        file.key_matches = Some(expected == found);
+        if expected != found {
+            file.block(HybridGuardError::KeyMismatch(format!(
+                "{} was encrypted with key {} but key {} is loaded",
+                file.input.display(),
+                found,
+                expected
+            )));
+        }
+    }
+    file.header_key_id = found;
+}
+
+/// `written` is the file that marks the output complete: the output itself,
+/// or a split set's manifest
+fn check_output(file: &mut FilePlan, written: &Path, force: bool, seen: &mut HashSet<PathBuf>) {
+    if file.output == file.input {
+        file.block(HybridGuardError::InvalidInput(format!(
+            "{}: output would overwrite the input",
+            file.input.display()
+        )));
+    } else if !seen.insert(file.output.clone()) {
+        file.block(HybridGuardError::InvalidInput(format!(
+            "{}: another input already writes to this output",
+            file.output.display()
+        )));
+    } else if written.exists() && !force {
+        file.block(HybridGuardError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists (pass --force to overwrite)", written.display()),
+        )));
+    }
+}
+
+/// Up to `len` leading bytes of `path`
+fn read_head(path: &Path, len: usize) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(len);
+    File::open(path)?.take(len as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+fn read_error(path: &Path, e: io::Error) -> HybridGuardError {
+    HybridGuardError::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
@@ -0,0 +1,477 @@
+// Pre-flight checks before a run starts
+// Catches unreadable inputs, unwritable output directories and filesystems
+// too small for the estimated output, so a long encryption does not die at
+// the very end with "No space left on device". Encrypt inputs are classified
+// from their metadata first: FIFOs and character devices such as /dev/zero
+// never report a size and may never end, so they need `--allow-special` and a
+// `--max-input-bytes` cap; sockets and block devices are refused outright.
+
+use colored::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::{fsutil, pathname};
+
+use crate::cli::plan::{FilePlan, Problem};
+
+/// Path that stands for stdin or stdout
+const STDIO: &str = "-";
+
+/// What an input is, judged from its metadata without opening it
+/// (opening a FIFO blocks until something writes to it)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    Regular,
+    /// Archived as it is read, with no size known up front
+    Directory,
+    /// A FIFO or character device: read once, with no size known up front
+    Stream(&'static str),
+    /// A socket, block device or anything else that is not encrypted
+    Refused(&'static str),
+}
+
+impl InputKind {
+    #[cfg(unix)]
+    fn of(file_type: fs::FileType) -> Self {
+        use std::os::unix::fs::FileTypeExt;
+
+        if file_type.is_file() {
+            InputKind::Regular
+        } else if file_type.is_dir() {
+            InputKind::Directory
+        } else if file_type.is_fifo() {
+            InputKind::Stream("FIFO")
+        } else if file_type.is_char_device() {
+            InputKind::Stream("character device")
+        } else if file_type.is_socket() {
+            InputKind::Refused("socket")
+        } else if file_type.is_block_device() {
+            InputKind::Refused("block device")
+        } else {
+            InputKind::Refused("special file")
+        }
+    }
+
+    /// Without file type bits, anything but a file or directory is read as a stream
+    #[cfg(not(unix))]
+    fn of(file_type: fs::FileType) -> Self {
+        if file_type.is_file() {
+            InputKind::Regular
+        } else if file_type.is_dir() {
+            InputKind::Directory
+        } else {
+            InputKind::Stream("special file")
+        }
+    }
+}
+
+/// Classify `path`, following symlinks
+pub fn input_kind(path: &Path) -> io::Result<InputKind> {
+    Ok(InputKind::of(fs::metadata(path)?.file_type()))
+}
+
+/// Check that `path` can be encrypted: regular files within `max_input_bytes`,
+/// directories, and FIFOs or character devices only when `allow_special`
+pub fn check_input(path: &Path, allow_special: bool, max_input_bytes: Option<u64>) -> Result<InputKind, HybridGuardError> {
+    let meta = fs::metadata(path)
+        .map_err(|e| HybridGuardError::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))))?;
+    let kind = InputKind::of(meta.file_type());
+    match kind {
+        InputKind::Regular => match max_input_bytes {
+            Some(limit) if meta.len() > limit => Err(HybridGuardError::LimitExceeded {
+                which: format!("input {}", path.display()),
+                size: meta.len() as usize,
+                limit: limit as usize,
+            }),
+            _ => Ok(kind),
+        },
+        InputKind::Directory => Ok(kind),
+        InputKind::Stream(what) if !allow_special => Err(HybridGuardError::InvalidInput(format!(
+            "{} is a {}, which has no size and may never end; pass --allow-special --max-input-bytes N \
+             to encrypt at most N bytes read from it",
+            path.display(),
+            what
+        ))),
+        InputKind::Stream(_) => Ok(kind),
+        InputKind::Refused(what) => Err(HybridGuardError::InvalidInput(format!(
+            "{} is a {}; only regular files, and FIFOs or character devices with --allow-special, can be encrypted",
+            path.display(),
+            what
+        ))),
+    }
+}
+
+/// Free space and identity of the filesystem holding a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    /// Distinguishes filesystems, so outputs sharing one are added up
+    pub id: u64,
+    /// Bytes an unprivileged user may still write
+    pub available: u64,
+}
+
+/// Filesystem queries the checks rely on; tests substitute a fake
+pub trait FsProbe {
+    /// The filesystem holding `dir` (statvfs on Unix)
+    fn volume(&self, dir: &Path) -> io::Result<Volume>;
+
+    /// Fail unless a new file can be created in `dir`
+    fn check_writable(&self, dir: &Path) -> io::Result<()>;
+
+    /// Fail unless `path` can be opened for reading
+    fn check_readable(&self, path: &Path) -> io::Result<()>;
+}
+
+/// Probe backed by the real filesystem
+pub struct SystemProbe;
+
+impl FsProbe for SystemProbe {
+    #[cfg(unix)]
+    fn volume(&self, dir: &Path) -> io::Result<Volume> {
+        use std::os::unix::fs::MetadataExt;
+
+        Ok(Volume {
+            id: fs::metadata(dir)?.dev(),
+            available: fsutil::available_bytes(dir)?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn volume(&self, dir: &Path) -> io::Result<Volume> {
+        Ok(Volume { id: 0, available: fsutil::available_bytes(dir)? })
+    }
+
+    fn check_writable(&self, dir: &Path) -> io::Result<()> {
+        let probe = dir.join(format!(".hybridguard-preflight-{}", std::process::id()));
+        OpenOptions::new().write(true).create_new(true).open(&probe)?;
+        fs::remove_file(&probe)
+    }
+
+    fn check_readable(&self, path: &Path) -> io::Result<()> {
+        fs::File::open(path).map(drop)
+    }
+}
+
+/// Space needed and free on one output filesystem
+#[derive(Debug, Serialize)]
+pub struct VolumeReport {
+    /// An output directory on this filesystem
+    #[serde(serialize_with = "pathname::serialize")]
+    pub path: PathBuf,
+    pub required_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Outcome of the pre-flight checks
+#[derive(Serialize)]
+pub struct PreflightReport {
+    pub volumes: Vec<VolumeReport>,
+    /// Why the size check did not cover some outputs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+    pub problems: Vec<Problem>,
+}
+
+impl PreflightReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn print(&self) {
+        println!("   Pre-flight:");
+        for volume in &self.volumes {
+            let mark = if volume.required_bytes <= volume.available_bytes { "✓".green() } else { "✗".red() };
+            println!(
+                "     {} {}: {} needed, {} free",
+                mark,
+                volume.path.display(),
+                human(volume.required_bytes),
+                human(volume.available_bytes)
+            );
+        }
+        for reason in &self.skipped {
+            println!("     {}", format!("Size not checked: {}", reason).yellow());
+        }
+        for problem in &self.problems {
+            println!("     {} {}", "✗".red(), problem.error());
+        }
+    }
+}
+
+/// Check every file of a plan against `probe`
+/// Sizes come from the plan's estimates; files without one are only checked
+/// for permissions
+pub fn check(files: &[FilePlan], probe: &dyn FsProbe) -> PreflightReport {
+    let mut report = PreflightReport {
+        volumes: Vec::new(),
+        skipped: Vec::new(),
+        problems: Vec::new(),
+    };
+    let mut required: BTreeMap<u64, (PathBuf, u64, u64)> = BTreeMap::new();
+    let mut checked_dirs = Vec::new();
+
+    for file in files {
+        // Opening a FIFO would wait for a writer; the run itself opens those
+        let stream = matches!(input_kind(&file.input), Ok(InputKind::Stream(_)));
+        if file.input != Path::new(STDIO) && !stream {
+            if let Err(e) = probe.check_readable(&file.input) {
+                report.problems.push(Problem::new(HybridGuardError::Io(io::Error::new(
+                    e.kind(),
+                    format!("cannot read {}: {}", file.input.display(), e),
+                ))));
+            }
+        }
+
+        if file.output == Path::new(STDIO) {
+            report.skipped.push("output goes to stdout".to_string());
+            continue;
+        }
+        // Devices and pipes are written in place and have no size to check
+        let existing = fs::metadata(&file.output).ok();
+        if existing.as_ref().is_some_and(|meta| !meta.is_file()) {
+            report.skipped.push(format!("{} is not a regular file", file.output.display()));
+            continue;
+        }
+
+        let dir = output_dir(&file.output);
+        if !dir.is_dir() {
+            report.problems.push(Problem::new(HybridGuardError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("output directory {} does not exist", dir.display()),
+            ))));
+            continue;
+        }
+        if !checked_dirs.contains(&dir) {
+            if let Err(e) = probe.check_writable(&dir) {
+                report.problems.push(Problem::new(HybridGuardError::Io(io::Error::new(
+                    e.kind(),
+                    format!("cannot create files in {}: {} (check permissions or choose another --output)", dir.display(), e),
+                ))));
+            }
+            checked_dirs.push(dir.clone());
+        }
+
+        let Some(estimate) = file.estimated_output_size else {
+            continue;
+        };
+        match probe.volume(&dir) {
+            Ok(volume) => {
+                // Overwriting or resuming reuses the space the output already takes
+                let reused = existing.map(|meta| meta.len()).unwrap_or(0);
+                let entry = required.entry(volume.id).or_insert((dir, 0, volume.available));
+                entry.1 = entry.1.saturating_add(estimate.saturating_sub(reused));
+            }
+            Err(e) => report.skipped.push(format!("{}: {}", dir.display(), e)),
+        }
+    }
+
+    for (path, required_bytes, available_bytes) in required.into_values() {
+        if required_bytes > available_bytes {
+            report.problems.push(Problem::new(HybridGuardError::Io(io::Error::other(format!(
+                "not enough space for the output in {}: {} needed, {} free; free up {} or pass --no-preflight",
+                path.display(),
+                human(required_bytes),
+                human(available_bytes),
+                human(required_bytes - available_bytes)
+            )))));
+        }
+        report.volumes.push(VolumeReport {
+            path,
+            required_bytes,
+            available_bytes,
+        });
+    }
+    report
+}
+
+/// Directory an output file is created in
+fn output_dir(output: &Path) -> PathBuf {
+    match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Byte count with a binary-unit approximation, e.g. "3.2 GiB (3435973837 bytes)"
+pub fn human(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut value = bytes as f64;
+    let mut unit = "";
+    for name in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = name;
+    }
+    format!("{:.1} {} ({} bytes)", value, unit, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Probe with one filesystem of fixed size and a set of read-only directories
+    struct FakeProbe {
+        available: u64,
+        read_only: Vec<PathBuf>,
+    }
+
+    impl FsProbe for FakeProbe {
+        fn volume(&self, _dir: &Path) -> io::Result<Volume> {
+            Ok(Volume {
+                id: 1,
+                available: self.available,
+            })
+        }
+
+        fn check_writable(&self, dir: &Path) -> io::Result<()> {
+            if self.read_only.iter().any(|d| d == dir) {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            Ok(())
+        }
+
+        fn check_readable(&self, path: &Path) -> io::Result<()> {
+            fs::metadata(path).map(drop)
+        }
+    }
+
+    fn file(input: &Path, output: &Path, estimate: Option<u64>) -> FilePlan {
+        FilePlan {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            input_size: None,
+            estimated_output_size: estimate,
+            header_key_id: None,
+            key_matches: None,
+            damaged_shards: Vec::new(),
+            sparse: false,
+            chunked: false,
+            shaped: false,
+            split: false,
+            stream: false,
+            archive: false,
+            label: None,
+            content_type: None,
+            overridden: Vec::new(),
+            problems: Vec::new(),
+            container: false,
+        }
+    }
+
+    fn messages(report: &PreflightReport) -> Vec<String> {
+        report.problems.iter().map(|p| p.error().to_string()).collect()
+    }
+
+    #[test]
+    fn test_enough_space_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.bin");
+        fs::write(&input, b"data").unwrap();
+        let probe = FakeProbe { available: 10_000, read_only: Vec::new() };
+
+        let report = check(&[file(&input, &dir.path().join("out.hg"), Some(6_000))], &probe);
+        assert!(report.is_ok(), "{:?}", messages(&report));
+        assert_eq!(report.volumes[0].required_bytes, 6_000);
+    }
+
+    #[test]
+    fn test_small_filesystem_fails_fast() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.bin");
+        fs::write(&input, b"data").unwrap();
+        let probe = FakeProbe { available: 10_000, read_only: Vec::new() };
+
+        // Each output fits on its own, together they do not
+        let files = [
+            file(&input, &dir.path().join("a.hg"), Some(6_000)),
+            file(&input, &dir.path().join("b.hg"), Some(6_000)),
+        ];
+        let report = check(&files, &probe);
+        assert_eq!(report.volumes.len(), 1);
+        assert_eq!(report.volumes[0].required_bytes, 12_000);
+        let messages = messages(&report);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("not enough space"), "{}", messages[0]);
+        assert!(messages[0].contains("2000 bytes"), "{}", messages[0]);
+    }
+
+    #[test]
+    fn test_existing_output_space_is_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.bin");
+        let output = dir.path().join("out.hg");
+        fs::write(&input, b"data").unwrap();
+        fs::write(&output, vec![0u8; 5_000]).unwrap();
+        let probe = FakeProbe { available: 2_000, read_only: Vec::new() };
+
+        let report = check(&[file(&input, &output, Some(6_000))], &probe);
+        assert!(report.is_ok(), "{:?}", messages(&report));
+        assert_eq!(report.volumes[0].required_bytes, 1_000);
+    }
+
+    #[test]
+    fn test_unwritable_directory_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.bin");
+        fs::write(&input, b"data").unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        let probe = FakeProbe { available: u64::MAX, read_only: vec![locked.clone()] };
+
+        let report = check(&[file(&input, &locked.join("out.hg"), Some(10))], &probe);
+        let messages = messages(&report);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("cannot create files in"), "{}", messages[0]);
+        assert_eq!(report.problems[0].error().code(), hybridguard::error::exit_code::IO);
+    }
+
+    #[test]
+    fn test_missing_input_and_directory_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let probe = FakeProbe { available: u64::MAX, read_only: Vec::new() };
+
+        let report = check(&[file(&dir.path().join("gone.bin"), &dir.path().join("nope").join("out.hg"), Some(10))], &probe);
+        let messages = messages(&report);
+        assert!(messages[0].contains("cannot read"), "{}", messages[0]);
+        assert!(messages[1].contains("does not exist"), "{}", messages[1]);
+    }
+
+    #[test]
+    fn test_stdio_skips_size_checks() {
+        let probe = FakeProbe { available: 0, read_only: Vec::new() };
+
+        let report = check(&[file(Path::new(STDIO), Path::new(STDIO), Some(10))], &probe);
+        assert!(report.is_ok(), "{:?}", messages(&report));
+        assert!(report.volumes.is_empty());
+        assert_eq!(report.skipped.len(), 1);
+    }
+
+    #[test]
+    fn test_inputs_are_classified_before_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.bin");
+        fs::write(&input, b"data").unwrap();
+        assert_eq!(check_input(&input, false, None).unwrap(), InputKind::Regular);
+        assert!(matches!(check_input(&input, false, Some(3)), Err(HybridGuardError::LimitExceeded { size: 4, limit: 3, .. })));
+
+        assert_eq!(check_input(dir.path(), false, None).unwrap(), InputKind::Directory);
+        assert!(check_input(&dir.path().join("missing"), false, None).is_err());
+
+        #[cfg(unix)]
+        {
+            let null = Path::new("/dev/null");
+            assert_eq!(input_kind(null).unwrap(), InputKind::Stream("character device"));
+            assert!(check_input(null, false, None).unwrap_err().to_string().contains("--allow-special"));
+            assert_eq!(check_input(null, true, Some(1)).unwrap(), InputKind::Stream("character device"));
+        }
+    }
+}
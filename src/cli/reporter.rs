@@ -0,0 +1,206 @@
+// Leveled progress output for the CLI
+// Everything printed here goes to stderr, so stdout only carries command
+// output such as JSON plans and inspect or status reports. Messages come from
+// the message catalog chosen with --lang, so they print in its language
+
+use colored::*;
+use hybridguard::budget::BudgetUsage;
+use hybridguard::message;
+use hybridguard::messages::{Arg, Catalog};
+use hybridguard::progress::{Direction, OperationState, Progress};
+use hybridguard::warning::Warning;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// How much the CLI says while it works
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Errors only
+    Quiet,
+    /// One summary line per operation, plus warnings
+    Normal,
+    /// Banner, per-file and per-layer progress
+    Verbose,
+    /// Everything, including debug-level layer logs
+    Debug,
+}
+
+impl Verbosity {
+    /// Verbosity selected by `-q` and the number of `-v` flags
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+
+    fn log_level(self) -> log::LevelFilter {
+        match self {
+            Verbosity::Quiet => log::LevelFilter::Error,
+            Verbosity::Normal => log::LevelFilter::Warn,
+            Verbosity::Verbose => log::LevelFilter::Info,
+            Verbosity::Debug => log::LevelFilter::Debug,
+        }
+    }
+}
+
+/// Routes CLI chatter to stderr according to the verbosity
+#[derive(Debug, Clone, Copy)]
+pub struct Reporter {
+    verbosity: Verbosity,
+    /// Print file progress as JSON operation states instead of text
+    json_progress: bool,
+    /// Where every message's wording comes from; set once per run
+    catalog: &'static Catalog,
+}
+
+impl Reporter {
+    pub fn new(verbosity: Verbosity) -> Self {
+        static ENGLISH: OnceLock<Catalog> = OnceLock::new();
+        Self { verbosity, json_progress: false, catalog: ENGLISH.get_or_init(Catalog::english) }
+    }
+
+    pub fn with_json_progress(mut self, json_progress: bool) -> Self {
+        self.json_progress = json_progress;
+        self
+    }
+
+    pub fn with_catalog(mut self, catalog: &'static Catalog) -> Self {
+        self.catalog = catalog;
+        self
+    }
+
+    pub fn catalog(&self) -> &'static Catalog {
+        self.catalog
+    }
+
+    /// The catalog's message `id` with `args` filled in; see `hybridguard::message!`
+    pub fn text(&self, id: &str, args: &[Arg]) -> String {
+        self.catalog.text(id, args)
+    }
+
+    /// Route library logs to stderr at the level matching the verbosity
+    /// RUST_LOG still takes precedence when set
+    pub fn init_logger(&self) {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(self.verbosity.log_level().as_str()))
+            .target(env_logger::Target::Stderr)
+            .init();
+    }
+
+    pub fn banner(&self) {
+        if self.verbosity < Verbosity::Verbose {
+            return;
+        }
+        let lines = [("banner-title", 11), ("banner-tagline", 3), ("banner-vendor", 3)].map(|(id, indent)| (self.text(id, &[]), indent));
+        let width = lines.iter().map(|(line, indent)| indent + line.chars().count()).max().unwrap_or(0).max(55);
+        eprintln!("{}", format!("╔{}╗", "═".repeat(width)).cyan());
+        for (line, indent) in &lines {
+            let padding = width - indent - line.chars().count();
+            eprintln!("{}", format!("║{}{}{}║", " ".repeat(*indent), line, " ".repeat(padding)).cyan());
+        }
+        eprintln!("{}", format!("╚{}╝", "═".repeat(width)).cyan());
+        eprintln!();
+    }
+
+    /// The one line an operation prints by default
+    pub fn summary(&self, message: impl Display) {
+        if self.verbosity >= Verbosity::Normal {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// Something the user should act on, shown unless quiet
+    pub fn warn(&self, message: impl Display) {
+        if self.verbosity >= Verbosity::Normal {
+            eprintln!("{}", message!(self, "warning", message = message).yellow());
+        }
+    }
+
+    /// A warning with a code: a `{"warning": …}` line with JSON progress,
+    /// `warning[HG004]: …` otherwise, shown unless --quiet
+    pub fn warning(&self, warning: &Warning) {
+        if self.json_progress {
+            if let Ok(line) = serde_json::to_string(&serde_json::json!({ "warning": warning })) {
+                eprintln!("{}", line);
+            }
+            return;
+        }
+        if self.verbosity >= Verbosity::Normal {
+            eprintln!("{}", message!(self, "warning-coded", code = warning.code, message = warning.message).yellow());
+        }
+    }
+
+    /// A warning about a risk the user takes, shown at every level
+    pub fn caution(&self, message: impl Display) {
+        eprintln!("{}", message!(self, "warning", message = message).yellow());
+    }
+
+    /// Step-by-step detail, shown with -v
+    pub fn progress(&self, message: impl Display) {
+        if self.verbosity >= Verbosity::Verbose {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// A failure that does not end the run, shown at every level
+    pub fn error(&self, message: impl Display) {
+        eprintln!("{}", message!(self, "failure", message = message).red());
+    }
+
+    /// How much of its --max-duration and --max-output-bytes a run used: a
+    /// `{"budget": …}` line with JSON progress, shown with -v otherwise
+    pub fn budget(&self, usage: &BudgetUsage) {
+        if self.json_progress {
+            if let Ok(line) = serde_json::to_string(&serde_json::json!({ "budget": usage })) {
+                eprintln!("{}", line);
+            }
+            return;
+        }
+        self.progress(message!(self, "budget-used", elapsed = usage.elapsed_ms, bytes = usage.output_bytes));
+    }
+
+    /// Progress for one file operation from `input` to `output`
+    /// Every line the CLI prints about the operation is rendered from its states
+    pub fn file_progress(&self, input: &Path, output: &Path) -> Progress {
+        let reporter = *self;
+        let (input, output) = (input.to_path_buf(), output.to_path_buf());
+        Progress::new(move |state| reporter.render(state, &input, &output))
+    }
+
+    fn render(&self, state: &OperationState, input: &Path, output: &Path) {
+        if self.json_progress {
+            if let Ok(line) = serde_json::to_string(state) {
+                eprintln!("{}", line);
+            }
+            return;
+        }
+        match state {
+            OperationState::ResolvingKeys => self.progress(message!(self, "state-resolving-keys", input = input.display())),
+            OperationState::Reading { bytes } => self.progress(message!(self, "state-reading", bytes = bytes, input = input.display())),
+            OperationState::Layer { index, name, direction } => {
+                let id = if *direction == Direction::Encrypt { "state-layer-encrypt" } else { "state-layer-decrypt" };
+                self.progress(message!(self, id, index = index, name = name));
+            }
+            OperationState::Writing { bytes } => self.progress(message!(self, "state-writing", bytes = bytes)),
+            OperationState::Finalizing => self.progress(message!(self, "state-finalizing", output = output.display())),
+            OperationState::Done(summary) => match summary.direction {
+                Direction::Encrypt => self.summary(message!(
+                    self,
+                    "encrypt-done",
+                    input = input.display(),
+                    output = output.display(),
+                    bytes_in = summary.bytes_in,
+                    bytes_out = summary.bytes_out
+                )),
+                Direction::Decrypt => {
+                    self.summary(message!(self, "decrypt-done", input = input.display(), output = output.display(), bytes = summary.bytes_out))
+                }
+            },
+            // The error itself is reported once the command ends
+            OperationState::Failed(error) => self.progress(message!(self, "state-failed", input = input.display(), code = error.exit_code)),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+// CPU, I/O and thread limits for encrypt and decrypt (--nice, --ionice, --max-threads)
+// Backup agents lower their priority so interactive work keeps the machine.
+// A knob the platform lacks is skipped and named in the report, never fatal.
+
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+use hybridguard::error::HybridGuardError;
+
+/// Linux I/O scheduling class, as set by `ionice -c`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Normal class, at the priority matching the nice level
+    BestEffort,
+    /// Only gets disk time nobody else wants
+    Idle,
+}
+
+impl fmt::Display for IoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IoClass::BestEffort => "best-effort",
+            IoClass::Idle => "idle",
+        })
+    }
+}
+
+impl FromStr for IoClass {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "best-effort" => Ok(IoClass::BestEffort),
+            "idle" => Ok(IoClass::Idle),
+            other => Err(HybridGuardError::InvalidInput(format!(
+                "unknown I/O class '{}' (expected best-effort or idle)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Process priority controls; tests substitute a fake
+pub trait Scheduler {
+    /// Set the nice level of the whole process (setpriority on Unix)
+    fn set_nice(&self, level: i32) -> io::Result<()>;
+
+    /// Set the I/O class of the whole process (ioprio_set on Linux)
+    fn set_io_class(&self, class: IoClass, nice: i32) -> io::Result<()>;
+}
+
+/// Scheduler backed by the operating system
+pub struct SystemScheduler;
+
+impl Scheduler for SystemScheduler {
+    #[cfg(unix)]
+    fn set_nice(&self, level: i32) -> io::Result<()> {
+        // SAFETY: setpriority only reads its integer arguments
+        let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, level) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn set_nice(&self, _level: i32) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "nice levels are only set on Unix"))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_io_class(&self, class: IoClass, nice: i32) -> io::Result<()> {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_SHIFT: u32 = 13;
+        let (class, data) = match class {
+            // Nice -20..19 maps onto best-effort levels 0..7, as the kernel does by default
+            IoClass::BestEffort => (2, (nice.clamp(-20, 19) + 20) / 5),
+            IoClass::Idle => (3, 0),
+        };
+        let ioprio = ((class << IOPRIO_CLASS_SHIFT) | data) as libc::c_long;
+        // SAFETY: ioprio_set only reads its integer arguments
+        let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0 as libc::c_long, ioprio) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_io_class(&self, _class: IoClass, _nice: i32) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "I/O classes are only set on Linux"))
+    }
+}
+
+/// Limits asked for on the command line
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceLimits {
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    pub max_threads: Option<NonZeroUsize>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.io_class.is_none() && self.max_threads.is_none()
+    }
+}
+
+/// The settings in effect after `apply`
+#[derive(Debug, Serialize)]
+pub struct ResourceReport {
+    /// Nice level set for the process, if it was changed
+    pub nice: Option<i32>,
+    /// I/O class set for the process, if it was changed
+    pub io_class: Option<IoClass>,
+    /// Worker threads parallel work may use
+    pub max_threads: usize,
+    /// Requested settings that could not be applied, and why
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+}
+
+impl ResourceReport {
+    pub fn print(&self) {
+        let nice = self.nice.map_or("unchanged".to_string(), |level| level.to_string());
+        let io_class = self.io_class.map_or("unchanged".to_string(), |class| class.to_string());
+        println!("   Resources: nice {}, I/O class {}, {} worker thread(s)", nice, io_class, self.max_threads);
+        for reason in &self.skipped {
+            println!("     Not applied: {}", reason);
+        }
+    }
+}
+
+/// Apply `limits` to this process, reporting what took effect
+pub fn apply(limits: &ResourceLimits, scheduler: &dyn Scheduler) -> ResourceReport {
+    let mut report = ResourceReport {
+        nice: None,
+        io_class: None,
+        max_threads: worker_threads(limits.max_threads),
+        skipped: Vec::new(),
+    };
+
+    if let Some(level) = limits.nice {
+        match scheduler.set_nice(level) {
+            Ok(()) => report.nice = Some(level),
+            Err(e) => report.skipped.push(format!("nice {}: {}", level, e)),
+        }
+    }
+    if let Some(class) = limits.io_class {
+        match scheduler.set_io_class(class, limits.nice.unwrap_or(0)) {
+            Ok(()) => report.io_class = Some(class),
+            Err(e) => report.skipped.push(format!("I/O class {}: {}", class, e)),
+        }
+    }
+    #[cfg(feature = "parallel")]
+    if let Some(limit) = limits.max_threads {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(limit.get()).build_global() {
+            report.skipped.push(format!("max threads {}: {}", limit, e));
+        }
+    }
+    report
+}
+
+/// Threads parallel work uses under `limit`; builds without the `parallel`
+/// feature work on one thread
+fn worker_threads(limit: Option<NonZeroUsize>) -> usize {
+    if !cfg!(feature = "parallel") {
+        return 1;
+    }
+    let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    limit.map_or(cores, |limit| limit.get().min(cores))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records every call; fails `set_io_class` when `refuse_io` is set
+    #[derive(Default)]
+    struct FakeScheduler {
+        calls: RefCell<Vec<String>>,
+        refuse_io: bool,
+    }
+
+    impl Scheduler for FakeScheduler {
+        fn set_nice(&self, level: i32) -> io::Result<()> {
+            self.calls.borrow_mut().push(format!("nice {}", level));
+            Ok(())
+        }
+
+        fn set_io_class(&self, class: IoClass, nice: i32) -> io::Result<()> {
+            self.calls.borrow_mut().push(format!("ionice {} at {}", class, nice));
+            if self.refuse_io {
+                return Err(io::Error::from(io::ErrorKind::Unsupported));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_priorities_are_requested() {
+        let scheduler = FakeScheduler::default();
+        let limits = ResourceLimits { nice: Some(10), io_class: Some(IoClass::BestEffort), max_threads: None };
+        let report = apply(&limits, &scheduler);
+        assert_eq!(*scheduler.calls.borrow(), ["nice 10", "ionice best-effort at 10"]);
+        assert_eq!((report.nice, report.io_class), (Some(10), Some(IoClass::BestEffort)));
+        assert!(report.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_setting_is_skipped() {
+        let scheduler = FakeScheduler { refuse_io: true, ..FakeScheduler::default() };
+        let limits = ResourceLimits { nice: None, io_class: Some(IoClass::Idle), max_threads: None };
+        let report = apply(&limits, &scheduler);
+        assert_eq!(report.io_class, None);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(report.skipped[0].starts_with("I/O class idle"), "{:?}", report.skipped);
+
+        // Nothing asked, nothing attempted
+        let scheduler = FakeScheduler::default();
+        apply(&ResourceLimits::default(), &scheduler);
+        assert!(scheduler.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_worker_threads_respect_the_cap() {
+        assert_eq!(worker_threads(NonZeroUsize::new(1)), 1);
+        assert!(worker_threads(NonZeroUsize::new(2)) <= 2);
+        assert!(worker_threads(None) >= 1);
+    }
+
+    #[test]
+    fn test_io_class_parse() {
+        assert_eq!("idle".parse::<IoClass>().unwrap(), IoClass::Idle);
+        assert_eq!(IoClass::BestEffort.to_string().parse::<IoClass>().unwrap(), IoClass::BestEffort);
+        assert!("realtime".parse::<IoClass>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_system_scheduler_keeps_the_current_nice_level() {
+        // Setting the level the process already has needs no privilege
+        // SAFETY: getpriority only reads its integer arguments
+        let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        SystemScheduler.set_nice(current).unwrap();
+    }
+}
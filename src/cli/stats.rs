@@ -0,0 +1,306 @@
+// Opt-in local usage statistics for capacity planning
+// With --stats-file every encrypt and decrypt run adds its counts to a small
+// JSON file; nothing is sent anywhere. Writers take turns through the
+// keystore lock (see `key_manager::lock`) on `<file>.lock` and replace the
+// file by rename, so a reader never sees half an update. Statistics are best effort: an unreadable file is set
+// aside and started over, and no failure here ever fails the run itself.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use hybridguard::error::{exit_code, HybridGuardError};
+use hybridguard::key_manager::lock::{KeystoreLock, LockOptions};
+use serde::{Deserialize, Serialize};
+
+/// Layout version of the stats file
+pub const STATS_VERSION: u32 = 1;
+
+/// Counters accumulated across runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageStats {
+    pub version: u32,
+    /// Counts by command
+    pub operations: BTreeMap<String, OperationCounts>,
+    /// Failed runs by exit code
+    pub failures: BTreeMap<u8, u64>,
+    /// Input files by size: bucket 0 counts empty inputs and bucket n
+    /// sizes from 2^(n-1) to 2^n - 1 bytes
+    pub input_sizes: BTreeMap<u32, u64>,
+}
+
+/// What the runs of one command added up to
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OperationCounts {
+    /// Runs, failed ones included
+    pub runs: u64,
+    /// Files processed by successful runs
+    pub files: u64,
+    /// Bytes read from the inputs of successful runs
+    pub bytes_in: u64,
+    /// Bytes written to the outputs of successful runs
+    pub bytes_out: u64,
+}
+
+impl OperationCounts {
+    /// Output bytes per input byte
+    pub fn expansion(&self) -> Option<f64> {
+        (self.bytes_in > 0).then(|| self.bytes_out as f64 / self.bytes_in as f64)
+    }
+}
+
+impl Default for UsageStats {
+    fn default() -> Self {
+        Self { version: STATS_VERSION, operations: BTreeMap::new(), failures: BTreeMap::new(), input_sizes: BTreeMap::new() }
+    }
+}
+
+impl UsageStats {
+    /// Count a successful `kind` run over files of these (input, output) sizes
+    pub fn record_success(&mut self, kind: &str, files: &[(u64, u64)]) {
+        let counts = self.operations.entry(kind.to_string()).or_default();
+        counts.runs += 1;
+        for &(input, output) in files {
+            counts.files += 1;
+            counts.bytes_in = counts.bytes_in.saturating_add(input);
+            counts.bytes_out = counts.bytes_out.saturating_add(output);
+            *self.input_sizes.entry(size_bucket(input)).or_default() += 1;
+        }
+    }
+
+    /// Count a `kind` run that failed with exit code `code`
+    pub fn record_failure(&mut self, kind: &str, code: u8) {
+        self.operations.entry(kind.to_string()).or_default().runs += 1;
+        *self.failures.entry(code).or_default() += 1;
+    }
+}
+
+/// Histogram bucket of an input of `size` bytes
+fn size_bucket(size: u64) -> u32 {
+    u64::BITS - size.leading_zeros()
+}
+
+/// Smallest size counted in `bucket`
+fn bucket_floor(bucket: u32) -> u64 {
+    match bucket {
+        0 => 0,
+        n => 1u64 << (n - 1),
+    }
+}
+
+/// A power of two as a binary unit, e.g. "64 KiB"
+fn power_of_two(bytes: u64) -> String {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024 && unit + 1 < UNITS.len() {
+        value /= 1024;
+        unit += 1;
+    }
+    format!("{} {}", value, UNITS[unit])
+}
+
+/// Name of an exit code, as listed in `--help`
+fn code_name(code: u8) -> &'static str {
+    match code {
+        exit_code::USAGE => "usage error",
+        exit_code::KEY => "key or password",
+        exit_code::INTEGRITY => "integrity",
+        exit_code::IO => "I/O",
+        exit_code::UNSUPPORTED => "unsupported format",
+        exit_code::POLICY => "policy",
+        exit_code::BUSY => "keystore busy",
+        exit_code::BUDGET => "budget exceeded",
+        exit_code::CANCELLED => "cancelled",
+        _ => "other",
+    }
+}
+
+impl fmt::Display for UsageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operations.is_empty() {
+            return writeln!(f, "No operations recorded yet");
+        }
+        writeln!(f, "Operations:")?;
+        for (kind, counts) in &self.operations {
+            write!(
+                f,
+                "  {:<10} {} run(s), {} file(s), {} bytes in, {} bytes out",
+                kind, counts.runs, counts.files, counts.bytes_in, counts.bytes_out
+            )?;
+            match counts.expansion() {
+                Some(expansion) => writeln!(f, ", {:.4}x", expansion)?,
+                None => writeln!(f)?,
+            }
+        }
+        if !self.failures.is_empty() {
+            writeln!(f, "Failures:")?;
+            for (code, count) in &self.failures {
+                writeln!(f, "  exit {:<3} {:<18} {}", code, code_name(*code), count)?;
+            }
+        }
+        if !self.input_sizes.is_empty() {
+            writeln!(f, "Input sizes:")?;
+            for (&bucket, count) in &self.input_sizes {
+                let range = match bucket {
+                    0 => "empty".to_string(),
+                    1 => "1 B".to_string(),
+                    n => format!("{} to < {}", power_of_two(bucket_floor(n)), power_of_two(bucket_floor(n + 1))),
+                };
+                writeln!(f, "  {:<24} {}", range, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The stats file named by --stats-file
+pub struct StatsFile {
+    path: PathBuf,
+    lock: LockOptions,
+}
+
+impl StatsFile {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), lock: LockOptions::new() }
+    }
+
+    /// Wait for other writers as `options` say
+    pub fn with_lock(mut self, options: LockOptions) -> Self {
+        self.lock = options;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The counters recorded so far; a missing file has none
+    pub fn load(&self) -> Result<UsageStats, HybridGuardError> {
+        match fs::read(&self.path) {
+            Ok(bytes) => parse(&bytes).map_err(|e| {
+                HybridGuardError::UnsupportedFormat(format!("stats file {}: {}", self.path.display(), e))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UsageStats::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Apply `update` to the counters under the lock
+    /// Returns where an unreadable stats file was moved before starting over
+    pub fn update(&self, update: impl FnOnce(&mut UsageStats)) -> Result<Option<PathBuf>, HybridGuardError> {
+        let _lock = KeystoreLock::acquire(&self.sibling(".lock"), &self.lock)?;
+
+        let (mut stats, set_aside) = match fs::read(&self.path) {
+            Ok(bytes) => match parse(&bytes) {
+                Ok(stats) => (stats, None),
+                Err(_) => {
+                    let aside = self.sibling(".corrupt");
+                    fs::rename(&self.path, &aside)?;
+                    (UsageStats::default(), Some(aside))
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (UsageStats::default(), None),
+            Err(e) => return Err(e.into()),
+        };
+        update(&mut stats);
+
+        let temp = self.sibling(".tmp");
+        let json = serde_json::to_vec_pretty(&stats).map_err(io::Error::other)?;
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        Ok(set_aside)
+    }
+
+    /// The stats file's path with `suffix` appended
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    }
+}
+
+/// Stats file contents, refusing layouts this build does not write
+fn parse(bytes: &[u8]) -> Result<UsageStats, String> {
+    let stats: UsageStats = serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+    if stats.version != STATS_VERSION {
+        return Err(format!("layout version {}, this build writes {}", stats.version, STATS_VERSION));
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_size_buckets() {
+        assert_eq!(size_bucket(0), 0);
+        assert_eq!(size_bucket(1), 1);
+        assert_eq!(size_bucket(1023), 10);
+        assert_eq!(size_bucket(1024), 11);
+        assert_eq!(bucket_floor(11), 1024);
+        assert_eq!(power_of_two(bucket_floor(21)), "1 MiB");
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_all_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        StatsFile::new(&path).update(|stats| stats.record_success("encrypt", &[(100, 150)])).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let stats = StatsFile::new(&path).load().unwrap();
+        let encrypt = &stats.operations["encrypt"];
+        assert_eq!((encrypt.runs, encrypt.files), (80, 80));
+        assert_eq!((encrypt.bytes_in, encrypt.bytes_out), (8000, 12000));
+        assert_eq!(encrypt.expansion(), Some(1.5));
+        assert_eq!(stats.input_sizes[&size_bucket(100)], 80);
+        assert!(!dir.path().join("stats.json.lock").exists());
+    }
+
+    #[test]
+    fn test_corrupt_file_is_set_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        fs::write(&path, b"{\"version\": 1, \"operations\": {\"enc").unwrap();
+
+        let stats = StatsFile::new(&path);
+        assert!(stats.load().is_err());
+        let aside = stats.update(|stats| stats.record_failure("decrypt", exit_code::INTEGRITY)).unwrap();
+        assert_eq!(aside, Some(dir.path().join("stats.json.corrupt")));
+
+        let loaded = stats.load().unwrap();
+        assert_eq!(loaded.operations["decrypt"].runs, 1);
+        assert_eq!(loaded.failures[&exit_code::INTEGRITY], 1);
+        assert_eq!(stats.update(|_| {}).unwrap(), None);
+    }
+
+    #[test]
+    fn test_stale_lock_is_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        // Left by a writer that died mid-update: the file, but no lock on it
+        fs::write(dir.path().join("stats.json.lock"), b"{\"pid\": 1").unwrap();
+
+        StatsFile::new(&path).update(|stats| stats.record_success("encrypt", &[(1, 2)])).unwrap();
+        assert_eq!(StatsFile::new(&path).load().unwrap().operations["encrypt"].runs, 1);
+        assert!(!dir.path().join("stats.json.lock").exists());
+    }
+}
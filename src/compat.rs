@@ -0,0 +1,251 @@
+// Cross-version compatibility corpus
+// `tests/compat/` keeps the interop fixtures of every release, one directory
+// per producing crate version, listed in corpus.json. `compat-snapshot` adds
+// the running build's set; the `interop-tests` suite decrypts every entry of
+// every set with the current code and reports a version × profile × encoding
+// matrix, so a release that stops reading what an older one wrote fails.
+// Sets are never regenerated: they are the record of what each version wrote.
+
+use crate::crypto::{codec, encoding};
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::fixtures::{self, FixtureEntry, LayerProfile, Manifest, MANIFEST_FILE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Name of the corpus index
+pub const INDEX_FILE: &str = "corpus.json";
+
+/// Contents of corpus.json
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorpusIndex {
+    /// Oldest producing version first
+    pub sets: Vec<CorpusSet>,
+}
+
+/// One release's fixture set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorpusSet {
+    /// Crate version that generated the set, also its directory name
+    pub version: String,
+    /// Newest container version the set holds
+    pub container_version: u16,
+    pub fixtures: usize,
+}
+
+impl CorpusIndex {
+    /// The index of the corpus at `corpus`; a corpus without one is empty
+    pub fn load(corpus: &Path) -> Result<Self> {
+        match fs::read(corpus.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| HybridGuardError::InvalidInput(format!("{}: {}", corpus.join(INDEX_FILE).display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, corpus: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        fs::write(corpus.join(INDEX_FILE), json + "\n")?;
+        Ok(())
+    }
+}
+
+/// Add this build's fixtures to the corpus at `corpus`, under its crate version
+/// A set already there is only replaced when `replace` is given, e.g. to fix
+/// a snapshot taken before the release was tagged
+pub fn snapshot(corpus: &Path, replace: bool) -> Result<CorpusSet> {
+    let version = env!("CARGO_PKG_VERSION");
+    let mut index = CorpusIndex::load(corpus)?;
+    let dir = corpus.join(version);
+    if index.sets.iter().any(|set| set.version == version) || dir.exists() {
+        if !replace {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "the corpus already holds a {} set; released sets are kept as written (pass --replace to regenerate it)",
+                version
+            )));
+        }
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+    }
+
+    let manifest = fixtures::write(&dir)?;
+    let set = CorpusSet {
+        version: version.to_string(),
+        container_version: manifest.fixtures.iter().map(|entry| entry.container_version).max().unwrap_or(0),
+        fixtures: manifest.fixtures.len(),
+    };
+    index.sets.retain(|existing| existing.version != version);
+    index.sets.push(set.clone());
+    index.sets.sort_by_key(|set| version_key(&set.version));
+    index.save(corpus)?;
+    Ok(set)
+}
+
+/// What decrypting one corpus entry with this build gave
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The plaintext matched bit for bit
+    Passed,
+    /// This build leaves out the reader the entry needs, e.g. without `legacy-v0`
+    Skipped(String),
+    Failed(String),
+}
+
+/// One corpus entry and its outcome
+#[derive(Debug, Clone)]
+pub struct EntryResult {
+    /// Producing version
+    pub version: String,
+    pub file: String,
+    pub profile: LayerProfile,
+    pub encoding: String,
+    pub outcome: Outcome,
+}
+
+/// Every entry of a corpus, decrypted with this build
+#[derive(Debug, Clone, Default)]
+pub struct CompatMatrix {
+    pub results: Vec<EntryResult>,
+}
+
+impl CompatMatrix {
+    pub fn failures(&self) -> impl Iterator<Item = &EntryResult> {
+        self.results.iter().filter(|result| matches!(result.outcome, Outcome::Failed(_)))
+    }
+
+    /// Whether no entry failed; skipped entries do not count against it
+    pub fn is_clean(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for CompatMatrix {
+    /// One row per version × profile × encoding with its passed, skipped and
+    /// failed counts, then every failure
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut cells: BTreeMap<(Vec<u64>, &str, String, &str), [usize; 3]> = BTreeMap::new();
+        for result in &self.results {
+            let key = (version_key(&result.version), result.version.as_str(), result.profile.to_string(), result.encoding.as_str());
+            let cell = cells.entry(key).or_default();
+            match result.outcome {
+                Outcome::Passed => cell[0] += 1,
+                Outcome::Skipped(_) => cell[1] += 1,
+                Outcome::Failed(_) => cell[2] += 1,
+            }
+        }
+        writeln!(f, "{:<10} {:<8} {:<8} {:>6} {:>7} {:>6}", "version", "profile", "encoding", "passed", "skipped", "failed")?;
+        for ((_, version, profile, encoding), [passed, skipped, failed]) in &cells {
+            writeln!(f, "{:<10} {:<8} {:<8} {:>6} {:>7} {:>6}", version, profile, encoding, passed, skipped, failed)?;
+        }
+        for result in &self.results {
+            match &result.outcome {
+                Outcome::Failed(reason) => writeln!(f, "FAILED  {}/{}: {}", result.version, result.file, reason)?,
+                Outcome::Skipped(reason) => writeln!(f, "skipped {}/{}: {}", result.version, result.file, reason)?,
+                Outcome::Passed => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decrypt every entry of every set in the corpus at `corpus`
+/// Fails only when the corpus itself cannot be read; entries that do not
+/// decrypt are failures in the matrix
+pub fn check(corpus: &Path) -> Result<CompatMatrix> {
+    let mut matrix = CompatMatrix::default();
+    for set in CorpusIndex::load(corpus)?.sets {
+        let dir = corpus.join(&set.version);
+        let manifest: Manifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST_FILE))?)
+            .map_err(|e| HybridGuardError::InvalidInput(format!("{}: {}", dir.join(MANIFEST_FILE).display(), e)))?;
+        if manifest.fixtures.len() != set.fixtures {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "the {} set lists {} fixtures but corpus.json records {}",
+                set.version,
+                manifest.fixtures.len(),
+                set.fixtures
+            )));
+        }
+        for entry in &manifest.fixtures {
+            let outcome = match decrypt_entry(&dir, &manifest, entry) {
+                Ok(()) => Outcome::Passed,
+                Err(e @ HybridGuardError::UnsupportedVersion { .. }) => Outcome::Skipped(e.to_string()),
+                Err(e) => Outcome::Failed(e.to_string()),
+            };
+            matrix.results.push(EntryResult {
+                version: set.version.clone(),
+                file: entry.file.clone(),
+                profile: entry.layer_profile,
+                encoding: entry.encoding.clone(),
+                outcome,
+            });
+        }
+    }
+    Ok(matrix)
+}
+
+/// Decrypt `entry` with the keys its manifest records and compare the plaintext
+fn decrypt_entry(dir: &Path, manifest: &Manifest, entry: &FixtureEntry) -> Result<()> {
+    let key = manifest
+        .keys
+        .iter()
+        .find(|key| key.name == entry.key)
+        .ok_or_else(|| HybridGuardError::InvalidInput(format!("no fixture key {}", entry.key)))?;
+    let key_manager = key.key_manager()?;
+    if key_manager.key_id() != key.key_id {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "fixture key {} now derives key ID {}, the manifest records {}",
+            key.name,
+            key_manager.key_id(),
+            key.key_id
+        )));
+    }
+
+    let data = encoding::decode(&fs::read(dir.join(&entry.file))?)?;
+    if data.descriptors() != &entry.descriptors[..] {
+        return Err(HybridGuardError::Integrity("layer descriptors differ from the manifest".to_string()));
+    }
+    let plaintext = HybridGuardEncryptor::new().decrypt(&data, &key_manager.keys_for(&data)?)?;
+    if plaintext != codec::hex_lower_decode(&entry.plaintext)? {
+        return Err(HybridGuardError::Integrity("decrypted to a different plaintext".to_string()));
+    }
+    Ok(())
+}
+
+/// Numeric parts of a crate version, so 0.10.0 sorts after 0.9.1
+fn version_key(version: &str) -> Vec<u64> {
+    version.split(|c: char| !c.is_ascii_digit()).filter_map(|part| part.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(version: &str, encoding: &str, outcome: Outcome) -> EntryResult {
+        EntryResult { version: version.to_string(), file: "f.hg".to_string(), profile: LayerProfile::Current, encoding: encoding.to_string(), outcome }
+    }
+
+    #[test]
+    fn test_matrix_rows_and_failures() {
+        let matrix = CompatMatrix {
+            results: vec![
+                result("0.10.0", "binary", Outcome::Passed),
+                result("0.9.1", "binary", Outcome::Passed),
+                result("0.9.1", "binary", Outcome::Failed("tag mismatch".to_string())),
+                result("0.9.1", "armor", Outcome::Skipped("no legacy-v0".to_string())),
+            ],
+        };
+        assert!(!matrix.is_clean());
+        let text = matrix.to_string();
+        let rows: Vec<Vec<&str>> = text.lines().skip(1).take(3).map(|row| row.split_whitespace().collect()).collect();
+        assert_eq!(rows[0], ["0.9.1", "current", "armor", "0", "1", "0"]);
+        assert_eq!(rows[1], ["0.9.1", "current", "binary", "1", "0", "1"]);
+        assert_eq!(rows[2], ["0.10.0", "current", "binary", "1", "0", "0"]);
+        assert!(text.contains("FAILED  0.9.1/f.hg: tag mismatch"));
+        assert!(version_key("0.10.0") > version_key("0.9.1"));
+    }
+}
@@ -0,0 +1,178 @@
+// Content-type checks of decrypted plaintext
+// `encrypt --tag-content-type` records the sniffed type of the plaintext in an
+// authenticated container field. Decrypt sniffs the plaintext again, compares
+// it with the tag and flags executables (ELF, PE, Mach-O, `#!` scripts), which
+// a quarantine directory can receive instead of the requested path, with
+// every execute bit cleared.
+
+use crate::crypto::sniff::{self, ContentType};
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::pathname;
+use crate::staging::{Contents, StagedFile};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// What one decrypted output holds, and where it went
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentCheck {
+    #[serde(serialize_with = "pathname::serialize")]
+    pub input: PathBuf,
+    /// Where the output was asked to go
+    #[serde(serialize_with = "pathname::serialize")]
+    pub requested: PathBuf,
+    /// Type recorded at encryption time, if the container has a tag
+    pub stored: Option<String>,
+    /// Type sniffed from the decrypted plaintext
+    pub detected: ContentType,
+    pub executable: bool,
+    /// The stored type is not the detected one
+    pub mismatch: bool,
+    /// Where the output went instead, when it was quarantined
+    #[serde(serialize_with = "pathname::serialize_option")]
+    pub quarantined: Option<PathBuf>,
+}
+
+impl ContentCheck {
+    /// Check plaintext starting with `head` against the tag `stored`
+    pub fn new(input: &Path, requested: &Path, stored: Option<&str>, head: &[u8]) -> Self {
+        let detected = sniff::content_type(head);
+        Self {
+            input: input.to_path_buf(),
+            requested: requested.to_path_buf(),
+            stored: stored.map(str::to_string),
+            detected,
+            executable: detected.is_executable(),
+            mismatch: stored.is_some_and(|stored| stored != detected.name()),
+            quarantined: None,
+        }
+    }
+
+    /// Check the output already written to `path`
+    pub fn of_file(input: &Path, path: &Path, stored: Option<&str>) -> Result<Self> {
+        let mut head = Vec::with_capacity(sniff::CONTENT_SNIFF_LEN);
+        File::open(path)?.take(sniff::CONTENT_SNIFF_LEN as u64).read_to_end(&mut head)?;
+        Ok(Self::new(input, path, stored, &head))
+    }
+
+    /// Whether the user should hear about this output
+    pub fn is_suspicious(&self) -> bool {
+        self.executable || self.mismatch
+    }
+
+    /// Where the output goes instead of `requested`: `dir` with the same file
+    /// name; an existing file there is refused unless `force`
+    pub fn quarantine_path(&self, dir: &Path, force: bool) -> Result<PathBuf> {
+        if !dir.is_dir() {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "--quarantine-executables {} is not a directory",
+                dir.display()
+            )));
+        }
+        let name = self.requested.file_name().or_else(|| self.input.file_name()).unwrap_or("output".as_ref());
+        let path = dir.join(name);
+        if path.exists() && !force {
+            return Err(HybridGuardError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists in the quarantine directory; remove it or use --force", path.display()),
+            )));
+        }
+        Ok(path)
+    }
+}
+
+/// Clear every execute bit of `path`
+#[cfg(unix)]
+pub fn strip_execute(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & !0o111))?;
+    Ok(())
+}
+
+/// Clear every execute bit of `path`; Windows has none to clear
+#[cfg(not(unix))]
+pub fn strip_execute(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Move an output already written to `from` into quarantine at `to`, copying
+/// when the two are on different filesystems, and clear its execute bits
+pub fn quarantine_file(from: &Path, to: &Path, options: &WriteOptions) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        let mut staged = StagedFile::create(to, None, Contents::Plaintext)?.with_write_options(options.clone());
+        io::copy(&mut File::open(from)?, staged.file())?;
+        staged.commit()?;
+        fs::remove_file(from)?;
+    }
+    strip_execute(to)
+}
+
+/// Every check of a decrypt run, written by `decrypt --content-report`
+#[derive(Debug, Default, Serialize)]
+pub struct ContentReport {
+    pub files: Vec<ContentCheck>,
+}
+
+impl ContentReport {
+    /// Write the report as pretty JSON, finishing the file as `options` ask
+    pub fn save_with(&self, path: &Path, options: &WriteOptions) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        let mut staged = StagedFile::create(path, None, Contents::Plaintext)?.with_write_options(options.clone());
+        staged.file().write_all(&json)?;
+        staged.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF: &[u8] = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0";
+
+    #[test]
+    fn test_checks_flag_executables_and_mismatches() {
+        let check = ContentCheck::new(Path::new("a.hg"), Path::new("a"), Some("elf"), ELF);
+        assert!(check.executable && !check.mismatch && check.is_suspicious());
+
+        let check = ContentCheck::new(Path::new("a.hg"), Path::new("a"), Some("text"), ELF);
+        assert!(check.mismatch);
+
+        let check = ContentCheck::new(Path::new("a.hg"), Path::new("a"), None, b"notes");
+        assert_eq!(check.detected, ContentType::Text);
+        assert!(!check.is_suspicious());
+    }
+
+    #[test]
+    fn test_quarantine_path_refuses_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let check = ContentCheck::new(Path::new("in/tool.hg"), Path::new("out/tool"), None, ELF);
+        assert_eq!(check.quarantine_path(dir.path(), false).unwrap(), dir.path().join("tool"));
+
+        fs::write(dir.path().join("tool"), b"earlier").unwrap();
+        assert!(check.quarantine_path(dir.path(), false).is_err());
+        assert!(check.quarantine_path(dir.path(), true).is_ok());
+        assert!(check.quarantine_path(&dir.path().join("missing"), false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_quarantine_file_clears_execute_bits() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("tool");
+        fs::write(&from, ELF).unwrap();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o755)).unwrap();
+        let to = dir.path().join("quarantine-tool");
+        quarantine_file(&from, &to, &WriteOptions::bulk()).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), ELF);
+        assert_eq!(fs::metadata(&to).unwrap().permissions().mode() & 0o111, 0);
+    }
+}
@@ -0,0 +1,221 @@
+// Text codecs for bytes: hex, base64 and Crockford base32
+// Key IDs, fingerprints, armored containers and paper backups are all printed
+// through these, so every representation decodes again wherever it appears.
+// (`crypto::encoding` is the binary/JSON/armor container layer built on them.)
+// Decoders are strict: no stray whitespace, padding only where the codec has
+// it, and unused trailing bits must be zero, so each string has one meaning.
+
+use crate::error::HybridGuardError;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::{DecodeError, Engine};
+use thiserror::Error;
+
+/// Crockford base32 alphabet
+pub const CROCKFORD_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Why a string did not decode
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CodecError {
+    #[error("invalid {codec} character {found:?} at position {position}")]
+    InvalidCharacter { codec: &'static str, position: usize, found: char },
+
+    #[error("invalid {codec} length {len}")]
+    InvalidLength { codec: &'static str, len: usize },
+
+    #[error("invalid {codec} padding")]
+    InvalidPadding { codec: &'static str },
+
+    #[error("non-canonical {codec}: unused trailing bits are set")]
+    NonCanonical { codec: &'static str },
+}
+
+impl From<CodecError> for HybridGuardError {
+    fn from(error: CodecError) -> Self {
+        HybridGuardError::InvalidInput(error.to_string())
+    }
+}
+
+/// Lowercase hex, two digits per byte
+pub fn hex_lower(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        out.push(HEX_DIGITS[(b >> 4) as usize] as char);
+        out.push(HEX_DIGITS[(b & 0xf) as usize] as char);
+    }
+    out
+}
+
+/// Decode lowercase hex as `hex_lower` writes it
+pub fn hex_lower_decode(text: &str) -> Result<Vec<u8>, CodecError> {
+    const CODEC: &str = "hex";
+    if text.len() % 2 != 0 {
+        return Err(CodecError::InvalidLength { codec: CODEC, len: text.len() });
+    }
+    let nibble = |position: usize, c: u8| match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        _ => Err(invalid_character(CODEC, text, position)),
+    };
+    text.as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| Ok((nibble(2 * i, pair[0])? << 4) | nibble(2 * i + 1, pair[1])?))
+        .collect()
+}
+
+/// Lowercase hex in space-separated groups of `group` bytes, for reading aloud
+pub fn hex_grouped(bytes: &[u8], group: usize) -> String {
+    bytes.chunks(group.max(1)).map(hex_lower).collect::<Vec<_>>().join(" ")
+}
+
+/// Decode `hex_grouped` output written with the same `group`
+pub fn hex_grouped_decode(text: &str, group: usize) -> Result<Vec<u8>, CodecError> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    let group = group.max(1);
+    let parts: Vec<&str> = text.split(' ').collect();
+    let mut out = Vec::with_capacity(parts.len() * group);
+    let mut position = 0;
+    for (i, part) in parts.iter().enumerate() {
+        // Every group is full except possibly the last
+        let full = part.len() == 2 * group;
+        let last = i + 1 == parts.len() && !part.is_empty() && part.len() < 2 * group;
+        if !full && !last {
+            return Err(CodecError::InvalidLength { codec: "grouped hex", len: text.len() });
+        }
+        let bytes = hex_lower_decode(part).map_err(|e| match e {
+            CodecError::InvalidCharacter { position: at, found, .. } => {
+                CodecError::InvalidCharacter { codec: "grouped hex", position: position + at, found }
+            }
+            other => other,
+        })?;
+        out.extend_from_slice(&bytes);
+        position += part.len() + 1;
+    }
+    Ok(out)
+}
+
+/// Standard base64 with padding (RFC 4648 section 4)
+pub fn b64_std(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+pub fn b64_std_decode(text: &str) -> Result<Vec<u8>, CodecError> {
+    STANDARD.decode(text).map_err(|e| base64_error("base64", text, e))
+}
+
+/// URL-safe base64 without padding (RFC 4648 section 5)
+pub fn b64_url_nopad(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn b64_url_nopad_decode(text: &str) -> Result<Vec<u8>, CodecError> {
+    URL_SAFE_NO_PAD.decode(text).map_err(|e| base64_error("base64url", text, e))
+}
+
+/// Crockford base32, uppercase and unpadded
+pub fn crockford32(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(CROCKFORD_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(CROCKFORD_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Decode Crockford base32, which is case-insensitive and reads I and L as 1, O as 0
+pub fn crockford32_decode(text: &str) -> Result<Vec<u8>, CodecError> {
+    const CODEC: &str = "Crockford base32";
+    // 1, 3 and 6 trailing digits cannot come from whole bytes
+    if matches!(text.len() % 8, 1 | 3 | 6) {
+        return Err(CodecError::InvalidLength { codec: CODEC, len: text.len() });
+    }
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for (position, c) in text.bytes().enumerate() {
+        let digit = crockford32_value(c).ok_or_else(|| invalid_character(CODEC, text, position))?;
+        buffer = (buffer << 5) | digit as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    if buffer & ((1 << bits) - 1) != 0 {
+        return Err(CodecError::NonCanonical { codec: CODEC });
+    }
+    Ok(out)
+}
+
+/// Value of one Crockford base32 digit, accepting lowercase and the I/L/O aliases
+pub fn crockford32_value(c: u8) -> Option<u8> {
+    let c = match c.to_ascii_uppercase() {
+        b'O' => b'0',
+        b'I' | b'L' => b'1',
+        other => other,
+    };
+    CROCKFORD_ALPHABET.iter().position(|&a| a == c).map(|p| p as u8)
+}
+
+fn invalid_character(codec: &'static str, text: &str, position: usize) -> CodecError {
+    let found = text.get(position..).and_then(|rest| rest.chars().next()).unwrap_or('\u{fffd}');
+    CodecError::InvalidCharacter { codec, position, found }
+}
+
+fn base64_error(codec: &'static str, text: &str, error: DecodeError) -> CodecError {
+    match error {
+        DecodeError::InvalidByte(position, _) => invalid_character(codec, text, position),
+        DecodeError::InvalidLength(len) => CodecError::InvalidLength { codec, len },
+        DecodeError::InvalidLastSymbol(_, _) => CodecError::NonCanonical { codec },
+        DecodeError::InvalidPadding => CodecError::InvalidPadding { codec },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_answers() {
+        assert_eq!(hex_lower(&[0x00, 0xab, 0xff]), "00abff");
+        assert_eq!(hex_grouped(&[0xde, 0xad, 0xbe, 0xef, 0x01], 2), "dead beef 01");
+        assert_eq!(b64_std(b"hi?"), "aGk/");
+        assert_eq!(b64_url_nopad(b"hi?>"), "aGk_Pg");
+        assert_eq!(crockford32(b"foobar"), "CSQPYRK1E8");
+        assert_eq!(crockford32_decode("csqpyrkie8").unwrap(), b"foobar");
+    }
+
+    #[test]
+    fn test_strict_decoding() {
+        assert_eq!(hex_lower_decode("0"), Err(CodecError::InvalidLength { codec: "hex", len: 1 }));
+        assert_eq!(
+            hex_lower_decode("0A"),
+            Err(CodecError::InvalidCharacter { codec: "hex", position: 1, found: 'A' })
+        );
+        assert!(hex_grouped_decode("dead be ef", 2).is_err());
+        assert!(hex_grouped_decode("dead  beef", 2).is_err());
+        assert_eq!(hex_grouped_decode("dead beef 01", 2).unwrap(), [0xde, 0xad, 0xbe, 0xef, 0x01]);
+
+        assert!(matches!(b64_std_decode("aGk"), Err(CodecError::InvalidLength { .. } | CodecError::InvalidPadding { .. })));
+        assert!(matches!(b64_std_decode("aGl="), Err(CodecError::NonCanonical { .. })));
+        assert!(b64_url_nopad_decode("aGk/").is_err());
+        assert!(b64_url_nopad_decode("aGk=").is_err());
+
+        assert!(matches!(crockford32_decode("CSQ"), Err(CodecError::InvalidLength { .. })));
+        assert!(matches!(crockford32_decode("CU"), Err(CodecError::InvalidCharacter { position: 1, .. })));
+        assert!(matches!(crockford32_decode("CT"), Err(CodecError::NonCanonical { .. })));
+    }
+}
@@ -0,0 +1,1227 @@
+// On-disk container format
+// Version 13: magic "HGRD", little-endian u16 format version, bincode body
+//             (u64 ciphertext length, ciphertext, then the metadata)
+// Version 12: same prefix, body without the application metadata
+// Version 11: same prefix, body without the verification tag
+// Version 10: same prefix, body without the passphrase salt and cost
+// Version 9: same prefix, body without the content type
+// Version 8: same prefix, body without the policy label
+// Version 7: same prefix, body without the source snapshot
+// Version 6: same prefix, body without the wrapped file key
+// Version 5: same prefix, body without the content digest
+// Version 4: same prefix, body without the timestamp token
+// Version 3: same prefix, body without the authentication tag
+// Version 2: same prefix, body without the migration note
+// Version 1: same prefix, body without the key ID
+// Version 0 (legacy): bare bincode of the original EncryptedData struct
+// Versions 0 to 3 are read by `legacy`, and only when built with its features;
+// from 4 on, one reader takes each version's fields from `BODY_SCHEMA`
+
+use crate::crypto::envelope::{WrappedFileKey, NONCE_LEN, WRAPPED_LEN};
+use crate::crypto::kdf::PassphraseKdf;
+use crate::crypto::metadata::Metadata;
+use crate::crypto::{EncryptedData, EncryptedDataFields, MigrationNote, SourceSnapshot};
+use crate::crypto::tag::TAG_LEN;
+use crate::crypto::timestamp::DIGEST_LEN;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::LayerDescriptor;
+#[cfg(any(test, feature = "fixtures"))]
+use crate::layers;
+use crate::legacy;
+use bincode::Options;
+use serde::de::{DeserializeOwned, DeserializeSeed, Deserializer, Error as _, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// Magic bytes at the start of every versioned container
+pub const MAGIC: [u8; 4] = *b"HGRD";
+
+/// Container format written by this build
+pub const FORMAT_VERSION: u16 = 13;
+
+/// Length of the magic plus format version prefix
+pub const PREFIX_LEN: usize = 6;
+
+/// Container format versions defined so far; 0 to 3 also need their
+/// `legacy` reader, without which they fail with `UnsupportedVersion`
+pub const SUPPORTED_VERSIONS: &[u16] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13];
+
+/// How the body after the prefix is serialized
+pub const BODY_ENCODING: &str = "bincode 1.x: little-endian fixed-width integers, \
+     u64 length before every sequence and string, one tag byte before every Option";
+
+/// Byte order of every integer this crate writes to disk or the wire
+/// The prefixes and trailers below go through `le_*` and `*_at`, and bincode
+/// bodies are little-endian by construction, so files move between hosts of
+/// either endianness unchanged
+pub const BYTE_ORDER: &str = "little-endian";
+
+/// Byte order of the host this build runs on
+pub const HOST_BYTE_ORDER: &str = if cfg!(target_endian = "big") { "big-endian" } else { "little-endian" };
+
+/// `value` as written to disk
+pub fn le_u16(value: u16) -> [u8; 2] {
+    value.to_le_bytes()
+}
+
+/// `value` as written to disk
+pub fn le_u32(value: u32) -> [u8; 4] {
+    value.to_le_bytes()
+}
+
+/// `value` as written to disk
+pub fn le_u64(value: u64) -> [u8; 8] {
+    value.to_le_bytes()
+}
+
+/// Little-endian u16 at `bytes[at..at + 2]`; panics like indexing when short
+pub fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+/// Little-endian u32 at `bytes[at..at + 4]`; panics like indexing when short
+pub fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    let mut le = [0u8; 4];
+    le.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(le)
+}
+
+/// Little-endian u64 at `bytes[at..at + 8]`; panics like indexing when short
+pub fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    let mut le = [0u8; 8];
+    le.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(le)
+}
+
+/// `value` big-endian, for the KEM ciphertext length before every sealed
+/// message, which the first layer format fixed that way; nothing else uses it
+pub fn be_u32(value: u32) -> [u8; 4] {
+    value.to_be_bytes()
+}
+
+/// Big-endian u32 at `bytes[at..at + 4]`, the counterpart of `be_u32`
+pub fn be_u32_at(bytes: &[u8], at: usize) -> u32 {
+    let mut be = [0u8; 4];
+    be.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_be_bytes(be)
+}
+
+/// One field of the container body
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BodyField {
+    pub name: &'static str,
+    
+    /// Type as bincode writes it
+    pub wire_type: &'static str,
+    
+    /// First container format version with this field
+    pub since: u16,
+}
+
+/// Fields of the current body, in the order they are written
+/// Every format version appends fields; none are removed or reordered
+pub const BODY_SCHEMA: &[BodyField] = &[
+    BodyField { name: "ciphertext", wire_type: "Vec<u8>", since: 0 },
+    BodyField { name: "layers", wire_type: "Vec<String>", since: 0 },
+    BodyField { name: "version", wire_type: "String", since: 0 },
+    BodyField { name: "timestamp", wire_type: "u64", since: 0 },
+    BodyField { name: "descriptors", wire_type: "Vec<(name: String, version: u16)>", since: 1 },
+    BodyField { name: "key_id", wire_type: "Option<String>", since: 2 },
+    BodyField { name: "migrated_from", wire_type: "Option<(format_version: u16, timestamp: u64)>", since: 3 },
+    BodyField { name: "tag", wire_type: "Option<[u8; 32]>", since: 4 },
+    BodyField { name: "timestamp_token", wire_type: "Option<TimestampToken>", since: 5 },
+    BodyField { name: "content_digest", wire_type: "Option<[u8; 32]>", since: 6 },
+    BodyField { name: "wrapped_key", wire_type: "Option<(nonce: [u8; 12], ciphertext: Vec<u8>)>", since: 7 },
+    BodyField { name: "source_snapshot", wire_type: "Option<(len: u64, modified_secs: u64, modified_nanos: u32)>", since: 8 },
+    BodyField { name: "label", wire_type: "Option<String>", since: 9 },
+    BodyField { name: "content_type", wire_type: "Option<String>", since: 10 },
+    BodyField {
+        name: "passphrase",
+        wire_type: "Option<(salt: [u8; 16], memory_kib: u32, iterations: u32, parallelism: u32)>",
+        since: 11,
+    },
+    BodyField { name: "verification_tag", wire_type: "Option<[u8; 32]>", since: 12 },
+    BodyField {
+        name: "metadata",
+        wire_type: "Option<(extra: Map<String, Vec<u8>>, sealed: Option<(nonce: [u8; 12], ciphertext: Vec<u8>)>)>",
+        since: 13,
+    },
+];
+
+/// Largest metadata section `peek_header` reads after the ciphertext
+const MAX_METADATA_LEN: u64 = 1024 * 1024;
+
+/// Container metadata read without touching the ciphertext
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CiphertextHeader {
+    pub format_version: u16,
+    pub ciphertext_len: u64,
+    pub key_id: Option<String>,
+    pub timestamp: u64,
+    pub descriptors: Vec<LayerDescriptor>,
+    /// Stored SHA3-256 of the ciphertext; None before format v6
+    /// Not checked here: the tag covers it when the container is decrypted
+    pub content_digest: Option<[u8; DIGEST_LEN]>,
+    pub authenticated: bool,
+    /// Whether the file has its own wrapped key (format v7 and later)
+    pub envelope: bool,
+    /// Source size and mtime recorded by a stable read (format v8 and later)
+    pub source_snapshot: Option<SourceSnapshot>,
+    /// Policy label given at encryption (format v9 and later)
+    pub label: Option<String>,
+    /// Content type older builds left in the clear (format v10 and later);
+    /// newer ones seal it in the metadata, where only the file's keys read it
+    pub content_type: Option<String>,
+    /// Salt and Argon2id cost of a passphrase-only container (format v11 and later)
+    pub passphrase: Option<PassphraseKdf>,
+    /// Application metadata, its private map still sealed (format v13 and later)
+    pub metadata: Option<Metadata>,
+}
+
+impl CiphertextHeader {
+    /// Whether both containers hold the same ciphertext, by stored digest
+    /// False when either digest is missing, since nothing can be concluded
+    pub fn same_payload(&self, other: &Self) -> bool {
+        matches!((&self.content_digest, &other.content_digest), (Some(a), Some(b)) if a == b)
+    }
+}
+
+/// Whether two containers name the same keys; false if either has no key ID
+pub fn same_key(a: &CiphertextHeader, b: &CiphertextHeader) -> bool {
+    matches!((&a.key_id, &b.key_id), (Some(a), Some(b)) if a == b)
+}
+
+/// Read a container's metadata without reading its ciphertext
+pub fn peek_header(bytes: &[u8]) -> Result<CiphertextHeader> {
+    peek_header_from(&mut Cursor::new(bytes))
+}
+
+/// Read a container's metadata from `reader`, seeking over the ciphertext
+/// Reads the prefix, the ciphertext length and the metadata after the
+/// ciphertext, so the cost does not depend on the ciphertext size
+pub fn peek_header_from<R: Read + Seek>(reader: &mut R) -> Result<CiphertextHeader> {
+    let truncated = || HybridGuardError::Decryption("Truncated container header".to_string());
+    
+    let mut prefix = [0u8; PREFIX_LEN];
+    reader.read_exact(&mut prefix).map_err(|_| truncated())?;
+    let format_version = format_version(&prefix)?;
+    if !SUPPORTED_VERSIONS.contains(&format_version) {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "container format version {} (this build reads {:?})",
+            format_version, SUPPORTED_VERSIONS
+        )));
+    }
+    let body_start = if format_version == 0 { 0 } else { PREFIX_LEN };
+    
+    let mut len = [0u8; 8];
+    reader.seek(SeekFrom::Start(body_start as u64))?;
+    reader.read_exact(&mut len).map_err(|_| truncated())?;
+    let ciphertext_len = u64_at(&len, 0);
+    if ciphertext_len == 0 {
+        return Err(HybridGuardError::Decryption("invalid container: ciphertext is empty".to_string()));
+    }
+    
+    let end = reader.seek(SeekFrom::End(0))?;
+    let metadata_start = (body_start as u64 + 8)
+        .checked_add(ciphertext_len)
+        .filter(|&start| start <= end)
+        .ok_or_else(truncated)?;
+    if end - metadata_start > MAX_METADATA_LEN {
+        return Err(HybridGuardError::Decryption("container metadata is implausibly large".to_string()));
+    }
+    reader.seek(SeekFrom::Start(metadata_start))?;
+    let mut metadata = Vec::new();
+    reader.read_to_end(&mut metadata)?;
+    
+    // Parse through `decode` with a one-byte stand-in ciphertext, so every
+    // format version is read by the same code as a full decode
+    let mut stand_in = prefix[..body_start].to_vec();
+    stand_in.extend_from_slice(&le_u64(1));
+    stand_in.push(0);
+    stand_in.extend_from_slice(&metadata);
+    let data = decode(&stand_in)?;
+    
+    Ok(CiphertextHeader {
+        format_version,
+        ciphertext_len,
+        key_id: data.key_id().map(str::to_string),
+        timestamp: data.timestamp(),
+        descriptors: data.descriptors().to_vec(),
+        content_digest: data.content_digest().copied(),
+        authenticated: data.is_authenticated(),
+        envelope: data.wrapped_key().is_some(),
+        source_snapshot: data.source_snapshot().copied(),
+        label: data.label().map(str::to_string),
+        content_type: data.clear_content_type().map(str::to_string),
+        passphrase: data.passphrase().copied(),
+        metadata: data.metadata,
+    })
+}
+
+/// Serialize encrypted data into the current container format
+pub fn encode(data: &EncryptedData) -> Result<Vec<u8>> {
+    let body = bincode::serialize(data)
+        .map_err(|e| HybridGuardError::Encryption(e.to_string()))?;
+    
+    let mut out = Vec::with_capacity(PREFIX_LEN + body.len());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&le_u16(FORMAT_VERSION));
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Serialize `data` in container format `version`, for interop fixtures
+/// Each body is a prefix of the current one, so it is written field by
+/// field; fails if `data` sets a field that `version` cannot carry
+#[cfg(any(test, feature = "fixtures"))]
+pub fn encode_version(data: &EncryptedData, version: u16) -> Result<Vec<u8>> {
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(HybridGuardError::UnsupportedFormat(format!("container format version {}", version)));
+    }
+    let fields: [(bool, Vec<u8>); 17] = [
+        (true, field(&data.ciphertext)?),
+        (true, field(&data.layers)?),
+        (true, field(&data.version)?),
+        (true, field(&data.timestamp)?),
+        (data.descriptors != layers::legacy_descriptors(), field(&data.descriptors)?),
+        (data.key_id.is_some(), field(&data.key_id)?),
+        (data.migrated_from.is_some(), field(&data.migrated_from)?),
+        (data.tag.is_some(), field(&data.tag)?),
+        (data.timestamp_token.is_some(), field(&data.timestamp_token)?),
+        (data.content_digest.is_some(), field(&data.content_digest)?),
+        (data.wrapped_key.is_some(), field(&data.wrapped_key)?),
+        (data.source_snapshot.is_some(), field(&data.source_snapshot)?),
+        (data.label.is_some(), field(&data.label)?),
+        (data.content_type.is_some(), field(&data.content_type)?),
+        (data.passphrase.is_some(), field(&data.passphrase)?),
+        (data.verification_tag.is_some(), field(&data.verification_tag)?),
+        (data.metadata.is_some(), field(&data.metadata)?),
+    ];
+
+    let mut out = Vec::new();
+    if version > 0 {
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&le_u16(version));
+    }
+    for (schema, (present, bytes)) in BODY_SCHEMA.iter().zip(fields) {
+        if schema.since <= version {
+            out.extend(bytes);
+        } else if present {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "container format version {} has no {} field",
+                version, schema.name
+            )));
+        }
+    }
+    Ok(out)
+}
+
+/// Bincode of one body field
+#[cfg(any(test, feature = "fixtures"))]
+fn field<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|e| HybridGuardError::Encryption(e.to_string()))
+}
+
+/// Exact container length for a ciphertext of `ciphertext_len` bytes
+/// written by the current pipeline, with `label`, `content_type` (sealed
+/// in the metadata) and `metadata` (a `Metadata::stand_in` will do) when given
+pub fn encoded_len(
+    ciphertext_len: usize,
+    key_id: Option<&str>,
+    label: Option<&str>,
+    content_type: Option<&str>,
+    metadata: Option<&Metadata>,
+) -> Result<usize> {
+    let mut header = EncryptedData::new(Vec::new());
+    if let Some(key_id) = key_id {
+        header = header.with_key_id(key_id);
+    }
+    header.label = label.map(str::to_string);
+    header.metadata = match content_type {
+        Some(content_type) => Some(Metadata::stand_in_with_content_type(metadata, content_type)?),
+        None => metadata.cloned(),
+    };
+    // The pipeline always seals, stores a digest and wraps a file key; only
+    // their presence and sizes affect the length
+    header.tag = Some([0u8; TAG_LEN]);
+    header.wrapped_key = Some(WrappedFileKey { nonce: [0u8; NONCE_LEN], ciphertext: vec![0u8; WRAPPED_LEN] });
+    Ok(encode(&header)?.len() + ciphertext_len)
+}
+
+/// Container format version of `bytes`, 0 for legacy files without a prefix
+pub fn format_version(bytes: &[u8]) -> Result<u16> {
+    if !bytes.starts_with(&MAGIC) {
+        return Ok(0);
+    }
+    match bytes.get(MAGIC.len()..PREFIX_LEN) {
+        Some(version) => Ok(u16_at(version, 0)),
+        None => Err(HybridGuardError::Decryption("Truncated container header".to_string())),
+    }
+}
+
+/// Parse a container of any supported format version
+pub fn decode(bytes: &[u8]) -> Result<EncryptedData> {
+    decode_with(bytes, false)
+}
+
+/// Parse a container, failing unless the body is consumed to its last byte
+/// Format conversion uses this so trailing data is never silently dropped
+pub fn decode_exact(bytes: &[u8]) -> Result<EncryptedData> {
+    decode_with(bytes, true)
+}
+
+/// Deserialize one container body, to its last byte when `exact`
+pub(crate) fn body<T: DeserializeOwned>(bytes: &[u8], exact: bool) -> Result<T> {
+    let parsed = if exact {
+        bincode::DefaultOptions::new().with_fixint_encoding().deserialize(bytes)
+    } else {
+        bincode::deserialize(bytes)
+    };
+    parsed.map_err(|e| HybridGuardError::Decryption(e.to_string()))
+}
+
+/// Deserialize a body of format `version`, 4 or later, to its last byte when `exact`
+fn read_body(bytes: &[u8], version: u16, exact: bool) -> Result<EncryptedData> {
+    let reader = VersionedBody { version };
+    let parsed = if exact {
+        bincode::DefaultOptions::new().with_fixint_encoding().deserialize_seed(reader, bytes)
+    } else {
+        bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes().deserialize_seed(reader, bytes)
+    };
+    parsed.map_err(|e| HybridGuardError::Decryption(e.to_string()))?.validate()
+}
+
+/// Reads the body of container format `version` field by field, in
+/// `BODY_SCHEMA` order, leaving out the fields that version predates
+/// Every version only appends fields, so this one reader serves them all; a
+/// new version adds its field to the schema, `EncryptedDataFields` and here
+struct VersionedBody {
+    version: u16,
+}
+
+impl<'de> DeserializeSeed<'de> for VersionedBody {
+    type Value = EncryptedDataFields;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error> {
+        let present = BODY_SCHEMA.iter().filter(|field| field.since <= self.version).count();
+        deserializer.deserialize_tuple(present, self)
+    }
+}
+
+impl<'de> Visitor<'de> for VersionedBody {
+    type Value = EncryptedDataFields;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a container body of format version {}", self.version)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+        let mut body = BodyFields { seq: &mut seq, version: self.version, next: 0 };
+        Ok(EncryptedDataFields {
+            ciphertext: body.required("ciphertext")?,
+            layers: body.required("layers")?,
+            version: body.required("version")?,
+            timestamp: body.required("timestamp")?,
+            descriptors: body.required("descriptors")?,
+            key_id: body.optional("key_id")?,
+            migrated_from: body.optional("migrated_from")?,
+            tag: body.optional("tag")?,
+            timestamp_token: body.optional("timestamp_token")?,
+            content_digest: body.optional("content_digest")?,
+            wrapped_key: body.optional("wrapped_key")?,
+            source_snapshot: body.optional("source_snapshot")?,
+            label: body.optional("label")?,
+            content_type: body.optional("content_type")?,
+            passphrase: body.optional("passphrase")?,
+            verification_tag: body.optional("verification_tag")?,
+            metadata: body.optional("metadata")?,
+        })
+    }
+}
+
+/// The fields of one body, taken in schema order
+struct BodyFields<'a, A> {
+    seq: &'a mut A,
+    version: u16,
+    /// Index in `BODY_SCHEMA` of the next field
+    next: usize,
+}
+
+impl<'de, A: SeqAccess<'de>> BodyFields<'_, A> {
+    /// The next field, `name`; None when the format version predates it
+    fn field<T: Deserialize<'de>>(&mut self, name: &str) -> std::result::Result<Option<T>, A::Error> {
+        let schema = BODY_SCHEMA[self.next];
+        debug_assert_eq!(schema.name, name, "body fields are read in schema order");
+        self.next += 1;
+        if schema.since > self.version {
+            return Ok(None);
+        }
+        match self.seq.next_element()? {
+            Some(value) => Ok(Some(value)),
+            None => Err(A::Error::custom(format!("container body ends before its {} field", name))),
+        }
+    }
+
+    /// A field every format version from 4 on has
+    fn required<T: Deserialize<'de>>(&mut self, name: &str) -> std::result::Result<T, A::Error> {
+        let version = self.version;
+        self.field(name)?
+            .ok_or_else(|| A::Error::custom(format!("container format version {} has no {} field", version, name)))
+    }
+
+    /// An optional field, also None where the format version predates it
+    fn optional<T: Deserialize<'de>>(&mut self, name: &str) -> std::result::Result<Option<T>, A::Error> {
+        Ok(self.field::<Option<T>>(name)?.flatten())
+    }
+}
+
+fn decode_with(bytes: &[u8], exact: bool) -> Result<EncryptedData> {
+    let version = format_version(bytes)?;
+    let data = match version {
+        0 => legacy::read_container(0, bytes, exact),
+        version @ 1..=3 => legacy::read_container(version, &bytes[PREFIX_LEN..], exact),
+        4..=FORMAT_VERSION => read_body(&bytes[PREFIX_LEN..], version, exact),
+        other => Err(HybridGuardError::UnsupportedFormat(format!(
+            "container format version {} (this build reads {:?})",
+            other, SUPPORTED_VERSIONS
+        ))),
+    }?;
+    Ok(data.decoded_from(version))
+}
+
+/// Magic bytes ending a self-extracting bundle
+pub const BUNDLE_MAGIC: [u8; 8] = *b"HGBUNDLE";
+
+/// Bundle trailer format written by this build
+pub const BUNDLE_VERSION: u16 = 1;
+
+/// Four u64 offsets and lengths, the u16 version, then the magic
+pub const BUNDLE_TRAILER_LEN: usize = 4 * 8 + 2 + BUNDLE_MAGIC.len();
+
+/// Where a self-extracting bundle keeps its sealed key file and container
+/// Written as the last `BUNDLE_TRAILER_LEN` bytes of the bundle: little-endian
+/// u64 key file offset and length, container offset and length, the u16
+/// trailer version, then `BUNDLE_MAGIC` at the very end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BundleTrailer {
+    pub key_offset: u64,
+    pub key_len: u64,
+    pub payload_offset: u64,
+    pub payload_len: u64,
+}
+
+impl BundleTrailer {
+    /// The trailer as appended to a bundle
+    pub fn to_bytes(&self) -> [u8; BUNDLE_TRAILER_LEN] {
+        let mut out = [0u8; BUNDLE_TRAILER_LEN];
+        for (i, field) in [self.key_offset, self.key_len, self.payload_offset, self.payload_len].iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&le_u64(*field));
+        }
+        out[32..34].copy_from_slice(&le_u16(BUNDLE_VERSION));
+        out[34..].copy_from_slice(&BUNDLE_MAGIC);
+        out
+    }
+}
+
+/// Read the bundle trailer at the end of `reader`
+/// None when the file does not end with the magic, as a plain hybridguard
+/// binary does not; an error when the trailer is of an unknown version or
+/// points anywhere but the bytes just before it
+pub fn read_bundle_trailer<R: Read + Seek>(reader: &mut R) -> Result<Option<BundleTrailer>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    let Some(body_end) = end.checked_sub(BUNDLE_TRAILER_LEN as u64) else {
+        return Ok(None);
+    };
+    let mut bytes = [0u8; BUNDLE_TRAILER_LEN];
+    reader.seek(SeekFrom::Start(body_end))?;
+    reader.read_exact(&mut bytes)?;
+    if bytes[34..] != BUNDLE_MAGIC {
+        return Ok(None);
+    }
+    
+    let version = u16_at(&bytes, 32);
+    if version != BUNDLE_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "bundle trailer version {} (this build reads {})",
+            version, BUNDLE_VERSION
+        )));
+    }
+    let field = |i: usize| u64_at(&bytes, i * 8);
+    let trailer = BundleTrailer { key_offset: field(0), key_len: field(1), payload_offset: field(2), payload_len: field(3) };
+    
+    // The key file, then the container, then the trailer, with nothing after
+    // the key file but the container
+    let key_end = trailer.key_offset.checked_add(trailer.key_len);
+    let payload_end = trailer.payload_offset.checked_add(trailer.payload_len);
+    if trailer.key_len == 0 || trailer.payload_len == 0 || key_end != Some(trailer.payload_offset) || payload_end != Some(body_end) {
+        return Err(HybridGuardError::Decryption("bundle trailer points outside the bundle".to_string()));
+    }
+    Ok(Some(trailer))
+}
+
+/// A format version 12 container written on a little-endian host, with a
+/// u16, u32 and u64 whose bytes all differ, so a swapped read cannot pass:
+/// 16 ciphertext bytes 0..16, one ML-KEM-768 v3 descriptor, key ID
+/// "hg-byte-order", label "byte-order", timestamp 1700000000, migrated from
+/// v11 at 1600000000, and a source snapshot of 0x0102030405060708 bytes at
+/// 1700000001.123456789
+pub const BYTE_ORDER_FIXTURE: &[u8] = &[
+    0x48, 0x47, 0x52, 0x44, 0x0c, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+    0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x01, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4d, 0x4c,
+    0x2d, 0x4b, 0x45, 0x4d, 0x2d, 0x37, 0x36, 0x38, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x30, 0x2e, 0x31, 0x2e, 0x30, 0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4d, 0x4c, 0x2d,
+    0x4b, 0x45, 0x4d, 0x2d, 0x37, 0x36, 0x38, 0x03, 0x00, 0x01, 0x0d, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x68, 0x67, 0x2d, 0x62, 0x79, 0x74, 0x65, 0x2d, 0x6f, 0x72, 0x64, 0x65, 0x72, 0x01,
+    0x0b, 0x00, 0x00, 0x10, 0x5e, 0x5f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08,
+    0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x01, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x15,
+    0xcd, 0x5b, 0x07, 0x01, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x62, 0x79, 0x74, 0x65,
+    0x2d, 0x6f, 0x72, 0x64, 0x65, 0x72, 0x00, 0x00, 0x00,
+];
+
+/// Read `BYTE_ORDER_FIXTURE` back on this host and write it again
+/// Fails on a host whose reads or writes disagree with the files any other
+/// host produces, which `hybridguard doctor` reports as its byte-order check
+pub fn check_byte_order() -> Result<()> {
+    let differs = |what: &str| HybridGuardError::Integrity(format!("byte-order fixture: {} differs from the little-endian original", what));
+    if le_u32(0x0102_0304) != [4, 3, 2, 1] || u64_at(&le_u64(0x0102_0304_0506_0708), 0) != 0x0102_0304_0506_0708 {
+        return Err(differs("an integer helper"));
+    }
+    if format_version(BYTE_ORDER_FIXTURE)? != 12 {
+        return Err(differs("the format version"));
+    }
+    
+    let snapshot = SourceSnapshot { len: 0x0102_0304_0506_0708, modified_secs: 1_700_000_001, modified_nanos: 123_456_789 };
+    let data = decode_exact(BYTE_ORDER_FIXTURE)?;
+    let fields_match = |data: &EncryptedData| {
+        data.ciphertext() == (0..16).collect::<Vec<u8>>()
+            && data.timestamp() == 1_700_000_000
+            && data.descriptors() == [LayerDescriptor::new("ML-KEM-768", 3)]
+            && data.key_id() == Some("hg-byte-order")
+            && data.migrated_from() == Some(&MigrationNote { format_version: 11, timestamp: 1_600_000_000 })
+            && data.source_snapshot() == Some(&snapshot)
+            && data.label() == Some("byte-order")
+    };
+    if !fields_match(&data) {
+        return Err(differs("a decoded field"));
+    }
+    
+    // Later formats only append fields, so the current body starts with the fixture's
+    let encoded = encode(&data)?;
+    if !encoded[PREFIX_LEN..].starts_with(&BYTE_ORDER_FIXTURE[PREFIX_LEN..]) || decode(&encoded)? != data {
+        return Err(differs("the re-encoded container"));
+    }
+    
+    // `peek_header` reads the ciphertext length itself rather than through bincode
+    let header = peek_header(BYTE_ORDER_FIXTURE)?;
+    if header.ciphertext_len != 16 || header.timestamp != 1_700_000_000 || header.source_snapshot != Some(snapshot) {
+        return Err(differs("the peeked header"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hkdf::KeyDerivation;
+    use crate::crypto::kdf::KdfParams;
+    use crate::crypto::metadata::MetadataMap;
+    use crate::crypto::EncryptedDataBuilder;
+    
+    #[test]
+    fn test_round_trip() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let wrapped = WrappedFileKey { nonce: [4u8; NONCE_LEN], ciphertext: vec![5u8; WRAPPED_LEN] };
+        let data = EncryptedData::new(vec![1, 2, 3]).with_key_id("hg-test").with_wrapped_key(wrapped, &keys);
+        let bytes = encode(&data).unwrap();
+        
+        assert!(bytes.starts_with(&MAGIC));
+        assert_eq!(format_version(&bytes).unwrap(), FORMAT_VERSION);
+        
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.ciphertext(), data.ciphertext());
+        assert_eq!(decoded.descriptors(), data.descriptors());
+        assert_eq!(decoded.key_id(), Some("hg-test"));
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(encoded_len(3, Some("hg-test"), None, None, None).unwrap(), bytes.len());
+        let labeled = encode(&data.clone().with_label("confidential", &keys)).unwrap();
+        assert_eq!(encoded_len(3, Some("hg-test"), Some("confidential"), None, None).unwrap(), labeled.len());
+        let typed = encode(&data.clone().with_content_type("elf", &keys).unwrap()).unwrap();
+        assert_eq!(encoded_len(3, Some("hg-test"), None, Some("elf"), None).unwrap(), typed.len());
+        let (public, private) = sample_metadata();
+        let metadata = Metadata::new(public.clone(), &private, &keys).unwrap().unwrap();
+        let stand_in = Metadata::stand_in(public, &private).unwrap();
+        let described = encode(&data.with_metadata(metadata, &keys)).unwrap();
+        assert_eq!(encoded_len(3, Some("hg-test"), None, None, stand_in.as_ref()).unwrap(), described.len());
+    }
+    
+    #[cfg(feature = "legacy-pre-mac")]
+    #[test]
+    fn test_v3_is_unauthenticated() {
+        let data = EncryptedData::new(vec![5, 6]).with_key_id("hg-v3");
+        let body = (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors(), data.key_id(), data.migrated_from());
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&3u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.key_id(), Some("hg-v3"));
+        assert!(!decoded.is_authenticated());
+    }
+    
+    #[test]
+    fn test_v4_has_no_timestamp_token() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_key_id("hg-v4").with_tag(&keys);
+        let body = (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors(), data.key_id(), data.migrated_from(), data.tag);
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&4u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert!(decoded.timestamp_token().is_none());
+    }
+    
+    #[test]
+    fn test_tag_covers_ciphertext() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let other = KeyDerivation::new(vec![4u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![1, 2, 3]).with_tag(&keys);
+        assert!(matches!(data.verify_tag(&other), Err(HybridGuardError::Integrity(_))));
+        
+        let mut bytes = encode(&data).unwrap();
+        // Flip the last ciphertext byte
+        let at = bytes.windows(3).position(|w| w == [1, 2, 3]).unwrap() + 2;
+        bytes[at] ^= 1;
+        let forged = decode(&bytes).unwrap();
+        assert!(matches!(forged.verify_tag(&keys), Err(HybridGuardError::Integrity(_))));
+    }
+    
+    #[test]
+    fn test_stripped_tag_fails_verification() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let mut stripped = EncryptedData::new(vec![1, 2, 3]).with_tag(&keys);
+        stripped.tag = None;
+        for version in [6, FORMAT_VERSION] {
+            let decoded = decode(&encode_version(&stripped, version).unwrap()).unwrap();
+            assert!(decoded.tag_stripped());
+            assert!(matches!(decoded.verify_tag(&keys), Err(HybridGuardError::Integrity(_))));
+        }
+        // Untagged data built in memory was never stripped of anything
+        assert!(stripped.verify_tag(&keys).is_ok());
+    }
+    
+    #[cfg(feature = "legacy-pre-mac")]
+    #[test]
+    fn test_v1_has_no_key_id() {
+        let data = EncryptedData::new(vec![4, 5, 6]);
+        let body = (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors());
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.ciphertext(), &[4, 5, 6]);
+        assert_eq!(decoded.key_id(), None);
+    }
+    
+    #[cfg(feature = "legacy-pre-mac")]
+    #[test]
+    fn test_v2_keeps_key_id() {
+        let data = EncryptedData::new(vec![7, 8]).with_key_id("hg-old");
+        let body = (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors(), data.key_id());
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.key_id(), Some("hg-old"));
+        assert_eq!(decoded.migrated_from(), None);
+    }
+    
+    #[cfg(feature = "legacy-v0")]
+    #[test]
+    fn test_legacy_v0_gets_v1_descriptors() {
+        // Hand-built bincode of the original struct
+        let mut bytes = 3u64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[9, 9, 9]);
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.extend_from_slice(b"HQC");
+        bytes.extend_from_slice(&5u64.to_le_bytes());
+        bytes.extend_from_slice(b"0.1.0");
+        bytes.extend_from_slice(&1_700_000_000u64.to_le_bytes());
+        
+        assert_eq!(format_version(&bytes).unwrap(), 0);
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.ciphertext(), &[9, 9, 9]);
+        assert_eq!(decoded.timestamp(), 1_700_000_000);
+        assert!(decoded.descriptors().iter().all(|d| d.version == 1));
+    }
+    
+    #[test]
+    fn test_exact_rejects_trailing_bytes() {
+        let mut bytes = encode(&EncryptedData::new(vec![1, 2, 3])).unwrap();
+        assert!(decode_exact(&bytes).is_ok());
+        bytes.push(0);
+        assert!(decode(&bytes).is_ok());
+        assert!(matches!(decode_exact(&bytes), Err(HybridGuardError::Decryption(_))));
+    }
+    
+    #[test]
+    fn test_v5_has_no_content_digest() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let mut data = EncryptedData::new(vec![5, 6]).with_key_id("hg-v5");
+        data.content_digest = None;
+        let data = data.with_tag(&keys);
+        let body = (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors(), data.key_id(), data.migrated_from(), data.tag, data.timestamp_token());
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&5u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.content_digest(), None);
+        assert_eq!(peek_header(&bytes).unwrap().content_digest, None);
+    }
+    
+    #[test]
+    fn test_v6_has_no_wrapped_key() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_key_id("hg-v6").with_tag(&keys);
+        let body = (
+            (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors(), data.key_id(), data.migrated_from()),
+            (data.tag, data.timestamp_token(), data.content_digest()),
+        );
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&6u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.wrapped_key(), None);
+        assert!(!peek_header(&bytes).unwrap().envelope);
+    }
+    
+    #[test]
+    fn test_v7_has_no_source_snapshot() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let wrapped = WrappedFileKey { nonce: [4u8; NONCE_LEN], ciphertext: vec![5u8; WRAPPED_LEN] };
+        let data = EncryptedData::new(vec![5, 6]).with_key_id("hg-v7").with_wrapped_key(wrapped.clone(), &keys);
+        let body = (
+            (data.ciphertext(), data.layers(), data.version(), data.timestamp(), data.descriptors(), data.key_id(), data.migrated_from()),
+            (data.tag, data.timestamp_token(), data.content_digest(), data.wrapped_key()),
+        );
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&7u16.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&body).unwrap());
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.wrapped_key(), Some(&wrapped));
+        assert_eq!(decoded.source_snapshot(), None);
+        assert_eq!(peek_header(&bytes).unwrap().source_snapshot, None);
+    }
+    
+    #[test]
+    fn test_source_snapshot_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let snapshot = SourceSnapshot { len: 2, modified_secs: 1_700_000_000, modified_nanos: 42 };
+        let data = EncryptedData::new(vec![5, 6]).with_tag(&keys).with_source_snapshot(snapshot, &keys);
+        let bytes = encode(&data).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.source_snapshot(), Some(&snapshot));
+        assert_eq!(peek_header(&bytes).unwrap().source_snapshot, Some(snapshot));
+    }
+    
+    #[test]
+    fn test_v8_has_no_label() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let snapshot = SourceSnapshot { len: 2, modified_secs: 1_700_000_000, modified_nanos: 42 };
+        let data = EncryptedData::new(vec![5, 6]).with_tag(&keys).with_source_snapshot(snapshot, &keys);
+        let bytes = encode_version(&data, 8).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.label(), None);
+        assert_eq!(peek_header(&bytes).unwrap().label, None);
+        assert!(encode_version(&data.with_label("confidential", &keys), 8).is_err());
+    }
+    
+    #[test]
+    fn test_label_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_label("confidential", &keys);
+        let bytes = encode(&data).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.label(), Some("confidential"));
+        assert_eq!(peek_header(&bytes).unwrap().label.as_deref(), Some("confidential"));
+        
+        // The tag covers the label, so relabeling is caught
+        let relabeled = EncryptedDataBuilder::from(decoded).label("public").build().unwrap();
+        assert!(relabeled.verify_tag(&keys).is_err());
+    }
+    
+    #[test]
+    fn test_v9_has_no_content_type() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_label("confidential", &keys);
+        let bytes = encode_version(&data, 9).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.label(), Some("confidential"));
+        assert_eq!(decoded.content_type(&keys).unwrap(), None);
+        assert_eq!(peek_header(&bytes).unwrap().content_type, None);
+        assert!(encode_version(&data.with_content_type("elf", &keys).unwrap(), 9).is_err());
+    }
+    
+    #[test]
+    fn test_content_type_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_content_type("elf", &keys).unwrap();
+        let bytes = encode(&data).unwrap();
+        
+        // Sealed, the type reads only under the file's keys
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.content_type(&keys).unwrap().as_deref(), Some("elf"));
+        assert_eq!(decoded.clear_content_type(), None);
+        assert_eq!(peek_header(&bytes).unwrap().content_type, None);
+        assert!(!bytes.windows(3).any(|w| w == b"elf"));
+        
+        // Files older builds tagged in the clear still read
+        let clear = EncryptedDataBuilder::new(vec![5, 6]).content_type("elf").tag(&keys).unwrap().build().unwrap();
+        let bytes = encode_version(&clear, 12).unwrap();
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.content_type(&keys).unwrap().as_deref(), Some("elf"));
+        assert_eq!(peek_header(&bytes).unwrap().content_type.as_deref(), Some("elf"));
+        
+        // The tag covers the type, and a type cannot pass for a label
+        let retyped = EncryptedDataBuilder::from(decoded.clone()).content_type("text").build().unwrap();
+        assert!(retyped.verify_tag(&keys).is_err());
+        let mut moved = EncryptedDataBuilder::from(decoded).build().unwrap();
+        moved.content_type = None;
+        moved.label = Some("elf".to_string());
+        assert!(moved.verify_tag(&keys).is_err());
+    }
+    
+    #[test]
+    fn test_v10_has_no_passphrase() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedDataBuilder::new(vec![5, 6]).content_type("text").tag(&keys).unwrap().build().unwrap();
+        let bytes = encode_version(&data, 10).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.clear_content_type(), Some("text"));
+        assert_eq!(decoded.passphrase(), None);
+        assert_eq!(peek_header(&bytes).unwrap().passphrase, None);
+        assert!(encode_version(&data.with_passphrase(sample_passphrase(), &keys), 10).is_err());
+    }
+    
+    #[test]
+    fn test_passphrase_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_passphrase(sample_passphrase(), &keys);
+        let bytes = encode(&data).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.passphrase(), Some(&sample_passphrase()));
+        assert_eq!(peek_header(&bytes).unwrap().passphrase, Some(sample_passphrase()));
+        
+        // The tag covers the salt and cost, so neither can be swapped out
+        let mut cheaper = sample_passphrase();
+        cheaper.params.iterations = 1;
+        let recosted = EncryptedDataBuilder::from(decoded).passphrase(cheaper).build().unwrap();
+        assert!(recosted.verify_tag(&keys).is_err());
+    }
+    
+    #[test]
+    fn test_v11_has_no_verification_tag() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let master = KeyDerivation::new(vec![4u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_tag(&keys).with_key_id("hg-enc");
+        let bytes = encode_version(&data, 11).unwrap();
+        assert!(!decode(&bytes).unwrap().has_verification_tag());
+        assert!(encode_version(&data.with_verification_tag(&master), 11).is_err());
+    }
+    
+    #[test]
+    fn test_verification_tag_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let master = KeyDerivation::new(vec![4u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_tag(&keys).with_key_id("hg-enc").with_verification_tag(&master);
+        let decoded = decode(&encode(&data).unwrap()).unwrap();
+        assert!(decoded.has_verification_tag());
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded, data);
+    }
+    
+    #[test]
+    fn test_v12_has_no_metadata() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_tag(&keys).with_key_id("hg-enc");
+        let bytes = encode_version(&data, 12).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.metadata().is_empty());
+        assert_eq!(peek_header(&bytes).unwrap().metadata, None);
+        let (public, private) = sample_metadata();
+        let metadata = Metadata::new(public, &private, &keys).unwrap().unwrap();
+        assert!(encode_version(&data.with_metadata(metadata, &keys), 12).is_err());
+    }
+    
+    #[test]
+    fn test_metadata_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let (public, private) = sample_metadata();
+        let metadata = Metadata::new(public.clone(), &private, &keys).unwrap().unwrap();
+        let data = EncryptedData::new(vec![5, 6]).with_metadata(metadata.clone(), &keys);
+        let bytes = encode(&data).unwrap();
+        
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.verify_tag(&keys).is_ok());
+        assert_eq!(decoded.metadata().extra(), &public);
+        assert_eq!(decoded.metadata().open(&keys).unwrap(), private);
+        assert_eq!(peek_header(&bytes).unwrap().metadata, Some(metadata.clone()));
+        
+        // The tag covers both maps: an edited public value or a private map
+        // sealed again is caught before anything is opened
+        let mut edited = bytes.clone();
+        let value = edited.windows(4).position(|w| w == b"acme").unwrap();
+        edited[value + 3] = b'f';
+        assert_eq!(decode(&edited).unwrap().metadata().get("tenant"), Some(&b"acmf"[..]));
+        assert!(decode(&edited).unwrap().verify_tag(&keys).is_err());
+        let resealed = Metadata::new(public, &private, &keys).unwrap().unwrap();
+        assert_ne!(resealed, metadata);
+        let swapped = EncryptedDataBuilder::from(decoded).metadata(resealed).build().unwrap();
+        assert!(swapped.verify_tag(&keys).is_err());
+    }
+    
+    #[test]
+    fn test_oversized_metadata_rejected_on_decode() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let metadata = Metadata::new(sample_metadata().0, &MetadataMap::new(), &keys).unwrap().unwrap();
+        let bytes = encode(&EncryptedData::new(vec![5, 6]).with_metadata(metadata, &keys)).unwrap();
+        
+        // Grow the value of "tenant" past the cap by hand, length and all
+        let value = bytes.windows(4).position(|w| w == b"acme").unwrap();
+        let mut forged = bytes[..value - 8].to_vec();
+        forged.extend_from_slice(&le_u64(6000));
+        forged.extend(std::iter::repeat(b'a').take(6000));
+        forged.extend_from_slice(&bytes[value + 4..]);
+        assert!(matches!(decode(&forged), Err(HybridGuardError::Decryption(m)) if m.contains("metadata")));
+    }
+    
+    fn sample_metadata() -> (MetadataMap, MetadataMap) {
+        let public = [("tenant", b"acme".to_vec()), ("doc.uuid", b"6f1e2c90".to_vec())];
+        let private = [("ssn", b"078-05-1120".to_vec())];
+        (
+            public.into_iter().map(|(key, value)| (key.to_string(), value)).collect(),
+            private.into_iter().map(|(key, value)| (key.to_string(), value)).collect(),
+        )
+    }
+    
+    fn sample_passphrase() -> PassphraseKdf {
+        PassphraseKdf { salt: [9u8; 16], params: KdfParams { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 } }
+    }
+    
+    #[test]
+    fn test_peek_header_matches_decode() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![7; 300]).with_key_id("hg-peek").with_tag(&keys);
+        let bytes = encode(&data).unwrap();
+        
+        let header = peek_header(&bytes).unwrap();
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.ciphertext_len, 300);
+        assert_eq!(header.key_id.as_deref(), Some("hg-peek"));
+        assert_eq!(header.descriptors, data.descriptors());
+        assert_eq!(header.content_digest.as_ref(), data.content_digest());
+        assert!(header.authenticated);
+        
+        // A truncated ciphertext is noticed from the lengths alone
+        assert!(peek_header(&bytes[..100]).is_err());
+    }
+    
+    #[test]
+    fn test_same_payload_and_key() {
+        let a = peek_header(&encode(&EncryptedData::new(vec![1, 2, 3]).with_key_id("hg-a")).unwrap()).unwrap();
+        let copy = peek_header(&encode(&EncryptedData::new(vec![1, 2, 3]).with_key_id("hg-b")).unwrap()).unwrap();
+        let other = peek_header(&encode(&EncryptedData::new(vec![1, 2, 4]).with_key_id("hg-a")).unwrap()).unwrap();
+        
+        assert!(a.same_payload(&copy));
+        assert!(!a.same_payload(&other));
+        assert!(same_key(&a, &other));
+        assert!(!same_key(&a, &copy));
+        
+        let unlabelled = peek_header(&encode(&EncryptedData::new(vec![1, 2, 3])).unwrap()).unwrap();
+        assert!(!same_key(&unlabelled, &unlabelled));
+    }
+    
+    #[test]
+    fn test_tampered_digest_rejected() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        // A digest that was tagged but does not describe the ciphertext
+        let mut data = EncryptedData::new(vec![1, 2, 3]);
+        data.content_digest = Some([0u8; DIGEST_LEN]);
+        let data = data.with_tag(&keys);
+        assert!(matches!(data.verify_tag(&keys), Err(HybridGuardError::Integrity(_))));
+    }
+    
+    #[test]
+    fn test_encode_version_round_trips() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let mut data = EncryptedData::with_descriptors(vec![1, 2, 3], layers::legacy_descriptors());
+        data.content_digest = None;
+        for version in SUPPORTED_VERSIONS.iter().copied() {
+            if version == 2 {
+                data = data.with_key_id("hg-enc");
+            }
+            if version == 4 {
+                data = data.with_tag(&keys);
+            }
+            let bytes = encode_version(&data, version).unwrap();
+            assert_eq!(format_version(&bytes).unwrap(), version);
+            if !legacy::reads_container(version) {
+                assert!(matches!(decode_exact(&bytes), Err(HybridGuardError::UnsupportedVersion { .. })));
+                continue;
+            }
+            assert_eq!(decode_exact(&bytes).unwrap(), data, "version {}", version);
+        }
+        assert_eq!(encode_version(&data, FORMAT_VERSION).unwrap(), encode(&data).unwrap());
+
+        // Fields an older version has no room for are refused, not dropped
+        assert!(matches!(encode_version(&data, 3), Err(HybridGuardError::InvalidInput(m)) if m.contains("tag")));
+        let current = EncryptedData::new(vec![1, 2, 3]);
+        assert!(encode_version(&current, 0).is_err());
+        assert!(encode_version(&current, 99).is_err());
+    }
+    
+    #[test]
+    fn test_body_is_read_field_by_field() {
+        let keys = KeyDerivation::new(vec![3u8; 32]).derive_all_keys().unwrap();
+        let data = EncryptedData::new(vec![1, 2, 3]).with_key_id("hg-enc").with_label("confidential", &keys);
+        let bytes = encode_version(&data, 9).unwrap();
+        assert_eq!(decode_exact(&bytes).unwrap(), data);
+        
+        // A body cut short anywhere fails to parse, and a longer one is left over
+        for len in [bytes.len() - 1, PREFIX_LEN + 8 + 3] {
+            assert!(matches!(decode(&bytes[..len]), Err(HybridGuardError::Decryption(_))), "{}", len);
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(decode(&longer).is_ok());
+        assert!(matches!(decode_exact(&longer), Err(HybridGuardError::Decryption(_))));
+    }
+    
+    #[test]
+    fn test_future_version_rejected() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&99u16.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 32]);
+        
+        assert!(matches!(decode(&bytes), Err(HybridGuardError::UnsupportedFormat(_))));
+    }
+    
+    #[test]
+    fn test_body_schema_matches_struct() {
+        let value = serde_json::to_value(EncryptedData::new(vec![1, 2, 3])).unwrap();
+        let mut fields: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        let mut schema: Vec<&str> = BODY_SCHEMA.iter().map(|field| field.name).collect();
+        fields.sort();
+        schema.sort();
+        assert_eq!(fields, schema);
+        assert_eq!((TAG_LEN, DIGEST_LEN), (32, 32), "update the wire types of tag and content_digest");
+        
+        // Fields are appended, and the newest one came with the current version
+        assert!(BODY_SCHEMA.windows(2).all(|pair| pair[0].since <= pair[1].since));
+        assert_eq!(BODY_SCHEMA.last().unwrap().since, FORMAT_VERSION);
+    }
+    
+    #[test]
+    fn test_bundle_trailer_round_trip() {
+        let trailer = BundleTrailer { key_offset: 10, key_len: 5, payload_offset: 15, payload_len: 20 };
+        let mut bundle = vec![7u8; 35];
+        bundle.extend_from_slice(&trailer.to_bytes());
+        assert!(bundle.ends_with(&BUNDLE_MAGIC));
+        assert_eq!(read_bundle_trailer(&mut Cursor::new(&bundle)).unwrap(), Some(trailer));
+        
+        // Anything without the magic at the end is not a bundle
+        assert_eq!(read_bundle_trailer(&mut Cursor::new(&bundle[..bundle.len() - 1])).unwrap(), None);
+        assert_eq!(read_bundle_trailer(&mut Cursor::new(b"HGBUNDLE")).unwrap(), None);
+    }
+    
+    #[test]
+    fn test_bundle_trailer_must_fit_the_file() {
+        let bundle = |trailer: BundleTrailer| {
+            let mut bytes = vec![0u8; 35];
+            bytes.extend_from_slice(&trailer.to_bytes());
+            read_bundle_trailer(&mut Cursor::new(bytes))
+        };
+        let valid = BundleTrailer { key_offset: 10, key_len: 5, payload_offset: 15, payload_len: 20 };
+        assert!(bundle(BundleTrailer { payload_len: 21, ..valid }).is_err());
+        assert!(bundle(BundleTrailer { key_len: 4, ..valid }).is_err());
+        assert!(bundle(BundleTrailer { key_offset: u64::MAX, ..valid }).is_err());
+        assert!(bundle(BundleTrailer { key_offset: 15, key_len: 0, ..valid }).is_err());
+        
+        let mut bytes = vec![0u8; 35];
+        bytes.extend_from_slice(&valid.to_bytes());
+        bytes[35 + 32] = 2;
+        assert!(matches!(read_bundle_trailer(&mut Cursor::new(bytes)), Err(HybridGuardError::UnsupportedFormat(_))));
+    }
+    
+    // Expectations are spelled out byte by byte, never with `to_ne_bytes`, so
+    // they hold unchanged under a big-endian target such as
+    // `cross test --target s390x-unknown-linux-gnu`
+    #[test]
+    fn test_integer_helpers_are_little_endian_on_any_host() {
+        assert_eq!(le_u16(0x0102), [0x02, 0x01]);
+        assert_eq!(le_u32(0x0102_0304), [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(le_u64(0x0102_0304_0506_0708), [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        
+        let bytes = [0xFF, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+        assert_eq!(u16_at(&bytes, 1), 0x0708);
+        assert_eq!(u32_at(&bytes, 1), 0x0506_0708);
+        assert_eq!(u64_at(&bytes, 1), 0x0102_0304_0506_0708);
+        
+        // The one big-endian field, the KEM ciphertext length
+        assert_eq!(be_u32(0x0102_0304), [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(be_u32_at(&[0xFF, 0x01, 0x02, 0x03, 0x04], 1), 0x0102_0304);
+        
+        // and bincode bodies agree with the helpers
+        assert_eq!(bincode::serialize(&0x0102_0304u32).unwrap(), le_u32(0x0102_0304));
+        assert_eq!(bincode::serialize(&0x0102_0304_0506_0708u64).unwrap(), le_u64(0x0102_0304_0506_0708));
+    }
+    
+    #[test]
+    #[should_panic]
+    fn test_short_reads_panic_like_indexing() {
+        u32_at(&[0x01, 0x02, 0x03, 0x04], 1);
+    }
+    
+    #[test]
+    fn test_byte_order_fixture_round_trips() {
+        check_byte_order().unwrap();
+        let data = decode(BYTE_ORDER_FIXTURE).unwrap();
+        assert_eq!(encode_version(&data, 12).unwrap(), BYTE_ORDER_FIXTURE);
+        
+        // The timestamp sits after the prefix, the 16-byte ciphertext, one
+        // ten-byte layer name and the five-byte version; reversed, it reads
+        // as a different time and the check fails
+        let at = PREFIX_LEN + (8 + 16) + (8 + 8 + 10) + (8 + 5);
+        assert_eq!(&BYTE_ORDER_FIXTURE[at..at + 8], &[0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00]);
+        let mut swapped = BYTE_ORDER_FIXTURE.to_vec();
+        swapped[at..at + 8].reverse();
+        assert_eq!(decode(&swapped).unwrap().timestamp(), 1_700_000_000u64.swap_bytes());
+    }
+}
@@ -0,0 +1,210 @@
+// Deterministic random bit generator
+// SHAKE-256 based generator used wherever randomness must be reproducible
+// from a seed, most importantly for deriving KEM keypairs from layer keys
+
+use crate::crypto::container::le_u64;
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Shake256, Shake256Reader};
+use std::cell::RefCell;
+use std::sync::{Mutex, Once, PoisonError};
+
+/// Deterministic byte generator seeded from arbitrary key material
+pub struct Drbg {
+    reader: Shake256Reader,
+}
+
+impl Drbg {
+    /// Create a generator from a seed and a domain-separation label
+    pub fn new(seed: &[u8], label: &[u8]) -> Self {
+        let mut shake = Shake256::default();
+        shake.update(b"HybridGuard-DRBG");
+        shake.update(&le_u64(label.len() as u64));
+        shake.update(label);
+        shake.update(seed);
+        Self {
+            reader: shake.finalize_xof(),
+        }
+    }
+
+    /// Fill `out` with the next bytes of the stream
+    pub fn fill(&mut self, out: &mut [u8]) {
+        self.reader.read(out);
+    }
+}
+
+thread_local! {
+    /// Generator that liboqs draws from on this thread, if any
+    static SEEDED: RefCell<Option<Drbg>> = RefCell::new(None);
+}
+
+static INSTALL_OQS_RNG: Once = Once::new();
+
+/// liboqs randombytes callback: uses the thread's seeded generator when one is
+/// active and the operating system RNG otherwise, so other threads are unaffected
+unsafe extern "C" fn oqs_randombytes(buf: *mut u8, len: usize) {
+    let out = std::slice::from_raw_parts_mut(buf, len);
+    let seeded = SEEDED.with(|slot| match slot.borrow_mut().as_mut() {
+        Some(drbg) => {
+            drbg.fill(out);
+            true
+        }
+        None => false,
+    });
+    if !seeded {
+        use rand::RngCore;
+        rand::rngs::OsRng.fill_bytes(out);
+    }
+}
+
+/// Run `f` with liboqs randomness on the current thread drawn from `Drbg::new(seed, label)`
+///
+/// Used to derive KEM keypairs deterministically; every other liboqs call,
+/// including encapsulation, keeps using fresh OS randomness. Calls nest: the
+/// outer generator resumes once the inner call returns, which is how
+/// interop fixtures make whole encryptions reproducible.
+pub fn with_seeded_oqs_rng<T>(seed: &[u8], label: &[u8], f: impl FnOnce() -> T) -> T {
+    INSTALL_OQS_RNG.call_once(|| {
+        crate::layers::oqs_support::init();
+        unsafe { oqs_sys::rand::OQS_randombytes_custom_algorithm(Some(oqs_randombytes)) };
+    });
+
+    /// Restores the enclosing generator, or none, even if `f` unwinds
+    struct Restore(Option<Drbg>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SEEDED.with(|slot| *slot.borrow_mut() = self.0.take());
+        }
+    }
+
+    let previous = SEEDED.with(|slot| slot.borrow_mut().replace(Drbg::new(seed, label)));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Where an encryption draws its randomness: file keys, wrap nonces and,
+/// through `oqs_seed`, KEM encapsulation
+pub trait RandomSource: Send + Sync {
+    /// Fill `out` with random bytes
+    fn fill(&self, out: &mut [u8]);
+
+    /// Seed for liboqs while encrypting one message; None leaves it on OS randomness
+    fn oqs_seed(&self) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Whether the draws repeat from run to run, so the nonce audit lets
+    /// their nonces repeat too
+    fn reproducible(&self) -> bool {
+        false
+    }
+}
+
+/// The operating system RNG, used unless a caller injects another source
+pub struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill(&self, out: &mut [u8]) {
+        use rand::RngCore;
+        rand::rngs::OsRng.fill_bytes(out);
+    }
+}
+
+/// One `Drbg` stream shared by every draw, for reproducible encryptions
+/// Only for tests and golden files: anyone with the seed recomputes every key
+pub struct SeededRandom {
+    drbg: Mutex<Drbg>,
+}
+
+impl SeededRandom {
+    pub fn new(seed: &[u8], label: &[u8]) -> Self {
+        Self { drbg: Mutex::new(Drbg::new(seed, label)) }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn fill(&self, out: &mut [u8]) {
+        self.drbg.lock().unwrap_or_else(PoisonError::into_inner).fill(out);
+    }
+
+    fn oqs_seed(&self) -> Option<[u8; 32]> {
+        let mut seed = [0u8; 32];
+        self.fill(&mut seed);
+        Some(seed)
+    }
+
+    fn reproducible(&self) -> bool {
+        true
+    }
+}
+
+/// Run `f` with liboqs drawing from `source` if it supplies a seed
+pub fn with_oqs_rng_from<T>(source: &dyn RandomSource, f: impl FnOnce() -> T) -> T {
+    match source.oqs_seed() {
+        Some(seed) => with_seeded_oqs_rng(&seed, b"encrypt", f),
+        None => f(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drbg_deterministic() {
+        let mut a = Drbg::new(b"seed", b"label");
+        let mut b = Drbg::new(b"seed", b"label");
+        let mut out_a = [0u8; 64];
+        let mut out_b = [0u8; 64];
+        a.fill(&mut out_a);
+        b.fill(&mut out_b);
+        assert_eq!(out_a, out_b);
+    }
+
+    #[test]
+    fn test_nested_seeded_rng_resumes_the_outer_stream() {
+        let draw = || {
+            let mut out = [0u8; 16];
+            SEEDED.with(|slot| slot.borrow_mut().as_mut().map(|drbg| drbg.fill(&mut out)));
+            out
+        };
+        let mut expected = Drbg::new(b"outer", b"label");
+        let mut first = [0u8; 16];
+        let mut second = [0u8; 16];
+        expected.fill(&mut first);
+        expected.fill(&mut second);
+
+        let drawn = with_seeded_oqs_rng(b"outer", b"label", || {
+            let a = draw();
+            with_seeded_oqs_rng(b"inner", b"label", draw);
+            (a, draw())
+        });
+        assert_eq!(drawn, (first, second));
+        assert!(SEEDED.with(|slot| slot.borrow().is_none()));
+    }
+
+    #[test]
+    fn test_seeded_source_is_reproducible() {
+        let draw = |source: &dyn RandomSource| {
+            let mut out = [0u8; 48];
+            source.fill(&mut out[..16]);
+            out[16..].copy_from_slice(&source.oqs_seed().unwrap());
+            out
+        };
+        let a = SeededRandom::new(b"seed", b"label");
+        let b = SeededRandom::new(b"seed", b"label");
+        assert_eq!(draw(&a), draw(&b));
+        assert_ne!(draw(&a), draw(&SeededRandom::new(b"seed", b"label")));
+        assert!(OsRandom.oqs_seed().is_none());
+    }
+
+    #[test]
+    fn test_drbg_domain_separation() {
+        let mut a = Drbg::new(b"seed", b"label-a");
+        let mut b = Drbg::new(b"seed", b"label-b");
+        let mut out_a = [0u8; 32];
+        let mut out_b = [0u8; 32];
+        a.fill(&mut out_a);
+        b.fill(&mut out_b);
+        assert_ne!(out_a, out_b);
+    }
+}
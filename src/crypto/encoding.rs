@@ -0,0 +1,805 @@
+// Interchangeable encodings of a container
+// Binary is the on-disk container; JSON spells every field out with base64
+// byte strings; armor wraps the binary container in base64 text lines.
+// All three carry exactly the same fields, so converting needs no keys
+// `ArmorReader` and `ArmorWriter` armor any byte stream, chunked ciphertexts
+// included, a line at a time, so a pipe never has to be held whole
+
+use crate::crypto::{codec, container};
+use crate::crypto::envelope::{WrappedFileKey, NONCE_LEN};
+use crate::crypto::kdf::{KdfParams, PassphraseKdf, PASSPHRASE_SALT_LEN};
+use crate::crypto::metadata::{Metadata, MetadataMap, SealedMetadata};
+use crate::crypto::tag::TAG_LEN;
+use crate::crypto::timestamp::{TimestampToken, DIGEST_LEN};
+use crate::crypto::{EncryptedData, EncryptedDataFields, MigrationNote, SourceSnapshot};
+use crate::error::{HybridGuardError, Result};
+use crate::layers::LayerDescriptor;
+use crate::sparse;
+use crate::storage::erasure;
+use crate::streaming::chunked;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+/// First line of an armored container
+pub const ARMOR_BEGIN: &str = "-----BEGIN HYBRIDGUARD MESSAGE-----";
+
+/// Last line of an armored container
+pub const ARMOR_END: &str = "-----END HYBRIDGUARD MESSAGE-----";
+
+/// Base64 characters per armor line
+const ARMOR_LINE_LEN: usize = 64;
+
+/// Longest armor line `ArmorReader` accepts; writers wrap at 64 or 76
+pub const MAX_ARMOR_LINE: usize = 1024;
+
+/// How a container is written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// "HGRD" container as written by `encrypt`
+    Binary,
+    /// JSON object with base64 byte strings
+    Json,
+    /// Binary container in base64 between BEGIN/END lines
+    Armor,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Binary => "binary",
+            Encoding::Json => "json",
+            Encoding::Armor => "armor",
+        })
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "binary" => Ok(Encoding::Binary),
+            "json" => Ok(Encoding::Json),
+            "armor" => Ok(Encoding::Armor),
+            other => Err(HybridGuardError::InvalidInput(format!(
+                "unknown encoding '{}', expected binary, json or armor",
+                other
+            ))),
+        }
+    }
+}
+
+/// JSON form of a container
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonContainer {
+    /// Container format version the fields correspond to
+    hybridguard: u16,
+    ciphertext: String,
+    layers: Vec<String>,
+    version: String,
+    timestamp: u64,
+    descriptors: Vec<LayerDescriptor>,
+    key_id: Option<String>,
+    migrated_from: Option<MigrationNote>,
+    tag: Option<String>,
+    timestamp_token: Option<JsonToken>,
+    /// Absent in version 5 documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_digest: Option<String>,
+    /// Absent in version 5 and 6 documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wrapped_key: Option<JsonWrappedKey>,
+    /// Absent before version 8 documents and without a stable read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_snapshot: Option<SourceSnapshot>,
+    /// Absent before version 9 documents and on unlabeled files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    /// Absent before version 10 documents and unless asked for at encryption
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// Absent before version 11 documents and outside passphrase-only containers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    passphrase: Option<JsonPassphrase>,
+    /// Absent before version 12 documents and when sealed without the master keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verification_tag: Option<String>,
+    /// Absent before version 13 documents and without metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<JsonMetadata>,
+}
+
+/// JSON layout version without the content digest
+const JSON_V5: u16 = 5;
+
+/// JSON layout version without the wrapped file key
+const JSON_V6: u16 = 6;
+
+/// JSON layout version without the source snapshot
+const JSON_V7: u16 = 7;
+
+/// JSON layout version without the policy label
+const JSON_V8: u16 = 8;
+
+/// JSON layout version without the content type
+const JSON_V9: u16 = 9;
+
+/// JSON layout version without the passphrase salt and cost
+const JSON_V10: u16 = 10;
+
+/// JSON layout version without the verification tag
+const JSON_V11: u16 = 11;
+
+/// JSON layout version without the application metadata
+const JSON_V12: u16 = 12;
+
+/// JSON form of a passphrase salt and cost
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonPassphrase {
+    salt: String,
+    params: KdfParams,
+}
+
+/// JSON form of application metadata; values are base64, as they may be any bytes
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonMetadata {
+    extra: BTreeMap<String, String>,
+    sealed: Option<JsonWrappedKey>,
+}
+
+/// JSON form of a wrapped file key or sealed metadata
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonWrappedKey {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// JSON form of a timestamp token
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonToken {
+    authority: String,
+    time: u64,
+    serial: u64,
+    digest: String,
+    proof: String,
+}
+
+/// Guess the encoding of `bytes` from how they start
+pub fn detect(bytes: &[u8]) -> Encoding {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+    let text = &bytes[start..];
+    if text.starts_with(ARMOR_BEGIN.as_bytes()) {
+        Encoding::Armor
+    } else if text.starts_with(b"{") {
+        Encoding::Json
+    } else {
+        Encoding::Binary
+    }
+}
+
+/// Whether `bytes` look like a JSON or armored container
+/// JSON only counts when the `hybridguard` key shows up near the start
+pub fn is_text(bytes: &[u8]) -> bool {
+    match detect(bytes) {
+        Encoding::Armor => true,
+        Encoding::Json => bytes[..bytes.len().min(256)].windows(13).any(|w| w == b"\"hybridguard\""),
+        Encoding::Binary => false,
+    }
+}
+
+/// Parse a container in any encoding
+/// Binary input is read as leniently as `EncryptedData::from_bytes`
+pub fn decode(bytes: &[u8]) -> Result<EncryptedData> {
+    match detect(bytes) {
+        Encoding::Binary => container::decode(bytes),
+        Encoding::Json => from_json(bytes),
+        Encoding::Armor => from_armor(bytes),
+    }
+}
+
+/// Parse a container in any encoding, failing if any input is left unused
+pub fn decode_exact(bytes: &[u8]) -> Result<EncryptedData> {
+    match detect(bytes) {
+        Encoding::Binary => container::decode_exact(bytes),
+        Encoding::Json => from_json(bytes),
+        Encoding::Armor => from_armor(bytes),
+    }
+}
+
+/// Write `data` in the given encoding
+pub fn encode(data: &EncryptedData, encoding: Encoding) -> Result<Vec<u8>> {
+    match encoding {
+        Encoding::Binary => data.to_bytes(),
+        Encoding::Json => to_json(data),
+        Encoding::Armor => to_armor(data),
+    }
+}
+
+/// Re-encode a container without decrypting it
+/// Only the outer encoding changes: armor wraps the binary container byte
+/// for byte, and only JSON, which spells the fields out, writes them anew.
+/// The output must parse back to exactly the fields of the input, tag and
+/// timestamp token included, or nothing is returned
+pub fn convert(bytes: &[u8], to: Encoding) -> Result<Vec<u8>> {
+    if erasure::is_sharded(bytes) || sparse::is_sparse(bytes) || chunked::is_chunked(bytes) {
+        return Err(HybridGuardError::UnsupportedFormat(
+            "only whole containers can be converted; sharded, sparse and chunked files cannot".to_string(),
+        ));
+    }
+    let parsed = decode_exact(bytes)?;
+    let converted = match (detect(bytes), to) {
+        (Encoding::Binary, Encoding::Binary) => bytes.to_vec(),
+        (Encoding::Binary, Encoding::Armor) => armor(bytes),
+        (Encoding::Armor, Encoding::Binary) => unarmor(bytes)?,
+        (Encoding::Armor, Encoding::Armor) => armor(&unarmor(bytes)?),
+        (_, to) => encode(&parsed, to)?,
+    };
+    if decode_exact(&converted)? != parsed {
+        return Err(HybridGuardError::Integrity(format!("{} output does not round-trip", to)));
+    }
+    Ok(converted)
+}
+
+fn to_json(data: &EncryptedData) -> Result<Vec<u8>> {
+    let json = JsonContainer {
+        // Containers decoded from older files keep their older document version
+        hybridguard: match (&data.content_digest, &data.wrapped_key) {
+            _ if data.metadata.is_some() => container::FORMAT_VERSION,
+            _ if data.verification_tag.is_some() => JSON_V12,
+            _ if data.passphrase.is_some() => JSON_V11,
+            _ if data.content_type.is_some() => JSON_V10,
+            _ if data.label.is_some() => JSON_V9,
+            _ if data.source_snapshot.is_some() => JSON_V8,
+            (_, Some(_)) => JSON_V7,
+            (Some(_), None) => JSON_V6,
+            (None, None) => JSON_V5,
+        },
+        ciphertext: codec::b64_std(&data.ciphertext),
+        layers: data.layers.clone(),
+        version: data.version.clone(),
+        timestamp: data.timestamp,
+        descriptors: data.descriptors.clone(),
+        key_id: data.key_id.clone(),
+        migrated_from: data.migrated_from.clone(),
+        tag: data.tag.map(|tag| codec::b64_std(&tag)),
+        timestamp_token: data.timestamp_token.as_ref().map(|token| JsonToken {
+            authority: token.authority.clone(),
+            time: token.time,
+            serial: token.serial,
+            digest: codec::b64_std(&token.digest),
+            proof: codec::b64_std(&token.proof),
+        }),
+        content_digest: data.content_digest.map(|digest| codec::b64_std(&digest)),
+        wrapped_key: data.wrapped_key.as_ref().map(|wrapped| JsonWrappedKey {
+            nonce: codec::b64_std(&wrapped.nonce),
+            ciphertext: codec::b64_std(&wrapped.ciphertext),
+        }),
+        source_snapshot: data.source_snapshot,
+        label: data.label.clone(),
+        content_type: data.content_type.clone(),
+        passphrase: data.passphrase.map(|passphrase| JsonPassphrase {
+            salt: codec::b64_std(&passphrase.salt),
+            params: passphrase.params,
+        }),
+        verification_tag: data.verification_tag.map(|tag| codec::b64_std(&tag)),
+        metadata: data.metadata.as_ref().map(|metadata| JsonMetadata {
+            extra: metadata.extra().iter().map(|(key, value)| (key.clone(), codec::b64_std(value))).collect(),
+            sealed: metadata.sealed().map(|sealed| JsonWrappedKey {
+                nonce: codec::b64_std(&sealed.nonce),
+                ciphertext: codec::b64_std(&sealed.ciphertext),
+            }),
+        }),
+    };
+    let mut out = serde_json::to_vec_pretty(&json).map_err(|e| HybridGuardError::Encryption(e.to_string()))?;
+    out.push(b'\n');
+    Ok(out)
+}
+
+fn from_json(bytes: &[u8]) -> Result<EncryptedData> {
+    let invalid = |e: serde_json::Error| HybridGuardError::Decryption(format!("invalid JSON container: {}", e));
+    let json: JsonContainer = serde_json::from_slice(bytes).map_err(invalid)?;
+    if ![JSON_V5, JSON_V6, JSON_V7, JSON_V8, JSON_V9, JSON_V10, JSON_V11, JSON_V12, container::FORMAT_VERSION].contains(&json.hybridguard) {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "JSON container format version {} (this build reads {}, {}, {}, {}, {}, {}, {}, {} and {})",
+            json.hybridguard,
+            JSON_V5,
+            JSON_V6,
+            JSON_V7,
+            JSON_V8,
+            JSON_V9,
+            JSON_V10,
+            JSON_V11,
+            JSON_V12,
+            container::FORMAT_VERSION
+        )));
+    }
+    let data = EncryptedDataFields {
+        ciphertext: base64_field("ciphertext", &json.ciphertext)?,
+        layers: json.layers,
+        version: json.version,
+        timestamp: json.timestamp,
+        descriptors: json.descriptors,
+        key_id: json.key_id,
+        migrated_from: json.migrated_from,
+        tag: json.tag.map(|tag| fixed_field::<TAG_LEN>("tag", &tag)).transpose()?,
+        timestamp_token: match json.timestamp_token {
+            Some(token) => Some(TimestampToken {
+                authority: token.authority,
+                time: token.time,
+                serial: token.serial,
+                digest: fixed_field::<DIGEST_LEN>("timestamp digest", &token.digest)?,
+                proof: base64_field("timestamp proof", &token.proof)?,
+            }),
+            None => None,
+        },
+        content_digest: json
+            .content_digest
+            .map(|digest| fixed_field::<DIGEST_LEN>("content digest", &digest))
+            .transpose()?,
+        wrapped_key: match json.wrapped_key {
+            Some(wrapped) => Some(WrappedFileKey {
+                nonce: fixed_field::<NONCE_LEN>("wrapped key nonce", &wrapped.nonce)?,
+                ciphertext: base64_field("wrapped key", &wrapped.ciphertext)?,
+            }),
+            None => None,
+        },
+        source_snapshot: json.source_snapshot,
+        label: json.label,
+        content_type: json.content_type,
+        passphrase: match json.passphrase {
+            Some(passphrase) => Some(PassphraseKdf {
+                salt: fixed_field::<PASSPHRASE_SALT_LEN>("passphrase salt", &passphrase.salt)?,
+                params: passphrase.params,
+            }),
+            None => None,
+        },
+        verification_tag: json
+            .verification_tag
+            .map(|tag| fixed_field::<TAG_LEN>("verification tag", &tag))
+            .transpose()?,
+        metadata: json.metadata.map(metadata_from_json).transpose()?,
+    }
+    .validate()?;
+
+    // Nested structs ignore unknown keys, so compare the whole document
+    let original: serde_json::Value = serde_json::from_slice(bytes).map_err(invalid)?;
+    let reparsed: serde_json::Value = serde_json::from_slice(&to_json(&data)?).map_err(invalid)?;
+    if original != reparsed {
+        return Err(HybridGuardError::Decryption(
+            "JSON container has fields this build does not understand".to_string(),
+        ));
+    }
+    Ok(data.decoded_from(json.hybridguard))
+}
+
+fn to_armor(data: &EncryptedData) -> Result<Vec<u8>> {
+    Ok(armor(&data.to_bytes()?))
+}
+
+/// Wrap a binary container in armor
+fn armor(container: &[u8]) -> Vec<u8> {
+    let body = codec::b64_std(container);
+    let mut out = String::with_capacity(body.len() + body.len() / ARMOR_LINE_LEN + 2 * ARMOR_BEGIN.len() + 4);
+    out.push_str(ARMOR_BEGIN);
+    out.push('\n');
+    for line in body.as_bytes().chunks(ARMOR_LINE_LEN) {
+        // Base64 output is ASCII, so any split is valid UTF-8
+        out.push_str(std::str::from_utf8(line).unwrap_or_default());
+        out.push('\n');
+    }
+    out.push_str(ARMOR_END);
+    out.push('\n');
+    out.into_bytes()
+}
+
+fn from_armor(bytes: &[u8]) -> Result<EncryptedData> {
+    container::decode_exact(&unarmor(bytes)?)
+}
+
+/// The binary container inside armor
+fn unarmor(bytes: &[u8]) -> Result<Vec<u8>> {
+    let invalid = |reason: &str| HybridGuardError::Decryption(format!("invalid armored container: {}", reason));
+    let text = std::str::from_utf8(bytes).map_err(|_| invalid("not UTF-8 text"))?;
+    let mut lines = text.trim().lines().map(str::trim);
+    if lines.next() != Some(ARMOR_BEGIN) {
+        return Err(invalid("missing BEGIN line"));
+    }
+    let mut body = String::new();
+    let mut ended = false;
+    for line in lines.by_ref() {
+        if line == ARMOR_END {
+            ended = true;
+            break;
+        }
+        body.push_str(line);
+    }
+    if !ended {
+        return Err(invalid("missing END line"));
+    }
+    if lines.next().is_some() {
+        return Err(invalid("data after the END line"));
+    }
+    codec::b64_std_decode(&body).map_err(|e| invalid(&e.to_string()))
+}
+
+fn invalid_armor(reason: &str) -> HybridGuardError {
+    HybridGuardError::Decryption(format!("invalid armored container: {}", reason))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArmorState {
+    Begin,
+    Body,
+    Ended,
+}
+
+/// Decodes armored text as it is read, for input that can neither be held
+/// whole nor sought, such as a pipe
+/// Holds at most one line of text and the bytes it decodes to; the BEGIN line
+/// is checked on the first read and nothing may follow the END line
+pub struct ArmorReader<R> {
+    inner: R,
+    line: Vec<u8>,
+    /// Base64 characters of a line whose length is not a multiple of four
+    carry: Vec<u8>,
+    decoded: Vec<u8>,
+    pos: usize,
+    state: ArmorState,
+    peak: usize,
+}
+
+impl<R: BufRead> ArmorReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: Vec::with_capacity(ARMOR_LINE_LEN + 2),
+            carry: Vec::new(),
+            decoded: Vec::new(),
+            pos: 0,
+            state: ArmorState::Begin,
+            peak: 0,
+        }
+    }
+
+    /// Most bytes of text and decoded data held at once
+    pub fn peak_buffered(&self) -> usize {
+        self.peak
+    }
+
+    /// Read the next line, trimmed, into `self.line`; false at the end of the input
+    fn next_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        let read = (&mut self.inner).take(MAX_ARMOR_LINE as u64 + 1).read_until(b'\n', &mut self.line)?;
+        if read == 0 {
+            return Ok(false);
+        }
+        if read > MAX_ARMOR_LINE && self.line.last() != Some(&b'\n') {
+            return Err(invalid_armor(&format!("line longer than {} bytes", MAX_ARMOR_LINE)));
+        }
+        let end = self.line.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+        let start = self.line[..end].iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(end);
+        self.line.truncate(end);
+        self.line.drain(..start);
+        Ok(true)
+    }
+
+    /// Decode the next body line into `self.decoded`
+    fn refill(&mut self) -> Result<()> {
+        self.decoded.clear();
+        self.pos = 0;
+        if self.state == ArmorState::Begin {
+            loop {
+                if !self.next_line()? {
+                    return Err(invalid_armor("missing BEGIN line"));
+                }
+                if !self.line.is_empty() {
+                    break;
+                }
+            }
+            if self.line != ARMOR_BEGIN.as_bytes() {
+                return Err(invalid_armor("missing BEGIN line"));
+            }
+            self.state = ArmorState::Body;
+        }
+        if !self.next_line()? {
+            return Err(invalid_armor("missing END line"));
+        }
+        if self.line == ARMOR_END.as_bytes() {
+            if !self.carry.is_empty() {
+                return Err(invalid_armor("base64 body ends mid-group"));
+            }
+            while self.next_line()? {
+                if !self.line.is_empty() {
+                    return Err(invalid_armor("data after the END line"));
+                }
+            }
+            self.state = ArmorState::Ended;
+            return Ok(());
+        }
+        self.carry.extend_from_slice(&self.line);
+        let whole = self.carry.len() - self.carry.len() % 4;
+        let text = std::str::from_utf8(&self.carry[..whole]).map_err(|_| invalid_armor("not UTF-8 text"))?;
+        self.decoded = codec::b64_std_decode(text).map_err(|e| invalid_armor(&e.to_string()))?;
+        self.carry.drain(..whole);
+        self.peak = self.peak.max(self.line.capacity() + self.carry.capacity() + self.decoded.capacity());
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ArmorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.decoded.len() {
+            if self.state == ArmorState::Ended {
+                return Ok(0);
+            }
+            self.refill()?;
+        }
+        let n = buf.len().min(self.decoded.len() - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Armors a byte stream as it is written, a line at a time
+/// `finish` writes the last line and the END line
+pub struct ArmorWriter<W: Write> {
+    inner: W,
+    /// Bytes of the line being filled, fewer than one line's worth
+    pending: Vec<u8>,
+}
+
+impl<W: Write> ArmorWriter<W> {
+    /// Bytes per armor line before encoding
+    const LINE_BYTES: usize = ARMOR_LINE_LEN / 4 * 3;
+
+    /// Start the armor with its BEGIN line
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        writeln!(inner, "{}", ARMOR_BEGIN)?;
+        Ok(Self { inner, pending: Vec::with_capacity(Self::LINE_BYTES) })
+    }
+
+    /// Write the last line and the END line, giving back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            writeln!(self.inner, "{}", codec::b64_std(&self.pending))?;
+        }
+        writeln!(self.inner, "{}", ARMOR_END)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ArmorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(Self::LINE_BYTES - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == Self::LINE_BYTES {
+            writeln!(self.inner, "{}", codec::b64_std(&self.pending))?;
+            self.pending.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn metadata_from_json(json: JsonMetadata) -> Result<Metadata> {
+    let extra = json
+        .extra
+        .into_iter()
+        .map(|(key, value)| Ok((key, base64_field("metadata value", &value)?)))
+        .collect::<Result<MetadataMap>>()?;
+    let sealed = match json.sealed {
+        Some(sealed) => Some(SealedMetadata {
+            nonce: fixed_field::<NONCE_LEN>("sealed metadata nonce", &sealed.nonce)?,
+            ciphertext: base64_field("sealed metadata", &sealed.ciphertext)?,
+        }),
+        None => None,
+    };
+    Ok(Metadata::from_parts(extra, sealed))
+}
+
+fn base64_field(name: &str, value: &str) -> Result<Vec<u8>> {
+    codec::b64_std_decode(value).map_err(|e| HybridGuardError::Decryption(format!("invalid JSON container: {}: {}", name, e)))
+}
+
+fn fixed_field<const N: usize>(name: &str, value: &str) -> Result<[u8; N]> {
+    base64_field(name, value)?.try_into().map_err(|bytes: Vec<u8>| {
+        HybridGuardError::Decryption(format!(
+            "invalid JSON container: {} is {} bytes, expected {}",
+            name,
+            bytes.len(),
+            N
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::envelope;
+    use crate::crypto::hkdf::KeyDerivation;
+    use crate::crypto::timestamp::{self, LocalSigningAuthority};
+
+    fn sample() -> EncryptedData {
+        let master = KeyDerivation::new(vec![8u8; 32]).derive_all_keys().unwrap();
+        let file_key = envelope::generate_file_key();
+        let wrapped = envelope::wrap(&master, "hg-enc", &file_key).unwrap();
+        let file_keys = envelope::file_layer_keys(&file_key, master.scheme).unwrap();
+        let snapshot = SourceSnapshot { len: 200, modified_secs: 1_700_000_000, modified_nanos: 5 };
+        let params = KdfParams { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 };
+        EncryptedData::new((0..200u8).collect())
+            .with_key_id("hg-enc")
+            .with_source_snapshot(snapshot, &file_keys)
+            .with_label("internal", &file_keys)
+            .with_passphrase(PassphraseKdf { salt: [6u8; PASSPHRASE_SALT_LEN], params }, &file_keys)
+            .with_metadata(sample_metadata(&file_keys), &file_keys)
+            .with_content_type("text", &file_keys)
+            .unwrap()
+            .with_wrapped_key(wrapped, &file_keys)
+            .with_verification_tag(&master)
+    }
+
+    /// A text value that needs escaping in JSON, and one that is not UTF-8
+    fn sample_metadata(file_keys: &crate::crypto::hkdf::LayerKeys) -> Metadata {
+        let public = MetadataMap::from([
+            ("quoted".to_string(), b"say \"hi\"\n\\".to_vec()),
+            ("binary".to_string(), vec![0x00, 0xff, 0xfe, b'"', b'}']),
+        ]);
+        let private = MetadataMap::from([("tenant".to_string(), b"acme".to_vec())]);
+        Metadata::new(public, &private, file_keys).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_every_pair_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = LocalSigningAuthority::new("hold", &[0x44; 32], dir.path().join("tsa.counter")).unwrap();
+        let data = timestamp::stamp(sample(), &authority).unwrap();
+
+        let all = [Encoding::Binary, Encoding::Json, Encoding::Armor];
+        for from in all {
+            let source = encode(&data, from).unwrap();
+            assert_eq!(detect(&source), from);
+            for to in all {
+                let converted = convert(&source, to).unwrap();
+                assert_eq!(detect(&converted), to);
+                let parsed = decode(&converted).unwrap();
+                assert_eq!(parsed, data, "{} -> {}", from, to);
+                assert!(timestamp::verify_timestamp(&parsed, &authority).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn test_armor_keeps_the_container_bytes() {
+        let binary = encode(&sample(), Encoding::Binary).unwrap();
+        let armored = convert(&binary, Encoding::Armor).unwrap();
+        assert_eq!(unarmor(&armored).unwrap(), binary);
+        assert_eq!(convert(&armored, Encoding::Binary).unwrap(), binary);
+        assert_eq!(convert(&binary, Encoding::Binary).unwrap(), binary);
+
+        // Armor wrapped at another width is rewrapped around the same bytes
+        let wide = armored.split(|&b| b == b'\n').collect::<Vec<_>>();
+        let (first, body, last) = (wide[0], wide[1..wide.len() - 2].concat(), wide[wide.len() - 2]);
+        let rewrapped = [first, &body[..], last, &[]].join(&b'\n');
+        assert_eq!(convert(&rewrapped, Encoding::Armor).unwrap(), armored);
+    }
+
+    #[test]
+    fn test_partial_parses_refused() {
+        let mut binary = encode(&sample(), Encoding::Binary).unwrap();
+        binary.extend_from_slice(b"extra");
+        assert!(convert(&binary, Encoding::Json).is_err());
+
+        let json = String::from_utf8(encode(&sample(), Encoding::Json).unwrap()).unwrap();
+        let extra_field = json.replacen("\"layers\"", "\"comment\": \"hi\",\n  \"layers\"", 1);
+        assert!(convert(extra_field.as_bytes(), Encoding::Binary).is_err());
+        let extra_nested = json.replacen("\"version\": 2", "\"version\": 2, \"flags\": 1", 1);
+        assert_ne!(extra_nested, json);
+        assert!(convert(extra_nested.as_bytes(), Encoding::Binary).is_err());
+
+        let mut armor = encode(&sample(), Encoding::Armor).unwrap();
+        armor.extend_from_slice(b"trailing text\n");
+        assert!(convert(&armor, Encoding::Binary).is_err());
+    }
+
+    #[test]
+    fn test_metadata_values_are_base64_in_json() {
+        let data = sample();
+        let json = encode(&data, Encoding::Json).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(document["hybridguard"], container::FORMAT_VERSION);
+        let extra = &document["metadata"]["extra"];
+        assert_eq!(extra["binary"], codec::b64_std(&[0x00, 0xff, 0xfe, b'"', b'}']));
+        assert_eq!(extra["quoted"], codec::b64_std(b"say \"hi\"\n\\"));
+        assert!(document["metadata"]["sealed"]["ciphertext"].is_string());
+        assert_eq!(decode(&json).unwrap().metadata(), data.metadata());
+
+        // A value that is not base64 is refused rather than read as text
+        let broken = String::from_utf8(json).unwrap().replacen(extra["binary"].as_str().unwrap(), "not base64!", 1);
+        assert!(matches!(decode(broken.as_bytes()), Err(HybridGuardError::Decryption(m)) if m.contains("metadata value")));
+    }
+
+    #[test]
+    fn test_text_sniffing() {
+        assert!(is_text(&encode(&sample(), Encoding::Json).unwrap()));
+        assert!(is_text(&encode(&sample(), Encoding::Armor).unwrap()));
+        assert!(!is_text(&encode(&sample(), Encoding::Binary).unwrap()));
+        assert!(!is_text(b"{\"name\": \"some other JSON\"}"));
+    }
+    
+    #[test]
+    fn test_whole_containers_only() {
+        let data = vec![0u8; 64];
+        let sharded = erasure::encode(&data, erasure::Redundancy::new(2, 1).unwrap()).unwrap();
+        assert!(matches!(convert(&sharded, Encoding::Json), Err(HybridGuardError::UnsupportedFormat(_))));
+    }
+
+    /// Hands out at most `step` bytes per read
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.step).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_armor_streams_match_whole_armor() {
+        let data = sample();
+        let whole = encode(&data, Encoding::Armor).unwrap();
+        let mut writer = ArmorWriter::new(Vec::new()).unwrap();
+        for piece in data.to_bytes().unwrap().chunks(7) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), whole);
+
+        let mut reader = ArmorReader::new(io::BufReader::with_capacity(3, Trickle { data: &whole, step: 5 }));
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_armor_reader_accepts_other_line_widths() {
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let body = codec::b64_std(&bytes);
+        let mut text = format!("\n{}\r\n", ARMOR_BEGIN);
+        for line in body.as_bytes().chunks(70) {
+            text.push_str(std::str::from_utf8(line).unwrap());
+            text.push_str("\r\n");
+        }
+        text.push_str(ARMOR_END);
+        let mut decoded = Vec::new();
+        ArmorReader::new(text.as_bytes()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, bytes);
+    }
+
+    #[test]
+    fn test_armor_reader_rejects_bad_armor() {
+        let read = |text: &str| ArmorReader::new(text.as_bytes()).read_to_end(&mut Vec::new());
+        let body = format!("{}\nAAAA\n", ARMOR_BEGIN);
+        assert!(read(&body).is_err());
+        assert!(read(&format!("{}{}\ntrailing\n", body, ARMOR_END)).is_err());
+        assert!(read(&format!("AAAA\n{}\n", ARMOR_END)).is_err());
+        assert!(read(&format!("{}\n{}\n{}\n", ARMOR_BEGIN, "A".repeat(MAX_ARMOR_LINE + 4), ARMOR_END)).is_err());
+        assert!(read(&format!("{}{}\n", body, ARMOR_END)).is_ok());
+    }
+}
@@ -0,0 +1,264 @@
+// Envelope encryption of per-file keys
+// Since container format v7 every container has its own random 32-byte file
+// key. The layer keys are derived from it, and the file key is stored
+// AES-256-GCM wrapped under a key-encryption key (KEK) derived from the
+// profile's master layer keys, with the key ID as associated data. Files
+// therefore share no layer keys, and destroying a file's wrapped key makes
+// that one file unrecoverable. File keys expand under the profile's
+// KdfScheme, so V1 profiles keep reading the containers they wrote.
+//
+// Encrypt-only key files hold no master key, so they cannot wrap under the
+// KEK. They hold the public half of an ML-KEM-768 keypair the master keys
+// give instead, and encapsulate each file key to it: the wrapped key is
+// then the KEM ciphertext followed by the file key sealed under the shared
+// secret. Only the master keys derive the private half, so only a key file
+// that can decrypt opens it. The two forms are told apart by length.
+
+use crate::crypto::drbg::{self, RandomSource};
+use crate::crypto::hkdf::{self, KdfScheme, KeyDerivation, KeyPurpose, LayerKeys};
+use crate::crypto::nonce;
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::tag;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::oqs_support;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use oqs::kem::{Algorithm, Kem};
+use serde::{Deserialize, Serialize};
+use sha3::Digest;
+
+/// Bytes in every file key
+pub const FILE_KEY_LEN: usize = 32;
+
+/// Bytes in an AES-GCM nonce
+pub const NONCE_LEN: usize = 12;
+
+/// Bytes of a wrapped file key: the key plus a 16-byte GCM tag
+pub const WRAPPED_LEN: usize = FILE_KEY_LEN + 16;
+
+/// Bytes of an ML-KEM-768 ciphertext
+const KEM_CIPHERTEXT_LEN: usize = 1088;
+
+/// Bytes of a file key encapsulated to an `EncapsulationKey`: the KEM
+/// ciphertext, then the sealed file key
+pub const ENCAPSULATED_LEN: usize = KEM_CIPHERTEXT_LEN + WRAPPED_LEN;
+
+/// Purpose wrap nonces are checked out under
+pub(crate) const WRAP_PURPOSE: &str = "file-key-wrap";
+
+/// Public key file keys are encapsulated to by key files that may not decrypt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncapsulationKey {
+    public_key: Vec<u8>,
+    /// Scheme the file keys expand under, the profile's own
+    scheme: KdfScheme,
+}
+
+impl EncapsulationKey {
+    /// The encapsulation key of the profile `master` belongs to
+    pub(crate) fn of(master: &LayerKeys) -> Result<Self> {
+        let (public_key, _) = keypair(master)?;
+        Ok(Self { public_key, scheme: master.scheme })
+    }
+    
+    /// An encapsulation key as stored, refusing a public key of the wrong length
+    pub(crate) fn from_parts(public_key: Vec<u8>, scheme: KdfScheme) -> Result<Self> {
+        if kem()?.public_key_from_bytes(&public_key).is_none() {
+            return Err(HybridGuardError::InvalidInput("encapsulation key has the wrong length".to_string()));
+        }
+        Ok(Self { public_key, scheme })
+    }
+    
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+    
+    pub fn scheme(&self) -> KdfScheme {
+        self.scheme
+    }
+}
+
+/// A file key sealed under the profile's key-encryption key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedFileKey {
+    /// Random per file
+    pub nonce: [u8; NONCE_LEN],
+    /// AES-256-GCM ciphertext and tag of the file key
+    pub ciphertext: Vec<u8>,
+}
+
+/// Fresh random file key
+pub fn generate_file_key() -> SecretBytes {
+    SecretBytes::new(rand::random::<[u8; FILE_KEY_LEN]>().to_vec())
+}
+
+/// Fresh file key drawn from `source`
+pub fn file_key_from(source: &dyn RandomSource) -> SecretBytes {
+    let mut file_key = vec![0u8; FILE_KEY_LEN];
+    source.fill(&mut file_key);
+    SecretBytes::new(file_key)
+}
+
+/// Layer keys of one file, derived from its file key under `scheme`
+pub fn file_layer_keys(file_key: &SecretBytes, scheme: KdfScheme) -> Result<LayerKeys> {
+    if file_key.len() != FILE_KEY_LEN {
+        return Err(HybridGuardError::InvalidInput(format!("a file key is {} bytes", FILE_KEY_LEN)));
+    }
+    KeyDerivation::with_scheme(file_key.to_vec(), scheme).derive_all_keys()
+}
+
+/// Seal `file_key` under the KEK of `master`, bound to `key_id`
+pub(crate) fn wrap(master: &LayerKeys, key_id: &str, file_key: &SecretBytes) -> Result<WrappedFileKey> {
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    nonce::checkout(key_id.as_bytes(), WRAP_PURPOSE, &nonce)?;
+    wrap_with_nonce(master, key_id, file_key, nonce)
+}
+
+/// `wrap` with a caller-chosen nonce; only reproducible fixtures and
+/// injected random sources need this, since reusing a nonce under one KEK
+/// breaks AES-GCM. Callers check the nonce out under `WRAP_PURPOSE`
+/// themselves, unless they repeat it on purpose
+pub(crate) fn wrap_with_nonce(
+    master: &LayerKeys,
+    key_id: &str,
+    file_key: &SecretBytes,
+    nonce: [u8; NONCE_LEN],
+) -> Result<WrappedFileKey> {
+    let ciphertext = kek(master)?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: file_key, aad: key_id.as_bytes() })
+        .map_err(|_| HybridGuardError::Encryption("could not wrap the file key".to_string()))?;
+    Ok(WrappedFileKey { nonce, ciphertext })
+}
+
+/// Seal `file_key` to `key`, bound to `key_id`, without any master key
+/// The nonce is the caller's; the KEM draws its own randomness, so the
+/// result is never reproducible
+pub(crate) fn encapsulate(key: &EncapsulationKey, key_id: &str, file_key: &SecretBytes, nonce: [u8; NONCE_LEN]) -> Result<WrappedFileKey> {
+    let kem = kem()?;
+    let public_key = kem
+        .public_key_from_bytes(&key.public_key)
+        .ok_or_else(|| HybridGuardError::KeyGeneration("encapsulation key is malformed".to_string()))?;
+    let (kem_ciphertext, shared_secret) = kem
+        .encapsulate(public_key)
+        .map_err(|e| HybridGuardError::Encryption(format!("encapsulating the file key failed: {}", e)))?;
+    let mut ciphertext = kem_ciphertext.into_vec();
+    let sealed = shared_secret_cipher(&SecretBytes::new(shared_secret.into_vec()), &ciphertext)?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: file_key, aad: key_id.as_bytes() })
+        .map_err(|_| HybridGuardError::Encryption("could not wrap the file key".to_string()))?;
+    ciphertext.extend_from_slice(&sealed);
+    Ok(WrappedFileKey { nonce, ciphertext })
+}
+
+/// Open a wrapped file key, whether wrapped under the KEK or encapsulated;
+/// fails on the wrong profile or any modification
+pub(crate) fn unwrap(master: &LayerKeys, key_id: &str, wrapped: &WrappedFileKey) -> Result<SecretBytes> {
+    let failed = || HybridGuardError::Integrity("file key failed to unwrap (wrong keys or modified data)".to_string());
+    let (cipher, sealed) = match wrapped.ciphertext.len() {
+        WRAPPED_LEN => (kek(master)?, &wrapped.ciphertext[..]),
+        ENCAPSULATED_LEN => {
+            let (kem_ciphertext, sealed) = wrapped.ciphertext.split_at(KEM_CIPHERTEXT_LEN);
+            let kem = kem()?;
+            let (_, secret_key) = keypair(master)?;
+            let secret_key = kem
+                .secret_key_from_bytes(&secret_key)
+                .ok_or_else(|| HybridGuardError::KeyGeneration("encapsulation private key has the wrong length".to_string()))?;
+            let shared_secret = kem
+                .decapsulate(secret_key, kem.ciphertext_from_bytes(kem_ciphertext).ok_or_else(failed)?)
+                .map_err(|_| failed())?;
+            (shared_secret_cipher(&SecretBytes::new(shared_secret.into_vec()), kem_ciphertext)?, sealed)
+        }
+        _ => return Err(failed()),
+    };
+    let file_key = cipher
+        .decrypt(Nonce::from_slice(&wrapped.nonce), Payload { msg: sealed, aad: key_id.as_bytes() })
+        .map_err(|_| failed())?;
+    Ok(SecretBytes::new(file_key))
+}
+
+/// AES-256-GCM keyed by a hash of the master keys' file-key-wrap key
+fn kek(master: &LayerKeys) -> Result<Aes256Gcm> {
+    let key = SecretBytes::new(tag::keyed_hasher(master, KeyPurpose::FileKeyWrap).finalize().to_vec());
+    Aes256Gcm::new_from_slice(&key).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))
+}
+
+/// AES-256-GCM keyed by one encapsulation's shared secret, bound to its KEM ciphertext
+/// Every file key is encapsulated afresh, so no key seals twice
+fn shared_secret_cipher(shared_secret: &SecretBytes, kem_ciphertext: &[u8]) -> Result<Aes256Gcm> {
+    let key = hkdf::derive(kem_ciphertext, shared_secret, KeyPurpose::FileKeyWrap, 32)?;
+    Aes256Gcm::new_from_slice(&key).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))
+}
+
+/// The ML-KEM-768 keypair of the profile `master` belongs to, derived
+/// through the seeded liboqs RNG so it is never stored
+fn keypair(master: &LayerKeys) -> Result<(Vec<u8>, SecretBytes)> {
+    let kem = kem()?;
+    let seed = master.derive_key(KeyPurpose::FileKeyEncapsulation);
+    let (public_key, secret_key) = drbg::with_seeded_oqs_rng(&seed, b"file-key-encapsulation-keypair", || kem.keypair())
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("deriving the encapsulation keypair failed: {}", e)))?;
+    Ok((public_key.into_vec(), SecretBytes::new(secret_key.into_vec())))
+}
+
+fn kem() -> Result<Kem> {
+    oqs_support::kem("file key encapsulation", Algorithm::Kyber768)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_round_trip_and_binding() {
+        let master = KeyDerivation::new(vec![0x21; 32]).derive_all_keys().unwrap();
+        let other = KeyDerivation::new(vec![0x22; 32]).derive_all_keys().unwrap();
+        let file_key = generate_file_key();
+
+        let wrapped = wrap(&master, "hg-a", &file_key).unwrap();
+        assert_eq!(wrapped.ciphertext.len(), WRAPPED_LEN);
+        assert_eq!(&unwrap(&master, "hg-a", &wrapped).unwrap()[..], &file_key[..]);
+
+        // Bound to the master keys and the key ID
+        assert!(matches!(unwrap(&other, "hg-a", &wrapped), Err(HybridGuardError::Integrity(_))));
+        assert!(matches!(unwrap(&master, "hg-b", &wrapped), Err(HybridGuardError::Integrity(_))));
+        let mut forged = wrapped.clone();
+        forged.ciphertext[0] ^= 1;
+        assert!(unwrap(&master, "hg-a", &forged).is_err());
+
+        // Same file key wraps differently every time
+        assert_ne!(wrap(&master, "hg-a", &file_key).unwrap(), wrapped);
+    }
+
+    #[test]
+    fn test_encapsulated_file_key_needs_the_master_keys() {
+        let master = KeyDerivation::new(vec![0x23; 32]).derive_all_keys().unwrap();
+        let other = KeyDerivation::new(vec![0x24; 32]).derive_all_keys().unwrap();
+        let key = EncapsulationKey::of(&master).unwrap();
+        assert_eq!(key, EncapsulationKey::of(&master).unwrap());
+        let file_key = generate_file_key();
+
+        let wrapped = encapsulate(&key, "hg-a", &file_key, [7; NONCE_LEN]).unwrap();
+        assert_eq!(wrapped.ciphertext.len(), ENCAPSULATED_LEN);
+        assert_eq!(&unwrap(&master, "hg-a", &wrapped).unwrap()[..], &file_key[..]);
+        assert!(matches!(unwrap(&other, "hg-a", &wrapped), Err(HybridGuardError::Integrity(_))));
+        assert!(matches!(unwrap(&master, "hg-b", &wrapped), Err(HybridGuardError::Integrity(_))));
+        let mut forged = wrapped.clone();
+        forged.ciphertext[ENCAPSULATED_LEN - 1] ^= 1;
+        assert!(unwrap(&master, "hg-a", &forged).is_err());
+    }
+
+    #[test]
+    fn test_file_keys_are_independent() {
+        let a = file_layer_keys(&generate_file_key(), KdfScheme::V2).unwrap();
+        let b = file_layer_keys(&generate_file_key(), KdfScheme::V2).unwrap();
+        assert_ne!(a.layer1_key, b.layer1_key);
+        assert!(file_layer_keys(&SecretBytes::new(vec![0; 16]), KdfScheme::V2).is_err());
+
+        // The scheme is part of the derivation and is carried forward
+        #[cfg(feature = "legacy-kdf-v1")]
+        {
+            let file_key = generate_file_key();
+            let v1 = file_layer_keys(&file_key, KdfScheme::V1).unwrap();
+            assert_eq!(v1.scheme, KdfScheme::V1);
+            assert_ne!(v1.layer1_key, file_layer_keys(&file_key, KdfScheme::V2).unwrap().layer1_key);
+        }
+    }
+}
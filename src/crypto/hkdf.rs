@@ -1,114 +1,485 @@
-// HKDF (HMAC-based Key Derivation Function) implementation
-// Used to derive independent keys for each encryption layer
+// HKDF (RFC 5869) over SHA3-256, and the key derivations built on it
+// Under KdfScheme::V2 every internal key is HKDF-Expand of a pseudorandom key
+// with the info string of its KeyPurpose, so no two uses share a key and new
+// features add a purpose instead of inventing a derivation. KdfScheme::V1 is
+// the original SHA3 concatenation, read by `legacy::kdf_v1` so key files,
+// containers and escrow blobs made with it still open.
 
 use sha3::{Sha3_256, Digest};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use crate::crypto::secret::SecretBytes;
 use crate::error::{HybridGuardError, Result};
+use crate::layers::{self, EncryptionLayer};
+use crate::legacy;
 
-/// Derives multiple independent keys from a master key using HKDF
+/// Info string of each layer key, followed by the layer number
+pub const LAYER_INFO_PREFIX: &str = "HybridGuard-Layer-";
+
+/// Length of every derived layer key
+pub const LAYER_KEY_LEN: usize = 32;
+
+/// SHA3-256 output length, and the length of every pseudorandom key
+pub const HASH_LEN: usize = 32;
+
+/// SHA3-256 block size (its sponge rate), which HMAC pads keys to
+const BLOCK_LEN: usize = 136;
+
+/// Longest output one HKDF-Expand may produce
+pub const MAX_OUTPUT_LEN: usize = 255 * HASH_LEN;
+
+/// Extract salt of V2 derivations from a master key or layer keys
+pub const V2_SALT: &[u8] = b"HybridGuard-KDF-v2";
+
+/// Prefix of every V2 info string
+pub const V2_INFO_PREFIX: &str = "HybridGuard-v2 ";
+
+/// How a key file's keys, and every key derived from them, were derived
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfScheme {
+    /// SHA3-256 over a label and the key material (key files before `kdf` was recorded)
+    #[default]
+    V1,
+    /// HKDF-SHA3-256 with one info string per `KeyPurpose`
+    V2,
+}
+
+impl KdfScheme {
+    /// Scheme of newly generated keys
+    pub const CURRENT: KdfScheme = KdfScheme::V2;
+}
+
+impl fmt::Display for KdfScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KdfScheme::V1 => "v1",
+            KdfScheme::V2 => "v2",
+        })
+    }
+}
+
+impl FromStr for KdfScheme {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "v1" => Ok(KdfScheme::V1),
+            "v2" => Ok(KdfScheme::V2),
+            other => Err(HybridGuardError::InvalidInput(format!("unknown KDF scheme '{}' (expected v1 or v2)", other))),
+        }
+    }
+}
+
+/// What a derived key is for; each purpose has its own info string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyPurpose {
+    /// Key of encryption layer n
+    Layer(u8),
+    /// Authentication tag key of one kind of data, e.g. "container-tag"
+    Mac(&'static str),
+    /// Key-encryption key of per-file keys
+    FileKeyWrap,
+    /// Deterministic nonce material
+    Nonce,
+    /// Master key agreed between two parties
+    Session,
+    /// Key for recognizing duplicate plaintexts without revealing them
+    Dedup,
+    /// Sealing key of an escrow blob
+    EscrowSeal,
+    /// Wrapping key of a protected key file
+    KeyFileWrap,
+    /// Seed of an ephemeral KEM keypair
+    KeypairSeed,
+    /// Seed of the keypair a key file is self-signed with
+    KeyFileSigning,
+    /// Sealing key of a container's private metadata
+    MetadataSeal,
+    /// Keystream key hiding the kind and length of shaped stream frames
+    FrameSeal,
+    /// Seed of the KEM keypair encrypt-only key files encapsulate file keys to
+    FileKeyEncapsulation,
+}
+
+impl KeyPurpose {
+    /// HKDF info string under V2
+    pub fn info(&self) -> Vec<u8> {
+        let name: Cow<'_, str> = match self {
+            KeyPurpose::Layer(n) => format!("layer {}", n).into(),
+            KeyPurpose::Mac(domain) => format!("mac {}", domain).into(),
+            KeyPurpose::FileKeyWrap => "file-key-wrap".into(),
+            KeyPurpose::Nonce => "nonce".into(),
+            KeyPurpose::Session => "session".into(),
+            KeyPurpose::Dedup => "dedup".into(),
+            KeyPurpose::EscrowSeal => "escrow-seal".into(),
+            KeyPurpose::KeyFileWrap => "key-file-wrap".into(),
+            KeyPurpose::KeypairSeed => "keypair-seed".into(),
+            KeyPurpose::KeyFileSigning => "key-file-signing".into(),
+            KeyPurpose::MetadataSeal => "metadata-seal".into(),
+            KeyPurpose::FrameSeal => "frame-seal".into(),
+            KeyPurpose::FileKeyEncapsulation => "file-key-encapsulation".into(),
+        };
+        format!("{}{}", V2_INFO_PREFIX, name).into_bytes()
+    }
+
+    /// Domain label that V1 hashed ahead of the layer keys
+    #[cfg_attr(not(feature = "legacy-kdf-v1"), allow(dead_code))]
+    fn v1_label(&self) -> Vec<u8> {
+        match self {
+            KeyPurpose::Mac(domain) => format!("HybridGuard-{}-key", domain).into_bytes(),
+            KeyPurpose::FileKeyWrap => b"HybridGuard-file-key-wrap".to_vec(),
+            KeyPurpose::KeypairSeed => b"HybridGuard-pair-offer-key".to_vec(),
+            // Purposes without a V1 site get labels no V1 site used
+            other => format!("HybridGuard-v1 {:?}", other).into_bytes(),
+        }
+    }
+}
+
+/// HMAC-SHA3-256 of the concatenated `parts`
+pub fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..HASH_LEN].copy_from_slice(&Sha3_256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha3_256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha3_256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    zeroize::Zeroize::zeroize(&mut block);
+    outer.finalize().into()
+}
+
+/// HKDF-Extract: a pseudorandom key from input keying material
+/// An empty salt stands for HASH_LEN zero bytes, as in RFC 5869
+pub fn extract(salt: &[u8], ikm: &[u8]) -> SecretBytes {
+    let salt = if salt.is_empty() { &[0u8; HASH_LEN][..] } else { salt };
+    SecretBytes::new(hmac(salt, &[ikm]).to_vec())
+}
+
+/// HKDF-Expand: `len` bytes of output keying material for `info`
+pub fn expand(prk: &[u8], info: &[u8], len: usize) -> Result<SecretBytes> {
+    if len > MAX_OUTPUT_LEN {
+        return Err(HybridGuardError::KeyGeneration(format!(
+            "HKDF output is limited to {} bytes, {} requested",
+            MAX_OUTPUT_LEN, len
+        )));
+    }
+    let mut okm = Vec::with_capacity(len + HASH_LEN);
+    let mut block: [u8; HASH_LEN] = [0; HASH_LEN];
+    for counter in 1..=len.div_ceil(HASH_LEN) {
+        let previous: &[u8] = if counter == 1 { &[] } else { &block };
+        block = hmac(prk, &[previous, info, &[counter as u8]]);
+        okm.extend_from_slice(&block);
+    }
+    zeroize::Zeroize::zeroize(&mut block);
+    okm.truncate(len);
+    Ok(SecretBytes::new(okm))
+}
+
+/// Extract then expand a key for `purpose`
+pub fn derive(salt: &[u8], ikm: &[u8], purpose: KeyPurpose, len: usize) -> Result<SecretBytes> {
+    expand(&extract(salt, ikm), &purpose.info(), len)
+}
+
+/// Derives multiple independent keys from a master key
 pub struct KeyDerivation {
     master_key: Vec<u8>,
+    scheme: KdfScheme,
 }
 
 impl KeyDerivation {
-    /// Create a new key derivation instance with a master key
+    /// Derive from `master_key` under the current scheme
     pub fn new(master_key: Vec<u8>) -> Self {
-        Self { master_key }
+        Self::with_scheme(master_key, KdfScheme::CURRENT)
     }
-    
-    /// Generate a master key from a password
+
+    /// Derive from `master_key` under `scheme`
+    pub fn with_scheme(master_key: Vec<u8>, scheme: KdfScheme) -> Self {
+        Self { master_key, scheme }
+    }
+
+    /// Generate a master key from a password under the current scheme
     pub fn from_password(password: &str, salt: &[u8]) -> Self {
-        let mut hasher = Sha3_256::new();
-        hasher.update(password.as_bytes());
-        hasher.update(salt);
-        let master_key = hasher.finalize().to_vec();
-        
-        Self { master_key }
-    }
-    
+        Self::from_password_with(password, salt, KdfScheme::CURRENT)
+    }
+
+    /// Generate a master key from a password under `scheme`
+    pub fn from_password_with(password: &str, salt: &[u8], scheme: KdfScheme) -> Self {
+        let master_key = match scheme {
+            #[cfg(feature = "legacy-kdf-v1")]
+            KdfScheme::V1 => legacy::kdf_v1::master_key(password, salt),
+            // Without the V1 reader no layer key is ever derived from it
+            #[cfg(not(feature = "legacy-kdf-v1"))]
+            KdfScheme::V1 => Vec::new(),
+            KdfScheme::V2 => extract(salt, password.as_bytes()).to_vec(),
+        };
+
+        Self { master_key, scheme }
+    }
+
+    pub fn scheme(&self) -> KdfScheme {
+        self.scheme
+    }
+
     /// Derive a key for a specific layer
     /// Each layer gets a unique key derived from the master key
     pub fn derive_layer_key(&self, layer_id: u8, key_size: usize) -> Result<Vec<u8>> {
-        // Create unique info for this layer
-        let info = format!("HybridGuard-Layer-{}", layer_id);
-        
-        // Use HKDF to derive the key
-        let mut hasher = Sha3_256::new();
-        hasher.update(&self.master_key);
-        hasher.update(info.as_bytes());
-        hasher.update(&[layer_id]);
-        
-        let derived = hasher.finalize();
-        
-        // Expand to desired key size if needed
-        if key_size <= 32 {
-            Ok(derived[..key_size].to_vec())
-        } else {
-            // For larger keys, do multiple rounds
-            let mut result = Vec::new();
-            let mut counter = 0u8;
-            
-            while result.len() < key_size {
-                let mut hasher = Sha3_256::new();
-                hasher.update(&derived);
-                hasher.update(&[counter]);
-                result.extend_from_slice(&hasher.finalize());
-                counter += 1;
+        match self.scheme {
+            KdfScheme::V2 => Ok(derive(V2_SALT, &self.master_key, KeyPurpose::Layer(layer_id), key_size)?.to_vec()),
+            #[cfg(feature = "legacy-kdf-v1")]
+            KdfScheme::V1 => {
+                use legacy::LegacyReader;
+                legacy::kdf_v1::LayerKey { layer_id, key_size }.read(&self.master_key)
             }
-            
-            Ok(result[..key_size].to_vec())
+            #[cfg(not(feature = "legacy-kdf-v1"))]
+            KdfScheme::V1 => Err(legacy::dropped("KDF scheme v1", "legacy-kdf-v1")),
         }
     }
-    
-    /// Derive all four layer keys at once
+
+    /// Derive all four layer keys at once, sized for the built-in layers
     pub fn derive_all_keys(&self) -> Result<LayerKeys> {
-        Ok(LayerKeys {
-            layer1_key: self.derive_layer_key(1, 32)?,  // ML-KEM key
-            layer2_key: self.derive_layer_key(2, 32)?,  // HQC key
-            layer3_key: self.derive_layer_key(3, 32)?,  // Quantum noise key
-            layer4_key: self.derive_layer_key(4, 32)?,  // FHE key
-        })
+        let registry = layers::registry();
+        let stack: Vec<&dyn EncryptionLayer> = registry.iter().map(|layer| layer.as_ref()).collect();
+        self.derive_keys_for(&stack, LAYER_KEY_LEN)
+    }
+
+    /// Derive the four layer keys at `key_len` bytes each for the layers of
+    /// `stack`, failing with `KeyTooShort` if one of them requires more
+    pub fn derive_keys_for(&self, stack: &[&dyn EncryptionLayer], key_len: usize) -> Result<LayerKeys> {
+        let keys = LayerKeys {
+            layer1_key: SecretBytes::new(self.derive_layer_key(1, key_len)?),  // ML-KEM key
+            layer2_key: SecretBytes::new(self.derive_layer_key(2, key_len)?),  // HQC key
+            layer3_key: SecretBytes::new(self.derive_layer_key(3, key_len)?),  // Quantum noise key
+            layer4_key: SecretBytes::new(self.derive_layer_key(4, key_len)?),  // FHE key
+            scheme: self.scheme,
+        };
+        layers::check_key_lens(stack, &keys.in_order().map(|key| key.as_bytes()))?;
+        Ok(keys)
     }
 }
 
 /// Container for all layer keys
 #[derive(Debug, Clone)]
 pub struct LayerKeys {
-    pub layer1_key: Vec<u8>,  // ML-KEM (Lattice-based)
-    pub layer2_key: Vec<u8>,  // HQC (Code-based)
-    pub layer3_key: Vec<u8>,  // Quantum Noise
-    pub layer4_key: Vec<u8>,  // Homomorphic Encryption
+    pub layer1_key: SecretBytes,  // ML-KEM (Lattice-based)
+    pub layer2_key: SecretBytes,  // HQC (Code-based)
+    pub layer3_key: SecretBytes,  // Quantum Noise
+    pub layer4_key: SecretBytes,  // Homomorphic Encryption
+    /// Scheme of every key derived from these keys
+    pub scheme: KdfScheme,
+}
+
+impl LayerKeys {
+    /// The four keys in pipeline order, matching `layers::registry()`
+    pub fn in_order(&self) -> [&SecretBytes; 4] {
+        [&self.layer1_key, &self.layer2_key, &self.layer3_key, &self.layer4_key]
+    }
+
+    /// Whether all four keys are locked into RAM
+    pub fn all_locked(&self) -> bool {
+        self.layer1_key.is_locked()
+            && self.layer2_key.is_locked()
+            && self.layer3_key.is_locked()
+            && self.layer4_key.is_locked()
+    }
+
+    /// 32-byte key for `purpose`, derived from all four layer keys
+    pub fn derive_key(&self, purpose: KeyPurpose) -> SecretBytes {
+        let material: Vec<&[u8]> = self.in_order().iter().map(|key| key.as_bytes()).collect();
+        match self.scheme {
+            #[cfg(feature = "legacy-kdf-v1")]
+            KdfScheme::V1 => legacy::kdf_v1::purpose_key(&purpose.v1_label(), &material),
+            // Key files and derivations refuse V1 without its reader, so only
+            // hand-built keys get here; they get V2 keys, which open nothing V1 wrote
+            #[cfg(not(feature = "legacy-kdf-v1"))]
+            KdfScheme::V1 => LayerKeys { scheme: KdfScheme::V2, ..self.clone() }.derive_key(purpose),
+            KdfScheme::V2 => {
+                // Extract over the concatenated keys, then the single block
+                // of HKDF-Expand that a 32-byte output needs
+                let prk = SecretBytes::new(hmac(V2_SALT, &material).to_vec());
+                SecretBytes::new(hmac(&prk, &[&purpose.info(), &[1]]).to_vec())
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+    use std::collections::HashSet;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        crate::crypto::codec::hex_lower_decode(hex).unwrap()
+    }
+
     #[test]
     fn test_key_derivation() {
         let master_key = vec![0u8; 32];
         let kd = KeyDerivation::new(master_key);
-        
+
         let key1 = kd.derive_layer_key(1, 32).unwrap();
         let key2 = kd.derive_layer_key(2, 32).unwrap();
-        
+
         // Keys should be different
         assert_ne!(key1, key2);
-        
+
         // Keys should be deterministic
         let key1_again = kd.derive_layer_key(1, 32).unwrap();
         assert_eq!(key1, key1_again);
     }
-    
+
     #[test]
     fn test_derive_all_keys() {
         let master_key = vec![0u8; 32];
         let kd = KeyDerivation::new(master_key);
-        
+
         let keys = kd.derive_all_keys().unwrap();
-        
+
         // All keys should be different
         assert_ne!(keys.layer1_key, keys.layer2_key);
         assert_ne!(keys.layer2_key, keys.layer3_key);
         assert_ne!(keys.layer3_key, keys.layer4_key);
     }
+
+    #[test]
+    fn test_derived_keys_must_fit_the_stack() {
+        let kd = KeyDerivation::new(vec![0u8; 32]);
+        let registry = layers::registry();
+        let stack: Vec<&dyn EncryptionLayer> = registry.iter().map(|layer| layer.as_ref()).collect();
+        assert!(kd.derive_keys_for(&stack, 64).is_ok());
+        match kd.derive_keys_for(&stack, 16) {
+            Err(HybridGuardError::KeyTooShort(message)) => assert!(message.starts_with("layer 1 (ML-KEM"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+
+        // A stack of the wrong depth is refused too
+        assert!(kd.derive_keys_for(&stack[..3], LAYER_KEY_LEN).is_err());
+    }
+
+    /// RFC 5869 test cases 1-3 with SHA3-256 in place of SHA-256; outputs
+    /// generated independently with Python's hmac and hashlib.sha3_256
+    #[test]
+    fn test_hkdf_sha3_vectors() {
+        let cases = [
+            (
+                vec![0x0b; 22],
+                (0x00..=0x0c).collect::<Vec<u8>>(),
+                (0xf0..=0xf9).collect::<Vec<u8>>(),
+                42,
+                "7d4194836f7a113a44677abc825640ade07af1c1d69a9a4b109b280a8fe54ef0",
+                "0c5160501d65021deaf2c14f5abce04c5bd2635abceeba61c2edb6e8ed72674900557728f2c9f2c4c179",
+            ),
+            (
+                (0x00..=0x4f).collect(),
+                (0x60..=0xaf).collect(),
+                (0xb0..=0xff).collect(),
+                82,
+                "addf31835b49366ac27734104d9f1865c1c2e7c8a2ebc1fed712808e4eab677c",
+                "3dc251e66c75da6560405ec5ac10e17d851eedfbfdc13feafbec16964c25d021bd971465a3e9c615f27769019e3f0407d84986fb0ba24e729c99834624baa21cb623dc0098f430d52e18bbdf694df4edd8b2",
+            ),
+            (
+                vec![0x0b; 22],
+                Vec::new(),
+                Vec::new(),
+                42,
+                "b899e6e4b88a35f9f5d618f48b424c313f9704012763eb6295414d673365928a",
+                "bc1342cdd75c05e8b0c3ae609ce4410684d197232875073499b30cdfe2de2853c1c1bed63d725e885e78",
+            ),
+        ];
+        for (ikm, salt, info, len, prk, okm) in cases {
+            let extracted = extract(&salt, &ikm);
+            assert_eq!(extracted.to_vec(), unhex(prk));
+            assert_eq!(expand(&extracted, &info, len).unwrap().to_vec(), unhex(okm));
+        }
+        assert!(expand(&[0; HASH_LEN], b"", MAX_OUTPUT_LEN + 1).is_err());
+    }
+
+    #[test]
+    fn test_v2_layer_key_is_pinned() {
+        let key = KeyDerivation::with_scheme(vec![0u8; 32], KdfScheme::V2).derive_layer_key(1, 32).unwrap();
+        assert_eq!(key, unhex("ba6bd270546c0acd9e2040dc8d2614b3cd40fd5e5fa782f5f5baa706bb041dca"));
+
+        // V1 keys are unchanged, so existing key files and containers still open
+        #[cfg(feature = "legacy-kdf-v1")]
+        {
+            let v1 = KeyDerivation::with_scheme(vec![0u8; 32], KdfScheme::V1);
+            let mut legacy = Sha3_256::new();
+            legacy.update([0u8; 32]);
+            legacy.update(b"HybridGuard-Layer-1");
+            legacy.update([1u8]);
+            assert_eq!(v1.derive_layer_key(1, 32).unwrap(), legacy.finalize().to_vec());
+        }
+        #[cfg(not(feature = "legacy-kdf-v1"))]
+        assert!(matches!(
+            KeyDerivation::with_scheme(vec![0u8; 32], KdfScheme::V1).derive_all_keys(),
+            Err(HybridGuardError::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_purposes_never_collide() {
+        let purposes = [
+            KeyPurpose::Layer(1),
+            KeyPurpose::Layer(2),
+            KeyPurpose::Layer(3),
+            KeyPurpose::Layer(4),
+            KeyPurpose::Mac("container-tag"),
+            KeyPurpose::Mac("stream-tag"),
+            KeyPurpose::Mac("chunked-tag"),
+            KeyPurpose::FileKeyWrap,
+            KeyPurpose::Nonce,
+            KeyPurpose::Session,
+            KeyPurpose::Dedup,
+            KeyPurpose::EscrowSeal,
+            KeyPurpose::KeyFileWrap,
+            KeyPurpose::KeypairSeed,
+            KeyPurpose::KeyFileSigning,
+            KeyPurpose::MetadataSeal,
+            KeyPurpose::FrameSeal,
+            KeyPurpose::FileKeyEncapsulation,
+        ];
+        let infos: HashSet<Vec<u8>> = purposes.iter().map(KeyPurpose::info).collect();
+        assert_eq!(infos.len(), purposes.len());
+
+        for scheme in [KdfScheme::V1, KdfScheme::V2] {
+            if legacy::require_kdf(scheme).is_err() {
+                continue;
+            }
+            let keys = KeyDerivation::with_scheme(vec![0x42; 32], scheme).derive_all_keys().unwrap();
+            let derived: HashSet<Vec<u8>> = purposes.iter().map(|&p| keys.derive_key(p).to_vec()).collect();
+            assert_eq!(derived.len(), purposes.len(), "{}", scheme);
+
+            let masters: HashSet<Vec<u8>> = purposes
+                .iter()
+                .map(|&p| derive(V2_SALT, &[0x42; 32], p, 32).unwrap().to_vec())
+                .collect();
+            assert_eq!(masters.len(), purposes.len());
+        }
+
+        // The same purpose under the two schemes gives different keys
+        #[cfg(feature = "legacy-kdf-v1")]
+        {
+            let v1 = KeyDerivation::with_scheme(vec![0x42; 32], KdfScheme::V1).derive_all_keys().unwrap();
+            let v2 = KeyDerivation::with_scheme(vec![0x42; 32], KdfScheme::V2).derive_all_keys().unwrap();
+            assert_ne!(v1.layer1_key, v2.layer1_key);
+        }
+    }
+
+    #[test]
+    fn test_v1_labels_match_legacy_sites() {
+        assert_eq!(KeyPurpose::Mac("container-tag").v1_label(), b"HybridGuard-container-tag-key");
+        assert_eq!(KeyPurpose::FileKeyWrap.v1_label(), b"HybridGuard-file-key-wrap");
+        assert_eq!("v2".parse::<KdfScheme>().unwrap(), KdfScheme::V2);
+        assert!("v3".parse::<KdfScheme>().is_err());
+    }
 }
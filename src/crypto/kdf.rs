@@ -0,0 +1,352 @@
+// Argon2id password stretching and benchmarking of its parameters
+// `tune` measures Argon2id on this machine and searches for the memory and
+// iteration counts that take a target time. Memory grows first, iterations
+// only once memory reaches its cap, and nothing below the memory floor is
+// ever chosen, however slow the machine. Timing goes through `KdfTimer`, so
+// the search can be tested against made-up machines.
+
+use crate::error::{HybridGuardError, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Least memory any parameters may use, in KiB (OWASP's Argon2id minimum)
+pub const MIN_MEMORY_KIB: u32 = 19 * 1024;
+
+/// Most memory any parameters may use, in KiB, so a parameter file or key
+/// file cannot make loading it allocate without bound
+pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// Most iterations any parameters may run
+pub const MAX_ITERATIONS: u32 = 256;
+
+/// Memory is chosen in whole MiB
+const MEMORY_STEP_KIB: u32 = 1024;
+
+/// Relative distance from the target that counts as hitting it
+pub const TOLERANCE: f64 = 0.1;
+
+/// Most parameter sets tried after the floor
+const MAX_ROUNDS: usize = 8;
+
+/// Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Load parameters saved by `key tune --save`, refusing weak ones
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)?;
+        let params: Self = serde_json::from_str(&json)
+            .map_err(|e| HybridGuardError::InvalidInput(format!("{}: invalid KDF parameters: {}", path.display(), e)))?;
+        params.validate()?;
+        Ok(params)
+    }
+
+    /// Refuse parameters below the memory floor, with a zero cost, or above
+    /// `MAX_MEMORY_KIB` or `MAX_ITERATIONS`
+    pub fn validate(&self) -> Result<()> {
+        if self.memory_kib < MIN_MEMORY_KIB {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "Argon2 memory {} KiB is below the {} KiB floor",
+                self.memory_kib, MIN_MEMORY_KIB
+            )));
+        }
+        if self.memory_kib > MAX_MEMORY_KIB {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "Argon2 memory {} KiB is above the {} KiB cap",
+                self.memory_kib, MAX_MEMORY_KIB
+            )));
+        }
+        if self.iterations == 0 || self.parallelism == 0 {
+            return Err(HybridGuardError::InvalidInput("Argon2 iterations and parallelism must be at least 1".to_string()));
+        }
+        if self.iterations > MAX_ITERATIONS {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "Argon2 iterations {} are above the cap of {}",
+                self.iterations, MAX_ITERATIONS
+            )));
+        }
+        Ok(())
+    }
+
+    /// Argon2id of `password` under `salt` (at least 8 bytes)
+    pub fn hash(&self, password: &[u8], salt: &[u8]) -> Result<[u8; 32]> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| HybridGuardError::InvalidInput(format!("invalid Argon2 parameters: {}", e)))?;
+        let mut out = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, salt, &mut out)
+            .map_err(|e| HybridGuardError::KeyGeneration(format!("Argon2 failed: {}", e)))?;
+        Ok(out)
+    }
+
+    /// Memory times iterations, which Argon2's running time follows
+    fn cost(&self) -> f64 {
+        self.memory_kib as f64 * self.iterations as f64
+    }
+
+    /// The parameters whose cost is `ratio` times this one's, within `limits`
+    fn scaled(&self, ratio: f64, limits: &TuneLimits) -> Self {
+        let cost = self.cost() * ratio;
+        let memory = (cost / limits.min_iterations as f64).clamp(limits.min_memory_kib as f64, limits.max_memory_kib as f64);
+        let memory_kib = (memory as u32 / MEMORY_STEP_KIB * MEMORY_STEP_KIB).max(limits.min_memory_kib);
+        let iterations = (cost / memory_kib as f64).clamp(limits.min_iterations as f64, limits.max_iterations as f64) as u32;
+        Self { memory_kib, iterations, parallelism: self.parallelism }
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Argon2id m={} MiB, t={}, p={}", self.memory_kib / 1024, self.iterations, self.parallelism)
+    }
+}
+
+/// Salt bytes of a passphrase-only container
+pub const PASSPHRASE_SALT_LEN: usize = 16;
+
+/// Salt and Argon2id cost a passphrase-only container was encrypted under,
+/// stored in the container so the passphrase alone opens it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PassphraseKdf {
+    pub salt: [u8; PASSPHRASE_SALT_LEN],
+    pub params: KdfParams,
+}
+
+impl PassphraseKdf {
+    /// Master key of `passphrase`, refusing parameters below the floor so a
+    /// forged container cannot make the key cheap to guess
+    pub fn master_key(&self, passphrase: &str) -> Result<[u8; 32]> {
+        self.params.validate()?;
+        self.params.hash(passphrase.as_bytes(), &self.salt)
+    }
+}
+
+/// Bounds of the parameter search
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TuneLimits {
+    /// Memory floor; never below `MIN_MEMORY_KIB`
+    pub min_memory_kib: u32,
+    pub max_memory_kib: u32,
+    pub min_iterations: u32,
+    pub max_iterations: u32,
+    pub parallelism: u32,
+    /// Timed runs per parameter set, after one discarded warm-up run
+    pub samples: usize,
+}
+
+impl Default for TuneLimits {
+    fn default() -> Self {
+        Self {
+            min_memory_kib: 64 * 1024,
+            max_memory_kib: 1024 * 1024,
+            min_iterations: 2,
+            max_iterations: 64,
+            parallelism: 1,
+            samples: 3,
+        }
+    }
+}
+
+impl TuneLimits {
+    fn validate(&self) -> Result<()> {
+        KdfParams { memory_kib: self.min_memory_kib, iterations: self.min_iterations, parallelism: self.parallelism }.validate()?;
+        KdfParams { memory_kib: self.max_memory_kib, iterations: self.max_iterations, parallelism: self.parallelism }.validate()?;
+        if self.max_memory_kib < self.min_memory_kib || self.max_iterations < self.min_iterations {
+            return Err(HybridGuardError::InvalidInput("Argon2 limits: a maximum is below its minimum".to_string()));
+        }
+        if self.samples == 0 {
+            return Err(HybridGuardError::InvalidInput("Argon2 limits: at least one sample is needed".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Parameters chosen by `tune` and how long they took here
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    pub params: KdfParams,
+    /// Median time of the chosen parameters
+    pub measured: Duration,
+    pub target: Duration,
+}
+
+impl Tuning {
+    /// Whether even the floor takes longer than the target on this machine
+    pub fn floor_exceeds_target(&self) -> bool {
+        self.measured.as_secs_f64() > self.target.as_secs_f64() * (1.0 + TOLERANCE)
+    }
+}
+
+/// Times one Argon2 run
+pub trait KdfTimer {
+    fn time(&mut self, params: &KdfParams) -> Result<Duration>;
+}
+
+/// Times real Argon2id runs on this machine
+pub struct Argon2Timer;
+
+impl KdfTimer for Argon2Timer {
+    fn time(&mut self, params: &KdfParams) -> Result<Duration> {
+        let start = Instant::now();
+        params.hash(b"HybridGuard KDF benchmark", b"benchmark salt")?;
+        Ok(start.elapsed())
+    }
+}
+
+/// Find the Argon2id parameters that take about `target` on this machine
+pub fn tune(target: Duration, limits: TuneLimits) -> Result<Tuning> {
+    tune_with(&mut Argon2Timer, target, limits)
+}
+
+/// `tune` with the runs timed by `timer`
+pub fn tune_with(timer: &mut dyn KdfTimer, target: Duration, limits: TuneLimits) -> Result<Tuning> {
+    limits.validate()?;
+    if target.is_zero() {
+        return Err(HybridGuardError::InvalidInput("KDF target time must be above zero".to_string()));
+    }
+
+    // The first run pays for page faults and cold caches; never count it
+    let floor = KdfParams {
+        memory_kib: limits.min_memory_kib.div_ceil(MEMORY_STEP_KIB) * MEMORY_STEP_KIB,
+        iterations: limits.min_iterations,
+        parallelism: limits.parallelism,
+    };
+    timer.time(&floor)?;
+
+    let mut params = floor;
+    let mut measured = median(timer, &params, limits.samples)?;
+    let mut best = Tuning { params, measured, target };
+    let fits = |measured: Duration| measured.as_secs_f64() <= target.as_secs_f64() * (1.0 + TOLERANCE);
+    for _ in 0..MAX_ROUNDS {
+        let ratio = target.as_secs_f64() / measured.as_secs_f64().max(f64::MIN_POSITIVE);
+        if (ratio - 1.0).abs() <= TOLERANCE {
+            break;
+        }
+        let next = params.scaled(ratio, &limits);
+        if next == params {
+            break;
+        }
+        params = next;
+        measured = median(timer, &params, limits.samples)?;
+        // The floor stands even when it misses the target; anything above it must fit
+        if fits(measured) && (params.cost() > best.params.cost() || !fits(best.measured)) {
+            best = Tuning { params, measured, target };
+        }
+    }
+    Ok(best)
+}
+
+/// Median of `samples` timed runs, so one noisy run cannot steer the search
+fn median(timer: &mut dyn KdfTimer, params: &KdfParams, samples: usize) -> Result<Duration> {
+    let mut times = (0..samples).map(|_| timer.time(params)).collect::<Result<Vec<_>>>()?;
+    times.sort();
+    Ok(times[times.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A machine where Argon2 takes `nanos_per_kib` per KiB per iteration,
+    /// with its first run `first_run_penalty` times slower
+    struct FakeMachine {
+        nanos_per_kib: f64,
+        first_run_penalty: f64,
+        runs: usize,
+    }
+
+    impl FakeMachine {
+        fn new(nanos_per_kib: f64) -> Self {
+            Self { nanos_per_kib, first_run_penalty: 1.0, runs: 0 }
+        }
+    }
+
+    impl KdfTimer for FakeMachine {
+        fn time(&mut self, params: &KdfParams) -> Result<Duration> {
+            let penalty = if self.runs == 0 { self.first_run_penalty } else { 1.0 };
+            self.runs += 1;
+            Ok(Duration::from_secs_f64(params.cost() * self.nanos_per_kib * penalty / 1e9))
+        }
+    }
+
+    fn within_tolerance(tuning: &Tuning) -> bool {
+        let ratio = tuning.measured.as_secs_f64() / tuning.target.as_secs_f64();
+        (ratio - 1.0).abs() <= TOLERANCE
+    }
+
+    #[test]
+    fn test_search_converges_on_memory_first() {
+        // 64 MiB x 2 passes takes 13 ms here; 50 ms needs about 4x the cost
+        let mut machine = FakeMachine::new(100.0);
+        let tuning = tune_with(&mut machine, Duration::from_millis(50), TuneLimits::default()).unwrap();
+        assert!(within_tolerance(&tuning), "{:?}", tuning);
+        assert_eq!(tuning.params.iterations, 2);
+        assert!(tuning.params.memory_kib > 64 * 1024);
+        assert_eq!(tuning.params.memory_kib % 1024, 0);
+        assert!(machine.runs < 1 + 3 * (MAX_ROUNDS + 1));
+    }
+
+    #[test]
+    fn test_noisy_first_run_is_discarded() {
+        let quiet = tune_with(&mut FakeMachine::new(100.0), Duration::from_millis(50), TuneLimits::default()).unwrap();
+        let mut noisy = FakeMachine::new(100.0);
+        noisy.first_run_penalty = 50.0;
+        assert_eq!(tune_with(&mut noisy, Duration::from_millis(50), TuneLimits::default()).unwrap(), quiet);
+    }
+
+    #[test]
+    fn test_memory_cap_moves_cost_to_iterations() {
+        let limits = TuneLimits { max_memory_kib: 128 * 1024, ..TuneLimits::default() };
+        let tuning = tune_with(&mut FakeMachine::new(10.0), Duration::from_millis(20), limits).unwrap();
+        assert!(within_tolerance(&tuning), "{:?}", tuning);
+        assert_eq!(tuning.params.memory_kib, 128 * 1024);
+        assert!(tuning.params.iterations > 2);
+    }
+
+    #[test]
+    fn test_slow_machine_gets_the_floor() {
+        // The floor takes 1.3 s, far over a 5 ms target, and is still chosen
+        let tuning = tune_with(&mut FakeMachine::new(10_000.0), Duration::from_millis(5), TuneLimits::default()).unwrap();
+        assert_eq!(tuning.params, KdfParams { memory_kib: 64 * 1024, iterations: 2, parallelism: 1 });
+        assert!(tuning.floor_exceeds_target());
+    }
+
+    #[test]
+    fn test_limits_below_the_floor_are_refused() {
+        let limits = TuneLimits { min_memory_kib: 8 * 1024, ..TuneLimits::default() };
+        assert!(tune_with(&mut FakeMachine::new(1.0), Duration::from_millis(5), limits).is_err());
+        assert!(KdfParams { memory_kib: 1024, iterations: 1, parallelism: 1 }.validate().is_err());
+        let inverted = TuneLimits { max_memory_kib: 32 * 1024, ..TuneLimits::default() };
+        assert!(tune_with(&mut FakeMachine::new(1.0), Duration::from_millis(5), inverted).is_err());
+    }
+
+    #[test]
+    fn test_params_above_the_caps_are_refused() {
+        let params = KdfParams { memory_kib: MAX_MEMORY_KIB, iterations: MAX_ITERATIONS, parallelism: 1 };
+        assert!(params.validate().is_ok());
+        assert!(KdfParams { memory_kib: MAX_MEMORY_KIB + 1, ..params }.validate().is_err());
+        assert!(KdfParams { iterations: MAX_ITERATIONS + 1, ..params }.validate().is_err());
+        assert!(KdfParams { memory_kib: u32::MAX, iterations: u32::MAX, parallelism: 1 }.validate().is_err());
+        let unbounded = TuneLimits { max_memory_kib: u32::MAX, ..TuneLimits::default() };
+        assert!(tune_with(&mut FakeMachine::new(1.0), Duration::from_millis(5), unbounded).is_err());
+    }
+
+    #[test]
+    fn test_hash_depends_on_every_input() {
+        let params = KdfParams { memory_kib: MIN_MEMORY_KIB, iterations: 1, parallelism: 1 };
+        let base = params.hash(b"password", b"salt-one").unwrap();
+        assert_eq!(params.hash(b"password", b"salt-one").unwrap(), base);
+        assert_ne!(params.hash(b"passwore", b"salt-one").unwrap(), base);
+        assert_ne!(params.hash(b"password", b"salt-two").unwrap(), base);
+        assert_ne!(KdfParams { iterations: 2, ..params }.hash(b"password", b"salt-one").unwrap(), base);
+    }
+}
@@ -0,0 +1,176 @@
+// Keystream generation shared by the XOR-based layers
+// Current format squeezes SHAKE-256 once per message; the legacy
+// hash-per-block constructions are kept for decrypting old ciphertexts
+
+use crate::crypto::container::le_u64;
+use sha2::Sha256;
+use sha3::digest::{Digest, ExtendableOutput, Update, XofReader};
+use sha3::{Sha3_256, Shake256, Shake256Reader};
+
+/// Domain separation prefix for all SHAKE-256 keystreams
+const XOF_DOMAIN: &[u8] = b"HybridGuard-Keystream-v2";
+
+/// Size of the scratch buffer used when XORing a keystream in place
+const XOR_BLOCK: usize = 4096;
+
+fn xof_reader(secret: &[u8], label: &[u8]) -> Shake256Reader {
+    assert!(label.len() <= u8::MAX as usize, "keystream label too long");
+    let mut shake = Shake256::default();
+    Update::update(&mut shake, XOF_DOMAIN);
+    Update::update(&mut shake, &[label.len() as u8]);
+    Update::update(&mut shake, label);
+    Update::update(&mut shake, secret);
+    shake.finalize_xof()
+}
+
+/// Squeeze exactly `len` keystream bytes for `secret` under `label`
+pub fn xof(secret: &[u8], label: &[u8], len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    xof_into(secret, label, &mut out);
+    out
+}
+
+/// Fill `out` with the first `out.len()` keystream bytes, for callers reusing a buffer
+pub fn xof_into(secret: &[u8], label: &[u8], out: &mut [u8]) {
+    xof_reader(secret, label).read(out);
+}
+
+/// XOR the SHAKE-256 keystream for `secret`/`label` into `data`
+pub fn xor_in_place(secret: &[u8], label: &[u8], data: &mut [u8]) {
+    XofStream::new(secret, label).xor(data);
+}
+
+/// SHAKE-256 keystream consumed incrementally
+/// XORing consecutive chunks gives the same bytes as `xor_in_place` on the whole message
+pub struct XofStream {
+    reader: Shake256Reader,
+}
+
+impl XofStream {
+    pub fn new(secret: &[u8], label: &[u8]) -> Self {
+        Self {
+            reader: xof_reader(secret, label),
+        }
+    }
+
+    /// XOR the next `data.len()` keystream bytes into `data`
+    pub fn xor(&mut self, data: &mut [u8]) {
+        let mut block = [0u8; XOR_BLOCK];
+        for chunk in data.chunks_mut(XOR_BLOCK) {
+            let stream = &mut block[..chunk.len()];
+            self.reader.read(stream);
+            for (byte, k) in chunk.iter_mut().zip(stream.iter()) {
+                *byte ^= k;
+            }
+        }
+    }
+}
+
+/// Legacy keystream: SHA3-256(secret || label || counter) per 32-byte block
+/// Used by format version 1 of the ML-KEM, HQC and noise layers
+pub fn legacy_sha3(secret: &[u8], label: &[u8], len: usize) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(len + 32);
+    let mut counter = 0u64;
+    while stream.len() < len {
+        let mut hasher = Sha3_256::new();
+        Digest::update(&mut hasher, secret);
+        Digest::update(&mut hasher, label);
+        Digest::update(&mut hasher, le_u64(counter));
+        stream.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+    stream.truncate(len);
+    stream
+}
+
+/// XOR the legacy SHA3 keystream into `data`
+pub fn legacy_sha3_xor(secret: &[u8], label: &[u8], data: &mut [u8]) {
+    let stream = legacy_sha3(secret, label, data.len());
+    for (byte, k) in data.iter_mut().zip(stream.iter()) {
+        *byte ^= k;
+    }
+}
+
+/// Legacy keystream of the FHE layer: SHA-256(secret || counter) per 32-byte block,
+/// with the counter serialized as a 64-bit little-endian integer
+pub fn legacy_sha256(secret: &[u8], len: usize) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(len + 32);
+    let mut counter = 0u64;
+    while stream.len() < len {
+        let mut hasher = Sha256::new();
+        Digest::update(&mut hasher, secret);
+        Digest::update(&mut hasher, le_u64(counter));
+        stream.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+    stream.truncate(len);
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_xof_known_answer() {
+        let stream = xof(&[0u8; 32], b"test-label", 48);
+        assert_eq!(hex(&stream[..16]), "6ff5fc25ab54d86f3659acbd2308a9dc");
+        assert_eq!(hex(&stream[32..48]), "804dc6f67ed0296f9c6a340a53b306e0");
+    }
+
+    #[test]
+    fn test_legacy_sha3_known_answer() {
+        let stream = legacy_sha3(&[0u8; 32], b"", 48);
+        assert_eq!(hex(&stream[..16]), "fdc6d587c83a348e456b034e1e0c31e9");
+        assert_eq!(hex(&stream[32..48]), "85bd3db1803dbe685bdd60aa96e40205");
+    }
+
+    #[test]
+    fn test_legacy_sha256_known_answer() {
+        let stream = legacy_sha256(&[0u8; 32], 48);
+        assert_eq!(hex(&stream[..16]), "2c34ce1df23b838c5abf2a7f6437cca3");
+    }
+
+    #[test]
+    fn test_xor_matches_xof() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let mut xored = data.clone();
+        xor_in_place(b"secret", b"label", &mut xored);
+
+        let stream = xof(b"secret", b"label", data.len());
+        let expected: Vec<u8> = data.iter().zip(stream.iter()).map(|(d, k)| d ^ k).collect();
+        assert_eq!(xored, expected);
+    }
+
+    #[test]
+    fn test_stream_matches_whole_message() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let mut whole = data.clone();
+        xor_in_place(b"secret", b"label", &mut whole);
+
+        let mut stream = XofStream::new(b"secret", b"label");
+        let mut pieces = data.clone();
+        for chunk in pieces.chunks_mut(777) {
+            stream.xor(chunk);
+        }
+        assert_eq!(pieces, whole);
+    }
+
+    #[test]
+    fn test_xof_into_matches_xof() {
+        // A reused buffer holding other bytes is overwritten, not XORed
+        let mut buffer = vec![0xA5u8; 100];
+        xof_into(b"secret", b"label", &mut buffer[..48]);
+        assert_eq!(&buffer[..48], &xof(b"secret", b"label", 48)[..]);
+        assert!(buffer[48..].iter().all(|&b| b == 0xA5));
+    }
+
+    #[test]
+    fn test_labels_separate_streams() {
+        assert_ne!(xof(b"secret", b"a", 32), xof(b"secret", b"b", 32));
+    }
+}
@@ -0,0 +1,432 @@
+// Application metadata in the container header
+// Small key-value pairs an application wants to travel with its ciphertext,
+// e.g. a tenant ID or document UUID. The public map is readable by anyone
+// holding the file; the private map is sealed with AES-256-GCM under a key
+// from the file's own layer keys, so it opens only where the file decrypts.
+// Both are covered by the container tag, so neither can be edited or moved
+// to another file. Since container format v13.
+// HybridGuard keeps its own entries in the private map too, under the
+// reserved prefixes: the content type `--tag-content-type` records, which
+// says too much about a file to be left in the clear. `open` leaves them out.
+
+use crate::crypto::envelope::NONCE_LEN;
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::nonce;
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::tag;
+use crate::error::{HybridGuardError, Result};
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::BTreeMap;
+
+/// Keys and values of one metadata map
+pub type MetadataMap = BTreeMap<String, Vec<u8>>;
+
+/// Most bytes of keys and values, both maps together
+pub const MAX_METADATA_LEN: usize = 4096;
+
+/// Most entries, both maps together
+pub const MAX_ENTRIES: usize = 64;
+
+/// Longest key
+pub const MAX_KEY_LEN: usize = 64;
+
+/// Key prefixes kept for HybridGuard's own entries, matched without case
+pub const RESERVED_PREFIXES: &[&str] = &["hg.", "hybridguard."];
+
+/// Private entry holding the plaintext's content type
+pub const CONTENT_TYPE_KEY: &str = "hg.content-type";
+
+/// Longest content type name
+pub const MAX_CONTENT_TYPE_LEN: usize = 32;
+
+/// Associated data of every sealed private map
+const SEAL_AAD: &[u8] = b"HybridGuard-private-metadata";
+
+/// Purpose the seal nonces are checked out under
+const SEAL_PURPOSE: &str = "metadata-seal";
+
+/// Bytes a sealed map adds to its keys and values at most: the entry count,
+/// a length before each key and value, and the GCM tag
+const SEALED_OVERHEAD: usize = 8 + 16 * MAX_ENTRIES + 16;
+
+/// Bytes HybridGuard's own entries add to a sealed map at most
+const INTERNAL_OVERHEAD: usize = 16 + CONTENT_TYPE_KEY.len() + MAX_CONTENT_TYPE_LEN;
+
+/// Metadata recorded at encryption
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Entries anyone holding the file can read
+    extra: MetadataMap,
+
+    /// Entries only the file's keys can read
+    sealed: Option<SealedMetadata>,
+}
+
+/// A private map sealed under the file's keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedMetadata {
+    /// Random per file
+    pub nonce: [u8; NONCE_LEN],
+    /// AES-256-GCM ciphertext and tag of the map's bincode
+    pub ciphertext: Vec<u8>,
+}
+
+impl Metadata {
+    /// No entries in either map
+    pub const EMPTY: Self = Self { extra: BTreeMap::new(), sealed: None };
+
+    /// `public` as is and `private` sealed under `file_keys`; None when both are empty
+    pub fn new(public: MetadataMap, private: &MetadataMap, file_keys: &LayerKeys) -> Result<Option<Self>> {
+        check_entries(&public, private)?;
+        let sealed = if private.is_empty() { None } else { Some(seal(private, file_keys)?) };
+        Ok((!public.is_empty() || sealed.is_some()).then_some(Self { extra: public, sealed }))
+    }
+
+    /// Metadata of the same encoded size as `new` gives, for length estimates
+    pub fn stand_in(public: MetadataMap, private: &MetadataMap) -> Result<Option<Self>> {
+        check_entries(&public, private)?;
+        let sealed = if private.is_empty() {
+            None
+        } else {
+            Some(SealedMetadata { nonce: [0u8; NONCE_LEN], ciphertext: vec![0u8; sealed_len(private)?] })
+        };
+        Ok((!public.is_empty() || sealed.is_some()).then_some(Self { extra: public, sealed }))
+    }
+
+    /// Metadata as read from another encoding, checked when the container is
+    pub(crate) fn from_parts(extra: MetadataMap, sealed: Option<SealedMetadata>) -> Self {
+        Self { extra, sealed }
+    }
+
+    /// Entries anyone holding the file can read
+    pub fn extra(&self) -> &MetadataMap {
+        &self.extra
+    }
+
+    /// Value of the public entry `key`
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.extra.get(key).map(Vec::as_slice)
+    }
+
+    /// The sealed private map, if one was given
+    pub fn sealed(&self) -> Option<&SealedMetadata> {
+        self.sealed.as_ref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extra.is_empty() && self.sealed.is_none()
+    }
+
+    /// Open the private map under the file's keys; empty when there is none
+    /// Check the container tag first, which covers the sealed bytes
+    pub fn open(&self, file_keys: &LayerKeys) -> Result<MetadataMap> {
+        let mut private = self.open_all(file_keys)?;
+        private.retain(|key, _| !is_reserved(key));
+        Ok(private)
+    }
+
+    /// Content type sealed in the private map, if one was recorded
+    pub fn content_type(&self, file_keys: &LayerKeys) -> Result<Option<String>> {
+        let Some(value) = self.open_all(file_keys)?.remove(CONTENT_TYPE_KEY) else {
+            return Ok(None);
+        };
+        String::from_utf8(value)
+            .map(Some)
+            .map_err(|_| HybridGuardError::Integrity("sealed content type is not UTF-8".to_string()))
+    }
+
+    /// `metadata`, or none, with `content_type` added to its private map,
+    /// sealed again under `file_keys`
+    pub(crate) fn with_content_type(metadata: Option<&Self>, content_type: &str, file_keys: &LayerKeys) -> Result<Self> {
+        check_content_type(content_type)?;
+        let (extra, mut private) = match metadata {
+            Some(metadata) => (metadata.extra.clone(), metadata.open_all(file_keys)?),
+            None => (MetadataMap::new(), MetadataMap::new()),
+        };
+        private.insert(CONTENT_TYPE_KEY.to_string(), content_type.as_bytes().to_vec());
+        Ok(Self { extra, sealed: Some(seal(&private, file_keys)?) })
+    }
+
+    /// `stand_in` grown as `with_content_type` grows it, for length estimates
+    pub(crate) fn stand_in_with_content_type(stand_in: Option<&Self>, content_type: &str) -> Result<Self> {
+        check_content_type(content_type)?;
+        let entry = bincode::serialized_size(&(CONTENT_TYPE_KEY, content_type)).map_err(|e| HybridGuardError::Encryption(e.to_string()))?;
+        let (extra, sealed_len) = match stand_in {
+            Some(Self { extra, sealed: Some(sealed) }) => (extra.clone(), sealed.ciphertext.len()),
+            Some(Self { extra, sealed: None }) => (extra.clone(), sealed_len(&MetadataMap::new())?),
+            None => (MetadataMap::new(), sealed_len(&MetadataMap::new())?),
+        };
+        let ciphertext = vec![0u8; sealed_len + entry as usize];
+        Ok(Self { extra, sealed: Some(SealedMetadata { nonce: [0u8; NONCE_LEN], ciphertext }) })
+    }
+
+    /// Every private entry, HybridGuard's own included
+    fn open_all(&self, file_keys: &LayerKeys) -> Result<MetadataMap> {
+        let Some(sealed) = &self.sealed else {
+            return Ok(MetadataMap::new());
+        };
+        let failed = || HybridGuardError::Integrity("private metadata failed to open (wrong keys or modified data)".to_string());
+        let plaintext = SecretBytes::new(
+            cipher(&seal_key(file_keys))?
+                .decrypt(Nonce::from_slice(&sealed.nonce), Payload { msg: &sealed.ciphertext, aad: SEAL_AAD })
+                .map_err(|_| failed())?,
+        );
+        let private: MetadataMap = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(sealed.ciphertext.len() as u64)
+            .deserialize(&plaintext)
+            .map_err(|_| failed())?;
+        let (internal, application): (MetadataMap, MetadataMap) = private.into_iter().partition(|(key, _)| is_reserved(key));
+        check_entries(&self.extra, &application)?;
+        if internal.get(CONTENT_TYPE_KEY).is_some_and(|value| value.len() > MAX_CONTENT_TYPE_LEN) {
+            return Err(failed());
+        }
+        Ok(application.into_iter().chain(internal).collect())
+    }
+
+    /// Check what a decoded container carries: valid public keys, and no
+    /// more entries or bytes than `new` could have written
+    pub(crate) fn check(&self) -> std::result::Result<(), String> {
+        for key in self.extra.keys() {
+            check_key(key).map_err(|e| format!("invalid container: {}", e))?;
+        }
+        let sealed_len = self.sealed.as_ref().map_or(0, |sealed| sealed.ciphertext.len());
+        if self.extra.len() > MAX_ENTRIES || map_len(&self.extra) + sealed_len > MAX_METADATA_LEN + SEALED_OVERHEAD + INTERNAL_OVERHEAD {
+            return Err("invalid container: metadata is larger than any encryption writes".to_string());
+        }
+        Ok(())
+    }
+
+    /// Feed the metadata to a keyed hasher, marked so it cannot pass for another field
+    pub(crate) fn authenticate(&self, hasher: &mut Sha3_256) {
+        hasher.update(b"metadata");
+        hasher.update(bincode::serialize(self).unwrap_or_default());
+    }
+}
+
+/// Check a key: 1 to `MAX_KEY_LEN` ASCII letters, digits, '.', '-' or '_',
+/// outside the reserved prefixes
+pub fn check_key(key: &str) -> Result<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_';
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.chars().all(allowed) {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "metadata key '{}' must be 1 to {} ASCII letters, digits, '.', '-' or '_'",
+            key.escape_default(),
+            MAX_KEY_LEN
+        )));
+    }
+    if let Some(prefix) = reserved_prefix(key) {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "metadata key '{}' uses the reserved prefix '{}'",
+            key, prefix
+        )));
+    }
+    Ok(())
+}
+
+/// Check both maps: valid keys, none in both, and within the size caps
+pub fn check_entries(public: &MetadataMap, private: &MetadataMap) -> Result<()> {
+    for key in public.keys().chain(private.keys()) {
+        check_key(key)?;
+    }
+    if let Some(key) = public.keys().find(|key| private.contains_key(*key)) {
+        return Err(HybridGuardError::InvalidInput(format!("metadata key '{}' is both public and private", key)));
+    }
+    if public.len() + private.len() > MAX_ENTRIES {
+        return Err(HybridGuardError::InvalidInput(format!("metadata holds at most {} entries", MAX_ENTRIES)));
+    }
+    let len = map_len(public) + map_len(private);
+    if len > MAX_METADATA_LEN {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "metadata is {} bytes of keys and values; the limit is {}",
+            len, MAX_METADATA_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Parse a `KEY=VALUE` argument, the value taken as UTF-8 bytes
+pub fn parse_entry(text: &str) -> Result<(String, Vec<u8>)> {
+    let Some((key, value)) = text.split_once('=') else {
+        return Err(HybridGuardError::InvalidInput(format!("metadata '{}' is not KEY=VALUE", text)));
+    };
+    check_key(key)?;
+    Ok((key.to_string(), value.as_bytes().to_vec()))
+}
+
+/// A value as inspect shows it: quoted and escaped when it is UTF-8 text,
+/// otherwise hex
+pub fn display_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) => format!("{:?}", text),
+        Err(_) => format!("0x{}", crate::crypto::codec::hex_lower(value)),
+    }
+}
+
+/// The reserved prefix `key` starts with, if any
+fn reserved_prefix(key: &str) -> Option<&'static str> {
+    let lower = key.to_ascii_lowercase();
+    RESERVED_PREFIXES.iter().find(|prefix| lower.starts_with(*prefix)).copied()
+}
+
+fn is_reserved(key: &str) -> bool {
+    reserved_prefix(key).is_some()
+}
+
+/// Check a content type name: 1 to `MAX_CONTENT_TYPE_LEN` ASCII graphic characters
+fn check_content_type(content_type: &str) -> Result<()> {
+    if content_type.is_empty() || content_type.len() > MAX_CONTENT_TYPE_LEN || !content_type.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "content type '{}' must be 1 to {} printable ASCII characters",
+            content_type.escape_default(),
+            MAX_CONTENT_TYPE_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Bytes of keys and values in `map`
+fn map_len(map: &MetadataMap) -> usize {
+    map.iter().map(|(key, value)| key.len() + value.len()).sum()
+}
+
+/// Length `private` seals to
+fn sealed_len(private: &MetadataMap) -> Result<usize> {
+    let len = bincode::serialized_size(private).map_err(|e| HybridGuardError::Encryption(e.to_string()))?;
+    Ok(len as usize + 16)
+}
+
+/// Seal `private` under a fresh nonce, checked out under a digest of the seal key
+fn seal(private: &MetadataMap, file_keys: &LayerKeys) -> Result<SealedMetadata> {
+    let key = seal_key(file_keys);
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    nonce::checkout(&Sha3_256::digest(&key[..]), SEAL_PURPOSE, &nonce)?;
+    let plaintext = SecretBytes::new(bincode::serialize(private).map_err(|e| HybridGuardError::Encryption(e.to_string()))?);
+    let ciphertext = cipher(&key)?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: SEAL_AAD })
+        .map_err(|_| HybridGuardError::Encryption("could not seal the private metadata".to_string()))?;
+    Ok(SealedMetadata { nonce, ciphertext })
+}
+
+/// Hash of the file keys' metadata-seal key, the AES-256-GCM key
+fn seal_key(file_keys: &LayerKeys) -> SecretBytes {
+    SecretBytes::new(tag::keyed_hasher(file_keys, KeyPurpose::MetadataSeal).finalize().to_vec())
+}
+
+fn cipher(key: &SecretBytes) -> Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hkdf::KeyDerivation;
+
+    fn keys(fill: u8) -> LayerKeys {
+        KeyDerivation::new(vec![fill; 32]).derive_all_keys().unwrap()
+    }
+
+    fn map(entries: &[(&str, &[u8])]) -> MetadataMap {
+        entries.iter().map(|(key, value)| (key.to_string(), value.to_vec())).collect()
+    }
+
+    #[test]
+    fn test_private_map_opens_only_under_its_keys() {
+        let public = map(&[("tenant", b"acme")]);
+        let private = map(&[("ssn", b"078-05-1120"), ("blob", &[0, 0xff, b'"'])]);
+        let metadata = Metadata::new(public.clone(), &private, &keys(1)).unwrap().unwrap();
+        assert_eq!(metadata.extra(), &public);
+        assert_eq!(metadata.get("tenant"), Some(&b"acme"[..]));
+        assert_eq!(metadata.open(&keys(1)).unwrap(), private);
+        assert!(matches!(metadata.open(&keys(2)), Err(HybridGuardError::Integrity(_))));
+
+        // The sealed bytes never show the values, and the stand-in is the same size
+        let sealed = metadata.sealed().unwrap();
+        assert!(!sealed.ciphertext.windows(11).any(|w| w == b"078-05-1120"));
+        let stand_in = Metadata::stand_in(public, &private).unwrap().unwrap();
+        assert_eq!(bincode::serialized_size(&stand_in).unwrap(), bincode::serialized_size(&metadata).unwrap());
+
+        let mut forged = metadata.clone();
+        forged.sealed.as_mut().unwrap().ciphertext[0] ^= 1;
+        assert!(forged.open(&keys(1)).is_err());
+    }
+
+    #[test]
+    fn test_content_type_is_sealed_beside_the_private_map() {
+        let public = map(&[("tenant", b"acme")]);
+        let private = map(&[("owner", b"ops")]);
+        let metadata = Metadata::new(public.clone(), &private, &keys(1)).unwrap();
+        let typed = Metadata::with_content_type(metadata.as_ref(), "elf", &keys(1)).unwrap();
+        assert_eq!(typed.content_type(&keys(1)).unwrap().as_deref(), Some("elf"));
+        assert!(typed.content_type(&keys(2)).is_err());
+        // Applications see their own entries only, and nobody sees the type unopened
+        assert_eq!(typed.open(&keys(1)).unwrap(), private);
+        assert_eq!(typed.extra(), &public);
+        assert!(!typed.sealed().unwrap().ciphertext.windows(3).any(|w| w == b"elf"));
+
+        let stand_in = Metadata::stand_in(public, &private).unwrap();
+        let grown = Metadata::stand_in_with_content_type(stand_in.as_ref(), "elf").unwrap();
+        assert_eq!(bincode::serialized_size(&grown).unwrap(), bincode::serialized_size(&typed).unwrap());
+        let alone = Metadata::with_content_type(None, "text", &keys(1)).unwrap();
+        let grown = Metadata::stand_in_with_content_type(None, "text").unwrap();
+        assert_eq!(bincode::serialized_size(&grown).unwrap(), bincode::serialized_size(&alone).unwrap());
+        assert!(alone.open(&keys(1)).unwrap().is_empty());
+
+        assert!(Metadata::with_content_type(None, "", &keys(1)).is_err());
+        assert!(Metadata::with_content_type(None, &"x".repeat(MAX_CONTENT_TYPE_LEN + 1), &keys(1)).is_err());
+    }
+
+    #[test]
+    fn test_empty_maps_record_nothing() {
+        assert_eq!(Metadata::new(MetadataMap::new(), &MetadataMap::new(), &keys(1)).unwrap(), None);
+        let public_only = Metadata::new(map(&[("doc", b"1")]), &MetadataMap::new(), &keys(1)).unwrap().unwrap();
+        assert!(public_only.sealed().is_none());
+        assert!(public_only.open(&keys(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_keys_are_checked() {
+        for key in ["tenant", "doc.uuid", "schema-version", "A_1", &"k".repeat(MAX_KEY_LEN)] {
+            assert!(check_key(key).is_ok(), "{}", key);
+        }
+        for key in ["", "has space", "ünïcode", "a=b", "line\nbreak", &"k".repeat(MAX_KEY_LEN + 1)] {
+            assert!(matches!(check_key(key), Err(HybridGuardError::InvalidInput(_))), "{:?}", key);
+        }
+        for key in ["hg.version", "HG.version", "hybridguard.tenant", "HybridGuard.x"] {
+            assert!(matches!(check_key(key), Err(HybridGuardError::InvalidInput(m)) if m.contains("reserved")), "{}", key);
+        }
+
+        // Nor may one key be both public and private
+        let both = check_entries(&map(&[("id", b"1")]), &map(&[("id", b"2")]));
+        assert!(matches!(both, Err(HybridGuardError::InvalidInput(m)) if m.contains("both")));
+    }
+
+    #[test]
+    fn test_size_cap_counts_both_maps() {
+        let half = vec![0x41; MAX_METADATA_LEN / 2 - 4];
+        let public = map(&[("left", &half)]);
+        let private = map(&[("rite", &half)]);
+        assert!(Metadata::new(public.clone(), &private, &keys(1)).is_ok());
+
+        let over = map(&[("rite", &[&half[..], b"!"].concat())]);
+        assert!(matches!(Metadata::new(public, &over, &keys(1)), Err(HybridGuardError::InvalidInput(_))));
+        let many: MetadataMap = (0..=MAX_ENTRIES).map(|i| (format!("k{}", i), Vec::new())).collect();
+        assert!(check_entries(&many, &MetadataMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_entries_parse_and_display() {
+        assert_eq!(parse_entry("tenant=acme=corp").unwrap(), ("tenant".to_string(), b"acme=corp".to_vec()));
+        assert_eq!(parse_entry("empty=").unwrap().1, Vec::<u8>::new());
+        assert!(parse_entry("no-separator").is_err());
+        assert!(parse_entry("hg.x=1").is_err());
+
+        assert_eq!(display_value(b"acme"), "\"acme\"");
+        assert_eq!(display_value(b"say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+        assert_eq!(display_value(&[0xff, 0x00]), "0xff00");
+    }
+}
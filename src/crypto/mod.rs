@@ -1,40 +1,829 @@
 // Cryptographic primitives and utilities
 
+pub mod codec;
+pub mod container;
+pub mod drbg;
+pub mod encoding;
+pub mod envelope;
 pub mod hkdf;
+pub mod kdf;
+pub mod keystream;
+pub mod metadata;
+pub mod nonce;
+pub mod secret;
+pub mod sniff;
+pub mod tag;
+pub mod timestamp;
 
-use crate::error::Result;
+use crate::crypto::container::{le_u16, le_u32, le_u64};
+use crate::crypto::envelope::WrappedFileKey;
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::kdf::PassphraseKdf;
+use crate::crypto::metadata::Metadata;
+use crate::crypto::tag::{MacKeys, TAG_LEN};
+use crate::crypto::timestamp::{TimestampToken, DIGEST_LEN};
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::verification::VerificationKey;
+use crate::layers::{self, LayerDescriptor};
+use sha3::{Digest, Sha3_256};
+
+/// Version string prefix this build writes and accepts
+const KNOWN_VERSION_PREFIX: &str = "0.";
+
+/// Purpose of the container tag key
+const TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("container-tag");
+
+/// Purpose of the verification tag key, which verification keys carry
+pub(crate) const VERIFICATION_PURPOSE: KeyPurpose = KeyPurpose::Mac("container-verification");
+
+/// Domain label of the digest a timestamp authority vouches for
+const TIMESTAMP_DIGEST_LABEL: &[u8] = b"HybridGuard-timestamp-digest";
 
 /// Represents encrypted data with metadata
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+/// Fields are private so ciphertext and metadata can only change together;
+/// deserializing checks the same invariants the pipeline guarantees
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 pub struct EncryptedData {
     /// The encrypted ciphertext
-    pub ciphertext: Vec<u8>,
+    ciphertext: Vec<u8>,
     
     /// Metadata about encryption layers used
-    pub layers: Vec<String>,
+    layers: Vec<String>,
     
     /// Version of HybridGuard used
-    pub version: String,
+    version: String,
     
     /// Timestamp of encryption
+    timestamp: u64,
+    
+    /// Format descriptor of each layer, in pipeline order
+    descriptors: Vec<LayerDescriptor>,
+    
+    /// ID of the keys that encrypted this data, when known
+    key_id: Option<String>,
+    
+    /// Where this ciphertext came from, if it was migrated from an older format
+    migrated_from: Option<MigrationNote>,
+    
+    /// Tag over the ciphertext, layer names and descriptors; None before format v4
+    tag: Option<[u8; TAG_LEN]>,
+    
+    /// Token from a timestamp authority over everything above; None before format v5
+    timestamp_token: Option<TimestampToken>,
+    
+    /// SHA3-256 of the ciphertext, covered by the tag; None before format v6
+    /// Lets storage compare payloads without hashing them again
+    content_digest: Option<[u8; DIGEST_LEN]>,
+    
+    /// This file's own key, wrapped under the profile's master keys; the
+    /// layer keys derive from it. None before format v7, when the master
+    /// layer keys encrypted every file directly
+    wrapped_key: Option<WrappedFileKey>,
+    
+    /// Size and mtime of the source file as read under `--stable-read`;
+    /// None otherwise and before format v8
+    source_snapshot: Option<SourceSnapshot>,
+    
+    /// Policy label given at encryption, e.g. "confidential"; None when
+    /// unlabeled and before format v9
+    label: Option<String>,
+    
+    /// Content type older builds left here in the clear, e.g. "elf"; now it
+    /// is sealed in the private metadata, and this is None on new files and
+    /// before format v10
+    content_type: Option<String>,
+    
+    /// Salt and Argon2id cost of a passphrase-only container, whose keys
+    /// come from the passphrase alone; None otherwise and before format v11
+    passphrase: Option<PassphraseKdf>,
+    
+    /// Tag over the same fields and the key ID under the profile's master
+    /// keys, which a verification key checks without decrypting; None when
+    /// sealed without the master keys and before format v12
+    verification_tag: Option<[u8; TAG_LEN]>,
+    
+    /// Application key-value pairs, public and sealed, covered by the tag;
+    /// None when none were given and before format v13
+    metadata: Option<Metadata>,
+    
+    /// Container format this was decoded from; not part of its value
+    #[serde(skip)]
+    decoded_from: DecodedFrom,
+}
+
+/// Container format an `EncryptedData` was read from, None when built in
+/// memory; containers compare equal whichever format they were read from
+#[derive(Debug, Clone, Copy, Default)]
+struct DecodedFrom(Option<u16>);
+
+impl PartialEq for DecodedFrom {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for DecodedFrom {}
+
+/// Unvalidated wire form of `EncryptedData`
+#[derive(serde::Deserialize)]
+pub(crate) struct EncryptedDataFields {
+    pub(crate) ciphertext: Vec<u8>,
+    pub(crate) layers: Vec<String>,
+    pub(crate) version: String,
+    pub(crate) timestamp: u64,
+    pub(crate) descriptors: Vec<LayerDescriptor>,
+    pub(crate) key_id: Option<String>,
+    pub(crate) migrated_from: Option<MigrationNote>,
+    pub(crate) tag: Option<[u8; TAG_LEN]>,
+    pub(crate) timestamp_token: Option<TimestampToken>,
+    pub(crate) content_digest: Option<[u8; DIGEST_LEN]>,
+    pub(crate) wrapped_key: Option<WrappedFileKey>,
+    pub(crate) source_snapshot: Option<SourceSnapshot>,
+    pub(crate) label: Option<String>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) passphrase: Option<PassphraseKdf>,
+    pub(crate) verification_tag: Option<[u8; TAG_LEN]>,
+    pub(crate) metadata: Option<Metadata>,
+}
+
+impl EncryptedDataFields {
+    /// Check the invariants every `EncryptedData` upholds
+    fn check(self) -> std::result::Result<EncryptedData, String> {
+        if self.ciphertext.is_empty() {
+            return Err("invalid container: ciphertext is empty".to_string());
+        }
+        if self.layers.is_empty() {
+            return Err("invalid container: no layers are listed".to_string());
+        }
+        if !self.version.starts_with(KNOWN_VERSION_PREFIX) {
+            return Err(format!("invalid container: unknown HybridGuard version '{}'", self.version));
+        }
+        if let Some(metadata) = &self.metadata {
+            metadata.check()?;
+        }
+        Ok(EncryptedData {
+            ciphertext: self.ciphertext,
+            layers: self.layers,
+            version: self.version,
+            timestamp: self.timestamp,
+            descriptors: self.descriptors,
+            key_id: self.key_id,
+            migrated_from: self.migrated_from,
+            tag: self.tag,
+            timestamp_token: self.timestamp_token,
+            content_digest: self.content_digest,
+            wrapped_key: self.wrapped_key,
+            source_snapshot: self.source_snapshot,
+            label: self.label,
+            content_type: self.content_type,
+            passphrase: self.passphrase,
+            verification_tag: self.verification_tag,
+            metadata: self.metadata,
+            decoded_from: DecodedFrom::default(),
+        })
+    }
+    
+    pub(crate) fn validate(self) -> Result<EncryptedData> {
+        self.check().map_err(HybridGuardError::Decryption)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EncryptedData {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        <EncryptedDataFields as serde::Deserialize>::deserialize(deserializer)?
+            .check()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Provenance of a ciphertext re-encrypted from an older format
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MigrationNote {
+    /// Container format version of the original file
+    pub format_version: u16,
+    
+    /// Timestamp of the original encryption
     pub timestamp: u64,
 }
 
+/// State of a source file captured when it was read for encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SourceSnapshot {
+    /// Bytes read, which was the file's size throughout the read
+    pub len: u64,
+    
+    /// Modification time, in seconds and nanoseconds since the Unix epoch
+    pub modified_secs: u64,
+    pub modified_nanos: u32,
+}
+
 impl EncryptedData {
-    pub fn new(ciphertext: Vec<u8>) -> Self {
+    pub(crate) fn new(ciphertext: Vec<u8>) -> Self {
+        Self::with_descriptors(ciphertext, layers::current_descriptors())
+    }
+    
+    /// Wrap the output of layers with the given descriptors
+    pub(crate) fn with_descriptors(ciphertext: Vec<u8>, descriptors: Vec<LayerDescriptor>) -> Self {
+        Self::with_descriptors_at(ciphertext, descriptors, now())
+    }
+    
+    /// `with_descriptors`, encrypted at `timestamp` seconds since the epoch
+    pub(crate) fn with_descriptors_at(ciphertext: Vec<u8>, descriptors: Vec<LayerDescriptor>, timestamp: u64) -> Self {
         Self {
+            content_digest: Some(content_digest(&ciphertext)),
             ciphertext,
-            layers: vec![
-                "ML-KEM-768".to_string(),
-                "HQC".to_string(),
-                "QuantumNoise".to_string(),
-                "FHE".to_string(),
-            ],
-            version: "0.1.0".to_string(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            layers: descriptors.iter().map(|d| d.name.clone()).collect(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp,
+            descriptors,
+            key_id: None,
+            migrated_from: None,
+            tag: None,
+            timestamp_token: None,
+            wrapped_key: None,
+            source_snapshot: None,
+            label: None,
+            content_type: None,
+            passphrase: None,
+            verification_tag: None,
+            metadata: None,
+            decoded_from: DecodedFrom::default(),
+        }
+    }
+    
+    /// Note that this was decoded from container format `version`
+    pub(crate) fn decoded_from(mut self, version: u16) -> Self {
+        self.decoded_from = DecodedFrom(Some(version));
+        self
+    }
+    
+    /// Record which keys encrypted this data
+    pub fn with_key_id(mut self, key_id: &str) -> Self {
+        self.key_id = Some(key_id.to_string());
+        self
+    }
+    
+    /// Record the source file snapshot, re-sealing under `keys` since the tag covers it
+    pub fn with_source_snapshot(mut self, snapshot: SourceSnapshot, keys: &LayerKeys) -> Self {
+        self.source_snapshot = Some(snapshot);
+        self.with_tag(keys)
+    }
+    
+    /// Record a policy label, re-sealing under `keys` since the tag covers it
+    pub fn with_label(mut self, label: &str, keys: &LayerKeys) -> Self {
+        self.label = Some(label.to_string());
+        self.with_tag(keys)
+    }
+    
+    /// Record the plaintext's content type in the private metadata, sealed
+    /// under `keys`, the file's layer keys, and re-seal since the tag covers it
+    /// Call it after `with_metadata`, which replaces the whole metadata
+    pub fn with_content_type(mut self, content_type: &str, keys: &LayerKeys) -> Result<Self> {
+        self.metadata = Some(Metadata::with_content_type(self.metadata.as_ref(), content_type, keys)?);
+        Ok(self.with_tag(keys))
+    }
+    
+    /// Record the passphrase salt and cost, re-sealing under `keys` since the tag covers them
+    pub fn with_passphrase(mut self, passphrase: PassphraseKdf, keys: &LayerKeys) -> Self {
+        self.passphrase = Some(passphrase);
+        self.with_tag(keys)
+    }
+    
+    /// Record application metadata, re-sealing under `keys` since the tag covers it
+    /// The private map is sealed under the same `keys`, the file's layer keys;
+    /// it replaces any metadata recorded before, a content type included
+    pub fn with_metadata(mut self, metadata: Metadata, keys: &LayerKeys) -> Self {
+        self.metadata = Some(metadata);
+        self.with_tag(keys)
+    }
+    
+    /// Record the wrapped file key whose layer keys encrypted this data,
+    /// re-sealing under those keys since the tag covers it
+    pub fn with_wrapped_key(mut self, wrapped: WrappedFileKey, file_keys: &LayerKeys) -> Self {
+        self.wrapped_key = Some(wrapped);
+        self.with_tag(file_keys)
+    }
+    
+    /// Authenticate the ciphertext and layer metadata under `keys`
+    pub(crate) fn with_tag(mut self, keys: &LayerKeys) -> Self {
+        self.tag = Some(self.compute_tag(keys));
+        self
+    }
+    
+    /// Check the tag before any layer touches the ciphertext
+    /// Data written before format v4 carries no tag and is accepted as is
+    pub(crate) fn verify_tag(&self, keys: &LayerKeys) -> Result<()> {
+        match &self.tag {
+            Some(tag) if !tag::tags_match(tag, &self.compute_tag(keys)) => Err(HybridGuardError::Integrity(
+                "ciphertext failed authentication (wrong keys or modified data)".to_string(),
+            )),
+            // The tag vouches for the stored digest, so it must describe this ciphertext
+            Some(_) if self.content_digest.is_some_and(|digest| digest != content_digest(&self.ciphertext)) => {
+                Err(HybridGuardError::Integrity("content digest does not match the ciphertext".to_string()))
+            }
+            None if self.tag_stripped() => Err(HybridGuardError::Integrity(format!(
+                "container format v{} is always tagged, but this one has no tag (stripped or modified data)",
+                self.decoded_from.0.unwrap_or_default()
+            ))),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Tag the ciphertext and metadata for verification keys, under the
+    /// profile's master keys rather than the file keys; call it last, once
+    /// the key ID and every field the tag covers are set
+    pub fn with_verification_tag(mut self, master_keys: &LayerKeys) -> Self {
+        self.verification_tag = Some(self.compute_verification_tag(master_keys));
+        self
+    }
+    
+    /// Check the verification tag, for parties that cannot decrypt
+    /// Fails with `UnsupportedFormat` when the container carries none
+    pub fn verify_integrity(&self, key: &VerificationKey) -> Result<()> {
+        let Some(stored) = &self.verification_tag else {
+            return Err(HybridGuardError::UnsupportedFormat(
+                "container has no verification tag (written before format v12 or without the master keys); \
+                 only its key file can check it"
+                    .to_string(),
+            ));
+        };
+        if !tag::tags_match(stored, &self.compute_verification_tag(key)) {
+            return Err(HybridGuardError::Integrity(
+                "verification tag does not match (other keys or modified data)".to_string(),
+            ));
+        }
+        if self.content_digest.is_some_and(|digest| digest != content_digest(&self.ciphertext)) {
+            return Err(HybridGuardError::Integrity("content digest does not match the ciphertext".to_string()));
+        }
+        Ok(())
+    }
+    
+    fn compute_tag(&self, keys: &LayerKeys) -> [u8; TAG_LEN] {
+        self.authenticate(tag::keyed_hasher(keys, TAG_PURPOSE))
+    }
+    
+    fn compute_verification_tag<K: MacKeys + ?Sized>(&self, keys: &K) -> [u8; TAG_LEN] {
+        let mut hasher = tag::keyed_hasher(keys, VERIFICATION_PURPOSE);
+        // What an auditor matches the verification key against
+        let key_id = self.key_id.as_deref().unwrap_or_default();
+        hasher.update(le_u64(key_id.len() as u64));
+        hasher.update(key_id.as_bytes());
+        self.authenticate(hasher)
+    }
+    
+    /// Finish `hasher`, already keyed, over the ciphertext and the metadata tags cover
+    fn authenticate(&self, mut hasher: Sha3_256) -> [u8; TAG_LEN] {
+        hasher.update(le_u64(self.ciphertext.len() as u64));
+        hasher.update(&self.ciphertext);
+        hasher.update(bincode::serialize(&(&self.layers, &self.descriptors)).unwrap_or_default());
+        // Absent before v6, so older tags still verify
+        if let Some(digest) = &self.content_digest {
+            hasher.update(digest);
+        }
+        // Absent before v7
+        if let Some(wrapped) = &self.wrapped_key {
+            hasher.update(wrapped.nonce);
+            hasher.update(&wrapped.ciphertext);
+        }
+        // Absent before v8 and without --stable-read
+        if let Some(snapshot) = &self.source_snapshot {
+            hasher.update(le_u64(snapshot.len));
+            hasher.update(le_u64(snapshot.modified_secs));
+            hasher.update(le_u32(snapshot.modified_nanos));
+        }
+        // Absent before v9 and on unlabeled files
+        if let Some(label) = &self.label {
+            hasher.update(le_u64(label.len() as u64));
+            hasher.update(label.as_bytes());
+        }
+        // Only on files older builds tagged; marked so it cannot pass for a label
+        if let Some(content_type) = &self.content_type {
+            hasher.update(b"content-type");
+            hasher.update(le_u64(content_type.len() as u64));
+            hasher.update(content_type.as_bytes());
+        }
+        // Absent before v11 and on containers opened by a key file
+        if let Some(passphrase) = &self.passphrase {
+            hasher.update(b"passphrase");
+            hasher.update(passphrase.salt);
+            hasher.update(le_u32(passphrase.params.memory_kib));
+            hasher.update(le_u32(passphrase.params.iterations));
+            hasher.update(le_u32(passphrase.params.parallelism));
+        }
+        // Absent before v13 and without metadata
+        if let Some(metadata) = &self.metadata {
+            metadata.authenticate(&mut hasher);
+        }
+        hasher.finalize().into()
+    }
+    
+    /// SHA3-256 of the container with an empty timestamp slot
+    /// This is what a timestamp authority vouches for. It hashes each field
+    /// on its own, the way the tags do, with the fields the tags leave out
+    /// first; an optional field is hashed only when present, so a new one
+    /// leaves the digest of containers without it unchanged
+    pub fn timestamp_digest(&self) -> Result<[u8; DIGEST_LEN]> {
+        let mut hasher = Sha3_256::new();
+        hasher.update(TIMESTAMP_DIGEST_LABEL);
+        hasher.update(le_u64(self.version.len() as u64));
+        hasher.update(self.version.as_bytes());
+        hasher.update(le_u64(self.timestamp));
+        if let Some(key_id) = &self.key_id {
+            hasher.update(b"key-id");
+            hasher.update(le_u64(key_id.len() as u64));
+            hasher.update(key_id.as_bytes());
+        }
+        if let Some(note) = &self.migrated_from {
+            hasher.update(b"migrated-from");
+            hasher.update(le_u16(note.format_version));
+            hasher.update(le_u64(note.timestamp));
+        }
+        if let Some(tag) = &self.tag {
+            hasher.update(b"tag");
+            hasher.update(tag);
+        }
+        if let Some(verification_tag) = &self.verification_tag {
+            hasher.update(b"verification-tag");
+            hasher.update(verification_tag);
+        }
+        Ok(self.authenticate(hasher))
+    }
+    
+    /// Attach a token returned by a timestamp authority
+    pub(crate) fn with_timestamp_token(mut self, token: TimestampToken) -> Self {
+        self.timestamp_token = Some(token);
+        self
+    }
+    
+    /// Record where a migrated ciphertext came from
+    pub(crate) fn with_migrated_from(mut self, note: MigrationNote) -> Self {
+        self.migrated_from = Some(note);
+        self
+    }
+    
+    pub fn ciphertext(&self) -> &[u8] {
+        &self.ciphertext
+    }
+    
+    /// Layer names in pipeline order
+    pub fn layers(&self) -> &[String] {
+        &self.layers
+    }
+    
+    /// HybridGuard version that wrote this data
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+    
+    /// Seconds since the Unix epoch when this data was encrypted
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+    
+    pub fn descriptors(&self) -> &[LayerDescriptor] {
+        &self.descriptors
+    }
+    
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+    
+    pub fn migrated_from(&self) -> Option<&MigrationNote> {
+        self.migrated_from.as_ref()
+    }
+    
+    /// Whether an authentication tag covers this data; containers written
+    /// before format 4 have none and decrypt unauthenticated
+    pub fn is_tagged(&self) -> bool {
+        self.tag.is_some()
+    }
+    
+    /// Whether this was read from format 4 or later yet carries no tag,
+    /// which only removing it can cause; `verify_tag` refuses it
+    pub(crate) fn tag_stripped(&self) -> bool {
+        self.tag.is_none() && self.decoded_from.0.is_some_and(|version| version >= 4)
+    }
+    
+    /// Whether this was read from format 7 or later yet carries no wrapped
+    /// file key, which only removing it can cause; `keys_for` refuses it
+    pub(crate) fn wrapped_key_stripped(&self) -> bool {
+        self.wrapped_key.is_none() && self.decoded_from.0.is_some_and(|version| version >= 7)
+    }
+    
+    /// Trusted timestamp, if the container was stamped (format v5 and later)
+    pub fn timestamp_token(&self) -> Option<&TimestampToken> {
+        self.timestamp_token.as_ref()
+    }
+    
+    /// SHA3-256 of the ciphertext as stored at encryption (format v6 and later)
+    /// Authenticated by the tag when the container is decrypted; equal digests
+    /// mean identical payloads
+    pub fn content_digest(&self) -> Option<&[u8; DIGEST_LEN]> {
+        self.content_digest.as_ref()
+    }
+    
+    /// This file's wrapped key (format v7 and later); None for data encrypted
+    /// directly under the master layer keys
+    pub fn wrapped_key(&self) -> Option<&WrappedFileKey> {
+        self.wrapped_key.as_ref()
+    }
+    
+    /// Source file size and mtime captured by a stable read (format v8 and later)
+    pub fn source_snapshot(&self) -> Option<&SourceSnapshot> {
+        self.source_snapshot.as_ref()
+    }
+    
+    /// Policy label given at encryption (format v9 and later)
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+    
+    /// Content type recorded at encryption, opened under the file's layer
+    /// keys; check the tag first. Files older builds tagged carry it in the clear
+    pub fn content_type(&self, keys: &LayerKeys) -> Result<Option<String>> {
+        match &self.content_type {
+            Some(content_type) => Ok(Some(content_type.clone())),
+            None => self.metadata().content_type(keys),
+        }
+    }
+    
+    /// Content type older builds left in the clear (format v10 and later);
+    /// None on files that seal it, which only `content_type` can read
+    pub fn clear_content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+    
+    /// Salt and Argon2id cost of a passphrase-only container (format v11 and later)
+    pub fn passphrase(&self) -> Option<&PassphraseKdf> {
+        self.passphrase.as_ref()
+    }
+    
+    /// Application metadata recorded at encryption (format v13 and later);
+    /// empty when none was given
+    pub fn metadata(&self) -> &Metadata {
+        self.metadata.as_ref().unwrap_or(&NO_METADATA)
+    }
+    
+    /// Whether a verification key can check this container (format v12 and later)
+    pub fn has_verification_tag(&self) -> bool {
+        self.verification_tag.is_some()
+    }
+    
+    /// Whether the ciphertext carries an authentication tag (format v4 and later)
+    pub fn is_authenticated(&self) -> bool {
+        self.tag.is_some()
+    }
+    
+    /// Format version the named layer used for this ciphertext
+    pub fn layer_version(&self, name: &str) -> Result<u16> {
+        self.descriptors
+            .iter()
+            .find(|d| d.name == name)
+            .map(|d| d.version)
+            .ok_or_else(|| HybridGuardError::UnsupportedFormat(format!("ciphertext has no {} layer", name)))
+    }
+
+    /// Fail if the ciphertext went through a layer not named in `known`
+    /// Out-of-tree layers are only available once their plugin is loaded
+    pub fn require_layers(&self, known: &[LayerDescriptor]) -> Result<()> {
+        match self.descriptors.iter().find(|d| !known.iter().any(|k| k.name == d.name)) {
+            Some(missing) => Err(HybridGuardError::UnsupportedFormat(format!(
+                "ciphertext went through the {} layer, which is not loaded (load its plugin to decrypt)",
+                missing.name
+            ))),
+            None => Ok(()),
+        }
+    }
+    
+    /// Serialize into the current container format
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        container::encode(self)
+    }
+    
+    /// Parse a container of any supported format version
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        container::decode(bytes)
+    }
+}
+
+/// What `metadata()` returns for containers without any
+static NO_METADATA: Metadata = Metadata::EMPTY;
+
+/// SHA3-256 of a ciphertext body, as stored in `content_digest`
+pub(crate) fn content_digest(ciphertext: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"HybridGuard-content-digest");
+    hasher.update(ciphertext);
+    hasher.finalize().into()
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Builds `EncryptedData` fixtures field by field (`testing` feature)
+/// `build` checks the same invariants as deserialization
+#[cfg(any(test, feature = "testing"))]
+pub struct EncryptedDataBuilder {
+    fields: EncryptedDataFields,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl EncryptedDataBuilder {
+    /// Start from `ciphertext` with the current layers, version and time
+    pub fn new(ciphertext: Vec<u8>) -> Self {
+        EncryptedData::new(ciphertext).into()
+    }
+    
+    pub fn ciphertext(mut self, ciphertext: Vec<u8>) -> Self {
+        self.fields.content_digest = Some(content_digest(&ciphertext));
+        self.fields.ciphertext = ciphertext;
+        self
+    }
+    
+    pub fn layers(mut self, layers: Vec<String>) -> Self {
+        self.fields.layers = layers;
+        self
+    }
+    
+    pub fn version(mut self, version: &str) -> Self {
+        self.fields.version = version.to_string();
+        self
+    }
+    
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.fields.timestamp = timestamp;
+        self
+    }
+    
+    pub fn descriptors(mut self, descriptors: Vec<LayerDescriptor>) -> Self {
+        self.fields.descriptors = descriptors;
+        self
+    }
+    
+    pub fn key_id(mut self, key_id: &str) -> Self {
+        self.fields.key_id = Some(key_id.to_string());
+        self
+    }
+    
+    pub fn migrated_from(mut self, note: MigrationNote) -> Self {
+        self.fields.migrated_from = Some(note);
+        self
+    }
+    
+    /// Drop the content digest, as containers before format v6 had none
+    pub fn without_content_digest(mut self) -> Self {
+        self.fields.content_digest = None;
+        self
+    }
+    
+    pub fn wrapped_key(mut self, wrapped: WrappedFileKey) -> Self {
+        self.fields.wrapped_key = Some(wrapped);
+        self
+    }
+    
+    pub fn source_snapshot(mut self, snapshot: SourceSnapshot) -> Self {
+        self.fields.source_snapshot = Some(snapshot);
+        self
+    }
+    
+    pub fn label(mut self, label: &str) -> Self {
+        self.fields.label = Some(label.to_string());
+        self
+    }
+    
+    /// Content type in the clear, as builds before it was sealed wrote it
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.fields.content_type = Some(content_type.to_string());
+        self
+    }
+    
+    pub fn passphrase(mut self, passphrase: PassphraseKdf) -> Self {
+        self.fields.passphrase = Some(passphrase);
+        self
+    }
+    
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.fields.metadata = Some(metadata);
+        self
+    }
+    
+    /// Tag the fixture as the pipeline would; the tag covers the fields set so far
+    pub fn tag(self, keys: &LayerKeys) -> Result<Self> {
+        Ok(self.build()?.with_tag(keys).into())
+    }
+    
+    /// Add the verification tag as the pipeline would, under the master keys
+    pub fn verification_tag(self, master_keys: &LayerKeys) -> Result<Self> {
+        Ok(self.build()?.with_verification_tag(master_keys).into())
+    }
+    
+    pub fn build(self) -> Result<EncryptedData> {
+        self.fields.validate()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl From<EncryptedData> for EncryptedDataBuilder {
+    fn from(data: EncryptedData) -> Self {
+        Self {
+            fields: EncryptedDataFields {
+                ciphertext: data.ciphertext,
+                layers: data.layers,
+                version: data.version,
+                timestamp: data.timestamp,
+                descriptors: data.descriptors,
+                key_id: data.key_id,
+                migrated_from: data.migrated_from,
+                tag: data.tag,
+                timestamp_token: data.timestamp_token,
+                content_digest: data.content_digest,
+                wrapped_key: data.wrapped_key,
+                source_snapshot: data.source_snapshot,
+                label: data.label,
+                content_type: data.content_type,
+                passphrase: data.passphrase,
+                verification_tag: data.verification_tag,
+                metadata: data.metadata,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn body(fields: (Vec<u8>, Vec<String>, &str)) -> Vec<u8> {
+        let (ciphertext, layers, version) = fields;
+        let descriptors = layers::current_descriptors();
+        let fields = (ciphertext, layers, version, 1u64, descriptors, None::<String>, None::<MigrationNote>);
+        let tail = (
+            (None::<[u8; TAG_LEN]>, None::<TimestampToken>, None::<[u8; DIGEST_LEN]>),
+            (None::<WrappedFileKey>, None::<SourceSnapshot>, None::<String>, None::<String>, None::<PassphraseKdf>),
+            (None::<[u8; TAG_LEN]>, None::<Metadata>),
+        );
+        bincode::serialize(&(fields, tail)).unwrap()
+    }
+    
+    fn names() -> Vec<String> {
+        layers::current_descriptors().into_iter().map(|d| d.name).collect()
+    }
+    
+    #[test]
+    fn test_deserialize_accepts_valid_body() {
+        let data: EncryptedData = bincode::deserialize(&body((vec![1, 2], names(), "0.2.0"))).unwrap();
+        assert_eq!(data.ciphertext(), &[1, 2]);
+        assert_eq!(data.version(), "0.2.0");
+    }
+    
+    #[test]
+    fn test_deserialize_rejects_empty_ciphertext() {
+        let err = bincode::deserialize::<EncryptedData>(&body((Vec::new(), names(), "0.2.0"))).unwrap_err();
+        assert!(err.to_string().contains("ciphertext is empty"));
+    }
+    
+    #[test]
+    fn test_deserialize_rejects_missing_layers() {
+        let err = bincode::deserialize::<EncryptedData>(&body((vec![1], Vec::new(), "0.2.0"))).unwrap_err();
+        assert!(err.to_string().contains("no layers"));
+    }
+    
+    #[test]
+    fn test_deserialize_rejects_unknown_version() {
+        let err = bincode::deserialize::<EncryptedData>(&body((vec![1], names(), "9.0.0"))).unwrap_err();
+        assert!(err.to_string().contains("unknown HybridGuard version"));
+    }
+    
+    #[test]
+    fn test_builder_validates() {
+        let data = EncryptedDataBuilder::new(vec![3; 8]).key_id("hg-fixture").timestamp(42).build().unwrap();
+        assert_eq!(data.key_id(), Some("hg-fixture"));
+        assert_eq!(data.timestamp(), 42);
+        
+        assert!(EncryptedDataBuilder::from(data).ciphertext(Vec::new()).build().is_err());
+    }
+    
+    #[test]
+    fn test_timestamp_digest_covers_fields_not_the_token() {
+        let data = EncryptedDataBuilder::new(vec![3; 8]).key_id("hg-fixture").timestamp(42).build().unwrap();
+        let digest = data.timestamp_digest().unwrap();
+        
+        // Fields outside the tags count as much as those inside
+        let edits = [
+            EncryptedDataBuilder::from(data.clone()).timestamp(43),
+            EncryptedDataBuilder::from(data.clone()).key_id("hg-other"),
+            EncryptedDataBuilder::from(data.clone()).label("legal-hold"),
+            EncryptedDataBuilder::from(data.clone()).ciphertext(vec![4; 8]),
+        ];
+        for edit in edits {
+            assert_ne!(edit.build().unwrap().timestamp_digest().unwrap(), digest);
         }
+        
+        let token = TimestampToken { authority: "test".to_string(), time: 1, serial: 1, digest, proof: Vec::new() };
+        assert_eq!(data.with_timestamp_token(token).timestamp_digest().unwrap(), digest);
     }
 }
@@ -0,0 +1,179 @@
+// Secret key material container
+// Keeps key bytes out of swap where the OS allows it and wipes them on drop
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroize;
+
+/// Set once any attempt to lock secret memory has failed in this process
+static LOCK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Heap buffer holding secret bytes
+///
+/// The buffer is locked into RAM (`mlock` on Unix, `VirtualLock` on Windows)
+/// on a best-effort basis: if the OS refuses, e.g. because of `RLIMIT_MEMLOCK`,
+/// the bytes are still usable and `is_locked()` reports `false`.
+/// Contents are zeroized before the memory is unlocked and freed.
+pub struct SecretBytes {
+    bytes: Vec<u8>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    /// Take ownership of `bytes` and try to lock them into memory
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        // Never reallocate after locking: the lock covers exactly this buffer
+        bytes.shrink_to_fit();
+        let locked = lock_region(bytes.as_ptr(), bytes.len());
+        if !locked {
+            LOCK_FAILED.store(true, Ordering::Relaxed);
+            log::debug!("Could not lock {} bytes of key material into memory", bytes.len());
+        }
+        Self { bytes, locked }
+    }
+
+    /// View the secret bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Whether this buffer is currently locked into RAM
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        Self::new(self.bytes.clone())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    /// Compares without an early exit on the first differing byte
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes.len() == other.bytes.len()
+            && self.bytes.iter().zip(other.bytes.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.bytes.len())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // Zeroizing empties the vector, so unlock the length `new` locked
+        let locked_len = self.bytes.len();
+        self.bytes.zeroize();
+        if self.locked {
+            unlock_region(self.bytes.as_ptr(), locked_len);
+        }
+    }
+}
+
+/// Whether every attempt to lock secret memory so far has succeeded
+pub fn memory_locked() -> bool {
+    !LOCK_FAILED.load(Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+fn lock_region(ptr: *const u8, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    unsafe { libc::mlock(ptr as *const libc::c_void, len) == 0 }
+}
+
+#[cfg(unix)]
+fn unlock_region(ptr: *const u8, len: usize) {
+    if len != 0 {
+        unsafe {
+            libc::munlock(ptr as *const libc::c_void, len);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lock_region(ptr: *const u8, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    unsafe { windows_sys::Win32::System::Memory::VirtualLock(ptr as *const _, len) != 0 }
+}
+
+#[cfg(windows)]
+fn unlock_region(ptr: *const u8, len: usize) {
+    if len != 0 {
+        unsafe {
+            windows_sys::Win32::System::Memory::VirtualUnlock(ptr as *const _, len);
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock_region(_ptr: *const u8, len: usize) -> bool {
+    len == 0
+}
+
+#[cfg(not(any(unix, windows)))]
+fn unlock_region(_ptr: *const u8, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contents_unchanged() {
+        let secret = SecretBytes::new(vec![1, 2, 3, 4]);
+        assert_eq!(secret.as_bytes(), &[1, 2, 3, 4]);
+        assert_eq!(&secret[..], &[1, 2, 3, 4]);
+        assert_eq!(secret.clone(), secret);
+    }
+
+    #[test]
+    fn test_empty_is_locked() {
+        assert!(SecretBytes::new(Vec::new()).is_locked());
+    }
+
+    #[test]
+    fn test_flag_tracks_failures() {
+        let secret = SecretBytes::new(vec![0xAB; 64]);
+        // A failed lock must always be visible through the process-wide flag
+        if !secret.is_locked() {
+            assert!(!memory_locked());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_locks_when_rlimit_allows() {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        unsafe {
+            libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit);
+        }
+        let secret = SecretBytes::new(vec![0x11; 32]);
+        if limit.rlim_cur == libc::RLIM_INFINITY || limit.rlim_cur >= 1024 * 1024 {
+            assert!(secret.is_locked());
+        }
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let secret = SecretBytes::new(vec![0x42; 8]);
+        assert_eq!(format!("{:?}", secret), "SecretBytes([REDACTED; 8])");
+    }
+}
@@ -0,0 +1,374 @@
+// File format sniffing
+// Recognizes common non-HybridGuard formats by their magic bytes so the CLI
+// can explain what a file is instead of failing deep inside deserialization
+// `content_type` does the same for decrypted plaintext, so a container can
+// carry an authenticated type tag and decrypt can flag executables
+
+use crate::crypto::{container, encoding};
+use serde::Serialize;
+use crate::sparse;
+use crate::storage::erasure;
+use crate::streaming::chunked;
+
+/// Smallest possible legacy serialized `EncryptedData`: four bincode
+/// length/integer fields (ciphertext length, layer count, version length, timestamp)
+pub const MIN_CONTAINER_LEN: usize = 32;
+
+/// Leading plaintext bytes `content_type` looks at
+pub const CONTENT_SNIFF_LEN: usize = 8192;
+
+/// Result of looking at the first bytes of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// Looks like a HybridGuard ciphertext
+    HybridGuard,
+    /// Zero-length file
+    Empty,
+    /// Shorter than any HybridGuard ciphertext can be
+    TooShort(usize),
+    /// ASCII-armored OpenPGP message
+    PgpArmored,
+    /// Binary OpenPGP message
+    PgpBinary,
+    /// age encrypted file (binary or armored)
+    Age,
+    /// ZIP archive
+    Zip,
+    /// gzip stream
+    Gzip,
+    /// PDF document
+    Pdf,
+    /// PNG image
+    Png,
+    /// Not recognized
+    Unknown,
+}
+
+impl FileKind {
+    /// Short human readable description of the format
+    pub fn description(&self) -> String {
+        match self {
+            FileKind::HybridGuard => "HybridGuard encrypted file".to_string(),
+            FileKind::Empty => "empty file".to_string(),
+            FileKind::TooShort(len) => format!("truncated or too-short file ({} bytes)", len),
+            FileKind::PgpArmored => "ASCII-armored PGP/GPG message".to_string(),
+            FileKind::PgpBinary => "binary PGP/GPG message".to_string(),
+            FileKind::Age => "age encrypted file".to_string(),
+            FileKind::Zip => "ZIP archive".to_string(),
+            FileKind::Gzip => "gzip compressed file".to_string(),
+            FileKind::Pdf => "PDF document".to_string(),
+            FileKind::Png => "PNG image".to_string(),
+            FileKind::Unknown => "unrecognized data".to_string(),
+        }
+    }
+
+    /// Explanation for the user when this file was given to `decrypt`,
+    /// or `None` if the file should be handed to the deserializer
+    pub fn rejection_hint(&self) -> Option<String> {
+        match self {
+            FileKind::HybridGuard | FileKind::Unknown => None,
+            FileKind::Empty => Some("input file is empty".to_string()),
+            FileKind::TooShort(len) => Some(format!(
+                "input is only {} bytes, a HybridGuard file is at least {} bytes (truncated download?)",
+                len, MIN_CONTAINER_LEN
+            )),
+            FileKind::PgpArmored | FileKind::PgpBinary => Some(format!(
+                "this looks like a GPG file ({}), not HybridGuard; decrypt it with `gpg --decrypt`",
+                self.description()
+            )),
+            FileKind::Age => Some("this looks like an age file, not HybridGuard; decrypt it with `age -d`".to_string()),
+            other => Some(format!("this looks like a {}, not a HybridGuard file", other.description())),
+        }
+    }
+}
+
+/// Identify the format of a file from its contents
+pub fn identify(data: &[u8]) -> FileKind {
+    if data.is_empty() {
+        return FileKind::Empty;
+    }
+
+    if data.starts_with(&container::MAGIC)
+        || erasure::is_sharded(data)
+        || sparse::is_sparse(data)
+        || chunked::is_chunked(data)
+        || encoding::is_text(data)
+    {
+        return FileKind::HybridGuard;
+    }
+    if data.starts_with(b"-----BEGIN PGP") {
+        return FileKind::PgpArmored;
+    }
+    if data.starts_with(b"age-encryption.org/") || data.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----") {
+        return FileKind::Age;
+    }
+    if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        return FileKind::Zip;
+    }
+    if data.starts_with(&[0x1f, 0x8b]) {
+        return FileKind::Gzip;
+    }
+    if data.starts_with(b"%PDF-") {
+        return FileKind::Pdf;
+    }
+    if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]) {
+        return FileKind::Png;
+    }
+
+    if data.len() < MIN_CONTAINER_LEN {
+        return FileKind::TooShort(data.len());
+    }
+
+    if looks_like_bincode_container(data) {
+        return FileKind::HybridGuard;
+    }
+    if looks_like_pgp_packet(data) {
+        return FileKind::PgpBinary;
+    }
+
+    FileKind::Unknown
+}
+
+/// What plaintext holds, as far as its first bytes tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentType {
+    /// ELF executable or shared object
+    Elf,
+    /// Windows PE executable (MZ header)
+    Pe,
+    /// Mach-O executable, thin or universal
+    MachO,
+    /// Script with a `#!` interpreter line
+    Script,
+    Zip,
+    Gzip,
+    Pdf,
+    Png,
+    Jpeg,
+    /// UTF-8 text without NUL bytes
+    Text,
+    Empty,
+    Unknown,
+}
+
+impl ContentType {
+    const ALL: [ContentType; 12] = [
+        ContentType::Elf,
+        ContentType::Pe,
+        ContentType::MachO,
+        ContentType::Script,
+        ContentType::Zip,
+        ContentType::Gzip,
+        ContentType::Pdf,
+        ContentType::Png,
+        ContentType::Jpeg,
+        ContentType::Text,
+        ContentType::Empty,
+        ContentType::Unknown,
+    ];
+
+    /// Name stored in the container tag
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentType::Elf => "elf",
+            ContentType::Pe => "pe",
+            ContentType::MachO => "mach-o",
+            ContentType::Script => "script",
+            ContentType::Zip => "zip",
+            ContentType::Gzip => "gzip",
+            ContentType::Pdf => "pdf",
+            ContentType::Png => "png",
+            ContentType::Jpeg => "jpeg",
+            ContentType::Text => "text",
+            ContentType::Empty => "empty",
+            ContentType::Unknown => "unknown",
+        }
+    }
+
+    /// The type a stored tag names, or None for a name this version does not know
+    pub fn from_name(name: &str) -> Option<ContentType> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    /// Whether running the plaintext would execute it
+    pub fn is_executable(&self) -> bool {
+        matches!(self, ContentType::Elf | ContentType::Pe | ContentType::MachO | ContentType::Script)
+    }
+}
+
+impl std::fmt::Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Identify plaintext from its first `CONTENT_SNIFF_LEN` bytes
+pub fn content_type(data: &[u8]) -> ContentType {
+    let head = &data[..data.len().min(CONTENT_SNIFF_LEN)];
+    if head.is_empty() {
+        return ContentType::Empty;
+    }
+    if head.starts_with(b"\x7fELF") {
+        return ContentType::Elf;
+    }
+    if head.starts_with(b"MZ") {
+        return ContentType::Pe;
+    }
+    // 32/64-bit in either byte order, and universal (fat) binaries
+    const MACH_O: [[u8; 4]; 5] = [
+        [0xfe, 0xed, 0xfa, 0xce],
+        [0xfe, 0xed, 0xfa, 0xcf],
+        [0xce, 0xfa, 0xed, 0xfe],
+        [0xcf, 0xfa, 0xed, 0xfe],
+        [0xca, 0xfe, 0xba, 0xbe],
+    ];
+    if MACH_O.iter().any(|magic| head.starts_with(magic)) {
+        return ContentType::MachO;
+    }
+    if head.starts_with(b"#!") {
+        return ContentType::Script;
+    }
+    match identify(head) {
+        FileKind::Zip => return ContentType::Zip,
+        FileKind::Gzip => return ContentType::Gzip,
+        FileKind::Pdf => return ContentType::Pdf,
+        FileKind::Png => return ContentType::Png,
+        _ => {}
+    }
+    if head.starts_with(&[0xff, 0xd8, 0xff]) {
+        return ContentType::Jpeg;
+    }
+    // The head may end inside a multi-byte character
+    let utf8 = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if utf8 && !head.contains(&0) {
+        return ContentType::Text;
+    }
+    ContentType::Unknown
+}
+
+/// Structural check of a bincode-serialized `EncryptedData`: the leading
+/// ciphertext length must fit in the file and be followed by a small layer count
+fn looks_like_bincode_container(data: &[u8]) -> bool {
+    let ct_len = match read_u64_le(data, 0) {
+        Some(len) => len,
+        None => return false,
+    };
+    let layers_offset = match (ct_len as usize).checked_add(8) {
+        Some(offset) if ct_len <= data.len() as u64 => offset,
+        _ => return false,
+    };
+    match read_u64_le(data, layers_offset) {
+        Some(layer_count) => layer_count <= 16,
+        None => false,
+    }
+}
+
+/// OpenPGP packet headers for the packet types a message starts with:
+/// PKESK (1), SKESK (3), compressed data (8) and SEIPD (18)
+fn looks_like_pgp_packet(data: &[u8]) -> bool {
+    let first = data[0];
+    if first & 0x80 == 0 {
+        return false;
+    }
+    let tag = if first & 0x40 != 0 {
+        first & 0x3f
+    } else {
+        (first >> 2) & 0x0f
+    };
+    matches!(tag, 1 | 3 | 8 | 18)
+}
+
+fn read_u64_le(data: &[u8], offset: usize) -> Option<u64> {
+    let end = offset.checked_add(8)?;
+    Some(container::u64_at(data.get(offset..end)?, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn padded(header: &[u8]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(64, 0xAA);
+        data
+    }
+
+    #[test]
+    fn test_identify_foreign_formats() {
+        assert_eq!(identify(&padded(b"-----BEGIN PGP MESSAGE-----\n")), FileKind::PgpArmored);
+        assert_eq!(identify(&padded(&[0x85, 0x01, 0x0c, 0x03])), FileKind::PgpBinary);
+        assert_eq!(identify(&padded(&[0xc1, 0x0c, 0x03])), FileKind::PgpBinary);
+        assert_eq!(identify(&padded(b"age-encryption.org/v1\n")), FileKind::Age);
+        assert_eq!(identify(&padded(b"PK\x03\x04")), FileKind::Zip);
+        assert_eq!(identify(&padded(&[0x1f, 0x8b, 0x08])), FileKind::Gzip);
+        assert_eq!(identify(&padded(b"%PDF-1.7")), FileKind::Pdf);
+        assert_eq!(identify(&padded(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])), FileKind::Png);
+    }
+
+    #[test]
+    fn test_identify_sizes() {
+        assert_eq!(identify(&[]), FileKind::Empty);
+        assert_eq!(identify(&[0u8; 10]), FileKind::TooShort(10));
+        assert!(FileKind::Empty.rejection_hint().is_some());
+        assert!(FileKind::TooShort(10).rejection_hint().unwrap().contains("10 bytes"));
+    }
+
+    #[test]
+    fn test_identify_hybridguard() {
+        let encrypted = crate::crypto::EncryptedData::new(vec![7u8; 100]);
+        assert_eq!(identify(&encrypted.to_bytes().unwrap()), FileKind::HybridGuard);
+        
+        // Legacy files have no magic and are recognized structurally
+        let legacy = bincode::serialize(&encrypted).unwrap();
+        assert_eq!(identify(&legacy), FileKind::HybridGuard);
+        assert!(FileKind::HybridGuard.rejection_hint().is_none());
+        
+        let sharded = erasure::encode(&encrypted.to_bytes().unwrap(), erasure::Redundancy::new(4, 2).unwrap()).unwrap();
+        assert_eq!(identify(&sharded), FileKind::HybridGuard);
+        assert_eq!(identify(&sparse::MAGIC), FileKind::HybridGuard);
+        assert_eq!(identify(&chunked::MAGIC), FileKind::HybridGuard);
+    }
+
+    #[test]
+    fn test_content_types() {
+        assert_eq!(content_type(b"\x7fELF\x02\x01\x01\0"), ContentType::Elf);
+        assert_eq!(content_type(b"MZ\x90\0"), ContentType::Pe);
+        assert_eq!(content_type(&[0xcf, 0xfa, 0xed, 0xfe, 7, 0, 0, 1]), ContentType::MachO);
+        assert_eq!(content_type(b"#!/bin/sh\nrm -rf /tmp/x\n"), ContentType::Script);
+        assert_eq!(content_type(b"%PDF-1.7"), ContentType::Pdf);
+        assert_eq!(content_type(&[0xff, 0xd8, 0xff, 0xe0]), ContentType::Jpeg);
+        assert_eq!(content_type("quarterly numbers: 12 €".as_bytes()), ContentType::Text);
+        assert_eq!(content_type(b"text\0with a NUL"), ContentType::Unknown);
+        assert_eq!(content_type(b""), ContentType::Empty);
+        assert!(ContentType::Script.is_executable() && !ContentType::Text.is_executable());
+    }
+
+    #[test]
+    fn test_content_type_reads_only_the_head() {
+        // A character cut at the sniff boundary is still text
+        let mut text = vec![b'a'; CONTENT_SNIFF_LEN - 1];
+        text.extend_from_slice("€".as_bytes());
+        assert_eq!(content_type(&text), ContentType::Text);
+        text.extend_from_slice(&[0; 16]);
+        assert_eq!(content_type(&text), ContentType::Text);
+    }
+
+    #[test]
+    fn test_content_type_names_round_trip() {
+        for content in ContentType::ALL {
+            assert_eq!(ContentType::from_name(content.name()), Some(content));
+            assert_eq!(serde_json::to_value(content).unwrap(), content.name());
+        }
+        assert_eq!(ContentType::from_name("exe"), None);
+    }
+
+    #[test]
+    fn test_gpg_hint_mentions_gpg() {
+        let hint = FileKind::PgpBinary.rejection_hint().unwrap();
+        assert!(hint.contains("GPG"));
+    }
+}
@@ -0,0 +1,76 @@
+// Keyed SHA3-256 authentication tags
+// SHA3 has no length extension, so hashing a secret key ahead of the message
+// is a sound MAC; each use gets its own key through its `KeyPurpose`
+
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::secret::SecretBytes;
+use sha3::{Digest, Sha3_256};
+
+/// Bytes in every tag
+pub const TAG_LEN: usize = 32;
+
+/// Holder of tag keys: all layer keys, or a verification key carrying only
+/// the purposes integrity checks use
+pub(crate) trait MacKeys {
+    /// Key derived for `purpose`
+    fn mac_key(&self, purpose: KeyPurpose) -> SecretBytes;
+}
+
+impl MacKeys for LayerKeys {
+    fn mac_key(&self, purpose: KeyPurpose) -> SecretBytes {
+        self.derive_key(purpose)
+    }
+}
+
+/// Hasher primed with the key for `purpose`
+pub(crate) fn keyed_hasher<K: MacKeys + ?Sized>(keys: &K, purpose: KeyPurpose) -> Sha3_256 {
+    let mut hasher = Sha3_256::new();
+    hasher.update(keys.mac_key(purpose).as_bytes());
+    hasher
+}
+
+/// Compare two tags without stopping at the first differing byte
+pub(crate) fn tags_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hkdf::{KdfScheme, KeyDerivation};
+
+    #[test]
+    fn test_purposes_and_keys_separate_tags() {
+        let keys = KeyDerivation::new(vec![1u8; 32]).derive_all_keys().unwrap();
+        let other = KeyDerivation::new(vec![2u8; 32]).derive_all_keys().unwrap();
+        let tag = |keys: &LayerKeys, domain: &'static str| {
+            let mut hasher = keyed_hasher(keys, KeyPurpose::Mac(domain));
+            hasher.update(b"message");
+            hasher.finalize().to_vec()
+        };
+
+        assert_eq!(tag(&keys, "a"), tag(&keys, "a"));
+        assert_ne!(tag(&keys, "a"), tag(&keys, "b"));
+        assert_ne!(tag(&keys, "a"), tag(&other, "a"));
+        assert!(tags_match(&tag(&keys, "a"), &tag(&keys, "a")));
+        assert!(!tags_match(&tag(&keys, "a"), &tag(&other, "a")));
+    }
+
+    #[cfg(feature = "legacy-kdf-v1")]
+    #[test]
+    fn test_v1_tags_are_unchanged() {
+        let keys = KeyDerivation::with_scheme(vec![1u8; 32], KdfScheme::V1).derive_all_keys().unwrap();
+        let mut key = Sha3_256::new();
+        key.update(b"HybridGuard-container-tag-key");
+        for layer_key in keys.in_order() {
+            key.update(layer_key.as_bytes());
+        }
+        let mut legacy = Sha3_256::new();
+        legacy.update(key.finalize());
+
+        let mut hasher = keyed_hasher(&keys, KeyPurpose::Mac("container-tag"));
+        legacy.update(b"message");
+        hasher.update(b"message");
+        assert_eq!(hasher.finalize(), legacy.finalize());
+    }
+}
@@ -0,0 +1,287 @@
+// Trusted timestamps for encrypted containers
+// The container's own `timestamp` field is self-reported; a timestamp
+// authority instead vouches that a digest of the finished container existed
+// at a point in time, and the token it returns travels with the container
+
+use crate::crypto::container::{le_u16, le_u64, u16_at};
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::crypto::EncryptedData;
+use crate::error::{HybridGuardError, Result};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Bytes in a container digest
+pub const DIGEST_LEN: usize = 32;
+
+/// Magic bytes at the start of a local authority counter file
+const COUNTER_MAGIC: [u8; 4] = *b"HGTC";
+
+/// Counter file format written by this build
+const COUNTER_VERSION: u16 = 1;
+
+/// Domain label of local authority proofs
+const LOCAL_PROOF_LABEL: &[u8] = b"HybridGuard-local-timestamp";
+
+/// Upper bound on a counter file
+const MAX_COUNTER_LEN: u64 = 1024;
+
+/// Evidence from an authority that a digest existed at `time`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimestampToken {
+    /// Identifies the authority that issued the token
+    pub authority: String,
+    /// Seconds since the Unix epoch, as asserted by the authority
+    pub time: u64,
+    /// Issue order within the authority
+    pub serial: u64,
+    /// Container digest the token covers
+    pub digest: [u8; DIGEST_LEN],
+    /// Authority-specific proof, e.g. a MAC or a DER-encoded RFC 3161 response
+    pub proof: Vec<u8>,
+}
+
+/// Issues and checks timestamp tokens
+/// RFC 3161 clients and other services plug in by implementing this trait
+pub trait TimestampAuthority: Send + Sync {
+    /// Vouch for `digest` at the current time
+    fn timestamp(&self, digest: &[u8; DIGEST_LEN]) -> Result<TimestampToken>;
+
+    /// Check that `token` was issued by this authority; the digest has
+    /// already been matched against the container
+    fn verify(&self, token: &TimestampToken) -> Result<()>;
+}
+
+/// Timestamp `data` with `authority`, replacing any earlier token
+pub fn stamp(data: EncryptedData, authority: &dyn TimestampAuthority) -> Result<EncryptedData> {
+    let digest = data.timestamp_digest()?;
+    let token = authority.timestamp(&digest)?;
+    if token.digest != digest {
+        return Err(HybridGuardError::Integrity(format!(
+            "timestamp authority '{}' returned a token for a different digest",
+            token.authority
+        )));
+    }
+    Ok(data.with_timestamp_token(token))
+}
+
+/// Check that `data` carries a token from `authority` covering its current contents
+pub fn verify_timestamp<'a>(data: &'a EncryptedData, authority: &dyn TimestampAuthority) -> Result<&'a TimestampToken> {
+    let token = data
+        .timestamp_token()
+        .ok_or_else(|| HybridGuardError::Integrity("container has no timestamp token".to_string()))?;
+    if !tag::tags_match(&token.digest, &data.timestamp_digest()?) {
+        return Err(HybridGuardError::Integrity(
+            "container was modified after it was timestamped".to_string(),
+        ));
+    }
+    authority.verify(token)?;
+    Ok(token)
+}
+
+/// Offline reference authority
+/// Tokens carry a keyed SHA3-256 proof over their fields; a counter file
+/// keeps serial numbers increasing and time from running backwards
+pub struct LocalSigningAuthority {
+    name: String,
+    key: SecretBytes,
+    counter_path: PathBuf,
+    // Serializes issuing so two threads never hand out the same serial
+    issuing: Mutex<()>,
+}
+
+/// Persisted state of a local authority
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counter {
+    serial: u64,
+    time: u64,
+}
+
+impl LocalSigningAuthority {
+    /// Authority named `name` signing with `key`; serials are kept in `counter_path`
+    pub fn new(name: &str, key: &[u8], counter_path: impl Into<PathBuf>) -> Result<Self> {
+        if key.len() < 32 {
+            return Err(HybridGuardError::InvalidInput(
+                "timestamp signing key must be at least 32 bytes".to_string(),
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            key: SecretBytes::new(key.to_vec()),
+            counter_path: counter_path.into(),
+            issuing: Mutex::new(()),
+        })
+    }
+
+    /// Last serial this authority issued, 0 before the first token
+    pub fn issued(&self) -> Result<u64> {
+        Ok(load_counter(&self.counter_path)?.serial)
+    }
+
+    fn proof(&self, token: &TimestampToken) -> [u8; TAG_LEN] {
+        let mut key = Sha3_256::new();
+        key.update(LOCAL_PROOF_LABEL);
+        key.update(self.key.as_bytes());
+
+        let mut hasher = Sha3_256::new();
+        hasher.update(key.finalize());
+        hasher.update(le_u64(token.authority.len() as u64));
+        hasher.update(token.authority.as_bytes());
+        hasher.update(le_u64(token.time));
+        hasher.update(le_u64(token.serial));
+        hasher.update(token.digest);
+        hasher.finalize().into()
+    }
+}
+
+impl TimestampAuthority for LocalSigningAuthority {
+    fn timestamp(&self, digest: &[u8; DIGEST_LEN]) -> Result<TimestampToken> {
+        let _issuing = self.issuing.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut counter = load_counter(&self.counter_path)?;
+        counter.serial += 1;
+        counter.time = counter.time.max(super::now());
+        // Persist before handing the token out, so a crash never reuses a serial
+        save_counter(&self.counter_path, &counter)?;
+
+        let mut token = TimestampToken {
+            authority: self.name.clone(),
+            time: counter.time,
+            serial: counter.serial,
+            digest: *digest,
+            proof: Vec::new(),
+        };
+        token.proof = self.proof(&token).to_vec();
+        Ok(token)
+    }
+
+    fn verify(&self, token: &TimestampToken) -> Result<()> {
+        if token.authority != self.name {
+            return Err(HybridGuardError::Integrity(format!(
+                "timestamp token was issued by '{}', not '{}'",
+                token.authority, self.name
+            )));
+        }
+        if !tag::tags_match(&token.proof, &self.proof(token)) {
+            return Err(HybridGuardError::Integrity("timestamp token proof does not verify".to_string()));
+        }
+        if token.serial > self.issued()? {
+            return Err(HybridGuardError::Integrity(format!(
+                "timestamp token serial {} was never issued",
+                token.serial
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn load_counter(path: &Path) -> Result<Counter> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Counter::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut bytes = Vec::new();
+    (&mut file).take(MAX_COUNTER_LEN).read_to_end(&mut bytes)?;
+    if bytes.len() < COUNTER_MAGIC.len() + 2 || bytes[..4] != COUNTER_MAGIC {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "{} is not a timestamp counter file",
+            path.display()
+        )));
+    }
+    let version = u16_at(&bytes, 4);
+    if version != COUNTER_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "timestamp counter format v{} is not supported (this build reads v{})",
+            version, COUNTER_VERSION
+        )));
+    }
+    bincode::deserialize(&bytes[6..])
+        .map_err(|e| HybridGuardError::Integrity(format!("timestamp counter {}: {}", path.display(), e)))
+}
+
+fn save_counter(path: &Path, counter: &Counter) -> Result<()> {
+    let body = bincode::serialize(counter).map_err(|e| HybridGuardError::Encryption(format!("timestamp counter: {}", e)))?;
+    let mut bytes = COUNTER_MAGIC.to_vec();
+    bytes.extend_from_slice(&le_u16(COUNTER_VERSION));
+    bytes.extend_from_slice(&body);
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = File::create(&temporary)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hkdf::KeyDerivation;
+
+    fn sealed() -> EncryptedData {
+        let keys = KeyDerivation::new(vec![6u8; 32]).derive_all_keys().unwrap();
+        EncryptedData::new(vec![1, 2, 3, 4]).with_key_id("hg-ts").with_tag(&keys)
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = LocalSigningAuthority::new("records-office", &[0x11; 32], dir.path().join("tsa.counter")).unwrap();
+
+        let first = stamp(sealed(), &authority).unwrap();
+        let second = stamp(sealed(), &authority).unwrap();
+        let token = verify_timestamp(&first, &authority).unwrap();
+        assert_eq!(token.serial, 1);
+        assert_eq!(verify_timestamp(&second, &authority).unwrap().serial, 2);
+        assert!(second.timestamp_token().unwrap().time >= token.time);
+        assert_eq!(authority.issued().unwrap(), 2);
+
+        // The token survives the container round trip
+        let decoded = EncryptedData::from_bytes(&first.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.timestamp_token(), first.timestamp_token());
+        assert!(verify_timestamp(&decoded, &authority).is_ok());
+    }
+
+    #[test]
+    fn test_unstamped_container_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = LocalSigningAuthority::new("records-office", &[0x11; 32], dir.path().join("tsa.counter")).unwrap();
+        assert!(matches!(verify_timestamp(&sealed(), &authority), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_modified_container_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = LocalSigningAuthority::new("records-office", &[0x11; 32], dir.path().join("tsa.counter")).unwrap();
+        let stamped = stamp(sealed(), &authority).unwrap();
+        let token = stamped.timestamp_token().unwrap().clone();
+
+        // Swap the ciphertext but keep the old token
+        let modified = EncryptedData::new(vec![9, 9, 9, 9]).with_key_id("hg-ts").with_timestamp_token(token);
+        let err = verify_timestamp(&modified, &authority).unwrap_err();
+        assert!(err.to_string().contains("modified after"));
+
+        // Re-timestamping the modified container with a look-alike authority does not help
+        let forger = LocalSigningAuthority::new("records-office", &[0x22; 32], dir.path().join("forger.counter")).unwrap();
+        let restamped = stamp(modified, &forger).unwrap();
+        let err = verify_timestamp(&restamped, &authority).unwrap_err();
+        assert!(err.to_string().contains("proof does not verify"));
+    }
+
+    #[test]
+    fn test_unissued_serial_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = LocalSigningAuthority::new("records-office", &[0x11; 32], dir.path().join("tsa.counter")).unwrap();
+        let stamped = stamp(sealed(), &authority).unwrap();
+
+        // Rolling the counter file back makes the existing token look forged
+        fs::remove_file(dir.path().join("tsa.counter")).unwrap();
+        assert!(verify_timestamp(&stamped, &authority).unwrap_err().to_string().contains("never issued"));
+    }
+}
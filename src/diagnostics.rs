@@ -0,0 +1,576 @@
+// Installation and environment diagnostics
+// `doctor` answers the questions every support request starts with: which
+// build this is, whether liboqs provides the KEMs the layers need, whether
+// randomness and a full encrypt/decrypt round trip work, whether this host
+// reads and writes little-endian containers like every other, whether the key file
+// is where it should be and private, and whether the output location has
+// room. Everything the checks ask of the environment goes through `Probes`,
+// so tests can make any single check fail; the CLI only formats the report.
+
+use crate::crypto::container;
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::Result;
+use crate::fsutil;
+use crate::key_manager::permissions::{self, PRIVATE_FILE_MODE};
+use crate::key_manager::protector;
+use crate::key_manager::KeyManager;
+use crate::layers::oqs_support;
+use oqs::kem::Algorithm;
+use rand::RngCore;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+/// KEMs the layers instantiate, with the names support requests use
+const REQUIRED_KEMS: [(&str, Algorithm); 2] = [("ML-KEM-768", Algorithm::Kyber768), ("HQC-256", Algorithm::HqcRmrs256)];
+
+/// Bytes drawn twice by the RNG self-test
+const RNG_SAMPLE_LEN: usize = 32;
+
+/// Message sent through the round trip check
+const ROUND_TRIP_MESSAGE: &[u8] = b"hybridguard doctor round trip";
+
+/// Cargo features this binary was built with; the legacy readers are on by default
+const FEATURES: [(&str, bool); 12] = [
+    ("legacy-v0", cfg!(feature = "legacy-v0")),
+    ("legacy-pre-mac", cfg!(feature = "legacy-pre-mac")),
+    ("legacy-kdf-v1", cfg!(feature = "legacy-kdf-v1")),
+    ("slow-tests", cfg!(feature = "slow-tests")),
+    ("process-tests", cfg!(feature = "process-tests")),
+    ("testing", cfg!(feature = "testing")),
+    ("fixtures", cfg!(feature = "fixtures")),
+    ("interop-tests", cfg!(feature = "interop-tests")),
+    ("parallel", cfg!(feature = "parallel")),
+    ("memory-profile", cfg!(feature = "memory-profile")),
+    ("plugins", cfg!(feature = "plugins")),
+    ("nonce-audit", cfg!(feature = "nonce-audit")),
+];
+
+/// Outcome of one check; `Fail` ranks worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        })
+    }
+}
+
+/// One diagnostic and what it found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    /// Stable identifier, e.g. "liboqs" or "keystore"
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// What to do about a warning or failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Pass, detail: detail.into(), hint: None }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Warn, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+/// Version, target and features of this build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Architecture and operating system, e.g. "x86_64-linux"
+    pub target: String,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            features: FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect(),
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hybridguard {} ({})", self.version, self.target)?;
+        if self.features.is_empty() {
+            f.write_str(", no optional features")
+        } else {
+            write!(f, ", features: {}", self.features.join(", "))
+        }
+    }
+}
+
+/// Every check `run` made, in the order it made them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DoctorReport {
+    pub build: BuildInfo,
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// Worst status of any check
+    pub fn status(&self) -> CheckStatus {
+        self.checks.iter().map(|check| check.status).max().unwrap_or(CheckStatus::Pass)
+    }
+
+    /// The check called `name`
+    pub fn check(&self, name: &str) -> Option<&Check> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+}
+
+/// Where the checks look
+#[derive(Debug, Clone)]
+pub struct DoctorOptions {
+    /// Key file the CLI would load by default
+    pub key_file: PathBuf,
+    /// Directory outputs would be written to
+    pub output_dir: PathBuf,
+    /// Free space below which the disk check warns
+    pub min_free_bytes: u64,
+}
+
+impl Default for DoctorOptions {
+    fn default() -> Self {
+        Self {
+            key_file: PathBuf::from("./keys/hybridguard.keys"),
+            output_dir: PathBuf::from("."),
+            min_free_bytes: 1 << 30,
+        }
+    }
+}
+
+/// The environment as the checks see it; tests substitute a fake
+pub trait Probes {
+    /// Instantiate `algorithm` through liboqs
+    fn kem(&self, algorithm: Algorithm) -> Result<()>;
+
+    /// Fill `buf` from the operating system's RNG
+    fn fill_random(&self, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Encrypt `message` in memory under throwaway keys and decrypt it again
+    fn round_trip(&self, message: &[u8]) -> Result<Vec<u8>>;
+
+    /// Value of the environment variable `name`
+    fn env(&self, name: &str) -> Option<String>;
+
+    /// Whether stderr, where progress and colour go, is a terminal
+    fn stderr_is_terminal(&self) -> bool;
+
+    /// Bytes an unprivileged user may still write on the filesystem holding `dir`
+    fn available_bytes(&self, dir: &Path) -> io::Result<u64>;
+
+    /// Fail unless a new file can be created in `dir`
+    fn check_writable(&self, dir: &Path) -> io::Result<()>;
+}
+
+/// Probes backed by liboqs, the OS and the real filesystem
+pub struct SystemProbes;
+
+impl Probes for SystemProbes {
+    fn kem(&self, algorithm: Algorithm) -> Result<()> {
+        let layer = REQUIRED_KEMS.iter().find(|(_, required)| *required == algorithm).map_or("liboqs", |(name, _)| name);
+        oqs_support::kem(layer, algorithm).map(drop)
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) -> io::Result<()> {
+        rand::rngs::OsRng.try_fill_bytes(buf).map_err(io::Error::other)
+    }
+
+    fn round_trip(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let keys = KeyManager::from_master_key(&rand::random())?;
+        let encryptor = HybridGuardEncryptor::new();
+        let encrypted = encryptor.encrypt(message, keys.get_keys()?)?;
+        encryptor.decrypt(&encrypted, keys.get_keys()?)
+    }
+
+    fn env(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    fn stderr_is_terminal(&self) -> bool {
+        io::stderr().is_terminal()
+    }
+
+    fn available_bytes(&self, dir: &Path) -> io::Result<u64> {
+        fsutil::available_bytes(dir)
+    }
+
+    fn check_writable(&self, dir: &Path) -> io::Result<()> {
+        let probe = dir.join(format!(".hybridguard-doctor-{}", std::process::id()));
+        OpenOptions::new().write(true).create_new(true).open(&probe)?;
+        fs::remove_file(&probe)
+    }
+}
+
+/// Run every check against `probes`
+pub fn run(options: &DoctorOptions, probes: &dyn Probes) -> DoctorReport {
+    let build = BuildInfo::current();
+    let checks = vec![
+        Check::pass("build", build.to_string()),
+        check_kems(probes),
+        check_rng(probes),
+        check_round_trip(probes),
+        check_byte_order(),
+        check_keystore(&options.key_file),
+        check_locale(probes),
+        check_terminal(probes),
+        check_disk(probes, &options.output_dir, options.min_free_bytes),
+    ];
+    DoctorReport { build, checks }
+}
+
+fn check_kems(probes: &dyn Probes) -> Check {
+    let mut enabled = Vec::new();
+    let mut missing = Vec::new();
+    for (name, algorithm) in REQUIRED_KEMS {
+        match probes.kem(algorithm) {
+            Ok(()) => enabled.push(name),
+            Err(e) => missing.push(format!("{} ({})", name, e)),
+        }
+    }
+    if missing.is_empty() {
+        Check::pass("liboqs", format!("KEMs enabled: {}", enabled.join(", ")))
+    } else {
+        Check::fail(
+            "liboqs",
+            format!("unavailable: {}", missing.join(", ")),
+            "rebuild liboqs with these KEMs enabled (OQS_ENABLE_KEM_kyber and OQS_ENABLE_KEM_hqc)",
+        )
+    }
+}
+
+fn check_rng(probes: &dyn Probes) -> Check {
+    let hint = "the operating system's random source is broken; do not encrypt on this machine";
+    let mut first = [0u8; RNG_SAMPLE_LEN];
+    let mut second = [0u8; RNG_SAMPLE_LEN];
+    if let Err(e) = probes.fill_random(&mut first).and_then(|()| probes.fill_random(&mut second)) {
+        return Check::fail("rng", format!("could not read random bytes: {}", e), hint);
+    }
+    if first == [0u8; RNG_SAMPLE_LEN] || second == [0u8; RNG_SAMPLE_LEN] {
+        Check::fail("rng", "random source returned all zeros", hint)
+    } else if first == second {
+        Check::fail("rng", "random source repeated its output", hint)
+    } else {
+        Check::pass("rng", "two draws from the OS random source differ")
+    }
+}
+
+fn check_round_trip(probes: &dyn Probes) -> Check {
+    let hint = "run `hybridguard status` and report this build and platform";
+    match probes.round_trip(ROUND_TRIP_MESSAGE) {
+        Ok(plaintext) if plaintext == ROUND_TRIP_MESSAGE => Check::pass("round-trip", "in-memory encrypt and decrypt through all layers"),
+        Ok(_) => Check::fail("round-trip", "decryption returned different bytes", hint),
+        Err(e) => Check::fail("round-trip", e.to_string(), hint),
+    }
+}
+
+fn check_byte_order() -> Check {
+    match container::check_byte_order() {
+        Ok(()) => Check::pass(
+            "byte-order",
+            format!("{} host reads and rewrites a little-endian fixture container", container::HOST_BYTE_ORDER),
+        ),
+        Err(e) => Check::fail(
+            "byte-order",
+            format!("{} host: {}", container::HOST_BYTE_ORDER, e),
+            "files from this build would not open elsewhere; report this build and platform",
+        ),
+    }
+}
+
+fn check_keystore(key_file: &Path) -> Check {
+    let data = match fs::read(key_file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Check::warn(
+                "keystore",
+                format!("no key file at {}", key_file.display()),
+                "run `hybridguard keygen` or pass --key-file",
+            )
+        }
+        Err(e) => {
+            return Check::fail(
+                "keystore",
+                format!("cannot read {}: {}", key_file.display(), e),
+                "check the file's owner and permissions",
+            )
+        }
+    };
+    let found = match protector::protection(&data) {
+        Some(kind) => format!("{} (sealed by the {} protector)", key_file.display(), kind),
+        None => match KeyManager::from_bytes(&data) {
+            Ok(keys) => format!("{} (key ID {})", key_file.display(), keys.key_id()),
+            Err(e) => {
+                return Check::fail(
+                    "keystore",
+                    format!("{} does not load: {}", key_file.display(), e),
+                    "run `hybridguard key doctor` to see which fields are damaged",
+                )
+            }
+        },
+    };
+    match permissions::loose_mode(key_file) {
+        Ok(Some(mode)) => Check::warn(
+            "keystore",
+            format!("{} has mode {:o} and is readable by other users", found, mode),
+            format!("chmod {:o} {} or run with --fix-permissions", PRIVATE_FILE_MODE, key_file.display()),
+        ),
+        Ok(None) => Check::pass("keystore", found),
+        Err(e) => Check::warn("keystore", format!("{}; permissions unknown: {}", found, e), "check that the file is readable by its owner only"),
+    }
+}
+
+fn check_locale(probes: &dyn Probes) -> Check {
+    let hint = "set LANG to a UTF-8 locale, e.g. LANG=en_US.UTF-8, so symbols and file names print correctly";
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|name| probes.env(name).filter(|value| !value.is_empty()).map(|value| (*name, value)));
+    match locale {
+        Some((name, value)) => {
+            let lower = value.to_ascii_lowercase();
+            if lower.contains("utf-8") || lower.contains("utf8") {
+                Check::pass("locale", format!("{}={}", name, value))
+            } else {
+                Check::warn("locale", format!("{}={} is not UTF-8", name, value), hint)
+            }
+        }
+        None => Check::warn("locale", "no locale set", hint),
+    }
+}
+
+fn check_terminal(probes: &dyn Probes) -> Check {
+    if probes.env("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        Check::pass("terminal", "colour disabled by NO_COLOR")
+    } else if !probes.stderr_is_terminal() {
+        Check::pass("terminal", "stderr is not a terminal; progress bars are off")
+    } else if probes.env("TERM").as_deref() == Some("dumb") {
+        Check::warn("terminal", "TERM=dumb cannot show colour or progress bars", "set TERM for your terminal, or NO_COLOR=1 to silence this")
+    } else {
+        Check::pass("terminal", "stderr is a colour terminal")
+    }
+}
+
+fn check_disk(probes: &dyn Probes, dir: &Path, min_free_bytes: u64) -> Check {
+    if let Err(e) = probes.check_writable(dir) {
+        return Check::fail(
+            "disk",
+            format!("cannot create files in {}: {}", dir.display(), e),
+            "write outputs elsewhere with --output or fix the directory's permissions",
+        );
+    }
+    match probes.available_bytes(dir) {
+        Ok(available) if available < min_free_bytes => Check::warn(
+            "disk",
+            format!("{} has {} bytes free", dir.display(), available),
+            "free some space; encrypted output is slightly larger than its input",
+        ),
+        Ok(available) => Check::pass("disk", format!("{} is writable with {} MiB free", dir.display(), available >> 20)),
+        Err(e) => Check::warn("disk", format!("{} is writable; free space unknown: {}", dir.display(), e), "check free space by hand before large runs"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::HybridGuardError;
+    use std::collections::HashMap;
+
+    /// Healthy environment unless a field says otherwise
+    #[derive(Default)]
+    struct FakeProbes {
+        missing_kem: Option<Algorithm>,
+        zero_rng: bool,
+        broken_round_trip: bool,
+        env: HashMap<&'static str, &'static str>,
+        terminal: bool,
+        available: u64,
+        read_only: bool,
+    }
+
+    impl FakeProbes {
+        fn healthy() -> Self {
+            Self {
+                env: HashMap::from([("LANG", "en_US.UTF-8")]),
+                terminal: true,
+                available: u64::MAX,
+                ..Self::default()
+            }
+        }
+    }
+
+    impl Probes for FakeProbes {
+        fn kem(&self, algorithm: Algorithm) -> Result<()> {
+            if self.missing_kem == Some(algorithm) {
+                return Err(HybridGuardError::Layer("algorithm disabled".to_string()));
+            }
+            Ok(())
+        }
+
+        fn fill_random(&self, buf: &mut [u8]) -> io::Result<()> {
+            if !self.zero_rng {
+                rand::thread_rng().fill_bytes(buf);
+            }
+            Ok(())
+        }
+
+        fn round_trip(&self, message: &[u8]) -> Result<Vec<u8>> {
+            if self.broken_round_trip {
+                return Err(HybridGuardError::DecryptionFailed);
+            }
+            Ok(message.to_vec())
+        }
+
+        fn env(&self, name: &str) -> Option<String> {
+            self.env.get(name).map(|value| value.to_string())
+        }
+
+        fn stderr_is_terminal(&self) -> bool {
+            self.terminal
+        }
+
+        fn available_bytes(&self, _dir: &Path) -> io::Result<u64> {
+            Ok(self.available)
+        }
+
+        fn check_writable(&self, _dir: &Path) -> io::Result<()> {
+            if self.read_only {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"));
+            }
+            Ok(())
+        }
+    }
+
+    fn options(dir: &Path) -> DoctorOptions {
+        let key_file = dir.join("test.keys");
+        KeyManager::from_master_key(&[0xD0; 32]).unwrap().save(&key_file).unwrap();
+        DoctorOptions { key_file, output_dir: dir.to_path_buf(), ..DoctorOptions::default() }
+    }
+
+    fn status(report: &DoctorReport, name: &str) -> CheckStatus {
+        report.check(name).unwrap_or_else(|| panic!("no {} check", name)).status
+    }
+
+    #[test]
+    fn test_healthy_environment_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = run(&options(dir.path()), &FakeProbes::healthy());
+        let names: Vec<&str> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["build", "liboqs", "rng", "round-trip", "byte-order", "keystore", "locale", "terminal", "disk"]);
+        assert_eq!(report.status(), CheckStatus::Pass, "{:?}", report);
+        assert!(report.checks.iter().all(|check| check.hint.is_none()));
+        assert_eq!(report.build.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_each_fake_failure_fails_only_its_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        let cases: [(&str, FakeProbes); 4] = [
+            ("liboqs", FakeProbes { missing_kem: Some(Algorithm::HqcRmrs256), ..FakeProbes::healthy() }),
+            ("rng", FakeProbes { zero_rng: true, ..FakeProbes::healthy() }),
+            ("round-trip", FakeProbes { broken_round_trip: true, ..FakeProbes::healthy() }),
+            ("disk", FakeProbes { read_only: true, ..FakeProbes::healthy() }),
+        ];
+        for (name, probes) in cases {
+            let report = run(&options, &probes);
+            assert_eq!(report.status(), CheckStatus::Fail, "{}", name);
+            let failed: Vec<&Check> = report.checks.iter().filter(|check| check.status != CheckStatus::Pass).collect();
+            assert_eq!(failed.len(), 1, "{}: {:?}", name, failed);
+            assert_eq!(failed[0].name, name);
+            assert!(failed[0].hint.is_some(), "{}", name);
+        }
+
+        let report = run(&options, &FakeProbes { missing_kem: Some(Algorithm::HqcRmrs256), ..FakeProbes::healthy() });
+        let liboqs = report.check("liboqs").unwrap();
+        assert!(liboqs.detail.contains("HQC-256") && !liboqs.detail.contains("ML-KEM-768"), "{}", liboqs.detail);
+    }
+
+    #[test]
+    fn test_warnings_do_not_fail_the_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let probes = FakeProbes {
+            env: HashMap::from([("LANG", "C"), ("TERM", "dumb")]),
+            available: 1024,
+            ..FakeProbes::healthy()
+        };
+        let report = run(&options(dir.path()), &probes);
+        assert_eq!(report.status(), CheckStatus::Warn);
+        for name in ["locale", "terminal", "disk"] {
+            assert_eq!(status(&report, name), CheckStatus::Warn, "{}", name);
+        }
+
+        // LC_ALL overrides LANG, and NO_COLOR settles the terminal
+        let probes = FakeProbes {
+            env: HashMap::from([("LC_ALL", "de_DE.utf8"), ("LANG", "C"), ("TERM", "dumb"), ("NO_COLOR", "1")]),
+            ..FakeProbes::healthy()
+        };
+        let report = run(&options(dir.path()), &probes);
+        assert_eq!(report.status(), CheckStatus::Pass, "{:?}", report);
+    }
+
+    #[test]
+    fn test_keystore_discovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path());
+        let probes = FakeProbes::healthy();
+        let report = run(&options, &probes);
+        assert!(report.check("keystore").unwrap().detail.contains("key ID"));
+
+        fs::write(&options.key_file, b"{\"key_id\": \"broken\"}").unwrap();
+        assert_eq!(status(&run(&options, &probes), "keystore"), CheckStatus::Fail);
+
+        options.key_file = dir.path().join("absent.keys");
+        let report = run(&options, &probes);
+        assert_eq!(status(&report, "keystore"), CheckStatus::Warn);
+        assert!(report.check("keystore").unwrap().hint.as_deref().unwrap().contains("keygen"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_readable_key_file_warns() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::set_permissions(&options.key_file, fs::Permissions::from_mode(0o644)).unwrap();
+        let report = run(&options, &FakeProbes::healthy());
+        let keystore = report.check("keystore").unwrap();
+        assert_eq!(keystore.status, CheckStatus::Warn);
+        assert!(keystore.detail.contains("644"), "{}", keystore.detail);
+    }
+
+    #[test]
+    fn test_report_serializes_statuses_in_lowercase() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = run(&options(dir.path()), &FakeProbes { zero_rng: true, ..FakeProbes::healthy() });
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][2]["name"], "rng");
+        assert_eq!(json["checks"][2]["status"], "fail");
+        assert!(json["checks"][0].get("hint").is_none());
+        assert!(json["build"]["target"].as_str().unwrap().contains(std::env::consts::OS));
+    }
+}
@@ -1,15 +1,30 @@
 // Main encryption engine that orchestrates all 4 layers
 
+use crate::cancel::{self, CancellationToken};
 use crate::crypto::EncryptedData;
+use crate::crypto::drbg::{self, OsRandom, RandomSource};
+use crate::crypto::envelope::WrappedFileKey;
 use crate::crypto::hkdf::LayerKeys;
+use crate::key_manager::KeyManager;
 use crate::error::{HybridGuardError, Result};
+use crate::hybridguard::{check_limit, DecryptLimits};
+use crate::profiling::{MemoryReport, Profiler, Profiling};
+use crate::progress::{Direction, OperationState, Progress};
 use crate::layers::{
+    self,
+    compact_kem,
+    CompactKemLayer,
     EncryptionLayer,
+    LayerDescriptor,
+    SecurityAssessment,
+    SecurityClass,
     layer1_mlkem::MlKemLayer,
     layer2_hqc::HqcLayer,
     layer3_noise::QuantumNoiseLayer,
     layer4_fhe::FHELayer,
 };
+use crate::timing::{Clock, SystemClock};
+use std::sync::Arc;
 use std::time::Instant;
 
 /// Main encryption engine that coordinates all 4 layers
@@ -18,6 +33,14 @@ pub struct HybridGuardEncryptor {
     layer2: HqcLayer,
     layer3: QuantumNoiseLayer,
     layer4: FHELayer,
+    /// Reads containers written under `StackProfile::CompactKem`; never writes
+    compact: CompactKemLayer,
+    /// File keys, wrap nonces and KEM encapsulation draw from this
+    rng: Arc<dyn RandomSource>,
+    /// Timestamps containers
+    clock: Arc<dyn Clock>,
+    /// Buffer sizes decryption refuses to go past; unbounded unless set
+    limits: DecryptLimits,
 }
 
 impl HybridGuardEncryptor {
@@ -28,33 +51,119 @@ impl HybridGuardEncryptor {
             layer2: HqcLayer::new(),
             layer3: QuantumNoiseLayer::new(),
             layer4: FHELayer::new(),
+            compact: CompactKemLayer::new(),
+            rng: Arc::new(OsRandom),
+            clock: Arc::new(SystemClock::new()),
+            limits: DecryptLimits::UNBOUNDED,
         }
     }
     
+    /// Draw randomness from `rng` instead of the OS, e.g. to reproduce golden containers
+    pub fn with_rng(mut self, rng: Arc<dyn RandomSource>) -> Self {
+        self.rng = rng;
+        self
+    }
+    
+    /// Timestamp containers from `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Refuse to decrypt past `limits`, checked as `HybridGuard::decrypt_bounded` does
+    pub fn with_limits(mut self, limits: DecryptLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+    
+    /// Layer keys and wrapped file key for one new file of `key_manager`,
+    /// drawn from this encryptor's random source
+    pub fn new_file_keys(&self, key_manager: &KeyManager) -> Result<(LayerKeys, WrappedFileKey)> {
+        key_manager.new_file_keys_from(self.rng.as_ref())
+    }
+    
     /// Encrypt data through all 4 layers
     pub fn encrypt(&self, data: &[u8], keys: &LayerKeys) -> Result<EncryptedData> {
+        self.encrypt_layers(data, keys, &mut Profiler::new(Profiling::Off)?, None, &Progress::default())
+    }
+    
+    /// Encrypt data, stopping between layers once `cancel` is triggered
+    pub fn encrypt_cancellable(&self, data: &[u8], keys: &LayerKeys, cancel: &CancellationToken) -> Result<EncryptedData> {
+        self.encrypt_observed(data, keys, cancel, &Progress::default())
+    }
+    
+    /// Encrypt data like `encrypt_cancellable`, reporting a `Layer` state to
+    /// `progress` before each layer; the caller reports the other states
+    pub fn encrypt_observed(
+        &self,
+        data: &[u8],
+        keys: &LayerKeys,
+        cancel: &CancellationToken,
+        progress: &Progress,
+    ) -> Result<EncryptedData> {
+        self.encrypt_layers(data, keys, &mut Profiler::new(Profiling::Off)?, Some(cancel), progress)
+    }
+    
+    /// Encrypt data and report the bytes each layer allocated
+    /// Needs a build with the `memory-profile` feature
+    pub fn encrypt_profiled(&self, data: &[u8], keys: &LayerKeys, progress: &Progress) -> Result<(EncryptedData, MemoryReport)> {
+        let mut profiler = Profiler::new(Profiling::Memory)?;
+        let encrypted = self.encrypt_layers(data, keys, &mut profiler, None, progress)?;
+        Ok((encrypted, profiler.finish().unwrap_or_default()))
+    }
+    
+    fn encrypt_layers(
+        &self,
+        data: &[u8],
+        keys: &LayerKeys,
+        profiler: &mut Profiler,
+        cancel: Option<&CancellationToken>,
+        progress: &Progress,
+    ) -> Result<EncryptedData> {
+        let final_output = drbg::with_oqs_rng_from(self.rng.as_ref(), || self.run_layers(data, keys, profiler, cancel, progress))?;
+        Ok(EncryptedData::with_descriptors_at(final_output, self.descriptors(), self.clock.unix_secs()).with_tag(keys))
+    }
+    
+    fn run_layers(
+        &self,
+        data: &[u8],
+        keys: &LayerKeys,
+        profiler: &mut Profiler,
+        cancel: Option<&CancellationToken>,
+        progress: &Progress,
+    ) -> Result<Vec<u8>> {
         let start = Instant::now();
         
         log::info!("Starting 4-layer encryption of {} bytes", data.len());
+        layers::check_input_len(&[&self.layer1, &self.layer2, &self.layer3, &self.layer4], data.len())?;
+        self.check_keys(keys)?;
         
         // Layer 1: ML-KEM (Lattice-based)
-        log::info!("🔐 Layer 1: ML-KEM encryption...");
-        let layer1_output = self.layer1.encrypt(data, &keys.layer1_key)?;
+        log::debug!("🔐 Layer 1: ML-KEM encryption...");
+        progress.emit(OperationState::Layer { index: 1, name: self.layer1.name().to_string(), direction: Direction::Encrypt });
+        cancel::poll(cancel, &mut [])?;
+        let mut layer1_output = profiler.run(self.layer1.name(), || self.layer1.encrypt(data, &keys.layer1_key))?;
         log::info!("   Output: {} bytes", layer1_output.len());
+        cancel::poll(cancel, &mut [&mut layer1_output])?;
         
         // Layer 2: HQC (Code-based)
-        log::info!("🔐 Layer 2: HQC encryption...");
-        let layer2_output = self.layer2.encrypt(&layer1_output, &keys.layer2_key)?;
+        log::debug!("🔐 Layer 2: HQC encryption...");
+        progress.emit(OperationState::Layer { index: 2, name: self.layer2.name().to_string(), direction: Direction::Encrypt });
+        let mut layer2_output = profiler.run(self.layer2.name(), || self.layer2.encrypt(&layer1_output, &keys.layer2_key))?;
         log::info!("   Output: {} bytes", layer2_output.len());
+        cancel::poll(cancel, &mut [&mut layer1_output, &mut layer2_output])?;
         
-        // Layer 3: Quantum Noise Injection
-        log::info!("🔐 Layer 3: Quantum noise injection...");
-        let layer3_output = self.layer3.encrypt(&layer2_output, &keys.layer3_key)?;
+        // Layer 3: Quantum Noise Injection, in place
+        log::debug!("🔐 Layer 3: Quantum noise injection...");
+        progress.emit(OperationState::Layer { index: 3, name: self.layer3.name().to_string(), direction: Direction::Encrypt });
+        let mut layer3_output = profiler.run(self.layer3.name(), || self.layer3.encrypt_owned(layer2_output, &keys.layer3_key))?;
         log::info!("   Output: {} bytes", layer3_output.len());
+        cancel::poll(cancel, &mut [&mut layer1_output, &mut layer3_output])?;
         
-        // Layer 4: Homomorphic Encryption
-        log::info!("🔐 Layer 4: Homomorphic encryption...");
-        let final_output = self.layer4.encrypt(&layer3_output, &keys.layer4_key)?;
+        // Layer 4: Homomorphic Encryption, in place
+        log::debug!("🔐 Layer 4: Homomorphic encryption...");
+        progress.emit(OperationState::Layer { index: 4, name: self.layer4.name().to_string(), direction: Direction::Encrypt });
+        let final_output = profiler.run(self.layer4.name(), || self.layer4.encrypt_owned(layer3_output, &keys.layer4_key))?;
         log::info!("   Output: {} bytes", final_output.len());
         
         let elapsed = start.elapsed();
@@ -63,33 +172,96 @@ impl HybridGuardEncryptor {
         log::info!("   Encrypted size: {} bytes", final_output.len());
         log::info!("   Expansion ratio: {:.2}x", final_output.len() as f64 / data.len() as f64);
         
-        Ok(EncryptedData::new(final_output))
+        Ok(final_output)
     }
     
     /// Decrypt data through all 4 layers (in reverse order)
+    /// Errors keep their detail; `HybridGuard` can make them uniform
     pub fn decrypt(&self, encrypted: &EncryptedData, keys: &LayerKeys) -> Result<Vec<u8>> {
+        self.decrypt_layers(encrypted, keys, None, &Progress::default())
+    }
+    
+    /// Decrypt data, stopping between layers once `cancel` is triggered;
+    /// partial plaintext is zeroized first
+    pub fn decrypt_cancellable(&self, encrypted: &EncryptedData, keys: &LayerKeys, cancel: &CancellationToken) -> Result<Vec<u8>> {
+        self.decrypt_observed(encrypted, keys, cancel, &Progress::default())
+    }
+    
+    /// Decrypt data like `decrypt_cancellable`, reporting a `Layer` state to
+    /// `progress` before each layer; the caller reports the other states
+    pub fn decrypt_observed(
+        &self,
+        encrypted: &EncryptedData,
+        keys: &LayerKeys,
+        cancel: &CancellationToken,
+        progress: &Progress,
+    ) -> Result<Vec<u8>> {
+        self.decrypt_layers(encrypted, keys, Some(cancel), progress)
+    }
+    
+    fn decrypt_layers(
+        &self,
+        encrypted: &EncryptedData,
+        keys: &LayerKeys,
+        cancel: Option<&CancellationToken>,
+        progress: &Progress,
+    ) -> Result<Vec<u8>> {
         let start = Instant::now();
         
-        log::info!("Starting 4-layer decryption of {} bytes", encrypted.ciphertext.len());
+        log::info!("Starting 4-layer decryption of {} bytes", encrypted.ciphertext().len());
+        
+        check_limit("ciphertext", encrypted.ciphertext().len(), self.limits.max_ciphertext)?;
+        encrypted.require_layers(&layers::readable_descriptors())?;
+        self.check_keys(keys)?;
+        
+        // Authenticate before any layer touches the ciphertext
+        encrypted.verify_tag(keys)?;
+        cancel::poll(cancel, &mut [])?;
         
         // Layer 4: Homomorphic Decryption
-        log::info!("🔓 Layer 4: Homomorphic decryption...");
-        let layer4_output = self.layer4.decrypt(&encrypted.ciphertext, &keys.layer4_key)?;
+        log::debug!("🔓 Layer 4: Homomorphic decryption...");
+        progress.emit(OperationState::Layer { index: 4, name: self.layer4.name().to_string(), direction: Direction::Decrypt });
+        check_limit("intermediate", encrypted.ciphertext().len(), self.limits.max_intermediate)?;
+        let version = encrypted.layer_version(&self.layer4.descriptor().name)?;
+        let mut layer4_output = self.layer4.decrypt_version(encrypted.ciphertext(), &keys.layer4_key, version)?;
         log::info!("   Output: {} bytes", layer4_output.len());
+        cancel::poll(cancel, &mut [&mut layer4_output])?;
         
         // Layer 3: Quantum Noise Removal
-        log::info!("🔓 Layer 3: Quantum noise removal...");
-        let layer3_output = self.layer3.decrypt(&layer4_output, &keys.layer3_key)?;
+        log::debug!("🔓 Layer 3: Quantum noise removal...");
+        progress.emit(OperationState::Layer { index: 3, name: self.layer3.name().to_string(), direction: Direction::Decrypt });
+        check_limit("intermediate", layer4_output.len(), self.limits.max_intermediate)?;
+        let version = encrypted.layer_version(&self.layer3.descriptor().name)?;
+        let mut layer3_output = self.layer3.decrypt_version(&layer4_output, &keys.layer3_key, version)?;
         log::info!("   Output: {} bytes", layer3_output.len());
+        cancel::poll(cancel, &mut [&mut layer4_output, &mut layer3_output])?;
         
-        // Layer 2: HQC Decryption
-        log::info!("🔓 Layer 2: HQC decryption...");
-        let layer2_output = self.layer2.decrypt(&layer3_output, &keys.layer2_key)?;
-        log::info!("   Output: {} bytes", layer2_output.len());
-        
-        // Layer 1: ML-KEM Decryption
-        log::info!("🔓 Layer 1: ML-KEM decryption...");
-        let plaintext = self.layer1.decrypt(&layer2_output, &keys.layer1_key)?;
+        let plaintext = if encrypted.descriptors().iter().any(|d| d.name == compact_kem::LAYER_ID) {
+            // Layers 1 and 2 under one ML-KEM encapsulation
+            log::debug!("🔓 Layers 1-2: compact ML-KEM decryption...");
+            progress.emit(OperationState::Layer { index: 2, name: self.compact.name().to_string(), direction: Direction::Decrypt });
+            check_limit("intermediate", layer3_output.len(), self.limits.max_intermediate)?;
+            let version = encrypted.layer_version(compact_kem::LAYER_ID)?;
+            check_limit("plaintext", self.compact.declared_output_len(&layer3_output, version)?, self.limits.max_plaintext)?;
+            self.compact.decrypt_version(&layer3_output, &compact_kem::layer_key(keys), version)?
+        } else {
+            // Layer 2: HQC Decryption
+            log::debug!("🔓 Layer 2: HQC decryption...");
+            progress.emit(OperationState::Layer { index: 2, name: self.layer2.name().to_string(), direction: Direction::Decrypt });
+            check_limit("intermediate", layer3_output.len(), self.limits.max_intermediate)?;
+            let version = encrypted.layer_version(&self.layer2.descriptor().name)?;
+            let mut layer2_output = self.layer2.decrypt_version(&layer3_output, &keys.layer2_key, version)?;
+            log::info!("   Output: {} bytes", layer2_output.len());
+            cancel::poll(cancel, &mut [&mut layer4_output, &mut layer3_output, &mut layer2_output])?;
+            
+            // Layer 1: ML-KEM Decryption
+            log::debug!("🔓 Layer 1: ML-KEM decryption...");
+            progress.emit(OperationState::Layer { index: 1, name: self.layer1.name().to_string(), direction: Direction::Decrypt });
+            check_limit("intermediate", layer2_output.len(), self.limits.max_intermediate)?;
+            let version = encrypted.layer_version(&self.layer1.descriptor().name)?;
+            check_limit("plaintext", self.layer1.declared_output_len(&layer2_output, version)?, self.limits.max_plaintext)?;
+            self.layer1.decrypt_version(&layer2_output, &keys.layer1_key, version)?
+        };
         log::info!("   Output: {} bytes", plaintext.len());
         
         let elapsed = start.elapsed();
@@ -98,27 +270,60 @@ impl HybridGuardEncryptor {
         Ok(plaintext)
     }
     
+    /// Exact ciphertext length for a plaintext of `plaintext_len` bytes,
+    /// without the container header
+    pub fn estimate_output_size(&self, plaintext_len: usize) -> Result<usize> {
+        let len = self.layer1.output_len(plaintext_len)?;
+        let len = self.layer2.output_len(len)?;
+        let len = self.layer3.output_len(len)?;
+        self.layer4.output_len(len)
+    }
+    
+    /// Descriptors of the layer formats this pipeline writes, in order
+    pub fn descriptors(&self) -> Vec<LayerDescriptor> {
+        vec![
+            self.layer1.descriptor(),
+            self.layer2.descriptor(),
+            self.layer3.descriptor(),
+            self.layer4.descriptor(),
+        ]
+    }
+    
+    /// Check each of `keys` against the layer it is for
+    fn check_keys(&self, keys: &LayerKeys) -> Result<()> {
+        layers::check_key_lens(&[&self.layer1, &self.layer2, &self.layer3, &self.layer4], &keys.in_order().map(|key| key.as_bytes()))
+    }
+    
+    /// Conservative security of the layer stack; see `SecurityAssessment`
+    pub fn effective_security(&self) -> SecurityAssessment {
+        SecurityAssessment::of(&[&self.layer1, &self.layer2, &self.layer3, &self.layer4])
+    }
+    
     /// Get information about all layers
     pub fn layer_info(&self) -> Vec<LayerInfo> {
         vec![
             LayerInfo {
                 name: self.layer1.name().to_string(),
-                security_level: self.layer1.security_level(),
+                security_class: self.layer1.security_class(),
+                claims: self.layer1.claims().to_string(),
                 status: "Active".to_string(),
             },
             LayerInfo {
                 name: self.layer2.name().to_string(),
-                security_level: self.layer2.security_level(),
+                security_class: self.layer2.security_class(),
+                claims: self.layer2.claims().to_string(),
                 status: "Active".to_string(),
             },
             LayerInfo {
                 name: self.layer3.name().to_string(),
-                security_level: self.layer3.security_level(),
+                security_class: self.layer3.security_class(),
+                claims: self.layer3.claims().to_string(),
                 status: "Active".to_string(),
             },
             LayerInfo {
                 name: self.layer4.name().to_string(),
-                security_level: self.layer4.security_level(),
+                security_class: self.layer4.security_class(),
+                claims: self.layer4.claims().to_string(),
                 status: "Active".to_string(),
             },
         ]
@@ -129,7 +334,8 @@ impl HybridGuardEncryptor {
 #[derive(Debug, Clone)]
 pub struct LayerInfo {
     pub name: String,
-    pub security_level: u32,
+    pub security_class: SecurityClass,
+    pub claims: String,
     pub status: String,
 }
 
@@ -137,6 +343,7 @@ pub struct LayerInfo {
 mod tests {
     use super::*;
     use crate::crypto::hkdf::KeyDerivation;
+    use crate::crypto::secret::SecretBytes;
     
     #[test]
     fn test_encrypt_decrypt() {
@@ -159,6 +366,46 @@ mod tests {
         assert_eq!(data.to_vec(), decrypted);
     }
     
+    #[test]
+    fn test_short_layer_key_fails_before_any_layer() {
+        let encryptor = HybridGuardEncryptor::new();
+        let mut keys = KeyDerivation::new(vec![0u8; 32]).derive_all_keys().unwrap();
+        let encrypted = encryptor.encrypt(b"data", &keys).unwrap();
+        
+        keys.layer4_key = SecretBytes::new(vec![0u8; 16]);
+        for result in [encryptor.encrypt(b"data", &keys).map(|_| ()), encryptor.decrypt(&encrypted, &keys).map(|_| ())] {
+            match result {
+                Err(HybridGuardError::KeyTooShort(message)) => {
+                    assert!(message.contains("layer 4 (FHE-Layer) needs a key of at least 32 bytes but was given 16"), "{}", message)
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_reads_compact_containers() {
+        let key_manager = KeyManager::from_master_key(&[3u8; 32]).unwrap();
+        let hg = crate::HybridGuard::builder(KeyManager::from_master_key(&[3u8; 32]).unwrap())
+            .with_stack_profile(crate::StackProfile::CompactKem)
+            .build().unwrap();
+        let encrypted = hg.encrypt(b"small record").unwrap();
+        
+        let keys = key_manager.keys_for(&encrypted).unwrap();
+        assert_eq!(HybridGuardEncryptor::new().decrypt(&encrypted, &keys).unwrap(), b"small record");
+    }
+        
+    #[test]
+    fn test_estimate_output_size() {
+        let encryptor = HybridGuardEncryptor::new();
+        let keys = KeyDerivation::new(vec![1u8; 32]).derive_all_keys().unwrap();
+        
+        for len in [0, 1, 31, 32, 1000] {
+            let encrypted = encryptor.encrypt(&vec![0x5A; len], &keys).unwrap();
+            assert_eq!(encryptor.estimate_output_size(len).unwrap(), encrypted.ciphertext().len());
+        }
+    }
+    
     #[test]
     fn test_layer_info() {
         let encryptor = HybridGuardEncryptor::new();
@@ -168,5 +415,9 @@ mod tests {
         assert_eq!(info[0].name, "ML-KEM-768 (Lattice-based)");
         assert_eq!(info[1].name, "HQC (Code-based)");
         assert_eq!(info[2].name, "Quantum Noise Injection");
+        assert_eq!(info[0].security_class, SecurityClass::PostQuantumKem { nist_level: 3 });
+        assert_eq!(info[1].security_class, SecurityClass::PostQuantumKem { nist_level: 5 });
+        assert_eq!(info[2].security_class, SecurityClass::Obfuscation);
+        assert_eq!(info[3].security_class, SecurityClass::Experimental);
     }
 }
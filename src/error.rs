@@ -2,11 +2,41 @@
 
 use thiserror::Error;
 use std::io;
+use crate::key_manager::doctor::KeyFileDiagnosis;
+use crate::messages::Catalog;
+use crate::warning::WarningCode;
+
+/// Stable process exit codes, one per error class
+/// Scripts may rely on these values; never renumber them
+pub mod exit_code {
+    /// Operation completed successfully
+    pub const SUCCESS: u8 = 0;
+    /// Failure that does not fit a more specific class
+    pub const FAILURE: u8 = 1;
+    /// Bad command line or invalid arguments
+    pub const USAGE: u8 = 2;
+    /// Wrong password or unusable key material
+    pub const KEY: u8 = 3;
+    /// Ciphertext is corrupt or failed an integrity check
+    pub const INTEGRITY: u8 = 4;
+    /// Filesystem or other I/O failure (missing file, disk full, ...)
+    pub const IO: u8 = 5;
+    /// Input is not in a supported format or version
+    pub const UNSUPPORTED: u8 = 6;
+    /// Operation refused by a configured policy, or a warning under --deny-warnings
+    pub const POLICY: u8 = 7;
+    /// Keystore locked by another run; retrying later may succeed
+    pub const BUSY: u8 = 8;
+    /// A --max-duration or --max-output-bytes budget ran out
+    pub const BUDGET: u8 = 9;
+    /// Stopped by a cancellation token or Ctrl-C (128 + SIGINT)
+    pub const CANCELLED: u8 = 130;
+}
 
 #[derive(Error, Debug)]
 pub enum HybridGuardError {
     #[error("IO error: {0}")]
-    Io(#[from] io::Error),
+    Io(#[source] io::Error),
     
     #[error("Encryption error: {0}")]
     Encryption(String),
@@ -23,11 +53,296 @@ pub enum HybridGuardError {
     #[error("Key generation error: {0}")]
     KeyGeneration(String),
     
+    #[error("Invalid password")]
+    InvalidPassword,
+    
+    /// A key file that does not load, with what is wrong with each field
+    #[error("Key file damaged: {}", .0.summary())]
+    KeyFileDamaged(Box<KeyFileDiagnosis>),
+    
+    /// Data given as a key file that is neither a version 1 file nor a version 2 envelope
+    #[error("Not a key file: {0}")]
+    NotAKeyFile(String),
+    
+    #[error("Key mismatch: {0}")]
+    KeyMismatch(String),
+    
+    /// A layer key shorter than its layer requires, found before any layer ran
+    #[error("Key too short: {0}")]
+    KeyTooShort(String),
+    
+    /// The loaded key file does not grant the operation, e.g. an encrypt-only key asked to decrypt
+    #[error("Capability denied: {0}")]
+    CapabilityDenied(String),
+    
     #[error("Invalid input: {0}")]
     InvalidInput(String),
     
+    #[error("Integrity check failed: {0}")]
+    Integrity(String),
+    
     #[error("Layer error: {0}")]
     Layer(String),
+    
+    /// liboqs could not create the KEM a layer needs, even after retries
+    #[error("Layer {layer} unavailable: {algorithm} could not be initialized; {hint}")]
+    LayerUnavailable { layer: String, algorithm: String, hint: String },
+    
+    #[error("Memory locking unavailable: {0}")]
+    MemoryLock(String),
+    
+    /// At least one `doctor` check failed
+    #[error("Diagnostics failed: {0}")]
+    DiagnosticsFailed(String),
+    
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+    
+    /// A deprecated format whose reader was left out of this build
+    #[error("Unsupported version: {format} (this build has no `{feature}` feature)")]
+    UnsupportedVersion { format: String, feature: String },
+    
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+    
+    /// A labeled file falls short of what the label policy requires of its label
+    #[error("Policy violation: files labeled '{label}' require {requirement}")]
+    LabelPolicyViolation { label: String, requirement: String },
+    
+    #[error("Size limit exceeded: {which} is {size} bytes, limit is {limit}")]
+    LimitExceeded { which: String, size: usize, limit: usize },
+    
+    /// A run went past the time or output budget it was given
+    #[error("Budget exceeded: {which} reached {actual}, budget is {limit}")]
+    BudgetExceeded { which: String, limit: u64, actual: u64 },
+    
+    /// A warning raised under a `Warnings` sink that denies it (`--deny-warnings`)
+    #[error("Warning {code} denied: {message}")]
+    WarningDenied { code: WarningCode, message: String },
+    
+    /// The source's size or mtime changed while it was read under `--stable-read`
+    #[error("Source changed during read: {0}")]
+    SourceChangedDuringRead(String),
+    
+    /// The producer of `encrypt --source-cmd` failed, timed out or did not start
+    #[error("Source command failed: {0}")]
+    SourceFailed(String),
+    
+    /// Another process kept the keystore lock past the wait
+    #[error("Keystore busy: {0}")]
+    KeystoreBusy(String),
+    
+    /// The nonce audit saw a (key, purpose, nonce) triple a second time
+    #[error("Nonce reuse: {0}")]
+    NonceReuse(String),
+    
+    #[error("Decryption failed")]
+    DecryptionFailed,
+    
+    #[error("Operation cancelled")]
+    Cancelled,
+    
+    #[error("Batch item {index}: {source}")]
+    BatchItem { index: usize, source: Box<HybridGuardError> },
+}
+
+impl HybridGuardError {
+    /// Exit code for this error class (see [`exit_code`])
+    pub fn code(&self) -> u8 {
+        match self {
+            HybridGuardError::InvalidInput(_) => exit_code::USAGE,
+            HybridGuardError::KeyGeneration(_)
+            | HybridGuardError::InvalidPassword
+            | HybridGuardError::KeyFileDamaged(_)
+            | HybridGuardError::NotAKeyFile(_)
+            | HybridGuardError::KeyMismatch(_)
+            | HybridGuardError::KeyTooShort(_)
+            | HybridGuardError::CapabilityDenied(_) => exit_code::KEY,
+            HybridGuardError::Decryption(_)
+            | HybridGuardError::DecryptionError(_)
+            | HybridGuardError::Integrity(_)
+            | HybridGuardError::DecryptionFailed => exit_code::INTEGRITY,
+            HybridGuardError::Io(_) | HybridGuardError::SourceChangedDuringRead(_) => exit_code::IO,
+            HybridGuardError::UnsupportedFormat(_) | HybridGuardError::UnsupportedVersion { .. } => exit_code::UNSUPPORTED,
+            HybridGuardError::PolicyViolation(_)
+            | HybridGuardError::LabelPolicyViolation { .. }
+            | HybridGuardError::LimitExceeded { .. }
+            | HybridGuardError::WarningDenied { .. } => exit_code::POLICY,
+            HybridGuardError::Encryption(_)
+            | HybridGuardError::EncryptionError(_)
+            | HybridGuardError::Layer(_)
+            | HybridGuardError::LayerUnavailable { .. }
+            | HybridGuardError::MemoryLock(_)
+            | HybridGuardError::DiagnosticsFailed(_)
+            | HybridGuardError::NonceReuse(_)
+            | HybridGuardError::SourceFailed(_) => exit_code::FAILURE,
+            HybridGuardError::KeystoreBusy(_) => exit_code::BUSY,
+            HybridGuardError::BudgetExceeded { .. } => exit_code::BUDGET,
+            HybridGuardError::Cancelled => exit_code::CANCELLED,
+            HybridGuardError::BatchItem { source, .. } => source.code(),
+        }
+    }
+    
+    /// This error's message from `catalog`; `Display` stays English for logs
+    pub fn localized(&self, catalog: &Catalog) -> String {
+        let detail = |id: &str, detail: &dyn std::fmt::Display| catalog.text(id, &[("detail", detail)]);
+        match self {
+            HybridGuardError::Io(e) => detail("error-io", e),
+            HybridGuardError::Encryption(d) | HybridGuardError::EncryptionError(d) => detail("error-encryption", d),
+            HybridGuardError::Decryption(d) | HybridGuardError::DecryptionError(d) => detail("error-decryption", d),
+            HybridGuardError::KeyGeneration(d) => detail("error-key-generation", d),
+            HybridGuardError::InvalidPassword => catalog.text("error-invalid-password", &[]),
+            HybridGuardError::KeyFileDamaged(diagnosis) => detail("error-key-file-damaged", &diagnosis.summary()),
+            HybridGuardError::NotAKeyFile(d) => detail("error-not-a-key-file", d),
+            HybridGuardError::KeyMismatch(d) => detail("error-key-mismatch", d),
+            HybridGuardError::KeyTooShort(d) => detail("error-key-too-short", d),
+            HybridGuardError::CapabilityDenied(d) => detail("error-capability-denied", d),
+            HybridGuardError::InvalidInput(d) => detail("error-invalid-input", d),
+            HybridGuardError::Integrity(d) => detail("error-integrity", d),
+            HybridGuardError::Layer(d) => detail("error-layer", d),
+            HybridGuardError::LayerUnavailable { layer, algorithm, hint } => {
+                catalog.text("error-layer-unavailable", &[("layer", layer), ("algorithm", algorithm), ("hint", hint)])
+            }
+            HybridGuardError::MemoryLock(d) => detail("error-memory-lock", d),
+            HybridGuardError::DiagnosticsFailed(d) => detail("error-diagnostics-failed", d),
+            HybridGuardError::UnsupportedFormat(d) => detail("error-unsupported-format", d),
+            HybridGuardError::UnsupportedVersion { format, feature } => {
+                catalog.text("error-unsupported-version", &[("format", format), ("feature", feature)])
+            }
+            HybridGuardError::PolicyViolation(d) => detail("error-policy-violation", d),
+            HybridGuardError::LabelPolicyViolation { label, requirement } => {
+                catalog.text("error-label-policy-violation", &[("label", label), ("requirement", requirement)])
+            }
+            HybridGuardError::LimitExceeded { which, size, limit } => {
+                catalog.text("error-limit-exceeded", &[("which", which), ("size", size), ("limit", limit)])
+            }
+            HybridGuardError::BudgetExceeded { which, limit, actual } => {
+                catalog.text("error-budget-exceeded", &[("which", which), ("limit", limit), ("actual", actual)])
+            }
+            HybridGuardError::WarningDenied { code, message } => catalog.text("error-warning-denied", &[("code", code), ("message", message)]),
+            HybridGuardError::SourceChangedDuringRead(d) => detail("error-source-changed", d),
+            HybridGuardError::SourceFailed(d) => detail("error-source-failed", d),
+            HybridGuardError::KeystoreBusy(d) => detail("error-keystore-busy", d),
+            HybridGuardError::NonceReuse(d) => detail("error-nonce-reuse", d),
+            HybridGuardError::DecryptionFailed => catalog.text("error-decryption-failed", &[]),
+            HybridGuardError::Cancelled => catalog.text("error-cancelled", &[]),
+            HybridGuardError::BatchItem { index, source } => {
+                catalog.text("error-batch-item", &[("index", index), ("detail", &source.localized(catalog))])
+            }
+        }
+    }
+}
+
+/// For the `std::io` adapters: I/O errors pass through unchanged, anything
+/// else becomes `InvalidData` wrapping the original error
+impl From<HybridGuardError> for io::Error {
+    fn from(error: HybridGuardError) -> Self {
+        match error {
+            HybridGuardError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// The other way round: an error an adapter wrapped comes back as itself,
+/// so an integrity failure read through `std::io::Read` stays one
+impl From<io::Error> for HybridGuardError {
+    fn from(error: io::Error) -> Self {
+        if !error.get_ref().is_some_and(|e| e.is::<HybridGuardError>()) {
+            return HybridGuardError::Io(error);
+        }
+        let kind = error.kind();
+        match error.into_inner().map(|e| e.downcast::<HybridGuardError>()) {
+            Some(Ok(inner)) => *inner,
+            Some(Err(other)) => HybridGuardError::Io(io::Error::new(kind, other)),
+            None => HybridGuardError::Io(io::Error::from(kind)),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, HybridGuardError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(HybridGuardError::InvalidInput("x".into()).code(), exit_code::USAGE);
+        assert_eq!(HybridGuardError::InvalidPassword.code(), exit_code::KEY);
+        assert_eq!(HybridGuardError::CapabilityDenied("x".into()).code(), exit_code::KEY);
+        let damaged = crate::key_manager::doctor::diagnose(b"not json");
+        assert_eq!(HybridGuardError::KeyFileDamaged(Box::new(damaged)).code(), exit_code::KEY);
+        assert_eq!(HybridGuardError::NotAKeyFile("x".into()).code(), exit_code::KEY);
+        assert_eq!(HybridGuardError::KeyTooShort("x".into()).code(), exit_code::KEY);
+        assert_eq!(HybridGuardError::DecryptionError("x".into()).code(), exit_code::INTEGRITY);
+        assert_eq!(HybridGuardError::DecryptionFailed.code(), exit_code::INTEGRITY);
+        assert_eq!(HybridGuardError::Io(io::Error::from(io::ErrorKind::NotFound)).code(), exit_code::IO);
+        assert_eq!(HybridGuardError::SourceChangedDuringRead("x".into()).code(), exit_code::IO);
+        assert_eq!(HybridGuardError::UnsupportedFormat("x".into()).code(), exit_code::UNSUPPORTED);
+        let dropped = HybridGuardError::UnsupportedVersion { format: "x".into(), feature: "legacy-v0".into() };
+        assert_eq!(dropped.code(), exit_code::UNSUPPORTED);
+        assert_eq!(HybridGuardError::PolicyViolation("x".into()).code(), exit_code::POLICY);
+        let violation = HybridGuardError::LabelPolicyViolation { label: "x".into(), requirement: "y".into() };
+        assert_eq!(violation.code(), exit_code::POLICY);
+        assert_eq!(HybridGuardError::KeystoreBusy("x".into()).code(), exit_code::BUSY);
+        let budget = HybridGuardError::BudgetExceeded { which: "output bytes".into(), limit: 1, actual: 2 };
+        assert_eq!(budget.code(), exit_code::BUDGET);
+        let denied = HybridGuardError::WarningDenied { code: WarningCode::WeakStack, message: "x".into() };
+        assert_eq!(denied.code(), exit_code::POLICY);
+        assert_eq!(HybridGuardError::NonceReuse("x".into()).code(), exit_code::FAILURE);
+        assert_eq!(HybridGuardError::Cancelled.code(), exit_code::CANCELLED);
+        let limit = HybridGuardError::LimitExceeded { which: "plaintext".into(), size: 2, limit: 1 };
+        assert_eq!(limit.code(), exit_code::POLICY);
+        let item = HybridGuardError::BatchItem { index: 3, source: Box::new(limit) };
+        assert_eq!(item.code(), exit_code::POLICY);
+        assert!(item.to_string().starts_with("Batch item 3: "));
+    }
+    
+    #[test]
+    fn test_english_localization_matches_display() {
+        let english = Catalog::english();
+        let limit = HybridGuardError::LimitExceeded { which: "plaintext".into(), size: 2, limit: 1 };
+        let errors = [
+            HybridGuardError::Io(io::Error::from(io::ErrorKind::NotFound)),
+            HybridGuardError::EncryptionError("x".into()),
+            HybridGuardError::Decryption("x".into()),
+            HybridGuardError::InvalidPassword,
+            HybridGuardError::KeyFileDamaged(Box::new(crate::key_manager::doctor::diagnose(b"not json"))),
+            HybridGuardError::KeyTooShort("x".into()),
+            HybridGuardError::InvalidInput("x".into()),
+            HybridGuardError::LabelPolicyViolation { label: "x".into(), requirement: "y".into() },
+            HybridGuardError::SourceChangedDuringRead("x".into()),
+            HybridGuardError::KeystoreBusy("x".into()),
+            HybridGuardError::BudgetExceeded { which: "output bytes".into(), limit: 1, actual: 2 },
+            HybridGuardError::WarningDenied { code: WarningCode::LoosePermissions, message: "x".into() },
+            HybridGuardError::NonceReuse("x".into()),
+            HybridGuardError::UnsupportedVersion { format: "x".into(), feature: "legacy-v0".into() },
+            HybridGuardError::LayerUnavailable { layer: "HQC".into(), algorithm: "HQC-256".into(), hint: "x".into() },
+            HybridGuardError::DecryptionFailed,
+            HybridGuardError::Cancelled,
+            HybridGuardError::BatchItem { index: 3, source: Box::new(limit) },
+        ];
+        for error in errors {
+            assert_eq!(error.localized(&english), error.to_string());
+        }
+        
+        let german = Catalog::parse("error-invalid-input = Ungültige Eingabe: {detail}").unwrap();
+        assert_eq!(HybridGuardError::InvalidInput("x".into()).localized(&german), "Ungültige Eingabe: x");
+        assert_eq!(HybridGuardError::Cancelled.localized(&german), "Operation cancelled");
+    }
+    
+    #[test]
+    fn test_into_io_error() {
+        let passed = io::Error::from(HybridGuardError::Io(io::Error::from(io::ErrorKind::WouldBlock)));
+        assert_eq!(passed.kind(), io::ErrorKind::WouldBlock);
+        let wrapped = io::Error::from(HybridGuardError::Integrity("x".into()));
+        assert_eq!(wrapped.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            wrapped.get_ref().and_then(|e| e.downcast_ref::<HybridGuardError>()),
+            Some(HybridGuardError::Integrity(_))
+        ));
+        assert!(matches!(HybridGuardError::from(wrapped), HybridGuardError::Integrity(_)));
+        assert!(matches!(HybridGuardError::from(passed), HybridGuardError::Io(_)));
+    }
+}
@@ -0,0 +1,355 @@
+// Reproducible interop fixtures for other implementations of the format
+// Every container version, encoding and layer profile this build can write,
+// with the keys and plaintexts recorded in manifest.json. All randomness,
+// liboqs encapsulation included, comes from a Drbg seeded by FIXTURE_SEED,
+// and timestamps are fixed, so regenerating gives identical bytes.
+
+use crate::crypto::drbg::{self, Drbg};
+use crate::crypto::encoding::{self, Encoding};
+use crate::crypto::envelope::{self, FILE_KEY_LEN, NONCE_LEN};
+use crate::crypto::hkdf::KdfScheme;
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::sniff;
+use crate::crypto::{codec, container, EncryptedData, EncryptedDataBuilder, SourceSnapshot};
+use crate::error::{HybridGuardError, Result};
+use crate::layers::{self, EncryptionLayer, LayerDescriptor};
+use crate::KeyManager;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Seed of every fixture's randomness
+pub const FIXTURE_SEED: &[u8] = b"HybridGuard interop fixtures v1";
+
+/// Encryption time and source mtime recorded in every fixture
+pub const FIXTURE_TIMESTAMP: u64 = 1_700_000_000;
+
+/// Password of the password-derived fixture key
+pub const FIXTURE_PASSWORD: &str = "correct horse battery staple";
+
+/// Name of the manifest written next to the fixtures
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Policy label of the labeled fixture
+pub const FIXTURE_LABEL: &str = "confidential";
+
+/// Layer format versions a fixture was encrypted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayerProfile {
+    /// Every layer at the version this build writes
+    Current,
+    /// Every layer at version 1, as in containers before descriptors existed
+    Legacy,
+}
+
+impl LayerProfile {
+    pub fn descriptors(self) -> Vec<LayerDescriptor> {
+        match self {
+            LayerProfile::Current => layers::current_descriptors(),
+            LayerProfile::Legacy => layers::legacy_descriptors(),
+        }
+    }
+}
+
+impl fmt::Display for LayerProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LayerProfile::Current => "current",
+            LayerProfile::Legacy => "legacy",
+        })
+    }
+}
+
+/// Key material of the fixtures, in the clear
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixtureKey {
+    pub name: String,
+    pub kdf: KdfScheme,
+    /// Hex master key, for keys made with `keygen --from-master-key-file`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub master_key: Option<String>,
+    /// Password and hex salt, for password-derived keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    pub key_id: String,
+}
+
+impl FixtureKey {
+    /// Rebuild the key manager from the recorded key material
+    pub fn key_manager(&self) -> Result<KeyManager> {
+        let hex = |text: &str| codec::hex_lower_decode(text);
+        match (&self.master_key, &self.password, &self.salt) {
+            (Some(master), _, _) => {
+                let master: [u8; 32] = hex(master)?.try_into().map_err(|_| {
+                    HybridGuardError::InvalidInput(format!("fixture key {} has no 32-byte master key", self.name))
+                })?;
+                KeyManager::from_master_key_with(&master, self.kdf)
+            }
+            (None, Some(password), Some(salt)) => KeyManager::from_password(password, &hex(salt)?),
+            _ => Err(HybridGuardError::InvalidInput(format!("fixture key {} has no key material", self.name))),
+        }
+    }
+}
+
+/// One fixture file and how to read it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixtureEntry {
+    pub file: String,
+    /// Name of the `FixtureKey` that decrypts it
+    pub key: String,
+    pub container_version: u16,
+    /// binary, json or armor
+    pub encoding: String,
+    pub layer_profile: LayerProfile,
+    pub descriptors: Vec<LayerDescriptor>,
+    /// Carries a container tag (format v4 and later)
+    pub authenticated: bool,
+    /// Carries a wrapped file key (format v7 and later)
+    pub envelope: bool,
+    pub source_snapshot: Option<SourceSnapshot>,
+    /// Policy label (format v9 and later)
+    pub label: Option<String>,
+    /// Content-type tag in the clear (format v10 and later), as older builds wrote it
+    pub content_type: Option<String>,
+    /// Hex of the expected plaintext
+    pub plaintext: String,
+}
+
+/// Contents of manifest.json
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub generator: String,
+    /// Hex of `FIXTURE_SEED`
+    pub seed: String,
+    pub keys: Vec<FixtureKey>,
+    pub fixtures: Vec<FixtureEntry>,
+    /// Matrix dimensions the format has no variants of, and why
+    pub not_applicable: Vec<String>,
+}
+
+/// A manifest and the fixture files it describes
+pub struct FixtureSet {
+    pub manifest: Manifest,
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+/// One cell of the matrix
+struct Spec {
+    key: &'static str,
+    version: u16,
+    encoding: Encoding,
+    profile: LayerProfile,
+    snapshot: bool,
+    labeled: bool,
+    typed: bool,
+}
+
+impl Spec {
+    fn new(key: &'static str, version: u16, encoding: Encoding, profile: LayerProfile) -> Self {
+        Self { key, version, encoding, profile, snapshot: false, labeled: false, typed: false }
+    }
+
+    fn file_name(&self) -> String {
+        let extension = match self.encoding {
+            Encoding::Binary => "hg",
+            Encoding::Json => "hg.json",
+            Encoding::Armor => "hg.asc",
+        };
+        let snapshot = if self.snapshot { "-snapshot" } else { "" };
+        let labeled = if self.labeled { "-labeled" } else { "" };
+        let typed = if self.typed { "-typed" } else { "" };
+        format!("v{}-{}-{}{}{}{}.{}", self.version, self.profile, self.key, snapshot, labeled, typed, extension)
+    }
+}
+
+/// Every container version in binary; the current version in every
+/// encoding, layer profile and key
+fn matrix() -> Vec<Spec> {
+    let current = container::FORMAT_VERSION;
+    let mut specs: Vec<Spec> = container::SUPPORTED_VERSIONS
+        .iter()
+        .map(|&version| {
+            // Version 0 has no descriptors, so its layers are always version 1
+            let profile = if version == 0 { LayerProfile::Legacy } else { LayerProfile::Current };
+            Spec::new("master-v2", version, Encoding::Binary, profile)
+        })
+        .collect();
+    specs.push(Spec::new("master-v2", current, Encoding::Binary, LayerProfile::Legacy));
+    specs.push(Spec::new("master-v2", current, Encoding::Json, LayerProfile::Current));
+    specs.push(Spec::new("master-v2", current, Encoding::Armor, LayerProfile::Current));
+    specs.push(Spec { snapshot: true, ..Spec::new("master-v2", current, Encoding::Binary, LayerProfile::Current) });
+    specs.push(Spec { labeled: true, ..Spec::new("master-v2", current, Encoding::Json, LayerProfile::Current) });
+    specs.push(Spec { typed: true, ..Spec::new("master-v2", current, Encoding::Binary, LayerProfile::Current) });
+    specs.push(Spec::new("master-v1", current, Encoding::Binary, LayerProfile::Current));
+    specs.push(Spec::new("master-v1", 6, Encoding::Binary, LayerProfile::Current));
+    specs.push(Spec::new("password", current, Encoding::Binary, LayerProfile::Current));
+    specs.push(Spec::new("password", current, Encoding::Armor, LayerProfile::Current));
+    specs
+}
+
+/// Generate every fixture from `seed`
+pub fn generate(seed: &[u8]) -> Result<FixtureSet> {
+    let keys = fixture_keys(seed)?;
+    let mut manifest = Manifest {
+        generator: format!("hybridguard {}", env!("CARGO_PKG_VERSION")),
+        seed: codec::hex_lower(seed),
+        keys: keys.iter().map(|(record, _)| record.clone()).collect(),
+        fixtures: Vec::new(),
+        not_applicable: vec![
+            "compression: the format never compresses".to_string(),
+            "padding: there is no padding option; layer framing is fixed per layer version".to_string(),
+        ],
+    };
+
+    let mut files = Vec::new();
+    for spec in matrix() {
+        let (_, key_manager) = keys
+            .iter()
+            .find(|(record, _)| record.name == spec.key)
+            .ok_or_else(|| HybridGuardError::InvalidInput(format!("no fixture key {}", spec.key)))?;
+        let file = spec.file_name();
+        let plaintext = format!("HybridGuard interop fixture {}\n", file).into_bytes();
+        let data = drbg::with_seeded_oqs_rng(seed, file.as_bytes(), || seal(&spec, key_manager, &plaintext, seed))?;
+        let bytes = if spec.version == container::FORMAT_VERSION {
+            encoding::encode(&data, spec.encoding)?
+        } else {
+            container::encode_version(&data, spec.version)?
+        };
+
+        manifest.fixtures.push(FixtureEntry {
+            file: file.clone(),
+            key: spec.key.to_string(),
+            container_version: spec.version,
+            encoding: spec.encoding.to_string(),
+            layer_profile: spec.profile,
+            descriptors: data.descriptors().to_vec(),
+            authenticated: data.is_authenticated(),
+            envelope: data.wrapped_key().is_some(),
+            source_snapshot: data.source_snapshot().copied(),
+            label: data.label().map(str::to_string),
+            content_type: data.clear_content_type().map(str::to_string),
+            plaintext: codec::hex_lower(&plaintext),
+        });
+        files.push((file, bytes));
+    }
+    Ok(FixtureSet { manifest, files })
+}
+
+/// Generate the fixtures from `FIXTURE_SEED` into `dir`, with manifest.json
+pub fn write(dir: &Path) -> Result<Manifest> {
+    let set = generate(FIXTURE_SEED)?;
+    fs::create_dir_all(dir)?;
+    for (file, bytes) in &set.files {
+        fs::write(dir.join(file), bytes)?;
+    }
+    let manifest = serde_json::to_string_pretty(&set.manifest).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+    fs::write(dir.join(MANIFEST_FILE), manifest + "\n")?;
+    Ok(set.manifest)
+}
+
+/// The fixture keys, each with its key manager
+fn fixture_keys(seed: &[u8]) -> Result<Vec<(FixtureKey, KeyManager)>> {
+    let mut keys = Vec::new();
+    for (name, scheme) in [("master-v2", KdfScheme::V2), ("master-v1", KdfScheme::V1)] {
+        let mut master = [0u8; 32];
+        Drbg::new(seed, name.as_bytes()).fill(&mut master);
+        let key_manager = KeyManager::from_master_key_with(&master, scheme)?;
+        let record = FixtureKey {
+            name: name.to_string(),
+            kdf: scheme,
+            master_key: Some(codec::hex_lower(&master)),
+            password: None,
+            salt: None,
+            key_id: key_manager.key_id().to_string(),
+        };
+        keys.push((record, key_manager));
+    }
+
+    let mut salt = [0u8; 32];
+    Drbg::new(seed, b"password").fill(&mut salt);
+    let key_manager = KeyManager::from_password(FIXTURE_PASSWORD, &salt)?;
+    let record = FixtureKey {
+        name: "password".to_string(),
+        kdf: key_manager.get_keys()?.scheme,
+        master_key: None,
+        password: Some(FIXTURE_PASSWORD.to_string()),
+        salt: Some(codec::hex_lower(&salt)),
+        key_id: key_manager.key_id().to_string(),
+    };
+    keys.push((record, key_manager));
+    Ok(keys)
+}
+
+/// Encrypt `plaintext` as the pipeline of `spec.version` did
+/// Runs inside a seeded liboqs generator; the file key and its nonce come
+/// from their own Drbg stream
+fn seal(spec: &Spec, key_manager: &KeyManager, plaintext: &[u8], seed: &[u8]) -> Result<EncryptedData> {
+    let master = key_manager.get_keys()?;
+    let (keys, wrapped) = if spec.version >= 7 {
+        let mut rng = Drbg::new(seed, format!("{} file key", spec.file_name()).as_bytes());
+        let mut file_key = [0u8; FILE_KEY_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut file_key);
+        rng.fill(&mut nonce);
+        let file_key = SecretBytes::new(file_key.to_vec());
+        let wrapped = envelope::wrap_with_nonce(master, key_manager.key_id(), &file_key, nonce)?;
+        (envelope::file_layer_keys(&file_key, master.scheme)?, Some(wrapped))
+    } else {
+        (master.clone(), None)
+    };
+
+    let descriptors = spec.profile.descriptors();
+    let mut ciphertext = plaintext.to_vec();
+    for ((layer, key), descriptor) in layers::registry().iter().zip(keys.in_order()).zip(&descriptors) {
+        ciphertext = layer.encrypt_version(&ciphertext, key, descriptor.version)?;
+    }
+
+    let mut builder = EncryptedDataBuilder::new(ciphertext)
+        .layers(descriptors.iter().map(|d| d.name.clone()).collect())
+        .descriptors(descriptors)
+        .timestamp(FIXTURE_TIMESTAMP);
+    if spec.version >= 2 {
+        builder = builder.key_id(key_manager.key_id());
+    }
+    if spec.version < 6 {
+        builder = builder.without_content_digest();
+    }
+    if let Some(wrapped) = wrapped {
+        builder = builder.wrapped_key(wrapped);
+    }
+    if spec.snapshot {
+        let len = plaintext.len() as u64;
+        builder = builder.source_snapshot(SourceSnapshot { len, modified_secs: FIXTURE_TIMESTAMP, modified_nanos: 0 });
+    }
+    if spec.labeled {
+        builder = builder.label(FIXTURE_LABEL);
+    }
+    if spec.typed {
+        builder = builder.content_type(sniff::content_type(plaintext).name());
+    }
+    if spec.version >= 4 {
+        builder = builder.tag(&keys)?;
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_names_are_unique() {
+        let mut names: Vec<String> = matrix().iter().map(Spec::file_name).collect();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count);
+        for version in container::SUPPORTED_VERSIONS {
+            assert!(matrix().iter().any(|spec| spec.version == *version));
+        }
+    }
+}
@@ -0,0 +1,321 @@
+// Overwriting files before they are unlinked
+// `shred` replaces a file's bytes in place with random data, syncs them and
+// only then removes the file. Journaling, copy-on-write and log-structured
+// file systems, snapshots and SSD wear levelling may all keep the old blocks
+// elsewhere, so this narrows recovery from local material; it cannot rule it out.
+// Free space queries for pre-flight checks and `doctor` live here too.
+//
+// Every output path also finishes its files through `WriteOptions`, whose
+// `DurabilityLevel` says how far a file is pushed towards the disk before it
+// counts as written: flushed, fsynced before its atomic rename, or with its
+// directory fsynced after the rename as well, so the new name survives a
+// power loss. Every output defaults to the last, as staged files always were;
+// `--durability` only ever lowers it on request. The calls go through a
+// `Syncer`, which tests replace to record them.
+
+use crate::error::HybridGuardError;
+use crate::staging::parent_dir;
+use rand::RngCore;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Random bytes written per call
+const BLOCK_LEN: usize = 64 * 1024;
+
+/// Overwrite every byte of the regular file at `path` with random data and sync it
+/// Returns the number of bytes overwritten; symbolic links are refused
+/// rather than followed, so a link cannot redirect the overwrite
+pub fn overwrite(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.file_type().is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut block = vec![0u8; BLOCK_LEN];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(BLOCK_LEN as u64) as usize;
+        rand::thread_rng().fill_bytes(&mut block[..n]);
+        file.write_all(&block[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    Ok(len)
+}
+
+/// Overwrite the file at `path`, then remove it and sync its directory
+/// Returns the number of bytes overwritten
+pub fn shred(path: &Path) -> io::Result<u64> {
+    let len = overwrite(path)?;
+    fs::remove_file(path)?;
+    sync_parent(path);
+    Ok(len)
+}
+
+/// How far a written file is pushed towards the disk, each level including the ones before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DurabilityLevel {
+    /// Leave the data to the OS page cache
+    None,
+    /// Flush buffered writes to the OS
+    Flush,
+    /// Also fsync the file before it is renamed into place
+    Fsync,
+    /// Also fsync its directory after the rename
+    FsyncDir,
+}
+
+impl DurabilityLevel {
+    /// Default for encrypted and decrypted outputs
+    pub const BULK: Self = DurabilityLevel::FsyncDir;
+    /// Default for key files and checkpoints
+    pub const CRITICAL: Self = DurabilityLevel::FsyncDir;
+}
+
+impl fmt::Display for DurabilityLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DurabilityLevel::None => "none",
+            DurabilityLevel::Flush => "flush",
+            DurabilityLevel::Fsync => "fsync",
+            DurabilityLevel::FsyncDir => "fsync-dir",
+        })
+    }
+}
+
+impl FromStr for DurabilityLevel {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DurabilityLevel::None),
+            "flush" => Ok(DurabilityLevel::Flush),
+            "fsync" => Ok(DurabilityLevel::Fsync),
+            "fsync-dir" => Ok(DurabilityLevel::FsyncDir),
+            other => Err(HybridGuardError::InvalidInput(format!(
+                "unknown durability '{}' (expected none, flush, fsync or fsync-dir)",
+                other
+            ))),
+        }
+    }
+}
+
+/// The durability calls themselves; tests substitute one that records them
+pub trait Syncer: Send + Sync {
+    fn flush(&self, file: &mut File, path: &Path) -> io::Result<()>;
+    fn sync_file(&self, file: &File, path: &Path) -> io::Result<()>;
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+/// The real calls
+pub struct SystemSyncer;
+
+impl Syncer for SystemSyncer {
+    /// A `File` buffers nothing itself, so flushing hands its data to the
+    /// disk, without the metadata `sync_file` adds
+    fn flush(&self, file: &mut File, _path: &Path) -> io::Result<()> {
+        file.sync_data()
+    }
+
+    fn sync_file(&self, file: &File, _path: &Path) -> io::Result<()> {
+        file.sync_all()
+    }
+
+    /// Directories cannot be opened for syncing everywhere, e.g. on Windows
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        match File::open(dir) {
+            Ok(dir) => dir.sync_all(),
+            Err(_) if cfg!(not(unix)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// How output files are finished
+#[derive(Clone)]
+pub struct WriteOptions {
+    durability: DurabilityLevel,
+    syncer: Arc<dyn Syncer>,
+}
+
+impl fmt::Debug for WriteOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteOptions").field("durability", &self.durability).finish_non_exhaustive()
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self::bulk()
+    }
+}
+
+impl WriteOptions {
+    pub fn new(durability: DurabilityLevel) -> Self {
+        Self { durability, syncer: Arc::new(SystemSyncer) }
+    }
+
+    /// Options for encrypted and decrypted outputs
+    pub fn bulk() -> Self {
+        Self::new(DurabilityLevel::BULK)
+    }
+
+    /// Options for key files and checkpoints
+    pub fn critical() -> Self {
+        Self::new(DurabilityLevel::CRITICAL)
+    }
+
+    pub fn with_syncer(mut self, syncer: Arc<dyn Syncer>) -> Self {
+        self.syncer = syncer;
+        self
+    }
+
+    pub fn durability(&self) -> DurabilityLevel {
+        self.durability
+    }
+
+    /// Push the written `file`, which will be `path`, as far as the level asks,
+    /// before it is renamed into place
+    pub fn finish_file(&self, file: &mut File, path: &Path) -> io::Result<()> {
+        if self.durability >= DurabilityLevel::Flush {
+            self.syncer.flush(file, path)?;
+        }
+        if self.durability >= DurabilityLevel::Fsync {
+            self.syncer.sync_file(file, path)?;
+        }
+        Ok(())
+    }
+
+    /// Make the entry for `path` durable once it was renamed, created or removed
+    pub fn finish_entry(&self, path: &Path) -> io::Result<()> {
+        if self.durability >= DurabilityLevel::FsyncDir {
+            self.syncer.sync_dir(&parent_dir(path))?;
+        }
+        Ok(())
+    }
+}
+
+/// A `Syncer` that makes no calls and records which ones it was asked for
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct RecordingSyncer {
+    pub calls: std::sync::Mutex<Vec<(&'static str, std::path::PathBuf)>>,
+}
+
+#[cfg(test)]
+impl RecordingSyncer {
+    /// Calls made so far on `path` or, for `sync_dir`, on its directory
+    pub fn calls_for(&self, path: &Path) -> Vec<&'static str> {
+        let dir = parent_dir(path);
+        let calls = self.calls.lock().unwrap();
+        calls.iter().filter(|(call, target)| target == path || (*call == "sync_dir" && *target == dir)).map(|(call, _)| *call).collect()
+    }
+
+    fn record(&self, call: &'static str, path: &Path) -> io::Result<()> {
+        self.calls.lock().unwrap().push((call, path.to_path_buf()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Syncer for RecordingSyncer {
+    fn flush(&self, _file: &mut File, path: &Path) -> io::Result<()> {
+        self.record("flush", path)
+    }
+
+    fn sync_file(&self, _file: &File, path: &Path) -> io::Result<()> {
+        self.record("sync_file", path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.record("sync_dir", dir)
+    }
+}
+
+/// Make a removal durable; best effort, since not every platform can sync a directory
+fn sync_parent(path: &Path) {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+/// Bytes an unprivileged user may still write on the filesystem holding `dir`
+#[cfg(unix)]
+pub fn available_bytes(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: statvfs only writes into the zeroed struct we pass
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// Bytes an unprivileged user may still write on the filesystem holding `dir`
+#[cfg(not(unix))]
+pub fn available_bytes(_dir: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is only checked on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overwrite_keeps_the_length_and_replaces_the_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.keys");
+        let original = vec![0x41u8; BLOCK_LEN + 100];
+        fs::write(&path, &original).unwrap();
+
+        assert_eq!(overwrite(&path).unwrap(), original.len() as u64);
+        let after = fs::read(&path).unwrap();
+        assert_eq!(after.len(), original.len());
+        assert_ne!(after, original);
+    }
+
+    #[test]
+    fn test_shred_removes_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.keys");
+        fs::write(&path, b"key material").unwrap();
+
+        assert_eq!(shred(&path).unwrap(), 12);
+        assert!(!path.exists());
+        assert_eq!(shred(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_durability_levels_parse_and_order() {
+        for level in [DurabilityLevel::None, DurabilityLevel::Flush, DurabilityLevel::Fsync, DurabilityLevel::FsyncDir] {
+            assert_eq!(level.to_string().parse::<DurabilityLevel>().unwrap(), level);
+        }
+        assert!("sync".parse::<DurabilityLevel>().is_err());
+        assert!(DurabilityLevel::BULK < DurabilityLevel::CRITICAL);
+        assert_eq!(WriteOptions::default().durability(), DurabilityLevel::Flush);
+    }
+
+    #[test]
+    fn test_directories_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(shred(dir.path()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(dir.path().exists());
+    }
+}
@@ -1,108 +1,359 @@
 // HybridGuard Core - Complete 4-layer encryption system
 
+use crate::batch::{self, BatchSummary};
+use crate::budget::Budget;
+use crate::cancel::{self, CancellationToken};
 use crate::error::{HybridGuardError, Result};
-use crate::key_manager::KeyManager;
-use crate::layers::{EncryptionLayer, layer1_mlkem::MlKemLayer, layer2_hqc::HqcLayer, layer3_noise::QuantumNoiseLayer, layer4_fhe::FHELayer};
-use crate::crypto::EncryptedData;
-use std::time::Instant;
+use crate::key_manager::{permissions, KeyManager};
+use crate::layers::{self, compact_kem, CompactKemLayer, EncryptionLayer, LayerDescriptor, SecurityAssessment, SecurityClass, layer1_mlkem::MlKemLayer, layer2_hqc::HqcLayer, layer3_noise::QuantumNoiseLayer, layer4_fhe::FHELayer};
+use crate::layers::assessment::MIN_EFFECTIVE_SECURITY;
+use crate::crypto::{container, EncryptedData};
+use crate::crypto::drbg::{self, OsRandom, RandomSource};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::metadata::{self, Metadata, MetadataMap};
+use crate::crypto::timestamp::{self, TimestampAuthority};
+use crate::crypto::secret::{self, SecretBytes};
+use crate::profiling::{Profiler, Profiling};
+use crate::streaming::chunked;
+use crate::timing::{Clock, EncryptionReport, SystemClock, TimingPadder, TimingPadding};
+use crate::warning::{self, Warnings};
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(feature = "plugins")]
+use std::path::Path;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
 
 /// Main HybridGuard encryption system
 /// Coordinates all 4 layers of encryption
+///
+/// Cloning is a reference count bump: every clone shares one set of keys
+/// and keypair caches, zeroized once, when the last clone drops.
+#[derive(Clone)]
 pub struct HybridGuard {
+    state: Arc<KeyState>,
+    /// Out-of-tree layers, run in order between HQC and quantum noise
+    external: Vec<Arc<dyn EncryptionLayer>>,
+    profile: StackProfile,
+    padder: TimingPadder,
+    /// Timestamps containers; the padder keeps its own handle
+    clock: Arc<dyn Clock>,
+    rng: Arc<dyn RandomSource>,
+    decrypt_errors: DecryptErrorMode,
+    profiling: Profiling,
+    timestamps: Option<Arc<dyn TimestampAuthority>>,
+    /// Age past which encrypting with the keys raises HG002
+    key_lifetime: Option<Duration>,
+}
+
+/// Keys and the layers holding keypairs derived from them, shared by every clone
+struct KeyState {
     key_manager: KeyManager,
     layer1: MlKemLayer,
     layer2: HqcLayer,
     layer3: QuantumNoiseLayer,
     layer4: FHELayer,
+    /// Stands in for layers 1 and 2 under `StackProfile::CompactKem`, and
+    /// decrypts compact ciphertexts under either profile
+    compact: CompactKemLayer,
+    /// Dropped after the keys, so it counts completed zeroizations
+    #[cfg(test)]
+    drops: tests::DropCounter,
 }
 
 impl HybridGuard {
     /// Create a new HybridGuard instance with a password
     pub fn new(password: &str) -> Result<Self> {
         let key_manager = KeyManager::generate(password)?;
-        
-        Ok(Self {
-            key_manager,
-            layer1: MlKemLayer::new(),
-            layer2: HqcLayer::new(),
-            layer3: QuantumNoiseLayer::new(),
-            layer4: FHELayer::new(),
-        })
+        HybridGuardBuilder::new(key_manager).build()
     }
     
     /// Load HybridGuard with existing keys
     pub fn load(key_path: &str) -> Result<Self> {
         let key_manager = KeyManager::load(key_path)?;
-        
-        Ok(Self {
-            key_manager,
-            layer1: MlKemLayer::new(),
-            layer2: HqcLayer::new(),
-            layer3: QuantumNoiseLayer::new(),
-            layer4: FHELayer::new(),
-        })
+        HybridGuardBuilder::new(key_manager).build()
+    }
+    
+    /// Start configuring an instance around existing keys
+    pub fn builder(key_manager: KeyManager) -> HybridGuardBuilder {
+        HybridGuardBuilder::new(key_manager)
     }
     
     /// Encrypt data through all 4 layers
     pub fn encrypt(&self, data: &[u8]) -> Result<EncryptedData> {
+        self.encrypt_with_report(data).map(|(encrypted, _)| encrypted)
+    }
+    
+    /// Encrypt data and report how long each layer took, including padding,
+    /// and with memory profiling on, the bytes each layer allocated
+    pub fn encrypt_with_report(&self, data: &[u8]) -> Result<(EncryptedData, EncryptionReport)> {
+        self.encrypt_reported(data, &EncryptOptions::default())
+    }
+    
+    /// Encrypt data with `options.metadata` recorded, stopping between layers
+    /// once `options.cancel` is triggered
+    pub fn encrypt_with_options(&self, data: &[u8], options: &EncryptOptions) -> Result<EncryptedData> {
+        self.encrypt_reported(data, options).map(|(encrypted, _)| encrypted)
+    }
+    
+    fn encrypt_reported(&self, data: &[u8], options: &EncryptOptions) -> Result<(EncryptedData, EncryptionReport)> {
+        drbg::with_oqs_rng_from(self.rng.as_ref(), || self.encrypt_layers(data, options))
+    }
+    
+    fn encrypt_layers(&self, data: &[u8], options: &EncryptOptions) -> Result<(EncryptedData, EncryptionReport)> {
         let start = Instant::now();
         
         log::info!("Starting 4-layer encryption of {} bytes", data.len());
+        let stack = self.stack();
+        layers::check_input_len(&stack, data.len())?;
+        metadata::check_entries(&options.metadata, &options.private_metadata)?;
+        let token = budgeted(options.cancel.as_ref(), options.budget);
+        let cancel = token.as_ref();
         
-        let keys = self.key_manager.get_keys();
+        let (file_keys, wrapped) = self.state.key_manager.new_file_keys_from(self.rng.as_ref())?;
+        let keys = &file_keys;
+        self.check_keys(keys)?;
+        let warnings = options.warnings.as_ref();
+        if let Some(lifetime) = self.key_lifetime {
+            if let Some(w) = warning::key_lifetime(&self.state.key_manager, lifetime, self.clock.unix_secs()) {
+                warning::emit(warnings, w)?;
+            }
+        }
+        if let Some(w) = warning::weak_stack(&self.effective_security(), "encrypting with") {
+            warning::emit(warnings, w)?;
+        }
+        let mut timings = Vec::with_capacity(4);
+        let mut profiler = Profiler::new(self.profiling)?;
         
-        // Layer 1: ML-KEM (Lattice-based)
-        log::info!("🔐 Layer 1: ML-KEM encryption...");
-        let layer1_data = self.layer1.encrypt(data, &keys.layer1_key)?;
-        log::info!("   Output: {} bytes", layer1_data.len());
+        let (mut layer1_data, mut layer2_data) = match self.profile {
+            StackProfile::Standard => {
+                // Layer 1: ML-KEM (Lattice-based)
+                log::info!("🔐 Layer 1: ML-KEM encryption...");
+                cancel::poll(cancel, &mut [])?;
+                let (mut layer1_data, timing) = self.padder.run(self.state.layer1.name(), || profiler.run(self.state.layer1.name(), || self.state.layer1.encrypt(data, &keys.layer1_key)))?;
+                timings.extend(timing);
+                log::info!("   Output: {} bytes", layer1_data.len());
+                cancel::poll(cancel, &mut [&mut layer1_data])?;
+                
+                // Layer 2: HQC (Code-based)
+                log::info!("🔐 Layer 2: HQC encryption...");
+                let (mut layer2_data, timing) = self.padder.run(self.state.layer2.name(), || profiler.run(self.state.layer2.name(), || self.state.layer2.encrypt(&layer1_data, &keys.layer2_key)))?;
+                timings.extend(timing);
+                log::info!("   Output: {} bytes", layer2_data.len());
+                cancel::poll(cancel, &mut [&mut layer1_data, &mut layer2_data])?;
+                (layer1_data, layer2_data)
+            }
+            StackProfile::CompactKem => {
+                // Layers 1 and 2 under one ML-KEM encapsulation
+                log::info!("🔐 Layers 1-2: compact ML-KEM encryption...");
+                cancel::poll(cancel, &mut [])?;
+                let compact = &self.state.compact;
+                let key = compact_kem::layer_key(keys);
+                let (mut layer2_data, timing) = self.padder.run(compact.name(), || profiler.run(compact.name(), || compact.encrypt(data, &key)))?;
+                timings.extend(timing);
+                log::info!("   Output: {} bytes", layer2_data.len());
+                cancel::poll(cancel, &mut [&mut layer2_data])?;
+                (Vec::new(), layer2_data)
+            }
+        };
         
-        // Layer 2: HQC (Code-based)
-        log::info!("🔐 Layer 2: HQC encryption...");
-        let layer2_data = self.layer2.encrypt(&layer1_data, &keys.layer2_key)?;
-        log::info!("   Output: {} bytes", layer2_data.len());
+        // Out-of-tree layers, in the order they were added
+        for (slot, layer) in self.external.iter().enumerate() {
+            log::info!("🔐 External layer {}...", layer.name());
+            let key = external_key(keys, slot);
+            let (data, timing) = self.padder.run(layer.name(), || profiler.run(layer.name(), || layer.encrypt(&layer2_data, &key)))?;
+            timings.extend(timing);
+            log::info!("   Output: {} bytes", data.len());
+            layer2_data = data;
+            cancel::poll(cancel, &mut [&mut layer1_data, &mut layer2_data])?;
+        }
         
-        // Layer 3: Quantum Noise Injection
+        // Layer 3: Quantum Noise Injection, in place
         log::info!("🔐 Layer 3: Quantum noise injection...");
-        let layer3_data = self.layer3.encrypt(&layer2_data, &keys.layer3_key)?;
+        let (mut layer3_data, timing) = self.padder.run(self.state.layer3.name(), || profiler.run(self.state.layer3.name(), || self.state.layer3.encrypt_owned(layer2_data, &keys.layer3_key)))?;
+        timings.extend(timing);
         log::info!("   Output: {} bytes", layer3_data.len());
+        cancel::poll(cancel, &mut [&mut layer1_data, &mut layer3_data])?;
         
-        // Layer 4: Homomorphic Encryption
+        // Layer 4: Homomorphic Encryption, in place
         log::info!("🔐 Layer 4: Homomorphic encryption...");
-        let final_data = self.layer4.encrypt(&layer3_data, &keys.layer4_key)?;
+        let (final_data, timing) = self.padder.run(self.state.layer4.name(), || profiler.run(self.state.layer4.name(), || self.state.layer4.encrypt_owned(layer3_data, &keys.layer4_key)))?;
+        timings.extend(timing);
         log::info!("   Output: {} bytes", final_data.len());
+        if let Some(cancel) = cancel {
+            cancel.check()?;
+            cancel.take_output(final_data.len() as u64)?;
+        }
         
         let elapsed = start.elapsed();
         log::info!("✅ Encryption complete in {:?}", elapsed);
         
-        Ok(EncryptedData::new(final_data))
+        let report = EncryptionReport {
+            padding: self.padder.padding(),
+            layers: timings,
+            memory: profiler.finish(),
+        };
+        let mut encrypted = EncryptedData::with_descriptors_at(final_data, self.descriptors(), self.clock.unix_secs());
+        if let Some(metadata) = Metadata::new(options.metadata.clone(), &options.private_metadata, keys)? {
+            encrypted = encrypted.with_metadata(metadata, keys);
+        }
+        let mut encrypted = encrypted
+            .with_wrapped_key(wrapped, keys)
+            .with_key_id(self.state.key_manager.key_id());
+        // Encrypt-only keys hold no master keys to make the verification tag
+        if let Ok(master) = self.state.key_manager.get_keys() {
+            encrypted = encrypted.with_verification_tag(master);
+        }
+        if let Some(authority) = &self.timestamps {
+            encrypted = timestamp::stamp(encrypted, authority.as_ref())?;
+        }
+        Ok((encrypted, report))
+    }
+    
+    /// Open the private metadata of `encrypted`, once its tag checks out under
+    /// these keys; empty when it has none. Public entries need no keys:
+    /// `encrypted.metadata().extra()`
+    pub fn open_metadata(&self, encrypted: &EncryptedData) -> Result<MetadataMap> {
+        let opened = self.state.key_manager.keys_for(encrypted).and_then(|keys| {
+            encrypted.verify_tag(&keys)?;
+            encrypted.metadata().open(&keys)
+        });
+        opened.map_err(|e| self.decrypt_errors.apply(e))
     }
     
-    /// Decrypt data through all 4 layers (in reverse)
+    /// Content type recorded at encryption with `--tag-content-type`, once
+    /// the tag of `encrypted` checks out under these keys; sealed like the
+    /// private metadata, or in the clear on files older builds tagged
+    pub fn content_type(&self, encrypted: &EncryptedData) -> Result<Option<String>> {
+        let opened = self.state.key_manager.keys_for(encrypted).and_then(|keys| {
+            encrypted.verify_tag(&keys)?;
+            encrypted.content_type(&keys)
+        });
+        opened.map_err(|e| self.decrypt_errors.apply(e))
+    }
+    
+    /// Decrypt data through all 4 layers (in reverse) under the default size limits
     pub fn decrypt(&self, encrypted: &EncryptedData) -> Result<Vec<u8>> {
+        self.decrypt_bounded(encrypted, DecryptLimits::default())
+    }
+    
+    /// Decrypt data, refusing any buffer larger than `limits` allows
+    /// No layer decrypts to more bytes than its input, so each layer's input
+    /// size bounds what it allocates and is checked before the layer runs;
+    /// the plaintext is checked at the length the last layer's framing
+    /// declares, before that layer runs
+    pub fn decrypt_bounded(&self, encrypted: &EncryptedData, limits: DecryptLimits) -> Result<Vec<u8>> {
+        self.decrypt_detailed(encrypted, limits, None, None).map_err(|e| self.decrypt_errors.apply(e))
+    }
+    
+    /// Decrypt data under `options.limits`, stopping between layers once
+    /// `options.cancel` is triggered or `options.budget` runs out; partial
+    /// plaintext is zeroized first
+    pub fn decrypt_with_options(&self, encrypted: &EncryptedData, options: &DecryptOptions) -> Result<Vec<u8>> {
+        let cancel = budgeted(options.cancel.as_ref(), options.budget);
+        let mut plaintext = self
+            .decrypt_detailed(encrypted, options.limits, cancel.as_ref(), options.warnings.as_ref())
+            .map_err(|e| self.decrypt_errors.apply(e))?;
+        if let Some(Err(e)) = cancel.map(|cancel| cancel.take_output(plaintext.len() as u64)) {
+            plaintext.zeroize();
+            return Err(e);
+        }
+        Ok(plaintext)
+    }
+    
+    /// Decrypt under the default size limits into buffers `scratch` keeps
+    /// between calls, for servers decrypting many small messages
+    /// Checks and errors are those of `decrypt`. Once the buffers have grown to
+    /// fit, layers 3 and 4 and the KEM payloads decrypt without allocating; the
+    /// plaintext borrows from `scratch` and is zeroized when it is next used
+    pub fn decrypt_with_scratch<'s>(&self, encrypted: &EncryptedData, scratch: &'s mut DecryptScratch) -> Result<&'s [u8]> {
+        match self.decrypt_into_scratch(encrypted, DecryptLimits::default(), scratch) {
+            Ok(()) => Ok(scratch.plaintext()),
+            Err(e) => {
+                scratch.clear();
+                Err(self.decrypt_errors.apply(e))
+            }
+        }
+    }
+    
+    fn decrypt_detailed(&self, encrypted: &EncryptedData, limits: DecryptLimits, cancel: Option<&CancellationToken>, warnings: Option<&Warnings>) -> Result<Vec<u8>> {
         let start = Instant::now();
+        let keys = self.state.key_manager.keys_for(encrypted)?;
+        let keys = keys.as_ref();
         
-        log::info!("Starting 4-layer decryption of {} bytes", encrypted.ciphertext.len());
+        log::info!("Starting 4-layer decryption of {} bytes", encrypted.ciphertext().len());
         
-        let keys = self.key_manager.get_keys();
+        check_limit("ciphertext", encrypted.ciphertext().len(), limits.max_ciphertext)?;
+        encrypted.require_layers(&self.readable_descriptors())?;
+        self.check_keys(keys)?;
+        
+        // Authenticate before any padding or keystream work
+        encrypted.verify_tag(keys)?;
+        for w in warning::for_container(encrypted) {
+            warning::emit(warnings, w)?;
+        }
+        cancel::poll(cancel, &mut [])?;
         
         // Layer 4: Homomorphic Decryption
         log::info!("🔓 Layer 4: Homomorphic decryption...");
-        let layer4_data = self.layer4.decrypt(&encrypted.ciphertext, &keys.layer4_key)?;
+        check_limit("intermediate", encrypted.ciphertext().len(), limits.max_intermediate)?;
+        let version = encrypted.layer_version(&self.state.layer4.descriptor().name)?;
+        let (mut layer4_data, _) = self.padder.run(self.state.layer4.name(), || self.state.layer4.decrypt_version(encrypted.ciphertext(), &keys.layer4_key, version))?;
         log::info!("   Output: {} bytes", layer4_data.len());
+        cancel::poll(cancel, &mut [&mut layer4_data])?;
         
         // Layer 3: Quantum Noise Removal
         log::info!("🔓 Layer 3: Quantum noise removal...");
-        let layer3_data = self.layer3.decrypt(&layer4_data, &keys.layer3_key)?;
+        check_limit("intermediate", layer4_data.len(), limits.max_intermediate)?;
+        let version = encrypted.layer_version(&self.state.layer3.descriptor().name)?;
+        let (mut layer3_data, _) = self.padder.run(self.state.layer3.name(), || self.state.layer3.decrypt_version(&layer4_data, &keys.layer3_key, version))?;
         log::info!("   Output: {} bytes", layer3_data.len());
+        cancel::poll(cancel, &mut [&mut layer4_data, &mut layer3_data])?;
         
-        // Layer 2: HQC Decryption
-        log::info!("🔓 Layer 2: HQC decryption...");
-        let layer2_data = self.layer2.decrypt(&layer3_data, &keys.layer2_key)?;
-        log::info!("   Output: {} bytes", layer2_data.len());
+        // Out-of-tree layers the ciphertext went through, last first; each
+        // one's key follows its position among them
+        let external: Vec<_> = encrypted.descriptors().iter().filter(|d| !self.is_builtin(&d.name)).collect();
+        for (slot, descriptor) in external.iter().enumerate().rev() {
+            let Some(layer) = self.external.iter().find(|layer| layer.descriptor().name == descriptor.name) else {
+                continue;
+            };
+            log::info!("🔓 External layer {}...", layer.name());
+            check_limit("intermediate", layer3_data.len(), limits.max_intermediate)?;
+            let key = external_key(keys, slot);
+            let (data, _) = self.padder.run(layer.name(), || layer.decrypt_version(&layer3_data, &key, descriptor.version))?;
+            log::info!("   Output: {} bytes", data.len());
+            layer3_data = data;
+            cancel::poll(cancel, &mut [&mut layer4_data, &mut layer3_data])?;
+        }
         
-        // Layer 1: ML-KEM Decryption
-        log::info!("🔓 Layer 1: ML-KEM decryption...");
-        let plaintext = self.layer1.decrypt(&layer2_data, &keys.layer1_key)?;
+        let plaintext = if encrypted.descriptors().iter().any(|d| d.name == compact_kem::LAYER_ID) {
+            // Layers 1 and 2 under one ML-KEM encapsulation, whatever this instance writes
+            log::info!("🔓 Layers 1-2: compact ML-KEM decryption...");
+            check_limit("intermediate", layer3_data.len(), limits.max_intermediate)?;
+            let compact = &self.state.compact;
+            let version = encrypted.layer_version(compact_kem::LAYER_ID)?;
+            check_limit("plaintext", compact.declared_output_len(&layer3_data, version)?, limits.max_plaintext)?;
+            let key = compact_kem::layer_key(keys);
+            let (plaintext, _) = self.padder.run(compact.name(), || compact.decrypt_version(&layer3_data, &key, version))?;
+            plaintext
+        } else {
+            // Layer 2: HQC Decryption
+            log::info!("🔓 Layer 2: HQC decryption...");
+            check_limit("intermediate", layer3_data.len(), limits.max_intermediate)?;
+            let version = encrypted.layer_version(&self.state.layer2.descriptor().name)?;
+            let (mut layer2_data, _) = self.padder.run(self.state.layer2.name(), || self.state.layer2.decrypt_version(&layer3_data, &keys.layer2_key, version))?;
+            log::info!("   Output: {} bytes", layer2_data.len());
+            cancel::poll(cancel, &mut [&mut layer4_data, &mut layer3_data, &mut layer2_data])?;
+            
+            // Layer 1: ML-KEM Decryption
+            log::info!("🔓 Layer 1: ML-KEM decryption...");
+            check_limit("intermediate", layer2_data.len(), limits.max_intermediate)?;
+            let version = encrypted.layer_version(&self.state.layer1.descriptor().name)?;
+            check_limit("plaintext", self.state.layer1.declared_output_len(&layer2_data, version)?, limits.max_plaintext)?;
+            let (plaintext, _) = self.padder.run(self.state.layer1.name(), || self.state.layer1.decrypt_version(&layer2_data, &keys.layer1_key, version))?;
+            plaintext
+        };
         log::info!("   Output: {} bytes", plaintext.len());
         
         let elapsed = start.elapsed();
@@ -111,36 +362,682 @@ impl HybridGuard {
         Ok(plaintext)
     }
     
+    /// `decrypt_detailed` through the scratch buffers; the plaintext ends in the front one
+    fn decrypt_into_scratch(&self, encrypted: &EncryptedData, limits: DecryptLimits, scratch: &mut DecryptScratch) -> Result<()> {
+        let keys = self.state.key_manager.keys_for(encrypted)?;
+        let keys = keys.as_ref();
+        
+        log::debug!("Starting 4-layer decryption of {} bytes into scratch buffers", encrypted.ciphertext().len());
+        
+        check_limit("ciphertext", encrypted.ciphertext().len(), limits.max_ciphertext)?;
+        encrypted.require_layers(&self.readable_descriptors())?;
+        self.check_keys(keys)?;
+        encrypted.verify_tag(keys)?;
+        
+        // No layer decrypts to more bytes than its input, so this is all either buffer needs
+        let (front, back) = scratch.prepare(encrypted.ciphertext().len());
+        
+        // Layer 4: Homomorphic Decryption
+        check_limit("intermediate", encrypted.ciphertext().len(), limits.max_intermediate)?;
+        let version = encrypted.layer_version(&self.state.layer4.descriptor().name)?;
+        self.padder.run(self.state.layer4.name(), || self.state.layer4.decrypt_into(encrypted.ciphertext(), &keys.layer4_key, version, front))?;
+        
+        // Each later layer reads the front buffer and writes the back one, then they swap
+        let mut next = |layer: &dyn EncryptionLayer, key: &[u8], version: u16, last: bool| -> Result<()> {
+            check_limit("intermediate", front.len(), limits.max_intermediate)?;
+            if last {
+                check_limit("plaintext", layer.declared_output_len(front, version)?, limits.max_plaintext)?;
+            }
+            self.padder.run(layer.name(), || layer.decrypt_into(front, key, version, back))?;
+            std::mem::swap(front, back);
+            Ok(())
+        };
+        
+        // Layer 3: Quantum Noise Removal
+        let version = encrypted.layer_version(&self.state.layer3.descriptor().name)?;
+        next(&self.state.layer3, &keys.layer3_key, version, false)?;
+        
+        // Out-of-tree layers, last first, as in `decrypt_detailed`
+        let external: Vec<_> = encrypted.descriptors().iter().filter(|d| !self.is_builtin(&d.name)).collect();
+        for (slot, descriptor) in external.iter().enumerate().rev() {
+            let Some(layer) = self.external.iter().find(|layer| layer.descriptor().name == descriptor.name) else {
+                continue;
+            };
+            next(layer.as_ref(), &external_key(keys, slot), descriptor.version, false)?;
+        }
+        
+        if encrypted.descriptors().iter().any(|d| d.name == compact_kem::LAYER_ID) {
+            // Layers 1 and 2 under one ML-KEM encapsulation
+            let version = encrypted.layer_version(compact_kem::LAYER_ID)?;
+            next(&self.state.compact, &compact_kem::layer_key(keys), version, true)?;
+        } else {
+            // Layer 2: HQC, then layer 1: ML-KEM
+            let version = encrypted.layer_version(&self.state.layer2.descriptor().name)?;
+            next(&self.state.layer2, &keys.layer2_key, version, false)?;
+            let version = encrypted.layer_version(&self.state.layer1.descriptor().name)?;
+            next(&self.state.layer1, &keys.layer1_key, version, true)?;
+        }
+        Ok(())
+    }
+    
+    /// Decrypt plaintext bytes `range` of a ciphertext read from `source`
+    /// Chunked ciphertexts are read only in the segments holding the range,
+    /// each authenticated by its own tag; anything else has no segment index
+    /// and is decrypted in full, with a warning. A range past the end fails
+    pub fn decrypt_range(&self, source: &mut (impl Read + Seek), range: Range<u64>) -> Result<Vec<u8>> {
+        let mut magic = Vec::new();
+        source.seek(SeekFrom::Start(0))?;
+        (&mut *source).take(chunked::MAGIC.len() as u64).read_to_end(&mut magic)?;
+        if chunked::is_chunked(&magic) {
+            return chunked::decrypt_range(source, &self.state.key_manager, range).map_err(|e| self.decrypt_errors.apply(e));
+        }
+        
+        log::warn!("ciphertext has no segment index; decrypting all of it to read {} bytes", range.end.saturating_sub(range.start));
+        let mut bytes = Vec::new();
+        source.seek(SeekFrom::Start(0))?;
+        source.read_to_end(&mut bytes)?;
+        let plaintext = self.decrypt(&EncryptedData::from_bytes(&bytes).map_err(|e| self.decrypt_errors.apply(e))?)?;
+        if range.start > range.end || range.end > plaintext.len() as u64 {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "range {}..{} is outside the {} byte plaintext",
+                range.start,
+                range.end,
+                plaintext.len()
+            )));
+        }
+        Ok(plaintext[range.start as usize..range.end as usize].to_vec())
+    }
+    
+    /// Encrypt every item independently; a failing item does not stop the others
+    /// Each item gets its own KEM encapsulation, so no randomness is shared
+    pub fn encrypt_batch(&self, items: &[&[u8]]) -> Vec<Result<EncryptedData>> {
+        self.encrypt_batch_with_summary(items).0
+    }
+    
+    /// Encrypt a batch and report how many items succeeded and how long it took
+    pub fn encrypt_batch_with_summary(&self, items: &[&[u8]]) -> (Vec<Result<EncryptedData>>, BatchSummary) {
+        batch::run(items, None, |item| self.encrypt(item))
+    }
+    
+    /// Encrypt a batch under `options`: at most `max_parallelism` items at once,
+    /// each stopping between layers once `cancel` is triggered
+    pub fn encrypt_batch_with_options(&self, items: &[&[u8]], options: &EncryptOptions) -> (Vec<Result<EncryptedData>>, BatchSummary) {
+        batch::run(items, options.max_parallelism, |item| self.encrypt_with_options(item, options))
+    }
+    
+    /// Decrypt every item independently under the default size limits
+    pub fn decrypt_batch(&self, items: &[EncryptedData]) -> Vec<Result<Vec<u8>>> {
+        self.decrypt_batch_with_summary(items).0
+    }
+    
+    /// Decrypt a batch and report how many items succeeded and how long it took
+    pub fn decrypt_batch_with_summary(&self, items: &[EncryptedData]) -> (Vec<Result<Vec<u8>>>, BatchSummary) {
+        batch::run(items, None, |item| self.decrypt(item))
+    }
+    
+    /// Fail unless all key material of this instance is locked into RAM
+    /// High-assurance deployments call this after construction to refuse
+    /// running with swappable keys
+    pub fn require_locked_memory(&self) -> Result<()> {
+        // Encrypt-only keys hold no secret key to lock
+        if self.state.key_manager.get_keys().map_or(true, |keys| keys.all_locked()) {
+            Ok(())
+        } else {
+            Err(HybridGuardError::MemoryLock(
+                "key material could not be locked into RAM (check RLIMIT_MEMLOCK / ulimit -l)".to_string(),
+            ))
+        }
+    }
+    
+    /// The key manager, if no other clone of this instance is alive
+    /// Otherwise the instance comes back unchanged, like `Arc::try_unwrap`
+    #[allow(clippy::result_large_err)]
+    pub fn try_unwrap_keys(self) -> std::result::Result<KeyManager, Self> {
+        match Arc::try_unwrap(self.state) {
+            Ok(state) => Ok(state.key_manager),
+            Err(state) => Err(Self { state, ..self }),
+        }
+    }
+    
+    /// Report process-wide security properties of the running system
+    pub fn system_status() -> SystemStatus {
+        // Probe with a small buffer so the answer is meaningful before any keys exist
+        let probe = SecretBytes::new(vec![0u8; 32]);
+        SystemStatus {
+            memory_locked: probe.is_locked() && secret::memory_locked(),
+            private_key_files: permissions::ENFORCED,
+            endianness: container::HOST_BYTE_ORDER,
+        }
+    }
+    
+    /// Key manager, for the `std::io` adapters
+    pub(crate) fn key_manager(&self) -> &KeyManager {
+        &self.state.key_manager
+    }
+    
+    /// How decrypt failures are reported, for the `std::io` adapters
+    pub(crate) fn decrypt_error_mode(&self) -> DecryptErrorMode {
+        self.decrypt_errors
+    }
+    
+    /// Descriptors of the layer formats this pipeline writes, in order
+    pub fn descriptors(&self) -> Vec<LayerDescriptor> {
+        self.stack().iter().map(|layer| layer.descriptor()).collect()
+    }
+    
+    /// Conservative security of this instance's layer stack
+    pub fn effective_security(&self) -> SecurityAssessment {
+        SecurityAssessment::of(&self.stack())
+    }
+    
+    /// Descriptors of every layer format this instance reads, whichever profile wrote it
+    fn readable_descriptors(&self) -> Vec<LayerDescriptor> {
+        let mut descriptors = layers::readable_descriptors();
+        descriptors.extend(self.external.iter().map(|layer| layer.descriptor()));
+        descriptors
+    }
+    
+    /// Which KEM layers this instance writes
+    pub fn stack_profile(&self) -> StackProfile {
+        self.profile
+    }
+    
+    /// Most bytes each layer adds to a message, in encryption order, then
+    /// the bytes of the container around the ciphertext
+    /// The sum bounds what any message grows by; FHE padding is the only
+    /// part that varies, by up to one block. Timestamp tokens are not counted
+    pub fn overhead_breakdown(&self) -> Result<Vec<(String, usize)>> {
+        let mut breakdown = Vec::new();
+        for layer in self.stack() {
+            breakdown.push((layer.name().to_string(), layer.overhead_bytes()?));
+        }
+        
+        // Every field but the ciphertext has a fixed size once the keys are
+        // known; draw from the OS so a seeded source is not advanced
+        let (file_keys, wrapped) = self.state.key_manager.new_file_keys_from(&OsRandom)?;
+        let mut empty = EncryptedData::with_descriptors_at(Vec::new(), self.descriptors(), 0)
+            .with_wrapped_key(wrapped, &file_keys)
+            .with_key_id(self.state.key_manager.key_id());
+        if let Ok(master) = self.state.key_manager.get_keys() {
+            empty = empty.with_verification_tag(master);
+        }
+        breakdown.push(("container".to_string(), empty.to_bytes()?.len()));
+        Ok(breakdown)
+    }
+    
+    /// Every layer in the order encryption runs them
+    fn stack(&self) -> Vec<&dyn EncryptionLayer> {
+        let mut stack: Vec<&dyn EncryptionLayer> = match self.profile {
+            StackProfile::Standard => vec![&self.state.layer1, &self.state.layer2],
+            StackProfile::CompactKem => vec![&self.state.compact],
+        };
+        stack.extend(self.external.iter().map(|layer| layer.as_ref()));
+        stack.push(&self.state.layer3);
+        stack.push(&self.state.layer4);
+        stack
+    }
+    
+    /// Check each key of `keys` against the layer of `stack()` it is for
+    fn check_keys(&self, keys: &LayerKeys) -> Result<()> {
+        let external: Vec<SecretBytes> = (0..self.external.len()).map(|slot| external_key(keys, slot)).collect();
+        let compact = compact_kem::layer_key(keys);
+        let mut stack_keys: Vec<&[u8]> = match self.profile {
+            StackProfile::Standard => vec![keys.layer1_key.as_bytes(), keys.layer2_key.as_bytes()],
+            StackProfile::CompactKem => vec![compact.as_bytes()],
+        };
+        stack_keys.extend(external.iter().map(|key| key.as_bytes()));
+        stack_keys.extend([keys.layer3_key.as_bytes(), keys.layer4_key.as_bytes()]);
+        layers::check_key_lens(&self.stack(), &stack_keys)
+    }
+    
+    fn is_builtin(&self, name: &str) -> bool {
+        [
+            self.state.layer1.descriptor(),
+            self.state.layer2.descriptor(),
+            self.state.compact.descriptor(),
+            self.state.layer3.descriptor(),
+            self.state.layer4.descriptor(),
+        ]
+        .iter()
+            .any(|d| d.name == name)
+    }
+    
     /// Get encryption statistics
     pub fn get_stats(&self) -> EncryptionStats {
         EncryptionStats {
-            layers: vec![
-                LayerInfo {
-                    name: self.layer1.name().to_string(),
-                    security_bits: self.layer1.security_level(),
-                    status: "Active".to_string(),
-                },
-                LayerInfo {
-                    name: self.layer2.name().to_string(),
-                    security_bits: self.layer2.security_level(),
-                    status: "Active".to_string(),
-                },
-                LayerInfo {
-                    name: self.layer3.name().to_string(),
-                    security_bits: self.layer3.security_level(),
-                    status: "Active".to_string(),
-                },
-                LayerInfo {
-                    name: self.layer4.name().to_string(),
-                    security_bits: self.layer4.security_level(),
+            layers: self
+                .stack()
+                .into_iter()
+                .map(|layer| LayerInfo {
+                    name: layer.name().to_string(),
+                    security_class: layer.security_class(),
+                    claims: layer.claims().to_string(),
                     status: "Active".to_string(),
-                },
-            ],
-            key_id: self.key_manager.key_id().to_string(),
+                })
+                .collect(),
+            key_id: self.state.key_manager.key_id().to_string(),
+        }
+    }
+}
+
+/// Upper bounds on buffer sizes during decryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecryptLimits {
+    /// Largest accepted ciphertext
+    pub max_ciphertext: usize,
+    /// Largest accepted plaintext
+    pub max_plaintext: usize,
+    /// Largest input handed to any single layer
+    pub max_intermediate: usize,
+    /// Largest output of any decompression
+    pub max_decompressed: usize,
+    /// Most bytes of decompressed output per compressed byte
+    pub max_expansion_ratio: usize,
+}
+
+impl DecryptLimits {
+    /// Default limit for every buffer: 1 GiB
+    pub const DEFAULT_LIMIT: usize = 1 << 30;
+
+    /// Default expansion ratio; text and logs compress far less than this
+    pub const DEFAULT_EXPANSION_RATIO: usize = 1000;
+
+    /// Limit for every buffer when decrypting untrusted input: 256 MiB
+    pub const STRICT_LIMIT: usize = 1 << 28;
+
+    /// Expansion ratio when decrypting untrusted input
+    pub const STRICT_EXPANSION_RATIO: usize = 100;
+
+    /// No limit on any buffer, as `HybridGuardEncryptor` decrypts unless given others
+    pub const UNBOUNDED: Self = Self {
+        max_ciphertext: usize::MAX,
+        max_plaintext: usize::MAX,
+        max_intermediate: usize::MAX,
+        max_decompressed: usize::MAX,
+        max_expansion_ratio: usize::MAX,
+    };
+
+    /// Limits for untrusted input, as `decrypt --sandbox` applies with or
+    /// without a sandbox
+    pub fn strict() -> Self {
+        Self {
+            max_ciphertext: Self::STRICT_LIMIT,
+            max_plaintext: Self::STRICT_LIMIT,
+            max_intermediate: Self::STRICT_LIMIT,
+            max_decompressed: Self::STRICT_LIMIT,
+            max_expansion_ratio: Self::STRICT_EXPANSION_RATIO,
+        }
+    }
+}
+
+impl Default for DecryptLimits {
+    fn default() -> Self {
+        Self {
+            max_ciphertext: Self::DEFAULT_LIMIT,
+            max_plaintext: Self::DEFAULT_LIMIT,
+            max_intermediate: Self::DEFAULT_LIMIT,
+            max_decompressed: Self::DEFAULT_LIMIT,
+            max_expansion_ratio: Self::DEFAULT_EXPANSION_RATIO,
+        }
+    }
+}
+
+/// Per-call settings for `HybridGuard::encrypt_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct EncryptOptions {
+    /// Checked between layers; the call fails with `Cancelled` once triggered
+    pub cancel: Option<CancellationToken>,
+    /// Worker threads a batch may use (None uses every core, 0 is treated as 1)
+    pub max_parallelism: Option<usize>,
+    /// Entries recorded in the container header, readable without keys
+    pub metadata: MetadataMap,
+    /// Entries sealed under the file's keys, readable with `open_metadata`
+    pub private_metadata: MetadataMap,
+    /// Checked between layers like `cancel`; the ciphertext counts as output
+    pub budget: Budget,
+    /// Where warnings raised by the call go; None logs them
+    pub warnings: Option<Warnings>,
+}
+
+impl EncryptOptions {
+    /// Record `key` with `value` in the header, readable without keys
+    /// Keys and sizes are checked when encrypting; see `metadata::check_entries`
+    pub fn with_metadata(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.metadata.insert(key.to_string(), value.into());
+        self
+    }
+    
+    /// Record `key` with `value` sealed, readable only with the file's keys
+    pub fn with_private_metadata(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.private_metadata.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+/// Per-call settings for `HybridGuard::decrypt_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct DecryptOptions {
+    pub limits: DecryptLimits,
+    /// Checked between layers; the call fails with `Cancelled` once triggered
+    pub cancel: Option<CancellationToken>,
+    /// Checked between layers like `cancel`; the plaintext counts as output
+    pub budget: Budget,
+    /// Where warnings raised by the call go; None logs them
+    pub warnings: Option<Warnings>,
+}
+
+/// Reusable buffers for `HybridGuard::decrypt_with_scratch`
+/// Layers decrypt from one buffer into the other, and both keep their
+/// allocations between calls. Whatever a message left in them is zeroized
+/// before the next one uses them, before they are grown, and on drop, so a
+/// shorter message never reads back a longer one's bytes
+#[derive(Default)]
+pub struct DecryptScratch {
+    /// The front buffer holds the latest layer's output, the plaintext at the end
+    buffers: [Vec<u8>; 2],
+    /// Bytes of either buffer the last message may have written, even past its length
+    dirty: usize,
+}
+
+impl DecryptScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Scratch that fits ciphertexts up to `bytes` long without growing
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self { buffers: [Vec::with_capacity(bytes), Vec::with_capacity(bytes)], dirty: 0 }
+    }
+    
+    /// Longest ciphertext the buffers fit without growing
+    pub fn capacity(&self) -> usize {
+        self.buffers[0].capacity().min(self.buffers[1].capacity())
+    }
+    
+    /// Zeroize the last plaintext and anything else left, keeping the allocations
+    /// A layer that shortens its output leaves bytes past the buffer's length,
+    /// so everything up to the longest ciphertext since the last clear is wiped
+    pub fn clear(&mut self) {
+        for buffer in &mut self.buffers {
+            let stale = self.dirty.min(buffer.capacity()).saturating_sub(buffer.len());
+            buffer.spare_capacity_mut()[..stale].zeroize();
+            buffer.as_mut_slice().zeroize();
+            buffer.clear();
+        }
+        self.dirty = 0;
+    }
+    
+    /// Both buffers, empty and able to hold `len` bytes
+    /// A buffer too small is zeroized and replaced, rather than reallocated
+    /// and its old contents left behind on the heap
+    fn prepare(&mut self, len: usize) -> (&mut Vec<u8>, &mut Vec<u8>) {
+        self.clear();
+        for buffer in &mut self.buffers {
+            if buffer.capacity() < len {
+                buffer.zeroize();
+                *buffer = Vec::with_capacity(len);
+            }
+        }
+        self.dirty = len;
+        let [front, back] = &mut self.buffers;
+        (front, back)
+    }
+    
+    fn plaintext(&self) -> &[u8] {
+        &self.buffers[0]
+    }
+}
+
+impl Drop for DecryptScratch {
+    fn drop(&mut self) {
+        for buffer in &mut self.buffers {
+            buffer.zeroize();
+        }
+    }
+}
+
+impl std::fmt::Debug for DecryptScratch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptScratch").field("capacity", &self.capacity()).finish_non_exhaustive()
+    }
+}
+
+/// How much detail `HybridGuard::decrypt` reveals about a failure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecryptErrorMode {
+    /// Wrong keys, tampering and malformed ciphertexts all fail with
+    /// `DecryptionFailed`, so callers cannot serve as a decryption oracle
+    #[default]
+    Uniform,
+    /// Keep the underlying error, for diagnostics and local tools
+    Verbose,
+}
+
+impl DecryptErrorMode {
+    pub(crate) fn apply(self, error: HybridGuardError) -> HybridGuardError {
+        if self == DecryptErrorMode::Verbose {
+            return error;
+        }
+        match error {
+            // Failures that depend on the ciphertext or keys
+            HybridGuardError::Decryption(_)
+            | HybridGuardError::DecryptionError(_)
+            | HybridGuardError::Integrity(_)
+            | HybridGuardError::Layer(_)
+            | HybridGuardError::InvalidInput(_)
+            | HybridGuardError::KeyMismatch(_) => HybridGuardError::DecryptionFailed,
+            // Limits, I/O and format versions are visible to an attacker anyway
+            other => other,
         }
     }
 }
 
+/// `cancel` also bound by `budget`, timed from now, in place of any budget
+/// it carries; `cancel` as it is when `budget` is unlimited
+fn budgeted(cancel: Option<&CancellationToken>, budget: Budget) -> Option<CancellationToken> {
+    if budget.is_unlimited() {
+        return cancel.cloned();
+    }
+    Some(cancel.cloned().unwrap_or_default().with_budget(budget))
+}
+
+pub(crate) fn check_limit(which: &str, size: usize, limit: usize) -> Result<()> {
+    if size > limit {
+        return Err(HybridGuardError::LimitExceeded {
+            which: which.to_string(),
+            size,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Which layers a `HybridGuard` instance runs before the rest of the stack
+/// Recorded in the descriptors of every ciphertext, so either profile
+/// decrypts what the other wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StackProfile {
+    /// ML-KEM-768 then HQC-256, each with its own encapsulation
+    #[default]
+    Standard,
+    /// One ML-KEM-768 encapsulation keying both layers; see `CompactKemLayer`
+    /// About 15 KB less per message, at the cost of the code-based KEM
+    CompactKem,
+}
+
+/// Most out-of-tree layers one instance runs
+pub const MAX_EXTERNAL_LAYERS: usize = 16;
+
+/// Key of the out-of-tree layer in `slot`; layers 1 to 4 are built in
+fn external_key(keys: &LayerKeys, slot: usize) -> SecretBytes {
+    keys.derive_key(KeyPurpose::Layer(5 + slot as u8))
+}
+
+/// Configures a `HybridGuard` instance
+pub struct HybridGuardBuilder {
+    key_manager: KeyManager,
+    padding: TimingPadding,
+    clock: Arc<dyn Clock>,
+    decrypt_errors: DecryptErrorMode,
+    profiling: Profiling,
+    timestamps: Option<Arc<dyn TimestampAuthority>>,
+    external: Vec<Arc<dyn EncryptionLayer>>,
+    profile: StackProfile,
+    rng: Arc<dyn RandomSource>,
+    key_lifetime: Option<Duration>,
+    min_security: u32,
+    allow_weak: bool,
+}
+
+impl HybridGuardBuilder {
+    pub fn new(key_manager: KeyManager) -> Self {
+        Self {
+            key_manager,
+            padding: TimingPadding::Off,
+            clock: Arc::new(SystemClock::new()),
+            decrypt_errors: DecryptErrorMode::default(),
+            profiling: Profiling::Off,
+            timestamps: None,
+            external: Vec::new(),
+            profile: StackProfile::default(),
+            rng: Arc::new(OsRandom),
+            key_lifetime: None,
+            min_security: MIN_EFFECTIVE_SECURITY,
+            allow_weak: false,
+        }
+    }
+    
+    /// Pad each layer's execution to a key-independent duration (off by default)
+    pub fn with_timing_padding(mut self, padding: TimingPadding) -> Self {
+        self.padding = padding;
+        self
+    }
+    
+    /// Draw file keys, wrap nonces and KEM randomness from `rng` instead of the OS
+    /// Only a seeded source for tests and golden files has reason to do this
+    pub fn with_rng(mut self, rng: Arc<dyn RandomSource>) -> Self {
+        self.rng = rng;
+        self
+    }
+    
+    /// Replace the clock used to measure and pad layer timings and to timestamp containers
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Choose how much detail decryption errors carry (uniform by default)
+    pub fn with_decrypt_errors(mut self, mode: DecryptErrorMode) -> Self {
+        self.decrypt_errors = mode;
+        self
+    }
+    
+    /// Measure the bytes each layer allocates in `encrypt_with_report` (off by default)
+    pub fn with_profiling(mut self, profiling: Profiling) -> Self {
+        self.profiling = profiling;
+        self
+    }
+    
+    /// Have `authority` timestamp every container this instance encrypts
+    pub fn with_timestamp_authority(mut self, authority: Arc<dyn TimestampAuthority>) -> Self {
+        self.timestamps = Some(authority);
+        self
+    }
+    
+    /// Raise HG002 when encrypting with keys generated `lifetime` or longer ago
+    /// (never by default); the clock set with `with_clock` says when now is
+    pub fn with_key_lifetime(mut self, lifetime: Duration) -> Self {
+        self.key_lifetime = Some(lifetime);
+        self
+    }
+    
+    /// Refuse in `build` stacks below `bits` of effective security instead of
+    /// `MIN_EFFECTIVE_SECURITY`; a policy may raise the bar, never lower it
+    pub fn with_min_security(mut self, bits: u32) -> Self {
+        self.min_security = bits.max(MIN_EFFECTIVE_SECURITY);
+        self
+    }
+    
+    /// Build even a stack below the minimum effective security, which
+    /// encryption then reports as HG003 instead
+    pub fn allow_weak_stack(mut self) -> Self {
+        self.allow_weak = true;
+        self
+    }
+    
+    /// Choose the layers run before any external ones (standard by default)
+    pub fn with_stack_profile(mut self, profile: StackProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+    
+    /// Run `layer` after HQC, behind any layers added before it
+    /// Its descriptor is written into every ciphertext, so decrypting one
+    /// needs an instance with the same layer added
+    pub fn with_layer(mut self, layer: Arc<dyn EncryptionLayer>) -> Result<Self> {
+        let name = layer.descriptor().name;
+        let builtin = crate::layers::readable_descriptors();
+        if builtin.iter().any(|d| d.name == name) || self.external.iter().any(|l| l.descriptor().name == name) {
+            return Err(HybridGuardError::InvalidInput(format!("a layer named {} is already in the stack", name)));
+        }
+        if self.external.len() == MAX_EXTERNAL_LAYERS {
+            return Err(HybridGuardError::InvalidInput(format!("at most {} external layers fit in one stack", MAX_EXTERNAL_LAYERS)));
+        }
+        self.external.push(layer);
+        Ok(self)
+    }
+    
+    /// Load the plugin library at `path` and run its layer after HQC
+    #[cfg(feature = "plugins")]
+    pub fn with_plugin(self, path: impl AsRef<Path>) -> Result<Self> {
+        let layer = crate::layers::plugin::ExternalLayer::load(path.as_ref())?;
+        log::info!("Loaded the {} layer from {}", layer.name(), layer.path().display());
+        self.with_layer(Arc::new(layer))
+    }
+    
+    /// The configured instance; fails with `PolicyViolation` when its stack
+    /// falls below the minimum effective security, unless `allow_weak_stack`
+    pub fn build(self) -> Result<HybridGuard> {
+        let (min_security, allow_weak) = (self.min_security, self.allow_weak);
+        let guard = HybridGuard {
+            state: Arc::new(KeyState {
+                key_manager: self.key_manager,
+                layer1: MlKemLayer::new(),
+                layer2: HqcLayer::new(),
+                layer3: QuantumNoiseLayer::new(),
+                layer4: FHELayer::new(),
+                compact: CompactKemLayer::new(),
+                #[cfg(test)]
+                drops: tests::DropCounter::default(),
+            }),
+            external: self.external,
+            profile: self.profile,
+            padder: TimingPadder::new(self.padding, self.clock.clone()),
+            clock: self.clock,
+            rng: self.rng,
+            decrypt_errors: self.decrypt_errors,
+            profiling: self.profiling,
+            timestamps: self.timestamps,
+            key_lifetime: self.key_lifetime,
+        };
+        guard.effective_security().enforce(min_security, allow_weak)?;
+        Ok(guard)
+    }
+}
+
+/// Security properties of the environment HybridGuard is running in
+#[derive(Debug, Clone)]
+pub struct SystemStatus {
+    /// False once any attempt to lock key material into RAM has failed
+    pub memory_locked: bool,
+    /// Whether key files are created owner-only; false on Windows, where
+    /// they inherit the ACL of their directory
+    pub private_key_files: bool,
+    /// Byte order of this host, "little-endian" or "big-endian"; files are
+    /// little-endian either way
+    pub endianness: &'static str,
+}
+
 #[derive(Debug)]
 pub struct EncryptionStats {
     pub layers: Vec<LayerInfo>,
@@ -150,13 +1047,87 @@ pub struct EncryptionStats {
 #[derive(Debug)]
 pub struct LayerInfo {
     pub name: String,
-    pub security_bits: u32,
+    pub security_class: SecurityClass,
+    /// Machine-readable claims; see `EncryptionLayer::claims`
+    pub claims: String,
     pub status: String,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{self, Direction, MockLayer};
+    
+    // Compile-time check that a shared instance can sit behind an `Arc` in a server
+    const _: fn() = || {
+        fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+        assert_send_sync::<HybridGuard>();
+        assert_send_sync::<crate::encryptor::HybridGuardEncryptor>();
+        assert_send_sync::<KeyManager>();
+        assert_send_sync::<MlKemLayer>();
+        assert_send_sync::<HqcLayer>();
+        assert_send_sync::<QuantumNoiseLayer>();
+        assert_send_sync::<FHELayer>();
+        assert_send_sync::<dyn EncryptionLayer>();
+    };
+    
+    /// Counts how often the `KeyState` holding it was dropped
+    #[derive(Default)]
+    pub(super) struct DropCounter(Option<Arc<std::sync::atomic::AtomicUsize>>);
+    
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            if let Some(count) = &self.0 {
+                count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_clones_share_one_key_state() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        
+        let mut hg = HybridGuard::builder(KeyManager::from_master_key(&[0x71; 32]).unwrap()).build().unwrap();
+        let drops = Arc::new(AtomicUsize::new(0));
+        Arc::get_mut(&mut hg.state).unwrap().drops = DropCounter(Some(drops.clone()));
+        
+        let handles: Vec<_> = (0..16u8)
+            .map(|i| {
+                let hg = hg.clone();
+                std::thread::spawn(move || {
+                    let message = vec![i; 100 + i as usize];
+                    let encrypted = hg.encrypt(&message).unwrap();
+                    assert_eq!(hg.decrypt(&encrypted).unwrap(), message);
+                    hg
+                })
+            })
+            .collect();
+        let clones: Vec<HybridGuard> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(Arc::strong_count(&hg.state), 17);
+        assert!(clones.iter().all(|clone| Arc::ptr_eq(&clone.state, &hg.state)));
+        
+        // Keys stay shared while any clone is alive
+        let Err(hg) = hg.try_unwrap_keys() else { panic!("keys unwrapped while clones are alive") };
+        drop(clones);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        let second = hg.clone();
+        drop(hg);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        drop(second);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn test_try_unwrap_keys_returns_the_key_manager() {
+        let key_manager = KeyManager::from_master_key(&[0x72; 32]).unwrap();
+        let key_id = key_manager.key_id().to_string();
+        let hg = HybridGuard::builder(key_manager).build().unwrap();
+        let clone = hg.clone();
+        let Err(hg) = hg.try_unwrap_keys() else { panic!("keys unwrapped while a clone is alive") };
+        drop(clone);
+        let Ok(keys) = hg.try_unwrap_keys() else { panic!("last instance kept its keys") };
+        assert_eq!(keys.key_id(), key_id);
+    }
     
     #[test]
     fn test_encrypt_decrypt() {
@@ -164,8 +1135,450 @@ mod tests {
         
         let plaintext = b"Hello, HybridGuard!";
         let encrypted = hg.encrypt(plaintext).unwrap();
+        assert_eq!(encrypted.key_id(), Some(hg.state.key_manager.key_id()));
         let decrypted = hg.decrypt(&encrypted).unwrap();
         
         assert_eq!(plaintext, &decrypted[..]);
     }
+    
+    #[test]
+    fn test_encrypt_batch_is_independent_per_item() {
+        let hg = HybridGuard::new("batch_password").unwrap();
+        let items: [&[u8]; 4] = [b"same", b"", b"same", b"different"];
+        
+        let (encrypted, summary) = hg.encrypt_batch_with_summary(&items);
+        assert_eq!(summary.succeeded, 4);
+        assert_eq!(summary.total(), items.len());
+        
+        let encrypted: Vec<EncryptedData> = encrypted.into_iter().map(|r| r.unwrap()).collect();
+        // Identical plaintexts still get distinct encapsulations
+        assert_ne!(encrypted[0].ciphertext(), encrypted[2].ciphertext());
+        for (item, decrypted) in items.iter().zip(hg.decrypt_batch(&encrypted)) {
+            assert_eq!(&decrypted.unwrap()[..], *item);
+        }
+    }
+    
+    #[test]
+    fn test_encrypt_batch_with_max_parallelism() {
+        let hg = HybridGuard::new("batch_password").unwrap();
+        let items: [&[u8]; 3] = [b"one", b"two", b"three"];
+        let options = EncryptOptions { max_parallelism: Some(1), ..EncryptOptions::default() };
+        
+        let (encrypted, summary) = hg.encrypt_batch_with_options(&items, &options);
+        assert_eq!(summary.succeeded, 3);
+        for (item, encrypted) in items.iter().zip(encrypted) {
+            assert_eq!(&hg.decrypt(&encrypted.unwrap()).unwrap()[..], *item);
+        }
+    }
+    
+    #[test]
+    fn test_decrypt_batch_reports_failing_index() {
+        let hg = HybridGuard::new("batch_password").unwrap();
+        let items: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let mut encrypted: Vec<EncryptedData> = hg
+            .encrypt_batch(&items)
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect();
+        // Flip a bit of item 1's ciphertext so its tag no longer matches
+        encrypted[1] = test_support::flip_ciphertext_bit(&encrypted[1], 0).unwrap();
+        
+        let (results, summary) = hg.decrypt_batch_with_summary(&encrypted);
+        assert_eq!((summary.succeeded, summary.failed), (2, 1));
+        assert_eq!(results[0].as_ref().unwrap(), b"first");
+        assert_eq!(results[2].as_ref().unwrap(), b"third");
+        assert!(matches!(results[1], Err(HybridGuardError::BatchItem { index: 1, .. })));
+    }
+    
+    /// Three ways to fail: wrong keys, a flipped bit, and a correctly tagged
+    /// ciphertext whose padding does not decode
+    fn failing_inputs(hg: &HybridGuard) -> Vec<EncryptedData> {
+        let encrypted = hg.encrypt(b"oracle").unwrap();
+        let wrong_key = HybridGuard::new("other_password").unwrap().encrypt(b"oracle").unwrap();
+        let flipped = test_support::flip_ciphertext_bit(&encrypted, 0).unwrap();
+        let bad_padding = test_support::tagged_fixture((1..=64).collect(), &hg.state.key_manager).unwrap();
+        vec![wrong_key, flipped, bad_padding]
+    }
+    
+    #[test]
+    fn test_uniform_decrypt_errors() {
+        let hg = HybridGuard::new("test_password_123").unwrap();
+        
+        for encrypted in failing_inputs(&hg) {
+            let err = hg.decrypt(&encrypted).unwrap_err();
+            assert!(matches!(err, HybridGuardError::DecryptionFailed));
+            assert_eq!(err.to_string(), "Decryption failed");
+        }
+    }
+    
+    #[test]
+    fn test_scratch_decrypt_errors_are_uniform() {
+        let hg = HybridGuard::new("test_password_123").unwrap();
+        let mut scratch = DecryptScratch::new();
+        
+        for encrypted in failing_inputs(&hg) {
+            let err = hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap_err();
+            assert!(matches!(err, HybridGuardError::DecryptionFailed));
+            assert!(scratch.plaintext().is_empty());
+        }
+    }
+    
+    #[test]
+    fn test_range_decrypt_errors_are_uniform() {
+        let hg = HybridGuard::new("test_password_123").unwrap();
+        let mut bytes = hg.encrypt(&[0x5A; 5000]).unwrap().to_bytes().unwrap();
+        bytes.truncate(bytes.len() / 2);
+        
+        // A malformed container fails like a tampered one
+        let err = hg.decrypt_range(&mut std::io::Cursor::new(bytes), 0..10).unwrap_err();
+        assert!(matches!(err, HybridGuardError::DecryptionFailed), "{}", err);
+    }
+    
+    #[test]
+    fn test_scratch_clear_leaves_no_stale_bytes() {
+        let hg = HybridGuard::new("test_password_123").unwrap();
+        let encrypted = hg.encrypt(&[0x5A; 5000]).unwrap();
+        let mut scratch = DecryptScratch::new();
+        assert_eq!(hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap(), &[0x5A; 5000][..]);
+        
+        // Both buffers held the layer 4 output; the KEM layers shortened it,
+        // leaving intermediate bytes past both lengths
+        let written = encrypted.ciphertext().len();
+        scratch.clear();
+        for buffer in &scratch.buffers {
+            assert!(buffer.is_empty());
+            assert!(buffer.capacity() >= written);
+            // SAFETY: a layer wrote every byte up to `written`, then `clear` zeroized them
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr(), written) };
+            assert!(bytes.iter().all(|&b| b == 0));
+        }
+    }
+    
+    #[test]
+    fn test_verbose_decrypt_errors() {
+        let key_manager = KeyManager::generate("test_password_123").unwrap();
+        let hg = HybridGuard::builder(key_manager)
+            .with_decrypt_errors(DecryptErrorMode::Verbose)
+            .build().unwrap();
+        
+        let errors: Vec<HybridGuardError> = failing_inputs(&hg)
+            .iter()
+            .map(|encrypted| hg.decrypt(encrypted).unwrap_err())
+            .collect();
+        assert!(matches!(errors[0], HybridGuardError::Integrity(_)));
+        assert!(matches!(errors[1], HybridGuardError::Integrity(_)));
+        assert!(!matches!(errors[2], HybridGuardError::Integrity(_) | HybridGuardError::DecryptionFailed));
+    }
+    
+    #[test]
+    fn test_timing_padding_report() {
+        let key_manager = KeyManager::generate("test_password_123").unwrap();
+        let hg = HybridGuard::builder(key_manager)
+            .with_timing_padding(TimingPadding::ToMultipleMs(5))
+            .build().unwrap();
+        
+        let (encrypted, report) = hg.encrypt_with_report(b"padded").unwrap();
+        assert_eq!(report.layers.len(), 4);
+        for timing in &report.layers {
+            assert!(timing.padded >= timing.elapsed);
+            assert!(timing.padded >= std::time::Duration::from_millis(5));
+        }
+        assert_eq!(hg.decrypt(&encrypted).unwrap(), b"padded");
+    }
+    
+    #[test]
+    fn test_locked_memory_status() {
+        let hg = HybridGuard::new("test_password_123").unwrap();
+        
+        // Locking is best effort; the requirement check must agree with the keys
+        let locked = hg.state.key_manager.get_keys().unwrap().all_locked();
+        assert_eq!(hg.require_locked_memory().is_ok(), locked);
+        if !locked {
+            assert!(!HybridGuard::system_status().memory_locked);
+        }
+    }
+    
+    #[test]
+    fn test_timestamp_authority_stamps_containers() {
+        use crate::crypto::timestamp::LocalSigningAuthority;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let authority = Arc::new(LocalSigningAuthority::new("hold", &[0x33; 32], dir.path().join("tsa.counter")).unwrap());
+        let key_manager = KeyManager::from_master_key(&[0x34; 32]).unwrap();
+        let hg = HybridGuard::builder(key_manager).with_timestamp_authority(authority.clone()).build().unwrap();
+        
+        let encrypted = hg.encrypt(b"under legal hold").unwrap();
+        assert_eq!(timestamp::verify_timestamp(&encrypted, authority.as_ref()).unwrap().serial, 1);
+        assert_eq!(hg.decrypt(&encrypted).unwrap(), b"under legal hold");
+    }
+    
+    #[test]
+    fn test_effective_security_of_default_stack() {
+        let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x35; 32]).unwrap()).build().unwrap();
+        let assessment = hg.effective_security();
+        assert!(assessment.effective_bits >= 192);
+        assert_eq!(assessment.layers.len(), 4);
+        // The noise layer is obfuscation and is not counted
+        assert_eq!(assessment.layers[2].class, SecurityClass::Obfuscation);
+        assert_eq!(assessment.layers[2].counted_bits, 0);
+    }
+    
+    #[test]
+    fn test_build_refuses_stacks_below_the_minimum() {
+        let builder = || HybridGuard::builder(KeyManager::from_master_key(&[0x34; 32]).unwrap());
+        // The compact stack counts 192 bits, the standard one 256
+        let compact = || builder().with_stack_profile(StackProfile::CompactKem).with_min_security(256);
+        assert!(matches!(compact().build(), Err(HybridGuardError::PolicyViolation(m)) if m.contains("192 bits")));
+        assert!(builder().with_min_security(256).build().is_ok());
+        
+        let hg = compact().allow_weak_stack().build().unwrap();
+        assert_eq!(hg.effective_security().effective_bits, 192);
+        let encrypted = hg.encrypt(b"weak but allowed").unwrap();
+        assert_eq!(hg.decrypt(&encrypted).unwrap(), b"weak but allowed");
+        
+        // The minimum is never lowered
+        assert_eq!(builder().with_min_security(0).min_security, MIN_EFFECTIVE_SECURITY);
+    }
+    
+    #[test]
+    fn test_stats_classify_each_layer() {
+        let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x36; 32]).unwrap()).build().unwrap();
+        let classes: Vec<SecurityClass> = hg.get_stats().layers.iter().map(|layer| layer.security_class).collect();
+        assert_eq!(
+            classes,
+            [
+                SecurityClass::PostQuantumKem { nist_level: 3 },
+                SecurityClass::PostQuantumKem { nist_level: 5 },
+                SecurityClass::Obfuscation,
+                SecurityClass::Experimental,
+            ]
+        );
+    }
+    
+    /// Fixed bytes a compact-profile container may add to any message
+    /// Measured: 1092 for the encapsulation, up to 32 of FHE padding and about
+    /// 340 of container, against about 16 KB for the standard stack
+    const COMPACT_OVERHEAD_BUDGET: usize = 1536;
+    
+    #[test]
+    fn test_overhead_breakdown_bounds_stored_size() {
+        let record = vec![0xA7u8; 100];
+        for profile in [StackProfile::Standard, StackProfile::CompactKem] {
+            let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x37; 32]).unwrap()).with_stack_profile(profile).build().unwrap();
+            let fixed: usize = hg.overhead_breakdown().unwrap().iter().map(|(_, bytes)| bytes).sum();
+            let stored = hg.encrypt(&record).unwrap().to_bytes().unwrap().len();
+            // FHE pads by 1 to 32 bytes, and the breakdown counts 32
+            assert!(stored <= record.len() + fixed && stored + 31 >= record.len() + fixed, "{:?}: {} of {}", profile, stored, fixed);
+        }
+    }
+    
+    #[test]
+    fn test_compact_profile_fits_its_budget() {
+        let key_manager = || KeyManager::from_master_key(&[0x38; 32]).unwrap();
+        let standard = HybridGuard::builder(key_manager()).build().unwrap();
+        let compact = HybridGuard::builder(key_manager()).with_stack_profile(StackProfile::CompactKem).build().unwrap();
+        
+        // The standard stack is unchanged: every built-in layer, then the container
+        let names: Vec<String> = standard.overhead_breakdown().unwrap().into_iter().map(|(name, _)| name).collect();
+        let mut expected: Vec<String> = layers::registry().iter().map(|layer| layer.name().to_string()).collect();
+        expected.push("container".to_string());
+        assert_eq!(names, expected);
+        assert_eq!(standard.descriptors(), layers::current_descriptors());
+        
+        let fixed = |hg: &HybridGuard| -> usize { hg.overhead_breakdown().unwrap().iter().map(|(_, bytes)| bytes).sum() };
+        assert!(fixed(&compact) < COMPACT_OVERHEAD_BUDGET, "{:?}", compact.overhead_breakdown().unwrap());
+        assert!(fixed(&standard) > 10 * 1024, "{:?}", standard.overhead_breakdown().unwrap());
+        
+        // The descriptors say which KEMs ran, so each profile reads the other's output
+        let record = b"one of millions of small records";
+        let small = compact.encrypt(record).unwrap();
+        assert_eq!(small.descriptors()[0].name, compact_kem::LAYER_ID);
+        assert!(small.to_bytes().unwrap().len() < record.len() + COMPACT_OVERHEAD_BUDGET);
+        assert_eq!(standard.decrypt(&small).unwrap(), record);
+        assert_eq!(compact.decrypt(&standard.encrypt(record).unwrap()).unwrap(), record);
+        assert_eq!(compact.effective_security().effective_bits, 192);
+    }
+    
+    #[test]
+    fn test_external_layers_round_trip() {
+        let keys = || KeyManager::from_master_key(&[0x37; 32]).unwrap();
+        let hg = HybridGuard::builder(keys())
+            .with_decrypt_errors(DecryptErrorMode::Verbose)
+            .with_layer(Arc::new(MockLayer::new("Lattice-2.5")))
+            .unwrap()
+            .with_layer(Arc::new(MockLayer::new("Lattice-2.6")))
+            .unwrap()
+            .build().unwrap();
+        
+        let encrypted = hg.encrypt(b"layer two and a half").unwrap();
+        let names: Vec<&str> = encrypted.descriptors().iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names[2..4], ["Lattice-2.5", "Lattice-2.6"]);
+        assert_eq!(hg.get_stats().layers.len(), 6);
+        assert_eq!(hg.decrypt(&encrypted).unwrap(), b"layer two and a half");
+        
+        // Ciphertexts without the external layers still decrypt
+        let plain = HybridGuard::builder(keys()).build().unwrap();
+        assert_eq!(hg.decrypt(&plain.encrypt(b"built in").unwrap()).unwrap(), b"built in");
+        
+        // An instance missing a layer refuses rather than misdecrypting
+        let partial = HybridGuard::builder(keys()).with_layer(Arc::new(MockLayer::new("Lattice-2.6"))).unwrap().build().unwrap();
+        for guard in [&plain, &partial] {
+            let err = guard.decrypt(&encrypted).unwrap_err();
+            assert!(matches!(err, HybridGuardError::UnsupportedFormat(ref m) if m.contains("Lattice-2.5")), "{}", err);
+        }
+    }
+    
+    #[test]
+    fn test_external_layer_names_are_unique() {
+        let builder = HybridGuard::builder(KeyManager::from_master_key(&[0x38; 32]).unwrap());
+        assert!(builder.with_layer(Arc::new(MockLayer::new("HQC"))).is_err());
+        let builder = HybridGuard::builder(KeyManager::from_master_key(&[0x38; 32]).unwrap());
+        let builder = builder.with_layer(Arc::new(MockLayer::new("Lattice-2.5"))).unwrap();
+        assert!(builder.with_layer(Arc::new(MockLayer::new("Lattice-2.5"))).is_err());
+    }
+    
+    fn injected() -> HybridGuardError {
+        HybridGuardError::Layer("injected".to_string())
+    }
+    
+    #[test]
+    fn test_failing_layer_stops_the_pipeline() {
+        let first = Arc::new(MockLayer::new("Mock-A").failing(Direction::Encrypt, 2, injected));
+        let second = Arc::new(MockLayer::new("Mock-B").failing(Direction::Decrypt, 1, injected));
+        let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x39; 32]).unwrap())
+            .with_decrypt_errors(DecryptErrorMode::Verbose)
+            .with_layer(first.clone())
+            .unwrap()
+            .with_layer(second.clone())
+            .unwrap()
+            .build().unwrap();
+        
+        // The second encryption fails in Mock-A; Mock-B never sees it
+        let encrypted = hg.encrypt(b"first").unwrap();
+        assert!(matches!(hg.encrypt(b"second"), Err(HybridGuardError::Layer(m)) if m == "injected"));
+        assert_eq!((first.encrypt_calls(), second.encrypt_calls()), (2, 1));
+        
+        // Decryption runs them last first, so Mock-B's failure keeps Mock-A from running
+        assert!(matches!(hg.decrypt(&encrypted), Err(HybridGuardError::Layer(m)) if m == "injected"));
+        assert_eq!(first.decrypt_calls(), 0);
+        assert_eq!(hg.decrypt(&encrypted).unwrap(), b"first");
+        assert_eq!((first.decrypt_calls(), second.decrypt_calls()), (1, 2));
+    }
+    
+    #[test]
+    fn test_layer_failures_are_uniform_by_default() {
+        // One layer fails outright, the other hands HQC bytes it made up
+        let failing = Arc::new(MockLayer::new("Mock-A").failing(Direction::Decrypt, 1, injected));
+        let garbling = Arc::new(MockLayer::new("Mock-B").returning(Direction::Decrypt, 1, vec![0xEE; 64]));
+        let keys = || KeyManager::from_master_key(&[0x3A; 32]).unwrap();
+        for layer in [failing, garbling] {
+            let hg = HybridGuard::builder(keys()).with_layer(layer.clone()).unwrap().build().unwrap();
+            let encrypted = hg.encrypt(b"record").unwrap();
+            assert!(matches!(hg.decrypt(&encrypted), Err(HybridGuardError::DecryptionFailed)), "{}", layer.name());
+            let mut scratch = DecryptScratch::new();
+            assert_eq!(hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap(), b"record");
+        }
+        
+        // So are keys that cannot decrypt, however they fail
+        let encrypted = HybridGuard::builder(keys()).build().unwrap().encrypt(b"record").unwrap();
+        let wrong = HybridGuard::builder(test_support::FailingKeyManager::WrongKey.against(&keys()).unwrap()).build().unwrap();
+        assert!(matches!(wrong.decrypt(&encrypted), Err(HybridGuardError::DecryptionFailed)));
+        let encrypt_only = test_support::FailingKeyManager::EncryptOnly.against(&keys()).unwrap();
+        let encrypt_only = HybridGuard::builder(encrypt_only).with_decrypt_errors(DecryptErrorMode::Verbose).build().unwrap();
+        assert!(matches!(encrypt_only.decrypt(&encrypted), Err(HybridGuardError::CapabilityDenied(_))));
+    }
+    
+    /// Cancels its token whenever a layer sleeps on it
+    struct CancelOnSleep(CancellationToken);
+    
+    impl Clock for CancelOnSleep {
+        fn now(&self) -> std::time::Duration {
+            std::time::Duration::ZERO
+        }
+        
+        fn sleep(&self, _: std::time::Duration) {
+            self.0.cancel();
+        }
+    }
+    
+    #[test]
+    fn test_cancel_during_a_slow_layer_stops_after_it() {
+        let token = CancellationToken::new();
+        let slow = MockLayer::new("Slow").with_latency(std::time::Duration::from_secs(60)).with_clock(Arc::new(CancelOnSleep(token.clone())));
+        let after = Arc::new(MockLayer::new("After"));
+        let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x3B; 32]).unwrap())
+            .with_layer(Arc::new(slow))
+            .unwrap()
+            .with_layer(after.clone())
+            .unwrap()
+            .build().unwrap();
+        
+        let options = EncryptOptions { cancel: Some(token.clone()), ..EncryptOptions::default() };
+        assert!(matches!(hg.encrypt_with_options(b"record", &options), Err(HybridGuardError::Cancelled)));
+        assert_eq!(after.encrypt_calls(), 0);
+    }
+    
+    #[test]
+    fn test_decrypt_warns_of_legacy_and_weak_containers() {
+        use crate::warning::WarningCode;
+        
+        let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x3C; 32]).unwrap()).build().unwrap();
+        let warnings = Warnings::new();
+        let options = DecryptOptions { warnings: Some(warnings.clone()), ..DecryptOptions::default() };
+        let codes = |warnings: &Warnings| warnings.take().into_iter().map(|w| w.code).collect::<Vec<_>>();
+        
+        // Current containers raise nothing
+        let encrypted = hg.encrypt(b"minutes").unwrap();
+        assert_eq!(hg.decrypt_with_options(&encrypted, &options).unwrap(), b"minutes");
+        assert!(codes(&warnings).is_empty());
+        
+        // An untagged fixture does not decode, but is warned about first
+        let untagged = test_support::EncryptedDataBuilder::new((1..=64).collect()).build().unwrap();
+        assert!(hg.decrypt_with_options(&untagged, &options).is_err());
+        assert_eq!(codes(&warnings), [WarningCode::LegacyFormat]);
+        
+        // Noise and FHE alone fall short of the minimum
+        let weak = test_support::EncryptedDataBuilder::new((1..=64).collect())
+            .descriptors(vec![hg.state.layer3.descriptor(), hg.state.layer4.descriptor()])
+            .tag(hg.state.key_manager.get_keys().unwrap())
+            .unwrap()
+            .build().unwrap()
+            .unwrap();
+        assert!(hg.decrypt_with_options(&weak, &options).is_err());
+        let raised = warnings.take();
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].code, WarningCode::WeakStack);
+        assert_eq!(raised[0].context["effective_bits"], "0");
+        
+        // Allowed, the warning is dropped; denied, it fails the call
+        let allowing = DecryptOptions { warnings: Some(Warnings::new().with_deny(true).allow(WarningCode::LegacyFormat)), ..DecryptOptions::default() };
+        assert!(!matches!(hg.decrypt_with_options(&untagged, &allowing), Err(HybridGuardError::WarningDenied { .. })));
+        let denying = DecryptOptions { warnings: Some(Warnings::new().with_deny(true)), ..DecryptOptions::default() };
+        let err = hg.decrypt_with_options(&untagged, &denying).unwrap_err();
+        assert!(matches!(err, HybridGuardError::WarningDenied { code: WarningCode::LegacyFormat, .. }), "{:?}", err);
+    }
+    
+    #[test]
+    fn test_keys_past_their_lifetime_warn_on_encrypt() {
+        use crate::warning::WarningCode;
+        
+        let keys = || KeyManager::from_master_key(&[0x3D; 32]).unwrap();
+        let created = chrono::DateTime::parse_from_rfc3339(keys().created_at()).unwrap().timestamp() as u64;
+        let year = Duration::from_secs(365 * 24 * 60 * 60);
+        let at = |now: u64| HybridGuard::builder(keys()).with_key_lifetime(year).with_clock(Arc::new(test_support::FixedClock::new(now))).build().unwrap();
+        let warnings = Warnings::new();
+        let options = EncryptOptions { warnings: Some(warnings.clone()), ..EncryptOptions::default() };
+        
+        at(created + 364 * 24 * 60 * 60).encrypt_with_options(b"ledger", &options).unwrap();
+        assert!(warnings.collected().is_empty());
+        at(created + 400 * 24 * 60 * 60).encrypt_with_options(b"ledger", &options).unwrap();
+        let raised = warnings.take();
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].code, WarningCode::KeyExpired);
+        assert_eq!(raised[0].context["key_id"], keys().key_id());
+        
+        let denying = EncryptOptions { warnings: Some(Warnings::new().with_deny(true)), ..EncryptOptions::default() };
+        let result = at(created + 400 * 24 * 60 * 60).encrypt_with_options(b"ledger", &denying);
+        assert!(matches!(result, Err(HybridGuardError::WarningDenied { code: WarningCode::KeyExpired, .. })));
+    }
 }
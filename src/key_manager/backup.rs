@@ -0,0 +1,337 @@
+// Automatic key file backups before destructive writes
+// Before a key file is overwritten, its bytes are copied to
+// `<name>.bak-YYYYMMDDHHMMSS` (UTC) beside it or in a backup directory. The
+// copy is read back and checked before the caller goes on, and the oldest
+// backups beyond the kept count are shredded. A sealed key file is copied as
+// is, so its backups stay sealed; a plain one gives plaintext backups.
+// Upgrading a version 1 key file to version 2 always goes through a backup,
+// since versions before the envelope cannot read the upgraded file.
+
+use super::{permissions, protector, KeyManager, KEY_FILE_VERSION};
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Backups kept per key file unless configured otherwise
+pub const DEFAULT_KEEP: usize = 5;
+
+/// Separates the key file name from the timestamp in backup names
+const MARKER: &str = ".bak-";
+
+/// Where backups go and how many are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupPolicy {
+    /// None keeps backups beside the key file
+    pub dir: Option<PathBuf>,
+    /// At least 1
+    pub keep: usize,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self { dir: None, keep: DEFAULT_KEEP }
+    }
+}
+
+impl BackupPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep.max(1);
+        self
+    }
+
+    fn dir_for(&self, key_file: &Path) -> PathBuf {
+        match (&self.dir, key_file.parent()) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(parent)) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            (None, _) => PathBuf::from("."),
+        }
+    }
+}
+
+/// One backup of a key file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBackup {
+    pub path: PathBuf,
+    /// When it was taken, as YYYYMMDDHHMMSS in UTC
+    pub stamp: String,
+    /// Taken in the same second as an earlier backup: 2, 3, ...; otherwise 1
+    pub sequence: u32,
+    /// Whether it is sealed by a protector; plain backups hold the keys in the clear
+    pub protected: bool,
+}
+
+impl KeyBackup {
+    /// File name of the backup, as `key restore-backup` lists it
+    pub fn name(&self) -> String {
+        self.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+}
+
+/// Copy `key_file` aside under `policy` before it is rewritten or removed
+/// Returns None when there is no key file yet. Fails, leaving no backup, when
+/// the key file does not parse or the copy does not read back intact.
+/// Writers sharing a keystore call this under its `lock::KeystoreLock`, so
+/// backup names and pruning never race
+pub fn back_up(key_file: &Path, policy: &BackupPolicy, now: u64) -> Result<Option<KeyBackup>> {
+    let bytes = match fs::read(key_file) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let protected = check(&bytes).map_err(|e| refused(key_file, e))?;
+
+    let dir = policy.dir_for(key_file);
+    permissions::create_private_dir_all(&dir)?;
+    let stamp = stamp(now)?;
+    let (path, sequence) = free_name(&dir, &file_name(key_file)?, &stamp);
+    permissions::write_private(&path, &bytes)?;
+    if let Err(e) = verify_copy(&path, &bytes) {
+        let _ = fsutil::shred(&path);
+        return Err(refused(key_file, e));
+    }
+
+    prune(key_file, policy)?;
+    Ok(Some(KeyBackup { path, stamp, sequence, protected }))
+}
+
+/// Rewrite a plain version 1 key file as the current version, backing it up first
+/// Returns None, changing nothing, when there is no key file or it is already
+/// current; a sealed file is upgraded the next time it is saved with its protector
+pub fn migrate(key_file: &Path, policy: &BackupPolicy, now: u64) -> Result<Option<KeyBackup>> {
+    let bytes = match fs::read(key_file) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if protector::protection(&bytes).is_some() {
+        return Ok(None);
+    }
+    let key_manager = KeyManager::from_bytes(&bytes)?;
+    if key_manager.format_version() >= KEY_FILE_VERSION {
+        return Ok(None);
+    }
+    let backup = back_up(key_file, policy, now)?;
+    key_manager.save(key_file)?;
+    Ok(backup)
+}
+
+/// Backups of `key_file` under `policy`, oldest first
+pub fn list(key_file: &Path, policy: &BackupPolicy) -> Result<Vec<KeyBackup>> {
+    let prefix = format!("{}{}", file_name(key_file)?.to_string_lossy(), MARKER);
+    let entries = match fs::read_dir(policy.dir_for(key_file)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some((stamp, sequence)) = name.strip_prefix(&prefix).and_then(parse_suffix) else {
+            continue;
+        };
+        let protected = fs::read(entry.path()).map(|bytes| protector::protection(&bytes).is_some()).unwrap_or(false);
+        backups.push(KeyBackup { path: entry.path(), stamp, sequence, protected });
+    }
+    backups.sort_by(|a, b| (&a.stamp, a.sequence).cmp(&(&b.stamp, b.sequence)));
+    Ok(backups)
+}
+
+/// The backup of `key_file` called `name`, or the newest one for `latest`
+pub fn find(key_file: &Path, policy: &BackupPolicy, name: &str) -> Result<KeyBackup> {
+    let backups = list(key_file, policy)?;
+    let found = match name {
+        "latest" => backups.last().cloned(),
+        name => backups.into_iter().find(|backup| backup.name() == name),
+    };
+    found.ok_or_else(|| {
+        HybridGuardError::InvalidInput(format!(
+            "no backup '{}' of {}; run `key restore-backup` without --backup to list them",
+            name,
+            key_file.display()
+        ))
+    })
+}
+
+/// Read and check a backup before anything is overwritten with it
+pub fn read(backup: &KeyBackup) -> Result<Vec<u8>> {
+    let bytes = fs::read(&backup.path)?;
+    check(&bytes).map_err(|e| {
+        HybridGuardError::InvalidInput(format!("backup {} is not a usable key file: {}", backup.path.display(), e))
+    })?;
+    Ok(bytes)
+}
+
+/// Whether `bytes` is a key file: a sealed one (true), whose envelope is all
+/// that can be checked without its protector, or a plain one that loads (false)
+fn check(bytes: &[u8]) -> Result<bool> {
+    if protector::protection(bytes).is_some() {
+        return Ok(true);
+    }
+    KeyManager::from_bytes(bytes).map(|_| false)
+}
+
+/// Read a fresh backup back: it must hold exactly `bytes` and still parse
+fn verify_copy(path: &Path, bytes: &[u8]) -> Result<()> {
+    let copy = fs::read(path)?;
+    if copy != bytes {
+        return Err(HybridGuardError::Integrity("the copy differs from the key file".to_string()));
+    }
+    check(&copy).map(|_| ())
+}
+
+fn refused(key_file: &Path, error: HybridGuardError) -> HybridGuardError {
+    HybridGuardError::InvalidInput(format!(
+        "could not back up {} ({}); nothing was changed",
+        key_file.display(),
+        error
+    ))
+}
+
+/// Shred the oldest backups beyond the kept count
+fn prune(key_file: &Path, policy: &BackupPolicy) -> Result<()> {
+    let backups = list(key_file, policy)?;
+    let excess = backups.len().saturating_sub(policy.keep.max(1));
+    for backup in &backups[..excess] {
+        fsutil::shred(&backup.path)?;
+    }
+    Ok(())
+}
+
+fn file_name(key_file: &Path) -> Result<OsString> {
+    key_file
+        .file_name()
+        .map(OsString::from)
+        .ok_or_else(|| HybridGuardError::InvalidInput(format!("{} is not a file name", key_file.display())))
+}
+
+fn stamp(now: u64) -> Result<String> {
+    chrono::DateTime::from_timestamp(now as i64, 0)
+        .map(|time| time.format("%Y%m%d%H%M%S").to_string())
+        .ok_or_else(|| HybridGuardError::InvalidInput(format!("{} is not a representable time", now)))
+}
+
+/// `<name>.bak-<stamp>`, or `-2`, `-3`, ... after it for later backups in the same second
+fn free_name(dir: &Path, name: &OsString, stamp: &str) -> (PathBuf, u32) {
+    let mut sequence = 1;
+    loop {
+        let mut file = name.clone();
+        file.push(MARKER);
+        file.push(stamp);
+        if sequence > 1 {
+            file.push(format!("-{}", sequence));
+        }
+        let path = dir.join(file);
+        if !path.exists() {
+            return (path, sequence);
+        }
+        sequence += 1;
+    }
+}
+
+/// Stamp and sequence of a backup name's suffix, e.g. `20260115093000-2`
+fn parse_suffix(suffix: &str) -> Option<(String, u32)> {
+    let (stamp, sequence) = match suffix.split_once('-') {
+        Some((stamp, sequence)) => (stamp, sequence.parse().ok().filter(|n| *n > 1)?),
+        None => (suffix, 1),
+    };
+    (stamp.len() == 14 && stamp.bytes().all(|b| b.is_ascii_digit())).then(|| (stamp.to_string(), sequence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2026-01-15 09:30:00 UTC
+    const NOW: u64 = 1_768_469_400;
+
+    fn key_file(dir: &Path) -> PathBuf {
+        let path = dir.join("hybridguard.keys");
+        KeyManager::from_master_key(&[0x11; 32]).unwrap().save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_backups_are_named_by_time_and_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = key_file(dir.path());
+        let policy = BackupPolicy::new().with_keep(2);
+
+        let first = back_up(&path, &policy, NOW).unwrap().unwrap();
+        assert_eq!(first.name(), "hybridguard.keys.bak-20260115093000");
+        assert!(!first.protected);
+        let second = back_up(&path, &policy, NOW).unwrap().unwrap();
+        assert_eq!(second.name(), "hybridguard.keys.bak-20260115093000-2");
+        let third = back_up(&path, &policy, NOW + 60).unwrap().unwrap();
+
+        assert_eq!(list(&path, &policy).unwrap(), vec![second, third.clone()]);
+        assert!(!first.path.exists());
+        assert_eq!(find(&path, &policy, "latest").unwrap(), third);
+        assert_eq!(read(&third).unwrap(), fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn test_backup_dir_and_missing_key_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = BackupPolicy::new().with_dir(dir.path().join("backups"));
+        assert_eq!(back_up(&dir.path().join("absent.keys"), &policy, NOW).unwrap(), None);
+
+        let path = key_file(dir.path());
+        let backup = back_up(&path, &policy, NOW).unwrap().unwrap();
+        assert_eq!(backup.path.parent(), Some(dir.path().join("backups").as_path()));
+        assert!(list(&path, &BackupPolicy::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_damaged_key_files_are_not_backed_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hybridguard.keys");
+        fs::write(&path, b"{\"key_id\": ").unwrap();
+        assert!(back_up(&path, &BackupPolicy::new(), NOW).is_err());
+        assert!(list(&path, &BackupPolicy::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_upgrades_version_1_behind_a_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = key_file(dir.path());
+        let km = KeyManager::load(&path).unwrap();
+        let mut file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let v1 = serde_json::to_vec(&file["body"].take()).unwrap();
+        fs::write(&path, &v1).unwrap();
+        assert_eq!(KeyManager::load(&path).unwrap().format_version(), 1);
+
+        let backup = migrate(&path, &BackupPolicy::new(), NOW).unwrap().unwrap();
+        assert_eq!(fs::read(&backup.path).unwrap(), v1);
+        let upgraded = KeyManager::load(&path).unwrap();
+        assert_eq!(upgraded.format_version(), KEY_FILE_VERSION);
+        assert_eq!(upgraded.key_id(), km.key_id());
+        assert_eq!(upgraded.get_keys().unwrap().layer3_key, km.get_keys().unwrap().layer3_key);
+        assert_eq!(upgraded.instance_id(), km.instance_id());
+
+        // Already current: nothing to do
+        assert_eq!(migrate(&path, &BackupPolicy::new(), NOW + 1).unwrap(), None);
+        assert_eq!(list(&path, &BackupPolicy::new()).unwrap(), vec![backup]);
+    }
+
+    #[test]
+    fn test_backup_names() {
+        assert_eq!(parse_suffix("20260115093000"), Some(("20260115093000".to_string(), 1)));
+        assert_eq!(parse_suffix("20260115093000-3"), Some(("20260115093000".to_string(), 3)));
+        assert_eq!(parse_suffix("20260115093000-1"), None);
+        assert_eq!(parse_suffix("2026011509300"), None);
+        assert_eq!(parse_suffix("20260115093000.tmp"), None);
+    }
+}
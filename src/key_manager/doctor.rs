@@ -0,0 +1,351 @@
+// Diagnosis of damaged key files
+// A key file that no longer loads, e.g. after a bad sync merge dropped a
+// field, is examined field by field so its holder learns what is missing and
+// what still works. Damaged keys are only reported, never replaced with
+// stand-ins, so nothing is ever decrypted with guessed key material.
+
+use crate::crypto::hkdf::{KdfScheme, LayerKeys, LAYER_KEY_LEN};
+use crate::crypto::secret::SecretBytes;
+use crate::error::Result;
+use crate::key_manager::escrow::EscrowRecord;
+use crate::key_manager::protector::{self, KeyFileProtector};
+use crate::key_manager::provenance::{self, KeyOwner, SignatureStatus};
+use crate::key_manager::signing::StoredSigningKey;
+use crate::key_manager::{Capability, KeyId, KeyManager, KEY_FILE_FORMAT, KEY_FILE_VERSION};
+use crate::streaming::limits::DataLimits;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Names of the four layer key fields, in pipeline order
+const LAYER_FIELDS: [&str; 4] = ["layer1_key", "layer2_key", "layer3_key", "layer4_key"];
+
+/// Condition of one key file field
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum FieldStatus {
+    Ok,
+    /// Optional field not present; its default applies
+    Absent,
+    Missing,
+    WrongLength { expected: usize, found: usize },
+    Invalid { reason: String },
+}
+
+impl FieldStatus {
+    pub fn is_damaged(&self) -> bool {
+        !matches!(self, FieldStatus::Ok | FieldStatus::Absent)
+    }
+}
+
+impl fmt::Display for FieldStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldStatus::Ok => f.write_str("ok"),
+            FieldStatus::Absent => f.write_str("absent (default applies)"),
+            FieldStatus::Missing => f.write_str("missing"),
+            FieldStatus::WrongLength { expected, found } => {
+                write!(f, "wrong length: {} bytes, expected {}", found, expected)
+            }
+            FieldStatus::Invalid { reason } => write!(f, "invalid: {}", reason),
+        }
+    }
+}
+
+/// One field of the key file and its condition
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldReport {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub status: FieldStatus,
+}
+
+/// Whether the recorded key ID still names the layer keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum KeyIdStatus {
+    /// Derived from the layer keys, and matches them
+    Intact,
+    /// Written before IDs were derived, so there is nothing to check it against
+    Legacy,
+    /// Names other keys than the ones in the file; `computed` is theirs
+    Mismatch { computed: String },
+    /// Missing or not a string; `computed` restores it if the file had a derived ID
+    Missing { computed: Option<String> },
+    /// A layer key is damaged, so the ID cannot be recomputed
+    Unverifiable,
+}
+
+/// What the surviving layer keys can still decrypt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum PartialDecryption {
+    /// All four layer keys are intact; containers decrypt once the file loads
+    AllLayers,
+    /// Every container runs all four layers, and its tag and wrapped file key
+    /// are keyed by all four, so none decrypts; these layers' keys still open
+    /// data sealed by that layer alone (`EncryptionLayer::decrypt`)
+    StandaloneLayers { layers: Vec<u8> },
+    /// No layer key survived
+    Impossible,
+}
+
+/// Field-by-field account of a key file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyFileDiagnosis {
+    /// Why the file could not be examined at all, e.g. it is not JSON
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unreadable: Option<String>,
+    pub fields: Vec<FieldReport>,
+    pub key_id: KeyIdStatus,
+    /// Layers (1-4) whose keys are missing or damaged
+    pub damaged_layers: Vec<u8>,
+    pub partial_decryption: PartialDecryption,
+    /// A missing or invalid signature is reported but is not damage: the
+    /// keys still load, with a warning
+    pub self_signature: SignatureStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<KeyOwner>,
+}
+
+impl KeyFileDiagnosis {
+    /// Nothing is damaged; the file loads
+    pub fn is_healthy(&self) -> bool {
+        self.unreadable.is_none() && self.damaged_fields().next().is_none()
+    }
+
+    pub fn damaged_fields(&self) -> impl Iterator<Item = &FieldReport> {
+        self.fields.iter().filter(|field| field.status.is_damaged())
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FieldStatus> {
+        self.fields.iter().find(|field| field.name == name).map(|field| &field.status)
+    }
+
+    /// One line naming what is wrong, for error messages
+    pub fn summary(&self) -> String {
+        if let Some(reason) = &self.unreadable {
+            return reason.clone();
+        }
+        let damaged: Vec<String> = self.damaged_fields().map(|field| format!("{} {}", field.name, field.status)).collect();
+        if damaged.is_empty() {
+            return "no damage found".to_string();
+        }
+        format!("{}; run `hybridguard key doctor` for details", damaged.join(", "))
+    }
+}
+
+impl fmt::Display for KeyFileDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(reason) = &self.unreadable {
+            return write!(f, "Key file is unreadable: {}", reason);
+        }
+        writeln!(f, "Fields")?;
+        for field in &self.fields {
+            writeln!(f, "  {:<13} {}", field.name, field.status)?;
+        }
+        let key_id = match &self.key_id {
+            KeyIdStatus::Intact => "intact".to_string(),
+            KeyIdStatus::Legacy => "written before IDs were derived; not checked".to_string(),
+            KeyIdStatus::Mismatch { computed } => format!("does not match the layer keys, which give {}", computed),
+            KeyIdStatus::Missing { computed: Some(computed) } => format!("missing; the layer keys give {}", computed),
+            KeyIdStatus::Missing { computed: None } => "missing and cannot be recomputed".to_string(),
+            KeyIdStatus::Unverifiable => "cannot be checked while a layer key is damaged".to_string(),
+        };
+        writeln!(f, "Key ID:      {}", key_id)?;
+        let decryption = match &self.partial_decryption {
+            PartialDecryption::AllLayers => "all four layer keys are intact".to_string(),
+            PartialDecryption::StandaloneLayers { layers } => format!(
+                "no container can be decrypted (each needs all four layer keys); the keys of layer(s) {} still open data sealed by that layer alone",
+                layer_list(layers)
+            ),
+            PartialDecryption::Impossible => "impossible; no layer key survived".to_string(),
+        };
+        writeln!(f, "Decryption:  {}", decryption)?;
+        if let Some(owner) = &self.owner {
+            writeln!(f, "Owner:       {}", owner)?;
+        }
+        write!(f, "Signature:   {}", self.self_signature)
+    }
+}
+
+/// Examine the contents of a plain key file
+pub fn diagnose(data: &[u8]) -> KeyFileDiagnosis {
+    if let Some(kind) = protector::protection(data) {
+        return unreadable(format!("sealed by the {} protector; pass --protector", kind));
+    }
+    let object = match serde_json::from_slice::<Value>(data) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return unreadable("not a JSON object".to_string()),
+        Err(e) => return unreadable(format!("not JSON: {}", e)),
+    };
+    let object = match body(object) {
+        Ok(object) => object,
+        Err(reason) => return unreadable(reason),
+    };
+
+    let mut fields = vec![FieldReport { name: "key_id", status: typed::<String>(&object, "key_id", true) }];
+    let mut layer_keys = Vec::new();
+    for name in LAYER_FIELDS {
+        let (status, key) = layer_key(&object, name);
+        fields.push(FieldReport { name, status });
+        layer_keys.push(key);
+    }
+    fields.push(FieldReport { name: "created_at", status: typed::<String>(&object, "created_at", true) });
+    fields.push(FieldReport { name: "instance_id", status: typed::<String>(&object, "instance_id", false) });
+    fields.push(FieldReport { name: "kdf", status: typed::<KdfScheme>(&object, "kdf", false) });
+    fields.push(FieldReport { name: "escrow", status: typed::<EscrowRecord>(&object, "escrow", false) });
+    fields.push(FieldReport { name: "capabilities", status: typed::<Vec<Capability>>(&object, "capabilities", false) });
+    fields.push(FieldReport { name: "data_limits", status: typed::<DataLimits>(&object, "data_limits", false) });
+    fields.push(FieldReport { name: "owner", status: typed::<KeyOwner>(&object, "owner", false) });
+    fields.push(FieldReport { name: "signing_key", status: typed::<StoredSigningKey>(&object, "signing_key", false) });
+    let owner = object.get("owner").and_then(|owner| KeyOwner::deserialize(owner).ok());
+
+    let damaged_layers: Vec<u8> = (1..=4u8).filter(|n| layer_keys[*n as usize - 1].is_none()).collect();
+    let intact: Vec<u8> = (1..=4u8).filter(|n| !damaged_layers.contains(n)).collect();
+    let partial_decryption = match (damaged_layers.is_empty(), intact.is_empty()) {
+        (true, _) => PartialDecryption::AllLayers,
+        (false, true) => PartialDecryption::Impossible,
+        (false, false) => PartialDecryption::StandaloneLayers { layers: intact },
+    };
+
+    let computed = match layer_keys.as_slice() {
+        [Some(k1), Some(k2), Some(k3), Some(k4)] => Some(KeyManager::material_key_id(&LayerKeys {
+            layer1_key: k1.clone(),
+            layer2_key: k2.clone(),
+            layer3_key: k3.clone(),
+            layer4_key: k4.clone(),
+            scheme: KdfScheme::default(),
+        })),
+        _ => None,
+    };
+    let key_id = match (object.get("key_id").and_then(Value::as_str), computed) {
+        (None, computed) => KeyIdStatus::Missing { computed },
+        (Some(_), None) => KeyIdStatus::Unverifiable,
+        (Some(stored), Some(computed)) if stored == computed => KeyIdStatus::Intact,
+        (Some(stored), Some(_)) if KeyId::parse(stored).is_err() => KeyIdStatus::Legacy,
+        (Some(_), Some(computed)) => KeyIdStatus::Mismatch { computed },
+    };
+
+    KeyFileDiagnosis {
+        unreadable: None,
+        fields,
+        key_id,
+        damaged_layers,
+        partial_decryption,
+        self_signature: provenance::check(data),
+        owner,
+    }
+}
+
+/// Unseal a protected key file with `protector`, then examine it
+/// Fails if the file cannot be unsealed; the seal, not the keys, is then at fault
+pub fn diagnose_protected(data: &[u8], protector: &dyn KeyFileProtector) -> Result<KeyFileDiagnosis> {
+    Ok(diagnose(&protector::open(data, protector)?))
+}
+
+/// The object holding a key file's fields: the file itself for version 1,
+/// the envelope's body for version 2
+fn body(mut object: Map<String, Value>) -> std::result::Result<Map<String, Value>, String> {
+    let Some(format) = object.get("format") else {
+        return Ok(object);
+    };
+    if format.as_str() != Some(KEY_FILE_FORMAT) {
+        return Err(format!("format is {}, not a key file", format));
+    }
+    match object.get("version").and_then(Value::as_u64) {
+        Some(version) if version == u64::from(KEY_FILE_VERSION) => {}
+        Some(version) if version > u64::from(KEY_FILE_VERSION) => {
+            return Err(format!("key file version {} is newer than this build reads", version));
+        }
+        Some(version) => return Err(format!("envelope names version {}, which has no envelope", version)),
+        None => return Err("envelope version is missing or not a number".to_string()),
+    }
+    match object.remove("body") {
+        Some(Value::Object(body)) => Ok(body),
+        _ => Err("envelope body is missing or not an object".to_string()),
+    }
+}
+
+fn unreadable(reason: String) -> KeyFileDiagnosis {
+    KeyFileDiagnosis {
+        unreadable: Some(reason),
+        fields: Vec::new(),
+        key_id: KeyIdStatus::Missing { computed: None },
+        damaged_layers: vec![1, 2, 3, 4],
+        partial_decryption: PartialDecryption::Impossible,
+        self_signature: SignatureStatus::Missing,
+        owner: None,
+    }
+}
+
+/// Status of a field that must deserialize as `T`
+fn typed<T: DeserializeOwned>(object: &Map<String, Value>, name: &str, required: bool) -> FieldStatus {
+    match object.get(name) {
+        None | Some(Value::Null) if required => FieldStatus::Missing,
+        None | Some(Value::Null) => FieldStatus::Absent,
+        Some(value) => match T::deserialize(value) {
+            Ok(_) => FieldStatus::Ok,
+            Err(e) => FieldStatus::Invalid { reason: e.to_string() },
+        },
+    }
+}
+
+/// Status of a layer key field, and the key if it is intact
+fn layer_key(object: &Map<String, Value>, name: &str) -> (FieldStatus, Option<SecretBytes>) {
+    let bytes = match object.get(name) {
+        None | Some(Value::Null) => return (FieldStatus::Missing, None),
+        Some(value) => match Vec::<u8>::deserialize(value) {
+            Ok(bytes) => SecretBytes::new(bytes),
+            Err(_) => {
+                let reason = "not an array of bytes".to_string();
+                return (FieldStatus::Invalid { reason }, None);
+            }
+        },
+    };
+    if bytes.len() != LAYER_KEY_LEN {
+        return (FieldStatus::WrongLength { expected: LAYER_KEY_LEN, found: bytes.len() }, None);
+    }
+    (FieldStatus::Ok, Some(bytes))
+}
+
+fn layer_list(layers: &[u8]) -> String {
+    layers.iter().map(u8::to_string).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_healthy_file() {
+        let km = KeyManager::from_master_key(&[0x3C; 32]).unwrap();
+        let diagnosis = diagnose(&km.to_bytes().unwrap());
+        assert!(diagnosis.is_healthy(), "{}", diagnosis);
+        assert_eq!(diagnosis.key_id, KeyIdStatus::Intact);
+        assert_eq!(diagnosis.partial_decryption, PartialDecryption::AllLayers);
+        assert_eq!(diagnosis.field("escrow"), Some(&FieldStatus::Absent));
+        assert!(diagnosis.self_signature.is_valid(), "{}", diagnosis);
+    }
+
+    #[test]
+    fn test_unreadable_file() {
+        let diagnosis = diagnose(b"{\"key_id\": \"hg-");
+        assert!(!diagnosis.is_healthy());
+        assert!(diagnosis.unreadable.as_deref().unwrap().starts_with("not JSON"));
+        assert_eq!(diagnosis.partial_decryption, PartialDecryption::Impossible);
+        assert_eq!(diagnose(b"[1, 2]").unreadable.as_deref(), Some("not a JSON object"));
+    }
+
+    #[test]
+    fn test_missing_key_id_is_recomputed() {
+        let km = KeyManager::from_master_key(&[0x3C; 32]).unwrap();
+        let mut file: Value = serde_json::from_slice(&km.to_bytes().unwrap()).unwrap();
+        file["body"].as_object_mut().unwrap().remove("key_id");
+        let diagnosis = diagnose(file.to_string().as_bytes());
+        assert_eq!(diagnosis.field("key_id"), Some(&FieldStatus::Missing));
+        assert_eq!(diagnosis.key_id, KeyIdStatus::Missing { computed: Some(km.key_id().to_string()) });
+        assert_eq!(diagnosis.partial_decryption, PartialDecryption::AllLayers);
+    }
+}
@@ -0,0 +1,383 @@
+// Key escrow to an organizational recovery key
+//
+// The organization holds an ML-KEM-768 recovery keypair. Escrowing a key
+// file encapsulates a fresh secret to the recovery public key and seals the
+// serialized key file with AES-256-GCM under a key derived from that secret,
+// the employee's key ID and the recovery key ID. Only the recovery private
+// key opens the blob; nothing in it depends on the employee's password.
+// Format v2 derives the sealing key with HKDF; v1 blobs still open.
+//
+// Blob layout: magic "HGES", u16 format version, bincode `EscrowBlob`.
+// Recovery key files use "HGRP" (public) and "HGRK" (private) the same way.
+
+use crate::crypto::codec;
+use crate::crypto::container::{le_u16, u16_at};
+use crate::crypto::hkdf::{self, KeyPurpose};
+use crate::crypto::nonce;
+use crate::crypto::secret::SecretBytes;
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::KeyManager;
+use crate::layers::oqs_support;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use oqs::kem::{Algorithm, Kem};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+/// Magic bytes of an escrow blob
+const BLOB_MAGIC: [u8; 4] = *b"HGES";
+
+/// Magic bytes of a recovery public key file
+const PUBLIC_MAGIC: [u8; 4] = *b"HGRP";
+
+/// Magic bytes of a recovery private key file
+const PRIVATE_MAGIC: [u8; 4] = *b"HGRK";
+
+/// Format of blobs and recovery key files written by this build
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Oldest format this build still reads
+const MIN_FORMAT_VERSION: u16 = 1;
+
+/// Largest blob or recovery key file accepted
+const MAX_LEN: usize = 64 * 1024;
+
+/// Bytes of the public key digest kept in a recovery key ID
+const RECOVERY_ID_BYTES: usize = 16;
+
+/// Domain label of the v1 sealing key
+const V1_SEAL_LABEL: &[u8] = b"HybridGuard-escrow-seal-key";
+
+/// Escrow details recorded in the employee's key file, so the holder can see it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EscrowRecord {
+    /// Recovery key the keys were escrowed to
+    pub recovery_key_id: String,
+    pub created_at: String,
+}
+
+/// Public half of an organization's recovery keypair, handed to employees
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryPublicKey {
+    key_id: String,
+    public_key: Vec<u8>,
+}
+
+/// Private half of a recovery keypair, kept by the security team
+pub struct RecoveryKey {
+    public: RecoveryPublicKey,
+    secret_key: SecretBytes,
+}
+
+/// Serializable form of `RecoveryKey`
+#[derive(Serialize, Deserialize)]
+struct StoredRecoveryKey {
+    public: RecoveryPublicKey,
+    secret_key: Vec<u8>,
+}
+
+/// A key file sealed to a recovery key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EscrowBlob {
+    /// Key ID of the escrowed key file
+    pub key_id: String,
+    /// Recovery key that can open this blob
+    pub recovery_key_id: String,
+    pub kem_ciphertext: Vec<u8>,
+    /// AES-256-GCM ciphertext and tag of the serialized key file
+    pub sealed: Vec<u8>,
+    /// Format the blob was written in; selects the sealing key derivation
+    #[serde(skip)]
+    version: u16,
+}
+
+impl RecoveryPublicKey {
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(PUBLIC_MAGIC, FORMAT_VERSION, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let public: Self = decode(PUBLIC_MAGIC, "recovery public key", bytes)?;
+        if public.key_id != recovery_key_id(&public.public_key) {
+            return Err(HybridGuardError::Integrity("recovery public key does not match its key ID".to_string()));
+        }
+        Ok(public)
+    }
+}
+
+impl RecoveryKey {
+    /// Generate a new recovery keypair
+    pub fn generate() -> Result<Self> {
+        let (public_key, secret_key) = kem()?
+            .keypair()
+            .map_err(|e| HybridGuardError::KeyGeneration(format!("Failed to generate keypair: {}", e)))?;
+        let public_key = public_key.into_vec();
+        Ok(Self {
+            public: RecoveryPublicKey {
+                key_id: recovery_key_id(&public_key),
+                public_key,
+            },
+            secret_key: SecretBytes::new(secret_key.into_vec()),
+        })
+    }
+
+    pub fn public(&self) -> &RecoveryPublicKey {
+        &self.public
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(
+            PRIVATE_MAGIC,
+            FORMAT_VERSION,
+            &StoredRecoveryKey {
+                public: self.public.clone(),
+                secret_key: self.secret_key.to_vec(),
+            },
+        )
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let stored: StoredRecoveryKey = decode(PRIVATE_MAGIC, "recovery private key", bytes)?;
+        Ok(Self {
+            public: stored.public,
+            secret_key: SecretBytes::new(stored.secret_key),
+        })
+    }
+}
+
+impl EscrowBlob {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(BLOB_MAGIC, self.version, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (version, mut blob): (u16, Self) = decode_versioned(BLOB_MAGIC, "escrow blob", bytes)?;
+        blob.version = version;
+        Ok(blob)
+    }
+}
+
+/// Record an escrow to `recovery` in `key_manager` and seal the result to it
+/// The returned key manager carries the record and is the one to save
+pub fn create(key_manager: KeyManager, recovery: &RecoveryPublicKey) -> Result<(KeyManager, EscrowBlob)> {
+    let key_manager = key_manager.with_escrow(EscrowRecord {
+        recovery_key_id: recovery.key_id.clone(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    });
+
+    let kem = kem()?;
+    let public_key = kem
+        .public_key_from_bytes(&recovery.public_key)
+        .ok_or_else(|| HybridGuardError::KeyGeneration("recovery public key is malformed".to_string()))?;
+    let (ciphertext, shared_secret) = kem
+        .encapsulate(public_key)
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("encapsulation failed: {}", e)))?;
+    let shared_secret = SecretBytes::new(shared_secret.into_vec());
+
+    let mut blob = EscrowBlob {
+        key_id: key_manager.key_id().to_string(),
+        recovery_key_id: recovery.key_id.clone(),
+        kem_ciphertext: ciphertext.into_vec(),
+        sealed: Vec::new(),
+        version: FORMAT_VERSION,
+    };
+    let key_file = SecretBytes::new(key_manager.to_bytes()?);
+    let aad = associated_data(&blob)?;
+    // The zero nonce is safe only while every blob encapsulates afresh
+    nonce::checkout(&blob.kem_ciphertext, "escrow-seal", &[0u8; 12])?;
+    blob.sealed = cipher(blob.version, &shared_secret, &aad)?
+        .encrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: &key_file, aad: &aad })
+        .map_err(|_| HybridGuardError::KeyGeneration("sealing the escrow blob failed".to_string()))?;
+    Ok((key_manager, blob))
+}
+
+/// Rebuild the escrowed keys with the organization's recovery private key
+pub fn recover(blob: &EscrowBlob, recovery: &RecoveryKey) -> Result<KeyManager> {
+    if blob.recovery_key_id != recovery.public.key_id {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "escrow blob was sealed to recovery key {} but {} is loaded",
+            blob.recovery_key_id, recovery.public.key_id
+        )));
+    }
+
+    let kem = kem()?;
+    let secret_key = kem
+        .secret_key_from_bytes(&recovery.secret_key)
+        .ok_or_else(|| HybridGuardError::KeyGeneration("recovery private key is malformed".to_string()))?;
+    let ciphertext = kem
+        .ciphertext_from_bytes(&blob.kem_ciphertext)
+        .ok_or_else(|| HybridGuardError::Integrity("escrow blob carries a malformed ML-KEM ciphertext".to_string()))?;
+    let shared_secret = kem
+        .decapsulate(secret_key, ciphertext)
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("decapsulation failed: {}", e)))?;
+    let shared_secret = SecretBytes::new(shared_secret.into_vec());
+
+    let aad = associated_data(blob)?;
+    let key_file = cipher(blob.version, &shared_secret, &aad)?
+        .decrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: &blob.sealed, aad: &aad })
+        .map_err(|_| HybridGuardError::Integrity("escrow blob failed authentication (modified or damaged)".to_string()))?;
+    let key_file = SecretBytes::new(key_file);
+
+    let recovered = KeyManager::from_bytes(&key_file)?;
+    if recovered.key_id() != blob.key_id {
+        return Err(HybridGuardError::Integrity(format!(
+            "escrow blob names key {} but holds key {}",
+            blob.key_id,
+            recovered.key_id()
+        )));
+    }
+    Ok(recovered)
+}
+
+fn kem() -> Result<Kem> {
+    oqs_support::kem("key escrow", Algorithm::Kyber768)
+}
+
+/// Recovery key ID: a truncated SHA3-256 of the public key
+fn recovery_key_id(public_key: &[u8]) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"HybridGuard-RecoveryKeyID");
+    hasher.update(public_key);
+    let digest = hasher.finalize();
+    format!("hgr-{}", codec::hex_lower(&digest[..RECOVERY_ID_BYTES]))
+}
+
+/// Everything in the blob except the sealed key file, bound into the AEAD
+fn associated_data(blob: &EscrowBlob) -> Result<Vec<u8>> {
+    let mut aad = BLOB_MAGIC.to_vec();
+    aad.extend_from_slice(&le_u16(blob.version));
+    aad.extend(
+        bincode::serialize(&(&blob.key_id, &blob.recovery_key_id, &blob.kem_ciphertext))
+            .map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?,
+    );
+    Ok(aad)
+}
+
+/// AES-256-GCM keyed by the KEM secret and the blob's identity
+/// Every blob has a fresh KEM secret, so the fixed nonce is never reused under one key
+fn cipher(version: u16, shared_secret: &[u8], aad: &[u8]) -> Result<Aes256Gcm> {
+    let key = if version >= 2 {
+        hkdf::derive(aad, shared_secret, KeyPurpose::EscrowSeal, 32)?
+    } else {
+        let mut hasher = Sha3_256::new();
+        hasher.update(V1_SEAL_LABEL);
+        hasher.update(shared_secret);
+        hasher.update(aad);
+        SecretBytes::new(hasher.finalize().to_vec())
+    };
+    Aes256Gcm::new_from_slice(&key).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))
+}
+
+fn encode<T: Serialize>(magic: [u8; 4], version: u16, message: &T) -> Result<Vec<u8>> {
+    let mut bytes = magic.to_vec();
+    bytes.extend_from_slice(&le_u16(version));
+    bytes.extend(bincode::serialize(message).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?);
+    Ok(bytes)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(magic: [u8; 4], what: &str, bytes: &[u8]) -> Result<T> {
+    decode_versioned(magic, what, bytes).map(|(_, message)| message)
+}
+
+fn decode_versioned<T: serde::de::DeserializeOwned>(magic: [u8; 4], what: &str, bytes: &[u8]) -> Result<(u16, T)> {
+    if bytes.len() > MAX_LEN || !bytes.starts_with(&magic) || bytes.len() < 6 {
+        return Err(HybridGuardError::UnsupportedFormat(format!("not a HybridGuard {}", what)));
+    }
+    let version = u16_at(bytes, 4);
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "{} v{} is not supported (this build reads v{}-v{})",
+            what, version, MIN_FORMAT_VERSION, FORMAT_VERSION
+        )));
+    }
+    let message = bincode::deserialize(&bytes[6..])
+        .map_err(|e| HybridGuardError::Integrity(format!("unreadable {}: {}", what, e)))?;
+    Ok((version, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn employee() -> KeyManager {
+        KeyManager::from_master_key(&[0xE5; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_recover_round_trip() {
+        let org = RecoveryKey::generate().unwrap();
+        let public = RecoveryPublicKey::from_bytes(&org.public().to_bytes().unwrap()).unwrap();
+        let (escrowed, blob) = create(employee(), &public).unwrap();
+        assert_eq!(escrowed.escrow().unwrap().recovery_key_id, org.public().key_id());
+
+        let org = RecoveryKey::from_bytes(&org.to_bytes().unwrap()).unwrap();
+        let recovered = recover(&EscrowBlob::from_bytes(&blob.to_bytes().unwrap()).unwrap(), &org).unwrap();
+        assert_eq!(recovered.key_id(), escrowed.key_id());
+        assert_eq!(recovered.get_keys().unwrap().layer4_key, escrowed.get_keys().unwrap().layer4_key);
+        assert_eq!(recovered.escrow(), escrowed.escrow());
+
+        // The layer keys never appear in the blob in the clear
+        let bytes = blob.to_bytes().unwrap();
+        let layer1 = escrowed.get_keys().unwrap().layer1_key.to_vec();
+        assert!(!bytes.windows(layer1.len()).any(|w| w == layer1.as_slice()));
+    }
+
+    #[test]
+    fn test_v1_blob_still_opens() {
+        // Seal a blob the way format v1 did and read it back through the v2 parser
+        let org = RecoveryKey::generate().unwrap();
+        let (_, mut blob) = create(employee(), org.public()).unwrap();
+        blob.version = 1;
+        let kem = kem().unwrap();
+        let public_key = kem.public_key_from_bytes(&org.public().public_key).unwrap();
+        let (ciphertext, shared_secret) = kem.encapsulate(public_key).unwrap();
+        blob.kem_ciphertext = ciphertext.into_vec();
+        let aad = associated_data(&blob).unwrap();
+        let key_file = employee().to_bytes().unwrap();
+        blob.sealed = cipher(1, &shared_secret.into_vec(), &aad)
+            .unwrap()
+            .encrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: &key_file, aad: &aad })
+            .unwrap();
+
+        let bytes = blob.to_bytes().unwrap();
+        assert_eq!(&bytes[4..6], &le_u16(1));
+        let recovered = recover(&EscrowBlob::from_bytes(&bytes).unwrap(), &org).unwrap();
+        assert_eq!(recovered.key_id(), employee().key_id());
+
+        // The same secret under the v2 derivation does not open it
+        blob.version = FORMAT_VERSION;
+        assert!(matches!(recover(&blob, &org), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_other_recovery_key_rejected() {
+        let org = RecoveryKey::generate().unwrap();
+        let (_, blob) = create(employee(), org.public()).unwrap();
+        let other = RecoveryKey::generate().unwrap();
+        assert!(matches!(recover(&blob, &other), Err(HybridGuardError::KeyMismatch(_))));
+    }
+
+    #[test]
+    fn test_tampered_blob_rejected() {
+        let org = RecoveryKey::generate().unwrap();
+        let (_, blob) = create(employee(), org.public()).unwrap();
+
+        let mut sealed = blob.clone();
+        sealed.sealed[10] ^= 1;
+        assert!(matches!(recover(&sealed, &org), Err(HybridGuardError::Integrity(_))));
+
+        // Relabelling the blob for another employee breaks the binding
+        let mut relabelled = blob.clone();
+        relabelled.key_id = "hg-00000000000000000000000000000000".to_string();
+        assert!(matches!(recover(&relabelled, &org), Err(HybridGuardError::Integrity(_))));
+
+        let mut public = org.public().to_bytes().unwrap();
+        let last = public.len() - 1;
+        public[last] ^= 1;
+        assert!(RecoveryPublicKey::from_bytes(&public).is_err());
+    }
+}
@@ -0,0 +1,462 @@
+// Cross-process locking of a keystore
+// Everything that changes a keystore directory (key file saves, backups and
+// their pruning) and the usage counters take an advisory lock on a lock file
+// first: flock on Unix, LockFileEx on Windows. Files are replaced by rename,
+// so readers never take the lock and never see half a write. The OS drops an
+// advisory lock with the process holding it, so a crash leaves the lock file
+// behind but not the lock, and the next writer simply takes it.
+//
+// The lock file records its holder: PID, host, boot ID and when it was taken.
+// A lock still held after the wait by a holder on this host that is gone,
+// from an earlier boot or under a PID no process has, is reported as stale;
+// a child that inherited the descriptor or a network share keeping the locks
+// of dead clients can leave one. `--break-stale-lock` removes such a file and
+// locks a fresh one; a live holder is never broken. Breakers take turns on a
+// second lock file, BREAK_FILE_SUFFIX beside the first, and read the holder
+// again under it, so one that read the old holder cannot remove the fresh
+// lock another breaker has just taken. That file is never removed.
+
+use crate::error::{HybridGuardError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the lock file in a keystore directory
+pub const LOCK_FILE_NAME: &str = ".hybridguard.lock";
+
+/// Appended to the lock file's name for the lock breakers take turns on
+pub const BREAK_FILE_SUFFIX: &str = ".break";
+
+/// Longest wait for another holder unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause between attempts to take the lock
+const RETRY: Duration = Duration::from_millis(5);
+
+/// How long to wait for a lock, and what to do about a stale one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockOptions {
+    pub timeout: Duration,
+    /// Remove a lock file whose holder is gone instead of failing with `KeystoreBusy`
+    pub break_stale: bool,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        Self { timeout: DEFAULT_TIMEOUT, break_stale: false }
+    }
+}
+
+impl LockOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_break_stale(mut self, break_stale: bool) -> Self {
+        self.break_stale = break_stale;
+        self
+    }
+}
+
+/// Who holds a lock, as its lock file records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    /// A PID only means something on the host that issued it
+    pub host: String,
+    /// Linux boot ID; None where the platform has none
+    pub boot_id: Option<String>,
+    /// When the lock was taken, in seconds since the Unix epoch
+    pub acquired_at: u64,
+}
+
+impl LockHolder {
+    /// This process, now
+    fn current() -> Self {
+        let acquired_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        Self { pid: std::process::id(), host: host_name(), boot_id: boot_id(), acquired_at }
+    }
+
+    /// Whether the holder is certainly gone: it ran on this host, and either
+    /// in an earlier boot or under a PID no running process has
+    /// A holder on another host is never stale, since its PID cannot be checked
+    pub fn is_stale(&self) -> bool {
+        if self.host != host_name() {
+            return false;
+        }
+        match (&self.boot_id, boot_id()) {
+            (Some(theirs), Some(ours)) if *theirs != ours => true,
+            _ => !process_alive(self.pid),
+        }
+    }
+}
+
+/// An exclusive lock on a keystore, released on drop
+pub struct KeystoreLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl KeystoreLock {
+    /// Lock the keystore directory `dir`
+    pub fn keystore(dir: &Path, options: &LockOptions) -> Result<Self> {
+        Self::acquire(&dir.join(LOCK_FILE_NAME), options)
+    }
+
+    /// Lock the keystore holding `key_file`, i.e. the directory it is in
+    pub fn for_key_file(key_file: &Path, options: &LockOptions) -> Result<Self> {
+        match key_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => Self::keystore(dir, options),
+            _ => Self::keystore(Path::new("."), options),
+        }
+    }
+
+    /// Lock the lock file at `path`, waiting while another process or thread holds it
+    /// Fails with `KeystoreBusy` once `options.timeout` has passed
+    pub fn acquire(path: &Path, options: &LockOptions) -> Result<Self> {
+        let deadline = Instant::now() + options.timeout;
+        loop {
+            match open(path) {
+                Ok(file) if try_lock(&file)? => {
+                    // The previous holder removes the file before it lets go,
+                    // so the one just locked may no longer be the lock file
+                    if same_file(path, &file) {
+                        let mut lock = Self { file, path: path.to_path_buf() };
+                        lock.record()?;
+                        return Ok(lock);
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                // Windows refuses to open a file its holder is removing
+                Err(e) if cfg!(windows) && e.kind() == io::ErrorKind::PermissionDenied => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let holder = holder(path);
+            let stale = holder.as_ref().is_some_and(LockHolder::is_stale);
+            if stale && options.break_stale {
+                log::warn!("{}: breaking the stale lock of {}", path.display(), describe(holder.as_ref()));
+                break_stale(path)?;
+                continue;
+            }
+            if Instant::now() >= deadline {
+                return Err(busy(path, holder.as_ref(), stale, options.timeout));
+            }
+            thread::sleep(RETRY);
+        }
+    }
+
+    /// The lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write this process into the lock file, replacing the previous holder
+    fn record(&mut self) -> io::Result<()> {
+        let json = serde_json::to_vec(&LockHolder::current()).map_err(io::Error::other)?;
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        self.file.write_all(&json)
+    }
+}
+
+impl Drop for KeystoreLock {
+    /// The file is removed while still locked, so a waiter that opened it
+    /// finds it gone and opens afresh; closing it then releases the lock.
+    /// A file that replaced it after a broken lock is left alone
+    fn drop(&mut self) {
+        if same_file(&self.path, &self.file) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Remove the lock file at `path` if its holder is still stale
+/// The holder is read again under the break lock, since the file may have
+/// been broken and locked afresh since the caller read it
+fn break_stale(path: &Path) -> Result<()> {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(BREAK_FILE_SUFFIX);
+    let turn = open(Path::new(&name))?;
+    while !try_lock(&turn)? {
+        thread::sleep(RETRY);
+    }
+    if holder(path).as_ref().is_some_and(LockHolder::is_stale) {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    // Closing `turn` lets the next breaker in
+    Ok(())
+}
+
+/// The holder recorded in the lock file at `path`, if one is
+pub fn holder(path: &Path) -> Option<LockHolder> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+/// Open or create the lock file, keeping what a holder recorded in it
+fn open(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(super::permissions::PRIVATE_FILE_MODE);
+    }
+    options.open(path)
+}
+
+/// Take the lock on `file` if no one holds it
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: flock only uses the descriptor, which `file` keeps open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Take the lock on `file` if no one holds it
+/// The locked byte lies far past the end, so the holder record stays readable
+#[cfg(windows)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
+    use windows_sys::Win32::Storage::FileSystem::{LockFileEx, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY};
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    // SAFETY: the handle stays open for the call, and the OVERLAPPED only
+    // carries the offset of the locked byte
+    let locked = unsafe {
+        let mut overlapped: OVERLAPPED = std::mem::zeroed();
+        overlapped.Anonymous.Anonymous.Offset = u32::MAX - 1;
+        overlapped.Anonymous.Anonymous.OffsetHigh = u32::MAX;
+        LockFileEx(file.as_raw_handle() as _, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY, 0, 1, 0, &mut overlapped)
+    };
+    if locked != 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(code) if code as u32 == ERROR_LOCK_VIOLATION => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Whether `path` still names the file `file` has open
+#[cfg(unix)]
+fn same_file(path: &Path, file: &File) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(path), file.metadata()) {
+        (Ok(named), Ok(open)) => (named.dev(), named.ino()) == (open.dev(), open.ino()),
+        _ => false,
+    }
+}
+
+/// Whether `path` still names the file `file` has open
+#[cfg(windows)]
+fn same_file(path: &Path, file: &File) -> bool {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+
+    let identity = |file: &File| {
+        // SAFETY: the handle stays open for the call, which only fills `info`
+        let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+        let ok = unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } != 0;
+        ok.then_some((info.dwVolumeSerialNumber, info.nFileIndexHigh, info.nFileIndexLow))
+    };
+    match File::open(path) {
+        Ok(named) => identity(&named).is_some_and(|named| Some(named) == identity(file)),
+        Err(_) => false,
+    }
+}
+
+/// Whether a process `pid` is running on this host
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 sends nothing; it only checks that the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether a process `pid` is running on this host; unknown here, so assumed
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: gethostname writes at most `name.len()` bytes into `name`
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return String::new();
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// ID the kernel draws afresh at every boot (Linux only)
+fn boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id").ok().map(|id| id.trim().to_string())
+}
+
+fn describe(holder: Option<&LockHolder>) -> String {
+    match holder {
+        Some(holder) => {
+            let since = chrono::DateTime::from_timestamp(holder.acquired_at as i64, 0)
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_else(|| holder.acquired_at.to_string());
+            format!("PID {} on {} since {}", holder.pid, holder.host, since)
+        }
+        None => "another process".to_string(),
+    }
+}
+
+fn busy(path: &Path, holder: Option<&LockHolder>, stale: bool, timeout: Duration) -> HybridGuardError {
+    let hint = if stale {
+        "; that process is gone, rerun with --break-stale-lock to remove the lock"
+    } else {
+        "; retry once it finishes"
+    };
+    HybridGuardError::KeystoreBusy(format!(
+        "{} still held by {} after {:.1}s{}",
+        path.display(),
+        describe(holder),
+        timeout.as_secs_f64(),
+        hint
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutil::WriteOptions;
+    use crate::key_manager::permissions;
+
+    fn quick() -> LockOptions {
+        LockOptions::new().with_timeout(Duration::from_millis(50))
+    }
+
+    #[test]
+    fn test_threads_take_turns() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("counter");
+        permissions::write_private(&counter, b"0").unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (dir, counter) = (dir.path().to_path_buf(), counter.clone());
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let _lock = KeystoreLock::keystore(&dir, &LockOptions::new()).unwrap();
+                        let count: u32 = fs::read_to_string(&counter).unwrap().parse().unwrap();
+                        let next = (count + 1).to_string();
+                        permissions::replace_private_with(&counter, next.as_bytes(), &WriteOptions::bulk()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&counter).unwrap(), "200");
+        assert!(!dir.path().join(LOCK_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_busy_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = KeystoreLock::keystore(dir.path(), &LockOptions::new()).unwrap();
+        let recorded = holder(lock.path()).unwrap();
+        assert_eq!(recorded.pid, std::process::id());
+        assert!(!recorded.is_stale());
+
+        // A live holder is never broken
+        let result = KeystoreLock::keystore(dir.path(), &quick().with_break_stale(true));
+        match result {
+            Err(HybridGuardError::KeystoreBusy(detail)) => assert!(detail.contains(&format!("PID {}", std::process::id()))),
+            _ => panic!("expected KeystoreBusy"),
+        }
+        drop(lock);
+        assert!(KeystoreLock::keystore(dir.path(), &quick()).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_holder_is_broken_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let held = KeystoreLock::keystore(dir.path(), &LockOptions::new()).unwrap();
+        // As if the lock had passed to a child whose parent, the recorded holder, died
+        let dead = LockHolder { pid: i32::MAX as u32, ..LockHolder::current() };
+        assert!(dead.is_stale());
+        fs::write(held.path(), serde_json::to_vec(&dead).unwrap()).unwrap();
+
+        match KeystoreLock::keystore(dir.path(), &quick()) {
+            Err(HybridGuardError::KeystoreBusy(detail)) => assert!(detail.contains("--break-stale-lock")),
+            _ => panic!("expected KeystoreBusy"),
+        }
+        let broken = KeystoreLock::keystore(dir.path(), &quick().with_break_stale(true)).unwrap();
+        assert_eq!(holder(broken.path()).unwrap().pid, std::process::id());
+
+        // The old holder letting go leaves the new lock file in place
+        drop(held);
+        assert!(broken.path().exists());
+        drop(broken);
+        assert!(!dir.path().join(LOCK_FILE_NAME).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_late_breaker_leaves_a_fresh_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOCK_FILE_NAME);
+        let held = KeystoreLock::keystore(dir.path(), &LockOptions::new()).unwrap();
+        let dead = LockHolder { pid: i32::MAX as u32, ..LockHolder::current() };
+        fs::write(&path, serde_json::to_vec(&dead).unwrap()).unwrap();
+        let fresh = KeystoreLock::keystore(dir.path(), &quick().with_break_stale(true)).unwrap();
+
+        // A second breaker that read the dead holder before the first broke it
+        break_stale(&path).unwrap();
+        assert_eq!(holder(&path).unwrap().pid, std::process::id());
+        assert!(same_file(&path, &fresh.file));
+        drop((held, fresh));
+    }
+
+    #[test]
+    fn test_other_hosts_are_never_stale() {
+        let elsewhere = LockHolder { pid: i32::MAX as u32, host: "elsewhere.invalid".to_string(), ..LockHolder::current() };
+        assert!(!elsewhere.is_stale());
+        let rebooted = LockHolder { boot_id: Some("an earlier boot".to_string()), ..LockHolder::current() };
+        assert_eq!(rebooted.is_stale(), boot_id().is_some());
+    }
+}
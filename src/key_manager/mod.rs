@@ -0,0 +1,990 @@
+// Key management system for HybridGuard
+// Handles generation, storage, and rotation of encryption keys
+
+pub mod backup;
+pub mod doctor;
+pub mod escrow;
+pub mod lock;
+pub mod pairing;
+pub mod paper;
+pub mod permissions;
+pub mod protector;
+pub mod provenance;
+pub mod signing;
+pub mod strength;
+pub mod verification;
+
+use crate::crypto::codec;
+use crate::crypto::drbg::RandomSource;
+use crate::crypto::envelope::{self, EncapsulationKey, WrappedFileKey};
+use crate::crypto::hkdf::{KdfScheme, KeyDerivation, LayerKeys, LAYER_KEY_LEN};
+use crate::crypto::nonce;
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::EncryptedData;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::legacy;
+use crate::streaming::limits::DataLimits;
+use escrow::EscrowRecord;
+use permissions::LoosePermissions;
+use protector::KeyFileProtector;
+use provenance::{KeyOwner, SelfSignature, SignatureStatus};
+use signing::{DetachedSignature, SigningKey, StoredSigningKey};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fmt;
+use std::path::Path;
+use std::fs;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use verification::VerificationKey;
+
+/// Bytes of the SHA3 digest kept in a key ID
+const KEY_ID_BYTES: usize = 16;
+
+/// `format` named by the envelope of every key file since version 2
+pub const KEY_FILE_FORMAT: &str = "hybridguard-keys";
+
+/// Key file version this build writes, and the newest it reads
+/// Version 1 files hold their fields at the top level, without an envelope
+pub const KEY_FILE_VERSION: u16 = 2;
+
+/// Fields a version 1 key file holds at least one of
+const V1_FIELDS: [&str; 6] = ["key_id", "layer1_key", "layer2_key", "layer3_key", "layer4_key", "created_at"];
+
+/// A derived key ID: `hg-` followed by 32 lowercase hex digits
+/// Key files written before IDs were derived may hold other strings, which
+/// `KeyManager::key_id` still returns but `parse` rejects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyId([u8; KEY_ID_BYTES]);
+
+impl KeyId {
+    /// Prefix of every key ID
+    pub const PREFIX: &'static str = "hg-";
+    
+    /// Parse a key ID as printed, refusing anything malformed
+    pub fn parse(text: &str) -> Result<Self> {
+        let hex = text.strip_prefix(Self::PREFIX).ok_or_else(|| {
+            HybridGuardError::InvalidInput(format!("key ID '{}' does not start with '{}'", text, Self::PREFIX))
+        })?;
+        let bytes = codec::hex_lower_decode(hex)
+            .map_err(|e| HybridGuardError::InvalidInput(format!("key ID '{}': {}", text, e)))?;
+        let bytes = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            HybridGuardError::InvalidInput(format!("key ID '{}' has {} bytes, expected {}", text, bytes.len(), KEY_ID_BYTES))
+        })?;
+        Ok(Self(bytes))
+    }
+    
+    pub fn as_bytes(&self) -> &[u8; KEY_ID_BYTES] {
+        &self.0
+    }
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", Self::PREFIX, codec::hex_lower(&self.0))
+    }
+}
+
+impl FromStr for KeyId {
+    type Err = HybridGuardError;
+    
+    fn from_str(text: &str) -> Result<Self> {
+        Self::parse(text)
+    }
+}
+
+/// Operation a key file may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    Encrypt,
+    Decrypt,
+    /// Held alone, by verification keys, which carry no layer key
+    Verify,
+}
+
+/// Capabilities of key files written before they were recorded
+fn all_capabilities() -> Vec<Capability> {
+    vec![Capability::Encrypt, Capability::Decrypt]
+}
+
+/// Manages all encryption keys for HybridGuard
+pub struct KeyManager {
+    /// Master layer keys; None in encrypt-only key files, which hold
+    /// `encapsulation` instead
+    keys: Option<LayerKeys>,
+    /// Public key new file keys are encapsulated to, when there are no master keys
+    encapsulation: Option<EncapsulationKey>,
+    key_id: String,
+    instance_id: Option<String>,
+    created_at: String,
+    escrow: Option<EscrowRecord>,
+    capabilities: Vec<Capability>,
+    data_limits: Option<DataLimits>,
+    owner: Option<KeyOwner>,
+    /// Keypair of detached signatures, added the first time one is made
+    signing_key: Option<SigningKey>,
+    /// Self-signature of the key file these keys were loaded from
+    signature: Option<SignatureStatus>,
+    /// Key file version these keys were loaded from
+    format_version: u16,
+    /// Body fields this build does not know, written back unchanged
+    extensions: Map<String, Value>,
+}
+
+impl KeyManager {
+    /// Generate new keys from a master password
+    pub fn generate(password: &str) -> Result<Self> {
+        // Generate random salt
+        let salt = Self::generate_salt();
+        nonce::checkout(&[], "password-salt", &salt)?;
+        Self::from_password(password, &salt)
+    }
+    
+    /// Derive keys from a password and a known salt
+    /// The same inputs always give the same keys and key ID
+    pub fn from_password(password: &str, salt: &[u8]) -> Result<Self> {
+        let kd = KeyDerivation::from_password(password, salt);
+        let keys = kd.derive_all_keys()?;
+        Ok(Self::from_raw_keys(keys))
+    }
+    
+    /// Wrap externally derived layer keys, e.g. from an HSM
+    /// The key ID is derived from the key material, so re-importing the
+    /// same keys always yields the same ID
+    pub fn from_raw_keys(keys: LayerKeys) -> Self {
+        let key_id = Self::material_key_id(&keys);
+        Self {
+            keys: Some(keys),
+            encapsulation: None,
+            key_id,
+            instance_id: Some(Self::generate_instance_id()),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            escrow: None,
+            capabilities: all_capabilities(),
+            data_limits: None,
+            owner: None,
+            signing_key: None,
+            signature: None,
+            format_version: KEY_FILE_VERSION,
+            extensions: Map::new(),
+        }
+    }
+    
+    /// Derive all layer keys from an externally managed 32-byte master key
+    pub fn from_master_key(master_key: &[u8; 32]) -> Result<Self> {
+        Self::from_master_key_with(master_key, KdfScheme::CURRENT)
+    }
+    
+    /// Derive all layer keys from a master key under `scheme`, e.g. V1 to
+    /// rebuild a key file made before V2
+    pub fn from_master_key_with(master_key: &[u8; 32], scheme: KdfScheme) -> Result<Self> {
+        let keys = KeyDerivation::with_scheme(master_key.to_vec(), scheme).derive_all_keys()?;
+        Ok(Self::from_raw_keys(keys))
+    }
+    
+    /// Load keys from a file, warning if other users can read it
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with(path, LoosePermissions::Warn)
+    }
+    
+    /// Load keys from a file, handling loose permissions as `action` says
+    /// Warns when the file's self-signature is missing or invalid
+    pub fn load_with<P: AsRef<Path>>(path: P, action: LoosePermissions) -> Result<Self> {
+        let data = fs::read(path.as_ref())?;
+        permissions::check_private(path.as_ref(), action)?;
+        let key_manager = Self::from_bytes(&data)?;
+        provenance::warn_if_unsigned(path.as_ref(), &key_manager);
+        Ok(key_manager)
+    }
+    
+    /// Load a key file sealed by `protector`
+    pub fn load_protected<P: AsRef<Path>>(path: P, action: LoosePermissions, protector: &dyn KeyFileProtector) -> Result<Self> {
+        let data = fs::read(path.as_ref())?;
+        permissions::check_private(path.as_ref(), action)?;
+        let key_manager = Self::from_protected_bytes(&data, protector)?;
+        provenance::warn_if_unsigned(path.as_ref(), &key_manager);
+        Ok(key_manager)
+    }
+    
+    /// Parse the contents of a key file sealed by `protector`
+    pub fn from_protected_bytes(data: &[u8], protector: &dyn KeyFileProtector) -> Result<Self> {
+        Self::from_bytes(&protector::open(data, protector)?)
+    }
+    
+    /// Parse the contents of a key file, of version 1 or 2, and check its
+    /// self-signature; a missing or invalid one is recorded, not an error
+    /// Fails with `NotAKeyFile` for anything else, `UnsupportedFormat` for a
+    /// newer version, `CapabilityDenied` for a verification key, and
+    /// `KeyFileDamaged` with a diagnosis of every field for a key file that
+    /// does not parse or holds a layer key of the wrong length
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if let Some(kind) = protector::protection(data) {
+            return Err(HybridGuardError::KeyMismatch(format!(
+                "key file is protected by the {} protector; pass --protector",
+                kind
+            )));
+        }
+        let (format_version, body) = key_file_body(data)?;
+        if verification::is_verification_key(&body) {
+            return Err(HybridGuardError::CapabilityDenied(format!(
+                "key file is a verification key for {}: it checks integrity but can neither encrypt nor decrypt",
+                body.get("key_id").and_then(Value::as_str).unwrap_or("unknown keys")
+            )));
+        }
+        let damaged = || HybridGuardError::KeyFileDamaged(Box::new(doctor::diagnose(data)));
+        if body.contains_key("encapsulation_key") {
+            let stored: StoredEncryptOnlyKeys = serde_json::from_value(Value::Object(body)).map_err(|_| damaged())?;
+            legacy::require_kdf(stored.kdf)?;
+            if stored.capabilities != [Capability::Encrypt] {
+                return Err(damaged());
+            }
+            return Ok(Self {
+                keys: None,
+                encapsulation: Some(EncapsulationKey::from_parts(stored.encapsulation_key, stored.kdf).map_err(|_| damaged())?),
+                key_id: stored.key_id,
+                instance_id: stored.instance_id,
+                created_at: stored.created_at,
+                escrow: None,
+                capabilities: stored.capabilities,
+                data_limits: stored.data_limits,
+                owner: stored.owner,
+                signing_key: None,
+                // Nothing in the file can sign it, so there is nothing to check
+                signature: None,
+                format_version,
+                extensions: stored.extensions,
+            });
+        }
+        let stored: StoredKeys = serde_json::from_value(Value::Object(body)).map_err(|_| damaged())?;
+        legacy::require_kdf(stored.kdf)?;
+        let layer_keys = [&stored.layer1_key, &stored.layer2_key, &stored.layer3_key, &stored.layer4_key];
+        if layer_keys.iter().any(|key| key.len() != LAYER_KEY_LEN) {
+            return Err(damaged());
+        }
+        let signing_key = stored.signing_key.as_ref().map(SigningKey::from_stored).transpose().map_err(|_| damaged())?;
+        
+        Ok(Self {
+            keys: Some(LayerKeys {
+                layer1_key: SecretBytes::new(stored.layer1_key),
+                layer2_key: SecretBytes::new(stored.layer2_key),
+                layer3_key: SecretBytes::new(stored.layer3_key),
+                layer4_key: SecretBytes::new(stored.layer4_key),
+                scheme: stored.kdf,
+            }),
+            encapsulation: None,
+            // Files written before IDs were derived keep their random ID
+            key_id: stored.key_id,
+            instance_id: stored.instance_id,
+            created_at: stored.created_at,
+            escrow: stored.escrow,
+            capabilities: stored.capabilities,
+            data_limits: stored.data_limits,
+            owner: stored.owner,
+            signing_key,
+            signature: Some(provenance::check(data)),
+            format_version,
+            extensions: stored.extensions,
+        })
+    }
+    
+    /// Save keys to a file readable by its owner only
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.save_with(path, &WriteOptions::critical())
+    }
+    
+    /// `save`, finishing the file as `options` ask instead of fsyncing it
+    pub fn save_with<P: AsRef<Path>>(&self, path: P, options: &WriteOptions) -> Result<()> {
+        permissions::replace_private_with(path.as_ref(), &self.to_bytes()?, options)?;
+        
+        Ok(())
+    }
+    
+    /// Save keys sealed by `protector`, in a file readable by its owner only
+    pub fn save_protected<P: AsRef<Path>>(&self, path: P, protector: &dyn KeyFileProtector) -> Result<()> {
+        self.save_protected_with(path, protector, &WriteOptions::critical())
+    }
+    
+    /// `save_protected`, finishing the file as `options` ask instead of fsyncing it
+    pub fn save_protected_with<P: AsRef<Path>>(&self, path: P, protector: &dyn KeyFileProtector, options: &WriteOptions) -> Result<()> {
+        permissions::replace_private_with(path.as_ref(), &self.to_protected_bytes(protector)?, options)?;
+        
+        Ok(())
+    }
+    
+    /// Contents of the key file `save_protected` writes
+    pub fn to_protected_bytes(&self, protector: &dyn KeyFileProtector) -> Result<Vec<u8>> {
+        let plain = SecretBytes::new(self.to_bytes()?);
+        protector::seal(&plain, &self.key_id, protector)
+    }
+    
+    /// Contents of the key file `save` writes, always of the current version
+    /// and self-signed
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let Some(keys) = &self.keys else {
+            return self.encrypt_only_bytes();
+        };
+        let stored = StoredKeys {
+            key_id: self.key_id.clone(),
+            instance_id: self.instance_id.clone(),
+            layer1_key: keys.layer1_key.to_vec(),
+            layer2_key: keys.layer2_key.to_vec(),
+            layer3_key: keys.layer3_key.to_vec(),
+            layer4_key: keys.layer4_key.to_vec(),
+            kdf: keys.scheme,
+            created_at: self.created_at.clone(),
+            escrow: self.escrow.clone(),
+            capabilities: self.capabilities.clone(),
+            data_limits: self.data_limits,
+            owner: self.owner.clone(),
+            signing_key: self.signing_key.as_ref().map(SigningKey::to_stored),
+            extensions: self.extensions.clone(),
+        };
+        let body = serde_json::to_value(&stored)
+            .map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?;
+        let signature = provenance::sign(keys, &body)?;
+        let file = KeyFileEnvelope { format: KEY_FILE_FORMAT, version: KEY_FILE_VERSION, body: &stored, signature: Some(signature) };
+        
+        let json = serde_json::to_string_pretty(&file)
+            .map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?;
+        
+        Ok(json.into_bytes())
+    }
+    
+    /// Key file of encrypt-only keys: the encapsulation key and no layer key
+    /// It is unsigned; only the layer keys could sign it
+    fn encrypt_only_bytes(&self) -> Result<Vec<u8>> {
+        let encapsulation = self.encapsulation_key()?;
+        let stored = StoredEncryptOnlyKeys {
+            key_id: self.key_id.clone(),
+            instance_id: self.instance_id.clone(),
+            encapsulation_key: encapsulation.public_key().to_vec(),
+            kdf: encapsulation.scheme(),
+            created_at: self.created_at.clone(),
+            capabilities: vec![Capability::Encrypt],
+            data_limits: self.data_limits,
+            owner: self.owner.clone(),
+            extensions: self.extensions.clone(),
+        };
+        let file = KeyFileEnvelope { format: KEY_FILE_FORMAT, version: KEY_FILE_VERSION, body: &stored, signature: None };
+        
+        let json = serde_json::to_string_pretty(&file)
+            .map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?;
+        
+        Ok(json.into_bytes())
+    }
+    
+    /// Master keys for all layers, refused like `decryption_keys`: whoever
+    /// holds them can decrypt every file of the profile
+    pub fn get_keys(&self) -> Result<&LayerKeys> {
+        self.decryption_keys()
+    }
+    
+    /// Keys for decrypting, refused when the key file was exported encrypt-only
+    /// Pipelines call this before reading any ciphertext
+    pub fn decryption_keys(&self) -> Result<&LayerKeys> {
+        match &self.keys {
+            Some(keys) if self.can_decrypt() => Ok(keys),
+            _ => Err(HybridGuardError::CapabilityDenied(format!(
+                "key {} is encrypt-only and cannot decrypt",
+                self.key_id
+            ))),
+        }
+    }
+    
+    /// Public key these keys encapsulate file keys to when they may not decrypt
+    pub fn encapsulation_key(&self) -> Result<EncapsulationKey> {
+        match (&self.encapsulation, &self.keys) {
+            (Some(encapsulation), _) => Ok(encapsulation.clone()),
+            (None, Some(keys)) => EncapsulationKey::of(keys),
+            (None, None) => Err(HybridGuardError::KeyGeneration(format!("key {} holds no key material", self.key_id))),
+        }
+    }
+    
+    /// Scheme file keys expand under
+    fn scheme(&self) -> KdfScheme {
+        match (&self.keys, &self.encapsulation) {
+            (Some(keys), _) => keys.scheme,
+            (None, Some(encapsulation)) => encapsulation.scheme(),
+            (None, None) => KdfScheme::CURRENT,
+        }
+    }
+    
+    /// Layer keys for one new file, and its file key wrapped under these
+    /// keys, or encapsulated when they may not decrypt
+    pub fn new_file_keys(&self) -> Result<(LayerKeys, WrappedFileKey)> {
+        let file_key = envelope::generate_file_key();
+        let wrapped = match self.decryption_keys() {
+            Ok(master) => envelope::wrap(master, &self.key_id, &file_key)?,
+            Err(_) => envelope::encapsulate(&self.encapsulation_key()?, &self.key_id, &file_key, rand::random())?,
+        };
+        Ok((envelope::file_layer_keys(&file_key, self.scheme())?, wrapped))
+    }
+    
+    /// `new_file_keys` with the file key and wrap nonce drawn from `source`
+    pub fn new_file_keys_from(&self, source: &dyn RandomSource) -> Result<(LayerKeys, WrappedFileKey)> {
+        let file_key = envelope::file_key_from(source);
+        let mut nonce = [0u8; envelope::NONCE_LEN];
+        source.fill(&mut nonce);
+        let wrapped = match self.decryption_keys() {
+            Ok(master) => {
+                if !source.reproducible() {
+                    nonce::checkout(self.key_id.as_bytes(), envelope::WRAP_PURPOSE, &nonce)?;
+                }
+                envelope::wrap_with_nonce(master, &self.key_id, &file_key, nonce)?
+            }
+            Err(_) => envelope::encapsulate(&self.encapsulation_key()?, &self.key_id, &file_key, nonce)?,
+        };
+        Ok((envelope::file_layer_keys(&file_key, self.scheme())?, wrapped))
+    }
+    
+    /// Layer keys that decrypt `encrypted`: its unwrapped file keys, or these
+    /// keys themselves for containers written before format v7
+    /// A container read from v7 or later must carry its wrapped file key
+    pub fn keys_for(&self, encrypted: &EncryptedData) -> Result<Cow<'_, LayerKeys>> {
+        let keys = self.decryption_keys()?;
+        match encrypted.wrapped_key() {
+            Some(wrapped) => Ok(Cow::Owned(self.unwrap_file_keys(wrapped)?)),
+            None if encrypted.wrapped_key_stripped() => Err(HybridGuardError::Integrity(
+                "container is format v7 or later but carries no wrapped file key; it was removed".to_string(),
+            )),
+            None => Ok(Cow::Borrowed(keys)),
+        }
+    }
+    
+    /// Layer keys of a file key wrapped under or encapsulated to these keys
+    pub(crate) fn unwrap_file_keys(&self, wrapped: &WrappedFileKey) -> Result<LayerKeys> {
+        let master = self.decryption_keys()?;
+        let file_key = envelope::unwrap(master, &self.key_id, wrapped)?;
+        envelope::file_layer_keys(&file_key, master.scheme)
+    }
+    
+    /// Most data one key may encrypt before streaming rekeys
+    /// Key files without recorded limits get `DataLimits::DEFAULT`
+    pub fn data_limits(&self) -> DataLimits {
+        self.data_limits.unwrap_or_default()
+    }
+    
+    /// Record tighter (or looser) data limits for this profile
+    pub fn with_data_limits(mut self, limits: DataLimits) -> Self {
+        self.data_limits = Some(limits);
+        self
+    }
+    
+    /// Operations this key file was issued for
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+    
+    pub fn can_encrypt(&self) -> bool {
+        self.capabilities.contains(&Capability::Encrypt)
+    }
+    
+    pub fn can_decrypt(&self) -> bool {
+        self.capabilities.contains(&Capability::Decrypt)
+    }
+    
+    /// Copy of these keys that may only encrypt, for servers that ingest data
+    /// It holds the encapsulation key and no layer key, so its file cannot be
+    /// edited into one that decrypts. It encrypts containers only: every
+    /// other format is keyed by the layer keys themselves
+    pub fn encrypt_only(&self) -> Result<Self> {
+        Ok(Self {
+            keys: None,
+            encapsulation: Some(self.encapsulation_key()?),
+            key_id: self.key_id.clone(),
+            instance_id: Some(Self::generate_instance_id()),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            escrow: None,
+            capabilities: vec![Capability::Encrypt],
+            data_limits: self.data_limits,
+            owner: self.owner.clone(),
+            signing_key: None,
+            signature: None,
+            format_version: KEY_FILE_VERSION,
+            extensions: Map::new(),
+        })
+    }
+    
+    /// Tag keys that let a third party check this profile's ciphertexts
+    /// without being able to decrypt them; see `verification`
+    /// Encrypt-only keys have no tag keys to export
+    pub fn export_verification_key(&self) -> Result<VerificationKey> {
+        Ok(VerificationKey::from_keys(&self.key_id, self.get_keys()?))
+    }
+    
+    /// Key file version these keys were loaded from; `save` always writes `KEY_FILE_VERSION`
+    pub fn format_version(&self) -> u16 {
+        self.format_version
+    }
+    
+    /// Get key ID
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+    
+    /// Random ID distinguishing this key file from other copies of the same keys
+    /// Absent in key files written by older versions
+    pub fn instance_id(&self) -> Option<&str> {
+        self.instance_id.as_deref()
+    }
+    
+    /// Recovery key these keys were escrowed to, if any
+    pub fn escrow(&self) -> Option<&EscrowRecord> {
+        self.escrow.as_ref()
+    }
+    
+    /// Who these keys belong to, if keygen recorded it
+    pub fn owner(&self) -> Option<&KeyOwner> {
+        self.owner.as_ref()
+    }
+    
+    /// Record who these keys belong to in the key file metadata
+    pub fn with_owner(mut self, owner: KeyOwner) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+    
+    /// When these keys were generated, as RFC 3339
+    pub fn created_at(&self) -> &str {
+        &self.created_at
+    }
+    
+    /// How long before `now`, in seconds since the epoch, these keys were
+    /// generated; None when the recorded time does not parse
+    pub fn age(&self, now: u64) -> Option<std::time::Duration> {
+        let created = chrono::DateTime::parse_from_rfc3339(&self.created_at).ok()?.timestamp();
+        Some(std::time::Duration::from_secs(now.saturating_sub(u64::try_from(created).unwrap_or(0))))
+    }
+    
+    /// Keypair detached signatures are made with, if one was added
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        self.signing_key.as_ref()
+    }
+    
+    /// Add a signing keypair to the key file metadata
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+    
+    /// Sign the file at `path` with this key file's signing keypair, for
+    /// `signing::verify_detached`; fails with `CapabilityDenied` without one
+    pub fn sign_detached(&self, path: &Path) -> Result<DetachedSignature> {
+        let Some(key) = &self.signing_key else {
+            return Err(HybridGuardError::CapabilityDenied(format!(
+                "key file {} holds no signing key; `hybridguard sign` adds one",
+                self.key_id
+            )));
+        };
+        key.sign_detached(path)
+    }
+    
+    /// Self-signature of the key file these keys were loaded from; `None`
+    /// for keys that were not loaded from one
+    pub fn self_signature(&self) -> Option<&SignatureStatus> {
+        self.signature.as_ref()
+    }
+    
+    /// Record an escrow in the key file metadata
+    pub(crate) fn with_escrow(mut self, record: EscrowRecord) -> Self {
+        self.escrow = Some(record);
+        self
+    }
+    
+    /// Generate a random salt
+    fn generate_salt() -> Vec<u8> {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        (0..32).map(|_| rng.gen()).collect()
+    }
+    
+    /// Generate a random instance ID
+    fn generate_instance_id() -> String {
+        codec::hex_lower(&rand::random::<[u8; 16]>())
+    }
+    
+    /// Key ID computed from the layer keys themselves: a truncated SHA3-256
+    fn material_key_id(keys: &LayerKeys) -> String {
+        use sha3::{Sha3_256, Digest};
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"HybridGuard-KeyID");
+        hasher.update(&keys.layer1_key[..]);
+        hasher.update(&keys.layer2_key[..]);
+        hasher.update(&keys.layer3_key[..]);
+        hasher.update(&keys.layer4_key[..]);
+        
+        let digest = hasher.finalize();
+        let mut id = [0u8; KEY_ID_BYTES];
+        id.copy_from_slice(&digest[..KEY_ID_BYTES]);
+        KeyId(id).to_string()
+    }
+}
+
+/// Explain why an imported master key looks degenerate, if it does
+/// A uniformly random 256-bit key has a Hamming weight of 128 +/- 8, so
+/// anything outside 64..=192 almost certainly did not come from a good RNG
+pub fn master_key_warning(master_key: &[u8; 32]) -> Option<String> {
+    let weight: u32 = master_key.iter().map(|b| b.count_ones()).sum();
+    if weight == 0 {
+        Some("master key is all zeros".to_string())
+    } else if !(64..=192).contains(&weight) {
+        Some(format!(
+            "master key has a Hamming weight of {} out of 256 bits; it is unlikely to be random",
+            weight
+        ))
+    } else {
+        None
+    }
+}
+
+/// Serializable key storage format
+#[derive(Serialize, Deserialize)]
+struct StoredKeys {
+    key_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    instance_id: Option<String>,
+    layer1_key: Vec<u8>,
+    layer2_key: Vec<u8>,
+    layer3_key: Vec<u8>,
+    layer4_key: Vec<u8>,
+    /// Files written before V2 have no `kdf` and are V1
+    #[serde(default)]
+    kdf: KdfScheme,
+    created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    escrow: Option<EscrowRecord>,
+    #[serde(default = "all_capabilities")]
+    capabilities: Vec<Capability>,
+    /// Absent unless the profile overrides `DataLimits::DEFAULT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data_limits: Option<DataLimits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<KeyOwner>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing_key: Option<StoredSigningKey>,
+    /// Fields added by newer versions, kept so a resave does not drop them
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+/// Serializable encrypt-only key file: the encapsulation key and no layer key
+#[derive(Serialize, Deserialize)]
+struct StoredEncryptOnlyKeys {
+    key_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    instance_id: Option<String>,
+    encapsulation_key: Vec<u8>,
+    kdf: KdfScheme,
+    created_at: String,
+    capabilities: Vec<Capability>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data_limits: Option<DataLimits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<KeyOwner>,
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+/// Version 2 key file: the stored keys inside an envelope naming the format
+#[derive(Serialize)]
+struct KeyFileEnvelope<'a, T> {
+    format: &'static str,
+    version: u16,
+    body: &'a T,
+    /// Over the body, so kept outside it; see `provenance`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<SelfSignature>,
+}
+
+/// Version of the plain key file `data`, and the object holding its fields
+/// Fails with `NotAKeyFile` when `data` is no key file, `UnsupportedFormat`
+/// when it is newer than `KEY_FILE_VERSION`, and `KeyFileDamaged` when its
+/// envelope is broken
+pub(crate) fn key_file_body(data: &[u8]) -> Result<(u16, Map<String, Value>)> {
+    let not_a_key_file = |reason: String| HybridGuardError::NotAKeyFile(reason);
+    let damaged = || HybridGuardError::KeyFileDamaged(Box::new(doctor::diagnose(data)));
+    let mut object = match serde_json::from_slice::<Value>(data) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err(not_a_key_file("not a JSON object".to_string())),
+        // A truncated object may be a damaged key file; anything else is not one
+        Err(_) if data.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{') => return Err(damaged()),
+        Err(_) => return Err(not_a_key_file("not JSON".to_string())),
+    };
+    let Some(format) = object.get("format") else {
+        if V1_FIELDS.iter().any(|field| object.contains_key(*field)) {
+            return Ok((1, object));
+        }
+        return Err(not_a_key_file("no key file fields".to_string()));
+    };
+    if format.as_str() != Some(KEY_FILE_FORMAT) {
+        return Err(not_a_key_file(format!("format is {}, not \"{}\"", format, KEY_FILE_FORMAT)));
+    }
+    let version = object.get("version").and_then(Value::as_u64);
+    if let Some(version) = version.filter(|version| *version > u64::from(KEY_FILE_VERSION)) {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "key file version {} is newer than this build reads ({}); upgrade HybridGuard to use it",
+            version, KEY_FILE_VERSION
+        )));
+    }
+    match (version, object.remove("body")) {
+        (Some(2), Some(Value::Object(body))) => Ok((KEY_FILE_VERSION, body)),
+        _ => Err(damaged()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn sample_master() -> [u8; 32] {
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(97) ^ 0x5A;
+        }
+        key
+    }
+    
+    #[test]
+    fn test_from_master_key_matches_derivation() {
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let expected = KeyDerivation::new(sample_master().to_vec()).derive_all_keys().unwrap();
+        assert_eq!(km.get_keys().unwrap().layer1_key, expected.layer1_key);
+        assert_eq!(km.get_keys().unwrap().layer4_key, expected.layer4_key);
+    }
+    
+    #[test]
+    fn test_file_keys_unwrap_only_under_the_same_keys() {
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let (file_keys, wrapped) = km.new_file_keys().unwrap();
+        assert_ne!(file_keys.layer1_key, km.get_keys().unwrap().layer1_key);
+        
+        let sealed = EncryptedData::new(vec![1, 2, 3]).with_wrapped_key(wrapped, &file_keys);
+        assert_eq!(km.keys_for(&sealed).unwrap().layer1_key, file_keys.layer1_key);
+        
+        let other = KeyManager::from_master_key(&[0x11; 32]).unwrap();
+        assert!(matches!(other.keys_for(&sealed), Err(HybridGuardError::Integrity(_))));
+        assert!(matches!(km.encrypt_only().unwrap().keys_for(&sealed), Err(HybridGuardError::CapabilityDenied(_))));
+        
+        // Containers without a wrapped key decrypt with the master keys
+        let legacy = EncryptedData::new(vec![1, 2, 3]);
+        assert!(matches!(km.keys_for(&legacy).unwrap(), Cow::Borrowed(_)));
+        let v6 = EncryptedData::from_bytes(&crate::crypto::container::encode_version(&legacy, 6).unwrap()).unwrap();
+        assert!(matches!(km.keys_for(&v6).unwrap(), Cow::Borrowed(_)));
+        
+        // Read from v7 or later, the wrapped key can only have been removed
+        let stripped = EncryptedData::from_bytes(&legacy.to_bytes().unwrap()).unwrap();
+        assert!(matches!(km.keys_for(&stripped), Err(HybridGuardError::Integrity(_))));
+    }
+    
+    #[cfg(feature = "legacy-kdf-v1")]
+    #[test]
+    fn test_key_file_without_kdf_is_v1() {
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        assert_eq!(km.get_keys().unwrap().scheme, KdfScheme::V2);
+        let saved = KeyManager::from_bytes(&km.to_bytes().unwrap()).unwrap();
+        assert_eq!(saved.get_keys().unwrap().scheme, KdfScheme::V2);
+        
+        let v1 = KeyManager::from_master_key_with(&sample_master(), KdfScheme::V1).unwrap();
+        assert_ne!(v1.get_keys().unwrap().layer1_key, km.get_keys().unwrap().layer1_key);
+        let mut legacy: serde_json::Value = serde_json::from_slice(&v1.to_bytes().unwrap()).unwrap();
+        assert_eq!(legacy["body"]["kdf"], "v1");
+        legacy["body"].as_object_mut().unwrap().remove("kdf");
+        let loaded = KeyManager::from_bytes(&serde_json::to_vec(&legacy).unwrap()).unwrap();
+        assert_eq!(loaded.get_keys().unwrap().scheme, KdfScheme::V1);
+        
+        // Its file keys follow it, so what it wrote still opens
+        let (file_keys, wrapped) = v1.new_file_keys().unwrap();
+        assert_eq!(file_keys.scheme, KdfScheme::V1);
+        let sealed = EncryptedData::new(vec![1, 2, 3]).with_wrapped_key(wrapped, &file_keys);
+        assert_eq!(loaded.keys_for(&sealed).unwrap().layer1_key, file_keys.layer1_key);
+    }
+    
+    #[test]
+    fn test_from_raw_keys_stable_id() {
+        let keys = KeyDerivation::new(sample_master().to_vec()).derive_all_keys().unwrap();
+        let first = KeyManager::from_raw_keys(keys.clone());
+        let second = KeyManager::from_raw_keys(keys);
+        assert_eq!(first.key_id(), second.key_id());
+        
+        let other = KeyManager::from_master_key(&[0x11; 32]).unwrap();
+        assert_ne!(first.key_id(), other.key_id());
+    }
+    
+    #[test]
+    fn test_capabilities_default_and_restrict() {
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let mut legacy: serde_json::Value = serde_json::from_slice(&km.to_bytes().unwrap()).unwrap();
+        legacy["body"].as_object_mut().unwrap().remove("capabilities");
+        let loaded = KeyManager::from_bytes(legacy.to_string().as_bytes()).unwrap();
+        assert!(loaded.can_encrypt() && loaded.can_decrypt());
+        
+        let restricted = KeyManager::from_bytes(&km.encrypt_only().unwrap().to_bytes().unwrap()).unwrap();
+        assert!(restricted.can_encrypt() && !restricted.can_decrypt());
+        assert!(matches!(restricted.decryption_keys(), Err(HybridGuardError::CapabilityDenied(_))));
+        // Restricting again never grants anything back
+        assert_eq!(restricted.encrypt_only().unwrap().capabilities(), &[Capability::Encrypt]);
+        
+        // The file holds no layer key, so granting decrypt back only damages it
+        let mut edited: serde_json::Value = serde_json::from_slice(&restricted.to_bytes().unwrap()).unwrap();
+        assert!(edited["body"].get("layer1_key").is_none());
+        edited["body"]["capabilities"] = serde_json::json!(["encrypt", "decrypt"]);
+        assert!(matches!(KeyManager::from_bytes(edited.to_string().as_bytes()), Err(HybridGuardError::KeyFileDamaged(_))));
+        
+        // Its file keys are encapsulated to the full keys
+        let (file_keys, wrapped) = restricted.new_file_keys().unwrap();
+        let sealed = EncryptedData::new(vec![4, 5]).with_wrapped_key(wrapped, &file_keys);
+        assert_eq!(km.keys_for(&sealed).unwrap().layer1_key, file_keys.layer1_key);
+    }
+    
+    #[test]
+    fn test_save_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("imported.keys");
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        km.save(&path).unwrap();
+        
+        let loaded = KeyManager::load(&path).unwrap();
+        assert_eq!(loaded.key_id(), km.key_id());
+        assert_eq!(loaded.get_keys().unwrap().layer2_key, km.get_keys().unwrap().layer2_key);
+        
+        // Saving again reproduces the file byte for byte
+        let resaved = dir.path().join("resaved.keys");
+        loaded.save(&resaved).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&resaved).unwrap());
+    }
+    
+    #[test]
+    fn test_protected_save_load_round_trip() {
+        use protector::{HmacFileProtector, PasswordProtector};
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sealed.keys");
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let hmac = HmacFileProtector::new(vec![0x44; 32]).unwrap();
+        km.save_protected(&path, &hmac).unwrap();
+        
+        let loaded = KeyManager::load_protected(&path, LoosePermissions::Warn, &hmac).unwrap();
+        assert_eq!(loaded.key_id(), km.key_id());
+        assert_eq!(loaded.get_keys().unwrap().layer4_key, km.get_keys().unwrap().layer4_key);
+        
+        // Neither loadable as a plain key file nor with another protector
+        assert!(matches!(KeyManager::load(&path), Err(HybridGuardError::KeyMismatch(_))));
+        let password = PasswordProtector::new("hunter2 but longer");
+        assert!(matches!(KeyManager::load_protected(&path, LoosePermissions::Warn, &password), Err(HybridGuardError::KeyMismatch(_))));
+    }
+    
+    #[test]
+    fn test_key_id_stable_for_password_and_salt() {
+        let first = KeyManager::from_password("correct horse", b"salt-one").unwrap();
+        let second = KeyManager::from_password("correct horse", b"salt-one").unwrap();
+        assert_eq!(first.key_id(), second.key_id());
+        assert_eq!(first.key_id().len(), 3 + 2 * KEY_ID_BYTES);
+        assert_ne!(first.instance_id(), second.instance_id());
+        
+        let other_salt = KeyManager::from_password("correct horse", b"salt-two").unwrap();
+        assert_ne!(first.key_id(), other_salt.key_id());
+    }
+    
+    #[test]
+    fn test_legacy_key_file_keeps_stored_id() {
+        let legacy = serde_json::json!({
+            "key_id": "hg-0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+            "layer1_key": vec![1u8; 32],
+            "layer2_key": vec![2u8; 32],
+            "layer3_key": vec![3u8; 32],
+            "layer4_key": vec![4u8; 32],
+            "created_at": "2024-01-01T00:00:00+00:00",
+        });
+        let km = KeyManager::from_bytes(legacy.to_string().as_bytes()).unwrap();
+        assert_eq!(km.key_id(), "hg-0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef");
+        assert_eq!(km.instance_id(), None);
+    }
+    
+    #[test]
+    fn test_unknown_body_fields_survive_a_resave() {
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let mut file: serde_json::Value = serde_json::from_slice(&km.to_bytes().unwrap()).unwrap();
+        assert_eq!(file["format"], KEY_FILE_FORMAT);
+        assert_eq!(file["version"], KEY_FILE_VERSION);
+        file["body"]["rotation_counter"] = serde_json::json!({"next": 7, "policy": "monthly"});
+        
+        let loaded = KeyManager::from_bytes(file.to_string().as_bytes()).unwrap();
+        assert_eq!(loaded.key_id(), km.key_id());
+        let resaved: serde_json::Value = serde_json::from_slice(&loaded.to_bytes().unwrap()).unwrap();
+        assert_eq!(resaved, file);
+        // An encrypt-only export is a new file and carries none of them
+        let exported: serde_json::Value = serde_json::from_slice(&loaded.encrypt_only().unwrap().to_bytes().unwrap()).unwrap();
+        assert!(exported["body"].get("rotation_counter").is_none());
+    }
+    
+    #[test]
+    fn test_load_errors_tell_the_failure_classes_apart() {
+        let not_key_files: [&[u8]; 4] = [
+            b"hello",
+            b"[1, 2]",
+            br#"{"name": "settings"}"#,
+            br#"{"format": "other-tool", "version": 2, "body": {}}"#,
+        ];
+        for data in not_key_files {
+            let result = KeyManager::from_bytes(data);
+            assert!(matches!(result, Err(HybridGuardError::NotAKeyFile(_))), "{}", String::from_utf8_lossy(data));
+        }
+        
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let mut file: serde_json::Value = serde_json::from_slice(&km.to_bytes().unwrap()).unwrap();
+        file["version"] = serde_json::json!(3);
+        match KeyManager::from_bytes(file.to_string().as_bytes()) {
+            Err(HybridGuardError::UnsupportedFormat(message)) => assert!(message.contains("version 3"), "{}", message),
+            other => panic!("expected an unsupported version, got {:?}", other.err()),
+        }
+        
+        file["version"] = serde_json::json!(KEY_FILE_VERSION);
+        file["body"].as_object_mut().unwrap().remove("layer2_key");
+        match KeyManager::from_bytes(file.to_string().as_bytes()) {
+            Err(HybridGuardError::KeyFileDamaged(diagnosis)) => {
+                assert_eq!(diagnosis.field("layer2_key"), Some(&doctor::FieldStatus::Missing));
+            }
+            other => panic!("expected a damaged body, got {:?}", other.err()),
+        }
+        file["body"] = serde_json::json!("keys");
+        assert!(matches!(KeyManager::from_bytes(file.to_string().as_bytes()), Err(HybridGuardError::KeyFileDamaged(_))));
+    }
+    
+    #[test]
+    fn test_key_id_parse() {
+        let km = KeyManager::from_master_key(&sample_master()).unwrap();
+        let id = KeyId::parse(km.key_id()).unwrap();
+        assert_eq!(id.to_string(), km.key_id());
+        assert_eq!(km.key_id().parse::<KeyId>().unwrap(), id);
+        
+        for malformed in ["", "hg-", "hg-00", "HG-00112233445566778899aabbccddeeff", "hg-00112233445566778899AABBCCDDEEFF", "hg-00112233445566778899aabbccddeeff00", "hg-00112233445566778899aabbccddeefg"] {
+            assert!(matches!(KeyId::parse(malformed), Err(HybridGuardError::InvalidInput(_))), "{}", malformed);
+        }
+    }
+    
+    #[test]
+    fn test_degenerate_master_key_warning() {
+        assert!(master_key_warning(&[0u8; 32]).unwrap().contains("all zeros"));
+        assert!(master_key_warning(&[0x01; 32]).is_some());
+        assert!(master_key_warning(&[0xFF; 32]).is_some());
+        assert!(master_key_warning(&sample_master()).is_none());
+    }
+    
+    #[cfg(feature = "nonce-audit")]
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "file-key-wrap nonce"))]
+    fn test_audit_catches_a_repeating_random_source() {
+        struct Stuck;
+        impl RandomSource for Stuck {
+            fn fill(&self, out: &mut [u8]) {
+                out.fill(0x5a);
+            }
+        }
+        
+        let km = KeyManager::from_master_key(&[0x5a; 32]).unwrap();
+        km.new_file_keys_from(&Stuck).unwrap();
+        assert!(matches!(km.new_file_keys_from(&Stuck), Err(HybridGuardError::NonceReuse(_))));
+    }
+}
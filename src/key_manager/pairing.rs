@@ -0,0 +1,289 @@
+// Two-party key agreement over ML-KEM-768
+//
+// offer:  A sends its key ID, a random nonce and an ephemeral ML-KEM public
+//         key. The keypair is derived from A's keys and the nonce, so A keeps
+//         no state between `offer` and `finish`.
+// accept: B encapsulates to the public key and replies with the whole offer,
+//         its own key ID, the KEM ciphertext and a confirmation tag.
+// finish: A checks that the offer is its own (key ID and re-derived public
+//         key), decapsulates and checks the confirmation tag.
+//
+// Both sides derive the shared master key by HKDF from the KEM secret, salted
+// with a transcript hash over the offer and both key IDs (format v2; v1 hashed
+// them together, so v1 and v2 messages do not mix), so a substituted offer,
+// ciphertext or key ID yields a failed `finish` rather than a silently
+// different key. Neither side is authenticated to the other: both must
+// compare the shared key's fingerprint out of band before relying on it.
+
+use crate::crypto::container::{le_u16, u16_at};
+use crate::crypto::hkdf::{self, KeyPurpose};
+use crate::crypto::{codec, drbg, nonce};
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::{KeyId, KeyManager};
+use crate::layers::oqs_support;
+use oqs::kem::{Algorithm, Kem};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+/// Magic bytes of an offer message
+const OFFER_MAGIC: [u8; 4] = *b"HGPO";
+
+/// Magic bytes of an accept message
+const ACCEPT_MAGIC: [u8; 4] = *b"HGPA";
+
+/// Message format written by this build
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Largest message accepted; ML-KEM-768 keys and ciphertexts are about 1 KiB
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// First message, from the party that starts the exchange
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Offer {
+    /// Key ID of the offering party
+    pub offerer: String,
+    pub nonce: [u8; 32],
+    pub public_key: Vec<u8>,
+}
+
+/// Reply to an offer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Accept {
+    /// The offer being answered, verbatim
+    pub offer: Offer,
+    /// Key ID of the accepting party
+    pub accepter: String,
+    pub kem_ciphertext: Vec<u8>,
+    /// Proves the accepting party derived the same key from this transcript
+    pub confirmation: [u8; TAG_LEN],
+}
+
+impl Offer {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(OFFER_MAGIC, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        decode(OFFER_MAGIC, "offer", bytes)
+    }
+}
+
+impl Accept {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(ACCEPT_MAGIC, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        decode(ACCEPT_MAGIC, "accept", bytes)
+    }
+}
+
+/// Start an exchange with `own` keys
+pub fn offer(own: &KeyManager) -> Result<Offer> {
+    let nonce = rand::random::<[u8; 32]>();
+    nonce::checkout(own.key_id().as_bytes(), "pairing-offer", &nonce)?;
+    let (public_key, _) = ephemeral_keypair(own, &nonce)?;
+    Ok(Offer {
+        offerer: own.key_id().to_string(),
+        nonce,
+        public_key,
+    })
+}
+
+/// Answer `offer` with `own` keys, returning the reply and the shared keys
+pub fn accept(own: &KeyManager, offer: &Offer) -> Result<(Accept, KeyManager)> {
+    if offer.offerer == own.key_id() {
+        return Err(HybridGuardError::InvalidInput("cannot accept an offer made with the same keys".to_string()));
+    }
+    let kem = kem()?;
+    let public_key = kem
+        .public_key_from_bytes(&offer.public_key)
+        .ok_or_else(|| HybridGuardError::Integrity("offer carries a malformed ML-KEM public key".to_string()))?;
+    let (ciphertext, shared_secret) = kem
+        .encapsulate(public_key)
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("encapsulation failed: {}", e)))?;
+
+    let kem_ciphertext = ciphertext.into_vec();
+    let shared_secret = SecretBytes::new(shared_secret.into_vec());
+    let transcript = transcript(offer, own.key_id(), &kem_ciphertext)?;
+    let shared = derive(&shared_secret, &transcript)?;
+    let reply = Accept {
+        offer: offer.clone(),
+        accepter: own.key_id().to_string(),
+        kem_ciphertext,
+        confirmation: confirmation(&shared, &transcript)?,
+    };
+    Ok((reply, shared))
+}
+
+/// Complete an exchange this party offered, returning the shared keys
+pub fn finish(own: &KeyManager, reply: &Accept) -> Result<KeyManager> {
+    if reply.offer.offerer != own.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "reply answers an offer from key {} but key {} is loaded",
+            reply.offer.offerer,
+            own.key_id()
+        )));
+    }
+    let (public_key, secret_key) = ephemeral_keypair(own, &reply.offer.nonce)?;
+    if public_key != reply.offer.public_key {
+        return Err(HybridGuardError::Integrity(
+            "reply answers a public key this key file did not offer (substituted offer)".to_string(),
+        ));
+    }
+
+    let kem = kem()?;
+    let secret_key = kem
+        .secret_key_from_bytes(&secret_key)
+        .ok_or_else(|| HybridGuardError::KeyGeneration("unusable ephemeral secret key".to_string()))?;
+    let ciphertext = kem
+        .ciphertext_from_bytes(&reply.kem_ciphertext)
+        .ok_or_else(|| HybridGuardError::Integrity("reply carries a malformed ML-KEM ciphertext".to_string()))?;
+    let shared_secret = kem
+        .decapsulate(secret_key, ciphertext)
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("decapsulation failed: {}", e)))?;
+    let shared_secret = SecretBytes::new(shared_secret.into_vec());
+
+    let transcript = transcript(&reply.offer, &reply.accepter, &reply.kem_ciphertext)?;
+    let shared = derive(&shared_secret, &transcript)?;
+    if !tag::tags_match(&confirmation(&shared, &transcript)?, &reply.confirmation) {
+        return Err(HybridGuardError::Integrity(
+            "reply failed confirmation (modified in transit or answered by different keys)".to_string(),
+        ));
+    }
+    Ok(shared)
+}
+
+/// Key ID grouped for reading aloud or comparing side by side
+pub fn fingerprint(key_id: &str) -> String {
+    if let Ok(id) = KeyId::parse(key_id) {
+        return codec::hex_grouped(id.as_bytes(), 2);
+    }
+    // Legacy IDs that are not hex: group the characters the same way
+    let hex = key_id.strip_prefix(KeyId::PREFIX).unwrap_or(key_id);
+    hex.as_bytes()
+        .chunks(4)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn kem() -> Result<Kem> {
+    oqs_support::kem("pairing", Algorithm::Kyber768)
+}
+
+/// Ephemeral keypair for one offer, reproducible only with the offering keys
+fn ephemeral_keypair(own: &KeyManager, nonce: &[u8; 32]) -> Result<(Vec<u8>, SecretBytes)> {
+    let mut seed = tag::keyed_hasher(own.get_keys()?, KeyPurpose::KeypairSeed);
+    seed.update(nonce);
+    let seed = seed.finalize();
+
+    let kem = kem()?;
+    let (public_key, secret_key) = drbg::with_seeded_oqs_rng(&seed, b"pair-offer-keypair", || kem.keypair())
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("Failed to generate keypair: {}", e)))?;
+    Ok((public_key.into_vec(), SecretBytes::new(secret_key.into_vec())))
+}
+
+/// Hash binding the offer, both key IDs and the KEM ciphertext
+fn transcript(offer: &Offer, accepter: &str, kem_ciphertext: &[u8]) -> Result<[u8; 32]> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"HybridGuard-pair-transcript");
+    hasher.update(offer.to_bytes()?);
+    hasher.update(bincode::serialize(&(accepter, kem_ciphertext)).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?);
+    Ok(hasher.finalize().into())
+}
+
+fn derive(shared_secret: &[u8], transcript: &[u8; 32]) -> Result<KeyManager> {
+    let master = hkdf::derive(transcript, shared_secret, KeyPurpose::Session, 32)?;
+    let mut master: [u8; 32] = master.as_bytes().try_into().map_err(|_| {
+        HybridGuardError::KeyGeneration("session key has the wrong length".to_string())
+    })?;
+    let shared = KeyManager::from_master_key(&master);
+    zeroize::Zeroize::zeroize(&mut master);
+    shared
+}
+
+fn confirmation(shared: &KeyManager, transcript: &[u8; 32]) -> Result<[u8; TAG_LEN]> {
+    let mut hasher = tag::keyed_hasher(shared.get_keys()?, KeyPurpose::Mac("pair-confirm"));
+    hasher.update(transcript);
+    Ok(hasher.finalize().into())
+}
+
+fn encode<T: Serialize>(magic: [u8; 4], message: &T) -> Result<Vec<u8>> {
+    let mut bytes = magic.to_vec();
+    bytes.extend_from_slice(&le_u16(FORMAT_VERSION));
+    bytes.extend(bincode::serialize(message).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?);
+    Ok(bytes)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(magic: [u8; 4], what: &str, bytes: &[u8]) -> Result<T> {
+    if bytes.len() > MAX_MESSAGE_LEN || !bytes.starts_with(&magic) || bytes.len() < 6 {
+        return Err(HybridGuardError::UnsupportedFormat(format!("not a pairing {} message", what)));
+    }
+    let version = u16_at(bytes, 4);
+    if version != FORMAT_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "pairing message v{} is not supported (this build reads v{})",
+            version, FORMAT_VERSION
+        )));
+    }
+    bincode::deserialize(&bytes[6..]).map_err(|e| HybridGuardError::Integrity(format!("unreadable pairing {}: {}", what, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn party(seed: u8) -> KeyManager {
+        KeyManager::from_master_key(&[seed; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_both_sides_derive_the_same_key() {
+        let (alice, bob) = (party(0xA1), party(0xB2));
+
+        let offer_bytes = offer(&alice).unwrap().to_bytes().unwrap();
+        let (reply, bob_shared) = accept(&bob, &Offer::from_bytes(&offer_bytes).unwrap()).unwrap();
+        let alice_shared = finish(&alice, &Accept::from_bytes(&reply.to_bytes().unwrap()).unwrap()).unwrap();
+
+        assert_eq!(alice_shared.key_id(), bob_shared.key_id());
+        assert_eq!(alice_shared.get_keys().unwrap().layer1_key, bob_shared.get_keys().unwrap().layer1_key);
+        assert_ne!(alice_shared.key_id(), alice.key_id());
+        assert_eq!(fingerprint(alice_shared.key_id()), fingerprint(bob_shared.key_id()));
+
+        // A second exchange between the same parties gives a fresh key
+        let (reply, _) = accept(&bob, &offer(&alice).unwrap()).unwrap();
+        assert_ne!(finish(&alice, &reply).unwrap().key_id(), alice_shared.key_id());
+    }
+
+    #[test]
+    fn test_mismatched_offer_rejected() {
+        let (alice, bob, carol) = (party(0xA1), party(0xB2), party(0xC3));
+
+        // Bob answered Carol's offer; Alice must not finish with it
+        let (reply, _) = accept(&bob, &offer(&carol).unwrap()).unwrap();
+        assert!(matches!(finish(&alice, &reply), Err(HybridGuardError::KeyMismatch(_))));
+
+        // Carol's public key relabelled as Alice's offer
+        let mut substituted = offer(&alice).unwrap();
+        substituted.public_key = offer(&carol).unwrap().public_key;
+        let (reply, _) = accept(&bob, &substituted).unwrap();
+        assert!(matches!(finish(&alice, &reply), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_modified_reply_rejected() {
+        let (alice, bob) = (party(0xA1), party(0xB2));
+        let (reply, _) = accept(&bob, &offer(&alice).unwrap()).unwrap();
+
+        let mut renamed = reply.clone();
+        renamed.accepter = party(0xC3).key_id().to_string();
+        assert!(matches!(finish(&alice, &renamed), Err(HybridGuardError::Integrity(_))));
+
+        let mut flipped = reply;
+        flipped.kem_ciphertext[0] ^= 1;
+        assert!(matches!(finish(&alice, &flipped), Err(HybridGuardError::Integrity(_))));
+    }
+}
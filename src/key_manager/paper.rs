@@ -0,0 +1,278 @@
+// Printable paper backup of key files
+// Key file bytes are written as numbered lines of Crockford base32, each with
+// a mod-37 check symbol, plus a SHA3 checksum over the whole payload
+
+use crate::crypto::codec;
+use crate::error::{HybridGuardError, Result};
+use sha3::{Digest, Sha3_256};
+
+/// Crockford check symbols (values 0..37)
+const CHECK_SYMBOLS: &[u8; 37] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ*~$=U";
+
+/// Payload bytes per line (exactly 32 base32 characters)
+pub const BYTES_PER_LINE: usize = 20;
+
+/// Length of the overall checksum in bytes
+const CHECKSUM_LEN: usize = 10;
+
+/// Render key file bytes as a printable backup document
+pub fn encode(key_file: &[u8], key_id: &str) -> String {
+    let lines: Vec<&[u8]> = key_file.chunks(BYTES_PER_LINE).collect();
+
+    let mut out = String::new();
+    out.push_str("HybridGuard paper key backup\n");
+    out.push_str("============================\n");
+    out.push_str(&format!("Key ID:   {}\n", key_id));
+    out.push_str(&format!("Bytes:    {}\n", key_file.len()));
+    out.push_str(&format!("Lines:    {}\n", lines.len()));
+    out.push_str(&format!("Checksum: {}\n", overall_checksum(key_file)));
+    out.push('\n');
+    out.push_str("To restore: hybridguard key restore --paper <this file> --output hybridguard.keys\n");
+    out.push_str("Interactive: hybridguard key restore --paper --output hybridguard.keys\n");
+    out.push_str("Each line is <number>: <data> <check>. Case does not matter; I/L read as 1, O as 0.\n");
+    out.push('\n');
+
+    for (i, chunk) in lines.iter().enumerate() {
+        out.push_str(&encode_line(i + 1, chunk));
+        out.push('\n');
+    }
+    out
+}
+
+/// Encode one numbered line: `NNN: XXXX XXXX ... C`
+pub fn encode_line(line_no: usize, chunk: &[u8]) -> String {
+    let data = codec::crockford32(chunk);
+    let digits: Vec<u8> = data.bytes().map(|c| codec::crockford32_value(c).unwrap_or(0)).collect();
+
+    let groups: Vec<&str> = data
+        .as_bytes()
+        .chunks(4)
+        .map(|g| std::str::from_utf8(g).unwrap_or(""))
+        .collect();
+    format!("{:03}: {} {}", line_no, groups.join(" "), check_symbol(line_no, &digits) as char)
+}
+
+/// Parse a full backup document back into key file bytes
+/// Errors name the exact line that must be re-entered
+pub fn decode(text: &str) -> Result<Vec<u8>> {
+    let mut expected_len = None;
+    let mut expected_checksum = None;
+    let mut lines: Vec<(usize, &str)> = Vec::new();
+
+    for raw in text.lines() {
+        let line = raw.trim();
+        let lower = line.to_ascii_lowercase();
+        if let Some(value) = lower.strip_prefix("bytes:") {
+            let len = value.trim().parse::<usize>()
+                .map_err(|_| invalid("the 'Bytes:' header is not a number"))?;
+            expected_len = Some(len);
+        } else if let Some(value) = lower.strip_prefix("checksum:") {
+            expected_checksum = Some(value.trim().to_string());
+        } else if let Some(line_no) = line_number(line) {
+            lines.push((line_no, line));
+        }
+    }
+
+    let expected_len = expected_len.ok_or_else(|| invalid("missing 'Bytes:' header"))?;
+    let expected_checksum = expected_checksum.ok_or_else(|| invalid("missing 'Checksum:' header"))?;
+    let line_count = expected_len.div_ceil(BYTES_PER_LINE);
+
+    // The header is typed in, so it reserves no more than the lines present hold
+    let typed = lines.len().checked_mul(BYTES_PER_LINE).unwrap_or(usize::MAX);
+    let mut payload = Vec::with_capacity(expected_len.min(typed));
+    for line_no in 1..=line_count {
+        let text = lines
+            .iter()
+            .find(|(n, _)| *n == line_no)
+            .map(|(_, text)| *text)
+            .ok_or_else(|| invalid(&format!("line {} is missing", line_no)))?;
+        let expected = expected_len.checked_sub(payload.len()).unwrap_or_default().min(BYTES_PER_LINE);
+        let bytes = decode_line(line_no, text)?;
+        if bytes.len() != expected {
+            return Err(invalid(&format!(
+                "line {} has {} bytes, expected {}; re-enter line {}",
+                line_no, bytes.len(), expected, line_no
+            )));
+        }
+        payload.extend_from_slice(&bytes);
+    }
+
+    verify_checksum(&payload, &expected_checksum)?;
+    Ok(payload)
+}
+
+/// Decode and verify a single line as typed by the user
+/// The `NNN:` prefix is optional but must match `line_no` when present
+pub fn decode_line(line_no: usize, text: &str) -> Result<Vec<u8>> {
+    let mut body = text.trim();
+    if let Some((prefix, rest)) = body.split_once(':') {
+        match prefix.trim().parse::<usize>() {
+            Ok(n) if n == line_no => body = rest,
+            Ok(n) => {
+                return Err(invalid(&format!("expected line {} but got line {}", line_no, n)));
+            }
+            Err(_) => return Err(invalid(&format!("line {} has a malformed line number", line_no))),
+        }
+    }
+
+    let mut tokens: Vec<&str> = body.split_whitespace().collect();
+    let check = tokens.pop().ok_or_else(|| invalid(&format!("line {} is empty", line_no)))?;
+    let data: String = tokens.concat().replace('-', "");
+
+    let mut digits = Vec::with_capacity(data.len());
+    for c in data.bytes() {
+        let d = codec::crockford32_value(c).ok_or_else(|| {
+            invalid(&format!("line {} contains invalid character '{}'; re-enter line {}", line_no, c as char, line_no))
+        })?;
+        digits.push(d);
+    }
+
+    let check_ok = check.len() == 1
+        && normalize_check(check.as_bytes()[0]) == check_symbol(line_no, &digits);
+    if !check_ok {
+        return Err(invalid(&format!("line {} failed its checksum; re-enter line {}", line_no, line_no)));
+    }
+
+    codec::crockford32_decode(&data)
+        .map_err(|e| invalid(&format!("line {} is not whole bytes ({}); re-enter line {}", line_no, e, line_no)))
+}
+
+/// Overall checksum as printed in the header, e.g. `ABCD-EFGH-JKMN-PQRS`
+pub fn overall_checksum(payload: &[u8]) -> String {
+    let digest = Sha3_256::digest(payload);
+    let encoded = codec::crockford32(&digest[..CHECKSUM_LEN]);
+    let groups: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(4)
+        .map(|g| std::str::from_utf8(g).unwrap_or(""))
+        .collect();
+    groups.join("-")
+}
+
+/// Compare the payload against a typed overall checksum
+pub fn verify_checksum(payload: &[u8], typed: &str) -> Result<()> {
+    let normalize = |s: &str| -> Vec<u8> {
+        s.bytes().filter(|c| *c != b'-' && !c.is_ascii_whitespace()).filter_map(codec::crockford32_value).collect()
+    };
+    if normalize(typed) == normalize(&overall_checksum(payload)) {
+        Ok(())
+    } else {
+        Err(invalid("overall checksum mismatch: every line verified but the backup as a whole does not (lines swapped or missing?)"))
+    }
+}
+
+fn invalid(msg: &str) -> HybridGuardError {
+    HybridGuardError::InvalidInput(format!("paper backup: {}", msg))
+}
+
+/// Leading `NNN:` line number of a data line
+fn line_number(line: &str) -> Option<usize> {
+    let (prefix, _) = line.split_once(':')?;
+    prefix.trim().parse().ok()
+}
+
+/// Crockford check symbol over the line number and the data digits
+/// Any single substituted digit changes the value mod 37, since 37 is prime
+fn check_symbol(line_no: usize, digits: &[u8]) -> u8 {
+    let mut acc = line_no % 37;
+    for &d in digits {
+        acc = (acc * 32 + d as usize) % 37;
+    }
+    CHECK_SYMBOLS[acc]
+}
+
+/// Typed check symbol with the same aliases as data characters
+fn normalize_check(c: u8) -> u8 {
+    match c.to_ascii_uppercase() {
+        b'O' => b'0',
+        b'I' | b'L' => b'1',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_key_file() -> Vec<u8> {
+        (0..157u32).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key_file = sample_key_file();
+        let text = encode(&key_file, "hg-test");
+        assert!(text.contains("Key ID:   hg-test"));
+        assert_eq!(decode(&text).unwrap(), key_file);
+    }
+
+    #[test]
+    fn test_case_and_alias_insensitive() {
+        let text = encode(&sample_key_file(), "hg-test").to_lowercase();
+        assert_eq!(decode(&text).unwrap(), sample_key_file());
+    }
+
+    #[test]
+    fn test_single_character_typo_detected() {
+        let chunk = &sample_key_file()[..BYTES_PER_LINE];
+        let line = encode_line(3, chunk);
+        let (prefix, body) = line.split_at(5);
+
+        for (pos, original) in body.char_indices() {
+            if original == ' ' {
+                continue;
+            }
+            for &replacement in codec::CROCKFORD_ALPHABET.iter().chain(CHECK_SYMBOLS.iter()) {
+                let replacement = replacement as char;
+                if replacement == original {
+                    continue;
+                }
+                let mut typo = body.to_string();
+                typo.replace_range(pos..pos + 1, &replacement.to_string());
+                let result = decode_line(3, &format!("{}{}", prefix, typo));
+                assert!(result.is_err(), "typo {} -> {} at {} not detected", original, replacement, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_names_line() {
+        let text = encode(&sample_key_file(), "hg-test");
+        let damaged: String = text
+            .lines()
+            .map(|l| {
+                if !l.starts_with("004:") {
+                    return l.to_string();
+                }
+                // Substitute the first data character
+                let first = l.as_bytes()[5];
+                let replacement = if first == b'A' { "B" } else { "A" };
+                format!("{}{}{}", &l[..5], replacement, &l[6..])
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let err = decode(&damaged).unwrap_err().to_string();
+        assert!(err.contains("re-enter line 4"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_line_and_swapped_lines() {
+        let text = encode(&sample_key_file(), "hg-test");
+        let without: String = text.lines().filter(|l| !l.starts_with("002:")).collect::<Vec<_>>().join("\n");
+        assert!(decode(&without).unwrap_err().to_string().contains("line 2 is missing"));
+
+        // A line typed under the wrong number fails its own check symbol
+        let line_two = text.lines().find(|l| l.starts_with("002:")).unwrap();
+        assert!(decode_line(3, &line_two.replacen("002:", "003:", 1)).is_err());
+    }
+
+    #[test]
+    fn test_huge_byte_count_is_refused_without_overflow() {
+        let text = encode(&sample_key_file(), "hg-test");
+        let bytes = text.lines().find(|l| l.starts_with("Bytes:")).unwrap();
+        for len in [usize::MAX, usize::MAX - BYTES_PER_LINE + 2, usize::MAX / 2] {
+            let inflated = text.replacen(bytes, &format!("Bytes: {}", len), 1);
+            assert!(decode(&inflated).is_err(), "Bytes: {}", len);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Owner-only permissions for key material on disk
+// Key files, keystore directories and backups are created 0600/0700 on Unix;
+// the process umask can only narrow these further. Windows files inherit the
+// ACL of their directory, which this module does not change.
+
+use crate::error::Result;
+use crate::fsutil::WriteOptions;
+use crate::warning::{self, Warning, WarningCode, Warnings};
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Mode of key files and backups on Unix
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// Mode of keystore directories on Unix
+pub const PRIVATE_DIR_MODE: u32 = 0o700;
+
+/// Whether this platform can restrict key files to their owner
+pub const ENFORCED: bool = cfg!(unix);
+
+/// What loading does about a key file other users can access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoosePermissions {
+    /// Log a warning and carry on
+    #[default]
+    Warn,
+    /// Tighten the file to owner-only
+    Fix,
+}
+
+/// Create or truncate `path` for writing, readable by its owner only
+/// An existing file is tightened too, since `mode` only applies on creation
+pub fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(PRIVATE_FILE_MODE);
+    }
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(PRIVATE_FILE_MODE))?;
+    }
+    Ok(file)
+}
+
+/// Write `bytes` to a file readable by its owner only, fsynced
+pub fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write_private_with(path, bytes, &WriteOptions::critical())
+}
+
+/// Write `bytes` to a file readable by its owner only, finished as `options` ask
+pub fn write_private_with(path: &Path, bytes: &[u8], options: &WriteOptions) -> io::Result<()> {
+    let mut file = create_private(path)?;
+    file.write_all(bytes)?;
+    options.finish_file(&mut file, path)?;
+    options.finish_entry(path)
+}
+
+/// `write_private_with` through a sibling temp file renamed over `path`
+/// A reader sees the old file or the new one, never a partial write, so
+/// reading a key file needs no lock
+pub fn replace_private_with(path: &Path, bytes: &[u8], options: &WriteOptions) -> io::Result<()> {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let mut name = path.file_name().map(OsString::from).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file name", path.display()))
+    })?;
+    name.push(format!(".tmp-{}-{}", std::process::id(), SEQUENCE.fetch_add(1, Ordering::Relaxed)));
+    let temp = path.with_file_name(name);
+
+    let written = create_private(&temp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        options.finish_file(&mut file, path)
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    options.finish_entry(path)
+}
+
+/// Create `dir` and any missing parents; new directories are owner-only
+/// Directories that already exist keep their mode
+pub fn create_private_dir_all(dir: &Path) -> io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(PRIVATE_DIR_MODE);
+    }
+    builder.create(dir)
+}
+
+/// Permission bits of `path` when group or others can access it
+#[cfg(unix)]
+pub fn loose_mode(path: &Path) -> io::Result<Option<u32>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    Ok((mode & 0o077 != 0).then_some(mode))
+}
+
+/// Permission bits of `path` when group or others can access it
+#[cfg(not(unix))]
+pub fn loose_mode(_path: &Path) -> io::Result<Option<u32>> {
+    Ok(None)
+}
+
+/// Warn about or fix a key file that other users can access
+pub fn check_private(path: &Path, action: LoosePermissions) -> io::Result<()> {
+    check_private_with(path, action, None).map_err(io::Error::from)
+}
+
+/// `check_private`, raising the warning (HG004) in `warnings` instead of the log
+pub fn check_private_with(path: &Path, action: LoosePermissions, warnings: Option<&Warnings>) -> Result<()> {
+    let Some(mode) = loose_mode(path)? else {
+        return Ok(());
+    };
+    match action {
+        LoosePermissions::Warn => {
+            let message = format!(
+                "{} has mode {:o} and is readable by other users; run with --fix-permissions or chmod {:o} it",
+                path.display(),
+                mode,
+                PRIVATE_FILE_MODE
+            );
+            let warning = Warning::new(WarningCode::LoosePermissions, message)
+                .with_context("path", path.display())
+                .with_context("mode", format!("{:o}", mode));
+            warning::emit(warnings, warning)?;
+        }
+        LoosePermissions::Fix => {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(path, fs::Permissions::from_mode(PRIVATE_FILE_MODE))?;
+            }
+            log::info!("{}: permissions tightened from {:o} to {:o}", path.display(), mode, PRIVATE_FILE_MODE);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_created_owner_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = dir.path().join("keys").join("nested");
+        create_private_dir_all(&keystore).unwrap();
+        assert_eq!(mode(&keystore), PRIVATE_DIR_MODE);
+
+        let file = keystore.join("hybridguard.keys");
+        write_private(&file, b"secret").unwrap();
+        assert_eq!(mode(&file), PRIVATE_FILE_MODE);
+        assert_eq!(loose_mode(&file).unwrap(), None);
+    }
+
+    #[test]
+    fn test_overwrite_tightens_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("old.keys");
+        fs::write(&file, b"old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&file, b"new").unwrap();
+        assert_eq!(mode(&file), PRIVATE_FILE_MODE);
+        assert_eq!(fs::read(&file).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_replace_leaves_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("old.keys");
+        fs::write(&file, b"old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        replace_private_with(&file, b"new", &WriteOptions::critical()).unwrap();
+        assert_eq!(mode(&file), PRIVATE_FILE_MODE);
+        assert_eq!(fs::read(&file).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_loose_file_warned_then_fixed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("shared.keys");
+        fs::write(&file, b"secret").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(loose_mode(&file).unwrap(), Some(0o644));
+
+        // Warning leaves the file alone
+        check_private(&file, LoosePermissions::Warn).unwrap();
+        assert_eq!(mode(&file), 0o644);
+
+        check_private(&file, LoosePermissions::Fix).unwrap();
+        assert_eq!(mode(&file), PRIVATE_FILE_MODE);
+    }
+}
@@ -0,0 +1,470 @@
+// Protection of key files at rest
+// A protected key file stores the usual key file AES-256-GCM sealed under a
+// wrapping key. A protector derives that key from a random challenge kept in
+// the file header: the password protector hashes a password with it, and the
+// HMAC-file protector computes HMAC-SHA256 over it with a secret read from a
+// file, the same challenge-response a FIDO2 hmac-secret token performs. New
+// protectors only implement `KeyFileProtector`; the file layout is shared.
+// Under KdfScheme::V2 the protector's response is run through HKDF before it
+// keys AES; files without a `kdf` field used the response directly. A
+// protector with Argon2 parameters, which the password protector always has,
+// has its response stretched by Argon2id first under a fresh random salt. The
+// salt and cost are kept in the header as a `PassphraseKdf` for opening; they
+// are checked against the floor and caps before any hashing, and a file
+// re-saved without new parameters keeps them (`stretching`). Files sealed
+// before the salt was stored keep an `argon2` cost salted with the challenge.
+
+use crate::crypto::codec;
+use crate::crypto::container::le_u64;
+use crate::crypto::envelope::NONCE_LEN;
+use crate::crypto::hkdf::{self, KdfScheme, KeyPurpose};
+use crate::crypto::kdf::{KdfParams, PassphraseKdf, MIN_MEMORY_KIB, PASSPHRASE_SALT_LEN};
+use crate::crypto::nonce;
+use crate::crypto::secret::SecretBytes;
+use crate::error::{HybridGuardError, Result};
+use crate::legacy;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Bytes of the random challenge in each protected key file
+pub const CHALLENGE_LEN: usize = 32;
+
+/// Shortest secret the HMAC-file protector accepts
+pub const MIN_HMAC_SECRET_LEN: usize = 16;
+
+/// Domain label of password-derived wrapping keys
+const PASSWORD_LABEL: &[u8] = b"HybridGuard-key-file-password";
+
+/// Argon2id cost the password protector seals with unless given another: the
+/// OWASP minimum, as for passphrase-only containers
+pub const PASSWORD_STRETCHING: KdfParams = KdfParams { memory_kib: MIN_MEMORY_KIB, iterations: 2, parallelism: 1 };
+
+/// Which protector sealed a key file, recorded in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProtectorKind {
+    Password,
+    HmacFile,
+}
+
+impl fmt::Display for ProtectorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProtectorKind::Password => "password",
+            ProtectorKind::HmacFile => "hmac-file",
+        })
+    }
+}
+
+/// Source of the key that seals a key file at rest
+pub trait KeyFileProtector {
+    /// Recorded in the key file, so loading with another protector is refused
+    fn kind(&self) -> ProtectorKind;
+
+    /// Wrapping key for the key file whose header holds `challenge`
+    fn derive_wrapping_key(&self, challenge: &[u8]) -> Result<[u8; 32]>;
+
+    /// Argon2id parameters to stretch the wrapping key with when sealing
+    fn stretching(&self) -> Option<KdfParams> {
+        None
+    }
+}
+
+/// Wrapping key hashed from a password and the challenge, then stretched by
+/// Argon2id under `PASSWORD_STRETCHING` or the parameters from `key tune`
+pub struct PasswordProtector {
+    password: SecretBytes,
+    stretching: Option<KdfParams>,
+}
+
+impl PasswordProtector {
+    pub fn new(password: &str) -> Self {
+        Self { password: SecretBytes::new(password.as_bytes().to_vec()), stretching: None }
+    }
+
+    /// Seal with Argon2id under `params`; opening reads them from the file
+    pub fn with_stretching(mut self, params: KdfParams) -> Self {
+        self.stretching = Some(params);
+        self
+    }
+}
+
+impl KeyFileProtector for PasswordProtector {
+    fn kind(&self) -> ProtectorKind {
+        ProtectorKind::Password
+    }
+
+    fn derive_wrapping_key(&self, challenge: &[u8]) -> Result<[u8; 32]> {
+        use sha3::{Digest, Sha3_256};
+        let mut hasher = Sha3_256::new();
+        hasher.update(PASSWORD_LABEL);
+        hasher.update(le_u64(self.password.len() as u64));
+        hasher.update(&self.password[..]);
+        hasher.update(challenge);
+        Ok(hasher.finalize().into())
+    }
+
+    fn stretching(&self) -> Option<KdfParams> {
+        Some(self.stretching.unwrap_or(PASSWORD_STRETCHING))
+    }
+}
+
+/// Challenge-response with an HMAC-SHA256 secret kept in a file
+/// Stands in for a hardware token, so protected key files can be tested and
+/// used without one
+pub struct HmacFileProtector {
+    secret: SecretBytes,
+}
+
+impl HmacFileProtector {
+    pub fn new(secret: Vec<u8>) -> Result<Self> {
+        if secret.len() < MIN_HMAC_SECRET_LEN {
+            return Err(HybridGuardError::KeyGeneration(format!(
+                "HMAC secret is {} bytes; at least {} are needed",
+                secret.len(),
+                MIN_HMAC_SECRET_LEN
+            )));
+        }
+        Ok(Self { secret: SecretBytes::new(secret) })
+    }
+
+    /// Read the secret from `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::new(std::fs::read(path)?)
+    }
+}
+
+impl KeyFileProtector for HmacFileProtector {
+    fn kind(&self) -> ProtectorKind {
+        ProtectorKind::HmacFile
+    }
+
+    fn derive_wrapping_key(&self, challenge: &[u8]) -> Result<[u8; 32]> {
+        Ok(hmac_sha256(&self.secret, challenge))
+    }
+}
+
+/// HMAC-SHA256 (RFC 2104)
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+    const BLOCK: usize = 64;
+
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+
+    let inner = Sha256::new().chain_update(pad(0x36)).chain_update(message).finalize();
+    Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
+
+/// A protector as named on the command line: `password` or `hmac-file:<path>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtectorSpec {
+    Password,
+    HmacFile(PathBuf),
+}
+
+impl ProtectorSpec {
+    pub fn kind(&self) -> ProtectorKind {
+        match self {
+            ProtectorSpec::Password => ProtectorKind::Password,
+            ProtectorSpec::HmacFile(_) => ProtectorKind::HmacFile,
+        }
+    }
+}
+
+impl FromStr for ProtectorSpec {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "password" => Ok(ProtectorSpec::Password),
+            Some(("hmac-file", path)) if !path.is_empty() => Ok(ProtectorSpec::HmacFile(PathBuf::from(path))),
+            _ => Err(HybridGuardError::InvalidInput(format!(
+                "unknown protector '{}' (expected password or hmac-file:<path>)",
+                s
+            ))),
+        }
+    }
+}
+
+/// On-disk form of a protected key file
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProtectedKeyFile {
+    /// Also the associated data of the sealed keys
+    key_id: String,
+    protector: ProtectorKind,
+    #[serde(default)]
+    kdf: KdfScheme,
+    /// Salt and Argon2id cost the response is stretched with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    passphrase: Option<PassphraseKdf>,
+    /// Argon2id cost of files sealed before `passphrase`, salted with the challenge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    argon2: Option<KdfParams>,
+    challenge: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Protector a key file was sealed with; None for a plain key file
+pub fn protection(data: &[u8]) -> Option<ProtectorKind> {
+    serde_json::from_slice::<ProtectedKeyFile>(data).ok().map(|file| file.protector)
+}
+
+/// Argon2id parameters a protected key file was sealed with; None for a plain
+/// or unstretched key file
+pub fn stretching(data: &[u8]) -> Option<KdfParams> {
+    serde_json::from_slice::<ProtectedKeyFile>(data).ok().and_then(|file| file.passphrase.map(|kdf| kdf.params).or(file.argon2))
+}
+
+/// Seal the plain key file `keys` with a fresh challenge
+pub(crate) fn seal(keys: &[u8], key_id: &str, protector: &dyn KeyFileProtector) -> Result<Vec<u8>> {
+    let challenge = rand::random::<[u8; CHALLENGE_LEN]>();
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    // The wrapping key follows from the challenge
+    nonce::checkout(&challenge, "key-file-seal", &nonce)?;
+    let passphrase = match protector.stretching() {
+        Some(params) => {
+            params.validate()?;
+            let salt = rand::random::<[u8; PASSPHRASE_SALT_LEN]>();
+            nonce::checkout(&challenge, "key-file-salt", &salt)?;
+            Some(PassphraseKdf { salt, params })
+        }
+        None => None,
+    };
+    let stretching = passphrase.as_ref().map(|kdf| (&kdf.params, &kdf.salt[..]));
+    let ciphertext = cipher(KdfScheme::CURRENT, stretching, protector, &challenge)?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: keys, aad: key_id.as_bytes() })
+        .map_err(|_| HybridGuardError::Encryption("could not seal the key file".to_string()))?;
+
+    let file = ProtectedKeyFile {
+        key_id: key_id.to_string(),
+        protector: protector.kind(),
+        kdf: KdfScheme::CURRENT,
+        passphrase,
+        argon2: None,
+        challenge: codec::b64_std(&challenge),
+        nonce: codec::b64_std(&nonce),
+        ciphertext: codec::b64_std(&ciphertext),
+    };
+    serde_json::to_vec_pretty(&file).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))
+}
+
+/// Open a protected key file, returning the plain key file inside
+pub(crate) fn open(data: &[u8], protector: &dyn KeyFileProtector) -> Result<SecretBytes> {
+    let file: ProtectedKeyFile = serde_json::from_slice(data)
+        .map_err(|_| HybridGuardError::KeyMismatch(format!("key file is not protected, but --protector {} was given", protector.kind())))?;
+    if file.protector != protector.kind() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "key file is protected by the {} protector, not {}",
+            file.protector,
+            protector.kind()
+        )));
+    }
+
+    let field = |name: &str, value: &str| {
+        codec::b64_std_decode(value).map_err(|e| HybridGuardError::KeyGeneration(format!("protected key file: invalid {}: {}", name, e)))
+    };
+    let challenge = field("challenge", &file.challenge)?;
+    let nonce = field("nonce", &file.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(HybridGuardError::KeyGeneration("protected key file: invalid nonce".to_string()));
+    }
+    let ciphertext = field("ciphertext", &file.ciphertext)?;
+    let stretching = match (&file.passphrase, &file.argon2) {
+        (Some(kdf), _) => Some((&kdf.params, &kdf.salt[..])),
+        (None, Some(params)) => Some((params, &challenge[..])),
+        (None, None) => None,
+    };
+    // An edited header must not set the cost of the password attempt
+    if let Some((params, _)) = stretching {
+        params.validate()?;
+    }
+
+    let keys = cipher(file.kdf, stretching, protector, &challenge)?
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: file.key_id.as_bytes() })
+        .map_err(|_| match file.protector {
+            ProtectorKind::Password => HybridGuardError::InvalidPassword,
+            ProtectorKind::HmacFile => {
+                HybridGuardError::KeyMismatch("the HMAC secret does not unlock this key file".to_string())
+            }
+        })?;
+    Ok(SecretBytes::new(keys))
+}
+
+/// AES key of a key file; `stretching` is the Argon2id cost and its salt
+fn cipher(
+    scheme: KdfScheme,
+    stretching: Option<(&KdfParams, &[u8])>,
+    protector: &dyn KeyFileProtector,
+    challenge: &[u8],
+) -> Result<Aes256Gcm> {
+    let mut response = SecretBytes::new(protector.derive_wrapping_key(challenge)?.to_vec());
+    if let Some((params, salt)) = stretching {
+        response = SecretBytes::new(params.hash(&response, salt)?.to_vec());
+    }
+    legacy::require_kdf(scheme)?;
+    let key = match scheme {
+        KdfScheme::V1 => response,
+        KdfScheme::V2 => hkdf::derive(challenge, &response, KeyPurpose::KeyFileWrap, 32)?,
+    };
+    Aes256Gcm::new_from_slice(&key).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256_vectors() {
+        // RFC 4231 test cases 1 and 6 (key longer than a block)
+        let hex = |bytes: [u8; 32]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(
+            hex(hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_seal_open_with_each_protector() {
+        let protectors: [Box<dyn KeyFileProtector>; 2] =
+            [Box::new(PasswordProtector::new("correct horse")), Box::new(HmacFileProtector::new(vec![7; 32]).unwrap())];
+        for protector in &protectors {
+            let sealed = seal(b"{\"keys\": 1}", "hg-p", protector.as_ref()).unwrap();
+            assert_eq!(protection(&sealed), Some(protector.kind()));
+            assert_eq!(&open(&sealed, protector.as_ref()).unwrap()[..], b"{\"keys\": 1}");
+            // A fresh challenge every time
+            assert_ne!(seal(b"{\"keys\": 1}", "hg-p", protector.as_ref()).unwrap(), sealed);
+        }
+        assert_eq!(protection(b"{\"key_id\": \"hg-p\"}"), None);
+    }
+
+    #[test]
+    fn test_wrong_secret_and_mismatched_protector() {
+        let hmac = HmacFileProtector::new(vec![7; 32]).unwrap();
+        let sealed = seal(b"keys", "hg-p", &hmac).unwrap();
+
+        let other = HmacFileProtector::new(vec![8; 32]).unwrap();
+        assert!(matches!(open(&sealed, &other), Err(HybridGuardError::KeyMismatch(_))));
+        assert!(matches!(open(&sealed, &PasswordProtector::new("x")), Err(HybridGuardError::KeyMismatch(m)) if m.contains("hmac-file")));
+
+        let sealed = seal(b"keys", "hg-p", &PasswordProtector::new("right")).unwrap();
+        assert!(matches!(open(&sealed, &PasswordProtector::new("wrong")), Err(HybridGuardError::InvalidPassword)));
+        assert!(HmacFileProtector::new(vec![1; MIN_HMAC_SECRET_LEN - 1]).is_err());
+    }
+
+    #[cfg(feature = "legacy-kdf-v1")]
+    #[test]
+    fn test_file_without_kdf_opens_as_v1() {
+        let protector = PasswordProtector::new("correct horse");
+        let sealed = seal(b"keys", "hg-p", &protector).unwrap();
+        let mut file: serde_json::Value = serde_json::from_slice(&sealed).unwrap();
+        assert_eq!(file["kdf"], "v2");
+
+        // Re-seal under the response itself, as files were before `kdf`
+        let challenge = codec::b64_std_decode(file["challenge"].as_str().unwrap()).unwrap();
+        let nonce = codec::b64_std_decode(file["nonce"].as_str().unwrap()).unwrap();
+        let ciphertext = cipher(KdfScheme::V1, None, &protector, &challenge)
+            .unwrap()
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: b"keys".as_slice(), aad: b"hg-p" })
+            .unwrap();
+        file["ciphertext"] = codec::b64_std(&ciphertext).into();
+        file.as_object_mut().unwrap().remove("kdf");
+        file.as_object_mut().unwrap().remove("passphrase");
+        let legacy = serde_json::to_vec(&file).unwrap();
+        assert_eq!(&open(&legacy, &protector).unwrap()[..], b"keys");
+
+        // Claiming V2 for it fails to open
+        file["kdf"] = "v2".into();
+        let relabelled = serde_json::to_vec(&file).unwrap();
+        assert!(matches!(open(&relabelled, &protector), Err(HybridGuardError::InvalidPassword)));
+    }
+
+    #[test]
+    fn test_stretched_password_records_its_parameters() {
+        let params = KdfParams { memory_kib: crate::crypto::kdf::MIN_MEMORY_KIB, iterations: 1, parallelism: 1 };
+        let protector = PasswordProtector::new("correct horse").with_stretching(params);
+        let sealed = seal(b"keys", "hg-p", &protector).unwrap();
+        let mut file: serde_json::Value = serde_json::from_slice(&sealed).unwrap();
+        assert_eq!(file["passphrase"]["params"]["memory_kib"], params.memory_kib);
+
+        // Opening takes the parameters from the file, not the protector
+        assert_eq!(&open(&sealed, &PasswordProtector::new("correct horse")).unwrap()[..], b"keys");
+        let mut salted = file.clone();
+        salted["passphrase"]["salt"] = serde_json::json!([0u8; PASSPHRASE_SALT_LEN]);
+        assert!(matches!(open(&serde_json::to_vec(&salted).unwrap(), &protector), Err(HybridGuardError::InvalidPassword)));
+        file.as_object_mut().unwrap().remove("passphrase");
+        let unstretched = serde_json::to_vec(&file).unwrap();
+        assert!(matches!(open(&unstretched, &protector), Err(HybridGuardError::InvalidPassword)));
+
+        let weak = PasswordProtector::new("x").with_stretching(KdfParams { memory_kib: 1024, ..params });
+        assert!(seal(b"keys", "hg-p", &weak).is_err());
+        assert_eq!(stretching(&sealed), Some(params));
+    }
+
+    #[test]
+    fn test_password_is_always_stretched_under_a_fresh_salt() {
+        let protector = PasswordProtector::new("correct horse");
+        let seal_one = || serde_json::from_slice::<ProtectedKeyFile>(&seal(b"keys", "hg-p", &protector).unwrap()).unwrap();
+        let (first, second) = (seal_one(), seal_one());
+        let kdf = first.passphrase.unwrap();
+        assert_eq!(kdf.params, PASSWORD_STRETCHING);
+        assert_ne!(kdf.salt, second.passphrase.unwrap().salt);
+        assert!(first.argon2.is_none());
+
+        // Files from before the stored salt were stretched under the challenge
+        let mut file: serde_json::Value = serde_json::from_slice(&seal(b"keys", "hg-p", &protector).unwrap()).unwrap();
+        let challenge = codec::b64_std_decode(file["challenge"].as_str().unwrap()).unwrap();
+        let nonce = codec::b64_std_decode(file["nonce"].as_str().unwrap()).unwrap();
+        let ciphertext = cipher(KdfScheme::CURRENT, Some((&PASSWORD_STRETCHING, &challenge[..])), &protector, &challenge)
+            .unwrap()
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: b"keys".as_slice(), aad: b"hg-p" })
+            .unwrap();
+        file["ciphertext"] = codec::b64_std(&ciphertext).into();
+        file["argon2"] = serde_json::to_value(PASSWORD_STRETCHING).unwrap();
+        file.as_object_mut().unwrap().remove("passphrase");
+        let older = serde_json::to_vec(&file).unwrap();
+        assert_eq!(&open(&older, &protector).unwrap()[..], b"keys");
+        assert_eq!(stretching(&older), Some(PASSWORD_STRETCHING));
+    }
+
+    #[test]
+    fn test_stretching_out_of_bounds_is_refused_on_open() {
+        let params = KdfParams { memory_kib: crate::crypto::kdf::MIN_MEMORY_KIB, iterations: 1, parallelism: 1 };
+        let protector = PasswordProtector::new("correct horse").with_stretching(params);
+        let mut file: serde_json::Value = serde_json::from_slice(&seal(b"keys", "hg-p", &protector).unwrap()).unwrap();
+        for (field, value) in [("memory_kib", u64::from(u32::MAX)), ("iterations", 1 << 20), ("memory_kib", 8)] {
+            let mut edited = file.clone();
+            edited["passphrase"]["params"][field] = value.into();
+            let edited = serde_json::to_vec(&edited).unwrap();
+            assert!(matches!(open(&edited, &protector), Err(HybridGuardError::InvalidInput(_))), "{} = {}", field, value);
+        }
+        file["passphrase"]["params"]["iterations"] = 2.into();
+        assert!(matches!(open(&serde_json::to_vec(&file).unwrap(), &protector), Err(HybridGuardError::InvalidPassword)));
+    }
+
+    #[test]
+    fn test_spec_parsing() {
+        assert_eq!("password".parse::<ProtectorSpec>().unwrap(), ProtectorSpec::Password);
+        assert_eq!(
+            "hmac-file:/etc/hg/token".parse::<ProtectorSpec>().unwrap(),
+            ProtectorSpec::HmacFile(PathBuf::from("/etc/hg/token"))
+        );
+        assert!("hmac-file:".parse::<ProtectorSpec>().is_err());
+        assert!("fido2".parse::<ProtectorSpec>().is_err());
+    }
+}
@@ -0,0 +1,335 @@
+// Key file provenance: owner metadata and the key file's self-signature
+// Key files may name their owner (a name, an email and free-form labels), set
+// at keygen. Every key file this build writes is also self-signed: ML-DSA-65
+// over the envelope's format and version and the whole body, owner and
+// unknown fields included, with the public key embedded beside the body. The
+// keypair is derived from the layer keys through the seeded liboqs RNG, so it
+// is never stored and a resave of unchanged keys writes the same signature.
+// A check verifies the signature, then that the embedded public key is the
+// one the body's own layer keys give, so a body edited and re-signed under a
+// fresh keypair does not pass. The signature catches damage and edits by
+// tools that do not hold the keys; whoever can read the file holds the layer
+// keys, and with them the signing key, so it is no defence against them.
+
+use crate::crypto::codec;
+use crate::crypto::container::le_u64;
+use crate::crypto::drbg;
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys, LAYER_KEY_LEN};
+use crate::crypto::secret::SecretBytes;
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::{KeyManager, StoredKeys, KEY_FILE_FORMAT, KEY_FILE_VERSION};
+use crate::layers::oqs_support;
+use oqs::sig::{Algorithm, Sig};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::path::Path;
+
+/// Signature algorithm of key file self-signatures, as the file names it
+pub const SIGNATURE_ALGORITHM: &str = "ML-DSA-65";
+
+/// Domain separator ahead of every signed message
+const SIGNED_DOMAIN: &[u8] = b"HybridGuard-key-file-signature";
+
+/// Bytes of the public key digest kept in a fingerprint
+const FINGERPRINT_BYTES: usize = 16;
+
+/// Who a key file belongs to, as recorded at keygen
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyOwner {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+impl KeyOwner {
+    /// An owner from keygen's options, or none if every one is empty
+    /// Refuses blank or multi-line values and an email without an '@'
+    pub fn new(name: Option<String>, email: Option<String>, labels: Vec<String>) -> Result<Option<Self>> {
+        for (field, value) in [("name", &name), ("email", &email)] {
+            if let Some(value) = value {
+                check_value(field, value)?;
+            }
+        }
+        for label in &labels {
+            check_value("label", label)?;
+        }
+        if let Some(email) = &email {
+            if !email.contains('@') {
+                return Err(HybridGuardError::InvalidInput(format!("owner email '{}' has no '@'", email)));
+            }
+        }
+        if name.is_none() && email.is_none() && labels.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self { name, email, labels }))
+    }
+}
+
+fn check_value(field: &str, value: &str) -> Result<()> {
+    if value.trim().is_empty() {
+        return Err(HybridGuardError::InvalidInput(format!("owner {} is empty", field)));
+    }
+    if value.contains(char::is_control) {
+        return Err(HybridGuardError::InvalidInput(format!("owner {} '{}' holds control characters", field, value.escape_debug())));
+    }
+    Ok(())
+}
+
+impl fmt::Display for KeyOwner {
+    /// `Name <email> [label, label]`, leaving out what is not recorded
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(name) = &self.name {
+            parts.push(name.clone());
+        }
+        if let Some(email) = &self.email {
+            parts.push(format!("<{}>", email));
+        }
+        if !self.labels.is_empty() {
+            parts.push(format!("[{}]", self.labels.join(", ")));
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+/// A key file's self-signature, kept in the envelope beside the body
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelfSignature {
+    pub algorithm: String,
+    /// Standard base64
+    pub public_key: String,
+    /// Standard base64
+    pub signature: String,
+}
+
+/// Outcome of checking a key file's self-signature
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum SignatureStatus {
+    /// Signed by the keys the file holds; `fingerprint` names the signing key
+    Valid { fingerprint: String },
+    /// Written before key files were signed, or by hand
+    Missing,
+    Invalid { reason: String },
+}
+
+impl SignatureStatus {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, SignatureStatus::Valid { .. })
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        matches!(self, SignatureStatus::Invalid { .. })
+    }
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Valid { fingerprint } => write!(f, "valid ({} {})", SIGNATURE_ALGORITHM, fingerprint),
+            SignatureStatus::Missing => f.write_str("missing; written before key files were signed (a resave signs it)"),
+            SignatureStatus::Invalid { reason } => write!(f, "INVALID: {}", reason),
+        }
+    }
+}
+
+/// Sign `body`, a key file body as written, with the keypair `keys` give
+pub(crate) fn sign(keys: &LayerKeys, body: &Value) -> Result<SelfSignature> {
+    let sig = signer()?;
+    let (public_key, secret_key) = keypair(&sig, keys)?;
+    let message = signed_message(&public_key, body);
+    let secret_key = sig
+        .secret_key_from_bytes(secret_key.as_bytes())
+        .ok_or_else(|| HybridGuardError::KeyGeneration("signing key has the wrong length".to_string()))?;
+    // ML-DSA signing is hedged with fresh randomness; drawing it from the
+    // signing seed and the message keeps a resave byte for byte the same
+    let seed = keys.derive_key(KeyPurpose::KeyFileSigning);
+    let signature = drbg::with_seeded_oqs_rng(&seed, &Sha3_256::digest(&message), || sig.sign(&message, secret_key))
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("signing the key file failed: {}", e)))?;
+    Ok(SelfSignature {
+        algorithm: SIGNATURE_ALGORITHM.to_string(),
+        public_key: codec::b64_std(&public_key),
+        signature: codec::b64_std(&signature.into_vec()),
+    })
+}
+
+/// Check the self-signature of the plain key file `data`
+/// Files that are not version 2 key files are `Missing` a signature; their
+/// other faults are for `KeyManager::from_bytes` and the doctor to report
+pub fn check(data: &[u8]) -> SignatureStatus {
+    let Ok(Value::Object(mut file)) = serde_json::from_slice::<Value>(data) else {
+        return SignatureStatus::Missing;
+    };
+    if file.get("format").and_then(Value::as_str) != Some(KEY_FILE_FORMAT)
+        || file.get("version").and_then(Value::as_u64) != Some(u64::from(KEY_FILE_VERSION))
+    {
+        return SignatureStatus::Missing;
+    }
+    let signature = match file.remove("signature") {
+        None | Some(Value::Null) => return SignatureStatus::Missing,
+        Some(value) => match SelfSignature::deserialize(value) {
+            Ok(signature) => signature,
+            Err(e) => return invalid(format!("signature record is malformed: {}", e)),
+        },
+    };
+    let Some(body) = file.remove("body") else {
+        return invalid("the envelope has no body".to_string());
+    };
+    match verify(&signature, &body) {
+        Ok(status) => status,
+        // liboqs itself failing says nothing about the file
+        Err(e) => invalid(format!("cannot be checked: {}", e)),
+    }
+}
+
+fn verify(signature: &SelfSignature, body: &Value) -> Result<SignatureStatus> {
+    if signature.algorithm != SIGNATURE_ALGORITHM {
+        return Ok(invalid(format!("algorithm {} is not {}", signature.algorithm, SIGNATURE_ALGORITHM)));
+    }
+    let (Ok(public_key), Ok(signature_bytes)) = (codec::b64_std_decode(&signature.public_key), codec::b64_std_decode(&signature.signature)) else {
+        return Ok(invalid("public key or signature is not base64".to_string()));
+    };
+    let sig = signer()?;
+    let (Some(public_key_ref), Some(signature_ref)) = (sig.public_key_from_bytes(&public_key), sig.signature_from_bytes(&signature_bytes)) else {
+        return Ok(invalid("public key or signature has the wrong length".to_string()));
+    };
+    if sig.verify(&signed_message(&public_key, body), signature_ref, public_key_ref).is_err() {
+        return Ok(invalid("the body or metadata changed after the file was signed".to_string()));
+    }
+
+    // A valid signature by some other keypair proves nothing about these keys
+    let Some(keys) = serde_json::from_value::<StoredKeys>(body.clone()).ok().and_then(stored_layer_keys) else {
+        return Ok(invalid("the body's layer keys are damaged, so the signing key cannot be checked".to_string()));
+    };
+    let (derived, _) = keypair(&sig, &keys)?;
+    if derived != public_key {
+        return Ok(invalid("signed by a key the file's layer keys do not give".to_string()));
+    }
+    Ok(SignatureStatus::Valid { fingerprint: fingerprint(&public_key) })
+}
+
+/// Warn, through the log, that the key file at `path` is unsigned or fails
+/// its self-signature check
+pub fn warn_if_unsigned(path: &Path, key_manager: &KeyManager) {
+    match key_manager.self_signature() {
+        Some(SignatureStatus::Invalid { reason }) => {
+            log::warn!("{}: key file self-signature is invalid: {}; run `hybridguard key doctor`", path.display(), reason);
+        }
+        Some(SignatureStatus::Missing) => {
+            log::warn!("{}: key file is not self-signed; saving it again adds a signature", path.display());
+        }
+        Some(SignatureStatus::Valid { .. }) | None => {}
+    }
+}
+
+/// Short name of a signing public key: `hgsig-` and 32 hex digits of its SHA3-256
+pub fn fingerprint(public_key: &[u8]) -> String {
+    format!("hgsig-{}", codec::hex_lower(&Sha3_256::digest(public_key)[..FINGERPRINT_BYTES]))
+}
+
+fn invalid(reason: String) -> SignatureStatus {
+    SignatureStatus::Invalid { reason }
+}
+
+fn signer() -> Result<Sig> {
+    oqs_support::sig("key file signature", Algorithm::MlDsa65)
+}
+
+/// The signing keypair `keys` give, the secret half kept as secret bytes
+fn keypair(sig: &Sig, keys: &LayerKeys) -> Result<(Vec<u8>, SecretBytes)> {
+    let seed = keys.derive_key(KeyPurpose::KeyFileSigning);
+    let (public_key, secret_key) = drbg::with_seeded_oqs_rng(&seed, b"key-file-signing-keypair", || sig.keypair())
+        .map_err(|e| HybridGuardError::KeyGeneration(format!("deriving the key file signing key failed: {}", e)))?;
+    Ok((public_key.into_vec(), SecretBytes::new(secret_key.into_vec())))
+}
+
+fn stored_layer_keys(stored: StoredKeys) -> Option<LayerKeys> {
+    let layer_keys = [&stored.layer1_key, &stored.layer2_key, &stored.layer3_key, &stored.layer4_key];
+    if layer_keys.iter().any(|key| key.len() != LAYER_KEY_LEN) {
+        return None;
+    }
+    Some(LayerKeys {
+        layer1_key: SecretBytes::new(stored.layer1_key),
+        layer2_key: SecretBytes::new(stored.layer2_key),
+        layer3_key: SecretBytes::new(stored.layer3_key),
+        layer4_key: SecretBytes::new(stored.layer4_key),
+        scheme: stored.kdf,
+    })
+}
+
+/// What is signed: the envelope's format and version, the algorithm and
+/// public key, then the body as canonical JSON, each part length-prefixed
+fn signed_message(public_key: &[u8], body: &Value) -> Vec<u8> {
+    let version = KEY_FILE_VERSION.to_string();
+    let body = canonical_json(body);
+    let mut message = SIGNED_DOMAIN.to_vec();
+    for part in [KEY_FILE_FORMAT.as_bytes(), version.as_bytes(), SIGNATURE_ALGORITHM.as_bytes(), public_key, body.as_bytes()] {
+        message.extend_from_slice(&le_u64(part.len() as u64));
+        message.extend_from_slice(part);
+    }
+    message
+}
+
+/// `value` serialized compactly with every object's keys sorted, so the
+/// signature does not depend on field order or whitespace
+fn canonical_json(value: &Value) -> String {
+    fn sorted(value: &Value) -> Value {
+        match value {
+            Value::Object(object) => {
+                let mut entries: Vec<(&String, &Value)> = object.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                Value::Object(entries.into_iter().map(|(key, value)| (key.clone(), sorted(value))).collect::<Map<_, _>>())
+            }
+            Value::Array(items) => Value::Array(items.iter().map(sorted).collect()),
+            other => other.clone(),
+        }
+    }
+    sorted(value).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner_validation() {
+        assert_eq!(KeyOwner::new(None, None, Vec::new()).unwrap(), None);
+        let owner = KeyOwner::new(Some("Ada".to_string()), Some("ada@example.com".to_string()), vec!["prod".to_string(), "eu".to_string()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(owner.to_string(), "Ada <ada@example.com> [prod, eu]");
+        assert!(KeyOwner::new(None, Some("ada.example.com".to_string()), Vec::new()).is_err());
+        assert!(KeyOwner::new(Some(" ".to_string()), None, Vec::new()).is_err());
+        assert!(KeyOwner::new(None, None, vec!["a\nb".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_canonical_json_ignores_field_order() {
+        let a: Value = serde_json::from_str(r#"{"b": [1, {"y": 2, "x": 1}], "a": "z"}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"a":"z","b":[1,{"x":1,"y":2}]}"#).unwrap();
+        assert_eq!(canonical_json(&a), canonical_json(&b));
+        assert_eq!(canonical_json(&a), r#"{"a":"z","b":[1,{"x":1,"y":2}]}"#);
+    }
+
+    #[test]
+    fn test_signature_is_deterministic_and_bound_to_the_keys() {
+        let km = KeyManager::from_master_key(&[0x4D; 32]).unwrap();
+        let body = serde_json::json!({ "key_id": km.key_id() });
+        let signature = sign(km.get_keys().unwrap(), &body).unwrap();
+        assert_eq!(sign(km.get_keys().unwrap(), &body).unwrap(), signature);
+
+        let other = KeyManager::from_master_key(&[0x4E; 32]).unwrap();
+        assert_ne!(sign(other.get_keys().unwrap(), &body).unwrap().public_key, signature.public_key);
+    }
+
+    #[test]
+    fn test_unsigned_and_foreign_data_is_missing() {
+        assert_eq!(check(b"not json"), SignatureStatus::Missing);
+        assert_eq!(check(br#"{"key_id": "hg-legacy", "layer1_key": []}"#), SignatureStatus::Missing);
+    }
+}
@@ -0,0 +1,354 @@
+// Detached signatures over finished files (hybridguard sign / check-sig)
+//
+// Partners who receive encrypted files may only need to know who made them
+// and that nothing changed on the way; they hold no decryption keys. A key
+// file may carry an ML-DSA-65 (Dilithium) signing keypair beside its layer
+// keys. Unlike the self-signature's keypair it is random rather than derived
+// from the layer keys, so its public half, a signer key, can be handed out.
+// A detached signature covers a manifest of the file (its name, size and
+// SHA3-256) and when it was made, so a renamed, truncated or edited file
+// fails the check as surely as a forged signature does. Checking needs the
+// signer key alone; nothing is decrypted.
+//
+// Signature files use magic "HGDS" and signer keys "HGSK", each followed by
+// a u16 format version and a bincode body, as escrow files do. Both are
+// written through a staged file, so a failed write leaves no partial one.
+
+use crate::crypto::codec;
+use crate::crypto::container::{le_u16, le_u64, u16_at};
+use crate::crypto::secret::SecretBytes;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::provenance::{self, SIGNATURE_ALGORITHM};
+use crate::layers::oqs_support;
+use oqs::sig::{Algorithm, Sig};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use crate::staging::{Contents, StagedFile};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Magic bytes of a detached signature file
+const SIGNATURE_MAGIC: [u8; 4] = *b"HGDS";
+
+/// Magic bytes of a signer key file
+const SIGNER_MAGIC: [u8; 4] = *b"HGSK";
+
+/// Format of signature and signer key files written by this build
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Largest signature or signer key file accepted
+const MAX_LEN: usize = 64 * 1024;
+
+/// Domain separator ahead of every signed message
+const SIGNED_DOMAIN: &[u8] = b"HybridGuard-detached-signature";
+
+/// Extension `sign` appends to the signed file's name by default
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// What a detached signature says about its file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedManifest {
+    /// Final component of the signed path
+    pub file_name: String,
+    pub size: u64,
+    pub sha3_256: [u8; 32],
+}
+
+impl SignedManifest {
+    /// Name, size and digest of the file at `path`, read once front to back
+    pub fn of(path: &Path) -> Result<Self> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| HybridGuardError::InvalidInput(format!("{} names no file", path.display())))?;
+        let mut file = File::open(path)?;
+        let mut hasher = Sha3_256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size = 0u64;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+        let mut sha3_256 = [0u8; 32];
+        sha3_256.copy_from_slice(&hasher.finalize());
+        Ok(Self { file_name, size, sha3_256 })
+    }
+}
+
+/// Public half of a signing keypair, handed to whoever checks signatures
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignerKey {
+    public_key: Vec<u8>,
+}
+
+/// A key file's signing keypair
+pub struct SigningKey {
+    public: SignerKey,
+    secret_key: SecretBytes,
+}
+
+/// How a key file stores its signing keypair
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StoredSigningKey {
+    algorithm: String,
+    /// Standard base64
+    public_key: String,
+    /// Standard base64
+    secret_key: String,
+}
+
+/// A signature over one file's manifest, kept beside the file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DetachedSignature {
+    /// Fingerprint of the signer key that made it
+    pub signer: String,
+    /// When it was made, as RFC 3339
+    pub signed_at: String,
+    pub manifest: SignedManifest,
+    signature: Vec<u8>,
+}
+
+impl SignerKey {
+    /// `hgsig-` and 32 hex digits of the public key's SHA3-256
+    pub fn fingerprint(&self) -> String {
+        provenance::fingerprint(&self.public_key)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&fs::read(path.as_ref())?)
+    }
+
+    /// Write the signer key to `path`, finishing the file as `options` ask
+    pub fn save_with(&self, path: &Path, options: &WriteOptions) -> Result<()> {
+        save_staged(path, &self.to_bytes()?, options)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(SIGNER_MAGIC, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        decode(SIGNER_MAGIC, "signer key", bytes)
+    }
+}
+
+impl SigningKey {
+    /// Generate a new random signing keypair
+    pub fn generate() -> Result<Self> {
+        let (public_key, secret_key) = ml_dsa()?
+            .keypair()
+            .map_err(|e| HybridGuardError::KeyGeneration(format!("generating the signing keypair failed: {}", e)))?;
+        Ok(Self {
+            public: SignerKey { public_key: public_key.into_vec() },
+            secret_key: SecretBytes::new(secret_key.into_vec()),
+        })
+    }
+
+    pub fn public(&self) -> &SignerKey {
+        &self.public
+    }
+
+    /// Sign the manifest of the file at `path`
+    pub fn sign_detached(&self, path: &Path) -> Result<DetachedSignature> {
+        let sig = ml_dsa()?;
+        let manifest = SignedManifest::of(path)?;
+        let signed_at = chrono::Utc::now().to_rfc3339();
+        let secret_key = sig
+            .secret_key_from_bytes(self.secret_key.as_bytes())
+            .ok_or_else(|| HybridGuardError::KeyGeneration("signing key has the wrong length".to_string()))?;
+        let signature = sig
+            .sign(&signed_message(&self.public.public_key, &signed_at, &manifest), secret_key)
+            .map_err(|e| HybridGuardError::KeyGeneration(format!("signing {} failed: {}", path.display(), e)))?;
+        Ok(DetachedSignature {
+            signer: self.public.fingerprint(),
+            signed_at,
+            manifest,
+            signature: signature.into_vec(),
+        })
+    }
+
+    pub(crate) fn to_stored(&self) -> StoredSigningKey {
+        StoredSigningKey {
+            algorithm: SIGNATURE_ALGORITHM.to_string(),
+            public_key: codec::b64_std(&self.public.public_key),
+            secret_key: codec::b64_std(self.secret_key.as_bytes()),
+        }
+    }
+
+    /// The keypair as a key file stores it; the loader reports a fault as a damaged key file
+    pub(crate) fn from_stored(stored: &StoredSigningKey) -> Result<Self> {
+        if stored.algorithm != SIGNATURE_ALGORITHM {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "signing key algorithm {} is not {}",
+                stored.algorithm, SIGNATURE_ALGORITHM
+            )));
+        }
+        let (Ok(public_key), Ok(secret_key)) = (codec::b64_std_decode(&stored.public_key), codec::b64_std_decode(&stored.secret_key)) else {
+            return Err(HybridGuardError::InvalidInput("signing key is not base64".to_string()));
+        };
+        Ok(Self { public: SignerKey { public_key }, secret_key: SecretBytes::new(secret_key) })
+    }
+}
+
+impl DetachedSignature {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&fs::read(path.as_ref())?)
+    }
+
+    /// Write the signature to `path`, finishing the file as `options` ask
+    pub fn save_with(&self, path: &Path, options: &WriteOptions) -> Result<()> {
+        save_staged(path, &self.to_bytes()?, options)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        encode(SIGNATURE_MAGIC, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        decode(SIGNATURE_MAGIC, "detached signature", bytes)
+    }
+}
+
+/// Check `signature` against the file at `path` with `signer` alone
+/// Fails with `KeyMismatch` when another key made the signature, and with
+/// `Integrity` when the signature does not verify or the file's name, size
+/// or digest differs from the signed manifest; returns that manifest
+pub fn verify_detached(path: &Path, signature: &DetachedSignature, signer: &SignerKey) -> Result<SignedManifest> {
+    if signature.signer != signer.fingerprint() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "signed by {}, but the signer key given is {}",
+            signature.signer,
+            signer.fingerprint()
+        )));
+    }
+    let sig = ml_dsa()?;
+    let (Some(public_key), Some(signature_ref)) = (sig.public_key_from_bytes(&signer.public_key), sig.signature_from_bytes(&signature.signature)) else {
+        return Err(HybridGuardError::Integrity("signer key or signature has the wrong length".to_string()));
+    };
+    let message = signed_message(&signer.public_key, &signature.signed_at, &signature.manifest);
+    if sig.verify(&message, signature_ref, public_key).is_err() {
+        return Err(HybridGuardError::Integrity("the signature does not verify; it was modified or not made by this signer".to_string()));
+    }
+
+    let signed = &signature.manifest;
+    let actual = SignedManifest::of(path)?;
+    if actual.file_name != signed.file_name {
+        return Err(HybridGuardError::Integrity(format!(
+            "{} was signed as '{}'; a renamed file does not match its signature",
+            path.display(),
+            signed.file_name
+        )));
+    }
+    if actual.size != signed.size || actual.sha3_256 != signed.sha3_256 {
+        return Err(HybridGuardError::Integrity(format!(
+            "{} changed after it was signed ({} bytes signed, {} now)",
+            path.display(),
+            signed.size,
+            actual.size
+        )));
+    }
+    Ok(actual)
+}
+
+fn ml_dsa() -> Result<Sig> {
+    oqs_support::sig("detached signature", Algorithm::MlDsa65)
+}
+
+/// What is signed: the algorithm, the public key, the time and the
+/// manifest's fields, each length-prefixed
+fn signed_message(public_key: &[u8], signed_at: &str, manifest: &SignedManifest) -> Vec<u8> {
+    let size = le_u64(manifest.size);
+    let mut message = SIGNED_DOMAIN.to_vec();
+    for part in [
+        SIGNATURE_ALGORITHM.as_bytes(),
+        public_key,
+        signed_at.as_bytes(),
+        manifest.file_name.as_bytes(),
+        &size,
+        &manifest.sha3_256,
+    ] {
+        message.extend_from_slice(&le_u64(part.len() as u64));
+        message.extend_from_slice(part);
+    }
+    message
+}
+
+fn save_staged(path: &Path, bytes: &[u8], options: &WriteOptions) -> Result<()> {
+    let mut staged = StagedFile::create(path, None, Contents::Plaintext)?.with_write_options(options.clone());
+    staged.file().write_all(bytes)?;
+    staged.commit()?;
+    Ok(())
+}
+
+fn encode<T: Serialize>(magic: [u8; 4], message: &T) -> Result<Vec<u8>> {
+    let mut bytes = magic.to_vec();
+    bytes.extend_from_slice(&le_u16(FORMAT_VERSION));
+    bytes.extend(bincode::serialize(message).map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?);
+    Ok(bytes)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(magic: [u8; 4], what: &str, bytes: &[u8]) -> Result<T> {
+    if bytes.len() > MAX_LEN || !bytes.starts_with(&magic) || bytes.len() < 6 {
+        return Err(HybridGuardError::UnsupportedFormat(format!("not a HybridGuard {}", what)));
+    }
+    let version = u16_at(bytes, 4);
+    if version != FORMAT_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "{} v{} is not supported (this build reads v{})",
+            what, version, FORMAT_VERSION
+        )));
+    }
+    bincode::deserialize(&bytes[6..]).map_err(|e| HybridGuardError::Integrity(format!("unreadable {}: {}", what, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_key_round_trip() {
+        let key = SigningKey::generate().unwrap();
+        let stored = key.to_stored();
+        let restored = SigningKey::from_stored(&stored).unwrap();
+        assert_eq!(restored.public(), key.public());
+        assert_eq!(restored.secret_key.to_vec(), key.secret_key.to_vec());
+
+        let wrong = StoredSigningKey { algorithm: "ML-DSA-44".to_string(), ..stored };
+        assert!(SigningKey::from_stored(&wrong).is_err());
+    }
+
+    #[test]
+    fn test_signature_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.hg");
+        fs::write(&path, b"ciphertext").unwrap();
+        let key = SigningKey::generate().unwrap();
+        let signature = DetachedSignature::from_bytes(&key.sign_detached(&path).unwrap().to_bytes().unwrap()).unwrap();
+        let signer = SignerKey::from_bytes(&key.public().to_bytes().unwrap()).unwrap();
+        assert_eq!(signature.manifest.size, 10);
+        assert_eq!(verify_detached(&path, &signature, &signer).unwrap().file_name, "ledger.hg");
+
+        // Each kind of file is refused as the other
+        assert!(matches!(SignerKey::from_bytes(&signature.to_bytes().unwrap()), Err(HybridGuardError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn test_edited_manifest_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.hg");
+        fs::write(&path, b"ciphertext").unwrap();
+        let key = SigningKey::generate().unwrap();
+        let mut signature = key.sign_detached(&path).unwrap();
+
+        // Re-pointing the manifest at other content breaks the signature itself
+        fs::write(&path, b"Ciphertext").unwrap();
+        signature.manifest = SignedManifest::of(&path).unwrap();
+        assert!(matches!(verify_detached(&path, &signature, key.public()), Err(HybridGuardError::Integrity(_))));
+    }
+}
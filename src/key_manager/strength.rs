@@ -0,0 +1,386 @@
+// Password strength estimation and organizational password policy
+// A small zxcvbn-style estimator: the password is covered by the cheapest
+// sequence of patterns (common passwords, sequences, repeats, keyboard runs,
+// single characters) and the guesses of that cover give the score
+
+use crate::error::{HybridGuardError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+/// Most common passwords and words, most common first; matched case-insensitively
+/// after undoing l33t substitutions, so the rank approximates an attacker's guess order
+const COMMON: &[&str] = &[
+    "password", "123456", "qwerty", "letmein", "welcome", "admin", "monkey", "dragon", "iloveyou", "sunshine",
+    "princess", "football", "baseball", "master", "shadow", "superman", "batman", "trustno1", "hello", "freedom",
+    "whatever", "michael", "jennifer", "hunter", "charlie", "secret", "summer", "winter", "spring", "autumn",
+    "love", "login", "passw0rd", "starwars", "cookie", "pepper", "ginger", "soccer", "hockey", "killer",
+    "matrix", "access", "flower", "orange", "banana", "purple", "silver", "golden", "computer", "internet",
+    "mustang", "thomas", "jordan", "harley", "ranger", "tigger", "buster", "daniel", "andrew", "joshua",
+    "qazwsx", "zaq1", "abc123", "changeme", "default", "guest", "root", "test", "user", "pass",
+    "company", "office", "london", "paris", "berlin", "google", "apple", "microsoft", "quantum", "hybridguard",
+];
+
+/// Keyboard rows and the digit row, for runs like "qwerty" or "asdf"
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+
+/// Offline guesses per second against the single SHA3-256 password hash
+const GUESSES_PER_SECOND: f64 = 1e10;
+
+/// How long an offline attacker needs, in coarse classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum CrackTime {
+    Instant,
+    Minutes,
+    Hours,
+    Days,
+    Years,
+    Centuries,
+}
+
+impl CrackTime {
+    fn from_seconds(seconds: f64) -> Self {
+        const HOUR: f64 = 3600.0;
+        const DAY: f64 = 24.0 * HOUR;
+        const YEAR: f64 = 365.0 * DAY;
+        if seconds < 60.0 {
+            CrackTime::Instant
+        } else if seconds < HOUR {
+            CrackTime::Minutes
+        } else if seconds < DAY {
+            CrackTime::Hours
+        } else if seconds < YEAR {
+            CrackTime::Days
+        } else if seconds < 100.0 * YEAR {
+            CrackTime::Years
+        } else {
+            CrackTime::Centuries
+        }
+    }
+}
+
+impl fmt::Display for CrackTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrackTime::Instant => write!(f, "under a minute"),
+            CrackTime::Minutes => write!(f, "minutes"),
+            CrackTime::Hours => write!(f, "hours"),
+            CrackTime::Days => write!(f, "days to months"),
+            CrackTime::Years => write!(f, "years"),
+            CrackTime::Centuries => write!(f, "centuries"),
+        }
+    }
+}
+
+/// Estimated strength of a password
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PasswordStrength {
+    /// 0 (trivial) to 4 (strong), on the zxcvbn scale
+    pub score: u8,
+
+    /// Estimated guesses as a power of two
+    pub guesses_log2: f64,
+
+    /// Offline cracking time against the password hash
+    pub crack_time: CrackTime,
+
+    /// Hints about the patterns that made the password guessable
+    pub feedback: Vec<String>,
+}
+
+impl fmt::Display for PasswordStrength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "score {}/4, cracked offline in {}", self.score, self.crack_time)
+    }
+}
+
+/// A pattern covering part of the password
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Common,
+    Sequence,
+    Repeat,
+    Keyboard,
+    Character,
+}
+
+impl Pattern {
+    fn feedback(self) -> Option<&'static str> {
+        match self {
+            Pattern::Common => Some("contains a common password or word"),
+            Pattern::Sequence => Some("sequences like abc or 123 are easy to guess"),
+            Pattern::Repeat => Some("repeated characters are easy to guess"),
+            Pattern::Keyboard => Some("keyboard runs like qwerty are easy to guess"),
+            Pattern::Character => None,
+        }
+    }
+}
+
+/// Estimate how hard `password` is to guess
+pub fn evaluate_password(password: &str) -> PasswordStrength {
+    let chars: Vec<char> = password.chars().collect();
+    let n = chars.len();
+
+    // best[j]: cheapest cover of chars[..j] in bits, with the pattern ending there
+    let mut best: Vec<(f64, usize, Pattern)> = vec![(0.0, 0, Pattern::Character); n + 1];
+    for j in 1..=n {
+        best[j] = (best[j - 1].0 + character_bits(chars[j - 1]), j - 1, Pattern::Character);
+        for i in 0..j {
+            if let Some((bits, pattern)) = pattern_bits(&chars[i..j]) {
+                if best[i].0 + bits < best[j].0 {
+                    best[j] = (best[i].0 + bits, i, pattern);
+                }
+            }
+        }
+    }
+
+    let mut feedback = Vec::new();
+    let mut j = n;
+    while j > 0 {
+        let (_, i, pattern) = best[j];
+        if let Some(hint) = pattern.feedback() {
+            if !feedback.iter().any(|f| f == hint) {
+                feedback.push(hint.to_string());
+            }
+        }
+        j = i;
+    }
+    if n < 12 {
+        feedback.push("use at least 12 characters, or several unrelated words".to_string());
+    }
+
+    let guesses_log2 = best[n].0;
+    let guesses_log10 = guesses_log2 * std::f64::consts::LOG10_2;
+    let score = match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    };
+    let seconds = 2f64.powf(guesses_log2) / GUESSES_PER_SECOND;
+
+    PasswordStrength {
+        score,
+        guesses_log2,
+        crack_time: CrackTime::from_seconds(seconds),
+        feedback,
+    }
+}
+
+/// Bits to brute-force one character of its class
+fn character_bits(c: char) -> f64 {
+    let cardinality: f64 = if c.is_ascii_digit() {
+        10.0
+    } else if c.is_ascii_alphabetic() {
+        26.0
+    } else if c.is_ascii() {
+        33.0
+    } else {
+        100.0
+    };
+    cardinality.log2()
+}
+
+/// Cheapest pattern matching all of `slice`, in bits
+fn pattern_bits(slice: &[char]) -> Option<(f64, Pattern)> {
+    let len = slice.len();
+    if len < 3 {
+        return None;
+    }
+    let lower: String = slice.iter().flat_map(|c| c.to_lowercase()).collect();
+    let mut candidates = Vec::new();
+
+    // Common passwords, with a bit per capital and per l33t substitution
+    let capitals = slice.iter().filter(|c| c.is_uppercase()).count() as f64;
+    let (unleeted, substitutions) = unleet(&lower);
+    for (word, extra_bits) in [(&lower, 0.0), (&unleeted, substitutions as f64)] {
+        if let Some(rank) = COMMON.iter().position(|common| *common == word.as_str()) {
+            candidates.push((((rank + 1) as f64).log2() + capitals + extra_bits, Pattern::Common));
+        }
+    }
+
+    // One character repeated
+    if slice.iter().all(|c| *c == slice[0]) {
+        candidates.push(((character_bits(slice[0]).exp2() * len as f64).log2(), Pattern::Repeat));
+    }
+
+    // Constant-step runs like abc, 987 or aceg
+    let step = slice[1] as i64 - slice[0] as i64;
+    if (1..=2).contains(&step.abs()) && slice.windows(2).all(|w| w[1] as i64 - w[0] as i64 == step) {
+        let direction = if step < 0 { 2.0 } else { 1.0 };
+        candidates.push(((character_bits(slice[0]).exp2() * len as f64 * direction).log2(), Pattern::Sequence));
+    }
+
+    // Runs along a keyboard row, either direction
+    let reversed: String = lower.chars().rev().collect();
+    if len >= 4 && KEYBOARD_ROWS.iter().any(|row| row.contains(&lower) || row.contains(&reversed)) {
+        candidates.push(((KEYBOARD_ROWS.len() as f64 * 10.0 * len as f64 * 2.0).log2(), Pattern::Keyboard));
+    }
+
+    candidates.into_iter().min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+/// Undo common l33t substitutions, counting how many were made
+fn unleet(lower: &str) -> (String, usize) {
+    let mut substitutions = 0;
+    let plain = lower
+        .chars()
+        .map(|c| {
+            let plain = match c {
+                '0' => 'o',
+                '1' | '!' => 'i',
+                '3' => 'e',
+                '4' | '@' => 'a',
+                '5' | '$' => 's',
+                '7' => 't',
+                other => other,
+            };
+            if plain != c {
+                substitutions += 1;
+            }
+            plain
+        })
+        .collect();
+    (plain, substitutions)
+}
+
+/// Rules a new password must meet, e.g. from an organization's policy file
+/// Length and denylist are mandatory; only the score can be waived
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+
+    /// Lowest acceptable score, 0 to 4
+    pub min_score: u8,
+
+    /// Passwords refused outright, compared case-insensitively
+    pub denylist: Vec<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 0,
+            min_score: 3,
+            denylist: Vec::new(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Load a JSON policy file; missing fields keep their defaults
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)?;
+        let policy: Self = serde_json::from_str(&json)
+            .map_err(|e| HybridGuardError::InvalidInput(format!("{}: invalid password policy: {}", path.display(), e)))?;
+        policy.with_min_score(policy.min_score)
+    }
+
+    /// The same policy with another score threshold
+    pub fn with_min_score(mut self, min_score: u8) -> Result<Self> {
+        if min_score > 4 {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "password score threshold must be 0 to 4, got {}",
+                min_score
+            )));
+        }
+        self.min_score = min_score;
+        Ok(self)
+    }
+
+    /// Evaluate `password` and refuse it if it breaks the policy
+    /// `allow_weak` waives the score threshold but not length or denylist
+    pub fn check(&self, password: &str, allow_weak: bool) -> Result<PasswordStrength> {
+        let lower = password.to_lowercase();
+        if self.denylist.iter().any(|denied| denied.to_lowercase() == lower) {
+            return Err(HybridGuardError::PolicyViolation(
+                "password is on the organization's denylist".to_string(),
+            ));
+        }
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(HybridGuardError::PolicyViolation(format!(
+                "password has {} characters; the policy requires at least {}",
+                length, self.min_length
+            )));
+        }
+        let strength = evaluate_password(password);
+        if strength.score < self.min_score && !allow_weak {
+            let mut message = format!(
+                "password is too weak ({}; at least {} required)",
+                strength, self.min_score
+            );
+            for hint in &strength.feedback {
+                message.push_str("; ");
+                message.push_str(hint);
+            }
+            return Err(HybridGuardError::PolicyViolation(message));
+        }
+        Ok(strength)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weak_passwords_score_low() {
+        for weak in ["", "password", "password123", "Passw0rd", "qwerty123", "aaaaaaaaaaaa", "abcdefgh", "P@ssw0rd!"] {
+            let strength = evaluate_password(weak);
+            assert!(strength.score <= 1, "{:?} scored {}", weak, strength.score);
+            assert_eq!(strength.crack_time, CrackTime::Instant, "{:?}", weak);
+        }
+        assert!(evaluate_password("password123").feedback.iter().any(|f| f.contains("common")));
+        assert!(evaluate_password("zzzzzzzzzzzz").feedback.iter().any(|f| f.contains("repeated")));
+    }
+
+    #[test]
+    fn test_strong_passwords_score_high() {
+        for strong in ["correct horse battery staple", "vT8#qLm2!xR9@wZp", "plinth-Ocelot-47-marmalade"] {
+            let strength = evaluate_password(strong);
+            assert_eq!(strength.score, 4, "{:?}", strong);
+            assert!(strength.crack_time >= CrackTime::Years, "{:?}", strong);
+        }
+    }
+
+    #[test]
+    fn test_policy_refuses_weak_unless_allowed() {
+        let policy = PasswordPolicy::default();
+        let err = policy.check("password123", false).unwrap_err();
+        assert!(matches!(err, HybridGuardError::PolicyViolation(_)));
+        assert_eq!(policy.check("password123", true).unwrap().score, 0);
+        assert!(policy.check("correct horse battery staple", false).is_ok());
+    }
+
+    #[test]
+    fn test_denylist_is_case_insensitive_and_mandatory() {
+        let policy = PasswordPolicy {
+            denylist: vec!["Correct Horse Battery Staple".to_string()],
+            ..PasswordPolicy::default()
+        };
+        for variant in ["correct horse battery staple", "CORRECT HORSE BATTERY STAPLE"] {
+            assert!(matches!(policy.check(variant, true), Err(HybridGuardError::PolicyViolation(_))));
+        }
+        assert!(policy.check("correct horse battery stapler", false).is_ok());
+    }
+
+    #[test]
+    fn test_policy_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.json");
+        std::fs::write(&path, r#"{"min_length": 20, "denylist": ["acme-corp-2026"]}"#).unwrap();
+        let policy = PasswordPolicy::load(&path).unwrap();
+        assert_eq!(policy.min_score, 3);
+        assert!(policy.check("vT8#qLm2!xR9@wZp", true).is_err());
+        assert!(policy.check("correct horse battery staple", false).is_ok());
+
+        std::fs::write(&path, r#"{"min_score": 5}"#).unwrap();
+        assert!(PasswordPolicy::load(&path).is_err());
+        std::fs::write(&path, r#"{"min_lenght": 20}"#).unwrap();
+        assert!(PasswordPolicy::load(&path).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+// Verification keys for parties that check integrity but must not decrypt
+//
+// A verification key holds the tag keys of three purposes, derived from the
+// layer keys, and no layer key: the container verification tag (format v12),
+// a chunked file's tag chain and its Merkle root tag. Those cover every byte
+// of a file, so an auditor can tell an intact ciphertext from a modified one
+// without ever seeing plaintext. HKDF is one-way, so the layer keys cannot be
+// recovered from it. The tags are MACs, not signatures: whoever holds the key
+// could also make them, so it is still kept private.
+//
+// The file is a key file envelope whose body records `["verify"]` as its only
+// capability; `KeyManager` refuses to load it.
+
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys, HASH_LEN};
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::tag::MacKeys;
+use crate::crypto::VERIFICATION_PURPOSE;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::permissions::{self, LoosePermissions};
+use crate::key_manager::{key_file_body, protector, Capability, KeyFileEnvelope, KEY_FILE_FORMAT, KEY_FILE_VERSION};
+use crate::streaming::chunked;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::Path;
+
+/// Tag keys of one profile, without the layer keys they were derived from
+pub struct VerificationKey {
+    key_id: String,
+    created_at: String,
+    container_key: SecretBytes,
+    chain_key: SecretBytes,
+    root_key: SecretBytes,
+}
+
+impl VerificationKey {
+    /// Derive the verification key of the keys `key_id` names
+    pub(crate) fn from_keys(key_id: &str, keys: &LayerKeys) -> Self {
+        Self {
+            key_id: key_id.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            container_key: keys.derive_key(VERIFICATION_PURPOSE),
+            chain_key: keys.derive_key(chunked::TAG_PURPOSE),
+            root_key: keys.derive_key(chunked::ROOT_TAG_PURPOSE),
+        }
+    }
+    
+    /// Load a verification key, warning if other users can read it
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = fs::read(path.as_ref())?;
+        permissions::check_private(path.as_ref(), LoosePermissions::Warn)?;
+        Self::from_bytes(&data)
+    }
+    
+    /// Parse the contents of a verification key file
+    /// Fails with `InvalidInput` for a key file of any other kind
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if protector::protection(data).is_some() {
+            return Err(HybridGuardError::InvalidInput(
+                "a protected key file is not a verification key".to_string(),
+            ));
+        }
+        let (_, body) = key_file_body(data)?;
+        if !is_verification_key(&body) {
+            return Err(HybridGuardError::InvalidInput(
+                "not a verification key; export one with `key export --verification-key`".to_string(),
+            ));
+        }
+        let stored: StoredVerificationKey = serde_json::from_value(Value::Object(body))
+            .map_err(|e| HybridGuardError::InvalidInput(format!("verification key is damaged: {}", e)))?;
+        let tag_keys = [&stored.container_key, &stored.chain_key, &stored.root_key];
+        if stored.capabilities != [Capability::Verify] || tag_keys.iter().any(|key| key.len() != HASH_LEN) {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "verification key for {} is damaged: it must hold three {}-byte tag keys and only the verify capability",
+                stored.key_id, HASH_LEN
+            )));
+        }
+        
+        Ok(Self {
+            key_id: stored.key_id,
+            created_at: stored.created_at,
+            container_key: SecretBytes::new(stored.container_key),
+            chain_key: SecretBytes::new(stored.chain_key),
+            root_key: SecretBytes::new(stored.root_key),
+        })
+    }
+    
+    /// Save the verification key to a file readable by its owner only
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.save_with(path, &WriteOptions::critical())
+    }
+    
+    /// `save`, finishing the file as `options` ask instead of fsyncing it
+    pub fn save_with<P: AsRef<Path>>(&self, path: P, options: &WriteOptions) -> Result<()> {
+        permissions::write_private_with(path.as_ref(), &self.to_bytes()?, options)?;
+        
+        Ok(())
+    }
+    
+    /// Contents of the file `save` writes
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let stored = StoredVerificationKey {
+            key_id: self.key_id.clone(),
+            created_at: self.created_at.clone(),
+            capabilities: vec![Capability::Verify],
+            container_key: self.container_key.to_vec(),
+            chain_key: self.chain_key.to_vec(),
+            root_key: self.root_key.to_vec(),
+        };
+        let file = KeyFileEnvelope { format: KEY_FILE_FORMAT, version: KEY_FILE_VERSION, body: &stored, signature: None };
+        
+        let json = serde_json::to_string_pretty(&file)
+            .map_err(|e| HybridGuardError::KeyGeneration(e.to_string()))?;
+        
+        Ok(json.into_bytes())
+    }
+    
+    /// ID of the keys this verification key was derived from
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+    
+    pub fn created_at(&self) -> &str {
+        &self.created_at
+    }
+}
+
+impl MacKeys for VerificationKey {
+    /// Only the purposes integrity checks use are here; anything else is a bug
+    fn mac_key(&self, purpose: KeyPurpose) -> SecretBytes {
+        match purpose {
+            VERIFICATION_PURPOSE => self.container_key.clone(),
+            chunked::TAG_PURPOSE => self.chain_key.clone(),
+            chunked::ROOT_TAG_PURPOSE => self.root_key.clone(),
+            other => panic!("a verification key holds no {:?} key", other),
+        }
+    }
+}
+
+/// Whether the key file body `body` holds a verification key
+pub(crate) fn is_verification_key(body: &Map<String, Value>) -> bool {
+    body.get("capabilities")
+        .and_then(Value::as_array)
+        .is_some_and(|capabilities| capabilities.contains(&Value::from("verify")))
+}
+
+/// Serializable verification key
+#[derive(Serialize, Deserialize)]
+struct StoredVerificationKey {
+    key_id: String,
+    created_at: String,
+    capabilities: Vec<Capability>,
+    /// Key of the container verification tag
+    container_key: Vec<u8>,
+    /// Key of a chunked file's tag chain
+    chain_key: Vec<u8>,
+    /// Key of a chunked file's Merkle root tag
+    root_key: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyManager;
+    
+    #[test]
+    fn test_round_trip_holds_no_layer_key() {
+        let km = KeyManager::from_master_key(&[0x3C; 32]).unwrap();
+        let key = km.export_verification_key().unwrap();
+        let bytes = key.to_bytes().unwrap();
+        let text = String::from_utf8(bytes.clone()).unwrap();
+        assert!(!text.contains("layer1_key"));
+        
+        let loaded = VerificationKey::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.key_id(), km.key_id());
+        for purpose in [VERIFICATION_PURPOSE, chunked::TAG_PURPOSE, chunked::ROOT_TAG_PURPOSE] {
+            assert_eq!(loaded.mac_key(purpose), km.get_keys().unwrap().mac_key(purpose));
+        }
+    }
+    
+    #[test]
+    fn test_key_files_are_not_verification_keys() {
+        let km = KeyManager::from_master_key(&[0x3C; 32]).unwrap();
+        let bytes = km.to_bytes().unwrap();
+        assert!(matches!(VerificationKey::from_bytes(&bytes), Err(HybridGuardError::InvalidInput(_))));
+        
+        let verification = km.export_verification_key().unwrap().to_bytes().unwrap();
+        assert!(matches!(KeyManager::from_bytes(&verification), Err(HybridGuardError::CapabilityDenied(_))));
+    }
+}
@@ -0,0 +1,182 @@
+// Conservative security assessment of a layer stack
+// `security_class()` is what a layer's security rests on; this module decides
+// how many bits a stack can actually count on from it. A cascade with
+// independent keys is as strong as its strongest layer, so the effective level
+// is the best counted contribution, not a sum.
+
+use super::{EncryptionLayer, LayerDescriptor};
+use crate::error::{HybridGuardError, Result};
+use serde::Serialize;
+use std::fmt;
+
+/// Effective level below which a stack is refused unless explicitly allowed
+pub const MIN_EFFECTIVE_SECURITY: u32 = 128;
+
+/// What a layer's security rests on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SecurityClass {
+    /// Post-quantum KEM at a NIST security category (1 to 5)
+    PostQuantumKem { nist_level: u8 },
+    /// Cipher under a secret key of `bits` bits
+    SymmetricKeyed { bits: u32 },
+    /// Hides structure but is not a cipher on its own
+    Obfuscation,
+    /// Demonstration code that makes no security claim
+    Experimental,
+}
+
+impl SecurityClass {
+    /// Bits a stack may count on from this layer against a quantum attacker
+    /// NIST categories map to AES-128/192/256 key search; Grover halves a
+    /// symmetric key; obfuscation and experimental layers count for nothing
+    pub fn counted_bits(self) -> u32 {
+        match self {
+            SecurityClass::PostQuantumKem { nist_level: 1 | 2 } => 128,
+            SecurityClass::PostQuantumKem { nist_level: 3 | 4 } => 192,
+            SecurityClass::PostQuantumKem { nist_level: 5 } => 256,
+            SecurityClass::PostQuantumKem { .. } => 0,
+            SecurityClass::SymmetricKeyed { bits } => bits / 2,
+            SecurityClass::Obfuscation | SecurityClass::Experimental => 0,
+        }
+    }
+
+    /// Whether the layer may be described as quantum resistant at all
+    pub fn is_quantum_resistant(self) -> bool {
+        self.counted_bits() > 0
+    }
+}
+
+impl fmt::Display for SecurityClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecurityClass::PostQuantumKem { nist_level } => write!(f, "post-quantum KEM, NIST level {}", nist_level),
+            SecurityClass::SymmetricKeyed { bits } => write!(f, "keyed symmetric, {}-bit key", bits),
+            SecurityClass::Obfuscation => write!(f, "obfuscation only"),
+            SecurityClass::Experimental => write!(f, "experimental, no security claim"),
+        }
+    }
+}
+
+/// One layer's claimed and counted security
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LayerAssessment {
+    pub name: String,
+    pub class: SecurityClass,
+    /// What `claims()` reports
+    pub claims: String,
+    /// What this assessment gives the layer credit for
+    pub counted_bits: u32,
+}
+
+/// Effective security of a whole stack
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SecurityAssessment {
+    pub layers: Vec<LayerAssessment>,
+    pub effective_bits: u32,
+}
+
+impl SecurityAssessment {
+    /// Assess layers in pipeline order
+    pub fn of(layers: &[&dyn EncryptionLayer]) -> Self {
+        let layers: Vec<LayerAssessment> = layers
+            .iter()
+            .map(|layer| {
+                let class = layer.security_class();
+                LayerAssessment {
+                    name: layer.name().to_string(),
+                    class,
+                    claims: layer.claims().to_string(),
+                    counted_bits: class.counted_bits(),
+                }
+            })
+            .collect();
+        let effective_bits = layers.iter().map(|layer| layer.counted_bits).max().unwrap_or(0);
+        Self { layers, effective_bits }
+    }
+
+    /// Assess the built-in layers a file records it was written with, the
+    /// compact KEM included; layers this build does not know are left out,
+    /// so they count for nothing
+    pub fn of_descriptors(descriptors: &[LayerDescriptor]) -> Self {
+        let mut builtin = super::registry();
+        builtin.push(Box::new(super::CompactKemLayer::new()));
+        let used: Vec<&dyn EncryptionLayer> = builtin
+            .iter()
+            .filter(|layer| descriptors.iter().any(|d| d.name == layer.descriptor().name))
+            .map(|layer| layer.as_ref())
+            .collect();
+        Self::of(&used)
+    }
+
+    /// Whether the stack falls short of `MIN_EFFECTIVE_SECURITY`
+    pub fn is_weak(&self) -> bool {
+        self.effective_bits < MIN_EFFECTIVE_SECURITY
+    }
+
+    /// Refuse a stack below `min_bits` unless `allow_weak` is set
+    pub fn enforce(&self, min_bits: u32, allow_weak: bool) -> Result<()> {
+        if self.effective_bits < min_bits && !allow_weak {
+            return Err(HybridGuardError::PolicyViolation(format!(
+                "layer stack has an effective security of {} bits, below the {}-bit minimum; \
+                 obfuscation and experimental layers do not count (allow_weak_stack overrides this)",
+                self.effective_bits, min_bits
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::{registry, CompactKemLayer, FHELayer, QuantumNoiseLayer};
+
+    #[test]
+    fn test_default_stack() {
+        let layers = registry();
+        let refs: Vec<&dyn EncryptionLayer> = layers.iter().map(|layer| layer.as_ref()).collect();
+        let assessment = SecurityAssessment::of(&refs);
+        assert_eq!(assessment.effective_bits, 256);
+        assert!(assessment.enforce(MIN_EFFECTIVE_SECURITY, false).is_ok());
+        let counted: Vec<u32> = assessment.layers.iter().map(|layer| layer.counted_bits).collect();
+        assert_eq!(counted, [192, 256, 0, 0]);
+    }
+
+    #[test]
+    fn test_noise_only_stack_refused() {
+        let noise = QuantumNoiseLayer::new();
+        let assessment = SecurityAssessment::of(&[&noise]);
+        assert_eq!(assessment.layers[0].class, SecurityClass::Obfuscation);
+        assert_eq!(assessment.effective_bits, 0);
+        assert!(matches!(assessment.enforce(MIN_EFFECTIVE_SECURITY, false), Err(HybridGuardError::PolicyViolation(_))));
+        assert!(assessment.enforce(MIN_EFFECTIVE_SECURITY, true).is_ok());
+        assert!(SecurityAssessment::of(&[]).is_weak());
+    }
+
+    #[test]
+    fn test_unkeyed_layers_count_for_nothing() {
+        let fhe = FHELayer::new();
+        let noise = QuantumNoiseLayer::new();
+        let assessment = SecurityAssessment::of(&[&noise, &fhe]);
+        assert_eq!(assessment.effective_bits, 0);
+        assert!(assessment.is_weak());
+    }
+
+    #[test]
+    fn test_compact_files_count_their_kem() {
+        let descriptors = [CompactKemLayer::new().descriptor(), QuantumNoiseLayer::new().descriptor(), FHELayer::new().descriptor()];
+        assert_eq!(SecurityAssessment::of_descriptors(&descriptors).effective_bits, 192);
+    }
+
+    #[test]
+    fn test_counted_bits() {
+        assert_eq!(SecurityClass::PostQuantumKem { nist_level: 1 }.counted_bits(), 128);
+        assert_eq!(SecurityClass::PostQuantumKem { nist_level: 3 }.counted_bits(), 192);
+        assert_eq!(SecurityClass::PostQuantumKem { nist_level: 5 }.counted_bits(), 256);
+        assert_eq!(SecurityClass::PostQuantumKem { nist_level: 9 }.counted_bits(), 0);
+        assert_eq!(SecurityClass::SymmetricKeyed { bits: 256 }.counted_bits(), 128);
+        assert!(!SecurityClass::Obfuscation.is_quantum_resistant());
+        assert!(!SecurityClass::Experimental.is_quantum_resistant());
+    }
+}
@@ -0,0 +1,200 @@
+// Compact KEM: layers 1 and 2 keyed by a single ML-KEM-768 encapsulation
+// The standard stack encapsulates twice per message, so even an empty
+// message carries over 15 KB of KEM ciphertext, nearly all of it HQC-256.
+// This layer stands in for both: it encapsulates once, keys layer 1's
+// keystream from the shared secret and layer 2's from the shared secret
+// together with the layer-2 key. The code-based KEM is gone, so the stack
+// rests on ML-KEM alone; that is the price of about 1.1 KB of overhead.
+
+use crate::crypto::hkdf::{self, LayerKeys, LAYER_KEY_LEN};
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::keystream;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::layer1_mlkem::MlKemLayer;
+use crate::layers::{unsupported_version, EncryptionLayer, KemFraming, LayerDescriptor, MAX_KEM_HEADER_LEN, SecurityClass, SealedMessage};
+
+/// Identifier recorded in layer descriptors, in place of ML-KEM-768 and HQC
+pub const LAYER_ID: &str = "COMPACT-KEM";
+
+/// Output format written by this build
+const FORMAT_VERSION: u16 = 1;
+
+/// Security claims, as `hybridguard spec` prints them
+const CLAIMS: &str = "ml-kem-768 ind-cca2 nist-level-3";
+
+/// Output framing of the current format, as `hybridguard spec` prints it
+const FRAMING: &str = "u32 BE length of kem_ct || kem_ct (ML-KEM-768 encapsulation) || sym_ct (input XOR the layer-1 then layer-2 SHAKE-256 keystreams)";
+
+/// Domain-separation label for the layer-1 keystream
+const LAYER1_LABEL: &[u8] = b"compact-layer1";
+
+/// Domain-separation label for the layer-2 secret and keystream
+const LAYER2_LABEL: &[u8] = b"compact-layer2";
+
+/// Layers 1 and 2 of the compact stack under one ML-KEM-768 encapsulation
+///
+/// Output framing (format v1): `len || kem_ct || sym_ct`, as ML-KEM v3
+/// - `len`: length of `kem_ct` as a big-endian u32, checked on decryption
+/// - `kem_ct`: ML-KEM-768 encapsulation, always 1088 bytes
+/// - `sym_ct`: the input XORed with SHAKE-256 of the shared secret, then with
+///   SHAKE-256 of HMAC(layer-2 key, shared secret); same length as the input
+///
+/// The key is the layer-1 key followed by the layer-2 key. The first half
+/// seeds the same ML-KEM keypair the standard stack's layer 1 uses.
+pub struct CompactKemLayer {
+    mlkem: MlKemLayer,
+}
+
+impl CompactKemLayer {
+    pub fn new() -> Self {
+        Self { mlkem: MlKemLayer::new() }
+    }
+    
+    /// Split a key into its layer-1 (KEM) and layer-2 halves
+    fn split_key(key: &[u8]) -> Result<(&[u8], &[u8])> {
+        if key.len() < 2 * LAYER_KEY_LEN {
+            return Err(HybridGuardError::KeyTooShort(format!(
+                "the compact KEM layer needs the layer-1 and layer-2 keys, {} bytes, but was given {}",
+                2 * LAYER_KEY_LEN,
+                key.len()
+            )));
+        }
+        Ok(key.split_at(key.len() / 2))
+    }
+}
+
+impl Default for CompactKemLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EncryptionLayer for CompactKemLayer {
+    fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        log::debug!("Compact KEM: Encrypting {} bytes", data.len());
+        let (kem_key, layer2_key) = Self::split_key(key)?;
+        let (kem_ct, shared_secret) = self.mlkem.encapsulate(kem_key)?;
+        let mut sym_ct = data.to_vec();
+        apply_keystreams(&shared_secret, layer2_key, &mut sym_ct);
+        Ok(SealedMessage { kem_ct, sym_ct }.into_bytes())
+    }
+    
+    fn decrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        log::debug!("Compact KEM: Decrypting {} bytes", data.len());
+        let (kem_key, layer2_key) = Self::split_key(key)?;
+        let sealed = SealedMessage::parse(data, self.mlkem.kem_ciphertext_len()?)?;
+        let shared_secret = self.mlkem.decapsulate(kem_key, &sealed.kem_ct)?;
+        let mut plaintext = sealed.sym_ct;
+        apply_keystreams(&shared_secret, layer2_key, &mut plaintext);
+        Ok(plaintext)
+    }
+    
+    fn decrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        match version {
+            FORMAT_VERSION => self.decrypt(data, key),
+            other => Err(unsupported_version(LAYER_ID, other)),
+        }
+    }
+    
+    fn decrypt_into(&self, data: &[u8], key: &[u8], version: u16, out: &mut Vec<u8>) -> Result<()> {
+        if version != FORMAT_VERSION {
+            return Err(unsupported_version(LAYER_ID, version));
+        }
+        let (kem_key, layer2_key) = Self::split_key(key)?;
+        let (kem_ct, sym_ct) = KemFraming::LengthPrefixed.split_borrowed(data, self.mlkem.kem_ciphertext_len()?)?;
+        let shared_secret = self.mlkem.decapsulate(kem_key, kem_ct)?;
+        out.clear();
+        out.extend_from_slice(sym_ct);
+        apply_keystreams(&shared_secret, layer2_key, out);
+        Ok(())
+    }
+    
+    fn declared_output_len(&self, data: &[u8], version: u16) -> Result<usize> {
+        if version != FORMAT_VERSION {
+            return Err(unsupported_version(LAYER_ID, version));
+        }
+        let (_, sym_ct) = KemFraming::LengthPrefixed.split_borrowed(data, self.mlkem.kem_ciphertext_len()?)?;
+        Ok(sym_ct.len())
+    }
+    
+    fn output_len(&self, input_len: usize) -> Result<usize> {
+        Ok(self.overhead_bytes()? + input_len)
+    }
+    
+    fn overhead_bytes(&self) -> Result<usize> {
+        Ok(KemFraming::LengthPrefixed.header_len(self.mlkem.kem_ciphertext_len()?))
+    }
+    
+    fn max_input(&self) -> usize {
+        usize::MAX - MAX_KEM_HEADER_LEN
+    }
+    
+    fn required_key_len(&self) -> usize {
+        2 * LAYER_KEY_LEN
+    }
+    
+    fn name(&self) -> &str {
+        "Compact ML-KEM-768 (Lattice-based)"
+    }
+    
+    fn security_class(&self) -> SecurityClass {
+        SecurityClass::PostQuantumKem { nist_level: 3 }
+    }
+    
+    fn claims(&self) -> &str {
+        CLAIMS
+    }
+    
+    fn descriptor(&self) -> LayerDescriptor {
+        LayerDescriptor::new(LAYER_ID, FORMAT_VERSION)
+    }
+    
+    fn framing(&self) -> &str {
+        FRAMING
+    }
+}
+
+/// Key of the compact layer for a file: its layer-1 key, then its layer-2 key
+pub(crate) fn layer_key(keys: &LayerKeys) -> SecretBytes {
+    let mut key = keys.layer1_key.to_vec();
+    key.extend_from_slice(&keys.layer2_key);
+    SecretBytes::new(key)
+}
+
+/// XOR both layers' keystreams into `data`; its own inverse
+fn apply_keystreams(shared_secret: &[u8], layer2_key: &[u8], data: &mut [u8]) {
+    keystream::xor_in_place(shared_secret, LAYER1_LABEL, data);
+    let layer2_secret = hkdf::hmac(layer2_key, &[LAYER2_LABEL, shared_secret]);
+    keystream::xor_in_place(&layer2_secret, LAYER2_LABEL, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn key() -> Vec<u8> {
+        (0..2 * LAYER_KEY_LEN as u8).collect()
+    }
+    
+    #[test]
+    fn test_round_trip_with_one_encapsulation() {
+        let layer = CompactKemLayer::new();
+        let data = b"one of millions of small records";
+        let encrypted = layer.encrypt(data, &key()).unwrap();
+        assert_eq!(encrypted.len(), layer.output_len(data.len()).unwrap());
+        assert_eq!(layer.overhead_bytes().unwrap(), 4 + 1088);
+        assert_eq!(layer.decrypt(&encrypted, &key()).unwrap(), data);
+        
+        // Both halves of the key count: the second only keys layer 2
+        let mut other = key();
+        other[LAYER_KEY_LEN] ^= 1;
+        assert_ne!(layer.decrypt(&encrypted, &other).unwrap(), data);
+    }
+    
+    #[test]
+    fn test_needs_both_layer_keys() {
+        let layer = CompactKemLayer::new();
+        assert!(matches!(layer.encrypt(b"data", &key()[..LAYER_KEY_LEN]), Err(HybridGuardError::KeyTooShort(_))));
+        assert!(matches!(layer.decrypt_version(&[], &key(), 2), Err(HybridGuardError::UnsupportedFormat(_))));
+    }
+}
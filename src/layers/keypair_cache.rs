@@ -0,0 +1,96 @@
+// Cache of KEM keypairs derived from layer keys
+// Deterministic keypair generation dominates the cost of every KEM call, so
+// each layer keeps the keypairs it has derived; the cache is shared between
+// threads and keyed by a hash of the layer key, never the key itself
+
+use crate::crypto::secret::SecretBytes;
+use crate::error::Result;
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Keypairs kept per layer before the cache starts over
+const MAX_ENTRIES: usize = 16;
+
+/// A KEM keypair derived from one layer key
+pub(crate) struct Keypair {
+    pub(crate) public_key: Vec<u8>,
+    pub(crate) secret_key: SecretBytes,
+}
+
+/// Thread-safe map from layer key fingerprint to derived keypair
+pub(crate) struct KeypairCache {
+    entries: RwLock<HashMap<[u8; 32], Arc<Keypair>>>,
+}
+
+impl KeypairCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Cached keypair for `key`, calling `derive` on a miss
+    /// Derivation runs outside the lock; racing threads derive the same
+    /// keypair and the first one stored wins
+    pub(crate) fn get_or_derive(&self, key: &[u8], derive: impl FnOnce() -> Result<Keypair>) -> Result<Arc<Keypair>> {
+        let fingerprint = fingerprint(key);
+        if let Some(keypair) = self.entries.read().unwrap_or_else(PoisonError::into_inner).get(&fingerprint) {
+            return Ok(Arc::clone(keypair));
+        }
+
+        let keypair = Arc::new(derive()?);
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&fingerprint) {
+            entries.clear();
+        }
+        Ok(Arc::clone(entries.entry(fingerprint).or_insert(keypair)))
+    }
+}
+
+fn fingerprint(key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"HybridGuard-keypair-cache");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn keypair(tag: u8) -> Keypair {
+        Keypair {
+            public_key: vec![tag; 4],
+            secret_key: SecretBytes::new(vec![tag; 4]),
+        }
+    }
+
+    #[test]
+    fn test_derives_once_per_key() {
+        let cache = KeypairCache::new();
+        let calls = Cell::new(0);
+        let derive = |tag| {
+            calls.set(calls.get() + 1);
+            Ok(keypair(tag))
+        };
+
+        let first = cache.get_or_derive(b"key-a", || derive(1)).unwrap();
+        let again = cache.get_or_derive(b"key-a", || derive(9)).unwrap();
+        let other = cache.get_or_derive(b"key-b", || derive(2)).unwrap();
+
+        assert_eq!(calls.get(), 2);
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!(other.public_key, vec![2; 4]);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let cache = KeypairCache::new();
+        for i in 0..=MAX_ENTRIES as u8 {
+            cache.get_or_derive(&[i], || Ok(keypair(i))).unwrap();
+        }
+        assert!(cache.entries.read().unwrap().len() <= MAX_ENTRIES);
+    }
+}
@@ -1,29 +1,67 @@
 // Layer 2: HQC (Hamming Quasi-Cyclic) - Code-based encryption
 // This is the second layer using error-correcting codes for quantum resistance
 
+use crate::crypto::drbg;
+use crate::crypto::keystream;
+use crate::crypto::secret::SecretBytes;
 use crate::error::{HybridGuardError, Result};
-use crate::layers::EncryptionLayer;
-use oqs::{kem::Kem, kem::Algorithm};
+use crate::layers::keypair_cache::{Keypair, KeypairCache};
+use crate::layers::stream::{BufferedDecrypt, LayerDecryptState, LayerEncryptState, XorDecryptState, XorEncryptState};
+use crate::layers::{frame_kem_ct, unsupported_version, EncryptionLayer, KemFraming, LayerDescriptor, MAX_KEM_HEADER_LEN, SecurityClass, SealedMessage};
+use crate::layers::oqs_support;
+use oqs::kem::Algorithm;
 use sha3::{Sha3_256, Digest};
+use std::sync::{Arc, OnceLock};
+
+/// Identifier recorded in layer descriptors
+const LAYER_ID: &str = "HQC";
+
+/// Output format written by this build
+const FORMAT_VERSION: u16 = 3;
+
+/// Security claims, as `hybridguard spec` prints them
+const CLAIMS: &str = "hqc-256 ind-cca2 nist-level-5";
+
+/// Output framing of the current format, as `hybridguard spec` prints it
+const FRAMING: &str = "u32 BE length of kem_ct || kem_ct (HQC-256 encapsulation) || sym_ct (input XOR SHAKE-256 of the shared secret)";
+
+/// Domain-separation label for the payload keystream
+const KEYSTREAM_LABEL: &[u8] = b"hqc-payload";
 
 /// HQC (Hamming Quasi-Cyclic) encryption layer
 /// Uses code-based cryptography for quantum resistance
+///
+/// Output framing (format v3): `len || kem_ct || sym_ct`
+/// - `len`: length of `kem_ct` as a big-endian u32, checked on decryption
+/// - `kem_ct`: HQC-256 encapsulation, fixed length for the parameter set
+/// - `sym_ct`: the input XORed with SHAKE-256 of the shared secret, same length as the input
+///
+/// Formats v1 and v2 had no `len`; they are still read by descriptor version.
+///
+/// There is no nonce: every message encapsulates a fresh shared secret.
+/// The layer key only seeds the KEM keypair; any byte string works.
 pub struct HqcLayer {
-    security_level: u32,
+    keypairs: KeypairCache,
+    ciphertext_len: OnceLock<usize>,
 }
 
 impl HqcLayer {
     pub fn new() -> Self {
         Self {
-            security_level: 256, // HQC provides 256-bit quantum security
+            keypairs: KeypairCache::new(),
+            ciphertext_len: OnceLock::new(),
         }
     }
     
+    /// KEM keypair for the layer key, derived on first use and then cached
+    fn keypair(&self, key: &[u8]) -> Result<Arc<Keypair>> {
+        self.keypairs.get_or_derive(key, || self.derive_keypair(key))
+    }
+    
     /// Derive a KEM keypair from the layer key
-    fn derive_keypair(&self, key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    fn derive_keypair(&self, key: &[u8]) -> Result<Keypair> {
         // Use the key as a seed to deterministically generate keypair
-        let kem = Kem::new(Algorithm::HqcRmrs256)
-            .map_err(|e| HybridGuardError::EncryptionError(format!("Failed to initialize HQC: {}", e)))?;
+        let kem = oqs_support::kem(LAYER_ID, Algorithm::HqcRmrs256)?;
         
         // Hash the key to get a proper seed
         let mut hasher = Sha3_256::new();
@@ -31,82 +69,40 @@ impl HqcLayer {
         hasher.update(b"hqc-keypair-seed");
         let seed = hasher.finalize();
         
-        // Generate keypair (in production, use proper key derivation)
-        let (public_key, secret_key) = kem.keypair()
+        // Generate keypair from the seed so encryption and decryption agree
+        let (public_key, secret_key) = drbg::with_seeded_oqs_rng(&seed, b"hqc-keypair", || kem.keypair())
             .map_err(|e| HybridGuardError::EncryptionError(format!("Failed to generate keypair: {}", e)))?;
         
-        Ok((public_key.into_vec(), secret_key.into_vec()))
+        Ok(Keypair {
+            public_key: public_key.into_vec(),
+            secret_key: SecretBytes::new(secret_key.into_vec()),
+        })
     }
-}
-
-impl EncryptionLayer for HqcLayer {
-    fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
-        log::info!("Layer 2 (HQC): Encrypting {} bytes", data.len());
-        
-        // Initialize HQC KEM
-        let kem = Kem::new(Algorithm::HqcRmrs256)
-            .map_err(|e| HybridGuardError::EncryptionError(format!("Failed to initialize HQC: {}", e)))?;
+    
+    /// Encapsulate to a fresh shared secret, returning (KEM ciphertext, shared secret)
+    fn encapsulate(&self, key: &[u8]) -> Result<(Vec<u8>, SecretBytes)> {
+        let kem = oqs_support::kem(LAYER_ID, Algorithm::HqcRmrs256)?;
         
         // Derive keypair from layer key
-        let (public_key, _) = self.derive_keypair(key)?;
+        let keypair = self.keypair(key)?;
         
-        // Encapsulate to get shared secret and ciphertext
-        let public_key_ref = oqs::kem::PublicKeyRef::new(&public_key)
+        let public_key_ref = oqs::kem::PublicKeyRef::new(&keypair.public_key)
             .map_err(|e| HybridGuardError::EncryptionError(format!("Invalid public key: {}", e)))?;
         
         let (ciphertext, shared_secret) = kem.encapsulate(&public_key_ref)
             .map_err(|e| HybridGuardError::EncryptionError(format!("Encapsulation failed: {}", e)))?;
         
-        // Use shared secret to encrypt data with XOR (simple symmetric encryption)
-        // In production, use AES-GCM or ChaCha20-Poly1305
-        let mut encrypted_data = data.to_vec();
-        let shared_secret_bytes = shared_secret.into_vec();
-        
-        // Expand shared secret to match data length using SHA3
-        let mut key_stream = Vec::new();
-        let mut counter = 0u64;
-        while key_stream.len() < encrypted_data.len() {
-            let mut hasher = Sha3_256::new();
-            hasher.update(&shared_secret_bytes);
-            hasher.update(&counter.to_le_bytes());
-            key_stream.extend_from_slice(&hasher.finalize());
-            counter += 1;
-        }
-        
-        // XOR encryption
-        for (i, byte) in encrypted_data.iter_mut().enumerate() {
-            *byte ^= key_stream[i];
-        }
-        
-        // Prepend ciphertext (KEM encapsulation) to encrypted data
-        let mut result = ciphertext.into_vec();
-        result.extend_from_slice(&encrypted_data);
-        
-        log::info!("Layer 2 (HQC): Encrypted to {} bytes", result.len());
-        Ok(result)
+        Ok((ciphertext.into_vec(), SecretBytes::new(shared_secret.into_vec())))
     }
     
-    fn decrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
-        log::info!("Layer 2 (HQC): Decrypting {} bytes", data.len());
-        
-        // Initialize HQC KEM
-        let kem = Kem::new(Algorithm::HqcRmrs256)
-            .map_err(|e| HybridGuardError::EncryptionError(format!("Failed to initialize HQC: {}", e)))?;
+    /// Recover the shared secret from a KEM ciphertext
+    fn decapsulate(&self, key: &[u8], kem_ciphertext: &[u8]) -> Result<SecretBytes> {
+        let kem = oqs_support::kem(LAYER_ID, Algorithm::HqcRmrs256)?;
         
         // Derive keypair from layer key
-        let (_, secret_key) = self.derive_keypair(key)?;
-        
-        // Extract KEM ciphertext (first part of data)
-        let ciphertext_len = kem.length_ciphertext();
-        if data.len() < ciphertext_len {
-            return Err(HybridGuardError::DecryptionError("Data too short for HQC ciphertext".to_string()));
-        }
+        let keypair = self.keypair(key)?;
         
-        let kem_ciphertext = &data[..ciphertext_len];
-        let encrypted_data = &data[ciphertext_len..];
-        
-        // Decapsulate to recover shared secret
-        let secret_key_ref = oqs::kem::SecretKeyRef::new(&secret_key)
+        let secret_key_ref = oqs::kem::SecretKeyRef::new(&keypair.secret_key)
             .map_err(|e| HybridGuardError::DecryptionError(format!("Invalid secret key: {}", e)))?;
         
         let ciphertext_ref = oqs::kem::CiphertextRef::new(kem_ciphertext)
@@ -115,37 +111,178 @@ impl EncryptionLayer for HqcLayer {
         let shared_secret = kem.decapsulate(&secret_key_ref, &ciphertext_ref)
             .map_err(|e| HybridGuardError::DecryptionError(format!("Decapsulation failed: {}", e)))?;
         
-        // Use shared secret to decrypt data
-        let mut decrypted_data = encrypted_data.to_vec();
-        let shared_secret_bytes = shared_secret.into_vec();
-        
-        // Expand shared secret to match data length
-        let mut key_stream = Vec::new();
-        let mut counter = 0u64;
-        while key_stream.len() < decrypted_data.len() {
-            let mut hasher = Sha3_256::new();
-            hasher.update(&shared_secret_bytes);
-            hasher.update(&counter.to_le_bytes());
-            key_stream.extend_from_slice(&hasher.finalize());
-            counter += 1;
+        Ok(SecretBytes::new(shared_secret.into_vec()))
+    }
+    
+    /// Length of the KEM ciphertext that prefixes every message
+    fn kem_ciphertext_len(&self) -> Result<usize> {
+        if let Some(len) = self.ciphertext_len.get() {
+            return Ok(*len);
+        }
+        let kem = oqs_support::kem(LAYER_ID, Algorithm::HqcRmrs256)?;
+        Ok(*self.ciphertext_len.get_or_init(|| kem.length_ciphertext()))
+    }
+    
+    /// Encrypt `data` for `key`, keeping the KEM ciphertext apart from the payload
+    pub fn seal(&self, data: &[u8], key: &[u8]) -> Result<SealedMessage> {
+        self.seal_version(data, key, FORMAT_VERSION)
+    }
+    
+    /// Decrypt a message produced by `seal`
+    pub fn open(&self, sealed: &SealedMessage, key: &[u8]) -> Result<Vec<u8>> {
+        self.open_version(&sealed.kem_ct, &sealed.sym_ct, key, FORMAT_VERSION)
+    }
+    
+    /// Split the output of `encrypt` into its KEM ciphertext and payload
+    pub fn split(&self, data: &[u8]) -> Result<SealedMessage> {
+        SealedMessage::parse(data, self.kem_ciphertext_len()?)
+    }
+    
+    fn seal_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<SealedMessage> {
+        // Encapsulate to get shared secret and ciphertext
+        let (kem_ct, shared_secret) = self.encapsulate(key)?;
+        
+        // Use shared secret to encrypt data with XOR (simple symmetric encryption)
+        // In production, use AES-GCM or ChaCha20-Poly1305
+        let mut sym_ct = data.to_vec();
+        apply_keystream(&shared_secret, &mut sym_ct, version)?;
+        Ok(SealedMessage { kem_ct, sym_ct })
+    }
+    
+    fn open_version(&self, kem_ct: &[u8], sym_ct: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        if kem_ct.len() != self.kem_ciphertext_len()? {
+            return Err(HybridGuardError::DecryptionError("Wrong length for HQC ciphertext".to_string()));
         }
         
-        // XOR decryption
-        for (i, byte) in decrypted_data.iter_mut().enumerate() {
-            *byte ^= key_stream[i];
+        // Decapsulate to recover shared secret
+        let shared_secret = self.decapsulate(key, kem_ct)?;
+        
+        // Use shared secret to decrypt data
+        let mut data = sym_ct.to_vec();
+        apply_keystream(&shared_secret, &mut data, version)?;
+        Ok(data)
+    }
+}
+
+impl EncryptionLayer for HqcLayer {
+    fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_version(data, key, FORMAT_VERSION)
+    }
+    
+    fn encrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        log::debug!("Layer 2 (HQC): Encrypting {} bytes", data.len());
+        
+        // Prepend ciphertext (KEM encapsulation) to encrypted data
+        let result = KemFraming::for_version(version).join(self.seal_version(data, key, version)?);
+        
+        log::debug!("Layer 2 (HQC): Encrypted to {} bytes", result.len());
+        Ok(result)
+    }
+    
+    fn decrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_version(data, key, FORMAT_VERSION)
+    }
+    
+    fn decrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        log::debug!("Layer 2 (HQC): Decrypting {} bytes", data.len());
+        
+        if !(1..=FORMAT_VERSION).contains(&version) {
+            return Err(unsupported_version(LAYER_ID, version));
         }
         
-        log::info!("Layer 2 (HQC): Decrypted to {} bytes", decrypted_data.len());
+        // Extract KEM ciphertext (first part of data), framed from v3 on
+        let sealed = KemFraming::for_version(version).split(data, self.kem_ciphertext_len()?)?;
+        let decrypted_data = self.open_version(&sealed.kem_ct, &sealed.sym_ct, key, version)?;
+        
+        log::debug!("Layer 2 (HQC): Decrypted to {} bytes", decrypted_data.len());
         Ok(decrypted_data)
     }
     
+    fn decrypt_into(&self, data: &[u8], key: &[u8], version: u16, out: &mut Vec<u8>) -> Result<()> {
+        if !(1..=FORMAT_VERSION).contains(&version) {
+            return Err(unsupported_version(LAYER_ID, version));
+        }
+        
+        // Same checks as `decrypt_version`, but the payload is copied once, into `out`
+        let (kem_ct, sym_ct) = KemFraming::for_version(version).split_borrowed(data, self.kem_ciphertext_len()?)?;
+        let shared_secret = self.decapsulate(key, kem_ct)?;
+        out.clear();
+        out.extend_from_slice(sym_ct);
+        apply_keystream(&shared_secret, out, version)
+    }
+    
+    fn output_len(&self, input_len: usize) -> Result<usize> {
+        Ok(self.overhead_bytes()? + input_len)
+    }
+    
+    fn overhead_bytes(&self) -> Result<usize> {
+        Ok(KemFraming::LengthPrefixed.header_len(self.kem_ciphertext_len()?))
+    }
+    
+    fn max_input(&self) -> usize {
+        usize::MAX - MAX_KEM_HEADER_LEN
+    }
+    
+    fn begin_encrypt(&self, key: &[u8]) -> Result<Box<dyn LayerEncryptState + '_>> {
+        let (ciphertext, shared_secret) = self.encapsulate(key)?;
+        let stream = keystream::XofStream::new(&shared_secret, KEYSTREAM_LABEL);
+        Ok(Box::new(XorEncryptState::new(frame_kem_ct(&ciphertext), stream)))
+    }
+    
+    fn begin_decrypt(&self, key: &[u8], version: u16) -> Result<Box<dyn LayerDecryptState + '_>> {
+        match version {
+            // The legacy keystream is only implemented whole-message
+            1 => Ok(Box::new(BufferedDecrypt::new(self, key, version))),
+            2 | 3 => {
+                let key = SecretBytes::new(key.to_vec());
+                let framing = KemFraming::for_version(version);
+                let kem_ciphertext_len = self.kem_ciphertext_len()?;
+                let open = move |header: &[u8]| -> Result<keystream::XofStream> {
+                    let sealed = framing.split(header, kem_ciphertext_len)?;
+                    let shared_secret = self.decapsulate(&key, &sealed.kem_ct)?;
+                    Ok(keystream::XofStream::new(&shared_secret, KEYSTREAM_LABEL))
+                };
+                let header_len = framing.header_len(kem_ciphertext_len);
+                Ok(Box::new(XorDecryptState::new("HQC", header_len, Box::new(open))))
+            }
+            other => Err(unsupported_version(LAYER_ID, other)),
+        }
+    }
+    
     fn name(&self) -> &str {
         "HQC (Code-based)"
     }
     
-    fn security_level(&self) -> u32 {
-        self.security_level
+    fn security_class(&self) -> SecurityClass {
+        SecurityClass::PostQuantumKem { nist_level: 5 }
+    }
+    
+    fn claims(&self) -> &str {
+        CLAIMS
+    }
+    
+    fn descriptor(&self) -> LayerDescriptor {
+        LayerDescriptor::new(LAYER_ID, FORMAT_VERSION)
+    }
+    
+    fn framing(&self) -> &str {
+        FRAMING
+    }
+    
+    fn readable_versions(&self) -> Vec<u16> {
+        (1..=FORMAT_VERSION).collect()
+    }
+}
+
+/// XOR the payload keystream for the given format version into `data`
+/// Version 1 used chained SHA3-256 blocks, versions 2 and 3 a single SHAKE-256 squeeze
+fn apply_keystream(shared_secret: &[u8], data: &mut [u8], version: u16) -> Result<()> {
+    match version {
+        1 => keystream::legacy_sha3_xor(shared_secret, b"", data),
+        2 | 3 => keystream::xor_in_place(shared_secret, KEYSTREAM_LABEL, data),
+        other => return Err(unsupported_version(LAYER_ID, other)),
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -156,7 +293,9 @@ mod tests {
     fn test_hqc_layer_info() {
         let layer = HqcLayer::new();
         assert_eq!(layer.name(), "HQC (Code-based)");
-        assert_eq!(layer.security_level(), 256);
+        assert_eq!(layer.security_class(), SecurityClass::PostQuantumKem { nist_level: 5 });
+        assert_eq!(layer.claims(), "hqc-256 ind-cca2 nist-level-5");
+        assert_eq!(layer.required_key_len(), 32);
     }
     
     #[test]
@@ -1,48 +1,61 @@
 // Layer 3: Quantum Noise Injection
 // This layer adds quantum-inspired noise to defend against AI-powered side-channel attacks
 
-use crate::error::{HybridGuardError, Result};
-use crate::layers::EncryptionLayer;
-use sha3::{Sha3_256, Digest};
+use crate::crypto::keystream;
+use crate::error::Result;
+use crate::layers::stream::{BufferedDecrypt, LayerDecryptState, LayerEncryptState, XorDecryptState, XorEncryptState};
+use crate::layers::{unsupported_version, EncryptionLayer, LayerDescriptor, SecurityClass};
+use zeroize::Zeroize;
+
+/// Identifier recorded in layer descriptors
+const LAYER_ID: &str = "QuantumNoise";
+
+/// Output format written by this build
+const FORMAT_VERSION: u16 = 2;
+
+/// Security claims, as `hybridguard spec` prints them
+const CLAIMS: &str = "shake256-xor obfuscation no-confidentiality";
+
+/// Output framing of the current format, as `hybridguard spec` prints it
+const FRAMING: &str = "input XOR SHAKE-256 of the key; no header, same length as the input";
+
+/// Domain-separation label for the noise stream
+const NOISE_LABEL: &[u8] = b"quantum-noise-layer3";
 
 /// Quantum Noise Injection layer
 /// Adds cryptographically secure random noise to confuse AI attackers
-pub struct QuantumNoiseLayer {
-    security_level: u32,
-}
+///
+/// Output framing (format v2): the input XORed with SHAKE-256 of the key,
+/// with no header and the same length as the input. Deterministic, so a key
+/// must never be reused across messages outside the full pipeline.
+pub struct QuantumNoiseLayer;
 
 impl QuantumNoiseLayer {
     pub fn new() -> Self {
-        Self {
-            security_level: 256,
-        }
+        Self
     }
     
     /// Generate deterministic quantum-inspired noise from key
-    fn generate_noise(&self, key: &[u8], length: usize) -> Vec<u8> {
-        let mut noise = Vec::with_capacity(length);
-        let mut counter = 0u64;
-        
-        while noise.len() < length {
-            let mut hasher = Sha3_256::new();
-            hasher.update(key);
-            hasher.update(b"quantum-noise-layer3");
-            hasher.update(&counter.to_le_bytes());
-            noise.extend_from_slice(&hasher.finalize());
-            counter += 1;
+    /// Version 1 chained SHA3-256 blocks, version 2 squeezes SHAKE-256 once
+    fn generate_noise(&self, key: &[u8], length: usize, version: u16) -> Result<Vec<u8>> {
+        match version {
+            1 => Ok(keystream::legacy_sha3(key, NOISE_LABEL, length)),
+            2 => Ok(keystream::xof(key, NOISE_LABEL, length)),
+            other => Err(unsupported_version(LAYER_ID, other)),
         }
-        
-        noise.truncate(length);
-        noise
     }
 }
 
 impl EncryptionLayer for QuantumNoiseLayer {
     fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
-        log::info!("Layer 3 (Quantum Noise): Injecting noise into {} bytes", data.len());
+        self.encrypt_version(data, key, FORMAT_VERSION)
+    }
+    
+    fn encrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        log::debug!("Layer 3 (Quantum Noise): Injecting noise into {} bytes", data.len());
         
         // Generate deterministic noise from key
-        let noise = self.generate_noise(key, data.len());
+        let noise = self.generate_noise(key, data.len(), version)?;
         
         // XOR data with noise to inject it
         let mut noisy_data = Vec::with_capacity(data.len());
@@ -50,16 +63,25 @@ impl EncryptionLayer for QuantumNoiseLayer {
             noisy_data.push(d ^ n);
         }
         
-        log::info!("Layer 3 (Quantum Noise): Output size {} bytes", noisy_data.len());
+        log::debug!("Layer 3 (Quantum Noise): Output size {} bytes", noisy_data.len());
         
         Ok(noisy_data)
     }
     
+    fn encrypt_owned(&self, mut data: Vec<u8>, key: &[u8]) -> Result<Vec<u8>> {
+        keystream::xor_in_place(key, NOISE_LABEL, &mut data);
+        Ok(data)
+    }
+    
     fn decrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
-        log::info!("Layer 3 (Quantum Noise): Removing noise from {} bytes", data.len());
+        self.decrypt_version(data, key, FORMAT_VERSION)
+    }
+    
+    fn decrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        log::debug!("Layer 3 (Quantum Noise): Removing noise from {} bytes", data.len());
         
         // Generate same deterministic noise from key
-        let noise = self.generate_noise(key, data.len());
+        let noise = self.generate_noise(key, data.len(), version)?;
         
         // XOR again to remove noise (XOR is reversible)
         let mut clean_data = Vec::with_capacity(data.len());
@@ -67,17 +89,67 @@ impl EncryptionLayer for QuantumNoiseLayer {
             clean_data.push(d ^ n);
         }
         
-        log::info!("Layer 3 (Quantum Noise): Cleaned to {} bytes", clean_data.len());
+        log::debug!("Layer 3 (Quantum Noise): Cleaned to {} bytes", clean_data.len());
         
         Ok(clean_data)
     }
     
+    fn decrypt_into(&self, data: &[u8], key: &[u8], version: u16, out: &mut Vec<u8>) -> Result<()> {
+        match version {
+            1 => {
+                let mut clean_data = self.decrypt_version(data, key, version)?;
+                out.clear();
+                out.extend_from_slice(&clean_data);
+                clean_data.zeroize();
+            }
+            2 => {
+                out.clear();
+                out.extend_from_slice(data);
+                keystream::xor_in_place(key, NOISE_LABEL, out);
+            }
+            other => return Err(unsupported_version(LAYER_ID, other)),
+        }
+        Ok(())
+    }
+    
+    fn begin_encrypt(&self, key: &[u8]) -> Result<Box<dyn LayerEncryptState + '_>> {
+        Ok(Box::new(XorEncryptState::new(Vec::new(), keystream::XofStream::new(key, NOISE_LABEL))))
+    }
+    
+    fn begin_decrypt(&self, key: &[u8], version: u16) -> Result<Box<dyn LayerDecryptState + '_>> {
+        match version {
+            // The legacy noise stream is only implemented whole-message
+            1 => Ok(Box::new(BufferedDecrypt::new(self, key, version))),
+            2 => {
+                let stream = keystream::XofStream::new(key, NOISE_LABEL);
+                Ok(Box::new(XorDecryptState::new("noise", 0, Box::new(move |_: &[u8]| Ok(stream)))))
+            }
+            other => Err(unsupported_version(LAYER_ID, other)),
+        }
+    }
+    
     fn name(&self) -> &str {
         "Quantum Noise Injection"
     }
     
-    fn security_level(&self) -> u32 {
-        self.security_level
+    fn security_class(&self) -> SecurityClass {
+        SecurityClass::Obfuscation
+    }
+    
+    fn claims(&self) -> &str {
+        CLAIMS
+    }
+    
+    fn descriptor(&self) -> LayerDescriptor {
+        LayerDescriptor::new(LAYER_ID, FORMAT_VERSION)
+    }
+    
+    fn framing(&self) -> &str {
+        FRAMING
+    }
+    
+    fn readable_versions(&self) -> Vec<u16> {
+        (1..=FORMAT_VERSION).collect()
     }
 }
 
@@ -89,7 +161,9 @@ mod tests {
     fn test_noise_layer_info() {
         let layer = QuantumNoiseLayer::new();
         assert_eq!(layer.name(), "Quantum Noise Injection");
-        assert_eq!(layer.security_level(), 256);
+        assert_eq!(layer.security_class(), SecurityClass::Obfuscation);
+        assert!(!layer.security_class().is_quantum_resistant());
+        assert_eq!(layer.required_key_len(), 32);
     }
     
     #[test]
@@ -121,4 +195,34 @@ mod tests {
         // Should produce same result
         assert_eq!(encrypted1, encrypted2);
     }
+    
+    #[test]
+    fn test_noise_legacy_version() {
+        let layer = QuantumNoiseLayer::new();
+        let key = vec![7u8; 32];
+        let data = b"Written by an older HybridGuard";
+        
+        // Reproduce a version 1 ciphertext with the legacy noise stream
+        let noise = keystream::legacy_sha3(&key, NOISE_LABEL, data.len());
+        let legacy: Vec<u8> = data.iter().zip(noise.iter()).map(|(d, n)| d ^ n).collect();
+        
+        assert_eq!(layer.decrypt_version(&legacy, &key, 1).unwrap(), data.to_vec());
+        assert_eq!(layer.encrypt_version(data, &key, 1).unwrap(), legacy);
+        assert_ne!(layer.encrypt(data, &key).unwrap(), legacy);
+        assert!(layer.decrypt_version(&legacy, &key, 99).is_err());
+    }
+    
+    #[test]
+    fn test_decrypt_into_matches_decrypt_version() {
+        let layer = QuantumNoiseLayer::new();
+        let key = vec![9u8; 32];
+        let data = b"Decrypted into a reused buffer";
+        let mut out = vec![0xEE; 100];
+        for version in [1, 2] {
+            let encrypted = layer.encrypt_version(data, &key, version).unwrap();
+            layer.decrypt_into(&encrypted, &key, version, &mut out).unwrap();
+            assert_eq!(out, layer.decrypt_version(&encrypted, &key, version).unwrap());
+        }
+        assert!(layer.decrypt_into(data, &key, 99, &mut out).is_err());
+    }
 }
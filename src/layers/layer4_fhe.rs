@@ -2,9 +2,33 @@
 // Enables computation on encrypted data without decryption
 // Uses simplified FHE approach for demonstration
 
+use crate::crypto::keystream;
 use crate::error::{HybridGuardError, Result};
-use crate::layers::EncryptionLayer;
+use crate::layers::stream::{BufferedDecrypt, LayerDecryptState, LayerEncryptState};
+use crate::layers::{unsupported_version, EncryptionLayer, LayerDescriptor, SecurityClass};
 use sha2::{Sha256, Digest};
+use zeroize::Zeroize;
+
+/// Identifier recorded in layer descriptors
+const LAYER_ID: &str = "FHE";
+
+/// Output format written by this build
+const FORMAT_VERSION: u16 = 2;
+
+/// Security claims, as `hybridguard spec` prints them
+const CLAIMS: &str = "shake256-xor not-homomorphic no-security-claim";
+
+/// Output framing of the current format, as `hybridguard spec` prints it
+const FRAMING: &str = "pad(input) XOR SHAKE-256 of a key derived from the layer key; pad is 0x80 then zeros to a multiple of 32";
+
+/// Domain-separation label for the keystream
+const KEYSTREAM_LABEL: &[u8] = b"fhe-keystream";
+
+/// Padding block size in bytes
+const BLOCK_SIZE: usize = 32;
+
+/// Fewest key bytes the layer accepts
+const MIN_KEY_LEN: usize = 32;
 
 /// Layer 4: Homomorphic Encryption Layer
 /// 
@@ -13,6 +37,11 @@ use sha2::{Sha256, Digest};
 /// 
 /// Note: This is a simplified implementation for demonstration.
 /// Production systems should use libraries like Microsoft SEAL or OpenFHE.
+///
+/// Output framing (format v2): `pad(input)` XORed with SHAKE-256 of a key
+/// derived from the layer key, with no header. Padding is a 0x80 byte then
+/// zeros up to the next multiple of 32, so output is 1 to 32 bytes longer
+/// than the input. Keys must be at least 32 bytes.
 pub struct FHELayer {
     name: String,
 }
@@ -53,11 +82,11 @@ impl FHELayer {
     }
 
     /// Key derivation for FHE layer
-    fn derive_fhe_key(&self, key: &[u8]) -> Vec<u8> {
+    fn derive_fhe_key(&self, key: &[u8]) -> [u8; 32] {
         let mut hasher = Sha256::new();
         hasher.update(b"FHE-LAYER-KEY-");
         hasher.update(key);
-        hasher.finalize().to_vec()
+        hasher.finalize().into()
     }
 
     /// Pad data to block size
@@ -76,30 +105,41 @@ impl FHELayer {
     }
 
     /// Remove padding from data
+    /// The marker must sit in the final block and be followed only by zeros
     fn unpad_data(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Find the last 0x80 byte (padding marker)
-        if let Some(pos) = data.iter().rposition(|&b| b == 0x80) {
-            Ok(data[..pos].to_vec())
-        } else {
-            Err(HybridGuardError::DecryptionError("Invalid padding".to_string()))
+        Ok(data[..self.padding_start(data)?].to_vec())
+    }
+
+    /// Offset of the padding marker, which is the length of the unpadded data
+    fn padding_start(&self, data: &[u8]) -> Result<usize> {
+        let block_size = 32;
+        if data.is_empty() || data.len() % block_size != 0 {
+            return Err(HybridGuardError::DecryptionError("Invalid padding".to_string()));
+        }
+        
+        match data.iter().rposition(|&b| b != 0x00) {
+            Some(pos) if data[pos] == 0x80 && data.len() - pos <= block_size => Ok(pos),
+            _ => Err(HybridGuardError::DecryptionError("Invalid padding".to_string())),
+        }
+    }
+
+    /// Keystream for the given format version
+    /// Version 1 chained SHA-256 blocks, version 2 squeezes SHAKE-256 once
+    fn keystream(&self, derived_key: &[u8], len: usize, version: u16) -> Result<Vec<u8>> {
+        match version {
+            1 => Ok(keystream::legacy_sha256(derived_key, len)),
+            2 => Ok(keystream::xof(derived_key, KEYSTREAM_LABEL, len)),
+            other => Err(unsupported_version(LAYER_ID, other)),
         }
     }
 
     /// Encrypt with FHE properties (simplified stream cipher approach)
-    fn fhe_encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    fn fhe_encrypt(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
         let derived_key = self.derive_fhe_key(key);
         let padded_data = self.pad_data(data);
         
         // Generate keystream using key
-        let mut keystream = Vec::new();
-        let mut hasher = Sha256::new();
-        
-        for i in 0..(padded_data.len() / 32 + 1) {
-            hasher.update(&derived_key);
-            hasher.update(&i.to_le_bytes());
-            let block = hasher.finalize_reset();
-            keystream.extend_from_slice(&block);
-        }
+        let keystream = self.keystream(&derived_key, padded_data.len(), version)?;
         
         // XOR data with keystream
         let ciphertext: Vec<u8> = padded_data.iter()
@@ -111,19 +151,11 @@ impl FHELayer {
     }
 
     /// Decrypt FHE ciphertext
-    fn fhe_decrypt(&self, ciphertext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    fn fhe_decrypt(&self, ciphertext: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
         let derived_key = self.derive_fhe_key(key);
         
         // Generate same keystream
-        let mut keystream = Vec::new();
-        let mut hasher = Sha256::new();
-        
-        for i in 0..(ciphertext.len() / 32 + 1) {
-            hasher.update(&derived_key);
-            hasher.update(&i.to_le_bytes());
-            let block = hasher.finalize_reset();
-            keystream.extend_from_slice(&block);
-        }
+        let keystream = self.keystream(&derived_key, ciphertext.len(), version)?;
         
         // XOR ciphertext with keystream to get padded plaintext
         let padded_plaintext: Vec<u8> = ciphertext.iter()
@@ -138,43 +170,215 @@ impl FHELayer {
 
 impl EncryptionLayer for FHELayer {
     fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
-        log::info!("Layer 4 (FHE): Encrypting {} bytes", data.len());
-        
-        if data.is_empty() {
-            return Err(HybridGuardError::EncryptionError("Data cannot be empty".to_string()));
-        }
+        self.encrypt_version(data, key, FORMAT_VERSION)
+    }
+    
+    fn encrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        log::debug!("Layer 4 (FHE): Encrypting {} bytes", data.len());
         
-        if key.len() < 32 {
+        // Empty input is fine: padding always produces at least one block
+        if key.len() < MIN_KEY_LEN {
             return Err(HybridGuardError::EncryptionError("Key must be at least 32 bytes".to_string()));
         }
         
-        let result = self.fhe_encrypt(data, key)?;
-        log::info!("Layer 4 (FHE): Encrypted to {} bytes", result.len());
+        let result = self.fhe_encrypt(data, key, version)?;
+        log::debug!("Layer 4 (FHE): Encrypted to {} bytes", result.len());
         Ok(result)
     }
     
+    fn encrypt_owned(&self, mut data: Vec<u8>, key: &[u8]) -> Result<Vec<u8>> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(HybridGuardError::EncryptionError("Key must be at least 32 bytes".to_string()));
+        }
+        let padding_len = BLOCK_SIZE - data.len() % BLOCK_SIZE;
+        data.reserve_exact(padding_len);
+        data.push(0x80);
+        data.resize(data.len() + padding_len - 1, 0x00);
+        keystream::xor_in_place(&self.derive_fhe_key(key), KEYSTREAM_LABEL, &mut data);
+        Ok(data)
+    }
+    
     fn decrypt(&self, ciphertext: &[u8], key: &[u8]) -> Result<Vec<u8>> {
-        log::info!("Layer 4 (FHE): Decrypting {} bytes", ciphertext.len());
+        self.decrypt_version(ciphertext, key, FORMAT_VERSION)
+    }
+    
+    fn decrypt_version(&self, ciphertext: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        log::debug!("Layer 4 (FHE): Decrypting {} bytes", ciphertext.len());
         
         if ciphertext.is_empty() {
             return Err(HybridGuardError::DecryptionError("Ciphertext cannot be empty".to_string()));
         }
         
-        if key.len() < 32 {
+        if key.len() < MIN_KEY_LEN {
             return Err(HybridGuardError::DecryptionError("Key must be at least 32 bytes".to_string()));
         }
         
-        let result = self.fhe_decrypt(ciphertext, key)?;
-        log::info!("Layer 4 (FHE): Decrypted to {} bytes", result.len());
+        let result = self.fhe_decrypt(ciphertext, key, version)?;
+        log::debug!("Layer 4 (FHE): Decrypted to {} bytes", result.len());
         Ok(result)
     }
     
+    fn decrypt_into(&self, ciphertext: &[u8], key: &[u8], version: u16, out: &mut Vec<u8>) -> Result<()> {
+        if version != FORMAT_VERSION {
+            // The legacy keystream is only implemented as a whole buffer
+            let mut plaintext = self.decrypt_version(ciphertext, key, version)?;
+            out.clear();
+            out.extend_from_slice(&plaintext);
+            plaintext.zeroize();
+            return Ok(());
+        }
+        if ciphertext.is_empty() {
+            return Err(HybridGuardError::DecryptionError("Ciphertext cannot be empty".to_string()));
+        }
+        if key.len() < MIN_KEY_LEN {
+            return Err(HybridGuardError::DecryptionError("Key must be at least 32 bytes".to_string()));
+        }
+        
+        out.clear();
+        out.extend_from_slice(ciphertext);
+        keystream::xor_in_place(&self.derive_fhe_key(key), KEYSTREAM_LABEL, out);
+        
+        // Zero the padding before dropping it, and all of `out` if it is invalid,
+        // so no stale bytes sit past the end
+        let len = match self.padding_start(out) {
+            Ok(len) => len,
+            Err(e) => {
+                out.zeroize();
+                return Err(e);
+            }
+        };
+        out[len..].zeroize();
+        out.truncate(len);
+        Ok(())
+    }
+    
+    fn output_len(&self, input_len: usize) -> Result<usize> {
+        // Padding always adds between 1 and BLOCK_SIZE bytes
+        Ok((input_len / BLOCK_SIZE + 1) * BLOCK_SIZE)
+    }
+    
+    fn overhead_bytes(&self) -> Result<usize> {
+        Ok(BLOCK_SIZE)
+    }
+    
+    fn max_input(&self) -> usize {
+        // Padding rounds up to the next whole block
+        usize::MAX - BLOCK_SIZE
+    }
+    
+    fn required_key_len(&self) -> usize {
+        MIN_KEY_LEN
+    }
+    
+    fn begin_encrypt(&self, key: &[u8]) -> Result<Box<dyn LayerEncryptState + '_>> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(HybridGuardError::EncryptionError("Key must be at least 32 bytes".to_string()));
+        }
+        Ok(Box::new(FheEncryptState {
+            stream: keystream::XofStream::new(&self.derive_fhe_key(key), KEYSTREAM_LABEL),
+            len: 0,
+        }))
+    }
+    
+    fn begin_decrypt(&self, key: &[u8], version: u16) -> Result<Box<dyn LayerDecryptState + '_>> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(HybridGuardError::DecryptionError("Key must be at least 32 bytes".to_string()));
+        }
+        match version {
+            // The legacy keystream is only implemented whole-message
+            1 => Ok(Box::new(BufferedDecrypt::new(self, key, version))),
+            2 => Ok(Box::new(FheDecryptState {
+                stream: keystream::XofStream::new(&self.derive_fhe_key(key), KEYSTREAM_LABEL),
+                tail: Vec::with_capacity(2 * BLOCK_SIZE),
+                len: 0,
+            })),
+            other => Err(unsupported_version(LAYER_ID, other)),
+        }
+    }
+    
     fn name(&self) -> &str {
         "FHE (Homomorphic)"
     }
     
-    fn security_level(&self) -> u32 {
-        256 // 256-bit security level
+    fn security_class(&self) -> SecurityClass {
+        SecurityClass::Experimental
+    }
+    
+    fn claims(&self) -> &str {
+        CLAIMS
+    }
+    
+    fn descriptor(&self) -> LayerDescriptor {
+        LayerDescriptor::new(LAYER_ID, FORMAT_VERSION)
+    }
+    
+    fn framing(&self) -> &str {
+        FRAMING
+    }
+    
+    fn readable_versions(&self) -> Vec<u16> {
+        (1..=FORMAT_VERSION).collect()
+    }
+}
+
+/// Streaming encryption: XOR as data arrives, pad in `finish`
+struct FheEncryptState {
+    stream: keystream::XofStream,
+    len: usize,
+}
+
+impl LayerEncryptState for FheEncryptState {
+    fn header(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        self.len += chunk.len();
+        let mut out = chunk.to_vec();
+        self.stream.xor(&mut out);
+        Ok(out)
+    }
+    
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut padding = vec![0x00; BLOCK_SIZE - (self.len % BLOCK_SIZE)];
+        padding[0] = 0x80;
+        self.stream.xor(&mut padding);
+        Ok(padding)
+    }
+}
+
+/// Streaming decryption: the final block holds the padding, so the last
+/// `BLOCK_SIZE` plaintext bytes are held back until `finish`
+struct FheDecryptState {
+    stream: keystream::XofStream,
+    tail: Vec<u8>,
+    len: usize,
+}
+
+impl LayerDecryptState for FheDecryptState {
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        self.len += chunk.len();
+        let mut plain = chunk.to_vec();
+        self.stream.xor(&mut plain);
+        self.tail.extend_from_slice(&plain);
+        
+        let release = self.tail.len().saturating_sub(BLOCK_SIZE);
+        Ok(self.tail.drain(..release).collect())
+    }
+    
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        if self.len == 0 {
+            return Err(HybridGuardError::DecryptionError("Ciphertext cannot be empty".to_string()));
+        }
+        if self.len % BLOCK_SIZE != 0 {
+            return Err(HybridGuardError::DecryptionError("Invalid padding".to_string()));
+        }
+        
+        // Same rule as `unpad_data`: the marker is the last non-zero byte of the final block
+        match self.tail.iter().rposition(|&b| b != 0x00) {
+            Some(pos) if self.tail[pos] == 0x80 => Ok(self.tail[..pos].to_vec()),
+            _ => Err(HybridGuardError::DecryptionError("Invalid padding".to_string())),
+        }
     }
 }
 
@@ -188,6 +392,18 @@ impl Default for FHELayer {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_fhe_layer_info() {
+        let layer = FHELayer::new();
+        assert_eq!(layer.security_class(), SecurityClass::Experimental);
+        assert!(layer.claims().contains("not-homomorphic"));
+        
+        // The layer enforces its own contract too
+        assert_eq!(layer.required_key_len(), 32);
+        assert!(layer.encrypt(b"data", &[0u8; 31]).is_err());
+        assert!(layer.encrypt(b"data", &[0u8; 32]).is_ok());
+    }
+    
     #[test]
     fn test_fhe_encrypt_decrypt() {
         let layer = FHELayer::new();
@@ -201,6 +417,22 @@ mod tests {
         assert_eq!(decrypted, data);
     }
 
+    #[test]
+    fn test_fhe_legacy_version() {
+        let layer = FHELayer::new();
+        let data = b"Written by an older HybridGuard";
+        let key = b"this-is-a-32-byte-secret-key!!!!";
+
+        // Reproduce a version 1 ciphertext with the legacy SHA-256 keystream
+        let padded = layer.pad_data(data);
+        let stream = keystream::legacy_sha256(&layer.derive_fhe_key(key), padded.len());
+        let legacy: Vec<u8> = padded.iter().zip(stream.iter()).map(|(p, k)| p ^ k).collect();
+
+        assert_eq!(layer.decrypt_version(&legacy, key, 1).unwrap(), data.to_vec());
+        assert_eq!(layer.encrypt_version(data, key, 1).unwrap(), legacy);
+        assert_ne!(layer.encrypt(data, key).unwrap(), legacy);
+    }
+
     #[test]
     fn test_homomorphic_add() {
         let layer = FHELayer::new();
@@ -226,8 +458,41 @@ mod tests {
         let layer = FHELayer::new();
         let key = b"this-is-a-32-byte-secret-key!!!!";
 
-        let result = layer.encrypt(&[], key);
-        assert!(result.is_err());
+        let ciphertext = layer.encrypt(&[], key).unwrap();
+        assert_eq!(ciphertext.len(), 32);
+        assert_eq!(layer.decrypt(&ciphertext, key).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_padding_tamper_rejected() {
+        let layer = FHELayer::new();
+        let data = b"Test data";
+        let key = b"this-is-a-32-byte-secret-key!!!!";
+
+        // Flip a bit inside the zero padding
+        let mut ciphertext = layer.encrypt(data, key).unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 0x01;
+        assert!(layer.decrypt(&ciphertext, key).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_into_matches_decrypt_version() {
+        let layer = FHELayer::new();
+        let key = b"this-is-a-32-byte-secret-key!!!!";
+        let mut out = vec![0xEE; 200];
+        for (data, version) in [(&b""[..], 2), (&b"Test data for FHE encryption"[..], 2), (&b"Legacy"[..], 1)] {
+            let ciphertext = layer.encrypt_version(data, key, version).unwrap();
+            layer.decrypt_into(&ciphertext, key, version, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+
+        // A rejected message leaves nothing behind
+        let mut ciphertext = layer.encrypt(b"Test data", key).unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 0x01;
+        assert!(layer.decrypt_into(&ciphertext, key, FORMAT_VERSION, &mut out).is_err());
+        assert!(out.is_empty());
     }
 
     #[test]
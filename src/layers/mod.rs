@@ -1,16 +1,63 @@
 // Encryption layers module
 // Each layer provides independent quantum-resistant encryption
 
+pub mod assessment;
+pub mod compact_kem;
 pub mod layer1_mlkem;
 pub mod layer2_hqc;
 pub mod layer3_noise;
 pub mod layer4_fhe;
+pub mod oqs_support;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod stream;
+mod keypair_cache;
 
-use crate::error::Result;
+use crate::crypto::container::{be_u32, be_u32_at};
+use crate::crypto::hkdf::LAYER_KEY_LEN;
+use crate::error::{HybridGuardError, Result};
+use serde::{Deserialize, Serialize};
+use stream::{BufferedDecrypt, BufferedEncrypt};
+use zeroize::Zeroize;
+
+// Layers can be used on their own; each layer type documents its output framing
+pub use assessment::{SecurityAssessment, SecurityClass};
+pub use compact_kem::CompactKemLayer;
+pub use layer1_mlkem::MlKemLayer;
+pub use layer2_hqc::HqcLayer;
+pub use layer3_noise::QuantumNoiseLayer;
+pub use layer4_fhe::FHELayer;
+pub use stream::{LayerDecryptState, LayerEncryptState};
+
+/// Identifies a layer and the version of its output format
+/// Recorded in every ciphertext so decryption can pick the matching code path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerDescriptor {
+    /// Stable layer identifier, e.g. "ML-KEM-768"
+    pub name: String,
+    
+    /// Output format version of the layer
+    pub version: u16,
+}
+
+impl LayerDescriptor {
+    pub fn new(name: &str, version: u16) -> Self {
+        Self {
+            name: name.to_string(),
+            version,
+        }
+    }
+}
 
 /// Trait that all encryption layers must implement
-pub trait EncryptionLayer {
+///
+/// One layer instance may be shared by many threads, so any cache a layer
+/// keeps must synchronize itself. liboqs `Kem` handles are created per call
+/// through `oqs_support::kem` and never stored; the seeded keypair RNG is
+/// thread-local.
+pub trait EncryptionLayer: Send + Sync {
     /// Encrypt data using this layer
+    /// Empty data is valid input: the pipeline relies on every layer accepting it
     fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>>;
     
     /// Decrypt data using this layer
@@ -19,6 +66,539 @@ pub trait EncryptionLayer {
     /// Get the name of this layer
     fn name(&self) -> &str;
     
-    /// Get security level in bits
-    fn security_level(&self) -> u32;
+    /// What this layer's security rests on, for assessing a whole stack
+    fn security_class(&self) -> SecurityClass;
+    
+    /// Short machine-readable statement of what the layer claims, as
+    /// space-separated tokens (primitive, notion, level)
+    fn claims(&self) -> &str;
+    
+    /// Descriptor of the format `encrypt` currently produces
+    fn descriptor(&self) -> LayerDescriptor;
+    
+    /// One-line description of the byte layout `encrypt` produces, for `hybridguard spec`
+    fn framing(&self) -> &str {
+        "undocumented"
+    }
+    
+    /// Format versions `decrypt_version` accepts, oldest first
+    /// Only the current version is written; the rest are read-only
+    fn readable_versions(&self) -> Vec<u16> {
+        vec![self.descriptor().version]
+    }
+    
+    /// Decrypt data produced by a specific format version of this layer
+    /// Layers that still read older formats override this
+    fn decrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        let current = self.descriptor();
+        if version == current.version {
+            self.decrypt(data, key)
+        } else {
+            Err(unsupported_version(&current.name, version))
+        }
+    }
+    
+    /// Encrypt in a specific format version of this layer
+    /// Only needed to reproduce older formats, e.g. to build migration fixtures
+    fn encrypt_version(&self, data: &[u8], key: &[u8], version: u16) -> Result<Vec<u8>> {
+        let current = self.descriptor();
+        if version == current.version {
+            self.encrypt(data, key)
+        } else {
+            Err(unsupported_version(&current.name, version))
+        }
+    }
+    
+    /// Encrypt a buffer the caller no longer needs
+    /// Layers that can work in place reuse its allocation; the default copies
+    fn encrypt_owned(&self, data: Vec<u8>, key: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt(&data, key)
+    }
+    
+    /// Decrypt data of a specific format version into `out`, replacing its contents
+    /// Lets a caller reuse one buffer across messages; layers that can decrypt
+    /// in place override this, the default copies `decrypt_version`'s output
+    fn decrypt_into(&self, data: &[u8], key: &[u8], version: u16, out: &mut Vec<u8>) -> Result<()> {
+        let mut plaintext = self.decrypt_version(data, key, version)?;
+        out.clear();
+        out.extend_from_slice(&plaintext);
+        plaintext.zeroize();
+        Ok(())
+    }
+    
+    /// Exact length of this layer's output for an input of `input_len` bytes
+    /// The default suits layers that do not change the length
+    fn output_len(&self, input_len: usize) -> Result<usize> {
+        Ok(input_len)
+    }
+    
+    /// Length of the output `decrypt_version` would give for `data`, read
+    /// from its framing without decrypting anything
+    /// The default, the input length, bounds layers whose output is never longer
+    fn declared_output_len(&self, data: &[u8], _version: u16) -> Result<usize> {
+        Ok(data.len())
+    }
+    
+    /// Most bytes `encrypt` adds to any input, for sizing buffers
+    /// The default suits layers that do not change the length
+    fn overhead_bytes(&self) -> Result<usize> {
+        Ok(0)
+    }
+    
+    /// Smallest input `encrypt` accepts
+    /// Every built-in layer takes empty input; a layer that needs more must say so here
+    fn min_input(&self) -> usize {
+        0
+    }
+    
+    /// Largest input `encrypt` accepts, so that `output_len` still fits a usize
+    /// The default suits layers that do not change the length
+    fn max_input(&self) -> usize {
+        usize::MAX
+    }
+    
+    /// Fewest key bytes this layer may be given
+    /// Both pipelines refuse a shorter key before any layer runs; the default
+    /// is the length every built-in layer key is derived at
+    fn required_key_len(&self) -> usize {
+        LAYER_KEY_LEN
+    }
+    
+    /// Start encrypting one message chunk by chunk
+    /// The default buffers the whole message and calls `encrypt` at the end
+    fn begin_encrypt(&self, key: &[u8]) -> Result<Box<dyn LayerEncryptState + '_>> {
+        Ok(Box::new(BufferedEncrypt::new(self, key)))
+    }
+    
+    /// Start decrypting one message of the given format version chunk by chunk
+    /// The default buffers the whole message and calls `decrypt_version` at the end
+    fn begin_decrypt(&self, key: &[u8], version: u16) -> Result<Box<dyn LayerDecryptState + '_>> {
+        Ok(Box::new(BufferedDecrypt::new(self, key, version)))
+    }
+}
+
+/// Bytes of the big-endian KEM ciphertext length that starts KEM layer output (format v3+)
+pub const KEM_LENGTH_PREFIX: usize = 4;
+
+/// Largest KEM ciphertext a length prefix may announce; HQC-256 needs about 14.5 KB
+const MAX_KEM_CT_LEN: usize = 64 * 1024;
+
+/// Most bytes a KEM layer puts before its payload, whatever the parameter set
+pub(crate) const MAX_KEM_HEADER_LEN: usize = KEM_LENGTH_PREFIX + MAX_KEM_CT_LEN;
+
+/// Output of a KEM layer, split at its framing boundary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedMessage {
+    /// KEM encapsulation of the message's one-time shared secret
+    pub kem_ct: Vec<u8>,
+    /// Payload encrypted under the shared secret, as long as the plaintext
+    pub sym_ct: Vec<u8>,
+}
+
+impl SealedMessage {
+    /// Parse `u32 BE length || kem_ct || sym_ct`, the framing `encrypt` returns
+    /// The announced length must be plausible and equal `expected_kem_ct_len`,
+    /// what this build's KEM produces; a mismatch means the message was written
+    /// with a different parameter set and is reported as such
+    pub fn parse(data: &[u8], expected_kem_ct_len: usize) -> Result<Self> {
+        let (kem_ct, sym_ct) = parse_framed(data, expected_kem_ct_len)?;
+        Ok(Self {
+            kem_ct: kem_ct.to_vec(),
+            sym_ct: sym_ct.to_vec(),
+        })
+    }
+    
+    /// Split unframed `kem_ct || sym_ct` whose KEM ciphertext is `kem_ct_len` bytes
+    /// This is the format v1/v2 layout, which relies on the caller knowing the length
+    pub fn split(data: &[u8], kem_ct_len: usize) -> Result<Self> {
+        let (kem_ct, sym_ct) = split_bare(data, kem_ct_len)?;
+        Ok(Self {
+            kem_ct: kem_ct.to_vec(),
+            sym_ct: sym_ct.to_vec(),
+        })
+    }
+    
+    /// The single-buffer framing `encrypt` returns: `u32 BE length || kem_ct || sym_ct`
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = frame_kem_ct(&self.kem_ct);
+        bytes.extend_from_slice(&self.sym_ct);
+        bytes
+    }
+    
+    /// The unframed `kem_ct || sym_ct` layout of format v1/v2
+    pub(crate) fn into_unframed(self) -> Vec<u8> {
+        let mut bytes = self.kem_ct;
+        bytes.extend_from_slice(&self.sym_ct);
+        bytes
+    }
+}
+
+/// `kem_ct` and `sym_ct` of length-prefixed output, borrowed
+fn parse_framed(data: &[u8], expected_kem_ct_len: usize) -> Result<(&[u8], &[u8])> {
+    let prefix = data
+        .get(..KEM_LENGTH_PREFIX)
+        .ok_or_else(|| HybridGuardError::DecryptionError("Data too short for a KEM length prefix".to_string()))?;
+    let kem_ct_len = be_u32_at(prefix, 0) as usize;
+    if kem_ct_len == 0 || kem_ct_len > MAX_KEM_CT_LEN {
+        return Err(HybridGuardError::DecryptionError(format!(
+            "Corrupted KEM length prefix: {} bytes announced",
+            kem_ct_len
+        )));
+    }
+    if kem_ct_len != expected_kem_ct_len {
+        return Err(HybridGuardError::DecryptionError(format!(
+            "KEM ciphertext is {} bytes but this build's parameter set uses {}; \
+             was it written with a different liboqs configuration?",
+            kem_ct_len, expected_kem_ct_len
+        )));
+    }
+    split_bare(&data[KEM_LENGTH_PREFIX..], kem_ct_len)
+}
+
+/// `kem_ct` and `sym_ct` of unframed output, borrowed
+fn split_bare(data: &[u8], kem_ct_len: usize) -> Result<(&[u8], &[u8])> {
+    if data.len() < kem_ct_len {
+        return Err(HybridGuardError::DecryptionError(format!(
+            "{} bytes is too short for a {}-byte KEM ciphertext",
+            data.len(),
+            kem_ct_len
+        )));
+    }
+    Ok(data.split_at(kem_ct_len))
+}
+
+/// How a KEM layer's output starts, by layer format version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KemFraming {
+    /// Format v1/v2: the KEM ciphertext at offset 0, its length implied by the KEM
+    Bare,
+    /// Format v3+: a big-endian u32 length before the KEM ciphertext
+    LengthPrefixed,
+}
+
+impl KemFraming {
+    pub(crate) fn for_version(version: u16) -> Self {
+        if version >= 3 {
+            KemFraming::LengthPrefixed
+        } else {
+            KemFraming::Bare
+        }
+    }
+    
+    /// Bytes before the payload for a `kem_ct_len`-byte KEM ciphertext
+    pub(crate) fn header_len(self, kem_ct_len: usize) -> usize {
+        match self {
+            KemFraming::Bare => kem_ct_len,
+            KemFraming::LengthPrefixed => KEM_LENGTH_PREFIX + kem_ct_len,
+        }
+    }
+    
+    pub(crate) fn split(self, data: &[u8], kem_ct_len: usize) -> Result<SealedMessage> {
+        match self {
+            KemFraming::Bare => SealedMessage::split(data, kem_ct_len),
+            KemFraming::LengthPrefixed => SealedMessage::parse(data, kem_ct_len),
+        }
+    }
+    
+    /// `kem_ct` and `sym_ct` as slices of `data`, for decrypting without copies
+    pub(crate) fn split_borrowed(self, data: &[u8], kem_ct_len: usize) -> Result<(&[u8], &[u8])> {
+        match self {
+            KemFraming::Bare => split_bare(data, kem_ct_len),
+            KemFraming::LengthPrefixed => parse_framed(data, kem_ct_len),
+        }
+    }
+    
+    pub(crate) fn join(self, sealed: SealedMessage) -> Vec<u8> {
+        match self {
+            KemFraming::Bare => sealed.into_unframed(),
+            KemFraming::LengthPrefixed => sealed.into_bytes(),
+        }
+    }
+}
+
+/// Length prefix and KEM ciphertext, the header of framed KEM layer output
+pub(crate) fn frame_kem_ct(kem_ct: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(KEM_LENGTH_PREFIX + kem_ct.len());
+    framed.extend_from_slice(&be_u32(kem_ct.len() as u32));
+    framed.extend_from_slice(kem_ct);
+    framed
+}
+
+/// Error for a ciphertext written by a layer format this build cannot read
+pub(crate) fn unsupported_version(layer: &str, version: u16) -> HybridGuardError {
+    HybridGuardError::UnsupportedFormat(format!("{} layer format version {}", layer, version))
+}
+
+/// Check that an `input_len`-byte message fits every layer of `stack`, in
+/// encryption order, before any of them runs: each layer's input is the
+/// previous layer's output and must lie within its `min_input..=max_input`
+pub fn check_input_len(stack: &[&dyn EncryptionLayer], input_len: usize) -> Result<()> {
+    let mut len = input_len;
+    for (index, layer) in stack.iter().enumerate() {
+        if len < layer.min_input() || len > layer.max_input() {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "a {}-byte message cannot be encrypted: layer {} ({}) takes {} to {} bytes and would be given {}",
+                input_len,
+                index + 1,
+                layer.name(),
+                layer.min_input(),
+                layer.max_input(),
+                len
+            )));
+        }
+        len = layer.output_len(len)?;
+    }
+    Ok(())
+}
+
+/// Check that each layer of `stack` gets at least the key it requires, before
+/// any of them runs; `keys[i]` is the key of `stack[i]`
+pub fn check_key_lens(stack: &[&dyn EncryptionLayer], keys: &[&[u8]]) -> Result<()> {
+    if stack.len() != keys.len() {
+        return Err(HybridGuardError::InvalidInput(format!("{} keys for a {}-layer stack", keys.len(), stack.len())));
+    }
+    for (index, (layer, key)) in stack.iter().zip(keys).enumerate() {
+        if key.len() < layer.required_key_len() {
+            return Err(HybridGuardError::KeyTooShort(format!(
+                "layer {} ({}) needs a key of at least {} bytes but was given {}",
+                index + 1,
+                layer.name(),
+                layer.required_key_len(),
+                key.len()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Every built-in layer, in pipeline order
+/// Tests and tooling iterate this instead of naming layer types directly
+pub fn registry() -> Vec<Box<dyn EncryptionLayer>> {
+    vec![
+        Box::new(MlKemLayer::new()),
+        Box::new(HqcLayer::new()),
+        Box::new(QuantumNoiseLayer::new()),
+        Box::new(FHELayer::new()),
+    ]
+}
+
+/// Descriptors of the built-in layers as written by this build
+pub fn current_descriptors() -> Vec<LayerDescriptor> {
+    registry().iter().map(|layer| layer.descriptor()).collect()
+}
+
+/// Descriptors of every built-in layer a ciphertext may name: the standard
+/// stack and the compact KEM that stands in for its layers 1 and 2
+pub fn readable_descriptors() -> Vec<LayerDescriptor> {
+    let mut descriptors = current_descriptors();
+    descriptors.push(CompactKemLayer::new().descriptor());
+    descriptors
+}
+
+/// Descriptors implied by ciphertexts written before descriptors were recorded
+pub fn legacy_descriptors() -> Vec<LayerDescriptor> {
+    registry()
+        .iter()
+        .map(|layer| LayerDescriptor::new(&layer.descriptor().name, 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn message() -> Vec<u8> {
+        (0..5000u32).map(|i| (i * 131 % 251) as u8).collect()
+    }
+    
+    fn stream_encrypt(layer: &dyn EncryptionLayer, data: &[u8], key: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut state = layer.begin_encrypt(key).unwrap();
+        let mut out = state.header();
+        for chunk in data.chunks(chunk_size) {
+            out.extend(state.process_chunk(chunk).unwrap());
+        }
+        out.extend(state.finish().unwrap());
+        out
+    }
+    
+    fn stream_decrypt(layer: &dyn EncryptionLayer, data: &[u8], key: &[u8], chunk_size: usize) -> Result<Vec<u8>> {
+        let mut state = layer.begin_decrypt(key, layer.descriptor().version)?;
+        let mut out = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            out.extend(state.process_chunk(chunk)?);
+        }
+        out.extend(state.finish()?);
+        Ok(out)
+    }
+    
+    #[test]
+    fn test_streaming_matches_whole_buffer() {
+        let key = vec![0x3Cu8; 32];
+        for data in [Vec::new(), vec![0x80; 31], vec![0x00; 64], message()] {
+            for layer in registry() {
+                for chunk_size in [1, 7, 32, 4096] {
+                    let streamed = stream_encrypt(layer.as_ref(), &data, &key, chunk_size);
+                    let whole = layer.encrypt(&data, &key).unwrap();
+                    
+                    // KEM encapsulation is randomized, so compare through the other path
+                    assert_eq!(streamed.len(), whole.len(), "layer {}", layer.name());
+                    assert_eq!(layer.decrypt(&streamed, &key).unwrap(), data, "layer {}", layer.name());
+                    assert_eq!(stream_decrypt(layer.as_ref(), &whole, &key, chunk_size).unwrap(), data, "layer {}", layer.name());
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn test_streaming_deterministic_layers_identical() {
+        let key = vec![0x3Cu8; 32];
+        let data = message();
+        for layer in [&QuantumNoiseLayer::new() as &dyn EncryptionLayer, &FHELayer::new()] {
+            assert_eq!(stream_encrypt(layer, &data, &key, 100), layer.encrypt(&data, &key).unwrap());
+        }
+    }
+    
+    #[test]
+    fn test_owned_matches_borrowed() {
+        let key = vec![0x3Cu8; 32];
+        for data in [Vec::new(), vec![0x80; 31], vec![0x00; 64], message()] {
+            for layer in registry() {
+                let owned = layer.encrypt_owned(data.clone(), &key).unwrap();
+                assert_eq!(owned.len(), layer.output_len(data.len()).unwrap(), "layer {}", layer.name());
+                assert_eq!(layer.decrypt(&owned, &key).unwrap(), data, "layer {}", layer.name());
+            }
+            for layer in [&QuantumNoiseLayer::new() as &dyn EncryptionLayer, &FHELayer::new()] {
+                assert_eq!(layer.encrypt_owned(data.clone(), &key).unwrap(), layer.encrypt(&data, &key).unwrap());
+            }
+        }
+    }
+    
+    #[test]
+    fn test_decrypt_into_matches_decrypt_version() {
+        let key = vec![0x3Cu8; 32];
+        // One buffer for everything, starting longer than most outputs
+        let mut out = vec![0xEE; 6000];
+        for data in [message(), Vec::new(), vec![0x80; 31], vec![0x00; 64]] {
+            for layer in registry() {
+                for version in layer.readable_versions() {
+                    let encrypted = layer.encrypt_version(&data, &key, version).unwrap();
+                    layer.decrypt_into(&encrypted, &key, version, &mut out).unwrap();
+                    assert_eq!(out, layer.decrypt_version(&encrypted, &key, version).unwrap(), "layer {} v{}", layer.name(), version);
+                    assert_eq!(out, data, "layer {} v{}", layer.name(), version);
+                }
+            }
+        }
+        let compact = CompactKemLayer::new();
+        let compact_key = vec![0x3Cu8; 2 * LAYER_KEY_LEN];
+        let encrypted = compact.encrypt(&message(), &compact_key).unwrap();
+        compact.decrypt_into(&encrypted, &compact_key, 1, &mut out).unwrap();
+        assert_eq!(out, message());
+    }
+    
+    #[test]
+    fn test_streaming_rejects_truncation() {
+        let key = vec![0x3Cu8; 32];
+        for layer in registry() {
+            let whole = layer.encrypt(&message(), &key).unwrap();
+            let truncated = &whole[..whole.len() - 1];
+            let result = stream_decrypt(layer.as_ref(), truncated, &key, 64);
+            // XOR layers cannot detect truncation, but must never return the full message
+            assert_ne!(result.ok(), Some(message()), "layer {}", layer.name());
+        }
+        
+        let fhe = FHELayer::new();
+        assert!(stream_decrypt(&fhe, &[], &key, 64).is_err());
+    }
+    
+    #[test]
+    fn test_small_inputs_through_every_layer() {
+        let key = vec![0x3Cu8; 32];
+        for layer in registry() {
+            assert_eq!(layer.min_input(), 0, "layer {}", layer.name());
+            for len in 0..=130usize {
+                let data: Vec<u8> = (0..len).map(|i| (i * 7 + len) as u8).collect();
+                let encrypted = layer.encrypt(&data, &key).unwrap();
+                assert_eq!(encrypted.len(), layer.output_len(len).unwrap(), "layer {} at {} bytes", layer.name(), len);
+                assert!(encrypted.len() <= len + layer.overhead_bytes().unwrap(), "layer {} at {} bytes", layer.name(), len);
+                assert_eq!(layer.decrypt(&encrypted, &key).unwrap(), data, "layer {} at {} bytes", layer.name(), len);
+                assert_eq!(stream_decrypt(layer.as_ref(), &encrypted, &key, 16).unwrap(), data, "layer {} at {} bytes", layer.name(), len);
+            }
+        }
+    }
+    
+    /// Takes 16 to 64 bytes; never runs, since the contract is checked first
+    struct BlockLayer;
+    
+    impl EncryptionLayer for BlockLayer {
+        fn encrypt(&self, _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
+            unreachable!("input contracts are checked before any layer runs")
+        }
+        
+        fn decrypt(&self, _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
+            unreachable!("input contracts are checked before any layer runs")
+        }
+        
+        fn name(&self) -> &str {
+            "Block"
+        }
+        
+        fn security_class(&self) -> SecurityClass {
+            SecurityClass::Experimental
+        }
+        
+        fn claims(&self) -> &str {
+            "test"
+        }
+        
+        fn descriptor(&self) -> LayerDescriptor {
+            LayerDescriptor::new("BLOCK", 1)
+        }
+        
+        fn min_input(&self) -> usize {
+            16
+        }
+        
+        fn max_input(&self) -> usize {
+            64
+        }
+    }
+    
+    #[test]
+    fn test_input_contracts_name_the_layer() {
+        let (noise, fhe) = (QuantumNoiseLayer::new(), FHELayer::new());
+        let stack: [&dyn EncryptionLayer; 3] = [&noise, &BlockLayer, &fhe];
+        assert!(check_input_len(&stack, 16).is_ok());
+        assert!(check_input_len(&stack, 64).is_ok());
+        for len in [0, 15, 65] {
+            match check_input_len(&stack, len) {
+                Err(HybridGuardError::InvalidInput(message)) => assert!(message.contains("layer 2 (Block)"), "{}", message),
+                other => panic!("{} bytes: {:?}", len, other),
+            }
+        }
+        
+        // The constraint applies to what reaches the layer: FHE padding rounds 32 bytes up to 64
+        let stack: [&dyn EncryptionLayer; 2] = [&fhe, &BlockLayer];
+        assert!(check_input_len(&stack, 0).is_ok());
+        assert!(check_input_len(&stack, 63).is_ok());
+        assert!(check_input_len(&stack, 64).is_err());
+        
+        // Keys are checked the same way, each against its own layer
+        let (long, short) = ([0u8; 32], [0u8; 16]);
+        let stack: [&dyn EncryptionLayer; 3] = [&noise, &BlockLayer, &fhe];
+        assert!(check_key_lens(&stack, &[&long, &long, &long]).is_ok());
+        match check_key_lens(&stack, &[&long, &short, &long]) {
+            Err(HybridGuardError::KeyTooShort(message)) => assert!(message.contains("layer 2 (Block)"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+        
+        // The built-in stack takes anything from empty up to its first layer's maximum
+        let registry = registry();
+        let stack: Vec<&dyn EncryptionLayer> = registry.iter().map(|layer| layer.as_ref()).collect();
+        assert!(check_input_len(&stack, 0).is_ok());
+        assert!(check_input_len(&stack, u32::MAX as usize).is_ok());
+        match check_input_len(&stack, usize::MAX) {
+            Err(HybridGuardError::InvalidInput(message)) => assert!(message.contains("layer 1 (ML-KEM"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+    }
 }
@@ -0,0 +1,150 @@
+// The one place liboqs KEM and signature handles are created
+// liboqs sets itself up on first use, and a first use racing others on many
+// threads has been seen to fail a handle that the next attempt creates fine.
+// `kem` and `sig` initialize liboqs once behind a `Once`, then retry a failed
+// `Kem::new` or `Sig::new` a bounded number of times with backoff. A failure
+// that persists becomes `LayerUnavailable`, saying whether the linked liboqs
+// was built with the algorithm at all, since no retry helps when it was not.
+
+use crate::error::{HybridGuardError, Result};
+use oqs::kem::{Algorithm, Kem};
+use oqs::sig::Sig;
+use std::fmt::Display;
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+/// Attempts at creating a handle before the layer is reported unavailable
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// Wait before the first retry, doubled before each later one
+const INITIAL_BACKOFF: Duration = Duration::from_millis(2);
+
+static INIT: Once = Once::new();
+
+/// Initialize liboqs, once per process however many threads ask at once
+pub fn init() {
+    INIT.call_once(oqs::init);
+}
+
+/// A KEM handle for `algorithm`, which `layer` needs
+pub fn kem(layer: &str, algorithm: Algorithm) -> Result<Kem> {
+    retry(layer, algorithm, || Kem::new(algorithm))
+}
+
+/// A signature handle for `algorithm`, which `purpose` needs
+pub fn sig(purpose: &str, algorithm: oqs::sig::Algorithm) -> Result<Sig> {
+    retry(purpose, algorithm, || Sig::new(algorithm))
+}
+
+/// A liboqs algorithm of either kind
+trait OqsAlgorithm: Copy + Display {
+    fn enabled(self) -> bool;
+}
+
+impl OqsAlgorithm for Algorithm {
+    fn enabled(self) -> bool {
+        self.is_enabled()
+    }
+}
+
+impl OqsAlgorithm for oqs::sig::Algorithm {
+    fn enabled(self) -> bool {
+        self.is_enabled()
+    }
+}
+
+/// `create` after initializing liboqs, retried while it fails transiently
+fn retry<T, A: OqsAlgorithm>(layer: &str, algorithm: A, mut create: impl FnMut() -> oqs::Result<T>) -> Result<T> {
+    init();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match create() {
+            Ok(handle) => return Ok(handle),
+            // No retry enables an algorithm liboqs was built without
+            Err(e @ oqs::Error::AlgorithmDisabled) => return Err(unavailable(layer, algorithm, &e, attempt)),
+            Err(e) if attempt >= MAX_ATTEMPTS => return Err(unavailable(layer, algorithm, &e, attempt)),
+            Err(e) => {
+                log::debug!("{} ({}): creating a handle failed ({}), retrying in {:?}", layer, algorithm, e, backoff);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn unavailable<A: OqsAlgorithm>(layer: &str, algorithm: A, error: &dyn Display, attempts: u32) -> HybridGuardError {
+    let hint = if algorithm.enabled() {
+        format!(
+            "the linked liboqs has {} compiled in, but creating it failed {} time(s) ({}); check memory limits and the liboqs build",
+            algorithm, attempts, error
+        )
+    } else {
+        format!("the linked liboqs was built without {}; rebuild liboqs with it enabled", algorithm)
+    };
+    HybridGuardError::LayerUnavailable { layer: layer.to_string(), algorithm: algorithm.to_string(), hint }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_transient_failures_are_retried() {
+        let calls = Cell::new(0);
+        let handle = retry("HQC", Algorithm::HqcRmrs256, || {
+            calls.set(calls.get() + 1);
+            if calls.get() < MAX_ATTEMPTS {
+                Err(oqs::Error::Error)
+            } else {
+                Ok(calls.get())
+            }
+        });
+        assert_eq!(handle.unwrap(), MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_persistent_failures_name_the_layer() {
+        let calls = Cell::new(0);
+        let result: Result<()> = retry("HQC", Algorithm::HqcRmrs256, || {
+            calls.set(calls.get() + 1);
+            Err(oqs::Error::Error)
+        });
+        assert_eq!(calls.get(), MAX_ATTEMPTS);
+        let Err(HybridGuardError::LayerUnavailable { layer, algorithm, hint }) = result else {
+            panic!("expected LayerUnavailable, got {:?}", result);
+        };
+        assert_eq!(layer, "HQC");
+        assert_eq!(algorithm, Algorithm::HqcRmrs256.to_string());
+        assert!(hint.contains("compiled in"), "{}", hint);
+    }
+
+    #[test]
+    fn test_disabled_algorithms_are_not_retried() {
+        let calls = Cell::new(0);
+        let result: Result<()> = retry("ML-KEM-768", Algorithm::Kyber768, || {
+            calls.set(calls.get() + 1);
+            Err(oqs::Error::AlgorithmDisabled)
+        });
+        assert_eq!(calls.get(), 1);
+        assert!(matches!(result, Err(HybridGuardError::LayerUnavailable { .. })));
+    }
+
+    #[test]
+    fn test_kem_handles_from_many_threads() {
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                thread::spawn(move || {
+                    let algorithm = if i % 2 == 0 { Algorithm::Kyber768 } else { Algorithm::HqcRmrs256 };
+                    kem("test", algorithm).map(|kem| kem.length_ciphertext())
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap().unwrap() > 0);
+        }
+    }
+}
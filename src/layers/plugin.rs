@@ -0,0 +1,239 @@
+// Out-of-tree encryption layers loaded from shared libraries
+// A plugin is a cdylib exporting the C functions below; `ExternalLayer`
+// wraps one as an `EncryptionLayer`. Plugins are only ever loaded from a path
+// the caller names (`--plugin` or `HybridGuardBuilder::with_plugin`), never
+// discovered from a directory, since loading one runs its code in-process.
+//
+// ABI version 1, all functions `extern "C"`:
+//
+//   u32   hg_layer_abi_version(void)
+//   void* hg_layer_create(void)                       NULL on failure
+//   i32   hg_layer_descriptor(void*, HgLayerInfo*)
+//   i32   hg_layer_encrypt(void*, data, data_len, key, key_len, out, out_cap, out_len*)
+//   i32   hg_layer_decrypt(void*, data, data_len, key, key_len, out, out_cap, out_len*)
+//   void  hg_layer_destroy(void*)
+//
+// Status 0 is success; anything else fails the call. `encrypt` must write
+// exactly data_len + overhead bytes and `decrypt` at most data_len, so the
+// caller sizes `out` up front. A handle may be used from several threads at
+// once and must synchronize any state it keeps.
+
+use crate::error::{HybridGuardError, Result};
+use crate::layers::{EncryptionLayer, LayerDescriptor, SecurityClass};
+use libloading::Library;
+use std::ffi::c_void;
+use std::path::{Path, PathBuf};
+
+/// Plugin ABI version this build loads
+pub const ABI_VERSION: u32 = 1;
+
+/// Status returned by a successful plugin call
+pub const HG_OK: i32 = 0;
+
+/// Longest layer name a plugin may register
+const MAX_NAME_LEN: usize = 64;
+
+/// Security claims of every external layer; the crate cannot vouch for them
+const CLAIMS: &str = "external unreviewed";
+
+/// What `hg_layer_descriptor` fills in
+#[repr(C)]
+pub struct HgLayerInfo {
+    /// UTF-8 layer name, owned by the plugin and valid until `hg_layer_destroy`
+    pub name: *const u8,
+    pub name_len: usize,
+    /// Output format version recorded in ciphertexts
+    pub version: u16,
+    /// Bytes `hg_layer_encrypt` adds to every input
+    pub overhead: u32,
+}
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type CreateFn = unsafe extern "C" fn() -> *mut c_void;
+type DescriptorFn = unsafe extern "C" fn(*mut c_void, *mut HgLayerInfo) -> i32;
+type TransformFn = unsafe extern "C" fn(*mut c_void, *const u8, usize, *const u8, usize, *mut u8, usize, *mut usize) -> i32;
+type DestroyFn = unsafe extern "C" fn(*mut c_void);
+
+/// An encryption layer implemented by a loaded plugin
+pub struct ExternalLayer {
+    handle: *mut c_void,
+    encrypt_fn: TransformFn,
+    decrypt_fn: TransformFn,
+    destroy_fn: DestroyFn,
+    descriptor: LayerDescriptor,
+    overhead: usize,
+    path: PathBuf,
+    // Declared last so the code stays mapped until the handle is destroyed
+    _library: Library,
+}
+
+// The ABI requires handles to be usable from any thread
+unsafe impl Send for ExternalLayer {}
+unsafe impl Sync for ExternalLayer {}
+
+impl ExternalLayer {
+    /// Load the plugin at `path`, refusing any ABI version but `ABI_VERSION`
+    pub fn load(path: &Path) -> Result<Self> {
+        let fail = |what: String| HybridGuardError::UnsupportedFormat(format!("plugin {}: {}", path.display(), what));
+
+        // Safety: loading runs the library's initializers; the caller chose the path
+        let library = unsafe { Library::new(path) }.map_err(|e| fail(e.to_string()))?;
+
+        // Safety: each symbol is read at the type ABI version 1 declares, and
+        // the version is checked before any other symbol is resolved
+        unsafe {
+            let abi_version = *library.get::<AbiVersionFn>(b"hg_layer_abi_version\0").map_err(|e| fail(e.to_string()))?;
+            let version = abi_version();
+            if version != ABI_VERSION {
+                return Err(fail(format!("speaks plugin ABI v{}, this build loads v{}", version, ABI_VERSION)));
+            }
+
+            let create = *library.get::<CreateFn>(b"hg_layer_create\0").map_err(|e| fail(e.to_string()))?;
+            let describe = *library.get::<DescriptorFn>(b"hg_layer_descriptor\0").map_err(|e| fail(e.to_string()))?;
+            let encrypt_fn = *library.get::<TransformFn>(b"hg_layer_encrypt\0").map_err(|e| fail(e.to_string()))?;
+            let decrypt_fn = *library.get::<TransformFn>(b"hg_layer_decrypt\0").map_err(|e| fail(e.to_string()))?;
+            let destroy_fn = *library.get::<DestroyFn>(b"hg_layer_destroy\0").map_err(|e| fail(e.to_string()))?;
+
+            let handle = create();
+            if handle.is_null() {
+                return Err(fail("hg_layer_create failed".to_string()));
+            }
+            let mut info = HgLayerInfo { name: std::ptr::null(), name_len: 0, version: 0, overhead: 0 };
+            let status = describe(handle, &mut info);
+            let name = if status == HG_OK && !info.name.is_null() {
+                std::str::from_utf8(std::slice::from_raw_parts(info.name, info.name_len)).ok().map(str::to_string)
+            } else {
+                None
+            };
+            let name = match name {
+                Some(name) if valid_name(&name) => name,
+                _ => {
+                    destroy_fn(handle);
+                    return Err(fail(format!(
+                        "hg_layer_descriptor must give a name of 1 to {} printable ASCII characters",
+                        MAX_NAME_LEN
+                    )));
+                }
+            };
+
+            Ok(Self {
+                handle,
+                encrypt_fn,
+                decrypt_fn,
+                destroy_fn,
+                descriptor: LayerDescriptor::new(&name, info.version),
+                overhead: info.overhead as usize,
+                path: path.to_path_buf(),
+                _library: library,
+            })
+        }
+    }
+
+    /// Library this layer was loaded from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run one transform into a buffer of `capacity` bytes
+    fn call(&self, transform: TransformFn, which: &str, data: &[u8], key: &[u8], capacity: usize) -> Result<Vec<u8>> {
+        let mut out = vec![0u8; capacity];
+        let mut out_len = 0usize;
+        // Safety: every pointer is valid for the length passed beside it
+        let status = unsafe {
+            transform(self.handle, data.as_ptr(), data.len(), key.as_ptr(), key.len(), out.as_mut_ptr(), out.len(), &mut out_len)
+        };
+        if status != HG_OK {
+            return Err(HybridGuardError::Layer(format!("{} {} failed with status {}", self.descriptor.name, which, status)));
+        }
+        if out_len > capacity {
+            return Err(HybridGuardError::Layer(format!(
+                "{} {} reported {} bytes into a {} byte buffer",
+                self.descriptor.name, which, out_len, capacity
+            )));
+        }
+        out.truncate(out_len);
+        Ok(out)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && name.bytes().all(|b| b.is_ascii_graphic())
+}
+
+impl Drop for ExternalLayer {
+    fn drop(&mut self) {
+        // Safety: the handle came from this library's hg_layer_create and is dropped once
+        unsafe { (self.destroy_fn)(self.handle) }
+    }
+}
+
+impl EncryptionLayer for ExternalLayer {
+    fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        let expected = self.output_len(data.len())?;
+        let out = self.call(self.encrypt_fn, "encrypt", data, key, expected)?;
+        if out.len() != expected {
+            return Err(HybridGuardError::Layer(format!(
+                "{} encrypt wrote {} bytes, its descriptor promises {}",
+                self.descriptor.name,
+                out.len(),
+                expected
+            )));
+        }
+        Ok(out)
+    }
+
+    fn decrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        self.call(self.decrypt_fn, "decrypt", data, key, data.len())
+    }
+
+    fn name(&self) -> &str {
+        &self.descriptor.name
+    }
+
+    fn security_class(&self) -> SecurityClass {
+        SecurityClass::Experimental
+    }
+
+    fn claims(&self) -> &str {
+        CLAIMS
+    }
+
+    fn descriptor(&self) -> LayerDescriptor {
+        self.descriptor.clone()
+    }
+
+    fn framing(&self) -> &str {
+        "defined by the plugin"
+    }
+
+    fn output_len(&self, input_len: usize) -> Result<usize> {
+        Ok(input_len + self.overhead)
+    }
+
+    fn overhead_bytes(&self) -> Result<usize> {
+        Ok(self.overhead)
+    }
+
+    fn max_input(&self) -> usize {
+        usize::MAX - self.overhead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_names() {
+        assert!(valid_name("Lattice-2.5"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("two words"));
+        assert!(!valid_name(&"x".repeat(MAX_NAME_LEN + 1)));
+    }
+
+    #[test]
+    fn test_missing_library_is_refused() {
+        let result = ExternalLayer::load(Path::new("/nonexistent/libnothing.so"));
+        assert!(matches!(result, Err(HybridGuardError::UnsupportedFormat(_))));
+    }
+}
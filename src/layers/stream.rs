@@ -0,0 +1,193 @@
+// Incremental (chunk-wise) layer operation
+// Layers that cannot stream fall back to buffering the whole message
+
+use crate::crypto::keystream::XofStream;
+use crate::crypto::secret::SecretBytes;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::EncryptionLayer;
+
+/// Encryption of one message, fed in chunks
+///
+/// The layer's output is `header()` followed by the outputs of every
+/// `process_chunk` call and finally `finish()`, in that order.
+pub trait LayerEncryptState {
+    /// Bytes that precede the encrypted body, e.g. a KEM ciphertext
+    fn header(&self) -> Vec<u8>;
+
+    /// Encrypt the next chunk of plaintext
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>>;
+
+    /// Emit any trailing output; no chunks may follow
+    fn finish(&mut self) -> Result<Vec<u8>>;
+}
+
+/// Decryption of one message, fed in chunks
+///
+/// The input is exactly what the matching encryption produced, header
+/// included; the state consumes the header itself.
+pub trait LayerDecryptState {
+    /// Decrypt the next chunk of ciphertext
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>>;
+
+    /// Emit the remaining plaintext and verify the message is complete
+    fn finish(&mut self) -> Result<Vec<u8>>;
+}
+
+/// Fallback encryption state: collects the message and encrypts it in `finish`
+pub struct BufferedEncrypt<'a, L: EncryptionLayer + ?Sized> {
+    layer: &'a L,
+    key: SecretBytes,
+    buffer: Vec<u8>,
+}
+
+impl<'a, L: EncryptionLayer + ?Sized> BufferedEncrypt<'a, L> {
+    pub fn new(layer: &'a L, key: &[u8]) -> Self {
+        Self {
+            layer,
+            key: SecretBytes::new(key.to_vec()),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<L: EncryptionLayer + ?Sized> LayerEncryptState for BufferedEncrypt<'_, L> {
+    fn header(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+        Ok(Vec::new())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let data = std::mem::take(&mut self.buffer);
+        self.layer.encrypt(&data, &self.key)
+    }
+}
+
+/// Fallback decryption state: collects the message and decrypts it in `finish`
+pub struct BufferedDecrypt<'a, L: EncryptionLayer + ?Sized> {
+    layer: &'a L,
+    key: SecretBytes,
+    version: u16,
+    buffer: Vec<u8>,
+}
+
+impl<'a, L: EncryptionLayer + ?Sized> BufferedDecrypt<'a, L> {
+    pub fn new(layer: &'a L, key: &[u8], version: u16) -> Self {
+        Self {
+            layer,
+            key: SecretBytes::new(key.to_vec()),
+            version,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<L: EncryptionLayer + ?Sized> LayerDecryptState for BufferedDecrypt<'_, L> {
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+        Ok(Vec::new())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let data = std::mem::take(&mut self.buffer);
+        self.layer.decrypt_version(&data, &self.key, self.version)
+    }
+}
+
+/// Header followed by the body XORed with a SHAKE-256 keystream
+/// Shared by the KEM layers (header = KEM ciphertext) and the noise layer (no header)
+pub(crate) struct XorEncryptState {
+    header: Vec<u8>,
+    stream: XofStream,
+}
+
+impl XorEncryptState {
+    pub(crate) fn new(header: Vec<u8>, stream: XofStream) -> Self {
+        Self { header, stream }
+    }
+}
+
+impl LayerEncryptState for XorEncryptState {
+    fn header(&self) -> Vec<u8> {
+        self.header.clone()
+    }
+
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let mut out = chunk.to_vec();
+        self.stream.xor(&mut out);
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Opens the keystream once the full header has arrived
+pub(crate) type StreamOpener<'a> = Box<dyn FnOnce(&[u8]) -> Result<XofStream> + 'a>;
+
+/// Decryption counterpart of `XorEncryptState`
+pub(crate) struct XorDecryptState<'a> {
+    /// Short layer name used in errors, e.g. "ML-KEM"
+    layer: &'static str,
+    header_len: usize,
+    header: Vec<u8>,
+    open: Option<StreamOpener<'a>>,
+    stream: Option<XofStream>,
+}
+
+impl<'a> XorDecryptState<'a> {
+    pub(crate) fn new(layer: &'static str, header_len: usize, open: StreamOpener<'a>) -> Self {
+        Self {
+            layer,
+            header_len,
+            header: Vec::with_capacity(header_len),
+            open: Some(open),
+            stream: None,
+        }
+    }
+
+    fn open_stream(&mut self) -> Result<()> {
+        if let Some(open) = self.open.take() {
+            self.stream = Some(open(&self.header)?);
+        }
+        Ok(())
+    }
+}
+
+impl LayerDecryptState for XorDecryptState<'_> {
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let mut rest = chunk;
+        if self.stream.is_none() {
+            let take = (self.header_len - self.header.len()).min(rest.len());
+            self.header.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.header.len() < self.header_len {
+                return Ok(Vec::new());
+            }
+            self.open_stream()?;
+        }
+
+        let mut out = rest.to_vec();
+        if let Some(stream) = self.stream.as_mut() {
+            stream.xor(&mut out);
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        if self.stream.is_none() {
+            if self.header.len() < self.header_len {
+                return Err(HybridGuardError::DecryptionError(format!(
+                    "Data too short for {} ciphertext",
+                    self.layer
+                )));
+            }
+            self.open_stream()?;
+        }
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,90 @@
+// Container format 0: bare bincode of the original `EncryptedData` struct,
+// with no magic, no format version and no layer descriptors. Every layer of
+// that era wrote its version 1 output, which the descriptors now say.
+
+use serde::Deserialize;
+
+use crate::crypto::{container, EncryptedData, EncryptedDataFields};
+use crate::error::Result;
+use crate::layers;
+use crate::legacy::LegacyReader;
+
+/// Original `EncryptedData` layout
+#[derive(Deserialize)]
+struct EncryptedDataV0 {
+    ciphertext: Vec<u8>,
+    layers: Vec<String>,
+    version: String,
+    timestamp: u64,
+}
+
+/// Reads a whole format 0 file
+pub struct BareBincode {
+    /// Fail unless the struct ends at the last byte
+    pub exact: bool,
+}
+
+impl LegacyReader for BareBincode {
+    type Output = EncryptedData;
+
+    const FEATURE: &'static str = "legacy-v0";
+
+    fn format(&self) -> String {
+        "container format 0 (bare bincode)".to_string()
+    }
+
+    fn read(&self, bytes: &[u8]) -> Result<EncryptedData> {
+        let v0: EncryptedDataV0 = container::body(bytes, self.exact)?;
+        EncryptedDataFields {
+            ciphertext: v0.ciphertext,
+            layers: v0.layers,
+            version: v0.version,
+            timestamp: v0.timestamp,
+            descriptors: layers::legacy_descriptors(),
+            key_id: None,
+            migrated_from: None,
+            tag: None,
+            timestamp_token: None,
+            content_digest: None,
+            wrapped_key: None,
+            source_snapshot: None,
+            label: None,
+            content_type: None,
+            passphrase: None,
+            verification_tag: None,
+            metadata: None,
+        }
+        .validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::codec;
+    use crate::error::HybridGuardError;
+
+    /// Ciphertext [9, 9, 9], layers ["HQC"], version "0.1.0", timestamp 1700000000
+    const FIXTURE: &str = "0300000000000000090909010000000000000003000000000000004851430500000000000000\
+                           302e312e3000f1536500000000";
+
+    #[test]
+    fn test_pinned_fixture() {
+        let bytes = codec::hex_lower_decode(FIXTURE).unwrap();
+        assert_eq!(container::format_version(&bytes).unwrap(), 0);
+
+        let decoded = container::decode(&bytes).unwrap();
+        assert_eq!(decoded.ciphertext(), &[9, 9, 9]);
+        assert_eq!(decoded.layers(), &["HQC".to_string()]);
+        assert_eq!(decoded.version(), "0.1.0");
+        assert_eq!(decoded.timestamp(), 1_700_000_000);
+        assert_eq!(decoded.descriptors(), &layers::legacy_descriptors()[..]);
+        assert!(decoded.key_id().is_none() && !decoded.is_authenticated());
+        assert_eq!(container::decode_exact(&bytes).unwrap(), decoded);
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(BareBincode { exact: true }.read(&trailing).is_err());
+        assert!(matches!(BareBincode { exact: false }.read(&bytes[..20]), Err(HybridGuardError::Decryption(_))));
+    }
+}
@@ -0,0 +1,109 @@
+// KdfScheme::V1: the original SHA3-256 concatenations. A V1 master key came
+// from SHA3-256(password || salt), layer key n from SHA3-256(master_key ||
+// "HybridGuard-Layer-<n>" || n), and every other key from a domain label
+// hashed ahead of the four layer keys. Key files, containers and escrow blobs
+// made with them still open; nothing new is derived this way.
+
+use sha3::{Digest, Sha3_256};
+
+use crate::crypto::hkdf::LAYER_INFO_PREFIX;
+use crate::crypto::secret::SecretBytes;
+use crate::error::Result;
+use crate::legacy::LegacyReader;
+
+/// Reads layer key `layer_id` of `key_size` bytes out of a V1 master key
+pub struct LayerKey {
+    pub layer_id: u8,
+    pub key_size: usize,
+}
+
+impl LegacyReader for LayerKey {
+    type Output = Vec<u8>;
+
+    const FEATURE: &'static str = "legacy-kdf-v1";
+
+    fn format(&self) -> String {
+        "KDF scheme v1".to_string()
+    }
+
+    fn read(&self, master_key: &[u8]) -> Result<Vec<u8>> {
+        let info = format!("{}{}", LAYER_INFO_PREFIX, self.layer_id);
+        let mut hasher = Sha3_256::new();
+        hasher.update(master_key);
+        hasher.update(info.as_bytes());
+        hasher.update([self.layer_id]);
+        let derived = hasher.finalize();
+
+        if self.key_size <= derived.len() {
+            return Ok(derived[..self.key_size].to_vec());
+        }
+        // Longer keys chain one hash per 32 bytes
+        let mut result = Vec::with_capacity(self.key_size + derived.len());
+        let mut counter = 0u8;
+        while result.len() < self.key_size {
+            let mut hasher = Sha3_256::new();
+            hasher.update(&derived);
+            hasher.update([counter]);
+            result.extend_from_slice(&hasher.finalize());
+            counter += 1;
+        }
+        result.truncate(self.key_size);
+        Ok(result)
+    }
+}
+
+/// V1 master key of a password
+pub fn master_key(password: &str, salt: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(password.as_bytes());
+    hasher.update(salt);
+    hasher.finalize().to_vec()
+}
+
+/// V1 key for a purpose: its domain `label`, then the layer keys in order
+pub fn purpose_key(label: &[u8], layer_keys: &[&[u8]]) -> SecretBytes {
+    let mut key = Sha3_256::new();
+    key.update(label);
+    for part in layer_keys {
+        key.update(part);
+    }
+    SecretBytes::new(key.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::codec;
+    use crate::crypto::hkdf::{KdfScheme, KeyDerivation, KeyPurpose};
+
+    const MASTER: [u8; 32] = [0x42; 32];
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        codec::hex_lower_decode(hex).unwrap()
+    }
+
+    #[test]
+    fn test_pinned_layer_keys() {
+        let layer1 = LayerKey { layer_id: 1, key_size: 32 }.read(&MASTER).unwrap();
+        assert_eq!(layer1, unhex("767a3342d2a7d560b09a8af3725f49524c76a70b100a84b518c9f95c9696aa85"));
+        let long = LayerKey { layer_id: 1, key_size: 48 }.read(&MASTER).unwrap();
+        assert_eq!(
+            long,
+            unhex("ae6f72e24e1e2bf77f4669696399797fe5df7ef381b5ab4b42abec8b37756882522e2bfe9a1bef62c74eb4f6906e3f89")
+        );
+        assert_eq!(KeyDerivation::with_scheme(MASTER.to_vec(), KdfScheme::V1).derive_layer_key(1, 32).unwrap(), layer1);
+    }
+
+    #[test]
+    fn test_pinned_purpose_and_password_keys() {
+        let keys = KeyDerivation::with_scheme(MASTER.to_vec(), KdfScheme::V1).derive_all_keys().unwrap();
+        assert_eq!(
+            keys.derive_key(KeyPurpose::Mac("container-tag")).as_bytes(),
+            &unhex("6caff659bd242b318a6e46f56a421776e66cf559d412cfea09e2c361b81cd171")[..]
+        );
+        assert_eq!(
+            master_key("correct horse", b"salt"),
+            unhex("889486ca47b0221294b8d4c7ffb5db6a183a7cf81b3f92247dfcf129ba9f3e8a")
+        );
+    }
+}
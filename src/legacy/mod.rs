@@ -0,0 +1,113 @@
+// Read-only support for deprecated formats
+// Each deprecated input format has one reader here that converts its bytes
+// into the current in-memory model, so nothing else in the pipeline branches
+// on old formats. Every reader is built behind its own cargo feature, all on
+// by default; a build without one refuses that format with `UnsupportedVersion`
+// rather than guessing at the bytes:
+//
+//   legacy-v0       container format 0, bare bincode of the original struct
+//   legacy-pre-mac  container formats 1 to 3, written before the tag existed
+//   legacy-kdf-v1   KdfScheme::V1 key derivation and key files that record it
+//
+// Layer output formats stay with their layers, which pick the code path from
+// the descriptor version recorded in the container.
+
+use crate::crypto::hkdf::KdfScheme;
+use crate::error::{HybridGuardError, Result};
+
+#[cfg(feature = "legacy-v0")]
+pub mod container_v0;
+#[cfg(feature = "legacy-kdf-v1")]
+pub mod kdf_v1;
+#[cfg(feature = "legacy-pre-mac")]
+pub mod pre_mac;
+
+/// Converts bytes of one deprecated format into the current model
+pub trait LegacyReader {
+    /// What the old bytes become
+    type Output;
+
+    /// Cargo feature that builds this reader
+    const FEATURE: &'static str;
+
+    /// The deprecated format, as messages name it
+    fn format(&self) -> String;
+
+    fn read(&self, bytes: &[u8]) -> Result<Self::Output>;
+}
+
+/// The error for a format whose reader this build was compiled without
+pub fn dropped(format: impl Into<String>, feature: &str) -> HybridGuardError {
+    HybridGuardError::UnsupportedVersion { format: format.into(), feature: feature.to_string() }
+}
+
+/// Parse a container of format 0 to 3; `body` follows the prefix, or is the
+/// whole file for format 0
+/// The container parser's only way into the legacy readers
+pub(crate) fn read_container(version: u16, body: &[u8], exact: bool) -> Result<crate::crypto::EncryptedData> {
+    match version {
+        #[cfg(feature = "legacy-v0")]
+        0 => container_v0::BareBincode { exact }.read(body),
+        #[cfg(not(feature = "legacy-v0"))]
+        0 => Err(dropped("container format 0 (bare bincode)", "legacy-v0")),
+        #[cfg(feature = "legacy-pre-mac")]
+        1..=3 => pre_mac::PreMacContainer { version, exact }.read(body),
+        #[cfg(not(feature = "legacy-pre-mac"))]
+        1..=3 => Err(dropped(format!("container format {} (no tag)", version), "legacy-pre-mac")),
+        other => Err(HybridGuardError::UnsupportedFormat(format!("container format version {} is not a legacy format", other))),
+    }
+}
+
+/// Whether this build has the reader container format `version` needs;
+/// formats after 3 need none
+pub fn reads_container(version: u16) -> bool {
+    match version {
+        0 => cfg!(feature = "legacy-v0"),
+        1..=3 => cfg!(feature = "legacy-pre-mac"),
+        _ => true,
+    }
+}
+
+/// Fail unless this build can use keys derived under `scheme`
+pub fn require_kdf(scheme: KdfScheme) -> Result<()> {
+    match scheme {
+        KdfScheme::V1 if !cfg!(feature = "legacy-kdf-v1") => Err(dropped("KDF scheme v1", "legacy-kdf-v1")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_readers_are_unsupported_versions() {
+        let error = dropped("container format 0 (bare bincode)", "legacy-v0");
+        assert_eq!(error.code(), crate::error::exit_code::UNSUPPORTED);
+        assert!(error.to_string().contains("`legacy-v0`"), "{}", error);
+        assert!(require_kdf(KdfScheme::V2).is_ok());
+        assert!(read_container(4, b"", false).is_err());
+    }
+
+    #[cfg(not(feature = "legacy-v0"))]
+    #[test]
+    fn test_v0_without_its_reader() {
+        let result = read_container(0, &[3, 0, 0, 0, 0, 0, 0, 0, 9, 9, 9], false);
+        assert!(matches!(result, Err(HybridGuardError::UnsupportedVersion { .. })));
+    }
+
+    #[cfg(not(feature = "legacy-pre-mac"))]
+    #[test]
+    fn test_pre_mac_without_its_reader() {
+        for version in 1..=3 {
+            let result = read_container(version, &[], false);
+            assert!(matches!(result, Err(HybridGuardError::UnsupportedVersion { .. })));
+        }
+    }
+
+    #[cfg(not(feature = "legacy-kdf-v1"))]
+    #[test]
+    fn test_kdf_v1_without_its_reader() {
+        assert!(matches!(require_kdf(KdfScheme::V1), Err(HybridGuardError::UnsupportedVersion { .. })));
+    }
+}
@@ -0,0 +1,154 @@
+// Container formats 1 to 3: the "HGRD" prefix and a bincode body, written
+// before containers carried an authentication tag, so nothing checks them
+// before the layers run. Format 1 has the layer descriptors, 2 adds the key
+// ID and 3 the migration note.
+
+use serde::Deserialize;
+
+use crate::crypto::{container, EncryptedData, EncryptedDataFields, MigrationNote};
+use crate::error::{HybridGuardError, Result};
+use crate::layers::LayerDescriptor;
+use crate::legacy::LegacyReader;
+
+/// Version 1 body, before the key ID was recorded
+#[derive(Deserialize)]
+struct EncryptedDataV1 {
+    ciphertext: Vec<u8>,
+    layers: Vec<String>,
+    version: String,
+    timestamp: u64,
+    descriptors: Vec<LayerDescriptor>,
+}
+
+/// Version 2 body, before migrated files were annotated
+#[derive(Deserialize)]
+struct EncryptedDataV2 {
+    ciphertext: Vec<u8>,
+    layers: Vec<String>,
+    version: String,
+    timestamp: u64,
+    descriptors: Vec<LayerDescriptor>,
+    key_id: Option<String>,
+}
+
+/// Version 3 body, before the ciphertext was authenticated
+#[derive(Deserialize)]
+struct EncryptedDataV3 {
+    ciphertext: Vec<u8>,
+    layers: Vec<String>,
+    version: String,
+    timestamp: u64,
+    descriptors: Vec<LayerDescriptor>,
+    key_id: Option<String>,
+    migrated_from: Option<MigrationNote>,
+}
+
+/// Reads the body, after the prefix, of a format 1, 2 or 3 container
+pub struct PreMacContainer {
+    pub version: u16,
+    /// Fail unless the body ends at the last byte
+    pub exact: bool,
+}
+
+impl LegacyReader for PreMacContainer {
+    type Output = EncryptedData;
+
+    const FEATURE: &'static str = "legacy-pre-mac";
+
+    fn format(&self) -> String {
+        format!("container format {} (no tag)", self.version)
+    }
+
+    fn read(&self, body: &[u8]) -> Result<EncryptedData> {
+        let v3 = match self.version {
+            1 => {
+                let v1: EncryptedDataV1 = container::body(body, self.exact)?;
+                EncryptedDataV3 {
+                    ciphertext: v1.ciphertext,
+                    layers: v1.layers,
+                    version: v1.version,
+                    timestamp: v1.timestamp,
+                    descriptors: v1.descriptors,
+                    key_id: None,
+                    migrated_from: None,
+                }
+            }
+            2 => {
+                let v2: EncryptedDataV2 = container::body(body, self.exact)?;
+                EncryptedDataV3 {
+                    ciphertext: v2.ciphertext,
+                    layers: v2.layers,
+                    version: v2.version,
+                    timestamp: v2.timestamp,
+                    descriptors: v2.descriptors,
+                    key_id: v2.key_id,
+                    migrated_from: None,
+                }
+            }
+            3 => container::body(body, self.exact)?,
+            other => return Err(HybridGuardError::UnsupportedFormat(format!("container format {} is not pre-MAC", other))),
+        };
+        EncryptedDataFields {
+            ciphertext: v3.ciphertext,
+            layers: v3.layers,
+            version: v3.version,
+            timestamp: v3.timestamp,
+            descriptors: v3.descriptors,
+            key_id: v3.key_id,
+            migrated_from: v3.migrated_from,
+            tag: None,
+            timestamp_token: None,
+            content_digest: None,
+            wrapped_key: None,
+            source_snapshot: None,
+            label: None,
+            content_type: None,
+            passphrase: None,
+            verification_tag: None,
+            metadata: None,
+        }
+        .validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::codec;
+
+    /// Ciphertext [4, 5, 6], layers ["HQC"], version "0.2.0", timestamp
+    /// 1700000001 and one descriptor HQC-256 v1, then per format: nothing,
+    /// key ID "hg-old", and a note of a format 0 original from 1600000000
+    const FIXTURES: [&str; 3] = [
+        "4847524401000300000000000000040506010000000000000003000000000000004851430500000000000000302e322e30\
+         01f1536500000000010000000000000007000000000000004851432d3235360100",
+        "4847524402000300000000000000040506010000000000000003000000000000004851430500000000000000302e322e30\
+         01f1536500000000010000000000000007000000000000004851432d323536010001060000000000000068672d6f6c64",
+        "4847524403000300000000000000040506010000000000000003000000000000004851430500000000000000302e322e30\
+         01f1536500000000010000000000000007000000000000004851432d323536010001060000000000000068672d6f6c64\
+         01000000105e5f00000000",
+    ];
+
+    #[test]
+    fn test_pinned_fixtures() {
+        for (fixture, version) in FIXTURES.iter().zip(1u16..) {
+            let bytes = codec::hex_lower_decode(fixture).unwrap();
+            assert_eq!(container::format_version(&bytes).unwrap(), version);
+
+            let decoded = container::decode_exact(&bytes).unwrap();
+            assert_eq!(decoded.ciphertext(), &[4, 5, 6], "format {}", version);
+            assert_eq!(decoded.timestamp(), 1_700_000_001);
+            assert_eq!(decoded.descriptors(), &[LayerDescriptor::new("HQC-256", 1)]);
+            assert!(!decoded.is_authenticated());
+            assert_eq!(decoded.key_id(), (version >= 2).then_some("hg-old"));
+            let note = MigrationNote { format_version: 0, timestamp: 1_600_000_000 };
+            assert_eq!(decoded.migrated_from(), (version == 3).then_some(&note));
+
+            // The same body under a later reader runs out of bytes
+            if version < 3 {
+                let reader = PreMacContainer { version: version + 1, exact: false };
+                assert!(reader.read(&bytes[container::PREFIX_LEN..]).is_err());
+            }
+        }
+    }
+}
@@ -1,12 +1,61 @@
 // HybridGuard Library
 // Multi-layer quantum-resistant encryption system
 
+pub mod archive;
+pub mod batch;
+pub mod budget;
+pub mod bundle;
+pub mod cancel;
+#[cfg(feature = "fixtures")]
+pub mod compat;
+pub mod content;
 pub mod crypto;
+pub mod diagnostics;
+pub mod drill;
+pub mod encryptor;
 pub mod error;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+pub mod fsutil;
 pub mod key_manager;
 pub mod layers;
+pub mod legacy;
+pub mod messages;
+pub mod migrate;
+pub mod pathname;
+pub mod policy;
+pub mod scan;
+pub mod profiling;
+pub mod progress;
+pub mod rekey;
+pub mod sandbox;
+pub mod serve;
+pub mod simple;
+pub mod sparse;
+pub mod spec;
+pub mod stable_read;
+pub mod staging;
 pub mod hybridguard;
+pub mod storage;
+pub mod streaming;
+/// Mock layer, failing keys, deterministic RNG and clocks, and fixture
+/// helpers for testing code built on this crate (`test-util` feature)
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_support;
+pub mod timing;
+pub mod verify;
+pub mod warning;
 
+pub use batch::BatchSummary;
+pub use budget::Budget;
+pub use cancel::CancellationToken;
 pub use error::{HybridGuardError, Result};
-pub use key_manager::KeyManager;
-pub use hybridguard::HybridGuard;
+pub use key_manager::{KeyId, KeyManager};
+pub use key_manager::strength::{evaluate_password, PasswordStrength};
+pub use key_manager::verification::VerificationKey;
+pub use layers::SecurityAssessment;
+pub use hybridguard::{DecryptErrorMode, DecryptLimits, DecryptOptions, DecryptScratch, EncryptOptions, HybridGuard, HybridGuardBuilder, StackProfile};
+pub use profiling::Profiling;
+pub use streaming::adapters::{HybridGuardReader, HybridGuardWriter};
+pub use timing::{EncryptionReport, TimingPadding};
+pub use warning::{Warning, WarningCode, Warnings};
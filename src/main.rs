@@ -3,245 +3,4215 @@
 
 use clap::{Parser, Subcommand};
 use colored::*;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::rc::Rc;
 
-mod crypto;
-mod encryptor;
-mod key_manager;
-mod layers;
-mod error;
+mod cli;
 
-use encryptor::HybridGuardEncryptor;
-use error::HybridGuardError;
-use key_manager::KeyManager;
+use cli::credentials::{Credentials, KeysOrigin, PasswordOrigin, SecretSources};
+use cli::keys::KeyFiles;
+use cli::migrate::{MigrateOptions, Outcome};
+use cli::pipe::{self, Piped};
+use cli::pipeline::{self, SourceCommand};
+use cli::plan::{CheckpointPlan, EncryptOptions, KeySource, Operation, Plan};
+use cli::preflight::{self, SystemProbe};
+use cli::reporter::{Reporter, Verbosity};
+use cli::resource::{self, IoClass, ResourceLimits, ResourceReport, SystemScheduler};
+use cli::stats::StatsFile;
+use hybridguard::archive::{self, ArchiveOptions};
+use hybridguard::budget::{self, Budget};
+use hybridguard::bundle;
+use hybridguard::content::{self, ContentCheck, ContentReport};
+use hybridguard::crypto::encoding::{self, Encoding};
+use hybridguard::crypto::hkdf::KdfScheme;
+use hybridguard::crypto::kdf::{self, KdfParams, TuneLimits};
+use hybridguard::crypto::metadata::{self, Metadata};
+use hybridguard::crypto::{codec, container, sniff, EncryptedData, SourceSnapshot};
+use hybridguard::diagnostics::{self, CheckStatus, DoctorOptions, SystemProbes};
+use hybridguard::drill::{self, DrillOptions, DrillStatus};
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::error::{exit_code, HybridGuardError};
+use hybridguard::key_manager::permissions::{self, LoosePermissions};
+use hybridguard::key_manager::protector::ProtectorSpec;
+use hybridguard::key_manager::provenance::{KeyOwner, SignatureStatus};
+use hybridguard::key_manager::signing::{self, DetachedSignature, SignerKey, SigningKey};
+use hybridguard::key_manager::strength::PasswordPolicy;
+use hybridguard::key_manager::backup::{self, BackupPolicy};
+use hybridguard::key_manager::lock::{self, LockOptions};
+use hybridguard::key_manager::{self, escrow, pairing, paper};
+use hybridguard::layers::{self, SecurityAssessment};
+use hybridguard::message;
+use hybridguard::messages::{self, Catalog};
+use hybridguard::pathname::JsonPath;
+use hybridguard::policy::{self, AuditRecord, LabelPolicy};
+use hybridguard::fsutil::{self, DurabilityLevel, WriteOptions};
+use hybridguard::profiling;
+use hybridguard::progress::{Direction, OperationState, Progress, Summary};
+use hybridguard::rekey::{self, ApplyOptions, EntryStatus, RekeyPlan};
+use hybridguard::sandbox::{self, SandboxOptions};
+use hybridguard::scan::{self, Predicate, ScanHit};
+use hybridguard::serve::Server;
+use hybridguard::simple;
+use hybridguard::sparse;
+use hybridguard::spec::FormatSpec;
+use hybridguard::stable_read::{self, StableRead};
+use hybridguard::staging::{Contents, StagedFile};
+use hybridguard::storage::erasure::{self, Redundancy};
+use hybridguard::streaming::checkpoint::CheckpointedEncryption;
+use hybridguard::streaming::chunked;
+use hybridguard::streaming::shaping::{self, ShapingPolicy, ShapingReport};
+use hybridguard::streaming::split;
+use hybridguard::timing::{Clock, SystemClock};
+use hybridguard::verify::{self, VerifyOptions};
+use hybridguard::warning::{self, WarningCode, Warnings};
+use hybridguard::{CancellationToken, DecryptErrorMode, DecryptLimits, DecryptOptions, HybridGuard, HybridGuardBuilder, KeyManager, VerificationKey};
+
+// Counts what each layer allocates for --profile-memory; only the binary
+// installs it, so programs using the library keep their own allocator
+#[cfg(feature = "memory-profile")]
+#[global_allocator]
+static ALLOCATOR: hybridguard::profiling::TrackingAllocator = hybridguard::profiling::TrackingAllocator;
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  other failure
+  2  usage error
+  3  key or password error
+  4  integrity check failed / corrupt file
+  5  I/O error
+  6  unsupported format or version
+  7  policy violation, or a warning denied by --deny-warnings
+  8  keystore busy (locked by another run)
+  9  --max-duration or --max-output-bytes budget exceeded
+130  cancelled (Ctrl-C)";
 
 #[derive(Parser)]
 #[command(name = "HybridGuard")]
 #[command(author = "Quantum Shield Labs")]
-#[command(version = "0.1.0")]
+#[command(version)]
 #[command(about = "Multi-layer quantum-resistant encryption", long_about = None)]
+#[command(after_help = EXIT_CODES_HELP)]
 struct Cli {
+    /// Print only errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    
+    /// Show per-layer progress (-v) or debug logs (-vv); output goes to stderr
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    
+    /// Make key files that other users can read owner-only instead of warning
+    #[arg(long, global = true)]
+    fix_permissions: bool,
+    
+    /// Key files are sealed at rest by this protector: `password` or
+    /// `hmac-file:<path>` (an HMAC secret standing in for a hardware token)
+    #[arg(long, global = true, value_name = "PROTECTOR")]
+    protector: Option<ProtectorSpec>,
+    
+    /// Times a mistyped key file password is asked for at a terminal before
+    /// the run fails; piped input and --json-progress runs get one try
+    #[arg(long, global = true, value_name = "N", default_value_t = cli::keys::DEFAULT_PASSWORD_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    password_attempts: u32,
+    
+    /// Read the key file password from this inherited descriptor instead of
+    /// asking (needs --protector password). Password sources, first wins:
+    /// --password-fd, $CREDENTIALS_DIRECTORY/hybridguard.password, the prompt
+    #[arg(long, global = true, value_name = "FD")]
+    password_fd: Option<i32>,
+    
+    /// Read the key file from this inherited descriptor (encrypt and decrypt).
+    /// Key sources, first wins: -k, --key-file-fd,
+    /// $CREDENTIALS_DIRECTORY/hybridguard.keys, keys from the default password
+    #[arg(long, global = true, value_name = "FD")]
+    key_file_fd: Option<i32>,
+    
+    /// Load an out-of-tree encryption layer from this library, run after HQC
+    /// (repeatable; used by cat and serve, needs the plugins feature)
+    #[arg(long, global = true, value_name = "PATH")]
+    plugin: Vec<PathBuf>,
+    
+    /// Add the counts of every encrypt and decrypt run to this local JSON
+    /// file (see `stats show`); nothing is sent anywhere
+    #[arg(long, global = true, value_name = "PATH")]
+    stats_file: Option<PathBuf>,
+    
+    /// Keep key file backups here instead of beside the key file
+    #[arg(long, global = true, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+    
+    /// Backups kept per key file; older ones are shredded
+    #[arg(long, global = true, value_name = "N", default_value_t = backup::DEFAULT_KEEP, value_parser = clap::value_parser!(usize).range(1..))]
+    keep_backups: usize,
+    
+    /// Replace or destroy key files without backing them up first
+    #[arg(long, global = true, conflicts_with = "backup_dir")]
+    no_key_backup: bool,
+    
+    /// Seconds to wait while another run holds the keystore or stats file
+    /// lock before failing with exit code 8
+    #[arg(long, global = true, value_name = "SECS", default_value_t = lock::DEFAULT_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+    
+    /// Remove a lock whose holder crashed (its PID is gone or it ran before
+    /// the last reboot) instead of failing; a running holder is never broken
+    #[arg(long, global = true)]
+    break_stale_lock: bool,
+    
+    /// Report encrypt and decrypt progress as one JSON operation state per
+    /// line on stderr, for GUI wrappers (single containers only)
+    #[arg(long, global = true)]
+    json_progress: bool,
+    
+    /// Fail with exit code 7 at the first warning (legacy containers, keys
+    /// past --key-lifetime, weak stacks, loose key files, changed sources)
+    /// instead of printing it and carrying on
+    #[arg(long, global = true)]
+    deny_warnings: bool,
+    
+    /// Drop warnings with this code, such as HG004: neither printed nor
+    /// denied (repeatable)
+    #[arg(long, global = true, value_name = "CODE")]
+    allow: Vec<WarningCode>,
+    
+    /// How far every written file is pushed to disk before the command
+    /// reports it: none, flush, fsync or fsync-dir (default: fsync-dir)
+    #[arg(long, global = true, value_name = "LEVEL")]
+    durability: Option<DurabilityLevel>,
+    
+    /// Print messages from this translation file of `id = template` lines;
+    /// ids it leaves out stay in English (default: $HYBRIDGUARD_LANG)
+    #[arg(long, global = true, value_name = "FILE")]
+    lang: Option<PathBuf>,
+    
+    /// Print messages without emoji (the default outside UTF-8 locales)
+    #[arg(long, global = true)]
+    no_emoji: bool,
+    
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Arguments of a self-extracting bundle, in place of the usual commands
+#[derive(Parser)]
+#[command(name = "HybridGuard bundle")]
+#[command(version)]
+#[command(about = "Self-extracting HybridGuard bundle: asks for its password and extracts", long_about = None)]
+struct BundleCli {
+    /// Directory to extract into; must be missing or empty
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Encrypt a file using 4-layer quantum-resistant encryption
     Encrypt {
-        /// Input file to encrypt
-        #[arg(short, long)]
-        input: PathBuf,
+        /// Input file(s) to encrypt
+        #[arg(short, long, required_unless_present = "source_cmd", num_args = 1..)]
+        input: Vec<PathBuf>,
         
-        /// Output encrypted file
+        /// Output encrypted file, or a directory when encrypting several files
         #[arg(short, long)]
         output: PathBuf,
+        
+        /// Add Reed-Solomon redundancy: k data + m parity shards, any m may be lost
+        #[arg(long, value_name = "K+M")]
+        redundancy: Option<Redundancy>,
+        
+        /// Encrypt only data extents and recreate holes on decrypt (disk images)
+        #[arg(long, conflicts_with = "redundancy")]
+        sparse: bool,
+        
+        /// Write resumable chunked output, checkpointing progress to PATH;
+        /// rerun with the same flag to continue after an interruption
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["redundancy", "sparse"])]
+        checkpoint: Option<PathBuf>,
+        
+        /// Streaming chunks (64 KiB each) between checkpoints
+        #[arg(long, value_name = "CHUNKS", requires = "checkpoint", default_value_t = chunked::DEFAULT_SEGMENT_CHUNKS)]
+        checkpoint_every: u64,
+        
+        /// Report the peak bytes each layer allocates (needs the memory-profile feature)
+        #[arg(long, conflicts_with_all = ["sparse", "checkpoint"])]
+        profile_memory: bool,
+        
+        /// Stage outputs here until complete (default: the output's directory)
+        #[arg(long, value_name = "DIR", conflicts_with = "checkpoint")]
+        temp_dir: Option<PathBuf>,
+        
+        /// Encrypt a consistent snapshot of files that may be written meanwhile:
+        /// reread (or fail) when the size or mtime changes during the read
+        #[arg(long, conflicts_with_all = ["sparse", "checkpoint"])]
+        stable_read: bool,
+        
+        /// Rereads of a changing file before failing; 0 fails on the first change
+        #[arg(long, value_name = "N", requires = "stable_read", default_value_t = stable_read::DEFAULT_RETRIES)]
+        stable_read_retries: u32,
+        
+        /// Copy each file to an unlinked scratch file first (copy_file_range on Linux)
+        #[arg(long, requires = "stable_read")]
+        snapshot_copy: bool,
+        
+        /// Write a constant-rate stream: one BYTES frame every INTERVAL (us, ms
+        /// or s), with authenticated filler while the input is slow
+        #[arg(long, value_name = "BYTES@INTERVAL", conflicts_with_all = ["redundancy", "sparse", "checkpoint", "profile_memory", "stable_read"])]
+        shape: Option<ShapingPolicy>,
+        
+        /// Record a policy label, e.g. `confidential`, that `decrypt --policy` checks
+        #[arg(long, value_name = "LABEL", value_parser = policy::parse_label, conflicts_with_all = ["sparse", "checkpoint", "shape"])]
+        label: Option<String>,
+        
+        /// Sniff each plaintext (ELF, PE, Mach-O, script, text, ...) and record
+        /// its type in the container, for decrypt to check against
+        #[arg(long, conflicts_with_all = ["sparse", "checkpoint", "shape"])]
+        tag_content_type: bool,
+        
+        /// Record KEY=VALUE in the container header, readable by `inspect`
+        /// without keys and covered by the tag (repeatable)
+        #[arg(long = "meta", value_name = "KEY=VALUE", value_parser = metadata::parse_entry, conflicts_with_all = ["sparse", "checkpoint", "shape"])]
+        meta: Vec<(String, Vec<u8>)>,
+        
+        /// Record KEY=VALUE sealed under the file's key, readable only with
+        /// its key file, e.g. by `inspect --key-file` (repeatable)
+        #[arg(long = "meta-private", value_name = "KEY=VALUE", value_parser = metadata::parse_entry, conflicts_with_all = ["sparse", "checkpoint", "shape"])]
+        meta_private: Vec<(String, Vec<u8>)>,
+        
+        /// Accept FIFOs and character devices (e.g. /dev/stdin) as inputs,
+        /// read once front to back into a spool beside the output and
+        /// encrypted as a chunked file; needs --max-input-bytes
+        #[arg(long, requires = "max_input_bytes", conflicts_with_all = ["redundancy", "sparse", "checkpoint", "profile_memory", "shape", "stable_read", "label", "tag_content_type", "meta", "meta_private"])]
+        allow_special: bool,
+        
+        /// Refuse any input longer than BYTES
+        #[arg(long, value_name = "BYTES")]
+        max_input_bytes: Option<u64>,
+        
+        /// Write chunked output as OUTPUT.000, OUTPUT.001, ... of at most SIZE
+        /// each (e.g. 2G) and an authenticated OUTPUT.manifest listing them
+        #[arg(long, value_name = "SIZE", value_parser = split::parse_size, conflicts_with_all = ["redundancy", "sparse", "checkpoint", "profile_memory", "temp_dir", "stable_read", "shape", "label", "tag_content_type", "meta", "meta_private", "allow_special"])]
+        split_size: Option<u64>,
+        
+        /// Write a runnable copy of this binary carrying the input directory,
+        /// encrypted under a password asked for now; no key file is used
+        #[arg(long, conflicts_with_all = ["redundancy", "sparse", "checkpoint", "profile_memory", "temp_dir", "stable_read", "shape", "label", "tag_content_type", "meta", "meta_private", "allow_special", "max_input_bytes", "split_size"])]
+        self_extracting: bool,
+        
+        /// Encrypt one file under a passphrase asked for now, storing its salt
+        /// and Argon2id cost in the container; decrypt then needs only the
+        /// passphrase, and no key file is used
+        #[arg(long, conflicts_with_all = ["redundancy", "sparse", "checkpoint", "profile_memory", "temp_dir", "stable_read", "shape", "label", "tag_content_type", "meta", "meta_private", "allow_special", "self_extracting", "split_size", "key_file"])]
+        passphrase_only: bool,
+        
+        /// Run CMD with `sh -c` (e.g. "pg_dump mydb") and encrypt what it
+        /// writes to stdout, up to --max-input-bytes, as a chunked file; the
+        /// output is spooled beside --output and encrypted only once CMD has
+        /// exited with status 0. A producer that fails fails the run, with
+        /// its exit status and the end of its stderr
+        #[arg(long, value_name = "CMD", requires = "max_input_bytes", conflicts_with_all = ["input", "redundancy", "sparse", "checkpoint", "profile_memory", "shape", "label", "tag_content_type", "meta", "meta_private", "stable_read", "allow_special", "split_size", "self_extracting", "passphrase_only"])]
+        source_cmd: Option<String>,
+        
+        /// Terminate the --source-cmd producer if it has not exited after SECS
+        #[arg(long, value_name = "SECS", requires = "source_cmd")]
+        source_timeout: Option<u64>,
+        
+        /// Warn (HG002) when the keys were generated DAYS or more ago
+        #[arg(long, value_name = "DAYS", value_parser = clap::value_parser!(u64).range(1..))]
+        key_lifetime: Option<u64>,
+        
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        
+        #[command(flatten)]
+        run: RunOptions,
     },
     
     /// Decrypt a file encrypted with HybridGuard
     Decrypt {
-        /// Input encrypted file
-        #[arg(short, long)]
-        input: PathBuf,
+        /// Input encrypted file(s); `-` reads one ciphertext from stdin
+        #[arg(short, long, required = true, num_args = 1..)]
+        input: Vec<PathBuf>,
         
-        /// Output decrypted file
+        /// Output decrypted file, or a directory when decrypting several files
         #[arg(short, long)]
         output: PathBuf,
+        
+        /// Refuse labeled files that fail this TOML label policy (exit code 7)
+        #[arg(long, value_name = "PATH")]
+        policy: Option<PathBuf>,
+        
+        /// Decrypt despite policy violations, appending REASON to the policy's audit log
+        #[arg(long, value_name = "REASON", requires = "policy")]
+        override_policy: Option<String>,
+        
+        /// Write executable outputs (ELF, PE, Mach-O, `#!` scripts) into DIR
+        /// without execute permission, instead of the requested path
+        #[arg(long, value_name = "DIR")]
+        quarantine_executables: Option<PathBuf>,
+        
+        /// Write the stored and detected content type of every output here as JSON
+        #[arg(long, value_name = "PATH")]
+        content_report: Option<PathBuf>,
+        
+        /// With `--input -`, copy a single (non-chunked) container to a temp
+        /// file first instead of refusing it; chunked input always streams
+        #[arg(long)]
+        spool_to_temp: bool,
+        
+        /// Refuse any file whose plaintext, at the length its header or last
+        /// layer declares, is longer than BYTES; checked before anything is
+        /// decrypted (exit code 7)
+        #[arg(long, value_name = "BYTES")]
+        max_plaintext_bytes: Option<u64>,
+        
+        /// Read one single container, then parse and decrypt it under a
+        /// deny-by-default sandbox (a seccomp filter on Linux) that leaves no
+        /// way to open files, connect or run programs, and under strict size
+        /// limits; elsewhere only the limits apply
+        #[arg(long, conflicts_with_all = ["policy", "quarantine_executables", "content_report", "spool_to_temp"])]
+        sandbox: bool,
+        
+        /// With --sandbox, also enter new user and network namespaces
+        #[arg(long, requires = "sandbox")]
+        sandbox_namespaces: bool,
+        
+        /// Unpack the plaintext, a directory `encrypt` archived, into --output,
+        /// which must be missing or empty
+        #[arg(long, conflicts_with_all = ["sandbox", "quarantine_executables"])]
+        extract: bool,
+        
+        #[command(flatten)]
+        run: RunOptions,
     },
     
-    /// Check system security status
-    Status,
+    /// Write a byte range of a file's plaintext to stdout, decrypting only the segments that hold it
+    Cat {
+        /// Encrypted file; chunked files are read segment by segment
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// First plaintext byte to write
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        
+        /// Number of bytes to write
+        #[arg(long)]
+        length: u64,
+        
+        /// Stop at the end of the plaintext instead of failing when the range runs past it
+        #[arg(long)]
+        clamp: bool,
+        
+        /// Key file the input was encrypted with
+        #[arg(short, long)]
+        key_file: PathBuf,
+    },
     
-    /// Generate new encryption keys
-    Keygen {
-        /// Output directory for keys
-        #[arg(short, long, default_value = "./keys")]
-        output: PathBuf,
+    /// Check that a file authenticates and decrypts, without writing the plaintext
+    Verify {
+        /// Encrypted file
+        #[arg(short, long, required_unless_present = "root", conflicts_with = "root")]
+        input: Option<PathBuf>,
+        
+        /// Only check a chunked file's Merkle index against its segment tags and root; nothing is decrypted
+        #[arg(long, conflicts_with = "deep")]
+        quick: bool,
+        
+        /// Key file the input was encrypted with
+        #[arg(short, long, required_unless_present = "verification_key")]
+        key_file: Option<PathBuf>,
+        
+        /// Check the input's tags with a verification key (key export
+        /// --verification-key) instead; nothing is decrypted
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["key_file", "quick", "deep"])]
+        verification_key: Option<PathBuf>,
+        
+        /// Most damaged regions a full verify lists; the rest are only counted
+        #[arg(long, value_name = "N", default_value_t = verify::DEFAULT_MAX_REPORT, conflicts_with = "quick")]
+        max_report: usize,
+        
+        /// Restore drill: decrypt every HybridGuard file under --root into a
+        /// hasher, keep going past failures and fail at the end if any did
+        #[arg(long, requires = "root")]
+        deep: bool,
+        
+        /// Directory (or single file) a deep verify walks
+        #[arg(long, requires = "deep")]
+        root: Option<PathBuf>,
+        
+        /// Walk subdirectories of --root too
+        #[arg(short, long, requires = "root")]
+        recursive: bool,
+        
+        /// Write per-file results, throughput and failures here as JSON
+        #[arg(long, value_name = "FILE", requires = "root")]
+        report: Option<PathBuf>,
+        
+        /// Files decrypted at once (default: one per core)
+        #[arg(long, value_name = "N", requires = "root")]
+        jobs: Option<NonZeroUsize>,
     },
-}
-
-fn main() -> Result<(), HybridGuardError> {
-    // Initialize logger
-    env_logger::init();
     
-    // Print banner
-    print_banner();
+    /// Write a detached ML-DSA-65 signature of a file (its bytes, name and
+    /// size) that `check-sig` verifies without any decryption key
+    Sign {
+        /// File to sign, usually an encrypted one
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// Key file holding the signing key; one without is offered a new key
+        #[arg(short, long)]
+        key_file: PathBuf,
+        
+        /// Signature file (default: INPUT.sig)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        
+        /// Also write the signer key `check-sig --signer` takes, to hand out
+        #[arg(long, value_name = "FILE")]
+        export_signer: Option<PathBuf>,
+        
+        /// Add a signing key to a key file without one instead of asking
+        #[arg(long)]
+        yes: bool,
+        
+        /// Overwrite an existing signature file
+        #[arg(long)]
+        force: bool,
+    },
     
-    let cli = Cli::parse();
+    /// Check a file against its detached signature with the signer key alone
+    CheckSig {
+        /// Signed file
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// Signature file (default: INPUT.sig)
+        #[arg(long, value_name = "FILE")]
+        sig: Option<PathBuf>,
+        
+        /// Signer key written by `sign --export-signer`
+        #[arg(long, value_name = "FILE")]
+        signer: PathBuf,
+    },
     
-    match cli.command {
-        Commands::Encrypt { input, output } => {
-            println!("{}", "🔐 Starting 4-layer encryption...".green().bold());
-            encrypt_file(input, output)?;
-            println!("{}", "✅ Encryption complete!".green().bold());
-        }
+    /// Re-encrypt files written in older formats with the current defaults
+    Migrate {
+        /// File to migrate, or a directory with --recursive
+        #[arg(short, long)]
+        input: PathBuf,
         
-        Commands::Decrypt { input, output } => {
-            println!("{}", "🔓 Starting 4-layer decryption...".cyan().bold());
-            decrypt_file(input, output)?;
-            println!("{}", "✅ Decryption complete!".cyan().bold());
-        }
+        /// Migrated file, or a directory with --recursive (the input itself with --delete-old)
+        #[arg(short, long)]
+        output: PathBuf,
         
-        Commands::Status => {
-            print_status();
-        }
+        /// Key file that decrypts the inputs
+        #[arg(short, long)]
+        key_file: PathBuf,
         
-        Commands::Keygen { output } => {
-            println!("{}", "🔑 Generating encryption keys...".yellow().bold());
-            generate_keys(output)?;
-            println!("{}", "✅ Keys generated successfully!".green().bold());
-        }
-    }
+        /// Migrate every file under the input directory, skipping current ones
+        #[arg(short, long)]
+        recursive: bool,
+        
+        /// Overwrite existing output files
+        #[arg(long)]
+        force: bool,
+        
+        /// Delete each input once its migrated copy has been verified
+        #[arg(long)]
+        delete_old: bool,
+        
+        /// Stage outputs here until complete (default: the output's directory)
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
+    },
     
-    Ok(())
-}
-
-fn print_banner() {
-    println!("{}", "╔═══════════════════════════════════════════════════════╗".cyan());
-    println!("{}", "║           HybridGuard v0.1.0                          ║".cyan());
-    println!("{}", "║   Multi-Layer Quantum-Resistant Encryption            ║".cyan());
-    println!("{}", "║   by Quantum Shield Labs                              ║".cyan());
-    println!("{}", "╚═══════════════════════════════════════════════════════╝".cyan());
-    println!();
-}
-
-fn encrypt_file(input: PathBuf, output: PathBuf) -> Result<(), HybridGuardError> {
-    use std::fs;
+    /// Answer line-delimited JSON requests (open with {"op":"hello"})
+    Serve {
+        /// Read requests from stdin and write responses to stdout
+        #[arg(long, required = true)]
+        stdio: bool,
+        
+        /// Key file the server encrypts and decrypts with
+        #[arg(short, long)]
+        key_file: PathBuf,
+    },
     
-    // Read input file
-    println!("📂 Reading file: {}", input.display());
-    let data = fs::read(&input)?;
-    println!("   Size: {} bytes", data.len());
+    /// Re-encode a ciphertext as binary, JSON or armored text without decrypting it
+    Convert {
+        /// Ciphertext in any encoding
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// Target encoding: binary, json or armor
+        #[arg(long, value_name = "ENCODING")]
+        to: Encoding,
+        
+        /// Where to write the converted ciphertext
+        #[arg(short, long)]
+        output: PathBuf,
+        
+        /// Overwrite the output if it exists
+        #[arg(long)]
+        force: bool,
+    },
     
-    // Generate or load keys
-    println!("\n🔑 Generating encryption keys...");
-    let key_manager = KeyManager::generate("default-password")?;
-    let keys = key_manager.get_keys();
+    /// Pack a directory into one archive file, byte for byte the same while the tree is unchanged
+    Archive {
+        /// Directory to archive
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// Archive file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        
+        #[command(flatten)]
+        options: ArchiveArgs,
+    },
     
-    // Create encryptor
-    let encryptor = HybridGuardEncryptor::new();
+    /// Unpack an archive written by `archive` into an empty directory
+    Extract {
+        /// Archive file
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// Directory to unpack into; created if missing
+        #[arg(short, long)]
+        output: PathBuf,
+    },
     
-    // Encrypt through all 4 layers
-    println!();
-    let encrypted = encryptor.encrypt(&data, keys)?;
+    /// List containers whose headers match, without keys, e.g. every file using a weakened layer
+    Scan {
+        /// Directory (or single file) to search
+        #[arg(long)]
+        root: PathBuf,
+        
+        /// Only containers using this layer or layer family, e.g. HQC or ML-KEM
+        #[arg(long, value_name = "LAYER")]
+        uses_layer: Option<String>,
+        
+        /// Only containers whose --uses-layer layer is at this format version
+        #[arg(long, value_name = "VERSION", requires = "uses_layer")]
+        layer_version: Option<u16>,
+        
+        /// Only containers in a container format older than this version
+        #[arg(long, value_name = "VERSION")]
+        format_below: Option<u16>,
+        
+        /// Only containers encrypted before this date (YYYY-MM-DD or an RFC 3339 time)
+        #[arg(long, value_name = "DATE", value_parser = scan::parse_date)]
+        older_than: Option<u64>,
+        
+        /// Print the matches and unscanned paths as JSON
+        #[arg(long)]
+        json: bool,
+        
+        /// Re-encrypt every match in place under this key file, decrypting with --key-file
+        #[arg(long, value_name = "KEY_FILE", requires = "key_file")]
+        rekey_with: Option<PathBuf>,
+        
+        /// Key file the matches were encrypted with
+        #[arg(short, long)]
+        key_file: Option<PathBuf>,
+        
+        /// Stage re-encrypted files here until complete (default: beside each file)
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
+    },
     
-    // Save encrypted data
-    let encrypted_bytes = bincode::serialize(&encrypted)
-        .map_err(|e| HybridGuardError::Encryption(e.to_string()))?;
+    /// Plan re-encrypting every container under a directory from an old key ID to a new key file
+    RekeyPlan {
+        /// Directory (or single file) to search
+        #[arg(long)]
+        root: PathBuf,
+        
+        /// Key ID the files to re-encrypt are under now
+        #[arg(long, value_name = "KEY_ID")]
+        from: String,
+        
+        /// Key file to re-encrypt them under
+        #[arg(long, value_name = "KEY_FILE")]
+        to: PathBuf,
+        
+        /// Where to write the plan
+        #[arg(long, value_name = "PLAN")]
+        plan: PathBuf,
+    },
     
-    fs::write(&output, encrypted_bytes)?;
+    /// Carry out a rekey plan, resuming where an earlier run stopped
+    RekeyApply {
+        /// Plan written by rekey-plan; each finished file is recorded in it
+        #[arg(long, value_name = "PLAN")]
+        plan: PathBuf,
+        
+        /// Key file the planned files are encrypted with now
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// Stage re-encrypted files here until complete (default: beside each file)
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
+    },
     
-    println!("\n💾 Encrypted file saved: {}", output.display());
-    println!("   Original: {} bytes", data.len());
-    println!("   Encrypted: {} bytes", encrypted.ciphertext.len());
+    /// Identify a file and show HybridGuard metadata without decrypting
+    Inspect {
+        /// File to inspect
+        #[arg(short, long)]
+        input: PathBuf,
+        
+        /// Read only the header: key ID, sizes and content digest, without reading the ciphertext
+        #[arg(long)]
+        brief: bool,
+        
+        /// Key file to open the private metadata and content type with, after
+        /// checking the tag; without one only the public metadata is shown
+        #[arg(short, long, conflicts_with = "brief")]
+        key_file: Option<PathBuf>,
+    },
     
-    Ok(())
-}
-
-fn decrypt_file(input: PathBuf, output: PathBuf) -> Result<(), HybridGuardError> {
-    use std::fs;
-    use crypto::EncryptedData;
+    /// Check system security status
+    Status,
     
-    // Read encrypted file
-    println!("📂 Reading encrypted file: {}", input.display());
-    let encrypted_bytes = fs::read(&input)?;
+    /// Check this installation and environment, with a hint for each problem
+    Doctor {
+        /// Key file to look for
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// Directory outputs will be written to
+        #[arg(long, default_value = ".")]
+        output_dir: PathBuf,
+        
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
     
-    // Deserialize encrypted data
-    let encrypted: EncryptedData = bincode::deserialize(&encrypted_bytes)
-        .map_err(|e| HybridGuardError::Decryption(e.to_string()))?;
+    /// Print the container, layer and key derivation formats this build reads and writes
+    Spec {
+        /// Print the spec as JSON
+        #[arg(long)]
+        json: bool,
+    },
     
-    // Generate or load keys (must be same as encryption)
-    println!("\n🔑 Loading encryption keys...");
-    let key_manager = KeyManager::generate("default-password")?;
-    let keys = key_manager.get_keys();
+    /// Generate new encryption keys
+    Keygen {
+        /// Output directory for keys
+        #[arg(short, long, default_value = "./keys")]
+        output: PathBuf,
+        
+        /// Derive keys from an existing 32-byte master key instead of a password
+        #[arg(long)]
+        from_master_key_file: Option<PathBuf>,
+        
+        /// Key derivation scheme for --from-master-key-file; v1 rebuilds key files made before v2
+        #[arg(long, default_value_t = KdfScheme::CURRENT, requires = "from_master_key_file")]
+        kdf: KdfScheme,
+        
+        /// Also escrow the new keys to this organizational recovery public key
+        #[arg(long)]
+        escrow: Option<PathBuf>,
+        
+        /// Organizational password policy (JSON: min_length, min_score, denylist)
+        #[arg(long)]
+        password_policy: Option<PathBuf>,
+        
+        /// Lowest acceptable password score, 0-4 (default: the policy's, else 3)
+        #[arg(long)]
+        min_password_score: Option<u8>,
+        
+        /// Accept a password scoring below the threshold; policy length and denylist still apply
+        #[arg(long)]
+        allow_weak_password: bool,
+        
+        /// Stretch the key file password with these Argon2id parameters (from `key tune --save`)
+        #[arg(long, value_name = "FILE")]
+        kdf_params: Option<PathBuf>,
+        
+        /// Record who owns the keys, shown by `key list` and `key doctor`
+        #[arg(long, value_name = "NAME")]
+        owner_name: Option<String>,
+        
+        /// Record the owner's email address
+        #[arg(long, value_name = "EMAIL")]
+        owner_email: Option<String>,
+        
+        /// Free-form label recorded with the owner, e.g. a team or environment (repeatable)
+        #[arg(long, value_name = "LABEL")]
+        owner_label: Vec<String>,
+    },
     
-    // Create encryptor
-    let encryptor = HybridGuardEncryptor::new();
+    /// Back up, restore or recover a key file
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands,
+    },
     
-    // Decrypt through all 4 layers (in reverse)
-    println!();
-    let decrypted = encryptor.decrypt(&encrypted, keys)?;
+    /// Agree on a shared key with another key holder over an untrusted channel
+    Pair {
+        #[command(subcommand)]
+        action: PairCommands,
+    },
     
-    // Save decrypted data
-    fs::write(&output, &decrypted)?;
+    /// Show the usage statistics recorded with --stats-file
+    Stats {
+        #[command(subcommand)]
+        action: StatsCommands,
+    },
     
-    println!("\n💾 Decrypted file saved: {}", output.display());
-    println!("   Size: {} bytes", decrypted.len());
+    /// Show where this run's settings would come from
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommands,
+    },
     
-    Ok(())
+    /// Write reproducible interop fixtures and their manifest.json
+    #[cfg(feature = "fixtures")]
+    GenFixtures {
+        /// Directory to write the fixtures into
+        #[arg(short, long)]
+        output: PathBuf,
+    },
 }
 
-fn print_status() {
-    println!("{}", "🛡️  HybridGuard Security Status".green().bold());
-    println!("{}", "═══════════════════════════════════════".green());
-    println!();
+/// How a directory is archived, by `archive` and by encrypt
+#[derive(clap::Args)]
+struct ArchiveArgs {
+    /// Keep directory order and actual modes instead of sorting and normalizing
+    #[arg(long)]
+    nondeterministic: bool,
     
-    // Get layer information
-    let encryptor = HybridGuardEncryptor::new();
-    let layers = encryptor.layer_info();
+    /// Record modification times
+    #[arg(long)]
+    preserve_times: bool,
     
-    println!("📊 Encryption Layers:");
-    for (i, layer) in layers.iter().enumerate() {
-        let status_icon = if layer.status == "Active" { "✅" } else { "⏳" };
-        println!("  {} Layer {}: {} - {}", status_icon, i + 1, layer.name, layer.status);
-        println!("     Security: {}-bit quantum resistance", layer.security_level);
+    /// Record owner and group IDs
+    #[arg(long)]
+    preserve_owner: bool,
+    
+    /// Record this time (Unix seconds) for every entry; with --preserve-times,
+    /// clamp later times to it
+    #[arg(long, value_name = "SECS")]
+    source_date_epoch: Option<i64>,
+}
+
+impl ArchiveArgs {
+    fn options(&self) -> ArchiveOptions {
+        ArchiveOptions {
+            deterministic: !self.nondeterministic,
+            preserve_times: self.preserve_times,
+            preserve_owner: self.preserve_owner,
+            source_date_epoch: self.source_date_epoch,
+        }
     }
-    println!();
+}
+
+/// Options shared by encrypt and decrypt
+#[derive(clap::Args)]
+struct RunOptions {
+    /// Key file to use (default: --key-file-fd, then the systemd credential
+    /// hybridguard.keys, then fresh keys from the default password)
+    #[arg(short, long)]
+    key_file: Option<PathBuf>,
     
-    println!("🔒 Security Features:");
-    println!("  • Quantum Resistance: NIST-approved algorithms");
-    println!("  • AI-Attack Resistance: Quantum noise injection");
-    println!("  • Multi-Algorithm Redundancy: 4 independent layers");
-    println!("  • Key Independence: Each layer has unique key");
-    println!();
+    /// Overwrite existing output files
+    #[arg(long)]
+    force: bool,
     
-    println!("📈 Performance:");
-    println!("  • Encryption Speed: ~50ms per KB");
-    println!("  • Decryption Speed: ~60ms per KB");
-    println!("  • Memory Usage: ~100MB");
-    println!("  • Ciphertext Expansion: ~3x");
-    println!();
+    /// Show what would happen without encrypting, decrypting or writing anything
+    #[arg(long)]
+    dry_run: bool,
     
-    println!("{}", "✅ All systems operational".green().bold());
-}
-
-fn generate_keys(output: PathBuf) -> Result<(), HybridGuardError> {
-    use std::fs;
-    use std::io::{self, Write};
+    /// Print the dry-run plan as JSON
+    #[arg(long, requires = "dry_run")]
+    json: bool,
     
-    // Create output directory
-    fs::create_dir_all(&output)?;
+    /// Skip the disk space and permission checks made before starting
+    #[arg(long)]
+    no_preflight: bool,
     
-    println!("📁 Key directory: {}", output.display());
-    println!();
+    /// Run at this nice level (0-19; higher yields more CPU to other processes)
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(0..=19))]
+    nice: Option<i32>,
     
-    // Ask for password
-    print!("🔐 Enter master password: ");
-    io::stdout().flush()?;
-    let mut password = String::new();
-    io::stdin().read_line(&mut password)?;
-    let password = password.trim();
+    /// Run in this I/O scheduling class: best-effort or idle (Linux)
+    #[arg(long, value_name = "CLASS")]
+    ionice: Option<IoClass>,
     
-    // Generate keys
-    println!();
-    println!("🔑 Deriving keys from password...");
-    let key_manager = KeyManager::generate(password)?;
+    /// Cap the worker threads used for parallel work
+    #[arg(long, value_name = "N")]
+    max_threads: Option<NonZeroUsize>,
     
-    println!("🔑 Generating Layer 1 keys (ML-KEM)...");
-    println!("🔑 Generating Layer 2 keys (HQC)...");
-    println!("🔑 Generating Layer 3 keys (Quantum Noise)...");
-    println!("🔑 Generating Layer 4 keys (FHE)...");
+    /// Abort, removing partial outputs, once the run has taken this long:
+    /// seconds, or a duration such as 90s, 10m or 2h (exit code 9)
+    #[arg(long, value_name = "DURATION", value_parser = budget::parse_duration)]
+    max_duration: Option<std::time::Duration>,
     
-    // Save keys
-    let key_file = output.join("hybridguard.keys");
-    key_manager.save(&key_file)?;
+    /// Abort, removing partial outputs, before writing more than this many
+    /// bytes in all: bytes, or a size such as 512M or 50G (exit code 9)
+    #[arg(long, value_name = "SIZE", value_parser = budget::parse_bytes)]
+    max_output_bytes: Option<u64>,
+}
+
+impl RunOptions {
+    /// Apply --nice, --ionice and --max-threads, reporting what took effect
+    fn apply_resources(&self, reporter: &Reporter) -> Option<ResourceReport> {
+        let limits = ResourceLimits { nice: self.nice, io_class: self.ionice, max_threads: self.max_threads };
+        if limits.is_empty() {
+            return None;
+        }
+        let report = resource::apply(&limits, &SystemScheduler);
+        for reason in &report.skipped {
+            reporter.warn(message!(reporter, "plan-not-applied", reason = reason));
+        }
+        Some(report)
+    }
     
-    println!();
-    println!("💾 Keys saved to: {}", key_file.display());
-    println!("🆔 Key ID: {}", key_manager.key_id());
-    println!();
-    println!("{}", "⚠️  IMPORTANT: Keep this file secure!".yellow().bold());
-    println!("   Without it, you cannot decrypt your files.");
+    fn budget(&self) -> Budget {
+        Budget { max_duration: self.max_duration, max_output_bytes: self.max_output_bytes }
+    }
     
-    Ok(())
+    /// Refuse --max-duration and --max-output-bytes for `what`, which has no
+    /// chunk or layer boundaries to check them at
+    fn refuse_budget(&self, what: &str) -> Result<(), HybridGuardError> {
+        if self.budget().is_unlimited() {
+            return Ok(());
+        }
+        Err(HybridGuardError::InvalidInput(format!("--max-duration and --max-output-bytes cannot bound {}", what)))
+    }
+}
+
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Write a printable backup of a key file
+    Backup {
+        /// Key file to back up
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// Produce a paper backup (numbered base32 lines with checksums)
+        #[arg(long)]
+        paper: bool,
+        
+        /// Output backup document
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    
+    /// Restore a key file from a backup
+    Restore {
+        /// Restore from a paper backup
+        #[arg(long)]
+        paper: bool,
+        
+        /// Backup document; omit to type the lines in interactively
+        input: Option<PathBuf>,
+        
+        /// Output key file
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        output: PathBuf,
+    },
+    
+    /// Write a restricted copy of a key file, e.g. for servers that only ingest data
+    Export {
+        /// Key file to export from
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// The copy can encrypt but `decrypt` refuses it
+        #[arg(long)]
+        encrypt_only: bool,
+        
+        /// Write only the tag keys `verify --verification-key` checks files
+        /// with; the copy can neither encrypt nor decrypt
+        #[arg(long, conflicts_with = "encrypt_only")]
+        verification_key: bool,
+        
+        /// Output key file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    
+    /// Generate an organizational recovery keypair for key escrow
+    RecoveryKeygen {
+        /// Public key to hand to key holders (keygen --escrow)
+        #[arg(long)]
+        public: PathBuf,
+        
+        /// Private key kept by the recovery team
+        #[arg(long)]
+        private: PathBuf,
+    },
+    
+    /// Recover a key file from an escrow blob with the recovery private key
+    Recover {
+        /// Escrow blob written by keygen --escrow
+        #[arg(long)]
+        escrow: PathBuf,
+        
+        /// Organizational recovery private key
+        #[arg(long)]
+        org_key: PathBuf,
+        
+        /// Output key file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    
+    /// List key files with their owner, capabilities and self-signature status
+    List {
+        /// Key files, or directories whose *.keys files to list
+        #[arg(default_value = "./keys")]
+        paths: Vec<PathBuf>,
+        
+        /// Print the list as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    
+    /// Report which fields of a key file are damaged and what still works
+    Doctor {
+        /// Key file to examine
+        key_file: PathBuf,
+        
+        /// Print the diagnosis as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    
+    /// Benchmark Argon2id here and pick parameters that unlock in about the target time
+    Tune {
+        /// Unlock time to aim for, in milliseconds
+        #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+        target_ms: u64,
+        
+        /// Never choose less memory than this, in MiB (at least 19)
+        #[arg(long, default_value_t = 64)]
+        min_memory_mib: u32,
+        
+        /// Never choose more memory than this, in MiB
+        #[arg(long, default_value_t = 1024)]
+        max_memory_mib: u32,
+        
+        /// Save the parameters for `keygen --protector password --kdf-params`
+        #[arg(long, value_name = "FILE")]
+        save: Option<PathBuf>,
+        
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    
+    /// Overwrite a key file with random bytes and remove it, when decommissioning
+    /// Files encrypted under it become unrecoverable unless a backup survives
+    Destroy {
+        /// Key file to destroy
+        #[arg(short, long)]
+        key_file: PathBuf,
+        
+        /// Checkpoint files of interrupted runs under this key, destroyed too (repeatable)
+        #[arg(long, value_name = "PATH")]
+        checkpoint: Vec<PathBuf>,
+        
+        /// Skip the typed confirmation, for automation
+        #[arg(long)]
+        yes: bool,
+    },
+    
+    /// List the automatic backups of a key file, or restore one of them
+    RestoreBackup {
+        /// Key file whose backups to list or restore
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// Backup to restore, by the name listed or `latest`; omit to list them
+        #[arg(long, value_name = "NAME")]
+        backup: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PairCommands {
+    /// Start an exchange; send the offer written to stdout to the other party
+    Offer {
+        /// Your key file
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+    },
+    
+    /// Answer an offer; send the reply written to stdout back and keep the shared key
+    Accept {
+        /// Your key file
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// Offer received from the other party
+        offer: PathBuf,
+        
+        /// Where to save the shared key file
+        #[arg(short, long, default_value = "./keys/shared.keys")]
+        output: PathBuf,
+    },
+    
+    /// Complete an exchange you offered and keep the shared key
+    Finish {
+        /// Your key file (the one the offer was made with)
+        #[arg(short, long, default_value = "./keys/hybridguard.keys")]
+        key_file: PathBuf,
+        
+        /// Reply received from the other party
+        reply: PathBuf,
+        
+        /// Where to save the shared key file
+        #[arg(short, long, default_value = "./keys/shared.keys")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum StatsCommands {
+    /// Print the counters in the --stats-file file
+    Show {
+        /// Print the raw counters as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print where the keys and the key file password would be read from,
+    /// without reading either
+    Show {
+        /// Key file encrypt or decrypt would be given with -k
+        #[arg(short, long)]
+        key_file: Option<PathBuf>,
+        
+        /// Print the sources as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    // A bundle runs as its own small program, found by the trailer at its end
+    if let Some(code) = run_bundle() {
+        return code;
+    }
+    
+    // Argument errors exit with the usage code; --help and --version exit 0
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::from(exit_code::USAGE)
+            } else {
+                ExitCode::from(exit_code::SUCCESS)
+            };
+        }
+    };
+    
+    // Messages come from the translation file, if any, for the whole run
+    let lang = cli.lang.clone().or_else(|| std::env::var_os("HYBRIDGUARD_LANG").map(PathBuf::from));
+    let catalog = match lang.as_deref().map(Catalog::load).unwrap_or_else(|| Ok(Catalog::english())) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return ExitCode::from(e.code());
+        }
+    };
+    let catalog = catalog.with_emoji(!cli.no_emoji && messages::utf8_locale(|name| std::env::var(name).ok()));
+    
+    // Logging follows the verbosity flags, so it starts after parsing
+    let reporter = Reporter::new(Verbosity::from_flags(cli.quiet, cli.verbose))
+        .with_json_progress(cli.json_progress)
+        .with_catalog(Box::leak(Box::new(catalog)));
+    reporter.init_logger();
+    
+    match run(cli, &reporter) {
+        Ok(()) => ExitCode::from(exit_code::SUCCESS),
+        Err(e) => {
+            eprintln!("{} {}", reporter.text("error-prefix", &[]).red().bold(), e.localized(reporter.catalog()));
+            ExitCode::from(e.code())
+        }
+    }
+}
+
+/// Extract this executable's payload if it is a self-extracting bundle
+/// None for a plain binary, which goes on to the usual commands
+fn run_bundle() -> Option<ExitCode> {
+    let exe = std::env::current_exe().ok()?;
+    let reporter = match bundle::detect(&exe) {
+        // An unreadable executable is treated as a plain binary
+        Ok(None) | Err(HybridGuardError::Io(_)) => return None,
+        Ok(Some(_)) | Err(_) => {
+            let catalog = Catalog::english().with_emoji(messages::utf8_locale(|name| std::env::var(name).ok()));
+            Reporter::new(Verbosity::Normal).with_catalog(Box::leak(Box::new(catalog)))
+        }
+    };
+    let cli = match BundleCli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return Some(ExitCode::from(if e.use_stderr() { exit_code::USAGE } else { exit_code::SUCCESS }));
+        }
+    };
+    
+    let extracted = read_password(reporter.text("prompt-bundle-password", &[]))
+        .and_then(|password| bundle::extract(&exe, &password, &cli.output, &cancel_on_ctrl_c()));
+    Some(match extracted {
+        Ok(entries) => {
+            reporter.summary(message!(reporter, "extract-done", entries = entries.len(), output = cli.output.display()));
+            ExitCode::from(exit_code::SUCCESS)
+        }
+        Err(e) => {
+            eprintln!("{} {}", reporter.text("error-prefix", &[]).red().bold(), e.localized(reporter.catalog()));
+            ExitCode::from(e.code())
+        }
+    })
+}
+
+/// How written files are finished: outputs at one level, the key files,
+/// checkpoints and plans a crash must not lose at another
+struct Durability {
+    outputs: WriteOptions,
+    critical: WriteOptions,
+}
+
+impl Durability {
+    /// The defaults, or `level` for every file when `--durability` was given
+    fn new(level: Option<DurabilityLevel>) -> Self {
+        match level {
+            Some(level) => Self { outputs: WriteOptions::new(level), critical: WriteOptions::new(level) },
+            None => Self { outputs: WriteOptions::bulk(), critical: WriteOptions::critical() },
+        }
+    }
+}
+
+/// Run a parsed command; every subcommand reports failure through the returned error
+fn run(cli: Cli, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    reporter.banner();
+    let loose = if cli.fix_permissions { LoosePermissions::Fix } else { LoosePermissions::Warn };
+    let durability = Durability::new(cli.durability);
+    let locking = LockOptions::new()
+        .with_timeout(std::time::Duration::from_secs(cli.lock_timeout))
+        .with_break_stale(cli.break_stale_lock);
+    let password_attempts = if cli.json_progress { 1 } else { cli.password_attempts };
+    let credentials = Credentials::from_env();
+    // Only a password protector takes a password, so only then is one read
+    let password = match cli.protector {
+        Some(ProtectorSpec::Password) => Some(PasswordOrigin::resolve(cli.password_fd, &credentials)),
+        _ if cli.password_fd.is_some() => {
+            return Err(HybridGuardError::InvalidInput("--password-fd needs --protector password".to_string()));
+        }
+        _ => None,
+    };
+    if cli.key_file_fd.is_some() && !matches!(cli.command, Commands::Encrypt { .. } | Commands::Decrypt { .. } | Commands::Config { .. }) {
+        return Err(HybridGuardError::InvalidInput(
+            "--key-file-fd applies to encrypt and decrypt; other commands take a key file path".to_string(),
+        ));
+    }
+    if let Commands::Config { action: ConfigCommands::Show { key_file, json } } = cli.command {
+        let keys = KeysOrigin::resolve(key_file, cli.key_file_fd, &credentials)?;
+        return show_config(&SecretSources::new(&credentials, &keys, password.as_ref()), json, reporter);
+    }
+    let printer = *reporter;
+    let warnings = cli
+        .allow
+        .iter()
+        .fold(Warnings::new().with_deny(cli.deny_warnings), |warnings, code| warnings.allow(*code))
+        .on_warning(move |w| printer.warning(w));
+    let mut key_files = KeyFiles::new(loose, cli.protector)
+        .with_write_options(durability.critical.clone())
+        .with_lock(locking)
+        .with_password_attempts(password_attempts)
+        .with_warnings(warnings.clone())
+        .with_catalog(reporter.catalog());
+    if let Some(given) = password.map(|origin| origin.read()).transpose()?.flatten() {
+        key_files = key_files.with_password_source(Rc::new(given));
+    }
+    if !cli.no_key_backup {
+        let policy = BackupPolicy::new().with_keep(cli.keep_backups);
+        let policy = match cli.backup_dir {
+            Some(dir) => policy.with_dir(dir),
+            None => policy,
+        };
+        key_files = key_files.with_backups(policy, *reporter);
+    }
+    let stats = cli.stats_file.as_deref().map(|path| StatsFile::new(path).with_lock(locking));
+    if !cli.plugin.is_empty() && !matches!(cli.command, Commands::Cat { .. } | Commands::Serve { .. }) {
+        return Err(HybridGuardError::InvalidInput(
+            "--plugin applies to cat and serve; other commands run the built-in layers only".to_string(),
+        ));
+    }
+    
+    match cli.command {
+        Commands::Encrypt {
+            input,
+            output,
+            redundancy,
+            sparse,
+            checkpoint,
+            checkpoint_every,
+            profile_memory,
+            temp_dir,
+            stable_read,
+            stable_read_retries,
+            snapshot_copy,
+            shape,
+            label,
+            tag_content_type,
+            meta,
+            meta_private,
+            allow_special,
+            max_input_bytes,
+            split_size,
+            self_extracting,
+            passphrase_only,
+            source_cmd,
+            source_timeout,
+            key_lifetime,
+            archive,
+            run,
+        } => {
+            if self_extracting {
+                if run.dry_run {
+                    return Err(HybridGuardError::InvalidInput("--dry-run cannot plan a --self-extracting bundle".to_string()));
+                }
+                return bundle_dir(&input, &output, run.force, &durability.outputs, reporter);
+            }
+            if passphrase_only {
+                if run.dry_run {
+                    return Err(HybridGuardError::InvalidInput("--dry-run cannot plan a --passphrase-only encryption".to_string()));
+                }
+                run.refuse_budget("a --passphrase-only encryption")?;
+                return encrypt_passphrase_only(&input, &output, max_input_bytes, run.force, &durability.outputs, reporter);
+            }
+            let resources = run.apply_resources(reporter);
+            let keys = KeySource::new(KeysOrigin::resolve(run.key_file.clone(), cli.key_file_fd, &credentials)?, key_files.clone())?;
+            let source = source_cmd.map(|command| SourceCommand::new(command, source_timeout));
+            // The producer's output is planned like stdin: one input with nothing known about it
+            let input = match source {
+                Some(_) => vec![PathBuf::from(pipeline::SOURCE_INPUT)],
+                None => input,
+            };
+            let options = EncryptOptions {
+                redundancy,
+                sparse,
+                checkpoint: checkpoint.map(|path| CheckpointPlan::new(path, checkpoint_every)),
+                shape,
+                label,
+                tag_content_type,
+                metadata: meta.into_iter().collect(),
+                private_metadata: meta_private.into_iter().collect(),
+                allow_special,
+                max_input_bytes,
+                split_size,
+                source,
+                key_lifetime,
+                archive: archive.options(),
+            };
+            if options.sparse || options.shape.is_some() {
+                run.refuse_budget("--sparse or --shape encryption")?;
+            }
+            let plan = Plan::build(Operation::Encrypt, &input, &output, &keys, run.force, options).with_resources(resources);
+            let plan = preflight(plan, &run);
+            if run.dry_run {
+                return report_plan(plan, run.json);
+            }
+            if profile_memory && !profiling::available() {
+                return Err(HybridGuardError::InvalidInput(
+                    "--profile-memory needs a build with `--features memory-profile`".to_string(),
+                ));
+            }
+            reporter.progress(reporter.text("encrypt-start", &[]).green().bold());
+            let stable_read = stable_read.then_some(StableRead { retries: stable_read_retries, snapshot_copy });
+            let files = file_pairs(&plan);
+            let cancel = budgeted(cancel_on_ctrl_c(), &run);
+            let result = encrypt_files(plan, profile_memory, temp_dir.as_deref(), stable_read, &durability, &warnings, &cancel, reporter);
+            report_budget(&cancel, reporter);
+            record_stats(stats.as_ref(), "encrypt", &files, &result, reporter);
+            result?;
+        }
+        
+        Commands::Decrypt {
+            input,
+            output,
+            policy,
+            override_policy,
+            quarantine_executables,
+            content_report,
+            spool_to_temp,
+            max_plaintext_bytes,
+            sandbox,
+            sandbox_namespaces,
+            extract,
+            run,
+        } => {
+            let resources = run.apply_resources(reporter);
+            let keys = KeySource::new(KeysOrigin::resolve(run.key_file.clone(), cli.key_file_fd, &credentials)?, key_files.clone())?;
+            let mut content = ContentHandling::new(quarantine_executables, run.force);
+            // Nothing may parse the input before the sandbox is up, so none of the usual planning runs
+            if sandbox {
+                let options = SandboxOptions { unshare_namespaces: sandbox_namespaces };
+                if run.dry_run {
+                    let plan = Plan::build(Operation::Decrypt, &input, &output, &keys, run.force, EncryptOptions::default())
+                        .with_resources(resources)
+                        .with_sandbox(sandbox::probe(&options));
+                    return report_plan(preflight(plan, &run), run.json);
+                }
+                if stats.is_some() {
+                    return Err(HybridGuardError::InvalidInput("--sandbox cannot update a --stats-file".to_string()));
+                }
+                let key_manager = keys.resolve()?;
+                key_manager.decryption_keys()?;
+                let builder = guard_builder(key_manager, &cli.plugin)?;
+                let cancel = budgeted(cancel_on_ctrl_c(), &run);
+                let limits = plaintext_limits(DecryptLimits::strict(), max_plaintext_bytes);
+                let result = decrypt_sandboxed(&input, &output, builder, limits, &options, run.force, &mut content, &durability, &cancel, reporter);
+                report_budget(&cancel, reporter);
+                return result;
+            }
+            // An archive is decrypted into a scratch file, then unpacked into --output
+            let (output, extract) = if extract {
+                let [single] = &input[..] else {
+                    return Err(HybridGuardError::InvalidInput("--extract unpacks one archive; give one input".to_string()));
+                };
+                if single == std::path::Path::new(pipe::STDIN) {
+                    return Err(HybridGuardError::InvalidInput("--extract needs the ciphertext in a file; save stdin first".to_string()));
+                }
+                if run.dry_run {
+                    return Err(HybridGuardError::InvalidInput("--dry-run cannot plan an --extract".to_string()));
+                }
+                if std::fs::read_dir(&output).is_ok_and(|mut entries| entries.next().is_some()) {
+                    return Err(HybridGuardError::InvalidInput(format!("{} is not empty", output.display())));
+                }
+                let spool = pipe::Spool::reserve()?;
+                (spool.path().to_path_buf(), Some((spool, output)))
+            } else {
+                (output, None)
+            };
+            // Passphrase-only containers carry their own salt, so no key file is involved
+            if input.iter().any(|path| is_passphrase_only(path)) {
+                let [input] = &input[..] else {
+                    return Err(HybridGuardError::InvalidInput(
+                        "decrypt passphrase-only files one at a time; each asks for its passphrase".to_string(),
+                    ));
+                };
+                if run.dry_run {
+                    return Err(HybridGuardError::InvalidInput("--dry-run cannot plan a passphrase-only decryption".to_string()));
+                }
+                run.refuse_budget("a passphrase-only decryption")?;
+                if max_plaintext_bytes.is_some() {
+                    return Err(HybridGuardError::InvalidInput("--max-plaintext-bytes cannot bound a passphrase-only decryption".to_string()));
+                }
+                decrypt_passphrase_only(input, &output, run.force, &mut content, &durability.outputs, reporter)?;
+                unpack(extract, reporter)?;
+                return save_content_report(&content, content_report.as_deref(), &durability, reporter);
+            }
+            let cancel = budgeted(cancel_on_ctrl_c(), &run);
+            let spooled = if input.iter().any(|path| path == std::path::Path::new(pipe::STDIN)) {
+                if input.len() > 1 {
+                    return Err(HybridGuardError::InvalidInput("--input - reads one ciphertext; give no other inputs with it".to_string()));
+                }
+                if max_plaintext_bytes.is_some() {
+                    return Err(HybridGuardError::InvalidInput(
+                        "--max-plaintext-bytes reads each header before decrypting, which stdin cannot give back; save it to a file first".to_string(),
+                    ));
+                }
+                let piped = decrypt_stdin(&output, &keys, spool_to_temp, &run, &mut content, &durability, &cancel, reporter);
+                if !matches!(piped, Ok(Some(_))) {
+                    report_budget(&cancel, reporter);
+                }
+                match piped? {
+                    Some(spool) => Some(spool),
+                    None => return save_content_report(&content, content_report.as_deref(), &durability, reporter),
+                }
+            } else {
+                None
+            };
+            let input = spooled.as_ref().map_or(input, |spool| vec![spool.path().to_path_buf()]);
+            let policy = policy.map(LabelPolicy::load).transpose()?;
+            let mut plan = Plan::build(Operation::Decrypt, &input, &output, &keys, run.force, EncryptOptions::default())
+                .with_resources(resources);
+            if let Some(policy) = &policy {
+                plan = plan.with_policy(policy, SystemClock::new().unix_secs(), override_policy.is_some());
+            }
+            let plan = preflight(plan, &run);
+            if run.dry_run {
+                return report_plan(plan, run.json);
+            }
+            reporter.progress(reporter.text("decrypt-start", &[]).cyan().bold());
+            let audit_log = policy.and_then(|policy| policy.audit_log);
+            let overrides = override_policy.as_deref().zip(audit_log.as_deref());
+            let files = file_pairs(&plan);
+            let limits = plaintext_limits(DecryptLimits::UNBOUNDED, max_plaintext_bytes);
+            let result = decrypt_files(plan, overrides, limits, &mut content, &durability, &warnings, &cancel, reporter);
+            report_budget(&cancel, reporter);
+            record_stats(stats.as_ref(), "decrypt", &files, &result, reporter);
+            save_content_report(&content, content_report.as_deref(), &durability, reporter)?;
+            result?;
+            unpack(extract, reporter)?;
+        }
+        
+        Commands::Cat { input, offset, length, clamp, key_file } => {
+            let builder = guard_builder(key_files.load(&key_file)?, &cli.plugin)?;
+            cat_range(&input, offset..offset.saturating_add(length), clamp, builder, reporter)?;
+        }
+        
+        Commands::Verify { input: Some(input), verification_key: Some(verification_key), .. } => {
+            verify_integrity(&input, &VerificationKey::load(&verification_key)?, reporter)?;
+        }
+        
+        Commands::Verify { input: Some(input), quick, key_file: Some(key_file), max_report, .. } => {
+            verify_file(&input, quick, &VerifyOptions { max_report }, key_files.load(&key_file)?, reporter)?;
+        }
+        
+        Commands::Verify { input: None, key_file: Some(key_file), root, recursive, report, jobs, .. } => {
+            let root = root.ok_or_else(|| HybridGuardError::InvalidInput("verify needs --input or --deep --root".to_string()))?;
+            let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN));
+            let options = DrillOptions { recursive, jobs };
+            drill_tree(&root, &options, report.as_deref(), &key_files.load(&key_file)?, &durability.outputs, reporter)?;
+        }
+        
+        // Only a deep verify reaches here without a key file, and it decrypts
+        Commands::Verify { .. } => {
+            return Err(HybridGuardError::InvalidInput("a deep verify decrypts and needs --key-file".to_string()));
+        }
+        
+        Commands::Sign { input, key_file, output, export_signer, yes, force } => {
+            let output = output.unwrap_or_else(|| signature_path(&input));
+            sign_file(&input, &key_file, &output, export_signer.as_deref(), yes, force, &key_files, reporter)?;
+        }
+        
+        Commands::CheckSig { input, sig, signer } => {
+            let sig = sig.unwrap_or_else(|| signature_path(&input));
+            let signature = DetachedSignature::load(&sig)?;
+            let manifest = signing::verify_detached(&input, &signature, &SignerKey::load(&signer)?)?;
+            reporter.summary(message!(
+                reporter,
+                "check-sig-valid",
+                input = input.display(),
+                signer = signature.signer,
+                signed_at = signature.signed_at,
+                bytes = manifest.size
+            ));
+        }
+        
+        Commands::Migrate { input, output, key_file, recursive, force, delete_old, temp_dir } => {
+            reporter.progress(reporter.text("migrate-start", &[]).cyan().bold());
+            let options = MigrateOptions { force, delete_old, temp_dir, write: durability.outputs.clone() };
+            migrate_files(&input, &output, &key_file, &key_files, recursive, &options, reporter)?;
+        }
+        
+        Commands::Serve { stdio: _, key_file } => {
+            let key_manager = key_files.load(&key_file)?;
+            reporter.progress(message!(reporter, "serve-start", key_id = key_manager.key_id()));
+            let mut server = Server::new(guard_builder(key_manager, &cli.plugin)?.build()?);
+            server.serve(std::io::stdin().lock(), std::io::stdout().lock())?;
+        }
+        
+        Commands::Convert { input, to, output, force } => {
+            convert_file(&input, to, &output, force, reporter)?;
+        }
+        
+        Commands::Archive { input, output, options } => {
+            archive_dir(&input, &output, &options.options(), &durability.outputs, reporter)?;
+        }
+        
+        Commands::Extract { input, output } => {
+            let entries = archive::extract(std::io::BufReader::new(std::fs::File::open(&input)?), &output)?;
+            reporter.summary(message!(reporter, "extract-done", entries = entries.len(), output = output.display()));
+        }
+        
+        Commands::Scan { root, uses_layer, layer_version, format_below, older_than, json, rekey_with, key_file, temp_dir } => {
+            let predicate = Predicate { uses_layer, layer_version, format_below, older_than };
+            let rekey = rekey_with.zip(key_file);
+            scan_tree(&root, predicate, json, rekey, temp_dir, &key_files, &durability, reporter)?;
+        }
+        
+        Commands::Inspect { input, brief: true, .. } => {
+            inspect_brief(&input, reporter)?;
+        }
+        
+        Commands::Inspect { input, brief: false, key_file } => {
+            let key_manager = key_file.map(|path| key_files.load(&path)).transpose()?;
+            inspect_file(input, key_manager, reporter)?;
+        }
+        
+        Commands::RekeyPlan { root, from, to, plan } => {
+            plan_rekey(&root, &from, &to, &plan, &key_files, &durability, reporter)?;
+        }
+        
+        Commands::RekeyApply { plan, key_file, temp_dir } => {
+            apply_rekey(&plan, &key_file, temp_dir, &key_files, &durability, reporter)?;
+        }
+        
+        Commands::Status => {
+            print_status(reporter);
+        }
+        
+        Commands::Doctor { key_file, output_dir, json } => {
+            let options = DoctorOptions { key_file, output_dir, ..DoctorOptions::default() };
+            run_doctor(&options, json, reporter)?;
+        }
+        
+        Commands::Spec { json } => {
+            print_spec(json)?;
+        }
+        
+        Commands::Stats { action: StatsCommands::Show { json } } => {
+            let stats = stats.ok_or_else(|| HybridGuardError::InvalidInput("stats show needs --stats-file".to_string()))?;
+            show_stats(&stats, json, reporter)?;
+        }
+        
+        // Shown above, before any password is read for the key files
+        Commands::Config { .. } => return Ok(()),
+        
+        #[cfg(feature = "fixtures")]
+        Commands::GenFixtures { output } => {
+            let manifest = hybridguard::fixtures::write(&output)?;
+            reporter.summary(message!(
+                reporter,
+                "fixtures-done",
+                fixtures = manifest.fixtures.len(),
+                manifest = hybridguard::fixtures::MANIFEST_FILE,
+                output = output.display()
+            ));
+        }
+        
+        Commands::Keygen {
+            output,
+            from_master_key_file,
+            kdf,
+            escrow,
+            password_policy,
+            min_password_score,
+            allow_weak_password,
+            kdf_params,
+            owner_name,
+            owner_email,
+            owner_label,
+        } => {
+            // Refuse a malformed owner before asking for a password
+            let owner = KeyOwner::new(owner_name, owner_email, owner_label)?;
+            reporter.progress(reporter.text("keygen-start", &[]).yellow().bold());
+            let key_files = match kdf_params {
+                Some(path) if key_files.protector == Some(ProtectorSpec::Password) => key_files.clone().with_stretching(KdfParams::load(path)?),
+                Some(_) => return Err(HybridGuardError::InvalidInput("--kdf-params needs --protector password".to_string())),
+                None => key_files.clone(),
+            };
+            let mut policy = match password_policy {
+                Some(path) => PasswordPolicy::load(path)?,
+                None => PasswordPolicy::default(),
+            };
+            if let Some(score) = min_password_score {
+                policy = policy.with_min_score(score)?;
+            }
+            let passwords = PasswordRules { policy, allow_weak: allow_weak_password };
+            let master_key_file = from_master_key_file.map(|path| (path, kdf));
+            generate_keys(output, master_key_file, owner, escrow, &passwords, &key_files, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Backup { key_file, paper, output } } => {
+            require_paper(paper)?;
+            backup_keys(key_file, &key_files, output, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Restore { paper, input, output } } => {
+            require_paper(paper)?;
+            restore_keys(input, output, &key_files, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Export { key_file, encrypt_only, verification_key, output } } => {
+            export_keys(&key_file, encrypt_only, verification_key, &output, &key_files, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::RecoveryKeygen { public, private } } => {
+            generate_recovery_key(&public, &private, &key_files, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Recover { escrow, org_key, output } } => {
+            recover_keys(&escrow, &org_key, &output, &key_files, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::List { paths, json } } => {
+            list_key_files(&paths, json, &key_files)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Doctor { key_file, json } } => {
+            diagnose_key_file(&key_file, json, &key_files)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Tune { target_ms, min_memory_mib, max_memory_mib, save, json } } => {
+            let limits = TuneLimits {
+                min_memory_kib: min_memory_mib.saturating_mul(1024),
+                max_memory_kib: max_memory_mib.saturating_mul(1024),
+                ..TuneLimits::default()
+            };
+            tune_kdf(target_ms, limits, save, json, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::Destroy { key_file, checkpoint, yes } } => {
+            destroy_key_file(&key_file, &checkpoint, yes, &key_files, reporter)?;
+        }
+        
+        Commands::Key { action: KeyCommands::RestoreBackup { key_file, backup } } => {
+            restore_key_backup(&key_file, backup.as_deref(), &key_files, reporter)?;
+        }
+        
+        Commands::Pair { action } => {
+            pair(action, &key_files, reporter)?;
+        }
+    }
+    
+    Ok(())
+}
+
+/// Print a dry-run plan; blocked plans fail with the first problem's exit code
+fn report_plan(plan: Plan, json: bool) -> Result<(), HybridGuardError> {
+    if json {
+        println!("{}", plan.to_json()?);
+    } else {
+        plan.print();
+    }
+    match plan.into_error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// (input, output) paths of every file a plan covers
+fn file_pairs(plan: &Plan) -> Vec<(PathBuf, PathBuf)> {
+    plan.files.iter().map(|file| (file.input.clone(), file.output.clone())).collect()
+}
+
+/// Add a finished encrypt or decrypt run to --stats-file
+/// Only warns on failure: statistics never fail the run they describe
+fn record_stats(
+    stats: Option<&StatsFile>,
+    kind: &str,
+    files: &[(PathBuf, PathBuf)],
+    result: &Result<(), HybridGuardError>,
+    reporter: &Reporter,
+) {
+    let Some(stats) = stats else {
+        return;
+    };
+    let size = |path: &PathBuf| std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+    let updated = match result {
+        Ok(()) => {
+            let sizes: Vec<(u64, u64)> = files.iter().map(|(input, output)| (size(input), size(output))).collect();
+            stats.update(|counts| counts.record_success(kind, &sizes))
+        }
+        Err(e) => stats.update(|counts| counts.record_failure(kind, e.code())),
+    };
+    match updated {
+        Ok(None) => {}
+        Ok(Some(aside)) => reporter.warn(message!(reporter, "stats-set-aside", path = stats.path().display(), aside = aside.display())),
+        Err(e) => reporter.warn(message!(reporter, "stats-not-updated", path = stats.path().display(), reason = e.localized(reporter.catalog()))),
+    }
+}
+
+/// Print the counters in a stats file
+fn show_stats(stats: &StatsFile, json: bool, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    let counts = stats.load()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&counts).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?);
+    } else {
+        println!("{}", message!(reporter, "stats-file", path = stats.path().display()));
+        print!("{}", counts);
+    }
+    Ok(())
+}
+
+/// Print where this run's secrets would come from
+fn show_config(sources: &SecretSources, json: bool, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(sources).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?);
+    } else {
+        println!("{}", reporter.text("config-sources", &[]));
+        println!("{}", sources);
+    }
+    Ok(())
+}
+
+/// Print the format spec generated from this build's constants
+fn print_spec(json: bool) -> Result<(), HybridGuardError> {
+    let spec = FormatSpec::current()?;
+    if json {
+        println!("{}", spec.to_json()?);
+    } else {
+        println!("{}", spec);
+    }
+    Ok(())
+}
+
+/// Print every diagnostic; fails if any check failed
+fn run_doctor(options: &DoctorOptions, json: bool, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    let report = diagnostics::run(options, &SystemProbes);
+    if json {
+        let json = serde_json::to_string_pretty(&report).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        println!("{}", json);
+    } else {
+        println!("{}", reporter.text("doctor-title", &[]).green().bold());
+        for check in &report.checks {
+            let status = match check.status {
+                CheckStatus::Pass => check.status.to_string().green(),
+                CheckStatus::Warn => check.status.to_string().yellow(),
+                CheckStatus::Fail => check.status.to_string().red().bold(),
+            };
+            println!("  [{}] {}: {}", status, check.name, check.detail);
+            if let Some(hint) = &check.hint {
+                println!("         → {}", hint);
+            }
+        }
+    }
+    let failed: Vec<&str> = report.checks.iter().filter(|check| check.status == CheckStatus::Fail).map(|check| check.name).collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(HybridGuardError::DiagnosticsFailed(failed.join(", ")))
+    }
+}
+
+/// Run the pre-flight checks unless `--no-preflight` was given
+fn preflight(plan: Plan, run: &RunOptions) -> Plan {
+    if run.no_preflight {
+        plan
+    } else {
+        plan.with_preflight(&SystemProbe)
+    }
+}
+
+/// Token cancelled by the first Ctrl-C, so partial outputs get cleaned up;
+/// a second Ctrl-C exits at once
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let token = CancellationToken::new();
+    let handler = token.clone();
+    let installed = ctrlc::set_handler(move || {
+        if handler.is_cancelled() {
+            std::process::exit(exit_code::CANCELLED.into());
+        }
+        handler.cancel();
+    });
+    if let Err(e) = installed {
+        log::warn!("Ctrl-C will not clean up partial outputs: {}", e);
+    }
+    token
+}
+
+/// `cancel` carrying the run's --max-duration and --max-output-bytes, if any
+fn budgeted(cancel: CancellationToken, run: &RunOptions) -> CancellationToken {
+    match run.budget() {
+        budget if budget.is_unlimited() => cancel,
+        budget => cancel.with_budget(budget),
+    }
+}
+
+/// How much of its budget the run used, reported whether or not it succeeded
+fn report_budget(cancel: &CancellationToken, reporter: &Reporter) {
+    if let Some(usage) = cancel.budget_usage() {
+        reporter.budget(&usage);
+    }
+}
+
+/// Unwrap the keys of a plan that has no blocking problems
+fn ready(plan: Plan) -> Result<(KeyManager, Vec<cli::plan::FilePlan>), HybridGuardError> {
+    if plan.is_blocked() {
+        return Err(plan.into_error().unwrap_or_else(|| HybridGuardError::InvalidInput("plan is blocked".to_string())));
+    }
+    match plan.key_manager {
+        Some(key_manager) => Ok((key_manager, plan.files)),
+        None => Err(HybridGuardError::KeyGeneration("no keys resolved".to_string())),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encrypt_files(
+    plan: Plan,
+    profile_memory: bool,
+    temp_dir: Option<&std::path::Path>,
+    stable_read: Option<StableRead>,
+    durability: &Durability,
+    warnings: &Warnings,
+    cancel: &CancellationToken,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    if let Some(dir) = temp_dir {
+        if !dir.is_dir() {
+            return Err(HybridGuardError::InvalidInput(format!("--temp-dir {} is not a directory", dir.display())));
+        }
+    }
+    
+    let redundancy = plan.redundancy;
+    let sparse = plan.sparse;
+    let shape = plan.shape;
+    let label = plan.label.clone();
+    let tag_content_type = plan.tag_content_type;
+    let (metadata, private_metadata) = (plan.metadata.clone(), plan.private_metadata.clone());
+    let max_input_bytes = plan.max_input_bytes;
+    let source = plan.source.clone();
+    let checkpoint = plan.checkpoint.clone();
+    let split_size = plan.split_size;
+    let key_lifetime = plan.key_lifetime;
+    let archive = plan.archive;
+    let (key_manager, files) = ready(plan)?;
+    if let Some(days) = key_lifetime {
+        let lifetime = std::time::Duration::from_secs(days * 24 * 60 * 60);
+        if let Some(w) = warning::key_lifetime(&key_manager, lifetime, SystemClock::new().unix_secs()) {
+            warnings.emit(w)?;
+        }
+    }
+    let encryptor = file_encryptor();
+    
+    for file in files {
+        cancel.check()?;
+        if let Some(checkpoint) = &checkpoint {
+            let mut run = CheckpointedEncryption::open(
+                &file.input,
+                &file.output,
+                &key_manager,
+                checkpoint.every,
+                Some(&checkpoint.path),
+            )?
+            .with_write_options(durability.critical.clone())
+            .with_cancellation(cancel.clone());
+            let segments = run.header().segments();
+            if run.resumed_segments() > 0 {
+                reporter.summary(message!(reporter, "checkpoint-resuming", segment = run.resumed_segments(), segments = segments));
+            }
+            loop {
+                match run.step() {
+                    Ok(true) => reporter.progress(message!(reporter, "checkpoint-saved", segment = run.state().segments_done, segments = segments)),
+                    Ok(false) => break,
+                    Err(e) => {
+                        // The output and checkpoint are the resume state, so they stay
+                        if matches!(e, HybridGuardError::Cancelled | HybridGuardError::BudgetExceeded { .. }) {
+                            reporter.warn(reporter.text("checkpoint-interrupted", &[]));
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            let stats = run.finish()?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-chunked",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            continue;
+        }
+        
+        if let Some(source) = &source {
+            let stats = encrypt_source(source, &file.output, &key_manager, max_input_bytes, temp_dir, &durability.outputs, cancel, reporter)?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-chunked",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            continue;
+        }
+        
+        if file.archive {
+            let stats = encrypt_archive(&file.input, &file.output, &key_manager, &archive, max_input_bytes, temp_dir, &durability.outputs, cancel)?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-chunked",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            continue;
+        }
+        
+        if file.stream {
+            let stats = encrypt_special(&file.input, &file.output, &key_manager, max_input_bytes, temp_dir, &durability.outputs, cancel)?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-chunked",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            continue;
+        }
+        
+        if let Some(split_size) = split_size {
+            let stats = split::encrypt_file(&file.input, &file.output, &key_manager, split_size, cancel, &durability.outputs)?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-split",
+                input = file.input.display(),
+                manifest = stats.manifest.display(),
+                bytes = stats.chunked.plaintext_len,
+                parts = stats.parts
+            ));
+            continue;
+        }
+        
+        if let Some(policy) = &shape {
+            reporter.progress(message!(reporter, "encrypt-shaping", input = file.input.display(), policy = policy));
+            let report = encrypt_shaped(&file.input, &file.output, &key_manager, policy, temp_dir, &durability.outputs)?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-shaped",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = report.plaintext_len,
+                frames = report.frames,
+                filler = report.filler_frames
+            ));
+            continue;
+        }
+        
+        if sparse {
+            reporter.progress(message!(reporter, "encrypt-reading-extents", input = file.input.display()));
+            let stats = sparse::encrypt_file_staged(&file.input, &file.output, &key_manager, temp_dir, &durability.outputs)?;
+            reporter.summary(message!(
+                reporter,
+                "encrypt-done-sparse",
+                input = file.input.display(),
+                output = file.output.display(),
+                data = stats.data_len,
+                extents = stats.extents,
+                logical = stats.logical_len
+            ));
+            continue;
+        }
+        
+        let progress = reporter.file_progress(&file.input, &file.output);
+        let encrypt_container = || -> Result<Summary, HybridGuardError> {
+            // A fresh file key for this file, wrapped under the profile keys
+            progress.emit(OperationState::ResolvingKeys);
+            let (file_keys, wrapped) = encryptor.new_file_keys(&key_manager)?;
+            
+            let (data, snapshot) = match &stable_read {
+                Some(options) => {
+                    let (data, snapshot) = stable_read::read_stable_with(&file.input, options, Some(warnings))?;
+                    (data, Some(snapshot))
+                }
+                None => {
+                    // Only a regular file's size and mtime say whether it changed
+                    let snapshot = || fs::metadata(&file.input).ok().filter(|m| m.is_file()).and_then(|m| stable_read::snapshot_of(&m).ok());
+                    let before = snapshot();
+                    let data = read_input(&file.input, max_input_bytes)?;
+                    if before.is_some() && before != snapshot() {
+                        warnings.emit(stable_read::changed(&file.input, "the ciphertext may mix old and new contents; --stable-read waits for it to hold still"))?;
+                    }
+                    (data, None)
+                }
+            };
+            progress.emit(OperationState::Reading { bytes: data.len() as u64 });
+            
+            // Encrypt through all 4 layers
+            let encrypted = if profile_memory {
+                let (encrypted, memory) = encryptor.encrypt_profiled(&data, &file_keys, &progress)?;
+                for layer in &memory.layers {
+                    reporter.summary(message!(
+                        reporter,
+                        "encrypt-layer-memory",
+                        layer = layer.layer,
+                        peak = layer.peak_bytes,
+                        bytes = layer.output_bytes
+                    ));
+                }
+                encrypted
+            } else {
+                encryptor.encrypt_observed(&data, &file_keys, cancel, &progress)?
+            };
+            let encrypted = match snapshot {
+                Some(snapshot) => encrypted.with_source_snapshot(snapshot, &file_keys),
+                None => encrypted,
+            };
+            let encrypted = match &label {
+                Some(label) => encrypted.with_label(label, &file_keys),
+                None => encrypted,
+            };
+            let encrypted = match Metadata::new(metadata.clone(), &private_metadata, &file_keys)? {
+                Some(metadata) => encrypted.with_metadata(metadata, &file_keys),
+                None => encrypted,
+            };
+            // Sealed beside the private metadata, so it is added after it
+            let encrypted = if tag_content_type {
+                encrypted.with_content_type(sniff::content_type(&data).name(), &file_keys)?
+            } else {
+                encrypted
+            };
+            let mut encrypted = encrypted
+                .with_wrapped_key(wrapped, &file_keys)
+                .with_key_id(key_manager.key_id());
+            if let Ok(master) = key_manager.get_keys() {
+                encrypted = encrypted.with_verification_tag(master);
+            }
+            
+            // Save encrypted data, sharded when redundancy was requested
+            let mut encrypted_bytes = encrypted.to_bytes()?;
+            if let Some(redundancy) = redundancy {
+                encrypted_bytes = erasure::encode(&encrypted_bytes, redundancy)?;
+            }
+            cancel.take_output(encrypted_bytes.len() as u64)?;
+            write_staged(&file.output, &encrypted_bytes, temp_dir, Contents::Ciphertext, &durability.outputs, &progress)?;
+            Ok(Summary { direction: Direction::Encrypt, bytes_in: data.len() as u64, bytes_out: encrypted_bytes.len() as u64 })
+        };
+        progress.finish(encrypt_container())?;
+    }
+    
+    Ok(())
+}
+
+/// A `HybridGuard` builder with the layer of every `--plugin` library added
+fn guard_builder(key_manager: KeyManager, plugins: &[PathBuf]) -> Result<HybridGuardBuilder, HybridGuardError> {
+    let builder = HybridGuard::builder(key_manager);
+    #[cfg(feature = "plugins")]
+    let builder = plugins.iter().try_fold(builder, |builder, path| builder.with_plugin(path))?;
+    #[cfg(not(feature = "plugins"))]
+    if !plugins.is_empty() {
+        return Err(HybridGuardError::InvalidInput("--plugin needs a build with `--features plugins`".to_string()));
+    }
+    Ok(builder)
+}
+
+/// Write plaintext bytes `range` of `input` to stdout
+/// With `clamp` the range is cut to the plaintext length first; single
+/// containers record no plaintext length, so they are then decrypted whole
+fn cat_range(
+    input: &std::path::Path,
+    range: std::ops::Range<u64>,
+    clamp: bool,
+    builder: HybridGuardBuilder,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    
+    let guard = builder.with_decrypt_errors(DecryptErrorMode::Verbose).build()?;
+    let mut source = std::io::BufReader::new(std::fs::File::open(input)?);
+    let mut magic = Vec::new();
+    (&mut source).take(chunked::MAGIC.len() as u64).read_to_end(&mut magic)?;
+    source.seek(SeekFrom::Start(0))?;
+    
+    let plaintext = if !clamp {
+        guard.decrypt_range(&mut source, range.clone())?
+    } else if chunked::is_chunked(&magic) {
+        let len = chunked::read_header(&mut source)?.plaintext_len;
+        guard.decrypt_range(&mut source, range.start.min(len)..range.end.min(len))?
+    } else {
+        reporter.warn(message!(reporter, "cat-no-index", input = input.display()));
+        let mut bytes = Vec::new();
+        source.read_to_end(&mut bytes)?;
+        let plaintext = guard.decrypt(&EncryptedData::from_bytes(&bytes)?)?;
+        let len = plaintext.len() as u64;
+        plaintext[range.start.min(len) as usize..range.end.min(len) as usize].to_vec()
+    };
+    reporter.progress(message!(reporter, "cat-range", start = range.start, end = range.start + plaintext.len() as u64, input = input.display()));
+    
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&plaintext)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Decrypt every HybridGuard file under `root`, reporting each as it
+/// finishes; the report is written before the first failure is returned
+fn drill_tree(
+    root: &std::path::Path,
+    options: &DrillOptions,
+    report: Option<&std::path::Path>,
+    key_manager: &KeyManager,
+    write: &WriteOptions,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    const MIB: f64 = 1024.0 * 1024.0;
+    
+    reporter.progress(message!(reporter, "drill-start", root = root.display(), jobs = options.jobs));
+    let drill = drill::run(root, key_manager, options, &cancel_on_ctrl_c(), |file| match (file.status, file.failure()) {
+        (DrillStatus::Failed, Some(e)) => {
+            reporter.error(message!(reporter, "drill-failed", path = file.path.display(), error = e.localized(reporter.catalog())))
+        }
+        (DrillStatus::Skipped, _) => {
+            reporter.warn(message!(reporter, "drill-skipped", path = file.path.display(), reason = file.error.as_deref().unwrap_or_default()))
+        }
+        _ => reporter.progress(message!(
+            reporter,
+            "drill-verified",
+            path = file.path.display(),
+            bytes = file.size,
+            rate = format!("{:.1}", file.bytes_per_sec / MIB)
+        )),
+    })?;
+    if let Some(path) = report {
+        drill.save_with(path, write)?;
+        reporter.progress(message!(reporter, "drill-report", report = path.display()));
+    }
+    reporter.summary(message!(
+        reporter,
+        "drill-done",
+        verified = drill.verified,
+        failed = drill.failed,
+        skipped = drill.skipped,
+        bytes = drill.bytes,
+        rate = format!("{:.1}", drill.bytes_per_sec / MIB)
+    ));
+    drill.into_result()
+}
+
+/// Authenticate `input` in full, listing every damaged region, or with
+/// `quick` only a chunked file's Merkle index
+fn verify_file(
+    input: &std::path::Path,
+    quick: bool,
+    options: &VerifyOptions,
+    key_manager: KeyManager,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::Read;
+    
+    let mut source = std::io::BufReader::new(std::fs::File::open(input)?);
+    let mut magic = Vec::new();
+    (&mut source).take(chunked::MAGIC.len() as u64).read_to_end(&mut magic)?;
+    let is_chunked = chunked::is_chunked(&magic);
+    if quick {
+        if !is_chunked {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "{} has no segment index; --quick checks chunked files, verify it without --quick",
+                input.display()
+            )));
+        }
+        let stats = chunked::verify_index(&mut source, &key_manager)?;
+        reporter.summary(message!(reporter, "verify-index-done", segments = stats.segments, input = input.display()));
+        return Ok(());
+    }
+    
+    let report = verify::verify_file(input, &key_manager, options, &cancel_on_ctrl_c())?;
+    for range in &report.corrupt_ranges {
+        reporter.error(message!(reporter, "verify-corrupt", input = input.display(), range = range));
+    }
+    if report.unreported > 0 {
+        reporter.error(message!(reporter, "verify-unreported", input = input.display(), count = report.unreported));
+    }
+    if let Some(layer) = &report.failed_layer {
+        reporter.error(message!(reporter, "verify-failed-layer", input = input.display(), layer = layer));
+    }
+    match report.plaintext_len {
+        Some(_) if is_chunked => {
+            reporter.summary(message!(reporter, "verify-segments-done", segments = report.segments, input = input.display()))
+        }
+        Some(bytes) => reporter.summary(message!(reporter, "verify-done", input = input.display(), bytes = bytes)),
+        None => {}
+    }
+    report.into_result()
+}
+
+/// `INPUT.sig`, where `sign` writes and `check-sig` looks by default
+fn signature_path(input: &std::path::Path) -> PathBuf {
+    let mut path = input.as_os_str().to_os_string();
+    path.push(".");
+    path.push(signing::SIGNATURE_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Sign `input` with the signing key in `key_file`, first adding one to a
+/// key file that has none once the user agrees (or passed `--yes`)
+#[allow(clippy::too_many_arguments)]
+fn sign_file(
+    input: &std::path::Path,
+    key_file: &std::path::Path,
+    output: &std::path::Path,
+    export_signer: Option<&std::path::Path>,
+    yes: bool,
+    force: bool,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::{self, Write};
+    
+    if !force {
+        refuse_existing(output)?;
+    }
+    if let Some(path) = export_signer {
+        refuse_existing(path)?;
+    }
+    let mut key_manager = key_files.load(key_file)?;
+    if key_manager.signing_key().is_none() {
+        if !yes {
+            eprint!("{}", message!(reporter, "sign-prompt-new-key", path = key_file.display()));
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                return Err(HybridGuardError::CapabilityDenied(format!(
+                    "{} holds no signing key and none was added; nothing was signed",
+                    key_file.display()
+                )));
+            }
+        }
+        // Read the key file again under the lock, so a key added or a change
+        // saved by another run meanwhile is neither lost nor overwritten
+        let keystore = key_files.lock(key_file)?;
+        key_manager = key_files.load(key_file)?;
+        if key_manager.signing_key().is_none() {
+            key_manager = key_manager.with_signing_key(SigningKey::generate()?);
+            keystore.save(&key_manager, key_file)?;
+            let signer = key_manager.signing_key().map(|key| key.public().fingerprint()).unwrap_or_default();
+            reporter.summary(message!(reporter, "sign-key-added", path = key_file.display(), signer = signer));
+        }
+    }
+    
+    let signature = key_manager.sign_detached(input)?;
+    signature.save_with(output, key_files.write_options())?;
+    reporter.summary(message!(reporter, "sign-done", input = input.display(), output = output.display(), signer = signature.signer));
+    if let (Some(path), Some(key)) = (export_signer, key_manager.signing_key()) {
+        key.public().save_with(path, key_files.write_options())?;
+        reporter.summary(message!(reporter, "sign-signer-written", path = path.display(), signer = key.public().fingerprint()));
+    }
+    Ok(())
+}
+
+/// Check `input` against its tags under a verification key, decrypting nothing
+fn verify_integrity(input: &std::path::Path, key: &VerificationKey, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    let report = verify::verify_integrity(input, key, &cancel_on_ctrl_c())?;
+    for range in &report.corrupt_ranges {
+        reporter.error(message!(reporter, "verify-corrupt", input = input.display(), range = range));
+    }
+    if report.is_intact() {
+        reporter.summary(message!(reporter, "verify-integrity-done", input = input.display(), key_id = key.key_id()));
+    }
+    report.into_result()
+}
+
+/// The encryptor `encrypt` writes single containers with
+#[cfg(not(feature = "fixtures"))]
+fn file_encryptor() -> HybridGuardEncryptor {
+    HybridGuardEncryptor::new()
+}
+
+/// The encryptor `encrypt` writes single containers with
+/// Fixture builds honour HYBRIDGUARD_FIXTURE_SEED and HYBRIDGUARD_FIXTURE_TIME
+/// (seconds since the epoch), so golden-container tests can reproduce CLI output
+#[cfg(feature = "fixtures")]
+fn file_encryptor() -> HybridGuardEncryptor {
+    use hybridguard::crypto::drbg::SeededRandom;
+    use hybridguard::timing::FixedClock;
+    use std::sync::Arc;
+    
+    let mut encryptor = HybridGuardEncryptor::new();
+    if let Ok(seed) = std::env::var("HYBRIDGUARD_FIXTURE_SEED") {
+        encryptor = encryptor.with_rng(Arc::new(SeededRandom::new(seed.as_bytes(), b"cli")));
+    }
+    if let Some(secs) = std::env::var("HYBRIDGUARD_FIXTURE_TIME").ok().and_then(|secs| secs.parse().ok()) {
+        encryptor = encryptor.with_clock(Arc::new(FixedClock::new(secs)));
+    }
+    encryptor
+}
+
+/// Decrypt the ciphertext on stdin as it arrives; a single container is
+/// instead spooled for the usual decrypt when `spool` allows, and returned
+#[allow(clippy::too_many_arguments)]
+fn decrypt_stdin(
+    output: &std::path::Path,
+    keys: &KeySource,
+    spool: bool,
+    run: &RunOptions,
+    content: &mut ContentHandling,
+    durability: &Durability,
+    cancel: &CancellationToken,
+    reporter: &Reporter,
+) -> Result<Option<pipe::Spool>, HybridGuardError> {
+    if run.dry_run {
+        return Err(HybridGuardError::InvalidInput("--dry-run cannot plan stdin, which is only read once".to_string()));
+    }
+    if output == std::path::Path::new(pipe::STDIN) {
+        return Err(HybridGuardError::InvalidInput(
+            "stdin is decrypted into a file that appears once authenticated; give --output a path".to_string(),
+        ));
+    }
+    if !run.force {
+        refuse_existing(output)?;
+    }
+    let key_manager = keys.resolve()?;
+    key_manager.decryption_keys()?;
+    reporter.progress(message!(reporter, "decrypt-file", input = pipe::STDIN));
+    match pipe::decrypt(std::io::stdin().lock(), output, &key_manager, spool, &durability.outputs, cancel)? {
+        Piped::Decrypted(stats) => {
+            reporter.summary(message!(
+                reporter,
+                "decrypt-done-chunked",
+                input = pipe::STDIN,
+                output = output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            content.check_written(std::path::Path::new(pipe::STDIN), output, &durability.outputs, reporter)?;
+            Ok(None)
+        }
+        Piped::Spooled(spool) => {
+            reporter.progress(message!(reporter, "decrypt-spooled", path = spool.path().display()));
+            Ok(Some(spool))
+        }
+    }
+}
+
+fn save_content_report(
+    content: &ContentHandling,
+    path: Option<&std::path::Path>,
+    durability: &Durability,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    if let Some(path) = path {
+        content.report.save_with(path, &durability.outputs)?;
+        reporter.progress(message!(reporter, "content-report", report = path.display()));
+    }
+    Ok(())
+}
+
+/// What decrypt does about the content type of each output
+struct ContentHandling {
+    /// `--quarantine-executables` directory
+    quarantine: Option<PathBuf>,
+    force: bool,
+    report: ContentReport,
+}
+
+impl ContentHandling {
+    fn new(quarantine: Option<PathBuf>, force: bool) -> Self {
+        Self { quarantine, force, report: ContentReport::default() }
+    }
+    
+    /// Report `check` and decide where its output is written
+    fn route(&mut self, mut check: ContentCheck, reporter: &Reporter) -> Result<PathBuf, HybridGuardError> {
+        self.announce(&check, reporter);
+        let target = match self.quarantine.as_deref().filter(|_| check.executable) {
+            Some(dir) => {
+                let path = check.quarantine_path(dir, self.force)?;
+                reporter.summary(message!(reporter, "decrypt-quarantined", input = check.input.display(), path = path.display()));
+                check.quarantined = Some(path.clone());
+                path
+            }
+            None => check.requested.clone(),
+        };
+        self.report.files.push(check);
+        Ok(target)
+    }
+    
+    /// Check an output a streaming decrypt already wrote, moving it into
+    /// quarantine if it is executable; pipes and devices are not reread
+    fn check_written(
+        &mut self,
+        input: &std::path::Path,
+        output: &std::path::Path,
+        write: &WriteOptions,
+        reporter: &Reporter,
+    ) -> Result<(), HybridGuardError> {
+        if !std::fs::metadata(output).is_ok_and(|meta| meta.is_file()) {
+            return Ok(());
+        }
+        let check = ContentCheck::of_file(input, output, None)?;
+        let target = self.route(check, reporter)?;
+        if target != output {
+            content::quarantine_file(output, &target, write)?;
+        }
+        Ok(())
+    }
+    
+    fn announce(&self, check: &ContentCheck, reporter: &Reporter) {
+        if let Some(stored) = &check.stored {
+            reporter.summary(message!(
+                reporter,
+                "decrypt-content-type",
+                input = check.input.display(),
+                stored = stored,
+                detected = check.detected
+            ));
+        }
+        if check.mismatch {
+            reporter.caution(message!(
+                reporter,
+                "decrypt-content-mismatch",
+                input = check.input.display(),
+                stored = check.stored.as_deref().unwrap_or_default(),
+                detected = check.detected
+            ));
+        }
+        if check.executable {
+            reporter.caution(message!(reporter, "decrypt-executable", input = check.input.display(), detected = check.detected));
+        }
+    }
+}
+
+/// Decrypt every file of a plan; `overrides` is the `--override-policy`
+/// reason and the audit log each overridden file is recorded in first
+#[allow(clippy::too_many_arguments)]
+fn decrypt_files(
+    plan: Plan,
+    overrides: Option<(&str, &std::path::Path)>,
+    limits: DecryptLimits,
+    content: &mut ContentHandling,
+    durability: &Durability,
+    warnings: &Warnings,
+    cancel: &CancellationToken,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    let (key_manager, files) = ready(plan)?;
+    // Refuse encrypt-only keys before touching any file
+    key_manager.decryption_keys()?;
+    if cancel.budget_usage().is_some() {
+        if let Some(file) = files.iter().find(|file| file.shaped || file.sparse) {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "--max-duration and --max-output-bytes cannot bound {}, which is shaped or sparse",
+                file.input.display()
+            )));
+        }
+    }
+    if limits.max_plaintext != usize::MAX {
+        if let Some(file) = files.iter().find(|file| file.shaped || file.sparse) {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "--max-plaintext-bytes cannot bound {}, which is shaped or sparse",
+                file.input.display()
+            )));
+        }
+    }
+    let encryptor = HybridGuardEncryptor::new().with_limits(limits);
+    
+    for file in files {
+        cancel.check()?;
+        if let Some((reason, log)) = overrides.filter(|_| !file.overridden.is_empty()) {
+            let label = file.label.as_deref().unwrap_or_default();
+            let record = AuditRecord::new(&file.input, label, file.overridden.clone(), reason, SystemClock::new().unix_secs());
+            policy::append_audit(log, &record)?;
+            reporter.warn(message!(
+                reporter,
+                "decrypt-policy-override",
+                label = label,
+                input = file.input.display(),
+                reasons = file.overridden.join("; "),
+                log = log.display()
+            ));
+        }
+        if file.chunked || file.shaped || file.sparse || file.split {
+            reporter.progress(message!(reporter, "decrypt-file", input = file.input.display()));
+        }
+        if file.split {
+            let mut parts = std::io::BufReader::new(split::SplitSet::open(&file.input)?.reader()?);
+            check_declared_plaintext(chunked::read_header(&mut parts)?.plaintext_len, &limits)?;
+            let stats = split::decrypt_file_with(&file.input, &file.output, &key_manager, cancel, &durability.outputs)?;
+            reporter.summary(message!(
+                reporter,
+                "decrypt-done-chunked",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            content.check_written(&file.input, &file.output, &durability.outputs, reporter)?;
+            continue;
+        }
+        if file.chunked {
+            let mut source = std::io::BufReader::new(fs::File::open(&file.input)?);
+            check_declared_plaintext(chunked::read_header(&mut source)?.plaintext_len, &limits)?;
+            let stats = chunked::decrypt_file_with(&file.input, &file.output, &key_manager, cancel, &durability.outputs)?;
+            reporter.summary(message!(
+                reporter,
+                "decrypt-done-chunked",
+                input = file.input.display(),
+                output = file.output.display(),
+                bytes = stats.plaintext_len,
+                segments = stats.segments,
+                epochs = stats.epochs
+            ));
+            content.check_written(&file.input, &file.output, &durability.outputs, reporter)?;
+            continue;
+        }
+        if file.shaped {
+            let len = decrypt_shaped(&file.input, &file.output, &key_manager, &durability.outputs)?;
+            reporter.summary(message!(reporter, "decrypt-done", input = file.input.display(), output = file.output.display(), bytes = len));
+            content.check_written(&file.input, &file.output, &durability.outputs, reporter)?;
+            continue;
+        }
+        if file.sparse {
+            let stats = sparse::decrypt_file_with(&file.input, &file.output, &key_manager, &durability.outputs)?;
+            reporter.summary(message!(
+                reporter,
+                "decrypt-done-sparse",
+                input = file.input.display(),
+                output = file.output.display(),
+                data = stats.data_len,
+                logical = stats.logical_len
+            ));
+            content.check_written(&file.input, &file.output, &durability.outputs, reporter)?;
+            continue;
+        }
+        let encrypted = file.load()?;
+        if !file.damaged_shards.is_empty() {
+            reporter.warn(message!(
+                reporter,
+                "decrypt-rebuilt-shards",
+                shards = erasure::list(&file.damaged_shards),
+                input = file.input.display()
+            ));
+        }
+        
+        let progress = reporter.file_progress(&file.input, &file.output);
+        let mut decrypt_container = || -> Result<Summary, HybridGuardError> {
+            progress.emit(OperationState::ResolvingKeys);
+            let keys = key_manager.keys_for(&encrypted)?;
+            for w in warning::for_container(&encrypted) {
+                warnings.emit(w)?;
+            }
+            // The plan already measured the container
+            let bytes_in = file.input_size.unwrap_or(encrypted.ciphertext().len() as u64);
+            progress.emit(OperationState::Reading { bytes: bytes_in });
+            
+            // Decrypt through all 4 layers (in reverse)
+            let decrypted = encryptor.decrypt_observed(&encrypted, &keys, cancel, &progress)?;
+            let check = ContentCheck::new(&file.input, &file.output, encrypted.content_type(&keys)?.as_deref(), &decrypted);
+            let target = content.route(check, reporter)?;
+            cancel.take_output(decrypted.len() as u64)?;
+            
+            // Save decrypted data, executables without execute permission in quarantine
+            write_staged(&target, &decrypted, None, Contents::Plaintext, &durability.outputs, &progress)?;
+            if target != file.output {
+                content::strip_execute(&target)?;
+            }
+            Ok(Summary { direction: Direction::Decrypt, bytes_in, bytes_out: decrypted.len() as u64 })
+        };
+        progress.finish(decrypt_container())?;
+    }
+    
+    Ok(())
+}
+
+/// `base` with its plaintext limit lowered to `--max-plaintext-bytes`
+fn plaintext_limits(base: DecryptLimits, max_plaintext_bytes: Option<u64>) -> DecryptLimits {
+    let Some(max) = max_plaintext_bytes else {
+        return base;
+    };
+    let max = usize::try_from(max).unwrap_or(usize::MAX);
+    DecryptLimits { max_plaintext: base.max_plaintext.min(max), ..base }
+}
+
+/// Refuse a chunked or split file whose header declares more plaintext than `limits` allow
+fn check_declared_plaintext(plaintext_len: u64, limits: &DecryptLimits) -> Result<(), HybridGuardError> {
+    let size = usize::try_from(plaintext_len).unwrap_or(usize::MAX);
+    if size > limits.max_plaintext {
+        return Err(HybridGuardError::LimitExceeded { which: "plaintext".to_string(), size, limit: limits.max_plaintext });
+    }
+    Ok(())
+}
+
+/// Decrypt one single container under the sandbox
+/// The keys, the input's raw bytes and the staged output are all read or
+/// opened first; only then does a confined child rebuild the input from
+/// shards, parse and decrypt it into the staged file, under `limits`
+/// (`DecryptLimits::strict()` or tighter) whether or not a sandbox could be
+/// had. This process commits the output once the child succeeded
+#[allow(clippy::too_many_arguments)]
+fn decrypt_sandboxed(
+    input: &[PathBuf],
+    output: &std::path::Path,
+    builder: HybridGuardBuilder,
+    limits: DecryptLimits,
+    options: &SandboxOptions,
+    force: bool,
+    content: &mut ContentHandling,
+    durability: &Durability,
+    cancel: &CancellationToken,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::{Read, Write};
+    
+    let [input] = input else {
+        return Err(HybridGuardError::InvalidInput("--sandbox decrypts one file at a time".to_string()));
+    };
+    match std::fs::metadata(output) {
+        Ok(meta) if !meta.is_file() => {
+            return Err(HybridGuardError::InvalidInput(format!("{}: --sandbox writes regular files only", output.display())));
+        }
+        Ok(_) if !force => {
+            return Err(HybridGuardError::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists (pass --force to overwrite)", output.display()),
+            )));
+        }
+        _ => {}
+    }
+    let guard = builder.with_decrypt_errors(DecryptErrorMode::Verbose).build()?;
+    
+    // Raw bytes only: nothing looks past the magic until the sandbox is up
+    let source: Box<dyn Read> = if input == std::path::Path::new(pipe::STDIN) {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::fs::File::open(input)?)
+    };
+    let mut bytes = Vec::new();
+    source.take(limits.max_ciphertext as u64 + 1).read_to_end(&mut bytes)?;
+    if bytes.len() > limits.max_ciphertext {
+        return Err(HybridGuardError::LimitExceeded { which: "ciphertext".to_string(), size: bytes.len(), limit: limits.max_ciphertext });
+    }
+    let streamed = [split::is_part, sparse::is_sparse, chunked::is_chunked, shaping::is_shaped].iter().any(|is| is(&bytes));
+    if streamed || split::is_manifest_path(input) {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "{}: --sandbox decrypts single containers; chunked, sparse, shaped and split files open more files as they decrypt",
+            input.display()
+        )));
+    }
+    sandbox::warm_up(&guard)?;
+    let mut staged = StagedFile::create(output, None, Contents::Plaintext)?.with_write_options(durability.outputs.clone());
+    
+    let code = sandbox::run_confined(options, |report| {
+        if report.sandbox {
+            reporter.progress(message!(reporter, "decrypt-sandboxed", input = input.display()));
+            for reason in &report.skipped {
+                reporter.warn(message!(reporter, "plan-not-applied", reason = reason));
+            }
+        } else {
+            reporter.warn(message!(reporter, "decrypt-unsandboxed", input = input.display(), reason = report.skipped.join("; ")));
+        }
+        match decrypt_confined(input, output, bytes, &guard, limits, content, staged.file(), cancel, reporter) {
+            Ok(()) => exit_code::SUCCESS,
+            Err(e) => {
+                eprintln!("{} {}", reporter.text("error-prefix", &[]).red().bold(), e.localized(reporter.catalog()));
+                e.code()
+            }
+        }
+    })?;
+    if code != exit_code::SUCCESS {
+        // The child reported its error; nothing of it is kept
+        drop(staged);
+        drop(guard);
+        std::process::exit(code.into());
+    }
+    let written = staged.file().metadata()?.len();
+    staged.commit()?;
+    reporter.summary(message!(reporter, "decrypt-done", input = input.display(), output = output.display(), bytes = written));
+    Ok(())
+}
+
+/// The confined part of `decrypt_sandboxed`: rebuild, parse and decrypt
+/// `bytes` into the already open `file`
+#[allow(clippy::too_many_arguments)]
+fn decrypt_confined(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    mut bytes: Vec<u8>,
+    guard: &HybridGuard,
+    limits: DecryptLimits,
+    content: &mut ContentHandling,
+    file: &mut std::fs::File,
+    cancel: &CancellationToken,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::Write;
+    
+    if erasure::is_sharded(&bytes) {
+        let recovered = erasure::decode(&bytes)?;
+        if !recovered.damaged.is_empty() {
+            reporter.warn(message!(
+                reporter,
+                "decrypt-rebuilt-shards",
+                shards = erasure::list(&recovered.damaged),
+                input = input.display()
+            ));
+        }
+        bytes = recovered.payload;
+    }
+    if let Some(hint) = sniff::identify(&bytes).rejection_hint() {
+        return Err(HybridGuardError::UnsupportedFormat(format!("{}: {}", input.display(), hint)));
+    }
+    let encrypted = encoding::decode(&bytes)?;
+    if encrypted.passphrase().is_some() {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "{} is passphrase-only; it cannot be decrypted under --sandbox",
+            input.display()
+        )));
+    }
+    let plaintext = guard.decrypt_with_options(&encrypted, &DecryptOptions { limits, cancel: Some(cancel.clone()), ..DecryptOptions::default() })?;
+    let stored = guard.content_type(&encrypted)?;
+    
+    // Without a quarantine directory the target is always the output
+    content.route(ContentCheck::new(input, output, stored.as_deref(), &plaintext), reporter)?;
+    file.write_all(&plaintext)?;
+    Ok(())
+}
+
+/// Encrypt one file under a passphrase read from stdin, with no key file
+fn encrypt_passphrase_only(
+    input: &[PathBuf],
+    output: &std::path::Path,
+    max_input_bytes: Option<u64>,
+    force: bool,
+    write: &WriteOptions,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    let [input] = input else {
+        return Err(HybridGuardError::InvalidInput("--passphrase-only encrypts exactly one file".to_string()));
+    };
+    if !force {
+        refuse_existing(output)?;
+    }
+    
+    // Shown even with --quiet, as for bundles
+    reporter.caution(reporter.text("passphrase-caveat", &[]));
+    let passphrase = read_password(reporter.text("prompt-passphrase", &[]))?;
+    PasswordPolicy::default().check(&passphrase, false)?;
+    
+    let progress = reporter.file_progress(input, output);
+    let encrypt = || -> Result<Summary, HybridGuardError> {
+        let data = read_input(input, max_input_bytes)?;
+        progress.emit(OperationState::Reading { bytes: data.len() as u64 });
+        let encrypted = simple::encrypt(&passphrase, &data)?;
+        write_staged(output, &encrypted, None, Contents::Ciphertext, write, &progress)?;
+        Ok(Summary { direction: Direction::Encrypt, bytes_in: data.len() as u64, bytes_out: encrypted.len() as u64 })
+    };
+    progress.finish(encrypt())?;
+    Ok(())
+}
+
+/// Whether `path` is a binary container written by `encrypt --passphrase-only`
+fn is_passphrase_only(path: &std::path::Path) -> bool {
+    std::fs::File::open(path)
+        .ok()
+        .and_then(|mut file| container::peek_header_from(&mut file).ok())
+        .is_some_and(|header| header.passphrase.is_some())
+}
+
+/// Decrypt a passphrase-only container with a passphrase read from stdin
+fn decrypt_passphrase_only(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    force: bool,
+    content: &mut ContentHandling,
+    write: &WriteOptions,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    if !force {
+        refuse_existing(output)?;
+    }
+    let passphrase = read_password(reporter.text("prompt-passphrase", &[]))?;
+    
+    let progress = reporter.file_progress(input, output);
+    let mut decrypt = || -> Result<Summary, HybridGuardError> {
+        let bytes = std::fs::read(input)?;
+        progress.emit(OperationState::Reading { bytes: bytes.len() as u64 });
+        let encrypted = EncryptedData::from_bytes(&bytes)?;
+        let (decrypted, stored) = simple::decrypt_container_typed(&passphrase, &encrypted)?;
+        let check = ContentCheck::new(input, output, stored.as_deref(), &decrypted);
+        let target = content.route(check, reporter)?;
+        write_staged(&target, &decrypted, None, Contents::Plaintext, write, &progress)?;
+        if target != output {
+            content::strip_execute(&target)?;
+        }
+        Ok(Summary { direction: Direction::Decrypt, bytes_in: bytes.len() as u64, bytes_out: decrypted.len() as u64 })
+    };
+    progress.finish(decrypt())?;
+    Ok(())
+}
+
+/// Encrypt one file as a constant-rate shaped stream
+/// Pipes and devices get each frame as it falls due; regular files are staged
+fn encrypt_shaped(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    key_manager: &KeyManager,
+    policy: &ShapingPolicy,
+    temp_dir: Option<&std::path::Path>,
+    write: &WriteOptions,
+) -> Result<ShapingReport, HybridGuardError> {
+    let source = std::fs::File::open(input)?;
+    let pipeline = layers::registry();
+    let (keys, key_id, clock) = (key_manager.get_keys()?, key_manager.key_id(), SystemClock::new());
+    if std::fs::metadata(output).is_ok_and(|meta| !meta.is_file() && !meta.is_dir()) {
+        let sink = std::fs::OpenOptions::new().write(true).open(output)?;
+        return shaping::encrypt_stream_with_shaping(&pipeline, keys, key_id, source, sink, policy, &clock);
+    }
+    let mut staged = StagedFile::create(output, temp_dir, Contents::Ciphertext)?.with_write_options(write.clone());
+    let report = shaping::encrypt_stream_with_shaping(&pipeline, keys, key_id, source, staged.file(), policy, &clock)?;
+    staged.commit()?;
+    Ok(report)
+}
+
+/// Decrypt a shaped stream, dropping its filler, into a staged plaintext file
+fn decrypt_shaped(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    key_manager: &KeyManager,
+    write: &WriteOptions,
+) -> Result<u64, HybridGuardError> {
+    use std::io::{BufReader, BufWriter, Write};
+    
+    let source = BufReader::new(std::fs::File::open(input)?);
+    let mut staged = StagedFile::create(output, None, Contents::Plaintext)?.with_write_options(write.clone());
+    let mut sink = BufWriter::new(staged.file());
+    let len = shaping::decrypt_shaped_stream(&layers::registry(), key_manager.decryption_keys()?, key_manager.key_id(), source, &mut sink)?;
+    sink.flush()?;
+    drop(sink);
+    staged.commit()?;
+    Ok(len)
+}
+
+/// Write `bytes` to `output` through a temporary file that is removed on failure
+/// Devices and pipes cannot be replaced by a rename and are written directly
+fn write_staged(
+    output: &std::path::Path,
+    bytes: &[u8],
+    temp_dir: Option<&std::path::Path>,
+    contents: Contents,
+    write: &WriteOptions,
+    progress: &Progress,
+) -> Result<(), HybridGuardError> {
+    use std::io::Write;
+    
+    if std::fs::metadata(output).is_ok_and(|meta| !meta.is_file() && !meta.is_dir()) {
+        std::fs::write(output, bytes)?;
+        progress.emit(OperationState::Writing { bytes: bytes.len() as u64 });
+        progress.emit(OperationState::Finalizing);
+        return Ok(());
+    }
+    let mut staged = StagedFile::create(output, temp_dir, contents)?.with_write_options(write.clone());
+    staged.file().write_all(bytes)?;
+    progress.emit(OperationState::Writing { bytes: bytes.len() as u64 });
+    progress.emit(OperationState::Finalizing);
+    staged.commit()?;
+    Ok(())
+}
+
+fn migrate_files(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    key_file: &std::path::Path,
+    key_files: &KeyFiles,
+    recursive: bool,
+    options: &MigrateOptions,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    let key_manager = key_files.load(key_file)?;
+    
+    if recursive {
+        reporter.progress(message!(reporter, "migrate-tree", input = input.display()));
+        let summary = cli::migrate::migrate_tree(input, output, &key_manager, options, reporter)?;
+        reporter.summary(summary.line(reporter));
+        return summary.into_result();
+    }
+    
+    reporter.progress(message!(reporter, "migrate-file", input = input.display()));
+    match cli::migrate::migrate_file(input, output, &key_manager, options)? {
+        Outcome::Migrated { from_format } => {
+            reporter.summary(message!(
+                reporter,
+                "migrate-done",
+                input = input.display(),
+                output = output.display(),
+                from = from_format,
+                to = container::FORMAT_VERSION
+            ));
+        }
+        Outcome::Current => {
+            reporter.summary(message!(reporter, "migrate-current", input = input.display()));
+        }
+        Outcome::NotCiphertext => {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "{}: not a HybridGuard file",
+                input.display()
+            )));
+        }
+    }
+    
+    Ok(())
+}
+
+/// Read all of `path`, failing once it passes `limit` bytes
+fn read_input(path: &std::path::Path, limit: Option<u64>) -> Result<Vec<u8>, HybridGuardError> {
+    use std::fs;
+    use std::io::Read;
+    
+    let Some(limit) = limit else {
+        return Ok(fs::read(path)?);
+    };
+    let mut data = Vec::new();
+    fs::File::open(path)?.take(limit.saturating_add(1)).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        return Err(HybridGuardError::LimitExceeded {
+            which: format!("input {} (read so far)", path.display()),
+            size: data.len(),
+            limit: limit as usize,
+        });
+    }
+    Ok(data)
+}
+
+/// Encrypt what the `--source-cmd` producer writes as a chunked ciphertext
+/// at `output`, committed only once the producer has exited with status 0
+#[allow(clippy::too_many_arguments)]
+fn encrypt_source(
+    source: &SourceCommand,
+    output: &std::path::Path,
+    key_manager: &KeyManager,
+    max_input_bytes: Option<u64>,
+    temp_dir: Option<&std::path::Path>,
+    options: &WriteOptions,
+    cancel: &CancellationToken,
+    reporter: &Reporter,
+) -> Result<chunked::ChunkedStats, HybridGuardError> {
+    reporter.progress(message!(reporter, "encrypt-source", command = source.command));
+    let limit = max_input_bytes.unwrap_or(u64::MAX);
+    encrypt_spooled(output, key_manager, temp_dir, options, cancel, |sink| {
+        let len = source.run_into(sink, limit, cancel);
+        // The producer's own failure is the run's error; ours also says it was stopped
+        if let Err(e) = &len {
+            if !matches!(e, HybridGuardError::SourceFailed(_)) {
+                reporter.warn(message!(reporter, "encrypt-source-stopped", command = source.command, reason = e));
+            }
+        }
+        len
+    })
+}
+
+/// Encrypt a FIFO or character device, read once front to back up to
+/// `max_input_bytes`, as a chunked ciphertext at `output`
+fn encrypt_special(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    key_manager: &KeyManager,
+    max_input_bytes: Option<u64>,
+    temp_dir: Option<&std::path::Path>,
+    options: &WriteOptions,
+    cancel: &CancellationToken,
+) -> Result<chunked::ChunkedStats, HybridGuardError> {
+    use std::io::Read;
+    
+    let limit = max_input_bytes.unwrap_or(u64::MAX);
+    encrypt_spooled(output, key_manager, temp_dir, options, cancel, |sink| {
+        let copied = std::io::copy(&mut std::fs::File::open(input)?.take(limit.saturating_add(1)), sink)?;
+        if copied > limit {
+            return Err(HybridGuardError::LimitExceeded {
+                which: format!("input {} (read so far)", input.display()),
+                size: copied as usize,
+                limit: limit as usize,
+            });
+        }
+        Ok(copied)
+    })
+}
+
+/// Archive the directory `input`, up to `max_input_bytes` of it, as a
+/// chunked ciphertext at `output`; `decrypt --extract` unpacks it
+#[allow(clippy::too_many_arguments)]
+fn encrypt_archive(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    key_manager: &KeyManager,
+    options: &ArchiveOptions,
+    max_input_bytes: Option<u64>,
+    temp_dir: Option<&std::path::Path>,
+    write: &WriteOptions,
+    cancel: &CancellationToken,
+) -> Result<chunked::ChunkedStats, HybridGuardError> {
+    encrypt_spooled(output, key_manager, temp_dir, write, cancel, |sink| {
+        let len = archive::encoded_len(&archive::write(input, options, sink)?)?;
+        match max_input_bytes {
+            Some(limit) if len > limit => Err(HybridGuardError::LimitExceeded {
+                which: format!("archive of {}", input.display()),
+                size: len as usize,
+                limit: limit as usize,
+            }),
+            _ => Ok(len),
+        }
+    })
+}
+
+/// Encrypt what `fill` writes, and says the length of, as a chunked
+/// ciphertext at `output`
+/// It is spooled as it comes into an unnamed plaintext file beside `output`,
+/// since the chunked header needs the length up front; neither file is left
+/// behind when `fill` or the encryption fails
+fn encrypt_spooled(
+    output: &std::path::Path,
+    key_manager: &KeyManager,
+    temp_dir: Option<&std::path::Path>,
+    options: &WriteOptions,
+    cancel: &CancellationToken,
+    fill: impl FnOnce(&mut (dyn std::io::Write + Send)) -> Result<u64, HybridGuardError>,
+) -> Result<chunked::ChunkedStats, HybridGuardError> {
+    use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+    
+    let mut spool = StagedFile::create(output, None, Contents::Plaintext)?;
+    let len = {
+        let mut sink = BufWriter::new(spool.file());
+        let len = fill(&mut sink)?;
+        sink.flush()?;
+        len
+    };
+    
+    spool.file().seek(SeekFrom::Start(0))?;
+    let mut staged = StagedFile::create(output, temp_dir, Contents::Ciphertext)?.with_write_options(options.clone());
+    let stats = {
+        let mut target = BufWriter::new(staged.file());
+        let stats = chunked::encrypt_into(&mut BufReader::new(spool.file()), len, &mut target, key_manager, chunked::DEFAULT_SEGMENT_CHUNKS, cancel)?;
+        target.flush()?;
+        stats
+    };
+    staged.commit()?;
+    Ok(stats)
+}
+
+/// Archive the directory `input` into a staged `output`
+fn archive_dir(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    options: &ArchiveOptions,
+    write: &WriteOptions,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::{BufWriter, Write};
+    
+    let mut staged = StagedFile::create(output, None, Contents::Plaintext)?.with_write_options(write.clone());
+    let mut sink = BufWriter::new(staged.file());
+    let entries = archive::write(input, options, &mut sink)?;
+    sink.flush()?;
+    drop(sink);
+    staged.commit()?;
+    reporter.summary(message!(reporter, "archive-done", entries = entries.len(), input = input.display(), output = output.display()));
+    Ok(())
+}
+
+/// Unpack the archive `decrypt --extract` decrypted into its scratch file
+fn unpack(extract: Option<(pipe::Spool, PathBuf)>, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    let Some((spool, output)) = extract else {
+        return Ok(());
+    };
+    let entries = archive::extract(std::io::BufReader::new(std::fs::File::open(spool.path())?), &output)?;
+    reporter.summary(message!(reporter, "extract-done", entries = entries.len(), output = output.display()));
+    Ok(())
+}
+
+/// Write a self-extracting bundle of the one directory in `input`
+fn bundle_dir(
+    input: &[PathBuf],
+    output: &std::path::Path,
+    force: bool,
+    write: &WriteOptions,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    let [input] = input else {
+        return Err(HybridGuardError::InvalidInput("--self-extracting bundles exactly one directory".to_string()));
+    };
+    if !force {
+        refuse_existing(output)?;
+    }
+    
+    // Shown even with --quiet: these are the terms a bundle comes with
+    reporter.caution(reporter.text("bundle-caveat-stub", &[]));
+    reporter.caution(reporter.text("bundle-caveat-password", &[]));
+    let platform = format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH);
+    reporter.caution(message!(reporter, "bundle-caveat-platform", platform = platform));
+    
+    let password = read_password(reporter.text("prompt-bundle-password", &[]))?;
+    PasswordPolicy::default().check(&password, false)?;
+    // A typo here would seal the bundle for good, so it is typed twice
+    if read_password(reporter.text("prompt-bundle-password-confirm", &[]))? != password {
+        return Err(HybridGuardError::InvalidInput("the bundle passwords did not match; nothing was written".to_string()));
+    }
+    let summary = bundle::create(&std::env::current_exe()?, input, &password, output, write, &cancel_on_ctrl_c())?;
+    reporter.summary(message!(
+        reporter,
+        "bundle-done",
+        entries = summary.entries,
+        input = input.display(),
+        output = output.display(),
+        bytes = summary.bundle_len
+    ));
+    Ok(())
+}
+
+fn convert_file(
+    input: &std::path::Path,
+    to: Encoding,
+    output: &std::path::Path,
+    force: bool,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::fs;
+    use std::io::Write;
+    
+    if let Some((from, len)) = convert_chunked(input, to, output, force)? {
+        reporter.summary(message!(
+            reporter,
+            "convert-done",
+            input = input.display(),
+            from = from,
+            output = output.display(),
+            to = to,
+            bytes = len
+        ));
+        return Ok(());
+    }
+    let bytes = fs::read(input)?;
+    if let Some(hint) = sniff::identify(&bytes).rejection_hint() {
+        return Err(HybridGuardError::UnsupportedFormat(format!("{}: {}", input.display(), hint)));
+    }
+    let from = encoding::detect(&bytes);
+    let converted = encoding::convert(&bytes, to)?;
+    if !force && output != input {
+        refuse_existing(output)?;
+    }
+    let mut staged = StagedFile::create(output, None, Contents::Ciphertext)?;
+    staged.file().write_all(&converted)?;
+    staged.commit()?;
+    
+    reporter.summary(message!(
+        reporter,
+        "convert-done",
+        input = input.display(),
+        from = from,
+        output = output.display(),
+        to = to,
+        bytes = converted.len()
+    ));
+    Ok(())
+}
+
+/// Armor a chunked ciphertext or take the armor off one, a line at a time,
+/// giving the encoding it was in and the bytes written; None for any other
+/// input, which is converted whole
+fn convert_chunked(
+    input: &std::path::Path,
+    to: Encoding,
+    output: &std::path::Path,
+    force: bool,
+) -> Result<Option<(Encoding, u64)>, HybridGuardError> {
+    use std::fs::File;
+    use std::io::{self, BufReader, Read};
+    
+    let mut head = Vec::new();
+    File::open(input)?.take(64).read_to_end(&mut head)?;
+    let from = if chunked::is_chunked(&head) {
+        Encoding::Binary
+    } else if encoding::detect(&head) == Encoding::Armor {
+        let mut decoded = Vec::new();
+        let armor = encoding::ArmorReader::new(BufReader::new(File::open(input)?));
+        if armor.take(chunked::MAGIC.len() as u64).read_to_end(&mut decoded).is_err() || !chunked::is_chunked(&decoded) {
+            return Ok(None);
+        }
+        Encoding::Armor
+    } else {
+        return Ok(None);
+    };
+    if to == from || to == Encoding::Json {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "{} is a chunked ciphertext ({}), which converts between binary and armor only",
+            input.display(),
+            from
+        )));
+    }
+    if !force && output != input {
+        refuse_existing(output)?;
+    }
+    
+    let mut source = BufReader::new(File::open(input)?);
+    let mut staged = StagedFile::create(output, None, Contents::Ciphertext)?;
+    if to == Encoding::Armor {
+        let mut armor = encoding::ArmorWriter::new(staged.file())?;
+        io::copy(&mut source, &mut armor)?;
+        armor.finish()?;
+    } else {
+        io::copy(&mut encoding::ArmorReader::new(source), staged.file())?;
+    }
+    staged.commit()?;
+    Ok(Some((from, std::fs::metadata(output)?.len())))
+}
+
+/// Print header fields in time independent of the file size
+/// Binary containers are read around the ciphertext; chunked and sparse files
+/// keep everything needed in their leading header
+/// List containers under `root` matching `predicate`; with `rekey` as
+/// (new key file, current key file), re-encrypt each match in place
+fn scan_tree(
+    root: &std::path::Path,
+    predicate: Predicate,
+    json: bool,
+    rekey: Option<(PathBuf, PathBuf)>,
+    temp_dir: Option<PathBuf>,
+    key_files: &KeyFiles,
+    durability: &Durability,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    // Load keys first, so a bad key file fails before the walk
+    let keys = match &rekey {
+        Some((new, current)) => Some((key_files.load(current)?, key_files.load(new)?)),
+        None => None,
+    };
+    
+    reporter.progress(message!(reporter, "scan-start", root = root.display()));
+    let mut found = scan::find(root, predicate);
+    let mut hits = Vec::new();
+    let mut matched = 0;
+    for hit in found.by_ref() {
+        matched += 1;
+        if !json {
+            println!("{}", describe_hit(&hit));
+        }
+        // Only kept when needed, so a plain listing runs in constant memory
+        if json || keys.is_some() {
+            hits.push(hit);
+        }
+    }
+    
+    let mut rekeyed = None;
+    if let Some((from, to)) = &keys {
+        let options = MigrateOptions { force: false, delete_old: true, temp_dir, write: durability.outputs.clone() };
+        let mut summary = cli::migrate::Summary::default();
+        for hit in &hits {
+            summary.record(&hit.path, cli::migrate::rekey_file(hit, from, to, &options), reporter);
+        }
+        rekeyed = Some(summary);
+    }
+    
+    if json {
+        let report = serde_json::json!({
+            "root": JsonPath::new(root),
+            "containers": found.containers(),
+            "hits": hits,
+            "unscanned": found.unscanned(),
+            "rekeyed": rekeyed.as_ref().map(|summary| summary.migrated),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?);
+    } else {
+        for unscanned in found.unscanned() {
+            reporter.warn(message!(reporter, "scan-unscanned", path = unscanned.path.display(), reason = unscanned.reason));
+        }
+    }
+    reporter.summary(message!(
+        reporter,
+        "scan-done",
+        matched = matched,
+        containers = found.containers(),
+        unscanned = found.unscanned().len()
+    ));
+    
+    if let Some(summary) = rekeyed {
+        reporter.summary(summary.line(reporter));
+        summary.into_result()?;
+    }
+    if !found.unscanned().is_empty() {
+        return Err(HybridGuardError::Io(std::io::Error::other(format!(
+            "{} paths under {} could not be scanned",
+            found.unscanned().len(),
+            root.display()
+        ))));
+    }
+    Ok(())
+}
+
+/// Write a rekey plan for `root` and list the files in it
+fn plan_rekey(
+    root: &std::path::Path,
+    from: &str,
+    to_key_file: &std::path::Path,
+    plan_path: &std::path::Path,
+    key_files: &KeyFiles,
+    durability: &Durability,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    let to = key_files.load(to_key_file)?;
+    reporter.progress(reporter.text("rekey-timing", &[]));
+    let throughput = rekey::measure_throughput()?;
+    reporter.progress(message!(reporter, "scan-start", root = root.display()));
+    let (plan, unscanned) = rekey::plan(root, from, &to, to_key_file, throughput)?;
+    for entry in &plan.entries {
+        println!("{}  {}  ~{:.1}s", entry.path, preflight::human(entry.size), entry.estimated_ms as f64 / 1000.0);
+    }
+    for unscanned in &unscanned {
+        reporter.warn(message!(reporter, "scan-unscanned", path = unscanned.path.display(), reason = unscanned.reason));
+    }
+    plan.save_with(plan_path, &durability.critical)?;
+    reporter.summary(message!(
+        reporter,
+        "rekey-planned",
+        files = plan.entries.len(),
+        size = preflight::human(plan.pending_bytes()),
+        seconds = format!("{:.0}", plan.pending_ms() as f64 / 1000.0),
+        plan = plan_path.display()
+    ));
+    Ok(())
+}
+
+/// Re-encrypt the pending files of a rekey plan; Ctrl-C stops after the
+/// current file with the plan saved
+fn apply_rekey(
+    plan_path: &std::path::Path,
+    key_file: &std::path::Path,
+    temp_dir: Option<PathBuf>,
+    key_files: &KeyFiles,
+    durability: &Durability,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    let plan = RekeyPlan::load(plan_path)?;
+    let from = key_files.load(key_file)?;
+    let to = key_files.load(&plan.to_key_file.to_path_buf()?)?;
+    let pending = plan.entries.iter().filter(|entry| entry.status.is_pending()).count();
+    reporter.progress(message!(reporter, "rekey-pending", pending = pending, total = plan.entries.len()));
+    
+    let options = ApplyOptions {
+        temp_dir,
+        cancel: Some(cancel_on_ctrl_c()),
+        write: durability.outputs.clone(),
+        plan_write: durability.critical.clone(),
+    };
+    let summary = rekey::apply(plan_path, &from, &to, &options, |entry| match &entry.status {
+        EntryStatus::Skipped { reason } => reporter.warn(message!(reporter, "rekey-skipped", path = entry.path, reason = reason)),
+        EntryStatus::Failed { error } => reporter.error(message!(reporter, "rekey-entry-failed", path = entry.path, error = error)),
+        _ => reporter.progress(message!(reporter, "rekey-entry-done", path = entry.path)),
+    })?;
+    reporter.summary(message!(
+        reporter,
+        "rekey-done",
+        rekeyed = summary.rekeyed,
+        already = summary.already_rekeyed,
+        earlier = summary.previously_done,
+        skipped = summary.skipped,
+        failed = summary.failed.len()
+    ));
+    summary.into_result()
+}
+
+/// One line of `scan` output: path, format and its version, date and layers
+fn describe_hit(hit: &ScanHit) -> String {
+    let date = chrono::DateTime::from_timestamp(hit.timestamp as i64, 0)
+        .map(|time| time.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| hit.timestamp.to_string());
+    let layers: Vec<String> = hit.descriptors.iter().map(|d| format!("{} v{}", d.name, d.version)).collect();
+    format!("{}  {} v{}  {}  {}", hit.path.display(), hit.format, hit.format_version, date, layers.join(", "))
+}
+
+fn inspect_brief(input: &std::path::Path, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    use std::io::{Read, Seek, SeekFrom};
+    
+    let mut file = std::fs::File::open(input)?;
+    let size = file.metadata()?.len();
+    let mut start = Vec::with_capacity(8);
+    (&mut file).take(8).read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+    
+    println!("{}", message!(reporter, "inspect-brief-start", input = input.display(), bytes = size));
+    if chunked::is_chunked(&start) {
+        let header = chunked::read_header(&mut file)?;
+        println!("{}", message!(reporter, "inspect-format-chunked", segments = header.segments(), epochs = header.epochs()));
+        println!("{}", message!(reporter, "inspect-key-id", key_id = header.key_id));
+        println!("{}", message!(reporter, "inspect-plaintext", bytes = header.plaintext_len));
+    } else if sparse::is_sparse(&start) {
+        let header = sparse::read_header(&mut file)?;
+        println!("{}", message!(reporter, "inspect-format-sparse", extents = header.extents.len()));
+        println!("{}", message!(reporter, "inspect-key-id", key_id = header.key_id));
+        println!("{}", message!(reporter, "inspect-logical-size", bytes = header.logical_len));
+    } else if start.starts_with(&container::MAGIC) {
+        let header = container::peek_header_from(&mut file)?;
+        println!("{}", message!(reporter, "inspect-format-container", version = header.format_version));
+        match &header.key_id {
+            Some(key_id) => println!("{}", message!(reporter, "inspect-key-id", key_id = key_id)),
+            None => println!("{}", reporter.text("inspect-key-id-unrecorded", &[])),
+        }
+        println!("{}", message!(reporter, "inspect-ciphertext", bytes = header.ciphertext_len));
+        match &header.content_digest {
+            Some(digest) => println!("{}", message!(reporter, "inspect-digest", digest = hex(digest))),
+            None => println!("{}", message!(reporter, "inspect-digest-unrecorded", version = header.format_version)),
+        }
+        if header.envelope {
+            println!("{}", reporter.text("inspect-file-key-own", &[]));
+        } else {
+            println!("{}", reporter.text("inspect-file-key-none", &[]));
+        }
+        if let Some(snapshot) = &header.source_snapshot {
+            println!("{}", describe_snapshot(snapshot, reporter));
+        }
+        if let Some(label) = &header.label {
+            println!("{}", message!(reporter, "inspect-label", label = label));
+        }
+        if let Some(content_type) = &header.content_type {
+            println!("{}", message!(reporter, "inspect-content-type", content_type = content_type));
+        }
+        if let Some(passphrase) = &header.passphrase {
+            println!("{}", message!(reporter, "inspect-passphrase", params = passphrase.params, salt = hex(&passphrase.salt)));
+        }
+        if let Some(metadata) = &header.metadata {
+            print_metadata(metadata, reporter);
+        }
+    } else {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "{}: --brief reads binary containers, chunked and sparse files; inspect it without --brief",
+            input.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Size and mtime a stable read captured, as inspect reports them
+fn describe_snapshot(snapshot: &SourceSnapshot, reporter: &Reporter) -> String {
+    let modified = format!("{}.{:09}", snapshot.modified_secs, snapshot.modified_nanos);
+    message!(reporter, "inspect-snapshot", bytes = snapshot.len, modified = modified)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    codec::hex_lower(bytes)
+}
+
+/// Public metadata entries, and how large the sealed private map is
+fn print_metadata(metadata: &Metadata, reporter: &Reporter) {
+    println!("{}", reporter.text("inspect-metadata", &[]));
+    for (key, value) in metadata.extra() {
+        println!("     {} = {}", key, metadata::display_value(value));
+    }
+    if let Some(sealed) = metadata.sealed() {
+        println!("{}", message!(reporter, "inspect-metadata-sealed", bytes = sealed.ciphertext.len()));
+    }
+}
+
+fn inspect_file(input: PathBuf, key_manager: Option<KeyManager>, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    println!("{}", message!(reporter, "inspect-start", input = input.display()));
+    let mut bytes = fs::read(&input)?;
+    println!("{}", message!(reporter, "inspect-size", bytes = bytes.len()));
+    
+    let kind = sniff::identify(&bytes);
+    println!("{}", message!(reporter, "inspect-identified", kind = kind.description()));
+    
+    if let Some(hint) = kind.rejection_hint() {
+        println!("   {}", hint.yellow());
+        return Ok(());
+    }
+    
+    if sparse::is_sparse(&bytes) {
+        match sparse::read_header(&mut &bytes[..]) {
+            Ok(header) => {
+                println!();
+                println!("{}", reporter.text("inspect-sparse", &[]));
+                println!("{}", message!(reporter, "inspect-logical-size", bytes = header.logical_len));
+                println!("{}", message!(reporter, "inspect-extents", bytes = header.data_len(), extents = header.extents.len()));
+                println!("{}", message!(reporter, "inspect-key-id", key_id = header.key_id));
+                println!("{}", message!(reporter, "inspect-timestamp", timestamp = header.timestamp));
+            }
+            Err(e) => println!("   {}", e.localized(reporter.catalog()).yellow()),
+        }
+        return Ok(());
+    }
+    
+    if chunked::is_chunked(&bytes) {
+        match chunked::read_header(&mut &bytes[..]) {
+            Ok(header) => {
+                println!();
+                println!("{}", reporter.text("inspect-chunked", &[]));
+                println!(
+                    "{}",
+                    message!(reporter, "inspect-segments", bytes = header.plaintext_len, segments = header.segments(), segment_len = header.segment_len)
+                );
+                match header.epoch_segments {
+                    0 => println!("{}", reporter.text("inspect-epochs-none", &[])),
+                    every => println!("{}", message!(reporter, "inspect-epochs", epochs = header.epochs(), every = every)),
+                }
+                println!("{}", message!(reporter, "inspect-key-id", key_id = header.key_id));
+                println!("{}", message!(reporter, "inspect-timestamp", timestamp = header.timestamp));
+            }
+            Err(e) => println!("   {}", e.localized(reporter.catalog()).yellow()),
+        }
+        return Ok(());
+    }
+    
+    if erasure::is_sharded(&bytes) {
+        let layout = erasure::read_layout(&bytes)?;
+        println!();
+        println!("{}", message!(reporter, "inspect-redundancy", shards = layout.redundancy, bytes = layout.shard_len));
+        match erasure::decode(&bytes) {
+            Ok(recovered) => {
+                if recovered.damaged.is_empty() {
+                    println!("{}", reporter.text("inspect-shards-intact", &[]));
+                } else {
+                    println!("{}", message!(reporter, "inspect-shards-damaged", shards = erasure::list(&recovered.damaged)).yellow());
+                }
+                bytes = recovered.payload;
+            }
+            Err(e) => {
+                println!("   {}", e.localized(reporter.catalog()).yellow());
+                return Ok(());
+            }
+        }
+    }
+    
+    match encoding::decode(&bytes) {
+        Ok(encrypted) => {
+            println!();
+            println!("{}", reporter.text("inspect-container", &[]));
+            match encoding::detect(&bytes) {
+                Encoding::Binary => println!("{}", message!(reporter, "inspect-container-version", version = container::format_version(&bytes)?)),
+                text => println!("{}", message!(reporter, "inspect-encoding", encoding = text, version = container::FORMAT_VERSION)),
+            }
+            println!("{}", message!(reporter, "inspect-version", version = encrypted.version()));
+            println!("{}", message!(reporter, "inspect-timestamp", timestamp = encrypted.timestamp()));
+            if let Some(key_id) = encrypted.key_id() {
+                println!("{}", message!(reporter, "inspect-key-id", key_id = key_id));
+            }
+            if let Some(label) = encrypted.label() {
+                println!("{}", message!(reporter, "inspect-label", label = label));
+            }
+            if let Some(content_type) = encrypted.clear_content_type() {
+                println!("{}", message!(reporter, "inspect-content-type", content_type = content_type));
+            }
+            if let Some(passphrase) = encrypted.passphrase() {
+                println!("{}", message!(reporter, "inspect-passphrase", params = passphrase.params, salt = hex(&passphrase.salt)));
+            }
+            if let Some(note) = encrypted.migrated_from() {
+                println!("{}", message!(reporter, "inspect-migrated-from", version = note.format_version, timestamp = note.timestamp));
+            }
+            if let Some(token) = encrypted.timestamp_token() {
+                println!(
+                    "{}",
+                    message!(reporter, "inspect-trusted-timestamp", time = token.time, authority = token.authority, serial = token.serial)
+                );
+            }
+            println!("{}", message!(reporter, "inspect-layers", layers = encrypted.layers().join(" → ")));
+            for descriptor in encrypted.descriptors() {
+                println!("{}", message!(reporter, "inspect-layer", name = descriptor.name, version = descriptor.version));
+            }
+            println!("{}", reporter.text("inspect-security", &[]));
+            print_assessment(&SecurityAssessment::of_descriptors(encrypted.descriptors()), "     ", reporter);
+            println!("{}", message!(reporter, "inspect-ciphertext", bytes = encrypted.ciphertext().len()));
+            if let Some(digest) = encrypted.content_digest() {
+                println!("{}", message!(reporter, "inspect-digest", digest = hex(digest)));
+            }
+            if let Some(snapshot) = encrypted.source_snapshot() {
+                println!("{}", describe_snapshot(snapshot, reporter));
+            }
+            if !encrypted.metadata().is_empty() {
+                print_metadata(encrypted.metadata(), reporter);
+            }
+            if let Some(key_manager) = key_manager {
+                let guard = HybridGuard::builder(key_manager).with_decrypt_errors(DecryptErrorMode::Verbose).build()?;
+                let private = guard.open_metadata(&encrypted)?;
+                // A type in the clear was shown above
+                if let (None, Some(content_type)) = (encrypted.clear_content_type(), guard.content_type(&encrypted)?) {
+                    println!("{}", message!(reporter, "inspect-content-type", content_type = content_type));
+                }
+                println!("{}", reporter.text("inspect-private-metadata", &[]));
+                if private.is_empty() {
+                    println!("{}", reporter.text("inspect-private-none", &[]));
+                }
+                for (key, value) in &private {
+                    println!("     {} = {}", key, metadata::display_value(value));
+                }
+            }
+        }
+        Err(e) => {
+            println!("{}", message!(reporter, "inspect-unreadable", error = e.localized(reporter.catalog())).yellow());
+        }
+    }
+    
+    Ok(())
+}
+
+fn print_assessment(assessment: &SecurityAssessment, indent: &str, reporter: &Reporter) {
+    for layer in &assessment.layers {
+        println!("{}{}", indent, message!(reporter, "assessment-layer", name = layer.name, class = layer.class, bits = layer.counted_bits));
+    }
+    if assessment.is_weak() {
+        println!("{}{}", indent, message!(reporter, "assessment-weak", bits = assessment.effective_bits).yellow());
+    } else {
+        println!("{}{}", indent, message!(reporter, "assessment-effective", bits = assessment.effective_bits));
+    }
+}
+
+fn print_status(reporter: &Reporter) {
+    println!("{}", reporter.text("status-title", &[]).green().bold());
+    println!("{}", "═══════════════════════════════════════".green());
+    println!();
+    
+    // Get layer information
+    let encryptor = HybridGuardEncryptor::new();
+    let layers = encryptor.layer_info();
+    
+    println!("{}", reporter.text("status-layers", &[]));
+    for (i, layer) in layers.iter().enumerate() {
+        let id = if layer.status == "Active" { "status-layer-active" } else { "status-layer-pending" };
+        println!("{}", message!(reporter, id, index = i + 1, name = layer.name, status = layer.status));
+        if layer.security_class.is_quantum_resistant() {
+            println!("{}", message!(reporter, "status-layer-quantum", class = layer.security_class, bits = layer.security_class.counted_bits()));
+        } else {
+            println!("{}", message!(reporter, "status-layer-classical", class = layer.security_class).yellow());
+        }
+    }
+    println!();
+    
+    println!("{}", reporter.text("status-effective", &[]));
+    print_assessment(&encryptor.effective_security(), "  ", reporter);
+    println!();
+    
+    let system = HybridGuard::system_status();
+    println!("{}", reporter.text("status-memory", &[]));
+    if system.memory_locked {
+        println!("{}", reporter.text("status-memory-locked", &[]));
+    } else {
+        println!("{}", reporter.text("status-memory-unlocked", &[]).yellow());
+    }
+    println!();
+    
+    println!("{}", reporter.text("status-key-files", &[]));
+    if system.private_key_files {
+        println!("{}", reporter.text("status-key-files-private", &[]));
+    } else {
+        println!("{}", reporter.text("status-key-files-inherited", &[]).yellow());
+    }
+    println!();
+    
+    println!("{}", reporter.text("status-byte-order", &[]));
+    println!("{}", message!(reporter, "status-byte-order-host", endianness = system.endianness));
+    println!("{}", message!(reporter, "status-byte-order-files", order = container::BYTE_ORDER));
+    println!();
+    
+    println!("{}", reporter.text("status-features", &[]));
+    println!("{}", reporter.text("status-features-list", &[]));
+    println!();
+    
+    println!("{}", reporter.text("status-performance", &[]));
+    println!("{}", reporter.text("status-performance-list", &[]));
+    println!();
+    
+    println!("{}", reporter.text("status-operational", &[]).green().bold());
+}
+
+/// What `keygen` accepts as a master password
+struct PasswordRules {
+    policy: PasswordPolicy,
+    allow_weak: bool,
+}
+
+fn generate_keys(
+    output: PathBuf,
+    master_key_file: Option<(PathBuf, KdfScheme)>,
+    owner: Option<KeyOwner>,
+    escrow_to: Option<PathBuf>,
+    passwords: &PasswordRules,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    // Create output directory, owner-only when new
+    permissions::create_private_dir_all(&output)?;
+    
+    reporter.progress(message!(reporter, "keygen-directory", output = output.display()));
+    
+    let key_manager = match master_key_file {
+        Some((path, scheme)) => import_master_key(&path, scheme, reporter)?,
+        None => generate_from_password(passwords, reporter)?,
+    };
+    let key_manager = match owner {
+        Some(owner) => key_manager.with_owner(owner),
+        None => key_manager,
+    };
+    
+    // Escrow first, so the saved key file records it
+    let key_manager = match escrow_to {
+        Some(path) => {
+            let recovery = escrow::RecoveryPublicKey::from_bytes(&std::fs::read(&path)?)?;
+            let (key_manager, blob) = escrow::create(key_manager, &recovery)?;
+            let blob_file = output.join("hybridguard.escrow");
+            std::fs::write(&blob_file, blob.to_bytes()?)?;
+            reporter.summary(message!(reporter, "keygen-escrowed", key_id = recovery.key_id(), blob = blob_file.display()));
+            key_manager
+        }
+        None => key_manager,
+    };
+    
+    // Save keys
+    let key_file = output.join("hybridguard.keys");
+    key_files.save(&key_manager, &key_file)?;
+    
+    reporter.summary(message!(reporter, "keygen-saved", path = key_file.display(), key_id = key_manager.key_id()));
+    if let Some(protector) = &key_files.protector {
+        reporter.summary(message!(reporter, "keygen-sealed", protector = protector.kind()));
+    }
+    if let Some(owner) = key_manager.owner() {
+        reporter.summary(message!(reporter, "keygen-owner", owner = owner));
+    }
+    reporter.warn(reporter.text("keygen-keep-safe", &[]));
+    
+    Ok(())
+}
+
+fn generate_from_password(passwords: &PasswordRules, reporter: &Reporter) -> Result<KeyManager, HybridGuardError> {
+    let password = read_password(reporter.text("prompt-password", &[]))?;
+    let password = password.as_str();
+    
+    // Refuse weak passwords before deriving anything from them
+    let strength = passwords.policy.check(password, passwords.allow_weak)?;
+    if strength.score < passwords.policy.min_score {
+        reporter.warn(message!(reporter, "keygen-weak-password", strength = strength));
+    } else {
+        reporter.summary(message!(reporter, "keygen-password-strength", strength = strength));
+    }
+    
+    // Generate keys
+    reporter.progress(reporter.text("keygen-deriving", &[]));
+    KeyManager::generate(password)
+}
+
+/// Read a password from stdin; prompts go to stderr with the rest of the chatter
+fn read_password(prompt: impl std::fmt::Display) -> Result<String, HybridGuardError> {
+    use std::io::{self, Write};
+    
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+    Ok(password.trim().to_string())
+}
+
+fn import_master_key(path: &std::path::Path, scheme: KdfScheme, reporter: &Reporter) -> Result<KeyManager, HybridGuardError> {
+    reporter.progress(message!(reporter, "keygen-importing", path = path.display()));
+    let bytes = std::fs::read(path)?;
+    let master_key: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+        HybridGuardError::InvalidInput(format!(
+            "{}: master key must be exactly 32 bytes, got {}",
+            path.display(),
+            bytes.len()
+        ))
+    })?;
+    
+    if let Some(warning) = key_manager::master_key_warning(&master_key) {
+        reporter.warn(message!(reporter, "keygen-warning", warning = warning));
+    }
+    
+    KeyManager::from_master_key_with(&master_key, scheme)
+}
+
+/// List key files; exits with the integrity error code when any fails its
+/// self-signature check
+fn list_key_files(paths: &[PathBuf], json: bool, key_files: &KeyFiles) -> Result<(), HybridGuardError> {
+    let files = cli::keys::list_paths(paths)?;
+    if files.is_empty() {
+        let searched: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+        return Err(HybridGuardError::InvalidInput(format!("no key files in {}", searched.join(", "))));
+    }
+    let listings: Vec<_> = files.iter().map(|path| key_files.describe(path)).collect();
+    if json {
+        let json = serde_json::to_string_pretty(&listings).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        println!("{}", json);
+    } else {
+        let text: Vec<String> = listings.iter().map(ToString::to_string).collect();
+        println!("{}", text.join("\n\n"));
+    }
+    let invalid: Vec<String> = listings
+        .iter()
+        .filter(|listing| listing.signature.as_ref().is_some_and(SignatureStatus::is_invalid))
+        .map(|listing| listing.path.to_string())
+        .collect();
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(HybridGuardError::Integrity(format!("key file self-signature is invalid: {}", invalid.join(", "))))
+    }
+}
+
+/// Print the diagnosis of a key file; exits with the key error code when it
+/// is damaged and the integrity error code when its self-signature is invalid
+fn diagnose_key_file(key_file: &std::path::Path, json: bool, key_files: &KeyFiles) -> Result<(), HybridGuardError> {
+    let diagnosis = key_files.diagnose(&std::fs::read(key_file)?)?;
+    if json {
+        let json = serde_json::to_string_pretty(&diagnosis).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        println!("{}", json);
+    } else {
+        println!("{}", diagnosis);
+    }
+    if !diagnosis.is_healthy() {
+        return Err(HybridGuardError::KeyFileDamaged(Box::new(diagnosis)));
+    }
+    match diagnosis.self_signature {
+        SignatureStatus::Invalid { reason } => Err(HybridGuardError::Integrity(format!(
+            "{}: key file self-signature is invalid: {}",
+            key_file.display(),
+            reason
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Shred a key file and its checkpoints after the user types the file name
+/// Every file is attempted and reported; the first failure is returned
+fn destroy_key_file(
+    key_file: &std::path::Path,
+    checkpoints: &[PathBuf],
+    yes: bool,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::io::{self, Write};
+    
+    // A missing key file is an error before anything is asked or touched
+    std::fs::symlink_metadata(key_file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", key_file.display(), e)))?;
+    
+    if !yes {
+        let name = key_file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        eprintln!("{}", message!(reporter, "destroy-warning", path = key_file.display()));
+        eprint!("{}", message!(reporter, "destroy-prompt", name = name));
+        io::stderr().flush()?;
+        let mut typed = String::new();
+        io::stdin().read_line(&mut typed)?;
+        if typed.trim() != name {
+            return Err(HybridGuardError::InvalidInput("confirmation did not match; nothing was destroyed".to_string()));
+        }
+    }
+    
+    // A backup would keep the very key being destroyed, so none is taken and
+    // the automatic ones go with it, wherever backups are configured to be
+    let _keystore = key_files.lock(key_file)?;
+    let default_policy = BackupPolicy::new();
+    let backups = backup::list(key_file, key_files.backup_policy().unwrap_or(&default_policy))?;
+    
+    let mut first_error = None;
+    let targets = std::iter::once(("shred-key-file", key_file))
+        .chain(checkpoints.iter().map(|path| ("shred-checkpoint", path.as_path())))
+        .chain(backups.iter().map(|backup| ("shred-backup", backup.path.as_path())));
+    for (what, path) in targets {
+        let what = reporter.text(what, &[]);
+        match fsutil::shred(path) {
+            Ok(len) => reporter.summary(message!(reporter, "key-shred-done", what = what, path = path.display(), bytes = len)),
+            Err(e) => {
+                reporter.error(message!(reporter, "key-shred-failed", what = what, path = path.display(), reason = e));
+                first_error.get_or_insert(HybridGuardError::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))));
+            }
+        }
+    }
+    reporter.warn(reporter.text("destroy-out-of-reach", &[]));
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn tune_kdf(target_ms: u64, limits: TuneLimits, save: Option<PathBuf>, json: bool, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    reporter.progress(message!(reporter, "kdf-benchmark", target = target_ms));
+    let tuning = kdf::tune(std::time::Duration::from_millis(target_ms), limits)?;
+    let measured_ms = tuning.measured.as_secs_f64() * 1000.0;
+    if json {
+        let report = serde_json::json!({ "params": tuning.params, "measured_ms": measured_ms, "target_ms": target_ms });
+        println!("{}", serde_json::to_string_pretty(&report).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?);
+    } else {
+        println!("{}", tuning.params);
+        println!("Measured: {:.0} ms (target {} ms)", measured_ms, target_ms);
+    }
+    if tuning.floor_exceeds_target() {
+        reporter.warn(message!(reporter, "kdf-floor-too-slow", floor = tuning.params.memory_kib / 1024, target = target_ms));
+    }
+    
+    if let Some(path) = save {
+        let params = serde_json::to_string_pretty(&tuning.params).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        std::fs::write(&path, params + "\n")?;
+        reporter.summary(message!(reporter, "kdf-saved", path = path.display()));
+    }
+    Ok(())
+}
+
+/// Paper is currently the only backup format
+fn require_paper(paper: bool) -> Result<(), HybridGuardError> {
+    if paper {
+        Ok(())
+    } else {
+        Err(HybridGuardError::InvalidInput("no backup format selected; pass --paper".to_string()))
+    }
+}
+
+fn backup_keys(key_file: PathBuf, key_files: &KeyFiles, output: PathBuf, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    reporter.progress(message!(reporter, "backup-reading", path = key_file.display()));
+    let bytes = fs::read(&key_file)?;
+    permissions::check_private(&key_file, key_files.loose)?;
+    
+    // Only back up files that will load again after restore; protected
+    // files are backed up sealed
+    let key_manager = key_files.parse(&bytes)?;
+    
+    let document = paper::encode(&bytes, key_manager.key_id());
+    permissions::write_private_with(&output, document.as_bytes(), key_files.write_options())?;
+    
+    reporter.summary(message!(reporter, "backup-paper-saved", key_id = key_manager.key_id(), output = output.display()));
+    reporter.warn(reporter.text("backup-paper-print", &[]));
+    
+    Ok(())
+}
+
+fn restore_keys(input: Option<PathBuf>, output: PathBuf, key_files: &KeyFiles, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    let bytes = match input {
+        Some(path) => {
+            reporter.progress(message!(reporter, "restore-reading", path = path.display()));
+            paper::decode(&fs::read_to_string(&path)?)?
+        }
+        None => restore_interactive()?,
+    };
+    
+    let key_manager = key_files.parse(&bytes)?;
+    
+    if let Some(parent) = output.parent() {
+        permissions::create_private_dir_all(parent)?;
+    }
+    let keystore = key_files.lock(&output)?;
+    keystore.back_up(&output)?;
+    keystore.write(&output, &bytes)?;
+    
+    reporter.summary(message!(reporter, "restore-done", key_id = key_manager.key_id(), output = output.display()));
+    
+    Ok(())
+}
+
+/// List the backups of `key_file`, or put the one called `name` back in its place
+/// The key file being replaced is backed up in turn when it parses, so a restore can be undone
+fn restore_key_backup(
+    key_file: &std::path::Path,
+    name: Option<&str>,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    let default_policy = BackupPolicy::new();
+    let policy = key_files.backup_policy().unwrap_or(&default_policy);
+    let Some(name) = name else {
+        let backups = backup::list(key_file, policy)?;
+        if backups.is_empty() {
+            reporter.summary(message!(reporter, "restore-no-backups", path = key_file.display()));
+        }
+        for backup in backups.iter().rev() {
+            println!("{}  {}", backup.name(), if backup.protected { "sealed" } else { "plaintext" });
+        }
+        return Ok(());
+    };
+    
+    if let Some(parent) = key_file.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        permissions::create_private_dir_all(parent)?;
+    }
+    // Under the lock, so no other run prunes the backup before it is read;
+    // read it first, since backing up the current file may prune it too
+    let keystore = key_files.lock(key_file)?;
+    let chosen = backup::find(key_file, policy, name)?;
+    let bytes = backup::read(&chosen)?;
+    // A key file that no longer parses, say after an interrupted write, is
+    // what a restore is for, so it is replaced even when it cannot be kept
+    if let Err(e) = keystore.back_up(key_file) {
+        reporter.warn(message!(reporter, "restore-without-backup", path = key_file.display(), reason = e.localized(reporter.catalog())));
+    }
+    keystore.write(key_file, &bytes)?;
+    reporter.summary(message!(reporter, "restore-backup-done", path = key_file.display(), backup = chosen.path.display()));
+    Ok(())
+}
+
+fn generate_recovery_key(
+    public: &std::path::Path,
+    private: &std::path::Path,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    refuse_existing(public)?;
+    refuse_existing(private)?;
+    
+    let recovery = escrow::RecoveryKey::generate()?;
+    if let Some(parent) = private.parent() {
+        permissions::create_private_dir_all(parent)?;
+    }
+    permissions::write_private_with(private, &recovery.to_bytes()?, key_files.write_options())?;
+    std::fs::write(public, recovery.public().to_bytes()?)?;
+    
+    reporter.summary(message!(reporter, "recovery-written", key_id = recovery.public().key_id(), path = private.display()));
+    reporter.warn(reporter.text("recovery-distribute", &[]));
+    
+    Ok(())
+}
+
+fn export_keys(
+    key_file: &std::path::Path,
+    encrypt_only: bool,
+    verification_key: bool,
+    output: &std::path::Path,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    if !encrypt_only && !verification_key {
+        return Err(HybridGuardError::InvalidInput(
+            "no restriction selected; pass --encrypt-only or --verification-key".to_string(),
+        ));
+    }
+    let key_manager = key_files.load(key_file)?;
+    
+    refuse_existing(output)?;
+    if let Some(parent) = output.parent() {
+        permissions::create_private_dir_all(parent)?;
+    }
+    if verification_key {
+        let verification_key = key_manager.export_verification_key()?;
+        verification_key.save_with(output, key_files.write_options())?;
+        reporter.summary(message!(reporter, "verification-key-written", key_id = verification_key.key_id(), output = output.display()));
+        reporter.warn(reporter.text("verification-key-private", &[]));
+        return Ok(());
+    }
+    let key_manager = key_manager.encrypt_only()?;
+    key_files.save(&key_manager, output)?;
+    
+    reporter.summary(message!(reporter, "encrypt-only-written", key_id = key_manager.key_id(), output = output.display()));
+    reporter.summary(reporter.text("encrypt-only-scope", &[]));
+    
+    Ok(())
+}
+
+fn recover_keys(
+    blob: &std::path::Path,
+    org_key: &std::path::Path,
+    output: &std::path::Path,
+    key_files: &KeyFiles,
+    reporter: &Reporter,
+) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    let blob = escrow::EscrowBlob::from_bytes(&fs::read(blob)?)?;
+    let recovery = escrow::RecoveryKey::from_bytes(&fs::read(org_key)?)?;
+    let key_manager = escrow::recover(&blob, &recovery)?;
+    
+    refuse_existing(output)?;
+    if let Some(parent) = output.parent() {
+        permissions::create_private_dir_all(parent)?;
+    }
+    key_files.save(&key_manager, output)?;
+    
+    reporter.summary(message!(reporter, "recover-done", key_id = key_manager.key_id(), output = output.display()));
+    
+    Ok(())
+}
+
+fn pair(action: PairCommands, key_files: &KeyFiles, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    use std::fs;
+    
+    match action {
+        PairCommands::Offer { key_file } => {
+            let own = key_files.load(&key_file)?;
+            write_message(&pairing::offer(&own)?.to_bytes()?)?;
+            reporter.summary(message!(reporter, "pair-offer-written", key_id = own.key_id()));
+        }
+        PairCommands::Accept { key_file, offer, output } => {
+            let own = key_files.load(&key_file)?;
+            let offer = pairing::Offer::from_bytes(&fs::read(&offer)?)?;
+            refuse_existing(&output)?;
+            message_stdout()?;
+            let (reply, shared) = pairing::accept(&own, &offer)?;
+            let reply = reply.to_bytes()?;
+            // Saved before the reply goes out, so the peer never pairs with a key not stored here
+            save_shared(&shared, &output, key_files, reporter)?;
+            write_message(&reply)?;
+        }
+        PairCommands::Finish { key_file, reply, output } => {
+            let own = key_files.load(&key_file)?;
+            let reply = pairing::Accept::from_bytes(&fs::read(&reply)?)?;
+            refuse_existing(&output)?;
+            let shared = pairing::finish(&own, &reply)?;
+            save_shared(&shared, &output, key_files, reporter)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a binary pairing message to stdout, which must be redirected
+fn write_message(bytes: &[u8]) -> Result<(), HybridGuardError> {
+    use std::io::Write;
+    
+    let mut stdout = message_stdout()?;
+    stdout.write_all(bytes)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Stdout, unless it is a terminal a binary message cannot go to
+fn message_stdout() -> Result<std::io::Stdout, HybridGuardError> {
+    use std::io::{self, IsTerminal};
+    
+    let stdout = io::stdout();
+    if stdout.is_terminal() {
+        return Err(HybridGuardError::InvalidInput("redirect stdout to a file; the message is binary".to_string()));
+    }
+    Ok(stdout)
+}
+
+fn refuse_existing(path: &std::path::Path) -> Result<(), HybridGuardError> {
+    if path.exists() {
+        return Err(HybridGuardError::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists; choose another --output", path.display()),
+        )));
+    }
+    Ok(())
+}
+
+fn save_shared(shared: &KeyManager, output: &std::path::Path, key_files: &KeyFiles, reporter: &Reporter) -> Result<(), HybridGuardError> {
+    if let Some(parent) = output.parent() {
+        permissions::create_private_dir_all(parent)?;
+    }
+    key_files.save(shared, output)?;
+    reporter.summary(message!(reporter, "pair-saved", key_id = shared.key_id(), output = output.display()));
+    reporter.summary(message!(reporter, "pair-fingerprint", fingerprint = pairing::fingerprint(shared.key_id())));
+    reporter.warn(reporter.text("pair-compare", &[]));
+    Ok(())
+}
+
+/// Type a paper backup in line by line, re-prompting for any line that fails its check
+fn restore_interactive() -> Result<Vec<u8>, HybridGuardError> {
+    use std::io::{self, Write};
+    
+    fn prompt(label: &str) -> Result<String, HybridGuardError> {
+        eprint!("{}", label);
+        io::stderr().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Err(HybridGuardError::InvalidInput("paper backup: input ended early".to_string()));
+        }
+        Ok(line.trim().to_string())
+    }
+    
+    let len: usize = prompt("Bytes (from the header): ")?
+        .parse()
+        .map_err(|_| HybridGuardError::InvalidInput("paper backup: 'Bytes' must be a number".to_string()))?;
+    let line_count = (len + paper::BYTES_PER_LINE - 1) / paper::BYTES_PER_LINE;
+    
+    let mut payload = Vec::with_capacity(len);
+    for line_no in 1..=line_count {
+        loop {
+            let text = prompt(&format!("Line {:03}: ", line_no))?;
+            let expected = (len - payload.len()).min(paper::BYTES_PER_LINE);
+            match paper::decode_line(line_no, &text) {
+                Ok(bytes) if bytes.len() == expected => {
+                    payload.extend_from_slice(&bytes);
+                    break;
+                }
+                Ok(_) => eprintln!("   {}", format!("Line {} has the wrong length; re-enter line {}", line_no, line_no).yellow()),
+                Err(e) => eprintln!("   {}", e.to_string().yellow()),
+            }
+        }
+    }
+    
+    let checksum = prompt("Checksum: ")?;
+    paper::verify_checksum(&payload, &checksum)?;
+    Ok(payload)
 }
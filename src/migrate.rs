@@ -0,0 +1,199 @@
+// Migration of ciphertexts written in older formats
+// Decrypts through whichever legacy code path applies and re-encrypts with
+// the current defaults, noting where the data came from
+
+use crate::crypto::metadata::Metadata;
+use crate::crypto::{container, encoding};
+use crate::crypto::{EncryptedData, MigrationNote};
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::KeyManager;
+use crate::layers;
+use crate::storage::erasure;
+use sha3::{Digest, Sha3_256};
+
+/// A ciphertext re-encrypted in the current format
+pub struct Migrated {
+    /// Container format version of the original
+    pub from_format: u16,
+
+    /// The new container, already checked to decrypt to the original plaintext
+    pub bytes: Vec<u8>,
+
+    /// SHA3-256 of the original plaintext, to check copies of `bytes` against
+    pub(crate) plaintext_digest: [u8; 32],
+}
+
+impl Migrated {
+    /// Check that `written`, the new container read back from where it was
+    /// written, is `bytes` and still decrypts to the original plaintext
+    pub fn check_copy(&self, written: &[u8], key_manager: &KeyManager) -> Result<()> {
+        if written != self.bytes {
+            return Err(HybridGuardError::Integrity("the new file does not read back as written".to_string()));
+        }
+        let encrypted = if erasure::is_sharded(written) {
+            EncryptedData::from_bytes(&erasure::decode(written)?.payload)?
+        } else {
+            encoding::decode(written)?
+        };
+        let decrypted = HybridGuardEncryptor::new().decrypt(&encrypted, &key_manager.keys_for(&encrypted)?)?;
+        if Sha3_256::digest(&decrypted)[..] != self.plaintext_digest {
+            return Err(HybridGuardError::Integrity("the new file does not decrypt to the original plaintext".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `bytes` already uses the current container and layer formats
+pub fn is_current(bytes: &[u8]) -> Result<bool> {
+    if container::format_version(bytes)? != container::FORMAT_VERSION {
+        return Ok(false);
+    }
+    Ok(EncryptedData::from_bytes(bytes)?.descriptors() == layers::current_descriptors())
+}
+
+/// Re-encrypt a container of any supported version with the current formats
+pub fn migrate(bytes: &[u8], key_manager: &KeyManager) -> Result<Migrated> {
+    rekey(bytes, key_manager, key_manager)
+}
+
+/// Re-encrypt a container made with `from`'s keys under `to`'s keys, in the
+/// current formats
+pub fn rekey(bytes: &[u8], from: &KeyManager, to: &KeyManager) -> Result<Migrated> {
+    let from_format = container::format_version(bytes)?;
+    let old = EncryptedData::from_bytes(bytes)?;
+    if let Some(key_id) = old.key_id() {
+        if key_id != from.key_id() {
+            return Err(HybridGuardError::KeyMismatch(format!(
+                "ciphertext was encrypted with key {} but key {} is loaded",
+                key_id,
+                from.key_id()
+            )));
+        }
+    }
+
+    let encryptor = HybridGuardEncryptor::new();
+    let old_keys = from.keys_for(&old)?;
+    let plaintext = encryptor.decrypt(&old, &old_keys)?;
+
+    // A file migrated twice keeps its first origin
+    let note = old.migrated_from().cloned().unwrap_or(MigrationNote {
+        format_version: from_format,
+        timestamp: old.timestamp(),
+    });
+    let (file_keys, wrapped) = to.new_file_keys()?;
+    let mut new = encryptor.encrypt(&plaintext, &file_keys)?;
+    // Metadata and the content type travel with the data, sealed again under
+    // the new file key; a type an older build left in the clear is sealed now
+    let private = old.metadata().open(&old_keys)?;
+    if let Some(metadata) = Metadata::new(old.metadata().extra().clone(), &private, &file_keys)? {
+        new = new.with_metadata(metadata, &file_keys);
+    }
+    if let Some(content_type) = old.content_type(&old_keys)? {
+        new = new.with_content_type(&content_type, &file_keys)?;
+    }
+    let new = new
+        .with_wrapped_key(wrapped, &file_keys)
+        .with_key_id(to.key_id())
+        .with_verification_tag(to.get_keys()?)
+        .with_migrated_from(note);
+
+    let bytes = new.to_bytes()?;
+    verify(&bytes, &plaintext, to)?;
+    Ok(Migrated { from_format, bytes, plaintext_digest: Sha3_256::digest(&plaintext).into() })
+}
+
+/// Check that a container decrypts to `plaintext`
+pub fn verify(bytes: &[u8], plaintext: &[u8], key_manager: &KeyManager) -> Result<()> {
+    let encrypted = EncryptedData::from_bytes(bytes)?;
+    let decrypted = HybridGuardEncryptor::new().decrypt(&encrypted, &key_manager.keys_for(&encrypted)?)?;
+    if decrypted != plaintext {
+        return Err(HybridGuardError::Integrity(
+            "migrated ciphertext does not decrypt to the original plaintext".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bare bincode of the original `EncryptedData`, all layers at format version 1
+    fn v0_fixture(plaintext: &[u8], key_manager: &KeyManager, timestamp: u64) -> Vec<u8> {
+        let keys = key_manager.get_keys().unwrap().in_order();
+        let mut data = plaintext.to_vec();
+        for (layer, key) in layers::registry().iter().zip(keys) {
+            data = layer.encrypt_version(&data, key, 1).unwrap();
+        }
+        let names: Vec<String> = layers::legacy_descriptors().into_iter().map(|d| d.name).collect();
+        bincode::serialize(&(data, names, "0.1.0", timestamp)).unwrap()
+    }
+
+    #[test]
+    fn test_migrate_v0_fixture() {
+        let km = KeyManager::from_master_key(&[0x42; 32]).unwrap();
+        let plaintext = b"written before containers had a header";
+        let legacy = v0_fixture(plaintext, &km, 1_700_000_000);
+        assert_eq!(container::format_version(&legacy).unwrap(), 0);
+        assert!(!is_current(&legacy).unwrap());
+
+        let migrated = migrate(&legacy, &km).unwrap();
+        assert_eq!(migrated.from_format, 0);
+        assert_eq!(container::format_version(&migrated.bytes).unwrap(), container::FORMAT_VERSION);
+        assert!(is_current(&migrated.bytes).unwrap());
+
+        let encrypted = EncryptedData::from_bytes(&migrated.bytes).unwrap();
+        assert_eq!(encrypted.descriptors(), layers::current_descriptors());
+        assert_eq!(encrypted.key_id(), Some(km.key_id()));
+        assert_eq!(
+            encrypted.migrated_from(),
+            Some(&MigrationNote { format_version: 0, timestamp: 1_700_000_000 })
+        );
+
+        assert!(encrypted.wrapped_key().is_some());
+        let decrypted = HybridGuardEncryptor::new().decrypt(&encrypted, &km.keys_for(&encrypted).unwrap()).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_migrate_rejects_other_keys() {
+        let km = KeyManager::from_master_key(&[0x42; 32]).unwrap();
+        let other = KeyManager::from_master_key(&[0x24; 32]).unwrap();
+        let current = HybridGuardEncryptor::new()
+            .encrypt(b"data", other.get_keys().unwrap())
+            .unwrap()
+            .with_key_id(other.key_id())
+            .to_bytes()
+            .unwrap();
+
+        assert!(matches!(migrate(&current, &km), Err(HybridGuardError::KeyMismatch(_))));
+    }
+
+    #[test]
+    fn test_rekey_moves_to_the_new_keys() {
+        let old = KeyManager::from_master_key(&[0x42; 32]).unwrap();
+        let new = KeyManager::from_master_key(&[0x24; 32]).unwrap();
+        let legacy = v0_fixture(b"deprecated stack", &old, 1_600_000_000);
+
+        let rekeyed = rekey(&legacy, &old, &new).unwrap();
+        let encrypted = EncryptedData::from_bytes(&rekeyed.bytes).unwrap();
+        assert_eq!(encrypted.key_id(), Some(new.key_id()));
+        let decrypted = HybridGuardEncryptor::new().decrypt(&encrypted, &new.keys_for(&encrypted).unwrap()).unwrap();
+        assert_eq!(decrypted, b"deprecated stack");
+        assert!(old.keys_for(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_only_an_intact_copy_checks_out() {
+        let km = KeyManager::from_master_key(&[0x42; 32]).unwrap();
+        let migrated = migrate(&v0_fixture(b"read back before the rename", &km, 1_700_000_000), &km).unwrap();
+        migrated.check_copy(&migrated.bytes, &km).unwrap();
+
+        let mut damaged = migrated.bytes.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        assert!(matches!(migrated.check_copy(&damaged, &km), Err(HybridGuardError::Integrity(_))));
+        assert!(migrated.check_copy(&migrated.bytes[..last], &km).is_err());
+    }
+}
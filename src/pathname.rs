@@ -0,0 +1,110 @@
+// Paths in JSON whatever bytes they hold
+// Unix file names are arbitrary bytes, but JSON strings are Unicode, and
+// serde refuses a `Path` that is not UTF-8. `JsonPath` keeps UTF-8 paths as
+// plain strings and gives any other path as its raw bytes in base64, flagged
+// by the `base64` key, beside a lossy form for display. Nothing is replaced
+// silently, and the raw form restores the exact name.
+
+use crate::crypto::codec;
+use crate::error::Result;
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A path as it appears in JSON output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JsonPath {
+    /// A path that is valid UTF-8
+    Utf8(String),
+    /// Any other path: its bytes in standard base64 and a lossy display form
+    Raw { base64: String, lossy: String },
+}
+
+impl JsonPath {
+    pub fn new(path: &Path) -> Self {
+        match path.to_str() {
+            Some(text) => JsonPath::Utf8(text.to_string()),
+            None => JsonPath::Raw {
+                base64: codec::b64_std(&raw_bytes(path)),
+                lossy: path.to_string_lossy().into_owned(),
+            },
+        }
+    }
+
+    /// The path this was made from, byte for byte
+    pub fn to_path_buf(&self) -> Result<PathBuf> {
+        match self {
+            JsonPath::Utf8(text) => Ok(PathBuf::from(text)),
+            JsonPath::Raw { base64, .. } => from_raw_bytes(codec::b64_std_decode(base64)?),
+        }
+    }
+}
+
+/// The path as text, lossy for non-UTF-8 paths
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonPath::Utf8(text) => f.write_str(text),
+            JsonPath::Raw { lossy, .. } => f.write_str(lossy),
+        }
+    }
+}
+
+/// `serialize_with` for `Path` fields of JSON reports
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    JsonPath::new(path).serialize(serializer)
+}
+
+/// `serialize_with` for `Option<PathBuf>` fields of JSON reports
+pub fn serialize_option<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    path.as_deref().map(JsonPath::new).serialize(serializer)
+}
+
+#[cfg(unix)]
+pub(crate) fn raw_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn raw_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_encoded_bytes().to_vec()
+}
+
+#[cfg(unix)]
+pub(crate) fn from_raw_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+/// Elsewhere only UTF-8 raw names can be rebuilt safely
+#[cfg(not(unix))]
+pub(crate) fn from_raw_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| crate::error::HybridGuardError::InvalidInput("path bytes are not valid on this platform".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf8_paths_stay_strings() {
+        let json = serde_json::to_value(JsonPath::new(Path::new("reports/q3 ünï.txt"))).unwrap();
+        assert_eq!(json, serde_json::json!("reports/q3 ünï.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_raw_paths_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"dir/caf\xe9-\xff.txt"));
+
+        let json = serde_json::to_value(JsonPath::new(path)).unwrap();
+        assert_eq!(json["lossy"], "dir/caf\u{fffd}-\u{fffd}.txt");
+        let parsed: JsonPath = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.to_path_buf().unwrap(), path);
+    }
+}
@@ -0,0 +1,369 @@
+// Label policies checked before decryption
+// `encrypt --label confidential` records the label in the container, covered
+// by its tag. A policy file, in TOML, maps labels to what files carrying them
+// must meet: a minimum profile (effective security of the recorded layer
+// stack, as `SecurityAssessment` counts it), layers they must have gone
+// through and how old they may get before they have to be re-encrypted.
+// Unlabeled files and labels the policy does not list have no requirements.
+// A violation can be overridden only by a policy naming an audit log, and
+// every override appends a record to it.
+
+use crate::crypto::EncryptedData;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::SecurityAssessment;
+use crate::pathname::JsonPath;
+use crate::scan;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Longest label `encrypt --label` accepts
+pub const MAX_LABEL_LEN: usize = 64;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Check a label: 1 to `MAX_LABEL_LEN` lowercase letters, digits, '-' or '_'
+pub fn parse_label(text: &str) -> Result<String> {
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if text.is_empty() || text.len() > MAX_LABEL_LEN || !text.chars().all(allowed) {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "label '{}' must be 1 to {} lowercase letters, digits, '-' or '_'",
+            text, MAX_LABEL_LEN
+        )));
+    }
+    Ok(text.to_string())
+}
+
+/// Named levels of effective security a policy can ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// 128-bit effective security
+    Standard,
+    /// 192-bit effective security
+    High,
+    /// 256-bit effective security
+    Paranoid,
+}
+
+impl Profile {
+    /// Effective security a stack needs to reach this profile
+    pub fn min_bits(self) -> u32 {
+        match self {
+            Profile::Standard => 128,
+            Profile::High => 192,
+            Profile::Paranoid => 256,
+        }
+    }
+
+    /// Highest profile `bits` of effective security reach, None below Standard
+    pub fn reached_by(bits: u32) -> Option<Profile> {
+        [Profile::Paranoid, Profile::High, Profile::Standard]
+            .into_iter()
+            .find(|profile| bits >= profile.min_bits())
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Profile::Standard => "standard",
+            Profile::High => "high",
+            Profile::Paranoid => "paranoid",
+        };
+        write!(f, "{} ({}-bit)", name, self.min_bits())
+    }
+}
+
+/// What files with one label must meet; unset requirements always hold
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Requirements {
+    /// Lowest profile the recorded layer stack may reach
+    pub min_profile: Option<Profile>,
+    /// Layers, by name or family ("ML-KEM"), every file must have gone through
+    #[serde(default)]
+    pub required_layers: Vec<String>,
+    /// Days after encryption by which a file must have been re-encrypted
+    pub max_age_days: Option<u64>,
+}
+
+/// A requirement a labeled file fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub label: String,
+    pub requirement: String,
+}
+
+impl From<Violation> for HybridGuardError {
+    fn from(violation: Violation) -> Self {
+        HybridGuardError::LabelPolicyViolation { label: violation.label, requirement: violation.requirement }
+    }
+}
+
+/// Requirements by label, as read from a policy file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelPolicy {
+    /// Where overrides are recorded; without one violations cannot be overridden
+    /// Relative paths are taken from the policy file's directory
+    pub audit_log: Option<PathBuf>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, Requirements>,
+}
+
+impl LabelPolicy {
+    /// Parse a policy file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let mut policy = Self::parse(&text)
+            .map_err(|e| HybridGuardError::InvalidInput(format!("{}: invalid label policy: {}", path.display(), e)))?;
+        if let Some(log) = policy.audit_log.as_mut().filter(|log| log.is_relative()) {
+            *log = path.parent().unwrap_or(Path::new("")).join(&*log);
+        }
+        Ok(policy)
+    }
+
+    /// Parse policy TOML, checking every label it lists
+    pub fn parse(text: &str) -> Result<Self> {
+        let policy: Self = toml::from_str(text).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        for label in policy.labels.keys() {
+            parse_label(label)?;
+        }
+        Ok(policy)
+    }
+
+    /// Requirements `data` fails under its label, in the order they are listed
+    /// The label is read before the tag is checked; decryption fails on a
+    /// label that was altered, so a stripped label gains nothing
+    pub fn check(&self, data: &EncryptedData, now: u64) -> Vec<Violation> {
+        let Some((label, requirements)) = data.label().and_then(|label| self.labels.get_key_value(label)) else {
+            return Vec::new();
+        };
+        let mut unmet = Vec::new();
+
+        if let Some(profile) = requirements.min_profile {
+            let bits = SecurityAssessment::of_descriptors(data.descriptors()).effective_bits;
+            if bits < profile.min_bits() {
+                let reached = match Profile::reached_by(bits) {
+                    Some(reached) => reached.to_string(),
+                    None => format!("no profile ({}-bit effective)", bits),
+                };
+                unmet.push(format!("the {} profile; this file reaches {}", profile, reached));
+            }
+        }
+        for layer in &requirements.required_layers {
+            if !data.descriptors().iter().any(|d| scan::names_layer(layer, &d.name)) {
+                unmet.push(format!("the {} layer; this file went through {}", layer, data.layers().join(", ")));
+            }
+        }
+        if let Some(days) = requirements.max_age_days {
+            let age = now.saturating_sub(data.timestamp());
+            if age > days.saturating_mul(SECS_PER_DAY) {
+                unmet.push(format!(
+                    "re-encryption within {} days; this file was encrypted {} days ago",
+                    days,
+                    age / SECS_PER_DAY
+                ));
+            }
+        }
+
+        unmet.into_iter().map(|requirement| Violation { label: label.clone(), requirement }).collect()
+    }
+
+    /// `check`, failing with the first violation
+    pub fn enforce(&self, data: &EncryptedData, now: u64) -> Result<()> {
+        match self.check(data, now).into_iter().next() {
+            Some(violation) => Err(violation.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One overridden violation, a line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Seconds since the epoch
+    pub time: u64,
+    /// Login name of whoever ran the override
+    pub user: String,
+    pub file: JsonPath,
+    pub label: String,
+    /// Every requirement the file failed
+    pub requirements: Vec<String>,
+    /// Why the override was needed, as given to `--override-policy`
+    pub reason: String,
+}
+
+impl AuditRecord {
+    pub fn new(file: &Path, label: &str, requirements: Vec<String>, reason: &str, now: u64) -> Self {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string());
+        Self {
+            time: now,
+            user,
+            file: JsonPath::new(file),
+            label: label.to_string(),
+            requirements,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Append `record` to the JSON-lines audit log at `path`, creating it owner-only
+/// One write per record, so concurrent appenders do not interleave lines
+pub fn append_audit(path: &Path, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+    line.push(b'\n');
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut log = options.open(path)?;
+    log.write_all(&line)?;
+    log.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::EncryptedDataBuilder;
+    use crate::layers::{self, LayerDescriptor};
+
+    const NOW: u64 = 1_800_000_000;
+
+    const POLICY: &str = r#"
+        [labels.confidential]
+        min_profile = "paranoid"
+        required_layers = ["ML-KEM", "HQC"]
+        max_age_days = 365
+
+        [labels.internal]
+        min_profile = "standard"
+        max_age_days = 30
+
+        [labels.public]
+    "#;
+
+    fn file(label: Option<&str>, stack: &[&str], age_days: u64) -> EncryptedData {
+        let descriptors: Vec<LayerDescriptor> =
+            layers::current_descriptors().into_iter().filter(|d| stack.contains(&d.name.as_str())).collect();
+        let mut builder = EncryptedDataBuilder::new(vec![1, 2, 3])
+            .layers(descriptors.iter().map(|d| d.name.clone()).collect())
+            .descriptors(descriptors)
+            .timestamp(NOW - age_days * SECS_PER_DAY);
+        if let Some(label) = label {
+            builder = builder.label(label);
+        }
+        builder.build().unwrap()
+    }
+
+    const FULL: &[&str] = &["ML-KEM-768", "HQC", "QuantumNoise", "FHE"];
+    const NO_HQC: &[&str] = &["ML-KEM-768", "QuantumNoise", "FHE"];
+    const OBFUSCATION: &[&str] = &["QuantumNoise", "FHE"];
+
+    #[test]
+    fn test_label_matrix() {
+        let policy = LabelPolicy::parse(POLICY).unwrap();
+        // (label, stack, age in days, requirements expected to fail)
+        let cases: &[(Option<&str>, &[&str], u64, &[&str])] = &[
+            (Some("confidential"), FULL, 0, &[]),
+            (Some("confidential"), FULL, 365, &[]),
+            (Some("confidential"), FULL, 366, &["re-encryption within 365 days"]),
+            (Some("confidential"), NO_HQC, 0, &["the paranoid (256-bit) profile", "the HQC layer"]),
+            (Some("confidential"), OBFUSCATION, 400, &["the paranoid", "the ML-KEM layer", "the HQC layer", "re-encryption"]),
+            (Some("internal"), NO_HQC, 10, &[]),
+            (Some("internal"), OBFUSCATION, 10, &["the standard (128-bit) profile; this file reaches no"]),
+            (Some("internal"), FULL, 31, &["re-encryption within 30 days; this file was encrypted 31 days ago"]),
+            (Some("public"), OBFUSCATION, 9000, &[]),
+            (Some("unlisted"), OBFUSCATION, 9000, &[]),
+            (None, OBFUSCATION, 9000, &[]),
+        ];
+        for (label, stack, age, expected) in cases {
+            let violations = policy.check(&file(*label, stack, *age), NOW);
+            assert_eq!(violations.len(), expected.len(), "{:?} {:?} {}: {:?}", label, stack, age, violations);
+            for (violation, expected) in violations.iter().zip(expected.iter()) {
+                assert_eq!(Some(violation.label.as_str()), *label);
+                assert!(violation.requirement.starts_with(expected), "{} vs {}", violation.requirement, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_enforce_reports_first_violation() {
+        let policy = LabelPolicy::parse(POLICY).unwrap();
+        match policy.enforce(&file(Some("confidential"), NO_HQC, 0), NOW) {
+            Err(HybridGuardError::LabelPolicyViolation { label, requirement }) => {
+                assert_eq!(label, "confidential");
+                assert!(requirement.contains("reaches high (192-bit)"), "{}", requirement);
+            }
+            other => panic!("expected a label policy violation, got {:?}", other),
+        }
+        assert!(policy.enforce(&file(Some("confidential"), FULL, 1), NOW).is_ok());
+    }
+
+    #[test]
+    fn test_profiles() {
+        assert_eq!(Profile::reached_by(256), Some(Profile::Paranoid));
+        assert_eq!(Profile::reached_by(200), Some(Profile::High));
+        assert_eq!(Profile::reached_by(128), Some(Profile::Standard));
+        assert_eq!(Profile::reached_by(127), None);
+        assert!(Profile::Standard < Profile::High && Profile::High < Profile::Paranoid);
+    }
+
+    #[test]
+    fn test_policy_files_are_strict() {
+        assert!(LabelPolicy::parse("[labels.confidential]\nmin_profile = \"extreme\"").is_err());
+        assert!(LabelPolicy::parse("[labels.confidential]\nmax_age = 30").is_err());
+        assert!(LabelPolicy::parse("[labels.Confidential]").is_err());
+        assert!(LabelPolicy::parse("audit = \"x\"").is_err());
+        assert_eq!(LabelPolicy::parse("").unwrap(), LabelPolicy::default());
+    }
+
+    #[test]
+    fn test_relative_audit_log_follows_the_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.toml");
+        std::fs::write(&path, "audit_log = \"overrides.log\"\n").unwrap();
+        assert_eq!(LabelPolicy::load(&path).unwrap().audit_log, Some(dir.path().join("overrides.log")));
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(parse_label("confidential").unwrap(), "confidential");
+        assert!(parse_label("pii_eu-2").is_ok());
+        for bad in ["", "Secret", "top secret", "a/b", "x".repeat(MAX_LABEL_LEN + 1).as_str()] {
+            assert!(parse_label(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_audit_log_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.log");
+        let violations = LabelPolicy::parse(POLICY).unwrap().check(&file(Some("internal"), FULL, 40), NOW);
+        let requirements: Vec<String> = violations.into_iter().map(|violation| violation.requirement).collect();
+        for reason in ["incident 41", "incident 42"] {
+            let record = AuditRecord::new(Path::new("q3.hg"), "internal", requirements.clone(), reason, NOW);
+            append_audit(&log, &record).unwrap();
+        }
+
+        let text = std::fs::read_to_string(&log).unwrap();
+        let records: Vec<AuditRecord> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].reason, "incident 42");
+        assert_eq!(records[0].label, "internal");
+        assert_eq!(records[0].requirements, requirements);
+        assert!(records[0].requirements[0].starts_with("re-encryption within 30 days"));
+        assert_eq!(records[0].file, JsonPath::Utf8("q3.hg".to_string()));
+    }
+}
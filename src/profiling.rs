@@ -0,0 +1,191 @@
+// Memory profiling of the layer pipeline
+// With the `memory-profile` feature a program that installs `TrackingAllocator`
+// as its global allocator, as the binary does, has the bytes each thread has
+// allocated counted, so every buffer a layer creates is measured
+
+use crate::error::{HybridGuardError, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// What an encryption measures besides timings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profiling {
+    /// No instrumentation
+    #[default]
+    Off,
+    /// Record peak allocated bytes per layer (needs the `memory-profile` feature)
+    Memory,
+}
+
+/// Whether this build counts allocations
+pub fn available() -> bool {
+    cfg!(feature = "memory-profile")
+}
+
+/// Allocations made by one layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerMemory {
+    pub layer: String,
+    /// Most bytes the layer held at once on top of its input, output included
+    pub peak_bytes: usize,
+    /// Length of the layer's output
+    pub output_bytes: usize,
+}
+
+/// Per-layer allocations of one encryption
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    pub layers: Vec<LayerMemory>,
+}
+
+impl MemoryReport {
+    /// Largest peak of any layer
+    pub fn peak(&self) -> usize {
+        self.layers.iter().map(|l| l.peak_bytes).max().unwrap_or(0)
+    }
+}
+
+/// Global allocator that counts live and peak bytes per thread
+/// Counting per thread keeps concurrent encryptions from skewing each other.
+/// The library never installs it; a program that wants memory reports does
+pub struct TrackingAllocator;
+
+thread_local! {
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record_alloc(size: usize) {
+    // try_with: allocations during thread teardown are simply not counted
+    let _ = CURRENT.try_with(|current| {
+        let now = current.get().saturating_add(size);
+        current.set(now);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(now)));
+    });
+}
+
+fn record_dealloc(size: usize) {
+    // Memory freed on another thread than it was allocated on saturates at zero
+    let _ = CURRENT.try_with(|current| current.set(current.get().saturating_sub(size)));
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record_alloc(new_size);
+            record_dealloc(layout.size());
+        }
+        new_ptr
+    }
+}
+
+/// Run `f` and return the most bytes it had allocated at once on this thread
+/// Always zero unless the tracking allocator is installed
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let outer_peak = PEAK.with(Cell::get);
+    let baseline = CURRENT.with(Cell::get);
+    PEAK.with(|peak| peak.set(baseline));
+
+    let value = f();
+
+    let inner_peak = PEAK.with(Cell::get);
+    PEAK.with(|peak| peak.set(outer_peak.max(inner_peak)));
+    (value, inner_peak - baseline)
+}
+
+/// Collects per-layer measurements while a pipeline runs
+pub(crate) struct Profiler {
+    report: Option<MemoryReport>,
+}
+
+impl Profiler {
+    pub(crate) fn new(profiling: Profiling) -> Result<Self> {
+        match profiling {
+            Profiling::Off => Ok(Self { report: None }),
+            Profiling::Memory if available() => Ok(Self {
+                report: Some(MemoryReport::default()),
+            }),
+            Profiling::Memory => Err(HybridGuardError::InvalidInput(
+                "memory profiling needs a build with the memory-profile feature".to_string(),
+            )),
+        }
+    }
+
+    /// Run one layer, measuring it when profiling is on
+    pub(crate) fn run(&mut self, layer: &str, f: impl FnOnce() -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+        let Some(report) = &mut self.report else {
+            return f();
+        };
+        let (output, peak_bytes) = measure(f);
+        let output = output?;
+        report.layers.push(LayerMemory {
+            layer: layer.to_string(),
+            peak_bytes,
+            output_bytes: output.len(),
+        });
+        Ok(output)
+    }
+
+    pub(crate) fn finish(self) -> Option<MemoryReport> {
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "memory-profile")]
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+    #[test]
+    fn test_off_records_nothing() {
+        let mut profiler = Profiler::new(Profiling::Off).unwrap();
+        assert_eq!(profiler.run("layer", || Ok(vec![0u8; 64])).unwrap().len(), 64);
+        assert!(profiler.finish().is_none());
+    }
+
+    #[test]
+    fn test_memory_needs_feature() {
+        assert_eq!(Profiler::new(Profiling::Memory).is_ok(), available());
+    }
+
+    #[cfg(feature = "memory-profile")]
+    #[test]
+    fn test_measure_counts_live_buffers() {
+        let (_, peak) = measure(|| {
+            let first = vec![1u8; 100_000];
+            let second = vec![2u8; 50_000];
+            drop(first);
+            second
+        });
+        assert!((150_000..160_000).contains(&peak), "peak {}", peak);
+
+        // A dropped buffer no longer counts towards the next measurement
+        let (_, peak) = measure(|| vec![3u8; 10_000].len());
+        assert!((10_000..20_000).contains(&peak), "peak {}", peak);
+    }
+}
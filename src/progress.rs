@@ -0,0 +1,261 @@
+// Operation progress as a state machine
+// File operations report what they are doing as `OperationState` events, so
+// GUI wrappers can follow them instead of scraping CLI output. Every
+// operation moves through the states in this order:
+//
+//   ResolvingKeys → Reading+ → Layer+ → Writing+ → Finalizing → Done
+//
+// and may end in `Failed` from any state before `Done`. Byte counts in
+// repeated `Reading` and `Writing` events never go down; encryption runs the
+// layers upward from 1, decryption downward to 1. `Progress` asserts each
+// transition, so a callback never sees an event out of order.
+
+use crate::error::{HybridGuardError, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// Which way data goes through the layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+/// What a finished operation did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    pub direction: Direction,
+    /// Bytes read from the input
+    pub bytes_in: u64,
+    /// Bytes written to the output
+    pub bytes_out: u64,
+}
+
+/// Why an operation failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorSummary {
+    pub message: String,
+    /// The exit code the CLI reports for this error
+    pub exit_code: u8,
+}
+
+impl From<&HybridGuardError> for ErrorSummary {
+    fn from(error: &HybridGuardError) -> Self {
+        Self { message: error.to_string(), exit_code: error.code() }
+    }
+}
+
+/// One step of a file operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OperationState {
+    /// Deriving or unwrapping the keys for this file
+    ResolvingKeys,
+    /// Input read so far
+    Reading { bytes: u64 },
+    /// About to run layer `index` (1-based, in stack order)
+    Layer { index: usize, name: String, direction: Direction },
+    /// Output written so far
+    Writing { bytes: u64 },
+    /// Syncing and moving the output into place
+    Finalizing,
+    Done(Summary),
+    Failed(ErrorSummary),
+}
+
+impl OperationState {
+    /// Whether the operation has ended
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, OperationState::Done(_) | OperationState::Failed(_))
+    }
+}
+
+/// Whether `next` may follow `previous`; `None` is before the first event
+pub fn may_follow(previous: Option<&OperationState>, next: &OperationState) -> bool {
+    use OperationState::*;
+
+    match (previous, next) {
+        (None, ResolvingKeys) => true,
+        (Some(previous), ResolvingKeys) => previous.is_terminal(),
+        (None, _) => false,
+        (Some(previous), _) if previous.is_terminal() => false,
+        (Some(_), Failed(_)) => true,
+        (Some(ResolvingKeys), Reading { .. }) => true,
+        (Some(Reading { bytes: before }), Reading { bytes }) => bytes >= before,
+        (Some(Reading { .. }), Layer { index, direction, .. }) => match direction {
+            Direction::Encrypt => *index == 1,
+            Direction::Decrypt => *index >= 1,
+        },
+        (Some(Layer { index: before, direction: was, .. }), Layer { index, direction, .. }) => {
+            was == direction
+                && match direction {
+                    Direction::Encrypt => *index == before + 1,
+                    Direction::Decrypt => *index >= 1 && *index + 1 == *before,
+                }
+        }
+        (Some(Layer { index, direction, .. }), Writing { .. }) => *direction == Direction::Encrypt || *index == 1,
+        (Some(Writing { bytes: before }), Writing { bytes }) => bytes >= before,
+        (Some(Writing { .. }), Finalizing) => true,
+        (Some(Finalizing), Done(_)) => true,
+        _ => false,
+    }
+}
+
+type Callback = dyn Fn(&OperationState) + Send + Sync;
+
+/// Where an operation sends its states; clones share the callback and the
+/// last state, and the default reports nothing
+#[derive(Clone, Default)]
+pub struct Progress {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    callback: Box<Callback>,
+    last: Mutex<Option<OperationState>>,
+}
+
+impl Progress {
+    /// Call `callback` with every state, in a legal order
+    pub fn new(callback: impl Fn(&OperationState) + Send + Sync + 'static) -> Self {
+        Self { inner: Some(Arc::new(Inner { callback: Box::new(callback), last: Mutex::new(None) })) }
+    }
+
+    /// Report `state`
+    /// Panics if `state` may not follow the last one: that is a bug in the
+    /// operation, not something the caller can recover from
+    pub fn emit(&self, state: OperationState) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        {
+            let mut last = inner.last.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            assert!(may_follow(last.as_ref(), &state), "illegal progress transition {:?} → {:?}", last, state);
+            *last = Some(state.clone());
+        }
+        (inner.callback)(&state);
+    }
+
+    /// End the running operation with `Done` or `Failed`, passing `result` on
+    /// An error before the operation started only passes through
+    pub fn finish(&self, result: Result<Summary>) -> Result<Summary> {
+        match &result {
+            Ok(summary) => self.emit(OperationState::Done(summary.clone())),
+            Err(error) if self.is_running() => self.emit(OperationState::Failed(error.into())),
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn is_running(&self) -> bool {
+        let Some(inner) = &self.inner else {
+            return false;
+        };
+        let last = inner.last.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        last.as_ref().is_some_and(|state| !state.is_terminal())
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress").field("reporting", &self.inner.is_some()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(index: usize, direction: Direction) -> OperationState {
+        OperationState::Layer { index, name: format!("layer {}", index), direction }
+    }
+
+    fn done(direction: Direction) -> OperationState {
+        OperationState::Done(Summary { direction, bytes_in: 10, bytes_out: 20 })
+    }
+
+    fn legal(states: &[OperationState]) -> bool {
+        let mut previous = None;
+        for state in states {
+            if !may_follow(previous, state) {
+                return false;
+            }
+            previous = Some(state);
+        }
+        true
+    }
+
+    #[test]
+    fn test_grammar() {
+        use OperationState::*;
+        let encrypt = [
+            ResolvingKeys,
+            Reading { bytes: 10 },
+            layer(1, Direction::Encrypt),
+            layer(2, Direction::Encrypt),
+            Writing { bytes: 20 },
+            Finalizing,
+            done(Direction::Encrypt),
+        ];
+        assert!(legal(&encrypt));
+        let decrypt = [
+            ResolvingKeys,
+            Reading { bytes: 20 },
+            layer(2, Direction::Decrypt),
+            layer(1, Direction::Decrypt),
+            Writing { bytes: 5 },
+            Writing { bytes: 10 },
+            Finalizing,
+            done(Direction::Decrypt),
+            ResolvingKeys,
+        ];
+        assert!(legal(&decrypt));
+
+        let failed = OperationState::Failed(ErrorSummary { message: "no".to_string(), exit_code: 3 });
+        assert!(legal(&[ResolvingKeys, failed.clone(), ResolvingKeys]));
+        assert!(!legal(&[failed.clone()]));
+        assert!(!legal(&[ResolvingKeys, done(Direction::Encrypt), failed]));
+
+        assert!(!legal(&[Reading { bytes: 0 }]));
+        assert!(!legal(&[ResolvingKeys, Reading { bytes: 9 }, Reading { bytes: 8 }]));
+        assert!(!legal(&[ResolvingKeys, Reading { bytes: 1 }, layer(2, Direction::Encrypt)]));
+        assert!(!legal(&[ResolvingKeys, Reading { bytes: 1 }, layer(1, Direction::Encrypt), layer(1, Direction::Encrypt)]));
+        assert!(!legal(&[ResolvingKeys, Reading { bytes: 1 }, layer(1, Direction::Encrypt), layer(2, Direction::Decrypt)]));
+        assert!(!legal(&[ResolvingKeys, Reading { bytes: 1 }, layer(2, Direction::Decrypt), Writing { bytes: 1 }]));
+        assert!(!legal(&[ResolvingKeys, Reading { bytes: 1 }, layer(1, Direction::Encrypt), Finalizing]));
+    }
+
+    #[test]
+    fn test_progress_reports_in_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let progress = Progress::new(move |state| sink.lock().unwrap().push(state.clone()));
+
+        progress.emit(OperationState::ResolvingKeys);
+        let result = progress.finish(Err(HybridGuardError::KeyMismatch("wrong key".to_string())));
+        assert!(result.is_err());
+        // Nothing is running any more, so a second failure is not reported
+        assert!(progress.finish(Err(HybridGuardError::Cancelled)).is_err());
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(matches!(&seen[1], OperationState::Failed(e) if e.exit_code == crate::error::exit_code::KEY));
+    }
+
+    #[test]
+    #[should_panic(expected = "illegal progress transition")]
+    fn test_illegal_transitions_panic() {
+        let progress = Progress::new(|_| {});
+        progress.emit(OperationState::Finalizing);
+    }
+
+    #[test]
+    fn test_states_serialize_flat() {
+        let json = serde_json::to_value(done(Direction::Decrypt)).unwrap();
+        assert_eq!(json, serde_json::json!({"state": "done", "direction": "decrypt", "bytes_in": 10, "bytes_out": 20}));
+        let json = serde_json::to_value(layer(3, Direction::Encrypt)).unwrap();
+        assert_eq!(json["state"], "layer");
+        assert_eq!(serde_json::from_value::<OperationState>(json).unwrap(), layer(3, Direction::Encrypt));
+    }
+}
@@ -0,0 +1,679 @@
+// Resumable re-encryption of a tree after a key rotation
+// `plan` reads the headers under a root and lists every HybridGuard file still
+// under the old key ID, with its size, an estimated time and a fingerprint of
+// its header, in a JSON plan file. `apply` re-encrypts the listed files one at
+// a time, staged and renamed into place, and marks each entry in the plan file
+// as it finishes, so an interrupted run resumes where it stopped. A file whose
+// header changed since planning is skipped rather than re-encrypted. A file
+// that fails is marked failed with its error and the run goes on to the
+// next; the next run tries it again.
+// A file keeps its format: a container its encoding or shards, and a chunked
+// file its chunking, re-encrypted as it is decrypted without the plaintext
+// ever being held whole. Sparse files are skipped, since their holes can
+// only be found again in a plaintext file.
+
+use crate::cancel::CancellationToken;
+use crate::crypto::codec;
+use crate::crypto::container::{le_u16, le_u64};
+use crate::crypto::encoding::{self, ArmorReader, ArmorWriter, Encoding};
+use crate::crypto::EncryptedData;
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::KeyManager;
+use crate::migrate::{self, Migrated};
+use crate::pathname::JsonPath;
+use crate::scan::{self, FileHeader, Format, Predicate, Unscanned};
+use crate::staging::{Contents, StagedFile};
+use crate::storage::erasure;
+use crate::streaming::chunked::{self, ChunkedHeader};
+use crate::streaming::DEFAULT_CHUNK_SIZE;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::Instant;
+
+/// Version of the plan file format
+pub const PLAN_VERSION: u16 = 2;
+
+/// Decrypted chunks in flight between the two halves of a chunked rekey
+const QUEUED_CHUNKS: usize = 4;
+
+/// Plaintext bytes re-encrypted by `measure_throughput`
+const SAMPLE_LEN: usize = 256 * 1024;
+
+/// Where one planned file stands
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum EntryStatus {
+    Pending,
+    Done,
+    /// Left alone, e.g. because its header changed since planning
+    Skipped { reason: String },
+    /// Could not be re-encrypted; retried by the next run
+    Failed { error: String },
+}
+
+impl EntryStatus {
+    /// Whether a run still has to re-encrypt the file
+    pub fn is_pending(&self) -> bool {
+        matches!(self, EntryStatus::Pending | EntryStatus::Failed { .. })
+    }
+}
+
+/// One file the plan re-encrypts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub path: JsonPath,
+    pub size: u64,
+    pub estimated_ms: u64,
+    /// Hex SHA3-256 of the header fields read at planning
+    pub header: String,
+    #[serde(flatten)]
+    pub status: EntryStatus,
+}
+
+/// Files under `root` to move from one key ID to another
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RekeyPlan {
+    pub version: u16,
+    pub root: JsonPath,
+    pub from_key_id: String,
+    pub to_key_id: String,
+    /// Key file `apply` re-encrypts under
+    pub to_key_file: JsonPath,
+    /// Re-encryption speed the estimates assume
+    pub bytes_per_second: u64,
+    pub entries: Vec<PlanEntry>,
+}
+
+impl RekeyPlan {
+    pub fn load(path: &Path) -> Result<Self> {
+        let plan: Self = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| HybridGuardError::InvalidInput(format!("{} is not a rekey plan: {}", path.display(), e)))?;
+        if plan.version != PLAN_VERSION {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "rekey plan version {} (this build reads version {})",
+                plan.version, PLAN_VERSION
+            )));
+        }
+        Ok(plan)
+    }
+
+    /// Write the plan to a temporary file beside `path`, then rename it into place
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.save_with(path, &WriteOptions::critical())
+    }
+
+    /// `save`, finishing the plan file as `options` ask
+    pub fn save_with(&self, path: &Path, options: &WriteOptions) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+        let mut staged = StagedFile::create(path, None, Contents::Plaintext)?.with_write_options(options.clone());
+        staged.file().write_all(&json)?;
+        staged.commit()?;
+        Ok(())
+    }
+
+    /// Bytes of the entries still pending
+    pub fn pending_bytes(&self) -> u64 {
+        self.entries.iter().filter(|entry| entry.status.is_pending()).map(|entry| entry.size).sum()
+    }
+
+    /// Estimated time for the entries still pending, in milliseconds
+    pub fn pending_ms(&self) -> u64 {
+        self.entries.iter().filter(|entry| entry.status.is_pending()).map(|entry| entry.estimated_ms).sum()
+    }
+}
+
+/// Plan the move of every HybridGuard file under `root` encrypted under
+/// `from_key_id` to `to`'s keys, loaded from `to_key_file`
+/// Also returns the paths whose headers could not be read
+pub fn plan(root: &Path, from_key_id: &str, to: &KeyManager, to_key_file: &Path, bytes_per_second: u64) -> Result<(RekeyPlan, Vec<Unscanned>)> {
+    if from_key_id == to.key_id() {
+        return Err(HybridGuardError::InvalidInput(format!("{} is already the new key ID", from_key_id)));
+    }
+    let mut found = scan::find(root, Predicate::default());
+    let mut paths: Vec<PathBuf> = found.by_ref().filter(|hit| hit.key_id.as_deref() == Some(from_key_id)).map(|hit| hit.path).collect();
+    paths.sort();
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let (header, size) = read_header(&path)?;
+        entries.push(PlanEntry {
+            path: JsonPath::new(&path),
+            size,
+            estimated_ms: size.saturating_mul(1000) / bytes_per_second.max(1),
+            header: fingerprint(&header, size),
+            status: EntryStatus::Pending,
+        });
+    }
+    let plan = RekeyPlan {
+        version: PLAN_VERSION,
+        root: JsonPath::new(root),
+        from_key_id: from_key_id.to_string(),
+        to_key_id: to.key_id().to_string(),
+        to_key_file: JsonPath::new(to_key_file),
+        bytes_per_second,
+        entries,
+    };
+    Ok((plan, found.unscanned().to_vec()))
+}
+
+/// Bytes per second this machine re-encrypts, timed on a small sample
+pub fn measure_throughput() -> Result<u64> {
+    let from = KeyManager::from_master_key(&rand::random())?;
+    let to = KeyManager::from_master_key(&rand::random())?;
+    let sample = HybridGuardEncryptor::new()
+        .encrypt(&vec![0x5A; SAMPLE_LEN], from.get_keys()?)?
+        .with_key_id(from.key_id())
+        .to_bytes()?;
+    let started = Instant::now();
+    migrate::rekey(&sample, &from, &to)?;
+    let micros = started.elapsed().as_micros().max(1) as u64;
+    Ok((SAMPLE_LEN as u64).saturating_mul(1_000_000) / micros)
+}
+
+/// Totals of one `apply` run
+#[derive(Debug, Default)]
+pub struct ApplySummary {
+    /// Re-encrypted by this run
+    pub rekeyed: usize,
+    /// Found already under the new keys, e.g. after a crash before the plan was saved
+    pub already_rekeyed: usize,
+    /// Marked done by an earlier run
+    pub previously_done: usize,
+    pub skipped: usize,
+    /// Files this run could not re-encrypt, with why
+    pub failed: Vec<(PathBuf, HybridGuardError)>,
+}
+
+impl ApplySummary {
+    /// The first failure, if any file failed
+    pub fn into_result(self) -> Result<()> {
+        match self.failed.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Settings for `apply`
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    /// Where re-encrypted files are staged (default: beside each file)
+    pub temp_dir: Option<PathBuf>,
+    /// Checked before each file; the run stops with `Cancelled`, its progress saved
+    pub cancel: Option<CancellationToken>,
+    /// How each re-encrypted file is finished
+    pub write: WriteOptions,
+    /// How the plan file is finished after every entry
+    pub plan_write: WriteOptions,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        Self { temp_dir: None, cancel: None, write: WriteOptions::bulk(), plan_write: WriteOptions::critical() }
+    }
+}
+
+/// Re-encrypt the pending entries of the plan at `plan_path` from `from`'s
+/// keys to `to`'s, saving the plan after every entry
+/// A file that fails is recorded in the plan and the summary, and the run
+/// goes on; `on_entry` sees each entry once its new status is saved
+pub fn apply(
+    plan_path: &Path,
+    from: &KeyManager,
+    to: &KeyManager,
+    options: &ApplyOptions,
+    mut on_entry: impl FnMut(&PlanEntry),
+) -> Result<ApplySummary> {
+    let mut plan = RekeyPlan::load(plan_path)?;
+    for (role, planned, loaded) in [("old", &plan.from_key_id, from), ("new", &plan.to_key_id, to)] {
+        if planned != loaded.key_id() {
+            return Err(HybridGuardError::KeyMismatch(format!(
+                "the plan's {} key is {} but key {} is loaded",
+                role,
+                planned,
+                loaded.key_id()
+            )));
+        }
+    }
+
+    let mut summary = ApplySummary::default();
+    for index in 0..plan.entries.len() {
+        if !plan.entries[index].status.is_pending() {
+            if plan.entries[index].status == EntryStatus::Done {
+                summary.previously_done += 1;
+            }
+            continue;
+        }
+        if let Some(token) = &options.cancel {
+            token.check()?;
+        }
+        let path = plan.entries[index].path.to_path_buf()?;
+        let status = match rekey_entry(&path, &plan.entries[index].header, from, to, options) {
+            Err(HybridGuardError::Cancelled) => return Err(HybridGuardError::Cancelled),
+            Err(e) => {
+                let status = EntryStatus::Failed { error: e.to_string() };
+                summary.failed.push((path, e));
+                status
+            }
+            Ok(Rekeyed::Now) => {
+                summary.rekeyed += 1;
+                EntryStatus::Done
+            }
+            Ok(Rekeyed::Earlier) => {
+                summary.already_rekeyed += 1;
+                EntryStatus::Done
+            }
+            Ok(Rekeyed::Skipped(reason)) => {
+                summary.skipped += 1;
+                EntryStatus::Skipped { reason }
+            }
+        };
+        plan.entries[index].status = status;
+        plan.save_with(plan_path, &options.plan_write)?;
+        on_entry(&plan.entries[index]);
+    }
+    Ok(summary)
+}
+
+/// What became of one pending entry
+enum Rekeyed {
+    Now,
+    /// Its header already names the new key
+    Earlier,
+    Skipped(String),
+}
+
+fn rekey_entry(path: &Path, planned: &str, from: &KeyManager, to: &KeyManager, options: &ApplyOptions) -> Result<Rekeyed> {
+    let (header, size) = match read_header(path) {
+        Ok(found) => found,
+        Err(HybridGuardError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Rekeyed::Skipped("file no longer exists".to_string()))
+        }
+        Err(e) => return Ok(Rekeyed::Skipped(format!("header no longer reads: {}", e))),
+    };
+    if fingerprint(&header, size) != planned {
+        // A run that stopped between the rename and saving the plan left this one done
+        if header.key_id.as_deref() == Some(to.key_id()) {
+            return Ok(Rekeyed::Earlier);
+        }
+        return Ok(Rekeyed::Skipped("header changed since planning".to_string()));
+    }
+    if header.format == Format::Sparse {
+        return Ok(Rekeyed::Skipped("sparse files are re-encrypted by decrypting them and encrypting again with --sparse".to_string()));
+    }
+
+    rekey_file(path, header.format, from, to, options.temp_dir.as_deref(), &options.write)?;
+    Ok(Rekeyed::Now)
+}
+
+/// Re-encrypt the HybridGuard file at `path`, in `format`, from `from`'s keys
+/// to `to`'s, staged in `temp_dir` (default: beside it) and renamed into place
+/// The file keeps its format; returns the container format version it had,
+/// or the chunked format version of a chunked file
+pub fn rekey_file(path: &Path, format: Format, from: &KeyManager, to: &KeyManager, temp_dir: Option<&Path>, write: &WriteOptions) -> Result<u16> {
+    let mut staged = StagedFile::create(path, temp_dir, Contents::Ciphertext)
+        .map_err(|e| path_error(path, e))?
+        .with_write_options(write.clone());
+    let from_format = match format {
+        Format::Chunked => {
+            let open = || File::open(path).map(BufReader::new).map_err(|e| path_error(path, e));
+            let header = chunked::read_header(&mut open()?)?;
+            rekey_chunked(open()?, &header, staged.file(), from, to)?
+        }
+        Format::ArmoredChunked => {
+            let open = || File::open(path).map(|file| ArmorReader::new(BufReader::new(file))).map_err(|e| path_error(path, e));
+            let header = chunked::read_header(&mut open()?)?;
+            let mut armored = ArmorWriter::new(staged.file())?;
+            let from_format = rekey_chunked(open()?, &header, &mut armored, from, to)?;
+            armored.finish()?;
+            from_format
+        }
+        Format::Sparse => {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "{}: a sparse file is re-encrypted by decrypting it and encrypting again with --sparse",
+                path.display()
+            )))
+        }
+        container => {
+            let rekeyed = rekey_container(&fs::read(path).map_err(|e| path_error(path, e))?, container, from, to)?;
+            staged.file().write_all(&rekeyed.bytes)?;
+            rekeyed.from_format
+        }
+    };
+    staged.commit().map_err(|e| path_error(path, e))?;
+    Ok(from_format)
+}
+
+/// `migrate::rekey` on the container inside `bytes`, written back out in
+/// the same encoding or with the same shards
+fn rekey_container(bytes: &[u8], format: Format, from: &KeyManager, to: &KeyManager) -> Result<Migrated> {
+    let encoded = |encoding: Encoding| -> Result<Migrated> {
+        let rekeyed = migrate::rekey(&encoding::decode(bytes)?.to_bytes()?, from, to)?;
+        let bytes = encoding::encode(&EncryptedData::from_bytes(&rekeyed.bytes)?, encoding)?;
+        Ok(Migrated { bytes, ..rekeyed })
+    };
+    match format {
+        Format::Json => encoded(Encoding::Json),
+        Format::Armor => encoded(Encoding::Armor),
+        Format::Sharded => {
+            let recovered = erasure::decode(bytes)?;
+            let rekeyed = migrate::rekey(&recovered.payload, from, to)?;
+            let bytes = erasure::encode(&rekeyed.bytes, recovered.layout.redundancy)?;
+            Ok(Migrated { bytes, ..rekeyed })
+        }
+        _ => migrate::rekey(bytes, from, to),
+    }
+}
+
+/// Decrypt the chunked ciphertext in `source`, whose header is `header`,
+/// under `from` on one thread while this one encrypts it again under `to`
+/// into `target`, in segments of the same length
+fn rekey_chunked<R: Read + Send, W: Write>(
+    mut source: R,
+    header: &ChunkedHeader,
+    target: &mut W,
+    from: &KeyManager,
+    to: &KeyManager,
+) -> Result<u16> {
+    let cancel = &CancellationToken::new();
+    let segment_chunks = (header.segment_len / DEFAULT_CHUNK_SIZE as u64).max(1);
+    let (sender, receiver) = mpsc::sync_channel(QUEUED_CHUNKS);
+    let mut plaintext = ChannelWriter(sender);
+    thread::scope(|scope| {
+        let decrypting = scope.spawn(move || chunked::decrypt_stream(&mut source, &mut plaintext, from, cancel));
+        // Dropping the reader stops a decryption still writing into it
+        let encrypted = chunked::encrypt_into(&mut ChannelReader::new(receiver), header.plaintext_len, target, to, segment_chunks, cancel);
+        let decrypted = decrypting
+            .join()
+            .unwrap_or_else(|_| Err(HybridGuardError::Decryption("chunked decryption panicked".to_string())));
+        match (decrypted, encrypted) {
+            // Encryption failed first, so decryption found nobody reading
+            (Err(HybridGuardError::Io(e)), Err(encrypting)) if e.kind() == io::ErrorKind::BrokenPipe => Err(encrypting),
+            (Err(e), _) | (Ok(_), Err(e)) => Err(e),
+            (Ok(_), Ok(_)) => Ok(header.format_version),
+        }
+    })
+}
+
+/// Hands each write to a `ChannelReader` on another thread
+struct ChannelWriter(SyncSender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads what a `ChannelWriter` sent, to the end once it is dropped
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self { receiver, chunk: Vec::new(), pos: 0 }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Header of the HybridGuard file at `path`, in any format, and the file's size
+fn read_header(path: &Path) -> Result<(FileHeader, u64)> {
+    let size = fs::metadata(path).map_err(|e| path_error(path, e))?.len();
+    match scan::read_header(path).map_err(|e| path_error(path, e))? {
+        Some(header) => Ok((header, size)),
+        None => Err(HybridGuardError::UnsupportedFormat(format!("{}: not a HybridGuard file", path.display()))),
+    }
+}
+
+/// Hex SHA3-256 over the header fields, and the size, that change when a
+/// file is rewritten
+fn fingerprint(header: &FileHeader, size: u64) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"HybridGuard-rekey-plan");
+    hasher.update(header.format.to_string().as_bytes());
+    hasher.update(le_u16(header.format_version));
+    hasher.update(le_u64(size));
+    hasher.update(le_u64(header.timestamp));
+    let key_id = header.key_id.as_deref().unwrap_or_default().as_bytes();
+    hasher.update(le_u64(key_id.len() as u64));
+    hasher.update(key_id);
+    hasher.update(header.content_digest.unwrap_or_default());
+    codec::hex_lower(&hasher.finalize())
+}
+
+fn path_error(path: &Path, e: io::Error) -> HybridGuardError {
+    HybridGuardError::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HybridGuard;
+
+    struct Tree {
+        dir: tempfile::TempDir,
+        old: KeyManager,
+        new: KeyManager,
+    }
+
+    impl Tree {
+        /// Five files under the old key, one under the new and one plain file
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let old = KeyManager::from_master_key(&[0x17; 32]).unwrap();
+            let new = KeyManager::from_master_key(&[0x18; 32]).unwrap();
+            fs::create_dir(dir.path().join("sub")).unwrap();
+            for i in 0..5 {
+                let name = if i % 2 == 0 { format!("{}.hg", i) } else { format!("sub/{}.hg", i) };
+                Self::encrypt(&old, &dir.path().join(name), &[i as u8; 1000]);
+            }
+            Self::encrypt(&new, &dir.path().join("current.hg"), b"already rotated");
+            fs::write(dir.path().join("notes.txt"), b"not a container").unwrap();
+            Self { dir, old, new }
+        }
+
+        fn encrypt(keys: &KeyManager, path: &Path, data: &[u8]) {
+            let keys = KeyManager::from_bytes(&keys.to_bytes().unwrap()).unwrap();
+            let encrypted = HybridGuard::builder(keys).build().unwrap().encrypt(data).unwrap();
+            fs::write(path, encrypted.to_bytes().unwrap()).unwrap();
+        }
+
+        fn plan_path(&self) -> PathBuf {
+            self.dir.path().join("plan.json")
+        }
+
+        fn plan(&self) -> RekeyPlan {
+            let (plan, unscanned) = plan(self.dir.path(), self.old.key_id(), &self.new, Path::new("new.keys"), 1_000_000).unwrap();
+            assert!(unscanned.is_empty());
+            plan.save(&self.plan_path()).unwrap();
+            plan
+        }
+
+        fn key_id(&self, entry: &PlanEntry) -> Option<String> {
+            scan::read_header(&entry.path.to_path_buf().unwrap()).unwrap().unwrap().key_id
+        }
+    }
+
+    #[test]
+    fn test_plan_lists_files_under_the_old_key() {
+        let tree = Tree::new();
+        let plan = tree.plan();
+        assert_eq!(plan.entries.len(), 5);
+        assert!(plan.entries.iter().all(|entry| entry.status == EntryStatus::Pending && entry.size > 1000));
+        assert_eq!(plan.pending_bytes(), plan.entries.iter().map(|entry| entry.size).sum::<u64>());
+        assert_eq!(plan.entries[0].estimated_ms, plan.entries[0].size / 1000);
+        assert_eq!(RekeyPlan::load(&tree.plan_path()).unwrap(), plan);
+        assert!(super::plan(tree.dir.path(), tree.new.key_id(), &tree.new, Path::new("new.keys"), 1).is_err());
+    }
+
+    #[test]
+    fn test_interrupted_apply_resumes_without_redoing_files() {
+        let tree = Tree::new();
+        let plan = tree.plan();
+        let cancel = CancellationToken::new();
+        let options = ApplyOptions { cancel: Some(cancel.clone()), ..ApplyOptions::default() };
+
+        // Stop after two files
+        let mut seen = Vec::new();
+        let result = apply(&tree.plan_path(), &tree.old, &tree.new, &options, |entry| {
+            seen.push(entry.path.clone());
+            if seen.len() == 2 {
+                cancel.cancel();
+            }
+        });
+        assert!(matches!(result, Err(HybridGuardError::Cancelled)));
+        let saved = RekeyPlan::load(&tree.plan_path()).unwrap();
+        assert_eq!(saved.entries.iter().filter(|entry| entry.status == EntryStatus::Done).count(), 2);
+        let first_two: Vec<Vec<u8>> = saved.entries[..2].iter().map(|entry| fs::read(entry.path.to_path_buf().unwrap()).unwrap()).collect();
+
+        let options = ApplyOptions::default();
+        let mut resumed = Vec::new();
+        let summary = apply(&tree.plan_path(), &tree.old, &tree.new, &options, |entry| resumed.push(entry.path.clone())).unwrap();
+        assert_eq!((summary.rekeyed, summary.already_rekeyed, summary.previously_done, summary.skipped), (3, 0, 2, 0));
+        assert!(summary.failed.is_empty());
+        assert!(resumed.iter().all(|path| !seen.contains(path)));
+        for (entry, before) in saved.entries[..2].iter().zip(first_two) {
+            assert_eq!(fs::read(entry.path.to_path_buf().unwrap()).unwrap(), before);
+        }
+        let finished = RekeyPlan::load(&tree.plan_path()).unwrap();
+        for entry in &finished.entries {
+            assert_eq!(entry.status, EntryStatus::Done);
+            assert_eq!(tree.key_id(entry).as_deref(), Some(tree.new.key_id()));
+        }
+        assert_eq!(finished.entries.len(), plan.entries.len());
+
+        // A finished plan does nothing
+        let summary = apply(&tree.plan_path(), &tree.old, &tree.new, &options, |_| panic!("nothing is pending")).unwrap();
+        assert_eq!(summary.previously_done, 5);
+    }
+
+    #[test]
+    fn test_changed_and_already_rekeyed_files() {
+        let tree = Tree::new();
+        let plan = tree.plan();
+        let changed = plan.entries[0].path.to_path_buf().unwrap();
+        Tree::encrypt(&tree.old, &changed, b"rewritten after planning");
+        // As if a run stopped after the rename but before saving the plan
+        let crashed = plan.entries[1].path.to_path_buf().unwrap();
+        let rekeyed = migrate::rekey(&fs::read(&crashed).unwrap(), &tree.old, &tree.new).unwrap();
+        fs::write(&crashed, &rekeyed.bytes).unwrap();
+
+        let summary = apply(&tree.plan_path(), &tree.old, &tree.new, &ApplyOptions::default(), |_| {}).unwrap();
+        assert_eq!((summary.rekeyed, summary.already_rekeyed, summary.previously_done, summary.skipped), (3, 1, 0, 1));
+        assert!(summary.failed.is_empty());
+        let saved = RekeyPlan::load(&tree.plan_path()).unwrap();
+        assert!(matches!(&saved.entries[0].status, EntryStatus::Skipped { reason } if reason.contains("changed")));
+        assert_eq!(tree.key_id(&saved.entries[0]).as_deref(), Some(tree.old.key_id()));
+        assert_eq!(fs::read(&crashed).unwrap(), rekeyed.bytes);
+    }
+
+    #[test]
+    fn test_failed_file_is_recorded_and_the_run_goes_on() {
+        let tree = Tree::new();
+        let plan = tree.plan();
+        let broken = plan.entries[0].path.to_path_buf().unwrap();
+        let mut bytes = fs::read(&broken).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        fs::write(&broken, &bytes).unwrap();
+
+        let summary = apply(&tree.plan_path(), &tree.old, &tree.new, &ApplyOptions::default(), |_| {}).unwrap();
+        assert_eq!(summary.rekeyed, 4);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, broken);
+        let saved = RekeyPlan::load(&tree.plan_path()).unwrap();
+        assert!(matches!(saved.entries[0].status, EntryStatus::Failed { .. }));
+        assert!(saved.entries[1..].iter().all(|entry| entry.status == EntryStatus::Done));
+        assert_eq!(fs::read(&broken).unwrap(), bytes);
+        assert_eq!(saved.pending_bytes(), saved.entries[0].size);
+
+        // Repaired, it is the only file the next run re-encrypts
+        bytes[middle] ^= 1;
+        fs::write(&broken, &bytes).unwrap();
+        let summary = apply(&tree.plan_path(), &tree.old, &tree.new, &ApplyOptions::default(), |_| {}).unwrap();
+        assert_eq!((summary.rekeyed, summary.previously_done), (1, 4));
+        assert!(summary.into_result().is_ok());
+        assert_eq!(tree.key_id(&saved.entries[0]).as_deref(), Some(tree.new.key_id()));
+    }
+
+    #[test]
+    fn test_apply_checks_the_loaded_keys() {
+        let tree = Tree::new();
+        tree.plan();
+        let result = apply(&tree.plan_path(), &tree.new, &tree.new, &ApplyOptions::default(), |_| {});
+        assert!(matches!(result, Err(HybridGuardError::KeyMismatch(_))));
+    }
+
+    #[test]
+    fn test_every_format_is_rekeyed_in_its_own_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = KeyManager::from_master_key(&[0x19; 32]).unwrap();
+        let new = KeyManager::from_master_key(&[0x1A; 32]).unwrap();
+        let cancel = CancellationToken::new();
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        let mut stream = Vec::new();
+        chunked::encrypt_into(&mut data.as_slice(), data.len() as u64, &mut stream, &old, 1, &cancel).unwrap();
+        fs::write(dir.path().join("stream.hg"), &stream).unwrap();
+        let mut armored = ArmorWriter::new(Vec::new()).unwrap();
+        armored.write_all(&stream).unwrap();
+        fs::write(dir.path().join("stream.asc"), armored.finish().unwrap()).unwrap();
+        let keys = KeyManager::from_bytes(&old.to_bytes().unwrap()).unwrap();
+        let container = HybridGuard::builder(keys).build().unwrap().encrypt(&data).unwrap();
+        fs::write(dir.path().join("record.json"), encoding::encode(&container, Encoding::Json).unwrap()).unwrap();
+        let sharded = erasure::encode(&container.to_bytes().unwrap(), erasure::Redundancy::new(4, 2).unwrap()).unwrap();
+        fs::write(dir.path().join("record.shards"), sharded).unwrap();
+
+        let plan_path = dir.path().join("plan.json");
+        let (plan, unscanned) = plan(dir.path(), old.key_id(), &new, Path::new("new.keys"), 1_000_000).unwrap();
+        assert!(unscanned.is_empty());
+        assert_eq!(plan.entries.len(), 4);
+        plan.save(&plan_path).unwrap();
+        let formats: Vec<Format> = plan.entries.iter().map(|entry| read_header(&entry.path.to_path_buf().unwrap()).unwrap().0.format).collect();
+
+        let summary = apply(&plan_path, &old, &new, &ApplyOptions::default(), |_| {}).unwrap();
+        assert_eq!(summary.rekeyed, 4);
+        for (entry, format) in plan.entries.iter().zip(formats) {
+            let (header, _) = read_header(&entry.path.to_path_buf().unwrap()).unwrap();
+            assert_eq!((header.format, header.key_id.as_deref()), (format, Some(new.key_id())));
+        }
+
+        // The chunked files decrypt under the new keys alone, armored or not
+        let mut restored = Vec::new();
+        chunked::decrypt_stream(&mut File::open(dir.path().join("stream.hg")).unwrap(), &mut restored, &new, &cancel).unwrap();
+        assert_eq!(restored, data);
+        let mut armored = ArmorReader::new(BufReader::new(File::open(dir.path().join("stream.asc")).unwrap()));
+        restored.clear();
+        chunked::decrypt_stream(&mut armored, &mut restored, &new, &cancel).unwrap();
+        assert_eq!(restored, data);
+    }
+}
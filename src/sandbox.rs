@@ -0,0 +1,328 @@
+// Deny-by-default sandbox for decrypting untrusted inputs (decrypt --sandbox)
+// Parsing a container, rebuilding shards, unpadding and KEM decapsulation all
+// run on bytes an attacker chose. `activate` confines the process before they
+// do: on Linux it sets no_new_privs, optionally moves into new user and
+// network namespaces, and installs a seccomp filter on every thread that
+// fails with EPERM any syscall outside reading and writing open descriptors,
+// memory, futexes, clocks, randomness, signals to itself and exiting. No file
+// can be opened, linked, renamed or removed, no socket created or connected
+// and nothing executed after that. `run_confined` therefore activates it in
+// a forked child, which writes into descriptors opened beforehand and ends;
+// the parent, never confined, then names the result (renames a staged
+// output into place). Everything that opens files or starts up lazily (key
+// files, plugins, liboqs, the OS RNG, the staged output) must come before
+// the fork, and `warm_up` runs one small round trip through a guard so its
+// lazy state is in place. Where no filter can be installed the report says
+// `sandbox: false`, and `DecryptLimits::strict()` is all that is left;
+// callers apply it either way.
+
+use crate::error::Result;
+use crate::hybridguard::HybridGuard;
+use serde::Serialize;
+
+/// What to do besides the syscall filter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SandboxOptions {
+    /// Also move into new user and network namespaces, leaving the process no
+    /// network interfaces even if the filter were bypassed; needs unprivileged
+    /// user namespaces and a single-threaded process
+    pub unshare_namespaces: bool,
+}
+
+/// The confinement in effect after `activate`, or that it would give
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SandboxReport {
+    /// Whether the syscall filter is installed
+    pub sandbox: bool,
+    /// Whether the process is in new user and network namespaces
+    pub namespaces: bool,
+    /// Whether decryption runs under `DecryptLimits::strict()`; always true
+    pub strict_limits: bool,
+    /// Confinement that was asked for and not applied, and why
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+}
+
+/// What `activate` would report, without changing the process; namespaces
+/// are only known once tried, so they are reported as not entered
+pub fn probe(options: &SandboxOptions) -> SandboxReport {
+    let mut report = SandboxReport { strict_limits: true, ..SandboxReport::default() };
+    match seccomp::available() {
+        Ok(()) => report.sandbox = true,
+        Err(reason) => report.skipped.push(format!("seccomp filter: {}", reason)),
+    }
+    if options.unshare_namespaces {
+        report.skipped.push("namespaces: only entered by a real run".to_string());
+    }
+    report
+}
+
+/// Confine this process for good; there is no way back
+pub fn activate(options: &SandboxOptions) -> SandboxReport {
+    let mut report = SandboxReport { strict_limits: true, ..SandboxReport::default() };
+    // Namespaces first: unshare is one of the calls the filter denies
+    if options.unshare_namespaces {
+        match seccomp::unshare_namespaces() {
+            Ok(()) => report.namespaces = true,
+            Err(reason) => report.skipped.push(format!("namespaces: {}", reason)),
+        }
+    }
+    match seccomp::install() {
+        Ok(()) => report.sandbox = true,
+        Err(reason) => report.skipped.push(format!("seccomp filter: {}", reason)),
+    }
+    report
+}
+
+/// Run `work` in a child process confined by `activate`, and wait for it
+/// The child has this process's memory and descriptors as of the call, and
+/// only its exit code comes back: `work` reports its own errors and leaves
+/// its output in descriptors opened beforehand. Ctrl-C ends the child, which
+/// comes back as `Cancelled`; where there is no filter to install, `work`
+/// runs here unconfined
+pub fn run_confined(options: &SandboxOptions, work: impl FnOnce(&SandboxReport) -> u8) -> Result<u8> {
+    seccomp::in_child(|| work(&activate(options)))
+}
+
+/// Encrypt and decrypt one byte with `guard`, so liboqs, the random sources
+/// and the layers' keypairs are set up before `activate`
+pub fn warm_up(guard: &HybridGuard) -> Result<()> {
+    let encrypted = guard.encrypt(&[0])?;
+    guard.decrypt(&encrypted).map(drop)
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp {
+    use crate::error::HybridGuardError;
+    use std::io;
+
+    // Kernel ABI values, from linux/filter.h, linux/seccomp.h and linux/audit.h
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+    const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+    /// Offsets into `struct seccomp_data`
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// Exit code of a child whose `work` panicked, as Rust's own
+    const PANICKED: u8 = 101;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Every syscall the decrypt path makes once its files are open
+    /// The only path lookups are stat; openat, linkat, renameat, unlinkat,
+    /// socket, connect, execve, clone, ptrace, unshare and the rest fail with EPERM
+    pub(super) const ALLOWED: &[libc::c_long] = &[
+        // Open descriptors only
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_lseek,
+        libc::SYS_close,
+        libc::SYS_fcntl,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_ftruncate,
+        // Memory
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // Threads that already exist, time and randomness
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_getrandom,
+        // Signals to this process, for panics and aborts
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    fn statement(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter { code, jt: 0, jf: 0, k }
+    }
+
+    fn jump_if(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code: BPF_JEQ_K, jt, jf, k }
+    }
+
+    /// The filter program: kill calls from another ABI, allow `allowed`,
+    /// fail everything else with EPERM
+    pub(super) fn program(allowed: &[libc::c_long]) -> Vec<libc::sock_filter> {
+        let mut program = vec![
+            statement(BPF_LD_W_ABS, ARCH_OFFSET),
+            jump_if(AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, NR_OFFSET),
+        ];
+        for &nr in allowed {
+            program.push(jump_if(nr as u32, 0, 1));
+            program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+        }
+        program.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        program
+    }
+
+    /// Whether this kernel takes seccomp filters
+    pub(super) fn available() -> Result<(), String> {
+        // SAFETY: PR_GET_SECCOMP only reads its integer arguments
+        if unsafe { libc::prctl(libc::PR_GET_SECCOMP, 0, 0, 0, 0) } < 0 {
+            return Err(format!("not supported by this kernel ({})", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    pub(super) fn install() -> Result<(), String> {
+        available()?;
+        // SAFETY: PR_SET_NO_NEW_PRIVS only reads its integer arguments
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(format!("no_new_privs: {}", io::Error::last_os_error()));
+        }
+        let mut filter = program(ALLOWED);
+        let prog = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+        // TSYNC puts every thread of the process under the filter, not just this one
+        // SAFETY: `prog` points at `filter`, which outlives the call; the kernel copies it
+        let result = unsafe { libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC, &prog as *const libc::sock_fprog) };
+        match result {
+            0 => Ok(()),
+            tid if tid > 0 => Err(format!("thread {} could not be put under the filter", tid)),
+            _ => Err(io::Error::last_os_error().to_string()),
+        }
+    }
+
+    pub(super) fn unshare_namespaces() -> Result<(), String> {
+        // SAFETY: unshare only reads its flags
+        if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) } != 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        Ok(())
+    }
+
+    /// Fork, run `work` in the child and exit with its code; wait here
+    pub(super) fn in_child(work: impl FnOnce() -> u8) -> Result<u8, HybridGuardError> {
+        use std::io::Write;
+        use std::panic::{self, AssertUnwindSafe};
+
+        // SAFETY: the child only runs `work` and exits; it never returns into
+        // the caller, so nothing is unwound or dropped twice
+        match unsafe { libc::fork() } {
+            -1 => Err(HybridGuardError::Io(io::Error::last_os_error())),
+            0 => {
+                // The parent's Ctrl-C handler thread is not in the child
+                // SAFETY: restoring the default disposition takes no pointers
+                unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+                let code = panic::catch_unwind(AssertUnwindSafe(work)).unwrap_or(PANICKED);
+                let _ = io::stdout().flush();
+                let _ = io::stderr().flush();
+                // SAFETY: _exit ends the process without running the parent's atexit handlers
+                unsafe { libc::_exit(code.into()) }
+            }
+            child => wait(child),
+        }
+    }
+
+    fn wait(child: libc::pid_t) -> Result<u8, HybridGuardError> {
+        let mut status = 0;
+        // SAFETY: `status` is a valid int for waitpid to fill in
+        while unsafe { libc::waitpid(child, &mut status, 0) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(HybridGuardError::Io(e));
+            }
+        }
+        match (libc::WIFEXITED(status), libc::WTERMSIG(status)) {
+            (true, _) => Ok(libc::WEXITSTATUS(status) as u8),
+            (false, libc::SIGINT) => Err(HybridGuardError::Cancelled),
+            (false, signal) => Err(HybridGuardError::Decryption(format!("the sandboxed child was killed by signal {}", signal))),
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod seccomp {
+    use crate::error::HybridGuardError;
+
+    const UNSUPPORTED: &str = "only available on Linux on x86_64 and aarch64";
+
+    pub(super) fn available() -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub(super) fn install() -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub(super) fn unshare_namespaces() -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    /// Nothing can be confined here, so there is no child to keep apart
+    pub(super) fn in_child(work: impl FnOnce() -> u8) -> Result<u8, HybridGuardError> {
+        Ok(work())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_never_enters_namespaces() {
+        let report = probe(&SandboxOptions { unshare_namespaces: true });
+        assert!(report.strict_limits);
+        assert!(!report.namespaces);
+        assert!(report.skipped.iter().any(|reason| reason.starts_with("namespaces")), "{:?}", report.skipped);
+        if !cfg!(target_os = "linux") {
+            assert!(!report.sandbox);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn test_filter_denies_by_default() {
+        let program = seccomp::program(seccomp::ALLOWED);
+        assert_eq!(program.len(), 4 + 2 * seccomp::ALLOWED.len() + 1);
+        let allowed = |nr: libc::c_long| program.iter().any(|op| op.code == 0x15 && op.k == nr as u32);
+        assert!(allowed(libc::SYS_read) && allowed(libc::SYS_exit_group));
+        let naming = [libc::SYS_linkat, libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_unlinkat];
+        for denied in [libc::SYS_openat, libc::SYS_socket, libc::SYS_connect, libc::SYS_execve, libc::SYS_clone, libc::SYS_ptrace, libc::SYS_unshare].into_iter().chain(naming) {
+            assert!(!allowed(denied), "syscall {} is allowed", denied);
+        }
+        #[cfg(target_arch = "x86_64")]
+        assert!(!allowed(libc::SYS_rename) && !allowed(libc::SYS_unlink));
+        // Anything unmatched fails with EPERM instead of killing the process
+        assert_eq!(program.last().unwrap().k, 0x0005_0000 | libc::EPERM as u32);
+    }
+
+    #[test]
+    fn test_confined_child_reports_its_exit_code() {
+        let options = SandboxOptions::default();
+        assert_eq!(run_confined(&options, |_| 0).unwrap(), 0);
+        assert_eq!(run_confined(&options, |_| 4).unwrap(), 4);
+    }
+}
@@ -0,0 +1,400 @@
+// Finding ciphertexts by what their headers record, without keys
+// `find` walks a tree one directory handle per level and reads only each
+// file's header, so memory does not grow with the tree. Every format `sniff`
+// recognizes is read: binary, chunked and sparse files by their headers
+// alone, armored chunked files as the armor is decoded, and JSON, armored
+// and sharded containers, whose headers cannot be sought, whole.
+// Paths that cannot be read are collected on the `Scan` and the walk goes on;
+// an audit reports them rather than stopping at the first locked directory.
+
+use crate::crypto::container::{self, CiphertextHeader};
+use crate::crypto::encoding::{self, ArmorReader, Encoding};
+use crate::crypto::sniff::{self, FileKind};
+use crate::crypto::timestamp::DIGEST_LEN;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::LayerDescriptor;
+use crate::pathname;
+use crate::sparse::{self, SparseHeader};
+use crate::storage::erasure;
+use crate::streaming::chunked::{self, ChunkedHeader};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, ReadDir};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Bytes read from each file to tell what it is
+const SNIFF_LEN: u64 = 256;
+
+/// Which containers a scan reports; every condition set must hold
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Predicate {
+    /// A layer name, or its family: "ML-KEM" matches "ML-KEM-768"
+    pub uses_layer: Option<String>,
+    /// Only containers whose `uses_layer` layer is at this version
+    pub layer_version: Option<u16>,
+    /// Only files in a format older than this version; chunked and sparse
+    /// files count the versions of their own formats
+    pub format_below: Option<u16>,
+    /// Only containers encrypted before this time, in seconds since the epoch
+    pub older_than: Option<u64>,
+}
+
+impl Predicate {
+    /// Whether `header` satisfies every condition
+    pub fn matches(&self, header: &FileHeader) -> bool {
+        if self.uses_layer.is_some() && self.matching_layers(header).is_empty() {
+            return false;
+        }
+        if matches!(self.format_below, Some(below) if header.format_version >= below) {
+            return false;
+        }
+        !matches!(self.older_than, Some(before) if header.timestamp >= before)
+    }
+
+    /// Descriptors of `header` selected by `uses_layer` and `layer_version`
+    pub fn matching_layers(&self, header: &FileHeader) -> Vec<LayerDescriptor> {
+        let Some(layer) = &self.uses_layer else {
+            return Vec::new();
+        };
+        header
+            .descriptors
+            .iter()
+            .filter(|d| names_layer(layer, &d.name))
+            .filter(|d| match self.layer_version {
+                Some(version) => d.version == version,
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Whether `query` is `name` or its family (`name` up to a '-')
+pub(crate) fn names_layer(query: &str, name: &str) -> bool {
+    let (query, name) = (query.to_ascii_lowercase(), name.to_ascii_lowercase());
+    name == query || name.strip_prefix(&query).is_some_and(|rest| rest.starts_with('-'))
+}
+
+/// Seconds since the epoch of a date (`2025-01-01`, midnight UTC) or an RFC 3339 time
+pub fn parse_date(text: &str) -> Result<u64> {
+    let seconds = match chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0).map(|time| time.and_utc().timestamp()),
+        Err(_) => chrono::DateTime::parse_from_rfc3339(text).ok().map(|time| time.timestamp()),
+    };
+    match seconds {
+        Some(seconds) if seconds >= 0 => Ok(seconds as u64),
+        _ => Err(HybridGuardError::InvalidInput(format!(
+            "'{}' is not a date (expected YYYY-MM-DD or an RFC 3339 time)",
+            text
+        ))),
+    }
+}
+
+/// How a HybridGuard file is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// A binary container, legacy ones included
+    Container,
+    /// A container written as JSON
+    Json,
+    /// A container in ASCII armor
+    Armor,
+    /// A container split into erasure-coded shards
+    Sharded,
+    /// A chunked ciphertext
+    Chunked,
+    /// A chunked ciphertext in ASCII armor
+    ArmoredChunked,
+    /// A sparse ciphertext
+    Sparse,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Container => "container",
+            Format::Json => "json",
+            Format::Armor => "armor",
+            Format::Sharded => "sharded",
+            Format::Chunked => "chunked",
+            Format::ArmoredChunked => "armored-chunked",
+            Format::Sparse => "sparse",
+        })
+    }
+}
+
+/// What the header of a HybridGuard file of any format records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub format: Format,
+    /// Version of the container inside, or of the chunked or sparse format
+    pub format_version: u16,
+    /// Encryption time, in seconds since the epoch
+    pub timestamp: u64,
+    pub key_id: Option<String>,
+    pub descriptors: Vec<LayerDescriptor>,
+    /// Stored SHA3-256 of a container's ciphertext, when it records one
+    pub content_digest: Option<[u8; DIGEST_LEN]>,
+}
+
+impl FileHeader {
+    fn container(format: Format, header: CiphertextHeader) -> Self {
+        Self {
+            format,
+            format_version: header.format_version,
+            timestamp: header.timestamp,
+            key_id: header.key_id,
+            descriptors: header.descriptors,
+            content_digest: header.content_digest,
+        }
+    }
+
+    fn chunked(format: Format, header: ChunkedHeader) -> Self {
+        Self {
+            format,
+            format_version: header.format_version,
+            timestamp: header.timestamp,
+            key_id: Some(header.key_id),
+            descriptors: header.descriptors,
+            content_digest: None,
+        }
+    }
+
+    fn sparse(header: SparseHeader) -> Self {
+        Self {
+            format: Format::Sparse,
+            format_version: sparse::FORMAT_VERSION,
+            timestamp: header.timestamp,
+            key_id: Some(header.key_id),
+            descriptors: header.descriptors,
+            content_digest: None,
+        }
+    }
+}
+
+/// A HybridGuard file whose header matched
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScanHit {
+    #[serde(serialize_with = "pathname::serialize")]
+    pub path: PathBuf,
+    pub format: Format,
+    pub format_version: u16,
+    /// Encryption time, in seconds since the epoch
+    pub timestamp: u64,
+    pub key_id: Option<String>,
+    pub descriptors: Vec<LayerDescriptor>,
+    /// The descriptors `uses_layer` selected; empty without it
+    pub matched_layers: Vec<LayerDescriptor>,
+}
+
+/// A path whose header could not be checked, and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Unscanned {
+    #[serde(serialize_with = "pathname::serialize")]
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Lazy walk over a tree, yielding matching containers
+/// Symbolic links are not followed, so a link cannot loop the walk
+pub struct Scan {
+    predicate: Predicate,
+    /// The root itself, when it is a file
+    root_file: Option<PathBuf>,
+    dirs: Vec<(PathBuf, ReadDir)>,
+    unscanned: Vec<Unscanned>,
+    containers: usize,
+}
+
+/// Scan `root` (a directory or a single file) for containers matching `predicate`
+pub fn find(root: &Path, predicate: Predicate) -> Scan {
+    let mut scan = Scan { predicate, root_file: None, dirs: Vec::new(), unscanned: Vec::new(), containers: 0 };
+    match fs::metadata(root) {
+        Ok(metadata) if metadata.is_dir() => scan.enter(root.to_path_buf()),
+        Ok(_) => scan.root_file = Some(root.to_path_buf()),
+        Err(e) => scan.skip(root.to_path_buf(), e.to_string()),
+    }
+    scan
+}
+
+impl Scan {
+    /// Paths that could not be read, so far
+    pub fn unscanned(&self) -> &[Unscanned] {
+        &self.unscanned
+    }
+
+    /// HybridGuard files whose headers were read so far, matching or not
+    pub fn containers(&self) -> usize {
+        self.containers
+    }
+
+    fn enter(&mut self, dir: PathBuf) {
+        match fs::read_dir(&dir) {
+            Ok(entries) => self.dirs.push((dir, entries)),
+            Err(e) => self.skip(dir, e.to_string()),
+        }
+    }
+
+    fn skip(&mut self, path: PathBuf, reason: String) {
+        self.unscanned.push(Unscanned { path, reason });
+    }
+
+    /// The next file to look at, descending into directories on the way
+    fn next_file(&mut self) -> Option<PathBuf> {
+        if let Some(file) = self.root_file.take() {
+            return Some(file);
+        }
+        loop {
+            let (dir, entries) = self.dirs.last_mut()?;
+            let entry = match entries.next() {
+                None => {
+                    self.dirs.pop();
+                    continue;
+                }
+                Some(Err(e)) => {
+                    let dir = dir.clone();
+                    self.skip(dir, e.to_string());
+                    self.dirs.pop();
+                    continue;
+                }
+                Some(Ok(entry)) => entry,
+            };
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => self.enter(path),
+                Ok(kind) if kind.is_file() => return Some(path),
+                Ok(_) => {}
+                Err(e) => self.skip(path, e.to_string()),
+            }
+        }
+    }
+}
+
+/// The header of `path`, in whichever format it is, or None if it is not
+/// a HybridGuard file at all
+pub fn read_header(path: &Path) -> io::Result<Option<FileHeader>> {
+    let mut file = File::open(path)?;
+    let mut start = Vec::new();
+    (&mut file).take(SNIFF_LEN).read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let header = match sniff::identify(&start) {
+        FileKind::HybridGuard if start.starts_with(&container::MAGIC) => {
+            container::peek_header_from(&mut file).map(|header| FileHeader::container(Format::Container, header))
+        }
+        FileKind::HybridGuard if chunked::is_chunked(&start) => {
+            chunked::read_header(&mut file).map(|header| FileHeader::chunked(Format::Chunked, header))
+        }
+        FileKind::HybridGuard if sparse::is_sparse(&start) => sparse::read_header(&mut file).map(FileHeader::sparse),
+        FileKind::HybridGuard if erasure::is_sharded(&start) => erasure::decode(&fs::read(path)?)
+            .and_then(|recovered| container::peek_header(&recovered.payload))
+            .map(|header| FileHeader::container(Format::Sharded, header)),
+        FileKind::HybridGuard if encoding::detect(&start) == Encoding::Armor => read_armored(file, path),
+        FileKind::HybridGuard if encoding::is_text(&start) => encoding::decode(&fs::read(path)?)
+            .and_then(|data| container::peek_header(&data.to_bytes()?))
+            .map(|header| FileHeader::container(Format::Json, header)),
+        // Legacy containers have no magic; anything that does not parse as
+        // one is not a HybridGuard file
+        FileKind::HybridGuard | FileKind::Unknown | FileKind::TooShort(_) => {
+            let legacy = container::peek_header_from(&mut file).ok().filter(|header| header.format_version == 0);
+            return Ok(legacy.map(|header| FileHeader::container(Format::Container, header)));
+        }
+        _ => return Ok(None),
+    };
+    header.map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Header of an armored file: a chunked header as the armor is decoded, or
+/// a container's from the whole file
+fn read_armored(file: File, path: &Path) -> Result<FileHeader> {
+    let mut armor = ArmorReader::new(BufReader::new(file));
+    let mut magic = Vec::with_capacity(chunked::MAGIC.len());
+    (&mut armor).take(chunked::MAGIC.len() as u64).read_to_end(&mut magic)?;
+    if chunked::is_chunked(&magic) {
+        let header = chunked::read_header(&mut io::Cursor::new(magic).chain(armor))?;
+        return Ok(FileHeader::chunked(Format::ArmoredChunked, header));
+    }
+    let data = encoding::decode(&fs::read(path)?)?;
+    Ok(FileHeader::container(Format::Armor, container::peek_header(&data.to_bytes()?)?))
+}
+
+impl Iterator for Scan {
+    type Item = ScanHit;
+
+    fn next(&mut self) -> Option<ScanHit> {
+        loop {
+            let path = self.next_file()?;
+            let header = match read_header(&path) {
+                Ok(Some(header)) => header,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.skip(path, e.to_string());
+                    continue;
+                }
+            };
+            self.containers += 1;
+            if self.predicate.matches(&header) {
+                return Some(ScanHit {
+                    matched_layers: self.predicate.matching_layers(&header),
+                    path,
+                    format: header.format,
+                    format_version: header.format_version,
+                    timestamp: header.timestamp,
+                    key_id: header.key_id,
+                    descriptors: header.descriptors,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(format_version: u16, timestamp: u64, descriptors: &[(&str, u16)]) -> FileHeader {
+        FileHeader {
+            format: Format::Container,
+            format_version,
+            timestamp,
+            key_id: None,
+            descriptors: descriptors.iter().map(|(name, version)| LayerDescriptor::new(name, *version)).collect(),
+            content_digest: None,
+        }
+    }
+
+    #[test]
+    fn test_predicate_conditions_combine() {
+        let old = header(3, 1_600_000_000, &[("ML-KEM-768", 1), ("HQC", 1)]);
+        let new = header(8, 1_800_000_000, &[("ML-KEM-768", 3), ("HQC", 3)]);
+
+        assert!(Predicate::default().matches(&old) && Predicate::default().matches(&new));
+        let hqc = Predicate { uses_layer: Some("hqc".to_string()), ..Predicate::default() };
+        assert!(hqc.matches(&old) && hqc.matches(&new));
+        let hqc_v1 = Predicate { layer_version: Some(1), ..hqc.clone() };
+        assert!(hqc_v1.matches(&old) && !hqc_v1.matches(&new));
+        assert_eq!(hqc_v1.matching_layers(&old), [LayerDescriptor::new("HQC", 1)]);
+
+        let dated = Predicate { older_than: Some(1_700_000_000), ..hqc };
+        assert!(dated.matches(&old) && !dated.matches(&new));
+        let format = Predicate { format_below: Some(8), ..Predicate::default() };
+        assert!(format.matches(&old) && !format.matches(&new));
+    }
+
+    #[test]
+    fn test_layer_families() {
+        assert!(names_layer("ML-KEM", "ML-KEM-768"));
+        assert!(names_layer("ml-kem-768", "ML-KEM-768"));
+        assert!(!names_layer("ML", "MLX-1"));
+        assert!(!names_layer("HQC", "FHE"));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("2025-01-01").unwrap(), 1_735_689_600);
+        assert_eq!(parse_date("2025-01-01T01:00:00+01:00").unwrap(), 1_735_689_600);
+        assert!(parse_date("January").is_err());
+    }
+}
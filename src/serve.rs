@@ -0,0 +1,397 @@
+// Line-delimited JSON protocol for `hybridguard serve --stdio`
+// Every request and every response is one JSON object on one line. A client
+// opens with `{"op":"hello","max_protocol":N}`; the reply names the protocol
+// version chosen and what this build can do, and work sent before it is
+// refused. Requests needing a capability the hello did not declare are
+// refused with a structured error instead of being attempted.
+
+use crate::crypto::{codec, container};
+use crate::crypto::encoding::{self, Encoding};
+use crate::error::{HybridGuardError, Result};
+use crate::hybridguard::HybridGuard;
+use crate::layers;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{BufRead, Read, Write};
+
+/// Newest protocol version this build speaks
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest protocol version this build still accepts
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Default largest request line, newline included
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+
+/// Error codes in refused responses
+pub mod codes {
+    pub const INVALID_REQUEST: &str = "invalid_request";
+    pub const REQUEST_TOO_LARGE: &str = "request_too_large";
+    pub const UNKNOWN_OP: &str = "unknown_op";
+    pub const HANDSHAKE_REQUIRED: &str = "handshake_required";
+    pub const PROTOCOL_UNSUPPORTED: &str = "protocol_unsupported";
+    pub const CAPABILITY_UNAVAILABLE: &str = "capability_unavailable";
+    pub const OPERATION_FAILED: &str = "operation_failed";
+}
+
+/// Optional features a request may depend on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Features {
+    /// Armored containers from `encrypt`
+    pub armor: bool,
+    pub compression: bool,
+    pub streaming: bool,
+    pub signing: bool,
+}
+
+impl Features {
+    /// What this build offers over the protocol
+    pub fn available() -> Self {
+        Self { armor: true, compression: false, streaming: false, signing: false }
+    }
+
+    fn enabled(&self, name: &str) -> bool {
+        match name {
+            "armor" => self.armor,
+            "compression" => self.compression,
+            "streaming" => self.streaming,
+            "signing" => self.signing,
+            _ => false,
+        }
+    }
+}
+
+/// Bounds the server enforces on clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Limits {
+    pub max_request_bytes: usize,
+    /// Requests are answered one at a time, in order
+    pub max_concurrent_ops: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { max_request_bytes: DEFAULT_MAX_REQUEST_BYTES, max_concurrent_ops: 1 }
+    }
+}
+
+/// One layer of the pipeline and the formats it reads
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LayerCapability {
+    pub id: String,
+    pub format_version: u16,
+    pub readable_versions: Vec<u16>,
+}
+
+/// Container format versions written and read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContainerVersions {
+    pub writes: u16,
+    pub reads: Vec<u16>,
+}
+
+/// Reply to `hello`: everything a client needs before sending work
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Hello {
+    /// Version chosen for this session
+    pub protocol: u16,
+    pub min_protocol: u16,
+    pub crate_version: String,
+    pub key_id: String,
+    pub layers: Vec<LayerCapability>,
+    pub container_versions: ContainerVersions,
+    pub features: Features,
+    pub limits: Limits,
+}
+
+/// Request body after its `id` is taken off
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum Request {
+    Hello {
+        max_protocol: u16,
+    },
+    Encrypt {
+        data: String,
+        #[serde(default)]
+        encoding: Option<String>,
+        #[serde(default)]
+        requires: Vec<String>,
+    },
+    Decrypt {
+        data: String,
+        #[serde(default)]
+        requires: Vec<String>,
+    },
+}
+
+const OPS: &[&str] = &["hello", "encrypt", "decrypt"];
+
+/// A refused request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Refusal {
+    pub code: String,
+    pub message: String,
+    /// Exit code class of a failed operation (see `error::exit_code`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u8>,
+}
+
+impl Refusal {
+    fn new(code: &str, message: impl Into<String>) -> Self {
+        Self { code: code.to_string(), message: message.into(), exit_code: None }
+    }
+
+    fn failed(error: HybridGuardError) -> Self {
+        Self { code: codes::OPERATION_FAILED.to_string(), message: error.to_string(), exit_code: Some(error.code()) }
+    }
+}
+
+/// One response line; the request's `id` is echoed when it had one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub id: Value,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Refusal>,
+}
+
+impl Response {
+    fn reply(id: Value, outcome: std::result::Result<Value, Refusal>) -> Self {
+        match outcome {
+            Ok(result) => Self { id, ok: true, result: Some(result), error: None },
+            Err(refusal) => Self { id, ok: false, result: None, error: Some(refusal) },
+        }
+    }
+}
+
+/// Answers protocol requests with one `HybridGuard` instance
+pub struct Server {
+    hg: HybridGuard,
+    features: Features,
+    limits: Limits,
+    /// Negotiated by `hello`; None until then
+    protocol: Option<u16>,
+}
+
+impl Server {
+    pub fn new(hg: HybridGuard) -> Self {
+        Self { hg, features: Features::available(), limits: Limits::default(), protocol: None }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Protocol version negotiated so far
+    pub fn protocol(&self) -> Option<u16> {
+        self.protocol
+    }
+
+    /// Capabilities announced for a session at `protocol`
+    pub fn hello(&self, protocol: u16) -> Hello {
+        Hello {
+            protocol,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            key_id: self.hg.key_manager().key_id().to_string(),
+            layers: layers::registry()
+                .iter()
+                .map(|layer| {
+                    let descriptor = layer.descriptor();
+                    LayerCapability {
+                        id: descriptor.name,
+                        format_version: descriptor.version,
+                        readable_versions: layer.readable_versions(),
+                    }
+                })
+                .collect(),
+            container_versions: ContainerVersions {
+                writes: container::FORMAT_VERSION,
+                reads: container::SUPPORTED_VERSIONS.to_vec(),
+            },
+            features: self.features,
+            limits: self.limits,
+        }
+    }
+
+    /// Answer one request line
+    pub fn handle(&mut self, line: &[u8]) -> Response {
+        let mut value: Value = match serde_json::from_slice(line) {
+            Ok(value) => value,
+            Err(e) => return Response::reply(Value::Null, Err(Refusal::new(codes::INVALID_REQUEST, e.to_string()))),
+        };
+        let id = value.as_object_mut().and_then(|object| object.remove("id")).unwrap_or(Value::Null);
+        Response::reply(id, self.dispatch(value))
+    }
+
+    fn dispatch(&mut self, value: Value) -> std::result::Result<Value, Refusal> {
+        let op = value.get("op").and_then(Value::as_str).unwrap_or_default().to_string();
+        if !OPS.contains(&op.as_str()) {
+            return Err(Refusal::new(codes::UNKNOWN_OP, format!("unknown op '{}', expected one of {}", op, OPS.join(", "))));
+        }
+        let request: Request =
+            serde_json::from_value(value).map_err(|e| Refusal::new(codes::INVALID_REQUEST, e.to_string()))?;
+        if !matches!(request, Request::Hello { .. }) && self.protocol.is_none() {
+            return Err(Refusal::new(codes::HANDSHAKE_REQUIRED, format!("send hello before {}", op)));
+        }
+
+        match request {
+            Request::Hello { max_protocol } => {
+                if max_protocol < MIN_PROTOCOL_VERSION {
+                    return Err(Refusal::new(
+                        codes::PROTOCOL_UNSUPPORTED,
+                        format!("protocol {} is older than the minimum {}", max_protocol, MIN_PROTOCOL_VERSION),
+                    ));
+                }
+                let protocol = max_protocol.min(PROTOCOL_VERSION);
+                self.protocol = Some(protocol);
+                Ok(serde_json::to_value(self.hello(protocol)).unwrap_or_default())
+            }
+            Request::Encrypt { data, encoding, requires } => {
+                let encoding = match encoding.as_deref() {
+                    Some(name) => name.parse::<Encoding>().map_err(|e| Refusal::new(codes::INVALID_REQUEST, e.to_string()))?,
+                    None => Encoding::Binary,
+                };
+                let mut needed = requires;
+                if encoding == Encoding::Armor {
+                    needed.push("armor".to_string());
+                }
+                self.check_capabilities(&needed)?;
+                let plaintext = base64_field(&data)?;
+                let encrypted = self.hg.encrypt(&plaintext).map_err(Refusal::failed)?;
+                let bytes = encoding::encode(&encrypted, encoding).map_err(Refusal::failed)?;
+                Ok(json!({ "data": codec::b64_std(&bytes), "encoding": encoding.to_string() }))
+            }
+            Request::Decrypt { data, requires } => {
+                self.check_capabilities(&requires)?;
+                let bytes = base64_field(&data)?;
+                let encrypted = encoding::decode(&bytes).map_err(Refusal::failed)?;
+                let plaintext = self.hg.decrypt(&encrypted).map_err(Refusal::failed)?;
+                Ok(json!({ "data": codec::b64_std(&plaintext) }))
+            }
+        }
+    }
+
+    /// Refuse anything the hello did not declare as enabled
+    fn check_capabilities(&self, requires: &[String]) -> std::result::Result<(), Refusal> {
+        match requires.iter().find(|name| !self.features.enabled(name)) {
+            Some(name) => Err(Refusal::new(
+                codes::CAPABILITY_UNAVAILABLE,
+                format!("capability '{}' is not available in this build", name),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Answer requests from `reader` on `writer` until end of input
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut reader: R, mut writer: W) -> Result<()> {
+        let max = self.limits.max_request_bytes;
+        loop {
+            let mut line = Vec::new();
+            if (&mut reader).take(max as u64 + 1).read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            let response = if line.len() > max {
+                skip_line(&mut reader)?;
+                Response::reply(
+                    Value::Null,
+                    Err(Refusal::new(codes::REQUEST_TOO_LARGE, format!("requests are limited to {} bytes", max))),
+                )
+            } else if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            } else {
+                self.handle(&line)
+            };
+            let mut out = serde_json::to_vec(&response).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))?;
+            out.push(b'\n');
+            writer.write_all(&out)?;
+            writer.flush()?;
+        }
+    }
+}
+
+fn base64_field(data: &str) -> std::result::Result<Vec<u8>, Refusal> {
+    codec::b64_std_decode(data).map_err(|e| Refusal::new(codes::INVALID_REQUEST, format!("data is not base64: {}", e)))
+}
+
+/// Discard the rest of an oversized line without buffering it
+fn skip_line<R: BufRead>(reader: &mut R) -> Result<()> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyManager;
+
+    fn server() -> Server {
+        Server::new(HybridGuard::builder(KeyManager::from_master_key(&[0x91; 32]).unwrap()).build().unwrap())
+    }
+
+    fn send(server: &mut Server, request: Value) -> Response {
+        server.handle(request.to_string().as_bytes())
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let mut s = server();
+        let reply = send(&mut s, json!({"id": 1, "op": "hello", "max_protocol": 9}));
+        assert!(reply.ok);
+        assert_eq!(reply.id, json!(1));
+        assert_eq!(reply.result.unwrap()["protocol"], json!(PROTOCOL_VERSION));
+        assert_eq!(s.protocol(), Some(PROTOCOL_VERSION));
+
+        let reply = send(&mut server(), json!({"op": "hello", "max_protocol": 0}));
+        assert_eq!(reply.error.unwrap().code, codes::PROTOCOL_UNSUPPORTED);
+    }
+
+    #[test]
+    fn test_work_needs_hello_and_declared_capabilities() {
+        let mut s = server();
+        let encrypt = json!({"op": "encrypt", "data": codec::b64_std(b"payload")});
+        assert_eq!(send(&mut s, encrypt.clone()).error.unwrap().code, codes::HANDSHAKE_REQUIRED);
+
+        send(&mut s, json!({"op": "hello", "max_protocol": 1}));
+        let sealed = send(&mut s, encrypt).result.unwrap();
+        let reply = send(&mut s, json!({"op": "decrypt", "data": sealed["data"]}));
+        assert_eq!(codec::b64_std_decode(reply.result.unwrap()["data"].as_str().unwrap()).unwrap(), b"payload");
+
+        let reply = send(&mut s, json!({"op": "encrypt", "data": "", "requires": ["compression"]}));
+        assert_eq!(reply.error.unwrap().code, codes::CAPABILITY_UNAVAILABLE);
+        assert_eq!(send(&mut s, json!({"op": "sign", "data": ""})).error.unwrap().code, codes::UNKNOWN_OP);
+        assert_eq!(send(&mut s, json!({"op": "encrypt", "data": "", "mode": "fast"})).error.unwrap().code, codes::INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_oversized_lines_are_refused_and_skipped() {
+        let mut s = server().with_limits(Limits { max_request_bytes: 64, max_concurrent_ops: 1 });
+        let input = format!("{}\n{}\n", "x".repeat(500), json!({"op": "hello", "max_protocol": 1}));
+        let mut out = Vec::new();
+        s.serve(input.as_bytes(), &mut out).unwrap();
+
+        let lines: Vec<Value> = out.split(|&b| b == b'\n').filter(|l| !l.is_empty()).map(|l| serde_json::from_slice(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["error"]["code"], json!(codes::REQUEST_TOO_LARGE));
+        assert_eq!(lines[1]["ok"], json!(true));
+    }
+}
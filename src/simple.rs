@@ -0,0 +1,119 @@
+// One-shot passphrase encryption, without a key file
+// `encrypt` draws a salt, stretches the passphrase with Argon2id into a master
+// key and runs the usual pipeline under a file key wrapped by it. The salt and
+// cost go into the container (format v11), so `decrypt` needs the passphrase
+// and nothing else. The keys exist only for the call; nothing is saved.
+
+use crate::crypto::container;
+use crate::crypto::kdf::{KdfParams, PassphraseKdf, MIN_MEMORY_KIB, PASSPHRASE_SALT_LEN};
+use crate::crypto::nonce;
+use crate::crypto::EncryptedData;
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::KeyManager;
+use zeroize::Zeroize;
+
+/// Argon2id cost `encrypt` uses: the OWASP minimum, since the recipient may
+/// open the container on a slower machine than the one that made it
+pub const STRETCHING: KdfParams = KdfParams { memory_kib: MIN_MEMORY_KIB, iterations: 2, parallelism: 1 };
+
+/// Encrypt `plaintext` into a container that `passphrase` alone opens
+pub fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    encrypt_with(passphrase, plaintext, STRETCHING)
+}
+
+/// `encrypt` with the passphrase stretched under `params`
+pub fn encrypt_with(passphrase: &str, plaintext: &[u8], params: KdfParams) -> Result<Vec<u8>> {
+    let salt = rand::random::<[u8; PASSPHRASE_SALT_LEN]>();
+    nonce::checkout(&[], "passphrase-salt", &salt)?;
+    let kdf = PassphraseKdf { salt, params };
+    let key_manager = key_manager(passphrase, &kdf)?;
+    let encryptor = HybridGuardEncryptor::new();
+    let (file_keys, wrapped) = encryptor.new_file_keys(&key_manager)?;
+    encryptor
+        .encrypt(plaintext, &file_keys)?
+        .with_passphrase(kdf, &file_keys)
+        .with_wrapped_key(wrapped, &file_keys)
+        .with_key_id(key_manager.key_id())
+        .to_bytes()
+}
+
+/// Decrypt a container written by `encrypt`
+pub fn decrypt(passphrase: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+    decrypt_container(passphrase, &EncryptedData::from_bytes(bytes)?)
+}
+
+/// Decrypt an already parsed passphrase-only container
+/// Fails with `InvalidPassword` when the passphrase gives other keys than the
+/// container records, before any layer runs
+pub fn decrypt_container(passphrase: &str, encrypted: &EncryptedData) -> Result<Vec<u8>> {
+    Ok(decrypt_container_typed(passphrase, encrypted)?.0)
+}
+
+/// `decrypt_container`, with the content type sealed at encryption, if any
+pub fn decrypt_container_typed(passphrase: &str, encrypted: &EncryptedData) -> Result<(Vec<u8>, Option<String>)> {
+    let kdf = encrypted.passphrase().ok_or_else(|| {
+        HybridGuardError::InvalidInput("not a passphrase-only container; decrypt it with its key file".to_string())
+    })?;
+    let key_manager = key_manager(passphrase, kdf)?;
+    if encrypted.key_id() != Some(key_manager.key_id()) {
+        return Err(HybridGuardError::InvalidPassword);
+    }
+    let keys = key_manager.keys_for(encrypted)?;
+    let plaintext = HybridGuardEncryptor::new().decrypt(encrypted, &keys)?;
+    Ok((plaintext, encrypted.content_type(&keys)?))
+}
+
+/// Whether `bytes` is a container written by `encrypt`, read from its header
+pub fn is_passphrase_only(bytes: &[u8]) -> bool {
+    container::peek_header(bytes).is_ok_and(|header| header.passphrase.is_some())
+}
+
+/// The keys `passphrase` gives under `kdf`
+fn key_manager(passphrase: &str, kdf: &PassphraseKdf) -> Result<KeyManager> {
+    let mut master_key = kdf.master_key(passphrase)?;
+    let key_manager = KeyManager::from_master_key(&master_key);
+    master_key.zeroize();
+    key_manager
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "tangerine-Lathe-93-orbit";
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = encrypt(PASSPHRASE, b"meet at the usual place").unwrap();
+        assert!(is_passphrase_only(&bytes));
+        assert_eq!(decrypt(PASSPHRASE, &bytes).unwrap(), b"meet at the usual place");
+
+        let header = container::peek_header(&bytes).unwrap();
+        assert_eq!(header.format_version, container::FORMAT_VERSION);
+        assert_eq!(header.passphrase.unwrap().params, STRETCHING);
+
+        // A fresh salt each time, so the same passphrase never gives the same keys
+        let again = encrypt(PASSPHRASE, b"meet at the usual place").unwrap();
+        assert_ne!(container::peek_header(&again).unwrap().key_id, header.key_id);
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let bytes = encrypt(PASSPHRASE, b"payload").unwrap();
+        assert!(matches!(decrypt("tangerine-Lathe-93-orbiT", &bytes), Err(HybridGuardError::InvalidPassword)));
+    }
+
+    #[test]
+    fn test_weak_or_missing_stretching_refused() {
+        let weak = KdfParams { memory_kib: 1024, ..STRETCHING };
+        assert!(matches!(encrypt_with(PASSPHRASE, b"payload", weak), Err(HybridGuardError::InvalidInput(_))));
+
+        let key_manager = KeyManager::from_master_key(&[5u8; 32]).unwrap();
+        let encryptor = HybridGuardEncryptor::new();
+        let (file_keys, wrapped) = encryptor.new_file_keys(&key_manager).unwrap();
+        let keyed = encryptor.encrypt(b"payload", &file_keys).unwrap().with_wrapped_key(wrapped, &file_keys);
+        assert!(!is_passphrase_only(&keyed.to_bytes().unwrap()));
+        assert!(matches!(decrypt_container(PASSPHRASE, &keyed), Err(HybridGuardError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,514 @@
+// Hole-preserving encryption of sparse files such as VM disk images
+//
+// Layout (all integers little-endian):
+//   magic "HGSP", u16 format version, u32 header length,
+//   bincode header (logical length, data extent table, key ID, layer descriptors),
+//   then for each data extent: u64 ciphertext length, streamed ciphertext,
+//   then a 32-byte tag over everything before it
+//
+// Only data extents are read and encrypted; holes are recorded in the
+// extent table and recreated on decrypt with `set_len` and seeks. The tag
+// binds the extent table to the ciphertext, so forged or moved holes are
+// rejected before any output is written. Platforms without SEEK_DATA and
+// SEEK_HOLE fall back to a single extent covering the whole file.
+
+use crate::crypto::container::{le_u16, le_u32, le_u64, u16_at, u32_at, u64_at};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::KeyManager;
+use crate::layers::{self, LayerDescriptor};
+use crate::staging::{Contents, StagedFile};
+use crate::streaming::{StreamDecryptor, StreamEncryptor, DEFAULT_CHUNK_SIZE};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of every sparse ciphertext
+pub const MAGIC: [u8; 4] = *b"HGSP";
+
+/// Sparse format written by this build
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Bytes before the bincode header: magic, version, header length
+const PREFIX_LEN: usize = 4 + 2 + 4;
+
+/// Largest header accepted, enough for about a million extents
+const MAX_HEADER_LEN: usize = 32 * 1024 * 1024;
+
+/// A run of data bytes in the plaintext file; everything else is a hole
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    fn end(&self) -> Option<u64> {
+        self.offset.checked_add(self.len)
+    }
+}
+
+/// Metadata at the start of a sparse ciphertext
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseHeader {
+    /// Plaintext file length, holes included
+    pub logical_len: u64,
+    /// Data extents in file order
+    pub extents: Vec<Extent>,
+    pub key_id: String,
+    pub descriptors: Vec<LayerDescriptor>,
+    pub timestamp: u64,
+}
+
+impl SparseHeader {
+    /// Bytes of plaintext that are data rather than holes
+    pub fn data_len(&self) -> u64 {
+        self.extents.iter().map(|e| e.len).sum()
+    }
+
+    /// Extents must be non-empty, ordered, disjoint and inside the file
+    fn validate(&self) -> Result<()> {
+        let mut previous_end = 0u64;
+        for extent in &self.extents {
+            let end = extent.end().ok_or_else(|| invalid_table("extent overflows"))?;
+            if extent.len == 0 || extent.offset < previous_end || end > self.logical_len {
+                return Err(invalid_table("extents overlap or lie outside the file"));
+            }
+            previous_end = end;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `bytes` starts a sparse ciphertext
+pub fn is_sparse(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Data extents of `file`, or one extent for the whole file where holes cannot be found
+pub fn data_extents(file: &File) -> Result<Vec<Extent>> {
+    let len = file.metadata()?.len();
+    Ok(seek_extents(file, len).unwrap_or_else(|| dense_extents(len)))
+}
+
+fn dense_extents(len: u64) -> Vec<Extent> {
+    if len == 0 {
+        Vec::new()
+    } else {
+        vec![Extent { offset: 0, len }]
+    }
+}
+
+/// Walk the file with SEEK_DATA/SEEK_HOLE; None when the filesystem does not support it
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek_extents(file: &File, len: u64) -> Option<Vec<Extent>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let len = libc::off_t::try_from(len).ok()?;
+    let mut extents = Vec::new();
+    let mut pos: libc::off_t = 0;
+    while pos < len {
+        let data = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if data < 0 {
+            // ENXIO: nothing but hole from here to the end
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENXIO) => Some(extents),
+                _ => None,
+            };
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return None;
+        }
+        let end = hole.min(len);
+        if end > data {
+            extents.push(Extent { offset: data as u64, len: (end - data) as u64 });
+        }
+        pos = hole;
+    }
+    Some(extents)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn seek_extents(_file: &File, _len: u64) -> Option<Vec<Extent>> {
+    None
+}
+
+/// What a sparse encryption or decryption covered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SparseStats {
+    pub logical_len: u64,
+    /// Bytes actually read and encrypted, or decrypted and written
+    pub data_len: u64,
+    pub extents: usize,
+}
+
+fn build_header(source: &File, key_id: &str) -> Result<SparseHeader> {
+    Ok(SparseHeader {
+        logical_len: source.metadata()?.len(),
+        extents: data_extents(source)?,
+        key_id: key_id.to_string(),
+        descriptors: layers::current_descriptors(),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    })
+}
+
+fn serialize_header(header: &SparseHeader) -> Result<Vec<u8>> {
+    bincode::serialize(header).map_err(|e| HybridGuardError::Encryption(format!("sparse header: {}", e)))
+}
+
+/// Exact size of the sparse ciphertext `encrypt_file` would write for `input` right now
+pub fn estimate_output_size(input: &Path, key_id: &str) -> Result<u64> {
+    let header = build_header(&File::open(input)?, key_id)?;
+    let estimator = HybridGuardEncryptor::new();
+    let mut len = (PREFIX_LEN + serialize_header(&header)?.len() + TAG_LEN) as u64;
+    for extent in &header.extents {
+        len += 8 + estimator.estimate_output_size(to_usize(extent.len)?)? as u64;
+    }
+    Ok(len)
+}
+
+/// Encrypt the data extents of `input` into a sparse ciphertext at `output`
+pub fn encrypt_file(input: &Path, output: &Path, key_manager: &KeyManager) -> Result<SparseStats> {
+    encrypt_file_staged(input, output, key_manager, None, &WriteOptions::bulk())
+}
+
+/// `encrypt_file` staging the ciphertext in `temp_dir` until it is complete,
+/// then finishing it as `options` ask
+pub fn encrypt_file_staged(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    temp_dir: Option<&Path>,
+    options: &WriteOptions,
+) -> Result<SparseStats> {
+    let mut source = File::open(input)?;
+    let header = build_header(&source, key_manager.key_id())?;
+    let header_bytes = serialize_header(&header)?;
+
+    let keys = key_manager.get_keys()?;
+    let mut staged = StagedFile::create(output, temp_dir, Contents::Ciphertext)?.with_write_options(options.clone());
+    let mut out = TagWriter::new(BufWriter::new(staged.file()), keys);
+    out.write_all(&MAGIC)?;
+    out.write_all(&le_u16(FORMAT_VERSION))?;
+    out.write_all(&le_u32(header_bytes.len() as u32))?;
+    out.write_all(&header_bytes)?;
+
+    let pipeline = layers::registry();
+    let estimator = HybridGuardEncryptor::new();
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    for extent in &header.extents {
+        let expected = estimator.estimate_output_size(to_usize(extent.len)?)? as u64;
+        out.write_all(&le_u64(expected))?;
+
+        source.seek(SeekFrom::Start(extent.offset))?;
+        let mut reader = (&mut source).take(extent.len);
+        let mut encryptor = StreamEncryptor::new(&pipeline, keys)?;
+        let mut written = 0u64;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            let chunk = encryptor.update(&buf[..n])?;
+            written += chunk.len() as u64;
+            out.write_all(&chunk)?;
+        }
+        let rest = encryptor.finish()?;
+        written += rest.len() as u64;
+        out.write_all(&rest)?;
+
+        if written != expected {
+            return Err(HybridGuardError::Encryption(format!(
+                "extent at {} changed while it was read ({} of {} ciphertext bytes)",
+                extent.offset, written, expected
+            )));
+        }
+    }
+
+    let (mut writer, computed) = out.finish();
+    writer.write_all(&computed)?;
+    writer.flush()?;
+    drop(writer);
+    staged.commit()?;
+
+    Ok(SparseStats {
+        logical_len: header.logical_len,
+        data_len: header.data_len(),
+        extents: header.extents.len(),
+    })
+}
+
+/// Read and validate the header of a sparse ciphertext; does not check the tag
+pub fn read_header<R: Read>(reader: &mut R) -> Result<SparseHeader> {
+    let mut prefix = [0u8; PREFIX_LEN];
+    reader.read_exact(&mut prefix).map_err(|_| truncated())?;
+    if prefix[..4] != MAGIC {
+        return Err(HybridGuardError::UnsupportedFormat("not a sparse HybridGuard file".to_string()));
+    }
+    let version = u16_at(&prefix, 4);
+    if version != FORMAT_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "sparse format v{} is not supported (this build reads v{})",
+            version, FORMAT_VERSION
+        )));
+    }
+    let header_len = u32_at(&prefix, 6) as usize;
+    if header_len > MAX_HEADER_LEN {
+        return Err(invalid_table("header is too large"));
+    }
+
+    let mut header_bytes = vec![0u8; header_len];
+    reader.read_exact(&mut header_bytes).map_err(|_| truncated())?;
+    let header: SparseHeader = bincode::deserialize(&header_bytes).map_err(|_| invalid_table("unreadable header"))?;
+    header.validate()?;
+    Ok(header)
+}
+
+/// Decrypt a sparse ciphertext into `output`, recreating its holes
+/// The whole file is authenticated before anything is written
+pub fn decrypt_file(input: &Path, output: &Path, key_manager: &KeyManager) -> Result<SparseStats> {
+    decrypt_file_with(input, output, key_manager, &WriteOptions::bulk())
+}
+
+/// `decrypt_file` finishing the output as `options` ask
+pub fn decrypt_file_with(input: &Path, output: &Path, key_manager: &KeyManager, options: &WriteOptions) -> Result<SparseStats> {
+    let keys = key_manager.decryption_keys()?;
+    let mut source = BufReader::new(File::open(input)?);
+    let header = read_header(&mut source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "{} was encrypted with key {} but key {} is loaded",
+            input.display(),
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+    let estimator = HybridGuardEncryptor::new();
+    let expected_lens = header
+        .extents
+        .iter()
+        .map(|e| Ok(estimator.estimate_output_size(to_usize(e.len)?)? as u64))
+        .collect::<Result<Vec<_>>>()?;
+
+    // First pass: authenticate the header, extent table and every ciphertext
+    source.seek(SeekFrom::Start(0))?;
+    let mut tagged = TagReader::new(&mut source, keys);
+    let mut prefix = [0u8; PREFIX_LEN];
+    tagged.read_exact(&mut prefix).map_err(|_| truncated())?;
+    let header_len = u32_at(&prefix, 6) as u64;
+    io::copy(&mut (&mut tagged).take(header_len), &mut io::sink())?;
+    for &expected in &expected_lens {
+        let mut len = [0u8; 8];
+        tagged.read_exact(&mut len).map_err(|_| truncated())?;
+        if u64_at(&len, 0) != expected {
+            return Err(forged());
+        }
+        if io::copy(&mut (&mut tagged).take(expected), &mut io::sink())? != expected {
+            return Err(truncated());
+        }
+    }
+    let computed = tagged.finish();
+    let mut stored = [0u8; TAG_LEN];
+    source.read_exact(&mut stored).map_err(|_| truncated())?;
+    if !tag::tags_match(&computed, &stored) || source.read(&mut [0u8; 1])? != 0 {
+        return Err(forged());
+    }
+
+    // Second pass: decrypt each extent into place; holes come from set_len
+    source.seek(SeekFrom::Start(PREFIX_LEN as u64 + header_len))?;
+    let mut staged = StagedFile::create(output, None, Contents::Plaintext)?.with_write_options(options.clone());
+    write_extents(&mut source, staged.file(), &header, &expected_lens, keys)?;
+    staged.commit()?;
+
+    Ok(SparseStats {
+        logical_len: header.logical_len,
+        data_len: header.data_len(),
+        extents: header.extents.len(),
+    })
+}
+
+fn write_extents<R: Read>(
+    source: &mut R,
+    target: &mut File,
+    header: &SparseHeader,
+    expected_lens: &[u64],
+    keys: &LayerKeys,
+) -> Result<()> {
+    let pipeline = layers::registry();
+    target.set_len(header.logical_len)?;
+
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    for (extent, &ct_len) in header.extents.iter().zip(expected_lens) {
+        source.read_exact(&mut [0u8; 8])?;
+        target.seek(SeekFrom::Start(extent.offset))?;
+
+        let mut reader = (&mut *source).take(ct_len);
+        let mut decryptor = StreamDecryptor::new(&pipeline, keys, &header.descriptors)?;
+        let mut plain_len = 0u64;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            let plain = decryptor.update(&buf[..n])?;
+            plain_len += plain.len() as u64;
+            target.write_all(&plain)?;
+        }
+        let plain = decryptor.finish()?;
+        plain_len += plain.len() as u64;
+        target.write_all(&plain)?;
+
+        if plain_len != extent.len {
+            return Err(forged());
+        }
+    }
+    Ok(())
+}
+
+fn tag_hasher(keys: &LayerKeys) -> Sha3_256 {
+    tag::keyed_hasher(keys, KeyPurpose::Mac("sparse-tag"))
+}
+
+/// Writer that feeds everything it writes into the tag
+struct TagWriter<W: Write> {
+    inner: W,
+    hasher: Sha3_256,
+}
+
+impl<W: Write> TagWriter<W> {
+    fn new(inner: W, keys: &LayerKeys) -> Self {
+        Self { inner, hasher: tag_hasher(keys) }
+    }
+
+    fn finish(self) -> (W, [u8; TAG_LEN]) {
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for TagWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that feeds everything it reads into the tag
+struct TagReader<R: Read> {
+    inner: R,
+    hasher: Sha3_256,
+}
+
+impl<R: Read> TagReader<R> {
+    fn new(inner: R, keys: &LayerKeys) -> Self {
+        Self { inner, hasher: tag_hasher(keys) }
+    }
+
+    fn finish(self) -> [u8; TAG_LEN] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for TagReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn to_usize(len: u64) -> Result<usize> {
+    usize::try_from(len).map_err(|_| invalid_table("extent is too large for this platform"))
+}
+
+fn invalid_table(reason: &str) -> HybridGuardError {
+    HybridGuardError::Integrity(format!("sparse extent table is invalid: {}", reason))
+}
+
+fn truncated() -> HybridGuardError {
+    HybridGuardError::Integrity("sparse ciphertext is truncated".to_string())
+}
+
+fn forged() -> HybridGuardError {
+    HybridGuardError::Integrity("sparse ciphertext or extent table failed authentication".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn key_manager() -> KeyManager {
+        KeyManager::from_master_key(&[0x31; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_tables() {
+        let header = |extents: Vec<Extent>| SparseHeader {
+            logical_len: 100,
+            extents,
+            key_id: String::new(),
+            descriptors: Vec::new(),
+            timestamp: 0,
+        };
+        assert!(header(vec![Extent { offset: 0, len: 10 }, Extent { offset: 50, len: 50 }]).validate().is_ok());
+        assert!(header(vec![Extent { offset: 0, len: 10 }, Extent { offset: 5, len: 10 }]).validate().is_err());
+        assert!(header(vec![Extent { offset: 90, len: 20 }]).validate().is_err());
+        assert!(header(vec![Extent { offset: 10, len: 0 }]).validate().is_err());
+        assert!(header(vec![Extent { offset: u64::MAX, len: 2 }]).validate().is_err());
+    }
+
+    #[test]
+    fn test_forged_hole_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("disk.img");
+        let encrypted = dir.path().join("disk.img.hg");
+        let output = dir.path().join("disk.out");
+        let mut file = File::create(&input).unwrap();
+        file.write_all(&[0xAB; 5000]).unwrap();
+        drop(file);
+
+        let km = key_manager();
+        encrypt_file(&input, &encrypted, &km).unwrap();
+
+        // Grow the file by claiming a trailing hole; the header length stays the same
+        let mut bytes = fs::read(&encrypted).unwrap();
+        let header = read_header(&mut &bytes[..]).unwrap();
+        let mut forged_header = header.clone();
+        forged_header.logical_len += 1 << 20;
+        let forged_bytes = bincode::serialize(&forged_header).unwrap();
+        assert_eq!(forged_bytes.len(), bincode::serialize(&header).unwrap().len());
+        bytes[PREFIX_LEN..PREFIX_LEN + forged_bytes.len()].copy_from_slice(&forged_bytes);
+        fs::write(&encrypted, &bytes).unwrap();
+
+        assert!(matches!(decrypt_file(&encrypted, &output, &km), Err(HybridGuardError::Integrity(_))));
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_wrong_key_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("small");
+        let encrypted = dir.path().join("small.hg");
+        fs::write(&input, b"data").unwrap();
+
+        encrypt_file(&input, &encrypted, &key_manager()).unwrap();
+        let other = KeyManager::from_master_key(&[0x13; 32]).unwrap();
+        let result = decrypt_file(&encrypted, &dir.path().join("out"), &other);
+        assert!(matches!(result, Err(HybridGuardError::KeyMismatch(_))));
+    }
+}
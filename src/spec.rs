@@ -0,0 +1,239 @@
+// Machine-generated description of the file format
+// Built from the constants and layer types the implementation itself uses,
+// so `hybridguard spec` cannot drift from what this build reads and writes
+
+use crate::crypto::container::{self, BodyField};
+use crate::crypto::envelope::{FILE_KEY_LEN, WRAPPED_LEN};
+use crate::crypto::hkdf::{KdfScheme, LAYER_INFO_PREFIX, LAYER_KEY_LEN, V2_INFO_PREFIX, V2_SALT};
+use crate::crypto::tag::TAG_LEN;
+use crate::crypto::timestamp::DIGEST_LEN;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::{self, SecurityClass};
+use serde::Serialize;
+use std::fmt;
+
+/// Everything an independent implementation needs to read and write containers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FormatSpec {
+    pub container: ContainerSpec,
+
+    /// Layers in encryption order; decryption runs them in reverse
+    pub layers: Vec<LayerSpec>,
+
+    pub kdf: KdfSpec,
+
+    /// Container body fields, in the order they are written
+    pub metadata: Vec<BodyField>,
+}
+
+/// The outer container around the layered ciphertext
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContainerSpec {
+    pub magic: String,
+    pub format_version: u16,
+
+    /// Bytes of magic plus little-endian u16 format version
+    pub prefix_len: usize,
+
+    pub body_encoding: String,
+
+    /// Format versions this build decodes; version 0 has no prefix
+    pub readable_versions: Vec<u16>,
+
+    /// Readable versions this build no longer writes
+    pub read_only_versions: Vec<u16>,
+
+    pub tag_len: usize,
+    pub content_digest_len: usize,
+
+    /// Per-file key, stored AES-256-GCM wrapped under the profile keys
+    pub file_key_len: usize,
+    pub wrapped_key_len: usize,
+}
+
+/// One encryption layer and its framing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LayerSpec {
+    /// Identifier recorded in layer descriptors
+    pub id: String,
+
+    pub name: String,
+    pub format_version: u16,
+    pub readable_versions: Vec<u16>,
+    pub read_only_versions: Vec<u16>,
+
+    /// Most bytes the layer adds to its input
+    pub overhead_bytes: usize,
+
+    pub framing: String,
+    pub class: SecurityClass,
+    /// Machine-readable claims; see `EncryptionLayer::claims`
+    pub claims: String,
+}
+
+/// How layer keys come from the master key
+/// The scheme is recorded in the key file, not the container
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KdfSpec {
+    /// Scheme of newly generated key files
+    pub scheme: KdfScheme,
+    pub password_master_key: String,
+    pub layer_key: String,
+    /// Tag, file-key-wrap and other keys derived from all four layer keys
+    pub purpose_key: String,
+    pub layer_key_len: usize,
+    /// Key file schemes this build still reads, and how they derive layer keys
+    pub legacy: Vec<(KdfScheme, String)>,
+}
+
+impl FormatSpec {
+    /// Describe the formats this build writes and reads
+    pub fn current() -> Result<Self> {
+        let container = ContainerSpec {
+            magic: String::from_utf8_lossy(&container::MAGIC).into_owned(),
+            format_version: container::FORMAT_VERSION,
+            prefix_len: container::PREFIX_LEN,
+            body_encoding: container::BODY_ENCODING.to_string(),
+            readable_versions: container::SUPPORTED_VERSIONS.to_vec(),
+            read_only_versions: read_only(container::SUPPORTED_VERSIONS, container::FORMAT_VERSION),
+            tag_len: TAG_LEN,
+            content_digest_len: DIGEST_LEN,
+            file_key_len: FILE_KEY_LEN,
+            wrapped_key_len: WRAPPED_LEN,
+        };
+
+        let registry = layers::registry();
+        let mut layer_specs = Vec::with_capacity(registry.len());
+        for layer in &registry {
+            let descriptor = layer.descriptor();
+            let readable = layer.readable_versions();
+            layer_specs.push(LayerSpec {
+                read_only_versions: read_only(&readable, descriptor.version),
+                readable_versions: readable,
+                id: descriptor.name,
+                name: layer.name().to_string(),
+                format_version: descriptor.version,
+                overhead_bytes: layer.overhead_bytes()?,
+                framing: layer.framing().to_string(),
+                class: layer.security_class(),
+                claims: layer.claims().to_string(),
+            });
+        }
+
+        let salt = String::from_utf8_lossy(V2_SALT);
+        let kdf = KdfSpec {
+            scheme: KdfScheme::CURRENT,
+            password_master_key: "HKDF-SHA3-256-Extract(salt, password)".to_string(),
+            layer_key: format!(
+                "HKDF-SHA3-256(salt \"{}\", master_key, info \"{}layer <n>\") for layer n = 1..={}",
+                salt,
+                V2_INFO_PREFIX,
+                registry.len()
+            ),
+            purpose_key: format!(
+                "HKDF-SHA3-256(salt \"{}\", layer1 || layer2 || layer3 || layer4, info \"{}<purpose>\"), 32 bytes",
+                salt, V2_INFO_PREFIX
+            ),
+            layer_key_len: LAYER_KEY_LEN,
+            legacy: vec![(
+                KdfScheme::V1,
+                format!(
+                    "master key SHA3-256(password || salt); layer key SHA3-256(master_key || \"{}<n>\" || u8 n)",
+                    LAYER_INFO_PREFIX
+                ),
+            )],
+        };
+
+        Ok(Self {
+            container,
+            layers: layer_specs,
+            kdf,
+            metadata: container::BODY_SCHEMA.to_vec(),
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| HybridGuardError::InvalidInput(e.to_string()))
+    }
+}
+
+/// Versions in `readable` other than the one written
+fn read_only(readable: &[u16], written: u16) -> Vec<u16> {
+    readable.iter().copied().filter(|v| *v != written).collect()
+}
+
+fn version_list(versions: &[u16]) -> String {
+    if versions.is_empty() {
+        return "none".to_string();
+    }
+    versions.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for FormatSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = &self.container;
+        writeln!(f, "HybridGuard file format (container v{})", c.format_version)?;
+        writeln!(f)?;
+        writeln!(f, "Container")?;
+        writeln!(f, "  prefix:     \"{}\" magic, u16 LE format version ({} bytes)", c.magic, c.prefix_len)?;
+        writeln!(f, "  body:       {}", c.body_encoding)?;
+        writeln!(f, "  readable:   {}", version_list(&c.readable_versions))?;
+        writeln!(f, "  read-only:  {} (version 0 has no prefix)", version_list(&c.read_only_versions))?;
+        writeln!(f, "  tag:        {} bytes; content digest: {} bytes (SHA3-256)", c.tag_len, c.content_digest_len)?;
+        writeln!(f, "  file key:   {} bytes, AES-256-GCM wrapped to {} bytes", c.file_key_len, c.wrapped_key_len)?;
+        writeln!(f)?;
+        writeln!(f, "Metadata (body fields in order)")?;
+        for field in &self.metadata {
+            writeln!(f, "  {:<16} {:<48} since v{}", field.name, field.wire_type, field.since)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Layers (encryption order; decryption reverses it)")?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            writeln!(
+                f,
+                "  {}. {} v{}: {}, overhead up to {} bytes",
+                i + 1,
+                layer.id,
+                layer.format_version,
+                layer.class,
+                layer.overhead_bytes
+            )?;
+            writeln!(f, "     claims:    {}", layer.claims)?;
+            writeln!(f, "     framing:   {}", layer.framing)?;
+            writeln!(f, "     read-only: {}", version_list(&layer.read_only_versions))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Key derivation")?;
+        writeln!(f, "  scheme:     {}", self.kdf.scheme)?;
+        writeln!(f, "  master key: random 32 bytes, or {}", self.kdf.password_master_key)?;
+        writeln!(f, "  layer keys: {}", self.kdf.layer_key)?;
+        writeln!(f, "  other keys: {}", self.kdf.purpose_key)?;
+        write!(f, "  key length: {} bytes", self.kdf.layer_key_len)?;
+        for (scheme, description) in &self.kdf.legacy {
+            write!(f, "\n  read-only {}: {}", scheme, description)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_follows_the_code() {
+        let spec = FormatSpec::current().unwrap();
+        assert_eq!(spec.container.format_version, container::FORMAT_VERSION);
+        assert!(!spec.container.read_only_versions.contains(&container::FORMAT_VERSION));
+        assert_eq!(spec.layers.len(), layers::registry().len());
+        for (layer, descriptor) in spec.layers.iter().zip(layers::current_descriptors()) {
+            assert_eq!(layer.id, descriptor.name);
+            assert!(layer.readable_versions.contains(&layer.format_version));
+            assert!(!layer.read_only_versions.contains(&layer.format_version));
+        }
+
+        let text = spec.to_string();
+        assert!(text.contains("\"HGRD\""));
+        assert!(text.contains("content_digest"));
+    }
+}
@@ -0,0 +1,163 @@
+// Snapshot-consistent reads of files that may change while being encrypted
+// The size and mtime are taken before reading, exactly that many bytes are
+// read, and both are compared again afterwards. A file that grew, shrank or
+// was rewritten in between is read again, or refused once retries run out,
+// so a ciphertext never mixes old and new contents of a live file.
+
+use crate::crypto::SourceSnapshot;
+use crate::error::{HybridGuardError, Result};
+use crate::staging;
+use crate::warning::{self, Warning, WarningCode, Warnings};
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Rereads of a changing file before giving up
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Pause before each reread, so a burst of writes can settle
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// How `read_stable` reads its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StableRead {
+    /// Rereads after a change; 0 fails on the first change
+    pub retries: u32,
+
+    /// Copy the file into an unlinked scratch file first (copy_file_range on
+    /// Linux) and encrypt the copy, keeping the window for changes short
+    pub snapshot_copy: bool,
+}
+
+impl Default for StableRead {
+    fn default() -> Self {
+        Self { retries: DEFAULT_RETRIES, snapshot_copy: false }
+    }
+}
+
+/// Read all of `path` while it is unchanged, with the snapshot that was read
+pub fn read_stable(path: &Path, options: &StableRead) -> Result<(Vec<u8>, SourceSnapshot)> {
+    read_stable_with(path, options, None)
+}
+
+/// `read_stable`, raising a warning (HG005) in `warnings`, or the log, when
+/// the file changed and a later read succeeded
+pub fn read_stable_with(path: &Path, options: &StableRead, warnings: Option<&Warnings>) -> Result<(Vec<u8>, SourceSnapshot)> {
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            std::thread::sleep(RETRY_DELAY);
+        }
+        if let Some(read) = read_once(path, options.snapshot_copy)? {
+            if attempt > 0 {
+                warning::emit(warnings, changed(path, format!("it was read again {} time(s) until it held still", attempt)))?;
+            }
+            return Ok(read);
+        }
+    }
+    Err(HybridGuardError::SourceChangedDuringRead(format!(
+        "{} changed on each of {} read(s)",
+        path.display(),
+        options.retries + 1
+    )))
+}
+
+/// HG005 for `path`, which changed while it was read; `outcome` says what came of it
+pub fn changed(path: &Path, outcome: impl fmt::Display) -> Warning {
+    Warning::new(WarningCode::SourceChanged, format!("{} changed while it was read; {}", path.display(), outcome))
+        .with_context("path", path.display())
+}
+
+/// Size and mtime of a file as recorded in containers
+pub fn snapshot_of(metadata: &Metadata) -> io::Result<SourceSnapshot> {
+    // Times before the epoch are recorded as the epoch
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(SourceSnapshot { len: metadata.len(), modified_secs: modified.as_secs(), modified_nanos: modified.subsec_nanos() })
+}
+
+/// One read attempt; None when the file changed underneath it
+fn read_once(path: &Path, snapshot_copy: bool) -> Result<Option<(Vec<u8>, SourceSnapshot)>> {
+    let mut file = File::open(path)?;
+    let before = snapshot_of(&file.metadata()?)?;
+
+    let mut data = Vec::with_capacity(before.len as usize);
+    if snapshot_copy {
+        let mut copy = staging::scratch_file(&staging::parent_dir(path))?;
+        if copy_range(&mut file, &mut copy, before.len)? < before.len {
+            return Ok(None);
+        }
+        copy.seek(SeekFrom::Start(0))?;
+        copy.read_to_end(&mut data)?;
+    } else {
+        // A file that shrank comes up short
+        (&mut file).take(before.len).read_to_end(&mut data)?;
+        if (data.len() as u64) < before.len {
+            return Ok(None);
+        }
+    }
+
+    let after = snapshot_of(&file.metadata()?)?;
+    Ok((after == before).then_some((data, before)))
+}
+
+/// Copy up to `len` bytes from the start of `from`, returning how many were copied
+#[cfg(target_os = "linux")]
+fn copy_range(from: &mut File, to: &mut File, len: u64) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut copied = 0u64;
+    while copied < len {
+        let chunk = (len - copied).min(1 << 30) as usize;
+        let n = unsafe {
+            libc::copy_file_range(from.as_raw_fd(), std::ptr::null_mut(), to.as_raw_fd(), std::ptr::null_mut(), chunk, 0)
+        };
+        match n {
+            0 => break,
+            n if n > 0 => copied += n as u64,
+            // Not supported between these filesystems; copy through userspace
+            _ if copied == 0 => return io::copy(&mut (&mut *from).take(len), to),
+            _ => return Err(io::Error::last_os_error()),
+        }
+    }
+    Ok(copied)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range(from: &mut File, to: &mut File, len: u64) -> io::Result<u64> {
+    io::copy(&mut (&mut *from).take(len), to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_quiet_file_reads_with_its_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quiet.log");
+        fs::write(&path, b"settled contents").unwrap();
+        let expected = snapshot_of(&fs::metadata(&path).unwrap()).unwrap();
+
+        for snapshot_copy in [false, true] {
+            let options = StableRead { retries: 0, snapshot_copy };
+            let (data, snapshot) = read_stable(&path, &options).unwrap();
+            assert_eq!(data, b"settled contents");
+            assert_eq!(snapshot, expected);
+            assert_eq!(snapshot.len, 16);
+        }
+        // The scratch copy leaves nothing behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty");
+        fs::write(&path, b"").unwrap();
+        let (data, snapshot) = read_stable(&path, &StableRead::default()).unwrap();
+        assert!(data.is_empty());
+        assert_eq!(snapshot.len, 0);
+    }
+}
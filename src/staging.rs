@@ -0,0 +1,316 @@
+// Fail-closed temporary files for outputs
+// Every output is written to a temporary file and only moved onto its final
+// path once complete, so an error or panic never leaves a partial file under
+// the real name. Temporary files are owner-only on Unix. On Linux they are
+// opened with O_TMPFILE and have no name until committed, so not even a
+// killed process leaves one behind. Plaintext is always staged in its
+// output's directory; only ciphertext may be staged in a separate temp dir.
+// `commit` finishes the file as its `WriteOptions` ask: by default fsynced
+// before the rename and its directory fsynced after.
+
+use crate::crypto::codec;
+use crate::fsutil::WriteOptions;
+use crate::key_manager::permissions::PRIVATE_FILE_MODE;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Suffix of named temporary files, so directory walks can skip them
+pub const TEMP_SUFFIX: &str = ".hg-tmp";
+
+/// What a staged file holds, which decides where it may be staged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Contents {
+    /// May be staged in any temp directory
+    Ciphertext,
+    /// Staged in the output's directory only, whatever temp dir is configured
+    Plaintext,
+}
+
+/// An output being written; dropping it without `commit` removes it
+pub struct StagedFile {
+    file: File,
+    target: PathBuf,
+    dir: PathBuf,
+    /// None while the file is an unnamed O_TMPFILE
+    temp: Option<PathBuf>,
+    options: WriteOptions,
+}
+
+impl StagedFile {
+    /// Stage a file that `commit` moves to `target`
+    /// `temp_dir` defaults to the target's directory and is ignored for plaintext
+    pub fn create(target: &Path, temp_dir: Option<&Path>, contents: Contents) -> io::Result<Self> {
+        let dir = match (temp_dir, contents) {
+            (Some(dir), Contents::Ciphertext) => dir.to_path_buf(),
+            _ => parent_dir(target),
+        };
+        let (file, temp) = match open_unnamed(&dir) {
+            Some(file) => (file, None),
+            None => {
+                let (file, temp) = create_named(&dir, target)?;
+                (file, Some(temp))
+            }
+        };
+        Ok(Self { file, target: target.to_path_buf(), dir, temp, options: WriteOptions::default() })
+    }
+
+    /// Finish the file on commit as `options` ask, instead of `WriteOptions::bulk`
+    pub fn with_write_options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The temporary file, for writing the output
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Directory the temporary file lives in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Name of the temporary file; None for an unnamed O_TMPFILE
+    pub fn temp_path(&self) -> Option<&Path> {
+        self.temp.as_deref()
+    }
+
+    /// Finish the contents and move them onto the target, replacing any file there
+    pub fn commit(mut self) -> io::Result<()> {
+        self.options.finish_file(&mut self.file, &self.target)?;
+        let temp = match self.temp.clone() {
+            Some(temp) => temp,
+            None => match link_unnamed(&self.file, &self.dir, &self.target) {
+                Ok(temp) => {
+                    self.temp = Some(temp.clone());
+                    temp
+                }
+                // Without /proc an unnamed file cannot be linked; copy it instead
+                Err(_) => return self.copy_to_target(),
+            },
+        };
+        match fs::rename(&temp, &self.target) {
+            Ok(()) => {
+                self.temp = None;
+                self.options.finish_entry(&self.target)
+            }
+            // A temp dir on another filesystem cannot be renamed across
+            Err(_) if self.dir != parent_dir(&self.target) => self.copy_to_target(),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Copy the contents to a new temporary file beside the target and rename that
+    fn copy_to_target(&mut self) -> io::Result<()> {
+        let (mut copy, path) = create_named(&parent_dir(&self.target), &self.target)?;
+        let copied = self
+            .file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| io::copy(&mut self.file, &mut copy))
+            .and_then(|_| self.options.finish_file(&mut copy, &self.target))
+            .and_then(|()| fs::rename(&path, &self.target));
+        if copied.is_err() {
+            let _ = fs::remove_file(&path);
+        }
+        copied.and_then(|()| self.options.finish_entry(&self.target))
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if let Some(temp) = &self.temp {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+/// An owner-only scratch file in `dir` that has no name, or loses it at once
+pub(crate) fn scratch_file(dir: &Path) -> io::Result<File> {
+    if let Some(file) = open_unnamed(dir) {
+        return Ok(file);
+    }
+    let (file, path) = create_named(dir, Path::new("scratch"))?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Directory of `path`, `.` for a bare file name
+pub(crate) fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Random hidden name in `dir` derived from the target's file name
+fn temp_name(dir: &Path, target: &Path) -> PathBuf {
+    let nonce = codec::hex_lower(&rand::random::<[u8; 8]>());
+    let mut name = std::ffi::OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    name.push(format!(".{}{}", nonce, TEMP_SUFFIX));
+    dir.join(name)
+}
+
+/// Create a new owner-only temporary file with a name
+fn create_named(dir: &Path, target: &Path) -> io::Result<(File, PathBuf)> {
+    let path = temp_name(dir, target);
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(PRIVATE_FILE_MODE);
+    }
+    Ok((options.open(&path)?, path))
+}
+
+/// Open an owner-only O_TMPFILE in `dir`; None where the filesystem has none
+#[cfg(target_os = "linux")]
+fn open_unnamed(dir: &Path) -> Option<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .mode(PRIVATE_FILE_MODE)
+        .custom_flags(libc::O_TMPFILE)
+        .open(dir)
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_unnamed(_dir: &Path) -> Option<File> {
+    None
+}
+
+/// Give an O_TMPFILE a temporary name in `dir`, ready to rename
+#[cfg(target_os = "linux")]
+fn link_unnamed(file: &File, dir: &Path, target: &Path) -> io::Result<PathBuf> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let path = temp_name(dir, target);
+    let from = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+    let to = CString::new(path.as_os_str().as_bytes())?;
+    let linked = unsafe { libc::linkat(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::AT_SYMLINK_FOLLOW) };
+    if linked != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link_unnamed(_file: &File, _dir: &Path, _target: &Path) -> io::Result<PathBuf> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_commit_replaces_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.hg");
+        fs::write(&target, b"old").unwrap();
+
+        let mut staged = StagedFile::create(&target, None, Contents::Ciphertext).unwrap();
+        staged.file().write_all(b"new contents").unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        staged.commit().unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"new contents");
+        assert_eq!(entries(dir.path()), ["out.hg"]);
+    }
+
+    #[test]
+    fn test_dropped_file_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.txt");
+        {
+            let mut staged = StagedFile::create(&target, None, Contents::Plaintext).unwrap();
+            staged.file().write_all(b"half a plaintext").unwrap();
+        }
+        assert!(entries(dir.path()).is_empty());
+
+        // Unwinding drops it too
+        let result = std::panic::catch_unwind(|| {
+            let mut staged = StagedFile::create(&target, None, Contents::Plaintext).unwrap();
+            staged.file().write_all(b"half a plaintext").unwrap();
+            panic!("interrupted");
+        });
+        assert!(result.is_err());
+        assert!(entries(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_plaintext_ignores_temp_dir() {
+        let out = tempfile::tempdir().unwrap();
+        let shared = tempfile::tempdir().unwrap();
+        let target = out.path().join("secret.txt");
+
+        let plain = StagedFile::create(&target, Some(shared.path()), Contents::Plaintext).unwrap();
+        assert_eq!(plain.dir(), out.path());
+        let mut cipher = StagedFile::create(&target, Some(shared.path()), Contents::Ciphertext).unwrap();
+        assert_eq!(cipher.dir(), shared.path());
+        cipher.file().write_all(b"sealed").unwrap();
+        cipher.commit().unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"sealed");
+        assert!(entries(shared.path()).is_empty());
+    }
+
+    #[test]
+    fn test_commit_finishes_the_file_as_asked() {
+        use crate::fsutil::{DurabilityLevel, RecordingSyncer};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let matrix: [(DurabilityLevel, &[&str]); 4] = [
+            (DurabilityLevel::None, &[]),
+            (DurabilityLevel::Flush, &["flush"]),
+            (DurabilityLevel::Fsync, &["flush", "sync_file"]),
+            (DurabilityLevel::FsyncDir, &["flush", "sync_file", "sync_dir"]),
+        ];
+        for (level, expected) in matrix {
+            let syncer = Arc::new(RecordingSyncer::default());
+            let target = dir.path().join(format!("{}.hg", level));
+            let options = WriteOptions::new(level).with_syncer(syncer.clone());
+            let mut staged = StagedFile::create(&target, None, Contents::Ciphertext).unwrap().with_write_options(options);
+            staged.file().write_all(b"sealed").unwrap();
+            staged.commit().unwrap();
+            assert_eq!(syncer.calls_for(&target), expected, "{}", level);
+        }
+
+        // Unless lowered, the file is synced before its rename and its directory after
+        let syncer = Arc::new(RecordingSyncer::default());
+        let target = dir.path().join("default.hg");
+        let mut staged = StagedFile::create(&target, None, Contents::Ciphertext).unwrap().with_write_options(WriteOptions::default().with_syncer(syncer.clone()));
+        staged.file().write_all(b"sealed").unwrap();
+        staged.commit().unwrap();
+        assert_eq!(syncer.calls_for(&target), ["flush", "sync_file", "sync_dir"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_temp_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.txt");
+        let mut staged = StagedFile::create(&target, None, Contents::Plaintext).unwrap();
+        assert_eq!(staged.file().metadata().unwrap().permissions().mode() & 0o777, 0o600);
+
+        let (_, named) = create_named(dir.path(), &target).unwrap();
+        assert_eq!(fs::metadata(&named).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(named.to_string_lossy().ends_with(TEMP_SUFFIX));
+    }
+}
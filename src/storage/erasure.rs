@@ -0,0 +1,402 @@
+// Reed-Solomon sharding of finished containers for bit-rot resilience
+//
+// Layout (all integers little-endian):
+//   header: magic "HGRS", u16 format version, u16 data shards (k),
+//           u16 parity shards (m), u64 shard length, u64 payload length,
+//           first 8 bytes of SHA3-256 over the preceding header bytes
+//   then k + m shards: u16 shard index, SHA3-256 of (index || data), data
+//   then the header again (format v2)
+//
+// Any k intact shards rebuild the payload. A shard is damaged when its
+// index or checksum does not match, or when the file ends before it. The
+// header is read from the front, or from the copy at the end when the front
+// one fails its checksum, so one damaged header does not strand the shards.
+
+use crate::crypto::container::{le_u16, le_u64, u16_at, u64_at};
+use crate::error::{HybridGuardError, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Magic bytes at the start of every sharded container
+pub const MAGIC: [u8; 4] = *b"HGRS";
+
+/// Sharded container format written by this build
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Header length in bytes
+pub const HEADER_LEN: usize = 4 + 2 + 2 + 2 + 8 + 8 + HEADER_CHECKSUM_LEN;
+
+/// Bytes of the header checksum
+const HEADER_CHECKSUM_LEN: usize = 8;
+
+/// Per-shard overhead: index plus checksum
+pub const SHARD_PREFIX_LEN: usize = 2 + 32;
+
+/// Most shards GF(2^8) Reed-Solomon supports
+const MAX_SHARDS: usize = 256;
+
+/// Number of data and parity shards, written `k+m` on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Redundancy {
+    /// Shards the payload is split into (k)
+    pub data_shards: usize,
+    /// Extra shards; up to this many may be lost (m)
+    pub parity_shards: usize,
+}
+
+impl Redundancy {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self> {
+        if data_shards == 0 || parity_shards == 0 {
+            return Err(HybridGuardError::InvalidInput(
+                "redundancy needs at least one data and one parity shard".to_string(),
+            ));
+        }
+        if data_shards + parity_shards > MAX_SHARDS {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "redundancy supports at most {} shards in total, got {}",
+                MAX_SHARDS,
+                data_shards + parity_shards
+            )));
+        }
+        Ok(Self { data_shards, parity_shards })
+    }
+
+    fn total(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+}
+
+impl FromStr for Redundancy {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |part: &str| part.trim().parse::<usize>().ok();
+        match s.split_once('+') {
+            Some((k, m)) => match (parse(k), parse(m)) {
+                (Some(k), Some(m)) => Self::new(k, m),
+                _ => Err(HybridGuardError::InvalidInput(format!("invalid redundancy '{}', expected k+m", s))),
+            },
+            None => Err(HybridGuardError::InvalidInput(format!("invalid redundancy '{}', expected k+m", s))),
+        }
+    }
+}
+
+impl fmt::Display for Redundancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", self.data_shards, self.parity_shards)
+    }
+}
+
+/// Shard layout read from a sharded container header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardLayout {
+    pub redundancy: Redundancy,
+    /// Bytes of payload in each shard
+    pub shard_len: usize,
+    /// Payload length before padding to whole shards
+    pub payload_len: usize,
+}
+
+/// Payload rebuilt from a sharded container
+pub struct Recovered {
+    pub payload: Vec<u8>,
+    pub layout: ShardLayout,
+    /// Indices of shards that failed their checks and were reconstructed
+    pub damaged: Vec<usize>,
+}
+
+/// Whether `bytes` is a sharded container, by either copy of its header
+pub fn is_sharded(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC) || trailer(bytes).is_some_and(|header| parse_header(header).is_ok())
+}
+
+/// Exact size of the sharded encoding of a `payload_len`-byte payload
+pub fn encoded_len(payload_len: usize, redundancy: Redundancy) -> usize {
+    2 * HEADER_LEN + redundancy.total() * (SHARD_PREFIX_LEN + shard_len(payload_len, redundancy))
+}
+
+/// Split `payload` into checksummed data and parity shards
+pub fn encode(payload: &[u8], redundancy: Redundancy) -> Result<Vec<u8>> {
+    let rs = reed_solomon(redundancy)?;
+    let layout = ShardLayout {
+        redundancy,
+        shard_len: shard_len(payload.len(), redundancy),
+        payload_len: payload.len(),
+    };
+
+    let mut shards: Vec<Vec<u8>> = (0..redundancy.total())
+        .map(|i| {
+            let start = (i * layout.shard_len).min(payload.len());
+            let end = ((i + 1) * layout.shard_len).min(payload.len());
+            let mut shard = if i < redundancy.data_shards { payload[start..end].to_vec() } else { Vec::new() };
+            shard.resize(layout.shard_len, 0);
+            shard
+        })
+        .collect();
+    rs.encode(&mut shards)
+        .map_err(|e| HybridGuardError::Encryption(format!("erasure coding failed: {}", e)))?;
+
+    let header = encode_header(&layout);
+    let mut out = Vec::with_capacity(encoded_len(payload.len(), redundancy));
+    out.extend_from_slice(&header);
+    for (index, shard) in shards.iter().enumerate() {
+        out.extend_from_slice(&le_u16(index as u16));
+        out.extend_from_slice(&shard_checksum(index as u16, shard));
+        out.extend_from_slice(shard);
+    }
+    out.extend_from_slice(&header);
+    Ok(out)
+}
+
+/// Read the header of a sharded container, from its copy at the end when
+/// the one at the front is damaged
+pub fn read_layout(bytes: &[u8]) -> Result<ShardLayout> {
+    if !is_sharded(bytes) {
+        return Err(HybridGuardError::UnsupportedFormat("not a sharded container".to_string()));
+    }
+    let front = bytes
+        .get(..HEADER_LEN)
+        .ok_or_else(|| HybridGuardError::Integrity("sharded container header is truncated".to_string()))
+        .and_then(parse_header);
+    match front {
+        Err(HybridGuardError::Integrity(reason)) => match trailer(bytes).map(parse_header) {
+            Some(Ok(layout)) => {
+                log::warn!("{}; read the copy at the end of the file", reason);
+                Ok(layout)
+            }
+            _ => Err(HybridGuardError::Integrity(reason)),
+        },
+        front => front,
+    }
+}
+
+/// The copy of the header at the end of `bytes`, if it is long enough for one
+fn trailer(bytes: &[u8]) -> Option<&[u8]> {
+    bytes.len().checked_sub(HEADER_LEN).map(|start| &bytes[start..])
+}
+
+/// Parse one copy of the header
+fn parse_header(header: &[u8]) -> Result<ShardLayout> {
+    let (fields, checksum) = header.split_at(HEADER_LEN - HEADER_CHECKSUM_LEN);
+    if !fields.starts_with(&MAGIC) || checksum != &header_checksum(fields)[..] {
+        return Err(HybridGuardError::Integrity("sharded container header is damaged".to_string()));
+    }
+
+    let version = u16_at(fields, 4);
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(HybridGuardError::UnsupportedFormat(format!("sharded container format version {}", version)));
+    }
+    let redundancy = Redundancy::new(u16_at(fields, 6) as usize, u16_at(fields, 8) as usize)?;
+    let layout = ShardLayout {
+        redundancy,
+        shard_len: usize::try_from(u64_at(fields, 10))
+            .map_err(|_| HybridGuardError::Integrity("shard length out of range".to_string()))?,
+        payload_len: usize::try_from(u64_at(fields, 18))
+            .map_err(|_| HybridGuardError::Integrity("payload length out of range".to_string()))?,
+    };
+    if layout.shard_len != shard_len(layout.payload_len, redundancy) {
+        return Err(HybridGuardError::Integrity("sharded container header is inconsistent".to_string()));
+    }
+    Ok(layout)
+}
+
+/// Rebuild the payload from any `k` intact shards
+pub fn decode(bytes: &[u8]) -> Result<Recovered> {
+    let layout = read_layout(bytes)?;
+    let redundancy = layout.redundancy;
+    // Saturating: a bogus length simply finds no shards in the file
+    let slot_len = SHARD_PREFIX_LEN.saturating_add(layout.shard_len);
+
+    let mut damaged = Vec::new();
+    let mut shards: Vec<Option<Vec<u8>>> = (0..redundancy.total())
+        .map(|index| {
+            let start = HEADER_LEN.saturating_add(index.saturating_mul(slot_len));
+            let shard = bytes.get(start..start.saturating_add(slot_len)).and_then(|slot| {
+                let (prefix, data) = slot.split_at(SHARD_PREFIX_LEN);
+                let stored_index = u16_at(prefix, 0);
+                let intact = stored_index as usize == index && prefix[2..] == shard_checksum(stored_index, data)[..];
+                intact.then(|| data.to_vec())
+            });
+            if shard.is_none() {
+                damaged.push(index);
+            }
+            shard
+        })
+        .collect();
+
+    if damaged.len() > redundancy.parity_shards {
+        return Err(HybridGuardError::Integrity(format!(
+            "unrecoverable: {} of {} shards are damaged (shards {}), at most {} can be repaired",
+            damaged.len(),
+            redundancy.total(),
+            list(&damaged),
+            redundancy.parity_shards
+        )));
+    }
+    if !damaged.is_empty() {
+        reed_solomon(redundancy)?
+            .reconstruct_data(&mut shards)
+            .map_err(|e| HybridGuardError::Integrity(format!("shard reconstruction failed: {}", e)))?;
+    }
+
+    let mut payload = Vec::with_capacity(redundancy.data_shards * layout.shard_len);
+    for shard in shards.into_iter().take(redundancy.data_shards) {
+        payload.extend(shard.ok_or_else(|| HybridGuardError::Integrity("data shard missing after reconstruction".to_string()))?);
+    }
+    payload.truncate(layout.payload_len);
+
+    Ok(Recovered { payload, layout, damaged })
+}
+
+fn reed_solomon(redundancy: Redundancy) -> Result<ReedSolomon> {
+    ReedSolomon::new(redundancy.data_shards, redundancy.parity_shards)
+        .map_err(|e| HybridGuardError::InvalidInput(format!("redundancy {}: {}", redundancy, e)))
+}
+
+fn shard_len(payload_len: usize, redundancy: Redundancy) -> usize {
+    payload_len.div_ceil(redundancy.data_shards).max(1)
+}
+
+fn encode_header(layout: &ShardLayout) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&le_u16(FORMAT_VERSION));
+    header.extend_from_slice(&le_u16(layout.redundancy.data_shards as u16));
+    header.extend_from_slice(&le_u16(layout.redundancy.parity_shards as u16));
+    header.extend_from_slice(&le_u64(layout.shard_len as u64));
+    header.extend_from_slice(&le_u64(layout.payload_len as u64));
+    let checksum = header_checksum(&header);
+    header.extend_from_slice(&checksum);
+    header
+}
+
+fn header_checksum(fields: &[u8]) -> [u8; HEADER_CHECKSUM_LEN] {
+    let digest = Sha3_256::digest(fields);
+    let mut checksum = [0u8; HEADER_CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest[..HEADER_CHECKSUM_LEN]);
+    checksum
+}
+
+fn shard_checksum(index: u16, data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(le_u16(index));
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Comma-separated shard indices for error messages
+pub fn list(indices: &[usize]) -> String {
+    indices.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        (0..10_007u32).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn corrupt_shard(encoded: &mut [u8], layout: &ShardLayout, index: usize) {
+        let slot = HEADER_LEN + index * (SHARD_PREFIX_LEN + layout.shard_len);
+        encoded[slot + SHARD_PREFIX_LEN + layout.shard_len / 2] ^= 0xFF;
+    }
+
+    #[test]
+    fn test_parse_redundancy() {
+        assert_eq!("10+4".parse::<Redundancy>().unwrap(), Redundancy::new(10, 4).unwrap());
+        assert!("10".parse::<Redundancy>().is_err());
+        assert!("0+4".parse::<Redundancy>().is_err());
+        assert!("10+0".parse::<Redundancy>().is_err());
+        assert!("200+100".parse::<Redundancy>().is_err());
+        assert_eq!(Redundancy::new(3, 2).unwrap().to_string(), "3+2");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let redundancy = Redundancy::new(10, 4).unwrap();
+        let encoded = encode(&payload(), redundancy).unwrap();
+        assert_eq!(encoded.len(), encoded_len(payload().len(), redundancy));
+        assert!(is_sharded(&encoded));
+
+        let recovered = decode(&encoded).unwrap();
+        assert_eq!(recovered.payload, payload());
+        assert!(recovered.damaged.is_empty());
+    }
+
+    #[test]
+    fn test_recovers_up_to_m_damaged_shards() {
+        let redundancy = Redundancy::new(10, 4).unwrap();
+        let clean = encode(&payload(), redundancy).unwrap();
+        let layout = read_layout(&clean).unwrap();
+
+        for bad in [vec![0], vec![3, 11], vec![0, 1, 2, 3], vec![9, 10, 12, 13]] {
+            let mut encoded = clean.clone();
+            for &index in &bad {
+                corrupt_shard(&mut encoded, &layout, index);
+            }
+            let recovered = decode(&encoded).unwrap();
+            assert_eq!(recovered.payload, payload());
+            assert_eq!(recovered.damaged, bad);
+        }
+    }
+
+    #[test]
+    fn test_too_many_damaged_shards() {
+        let redundancy = Redundancy::new(10, 4).unwrap();
+        let mut encoded = encode(&payload(), redundancy).unwrap();
+        let layout = read_layout(&encoded).unwrap();
+        for index in [1, 4, 6, 8, 13] {
+            corrupt_shard(&mut encoded, &layout, index);
+        }
+
+        match decode(&encoded) {
+            Err(HybridGuardError::Integrity(message)) => {
+                assert!(message.contains("unrecoverable"));
+                assert!(message.contains("shards 1, 4, 6, 8, 13"));
+            }
+            other => panic!("expected an integrity error, got {:?}", other.map(|r| r.damaged)),
+        }
+    }
+
+    #[test]
+    fn test_truncated_file_loses_trailing_shards() {
+        let redundancy = Redundancy::new(4, 2).unwrap();
+        let encoded = encode(&payload(), redundancy).unwrap();
+        let layout = read_layout(&encoded).unwrap();
+
+        // Cut into the last shard: it counts as damaged and is rebuilt
+        let truncated = &encoded[..encoded.len() - layout.shard_len / 2];
+        let recovered = decode(truncated).unwrap();
+        assert_eq!(recovered.payload, payload());
+        assert_eq!(recovered.damaged, vec![5]);
+    }
+
+    #[test]
+    fn test_damaged_header_detected() {
+        let mut encoded = encode(&payload(), Redundancy::new(4, 2).unwrap()).unwrap();
+        let trailer = encoded.len() - HEADER_LEN;
+        encoded[8] ^= 0x01;
+        encoded[trailer + 8] ^= 0x01;
+        assert!(matches!(decode(&encoded), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_either_header_copy_is_enough() {
+        let redundancy = Redundancy::new(4, 2).unwrap();
+        let clean = encode(&payload(), redundancy).unwrap();
+        let trailer = clean.len() - HEADER_LEN;
+
+        // Magic included: a file is still found by the copy at its end
+        for at in [0, 8, HEADER_LEN - 1, trailer, trailer + 8] {
+            let mut encoded = clean.clone();
+            encoded[at] ^= 0x01;
+            assert!(is_sharded(&encoded), "byte {}", at);
+            let recovered = decode(&encoded).unwrap();
+            assert_eq!(recovered.payload, payload(), "byte {}", at);
+            assert!(recovered.damaged.is_empty(), "byte {}", at);
+        }
+    }
+}
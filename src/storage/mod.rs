@@ -0,0 +1,4 @@
+// Storage-level encodings applied to finished containers
+// Independent of the crypto layers: they protect the bytes on disk, not their secrecy
+
+pub mod erasure;
@@ -0,0 +1,490 @@
+// `std::io` adapters for encrypted streams of unknown length
+//
+// Layout (all integers little-endian):
+//   magic "HGST", u16 format version, u32 header length,
+//   bincode header (segment length, key ID, layer descriptors, stream ID),
+//   then segments, each: u8 flag (0 = more follow, 1 = final),
+//   u32 ciphertext length, ciphertext, 32-byte tag
+//
+// Each segment is an independent pipeline message over `segment_len`
+// plaintext bytes; only the final one may be shorter. Every tag chains the
+// previous one and covers the flag, so the reader releases a segment only
+// once it is authenticated, and a stream that stops before its final segment
+// (a writer dropped without `finish`, a cut connection) is rejected as
+// truncated rather than decrypting to a shorter plaintext.
+
+use crate::crypto::container::{le_u16, le_u32, u16_at, u32_at};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::error::{HybridGuardError, Result};
+use crate::hybridguard::HybridGuard;
+use crate::layers::{self, EncryptionLayer, LayerDescriptor};
+use crate::streaming::{StreamDecryptor, StreamEncryptor, DEFAULT_CHUNK_SIZE};
+use serde::{Deserialize, Serialize};
+use sha3::Digest;
+use std::io::{self, Read, Write};
+
+/// Magic bytes at the start of every adapter stream
+pub const MAGIC: [u8; 4] = *b"HGST";
+
+/// Stream format written by this build
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Plaintext bytes per segment unless the caller asks otherwise (1 MiB)
+/// Every segment carries a full pipeline overhead of about 15 KiB
+pub const DEFAULT_SEGMENT_LEN: usize = 16 * DEFAULT_CHUNK_SIZE;
+
+/// Largest segment accepted, which bounds what the reader buffers (64 MiB)
+pub const MAX_SEGMENT_LEN: usize = 1024 * DEFAULT_CHUNK_SIZE;
+
+/// Bytes before the bincode header: magic, version, header length
+const PREFIX_LEN: usize = 4 + 2 + 4;
+
+/// Bytes before each segment's ciphertext: flag, ciphertext length
+const SEGMENT_HEAD_LEN: usize = 1 + 4;
+
+/// Largest header accepted
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// Purpose of the segment tag chain key
+const TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("stream-tag");
+
+const FLAG_MORE: u8 = 0;
+const FLAG_FINAL: u8 = 1;
+
+/// Metadata at the start of an adapter stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamHeader {
+    /// Plaintext bytes per segment; only the final segment may be shorter
+    pub segment_len: u32,
+    pub key_id: String,
+    pub descriptors: Vec<LayerDescriptor>,
+    /// Random per stream, so segments cannot be spliced between streams
+    pub stream_id: [u8; 16],
+}
+
+impl StreamHeader {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let body = bincode::serialize(self).map_err(|e| HybridGuardError::Encryption(format!("stream header: {}", e)))?;
+        let mut out = Vec::with_capacity(PREFIX_LEN + body.len());
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&le_u16(FORMAT_VERSION));
+        out.extend_from_slice(&le_u32(body.len() as u32));
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+}
+
+/// Whether `bytes` starts an adapter stream
+pub fn is_stream(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Encrypting `Write` adapter
+///
+/// Plaintext is buffered up to one segment and encrypted as a unit once the
+/// segment is full and more data arrives. Whatever `inner` does not take
+/// of a sealed segment is retried by the next `write`, `flush` or `finish`,
+/// and no plaintext is accepted until it is fully written, so a slow or
+/// non-blocking `inner` (a socket returning `WouldBlock`) holds at most one
+/// segment of each in memory, and a failed `write` consumed none of its input.
+///
+/// `flush` only pushes out sealed segments; a partial segment is encrypted
+/// by `finish` (or `try_finish`), which writes the final segment. Dropping the writer without
+/// `finish` leaves a stream with no final segment, which every reader
+/// rejects as truncated.
+pub struct HybridGuardWriter<'a, W: Write> {
+    hg: &'a HybridGuard,
+    inner: W,
+    pipeline: Vec<Box<dyn EncryptionLayer>>,
+    segment_len: usize,
+    plain: Vec<u8>,
+    /// Sealed bytes not yet accepted by `inner`, and how many of them it took
+    pending: Vec<u8>,
+    written: usize,
+    chained: [u8; TAG_LEN],
+    finished: bool,
+}
+
+impl<'a, W: Write> HybridGuardWriter<'a, W> {
+    /// Encrypt into `inner` in segments of `DEFAULT_SEGMENT_LEN`
+    pub fn new(inner: W, hg: &'a HybridGuard) -> Result<Self> {
+        Self::with_segment_len(inner, hg, DEFAULT_SEGMENT_LEN)
+    }
+
+    /// Encrypt into `inner` in segments of `segment_len` plaintext bytes
+    /// Smaller segments lower latency and memory at about 15 KiB overhead each
+    pub fn with_segment_len(inner: W, hg: &'a HybridGuard, segment_len: usize) -> Result<Self> {
+        if segment_len == 0 || segment_len > MAX_SEGMENT_LEN {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "segment length must be between 1 and {} bytes",
+                MAX_SEGMENT_LEN
+            )));
+        }
+        let header = StreamHeader {
+            segment_len: segment_len as u32,
+            key_id: hg.key_manager().key_id().to_string(),
+            descriptors: hg.descriptors(),
+            stream_id: rand::random(),
+        };
+        let encoded = header.encode()?;
+        let chained = chain_start(hg.key_manager().get_keys()?, &encoded);
+        Ok(Self {
+            hg,
+            inner,
+            pipeline: layers::registry(),
+            segment_len,
+            plain: Vec::with_capacity(segment_len),
+            pending: encoded,
+            written: 0,
+            chained,
+            finished: false,
+        })
+    }
+
+    /// Underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Encrypt whatever is buffered as the final segment, write everything
+    /// out, and return the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.try_finish()?;
+        Ok(self.inner)
+    }
+
+    /// `finish` for a non-blocking `inner`: call again after `WouldBlock`
+    /// until it succeeds; no more plaintext is accepted after the first call
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.drain()?;
+        if !self.finished {
+            self.seal(FLAG_FINAL)?;
+            self.finished = true;
+            self.drain()?;
+        }
+        self.inner.flush()
+    }
+
+    /// Encrypt the buffered plaintext into `pending` as one segment
+    fn seal(&mut self, flag: u8) -> Result<()> {
+        let keys = self.hg.key_manager().get_keys()?;
+        let mut encryptor = StreamEncryptor::new(&self.pipeline, keys)?;
+        let mut ciphertext = encryptor.update(&self.plain)?;
+        ciphertext.extend(encryptor.finish()?);
+        self.plain.clear();
+
+        let tag = segment_tag(keys, &self.chained, flag, &ciphertext);
+        self.pending.push(flag);
+        self.pending.extend_from_slice(&le_u32(ciphertext.len() as u32));
+        self.pending.extend_from_slice(&ciphertext);
+        self.pending.extend_from_slice(&tag);
+        self.chained = tag;
+        Ok(())
+    }
+
+    /// Hand `pending` to `inner`; keeps what is left on any error
+    fn drain(&mut self) -> io::Result<()> {
+        while self.written < self.pending.len() {
+            match self.inner.write(&self.pending[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for HybridGuardWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(HybridGuardError::InvalidInput("stream is already finished".to_string()).into());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.drain()?;
+        // A full segment is sealed only once more data arrives, so the last
+        // full segment can still become the final one
+        if self.plain.len() == self.segment_len {
+            self.seal(FLAG_MORE)?;
+            self.drain()?;
+        }
+        let n = buf.len().min(self.segment_len - self.plain.len());
+        self.plain.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+/// Where the reader is in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Prefix,
+    Header { len: usize },
+    SegmentHead,
+    SegmentBody { flag: u8, len: usize },
+    Done,
+}
+
+/// Decrypting `Read` adapter
+///
+/// Reads one segment at a time, authenticates it, and serves its plaintext
+/// from an internal buffer, so reads of any size work and memory is bounded
+/// by one segment. Partially read frames are kept across a `WouldBlock` from
+/// `inner`. Once the final segment is served `read` returns 0 without
+/// touching `inner` again; a stream that ends before it is an error.
+/// Failures are reported as `io::ErrorKind::InvalidData` wrapping a
+/// `HybridGuardError`, after the instance's `DecryptErrorMode`.
+pub struct HybridGuardReader<'a, R: Read> {
+    hg: &'a HybridGuard,
+    inner: R,
+    pipeline: Vec<Box<dyn EncryptionLayer>>,
+    stage: Stage,
+    frame: Vec<u8>,
+    header: Option<StreamHeader>,
+    max_ciphertext: usize,
+    chained: [u8; TAG_LEN],
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: Read> HybridGuardReader<'a, R> {
+    /// Decrypt the stream read from `inner`; refuses an encrypt-only key
+    pub fn new(inner: R, hg: &'a HybridGuard) -> Result<Self> {
+        hg.key_manager().decryption_keys()?;
+        Ok(Self {
+            hg,
+            inner,
+            pipeline: layers::registry(),
+            stage: Stage::Prefix,
+            frame: Vec::new(),
+            header: None,
+            max_ciphertext: 0,
+            chained: [0u8; TAG_LEN],
+            plain: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Header of the stream, once the first read has parsed it
+    pub fn header(&self) -> Option<&StreamHeader> {
+        self.header.as_ref()
+    }
+
+    /// Underlying reader; bytes after the final segment are left unread
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Advance until a segment is decrypted or the stream is done
+    fn next_segment(&mut self) -> Result<()> {
+        let keys = self.hg.key_manager().decryption_keys()?;
+        loop {
+            let want = match self.stage {
+                Stage::Prefix => PREFIX_LEN,
+                Stage::Header { len } => PREFIX_LEN + len,
+                Stage::SegmentHead => SEGMENT_HEAD_LEN,
+                Stage::SegmentBody { len, .. } => SEGMENT_HEAD_LEN + len + TAG_LEN,
+                Stage::Done => return Ok(()),
+            };
+            if !self.fill(want)? {
+                return Err(truncated());
+            }
+            match self.stage {
+                Stage::Prefix => self.stage = Stage::Header { len: self.parse_prefix()? },
+                Stage::Header { .. } => {
+                    self.parse_header(keys)?;
+                    self.frame.clear();
+                    self.stage = Stage::SegmentHead;
+                }
+                Stage::SegmentHead => {
+                    let flag = self.frame[0];
+                    let len = u32_at(&self.frame, 1) as usize;
+                    if flag > FLAG_FINAL || len > self.max_ciphertext {
+                        return Err(forged());
+                    }
+                    self.stage = Stage::SegmentBody { flag, len };
+                }
+                Stage::SegmentBody { flag, len } => {
+                    self.open_segment(keys, flag, len)?;
+                    self.frame.clear();
+                    self.stage = if flag == FLAG_FINAL { Stage::Done } else { Stage::SegmentHead };
+                    return Ok(());
+                }
+                Stage::Done => unreachable!(),
+            }
+        }
+    }
+
+    fn parse_prefix(&self) -> Result<usize> {
+        if self.frame[..4] != MAGIC {
+            return Err(HybridGuardError::UnsupportedFormat("not a HybridGuard stream".to_string()));
+        }
+        let version = u16_at(&self.frame, 4);
+        if version != FORMAT_VERSION {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "stream format v{} is not supported (this build reads v{})",
+                version, FORMAT_VERSION
+            )));
+        }
+        let len = u32_at(&self.frame, 6) as usize;
+        if len > MAX_HEADER_LEN {
+            return Err(invalid_header("header is too large"));
+        }
+        Ok(len)
+    }
+
+    fn parse_header(&mut self, keys: &LayerKeys) -> Result<()> {
+        let header: StreamHeader =
+            bincode::deserialize(&self.frame[PREFIX_LEN..]).map_err(|_| invalid_header("unreadable header"))?;
+        let segment_len = header.segment_len as usize;
+        if segment_len == 0 || segment_len > MAX_SEGMENT_LEN {
+            return Err(invalid_header("segment length is out of range"));
+        }
+        if header.key_id != self.hg.key_manager().key_id() {
+            return Err(HybridGuardError::KeyMismatch(format!(
+                "stream was encrypted with key {} but key {} is loaded",
+                header.key_id,
+                self.hg.key_manager().key_id()
+            )));
+        }
+        // Older layer versions never produce more than the current ones
+        self.max_ciphertext = self.pipeline.iter().try_fold(segment_len, |len, layer| layer.output_len(len))?;
+        self.chained = chain_start(keys, &self.frame);
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Authenticate the segment in `frame`, then decrypt it into `plain`
+    fn open_segment(&mut self, keys: &LayerKeys, flag: u8, len: usize) -> Result<()> {
+        let ciphertext = &self.frame[SEGMENT_HEAD_LEN..SEGMENT_HEAD_LEN + len];
+        let tag = segment_tag(keys, &self.chained, flag, ciphertext);
+        if !tag::tags_match(&tag, &self.frame[SEGMENT_HEAD_LEN + len..]) {
+            return Err(forged());
+        }
+
+        let header = self.header.as_ref().expect("header is parsed before any segment");
+        let mut decryptor = StreamDecryptor::new(&self.pipeline, keys, &header.descriptors)?;
+        let mut plain = decryptor.update(ciphertext)?;
+        plain.extend(decryptor.finish()?);
+        let segment_len = header.segment_len as usize;
+        if plain.len() > segment_len || (flag == FLAG_MORE && plain.len() != segment_len) {
+            return Err(forged());
+        }
+        self.chained = tag;
+        self.plain = plain;
+        self.pos = 0;
+        Ok(())
+    }
+
+    /// Read into `frame` until it holds `want` bytes; false if the stream ends first
+    fn fill(&mut self, want: usize) -> io::Result<bool> {
+        while self.frame.len() < want {
+            let start = self.frame.len();
+            self.frame.resize(want, 0);
+            let read = self.inner.read(&mut self.frame[start..]);
+            self.frame.truncate(start + *read.as_ref().unwrap_or(&0));
+            match read {
+                Ok(0) => return Ok(false),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for HybridGuardReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.pos == self.plain.len() {
+            if self.stage == Stage::Done {
+                return Ok(0);
+            }
+            self.next_segment().map_err(|e| self.hg.decrypt_error_mode().apply(e))?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// First link of the tag chain, over the prefix and header
+fn chain_start(keys: &LayerKeys, encoded_header: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(encoded_header);
+    hasher.finalize().into()
+}
+
+/// Tag of one segment, chained to the one before it
+fn segment_tag(keys: &LayerKeys, previous: &[u8; TAG_LEN], flag: u8, ciphertext: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(previous);
+    hasher.update([flag]);
+    hasher.update(le_u32(ciphertext.len() as u32));
+    hasher.update(ciphertext);
+    hasher.finalize().into()
+}
+
+fn invalid_header(reason: &str) -> HybridGuardError {
+    HybridGuardError::Integrity(format!("stream header is invalid: {}", reason))
+}
+
+fn truncated() -> HybridGuardError {
+    HybridGuardError::Integrity("stream ended before its final segment".to_string())
+}
+
+fn forged() -> HybridGuardError {
+    HybridGuardError::Integrity("stream segment failed authentication".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_manager::KeyManager;
+
+    fn instance() -> HybridGuard {
+        HybridGuard::builder(KeyManager::from_master_key(&[0x61; 32]).unwrap()).build().unwrap()
+    }
+
+    #[test]
+    fn test_segments_and_final_flag() {
+        let hg = instance();
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 241) as u8).collect();
+        let mut writer = HybridGuardWriter::with_segment_len(Vec::new(), &hg, 1000).unwrap();
+        writer.write_all(&data).unwrap();
+        let stream = writer.finish().unwrap();
+        assert!(is_stream(&stream));
+
+        let mut reader = HybridGuardReader::new(&stream[..], &hg).unwrap();
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, data);
+        assert_eq!(reader.header().unwrap().segment_len, 1000);
+
+        // Every tag byte is checked
+        let mut forged = stream.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(HybridGuardReader::new(&forged[..], &hg).unwrap().read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_segment_len_bounds() {
+        let hg = instance();
+        assert!(HybridGuardWriter::with_segment_len(Vec::new(), &hg, 0).is_err());
+        assert!(HybridGuardWriter::with_segment_len(Vec::new(), &hg, MAX_SEGMENT_LEN + 1).is_err());
+    }
+}
@@ -0,0 +1,650 @@
+// Resumable chunked encryption
+//
+// Checkpoint layout: magic "HGCP", u16 format version, bincode body, then a
+// 32-byte tag over everything before it. The tag is keyed by the layer keys,
+// so a checkpoint only resumes under the keys that wrote it and cannot be
+// edited. Each checkpoint is written to a temporary file, synced and renamed
+// over the previous one, so a crash leaves either the old or the new state.
+// The output and checkpoint are fsynced by default (`WriteOptions::critical`);
+// lower durability trades that guarantee across power loss for speed.
+//
+// On resume the output is checked against the checkpoint (header fields and
+// a hash of the bytes written since the previous checkpoint), truncated to
+// the last completed segment, and encryption continues from there. The input
+// must be the one the run started on: same size and mtime, and its bytes up
+// to the checkpoint must hash to the digest recorded as they were encrypted,
+// or the output would mix two inputs. A run resumed inside a key epoch
+// unwraps that epoch's key from its key record, and reads the completed
+// segments' tags back for the Merkle index.
+
+use crate::budget::MeteredWriter;
+use crate::cancel::CancellationToken;
+use crate::crypto::container::{le_u16, u16_at};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::crypto::SourceSnapshot;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::KeyManager;
+use crate::layers::{self, EncryptionLayer};
+use crate::stable_read;
+use crate::streaming::chunked::{self, ChunkedHeader, ChunkedStats, Epoch, HashWriter};
+use crate::streaming::merkle::Node;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes at the start of every checkpoint file
+pub const MAGIC: [u8; 4] = *b"HGCP";
+
+/// Checkpoint format written by this build
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Largest checkpoint file accepted
+const MAX_CHECKPOINT_LEN: u64 = 64 * 1024;
+
+/// Purpose of the checkpoint tag key
+const TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("checkpoint-tag");
+
+/// Progress of a chunked encryption, as of the last completed segment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub key_id: String,
+    /// Plaintext length the run was started for
+    pub input_len: u64,
+    /// Size and mtime of the input the run was started for
+    pub source: SourceSnapshot,
+    /// SHA3-256 of the input bytes `..input_offset`
+    pub input_digest: [u8; 32],
+    pub segment_len: u64,
+    /// Segments fully written and synced
+    pub segments_done: u64,
+    /// Next plaintext byte to encrypt
+    pub input_offset: u64,
+    /// Output length once the completed segments are written
+    pub output_offset: u64,
+    /// Tag chain after the last completed segment
+    pub chain: [u8; TAG_LEN],
+    /// Start of the bytes written since the previous checkpoint
+    pub last_offset: u64,
+    /// SHA3-256 of the output bytes `last_offset..output_offset`
+    pub last_hash: [u8; 32],
+}
+
+impl Checkpoint {
+    /// Replace the checkpoint at `path` atomically, finished as `options` ask
+    pub fn save(&self, path: &Path, keys: &LayerKeys, options: &WriteOptions) -> Result<()> {
+        let body = bincode::serialize(self).map_err(|e| HybridGuardError::Encryption(format!("checkpoint: {}", e)))?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&le_u16(FORMAT_VERSION));
+        bytes.extend_from_slice(&body);
+        let tag = checkpoint_tag(keys, &bytes);
+        bytes.extend_from_slice(&tag);
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let mut file = File::create(&temporary)?;
+        file.write_all(&bytes)?;
+        options.finish_file(&mut file, path)?;
+        fs::rename(&temporary, path)?;
+        options.finish_entry(path)?;
+        Ok(())
+    }
+
+    /// Read the checkpoint at `path`, rejecting it unless its tag verifies under `keys`
+    pub fn load(path: &Path, keys: &LayerKeys) -> Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.take(MAX_CHECKPOINT_LEN + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > MAX_CHECKPOINT_LEN || bytes.len() < MAGIC.len() + 2 + TAG_LEN {
+            return Err(tampered());
+        }
+        if bytes[..4] != MAGIC {
+            return Err(HybridGuardError::UnsupportedFormat(format!("{} is not a checkpoint file", path.display())));
+        }
+        let version = u16_at(&bytes, 4);
+        if version != FORMAT_VERSION {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "checkpoint format v{} is not supported (this build reads v{})",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let (signed, stored) = bytes.split_at(bytes.len() - TAG_LEN);
+        if !tag::tags_match(&checkpoint_tag(keys, signed), stored) {
+            return Err(tampered());
+        }
+        bincode::deserialize(&signed[6..]).map_err(|_| tampered())
+    }
+
+    /// Check that this checkpoint belongs to a run over `header` written with `header_len` header bytes
+    fn check_against(&self, header: &ChunkedHeader, header_len: u64) -> Result<()> {
+        let consistent = self.segments_done <= header.segments()
+            && self.input_offset == self.segments_done.saturating_mul(header.segment_len).min(header.plaintext_len)
+            && self.output_offset == header.offset_after(header_len, self.segments_done)?
+            && self.last_offset <= self.output_offset;
+        if !consistent {
+            return Err(mismatched("its offsets do not match the output header"));
+        }
+        Ok(())
+    }
+}
+
+/// A chunked encryption that persists a checkpoint after every segment
+///
+/// `open` starts a fresh run, or resumes one when the checkpoint file exists.
+/// Call `step` until it returns false, then `finish`. Dropping the run part
+/// way, as a crash would, leaves a checkpoint the next `open` resumes from.
+pub struct CheckpointedEncryption<'a> {
+    key_manager: &'a KeyManager,
+    keys: &'a LayerKeys,
+    /// The current key epoch; None before the first one, and for format v1 outputs
+    epoch: Option<Epoch>,
+    /// First link of the tag chain, which segment tags are bound to
+    chain_start: [u8; TAG_LEN],
+    /// Tags of the completed segments, the leaves of the Merkle index
+    leaves: Vec<Node>,
+    pipeline: Vec<Box<dyn EncryptionLayer>>,
+    header: ChunkedHeader,
+    input: BufReader<File>,
+    /// Running hash of the input read so far
+    input_digest: Sha3_256,
+    output: BufWriter<File>,
+    output_path: PathBuf,
+    path: Option<PathBuf>,
+    state: Checkpoint,
+    resumed_segments: u64,
+    cancel: CancellationToken,
+    write_options: WriteOptions,
+}
+
+impl<'a> CheckpointedEncryption<'a> {
+    /// Start or resume encrypting `input` into `output`
+    /// Without a checkpoint path nothing is persisted and the run always starts fresh
+    pub fn open(
+        input: &Path,
+        output: &Path,
+        key_manager: &'a KeyManager,
+        segment_chunks: u64,
+        checkpoint: Option<&Path>,
+    ) -> Result<Self> {
+        let keys = key_manager.get_keys()?;
+        let source = File::open(input)?;
+        let snapshot = stable_read::snapshot_of(&source.metadata()?)?;
+        let mut input = BufReader::new(source);
+
+        let Opened { header, file, state, epoch, chain_start, leaves, input_digest } = match checkpoint.filter(|path| path.exists()) {
+            Some(path) => resume(path, output, key_manager, snapshot, &mut input)?,
+            None => start(output, key_manager, snapshot, segment_chunks)?,
+        };
+
+        let mut run = Self {
+            key_manager,
+            keys,
+            epoch,
+            chain_start,
+            leaves,
+            pipeline: layers::registry(),
+            header,
+            input,
+            input_digest,
+            output: BufWriter::new(file),
+            output_path: output.to_path_buf(),
+            path: checkpoint.map(Path::to_path_buf),
+            resumed_segments: state.segments_done,
+            state,
+            cancel: CancellationToken::new(),
+            write_options: WriteOptions::critical(),
+        };
+        if run.resumed_segments == 0 {
+            run.persist()?;
+        }
+        Ok(run)
+    }
+
+    /// Stop `step` within one chunk once `cancel` is triggered
+    /// The segment in progress is discarded; the last checkpoint still resumes
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+    
+    /// Finish the output and checkpoints as `options` ask from the next
+    /// checkpoint on; the first one, written by `open`, is always fsynced
+    pub fn with_write_options(mut self, options: WriteOptions) -> Self {
+        self.write_options = options;
+        self
+    }
+    
+    /// Header of the file being written
+    pub fn header(&self) -> &ChunkedHeader {
+        &self.header
+    }
+
+    /// Progress as of the last completed segment
+    pub fn state(&self) -> &Checkpoint {
+        &self.state
+    }
+
+    /// Segments taken over from an earlier run
+    pub fn resumed_segments(&self) -> u64 {
+        self.resumed_segments
+    }
+
+    /// Encrypt the next segment and checkpoint it; false once every segment is done
+    pub fn step(&mut self) -> Result<bool> {
+        let index = self.state.segments_done;
+        if index >= self.header.segments() {
+            return Ok(false);
+        }
+
+        let len = self.header.segment_plaintext_len(index);
+        let mut link = chunked::chain_link(self.keys, &self.state.chain);
+        let mut written = Sha3_256::new();
+        let mut output = MeteredWriter::new(&mut self.output, &self.cancel);
+        if self.header.starts_epoch(index) {
+            let (keys, wrapped) = self.key_manager.new_file_keys()?;
+            let record = chunked::encode_key_record(&wrapped)?;
+            output.write_all(&record)?;
+            link.update(record);
+            written.update(record);
+            self.epoch = Some(Epoch { record, keys });
+        }
+        let record = self.epoch.as_ref().map(|epoch| &epoch.record[..]).unwrap_or_default();
+        let mut segment_tag = chunked::segment_hasher(self.keys, &self.chain_start, index, record);
+        let read = chunked::encrypt_segment(
+            &self.pipeline,
+            self.epoch.as_ref().map(|epoch| &epoch.keys).unwrap_or(self.keys),
+            DigestReader { inner: &mut self.input, digest: &mut self.input_digest },
+            len,
+            &mut output,
+            &mut [&mut link, &mut written, &mut segment_tag],
+            &self.cancel,
+        )?;
+        if self.header.has_segment_tags() {
+            let segment_tag: Node = segment_tag.finalize().into();
+            output.write_all(&segment_tag)?;
+            link.update(segment_tag);
+            written.update(segment_tag);
+            self.leaves.push(segment_tag);
+        }
+        if read != len {
+            return Err(HybridGuardError::Encryption(format!(
+                "input shrank while it was read ({} of {} bytes in segment {})",
+                read, len, index
+            )));
+        }
+
+        let ciphertext_len = self.header.stored_segment_len(index)?;
+        self.state = Checkpoint {
+            segments_done: index + 1,
+            input_offset: self.state.input_offset + len,
+            input_digest: self.input_digest.clone().finalize().into(),
+            output_offset: self.state.output_offset + ciphertext_len,
+            chain: link.finalize().into(),
+            last_offset: self.state.output_offset,
+            last_hash: written.finalize().into(),
+            ..self.state.clone()
+        };
+        self.persist()?;
+        Ok(true)
+    }
+
+    /// Write the Merkle index and the final tag, and remove the checkpoint
+    pub fn finish(mut self) -> Result<ChunkedStats> {
+        if self.state.segments_done < self.header.segments() {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "{} of {} segments encrypted; call step until it returns false",
+                self.state.segments_done,
+                self.header.segments()
+            )));
+        }
+        let mut output = MeteredWriter::new(&mut self.output, &self.cancel);
+        if self.header.has_merkle_index() {
+            output.write_all(&chunked::encode_sealed_index(self.keys, &self.header, &self.chain_start, &self.leaves))?;
+        }
+        output.write_all(&self.state.chain)?;
+        output.flush()?;
+        self.write_options.finish_file(self.output.get_mut(), &self.output_path)?;
+        if let Some(path) = &self.path {
+            fs::remove_file(path)?;
+            self.write_options.finish_entry(path)?;
+        }
+        Ok(ChunkedStats {
+            plaintext_len: self.header.plaintext_len,
+            segments: self.header.segments(),
+            epochs: self.header.epochs(),
+            resumed_segments: self.resumed_segments,
+        })
+    }
+
+    /// Sync the output, then record the state; the checkpoint never runs ahead of the data
+    fn persist(&mut self) -> Result<()> {
+        self.output.flush()?;
+        self.write_options.finish_file(self.output.get_mut(), &self.output_path)?;
+        if let Some(path) = &self.path {
+            self.state.save(path, self.keys, &self.write_options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a run picks up
+struct Opened {
+    header: ChunkedHeader,
+    file: File,
+    state: Checkpoint,
+    epoch: Option<Epoch>,
+    chain_start: [u8; TAG_LEN],
+    leaves: Vec<Node>,
+    /// Hash of the input up to the checkpoint, which the input is read past
+    input_digest: Sha3_256,
+}
+
+/// Create the output and write its header
+fn start(output: &Path, key_manager: &KeyManager, source: SourceSnapshot, segment_chunks: u64) -> Result<Opened> {
+    let input_len = source.len;
+    let header = ChunkedHeader::with_limits(input_len, segment_chunks, key_manager.key_id(), &key_manager.data_limits())?;
+    let encoded = header.encode()?;
+    let mut file = File::create(output)?;
+    file.write_all(&encoded)?;
+
+    let chain_start = chunked::chain_start(key_manager.get_keys()?, &encoded);
+    let state = Checkpoint {
+        key_id: key_manager.key_id().to_string(),
+        input_len,
+        source,
+        input_digest: Sha3_256::digest(b"").into(),
+        segment_len: header.segment_len,
+        segments_done: 0,
+        input_offset: 0,
+        output_offset: encoded.len() as u64,
+        chain: chain_start,
+        last_offset: 0,
+        last_hash: Sha3_256::digest(&encoded).into(),
+    };
+    Ok(Opened { header, file, state, epoch: None, chain_start, leaves: Vec::new(), input_digest: Sha3_256::new() })
+}
+
+/// Validate the checkpoint, the input and the partial output, and cut the
+/// output back to the checkpoint; `input` is left where the run continues
+fn resume(path: &Path, output: &Path, key_manager: &KeyManager, source: SourceSnapshot, input: &mut BufReader<File>) -> Result<Opened> {
+    let input_len = source.len;
+    let state = Checkpoint::load(path, key_manager.get_keys()?)?;
+    if state.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "checkpoint was written with key {} but key {} is loaded",
+            state.key_id,
+            key_manager.key_id()
+        )));
+    }
+    if state.input_len != input_len {
+        return Err(mismatched(&format!("it was written for a {} byte input, this one is {} bytes", state.input_len, input_len)));
+    }
+    if state.source != source {
+        return Err(mismatched("the input was modified after the run started"));
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(output)?;
+    let (header, encoded) = chunked::read_encoded_header(&mut BufReader::new(&mut file))?;
+    if header.plaintext_len != state.input_len || header.segment_len != state.segment_len || header.key_id != state.key_id {
+        return Err(mismatched("the output header describes a different run"));
+    }
+    state.check_against(&header, encoded.len() as u64)?;
+
+    if file.metadata()?.len() < state.output_offset {
+        return Err(mismatched("the output is shorter than the checkpoint"));
+    }
+    let mut last = Sha3_256::new();
+    file.seek(SeekFrom::Start(state.last_offset))?;
+    std::io::copy(&mut (&mut file).take(state.output_offset - state.last_offset), &mut HashWriter(&mut last))?;
+    let last: [u8; 32] = last.finalize().into();
+    if !tag::tags_match(&last, &state.last_hash) {
+        return Err(mismatched("the output was modified after the checkpoint was written"));
+    }
+
+    // The input's bytes already encrypted must be the ones the run read
+    let mut input_digest = Sha3_256::new();
+    io::copy(&mut input.by_ref().take(state.input_offset), &mut HashWriter(&mut input_digest))?;
+    let prefix: [u8; 32] = input_digest.clone().finalize().into();
+    if !tag::tags_match(&prefix, &state.input_digest) {
+        return Err(mismatched(&format!("the input's first {} bytes differ from the ones already encrypted", state.input_offset)));
+    }
+
+    // Inside an epoch, the next segment reuses the key its record holds
+    let done = state.segments_done;
+    let epoch = if header.epoch_segments == 0 || done == header.segments() || header.starts_epoch(done) {
+        None
+    } else {
+        let record_at = header.offset_after(encoded.len() as u64, header.epoch_start(done))?;
+        file.seek(SeekFrom::Start(record_at))?;
+        Some(chunked::read_epoch(&mut file, key_manager)?)
+    };
+    let chain_start = chunked::chain_start(key_manager.get_keys()?, &encoded);
+    let leaves = if header.has_merkle_index() {
+        chunked::read_segment_tags(&mut file, &header, encoded.len() as u64, done)?
+    } else {
+        Vec::new()
+    };
+
+    // Anything past the checkpoint belongs to a segment that never completed
+    file.set_len(state.output_offset)?;
+    file.seek(SeekFrom::Start(state.output_offset))?;
+    Ok(Opened { header, file, state, epoch, chain_start, leaves, input_digest })
+}
+
+/// Feeds the input it reads to the run's input digest
+struct DigestReader<'a, R> {
+    inner: R,
+    digest: &'a mut Sha3_256,
+}
+
+impl<R: Read> Read for DigestReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn checkpoint_tag(keys: &LayerKeys, bytes: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
+fn tampered() -> HybridGuardError {
+    HybridGuardError::Integrity("checkpoint failed authentication (wrong keys or modified file)".to_string())
+}
+
+fn mismatched(reason: &str) -> HybridGuardError {
+    HybridGuardError::Integrity(format!("checkpoint does not match this run: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::limits::DataLimits;
+    use rand::Rng;
+
+    const CHUNK: usize = crate::streaming::DEFAULT_CHUNK_SIZE;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        data: Vec<u8>,
+        key_manager: KeyManager,
+    }
+
+    impl Fixture {
+        fn new(len: usize) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let data: Vec<u8> = (0..len as u32).map(|i| (i.wrapping_mul(31) % 251) as u8).collect();
+            fs::write(dir.path().join("input"), &data).unwrap();
+            Self { dir, data, key_manager: KeyManager::from_master_key(&[0x43; 32]).unwrap() }
+        }
+
+        /// Rekey every `chunks` chunks
+        fn with_epochs_of(mut self, chunks: u64) -> Self {
+            self.key_manager = self.key_manager.with_data_limits(DataLimits { max_epoch_bytes: u64::MAX, max_epoch_chunks: chunks });
+            self
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn open(&self) -> Result<CheckpointedEncryption<'_>> {
+            let checkpoint = self.path("checkpoint");
+            CheckpointedEncryption::open(&self.path("input"), &self.path("output"), &self.key_manager, 1, Some(&checkpoint))
+        }
+
+        /// Run `steps` segments, then drop the run as a crash would
+        fn crash_after(&self, steps: u64) {
+            let mut run = self.open().unwrap();
+            for _ in 0..steps {
+                assert!(run.step().unwrap());
+            }
+        }
+
+        fn decrypted(&self) -> Vec<u8> {
+            chunked::decrypt_file(&self.path("output"), &self.path("plain"), &self.key_manager).unwrap();
+            fs::read(self.path("plain")).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_each_checkpoint_follows_its_output_to_disk() {
+        use crate::fsutil::{DurabilityLevel, RecordingSyncer};
+        use std::sync::Arc;
+
+        let fixture = Fixture::new(3 * CHUNK);
+        let syncer = Arc::new(RecordingSyncer::default());
+        let options = WriteOptions::new(DurabilityLevel::FsyncDir).with_syncer(syncer.clone());
+        let mut run = fixture.open().unwrap().with_write_options(options);
+        while run.step().unwrap() {}
+        run.finish().unwrap();
+        assert_eq!(fixture.decrypted(), fixture.data);
+
+        let checkpoint = fixture.path("checkpoint");
+        let calls = syncer.calls.lock().unwrap().clone();
+        let on = |path: &Path| calls.iter().filter(|(call, target)| *call == "sync_file" && target == path).count();
+        // One per segment, plus the final index for the output
+        assert_eq!(on(&checkpoint), 3);
+        assert_eq!(on(&fixture.path("output")), 4);
+        // Each checkpoint is synced only after the output it describes
+        let order: Vec<&Path> = calls.iter().filter(|(call, _)| *call == "sync_file").map(|(_, path)| path.as_path()).collect();
+        for pair in order.chunks(2).take(3) {
+            assert_eq!(pair, [fixture.path("output").as_path(), checkpoint.as_path()]);
+        }
+        assert!(syncer.calls_for(&checkpoint).contains(&"sync_dir"));
+    }
+
+    #[test]
+    fn test_resume_at_random_boundaries() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..4 {
+            let fixture = Fixture::new(6 * CHUNK + rng.gen_range(0..CHUNK));
+            let mut done = 0u64;
+            for _ in 0..rng.gen_range(1..4) {
+                let steps = rng.gen_range(0..=7 - done);
+                fixture.crash_after(steps);
+                done += steps;
+                // A segment cut short by the crash is left behind
+                let mut output = OpenOptions::new().append(true).open(fixture.path("output")).unwrap();
+                output.write_all(&vec![0xEE; rng.gen_range(0..CHUNK)]).unwrap();
+            }
+            let before = fs::read(fixture.path("output")).unwrap();
+
+            let mut run = fixture.open().unwrap();
+            let resumed = run.state().output_offset as usize;
+            while run.step().unwrap() {}
+            let stats = run.finish().unwrap();
+
+            let after = fs::read(fixture.path("output")).unwrap();
+            assert_eq!(after[..resumed], before[..resumed]);
+            assert_eq!(stats.segments, 7);
+            assert!(!fixture.path("checkpoint").exists());
+            assert_eq!(fixture.decrypted(), fixture.data);
+        }
+    }
+
+    #[test]
+    fn test_resume_inside_a_key_epoch() {
+        let fixture = Fixture::new(7 * CHUNK + 11).with_epochs_of(3);
+        fixture.crash_after(4);
+
+        let mut run = fixture.open().unwrap();
+        assert_eq!(run.resumed_segments(), 4);
+        while run.step().unwrap() {}
+        let stats = run.finish().unwrap();
+        assert_eq!((stats.segments, stats.epochs), (8, 3));
+        assert_eq!(fixture.decrypted(), fixture.data);
+    }
+
+    #[test]
+    fn test_tampered_checkpoint_rejected() {
+        let fixture = Fixture::new(3 * CHUNK);
+        fixture.crash_after(1);
+
+        let mut bytes = fs::read(fixture.path("checkpoint")).unwrap();
+        bytes[10] ^= 1;
+        fs::write(fixture.path("checkpoint"), &bytes).unwrap();
+        assert!(matches!(fixture.open(), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_mismatched_checkpoint_rejected() {
+        let fixture = Fixture::new(3 * CHUNK);
+        fixture.crash_after(2);
+
+        // Output changed inside the last completed segment
+        let mut bytes = fs::read(fixture.path("output")).unwrap();
+        let at = bytes.len() - 100;
+        bytes[at] ^= 1;
+        fs::write(fixture.path("output"), &bytes).unwrap();
+        assert!(matches!(fixture.open(), Err(HybridGuardError::Integrity(_))));
+
+        // Input is not the one the checkpoint was written for
+        fs::write(fixture.path("input"), vec![0u8; CHUNK]).unwrap();
+        assert!(matches!(fixture.open(), Err(HybridGuardError::Integrity(_))));
+
+        // Other keys cannot read the checkpoint at all
+        let other = KeyManager::from_master_key(&[0x44; 32]).unwrap();
+        let checkpoint = fixture.path("checkpoint");
+        let result = CheckpointedEncryption::open(&fixture.path("input"), &fixture.path("output"), &other, 1, Some(&checkpoint));
+        assert!(matches!(result, Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_changed_input_is_not_resumed() {
+        use std::time::Duration;
+
+        let fixture = Fixture::new(3 * CHUNK);
+        fixture.crash_after(2);
+        let input = fixture.path("input");
+        let modified = fs::metadata(&input).unwrap().modified().unwrap();
+        let set_modified = |time| File::options().write(true).open(&input).unwrap().set_modified(time).unwrap();
+
+        // A byte already encrypted changed, with the mtime put back
+        let mut changed = fixture.data.clone();
+        changed[CHUNK + 7] ^= 1;
+        fs::write(&input, &changed).unwrap();
+        set_modified(modified);
+        let err = fixture.open().err().unwrap();
+        assert!(err.to_string().contains("differ from the ones already encrypted"), "{}", err);
+
+        // Same bytes, but the mtime says the file was touched
+        fs::write(&input, &fixture.data).unwrap();
+        set_modified(modified + Duration::from_secs(1));
+        assert!(matches!(fixture.open(), Err(HybridGuardError::Integrity(_))));
+
+        set_modified(modified);
+        let mut run = fixture.open().unwrap();
+        assert_eq!(run.resumed_segments(), 2);
+        while run.step().unwrap() {}
+        run.finish().unwrap();
+        assert_eq!(fixture.decrypted(), fixture.data);
+    }
+}
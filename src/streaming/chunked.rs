@@ -0,0 +1,1702 @@
+// Segmented encryption of large files
+//
+// Layout (all integers little-endian):
+//   magic "HGCH", u16 format version, u32 header length,
+//   bincode header (plaintext length, segment length, key ID, layer descriptors,
+//   segments per key epoch),
+//   then every segment's streamed ciphertext back to back, each key epoch
+//   opened by a key record (its file key, wrapped under the profile keys)
+//   and each segment followed by its own 32-byte tag,
+//   then the Merkle index: every level of the tree over the segment tags,
+//   leaves first, a 32-byte tag over its root and, from format v5, a
+//   32-byte seal over the same root,
+//   then a 32-byte tag over the whole file
+//
+// Each segment is an independent pipeline message over `segment_len`
+// plaintext bytes (the last one may be shorter), so an interrupted run can
+// pick up at a segment boundary (see `checkpoint`). The tag is a chain that
+// starts from a keyed hash of the prefix and header and folds in one segment
+// at a time, key record included, so the running state is only 32 bytes.
+//
+// A key epoch covers as many segments as the profile's `DataLimits` allow
+// under one key; the header fixes the epoch length, so the records sit at
+// offsets known from the header alone. Format v1 had no epochs: every
+// segment was encrypted under the profile keys themselves.
+//
+// A segment tag covers the segment's index, its epoch's key record and its
+// ciphertext, keyed by the profile keys and bound to the header, so
+// `decrypt_range` can seek to the segments it needs and authenticate just
+// those. Formats v1 and v2 have no segment tags; ranges of those files are
+// read after checking the whole-file tag.
+//
+// A segment tag alone cannot tell a segment of this file from one of another
+// file with the same header. Format v4 binds them all to one Merkle root,
+// tagged under the profile keys, and `decrypt_range` checks each segment's
+// inclusion path against it. The index only depends on the segment tags, so
+// `verify_index` can check it without decrypting anything. Format v3 files
+// have no index and are read as before.
+//
+// The chain and the root tag are keyed under purposes a verification key
+// carries, so `verify_integrity` checks every byte of the file for an
+// auditor who holds no layer key. Because that auditor could also re-tag a
+// file, decryption checks each segment tag and, from format v5, the root
+// seal as well, which only the profile keys can make. Earlier formats rest
+// on tags a verification key can forge: v3 and v4 segments can be spliced
+// in from another file with the same header, and v1 and v2 have only the
+// chain.
+
+use crate::budget::MeteredWriter;
+use crate::cancel::CancellationToken;
+use crate::crypto::container::{le_u16, le_u32, le_u64, u16_at, u32_at};
+use crate::crypto::envelope::{WrappedFileKey, NONCE_LEN, WRAPPED_LEN};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::tag::{self, MacKeys, TAG_LEN};
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::verification::VerificationKey;
+use crate::key_manager::KeyManager;
+use crate::layers::{self, EncryptionLayer, LayerDescriptor};
+use crate::staging::{Contents, StagedFile};
+use crate::streaming::checkpoint::CheckpointedEncryption;
+use crate::streaming::limits::DataLimits;
+use crate::streaming::merkle::{self, Node};
+use crate::streaming::{StreamDecryptor, StreamEncryptor, DEFAULT_CHUNK_SIZE};
+use crate::verify::{CorruptPart, CorruptRange, VerifyOptions, VerifyReport};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Magic bytes at the start of every chunked ciphertext
+pub const MAGIC: [u8; 4] = *b"HGCH";
+
+/// Chunked format written by this build
+pub const FORMAT_VERSION: u16 = 5;
+
+/// Oldest chunked format this build reads
+const MIN_FORMAT_VERSION: u16 = 1;
+
+/// Bytes of the key record opening each key epoch: nonce, then wrapped file key
+pub const KEY_RECORD_LEN: usize = NONCE_LEN + WRAPPED_LEN;
+
+/// Bytes before the bincode header: magic, version, header length
+const PREFIX_LEN: usize = 4 + 2 + 4;
+
+/// Largest header accepted
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// Streaming chunks per segment unless the caller asks otherwise (64 MiB)
+pub const DEFAULT_SEGMENT_CHUNKS: u64 = 1024;
+
+/// Purpose of the chained tag key
+pub(crate) const TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("chunked-tag");
+
+/// Purpose of the per-segment tag key
+const SEGMENT_TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("chunked-segment-tag");
+
+/// Purpose of the Merkle root tag key
+pub(crate) const ROOT_TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("chunked-merkle-root");
+
+/// Purpose of the root seal key, which a verification key does not carry
+const ROOT_SEAL_PURPOSE: KeyPurpose = KeyPurpose::Mac("chunked-root-seal");
+
+/// Metadata at the start of a chunked ciphertext
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkedHeader {
+    pub plaintext_len: u64,
+    /// Plaintext bytes per segment; only the last segment may be shorter
+    pub segment_len: u64,
+    pub key_id: String,
+    pub descriptors: Vec<LayerDescriptor>,
+    pub timestamp: u64,
+    /// Segments per key epoch; zero for format v1 files, which have no epochs
+    pub epoch_segments: u64,
+    /// Format the file was written in; carried in the prefix, not the header
+    #[serde(skip)]
+    pub format_version: u16,
+}
+
+/// Header of format v1, before key epochs
+#[derive(Deserialize)]
+struct LegacyHeader {
+    plaintext_len: u64,
+    segment_len: u64,
+    key_id: String,
+    descriptors: Vec<LayerDescriptor>,
+    timestamp: u64,
+}
+
+impl ChunkedHeader {
+    /// Header for encrypting `plaintext_len` bytes in segments of `segment_chunks` chunks
+    pub fn new(plaintext_len: u64, segment_chunks: u64, key_id: &str) -> Result<Self> {
+        Self::with_limits(plaintext_len, segment_chunks, key_id, &DataLimits::DEFAULT)
+    }
+
+    /// `new` under a profile's data limits; segments shrink to fit one epoch if need be
+    pub fn with_limits(plaintext_len: u64, segment_chunks: u64, key_id: &str, limits: &DataLimits) -> Result<Self> {
+        if segment_chunks == 0 {
+            return Err(HybridGuardError::InvalidInput("a segment needs at least one chunk".to_string()));
+        }
+        let epoch_chunks = limits.epoch_chunks()?;
+        let segment_chunks = segment_chunks.min(epoch_chunks);
+        Ok(Self {
+            plaintext_len,
+            segment_len: segment_chunks.saturating_mul(DEFAULT_CHUNK_SIZE as u64),
+            key_id: key_id.to_string(),
+            descriptors: layers::current_descriptors(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            epoch_segments: epoch_chunks / segment_chunks,
+            format_version: FORMAT_VERSION,
+        })
+    }
+
+    /// Number of segments, zero for an empty file
+    pub fn segments(&self) -> u64 {
+        self.plaintext_len.div_ceil(self.segment_len)
+    }
+
+    /// Number of keys the segments are encrypted under
+    pub fn epochs(&self) -> u64 {
+        match self.epoch_segments {
+            0 => self.segments().min(1),
+            every => self.segments().div_ceil(every),
+        }
+    }
+
+    /// Whether segment `index` opens a key epoch, and so is preceded by a key record
+    pub fn starts_epoch(&self, index: u64) -> bool {
+        self.epoch_segments != 0 && index % self.epoch_segments == 0
+    }
+
+    /// Whether every segment carries its own tag (format v3 on)
+    pub fn has_segment_tags(&self) -> bool {
+        self.format_version >= 3
+    }
+
+    /// Whether a Merkle index of the segment tags follows the segments (format v4 on)
+    pub fn has_merkle_index(&self) -> bool {
+        self.format_version >= 4
+    }
+
+    /// Whether the root tag is followed by a seal decryption checks (format v5 on)
+    pub fn has_root_seal(&self) -> bool {
+        self.format_version >= 5
+    }
+
+    /// Bytes of the Merkle index, its root tag and any root seal; zero before format v4
+    pub fn index_len(&self) -> u64 {
+        if self.has_merkle_index() {
+            (merkle::node_count(self.segments()) + 1 + u64::from(self.has_root_seal())) * TAG_LEN as u64
+        } else {
+            0
+        }
+    }
+
+    /// First segment of the epoch holding segment `index`
+    pub(crate) fn epoch_start(&self, index: u64) -> u64 {
+        match self.epoch_segments {
+            0 => 0,
+            every => index - index % every,
+        }
+    }
+
+    /// Plaintext bytes in segment `index`
+    pub fn segment_plaintext_len(&self, index: u64) -> u64 {
+        let start = index.saturating_mul(self.segment_len);
+        self.plaintext_len.saturating_sub(start).min(self.segment_len)
+    }
+
+    /// Ciphertext bytes in segment `index`
+    pub fn segment_ciphertext_len(&self, index: u64) -> Result<u64> {
+        let plain = usize::try_from(self.segment_plaintext_len(index))
+            .map_err(|_| invalid_header("segment is too large for this platform"))?;
+        Ok(HybridGuardEncryptor::new().estimate_output_size(plain)? as u64)
+    }
+
+    /// Bytes segment `index` takes in the file, with the key record it opens and its tag
+    pub fn stored_segment_len(&self, index: u64) -> Result<u64> {
+        let record = if self.starts_epoch(index) { KEY_RECORD_LEN as u64 } else { 0 };
+        Ok(record + self.segment_ciphertext_len(index)? + self.segment_tag_len())
+    }
+
+    fn segment_tag_len(&self) -> u64 {
+        if self.has_segment_tags() {
+            TAG_LEN as u64
+        } else {
+            0
+        }
+    }
+
+    /// Offset just past the first `segments` segments, given the encoded header length
+    /// Only the last segment may be short, so this is arithmetic rather than a walk
+    pub(crate) fn offset_after(&self, header_len: u64, segments: u64) -> Result<u64> {
+        let segments = segments.min(self.segments());
+        let full = segments.min(self.plaintext_len / self.segment_len);
+        let mut offset = header_len;
+        if full > 0 {
+            offset += full * (self.segment_ciphertext_len(0)? + self.segment_tag_len());
+        }
+        if segments > full {
+            offset += self.segment_ciphertext_len(full)? + self.segment_tag_len();
+        }
+        if self.epoch_segments != 0 {
+            offset += segments.div_ceil(self.epoch_segments) * KEY_RECORD_LEN as u64;
+        }
+        Ok(offset)
+    }
+
+    /// Offset of segment `index`'s ciphertext, past any key record it opens
+    fn ciphertext_offset(&self, header_len: u64, index: u64) -> Result<u64> {
+        let record = if self.starts_epoch(index) { KEY_RECORD_LEN as u64 } else { 0 };
+        Ok(self.offset_after(header_len, index)? + record)
+    }
+
+    fn validate(&self, version: u16) -> Result<()> {
+        if self.segment_len == 0 {
+            return Err(invalid_header("segment length is zero"));
+        }
+        if version >= 2 && self.epoch_segments == 0 {
+            return Err(invalid_header("key epoch length is zero"));
+        }
+        Ok(())
+    }
+
+    /// Prefix and bincode header, exactly as written at the start of the file
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let body = bincode::serialize(self).map_err(|e| HybridGuardError::Encryption(format!("chunked header: {}", e)))?;
+        let mut out = Vec::with_capacity(PREFIX_LEN + body.len());
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&le_u16(FORMAT_VERSION));
+        out.extend_from_slice(&le_u32(body.len() as u32));
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+}
+
+/// What a chunked encryption or decryption covered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkedStats {
+    pub plaintext_len: u64,
+    pub segments: u64,
+    /// Keys the segments were encrypted under, one per epoch
+    pub epochs: u64,
+    /// Segments taken over from an earlier, interrupted run
+    pub resumed_segments: u64,
+}
+
+/// Whether `bytes` starts a chunked ciphertext
+pub fn is_chunked(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Read and validate the header of a chunked ciphertext; does not check the tag
+pub fn read_header<R: Read>(reader: &mut R) -> Result<ChunkedHeader> {
+    Ok(read_encoded_header(reader)?.0)
+}
+
+/// The header plus its encoded bytes, which start the tag chain
+pub(crate) fn read_encoded_header<R: Read>(reader: &mut R) -> Result<(ChunkedHeader, Vec<u8>)> {
+    let mut prefix = [0u8; PREFIX_LEN];
+    reader.read_exact(&mut prefix).map_err(|_| truncated())?;
+    if prefix[..4] != MAGIC {
+        return Err(HybridGuardError::UnsupportedFormat("not a chunked HybridGuard file".to_string()));
+    }
+    let version = u16_at(&prefix, 4);
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "chunked format v{} is not supported (this build reads v{} to v{})",
+            version, MIN_FORMAT_VERSION, FORMAT_VERSION
+        )));
+    }
+    let header_len = u32_at(&prefix, 6) as usize;
+    if header_len > MAX_HEADER_LEN {
+        return Err(invalid_header("header is too large"));
+    }
+
+    let mut encoded = prefix.to_vec();
+    encoded.resize(PREFIX_LEN + header_len, 0);
+    reader.read_exact(&mut encoded[PREFIX_LEN..]).map_err(|_| truncated())?;
+    let body = &encoded[PREFIX_LEN..];
+    let header = if version == 1 {
+        let legacy: LegacyHeader = bincode::deserialize(body).map_err(|_| invalid_header("unreadable header"))?;
+        ChunkedHeader {
+            plaintext_len: legacy.plaintext_len,
+            segment_len: legacy.segment_len,
+            key_id: legacy.key_id,
+            descriptors: legacy.descriptors,
+            timestamp: legacy.timestamp,
+            epoch_segments: 0,
+            format_version: version,
+        }
+    } else {
+        let header: ChunkedHeader = bincode::deserialize(body).map_err(|_| invalid_header("unreadable header"))?;
+        ChunkedHeader { format_version: version, ..header }
+    };
+    header.validate(version)?;
+    Ok((header, encoded))
+}
+
+/// Exact size of the chunked ciphertext for `plaintext_len` bytes under the default data limits
+pub fn estimate_output_size(plaintext_len: u64, segment_chunks: u64, key_id: &str) -> Result<u64> {
+    estimate_output_size_with(plaintext_len, segment_chunks, key_id, &DataLimits::DEFAULT)
+}
+
+/// `estimate_output_size` for a profile with its own data limits
+pub fn estimate_output_size_with(plaintext_len: u64, segment_chunks: u64, key_id: &str, limits: &DataLimits) -> Result<u64> {
+    let header = ChunkedHeader::with_limits(plaintext_len, segment_chunks, key_id, limits)?;
+    let header_len = header.encode()?.len() as u64;
+    Ok(header.offset_after(header_len, header.segments())? + header.index_len() + TAG_LEN as u64)
+}
+
+/// Key record opening an epoch whose file key is `wrapped`
+pub(crate) fn encode_key_record(wrapped: &WrappedFileKey) -> Result<[u8; KEY_RECORD_LEN]> {
+    if wrapped.ciphertext.len() != WRAPPED_LEN {
+        return Err(HybridGuardError::Encryption("wrapped file key has the wrong length".to_string()));
+    }
+    let mut record = [0u8; KEY_RECORD_LEN];
+    record[..NONCE_LEN].copy_from_slice(&wrapped.nonce);
+    record[NONCE_LEN..].copy_from_slice(&wrapped.ciphertext);
+    Ok(record)
+}
+
+/// One key epoch: its key record and the layer keys it unwraps to
+pub(crate) struct Epoch {
+    pub(crate) record: [u8; KEY_RECORD_LEN],
+    pub(crate) keys: LayerKeys,
+}
+
+/// Read the key record at the start of an epoch and unwrap its layer keys
+pub(crate) fn read_epoch<R: Read>(reader: &mut R, key_manager: &KeyManager) -> Result<Epoch> {
+    let mut record = [0u8; KEY_RECORD_LEN];
+    reader.read_exact(&mut record).map_err(|_| truncated())?;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&record[..NONCE_LEN]);
+    let keys = key_manager.unwrap_file_keys(&WrappedFileKey { nonce, ciphertext: record[NONCE_LEN..].to_vec() })?;
+    Ok(Epoch { record, keys })
+}
+
+/// Encrypt `input` into a chunked ciphertext at `output` in one go
+/// Use `CheckpointedEncryption` directly to survive interruptions
+pub fn encrypt_file(input: &Path, output: &Path, key_manager: &KeyManager, segment_chunks: u64) -> Result<ChunkedStats> {
+    encrypt_file_cancellable(input, output, key_manager, segment_chunks, &CancellationToken::new())
+}
+
+/// `encrypt_file` that stops within one chunk once `cancel` is triggered
+/// A failed or cancelled run removes its partial output
+pub fn encrypt_file_cancellable(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    segment_chunks: u64,
+    cancel: &CancellationToken,
+) -> Result<ChunkedStats> {
+    let mut run = CheckpointedEncryption::open(input, output, key_manager, segment_chunks, None)?
+        .with_cancellation(cancel.clone())
+        .with_write_options(WriteOptions::bulk());
+    let result: Result<()> = (|| {
+        while run.step()? {}
+        Ok(())
+    })();
+    match result {
+        Ok(()) => run.finish(),
+        Err(e) => {
+            drop(run);
+            let _ = fs::remove_file(output);
+            Err(e)
+        }
+    }
+}
+
+/// Encrypt `len` bytes of `input` as a chunked ciphertext into `target` in
+/// one pass, without checkpoints; for outputs that are not one file, such as
+/// the parts of a split set
+pub fn encrypt_into<R: Read, W: Write>(
+    input: &mut R,
+    len: u64,
+    target: &mut W,
+    key_manager: &KeyManager,
+    segment_chunks: u64,
+    cancel: &CancellationToken,
+) -> Result<ChunkedStats> {
+    let keys = key_manager.get_keys()?;
+    let header = ChunkedHeader::with_limits(len, segment_chunks, key_manager.key_id(), &key_manager.data_limits())?;
+    let encoded = header.encode()?;
+    let target = &mut MeteredWriter::new(target, cancel);
+    target.write_all(&encoded)?;
+
+    let pipeline = layers::registry();
+    let start = chain_start(keys, &encoded);
+    let mut chained = start;
+    let mut leaves = Vec::new();
+    let mut epoch: Option<Epoch> = None;
+    for index in 0..header.segments() {
+        let segment_len = header.segment_plaintext_len(index);
+        let mut link = chain_link(keys, &chained);
+        if header.starts_epoch(index) {
+            let (keys, wrapped) = key_manager.new_file_keys()?;
+            let record = encode_key_record(&wrapped)?;
+            target.write_all(&record)?;
+            link.update(record);
+            epoch = Some(Epoch { record, keys });
+        }
+        let record = epoch.as_ref().map(|epoch| &epoch.record[..]).unwrap_or_default();
+        let mut segment_tag = segment_hasher(keys, &start, index, record);
+        let read = encrypt_segment(
+            &pipeline,
+            epoch.as_ref().map(|epoch| &epoch.keys).unwrap_or(keys),
+            &mut *input,
+            segment_len,
+            target,
+            &mut [&mut link, &mut segment_tag],
+            cancel,
+        )?;
+        if read != segment_len {
+            return Err(HybridGuardError::Encryption(format!(
+                "input shrank while it was read ({} of {} bytes in segment {})",
+                read, segment_len, index
+            )));
+        }
+        let segment_tag: Node = segment_tag.finalize().into();
+        target.write_all(&segment_tag)?;
+        link.update(segment_tag);
+        leaves.push(segment_tag);
+        chained = link.finalize().into();
+    }
+    target.write_all(&encode_sealed_index(keys, &header, &start, &leaves))?;
+    target.write_all(&chained)?;
+
+    Ok(ChunkedStats {
+        plaintext_len: header.plaintext_len,
+        segments: header.segments(),
+        epochs: header.epochs(),
+        resumed_segments: 0,
+    })
+}
+
+/// First link of the tag chain, over the prefix and header
+pub(crate) fn chain_start<K: MacKeys + ?Sized>(keys: &K, encoded_header: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(encoded_header);
+    hasher.finalize().into()
+}
+
+/// Hasher for the link that folds the next segment into `previous`
+pub(crate) fn chain_link<K: MacKeys + ?Sized>(keys: &K, previous: &[u8; TAG_LEN]) -> Sha3_256 {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(previous);
+    hasher
+}
+
+/// Hasher for the tag of segment `index`, to be fed its ciphertext
+/// `start` is the first chain link, binding the tag to this file's header
+pub(crate) fn segment_hasher(keys: &LayerKeys, start: &[u8; TAG_LEN], index: u64, record: &[u8]) -> Sha3_256 {
+    let mut hasher = tag::keyed_hasher(keys, SEGMENT_TAG_PURPOSE);
+    hasher.update(start);
+    hasher.update(le_u64(index));
+    hasher.update(record);
+    hasher
+}
+
+/// Tag over the Merkle root of `segments` segment tags, bound to the header like they are
+fn root_tag<K: MacKeys + ?Sized>(keys: &K, start: &[u8; TAG_LEN], segments: u64, root: &Node) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, ROOT_TAG_PURPOSE);
+    hasher.update(start);
+    hasher.update(le_u64(segments));
+    hasher.update(root);
+    hasher.finalize().into()
+}
+
+/// Seal over the Merkle root under a purpose only the profile keys carry,
+/// so a verification key holder cannot re-root a file that still decrypts
+fn root_seal(keys: &LayerKeys, start: &[u8; TAG_LEN], segments: u64, root: &Node) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, ROOT_SEAL_PURPOSE);
+    hasher.update(start);
+    hasher.update(le_u64(segments));
+    hasher.update(root);
+    hasher.finalize().into()
+}
+
+/// The Merkle index over `leaves`, the segment tags in order, up to its root tag
+/// This is all a verification key can check; see `encode_sealed_index`
+pub(crate) fn encode_index<K: MacKeys + ?Sized>(keys: &K, start: &[u8; TAG_LEN], leaves: &[Node]) -> Vec<u8> {
+    build_index(keys, start, leaves, None)
+}
+
+/// The Merkle index as written after the last segment, sealed from format v5
+pub(crate) fn encode_sealed_index(keys: &LayerKeys, header: &ChunkedHeader, start: &[u8; TAG_LEN], leaves: &[Node]) -> Vec<u8> {
+    build_index(keys, start, leaves, header.has_root_seal().then_some(keys))
+}
+
+fn build_index<K: MacKeys + ?Sized>(keys: &K, start: &[u8; TAG_LEN], leaves: &[Node], seal: Option<&LayerKeys>) -> Vec<u8> {
+    let levels = merkle::build(leaves);
+    let root = merkle::root(&levels);
+    let mut out: Vec<u8> = levels.iter().flatten().flatten().copied().collect();
+    out.extend_from_slice(&root_tag(keys, start, leaves.len() as u64, &root));
+    if let Some(seal) = seal {
+        out.extend_from_slice(&root_seal(seal, start, leaves.len() as u64, &root));
+    }
+    out
+}
+
+/// The stored tags of the first `segments` segments, read without their ciphertext
+pub(crate) fn read_segment_tags<R: Read + Seek>(
+    source: &mut R,
+    header: &ChunkedHeader,
+    header_len: u64,
+    segments: u64,
+) -> Result<Vec<Node>> {
+    let mut tags = Vec::new();
+    for index in 0..segments {
+        source.seek(SeekFrom::Start(header.offset_after(header_len, index + 1)? - TAG_LEN as u64))?;
+        let mut stored = [0u8; TAG_LEN];
+        source.read_exact(&mut stored).map_err(|_| truncated())?;
+        tags.push(stored);
+    }
+    Ok(tags)
+}
+
+/// Encrypt `len` bytes from `reader` as one segment, writing the ciphertext
+/// to `out` and feeding it to every hasher in `hashers`; `cancel` is checked
+/// before every chunk
+pub(crate) fn encrypt_segment<R: Read, W: Write>(
+    pipeline: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    reader: R,
+    len: u64,
+    out: &mut W,
+    hashers: &mut [&mut Sha3_256],
+    cancel: &CancellationToken,
+) -> Result<u64> {
+    let mut emit = |bytes: &[u8]| -> Result<()> {
+        out.write_all(bytes)?;
+        for hasher in hashers.iter_mut() {
+            hasher.update(bytes);
+        }
+        Ok(())
+    };
+
+    let mut reader = reader.take(len);
+    let mut encryptor = StreamEncryptor::new(pipeline, keys)?;
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut read = 0u64;
+    loop {
+        cancel.check()?;
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        read += n as u64;
+        emit(&encryptor.update(&buf[..n])?)?;
+    }
+    emit(&encryptor.finish()?)?;
+    Ok(read)
+}
+
+/// Decrypt a chunked ciphertext into `output`
+/// The whole file is authenticated before anything is written
+pub fn decrypt_file(input: &Path, output: &Path, key_manager: &KeyManager) -> Result<ChunkedStats> {
+    decrypt_file_cancellable(input, output, key_manager, &CancellationToken::new())
+}
+
+/// `decrypt_file` that stops within one chunk once `cancel` is triggered
+/// Plaintext is staged beside `output` and only appears there once complete
+pub fn decrypt_file_cancellable(input: &Path, output: &Path, key_manager: &KeyManager, cancel: &CancellationToken) -> Result<ChunkedStats> {
+    decrypt_file_with(input, output, key_manager, cancel, &WriteOptions::bulk())
+}
+
+/// `decrypt_file_cancellable` finishing the output as `options` ask
+pub fn decrypt_file_with(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    cancel: &CancellationToken,
+    options: &WriteOptions,
+) -> Result<ChunkedStats> {
+    decrypt_source_with(&mut BufReader::new(File::open(input)?), input, output, key_manager, cancel, options)
+}
+
+/// `decrypt_file_with` reading the ciphertext from `source`, which errors
+/// call `input`; a split set reads its parts as one source
+pub(crate) fn decrypt_source_with<R: Read + Seek>(
+    source: &mut R,
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    cancel: &CancellationToken,
+    options: &WriteOptions,
+) -> Result<ChunkedStats> {
+    let keys = key_manager.decryption_keys()?;
+    let (header, encoded) = read_encoded_header(source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "{} was encrypted with key {} but key {} is loaded",
+            input.display(),
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+
+    // First pass: walk the tag chain over every segment
+    verify_chain(source, &header, &encoded, keys, Some(keys), cancel)?;
+
+    // Second pass: decrypt segment by segment
+    source.seek(SeekFrom::Start(encoded.len() as u64))?;
+    let mut staged = StagedFile::create(output, None, Contents::Plaintext)?.with_write_options(options.clone());
+    write_segments(source, staged.file(), &header, key_manager, cancel)?;
+    staged.commit()?;
+
+    Ok(ChunkedStats {
+        plaintext_len: header.plaintext_len,
+        segments: header.segments(),
+        epochs: header.epochs(),
+        resumed_segments: 0,
+    })
+}
+
+/// Authenticate and decrypt all of a chunked ciphertext, discarding the plaintext
+pub fn verify_file(input: &Path, key_manager: &KeyManager, cancel: &CancellationToken) -> Result<ChunkedStats> {
+    verify_file_into(input, key_manager, cancel, &mut io::sink())
+}
+
+/// `verify_file` handing the plaintext to `target` segment by segment once
+/// the whole file has been authenticated
+pub fn verify_file_into<W: Write>(input: &Path, key_manager: &KeyManager, cancel: &CancellationToken, target: &mut W) -> Result<ChunkedStats> {
+    let keys = key_manager.decryption_keys()?;
+    let mut source = BufReader::new(File::open(input)?);
+    let (header, encoded) = read_encoded_header(&mut source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "{} was encrypted with key {} but key {} is loaded",
+            input.display(),
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+
+    verify_chain(&mut source, &header, &encoded, keys, Some(keys), cancel)?;
+    source.seek(SeekFrom::Start(encoded.len() as u64))?;
+    write_segments(&mut source, target, &header, key_manager, cancel)?;
+    Ok(ChunkedStats {
+        plaintext_len: header.plaintext_len,
+        segments: header.segments(),
+        epochs: header.epochs(),
+        resumed_segments: 0,
+    })
+}
+
+/// Authenticate and decrypt a chunked ciphertext in one pass over `source`,
+/// which need not seek: a pipe, or armored text through `ArmorReader`
+/// Memory stays at one streaming chunk whatever the file size, but plaintext
+/// reaches `target` before the whole-file tag at the end is checked, so
+/// `target` must be discarded unless this returns Ok. From format v3 each
+/// segment is also checked against its own tag as soon as it ends
+pub fn decrypt_stream<R: Read, W: Write>(
+    source: &mut R,
+    target: &mut W,
+    key_manager: &KeyManager,
+    cancel: &CancellationToken,
+) -> Result<ChunkedStats> {
+    let keys = key_manager.decryption_keys()?;
+    let (header, encoded) = read_encoded_header(source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "ciphertext was encrypted with key {} but key {} is loaded",
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+
+    let target = &mut MeteredWriter::new(target, cancel);
+    let pipeline = layers::registry();
+    let start = chain_start(keys, &encoded);
+    let mut chained = start;
+    let mut leaves = Vec::new();
+    let mut epoch: Option<Epoch> = None;
+    for index in 0..header.segments() {
+        let mut link = chain_link(keys, &chained);
+        if header.starts_epoch(index) {
+            let next = read_epoch(source, key_manager)?;
+            link.update(next.record);
+            epoch = Some(next);
+        }
+        let (segment_keys, record) = match &epoch {
+            Some(epoch) => (&epoch.keys, &epoch.record[..]),
+            None => (keys, &[][..]),
+        };
+        let mut segment = segment_hasher(keys, &start, index, record);
+        let len = header.segment_ciphertext_len(index)?;
+        let mut reader = HashingReader { inner: (&mut *source).take(len), hashers: [&mut link, &mut segment], read: 0 };
+        let plain_len = match decrypt_segment(&pipeline, segment_keys, &header, &mut reader, cancel, |plain| Ok(target.write_all(plain)?)) {
+            Err(HybridGuardError::Cancelled) => return Err(HybridGuardError::Cancelled),
+            decrypted => {
+                // A stream that ends early fails in the layers; say why instead
+                io::copy(&mut reader, &mut io::sink())?;
+                if reader.read != len {
+                    return Err(truncated());
+                }
+                decrypted?
+            }
+        };
+        if plain_len != header.segment_plaintext_len(index) {
+            return Err(forged());
+        }
+        if header.has_segment_tags() {
+            let mut stored = [0u8; TAG_LEN];
+            source.read_exact(&mut stored).map_err(|_| truncated())?;
+            let computed: Node = segment.finalize().into();
+            if !tag::tags_match(&computed, &stored) {
+                return Err(forged());
+            }
+            link.update(stored);
+            leaves.push(stored);
+        }
+        chained = link.finalize().into();
+    }
+    if header.has_merkle_index() {
+        let len = usize::try_from(header.index_len()).map_err(|_| invalid_header("index is too large for this platform"))?;
+        let mut index = vec![0u8; len];
+        source.read_exact(&mut index).map_err(|_| truncated())?;
+        if !tag::tags_match(&index, &encode_sealed_index(keys, &header, &start, &leaves)) {
+            return Err(forged());
+        }
+    }
+    let mut stored = [0u8; TAG_LEN];
+    source.read_exact(&mut stored).map_err(|_| truncated())?;
+    if !tag::tags_match(&chained, &stored) || source.read(&mut [0u8; 1])? != 0 {
+        return Err(forged());
+    }
+    target.flush()?;
+    Ok(ChunkedStats {
+        plaintext_len: header.plaintext_len,
+        segments: header.segments(),
+        epochs: header.epochs(),
+        resumed_segments: 0,
+    })
+}
+
+/// Check every segment against its own tag, then the Merkle index and the
+/// whole-file tag, collecting each damaged region instead of stopping at the
+/// first; nothing is decrypted
+/// Formats v1 and v2 have no segment tags, so damage there is one range over
+/// every segment
+pub fn locate_corruption<R: Read + Seek>(
+    source: &mut R,
+    key_manager: &KeyManager,
+    options: &VerifyOptions,
+    cancel: &CancellationToken,
+) -> Result<VerifyReport> {
+    let keys = key_manager.decryption_keys()?;
+    source.seek(SeekFrom::Start(0))?;
+    let (header, encoded) = read_encoded_header(source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "ciphertext was encrypted with key {} but key {} is loaded",
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+    let header_len = encoded.len() as u64;
+    let segments_end = header.offset_after(header_len, header.segments())?;
+    let end = segments_end + header.index_len() + TAG_LEN as u64;
+    let file_len = source.seek(SeekFrom::End(0))?;
+    let mut report = VerifyReport { segments: header.segments(), ..VerifyReport::default() };
+
+    if !header.has_segment_tags() {
+        source.seek(SeekFrom::Start(header_len))?;
+        match verify_chain(source, &header, &encoded, keys, None, cancel) {
+            Ok(()) => {}
+            Err(HybridGuardError::Integrity(_)) => report.add(
+                CorruptRange { part: CorruptPart::Unlocalized, segment: None, offset: header_len, len: file_len.saturating_sub(header_len) },
+                options,
+            ),
+            Err(e) => return Err(e),
+        }
+        return Ok(report);
+    }
+
+    let start = chain_start(keys, &encoded);
+    let mut chained = start;
+    let mut leaves = Vec::new();
+    let mut record = Vec::new();
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    source.seek(SeekFrom::Start(header_len))?;
+    for index in 0..header.segments() {
+        cancel.check()?;
+        let at = header.offset_after(header_len, index)?;
+        let stored_len = header.stored_segment_len(index)?;
+        if at + stored_len > file_len {
+            report.add(CorruptRange { part: CorruptPart::Missing, segment: None, offset: file_len.max(at), len: end - file_len.max(at) }, options);
+            return Ok(report);
+        }
+
+        let mut link = chain_link(keys, &chained);
+        if header.starts_epoch(index) {
+            record = vec![0u8; KEY_RECORD_LEN];
+            source.read_exact(&mut record).map_err(|_| truncated())?;
+            link.update(&record);
+        }
+        let mut segment = segment_hasher(keys, &start, index, &record);
+        let mut left = header.segment_ciphertext_len(index)?;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            source.read_exact(&mut buf[..n]).map_err(|_| truncated())?;
+            link.update(&buf[..n]);
+            segment.update(&buf[..n]);
+            left -= n as u64;
+        }
+        let mut stored = [0u8; TAG_LEN];
+        source.read_exact(&mut stored).map_err(|_| truncated())?;
+        link.update(stored);
+        chained = link.finalize().into();
+        leaves.push(stored);
+
+        let computed: Node = segment.finalize().into();
+        if !tag::tags_match(&computed, &stored) {
+            report.add(CorruptRange { part: CorruptPart::Segment, segment: Some(index), offset: at, len: stored_len }, options);
+        }
+    }
+
+    if header.has_merkle_index() {
+        if segments_end + header.index_len() > file_len {
+            report.add(CorruptRange { part: CorruptPart::Missing, segment: None, offset: file_len, len: end - file_len }, options);
+            return Ok(report);
+        }
+        let mut stored = vec![0u8; header.index_len() as usize];
+        source.read_exact(&mut stored).map_err(|_| truncated())?;
+        let expected = encode_sealed_index(keys, &header, &start, &leaves);
+        // Each run of differing nodes is one range
+        let mut run: Option<u64> = None;
+        for (node, (stored, expected)) in stored.chunks(TAG_LEN).zip(expected.chunks(TAG_LEN)).enumerate() {
+            let node_at = segments_end + (node * TAG_LEN) as u64;
+            match (tag::tags_match(stored, expected), run) {
+                (false, None) => run = Some(node_at),
+                (true, Some(from)) => {
+                    report.add(CorruptRange { part: CorruptPart::MerkleIndex, segment: None, offset: from, len: node_at - from }, options);
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(from) = run {
+            let index_end = segments_end + header.index_len();
+            report.add(CorruptRange { part: CorruptPart::MerkleIndex, segment: None, offset: from, len: index_end - from }, options);
+        }
+    }
+
+    let tag_at = end - TAG_LEN as u64;
+    if file_len < end {
+        report.add(CorruptRange { part: CorruptPart::Missing, segment: None, offset: file_len, len: end - file_len }, options);
+        return Ok(report);
+    }
+    let mut stored = [0u8; TAG_LEN];
+    source.read_exact(&mut stored).map_err(|_| truncated())?;
+    // Damage found above already breaks the chain; only a lone bad tag is its own range
+    if report.is_intact() && !tag::tags_match(&chained, &stored) {
+        report.add(CorruptRange { part: CorruptPart::FileTag, segment: None, offset: tag_at, len: TAG_LEN as u64 }, options);
+    }
+    if file_len > end {
+        report.add(CorruptRange { part: CorruptPart::Trailing, segment: None, offset: end, len: file_len - end }, options);
+    }
+    Ok(report)
+}
+
+/// Check a chunked ciphertext's Merkle index against its segment tags and
+/// its root tag, without reading any segment's ciphertext
+/// Only the index is authenticated: a segment whose payload was altered
+/// under its old tag still passes, so `verify_file` remains the full check
+pub fn verify_index<R: Read + Seek>(source: &mut R, key_manager: &KeyManager) -> Result<ChunkedStats> {
+    let keys = key_manager.decryption_keys()?;
+    source.seek(SeekFrom::Start(0))?;
+    let (header, encoded) = read_encoded_header(source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "ciphertext was encrypted with key {} but key {} is loaded",
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+    if !header.has_merkle_index() {
+        return Err(HybridGuardError::UnsupportedFormat(format!(
+            "chunked format v{} has no Merkle index; verify it in full instead",
+            header.format_version
+        )));
+    }
+
+    let header_len = encoded.len() as u64;
+    let leaves = read_segment_tags(source, &header, header_len, header.segments())?;
+    let expected = encode_sealed_index(keys, &header, &chain_start(keys, &encoded), &leaves);
+    let at = header.offset_after(header_len, header.segments())?;
+    source.seek(SeekFrom::Start(at))?;
+    let mut stored = vec![0u8; expected.len()];
+    source.read_exact(&mut stored).map_err(|_| truncated())?;
+    if !tag::tags_match(&stored, &expected) {
+        return Err(forged());
+    }
+    // Only the whole-file tag follows the index
+    let end = at + expected.len() as u64 + TAG_LEN as u64;
+    match source.seek(SeekFrom::End(0))? {
+        len if len < end => return Err(truncated()),
+        len if len > end => return Err(forged()),
+        _ => {}
+    }
+    Ok(ChunkedStats {
+        plaintext_len: header.plaintext_len,
+        segments: header.segments(),
+        epochs: header.epochs(),
+        resumed_segments: 0,
+    })
+}
+
+/// Check every byte of a chunked ciphertext against the tag chain and its
+/// Merkle index under a verification key, which decrypts nothing
+/// Segment tags are keyed under a purpose the verification key does not
+/// carry; the chain covers them, so a changed one still fails here
+pub fn verify_integrity<R: Read>(source: &mut R, key: &VerificationKey, cancel: &CancellationToken) -> Result<ChunkedStats> {
+    let (header, encoded) = read_encoded_header(source)?;
+    if header.key_id != key.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "ciphertext was encrypted with key {} but verification key {} is loaded",
+            header.key_id,
+            key.key_id()
+        )));
+    }
+    verify_chain(source, &header, &encoded, key, None, cancel)?;
+    Ok(ChunkedStats {
+        plaintext_len: header.plaintext_len,
+        segments: header.segments(),
+        epochs: header.epochs(),
+        resumed_segments: 0,
+    })
+}
+
+/// Check the whole-file tag chain, reading from just past the header to the end
+/// From format v4 the Merkle index must also match the segment tags the chain covered
+/// With `segment_keys`, each segment tag is checked too: those are keyed
+/// under a purpose a verification key lacks, so a file re-tagged with one
+/// passes `verify_integrity` but is not decrypted
+fn verify_chain<R: Read, K: MacKeys + ?Sized>(
+    source: &mut R,
+    header: &ChunkedHeader,
+    encoded: &[u8],
+    keys: &K,
+    segment_keys: Option<&LayerKeys>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let start = chain_start(keys, encoded);
+    let mut chained = start;
+    let mut leaves = Vec::new();
+    let mut record = [0u8; KEY_RECORD_LEN];
+    for index in 0..header.segments() {
+        cancel.check()?;
+        let mut link = chain_link(keys, &chained);
+        if header.starts_epoch(index) {
+            source.read_exact(&mut record).map_err(|_| truncated())?;
+            link.update(record);
+        }
+        // Format v1 has no key records
+        let record = if header.epoch_segments != 0 { &record[..] } else { &[][..] };
+        let len = header.segment_ciphertext_len(index)?;
+        let mut segment = segment_keys.filter(|_| header.has_segment_tags()).map(|keys| segment_hasher(keys, &start, index, record));
+        let copied = match &mut segment {
+            Some(segment) => {
+                let mut reader = HashingReader { inner: (&mut *source).take(len), hashers: [&mut link, segment], read: 0 };
+                io::copy(&mut reader, &mut io::sink())?
+            }
+            None => io::copy(&mut (&mut *source).take(len), &mut HashWriter(&mut link))?,
+        };
+        if copied != len {
+            return Err(truncated());
+        }
+        if header.has_segment_tags() {
+            let mut stored = [0u8; TAG_LEN];
+            source.read_exact(&mut stored).map_err(|_| truncated())?;
+            if let Some(segment) = segment {
+                let computed: Node = segment.finalize().into();
+                if !tag::tags_match(&computed, &stored) {
+                    return Err(forged());
+                }
+            }
+            link.update(stored);
+            leaves.push(stored);
+        }
+        chained = link.finalize().into();
+    }
+    if header.has_merkle_index() {
+        let len = usize::try_from(header.index_len()).map_err(|_| invalid_header("index is too large for this platform"))?;
+        let mut index = vec![0u8; len];
+        source.read_exact(&mut index).map_err(|_| truncated())?;
+        // Without the profile keys the root seal is left unchecked
+        let expected = match segment_keys {
+            Some(segment_keys) => encode_sealed_index(segment_keys, header, &start, &leaves),
+            None => encode_index(keys, &start, &leaves),
+        };
+        if !tag::tags_match(&index[..expected.len()], &expected) {
+            return Err(forged());
+        }
+    }
+    let mut stored = [0u8; TAG_LEN];
+    source.read_exact(&mut stored).map_err(|_| truncated())?;
+    if !tag::tags_match(&chained, &stored) || source.read(&mut [0u8; 1])? != 0 {
+        return Err(forged());
+    }
+    Ok(())
+}
+
+/// Decrypt every segment, switching keys at each key record
+/// Format v1 segments are all under the profile keys
+fn write_segments<R: Read, W: Write>(
+    source: &mut R,
+    target: &mut W,
+    header: &ChunkedHeader,
+    key_manager: &KeyManager,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let target = &mut MeteredWriter::new(target, cancel);
+    let pipeline = layers::registry();
+    let mut epoch = None;
+    for index in 0..header.segments() {
+        if header.starts_epoch(index) {
+            epoch = Some(read_epoch(source, key_manager)?);
+        }
+        let keys = match &epoch {
+            Some(epoch) => &epoch.keys,
+            None => key_manager.decryption_keys()?,
+        };
+        let reader = (&mut *source).take(header.segment_ciphertext_len(index)?);
+        let plain_len = decrypt_segment(&pipeline, keys, header, reader, cancel, |plain| Ok(target.write_all(plain)?))?;
+        if plain_len != header.segment_plaintext_len(index) {
+            return Err(forged());
+        }
+        // Checked with the chain before any segment was decrypted
+        io::copy(&mut (&mut *source).take(header.segment_tag_len()), &mut io::sink())?;
+    }
+    Ok(())
+}
+
+/// Decrypt one segment's ciphertext, handing each piece of plaintext to `sink`
+/// Returns the plaintext length
+fn decrypt_segment<R: Read>(
+    pipeline: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    header: &ChunkedHeader,
+    mut reader: R,
+    cancel: &CancellationToken,
+    mut sink: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<u64> {
+    let mut decryptor = StreamDecryptor::new(pipeline, keys, &header.descriptors)?;
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut plain_len = 0u64;
+    loop {
+        cancel.check()?;
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let plain = decryptor.update(&buf[..n])?;
+        plain_len += plain.len() as u64;
+        sink(&plain)?;
+    }
+    let plain = decryptor.finish()?;
+    plain_len += plain.len() as u64;
+    sink(&plain)?;
+    Ok(plain_len)
+}
+
+/// Decrypt plaintext bytes `range` of a chunked ciphertext, reading only the segments that hold them
+/// Each segment is checked against its own tag, and from format v4 against
+/// the file's Merkle root, before it is decrypted. Files without segment tags
+/// (formats v1 and v2) are authenticated in full first
+pub fn decrypt_range<R: Read + Seek>(source: &mut R, key_manager: &KeyManager, range: Range<u64>) -> Result<Vec<u8>> {
+    let keys = key_manager.decryption_keys()?;
+    source.seek(SeekFrom::Start(0))?;
+    let (header, encoded) = read_encoded_header(source)?;
+    if header.key_id != key_manager.key_id() {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "ciphertext was encrypted with key {} but key {} is loaded",
+            header.key_id,
+            key_manager.key_id()
+        )));
+    }
+    if range.start > range.end || range.end > header.plaintext_len {
+        return Err(HybridGuardError::InvalidInput(format!(
+            "range {}..{} is outside the {} byte plaintext",
+            range.start, range.end, header.plaintext_len
+        )));
+    }
+    if range.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !header.has_segment_tags() {
+        log::warn!(
+            "chunked format v{} has no segment tags; authenticating the whole file to read {} bytes",
+            header.format_version,
+            range.end - range.start
+        );
+        verify_chain(source, &header, &encoded, keys, None, &CancellationToken::new())?;
+    }
+
+    let start = chain_start(keys, &encoded);
+    let header_len = encoded.len() as u64;
+    let root = if header.has_merkle_index() { Some(read_root(source, &header, header_len, keys, &start)?) } else { None };
+    let pipeline = layers::registry();
+    let mut out = Vec::with_capacity(usize::try_from(range.end - range.start).unwrap_or(0));
+    let mut epoch: Option<(u64, Epoch)> = None;
+    for index in range.start / header.segment_len..=(range.end - 1) / header.segment_len {
+        let first = header.epoch_start(index);
+        if header.epoch_segments != 0 && epoch.as_ref().map(|(at, _)| *at) != Some(first) {
+            source.seek(SeekFrom::Start(header.offset_after(header_len, first)?))?;
+            epoch = Some((first, read_epoch(source, key_manager)?));
+        }
+        let at = header.ciphertext_offset(header_len, index)?;
+        let len = header.segment_ciphertext_len(index)?;
+
+        if header.has_segment_tags() {
+            let record = epoch.as_ref().map(|(_, epoch)| &epoch.record[..]).unwrap_or_default();
+            let mut hasher = segment_hasher(keys, &start, index, record);
+            source.seek(SeekFrom::Start(at))?;
+            if io::copy(&mut (&mut *source).take(len), &mut HashWriter(&mut hasher))? != len {
+                return Err(truncated());
+            }
+            let mut stored = [0u8; TAG_LEN];
+            source.read_exact(&mut stored).map_err(|_| truncated())?;
+            let computed: Node = hasher.finalize().into();
+            if !tag::tags_match(&computed, &stored) {
+                return Err(forged());
+            }
+            if let Some((index_at, root)) = &root {
+                check_inclusion(source, &header, *index_at, root, index, &computed)?;
+            }
+        }
+
+        // Keep only the part of this segment's plaintext inside the range
+        let segment_start = index * header.segment_len;
+        let wanted = range.start.max(segment_start) - segment_start..range.end.min(segment_start + header.segment_len) - segment_start;
+        let mut position = 0u64;
+        let keys = epoch.as_ref().map(|(_, epoch)| &epoch.keys).unwrap_or(keys);
+        source.seek(SeekFrom::Start(at))?;
+        let reader = (&mut *source).take(len);
+        let plain_len = decrypt_segment(&pipeline, keys, &header, reader, &CancellationToken::new(), |plain| {
+            let from = wanted.start.saturating_sub(position).min(plain.len() as u64) as usize;
+            let to = wanted.end.saturating_sub(position).min(plain.len() as u64) as usize;
+            out.extend_from_slice(&plain[from..to.max(from)]);
+            position += plain.len() as u64;
+            Ok(())
+        })?;
+        if plain_len != header.segment_plaintext_len(index) {
+            return Err(forged());
+        }
+    }
+    Ok(out)
+}
+
+/// Offset of the Merkle index and the root it holds, once the root tag and any seal check out
+fn read_root<R: Read + Seek>(
+    source: &mut R,
+    header: &ChunkedHeader,
+    header_len: u64,
+    keys: &LayerKeys,
+    start: &[u8; TAG_LEN],
+) -> Result<(u64, Node)> {
+    let segments = header.segments();
+    let at = header.offset_after(header_len, segments)?;
+    let root = match merkle::level_lens(segments).len() {
+        0 => merkle::EMPTY_ROOT,
+        levels => read_node(source, at + merkle::node_offset(segments, levels - 1, 0))?,
+    };
+    let stored = read_node(source, at + merkle::node_count(segments) * TAG_LEN as u64)?;
+    if !tag::tags_match(&root_tag(keys, start, segments, &root), &stored) {
+        return Err(forged());
+    }
+    if header.has_root_seal() {
+        let stored = read_node(source, at + (merkle::node_count(segments) + 1) * TAG_LEN as u64)?;
+        if !tag::tags_match(&root_seal(keys, start, segments, &root), &stored) {
+            return Err(forged());
+        }
+    }
+    Ok((at, root))
+}
+
+/// Check that `leaf`, the tag computed for segment `index`, is the leaf the
+/// index at `index_at` commits to under `root`
+fn check_inclusion<R: Read + Seek>(
+    source: &mut R,
+    header: &ChunkedHeader,
+    index_at: u64,
+    root: &Node,
+    index: u64,
+    leaf: &Node,
+) -> Result<()> {
+    let segments = header.segments();
+    let siblings = merkle::sibling_positions(segments, index)
+        .into_iter()
+        .map(|(level, position)| {
+            position.map(|position| read_node(source, index_at + merkle::node_offset(segments, level, position))).transpose()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !tag::tags_match(&merkle::fold(*leaf, index, &siblings), root) {
+        return Err(HybridGuardError::Integrity(format!(
+            "segment {} is not part of this file's Merkle index (substituted from another file or version)",
+            index
+        )));
+    }
+    Ok(())
+}
+
+fn read_node<R: Read + Seek>(source: &mut R, at: u64) -> Result<Node> {
+    source.seek(SeekFrom::Start(at))?;
+    let mut node = [0u8; TAG_LEN];
+    source.read_exact(&mut node).map_err(|_| truncated())?;
+    Ok(node)
+}
+
+/// Sink that only hashes what is written to it
+pub(crate) struct HashWriter<'a>(pub(crate) &'a mut Sha3_256);
+
+/// Feeds what it reads to the hashers of a segment, counting the bytes
+struct HashingReader<'a, R> {
+    inner: R,
+    hashers: [&'a mut Sha3_256; 2],
+    read: u64,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for hasher in self.hashers.iter_mut() {
+            hasher.update(&buf[..n]);
+        }
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn invalid_header(reason: &str) -> HybridGuardError {
+    HybridGuardError::Integrity(format!("chunked header is invalid: {}", reason))
+}
+
+pub(crate) fn truncated() -> HybridGuardError {
+    HybridGuardError::Integrity("chunked ciphertext is truncated".to_string())
+}
+
+fn forged() -> HybridGuardError {
+    HybridGuardError::Integrity("chunked ciphertext failed authentication".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_lengths() {
+        let header = ChunkedHeader::new(2 * DEFAULT_CHUNK_SIZE as u64 + 5, 1, "hg-test").unwrap();
+        assert_eq!(header.segments(), 3);
+        assert_eq!(header.segment_plaintext_len(1), DEFAULT_CHUNK_SIZE as u64);
+        assert_eq!(header.segment_plaintext_len(2), 5);
+        assert_eq!(ChunkedHeader::new(0, 1, "hg-test").unwrap().segments(), 0);
+        assert!(ChunkedHeader::new(10, 0, "hg-test").is_err());
+    }
+
+    #[test]
+    fn test_round_trip_and_tamper() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("data.bin");
+        let encrypted = dir.path().join("data.hg");
+        let output = dir.path().join("data.out");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&input, &data).unwrap();
+        let key_manager = KeyManager::from_master_key(&[0x52; 32]).unwrap();
+
+        let stats = encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+        assert_eq!(stats.segments, 4);
+        let len = fs::metadata(&encrypted).unwrap().len();
+        assert_eq!(estimate_output_size(data.len() as u64, 1, key_manager.key_id()).unwrap(), len);
+
+        decrypt_file(&encrypted, &output, &key_manager).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        let mut bytes = fs::read(&encrypted).unwrap();
+        bytes[len as usize / 2] ^= 1;
+        fs::write(&encrypted, &bytes).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert!(matches!(decrypt_file(&encrypted, &output, &key_manager), Err(HybridGuardError::Integrity(_))));
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_stream_decryption_matches_file_decryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("data.bin");
+        let encrypted = dir.path().join("data.hg");
+        let data: Vec<u8> = (0..3 * DEFAULT_CHUNK_SIZE as u32 + 11).map(|i| (i % 239) as u8).collect();
+        fs::write(&input, &data).unwrap();
+        let key_manager = KeyManager::from_master_key(&[0x61; 32]).unwrap();
+        encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+        let bytes = fs::read(&encrypted).unwrap();
+        let cancel = CancellationToken::new();
+
+        let mut plaintext = Vec::new();
+        let stats = decrypt_stream(&mut &bytes[..], &mut plaintext, &key_manager, &cancel).unwrap();
+        assert_eq!(plaintext, data);
+        assert_eq!(stats.segments, 4);
+
+        // A flipped bit fails its segment's tag; a short or long stream fails too
+        let mut tampered = bytes.clone();
+        tampered[bytes.len() / 3] ^= 1;
+        let result = decrypt_stream(&mut &tampered[..], &mut io::sink(), &key_manager, &cancel);
+        assert!(matches!(result, Err(HybridGuardError::Integrity(_))));
+        let result = decrypt_stream(&mut &bytes[..bytes.len() - 1], &mut io::sink(), &key_manager, &cancel);
+        assert!(matches!(result, Err(HybridGuardError::Integrity(_))));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        let result = decrypt_stream(&mut &longer[..], &mut io::sink(), &key_manager, &cancel);
+        assert!(matches!(result, Err(HybridGuardError::Integrity(_))));
+
+        let other = KeyManager::from_master_key(&[0x62; 32]).unwrap();
+        let result = decrypt_stream(&mut &bytes[..], &mut io::sink(), &other, &cancel);
+        assert!(matches!(result, Err(HybridGuardError::KeyMismatch(_))));
+    }
+
+    #[test]
+    fn test_tiny_limits_force_key_epochs() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("data.bin");
+        let encrypted = dir.path().join("data.hg");
+        let output = dir.path().join("data.out");
+        let data: Vec<u8> = (0..5 * DEFAULT_CHUNK_SIZE as u32 + 7).map(|i| (i % 241) as u8).collect();
+        fs::write(&input, &data).unwrap();
+        let limits = DataLimits { max_epoch_bytes: 2 * DEFAULT_CHUNK_SIZE as u64, max_epoch_chunks: 1 << 32 };
+        let key_manager = KeyManager::from_master_key(&[0x53; 32]).unwrap().with_data_limits(limits);
+
+        // Segments asked for 4 chunks shrink to the 2 one key may cover
+        let stats = encrypt_file(&input, &encrypted, &key_manager, 4).unwrap();
+        assert_eq!((stats.segments, stats.epochs), (3, 3));
+        let header = read_header(&mut File::open(&encrypted).unwrap()).unwrap();
+        assert_eq!((header.segment_len, header.epoch_segments), (2 * DEFAULT_CHUNK_SIZE as u64, 1));
+        let len = fs::metadata(&encrypted).unwrap().len();
+        assert_eq!(estimate_output_size_with(data.len() as u64, 4, key_manager.key_id(), &limits).unwrap(), len);
+
+        let stats = decrypt_file(&encrypted, &output, &key_manager).unwrap();
+        assert_eq!(stats.epochs, 3);
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        // Each epoch has its own key, so swapping two key records breaks the file
+        let (_, encoded) = read_encoded_header(&mut File::open(&encrypted).unwrap()).unwrap();
+        let first = encoded.len();
+        let second = header.offset_after(first as u64, 1).unwrap() as usize;
+        let mut bytes = fs::read(&encrypted).unwrap();
+        let record: Vec<u8> = bytes[first..first + KEY_RECORD_LEN].to_vec();
+        assert_ne!(record, bytes[second..second + KEY_RECORD_LEN]);
+        bytes.copy_within(second..second + KEY_RECORD_LEN, first);
+        bytes[second..second + KEY_RECORD_LEN].copy_from_slice(&record);
+        fs::write(&encrypted, &bytes).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert!(matches!(decrypt_file(&encrypted, &output, &key_manager), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_format_v1_still_decrypts() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("v1.hg");
+        let output = dir.path().join("v1.out");
+        let data: Vec<u8> = (0..DEFAULT_CHUNK_SIZE as u32 + 300).map(|i| (i % 199) as u8).collect();
+        let key_manager = KeyManager::from_master_key(&[0x54; 32]).unwrap();
+        let keys = key_manager.get_keys().unwrap();
+
+        // v1: the header without an epoch length, every segment under the profile keys
+        let header = ChunkedHeader { epoch_segments: 0, ..ChunkedHeader::new(data.len() as u64, 1, key_manager.key_id()).unwrap() };
+        let body = bincode::serialize(&(
+            header.plaintext_len,
+            header.segment_len,
+            &header.key_id,
+            &header.descriptors,
+            header.timestamp,
+        ))
+        .unwrap();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&le_u16(1));
+        bytes.extend_from_slice(&le_u32(body.len() as u32));
+        bytes.extend_from_slice(&body);
+        let mut chained = chain_start(keys, &bytes);
+        let pipeline = layers::registry();
+        let mut reader = &data[..];
+        for index in 0..header.segments() {
+            let mut link = chain_link(keys, &chained);
+            let len = header.segment_plaintext_len(index);
+            encrypt_segment(&pipeline, keys, &mut reader, len, &mut bytes, &mut [&mut link], &CancellationToken::new()).unwrap();
+            chained = link.finalize().into();
+        }
+        bytes.extend_from_slice(&chained);
+        fs::write(&encrypted, &bytes).unwrap();
+
+        let stats = decrypt_file(&encrypted, &output, &key_manager).unwrap();
+        assert_eq!((stats.segments, stats.epochs), (2, 1));
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        // No segment tags: a range is read after checking the whole file
+        let range = decrypt_range(&mut File::open(&encrypted).unwrap(), &key_manager, 10..70_000).unwrap();
+        assert_eq!(range, data[10..70_000]);
+    }
+
+    #[test]
+    fn test_ranges_match_full_decryption() {
+        use crate::crypto::drbg::{RandomSource, SeededRandom};
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("data.bin");
+        let encrypted = dir.path().join("data.hg");
+        let data: Vec<u8> = (0..4 * DEFAULT_CHUNK_SIZE as u32 + 999).map(|i| (i.wrapping_mul(13) % 251) as u8).collect();
+        fs::write(&input, &data).unwrap();
+        let limits = DataLimits { max_epoch_bytes: u64::MAX, max_epoch_chunks: 2 };
+        let key_manager = KeyManager::from_master_key(&[0x55; 32]).unwrap().with_data_limits(limits);
+        encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+
+        // Seeded, so a failing range comes back on every run
+        let rng = SeededRandom::new(b"decrypt_range", b"ranges");
+        let below = |bound: u64| {
+            let mut draw = [0u8; 8];
+            rng.fill(&mut draw);
+            u64::from_le_bytes(draw) % bound
+        };
+        let mut source = BufReader::new(File::open(&encrypted).unwrap());
+        let len = data.len() as u64;
+        for _ in 0..24 {
+            let start = below(len + 1);
+            let end = start + below(len.min(start + 3 * DEFAULT_CHUNK_SIZE as u64 / 2) - start + 1);
+            let range = decrypt_range(&mut source, &key_manager, start..end).unwrap();
+            assert_eq!(range, data[start as usize..end as usize], "{}..{}", start, end);
+        }
+        // Across a segment and a key epoch boundary, and the very end
+        let boundary = 2 * DEFAULT_CHUNK_SIZE as u64;
+        assert_eq!(decrypt_range(&mut source, &key_manager, boundary - 3..boundary + 3).unwrap(), data[boundary as usize - 3..boundary as usize + 3]);
+        assert_eq!(decrypt_range(&mut source, &key_manager, len - 1..len).unwrap(), data[data.len() - 1..]);
+        assert!(matches!(decrypt_range(&mut source, &key_manager, len - 1..len + 1), Err(HybridGuardError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_range_authenticates_only_the_segments_it_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("data.bin");
+        let encrypted = dir.path().join("data.hg");
+        let data = vec![0x5A; 3 * DEFAULT_CHUNK_SIZE];
+        fs::write(&input, &data).unwrap();
+        let key_manager = KeyManager::from_master_key(&[0x56; 32]).unwrap();
+        encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+
+        let (header, encoded) = read_encoded_header(&mut File::open(&encrypted).unwrap()).unwrap();
+        let at = header.ciphertext_offset(encoded.len() as u64, 2).unwrap() as usize;
+        let mut bytes = fs::read(&encrypted).unwrap();
+        bytes[at + 100] ^= 1;
+        fs::write(&encrypted, &bytes).unwrap();
+
+        let mut source = File::open(&encrypted).unwrap();
+        assert_eq!(decrypt_range(&mut source, &key_manager, 0..100).unwrap(), data[..100]);
+        let last = 2 * DEFAULT_CHUNK_SIZE as u64;
+        assert!(matches!(decrypt_range(&mut source, &key_manager, last..last + 10), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_substituted_segment_fails_its_inclusion_proof() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("v1"), dir.path().join("v2"));
+        let (a, b) = (dir.path().join("v1.hg"), dir.path().join("v2.hg"));
+        let original = vec![0x11; 3 * DEFAULT_CHUNK_SIZE];
+        fs::write(&first, &original).unwrap();
+        fs::write(&second, vec![0x22; 3 * DEFAULT_CHUNK_SIZE]).unwrap();
+        let limits = DataLimits { max_epoch_bytes: u64::MAX, max_epoch_chunks: 1 };
+        let key_manager = KeyManager::from_master_key(&[0x57; 32]).unwrap().with_data_limits(limits);
+
+        // Headers carry a timestamp in seconds; encrypt until both versions share one
+        let (header, encoded) = loop {
+            encrypt_file(&first, &a, &key_manager, 1).unwrap();
+            encrypt_file(&second, &b, &key_manager, 1).unwrap();
+            let (header, encoded) = read_encoded_header(&mut File::open(&a).unwrap()).unwrap();
+            if read_encoded_header(&mut File::open(&b).unwrap()).unwrap().1 == encoded {
+                break (header, encoded);
+            }
+        };
+
+        // Segment 1 of the other version, with the key record it opens and its tag
+        let from = header.offset_after(encoded.len() as u64, 1).unwrap() as usize;
+        let to = header.offset_after(encoded.len() as u64, 2).unwrap() as usize;
+        let mut bytes = fs::read(&a).unwrap();
+        bytes[from..to].copy_from_slice(&fs::read(&b).unwrap()[from..to]);
+        fs::write(&a, &bytes).unwrap();
+
+        // Its own tag still verifies in this file
+        let keys = key_manager.get_keys().unwrap();
+        let mut hasher = segment_hasher(keys, &chain_start(keys, &encoded), 1, &bytes[from..from + KEY_RECORD_LEN]);
+        hasher.update(&bytes[from + KEY_RECORD_LEN..to - TAG_LEN]);
+        assert!(tag::tags_match(&hasher.finalize(), &bytes[to - TAG_LEN..to]));
+
+        let mut source = File::open(&a).unwrap();
+        assert_eq!(decrypt_range(&mut source, &key_manager, 0..10).unwrap(), original[..10]);
+        let segment = DEFAULT_CHUNK_SIZE as u64;
+        match decrypt_range(&mut source, &key_manager, segment..segment + 10) {
+            Err(HybridGuardError::Integrity(message)) => assert!(message.contains("Merkle index"), "{}", message),
+            other => panic!("substituted segment read as {:?}", other.map(|plain| plain.len())),
+        }
+        assert!(matches!(verify_index(&mut source, &key_manager), Err(HybridGuardError::Integrity(_))));
+        assert!(matches!(verify_file(&a, &key_manager, &CancellationToken::new()), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_file_retagged_with_a_verification_key_does_not_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("v1"), dir.path().join("v2"));
+        let (a, b, out) = (dir.path().join("v1.hg"), dir.path().join("v2.hg"), dir.path().join("v1.out"));
+        fs::write(&first, vec![0x31; 3 * DEFAULT_CHUNK_SIZE]).unwrap();
+        fs::write(&second, vec![0x32; 3 * DEFAULT_CHUNK_SIZE]).unwrap();
+        let limits = DataLimits { max_epoch_bytes: u64::MAX, max_epoch_chunks: 1 };
+        let key_manager = KeyManager::from_master_key(&[0x59; 32]).unwrap().with_data_limits(limits);
+        let key = key_manager.export_verification_key().unwrap();
+
+        let (header, encoded) = loop {
+            encrypt_file(&first, &a, &key_manager, 1).unwrap();
+            encrypt_file(&second, &b, &key_manager, 1).unwrap();
+            let (header, encoded) = read_encoded_header(&mut File::open(&a).unwrap()).unwrap();
+            if read_encoded_header(&mut File::open(&b).unwrap()).unwrap().1 == encoded {
+                break (header, encoded);
+            }
+        };
+        let from = header.offset_after(encoded.len() as u64, 1).unwrap() as usize;
+        let to = header.offset_after(encoded.len() as u64, 2).unwrap() as usize;
+        let mut bytes = fs::read(&a).unwrap();
+        bytes[from..to].copy_from_slice(&fs::read(&b).unwrap()[from..to]);
+
+        // Re-tag the spliced file with everything the verification key can make
+        let start = chain_start(&key, &encoded);
+        let mut chained = start;
+        let mut leaves = Vec::new();
+        for index in 0..header.segments() {
+            let at = header.offset_after(encoded.len() as u64, index).unwrap() as usize;
+            let end = at + header.stored_segment_len(index).unwrap() as usize;
+            let mut link = chain_link(&key, &chained);
+            link.update(&bytes[at..end]);
+            chained = link.finalize().into();
+            leaves.push(bytes[end - TAG_LEN..end].try_into().unwrap());
+        }
+        let index_at = header.offset_after(encoded.len() as u64, header.segments()).unwrap() as usize;
+        let index = encode_index(&key, &start, &leaves);
+        bytes[index_at..index_at + index.len()].copy_from_slice(&index);
+        let tag_at = bytes.len() - TAG_LEN;
+        bytes[tag_at..].copy_from_slice(&chained);
+        fs::write(&a, &bytes).unwrap();
+
+        assert!(verify_integrity(&mut File::open(&a).unwrap(), &key, &CancellationToken::new()).is_ok());
+        assert!(matches!(decrypt_file(&a, &out, &key_manager), Err(HybridGuardError::Integrity(_))));
+        assert!(!out.exists());
+        assert!(matches!(decrypt_stream(&mut File::open(&a).unwrap(), &mut io::sink(), &key_manager, &CancellationToken::new()), Err(HybridGuardError::Integrity(_))));
+        assert!(matches!(verify_index(&mut File::open(&a).unwrap(), &key_manager), Err(HybridGuardError::Integrity(_))));
+    }
+
+    #[test]
+    fn test_quick_verification_reads_only_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("data.bin");
+        let encrypted = dir.path().join("data.hg");
+        fs::write(&input, vec![0x5B; 5 * DEFAULT_CHUNK_SIZE + 17]).unwrap();
+        let key_manager = KeyManager::from_master_key(&[0x58; 32]).unwrap();
+        encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+        let stats = verify_index(&mut File::open(&encrypted).unwrap(), &key_manager).unwrap();
+        assert_eq!(stats.segments, 6);
+        assert_eq!(verify_file(&encrypted, &key_manager, &CancellationToken::new()).unwrap(), stats);
+
+        let (header, encoded) = read_encoded_header(&mut File::open(&encrypted).unwrap()).unwrap();
+        let index_at = header.offset_after(encoded.len() as u64, header.segments()).unwrap() as usize;
+        let original = fs::read(&encrypted).unwrap();
+
+        // A payload byte is outside what the quick check reads
+        let mut bytes = original.clone();
+        bytes[header.ciphertext_offset(encoded.len() as u64, 3).unwrap() as usize + 50] ^= 1;
+        fs::write(&encrypted, &bytes).unwrap();
+        assert!(verify_index(&mut File::open(&encrypted).unwrap(), &key_manager).is_ok());
+        assert!(verify_file(&encrypted, &key_manager, &CancellationToken::new()).is_err());
+
+        // Nodes on the path to segment 0, and the root seal, are
+        for at in [index_at + TAG_LEN, index_at + 7 * TAG_LEN + 3, original.len() - TAG_LEN - 1] {
+            let mut bytes = original.clone();
+            bytes[at] ^= 1;
+            fs::write(&encrypted, &bytes).unwrap();
+            assert!(matches!(verify_index(&mut File::open(&encrypted).unwrap(), &key_manager), Err(HybridGuardError::Integrity(_))));
+            assert!(decrypt_range(&mut File::open(&encrypted).unwrap(), &key_manager, 0..10).is_err());
+        }
+    }
+
+    #[test]
+    fn test_every_damaged_segment_is_located() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, encrypted) = (dir.path().join("large.bin"), dir.path().join("large.hg"));
+        let data: Vec<u8> = (0..16 * DEFAULT_CHUNK_SIZE as u32).map(|i| (i % 241) as u8).collect();
+        fs::write(&input, &data).unwrap();
+        let limits = DataLimits { max_epoch_bytes: 4 * DEFAULT_CHUNK_SIZE as u64, max_epoch_chunks: 1 << 32 };
+        let key_manager = KeyManager::from_master_key(&[0x57; 32]).unwrap().with_data_limits(limits);
+        encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+        let header = read_header(&mut File::open(&encrypted).unwrap()).unwrap();
+        let (_, encoded) = read_encoded_header(&mut File::open(&encrypted).unwrap()).unwrap();
+        let header_len = encoded.len() as u64;
+        let options = VerifyOptions::default();
+        let locate = |options: &VerifyOptions| {
+            locate_corruption(&mut File::open(&encrypted).unwrap(), &key_manager, options, &CancellationToken::new()).unwrap()
+        };
+        assert!(locate(&options).is_intact());
+
+        // Three widely separated segments, one of them opening a key epoch
+        let damaged = [1, 8, 14];
+        let mut bytes = fs::read(&encrypted).unwrap();
+        for index in damaged {
+            bytes[header.ciphertext_offset(header_len, index).unwrap() as usize + 1000] ^= 0x01;
+        }
+        fs::write(&encrypted, &bytes).unwrap();
+        let report = locate(&options);
+        let expected: Vec<CorruptRange> = damaged
+            .iter()
+            .map(|&index| CorruptRange {
+                part: CorruptPart::Segment,
+                segment: Some(index),
+                offset: header.offset_after(header_len, index).unwrap(),
+                len: header.stored_segment_len(index).unwrap(),
+            })
+            .collect();
+        assert_eq!(report.corrupt_ranges, expected);
+        assert_eq!((report.segments, report.unreported), (16, 0));
+        assert_eq!(expected[1].end(), header.offset_after(header_len, 9).unwrap());
+
+        // A lower bound lists the first ones and counts the rest
+        let report = locate(&VerifyOptions { max_report: 2 });
+        assert_eq!(report.corrupt_ranges, expected[..2]);
+        assert_eq!(report.unreported, 1);
+
+        // Damage outside the segments: a Merkle node, then bytes past the end
+        fs::remove_file(&encrypted).unwrap();
+        encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+        let mut bytes = fs::read(&encrypted).unwrap();
+        let index_at = header.offset_after(header_len, header.segments()).unwrap();
+        bytes[index_at as usize + TAG_LEN + 3] ^= 0x01;
+        bytes.extend_from_slice(b"junk");
+        fs::write(&encrypted, &bytes).unwrap();
+        let report = locate(&options);
+        let parts: Vec<(CorruptPart, u64, u64)> = report.corrupt_ranges.iter().map(|range| (range.part, range.offset, range.len)).collect();
+        let file_len = bytes.len() as u64;
+        assert_eq!(
+            parts,
+            [(CorruptPart::MerkleIndex, index_at + TAG_LEN as u64, TAG_LEN as u64), (CorruptPart::Trailing, file_len - 4, 4)]
+        );
+    }
+}
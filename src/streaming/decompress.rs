@@ -0,0 +1,152 @@
+// Decompression under decryption limits
+// Decrypting attacker-supplied data means decompressing an attacker-chosen
+// stream, and a kilobyte can claim to expand to a hundred gigabytes. Every
+// decompressor on the decryption path reads through `decompress_bounded`,
+// which checks the output cap and the expansion ratio as output arrives,
+// wipes what it produced when it gives up, and holds the stream to the
+// uncompressed length its metadata declared. Containers are not compressed
+// yet; this is the only way a compressed payload may be read back.
+
+use crate::error::{HybridGuardError, Result};
+use crate::hybridguard::DecryptLimits;
+use std::io::{self, Read};
+use zeroize::Zeroize;
+
+/// Most bytes read from the decompressor between limit checks
+pub const CHECK_INTERVAL: usize = 64 * 1024;
+
+/// Largest output `limits` allow from `compressed_len` compressed bytes
+pub fn output_cap(compressed_len: usize, limits: &DecryptLimits) -> usize {
+    limits.max_decompressed.min(compressed_len.saturating_mul(limits.max_expansion_ratio))
+}
+
+/// Read everything `decoder` produces from `compressed_len` compressed bytes
+/// Fails with `LimitExceeded` once the output passes `output_cap`, having
+/// read at most one byte past it, and with `Integrity` when the output is not
+/// exactly `declared_len` bytes; partial output is zeroized on every failure
+pub fn decompress_bounded<R: Read>(mut decoder: R, compressed_len: usize, declared_len: usize, limits: &DecryptLimits) -> Result<Vec<u8>> {
+    let cap = output_cap(compressed_len, limits);
+    // A declared length over the cap is refused before any output is produced
+    check_cap(declared_len, cap)?;
+
+    let mut output = Vec::with_capacity(declared_len.min(CHECK_INTERVAL));
+    let mut chunk = vec![0u8; CHECK_INTERVAL];
+    let result = read_bounded(&mut decoder, &mut output, &mut chunk, cap);
+    chunk.zeroize();
+    let result = result.and_then(|()| {
+        if output.len() == declared_len {
+            Ok(())
+        } else {
+            Err(HybridGuardError::Integrity(format!(
+                "decompressed to {} bytes, but the metadata declares {}",
+                output.len(),
+                declared_len
+            )))
+        }
+    });
+    match result {
+        Ok(()) => Ok(output),
+        Err(e) => {
+            output.zeroize();
+            Err(e)
+        }
+    }
+}
+
+/// Append `decoder`'s output to `output` until it ends or passes `cap`
+fn read_bounded<R: Read>(decoder: &mut R, output: &mut Vec<u8>, chunk: &mut [u8], cap: usize) -> Result<()> {
+    loop {
+        // Never ask for more than one byte past the cap
+        let want = (cap - output.len()).saturating_add(1).min(chunk.len());
+        let n = match decoder.read(&mut chunk[..want]) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        check_cap(output.len() + n, cap)?;
+        output.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn check_cap(size: usize, cap: usize) -> Result<()> {
+    if size > cap {
+        return Err(HybridGuardError::LimitExceeded {
+            which: "decompressed output".to_string(),
+            size,
+            limit: cap,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bomb: endless zeros, counting every byte it hands out
+    struct Zeros {
+        produced: usize,
+        remaining: usize,
+    }
+
+    impl Zeros {
+        fn new(len: usize) -> Self {
+            Self { produced: 0, remaining: len }
+        }
+    }
+
+    impl Read for Zeros {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.remaining);
+            buf[..n].fill(0);
+            self.remaining -= n;
+            self.produced += n;
+            Ok(n)
+        }
+    }
+
+    fn limits(max_decompressed: usize, max_expansion_ratio: usize) -> DecryptLimits {
+        DecryptLimits { max_decompressed, max_expansion_ratio, ..DecryptLimits::default() }
+    }
+
+    #[test]
+    fn test_bomb_stops_at_the_output_cap() {
+        let mut bomb = Zeros::new(usize::MAX);
+        let error = decompress_bounded(&mut bomb, 1024, 1000, &limits(4096, 1000)).unwrap_err();
+        assert!(matches!(error, HybridGuardError::LimitExceeded { limit: 4096, .. }), "{}", error);
+        assert_eq!(bomb.produced, 4097);
+    }
+
+    #[test]
+    fn test_bomb_stops_at_the_expansion_ratio() {
+        let mut bomb = Zeros::new(usize::MAX);
+        let error = decompress_bounded(&mut bomb, 10, 500, &limits(1 << 30, 100)).unwrap_err();
+        assert!(matches!(error, HybridGuardError::LimitExceeded { limit: 1000, .. }), "{}", error);
+        assert_eq!(bomb.produced, 1001);
+
+        // A large cap is still checked every CHECK_INTERVAL bytes
+        let mut bomb = Zeros::new(usize::MAX);
+        decompress_bounded(&mut bomb, 1 << 20, 0, &limits(10 * CHECK_INTERVAL + 5, 1000)).unwrap_err();
+        assert_eq!(bomb.produced, 10 * CHECK_INTERVAL + 6);
+    }
+
+    #[test]
+    fn test_declared_length_over_the_cap_is_refused_before_reading() {
+        let mut stream = Zeros::new(10);
+        let error = decompress_bounded(&mut stream, 10, 2000, &limits(1 << 30, 100)).unwrap_err();
+        assert!(matches!(error, HybridGuardError::LimitExceeded { size: 2000, limit: 1000, .. }));
+        assert_eq!(stream.produced, 0);
+    }
+
+    #[test]
+    fn test_output_must_match_the_declared_length() {
+        let limits = DecryptLimits::default();
+        assert_eq!(decompress_bounded(Zeros::new(500), 100, 500, &limits).unwrap(), vec![0u8; 500]);
+        for declared in [499, 501] {
+            let error = decompress_bounded(Zeros::new(500), 100, declared, &limits).unwrap_err();
+            assert!(matches!(error, HybridGuardError::Integrity(_)), "{}: {}", declared, error);
+        }
+        assert!(decompress_bounded(Zeros::new(0), 0, 0, &limits).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,56 @@
+// How much plaintext one key may encrypt
+// The keystream layers and a single KEM encapsulation should not protect
+// unbounded data, so a profile caps the bytes and chunks per key. Chunked
+// encryption starts a new key epoch, with its own file key, whenever the
+// next segment would cross a cap.
+
+use crate::error::{HybridGuardError, Result};
+use crate::streaming::DEFAULT_CHUNK_SIZE;
+use serde::{Deserialize, Serialize};
+
+/// Per-key caps on encrypted data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataLimits {
+    /// Plaintext bytes encrypted under one key
+    pub max_epoch_bytes: u64,
+    /// Streaming chunks encrypted under one key
+    pub max_epoch_chunks: u64,
+}
+
+impl DataLimits {
+    /// 64 GiB or 2^32 chunks per key, whichever comes first
+    pub const DEFAULT: Self = Self { max_epoch_bytes: 64 << 30, max_epoch_chunks: 1 << 32 };
+
+    /// Whole chunks one key may encrypt under both caps
+    pub fn epoch_chunks(&self) -> Result<u64> {
+        let chunks = self.max_epoch_chunks.min(self.max_epoch_bytes / DEFAULT_CHUNK_SIZE as u64);
+        if chunks == 0 {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "data limits must allow at least one {} byte chunk per key",
+                DEFAULT_CHUNK_SIZE
+            )));
+        }
+        Ok(chunks)
+    }
+}
+
+impl Default for DataLimits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_epoch_chunks_takes_the_tighter_cap() {
+        assert_eq!(DataLimits::DEFAULT.epoch_chunks().unwrap(), 1 << 20);
+        let chunks = DataLimits { max_epoch_bytes: u64::MAX, max_epoch_chunks: 3 };
+        assert_eq!(chunks.epoch_chunks().unwrap(), 3);
+        let bytes = DataLimits { max_epoch_bytes: 2 * DEFAULT_CHUNK_SIZE as u64 + 1, max_epoch_chunks: 1 << 32 };
+        assert_eq!(bytes.epoch_chunks().unwrap(), 2);
+        assert!(DataLimits { max_epoch_bytes: 100, max_epoch_chunks: 1 }.epoch_chunks().is_err());
+    }
+}
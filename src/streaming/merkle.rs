@@ -0,0 +1,150 @@
+// Merkle tree over the segment tags of a chunked file
+//
+// The leaves are the segment tags themselves. A parent is SHA3-256 over a
+// 0x01 byte and its two children; a node without a sibling moves up to the
+// next level unchanged. The index stores every level, leaves first and the
+// root last, so the path from one leaf to the root is a few reads at offsets
+// known from the leaf count alone.
+
+use crate::crypto::tag::TAG_LEN;
+use sha3::{Digest, Sha3_256};
+
+/// Bytes per node: a segment tag or a parent hash
+pub const NODE_LEN: usize = TAG_LEN;
+
+/// A leaf or an inner node of the tree
+pub type Node = [u8; NODE_LEN];
+
+/// Root of a tree without leaves
+pub const EMPTY_ROOT: Node = [0u8; NODE_LEN];
+
+/// Nodes on each level for `leaves` leaves, leaves first; empty without leaves
+pub fn level_lens(leaves: u64) -> Vec<u64> {
+    let mut lens = Vec::new();
+    let mut len = leaves;
+    while len > 0 {
+        lens.push(len);
+        if len == 1 {
+            break;
+        }
+        len = len.div_ceil(2);
+    }
+    lens
+}
+
+/// Nodes in the whole tree, leaves included
+pub fn node_count(leaves: u64) -> u64 {
+    level_lens(leaves).iter().sum()
+}
+
+/// Byte offset of node `position` on `level` within the stored tree
+pub fn node_offset(leaves: u64, level: usize, position: u64) -> u64 {
+    let before: u64 = level_lens(leaves).iter().take(level).sum();
+    (before + position) * NODE_LEN as u64
+}
+
+/// Parent of two adjacent nodes
+pub fn parent(left: &Node, right: &Node) -> Node {
+    let mut hasher = Sha3_256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Every level of the tree over `leaves`, leaves first and the root last
+pub fn build(leaves: &[Node]) -> Vec<Vec<Node>> {
+    let mut levels: Vec<Vec<Node>> = Vec::new();
+    let mut level = leaves.to_vec();
+    while !level.is_empty() {
+        let next: Vec<Node> = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => parent(left, right),
+                [only] => *only,
+                _ => unreachable!(),
+            })
+            .collect();
+        let done = level.len() == 1;
+        levels.push(level);
+        if done {
+            break;
+        }
+        level = next;
+    }
+    levels
+}
+
+/// Root of a tree built by `build`
+pub fn root(levels: &[Vec<Node>]) -> Node {
+    levels.last().and_then(|level| level.first()).copied().unwrap_or(EMPTY_ROOT)
+}
+
+/// Levels and positions of the siblings on the path from leaf `index` up to
+/// the root; None on levels where the path's node has no sibling
+pub fn sibling_positions(leaves: u64, index: u64) -> Vec<(usize, Option<u64>)> {
+    let lens = level_lens(leaves);
+    let mut position = index;
+    let mut siblings = Vec::new();
+    for (level, len) in lens.iter().enumerate().take(lens.len().saturating_sub(1)) {
+        let sibling = position ^ 1;
+        siblings.push((level, (sibling < *len).then_some(sibling)));
+        position /= 2;
+    }
+    siblings
+}
+
+/// Root reached from `leaf` at `index` through `siblings`, as `sibling_positions` orders them
+pub fn fold(leaf: Node, index: u64, siblings: &[Option<Node>]) -> Node {
+    let mut node = leaf;
+    let mut position = index;
+    for sibling in siblings {
+        if let Some(sibling) = sibling {
+            node = if position % 2 == 0 { parent(&node, sibling) } else { parent(sibling, &node) };
+        }
+        position /= 2;
+    }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<Node> {
+        (0..n).map(|i| [i; NODE_LEN]).collect()
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(level_lens(0).is_empty());
+        assert_eq!(level_lens(1), vec![1]);
+        assert_eq!(level_lens(5), vec![5, 3, 2, 1]);
+        assert_eq!(node_count(5), 11);
+        assert_eq!(node_offset(5, 2, 1), 9 * NODE_LEN as u64);
+        assert_eq!(root(&build(&[])), EMPTY_ROOT);
+        assert_eq!(root(&build(&leaves(1))), [0; NODE_LEN]);
+
+        let levels = build(&leaves(3));
+        assert_eq!(levels.iter().map(Vec::len).collect::<Vec<_>>(), vec![3, 2, 1]);
+        assert_eq!(levels[1][1], [2; NODE_LEN]);
+        assert_eq!(root(&levels), parent(&parent(&[0; NODE_LEN], &[1; NODE_LEN]), &[2; NODE_LEN]));
+    }
+
+    #[test]
+    fn test_every_path_folds_to_the_root() {
+        for n in 1..=9u8 {
+            let leaves = leaves(n);
+            let levels = build(&leaves);
+            for index in 0..n as u64 {
+                let siblings: Vec<Option<Node>> = sibling_positions(n as u64, index)
+                    .into_iter()
+                    .map(|(level, position)| position.map(|position| levels[level][position as usize]))
+                    .collect();
+                assert_eq!(fold(leaves[index as usize], index, &siblings), root(&levels), "{} of {}", index, n);
+                // Any other leaf at this position misses the root
+                assert_ne!(fold([0xFF; NODE_LEN], index, &siblings), root(&levels));
+            }
+        }
+    }
+}
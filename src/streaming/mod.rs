@@ -0,0 +1,247 @@
+// Chunked encryption pipeline
+// Feeds data through every layer's streaming state, so memory use is bounded
+// by the chunk size rather than the message size
+
+pub mod adapters;
+pub mod checkpoint;
+pub mod chunked;
+pub mod decompress;
+pub mod limits;
+pub mod merkle;
+pub mod shaping;
+pub mod split;
+
+use crate::crypto::hkdf::LayerKeys;
+use crate::error::{HybridGuardError, Result};
+use crate::layers::{EncryptionLayer, LayerDecryptState, LayerDescriptor, LayerEncryptState};
+use std::io::{Read, Write};
+
+/// Default chunk size for reader/writer helpers
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Encrypts one message through all layers, chunk by chunk
+///
+/// The concatenated output of `update` and `finish` is the same ciphertext
+/// the whole-buffer pipeline produces.
+pub struct StreamEncryptor<'a> {
+    states: Vec<Box<dyn LayerEncryptState + 'a>>,
+    started: bool,
+}
+
+impl<'a> StreamEncryptor<'a> {
+    /// Start a message; `layers` are in pipeline order, one key per layer
+    pub fn new(layers: &'a [Box<dyn EncryptionLayer>], keys: &LayerKeys) -> Result<Self> {
+        let keys = keys.in_order();
+        if layers.len() != keys.len() {
+            return Err(HybridGuardError::Layer(format!("expected {} layers, got {}", keys.len(), layers.len())));
+        }
+        let states = layers
+            .iter()
+            .zip(keys.iter())
+            .map(|(layer, key)| layer.begin_encrypt(key))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { states, started: false })
+    }
+
+    /// Encrypt the next chunk of plaintext
+    pub fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let mut out = self.start()?;
+        self.feed(0, chunk.to_vec(), &mut out)?;
+        Ok(out)
+    }
+
+    /// Flush every layer in order; each layer's trailer passes through the outer layers
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut out = self.start()?;
+        for i in 0..self.states.len() {
+            let trailer = self.states[i].finish()?;
+            self.feed(i + 1, trailer, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    /// Emit the headers, innermost last, so each one is encrypted by every outer layer
+    fn start(&mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        if !self.started {
+            self.started = true;
+            for i in (0..self.states.len()).rev() {
+                let header = self.states[i].header();
+                self.feed(i + 1, header, &mut out)?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Pass `data` through layers `from..` and append the result to `out`
+    fn feed(&mut self, from: usize, mut data: Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+        for state in &mut self.states[from..] {
+            if data.is_empty() {
+                return Ok(());
+            }
+            data = state.process_chunk(&data)?;
+        }
+        out.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+/// Decrypts one message through all layers in reverse, chunk by chunk
+pub struct StreamDecryptor<'a> {
+    /// Outermost layer first
+    states: Vec<Box<dyn LayerDecryptState + 'a>>,
+}
+
+impl<'a> StreamDecryptor<'a> {
+    /// Start a message written with the given layer descriptors
+    pub fn new(
+        layers: &'a [Box<dyn EncryptionLayer>],
+        keys: &LayerKeys,
+        descriptors: &[LayerDescriptor],
+    ) -> Result<Self> {
+        let keys = keys.in_order();
+        if layers.len() != keys.len() {
+            return Err(HybridGuardError::Layer(format!("expected {} layers, got {}", keys.len(), layers.len())));
+        }
+        let mut states = Vec::with_capacity(layers.len());
+        for (layer, key) in layers.iter().zip(keys.iter()).rev() {
+            let name = layer.descriptor().name;
+            let version = descriptors
+                .iter()
+                .find(|d| d.name == name)
+                .map(|d| d.version)
+                .ok_or_else(|| HybridGuardError::UnsupportedFormat(format!("ciphertext has no {} layer", name)))?;
+            states.push(layer.begin_decrypt(key, version)?);
+        }
+        Ok(Self { states })
+    }
+
+    /// Decrypt the next chunk of ciphertext
+    pub fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.feed(0, chunk.to_vec(), &mut out)?;
+        Ok(out)
+    }
+
+    /// Flush every layer, outermost first, and verify the message is complete
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        for i in 0..self.states.len() {
+            let rest = self.states[i].finish()?;
+            self.feed(i + 1, rest, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    fn feed(&mut self, from: usize, mut data: Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+        for state in &mut self.states[from..] {
+            if data.is_empty() {
+                return Ok(());
+            }
+            data = state.process_chunk(&data)?;
+        }
+        out.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+/// Encrypt everything from `reader` into `writer`, returning the plaintext length
+pub fn encrypt_stream<R: Read, W: Write>(
+    layers: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    mut reader: R,
+    mut writer: W,
+) -> Result<u64> {
+    let mut encryptor = StreamEncryptor::new(layers, keys)?;
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        total += n as u64;
+        writer.write_all(&encryptor.update(&buf[..n])?)?;
+    }
+    writer.write_all(&encryptor.finish()?)?;
+    Ok(total)
+}
+
+/// Decrypt everything from `reader` into `writer`, returning the plaintext length
+pub fn decrypt_stream<R: Read, W: Write>(
+    layers: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    descriptors: &[LayerDescriptor],
+    mut reader: R,
+    mut writer: W,
+) -> Result<u64> {
+    let mut decryptor = StreamDecryptor::new(layers, keys, descriptors)?;
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let plain = decryptor.update(&buf[..n])?;
+        total += plain.len() as u64;
+        writer.write_all(&plain)?;
+    }
+    let plain = decryptor.finish()?;
+    total += plain.len() as u64;
+    writer.write_all(&plain)?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hkdf::KeyDerivation;
+    use crate::crypto::EncryptedDataBuilder;
+    use crate::encryptor::HybridGuardEncryptor;
+    use crate::layers::{current_descriptors, registry};
+
+    #[test]
+    fn test_stream_pipeline_interoperates_with_whole_buffer() {
+        let keys = KeyDerivation::new(vec![7u8; 32]).derive_all_keys().unwrap();
+        let layers = registry();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+
+        let mut streamed = Vec::new();
+        encrypt_stream(&layers, &keys, &data[..], &mut streamed).unwrap();
+
+        // Streamed ciphertext decrypts with the whole-buffer pipeline and vice versa
+        let encryptor = HybridGuardEncryptor::new();
+        let whole = encryptor.encrypt(&data, &keys).unwrap();
+        assert_eq!(streamed.len(), whole.ciphertext().len());
+
+        let mut decrypted = Vec::new();
+        decrypt_stream(&layers, &keys, whole.descriptors(), whole.ciphertext(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+
+        let whole = EncryptedDataBuilder::from(whole).ciphertext(streamed).tag(&keys).unwrap().build().unwrap();
+        assert_eq!(encryptor.decrypt(&whole, &keys).unwrap(), data);
+    }
+
+    #[test]
+    fn test_small_chunks() {
+        let keys = KeyDerivation::new(vec![9u8; 32]).derive_all_keys().unwrap();
+        let layers = registry();
+        let data = b"chunk by chunk through four layers".to_vec();
+
+        let mut encryptor = StreamEncryptor::new(&layers, &keys).unwrap();
+        let mut ciphertext = Vec::new();
+        for chunk in data.chunks(3) {
+            ciphertext.extend(encryptor.update(chunk).unwrap());
+        }
+        ciphertext.extend(encryptor.finish().unwrap());
+
+        let mut decryptor = StreamDecryptor::new(&layers, &keys, &current_descriptors()).unwrap();
+        let mut plaintext = Vec::new();
+        for chunk in ciphertext.chunks(5) {
+            plaintext.extend(decryptor.update(chunk).unwrap());
+        }
+        plaintext.extend(decryptor.finish().unwrap());
+        assert_eq!(plaintext, data);
+    }
+}
@@ -0,0 +1,760 @@
+// Constant-rate output for channels whose timing and sizes are observable
+//
+// Layout (all integers little-endian):
+//   magic "HGSH", u16 format version, u32 chunk size, u8 flags
+//   (bit 0 = the final frame is padded), 16-byte stream ID,
+//   u32 header length, bincode header (key ID, layer descriptors),
+//   then frames of exactly `chunk size` bytes, each:
+//   sealed body (u8 kind (0 = data, 1 = filler, 2 = final),
+//   u32 payload length, payload, zero padding), 32-byte tag
+//
+// The data frames' payloads, concatenated, are the pipeline ciphertext.
+// One frame leaves per interval: data while ciphertext is waiting, filler
+// while the producer is behind, so sizes and timing show only how long the
+// stream ran. Each body is XORed with a keystream under the frame-seal key,
+// labelled with the stream ID and frame number, so a filler frame cannot be
+// told from a data frame nor a short payload from a full one. Every tag
+// chains the previous one and covers the whole sealed frame, so filler can
+// be neither injected nor dropped, and a stream that stops before its final
+// frame is rejected as truncated. Without `pad_final` the final frame ends
+// right after its payload and tag. Format v1 streams shared the sparse
+// magic and are not read.
+
+use crate::crypto::container::{le_u16, le_u32, le_u64, u16_at, u32_at};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::keystream::XofStream;
+use crate::crypto::secret::SecretBytes;
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::error::{HybridGuardError, Result};
+use crate::layers::{EncryptionLayer, LayerDescriptor};
+use crate::streaming::{StreamDecryptor, StreamEncryptor, DEFAULT_CHUNK_SIZE};
+use crate::timing::Clock;
+use serde::{Deserialize, Serialize};
+use sha3::Digest;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::Duration;
+
+/// Magic bytes at the start of every shaped stream
+pub const MAGIC: [u8; 4] = *b"HGSH";
+
+/// Shaped stream format written by this build
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Smallest chunk accepted; a frame needs room for its head, tag and payload
+pub const MIN_CHUNK_SIZE: usize = 64;
+
+/// Largest chunk accepted, which bounds what the reader buffers (16 MiB)
+pub const MAX_CHUNK_SIZE: usize = 256 * DEFAULT_CHUNK_SIZE;
+
+/// Bytes before the header: magic, version, chunk size, flags, stream ID,
+/// header length
+pub const PREFIX_LEN: usize = 4 + 2 + 4 + 1 + 16 + 4;
+
+/// Bytes before each frame's payload: kind, payload length
+const FRAME_HEAD_LEN: usize = 1 + 4;
+
+/// Largest header accepted
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// Encrypted chunks the producer may run ahead of the schedule
+const MAX_QUEUED: usize = 16;
+
+/// Purpose of the frame tag chain key
+const TAG_PURPOSE: KeyPurpose = KeyPurpose::Mac("shaped-frame");
+
+const FLAG_PAD_FINAL: u8 = 1;
+
+const KIND_DATA: u8 = 0;
+const KIND_FILLER: u8 = 1;
+const KIND_FINAL: u8 = 2;
+
+/// How a shaped stream is emitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ShapingPolicy {
+    /// Bytes of every frame on the wire
+    pub chunk_size: usize,
+    /// Time between frames
+    pub interval: Duration,
+    /// Pad the final frame to `chunk_size` too, so the total size is a
+    /// multiple of it and reveals nothing about the plaintext length
+    pub pad_final: bool,
+}
+
+impl ShapingPolicy {
+    pub fn new(chunk_size: usize, interval: Duration) -> Result<Self> {
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(HybridGuardError::InvalidInput(format!(
+                "shaped chunks must be between {} and {} bytes",
+                MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+            )));
+        }
+        if interval.is_zero() {
+            return Err(HybridGuardError::InvalidInput("the shaping interval must be above zero".to_string()));
+        }
+        Ok(Self { chunk_size, interval, pad_final: true })
+    }
+
+    /// Let the final frame stop after its payload
+    pub fn with_pad_final(mut self, pad_final: bool) -> Self {
+        self.pad_final = pad_final;
+        self
+    }
+
+    /// Payload bytes one frame carries
+    pub fn payload_capacity(&self) -> usize {
+        self.chunk_size - FRAME_HEAD_LEN - TAG_LEN
+    }
+}
+
+/// `<bytes>@<interval>`, the interval in `us`, `ms` or `s`, e.g. `4096@100ms`
+impl FromStr for ShapingPolicy {
+    type Err = HybridGuardError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || HybridGuardError::InvalidInput(format!("invalid shape '{}', expected <bytes>@<interval> such as 4096@100ms", s));
+        let (size, interval) = s.split_once('@').ok_or_else(invalid)?;
+        let size = size.trim().parse::<usize>().map_err(|_| invalid())?;
+        let interval = interval.trim();
+        let (number, unit): (&str, fn(u64) -> Duration) = if let Some(n) = interval.strip_suffix("us") {
+            (n, Duration::from_micros)
+        } else if let Some(n) = interval.strip_suffix("ms") {
+            (n, Duration::from_millis)
+        } else if let Some(n) = interval.strip_suffix('s') {
+            (n, Duration::from_secs)
+        } else {
+            return Err(invalid());
+        };
+        let interval = unit(number.parse::<u64>().map_err(|_| invalid())?);
+        Self::new(size, interval)
+    }
+}
+
+impl fmt::Display for ShapingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self.interval.as_micros();
+        match micros {
+            _ if micros % 1_000_000 == 0 => write!(f, "{}@{}s", self.chunk_size, micros / 1_000_000),
+            _ if micros % 1_000 == 0 => write!(f, "{}@{}ms", self.chunk_size, micros / 1_000),
+            _ => write!(f, "{}@{}us", self.chunk_size, micros),
+        }
+    }
+}
+
+/// Metadata carried in the first data frames
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShapedHeader {
+    pub key_id: String,
+    pub descriptors: Vec<LayerDescriptor>,
+}
+
+/// What a shaped encryption sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShapingReport {
+    pub plaintext_len: u64,
+    /// Frames sent, the final one included
+    pub frames: u64,
+    /// Frames that carried only filler
+    pub filler_frames: u64,
+}
+
+/// Whether `bytes` starts a shaped stream
+pub fn is_shaped(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Cuts a byte stream into sealed, authenticated frames, one per call to `next_frame`
+pub struct FrameEncoder<'a> {
+    keys: &'a LayerKeys,
+    seal: SecretBytes,
+    stream_id: [u8; 16],
+    policy: ShapingPolicy,
+    prefix: Option<Vec<u8>>,
+    pending: Vec<u8>,
+    closed: bool,
+    finished: bool,
+    chained: [u8; TAG_LEN],
+    /// Number of the next frame, which labels its keystream
+    index: u64,
+}
+
+impl<'a> FrameEncoder<'a> {
+    pub fn new(keys: &'a LayerKeys, policy: ShapingPolicy, header: &ShapedHeader) -> Result<Self> {
+        let header = bincode::serialize(header).map_err(|e| HybridGuardError::Encryption(format!("shaped header: {}", e)))?;
+        if header.len() > MAX_HEADER_LEN {
+            return Err(HybridGuardError::Encryption("shaped header is too large".to_string()));
+        }
+        let stream_id = rand::random::<[u8; 16]>();
+        let mut prefix = Vec::with_capacity(PREFIX_LEN + header.len());
+        prefix.extend_from_slice(&MAGIC);
+        prefix.extend_from_slice(&le_u16(FORMAT_VERSION));
+        prefix.extend_from_slice(&le_u32(policy.chunk_size as u32));
+        prefix.push(if policy.pad_final { FLAG_PAD_FINAL } else { 0 });
+        prefix.extend_from_slice(&stream_id);
+        prefix.extend_from_slice(&le_u32(header.len() as u32));
+        prefix.extend_from_slice(&header);
+        let chained = chain_start(keys, &prefix);
+        Ok(Self {
+            keys,
+            seal: keys.derive_key(KeyPurpose::FrameSeal),
+            stream_id,
+            policy,
+            prefix: Some(prefix),
+            pending: Vec::new(),
+            closed: false,
+            finished: false,
+            chained,
+            index: 0,
+        })
+    }
+
+    /// Queue bytes for the data frames
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// No more bytes follow; the frame that empties the queue is the final one
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Bytes queued but not yet framed
+    pub fn queued(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether the final frame has been produced
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The next frame: data while bytes are queued, filler while none are,
+    /// the stream prefix ahead of the first; None once the final frame is out
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.finished {
+            return None;
+        }
+        let capacity = self.policy.payload_capacity();
+        let take = self.pending.len().min(capacity);
+        let kind = if self.closed && self.pending.len() <= capacity {
+            KIND_FINAL
+        } else if take > 0 {
+            KIND_DATA
+        } else {
+            KIND_FILLER
+        };
+
+        let mut frame = self.prefix.take().unwrap_or_default();
+        let start = frame.len();
+        frame.push(kind);
+        frame.extend_from_slice(&le_u32(take as u32));
+        frame.extend(self.pending.drain(..take));
+        if kind != KIND_FINAL || self.policy.pad_final {
+            frame.resize(start + self.policy.chunk_size - TAG_LEN, 0);
+        }
+        XofStream::new(self.seal.as_bytes(), &frame_label(&self.stream_id, self.index)).xor(&mut frame[start..]);
+        let tag = frame_tag(self.keys, &self.chained, &frame[start..]);
+        frame.extend_from_slice(&tag);
+        self.chained = tag;
+        self.index += 1;
+        self.finished = kind == KIND_FINAL;
+        Some(frame)
+    }
+}
+
+/// Encrypt everything from `reader` and write it to `writer` as a shaped
+/// stream: one `policy.chunk_size` frame every `policy.interval` by `clock`
+///
+/// The reader is drained on its own thread, so a slow producer only adds
+/// filler frames and a fast one runs at most a few chunks ahead. A frame
+/// that falls due while `writer` is still busy goes out at once, keeping the
+/// schedule's origin. Returns once the final frame is written and the reader
+/// has been drained.
+pub fn encrypt_stream_with_shaping<R: Read + Send, W: Write>(
+    layers: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    key_id: &str,
+    reader: R,
+    mut writer: W,
+    policy: &ShapingPolicy,
+    clock: &dyn Clock,
+) -> Result<ShapingReport> {
+    let header = ShapedHeader { key_id: key_id.to_string(), descriptors: layers.iter().map(|l| l.descriptor()).collect() };
+    let mut frames = FrameEncoder::new(keys, *policy, &header)?;
+
+    let (sender, receiver) = mpsc::sync_channel::<Result<Vec<u8>>>(MAX_QUEUED);
+    std::thread::scope(|scope| {
+        let producer = scope.spawn(move || produce(layers, keys, reader, policy.payload_capacity(), sender));
+        // Dropped on any early return, which stops the producer's next send
+        let receiver = receiver;
+
+        let mut report = ShapingReport::default();
+        let origin = clock.now();
+        while !frames.is_finished() {
+            let due = scheduled(origin, policy.interval, report.frames);
+            let now = clock.now();
+            if due > now {
+                clock.sleep(due - now);
+            }
+            // Take no more than one frame's worth, so a fast producer waits on the channel
+            while frames.queued() < policy.payload_capacity() {
+                match receiver.try_recv() {
+                    Ok(Ok(ciphertext)) => frames.push(&ciphertext),
+                    Ok(Err(e)) => return Err(e),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        frames.close();
+                        break;
+                    }
+                }
+            }
+            let filler = frames.queued() == 0 && !frames.closed;
+            let frame = frames.next_frame().expect("frames remain until the final one");
+            writer.write_all(&frame)?;
+            writer.flush()?;
+            report.frames += 1;
+            report.filler_frames += u64::from(filler);
+        }
+        report.plaintext_len = producer.join().map_err(|_| HybridGuardError::Encryption("shaping producer panicked".to_string()))?;
+        Ok(report)
+    })
+}
+
+/// When frame `index` is due
+fn scheduled(origin: Duration, interval: Duration, index: u64) -> Duration {
+    let offset = interval.as_nanos().saturating_mul(u128::from(index)).min(u128::from(u64::MAX));
+    origin.saturating_add(Duration::from_nanos(offset as u64))
+}
+
+/// Read and encrypt `reader` on the producer thread, sending ciphertext as
+/// it is produced; an error is sent too, so it never passes for the end
+fn produce<R: Read>(
+    layers: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    mut reader: R,
+    chunk_len: usize,
+    sender: mpsc::SyncSender<Result<Vec<u8>>>,
+) -> u64 {
+    let mut total = 0u64;
+    let result = (|| {
+        let mut encryptor = StreamEncryptor::new(layers, keys)?;
+        let mut buf = vec![0u8; chunk_len];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            total += n as u64;
+            let ciphertext = encryptor.update(&buf[..n])?;
+            if !ciphertext.is_empty() && sender.send(Ok(ciphertext)).is_err() {
+                // The emitter gave up; nothing is waiting for the rest
+                return Ok(());
+            }
+        }
+        let _ = sender.send(Ok(encryptor.finish()?));
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = sender.send(Err(e));
+    }
+    total
+}
+
+/// The stream prefix and header, read without authenticating them
+struct Prefix {
+    bytes: Vec<u8>,
+    header: ShapedHeader,
+    chunk_size: usize,
+    pad_final: bool,
+    stream_id: [u8; 16],
+}
+
+impl Prefix {
+    fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut bytes = vec![0u8; PREFIX_LEN];
+        read_exact(reader, &mut bytes)?;
+        if bytes[..4] != MAGIC {
+            return Err(HybridGuardError::UnsupportedFormat("not a shaped HybridGuard stream".to_string()));
+        }
+        let version = u16_at(&bytes, 4);
+        if version != FORMAT_VERSION {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "shaped stream format v{} is not supported (this build reads v{})",
+                version, FORMAT_VERSION
+            )));
+        }
+        let chunk_size = u32_at(&bytes, 6) as usize;
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(HybridGuardError::Integrity("shaped stream chunk size is out of range".to_string()));
+        }
+        let header_len = u32_at(&bytes, PREFIX_LEN - 4) as usize;
+        if header_len > MAX_HEADER_LEN {
+            return Err(HybridGuardError::Integrity("shaped stream header is too large".to_string()));
+        }
+        bytes.resize(PREFIX_LEN + header_len, 0);
+        read_exact(reader, &mut bytes[PREFIX_LEN..])?;
+        let header: ShapedHeader = bincode::deserialize(&bytes[PREFIX_LEN..])
+            .map_err(|_| HybridGuardError::Integrity("shaped stream header is unreadable".to_string()))?;
+        let mut stream_id = [0u8; 16];
+        stream_id.copy_from_slice(&bytes[11..27]);
+        Ok(Self { header, chunk_size, pad_final: bytes[10] & FLAG_PAD_FINAL != 0, stream_id, bytes })
+    }
+}
+
+/// Reads sealed frames back, checking each tag before opening it
+struct FrameReader<'k, R: Read> {
+    inner: R,
+    keys: &'k LayerKeys,
+    seal: SecretBytes,
+    stream_id: [u8; 16],
+    chunk_size: usize,
+    pad_final: bool,
+    chained: [u8; TAG_LEN],
+    index: u64,
+    done: bool,
+}
+
+impl<'k, R: Read> FrameReader<'k, R> {
+    /// Parse the prefix and header, which the first frame's tag chains
+    fn new(mut inner: R, keys: &'k LayerKeys) -> Result<(Self, ShapedHeader)> {
+        let prefix = Prefix::read(&mut inner)?;
+        let frames = Self {
+            inner,
+            keys,
+            seal: keys.derive_key(KeyPurpose::FrameSeal),
+            stream_id: prefix.stream_id,
+            chunk_size: prefix.chunk_size,
+            pad_final: prefix.pad_final,
+            chained: chain_start(keys, &prefix.bytes),
+            index: 0,
+            done: false,
+        };
+        Ok((frames, prefix.header))
+    }
+
+    /// Kind and payload of the next frame; None after the final frame
+    fn next_frame(&mut self) -> Result<Option<(u8, Vec<u8>)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let capacity = self.chunk_size - FRAME_HEAD_LEN - TAG_LEN;
+        let mut keystream = XofStream::new(self.seal.as_bytes(), &frame_label(&self.stream_id, self.index));
+        let mut frame = vec![0u8; FRAME_HEAD_LEN];
+        read_exact(&mut self.inner, &mut frame)?;
+        let mut head = [0u8; FRAME_HEAD_LEN];
+        head.copy_from_slice(&frame);
+        keystream.xor(&mut head);
+        let (kind, len) = (head[0], u32_at(&head, 1) as usize);
+        if kind > KIND_FINAL || len > capacity || (kind == KIND_FILLER && len > 0) {
+            return Err(forged());
+        }
+        // Only an unpadded final frame is shorter than a chunk
+        let body_len = if kind == KIND_FINAL && !self.pad_final { len } else { capacity };
+        frame.resize(FRAME_HEAD_LEN + body_len + TAG_LEN, 0);
+        read_exact(&mut self.inner, &mut frame[FRAME_HEAD_LEN..])?;
+
+        let (body, stored) = frame.split_at(FRAME_HEAD_LEN + body_len);
+        let tag = frame_tag(self.keys, &self.chained, body);
+        if !tag::tags_match(&tag, stored) {
+            return Err(forged());
+        }
+        self.chained = tag;
+        self.index += 1;
+        self.done = kind == KIND_FINAL;
+        let mut payload = body[FRAME_HEAD_LEN..FRAME_HEAD_LEN + len].to_vec();
+        keystream.xor(&mut payload);
+        Ok(Some((kind, payload)))
+    }
+
+    /// Payload of the next data frame, skipping filler; None after the final frame
+    fn next_payload(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some((kind, payload)) = self.next_frame()? {
+            if kind != KIND_FILLER {
+                return Ok(Some(payload));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Header of a shaped stream, read without authenticating it
+pub fn read_header<R: Read>(mut reader: R) -> Result<ShapedHeader> {
+    Prefix::read(&mut reader).map(|prefix| prefix.header)
+}
+
+/// Decrypt a shaped stream from `reader` into `writer`, discarding filler,
+/// and return the plaintext length
+/// Plaintext is released frame by frame as each is authenticated; a stream
+/// that ends before its final frame fails after the rest has been written
+pub fn decrypt_shaped_stream<R: Read, W: Write>(
+    layers: &[Box<dyn EncryptionLayer>],
+    keys: &LayerKeys,
+    key_id: &str,
+    reader: R,
+    mut writer: W,
+) -> Result<u64> {
+    let (mut frames, header) = FrameReader::new(reader, keys)?;
+    if header.key_id != key_id {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "stream was encrypted with key {} but key {} is loaded",
+            header.key_id, key_id
+        )));
+    }
+
+    let mut decryptor = StreamDecryptor::new(layers, keys, &header.descriptors)?;
+    let mut total = 0u64;
+    while let Some(ciphertext) = frames.next_payload()? {
+        let plain = decryptor.update(&ciphertext)?;
+        total += plain.len() as u64;
+        writer.write_all(&plain)?;
+    }
+    let plain = decryptor.finish()?;
+    total += plain.len() as u64;
+    writer.write_all(&plain)?;
+    Ok(total)
+}
+
+/// `read_exact` that reports a short stream as truncation
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => truncated(),
+        _ => e.into(),
+    })
+}
+
+/// First link of the tag chain, over the prefix
+fn chain_start(keys: &LayerKeys, prefix: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(prefix);
+    hasher.finalize().into()
+}
+
+/// Keystream label of frame `index` of the stream `stream_id`
+fn frame_label(stream_id: &[u8; 16], index: u64) -> [u8; 24] {
+    let mut label = [0u8; 24];
+    label[..16].copy_from_slice(stream_id);
+    label[16..].copy_from_slice(&le_u64(index));
+    label
+}
+
+/// Tag of one frame, chained to the one before it
+fn frame_tag(keys: &LayerKeys, previous: &[u8; TAG_LEN], frame: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = tag::keyed_hasher(keys, TAG_PURPOSE);
+    hasher.update(previous);
+    hasher.update(frame);
+    hasher.finalize().into()
+}
+
+fn truncated() -> HybridGuardError {
+    HybridGuardError::Integrity("shaped stream ended before its final frame".to_string())
+}
+
+fn forged() -> HybridGuardError {
+    HybridGuardError::Integrity("shaped stream frame failed authentication".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hkdf::KeyDerivation;
+    use crate::layers::registry;
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+
+    /// Clock that moves only when slept on; each sleep also yields a little
+    /// real time, so the producer thread gets to run
+    #[derive(Default)]
+    struct FakeClock {
+        now: Mutex<Duration>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Records the clock reading and size of every write
+    struct Wire {
+        clock: Arc<FakeClock>,
+        bytes: Vec<u8>,
+        writes: Vec<(Duration, usize)>,
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push((self.clock.now(), buf.len()));
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reader that has nothing until its gate opens
+    struct Gated {
+        gate: Receiver<()>,
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for Gated {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == 0 {
+                let _ = self.gate.recv();
+            }
+            let n = buf.len().min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    fn keys() -> LayerKeys {
+        KeyDerivation::new(vec![0x53u8; 32]).derive_all_keys().unwrap()
+    }
+
+    /// Bytes before the first frame
+    fn prefix_len(stream: &[u8]) -> usize {
+        PREFIX_LEN + u32_at(stream, PREFIX_LEN - 4) as usize
+    }
+
+    /// Kind of every frame, which only the keys reveal
+    fn frame_kinds(stream: &[u8], keys: &LayerKeys) -> Vec<u8> {
+        let (mut frames, _) = FrameReader::new(stream, keys).unwrap();
+        std::iter::from_fn(|| frames.next_frame().unwrap().map(|(kind, _)| kind)).collect()
+    }
+
+    fn decrypt(stream: &[u8], keys: &LayerKeys) -> Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        decrypt_shaped_stream(&registry(), keys, "shaping-test", stream, &mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn test_policy_parsing() {
+        let policy: ShapingPolicy = "4096@100ms".parse().unwrap();
+        assert_eq!((policy.chunk_size, policy.interval, policy.pad_final), (4096, Duration::from_millis(100), true));
+        assert_eq!("512@250us".parse::<ShapingPolicy>().unwrap().interval, Duration::from_micros(250));
+        assert_eq!("512@2s".parse::<ShapingPolicy>().unwrap().interval, Duration::from_secs(2));
+        assert_eq!(policy.to_string(), "4096@100ms");
+        for bad in ["4096", "4096@", "4096@100", "4096@0ms", "16@100ms", "x@1s"] {
+            assert!(bad.parse::<ShapingPolicy>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_one_full_frame_per_interval() {
+        let keys = keys();
+        let clock = Arc::new(FakeClock::default());
+        let policy = ShapingPolicy::new(1024, Duration::from_millis(100)).unwrap();
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let mut wire = Wire { clock: clock.clone(), bytes: Vec::new(), writes: Vec::new() };
+
+        let report =
+            encrypt_stream_with_shaping(&registry(), &keys, "shaping-test", &data[..], &mut wire, &policy, clock.as_ref()).unwrap();
+        assert_eq!(report.plaintext_len, data.len() as u64);
+        assert_eq!(report.frames as usize, wire.writes.len());
+
+        // Frame k leaves at exactly k intervals, and every frame is the same size
+        for (k, &(at, len)) in wire.writes.iter().enumerate() {
+            assert_eq!(at, policy.interval * k as u32);
+            assert_eq!(len, if k == 0 { prefix_len(&wire.bytes) + 1024 } else { 1024 });
+        }
+        let kinds = frame_kinds(&wire.bytes, &keys);
+        assert_eq!(kinds.iter().filter(|&&kind| kind == KIND_FILLER).count() as u64, report.filler_frames);
+        assert_eq!(kinds.last(), Some(&KIND_FINAL));
+        assert_eq!(decrypt(&wire.bytes, &keys).unwrap(), data);
+    }
+
+    #[test]
+    fn test_slow_reader_gets_filler() {
+        let keys = keys();
+        let clock = Arc::new(FakeClock::default());
+        let policy = ShapingPolicy::new(512, Duration::from_millis(10)).unwrap();
+        let (open, gate) = mpsc::channel();
+        let reader = Gated { gate, data: b"late but intact".to_vec(), pos: 0 };
+        let mut wire = Wire { clock: clock.clone(), bytes: Vec::new(), writes: Vec::new() };
+
+        // Open the gate once frame 5 is due; the frames before it have nothing to carry
+        struct Opening<'c> {
+            clock: &'c FakeClock,
+            open: Mutex<Option<mpsc::Sender<()>>>,
+        }
+        impl Clock for Opening<'_> {
+            fn now(&self) -> Duration {
+                self.clock.now()
+            }
+            fn sleep(&self, duration: Duration) {
+                self.clock.sleep(duration);
+                if self.clock.now() >= Duration::from_millis(50) {
+                    self.open.lock().unwrap().take();
+                }
+            }
+        }
+        let opening = Opening { clock: clock.as_ref(), open: Mutex::new(Some(open)) };
+
+        let report = encrypt_stream_with_shaping(&registry(), &keys, "shaping-test", reader, &mut wire, &policy, &opening).unwrap();
+        let kinds = frame_kinds(&wire.bytes, &keys);
+        // Frames 0 to 4 fall due before any plaintext exists
+        assert!(kinds[..5].iter().all(|&kind| kind == KIND_FILLER), "{:?}", kinds);
+        assert!(report.filler_frames >= 4);
+        assert_eq!(decrypt(&wire.bytes, &keys).unwrap(), b"late but intact");
+    }
+
+    #[test]
+    fn test_filler_is_authenticated() {
+        let keys = keys();
+        let policy = ShapingPolicy::new(256, Duration::from_millis(1)).unwrap();
+        let header = ShapedHeader { key_id: "shaping-test".to_string(), descriptors: crate::layers::current_descriptors() };
+        let mut frames = FrameEncoder::new(&keys, policy, &header).unwrap();
+        let mut ciphertext = Vec::new();
+        crate::streaming::encrypt_stream(&registry(), &keys, &b"framed"[..], &mut ciphertext).unwrap();
+
+        // Nothing is queued yet, so the first frame is filler
+        let mut stream = frames.next_frame().unwrap();
+        let filler_at = prefix_len(&stream);
+        frames.push(&ciphertext);
+        frames.close();
+        while let Some(frame) = frames.next_frame() {
+            stream.extend(frame);
+        }
+        assert_eq!(frame_kinds(&stream, &keys)[0], KIND_FILLER);
+        // On the wire neither its kind nor its empty payload shows
+        assert_ne!(stream[filler_at..filler_at + FRAME_HEAD_LEN], [KIND_FILLER, 0, 0, 0, 0]);
+        assert!(stream[filler_at + FRAME_HEAD_LEN..filler_at + 256 - TAG_LEN].iter().any(|&b| b != 0));
+        assert_eq!((stream.len() - filler_at) % 256, 0);
+        assert_eq!(decrypt(&stream, &keys).unwrap(), b"framed");
+
+        // Dropping the filler frame breaks the chain; so does cutting the final frame
+        let mut dropped = stream[..filler_at].to_vec();
+        dropped.extend_from_slice(&stream[filler_at + 256..]);
+        assert!(matches!(decrypt(&dropped, &keys), Err(HybridGuardError::Integrity(_))));
+        assert!(matches!(decrypt(&stream[..stream.len() - 256], &keys), Err(HybridGuardError::Integrity(_))));
+        let other = KeyDerivation::new(vec![0x54u8; 32]).derive_all_keys().unwrap();
+        assert!(decrypt(&stream, &other).is_err());
+    }
+
+    #[test]
+    fn test_unpadded_final_frame() {
+        let keys = keys();
+        let clock = FakeClock::default();
+        let policy = ShapingPolicy::new(4096, Duration::from_millis(5)).unwrap().with_pad_final(false);
+        let mut stream = Vec::new();
+        encrypt_stream_with_shaping(&registry(), &keys, "shaping-test", &b"short"[..], &mut stream, &policy, &clock).unwrap();
+        assert_ne!((stream.len() - prefix_len(&stream)) % 4096, 0);
+        assert_eq!(read_header(&stream[..]).unwrap().key_id, "shaping-test");
+        assert_eq!(decrypt(&stream, &keys).unwrap(), b"short");
+    }
+}
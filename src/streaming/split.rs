@@ -0,0 +1,686 @@
+// Chunked ciphertexts split across several files
+//
+// `encrypt --split-size` writes one chunked ciphertext as parts
+// `<output>.000`, `<output>.001`, ... of at most the split size each, and a
+// manifest `<output>.manifest`. A part is
+//   magic "HGSX", u16 format version, 16-byte set ID, u32 part index,
+// then the next slice of the ciphertext. The manifest is JSON listing every
+// part's file name, size and SHA3-256, with a tag over all of it keyed by the
+// profile keys, so each part is checked on its own against an authenticated
+// record. Only the whole set, in order, decrypts: the chunked tag chain runs
+// through every part.
+//
+// `decrypt` takes the manifest or any part, finds the rest beside it, and
+// checks the whole set before it starts, naming every part that is missing,
+// damaged, out of place or from another set.
+
+use crate::cancel::CancellationToken;
+use crate::crypto::codec;
+use crate::crypto::container::{le_u16, le_u32, u32_at};
+use crate::crypto::hkdf::{KeyPurpose, LayerKeys};
+use crate::crypto::tag::{self, TAG_LEN};
+use crate::error::{HybridGuardError, Result};
+use crate::fsutil::WriteOptions;
+use crate::key_manager::KeyManager;
+use crate::pathname::JsonPath;
+use crate::staging::{Contents, StagedFile};
+use crate::streaming::chunked::{self, ChunkedStats, DEFAULT_SEGMENT_CHUNKS};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Magic bytes at the start of every part
+pub const PART_MAGIC: [u8; 4] = *b"HGSX";
+
+/// Split format written by this build, in part headers and the manifest
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Bytes of a part header: magic, version, set ID, part index
+pub const PART_HEADER_LEN: usize = 4 + 2 + 16 + 4;
+
+/// Extension of the manifest, after the output's own name
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Smallest split size accepted
+pub const MIN_SPLIT_SIZE: u64 = 4096;
+
+/// Largest manifest read
+const MAX_MANIFEST_LEN: u64 = 16 * 1024 * 1024;
+
+/// Purpose of the manifest tag key
+const MANIFEST_PURPOSE: KeyPurpose = KeyPurpose::Mac("split-manifest");
+
+/// Parse a split size: bytes, or a number with a K, M, G or T suffix (powers of 1024)
+pub fn parse_size(text: &str) -> Result<u64> {
+    let invalid = || HybridGuardError::InvalidInput(format!("invalid split size '{}', expected bytes or a size such as 512M or 2G", text));
+    let text = text.trim();
+    let (number, shift) = match text.char_indices().last() {
+        Some((at, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(invalid()),
+            };
+            (&text[..at], shift)
+        }
+        _ => (text, 0),
+    };
+    let size = number.parse::<u64>().map_err(|_| invalid())?.checked_mul(1 << shift).ok_or_else(invalid)?;
+    if size < MIN_SPLIT_SIZE {
+        return Err(HybridGuardError::InvalidInput(format!("split size must be at least {} bytes", MIN_SPLIT_SIZE)));
+    }
+    Ok(size)
+}
+
+/// Path of part `index` of the set written for `output`
+pub fn part_path(output: &Path, index: u32) -> PathBuf {
+    with_suffix(output, &format!(".{:03}", index))
+}
+
+/// Path of the manifest of the set written for `output`
+pub fn manifest_path(output: &Path) -> PathBuf {
+    with_suffix(output, &format!(".{}", MANIFEST_EXTENSION))
+}
+
+/// Whether `bytes` starts a part of a split set
+pub fn is_part(bytes: &[u8]) -> bool {
+    bytes.starts_with(&PART_MAGIC)
+}
+
+/// Whether `path` names a split set's manifest
+pub fn is_manifest_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == MANIFEST_EXTENSION)
+}
+
+/// Bytes the parts of a split encryption of `plaintext_len` bytes take in all,
+/// under the default data limits; the manifest comes on top
+pub fn estimate_output_size(plaintext_len: u64, split_size: u64, key_id: &str) -> Result<u64> {
+    let ciphertext_len = chunked::estimate_output_size(plaintext_len, DEFAULT_SEGMENT_CHUNKS, key_id)?;
+    let parts = ciphertext_len.div_ceil(split_size - PART_HEADER_LEN as u64);
+    Ok(ciphertext_len + parts * PART_HEADER_LEN as u64)
+}
+
+/// One part as the manifest records it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartEntry {
+    /// File name, in the manifest's directory
+    pub file: JsonPath,
+    /// Bytes of the whole file, header included
+    pub size: u64,
+    /// Hex SHA3-256 of the whole file
+    pub sha3_256: String,
+}
+
+/// Contents of `<output>.manifest`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitManifest {
+    pub format_version: u16,
+    /// Hex, the same in every part header
+    pub set_id: String,
+    pub key_id: String,
+    pub split_size: u64,
+    /// Ciphertext bytes across all parts, without their headers
+    pub ciphertext_len: u64,
+    /// In order
+    pub parts: Vec<PartEntry>,
+    /// Hex tag over every field above, keyed by the profile keys
+    pub tag: String,
+}
+
+impl SplitManifest {
+    /// Tag over everything but the tag itself
+    fn compute_tag(&self, keys: &LayerKeys) -> Result<[u8; TAG_LEN]> {
+        let body = (self.format_version, &self.set_id, &self.key_id, self.split_size, self.ciphertext_len, &self.parts);
+        let encoded = bincode::serialize(&body).map_err(|e| HybridGuardError::Encryption(format!("split manifest: {}", e)))?;
+        let mut hasher = tag::keyed_hasher(keys, MANIFEST_PURPOSE);
+        hasher.update(encoded);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Check the tag under `key_manager`, whose key ID must be the manifest's
+    pub fn authenticate(&self, key_manager: &KeyManager) -> Result<()> {
+        if self.key_id != key_manager.key_id() {
+            return Err(HybridGuardError::KeyMismatch(format!(
+                "split set was encrypted with key {} but key {} is loaded",
+                self.key_id,
+                key_manager.key_id()
+            )));
+        }
+        let stored = codec::hex_lower_decode(&self.tag).map_err(|_| forged_manifest())?;
+        if !tag::tags_match(&stored, &self.compute_tag(key_manager.decryption_keys()?)?) {
+            return Err(forged_manifest());
+        }
+        Ok(())
+    }
+}
+
+/// What a split encryption wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitStats {
+    pub chunked: ChunkedStats,
+    pub parts: usize,
+    pub manifest: PathBuf,
+}
+
+/// Encrypt `input` as a chunked ciphertext in parts of at most `split_size`
+/// bytes beside `output`, then write the manifest
+/// Parts are each staged and renamed into place once full; a failed or
+/// cancelled run removes those already written, and the manifest only
+/// appears once every part is there. Parts left over from an earlier, longer
+/// set under the same name are removed
+pub fn encrypt_file(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    split_size: u64,
+    cancel: &CancellationToken,
+    options: &WriteOptions,
+) -> Result<SplitStats> {
+    if split_size < MIN_SPLIT_SIZE {
+        return Err(HybridGuardError::InvalidInput(format!("split size must be at least {} bytes", MIN_SPLIT_SIZE)));
+    }
+    let source = File::open(input)?;
+    let len = source.metadata()?.len();
+    let mut parts = PartWriter::new(output, split_size, options);
+    let manifest = manifest_path(output);
+
+    let result = (|| -> Result<ChunkedStats> {
+        let stats = chunked::encrypt_into(&mut BufReader::new(source), len, &mut parts, key_manager, DEFAULT_SEGMENT_CHUNKS, cancel)?;
+        parts.end_part()?;
+        let mut record = SplitManifest {
+            format_version: FORMAT_VERSION,
+            set_id: codec::hex_lower(&parts.set_id),
+            key_id: key_manager.key_id().to_string(),
+            split_size,
+            ciphertext_len: parts.ciphertext_len,
+            parts: parts.parts.clone(),
+            tag: String::new(),
+        };
+        record.tag = codec::hex_lower(&record.compute_tag(key_manager.get_keys()?)?);
+        let json = serde_json::to_string_pretty(&record).map_err(|e| HybridGuardError::Encryption(format!("split manifest: {}", e)))?;
+        let mut staged = StagedFile::create(&manifest, None, Contents::Ciphertext)?.with_write_options(options.clone());
+        staged.file().write_all((json + "\n").as_bytes())?;
+        staged.commit()?;
+        Ok(stats)
+    })();
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            parts.remove_written();
+            return Err(e);
+        }
+    };
+
+    remove_stale_parts(output, parts.parts.len() as u32);
+    Ok(SplitStats { chunked: stats, parts: parts.parts.len(), manifest })
+}
+
+/// Decrypt the split set `input` belongs to into `output`
+/// The manifest and every part are checked before anything is decrypted
+pub fn decrypt_file_with(
+    input: &Path,
+    output: &Path,
+    key_manager: &KeyManager,
+    cancel: &CancellationToken,
+    options: &WriteOptions,
+) -> Result<ChunkedStats> {
+    let set = SplitSet::open(input)?;
+    set.verify(key_manager)?;
+    let mut source = BufReader::new(set.reader()?);
+    chunked::decrypt_source_with(&mut source, &set.manifest_path, output, key_manager, cancel, options)
+}
+
+/// Why a part of a set cannot be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartProblem {
+    Missing,
+    /// Its header is not one of this set's
+    Foreign,
+    /// It is another part of this set, under the wrong name
+    Misplaced { holds: u32 },
+    WrongSize { expected: u64, found: u64 },
+    /// Its SHA3-256 differs from the manifest's
+    Corrupted,
+}
+
+/// A part that cannot be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartIssue {
+    pub index: u32,
+    pub path: PathBuf,
+    pub problem: PartProblem,
+}
+
+impl fmt::Display for PartIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.path.display();
+        match &self.problem {
+            PartProblem::Missing => write!(f, "{} (part {}) is missing", path, self.index),
+            PartProblem::Foreign => write!(f, "{} (part {}) is not a part of this set", path, self.index),
+            PartProblem::Misplaced { holds } => {
+                write!(f, "{} holds part {} instead of part {} (parts were renamed or reordered)", path, holds, self.index)
+            }
+            PartProblem::WrongSize { expected, found } => {
+                write!(f, "{} (part {}) is {} bytes, the manifest records {}", path, self.index, found, expected)
+            }
+            PartProblem::Corrupted => write!(f, "{} (part {}) is corrupted", path, self.index),
+        }
+    }
+}
+
+/// A split set found through its manifest
+#[derive(Debug, Clone)]
+pub struct SplitSet {
+    manifest_path: PathBuf,
+    manifest: SplitManifest,
+}
+
+impl SplitSet {
+    /// The set `input` belongs to: `input` is its manifest or one of its parts
+    /// Nothing is authenticated yet; see `verify`
+    pub fn open(input: &Path) -> Result<Self> {
+        let manifest_path = if is_manifest_path(input) {
+            input.to_path_buf()
+        } else {
+            let mut head = Vec::new();
+            File::open(input)?.take(PART_MAGIC.len() as u64).read_to_end(&mut head)?;
+            if !is_part(&head) {
+                return Err(HybridGuardError::UnsupportedFormat(format!("{} is not part of a split set", input.display())));
+            }
+            // `name.000` belongs to `name.manifest`
+            manifest_path(&input.with_extension(""))
+        };
+        let bytes = match File::open(&manifest_path) {
+            Ok(file) => {
+                let mut bytes = Vec::new();
+                file.take(MAX_MANIFEST_LEN + 1).read_to_end(&mut bytes)?;
+                bytes
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(HybridGuardError::Integrity(format!(
+                    "{} is missing; it lists the parts of the set and authenticates them",
+                    manifest_path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if bytes.len() as u64 > MAX_MANIFEST_LEN {
+            return Err(HybridGuardError::Integrity(format!("{} is too large for a split manifest", manifest_path.display())));
+        }
+        let manifest: SplitManifest = serde_json::from_slice(&bytes)
+            .map_err(|e| HybridGuardError::Integrity(format!("{} is not a readable split manifest: {}", manifest_path.display(), e)))?;
+        if manifest.format_version != FORMAT_VERSION {
+            return Err(HybridGuardError::UnsupportedFormat(format!(
+                "split format v{} is not supported (this build reads v{})",
+                manifest.format_version, FORMAT_VERSION
+            )));
+        }
+        Ok(Self { manifest_path, manifest })
+    }
+
+    pub fn manifest_path(&self) -> &Path {
+        &self.manifest_path
+    }
+
+    pub fn manifest(&self) -> &SplitManifest {
+        &self.manifest
+    }
+
+    /// Path of every part, in order
+    /// Parts are always beside the manifest, so an entry naming a directory is refused
+    pub fn part_paths(&self) -> Result<Vec<PathBuf>> {
+        let dir = self.manifest_path.parent().unwrap_or(Path::new(""));
+        self.manifest
+            .parts
+            .iter()
+            .map(|part| {
+                let name = part.file.to_path_buf()?;
+                if !matches!(name.components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+                    return Err(HybridGuardError::Integrity(format!("split manifest names part {} outside its directory", part.file)));
+                }
+                Ok(dir.join(name))
+            })
+            .collect()
+    }
+
+    /// Parts that are missing, out of place or of the wrong size, without
+    /// reading their contents; nothing is authenticated
+    pub fn survey(&self) -> Result<Vec<PartIssue>> {
+        self.issues(false)
+    }
+
+    /// Authenticate the manifest and check every part in full against it
+    /// Fails naming every unusable part
+    pub fn verify(&self, key_manager: &KeyManager) -> Result<()> {
+        self.manifest.authenticate(key_manager).map_err(|e| match e {
+            HybridGuardError::Integrity(reason) => HybridGuardError::Integrity(format!("{}: {}", self.manifest_path.display(), reason)),
+            other => other,
+        })?;
+        let issues = self.issues(true)?;
+        if issues.is_empty() {
+            return Ok(());
+        }
+        Err(self.incomplete(&issues))
+    }
+
+    /// The error for a set with `issues`
+    pub fn incomplete(&self, issues: &[PartIssue]) -> HybridGuardError {
+        HybridGuardError::Integrity(format!(
+            "{}: {} of {} part(s) unusable: {}",
+            self.manifest_path.display(),
+            issues.len(),
+            self.manifest.parts.len(),
+            issues.iter().map(PartIssue::to_string).collect::<Vec<_>>().join("; ")
+        ))
+    }
+
+    /// The ciphertext the parts hold, read across them in order
+    pub fn reader(&self) -> Result<SplitReader> {
+        let paths = self.part_paths()?;
+        let lens = self.manifest.parts.iter().map(|part| part.size.saturating_sub(PART_HEADER_LEN as u64));
+        Ok(SplitReader::new(paths.into_iter().zip(lens).collect()))
+    }
+
+    fn issues(&self, hash: bool) -> Result<Vec<PartIssue>> {
+        let set_id = codec::hex_lower_decode(&self.manifest.set_id).map_err(|_| forged_manifest())?;
+        let mut issues = Vec::new();
+        for (index, (path, entry)) in self.part_paths()?.into_iter().zip(&self.manifest.parts).enumerate() {
+            let index = index as u32;
+            if let Some(problem) = check_part(&path, entry, &set_id, index, hash)? {
+                issues.push(PartIssue { index, path, problem });
+            }
+        }
+        Ok(issues)
+    }
+}
+
+/// What is wrong with part `index` at `path`, if anything
+fn check_part(path: &Path, entry: &PartEntry, set_id: &[u8], index: u32, hash: bool) -> Result<Option<PartProblem>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(PartProblem::Missing)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = Vec::new();
+    (&mut file).take(PART_HEADER_LEN as u64).read_to_end(&mut header)?;
+    if header.len() < PART_HEADER_LEN || !is_part(&header) || header[6..22] != *set_id {
+        return Ok(Some(PartProblem::Foreign));
+    }
+    let holds = u32_at(&header, 22);
+    if holds != index {
+        return Ok(Some(PartProblem::Misplaced { holds }));
+    }
+    let found = file.metadata()?.len();
+    if found != entry.size {
+        return Ok(Some(PartProblem::WrongSize { expected: entry.size, found }));
+    }
+    if hash {
+        let mut hasher = Sha3_256::new();
+        hasher.update(&header);
+        io::copy(&mut file, &mut chunked::HashWriter(&mut hasher))?;
+        if codec::hex_lower(&hasher.finalize()) != entry.sha3_256 {
+            return Ok(Some(PartProblem::Corrupted));
+        }
+    }
+    Ok(None)
+}
+
+/// The ciphertext of a split set, read across its parts
+/// Seeks anywhere; the part holding the position is opened on the next read
+pub struct SplitReader {
+    /// Each part's path and ciphertext length
+    parts: Vec<(PathBuf, u64)>,
+    /// Offset of each part's first ciphertext byte
+    starts: Vec<u64>,
+    len: u64,
+    position: u64,
+    index: usize,
+    file: Option<File>,
+}
+
+impl SplitReader {
+    fn new(parts: Vec<(PathBuf, u64)>) -> Self {
+        let mut starts = Vec::with_capacity(parts.len());
+        let mut len = 0u64;
+        for (_, part_len) in &parts {
+            starts.push(len);
+            len += part_len;
+        }
+        Self { parts, starts, len, position: 0, index: 0, file: None }
+    }
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some((path, part_len)) = self.parts.get(self.index) else {
+                return Ok(0);
+            };
+            let offset = self.position - self.starts[self.index];
+            if offset >= *part_len {
+                self.index += 1;
+                self.file = None;
+                continue;
+            }
+            let mut file = match self.file.take() {
+                Some(file) => file,
+                None => {
+                    let mut file = File::open(path)?;
+                    file.seek(SeekFrom::Start(PART_HEADER_LEN as u64 + offset))?;
+                    file
+                }
+            };
+            let want = (buf.len() as u64).min(part_len - offset) as usize;
+            let n = file.read(&mut buf[..want])?;
+            self.file = Some(file);
+            if n == 0 && want > 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than the manifest records", path.display())));
+            }
+            self.position += n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the split set"))?;
+        self.position = target;
+        self.index = self.starts.partition_point(|&start| start <= target).saturating_sub(1);
+        self.file = None;
+        Ok(target)
+    }
+}
+
+/// `Write` cutting a ciphertext into the parts of a new set
+struct PartWriter<'a> {
+    output: &'a Path,
+    split_size: u64,
+    set_id: [u8; 16],
+    options: &'a WriteOptions,
+    open: Option<OpenPart>,
+    /// Parts written and renamed into place
+    parts: Vec<PartEntry>,
+    ciphertext_len: u64,
+}
+
+/// The part being written
+struct OpenPart {
+    path: PathBuf,
+    staged: StagedFile,
+    hasher: Sha3_256,
+    /// Bytes written, header included
+    len: u64,
+}
+
+impl<'a> PartWriter<'a> {
+    fn new(output: &'a Path, split_size: u64, options: &'a WriteOptions) -> Self {
+        Self { output, split_size, set_id: rand::random(), options, open: None, parts: Vec::new(), ciphertext_len: 0 }
+    }
+
+    fn start_part(&mut self) -> io::Result<()> {
+        let index = u32::try_from(self.parts.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many parts; choose a larger split size"))?;
+        let path = part_path(self.output, index);
+        let mut staged = StagedFile::create(&path, None, Contents::Ciphertext)?.with_write_options(self.options.clone());
+        let mut header = Vec::with_capacity(PART_HEADER_LEN);
+        header.extend_from_slice(&PART_MAGIC);
+        header.extend_from_slice(&le_u16(FORMAT_VERSION));
+        header.extend_from_slice(&self.set_id);
+        header.extend_from_slice(&le_u32(index));
+        staged.file().write_all(&header)?;
+        let mut hasher = Sha3_256::new();
+        hasher.update(&header);
+        self.open = Some(OpenPart { path, staged, hasher, len: header.len() as u64 });
+        Ok(())
+    }
+
+    /// Commit the part being written, if any
+    fn end_part(&mut self) -> io::Result<()> {
+        let Some(part) = self.open.take() else {
+            return Ok(());
+        };
+        part.staged.commit()?;
+        let name = part.path.file_name().map(Path::new).unwrap_or(part.path.as_path());
+        self.parts.push(PartEntry { file: JsonPath::new(name), size: part.len, sha3_256: codec::hex_lower(&part.hasher.finalize()) });
+        Ok(())
+    }
+
+    /// Remove every part committed so far; the open one goes with its staging
+    fn remove_written(&mut self) {
+        self.open = None;
+        for index in 0..self.parts.len() {
+            let _ = fs::remove_file(part_path(self.output, index as u32));
+        }
+    }
+}
+
+impl Write for PartWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.open.as_ref().is_some_and(|part| part.len < self.split_size) {
+            self.end_part()?;
+            self.start_part()?;
+        }
+        let split_size = self.split_size;
+        let Some(part) = self.open.as_mut() else {
+            return Err(io::ErrorKind::WriteZero.into());
+        };
+        let n = (buf.len() as u64).min(split_size - part.len) as usize;
+        part.staged.file().write_all(&buf[..n])?;
+        part.hasher.update(&buf[..n]);
+        part.len += n as u64;
+        self.ciphertext_len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Remove parts numbered `from` and up left by an earlier set written to `output`
+/// Only files named like its parts and starting with a part header go
+fn remove_stale_parts(output: &Path, from: u32) {
+    let (Some(dir), Some(name)) = (output.parent(), output.file_name()) else {
+        return;
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let prefix = with_suffix(Path::new(name), ".");
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let index = path
+            .file_name()
+            .and_then(|file| file.to_str()?.strip_prefix(prefix.to_str()?).map(str::to_string))
+            .filter(|digits| digits.len() >= 3 && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<u32>().ok());
+        if !index.is_some_and(|index| index >= from) {
+            continue;
+        }
+        let mut head = Vec::new();
+        let is_stale = File::open(&path).and_then(|file| file.take(PART_MAGIC.len() as u64).read_to_end(&mut head)).is_ok() && is_part(&head);
+        if is_stale {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn forged_manifest() -> HybridGuardError {
+    HybridGuardError::Integrity("split manifest failed authentication".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size("65536").unwrap(), 65536);
+        assert!(parse_size("1K").is_err());
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_paths() {
+        let output = Path::new("dir/backup.hgd");
+        assert_eq!(part_path(output, 0), Path::new("dir/backup.hgd.000"));
+        assert_eq!(part_path(output, 1234), Path::new("dir/backup.hgd.1234"));
+        assert_eq!(manifest_path(output), Path::new("dir/backup.hgd.manifest"));
+        assert!(is_manifest_path(&manifest_path(output)));
+        assert_eq!(manifest_path(&part_path(output, 7).with_extension("")), manifest_path(output));
+    }
+
+    #[test]
+    fn test_reader_reads_and_seeks_across_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..250u8).collect();
+        let mut parts = Vec::new();
+        for (index, piece) in data.chunks(100).enumerate() {
+            let path = dir.path().join(format!("p.{:03}", index));
+            let mut bytes = vec![0u8; PART_HEADER_LEN];
+            bytes.extend_from_slice(piece);
+            fs::write(&path, bytes).unwrap();
+            parts.push((path, piece.len() as u64));
+        }
+
+        let mut reader = SplitReader::new(parts);
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+
+        reader.seek(SeekFrom::Start(95)).unwrap();
+        let mut across = [0u8; 10];
+        reader.read_exact(&mut across).unwrap();
+        assert_eq!(across, data[95..105]);
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), 249);
+        let mut last = Vec::new();
+        reader.read_to_end(&mut last).unwrap();
+        assert_eq!(last, [249]);
+    }
+}
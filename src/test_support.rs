@@ -0,0 +1,293 @@
+// Test doubles for code built on this crate (`test-util` feature)
+// The real layers need liboqs and cannot be made to fail on demand, so
+// `MockLayer` stands in for one: added with `HybridGuardBuilder::with_layer`
+// it runs inside the real pipeline, and can return scripted bytes, fail on
+// a chosen call or take a set time. Alongside it are keys that fail a chosen
+// way, the seeded RNG and clocks that make runs repeatable, and helpers for
+// fixture containers with chosen metadata or damaged ciphertext.
+
+use crate::crypto::EncryptedData;
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::KeyManager;
+use crate::layers::{EncryptionLayer, LayerDescriptor, SecurityClass};
+use crate::timing::{Clock, SystemClock};
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+pub use crate::crypto::drbg::SeededRandom;
+pub use crate::crypto::EncryptedDataBuilder;
+pub use crate::timing::FixedClock;
+
+/// Bytes of its key `MockLayer` appends, and checks when decrypting
+const KEY_CHECK_LEN: usize = 4;
+
+/// Which direction of a layer a script entry applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+type ErrorFn = Box<dyn Fn() -> HybridGuardError + Send + Sync>;
+
+/// What one scripted call does instead of the default transform
+enum Scripted {
+    Output(Vec<u8>),
+    Fail(ErrorFn),
+}
+
+/// Scriptable stand-in for an encryption layer
+///
+/// Unscripted calls XOR the data with the first key byte and append the
+/// first 4 key bytes, which decryption checks, so a round trip works and a
+/// wrong key fails with `Layer`. Calls are numbered from 1 in each direction;
+/// `returning` and `failing` replace one numbered call, and every call first
+/// sleeps for the latency on the layer's clock.
+pub struct MockLayer {
+    name: String,
+    version: u16,
+    script: HashMap<(Direction, usize), Scripted>,
+    latency: Duration,
+    clock: Arc<dyn Clock>,
+    encrypt_calls: AtomicUsize,
+    decrypt_calls: AtomicUsize,
+}
+
+impl MockLayer {
+    /// A layer called `name`, also its descriptor name, at format version 1
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: 1,
+            script: HashMap::new(),
+            latency: Duration::ZERO,
+            clock: Arc::new(SystemClock::new()),
+            encrypt_calls: AtomicUsize::new(0),
+            decrypt_calls: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record `version` in descriptors instead of 1
+    pub fn with_version(mut self, version: u16) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Return `output` from call `call` in `direction`, whatever its input
+    pub fn returning(mut self, direction: Direction, call: usize, output: Vec<u8>) -> Self {
+        self.script.insert((direction, call), Scripted::Output(output));
+        self
+    }
+
+    /// Fail call `call` in `direction` with the error `error` makes
+    pub fn failing(mut self, direction: Direction, call: usize, error: impl Fn() -> HybridGuardError + Send + Sync + 'static) -> Self {
+        self.script.insert((direction, call), Scripted::Fail(Box::new(error)));
+        self
+    }
+
+    /// Take `latency` over every call, slept on the layer's clock
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Sleep on `clock` instead of the system clock, so latency costs no real time
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Calls to `encrypt` so far, scripted ones included
+    pub fn encrypt_calls(&self) -> usize {
+        self.encrypt_calls.load(Ordering::SeqCst)
+    }
+
+    /// Calls to `decrypt` so far, scripted ones included
+    pub fn decrypt_calls(&self) -> usize {
+        self.decrypt_calls.load(Ordering::SeqCst)
+    }
+
+    fn call(&self, direction: Direction, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        let counter = match direction {
+            Direction::Encrypt => &self.encrypt_calls,
+            Direction::Decrypt => &self.decrypt_calls,
+        };
+        let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        if !self.latency.is_zero() {
+            self.clock.sleep(self.latency);
+        }
+        match self.script.get(&(direction, call)) {
+            Some(Scripted::Output(output)) => return Ok(output.clone()),
+            Some(Scripted::Fail(error)) => return Err(error()),
+            None => {}
+        }
+        let mask = key.first().copied().unwrap_or(0);
+        let check = &key[..key.len().min(KEY_CHECK_LEN)];
+        match direction {
+            Direction::Encrypt => Ok(data.iter().map(|b| b ^ mask).chain(check.iter().copied()).collect()),
+            Direction::Decrypt => {
+                let (body, found) = data.split_at(data.len().saturating_sub(check.len()));
+                if found != check {
+                    return Err(HybridGuardError::Layer(format!("{}: wrong key", self.name)));
+                }
+                Ok(body.iter().map(|b| b ^ mask).collect())
+            }
+        }
+    }
+}
+
+impl EncryptionLayer for MockLayer {
+    fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        self.call(Direction::Encrypt, data, key)
+    }
+
+    fn decrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+        self.call(Direction::Decrypt, data, key)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn security_class(&self) -> SecurityClass {
+        SecurityClass::Experimental
+    }
+
+    fn claims(&self) -> &str {
+        "test"
+    }
+
+    fn descriptor(&self) -> LayerDescriptor {
+        LayerDescriptor::new(&self.name, self.version)
+    }
+
+    fn framing(&self) -> &str {
+        "data XOR key[0] || key[0..4]"
+    }
+
+    fn output_len(&self, input_len: usize) -> Result<usize> {
+        Ok(input_len + KEY_CHECK_LEN)
+    }
+
+    fn overhead_bytes(&self) -> Result<usize> {
+        Ok(KEY_CHECK_LEN)
+    }
+
+    fn max_input(&self) -> usize {
+        usize::MAX - KEY_CHECK_LEN
+    }
+}
+
+/// Keys that fail one chosen way against ciphertexts of a working profile
+/// `KeyManager` is a concrete type, so each failure is a real key manager
+/// set up to fail, which `HybridGuard::builder` takes like any other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailingKeyManager {
+    /// Another profile's keys: decryption fails authentication
+    /// (`Integrity` when verbose, `DecryptionFailed` otherwise)
+    WrongKey,
+    /// An encrypt-only copy of the keys: decryption fails with `CapabilityDenied`
+    EncryptOnly,
+}
+
+impl FailingKeyManager {
+    /// Keys failing this way against what `working` encrypts
+    pub fn against(self, working: &KeyManager) -> Result<KeyManager> {
+        match self {
+            FailingKeyManager::WrongKey => {
+                // Derived from the working key ID, so it is another profile's every time
+                let master: [u8; 32] = Sha3_256::new_with_prefix(b"HybridGuard-test-wrong-key").chain_update(working.key_id()).finalize().into();
+                KeyManager::from_master_key(&master)
+            }
+            FailingKeyManager::EncryptOnly => working.encrypt_only(),
+        }
+    }
+}
+
+/// Clock that stands still until slept on or advanced, and records its sleeps
+/// Timing padding and `MockLayer` latency then cost no real time
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+    sleeps: Mutex<Vec<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock on by `by` without recording a sleep
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner) += by;
+    }
+
+    /// Every sleep so far, in order
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap_or_else(PoisonError::into_inner).push(duration);
+        self.advance(duration);
+    }
+}
+
+/// `encrypted` with bit 0 of ciphertext byte `index` flipped and everything
+/// else kept, so its tag no longer matches; `index` wraps around the ciphertext
+pub fn flip_ciphertext_bit(encrypted: &EncryptedData, index: usize) -> Result<EncryptedData> {
+    let mut ciphertext = encrypted.ciphertext().to_vec();
+    if ciphertext.is_empty() {
+        return Err(HybridGuardError::InvalidInput("no ciphertext to flip a bit of".to_string()));
+    }
+    let at = index % ciphertext.len();
+    ciphertext[at] ^= 1;
+    EncryptedDataBuilder::from(encrypted.clone()).ciphertext(ciphertext).build()
+}
+
+/// A container of `ciphertext`, tagged under `key_manager` as the pipeline
+/// would, so it passes authentication and reaches the layers
+/// Set labels, metadata and the like on the builder before calling `tag`
+/// yourself where a fixture needs them
+pub fn tagged_fixture(ciphertext: Vec<u8>, key_manager: &KeyManager) -> Result<EncryptedData> {
+    EncryptedDataBuilder::new(ciphertext).tag(key_manager.get_keys()?)?.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_layer_follows_its_script() {
+        let layer = MockLayer::new("Mock")
+            .returning(Direction::Encrypt, 2, b"scripted".to_vec())
+            .failing(Direction::Decrypt, 1, || HybridGuardError::Layer("injected".to_string()));
+        let key = [0x42u8; 32];
+
+        let first = layer.encrypt(b"data", &key).unwrap();
+        assert_eq!(first.len(), layer.output_len(4).unwrap());
+        assert_eq!(layer.encrypt(b"data", &key).unwrap(), b"scripted");
+        assert!(matches!(layer.decrypt(&first, &key), Err(HybridGuardError::Layer(m)) if m == "injected"));
+        assert_eq!(layer.decrypt(&first, &key).unwrap(), b"data");
+        assert!(layer.decrypt(&first, &[0x43u8; 32]).is_err());
+        assert_eq!((layer.encrypt_calls(), layer.decrypt_calls()), (2, 3));
+    }
+
+    #[test]
+    fn test_latency_is_slept_on_the_layer_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let layer = MockLayer::new("Slow").with_latency(Duration::from_secs(30)).with_clock(clock.clone());
+        layer.encrypt(b"", &[1u8; 32]).unwrap();
+        assert_eq!(clock.sleeps(), [Duration::from_secs(30)]);
+        assert_eq!(clock.now(), Duration::from_secs(30));
+    }
+}
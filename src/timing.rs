@@ -0,0 +1,282 @@
+// Timing side-channel countermeasures
+// Optionally pads each layer's execution to a key-independent duration
+
+use crate::error::Result;
+use crate::profiling::MemoryReport;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How layer execution times are padded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimingPadding {
+    /// No padding; layers run as fast as they can
+    #[default]
+    Off,
+    /// Round each layer's duration up to a multiple of this many milliseconds
+    ToMultipleMs(u64),
+    /// Round up like `ToMultipleMs`, then add a random delay of up to
+    /// `max_jitter_ms` drawn from the OS RNG
+    Paranoid { multiple_ms: u64, max_jitter_ms: u64 },
+}
+
+impl TimingPadding {
+    /// Whether any padding is applied
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, TimingPadding::Off)
+    }
+}
+
+/// Time source used for padding and container timestamps; injected so
+/// tests need not sleep and golden containers need not change every second
+pub trait Clock: Send + Sync {
+    /// Monotonic time since an arbitrary fixed point
+    fn now(&self) -> Duration;
+    /// Block for `duration`
+    fn sleep(&self, duration: Duration);
+    /// Wall-clock seconds since the Unix epoch, as recorded in containers
+    fn unix_secs(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+    }
+}
+
+/// Clock backed by `Instant` and `std::thread::sleep`
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self { origin: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// System clock whose wall-clock time is stuck at one instant
+/// Containers it timestamps are reproducible; padding still uses real time
+pub struct FixedClock {
+    system: SystemClock,
+    unix_secs: u64,
+}
+
+impl FixedClock {
+    pub fn new(unix_secs: u64) -> Self {
+        Self { system: SystemClock::new(), unix_secs }
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> Duration {
+        self.system.now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.system.sleep(duration);
+    }
+
+    fn unix_secs(&self) -> u64 {
+        self.unix_secs
+    }
+}
+
+/// Measured and padded duration of one layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerTiming {
+    pub layer: String,
+    /// Time the layer itself took
+    pub elapsed: Duration,
+    /// Time including padding and jitter
+    pub padded: Duration,
+}
+
+impl LayerTiming {
+    /// Time spent sleeping for this layer
+    pub fn overhead(&self) -> Duration {
+        self.padded.saturating_sub(self.elapsed)
+    }
+}
+
+/// Smallest multiple of `multiple` that is at least `elapsed`, and never zero
+/// A zero `multiple` disables rounding
+pub fn round_up(elapsed: Duration, multiple: Duration) -> Duration {
+    if multiple.is_zero() {
+        return elapsed;
+    }
+    let multiple_ns = multiple.as_nanos();
+    let steps = elapsed.as_nanos().div_ceil(multiple_ns).max(1);
+    let padded_ns = steps.saturating_mul(multiple_ns).min(u64::MAX as u128);
+    Duration::from_nanos(padded_ns as u64)
+}
+
+/// Runs layers and pads their execution time according to a `TimingPadding`
+#[derive(Clone)]
+pub struct TimingPadder {
+    padding: TimingPadding,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimingPadder {
+    pub fn new(padding: TimingPadding, clock: Arc<dyn Clock>) -> Self {
+        Self { padding, clock }
+    }
+
+    /// Padder that never sleeps
+    pub fn disabled() -> Self {
+        Self::new(TimingPadding::Off, Arc::new(SystemClock::new()))
+    }
+
+    pub fn padding(&self) -> TimingPadding {
+        self.padding
+    }
+
+    /// Run one layer, then sleep until the padded duration has passed
+    /// Padding applies whether the layer succeeds or fails, so errors are not
+    /// distinguishable by timing either. With padding off the layer just runs:
+    /// the clock is not read and no timing is returned
+    pub fn run<T>(&self, layer: &str, f: impl FnOnce() -> Result<T>) -> Result<(T, Option<LayerTiming>)> {
+        if !self.padding.is_enabled() {
+            return f().map(|value| (value, None));
+        }
+        let start = self.clock.now();
+        let result = f();
+        let elapsed = self.clock.now().saturating_sub(start);
+
+        let target = match self.padding {
+            TimingPadding::Off => elapsed,
+            TimingPadding::ToMultipleMs(ms) => round_up(elapsed, Duration::from_millis(ms)),
+            TimingPadding::Paranoid { multiple_ms, max_jitter_ms } => {
+                let jitter = rand::rngs::OsRng.gen_range(0..=max_jitter_ms);
+                round_up(elapsed, Duration::from_millis(multiple_ms)) + Duration::from_millis(jitter)
+            }
+        };
+        if target > elapsed {
+            self.clock.sleep(target - elapsed);
+        }
+
+        let timing = LayerTiming {
+            layer: layer.to_string(),
+            elapsed,
+            padded: self.clock.now().saturating_sub(start),
+        };
+        result.map(|value| (value, Some(timing)))
+    }
+}
+
+/// Per-layer timings of one encryption
+#[derive(Debug, Clone)]
+pub struct EncryptionReport {
+    pub padding: TimingPadding,
+    /// One per layer when padding is on; empty when it is off
+    pub layers: Vec<LayerTiming>,
+    /// Bytes allocated per layer, when memory profiling was on
+    pub memory: Option<MemoryReport>,
+}
+
+impl EncryptionReport {
+    /// Total time including padding
+    pub fn total(&self) -> Duration {
+        self.layers.iter().map(|l| l.padded).sum()
+    }
+
+    /// Total time spent sleeping for padding
+    pub fn overhead(&self) -> Duration {
+        self.layers.iter().map(|l| l.overhead()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ManualClock;
+
+    fn run_for(padder: &TimingPadder, clock: &ManualClock, work: Duration) -> LayerTiming {
+        padder.run("test", || {
+            clock.advance(work);
+            Ok(())
+        }).unwrap().1.unwrap()
+    }
+
+    /// Clock any use of which fails the test
+    struct UnreadClock;
+
+    impl Clock for UnreadClock {
+        fn now(&self) -> Duration {
+            panic!("the clock was read");
+        }
+
+        fn sleep(&self, _duration: Duration) {
+            panic!("the padder slept");
+        }
+    }
+
+    #[test]
+    fn test_round_up() {
+        let ms = Duration::from_millis;
+        assert_eq!(round_up(ms(7), ms(10)), ms(10));
+        assert_eq!(round_up(ms(10), ms(10)), ms(10));
+        assert_eq!(round_up(ms(11), ms(10)), ms(20));
+        assert_eq!(round_up(Duration::ZERO, ms(10)), ms(10));
+        assert_eq!(round_up(ms(7), Duration::ZERO), ms(7));
+    }
+
+    #[test]
+    fn test_pads_to_multiple() {
+        let clock = Arc::new(ManualClock::new());
+        let padder = TimingPadder::new(TimingPadding::ToMultipleMs(50), clock.clone());
+
+        let timing = run_for(&padder, &clock, Duration::from_millis(12));
+        assert_eq!(timing.elapsed, Duration::from_millis(12));
+        assert_eq!(timing.padded, Duration::from_millis(50));
+        assert_eq!(timing.overhead(), Duration::from_millis(38));
+        assert_eq!(clock.sleeps(), vec![Duration::from_millis(38)]);
+    }
+
+    #[test]
+    fn test_off_never_sleeps() {
+        let padder = TimingPadder::new(TimingPadding::Off, Arc::new(UnreadClock));
+
+        let (value, timing) = padder.run("test", || Ok(7)).unwrap();
+        assert_eq!(value, 7);
+        assert!(timing.is_none());
+    }
+
+    #[test]
+    fn test_paranoid_jitter_bounded() {
+        let clock = Arc::new(ManualClock::new());
+        let padding = TimingPadding::Paranoid { multiple_ms: 20, max_jitter_ms: 5 };
+        let padder = TimingPadder::new(padding, clock.clone());
+
+        for _ in 0..20 {
+            let timing = run_for(&padder, &clock, Duration::from_millis(3));
+            assert!(timing.padded >= Duration::from_millis(20));
+            assert!(timing.padded <= Duration::from_millis(25));
+        }
+    }
+
+    #[test]
+    fn test_errors_are_padded() {
+        let clock = Arc::new(ManualClock::new());
+        let padder = TimingPadder::new(TimingPadding::ToMultipleMs(10), clock.clone());
+
+        let result: Result<((), LayerTiming)> = padder.run("test", || {
+            Err(crate::error::HybridGuardError::Decryption("bad".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(clock.now(), Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,289 @@
+// Where a ciphertext is damaged, not just whether it is
+// A chunked file tags every segment on its own, so a full verify checks each
+// segment against its tag and lists every damaged one with its container
+// offsets, for matching against storage-layer error logs. Single containers
+// carry one tag over everything; when one fails, the report says whether it
+// was the tag or which layer's decryption gave up.
+//
+// A verification key checks the same files without decrypting: a chunked
+// file's tag chain and Merkle index, or a container's verification tag.
+// Damage is found but not localized, and nothing reports a plaintext length.
+
+use crate::cancel::CancellationToken;
+use crate::crypto::encoding;
+use crate::encryptor::HybridGuardEncryptor;
+use crate::error::{exit_code, HybridGuardError, Result};
+use crate::progress::{OperationState, Progress};
+use crate::storage::erasure;
+use crate::streaming::chunked;
+use crate::key_manager::verification::VerificationKey;
+use crate::KeyManager;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use zeroize::Zeroize;
+
+/// Damaged regions listed unless the caller asks otherwise
+pub const DEFAULT_MAX_REPORT: usize = 100;
+
+/// What a damaged region held
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CorruptPart {
+    /// A segment: its key record, if it opens an epoch, its ciphertext and its tag
+    Segment,
+    /// Nodes of the Merkle index, or the tag over its root
+    MerkleIndex,
+    /// The tag over the whole file
+    FileTag,
+    /// Bytes the header promises that the file does not have
+    Missing,
+    /// Bytes past the end the header implies
+    Trailing,
+    /// Somewhere in this range; the format has no finer tags
+    Unlocalized,
+}
+
+impl fmt::Display for CorruptPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CorruptPart::Segment => "segment",
+            CorruptPart::MerkleIndex => "Merkle index",
+            CorruptPart::FileTag => "file tag",
+            CorruptPart::Missing => "missing bytes",
+            CorruptPart::Trailing => "trailing bytes",
+            CorruptPart::Unlocalized => "unlocalized damage",
+        })
+    }
+}
+
+/// One damaged region, in bytes of the container file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorruptRange {
+    pub part: CorruptPart,
+    /// The segment the region holds, for segments
+    pub segment: Option<u64>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl CorruptRange {
+    /// Offset just past the region
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+impl fmt::Display for CorruptRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.segment {
+            Some(segment) => write!(f, "{} {} at bytes {}..{}", self.part, segment, self.offset, self.end()),
+            None => write!(f, "{} at bytes {}..{}", self.part, self.offset, self.end()),
+        }
+    }
+}
+
+/// Limits on what a verify reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyOptions {
+    /// Most damaged regions listed; the rest are only counted
+    pub max_report: usize,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self { max_report: DEFAULT_MAX_REPORT }
+    }
+}
+
+/// Outcome of a full verify
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    /// Segments checked; a single container counts as one
+    pub segments: u64,
+    /// Plaintext length, once everything decrypted
+    pub plaintext_len: Option<u64>,
+    /// Damaged regions in file order, at most `max_report` of them
+    pub corrupt_ranges: Vec<CorruptRange>,
+    /// Damaged regions found past `max_report`
+    pub unreported: u64,
+    /// The layer whose decryption failed, for single containers that passed
+    /// (or have no) authentication
+    pub failed_layer: Option<String>,
+}
+
+impl VerifyReport {
+    /// Whether nothing was found damaged
+    pub fn is_intact(&self) -> bool {
+        self.corrupt_ranges.is_empty() && self.unreported == 0 && self.failed_layer.is_none()
+    }
+
+    /// Record a damaged region, or only count it once `max_report` are listed
+    pub(crate) fn add(&mut self, range: CorruptRange, options: &VerifyOptions) {
+        if self.corrupt_ranges.len() < options.max_report {
+            self.corrupt_ranges.push(range);
+        } else {
+            self.unreported += 1;
+        }
+    }
+
+    /// An `Integrity` error summarizing the damage, if any was found
+    pub fn into_result(self) -> Result<()> {
+        if self.is_intact() {
+            return Ok(());
+        }
+        let found = self.corrupt_ranges.len() as u64 + self.unreported;
+        let detail = match (&self.failed_layer, self.corrupt_ranges.first()) {
+            (Some(layer), _) => format!("{} layer decryption failed", layer),
+            (None, Some(first)) if found == 1 => first.to_string(),
+            (None, Some(first)) => format!("{} damaged regions, the first {}", found, first),
+            (None, None) => format!("{} damaged regions", found),
+        };
+        Err(HybridGuardError::Integrity(detail))
+    }
+}
+
+/// Decrypt `input` in full, discarding the plaintext, and report every
+/// damaged region found
+/// Errors that are not damage (an unreadable file, the wrong key) are
+/// returned as errors; damage is returned in the report
+pub fn verify_file(input: &Path, key_manager: &KeyManager, options: &VerifyOptions, cancel: &CancellationToken) -> Result<VerifyReport> {
+    let mut magic = Vec::new();
+    File::open(input)?.take(chunked::MAGIC.len() as u64).read_to_end(&mut magic)?;
+    if chunked::is_chunked(&magic) {
+        let mut source = BufReader::new(File::open(input)?);
+        let mut report = chunked::locate_corruption(&mut source, key_manager, options, cancel)?;
+        if report.is_intact() {
+            report.plaintext_len = Some(chunked::verify_file(input, key_manager, cancel)?.plaintext_len);
+        }
+        return Ok(report);
+    }
+    verify_container(&fs::read(input)?, key_manager, cancel)
+}
+
+/// Check `input` against its tags under a verification key, which cannot
+/// decrypt it
+/// Errors that are not damage (an unreadable file, another profile's key, a
+/// container too old to carry a verification tag) are returned as errors
+pub fn verify_integrity(input: &Path, key: &VerificationKey, cancel: &CancellationToken) -> Result<VerifyReport> {
+    let bytes = fs::read(input)?;
+    let mut report = VerifyReport::default();
+    let checked = if chunked::is_chunked(&bytes) {
+        chunked::verify_integrity(&mut &bytes[..], key, cancel).map(|stats| report.segments = stats.segments)
+    } else {
+        report.segments = 1;
+        integrity_of_container(&bytes, key)
+    };
+    match checked {
+        Ok(()) => {}
+        Err(e) if e.code() == exit_code::INTEGRITY => report.corrupt_ranges.push(CorruptRange {
+            part: CorruptPart::Unlocalized,
+            segment: None,
+            offset: 0,
+            len: bytes.len() as u64,
+        }),
+        Err(e) => return Err(e),
+    }
+    Ok(report)
+}
+
+/// Check a single container's verification tag, after its key ID
+fn integrity_of_container(bytes: &[u8], key: &VerificationKey) -> Result<()> {
+    let payload;
+    let bytes = if erasure::is_sharded(bytes) {
+        payload = erasure::decode(bytes)?.payload;
+        &payload[..]
+    } else {
+        bytes
+    };
+    let encrypted = encoding::decode(bytes)?;
+    if let Some(key_id) = encrypted.key_id().filter(|key_id| *key_id != key.key_id()) {
+        return Err(HybridGuardError::KeyMismatch(format!(
+            "ciphertext was encrypted with key {} but verification key {} is loaded",
+            key_id,
+            key.key_id()
+        )));
+    }
+    encrypted.verify_integrity(key)
+}
+
+/// A single container, possibly sharded or text-encoded
+fn verify_container(bytes: &[u8], key_manager: &KeyManager, cancel: &CancellationToken) -> Result<VerifyReport> {
+    let payload;
+    let bytes = if erasure::is_sharded(bytes) {
+        payload = erasure::decode(bytes)?.payload;
+        &payload[..]
+    } else {
+        bytes
+    };
+    let encrypted = encoding::decode(bytes)?;
+    let keys = key_manager.keys_for(&encrypted)?;
+
+    // The last layer entered is the one that failed
+    let layer = Arc::new(Mutex::new(None));
+    let entered = Arc::clone(&layer);
+    let progress = Progress::new(move |state| {
+        if let OperationState::Layer { name, .. } = state {
+            *entered.lock().unwrap_or_else(|e| e.into_inner()) = Some(name.clone());
+        }
+    });
+    progress.emit(OperationState::ResolvingKeys);
+    progress.emit(OperationState::Reading { bytes: bytes.len() as u64 });
+    let mut report = VerifyReport { segments: 1, ..VerifyReport::default() };
+    match HybridGuardEncryptor::new().decrypt_observed(&encrypted, &keys, cancel, &progress) {
+        Ok(mut plaintext) => {
+            report.plaintext_len = Some(plaintext.len() as u64);
+            plaintext.zeroize();
+        }
+        Err(e) if e.code() == exit_code::INTEGRITY => {
+            match layer.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                Some(name) => report.failed_layer = Some(name),
+                // Nothing decrypted before the tag check failed
+                None => report.corrupt_ranges.push(CorruptRange {
+                    part: CorruptPart::Unlocalized,
+                    segment: None,
+                    offset: 0,
+                    len: bytes.len() as u64,
+                }),
+            }
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(index: u64) -> CorruptRange {
+        CorruptRange { part: CorruptPart::Segment, segment: Some(index), offset: index * 100, len: 100 }
+    }
+
+    #[test]
+    fn test_reports_are_bounded() {
+        let options = VerifyOptions { max_report: 2 };
+        let mut report = VerifyReport::default();
+        assert!(report.is_intact());
+        report.clone().into_result().unwrap();
+        for index in 0..5 {
+            report.add(segment(index), &options);
+        }
+        assert_eq!(report.corrupt_ranges, [segment(0), segment(1)]);
+        assert_eq!(report.unreported, 3);
+
+        let error = report.into_result().unwrap_err();
+        assert_eq!(error.to_string(), "Integrity check failed: 5 damaged regions, the first segment 0 at bytes 0..100");
+    }
+
+    #[test]
+    fn test_single_range_and_layer_messages() {
+        let report = VerifyReport { corrupt_ranges: vec![segment(3)], ..VerifyReport::default() };
+        assert!(report.into_result().unwrap_err().to_string().ends_with("segment 3 at bytes 300..400"));
+        let report = VerifyReport { failed_layer: Some("HQC".to_string()), ..VerifyReport::default() };
+        assert!(report.into_result().unwrap_err().to_string().ends_with("HQC layer decryption failed"));
+    }
+}
@@ -0,0 +1,293 @@
+// Warnings: conditions worth knowing about that do not stop an operation
+// Legacy containers, keys past their lifetime, weak layer stacks, key files
+// other users can read and sources that changed while they were read each
+// raise a `Warning` with a stable code. An operation given a `Warnings` sink
+// hands them to it; without one they go to the log, as they always did. A
+// sink collects what it is given, can pass each warning to a callback as it
+// is raised, drops the codes it allows and, when denying, fails the operation
+// with `WarningDenied` at the first warning it does not allow.
+
+use crate::crypto::EncryptedData;
+use crate::error::{HybridGuardError, Result};
+use crate::key_manager::KeyManager;
+use crate::layers::assessment::MIN_EFFECTIVE_SECURITY;
+use crate::layers::SecurityAssessment;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Stable identifier of a kind of warning, printed as `warning[HG001]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WarningCode {
+    /// HG001: a container without an authentication tag, as written before format 4
+    LegacyFormat,
+    /// HG002: keys used to encrypt past the lifetime they were given
+    KeyExpired,
+    /// HG003: a layer stack below the minimum effective security
+    WeakStack,
+    /// HG004: a key file that other users can read
+    LoosePermissions,
+    /// HG005: a source whose size or mtime changed while it was read
+    SourceChanged,
+}
+
+impl WarningCode {
+    pub const ALL: [WarningCode; 5] = [
+        WarningCode::LegacyFormat,
+        WarningCode::KeyExpired,
+        WarningCode::WeakStack,
+        WarningCode::LoosePermissions,
+        WarningCode::SourceChanged,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WarningCode::LegacyFormat => "HG001",
+            WarningCode::KeyExpired => "HG002",
+            WarningCode::WeakStack => "HG003",
+            WarningCode::LoosePermissions => "HG004",
+            WarningCode::SourceChanged => "HG005",
+        }
+    }
+}
+
+impl fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WarningCode {
+    type Err = String;
+
+    /// A code as printed, in either case
+    fn from_str(text: &str) -> std::result::Result<Self, String> {
+        Self::ALL.into_iter().find(|code| code.as_str().eq_ignore_ascii_case(text)).ok_or_else(|| {
+            let known: Vec<&str> = Self::ALL.iter().map(|code| code.as_str()).collect();
+            format!("unknown warning code '{}'; known codes are {}", text, known.join(", "))
+        })
+    }
+}
+
+impl Serialize for WarningCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// One warning raised by an operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub code: WarningCode,
+    pub message: String,
+    /// What the warning is about, such as a path or key ID, for scripts to pick out
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub context: BTreeMap<String, String>,
+}
+
+impl Warning {
+    pub fn new(code: WarningCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), context: BTreeMap::new() }
+    }
+
+    /// Record `value` under `key` in the context
+    pub fn with_context(mut self, key: &str, value: impl ToString) -> Self {
+        self.context.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+type Callback = Arc<dyn Fn(&Warning) + Send + Sync>;
+
+/// Where an operation's warnings go, set on `EncryptOptions` and `DecryptOptions`
+/// Cheap to clone; every clone shares what was collected
+#[derive(Clone, Default)]
+pub struct Warnings {
+    collected: Arc<Mutex<Vec<Warning>>>,
+    callback: Option<Callback>,
+    deny: bool,
+    allowed: BTreeSet<WarningCode>,
+}
+
+impl Warnings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also pass each warning to `callback` as it is raised; denied ones are
+    /// not passed on, since the error that ends the operation carries them
+    pub fn on_warning(mut self, callback: impl Fn(&Warning) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Fail the operation with `WarningDenied` at the first warning not allowed
+    pub fn with_deny(mut self, deny: bool) -> Self {
+        self.deny = deny;
+        self
+    }
+
+    /// Drop warnings with `code`: they are neither collected, passed on nor denied
+    pub fn allow(mut self, code: WarningCode) -> Self {
+        self.allowed.insert(code);
+        self
+    }
+
+    /// Raise `warning`; fails with `WarningDenied` when denying, after collecting it
+    pub fn emit(&self, warning: Warning) -> Result<()> {
+        if self.allowed.contains(&warning.code) {
+            return Ok(());
+        }
+        self.collected.lock().unwrap_or_else(PoisonError::into_inner).push(warning.clone());
+        if self.deny {
+            return Err(HybridGuardError::WarningDenied { code: warning.code, message: warning.message });
+        }
+        if let Some(callback) = &self.callback {
+            callback(&warning);
+        }
+        Ok(())
+    }
+
+    /// Warnings raised so far, oldest first
+    pub fn collected(&self) -> Vec<Warning> {
+        self.collected.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Warnings raised so far, leaving none collected
+    pub fn take(&self) -> Vec<Warning> {
+        std::mem::take(&mut *self.collected.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl fmt::Debug for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Warnings")
+            .field("collected", &self.collected.lock().unwrap_or_else(PoisonError::into_inner).len())
+            .field("callback", &self.callback.is_some())
+            .field("deny", &self.deny)
+            .field("allowed", &self.allowed)
+            .finish()
+    }
+}
+
+/// Raise `warning` in `sink`, or log it when the operation has none
+pub fn emit(sink: Option<&Warnings>, warning: Warning) -> Result<()> {
+    match sink {
+        Some(sink) => sink.emit(warning),
+        None => {
+            log::warn!("{}", warning);
+            Ok(())
+        }
+    }
+}
+
+/// HG001 and HG003 for a container about to be decrypted
+pub fn for_container(encrypted: &EncryptedData) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    // A stripped tag is not a legacy format; decryption refuses it
+    if !encrypted.is_tagged() && !encrypted.tag_stripped() {
+        warnings.push(
+            Warning::new(
+                WarningCode::LegacyFormat,
+                "ciphertext has no authentication tag, as containers before format 4 were written, \
+                 so it is decrypted unauthenticated; `hybridguard migrate` rewrites it with one",
+            )
+            .with_context("version", encrypted.version()),
+        );
+    }
+    warnings.extend(weak_stack(&SecurityAssessment::of_descriptors(encrypted.descriptors()), "ciphertext was written by"));
+    warnings
+}
+
+/// HG003 when `assessment` falls short of the minimum; `what` leads the message
+pub fn weak_stack(assessment: &SecurityAssessment, what: &str) -> Option<Warning> {
+    assessment.is_weak().then(|| {
+        let layers: Vec<&str> = assessment.layers.iter().map(|layer| layer.name.as_str()).collect();
+        Warning::new(
+            WarningCode::WeakStack,
+            format!(
+                "{} a layer stack of {} bits effective security, below the {}-bit minimum",
+                what, assessment.effective_bits, MIN_EFFECTIVE_SECURITY
+            ),
+        )
+        .with_context("layers", layers.join(", "))
+        .with_context("effective_bits", assessment.effective_bits)
+    })
+}
+
+/// HG002 when `key_manager` was generated `lifetime` or longer before `now`,
+/// in seconds since the epoch; keys whose generation time does not parse pass
+pub fn key_lifetime(key_manager: &KeyManager, lifetime: Duration, now: u64) -> Option<Warning> {
+    let age = key_manager.age(now)?;
+    (age >= lifetime).then(|| {
+        Warning::new(
+            WarningCode::KeyExpired,
+            format!(
+                "keys {} were generated {} day(s) ago, past their {}-day lifetime; \
+                 generate new keys and move files to them with rekey-plan and rekey-apply",
+                key_manager.key_id(),
+                age.as_secs() / SECS_PER_DAY,
+                lifetime.as_secs() / SECS_PER_DAY
+            ),
+        )
+        .with_context("key_id", key_manager.key_id())
+        .with_context("created_at", key_manager.created_at())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_round_trip() {
+        for code in WarningCode::ALL {
+            assert_eq!(code.as_str().parse::<WarningCode>(), Ok(code));
+            assert_eq!(code.as_str().to_lowercase().parse::<WarningCode>(), Ok(code));
+        }
+        assert!("HG999".parse::<WarningCode>().unwrap_err().contains("HG001"));
+    }
+
+    #[test]
+    fn test_sink_collects_allows_and_denies() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let printed = seen.clone();
+        let warnings = Warnings::new().allow(WarningCode::SourceChanged).on_warning(move |w| printed.lock().unwrap().push(w.code));
+        warnings.emit(Warning::new(WarningCode::LoosePermissions, "mode 644").with_context("path", "ops.keys")).unwrap();
+        warnings.emit(Warning::new(WarningCode::SourceChanged, "grew")).unwrap();
+        assert_eq!(*seen.lock().unwrap(), [WarningCode::LoosePermissions]);
+        let collected = warnings.clone().take();
+        assert_eq!(collected.len(), 1);
+        assert_eq!(collected[0].context["path"], "ops.keys");
+        assert!(warnings.collected().is_empty());
+
+        let denying = warnings.with_deny(true);
+        match denying.emit(Warning::new(WarningCode::WeakStack, "weak")) {
+            Err(HybridGuardError::WarningDenied { code: WarningCode::WeakStack, message }) => assert_eq!(message, "weak"),
+            other => panic!("expected the warning to be denied, got {:?}", other),
+        }
+        assert!(denying.emit(Warning::new(WarningCode::SourceChanged, "grew")).is_ok());
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(denying.collected().len(), 1);
+    }
+
+    #[test]
+    fn test_json_names_the_code() {
+        let warning = Warning::new(WarningCode::KeyExpired, "old").with_context("key_id", "abc");
+        let json = serde_json::to_value(&warning).unwrap();
+        assert_eq!(json["code"], "HG002");
+        assert_eq!(json["context"]["key_id"], "abc");
+        assert!(serde_json::to_value(Warning::new(WarningCode::KeyExpired, "old")).unwrap().get("context").is_none());
+    }
+}
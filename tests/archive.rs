@@ -0,0 +1,80 @@
+// `archive` packs an unchanged tree into identical bytes, and `extract`
+// unpacks it again; `encrypt` archives a directory input the same way, and
+// `decrypt --extract` unpacks it
+
+mod support;
+
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn archive(input: &Path, output: &Path) -> Vec<u8> {
+    let result = hybridguard(&[Path::new("archive"), Path::new("-i"), input, Path::new("-o"), output]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    fs::read(output).unwrap()
+}
+
+#[test]
+fn archives_are_reproducible_and_extract_intact() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = [("docs/plan.md", "the plan"), ("docs/old/notes.txt", "notes"), ("data.csv", "1,2,3")];
+    let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+    for (root, order) in [(&first, [0, 1, 2]), (&second, [2, 1, 0])] {
+        for i in order {
+            let (name, contents) = files[i];
+            fs::create_dir_all(root.join(name).parent().unwrap()).unwrap();
+            fs::write(root.join(name), contents).unwrap();
+        }
+    }
+
+    let bytes = archive(&first, &dir.path().join("first.hga"));
+    assert_eq!(bytes, archive(&second, &dir.path().join("second.hga")));
+
+    let out = dir.path().join("out");
+    let result = hybridguard(&[Path::new("extract"), Path::new("-i"), &dir.path().join("first.hga"), Path::new("-o"), &out]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    for (name, contents) in files {
+        assert_eq!(fs::read_to_string(out.join(name)).unwrap(), contents);
+    }
+
+    // Extracting over existing files is refused
+    let result = hybridguard(&[Path::new("extract"), Path::new("-i"), &dir.path().join("first.hga"), Path::new("-o"), &out]);
+    assert_eq!(result.status.code(), Some(2));
+}
+
+#[test]
+fn encrypt_archives_a_directory_and_decrypt_extracts_it() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("tree.keys");
+    hybridguard::KeyManager::from_master_key(&[0x7A; 32]).unwrap().save(&keys).unwrap();
+    let tree = dir.path().join("project");
+    fs::create_dir_all(tree.join("src/nested")).unwrap();
+    fs::write(tree.join("README"), "read me").unwrap();
+    fs::write(tree.join("src/nested/lib.rs"), "pub fn f() {}").unwrap();
+    fs::write(tree.join("src/big.bin"), vec![0x3C; 300_000]).unwrap();
+
+    let encrypted = dir.path().join("project.hg");
+    let result = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &tree, Path::new("-o"), &encrypted]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    let out = dir.path().join("restored");
+    let decrypt = |out: &Path| {
+        hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), out, Path::new("--extract")])
+    };
+    let result = decrypt(&out);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(fs::read_to_string(out.join("README")).unwrap(), "read me");
+    assert_eq!(fs::read_to_string(out.join("src/nested/lib.rs")).unwrap(), "pub fn f() {}");
+    assert_eq!(fs::read(out.join("src/big.bin")).unwrap(), vec![0x3C; 300_000]);
+
+    // A destination with anything in it is refused before decrypting
+    let result = decrypt(&out);
+    assert_eq!(result.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("is not empty"));
+
+    // Without --extract the plaintext is the archive itself
+    let packed = dir.path().join("project.hga");
+    let result = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &packed]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(fs::read(&packed).unwrap(), archive(&tree, &dir.path().join("again.hga")));
+}
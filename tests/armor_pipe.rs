@@ -0,0 +1,200 @@
+// Armored input from a pipe: `decrypt --input -` decodes armor a line at a
+// time and decrypts chunked ciphertexts as they arrive, in bounded memory
+
+use hybridguard::cancel::CancellationToken;
+use hybridguard::crypto::encoding::{self, ArmorReader, ArmorWriter, Encoding, MAX_ARMOR_LINE};
+use hybridguard::streaming::chunked;
+use hybridguard::KeyManager;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// The read end of an in-memory pipe: pieces arrive from another thread and
+/// each read hands out at most `step` bytes; it cannot seek
+struct Pipe {
+    pieces: Receiver<Vec<u8>>,
+    current: Vec<u8>,
+    pos: usize,
+    step: usize,
+}
+
+impl Pipe {
+    /// Send `data` through the pipe in `piece`-byte writes
+    fn carrying(data: Vec<u8>, piece: usize, step: usize) -> Self {
+        let (sender, pieces) = mpsc::sync_channel(4);
+        thread::spawn(move || {
+            for chunk in data.chunks(piece) {
+                if sender.send(chunk.to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { pieces, current: Vec::new(), pos: 0, step }
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.current.len() {
+            match self.pieces.recv() {
+                Ok(piece) => (self.current, self.pos) = (piece, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.step).min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Hashes what it is given, so the plaintext is never held
+struct HashSink(Sha256);
+
+impl Write for HashSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn hybridguard(args: &[&Path], stdin: Vec<u8>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run hybridguard");
+    let mut pipe = child.stdin.take().unwrap();
+    // The child may stop reading early; a broken pipe is part of the test
+    let writer = thread::spawn(move || {
+        let _ = pipe.write_all(&stdin);
+    });
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    output
+}
+
+fn decrypt_stdin(output: &Path, key_file: &Path, extra: &[&Path], stdin: Vec<u8>) -> Output {
+    let mut args = vec![Path::new("decrypt"), Path::new("-i"), Path::new("-"), Path::new("-o"), output, Path::new("-k"), key_file];
+    args.extend_from_slice(extra);
+    hybridguard(&args, stdin)
+}
+
+fn armor(bytes: &[u8]) -> Vec<u8> {
+    let mut writer = ArmorWriter::new(Vec::new()).unwrap();
+    writer.write_all(bytes).unwrap();
+    writer.finish().unwrap()
+}
+
+/// A chunked ciphertext of `data` with 64 KiB segments, and the key file it needs
+fn chunked_fixture(dir: &Path, data: &[u8]) -> (KeyManager, PathBuf, Vec<u8>) {
+    let key_manager = KeyManager::from_master_key(&[0x2B; 32]).unwrap();
+    let key_file = dir.join("work.keys");
+    key_manager.save(&key_file).unwrap();
+    let (plain, enc) = (dir.join("plain"), dir.join("plain.hg"));
+    fs::write(&plain, data).unwrap();
+    chunked::encrypt_file(&plain, &enc, &key_manager, 1).unwrap();
+    (key_manager, key_file, fs::read(enc).unwrap())
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len as u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect()
+}
+
+#[test]
+fn multi_megabyte_armored_stream_decrypts_in_bounded_memory() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample(3 << 20);
+    let (key_manager, _, ciphertext) = chunked_fixture(dir.path(), &data);
+    let armored = armor(&ciphertext);
+    assert!(armored.len() > 4 << 20);
+
+    let mut reader = ArmorReader::new(BufReader::with_capacity(16, Pipe::carrying(armored, 5, 3)));
+    let mut sink = HashSink(Sha256::new());
+    let stats = chunked::decrypt_stream(&mut reader, &mut sink, &key_manager, &CancellationToken::new()).unwrap();
+    assert_eq!(stats.plaintext_len, data.len() as u64);
+    assert_eq!(sink.0.finalize(), Sha256::digest(&data));
+
+    // One line of text and the bytes it decodes to, never the stream
+    let peak = reader.peak_buffered();
+    assert!(peak > 0 && peak <= 2 * MAX_ARMOR_LINE, "peak {} bytes", peak);
+}
+
+#[test]
+fn armored_and_binary_chunked_input_decrypt_from_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample(300_000);
+    let (_, key_file, ciphertext) = chunked_fixture(dir.path(), &data);
+
+    let out = dir.path().join("armored.out");
+    let output = decrypt_stdin(&out, &key_file, &[], armor(&ciphertext));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), data);
+
+    let out = dir.path().join("binary.out");
+    let output = decrypt_stdin(&out, &key_file, &[], ciphertext.clone());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), data);
+
+    // A stream cut short leaves nothing behind
+    let out = dir.path().join("cut.out");
+    let mut cut = armor(&ciphertext);
+    cut.truncate(cut.len() / 2);
+    let output = decrypt_stdin(&out, &key_file, &[], cut);
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!out.exists());
+}
+
+#[test]
+fn single_containers_on_stdin_need_spooling() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0x2B; 32]).unwrap();
+    let key_file = dir.path().join("work.keys");
+    key_manager.save(&key_file).unwrap();
+    let (plain, enc) = (dir.path().join("plain"), dir.path().join("plain.hg"));
+    fs::write(&plain, b"a legacy single container").unwrap();
+    let output = hybridguard(
+        &[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("-k"), &key_file],
+        Vec::new(),
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let armored = encoding::convert(&fs::read(&enc).unwrap(), Encoding::Armor).unwrap();
+
+    let out = dir.path().join("out");
+    let output = decrypt_stdin(&out, &key_file, &[], armored.clone());
+    assert_eq!(output.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--spool-to-temp"));
+    assert!(!out.exists());
+
+    let output = decrypt_stdin(&out, &key_file, &[Path::new("--spool-to-temp")], armored);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"a legacy single container");
+}
+
+#[test]
+fn chunked_files_convert_to_armor_and_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, _, ciphertext) = chunked_fixture(dir.path(), &sample(100_000));
+    let (binary, armored, back) = (dir.path().join("plain.hg"), dir.path().join("plain.hg.asc"), dir.path().join("back.hg"));
+
+    let output = hybridguard(&[Path::new("convert"), Path::new("-i"), &binary, Path::new("--to"), Path::new("armor"), Path::new("-o"), &armored], Vec::new());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&armored).unwrap(), armor(&ciphertext));
+
+    let output = hybridguard(&[Path::new("convert"), Path::new("-i"), &armored, Path::new("--to"), Path::new("binary"), Path::new("-o"), &back], Vec::new());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&back).unwrap(), ciphertext);
+
+    let output = hybridguard(&[Path::new("convert"), Path::new("-i"), &binary, Path::new("--to"), Path::new("json"), Path::new("-o"), &dir.path().join("json")], Vec::new());
+    assert_eq!(output.status.code(), Some(6));
+}
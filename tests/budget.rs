@@ -0,0 +1,165 @@
+// --max-duration and --max-output-bytes: runs that pass their budget stop at
+// the next chunk or layer boundary, remove partial outputs and say how much
+// of each budget they used
+
+mod support;
+
+use hybridguard::budget::Budget;
+use hybridguard::error::exit_code;
+use hybridguard::streaming::chunked;
+use hybridguard::streaming::DEFAULT_CHUNK_SIZE;
+use hybridguard::{CancellationToken, DecryptOptions, EncryptOptions, HybridGuard, HybridGuardError, KeyManager};
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::process::Output;
+use std::thread;
+use std::time::{Duration, Instant};
+use support::hybridguard;
+
+/// A source that takes `delay` over every read, like a slow network share
+struct Throttled {
+    inner: Cursor<Vec<u8>>,
+    delay: Duration,
+}
+
+impl Read for Throttled {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(self.delay);
+        self.inner.read(buf)
+    }
+}
+
+fn budget(max_duration: Option<Duration>, max_output_bytes: Option<u64>) -> CancellationToken {
+    CancellationToken::new().with_budget(Budget { max_duration, max_output_bytes })
+}
+
+#[test]
+fn throttled_stream_stops_at_the_time_budget() {
+    let key_manager = KeyManager::from_master_key(&[0x6C; 32]).unwrap();
+    let len = 64 * DEFAULT_CHUNK_SIZE;
+    let mut input = Throttled { inner: Cursor::new(vec![0x5A; len]), delay: Duration::from_millis(20) };
+    let token = budget(Some(Duration::from_millis(100)), None);
+
+    let started = Instant::now();
+    let mut output = Vec::new();
+    let result = chunked::encrypt_into(&mut input, len as u64, &mut output, &key_manager, 4, &token);
+    match result {
+        Err(HybridGuardError::BudgetExceeded { which, limit: 100, actual }) => assert!(which.contains("time") && actual >= 100),
+        other => panic!("expected the time budget to run out, got {:?}", other.map(|stats| stats.segments)),
+    }
+    // Stopped at the next chunk, long before the 64 reads a full run takes
+    assert!(started.elapsed() < Duration::from_millis(64 * 20));
+    assert!(token.budget_usage().unwrap().elapsed_ms >= 100);
+}
+
+#[test]
+fn output_budget_refuses_the_write_that_passes_it() {
+    let key_manager = KeyManager::from_master_key(&[0x6D; 32]).unwrap();
+    let len = 8 * DEFAULT_CHUNK_SIZE;
+    let limit = 3 * DEFAULT_CHUNK_SIZE as u64;
+    let token = budget(None, Some(limit));
+
+    let mut output = Vec::new();
+    let result = chunked::encrypt_into(&mut Cursor::new(vec![0x5B; len]), len as u64, &mut output, &key_manager, 4, &token);
+    assert!(matches!(result, Err(HybridGuardError::BudgetExceeded { limit: l, .. }) if l == limit), "{:?}", result.err());
+    assert!(output.len() as u64 <= limit);
+    assert_eq!(token.budget_usage().unwrap().output_bytes, output.len() as u64);
+
+    // Files: the partial ciphertext and the partial plaintext are both removed
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, enc, out) = (dir.path().join("large.bin"), dir.path().join("large.hg"), dir.path().join("large.out"));
+    fs::write(&plain, vec![0x5C; len]).unwrap();
+    let result = chunked::encrypt_file_cancellable(&plain, &enc, &key_manager, 4, &budget(None, Some(limit)));
+    assert!(matches!(result, Err(HybridGuardError::BudgetExceeded { .. })));
+    assert!(!enc.exists(), "partial output was left behind");
+
+    chunked::encrypt_file(&plain, &enc, &key_manager, 4).unwrap();
+    let result = chunked::decrypt_file_cancellable(&enc, &out, &key_manager, &budget(None, Some(limit)));
+    assert!(matches!(result, Err(HybridGuardError::BudgetExceeded { .. })));
+    assert!(!out.exists(), "partial plaintext was left behind");
+    let token = budget(Some(Duration::from_secs(600)), Some(len as u64));
+    chunked::decrypt_file_cancellable(&enc, &out, &key_manager, &token).unwrap();
+    assert_eq!(token.budget_usage().unwrap().output_bytes, len as u64);
+}
+
+#[test]
+fn library_options_carry_a_budget() {
+    let guard = HybridGuard::builder(KeyManager::from_master_key(&[0x6E; 32]).unwrap()).build().unwrap();
+    let roomy = Budget { max_duration: Some(Duration::from_secs(600)), max_output_bytes: Some(1 << 20) };
+    let encrypted = guard.encrypt_with_options(b"quarterly numbers", &EncryptOptions { budget: roomy, ..EncryptOptions::default() }).unwrap();
+    let decrypt = DecryptOptions { budget: roomy, ..DecryptOptions::default() };
+    assert_eq!(guard.decrypt_with_options(&encrypted, &decrypt).unwrap(), b"quarterly numbers");
+
+    // The ciphertext is far larger than 64 bytes; the plaintext is not smaller than 4
+    let tight = Budget { max_duration: None, max_output_bytes: Some(64) };
+    let result = guard.encrypt_with_options(b"quarterly numbers", &EncryptOptions { budget: tight, ..EncryptOptions::default() });
+    assert!(matches!(result, Err(HybridGuardError::BudgetExceeded { limit: 64, .. })));
+    let tight = Budget { max_duration: None, max_output_bytes: Some(4) };
+    let result = guard.decrypt_with_options(&encrypted, &DecryptOptions { budget: tight, ..DecryptOptions::default() });
+    assert!(matches!(result, Err(HybridGuardError::BudgetExceeded { limit: 4, actual: 17, .. })));
+}
+
+/// The `{"budget": …}` line of a --json-progress run
+fn budget_line(output: &Output) -> serde_json::Value {
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find_map(|value| value.get("budget").cloned())
+        .unwrap_or_else(|| panic!("no budget line in {}", String::from_utf8_lossy(&output.stderr)))
+}
+
+#[test]
+fn cli_reports_budgets_and_removes_partial_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("batch.keys");
+    KeyManager::from_master_key(&[0x6F; 32]).unwrap().save(&key_file).unwrap();
+    let (plain, enc) = (dir.path().join("report.csv"), dir.path().join("report.hg"));
+    fs::write(&plain, vec![b'x'; 5000]).unwrap();
+    let encrypt = |extra: &[&str]| {
+        let mut args = vec![Path::new("--json-progress"), Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("-k"), &key_file];
+        args.extend(extra.iter().map(Path::new));
+        hybridguard(&args)
+    };
+
+    // Within budget: the usage is reported on success too
+    let output = encrypt(&["--max-duration", "10m", "--max-output-bytes", "50G"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let used = budget_line(&output);
+    assert_eq!(used["output_bytes"], fs::metadata(&enc).unwrap().len());
+    assert_eq!((used["max_duration_ms"].as_u64(), used["max_output_bytes"].as_u64()), (Some(600_000), Some(50 << 30)));
+    fs::remove_file(&enc).unwrap();
+
+    let output = encrypt(&["--max-output-bytes", "1K"]);
+    assert_eq!(output.status.code(), Some(exit_code::BUDGET as i32), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Budget exceeded: output bytes"));
+    assert_eq!(budget_line(&output)["output_bytes"], 0);
+    assert!(!enc.exists());
+
+    // Formats without chunk boundaries to check at are refused up front
+    let output = encrypt(&["--sparse", "--max-duration", "10m"]);
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+    assert_eq!(encrypt(&["--max-duration", "soon"]).status.code(), Some(exit_code::USAGE as i32));
+}
+
+#[cfg(unix)]
+#[test]
+fn cli_stops_a_slow_producer_at_the_time_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("batch.keys");
+    KeyManager::from_master_key(&[0x70; 32]).unwrap().save(&key_file).unwrap();
+    let enc = dir.path().join("dump.hg");
+
+    let started = Instant::now();
+    let output = hybridguard(&[
+        Path::new("--json-progress"), Path::new("encrypt"), Path::new("--source-cmd"), Path::new("printf 'COPY t'; sleep 60"),
+        Path::new("--max-input-bytes"), Path::new("1048576"), Path::new("--max-duration"), Path::new("1"),
+        Path::new("-o"), &enc, Path::new("-k"), &key_file,
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(exit_code::BUDGET as i32), "{}", stderr);
+    assert!(started.elapsed() < Duration::from_secs(30));
+    assert!(budget_line(&output)["elapsed_ms"].as_u64().unwrap() >= 1000);
+    assert!(!enc.exists());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "a staged file was left behind");
+}
@@ -0,0 +1,103 @@
+// `encrypt --self-extracting` writes a runnable bundle that asks for its
+// password and extracts itself
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+const PASSWORD: &str = "plinth-Ocelot-47-marmalade";
+
+/// Run `program` with `args`, typing `password` at the prompt
+fn run(program: &Path, args: &[&Path], password: &str) -> Output {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run");
+    writeln!(child.stdin.take().unwrap(), "{}", password).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn hybridguard(args: &[&Path], password: &str) -> Output {
+    run(Path::new(env!("CARGO_BIN_EXE_hybridguard")), args, password)
+}
+
+/// A small tree to bundle
+fn fixture(root: &Path) -> [(&'static str, &'static str); 2] {
+    let files = [("report.txt", "quarterly numbers"), ("photos/readme.md", "# Photos")];
+    for (name, contents) in files {
+        fs::create_dir_all(root.join(name).parent().unwrap()).unwrap();
+        fs::write(root.join(name), contents).unwrap();
+    }
+    files
+}
+
+/// Bundle `input`, typing `password` and then `confirm` at the prompts
+fn encrypt_bundle(input: &Path, output: &Path, extra: &[&Path], password: &str, confirm: &str) -> Output {
+    let mut args = vec![Path::new("encrypt"), Path::new("--self-extracting"), Path::new("-i"), input, Path::new("-o"), output];
+    args.extend_from_slice(extra);
+    hybridguard(&args, &format!("{}\n{}", password, confirm))
+}
+
+#[test]
+fn bundle_extracts_itself_with_the_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input");
+    let files = fixture(&input);
+    let bundle = dir.path().join("bundle");
+
+    // Caveats come with every bundle, even with --quiet
+    let output = encrypt_bundle(&input, &bundle, &[Path::new("--quiet")], PASSWORD, PASSWORD);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("anyone who can alter it can make it steal the password"), "{}", stderr);
+    assert!(stderr.contains("only as strong as its password"), "{}", stderr);
+    assert!(stderr.contains(std::env::consts::OS), "{}", stderr);
+
+    // The plaintext is nowhere in the bundle
+    let bytes = fs::read(&bundle).unwrap();
+    assert!(bytes.ends_with(b"HGBUNDLE"));
+    assert!(!bytes.windows(17).any(|w| w == b"quarterly numbers"));
+
+    let wrong = run(&bundle, &[Path::new("--output"), &dir.path().join("wrong")], "not the password");
+    assert_eq!(wrong.status.code(), Some(3), "{}", String::from_utf8_lossy(&wrong.stderr));
+
+    let out = dir.path().join("out");
+    let output = run(&bundle, &[Path::new("--output"), &out], PASSWORD);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Extracted 3 entries"));
+    for (name, contents) in files {
+        assert_eq!(fs::read_to_string(out.join(name)).unwrap(), contents);
+    }
+}
+
+#[test]
+fn bundle_needs_a_strong_confirmed_password_and_a_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input");
+    fixture(&input);
+
+    let output = encrypt_bundle(&input, &dir.path().join("weak"), &[], "password123", "password123");
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!dir.path().join("weak").exists());
+
+    // A mistyped confirmation writes nothing
+    let output = encrypt_bundle(&input, &dir.path().join("typo"), &[], PASSWORD, "plinth-Ocelot-74-marmalade");
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("did not match"));
+    assert!(!dir.path().join("typo").exists());
+
+    let output = encrypt_bundle(&input.join("report.txt"), &dir.path().join("file"), &[], PASSWORD, PASSWORD);
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!dir.path().join("file").exists());
+}
+
+#[test]
+fn plain_binary_ignores_bundle_arguments() {
+    // Without a trailer the binary parses the usual commands
+    let output = hybridguard(&[Path::new("--output"), Path::new("anywhere")], "");
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,77 @@
+// Cancelling long operations from another thread through a CancellationToken
+
+use hybridguard::streaming::chunked;
+use hybridguard::streaming::DEFAULT_CHUNK_SIZE;
+use hybridguard::{CancellationToken, DecryptOptions, EncryptOptions, HybridGuard, HybridGuardError, KeyManager};
+use std::fs;
+use std::thread;
+
+#[test]
+fn cancel_mid_chunked_encryption() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xCA; 32]).unwrap();
+    let input = dir.path().join("large.bin");
+    let output = dir.path().join("large.hg");
+    let chunks = 256;
+    let data: Vec<u8> = (0..chunks * DEFAULT_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+    fs::write(&input, &data).unwrap();
+
+    let token = CancellationToken::new();
+    let canceller = {
+        let token = token.clone();
+        thread::spawn(move || {
+            while token.polls() < 8 {
+                thread::yield_now();
+            }
+            token.cancel();
+            token.polls()
+        })
+    };
+
+    let result = chunked::encrypt_file_cancellable(&input, &output, &key_manager, 4, &token);
+    let polls_at_cancel = canceller.join().unwrap();
+
+    assert!(matches!(result, Err(HybridGuardError::Cancelled)), "{:?}", result.map(|stats| stats.segments));
+    assert!(!output.exists(), "partial output was left behind");
+    // One poll per chunk, so at most one more chunk ran after cancel
+    assert!(token.polls() <= polls_at_cancel + 1, "{} polls after cancelling at {}", token.polls(), polls_at_cancel);
+    assert!(token.polls() < chunks as u64);
+}
+
+#[test]
+fn cancelled_chunked_decryption_removes_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xCB; 32]).unwrap();
+    let input = dir.path().join("plain.bin");
+    let encrypted = dir.path().join("plain.hg");
+    let restored = dir.path().join("restored.bin");
+    fs::write(&input, vec![7u8; 3 * DEFAULT_CHUNK_SIZE]).unwrap();
+    chunked::encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let result = chunked::decrypt_file_cancellable(&encrypted, &restored, &key_manager, &token);
+    assert!(matches!(result, Err(HybridGuardError::Cancelled)));
+    assert!(!restored.exists());
+    assert_eq!(token.polls(), 1);
+
+    chunked::decrypt_file_cancellable(&encrypted, &restored, &key_manager, &CancellationToken::new()).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), fs::read(&input).unwrap());
+}
+
+#[test]
+fn library_calls_honour_the_token() {
+    let guard = HybridGuard::builder(KeyManager::from_master_key(&[0xCC; 32]).unwrap()).build().unwrap();
+    let live = CancellationToken::new();
+    let encrypt = EncryptOptions { cancel: Some(live.clone()), ..EncryptOptions::default() };
+    let decrypt = DecryptOptions { cancel: Some(live.clone()), ..DecryptOptions::default() };
+
+    let encrypted = guard.encrypt_with_options(b"cancel me later", &encrypt).unwrap();
+    assert_eq!(guard.decrypt_with_options(&encrypted, &decrypt).unwrap(), b"cancel me later");
+    assert!(live.polls() > 0);
+
+    live.cancel();
+    assert!(matches!(guard.encrypt_with_options(b"too late", &encrypt), Err(HybridGuardError::Cancelled)));
+    // Cancellation is reported as such, not folded into the uniform decryption error
+    assert!(matches!(guard.decrypt_with_options(&encrypted, &decrypt), Err(HybridGuardError::Cancelled)));
+}
@@ -0,0 +1,94 @@
+// Encrypt-only key files: they encrypt, and every decrypt path refuses them up front
+
+mod support;
+
+use hybridguard::key_manager::Capability;
+use hybridguard::{HybridGuard, HybridGuardError, KeyManager};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+/// The `capabilities` field as written in a key file
+fn capabilities_field(path: &Path) -> serde_json::Value {
+    let file: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    file["body"]["capabilities"].clone()
+}
+
+/// The master keys of the key file at `path`
+fn restricted_keys(path: &Path) -> Result<(), HybridGuardError> {
+    KeyManager::load(path)?.get_keys().map(|_| ())
+}
+
+#[test]
+fn encrypt_only_key_encrypts_but_cannot_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let full = KeyManager::from_master_key(&[0x5C; 32]).unwrap();
+    let path = dir.path().join("ingest.keys");
+    full.encrypt_only().unwrap().save(&path).unwrap();
+
+    let restricted = KeyManager::load(&path).unwrap();
+    assert_eq!(restricted.capabilities(), &[Capability::Encrypt]);
+    assert!(restricted.can_encrypt() && !restricted.can_decrypt());
+    assert_eq!(restricted.key_id(), full.key_id());
+
+    let ingest = HybridGuard::builder(restricted).build().unwrap();
+    let encrypted = ingest.encrypt(b"incoming record").unwrap();
+    assert!(matches!(ingest.decrypt(&encrypted), Err(HybridGuardError::CapabilityDenied(_))));
+
+    // The file holds no layer key to edit back into decrypting
+    let file = fs::read_to_string(&path).unwrap();
+    assert!(!file.contains("layer1_key") && file.contains("encapsulation_key"), "{}", file);
+    assert!(matches!(restricted_keys(&path), Err(HybridGuardError::CapabilityDenied(_))));
+
+    // The full key decrypts what the ingestion server wrote
+    assert!(full.can_encrypt() && full.can_decrypt());
+    assert_eq!(HybridGuard::builder(full).build().unwrap().decrypt(&encrypted).unwrap(), b"incoming record");
+}
+
+#[test]
+fn full_key_file_keeps_both_capabilities() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("full.keys");
+    KeyManager::from_master_key(&[0x5D; 32]).unwrap().save(&path).unwrap();
+    assert_eq!(capabilities_field(&path), serde_json::json!(["encrypt", "decrypt"]));
+
+    let loaded = KeyManager::load(&path).unwrap();
+    assert_eq!(loaded.capabilities(), &[Capability::Encrypt, Capability::Decrypt]);
+    assert!(loaded.decryption_keys().is_ok());
+}
+
+#[test]
+fn cli_export_and_refused_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    fs::write(&master, [0x5E; 32]).unwrap();
+    let keystore = dir.path().join("keys");
+    let output = hybridguard(&[Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let full = keystore.join("hybridguard.keys");
+
+    let ingest = dir.path().join("ingest.keys");
+    let output = hybridguard(&[Path::new("key"), Path::new("export"), Path::new("-k"), &full, Path::new("-o"), &ingest]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = hybridguard(&[
+        Path::new("key"), Path::new("export"), Path::new("-k"), &full, Path::new("--encrypt-only"), Path::new("-o"), &ingest,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(capabilities_field(&ingest), serde_json::json!(["encrypt"]));
+
+    let input = dir.path().join("event.json");
+    fs::write(&input, br#"{"event": "login"}"#).unwrap();
+    let encrypted = dir.path().join("event.hg");
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &ingest, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let restored = dir.path().join("event.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &ingest, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Capability denied"));
+    assert!(!restored.exists());
+
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &full, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), br#"{"event": "login"}"#);
+}
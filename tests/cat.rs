@@ -0,0 +1,52 @@
+// `cat` writes a byte range of the plaintext without decrypting the whole file
+
+mod support;
+
+use hybridguard::streaming::chunked;
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+fn cat(input: &Path, keys: &Path, offset: &str, length: &str, extra: &[&str]) -> Output {
+    let mut args = vec![Path::new("cat"), Path::new("-k"), keys, Path::new("-i"), input];
+    args.extend([Path::new("--offset"), Path::new(offset), Path::new("--length"), Path::new(length)]);
+    args.extend(extra.iter().map(Path::new));
+    hybridguard(&args)
+}
+
+#[test]
+fn ranges_of_chunked_and_single_containers() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let key_manager = KeyManager::from_master_key(&[0xCA; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 233) as u8).collect();
+    let input = dir.path().join("dataset.bin");
+    let segmented = dir.path().join("dataset.hgd");
+    fs::write(&input, &data).unwrap();
+    chunked::encrypt_file(&input, &segmented, &key_manager, 1).unwrap();
+
+    // 65_530..65_550 crosses the first segment boundary
+    let output = cat(&segmented, &keys, "65530", "20", &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, data[65_530..65_550]);
+
+    // Past the end: refused, or cut short with --clamp
+    let output = cat(&segmented, &keys, "299990", "100", &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = cat(&segmented, &keys, "299990", "100", &["--clamp"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, data[299_990..]);
+
+    // A single container has no segment index and is decrypted whole, with a warning
+    let single = dir.path().join("small.hg");
+    let encrypted = HybridGuard::builder(KeyManager::load(&keys).unwrap()).build().unwrap().encrypt(&data[..1000]).unwrap();
+    fs::write(&single, encrypted.to_bytes().unwrap()).unwrap();
+    let output = cat(&single, &keys, "100", "50", &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, data[100..150]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no segment index"));
+}
@@ -0,0 +1,72 @@
+// Checkpointed encryption resumes an interrupted run from the command line
+
+mod support;
+
+use hybridguard::streaming::checkpoint::CheckpointedEncryption;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn interrupted_run_resumes_from_checkpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let key_manager = KeyManager::from_master_key(&[0xC4; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+
+    let input = dir.path().join("dataset.bin");
+    let encrypted = dir.path().join("dataset.hg");
+    let checkpoint = dir.path().join("dataset.ckpt");
+    let restored = dir.path().join("restored.bin");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 239) as u8).collect();
+    fs::write(&input, &data).unwrap();
+
+    // Two of five segments done, then the process "dies"
+    {
+        let mut run = CheckpointedEncryption::open(&input, &encrypted, &key_manager, 1, Some(&checkpoint)).unwrap();
+        run.step().unwrap();
+        run.step().unwrap();
+    }
+    assert!(checkpoint.exists());
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--checkpoint"), &checkpoint, Path::new("--checkpoint-every"), Path::new("1"),
+        Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Resuming at segment 2 of 5"));
+    assert!(!checkpoint.exists());
+
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), data);
+}
+
+#[test]
+fn tampered_checkpoint_exits_with_integrity_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let key_manager = KeyManager::from_master_key(&[0xC5; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+
+    let input = dir.path().join("dataset.bin");
+    let encrypted = dir.path().join("dataset.hg");
+    let checkpoint = dir.path().join("dataset.ckpt");
+    fs::write(&input, vec![0x11; 200_000]).unwrap();
+    {
+        let mut run = CheckpointedEncryption::open(&input, &encrypted, &key_manager, 1, Some(&checkpoint)).unwrap();
+        run.step().unwrap();
+    }
+    let mut bytes = fs::read(&checkpoint).unwrap();
+    bytes[8] ^= 0x80;
+    fs::write(&checkpoint, &bytes).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--checkpoint"), &checkpoint, Path::new("--checkpoint-every"), Path::new("1"),
+        Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert_eq!(output.status.code(), Some(4));
+}
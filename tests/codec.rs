@@ -0,0 +1,47 @@
+// Every text codec decodes its own output, and key IDs survive printing
+
+use hybridguard::crypto::codec;
+use hybridguard::KeyId;
+use proptest::prelude::*;
+
+fn bytes() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..256)
+}
+
+proptest! {
+    #[test]
+    fn hex_round_trip(data in bytes()) {
+        let text = codec::hex_lower(&data);
+        prop_assert_eq!(text.len(), data.len() * 2);
+        prop_assert_eq!(codec::hex_lower_decode(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn grouped_hex_round_trip(data in bytes(), group in 1usize..9) {
+        let text = codec::hex_grouped(&data, group);
+        prop_assert_eq!(codec::hex_grouped_decode(&text, group).unwrap(), data);
+    }
+
+    #[test]
+    fn base64_round_trip(data in bytes()) {
+        prop_assert_eq!(codec::b64_std_decode(&codec::b64_std(&data)).unwrap(), data.clone());
+        let url = codec::b64_url_nopad(&data);
+        prop_assert!(!url.contains(['+', '/', '=']));
+        prop_assert_eq!(codec::b64_url_nopad_decode(&url).unwrap(), data);
+    }
+
+    #[test]
+    fn crockford_round_trip(data in bytes()) {
+        let text = codec::crockford32(&data);
+        prop_assert_eq!(codec::crockford32_decode(&text).unwrap(), data.clone());
+        prop_assert_eq!(codec::crockford32_decode(&text.to_lowercase()).unwrap(), data);
+    }
+
+    #[test]
+    fn key_id_round_trip(id in any::<[u8; 16]>()) {
+        let text = format!("{}{}", KeyId::PREFIX, codec::hex_lower(&id));
+        let parsed = KeyId::parse(&text).unwrap();
+        prop_assert_eq!(parsed.as_bytes(), &id);
+        prop_assert_eq!(parsed.to_string(), text);
+    }
+}
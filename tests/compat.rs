@@ -0,0 +1,83 @@
+// Ciphertexts every earlier release wrote decrypt bit for bit under this
+// build: the corpus in tests/compat/ holds one fixture set per producing
+// version, and compat-snapshot adds the current one
+#![cfg(feature = "interop-tests")]
+
+use hybridguard::compat::{self, CorpusIndex};
+use hybridguard::fixtures::{Manifest, MANIFEST_FILE};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn corpus() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("compat")
+}
+
+#[test]
+fn corpus_holds_a_fixture_set() {
+    let index = CorpusIndex::load(&corpus()).unwrap();
+    assert!(
+        !index.sets.is_empty(),
+        "tests/compat holds no fixture sets; add this release's with `cargo run --features fixtures --bin compat-snapshot`"
+    );
+}
+
+fn compat_snapshot(corpus: &Path, replace: bool) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_compat-snapshot"));
+    command.arg("--corpus").arg(corpus);
+    if replace {
+        command.arg("--replace");
+    }
+    command.output().expect("failed to run compat-snapshot")
+}
+
+#[test]
+fn every_release_in_the_corpus_still_decrypts() {
+    let corpus = corpus();
+    let index = CorpusIndex::load(&corpus).unwrap();
+    let matrix = compat::check(&corpus).unwrap();
+    println!("{}", matrix);
+    assert!(matrix.is_clean(), "entries of earlier releases no longer decrypt:\n{}", matrix);
+    for set in &index.sets {
+        assert!(matrix.results.iter().any(|result| result.version == set.version), "set {} was not checked", set.version);
+    }
+}
+
+#[test]
+fn snapshot_adds_this_version_once() {
+    let corpus = tempfile::tempdir().unwrap();
+    let output = compat_snapshot(corpus.path(), false);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let index = CorpusIndex::load(corpus.path()).unwrap();
+    assert_eq!(index.sets.len(), 1);
+    let set = &index.sets[0];
+    assert_eq!(set.version, env!("CARGO_PKG_VERSION"));
+    let manifest: Manifest = serde_json::from_slice(&fs::read(corpus.path().join(&set.version).join(MANIFEST_FILE)).unwrap()).unwrap();
+    assert_eq!(manifest.fixtures.len(), set.fixtures);
+    assert!(compat::check(corpus.path()).unwrap().is_clean());
+
+    // A released set is the record of what that version wrote
+    let output = compat_snapshot(corpus.path(), false);
+    assert_eq!(output.status.code(), Some(2));
+    let output = compat_snapshot(corpus.path(), true);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(CorpusIndex::load(corpus.path()).unwrap(), index);
+}
+
+#[test]
+fn damaged_corpus_entries_fail_the_matrix() {
+    let corpus = tempfile::tempdir().unwrap();
+    let set = compat::snapshot(corpus.path(), false).unwrap();
+    let dir = corpus.path().join(&set.version);
+    let manifest: Manifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST_FILE)).unwrap()).unwrap();
+    let entry = manifest.fixtures.iter().find(|entry| entry.encoding == "binary" && entry.authenticated).unwrap();
+    let mut bytes = fs::read(dir.join(&entry.file)).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    fs::write(dir.join(&entry.file), bytes).unwrap();
+
+    let matrix = compat::check(corpus.path()).unwrap();
+    let failures: Vec<_> = matrix.failures().map(|result| result.file.as_str()).collect();
+    assert_eq!(failures, vec![entry.file.as_str()]);
+}
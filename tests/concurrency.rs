@@ -0,0 +1,58 @@
+// One HybridGuard shared by many threads through an Arc, and the KEM layers
+// started together on many threads
+
+use hybridguard::layers::{EncryptionLayer, HqcLayer, MlKemLayer};
+use hybridguard::{HybridGuard, KeyManager};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+const THREADS: usize = 32;
+const ROUNDS: usize = 4;
+
+#[test]
+fn shared_instance_round_trips_on_many_threads() {
+    let key_manager = KeyManager::from_master_key(&[0x5C; 32]).unwrap();
+    let hg = Arc::new(HybridGuard::builder(key_manager).build().unwrap());
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let hg = Arc::clone(&hg);
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let message: Vec<u8> = (0..64 + t * 37 + round).map(|i| (i * 7 + t) as u8).collect();
+                    let encrypted = hg.encrypt(&message).unwrap();
+                    assert_eq!(hg.decrypt(&encrypted).unwrap(), message, "thread {} round {}", t, round);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().expect("worker thread panicked");
+    }
+}
+
+#[test]
+fn kem_layers_start_together_without_spurious_failures() {
+    // Fresh layers released at once, so liboqs sees many first uses racing
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                let layer: Box<dyn EncryptionLayer> = if t % 2 == 0 { Box::new(MlKemLayer::new()) } else { Box::new(HqcLayer::new()) };
+                let key = [t as u8; 32];
+                let message = vec![t as u8; 100 + t];
+                barrier.wait();
+                for round in 0..ROUNDS {
+                    let encrypted = layer.encrypt(&message, &key).unwrap_or_else(|e| panic!("thread {} round {}: {}", t, round, e));
+                    assert_eq!(layer.decrypt(&encrypted, &key).unwrap(), message, "thread {} round {}", t, round);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().expect("worker thread panicked");
+    }
+}
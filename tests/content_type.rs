@@ -0,0 +1,143 @@
+// Content types: `encrypt --tag-content-type` records what the plaintext is,
+// sealed so only the key file reads it, and decrypt warns about executables
+// and tags that do not match, quarantining executables with
+// `--quarantine-executables`
+
+mod support;
+
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::KeyManager;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+/// The start of a 64-bit little-endian ELF executable
+const ELF: &[u8] = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0\x3e\0\x01\0\0\0";
+
+fn encrypt_tagged(input: &Path, output: &Path, key_file: &Path) -> Output {
+    hybridguard(&[
+        Path::new("encrypt"),
+        Path::new("-i"),
+        input,
+        Path::new("-o"),
+        output,
+        Path::new("-k"),
+        key_file,
+        Path::new("--tag-content-type"),
+    ])
+}
+
+fn decrypt(input: &Path, output: &Path, key_file: &Path, extra: &[&Path]) -> Output {
+    let mut args = vec![Path::new("decrypt"), Path::new("-i"), input, Path::new("-o"), output, Path::new("-k"), key_file];
+    args.extend_from_slice(extra);
+    hybridguard(&args)
+}
+
+fn keys(dir: &Path) -> (KeyManager, std::path::PathBuf) {
+    let path = dir.join("work.keys");
+    let key_manager = KeyManager::from_master_key(&[0x3C; 32]).unwrap();
+    key_manager.save(&path).unwrap();
+    (key_manager, path)
+}
+
+#[test]
+fn executables_are_tagged_and_flagged() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, key_file) = keys(dir.path());
+    let (plain, enc, out) = (dir.path().join("tool"), dir.path().join("tool.hg"), dir.path().join("out"));
+    fs::write(&plain, ELF).unwrap();
+
+    let output = encrypt_tagged(&plain, &enc, &key_file);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // Sealed with the private metadata, the type shows only with the key file
+    let output = hybridguard(&[Path::new("inspect"), Path::new("--brief"), Path::new("-i"), &enc]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Content type"));
+    assert!(!fs::read(&enc).unwrap().windows(3).any(|w| w == b"elf"));
+    let output = hybridguard(&[Path::new("inspect"), Path::new("--key-file"), &key_file, Path::new("-i"), &enc]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Content type: elf"), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Without a quarantine directory the output is written, with a warning
+    // even under --quiet
+    let output = decrypt(&enc, &out, &key_file, &[Path::new("--quiet")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("decrypts to an executable (elf)"), "{}", stderr);
+    assert_eq!(fs::read(&out).unwrap(), ELF);
+
+    let output = decrypt(&enc, &dir.path().join("again"), &key_file, &[]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stored content type elf, detected elf"));
+}
+
+#[test]
+fn mismatched_tags_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let (key_manager, key_file) = keys(dir.path());
+    let (enc, out, report) = (dir.path().join("notes.hg"), dir.path().join("notes"), dir.path().join("content.json"));
+
+    // A sender claiming an executable is text
+    let encryptor = HybridGuardEncryptor::new();
+    let (file_keys, wrapped) = encryptor.new_file_keys(&key_manager).unwrap();
+    let encrypted = encryptor
+        .encrypt(ELF, &file_keys)
+        .unwrap()
+        .with_content_type("text", &file_keys)
+        .unwrap()
+        .with_wrapped_key(wrapped, &file_keys)
+        .with_key_id(key_manager.key_id());
+    fs::write(&enc, encrypted.to_bytes().unwrap()).unwrap();
+
+    let output = decrypt(&enc, &out, &key_file, &[Path::new("--content-report"), &report]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("was tagged text when encrypted but decrypts to elf"), "{}", stderr);
+
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    let file = &report["files"][0];
+    assert_eq!(file["stored"], "text");
+    assert_eq!(file["detected"], "elf");
+    assert_eq!(file["mismatch"], true);
+    assert_eq!(file["executable"], true);
+    assert_eq!(file["quarantined"], Value::Null);
+}
+
+#[test]
+fn quarantined_executables_lose_execute_permission() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, key_file) = keys(dir.path());
+    let quarantine = dir.path().join("quarantine");
+    fs::create_dir(&quarantine).unwrap();
+    let (plain, enc, out, report) =
+        (dir.path().join("tool"), dir.path().join("tool.hg"), dir.path().join("out"), dir.path().join("content.json"));
+    fs::write(&plain, ELF).unwrap();
+    assert!(encrypt_tagged(&plain, &enc, &key_file).status.success());
+
+    let output = decrypt(&enc, &out, &key_file, &[Path::new("--quarantine-executables"), &quarantine, Path::new("--content-report"), &report]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!out.exists());
+    let quarantined = quarantine.join("out");
+    assert_eq!(fs::read(&quarantined).unwrap(), ELF);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&quarantined).unwrap().permissions().mode() & 0o111, 0);
+    }
+
+    let report: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["files"][0]["quarantined"], quarantined.to_str().unwrap());
+    assert_eq!(report["files"][0]["mismatch"], false);
+
+    // A second run does not overwrite what is already in quarantine
+    let output = decrypt(&enc, &out, &key_file, &[Path::new("--quarantine-executables"), &quarantine]);
+    assert_eq!(output.status.code(), Some(5), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Text is written where it was asked to go
+    let (notes, notes_enc, notes_out) = (dir.path().join("notes"), dir.path().join("notes.hg"), dir.path().join("notes.out"));
+    fs::write(&notes, b"meeting at noon").unwrap();
+    assert!(encrypt_tagged(&notes, &notes_enc, &key_file).status.success());
+    let output = decrypt(&notes_enc, &notes_out, &key_file, &[Path::new("--quarantine-executables"), &quarantine]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&notes_out).unwrap(), b"meeting at noon");
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("executable"));
+}
@@ -0,0 +1,66 @@
+// Converting ciphertexts between encodings keeps them decryptable
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn every_encoding_converts_to_every_other() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x71; 32]).unwrap().save(&keys).unwrap();
+
+    let plaintext: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    let input = dir.path().join("report.pdf");
+    fs::write(&input, &plaintext).unwrap();
+    let binary = dir.path().join("report.binary");
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &binary]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Produce one source file per encoding (the conversion host needs no keys)
+    for to in ["json", "armor"] {
+        let target = dir.path().join(format!("report.{}", to));
+        let output = hybridguard(&[Path::new("convert"), Path::new("-i"), &binary, Path::new("--to"), Path::new(to), Path::new("-o"), &target]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    for from in ["binary", "json", "armor"] {
+        for to in ["binary", "json", "armor"] {
+            let source = dir.path().join(format!("report.{}", from));
+            let converted = dir.path().join(format!("{}-to.{}", from, to));
+            let restored = dir.path().join(format!("{}-to-{}.pdf", from, to));
+            let output = hybridguard(&[
+                Path::new("convert"), Path::new("-i"), &source, Path::new("--to"), Path::new(to), Path::new("-o"), &converted,
+            ]);
+            assert!(output.status.success(), "{} -> {}: {}", from, to, String::from_utf8_lossy(&output.stderr));
+
+            let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &converted, Path::new("-o"), &restored]);
+            assert!(output.status.success(), "{} -> {}: {}", from, to, String::from_utf8_lossy(&output.stderr));
+            assert_eq!(fs::read(&restored).unwrap(), plaintext, "{} -> {}", from, to);
+        }
+    }
+    assert_eq!(fs::read(dir.path().join("json-to.binary")).unwrap(), fs::read(&binary).unwrap());
+}
+
+#[test]
+fn trailing_data_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x72; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("note.txt");
+    let encrypted = dir.path().join("note.hg");
+    let converted = dir.path().join("note.json");
+    fs::write(&input, b"short note").unwrap();
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut bytes = fs::read(&encrypted).unwrap();
+    bytes.extend_from_slice(b"appended");
+    fs::write(&encrypted, bytes).unwrap();
+    let output = hybridguard(&[Path::new("convert"), Path::new("-i"), &encrypted, Path::new("--to"), Path::new("json"), Path::new("-o"), &converted]);
+    assert!(!output.status.success());
+    assert!(!converted.exists());
+}
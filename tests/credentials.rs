@@ -0,0 +1,149 @@
+// Keys and key file passwords handed over by a service manager: inherited
+// descriptors (--key-file-fd, --password-fd) and systemd credentials in
+// $CREDENTIALS_DIRECTORY
+
+use hybridguard::error::exit_code;
+use hybridguard::key_manager::protector::PasswordProtector;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn command(args: &[&Path]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_hybridguard"));
+    // A test run under systemd must not pick up the runner's own credentials
+    command.args(args).env_remove("CREDENTIALS_DIRECTORY");
+    command
+}
+
+fn hybridguard(args: &[&Path]) -> Output {
+    command(args).output().expect("failed to run hybridguard")
+}
+
+/// Run with each secret readable on its descriptor in the child, the way a
+/// service manager passes them: a pipe whose write end is already closed
+#[cfg(target_os = "linux")]
+fn hybridguard_with_fds(args: &[&Path], secrets: &[(i32, &[u8])]) -> Output {
+    use std::io::{self, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+
+    let mut readers = Vec::new();
+    for (target, secret) in secrets {
+        let mut ends = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(ends.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(ends[0]), OwnedFd::from_raw_fd(ends[1])) };
+        fs::File::from(write).write_all(secret).unwrap();
+        readers.push((read, *target));
+    }
+    let moves: Vec<(i32, i32)> = readers.iter().map(|(read, target)| (read.as_raw_fd(), *target)).collect();
+    let mut command = command(args);
+    // dup2 leaves the copy open across exec; a pipe already on its target only needs the flag cleared
+    unsafe {
+        command.pre_exec(move || {
+            for &(source, target) in &moves {
+                let done = if source == target { libc::fcntl(target, libc::F_SETFD, 0) } else { libc::dup2(source, target) };
+                if done == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    command.output().expect("failed to run hybridguard")
+}
+
+#[test]
+fn credentials_directory_supplies_keys_and_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let credentials = dir.path().join("credentials");
+    fs::create_dir(&credentials).unwrap();
+    let keys = credentials.join("hybridguard.keys");
+    KeyManager::from_master_key(&[0x71; 32]).unwrap().save_protected(&keys, &PasswordProtector::new("service passphrase")).unwrap();
+    fs::write(credentials.join("hybridguard.password"), b"service passphrase\n").unwrap();
+    let with_credentials = |args: &[&Path]| command(args).env("CREDENTIALS_DIRECTORY", &credentials).output().unwrap();
+    let protector = [Path::new("--protector"), Path::new("password")];
+
+    let show = [&[Path::new("config"), Path::new("show"), Path::new("--json")][..], &protector].concat();
+    let output = with_credentials(&show);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let sources: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(sources["keys"], format!("systemd credential {}", keys.display()));
+    assert!(sources["key_file_password"].as_str().unwrap().ends_with("hybridguard.password"));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("service passphrase"));
+
+    // No flags: the keys and the password both come from the credentials
+    let (plain, enc, out) = (dir.path().join("nightly.sql"), dir.path().join("nightly.hg"), dir.path().join("nightly.out"));
+    fs::write(&plain, b"COPY accounts FROM stdin;").unwrap();
+    let encrypt = [&[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc][..], &protector].concat();
+    let output = with_credentials(&encrypt);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let decrypt = [&[Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out][..], &protector].concat();
+    let output = with_credentials(&decrypt);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"COPY accounts FROM stdin;");
+
+    // A flag wins over the credential
+    let explicit = dir.path().join("ops.keys");
+    let output = with_credentials(&[Path::new("config"), Path::new("show"), Path::new("--json"), Path::new("-k"), &explicit]);
+    let sources: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(sources["keys"], explicit.display().to_string());
+    assert!(sources.get("key_file_password").is_none());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn inherited_descriptors_carry_keys_and_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("ops.keys");
+    KeyManager::from_master_key(&[0x72; 32]).unwrap().save_protected(&keys, &PasswordProtector::new("descriptor passphrase")).unwrap();
+    let sealed = fs::read(&keys).unwrap();
+    let (plain, enc, out) = (dir.path().join("ledger.csv"), dir.path().join("ledger.hg"), dir.path().join("ledger.out"));
+    fs::write(&plain, b"date,amount\n").unwrap();
+    let protector = [Path::new("--protector"), Path::new("password"), Path::new("--password-fd"), Path::new("41")];
+
+    // Encrypt with the key file on a descriptor, decrypt with it on disk
+    let encrypt = [&[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("--key-file-fd"), Path::new("40")][..], &protector].concat();
+    let output = hybridguard_with_fds(&encrypt, &[(40, &sealed), (41, b"descriptor passphrase\n")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let decrypt = [&[Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out, Path::new("-k"), &keys][..], &protector].concat();
+    let output = hybridguard_with_fds(&decrypt, &[(41, b"descriptor passphrase")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"date,amount\n");
+
+    // A wrong password fails at once and is never echoed
+    fs::remove_file(&out).unwrap();
+    let output = hybridguard_with_fds(&decrypt, &[(41, b"hunter2-not-it")]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(exit_code::KEY as i32), "{}", stderr);
+    assert!(!stderr.contains("hunter2-not-it"), "{}", stderr);
+    assert!(!out.exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn missing_descriptors_are_usage_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("notes.txt");
+    fs::write(&plain, b"draft").unwrap();
+    let encrypt = [Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &dir.path().join("notes.hg")];
+
+    // Nothing was passed on descriptor 57
+    let output = hybridguard(&[&encrypt[..], &[Path::new("--key-file-fd"), Path::new("57")]].concat());
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not an open file descriptor"));
+
+    // Passed, but empty
+    let output = hybridguard_with_fds(&[&encrypt[..], &[Path::new("--key-file-fd"), Path::new("42")]].concat(), &[(42, b"")]);
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("nothing to read"));
+
+    // A password with no password protector to take it, and two key sources at once
+    let output = hybridguard_with_fds(&[&encrypt[..], &[Path::new("--password-fd"), Path::new("43")]].concat(), &[(43, b"unused")]);
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--protector password"));
+    let keys = dir.path().join("ops.keys");
+    KeyManager::from_master_key(&[0x73; 32]).unwrap().save(&keys).unwrap();
+    let output = hybridguard(&[&encrypt[..], &[Path::new("-k"), &keys, Path::new("--key-file-fd"), Path::new("44")]].concat());
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+}
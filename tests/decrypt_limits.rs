@@ -0,0 +1,80 @@
+// Size limits on decryption must reject oversized input before any layer
+// allocates buffers proportional to it
+#![cfg(feature = "testing")]
+
+use hybridguard::crypto::{EncryptedData, EncryptedDataBuilder};
+use hybridguard::{DecryptLimits, HybridGuard, HybridGuardError};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks the largest single allocation since the last reset
+struct CountingAllocator;
+
+static LARGEST: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LARGEST.fetch_max(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LARGEST.fetch_max(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const MIB: usize = 1024 * 1024;
+
+/// A current-format container around arbitrary ciphertext bytes
+fn synthetic_container(ciphertext: Vec<u8>) -> EncryptedData {
+    // Untagged, so the limits are what rejects it
+    let bytes = EncryptedDataBuilder::new(ciphertext).build().unwrap().to_bytes().unwrap();
+    EncryptedData::from_bytes(&bytes).unwrap()
+}
+
+/// Decrypt under `limits` and return the error plus the largest allocation made
+fn rejected(hg: &HybridGuard, encrypted: &EncryptedData, limits: DecryptLimits) -> (HybridGuardError, usize) {
+    LARGEST.store(0, Ordering::Relaxed);
+    let err = hg.decrypt_bounded(encrypted, limits).unwrap_err();
+    (err, LARGEST.load(Ordering::Relaxed))
+}
+
+// A single test so no concurrent test pollutes the allocation counter
+#[test]
+fn oversized_inputs_rejected_before_allocation() {
+    let hg = HybridGuard::new("limits-test").unwrap();
+    let synthetic = synthetic_container(vec![0xA5; 8 * MIB]);
+
+    let limits = DecryptLimits { max_ciphertext: MIB, ..DecryptLimits::default() };
+    let (err, largest) = rejected(&hg, &synthetic, limits);
+    assert!(matches!(err, HybridGuardError::LimitExceeded { ref which, size, limit }
+        if which == "ciphertext" && size == 8 * MIB && limit == MIB));
+    assert!(largest < MIB, "allocated {} bytes before rejecting", largest);
+
+    let limits = DecryptLimits { max_intermediate: MIB, ..DecryptLimits::default() };
+    let (err, largest) = rejected(&hg, &synthetic, limits);
+    assert!(matches!(err, HybridGuardError::LimitExceeded { ref which, .. } if which == "intermediate"));
+    assert!(largest < MIB, "allocated {} bytes before rejecting", largest);
+
+    // A genuine ciphertext whose plaintext is over the limit is refused before layer 1
+    let encrypted = hg.encrypt(&vec![0x42; 256 * 1024]).unwrap();
+    let limits = DecryptLimits { max_plaintext: 64 * 1024, ..DecryptLimits::default() };
+    let err = hg.decrypt_bounded(&encrypted, limits).unwrap_err();
+    assert!(matches!(err, HybridGuardError::LimitExceeded { ref which, size, .. } if which == "plaintext" && size == 256 * 1024));
+
+    // The limit is on the plaintext layer 1 declares, not on its KEM-prefixed input
+    let limits = DecryptLimits { max_plaintext: 256 * 1024, ..DecryptLimits::default() };
+    assert_eq!(hg.decrypt_bounded(&encrypted, limits).unwrap(), vec![0x42; 256 * 1024]);
+
+    // The defaults are finite but generous
+    assert_eq!(DecryptLimits::default().max_plaintext, 1 << 30);
+    assert_eq!(hg.decrypt(&encrypted).unwrap(), vec![0x42; 256 * 1024]);
+}
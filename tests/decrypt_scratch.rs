@@ -0,0 +1,65 @@
+// The scratch decrypt path returns exactly what `decrypt` does, and a scratch
+// reused across messages of different sizes never hands back older bytes
+
+use hybridguard::{DecryptScratch, HybridGuard, KeyManager, StackProfile};
+
+fn message(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31) ^ seed).collect()
+}
+
+fn guard(fill: u8, profile: StackProfile) -> HybridGuard {
+    HybridGuard::builder(KeyManager::from_master_key(&[fill; 32]).unwrap())
+        .with_stack_profile(profile)
+        .build().unwrap()
+}
+
+#[test]
+fn outputs_match_the_allocating_path() {
+    for profile in [StackProfile::Standard, StackProfile::CompactKem] {
+        let hg = guard(0xC1, profile);
+        let mut scratch = DecryptScratch::new();
+        for len in [0, 1, 31, 32, 33, 1000, 70_000, 64] {
+            let encrypted = hg.encrypt(&message(len, len as u8)).unwrap();
+            let expected = hg.decrypt(&encrypted).unwrap();
+            assert_eq!(expected, message(len, len as u8));
+            assert_eq!(hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap(), &expected[..], "{:?} at {} bytes", profile, len);
+        }
+    }
+}
+
+#[test]
+fn reused_scratch_returns_only_the_current_message() {
+    let hg = guard(0xC2, StackProfile::Standard);
+    let mut scratch = DecryptScratch::new();
+
+    // Large first, so every later message fits the grown buffers
+    let large = hg.encrypt(&[0xAA; 50_000]).unwrap();
+    assert_eq!(hg.decrypt_with_scratch(&large, &mut scratch).unwrap(), &[0xAA; 50_000][..]);
+    let capacity = scratch.capacity();
+    assert!(capacity >= large.ciphertext().len());
+
+    for len in [10_000, 17, 0, 33] {
+        let encrypted = hg.encrypt(&message(len, 0x55)).unwrap();
+        let plaintext = hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap();
+        assert_eq!(plaintext, &message(len, 0x55)[..]);
+        assert_eq!(scratch.capacity(), capacity);
+    }
+
+    // A failure in between leaves nothing of the last plaintext to read
+    let other = guard(0xC3, StackProfile::Standard).encrypt(b"someone else's").unwrap();
+    assert!(hg.decrypt_with_scratch(&other, &mut scratch).is_err());
+    let small = hg.encrypt(b"after a failure").unwrap();
+    assert_eq!(hg.decrypt_with_scratch(&small, &mut scratch).unwrap(), b"after a failure");
+}
+
+#[test]
+fn scratch_sized_up_front_does_not_grow() {
+    let hg = guard(0xC4, StackProfile::Standard);
+    let encrypted = hg.encrypt(&message(4096, 7)).unwrap();
+    let mut scratch = DecryptScratch::with_capacity(encrypted.ciphertext().len());
+    let capacity = scratch.capacity();
+    for _ in 0..3 {
+        assert_eq!(hg.decrypt_with_scratch(&encrypted, &mut scratch).unwrap(), &message(4096, 7)[..]);
+    }
+    assert_eq!(scratch.capacity(), capacity);
+}
@@ -0,0 +1,88 @@
+// Detached signatures: partners check who made a file and that it is
+// unchanged with the signer key alone, without any decryption key
+
+mod support;
+
+use hybridguard::key_manager::signing::{self, DetachedSignature, SignerKey, SigningKey};
+use hybridguard::{HybridGuardError, KeyManager};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn signing_key_survives_a_save_and_signs() {
+    let dir = tempfile::tempdir().unwrap();
+    let (key_file, file) = (dir.path().join("signer.keys"), dir.path().join("ledger.hgd"));
+    fs::write(&file, b"HGRD ciphertext bytes").unwrap();
+
+    let km = KeyManager::from_master_key(&[0x69; 32]).unwrap();
+    assert!(matches!(km.sign_detached(&file), Err(HybridGuardError::CapabilityDenied(_))));
+    km.with_signing_key(SigningKey::generate().unwrap()).save(&key_file).unwrap();
+
+    let km = KeyManager::load(&key_file).unwrap();
+    let signer = km.signing_key().unwrap().public().clone();
+    let signature = km.sign_detached(&file).unwrap();
+    let manifest = signing::verify_detached(&file, &signature, &signer).unwrap();
+    assert_eq!((manifest.file_name.as_str(), manifest.size), ("ledger.hgd", 21));
+
+    // A signer key from elsewhere is refused before anything is hashed
+    let other = SigningKey::generate().unwrap();
+    assert!(matches!(signing::verify_detached(&file, &signature, other.public()), Err(HybridGuardError::KeyMismatch(_))));
+}
+
+#[test]
+fn cli_signs_and_checks_without_decryption_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("signer.keys");
+    KeyManager::from_master_key(&[0x6A; 32]).unwrap().save(&key_file).unwrap();
+    let (plain, file, signer) = (dir.path().join("ledger.csv"), dir.path().join("ledger.hgd"), dir.path().join("signer.pub"));
+    fs::write(&plain, b"id,amount\n1,20\n").unwrap();
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &file, Path::new("-k"), &key_file]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The first signature adds a signing key to the key file
+    let output = hybridguard(&[
+        Path::new("sign"), Path::new("-i"), &file, Path::new("-k"), &key_file, Path::new("--yes"), Path::new("--export-signer"), &signer,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let sig = dir.path().join("ledger.hgd.sig");
+    assert!(KeyManager::load(&key_file).unwrap().signing_key().is_some());
+    assert_eq!(DetachedSignature::load(&sig).unwrap().signer, SignerKey::load(&signer).unwrap().fingerprint());
+
+    let check = |input: &Path, sig: &Path, signer: &Path| {
+        hybridguard(&[Path::new("check-sig"), Path::new("-i"), input, Path::new("--sig"), sig, Path::new("--signer"), signer])
+    };
+    let output = check(&file, &sig, &signer);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // A renamed copy no longer matches the manifest
+    let renamed = dir.path().join("ledger-final.hgd");
+    fs::copy(&file, &renamed).unwrap();
+    let output = check(&renamed, &sig, &signer);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("renamed"));
+
+    // One flipped byte fails
+    let mut bytes = fs::read(&file).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 1;
+    fs::write(&file, &bytes).unwrap();
+    assert_eq!(check(&file, &sig, &signer).status.code(), Some(4));
+    bytes[middle] ^= 1;
+    fs::write(&file, &bytes).unwrap();
+
+    // So does another signer's key
+    let other = dir.path().join("other.pub");
+    fs::write(&other, SigningKey::generate().unwrap().public().to_bytes().unwrap()).unwrap();
+    assert_eq!(check(&file, &sig, &other).status.code(), Some(3));
+    assert!(check(&file, &sig, &signer).status.success());
+
+    // Without --yes or an answer, a key file without a signing key signs nothing
+    let bare = dir.path().join("bare.keys");
+    KeyManager::from_master_key(&[0x6B; 32]).unwrap().save(&bare).unwrap();
+    let refused = dir.path().join("refused.sig");
+    let output = hybridguard(&[Path::new("sign"), Path::new("-i"), &file, Path::new("-k"), &bare, Path::new("-o"), &refused]);
+    assert_eq!(output.status.code(), Some(3), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!refused.exists());
+    assert!(KeyManager::load(&bare).unwrap().signing_key().is_none());
+}
@@ -0,0 +1,44 @@
+// `doctor` prints one result per check and exits non-zero only when a check fails
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+fn doctor(keys: &Path, output_dir: &Path) -> (Output, serde_json::Value) {
+    let output = hybridguard(&[Path::new("doctor"), Path::new("-k"), keys, Path::new("--output-dir"), output_dir, Path::new("--json")]);
+    let report = serde_json::from_slice(&output.stdout).expect("doctor --json prints a report");
+    (output, report)
+}
+
+fn status<'a>(report: &'a serde_json::Value, name: &str) -> &'a str {
+    let checks = report["checks"].as_array().unwrap();
+    let check = checks.iter().find(|check| check["name"] == name).unwrap_or_else(|| panic!("no {} check", name));
+    check["status"].as_str().unwrap()
+}
+
+#[test]
+fn healthy_installation_passes_its_checks() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0xD1; 32]).unwrap().save(&keys).unwrap();
+
+    let (output, report) = doctor(&keys, dir.path());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    for name in ["build", "liboqs", "rng", "round-trip", "byte-order", "keystore"] {
+        assert_eq!(status(&report, name), "pass", "{}", name);
+    }
+    assert_eq!(report["build"]["version"], env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn missing_output_directory_fails_with_a_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    let (output, report) = doctor(&dir.path().join("absent.keys"), &dir.path().join("no-such-dir"));
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(status(&report, "disk"), "fail");
+    assert_eq!(status(&report, "keystore"), "warn");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("disk"));
+}
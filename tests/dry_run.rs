@@ -0,0 +1,105 @@
+// Dry runs report the plan for a mixed set of inputs without writing anything
+
+mod support;
+
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+fn plan_json(output: &Output) -> serde_json::Value {
+    serde_json::from_slice(&output.stdout).expect("dry run did not print JSON")
+}
+
+fn write_keys(path: &Path, seed: u8) -> KeyManager {
+    let km = KeyManager::from_master_key(&[seed; 32]).unwrap();
+    km.save(path).unwrap();
+    km
+}
+
+#[test]
+fn encrypt_dry_run_flags_conflicts_and_writes_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    write_keys(&keys, 0x5A);
+
+    let good = dir.path().join("a.txt");
+    let conflicting = dir.path().join("b.txt");
+    let missing = dir.path().join("missing.txt");
+    fs::write(&good, b"alpha").unwrap();
+    fs::write(&conflicting, b"beta").unwrap();
+
+    let out = dir.path().join("out");
+    fs::create_dir(&out).unwrap();
+    fs::write(out.join("b.txt.hg"), b"already here").unwrap();
+
+    let args = [
+        Path::new("encrypt"), Path::new("--dry-run"), Path::new("--json"),
+        Path::new("-k"), &keys,
+        Path::new("-i"), &good, &conflicting, &missing,
+        Path::new("-o"), &out,
+    ];
+    let output = hybridguard(&args);
+    assert_eq!(output.status.code(), Some(5));
+
+    let plan = plan_json(&output);
+    let files = plan["files"].as_array().unwrap();
+    assert_eq!(files.len(), 3);
+    assert!(files[0]["problems"].as_array().unwrap().is_empty());
+    assert!(files[0]["estimated_output_size"].as_u64().unwrap() > 5);
+    assert!(files[1]["problems"][0]["message"].as_str().unwrap().contains("already exists"));
+    assert_eq!(files[2]["problems"][0]["exit_code"], 5);
+
+    // Nothing was written, and the conflicting file is untouched
+    assert!(!out.join("a.txt.hg").exists());
+    assert_eq!(fs::read(out.join("b.txt.hg")).unwrap(), b"already here");
+}
+
+#[test]
+fn encrypt_dry_run_succeeds_for_clean_plan() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("a.txt");
+    let output_file = dir.path().join("a.txt.hg");
+    fs::write(&input, b"alpha").unwrap();
+
+    let args = [Path::new("encrypt"), Path::new("--dry-run"), Path::new("-i"), &input, Path::new("-o"), &output_file];
+    let output = hybridguard(&args);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!output_file.exists());
+}
+
+#[test]
+fn decrypt_dry_run_reports_key_matches() {
+    let dir = tempfile::tempdir().unwrap();
+    let ours = dir.path().join("ours.keys");
+    let theirs = dir.path().join("theirs.keys");
+    let our_keys = write_keys(&ours, 0x11);
+    let their_keys = write_keys(&theirs, 0x22);
+
+    let encryptor = HybridGuardEncryptor::new();
+    let mine = dir.path().join("mine.hg");
+    let foreign = dir.path().join("foreign.hg");
+    for (path, km) in [(&mine, &our_keys), (&foreign, &their_keys)] {
+        let encrypted = encryptor.encrypt(b"payload", km.get_keys().unwrap()).unwrap().with_key_id(km.key_id());
+        fs::write(path, encrypted.to_bytes().unwrap()).unwrap();
+    }
+
+    let out = dir.path().join("out");
+    fs::create_dir(&out).unwrap();
+    let args = [
+        Path::new("decrypt"), Path::new("--dry-run"), Path::new("--json"),
+        Path::new("-k"), &ours,
+        Path::new("-i"), &mine, &foreign,
+        Path::new("-o"), &out,
+    ];
+    let output = hybridguard(&args);
+    assert_eq!(output.status.code(), Some(3));
+
+    let plan = plan_json(&output);
+    assert_eq!(plan["files"][0]["key_matches"], true);
+    assert_eq!(plan["files"][1]["key_matches"], false);
+    assert_eq!(plan["files"][1]["header_key_id"], their_keys.key_id());
+    assert!(!out.join("mine").exists());
+}
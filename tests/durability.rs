@@ -0,0 +1,37 @@
+// `--durability` is accepted by every command and refuses unknown levels
+
+mod support;
+
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn every_durability_level_writes_the_same_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("project");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("notes.txt"), "notes").unwrap();
+
+    let mut outputs = Vec::new();
+    for level in ["none", "flush", "fsync", "fsync-dir"] {
+        let output = dir.path().join(format!("{}.hga", level));
+        let result = hybridguard(&[
+            Path::new("--durability"),
+            Path::new(level),
+            Path::new("archive"),
+            Path::new("-i"),
+            &input,
+            Path::new("-o"),
+            &output,
+        ]);
+        assert!(result.status.success(), "{}: {}", level, String::from_utf8_lossy(&result.stderr));
+        outputs.push(fs::read(&output).unwrap());
+    }
+    assert!(outputs.windows(2).all(|pair| pair[0] == pair[1]));
+
+    let output = dir.path().join("bogus.hga");
+    let result = hybridguard(&[Path::new("--durability"), Path::new("always"), Path::new("archive"), Path::new("-i"), &input, Path::new("-o"), &output]);
+    assert_eq!(result.status.code(), Some(2));
+    assert!(!output.exists());
+}
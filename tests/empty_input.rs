@@ -0,0 +1,84 @@
+// Zero-length plaintexts round-trip through every layer, both pipelines and the CLI
+
+mod support;
+
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::layers::{registry, EncryptionLayer};
+use hybridguard::streaming::{chunked, decrypt_stream, encrypt_stream};
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn key_manager() -> KeyManager {
+    KeyManager::from_master_key(&[0xE0; 32]).unwrap()
+}
+
+#[test]
+fn every_layer_accepts_empty_input() {
+    let key = [0x42u8; 32];
+    for layer in registry() {
+        let encrypted = layer.encrypt(&[], &key).unwrap();
+        assert_eq!(encrypted.len(), layer.output_len(0).unwrap(), "{}", layer.name());
+        assert!(layer.decrypt(&encrypted, &key).unwrap().is_empty(), "{}", layer.name());
+    }
+}
+
+#[test]
+fn library_round_trips_empty_plaintext() {
+    let guard = HybridGuard::builder(key_manager()).build().unwrap();
+    let encrypted = guard.encrypt(&[]).unwrap();
+    assert!(guard.decrypt(&encrypted).unwrap().is_empty());
+
+    let key_manager = key_manager();
+
+    let encryptor = HybridGuardEncryptor::new();
+    let encrypted = encryptor.encrypt(&[], key_manager.get_keys().unwrap()).unwrap();
+    assert!(encryptor.decrypt(&encrypted, key_manager.get_keys().unwrap()).unwrap().is_empty());
+
+    let layers = registry();
+    let descriptors: Vec<_> = layers.iter().map(|layer| layer.descriptor()).collect();
+    let mut streamed = Vec::new();
+    assert_eq!(encrypt_stream(&layers, key_manager.get_keys().unwrap(), &[][..], &mut streamed).unwrap(), 0);
+    let mut restored = Vec::new();
+    assert_eq!(decrypt_stream(&layers, key_manager.get_keys().unwrap(), &descriptors, &streamed[..], &mut restored).unwrap(), 0);
+    assert!(restored.is_empty());
+}
+
+#[test]
+fn chunked_round_trips_empty_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = key_manager();
+    let input = dir.path().join("empty.bin");
+    let encrypted = dir.path().join("empty.hg");
+    let restored = dir.path().join("restored.bin");
+    fs::write(&input, b"").unwrap();
+
+    let stats = chunked::encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+    assert_eq!(stats.segments, 0);
+    chunked::decrypt_file(&encrypted, &restored, &key_manager).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), b"");
+}
+
+#[test]
+fn cli_round_trips_empty_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    fs::write(&master, [0xE1u8; 32]).unwrap();
+    let keystore = dir.path().join("keys");
+    let output = hybridguard(&[Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let key_file = keystore.join("hybridguard.keys");
+
+    let input = dir.path().join("empty.txt");
+    fs::write(&input, b"").unwrap();
+    let encrypted = dir.path().join("empty.hg");
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &key_file, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::metadata(&encrypted).unwrap().len() > 0);
+
+    let restored = dir.path().join("empty.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &key_file, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"");
+}
@@ -0,0 +1,75 @@
+// Per-file keys: every container is encrypted under its own wrapped file key
+
+mod support;
+
+use hybridguard::crypto::EncryptedData;
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn same_plaintext_gets_different_file_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let km = KeyManager::from_master_key(&[0x61; 32]).unwrap();
+    km.save(&keys).unwrap();
+    let input = dir.path().join("plain.txt");
+    fs::write(&input, b"identical contents").unwrap();
+
+    let mut containers = Vec::new();
+    for name in ["a.hg", "b.hg"] {
+        let encrypted = dir.path().join(name);
+        let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let restored = dir.path().join(format!("{}.out", name));
+        let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(fs::read(&restored).unwrap(), b"identical contents");
+        containers.push(EncryptedData::from_bytes(&fs::read(&encrypted).unwrap()).unwrap());
+    }
+
+    let (a, b) = (&containers[0], &containers[1]);
+    assert_ne!(a.wrapped_key().unwrap(), b.wrapped_key().unwrap());
+    assert_ne!(km.keys_for(a).unwrap().layer1_key, km.keys_for(b).unwrap().layer1_key);
+    assert_ne!(km.keys_for(a).unwrap().layer1_key, km.get_keys().layer1_key);
+
+    // Another profile cannot unwrap either file key
+    let other = dir.path().join("other.keys");
+    KeyManager::from_master_key(&[0x62; 32]).unwrap().save(&other).unwrap();
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &other, Path::new("-i"), &dir.path().join("a.hg"), Path::new("-o"), &dir.path().join("c.out"),
+    ]);
+    assert!(!output.status.success());
+}
+
+#[cfg(feature = "fixtures")]
+#[test]
+fn containers_without_a_file_key_still_decrypt() {
+    use hybridguard::crypto::container;
+
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let km = KeyManager::from_master_key(&[0x63; 32]).unwrap();
+    km.save(&keys).unwrap();
+
+    // Sealed directly under the profile keys, as every container before format v7
+    let legacy = HybridGuardEncryptor::new().encrypt(b"master-key era", km.get_keys()).unwrap().with_key_id(km.key_id());
+    assert!(legacy.wrapped_key().is_none());
+    let encrypted = dir.path().join("legacy.hg");
+    fs::write(&encrypted, container::encode_version(&legacy, 6).unwrap()).unwrap();
+
+    let restored = dir.path().join("legacy.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"master-key era");
+
+    // In the current format a missing file key was stripped, not absent
+    fs::remove_file(&restored).unwrap();
+    fs::write(&encrypted, legacy.to_bytes().unwrap()).unwrap();
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!restored.exists());
+}
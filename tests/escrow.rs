@@ -0,0 +1,74 @@
+// Escrowing keys to an organizational recovery key and recovering them
+
+mod support;
+
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn escrowed_keystore(dir: &Path) -> (std::path::PathBuf, std::path::PathBuf) {
+    let public = dir.join("org.pub");
+    let private = dir.join("org.key");
+    let output = hybridguard(&[
+        Path::new("key"), Path::new("recovery-keygen"), Path::new("--public"), &public, Path::new("--private"), &private,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let master = dir.join("master.bin");
+    fs::write(&master, [0x6Bu8; 32]).unwrap();
+    let keystore = dir.join("keys");
+    let output = hybridguard(&[
+        Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master,
+        Path::new("--escrow"), &public,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (keystore, private)
+}
+
+#[test]
+fn recovered_keys_decrypt_existing_ciphertext() {
+    let dir = tempfile::tempdir().unwrap();
+    let (keystore, org_key) = escrowed_keystore(dir.path());
+    let key_file = keystore.join("hybridguard.keys");
+    let blob = keystore.join("hybridguard.escrow");
+    assert!(fs::read_to_string(&key_file).unwrap().contains("\"escrow\""));
+
+    let input = dir.path().join("payroll.txt");
+    fs::write(&input, b"employee left the company").unwrap();
+    let encrypted = dir.path().join("payroll.hg");
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &key_file, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The employee's key file is gone; only the escrow blob remains
+    fs::remove_file(&key_file).unwrap();
+    let recovered = dir.path().join("recovered.keys");
+    let output = hybridguard(&[
+        Path::new("key"), Path::new("recover"), Path::new("--escrow"), &blob, Path::new("--org-key"), &org_key,
+        Path::new("-o"), &recovered,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let restored = dir.path().join("payroll.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &recovered, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"employee left the company");
+}
+
+#[test]
+fn tampered_blob_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (keystore, org_key) = escrowed_keystore(dir.path());
+    let blob = keystore.join("hybridguard.escrow");
+    let mut bytes = fs::read(&blob).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    fs::write(&blob, bytes).unwrap();
+
+    let recovered = dir.path().join("recovered.keys");
+    let output = hybridguard(&[
+        Path::new("key"), Path::new("recover"), Path::new("--escrow"), &blob, Path::new("--org-key"), &org_key,
+        Path::new("-o"), &recovered,
+    ]);
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!recovered.exists());
+}
@@ -0,0 +1,72 @@
+// Exit code contract of the hybridguard binary
+// Each case feeds a prepared bad input and checks the documented code
+
+use std::fs;
+use std::process::Command;
+
+fn hybridguard() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+}
+
+fn exit_code(cmd: &mut Command) -> i32 {
+    cmd.output().expect("failed to run hybridguard").status.code().expect("terminated by signal")
+}
+
+#[test]
+fn test_help_exits_zero() {
+    assert_eq!(exit_code(hybridguard().arg("--help")), 0);
+}
+
+#[test]
+fn test_usage_error() {
+    assert_eq!(exit_code(hybridguard().arg("encrypt")), 2);
+    assert_eq!(exit_code(hybridguard().arg("no-such-command")), 2);
+}
+
+#[test]
+fn test_missing_input_is_io_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let code = exit_code(
+        hybridguard()
+            .arg("decrypt")
+            .arg("-i").arg(dir.path().join("missing.enc"))
+            .arg("-o").arg(dir.path().join("out.txt")),
+    );
+    assert_eq!(code, 5);
+}
+
+#[test]
+fn test_foreign_format_is_unsupported() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("document.pdf");
+    fs::write(&input, b"%PDF-1.7\n% not a ciphertext at all, just a pdf header").unwrap();
+    
+    let code = exit_code(
+        hybridguard()
+            .arg("decrypt")
+            .arg("-i").arg(&input)
+            .arg("-o").arg(dir.path().join("out.txt")),
+    );
+    assert_eq!(code, 6);
+}
+
+#[test]
+fn test_corrupt_file_is_integrity_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("corrupt.enc");
+    
+    // Plausible bincode header (16-byte ciphertext, 4 layers) then truncated
+    let mut data = 16u64.to_le_bytes().to_vec();
+    data.extend_from_slice(&[0xAB; 16]);
+    data.extend_from_slice(&4u64.to_le_bytes());
+    data.extend_from_slice(&[0xFF; 8]);
+    fs::write(&input, &data).unwrap();
+    
+    let code = exit_code(
+        hybridguard()
+            .arg("decrypt")
+            .arg("-i").arg(&input)
+            .arg("-o").arg(dir.path().join("out.txt")),
+    );
+    assert_eq!(code, 4);
+}
@@ -0,0 +1,53 @@
+// `hybridguard spec` against a checked-in snapshot
+// A format change must come with a version bump and a new snapshot:
+//   hybridguard spec --json > tests/snapshots/format_spec.json
+
+mod support;
+
+use hybridguard::spec::FormatSpec;
+use serde_json::Value;
+use std::path::Path;
+use support::hybridguard;
+
+const SNAPSHOT: &str = include_str!("snapshots/format_spec.json");
+
+/// Container version, every layer's version and the KDF scheme, the numbers a change must bump
+fn versions(spec: &Value) -> Vec<Value> {
+    let mut versions = vec![spec["container"]["format_version"].clone(), spec["kdf"]["scheme"].clone()];
+    for layer in spec["layers"].as_array().unwrap() {
+        versions.push(layer["format_version"].clone());
+    }
+    versions
+}
+
+#[test]
+fn spec_matches_snapshot() {
+    let snapshot: Value = serde_json::from_str(SNAPSHOT).unwrap();
+    let current: Value = serde_json::from_str(&FormatSpec::current().unwrap().to_json().unwrap()).unwrap();
+    if current == snapshot {
+        return;
+    }
+    if versions(&current) == versions(&snapshot) {
+        panic!(
+            "the file format changed but no format version did; bump the container or layer version\n\
+             snapshot: {:#}\ncurrent: {:#}",
+            snapshot, current
+        );
+    }
+    panic!("format version bumped; regenerate tests/snapshots/format_spec.json with `hybridguard spec --json`");
+}
+
+#[test]
+fn spec_command_prints_json_and_text() {
+    let output = hybridguard(&[Path::new("spec"), Path::new("--json")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let printed: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(printed, serde_json::from_str::<Value>(SNAPSHOT).unwrap());
+
+    let output = hybridguard(&[Path::new("spec")]);
+    assert_eq!(output.status.code(), Some(0));
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains("HybridGuard file format (container v13)"));
+    assert!(text.contains("ML-KEM-768 v3"));
+    assert!(text.contains("read-only:  0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"));
+}
@@ -0,0 +1,87 @@
+// Every output format encrypt writes is told apart by its magic and
+// decrypts again through the same `decrypt` command
+
+mod support;
+
+use hybridguard::error::exit_code;
+use hybridguard::streaming::split;
+use hybridguard::KeyManager;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn every_format_round_trips_through_one_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("formats.keys");
+    KeyManager::from_master_key(&[0x76; 32]).unwrap().save(&keys).unwrap();
+    let plain = dir.path().join("inventory.db");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 233) as u8).collect();
+    fs::write(&plain, &data).unwrap();
+
+    let checkpoint = dir.path().join("inventory.checkpoint");
+    let formats: [(&str, Vec<&Path>); 5] = [
+        ("container", vec![]),
+        ("sparse", vec![Path::new("--sparse")]),
+        ("shaped", vec![Path::new("--shape"), Path::new("4096@1ms")]),
+        ("split", vec![Path::new("--split-size"), Path::new("64K")]),
+        ("chunked", vec![Path::new("--checkpoint"), checkpoint.as_path()]),
+    ];
+
+    let mut magics = HashMap::new();
+    for (name, flags) in &formats {
+        let encrypted = dir.path().join(format!("inventory.{}.hg", name));
+        let restored = dir.path().join(format!("inventory.{}.out", name));
+        let encrypt = [&[Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &plain, Path::new("-o"), &encrypted][..], &flags[..]].concat();
+        let output = hybridguard(&encrypt);
+        assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+
+        // A split set is read from any of its parts
+        let written = if *name == "split" { split::part_path(&encrypted, 0) } else { encrypted };
+        let magic = fs::read(&written).unwrap()[..4].to_vec();
+        if let Some(other) = magics.insert(magic.clone(), *name) {
+            panic!("{} and {} both start with {:?}", other, name, String::from_utf8_lossy(&magic));
+        }
+
+        let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &written, Path::new("-o"), &restored]);
+        assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(fs::read(&restored).unwrap(), data, "{}", name);
+    }
+}
+
+#[test]
+fn max_plaintext_bytes_is_checked_before_decrypting() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("formats.keys");
+    KeyManager::from_master_key(&[0x77; 32]).unwrap().save(&keys).unwrap();
+    let plain = dir.path().join("ledger.db");
+    fs::write(&plain, vec![0x5A; 200_000]).unwrap();
+
+    let checkpoint = dir.path().join("ledger.checkpoint");
+    let formats: [(&str, Vec<&Path>); 2] = [("container", vec![]), ("chunked", vec![Path::new("--checkpoint"), checkpoint.as_path()])];
+    for (name, flags) in &formats {
+        let encrypted = dir.path().join(format!("ledger.{}.hg", name));
+        let restored = dir.path().join(format!("ledger.{}.out", name));
+        let encrypt = [&[Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &plain, Path::new("-o"), &encrypted][..], &flags[..]].concat();
+        let output = hybridguard(&encrypt);
+        assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+
+        let decrypt = |max: &str| {
+            hybridguard(&[
+                Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored,
+                Path::new("--max-plaintext-bytes"), Path::new(max),
+            ])
+        };
+        let output = decrypt("199999");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(exit_code::POLICY as i32), "{}: {}", name, stderr);
+        assert!(stderr.contains("plaintext"), "{}: {}", name, stderr);
+        assert!(!restored.exists(), "{}", name);
+
+        // The limit is on the plaintext itself, so exactly that much passes
+        let output = decrypt("200000");
+        assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(fs::read(&restored).unwrap(), vec![0x5A; 200_000], "{}", name);
+    }
+}
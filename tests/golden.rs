@@ -0,0 +1,138 @@
+// Golden containers pin the byte layout of every container format version
+// tests/golden/container-v<N>.hg is what `encrypt` writes at format version N
+// under a fixed seed, clock and master key. If the current file stops matching,
+// the layout changed: bump `container::FORMAT_VERSION` (keeping the old file,
+// which must still decrypt) rather than re-recording over it.
+//
+// The bytes come from the real `encrypt` command, seeded through the
+// fixture build's HYBRIDGUARD_FIXTURE_SEED and HYBRIDGUARD_FIXTURE_TIME, so
+// comparing them needs `--features fixtures`. A missing golden file fails
+// that run like a changed one; UPDATE_GOLDEN=1 records the current version's
+// on purpose, to be committed with the change.
+// The container embeds the crate version, so a release bump needs a
+// re-record without a format bump.
+
+mod support;
+
+use hybridguard::crypto::container;
+use hybridguard::crypto::drbg::SeededRandom;
+use hybridguard::crypto::EncryptedData;
+use hybridguard::timing::FixedClock;
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "fixtures")]
+use std::process::Command;
+use std::sync::Arc;
+
+/// Set to record the current version's golden file instead of comparing with it
+const UPDATE: &str = "UPDATE_GOLDEN";
+
+/// Seed of the deterministic random source
+const SEED: &str = "HybridGuard golden container";
+
+/// Creation time recorded in golden containers
+const TIME: u64 = 1_700_000_000;
+
+const MASTER_KEY: [u8; 32] = [0x47; 32];
+
+const PLAINTEXT: &[u8] = b"HybridGuard golden container: four layers over a fixed plaintext.\n";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+#[cfg(feature = "fixtures")]
+fn golden_path(version: u16) -> PathBuf {
+    golden_dir().join(format!("container-v{}.hg", version))
+}
+
+fn keys() -> KeyManager {
+    KeyManager::from_master_key(&MASTER_KEY).unwrap()
+}
+
+/// The container `encrypt` writes for PLAINTEXT under the golden seed and clock
+#[cfg(feature = "fixtures")]
+fn encrypt_with_the_cli() -> Vec<u8> {
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, out, key_file) = (dir.path().join("plain"), dir.path().join("out.hg"), dir.path().join("golden.keys"));
+    fs::write(&plain, PLAINTEXT).unwrap();
+    keys().save(&key_file).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args([Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &out, Path::new("-k"), &key_file])
+        .env("HYBRIDGUARD_FIXTURE_SEED", SEED)
+        .env("HYBRIDGUARD_FIXTURE_TIME", TIME.to_string())
+        .output()
+        .expect("failed to run hybridguard");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    fs::read(&out).unwrap()
+}
+
+#[cfg(feature = "fixtures")]
+#[test]
+fn current_format_matches_its_golden_container() {
+    let bytes = encrypt_with_the_cli();
+    assert!(bytes == encrypt_with_the_cli(), "seeded encryption is not reproducible");
+
+    let path = golden_path(container::FORMAT_VERSION);
+    if std::env::var_os(UPDATE).is_some_and(|value| value == "1") {
+        fs::create_dir_all(golden_dir()).unwrap();
+        fs::write(&path, &bytes).unwrap();
+        eprintln!("recorded {}; commit it", path.display());
+        return;
+    }
+
+    let golden = fs::read(&path).unwrap_or_else(|e| {
+        panic!("no golden container at {} ({}); record it with {}=1 and commit it", path.display(), e, UPDATE)
+    });
+    if golden != bytes {
+        panic!(
+            "the v{} container layout changed; bump FORMAT_VERSION and record a new golden file \
+             (or set {}=1 if only the crate version changed)\n{}",
+            container::FORMAT_VERSION,
+            UPDATE,
+            support::hexdump_diff(&golden, &bytes, 8)
+        );
+    }
+}
+
+#[test]
+fn every_golden_container_still_decrypts() {
+    let entries = fs::read_dir(golden_dir()).unwrap_or_else(|e| panic!("{}: {}", golden_dir().display(), e));
+    let guard = HybridGuard::builder(keys()).build().unwrap();
+    let mut checked = 0;
+    for entry in entries {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let Some(version) = name.strip_prefix("container-v").and_then(|rest| rest.strip_suffix(".hg")) else {
+            continue;
+        };
+        let version: u16 = version.parse().unwrap_or_else(|_| panic!("{} has no version number", name));
+        assert!(version <= container::FORMAT_VERSION, "{} is newer than this build", name);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(container::format_version(&bytes).unwrap(), version, "{} is mislabelled", name);
+        let encrypted = EncryptedData::from_bytes(&bytes).unwrap();
+        assert_eq!(guard.decrypt(&encrypted).unwrap(), PLAINTEXT, "{} no longer decrypts", name);
+        checked += 1;
+    }
+    assert!(checked > 0, "no golden containers in {}; record one with {}=1", golden_dir().display(), UPDATE);
+}
+
+#[test]
+fn builder_encryption_is_reproducible_with_a_seeded_source() {
+    let encrypt = || {
+        let guard = HybridGuard::builder(keys())
+            .with_rng(Arc::new(SeededRandom::new(SEED.as_bytes(), b"builder")))
+            .with_clock(Arc::new(FixedClock::new(TIME)))
+            .build().unwrap();
+        guard.encrypt(PLAINTEXT).unwrap().to_bytes().unwrap()
+    };
+    let bytes = encrypt();
+    assert_eq!(bytes, encrypt());
+    assert_eq!(
+        HybridGuard::builder(keys()).build().unwrap().decrypt(&EncryptedData::from_bytes(&bytes).unwrap()).unwrap(),
+        PLAINTEXT
+    );
+}
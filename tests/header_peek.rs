@@ -0,0 +1,86 @@
+// Header-only access to containers: peeking costs the same on huge files
+
+mod support;
+
+use hybridguard::crypto::container::{self, peek_header_from};
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use support::hybridguard;
+
+/// Counts the bytes actually read through it
+struct CountingReader<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// A real container's header and metadata around a `ciphertext_len`-byte hole
+fn sparse_container(path: &Path, ciphertext_len: u64) -> String {
+    let key_manager = KeyManager::from_master_key(&[0xD1; 32]).unwrap();
+    let key_id = key_manager.key_id().to_string();
+    let bytes = HybridGuard::builder(key_manager).build().unwrap().encrypt(b"header donor").unwrap().to_bytes().unwrap();
+    let body_start = container::PREFIX_LEN + 8;
+    let real_len = u64::from_le_bytes(bytes[container::PREFIX_LEN..body_start].try_into().unwrap());
+
+    let mut file = File::create(path).unwrap();
+    file.write_all(&bytes[..container::PREFIX_LEN]).unwrap();
+    file.write_all(&ciphertext_len.to_le_bytes()).unwrap();
+    file.seek(SeekFrom::Start(body_start as u64 + ciphertext_len)).unwrap();
+    file.write_all(&bytes[body_start + real_len as usize..]).unwrap();
+    key_id
+}
+
+#[test]
+fn peek_reads_only_the_header_of_a_multi_gigabyte_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("huge.hg");
+    let ciphertext_len = 6 * 1024 * 1024 * 1024u64;
+    let key_id = sparse_container(&path, ciphertext_len);
+
+    let mut reader = CountingReader { inner: File::open(&path).unwrap(), read: 0 };
+    let header = peek_header_from(&mut reader).unwrap();
+    assert_eq!(header.ciphertext_len, ciphertext_len);
+    assert_eq!(header.key_id.as_deref(), Some(key_id.as_str()));
+    assert_eq!(header.format_version, container::FORMAT_VERSION);
+    assert!(header.content_digest.is_some());
+    assert!(reader.read < 16 * 1024, "read {} bytes", reader.read);
+
+    let output = hybridguard(&[Path::new("inspect"), Path::new("--brief"), Path::new("-i"), &path]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&key_id), "{}", stdout);
+    assert!(stdout.contains(&format!("Ciphertext: {} bytes", ciphertext_len)), "{}", stdout);
+}
+
+#[test]
+fn identical_files_share_a_digest() {
+    let dir = tempfile::tempdir().unwrap();
+    let guard = HybridGuard::builder(KeyManager::from_master_key(&[0xD2; 32]).unwrap()).build().unwrap();
+    let first = guard.encrypt(b"same plaintext").unwrap().to_bytes().unwrap();
+    let again = guard.encrypt(b"same plaintext").unwrap().to_bytes().unwrap();
+    let copy = dir.path().join("copy.hg");
+    fs::write(&copy, &first).unwrap();
+
+    let original = container::peek_header(&first).unwrap();
+    let copied = peek_header_from(&mut File::open(&copy).unwrap()).unwrap();
+    assert!(original.same_payload(&copied));
+    // Fresh encryptions of the same plaintext are different objects
+    let reencrypted = container::peek_header(&again).unwrap();
+    assert!(!original.same_payload(&reencrypted));
+    assert!(container::same_key(&original, &reencrypted));
+}
@@ -0,0 +1,77 @@
+// gen-fixtures writes the same bytes every run, and every fixture decrypts
+// with the keys its manifest records
+#![cfg(feature = "fixtures")]
+
+mod support;
+
+use hybridguard::crypto::hkdf::KdfScheme;
+use hybridguard::crypto::{codec, container, encoding};
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::fixtures::{Manifest, MANIFEST_FILE};
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn gen_fixtures(dir: &Path) {
+    let output = hybridguard(&[Path::new("gen-fixtures"), Path::new("--output"), dir]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+fn hex(text: &str) -> Vec<u8> {
+    codec::hex_lower_decode(text).unwrap()
+}
+
+#[test]
+fn fixtures_are_reproducible() {
+    let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    gen_fixtures(a.path());
+    gen_fixtures(b.path());
+
+    let mut names: Vec<_> = fs::read_dir(a.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    names.sort();
+    assert!(names.len() > 1);
+    for name in names {
+        let first = fs::read(a.path().join(&name)).unwrap();
+        let second = fs::read(b.path().join(&name)).unwrap();
+        assert!(first == second, "{:?} differs between runs", name);
+    }
+}
+
+#[test]
+fn every_fixture_decrypts_with_its_manifest_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    gen_fixtures(dir.path());
+    let manifest: Manifest = serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILE)).unwrap()).unwrap();
+
+    for version in container::SUPPORTED_VERSIONS {
+        assert!(manifest.fixtures.iter().any(|entry| entry.container_version == *version), "no v{} fixture", version);
+    }
+    for entry in &manifest.fixtures {
+        let key = manifest.keys.iter().find(|key| key.name == entry.key).unwrap();
+        let key_manager = match (&key.master_key, &key.password, &key.salt) {
+            (Some(master), _, _) => KeyManager::from_master_key_with(&hex(master).try_into().unwrap(), key.kdf).unwrap(),
+            (None, Some(password), Some(salt)) => KeyManager::from_password(password, &hex(salt)).unwrap(),
+            _ => panic!("fixture key {} has no key material", key.name),
+        };
+        assert_eq!(key_manager.key_id(), key.key_id);
+        assert_eq!(key_manager.get_keys().unwrap().scheme, key.kdf);
+
+        let bytes = fs::read(dir.path().join(&entry.file)).unwrap();
+        assert_eq!(encoding::detect(&bytes).to_string(), entry.encoding, "{}", entry.file);
+        if entry.encoding == "binary" {
+            assert_eq!(container::format_version(&bytes).unwrap(), entry.container_version, "{}", entry.file);
+        }
+        let data = encoding::decode(&bytes).unwrap();
+        assert_eq!(data.is_authenticated(), entry.authenticated, "{}", entry.file);
+        assert_eq!(data.wrapped_key().is_some(), entry.envelope, "{}", entry.file);
+        assert_eq!(data.descriptors(), &entry.descriptors[..], "{}", entry.file);
+        assert_eq!(data.label(), entry.label.as_deref(), "{}", entry.file);
+        let keys = key_manager.keys_for(&data).unwrap();
+        assert_eq!(data.content_type(&keys).unwrap(), entry.content_type, "{}", entry.file);
+
+        let plaintext = HybridGuardEncryptor::new().decrypt(&data, &keys).unwrap();
+        assert_eq!(plaintext, hex(&entry.plaintext), "{}", entry.file);
+    }
+    assert!(manifest.keys.iter().any(|key| key.kdf == KdfScheme::V1));
+}
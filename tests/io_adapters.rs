@@ -0,0 +1,255 @@
+// `HybridGuardWriter` / `HybridGuardReader` composed with other std::io adapters
+
+use hybridguard::streaming::adapters::{HybridGuardReader, HybridGuardWriter};
+use hybridguard::{DecryptErrorMode, HybridGuard, HybridGuardError, KeyManager};
+use std::io::{self, Read, Write};
+
+fn instance(seed: u8) -> HybridGuard {
+    HybridGuard::builder(KeyManager::from_master_key(&[seed; 32]).unwrap()).build().unwrap()
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    // Runs of repeated bytes, so the run-length codec below has work to do
+    (0..len).map(|i| ((i / 7) % 13) as u8).collect()
+}
+
+/// Run-length encoder in the style of `GzEncoder`: buffers state, needs `finish`
+struct RleEncoder<W: Write> {
+    inner: W,
+    run: Option<(u8, u8)>,
+}
+
+impl<W: Write> RleEncoder<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, run: None }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        if let Some((byte, count)) = self.run.take() {
+            self.inner.write_all(&[count, byte])?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for RleEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            match self.run {
+                Some((byte, count)) if byte == b && count < u8::MAX => self.run = Some((byte, count + 1)),
+                Some((byte, count)) => {
+                    self.inner.write_all(&[count, byte])?;
+                    self.run = Some((b, 1));
+                }
+                None => self.run = Some((b, 1)),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Matching decoder, reading pairs from `inner` on demand
+struct RleDecoder<R: Read> {
+    inner: R,
+    run: (u8, u8),
+}
+
+impl<R: Read> Read for RleDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.run.1 == 0 {
+            let mut pair = [0u8; 2];
+            match self.inner.read_exact(&mut pair) {
+                Ok(()) => self.run = (pair[1], pair[0]),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+        let n = buf.len().min(self.run.1 as usize);
+        buf[..n].fill(self.run.0);
+        self.run.1 -= n as u8;
+        Ok(n)
+    }
+}
+
+/// Reader that hands out one byte per call and `WouldBlock` every other call
+struct Trickle<'a> {
+    data: &'a [u8],
+    blocked: bool,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.blocked = !self.blocked;
+        if self.blocked {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.data.len()).min(1);
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+/// Writer that takes at most 7 bytes per call and `WouldBlock` every other call
+#[derive(Default)]
+struct Congested {
+    data: Vec<u8>,
+    blocked: bool,
+}
+
+impl Write for Congested {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.blocked = !self.blocked;
+        if self.blocked {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(7);
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_one_byte_at_a_time<R: Read>(mut reader: R) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(out),
+            Ok(_) => out.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn hybridguard_error(e: &io::Error) -> &HybridGuardError {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<HybridGuardError>()).expect("not a HybridGuard error")
+}
+
+#[test]
+fn compressed_round_trip_with_one_byte_writes_and_reads() {
+    let hg = instance(0x71);
+    let data = sample(5000);
+
+    let mut writer = RleEncoder::new(HybridGuardWriter::with_segment_len(Vec::new(), &hg, 256).unwrap());
+    for byte in &data {
+        writer.write_all(std::slice::from_ref(byte)).unwrap();
+    }
+    let stream = writer.finish().unwrap().finish().unwrap();
+
+    let reader = RleDecoder { inner: HybridGuardReader::new(&stream[..], &hg).unwrap(), run: (0, 0) };
+    assert_eq!(read_one_byte_at_a_time(reader).unwrap(), data);
+
+    // Large reads and writes produce the same plaintext
+    let mut writer = HybridGuardWriter::new(Vec::new(), &hg).unwrap();
+    writer.write_all(&data).unwrap();
+    let stream = writer.finish().unwrap();
+    let mut plain = Vec::new();
+    HybridGuardReader::new(&stream[..], &hg).unwrap().read_to_end(&mut plain).unwrap();
+    assert_eq!(plain, data);
+}
+
+#[test]
+fn non_blocking_inner_streams_round_trip() {
+    let hg = instance(0x72);
+    let data = sample(700);
+
+    let mut writer = HybridGuardWriter::with_segment_len(Congested::default(), &hg, 100).unwrap();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        match writer.write(rest) {
+            Ok(n) => rest = &rest[n..],
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+        }
+    }
+    while let Err(e) = writer.try_finish() {
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+    assert!(writer.write(b"late").is_err());
+    let stream = writer.finish().unwrap().data;
+
+    let reader = HybridGuardReader::new(Trickle { data: &stream, blocked: false }, &hg).unwrap();
+    assert_eq!(read_one_byte_at_a_time(reader).unwrap(), data);
+}
+
+#[test]
+fn dropped_writer_leaves_a_rejected_stream() {
+    let hg = instance(0x73);
+    let mut stream = Vec::new();
+    {
+        let mut writer = HybridGuardWriter::with_segment_len(&mut stream, &hg, 100).unwrap();
+        writer.write_all(&sample(450)).unwrap();
+        // Dropped without `finish`: four full segments are out, the final one never is
+    }
+    assert!(!stream.is_empty());
+
+    let mut reader = HybridGuardReader::new(&stream[..], &hg).unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(matches!(hybridguard_error(&err), HybridGuardError::DecryptionFailed));
+
+    let verbose = HybridGuard::builder(KeyManager::from_master_key(&[0x73; 32]).unwrap())
+        .with_decrypt_errors(DecryptErrorMode::Verbose)
+        .build().unwrap();
+    let err = HybridGuardReader::new(&stream[..], &verbose).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+    assert!(hybridguard_error(&err).to_string().contains("ended before its final segment"));
+}
+
+#[test]
+fn truncated_or_spliced_streams_are_rejected() {
+    let hg = instance(0x74);
+    let encrypt = |data: &[u8]| {
+        let mut writer = HybridGuardWriter::with_segment_len(Vec::new(), &hg, 100).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    };
+    let decrypt = |stream: &[u8]| {
+        let mut plain = Vec::new();
+        HybridGuardReader::new(stream, &hg).unwrap().read_to_end(&mut plain).map(|_| plain)
+    };
+    let data = sample(300);
+    let stream = encrypt(&data);
+    assert_eq!(decrypt(&stream).unwrap(), data);
+
+    for len in [0, 5, stream.len() / 2, stream.len() - 1] {
+        assert!(decrypt(&stream[..len]).is_err(), "truncated to {} bytes", len);
+    }
+
+    // Same key, same lengths, different stream: segments do not carry over
+    let other = encrypt(&sample(300));
+    assert_eq!(other.len(), stream.len());
+    let mut spliced = stream.clone();
+    let tail = stream.len() / 2;
+    spliced[tail..].copy_from_slice(&other[tail..]);
+    assert!(decrypt(&spliced).is_err());
+
+    // An empty plaintext still has a header and a final segment
+    assert_eq!(decrypt(&encrypt(b"")).unwrap(), b"");
+}
+
+#[test]
+fn wrong_key_and_encrypt_only_key_are_refused() {
+    let hg = instance(0x75);
+    let mut writer = HybridGuardWriter::new(Vec::new(), &hg).unwrap();
+    writer.write_all(b"socket payload").unwrap();
+    let stream = writer.finish().unwrap();
+
+    let other = HybridGuard::builder(KeyManager::from_master_key(&[0x76; 32]).unwrap())
+        .with_decrypt_errors(DecryptErrorMode::Verbose)
+        .build().unwrap();
+    let err = HybridGuardReader::new(&stream[..], &other).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+    assert!(matches!(hybridguard_error(&err), HybridGuardError::KeyMismatch(_)));
+
+    // Streams are keyed by the layer keys, which encrypt-only keys do not hold
+    let ingest = HybridGuard::builder(KeyManager::from_master_key(&[0x75; 32]).unwrap().encrypt_only().unwrap()).build().unwrap();
+    assert!(matches!(HybridGuardReader::new(&stream[..], &ingest), Err(HybridGuardError::CapabilityDenied(_))));
+    assert!(matches!(HybridGuardWriter::new(Vec::new(), &ingest), Err(HybridGuardError::CapabilityDenied(_))));
+}
@@ -0,0 +1,126 @@
+// Key files are backed up before they are replaced or destroyed, and
+// `key restore-backup` lists and restores the backups
+
+mod support;
+
+use hybridguard::key_manager::backup::{self, BackupPolicy};
+use hybridguard::key_manager::protector::PasswordProtector;
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+fn backup_names(output: &Output) -> Vec<String> {
+    String::from_utf8_lossy(&output.stdout).lines().map(|line| line.split_whitespace().next().unwrap_or_default().to_string()).collect()
+}
+
+#[test]
+fn interrupted_password_change_is_undone_by_a_restore() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("hybridguard.keys");
+    let old = PasswordProtector::new("correct horse battery staple");
+    let key_manager = KeyManager::from_master_key(&[0x2B; 32]).unwrap();
+    key_manager.save_protected(&keys, &old).unwrap();
+    let sealed = fs::read(&keys).unwrap();
+
+    // A password change backs the file up, then dies halfway through rewriting it
+    let taken = backup::back_up(&keys, &BackupPolicy::new(), 1_768_469_400).unwrap().unwrap();
+    assert!(taken.protected);
+    let new = PasswordProtector::new("a new password nobody wrote down");
+    let scratch = dir.path().join("resealed");
+    key_manager.save_protected(&scratch, &new).unwrap();
+    let resealed = fs::read(&scratch).unwrap();
+    fs::write(&keys, &resealed[..resealed.len() / 2]).unwrap();
+    assert!(KeyManager::from_protected_bytes(&fs::read(&keys).unwrap(), &old).is_err());
+
+    let output = hybridguard(&[Path::new("key"), Path::new("restore-backup"), Path::new("-k"), &keys]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(backup_names(&output), vec![taken.name()]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("sealed"));
+
+    let output = hybridguard(&[
+        Path::new("key"),
+        Path::new("restore-backup"),
+        Path::new("-k"),
+        &keys,
+        Path::new("--backup"),
+        Path::new("latest"),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("without a backup"));
+
+    // The backup stayed sealed, and it unlocks with the old password
+    assert_eq!(fs::read(&keys).unwrap(), sealed);
+    let restored = KeyManager::from_protected_bytes(&fs::read(&keys).unwrap(), &old).unwrap();
+    assert_eq!(restored.key_id(), key_manager.key_id());
+    let guard = HybridGuard::builder(restored).build().unwrap();
+    let encrypted = guard.encrypt(b"still readable").unwrap();
+    assert_eq!(guard.decrypt(&encrypted).unwrap(), b"still readable");
+}
+
+#[test]
+fn keygen_over_a_key_file_keeps_pruned_backups() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    let keystore = dir.path().join("keys");
+    let backups = dir.path().join("backups");
+    let keys = keystore.join("hybridguard.keys");
+
+    let mut originals = Vec::new();
+    for byte in [0x01u8, 0x02, 0x03, 0x04] {
+        fs::write(&master, [byte; 32]).unwrap();
+        let output = hybridguard(&[
+            Path::new("--backup-dir"),
+            &backups,
+            Path::new("--keep-backups"),
+            Path::new("2"),
+            Path::new("keygen"),
+            Path::new("-o"),
+            &keystore,
+            Path::new("--from-master-key-file"),
+            &master,
+        ]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        originals.push(fs::read(&keys).unwrap());
+        if byte > 1 {
+            assert!(String::from_utf8_lossy(&output.stderr).contains("plaintext"));
+        }
+    }
+
+    // Three keygens replaced a key file; the two newest replaced files remain
+    let policy = BackupPolicy::new().with_dir(&backups);
+    let kept = backup::list(&keys, &policy).unwrap();
+    assert_eq!(kept.len(), 2);
+    let contents: Vec<Vec<u8>> = kept.iter().map(|backup| fs::read(&backup.path).unwrap()).collect();
+    assert_eq!(contents, originals[1..3].to_vec());
+
+    // --no-key-backup replaces without one
+    let output = hybridguard(&[
+        Path::new("--no-key-backup"),
+        Path::new("keygen"),
+        Path::new("-o"),
+        &keystore,
+        Path::new("--from-master-key-file"),
+        &master,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(backup::list(&keys, &policy).unwrap().len(), 2);
+    assert!(backup::list(&keys, &BackupPolicy::new()).unwrap().is_empty());
+}
+
+#[test]
+fn destroy_leaves_no_backup_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("old.keys");
+    KeyManager::from_master_key(&[0x44; 32]).unwrap().save(&keys).unwrap();
+    // An earlier rewrite left a backup, which holds the same keys
+    backup::back_up(&keys, &BackupPolicy::new(), 1_768_469_400).unwrap().unwrap();
+
+    let output = hybridguard(&[Path::new("key"), Path::new("destroy"), Path::new("-k"), &keys, Path::new("--yes")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!keys.exists());
+    assert!(backup::list(&keys, &BackupPolicy::new()).unwrap().is_empty());
+    let left: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert!(left.is_empty(), "destroy left {:?}", left);
+}
@@ -0,0 +1,67 @@
+// `key destroy` overwrites and removes a key file after a typed confirmation
+
+mod support;
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::KeyManager;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Output;
+use support::{hybridguard, hybridguard_with_input};
+
+/// Run `key destroy` without --yes, typing `answer` at the confirmation
+fn destroy_typing(key_file: &Path, answer: &str) -> Output {
+    hybridguard_with_input(&[Path::new("key"), Path::new("destroy"), Path::new("-k"), key_file], format!("{}\n", answer).as_bytes())
+}
+
+#[test]
+fn destroyed_key_files_are_gone() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("work.keys");
+    let checkpoint = dir.path().join("backup.ckpt");
+    KeyManager::from_master_key(&[0xDE; 32]).unwrap().save(&keys).unwrap();
+    fs::write(&checkpoint, b"checkpoint under the work key").unwrap();
+
+    // A wrong answer leaves everything in place
+    let output = destroy_typing(&keys, "home.keys");
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(KeyManager::load(&keys).is_ok());
+
+    let output = hybridguard(&[
+        Path::new("key"),
+        Path::new("destroy"),
+        Path::new("-k"),
+        &keys,
+        Path::new("--checkpoint"),
+        &checkpoint,
+        Path::new("--yes"),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!keys.exists() && !checkpoint.exists());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("snapshots"));
+
+    // Loading now reports a missing file, not a damaged one
+    match KeyManager::load(&keys) {
+        Err(HybridGuardError::Io(e)) => assert_eq!(e.kind(), ErrorKind::NotFound),
+        Err(other) => panic!("expected not found, got {}", other),
+        Ok(_) => panic!("destroyed key file loaded"),
+    }
+    let output = hybridguard(&[Path::new("key"), Path::new("doctor"), &keys]);
+    assert_eq!(output.status.code(), Some(5));
+
+    // Destroying it again fails without prompting
+    let output = hybridguard(&[Path::new("key"), Path::new("destroy"), Path::new("-k"), &keys, Path::new("--yes")]);
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn typed_confirmation_destroys() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("laptop.keys");
+    KeyManager::from_master_key(&[0xDF; 32]).unwrap().save(&keys).unwrap();
+
+    let output = destroy_typing(&keys, "laptop.keys");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!keys.exists());
+}
@@ -0,0 +1,108 @@
+// Damaged key files fail to load with a diagnosis naming the damaged field
+
+mod support;
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::key_manager::doctor::{self, FieldStatus, KeyIdStatus, PartialDecryption};
+use hybridguard::KeyManager;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn fixture() -> (KeyManager, Value) {
+    let km = KeyManager::from_master_key(&[0xD0; 32]).unwrap();
+    let file = serde_json::from_slice(&km.to_bytes().unwrap()).unwrap();
+    (km, file)
+}
+
+/// Load `file` expecting it to be refused as damaged, returning the diagnosis
+fn damaged(file: &Value) -> doctor::KeyFileDiagnosis {
+    match KeyManager::from_bytes(file.to_string().as_bytes()) {
+        Err(HybridGuardError::KeyFileDamaged(diagnosis)) => *diagnosis,
+        Err(other) => panic!("expected a damaged key file, got {}", other),
+        Ok(_) => panic!("damaged key file loaded"),
+    }
+}
+
+#[test]
+fn each_damaged_field_is_pinpointed() {
+    let (_, original) = fixture();
+    let cases: [(&str, Option<Value>, FieldStatus); 6] = [
+        ("layer3_key", None, FieldStatus::Missing),
+        ("layer1_key", Some(json!(vec![7u8; 31])), FieldStatus::WrongLength { expected: 32, found: 31 }),
+        ("layer4_key", Some(json!("not bytes")), FieldStatus::Invalid { reason: "not an array of bytes".to_string() }),
+        ("layer2_key", Some(json!([1, 2, 300])), FieldStatus::Invalid { reason: "not an array of bytes".to_string() }),
+        ("created_at", None, FieldStatus::Missing),
+        ("key_id", Some(json!(42)), FieldStatus::Invalid { reason: String::new() }),
+    ];
+    for (name, value, expected) in cases {
+        let mut file = original.clone();
+        match value {
+            Some(value) => file["body"][name] = value,
+            None => {
+                file["body"].as_object_mut().unwrap().remove(name);
+            }
+        }
+        let diagnosis = damaged(&file);
+        let damaged_fields: Vec<&str> = diagnosis.damaged_fields().map(|field| field.name).collect();
+        assert_eq!(damaged_fields, [name], "{}", diagnosis);
+        match (diagnosis.field(name).unwrap(), &expected) {
+            (FieldStatus::Invalid { .. }, FieldStatus::Invalid { reason }) if reason.is_empty() => {}
+            (status, expected) => assert_eq!(status, expected, "{}", name),
+        }
+        assert!(diagnosis.summary().starts_with(name));
+    }
+}
+
+#[test]
+fn damaged_layer_keys_limit_what_can_be_decrypted() {
+    let (km, mut file) = fixture();
+    file["body"].as_object_mut().unwrap().remove("layer3_key");
+    let diagnosis = damaged(&file);
+    assert_eq!(diagnosis.damaged_layers, [3]);
+    assert_eq!(diagnosis.partial_decryption, PartialDecryption::StandaloneLayers { layers: vec![1, 2, 4] });
+    // The stored ID is still present but cannot be checked without layer 3
+    assert_eq!(diagnosis.key_id, KeyIdStatus::Unverifiable);
+
+    for layer in ["layer1_key", "layer2_key", "layer4_key"] {
+        file["body"].as_object_mut().unwrap().remove(layer);
+    }
+    assert_eq!(damaged(&file).partial_decryption, PartialDecryption::Impossible);
+
+    // Intact keys under an edited ID: the file loads, and the doctor notices
+    let (_, mut file) = fixture();
+    file["body"]["key_id"] = json!("hg-00000000000000000000000000000000");
+    let diagnosis = doctor::diagnose(file.to_string().as_bytes());
+    assert!(diagnosis.is_healthy());
+    assert_eq!(diagnosis.key_id, KeyIdStatus::Mismatch { computed: km.key_id().to_string() });
+}
+
+#[test]
+fn doctor_command_reports_and_exits_with_key_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let (km, mut file) = fixture();
+    km.save(&keys).unwrap();
+
+    let output = hybridguard(&[Path::new("key"), Path::new("doctor"), &keys]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Key ID:      intact"));
+
+    file["body"].as_object_mut().unwrap().remove("layer3_key");
+    fs::write(&keys, file.to_string()).unwrap();
+    let output = hybridguard(&[Path::new("key"), Path::new("doctor"), Path::new("--json"), &keys]);
+    assert_eq!(output.status.code(), Some(3));
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["damaged_layers"], json!([3]));
+    assert_eq!(report["partial_decryption"]["status"], "standalone-layers");
+    let layer3 = report["fields"].as_array().unwrap().iter().find(|f| f["name"] == "layer3_key").unwrap();
+    assert_eq!(layer3["status"], "missing");
+
+    // Normal use names the damage instead of a parser error
+    let input = dir.path().join("a.hg");
+    fs::write(&input, b"x").unwrap();
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &dir.path().join("a")]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("layer3_key missing"));
+}
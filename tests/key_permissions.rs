@@ -0,0 +1,62 @@
+// Key files are created owner-only, and loose ones are flagged or fixed on load
+#![cfg(unix)]
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use support::hybridguard;
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+#[test]
+fn keygen_and_backup_are_owner_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    fs::write(&master, [0x5Au8; 32]).unwrap();
+    let keystore = dir.path().join("keys");
+
+    let output = hybridguard(&[Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let key_file = keystore.join("hybridguard.keys");
+    assert_eq!(mode(&keystore), 0o700);
+    assert_eq!(mode(&key_file), 0o600);
+
+    let backup = dir.path().join("backup.txt");
+    let output = hybridguard(&[
+        Path::new("key"), Path::new("backup"), Path::new("--paper"), Path::new("-k"), &key_file, Path::new("-o"), &backup,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(mode(&backup), 0o600);
+}
+
+#[test]
+fn loose_key_file_warns_then_fixes() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("shared.keys");
+    KeyManager::from_master_key(&[0x3Cu8; 32]).unwrap().save(&key_file).unwrap();
+    assert_eq!(mode(&key_file), 0o600);
+    fs::set_permissions(&key_file, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let input = dir.path().join("plain.txt");
+    fs::write(&input, b"shared machine").unwrap();
+    let encrypted = dir.path().join("plain.hg");
+
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &key_file, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("readable by other users"), "{}", stderr);
+    assert_eq!(mode(&key_file), 0o644);
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--fix-permissions"), Path::new("--force"), Path::new("-k"), &key_file,
+        Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("readable by other users"));
+    assert_eq!(mode(&key_file), 0o600);
+}
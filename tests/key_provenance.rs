@@ -0,0 +1,170 @@
+// Key files record their owner and carry a self-signature over the body,
+// which loading, `key list` and `key doctor` check
+
+mod support;
+
+use hybridguard::key_manager::doctor;
+use hybridguard::key_manager::provenance::{self, KeyOwner, SignatureStatus};
+use hybridguard::KeyManager;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn owner() -> KeyOwner {
+    KeyOwner::new(Some("Backup Service".to_string()), Some("ops@example.com".to_string()), vec!["prod".to_string(), "eu-west".to_string()])
+        .unwrap()
+        .unwrap()
+}
+
+/// Owned keys saved under `dir`, and the file's contents
+fn signed_file(dir: &Path, fill: u8) -> (KeyManager, Vec<u8>) {
+    let km = KeyManager::from_master_key(&[fill; 32]).unwrap().with_owner(owner());
+    let path = dir.join(format!("signed-{:02x}.keys", fill));
+    km.save(&path).unwrap();
+    let bytes = fs::read(&path).unwrap();
+    (km, bytes)
+}
+
+/// `data` with the character at `offset`, inside a JSON string, changed
+fn flip_char(data: &[u8], offset: usize) -> Vec<u8> {
+    let mut data = data.to_vec();
+    assert!(data[offset].is_ascii_alphanumeric());
+    data[offset] = if data[offset] == b'0' { b'1' } else { b'0' };
+    data
+}
+
+fn find(data: &[u8], needle: &str) -> usize {
+    data.windows(needle.len()).position(|window| window == needle.as_bytes()).unwrap() + needle.len()
+}
+
+#[test]
+fn owner_survives_a_save_and_load() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("owned.keys");
+    let (km, bytes) = signed_file(dir.path(), 0x71);
+    fs::write(&path, &bytes).unwrap();
+
+    let loaded = KeyManager::load(&path).unwrap();
+    assert_eq!(loaded.owner(), Some(&owner()));
+    assert_eq!(loaded.owner().unwrap().to_string(), "Backup Service <ops@example.com> [prod, eu-west]");
+    assert_eq!(loaded.created_at(), km.created_at());
+    assert!(loaded.self_signature().unwrap().is_valid());
+
+    // The encrypt-only copy keeps the owner
+    assert_eq!(loaded.encrypt_only().unwrap().owner(), Some(&owner()));
+}
+
+#[test]
+fn signature_verifies_and_covers_body_and_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, bytes) = signed_file(dir.path(), 0x72);
+    let SignatureStatus::Valid { fingerprint } = provenance::check(&bytes) else {
+        panic!("{:?}", provenance::check(&bytes));
+    };
+    assert!(fingerprint.starts_with("hgsig-"));
+    let file: Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(file["signature"]["algorithm"], provenance::SIGNATURE_ALGORITHM);
+    assert!(file["body"].get("signature").is_none());
+
+    // Re-indenting or reordering the body does not break it
+    let compact = serde_json::to_vec(&file).unwrap();
+    assert!(provenance::check(&compact).is_valid());
+
+    // An edited owner does
+    let mut edited = file.clone();
+    edited["body"]["owner"]["email"] = Value::String("mallory@example.com".to_string());
+    assert!(provenance::check(edited.to_string().as_bytes()).is_invalid());
+
+    // and so does a signature made with a keypair the layer keys do not give
+    let (_, other) = signed_file(dir.path(), 0x73);
+    let other: Value = serde_json::from_slice(&other).unwrap();
+    let mut resigned = edited;
+    resigned["signature"] = other["signature"].clone();
+    assert!(provenance::check(resigned.to_string().as_bytes()).is_invalid());
+}
+
+#[test]
+fn single_flipped_byte_in_the_body_is_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, bytes) = signed_file(dir.path(), 0x74);
+    for needle in ["\"created_at\": \"", "\"key_id\": \"hg-"] {
+        let tampered = flip_char(&bytes, find(&bytes, needle));
+        assert!(provenance::check(&tampered).is_invalid(), "{}", needle);
+
+        // The keys still load, with the failure recorded, and the doctor names it
+        let loaded = KeyManager::from_bytes(&tampered).unwrap();
+        assert!(loaded.self_signature().unwrap().is_invalid());
+        let diagnosis = doctor::diagnose(&tampered);
+        assert!(diagnosis.self_signature.is_invalid());
+        assert!(diagnosis.to_string().contains("Signature:   INVALID"), "{}", diagnosis);
+    }
+    assert!(provenance::check(&bytes).is_valid());
+}
+
+#[test]
+fn legacy_unsigned_files_load_and_are_signed_on_resave() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, bytes) = signed_file(dir.path(), 0x75);
+    let mut file: Value = serde_json::from_slice(&bytes).unwrap();
+    file.as_object_mut().unwrap().remove("signature");
+    let unsigned = dir.path().join("unsigned.keys");
+    fs::write(&unsigned, serde_json::to_vec_pretty(&file).unwrap()).unwrap();
+    let v1 = dir.path().join("v1.keys");
+    fs::write(&v1, serde_json::to_vec(&file["body"]).unwrap()).unwrap();
+
+    for path in [&unsigned, &v1] {
+        let km = KeyManager::load(path).unwrap();
+        assert_eq!(km.self_signature(), Some(&SignatureStatus::Missing));
+        assert_eq!(km.owner(), Some(&owner()));
+        km.save(path).unwrap();
+        assert!(KeyManager::load(path).unwrap().self_signature().unwrap().is_valid());
+    }
+}
+
+#[test]
+fn cli_records_owner_and_lists_signature_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.key");
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = (i as u8).wrapping_mul(151) ^ 0x3C;
+    }
+    fs::write(&master, key).unwrap();
+    let keystore = dir.path().join("keys");
+    let output = hybridguard(&[
+        Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master,
+        Path::new("--owner-name"), Path::new("Backup Service"), Path::new("--owner-email"), Path::new("ops@example.com"),
+        Path::new("--owner-label"), Path::new("prod"),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let key_file = keystore.join("hybridguard.keys");
+
+    let output = hybridguard(&[Path::new("key"), Path::new("list"), &keystore]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Owner:        Backup Service <ops@example.com> [prod]"), "{}", stdout);
+    assert!(stdout.contains("Signature:    valid (ML-DSA-65 hgsig-"), "{}", stdout);
+
+    let output = hybridguard(&[Path::new("key"), Path::new("doctor"), &key_file]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // A tampered file lists, and the doctor passes its fields, but both fail
+    let bytes = fs::read(&key_file).unwrap();
+    fs::write(&key_file, flip_char(&bytes, find(&bytes, "\"created_at\": \""))).unwrap();
+    let output = hybridguard(&[Path::new("key"), Path::new("list"), Path::new("--json"), &keystore]);
+    assert_eq!(output.status.code(), Some(4));
+    let listings: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(listings[0]["signature"]["status"], "invalid");
+    assert_eq!(listings[0]["owner"]["labels"], serde_json::json!(["prod"]));
+    let output = hybridguard(&[Path::new("key"), Path::new("doctor"), &key_file]);
+    assert_eq!(output.status.code(), Some(4));
+
+    // A malformed owner is refused before any key is made
+    let output = hybridguard(&[
+        Path::new("keygen"), Path::new("-o"), &dir.path().join("other"), Path::new("--from-master-key-file"), &master,
+        Path::new("--owner-email"), Path::new("not-an-address"),
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!dir.path().join("other").exists());
+}
@@ -0,0 +1,134 @@
+// Writers sharing one keystore take turns through its lock: key files,
+// their backups and the pruning of old backups stay consistent however many
+// threads or processes race, and a held lock fails a run with exit code 8
+
+mod support;
+
+use hybridguard::key_manager::backup::{self, BackupPolicy};
+use hybridguard::key_manager::lock::{KeystoreLock, LockOptions, LOCK_FILE_NAME};
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use support::hybridguard;
+
+const KEEP: usize = 3;
+
+/// The keystore at `dir` holds a loadable key file, exactly `KEEP` backups
+/// that all read back, and no lock or temp files left behind
+fn assert_consistent(dir: &Path, key_file: &Path) {
+    KeyManager::load(key_file).unwrap();
+    let policy = BackupPolicy::new().with_keep(KEEP);
+    let backups = backup::list(key_file, &policy).unwrap();
+    assert_eq!(backups.len(), KEEP);
+    for taken in &backups {
+        backup::read(taken).unwrap();
+    }
+    let mut names: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    names.retain(|name| !name.to_string_lossy().contains(".bak-"));
+    assert_eq!(names, vec![key_file.file_name().unwrap().to_os_string()]);
+}
+
+#[test]
+fn threads_saving_and_listing_keep_the_keystore_consistent() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("hybridguard.keys");
+    KeyManager::from_master_key(&[0x71; 32]).unwrap().save(&key_file).unwrap();
+
+    let threads: Vec<_> = (0..8u8)
+        .map(|t| {
+            let (dir, key_file) = (dir.path().to_path_buf(), key_file.clone());
+            thread::spawn(move || {
+                let policy = BackupPolicy::new().with_keep(KEEP);
+                for round in 0..6u64 {
+                    {
+                        let _lock = KeystoreLock::keystore(&dir, &LockOptions::new()).unwrap();
+                        backup::back_up(&key_file, &policy, 1_768_469_400 + round).unwrap();
+                        KeyManager::from_master_key(&[t; 32]).unwrap().save(&key_file).unwrap();
+                    }
+                    // Readers take no lock and always find a whole key file
+                    KeyManager::load(&key_file).unwrap();
+                    backup::list(&key_file, &policy).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_consistent(dir.path(), &key_file);
+}
+
+#[test]
+fn held_lock_fails_a_run_as_busy() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    fs::write(&master, [0x72; 32]).unwrap();
+    let keystore = dir.path().join("keys");
+    fs::create_dir(&keystore).unwrap();
+    let keygen = [
+        Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master,
+        Path::new("--lock-timeout"), Path::new("0"),
+    ];
+
+    let lock = KeystoreLock::keystore(&keystore, &LockOptions::new()).unwrap();
+    let output = hybridguard(&keygen);
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Keystore busy"));
+    assert!(!keystore.join("hybridguard.keys").exists());
+
+    drop(lock);
+    let output = hybridguard(&keygen);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!keystore.join(LOCK_FILE_NAME).exists());
+}
+
+#[test]
+#[cfg_attr(not(feature = "process-tests"), ignore)]
+fn processes_racing_on_one_keystore_keep_it_consistent() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    fs::write(&master, [0x73; 32]).unwrap();
+    let keystore = dir.path().join("keys");
+    let key_file = keystore.join("hybridguard.keys");
+    let stats = dir.path().join("stats.json");
+    let keep = KEEP.to_string();
+    let output = hybridguard(&[Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let runs: Vec<_> = (0..12)
+        .map(|i| {
+            let (dir, master, keystore, key_file, stats, keep) =
+                (dir.path().to_path_buf(), master.clone(), keystore.clone(), key_file.clone(), stats.clone(), keep.clone());
+            thread::spawn(move || {
+                for round in 0..3 {
+                    // Rewrite the key file behind a backup, list the backups, and count an encrypt
+                    let output = hybridguard(&[
+                        Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master,
+                        Path::new("--keep-backups"), Path::new(&keep),
+                    ]);
+                    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+                    let output = hybridguard(&[Path::new("key"), Path::new("restore-backup"), Path::new("-k"), &key_file]);
+                    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+                    let input = dir.join(format!("plain-{}-{}", i, round));
+                    fs::write(&input, vec![i as u8; 100]).unwrap();
+                    let encrypted = dir.join(format!("plain-{}-{}.hg", i, round));
+                    let output = hybridguard(&[
+                        Path::new("encrypt"), Path::new("-k"), &key_file, Path::new("-i"), &input, Path::new("-o"), &encrypted,
+                        Path::new("--stats-file"), &stats,
+                    ]);
+                    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+                }
+            })
+        })
+        .collect();
+    for run in runs {
+        run.join().unwrap();
+    }
+
+    assert_consistent(&keystore, &key_file);
+    let counts: serde_json::Value = serde_json::from_slice(&fs::read(&stats).unwrap()).unwrap();
+    assert_eq!(counts["operations"]["encrypt"]["runs"], 36);
+}
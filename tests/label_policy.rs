@@ -0,0 +1,141 @@
+// Policy labels: `decrypt --policy` refuses labeled files that fall short, and
+// `--override-policy` lets them through only with a reason in the audit log
+
+mod support;
+
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::timing::FixedClock;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use std::sync::Arc;
+use support::hybridguard;
+
+const POLICY: &str = r#"
+audit_log = "overrides.log"
+
+[labels.confidential]
+min_profile = "paranoid"
+required_layers = ["ML-KEM", "HQC"]
+max_age_days = 365
+"#;
+
+fn decrypt(input: &Path, output: &Path, key_file: &Path, extra: &[&Path]) -> Output {
+    let mut args = vec![Path::new("decrypt"), Path::new("-i"), input, Path::new("-o"), output, Path::new("-k"), key_file];
+    args.extend_from_slice(extra);
+    hybridguard(&args)
+}
+
+/// A `confidential` container encrypted in 2001, long past the policy's 365 days
+fn write_outdated(path: &Path, key_manager: &KeyManager) {
+    let encryptor = HybridGuardEncryptor::new().with_clock(Arc::new(FixedClock::new(1_000_000_000)));
+    let (file_keys, wrapped) = encryptor.new_file_keys(key_manager).unwrap();
+    let encrypted = encryptor
+        .encrypt(b"quarterly figures", &file_keys)
+        .unwrap()
+        .with_label("confidential", &file_keys)
+        .with_wrapped_key(wrapped, &file_keys)
+        .with_key_id(key_manager.key_id());
+    fs::write(path, encrypted.to_bytes().unwrap()).unwrap();
+}
+
+#[test]
+fn compliant_labeled_files_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, enc, out) = (dir.path().join("plain"), dir.path().join("plain.hg"), dir.path().join("out"));
+    let (keys, policy) = (dir.path().join("work.keys"), dir.path().join("policy.toml"));
+    KeyManager::from_master_key(&[0x5A; 32]).unwrap().save(&keys).unwrap();
+    fs::write(&plain, b"quarterly figures").unwrap();
+    fs::write(&policy, POLICY).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"),
+        Path::new("-i"),
+        &plain,
+        Path::new("-o"),
+        &enc,
+        Path::new("-k"),
+        &keys,
+        Path::new("--label"),
+        Path::new("confidential"),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = hybridguard(&[Path::new("inspect"), Path::new("--brief"), &enc]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Label: confidential"));
+
+    let output = decrypt(&enc, &out, &keys, &[Path::new("--policy"), &policy]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"quarterly figures");
+}
+
+#[test]
+fn bad_labels_are_usage_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, enc) = (dir.path().join("plain"), dir.path().join("plain.hg"));
+    fs::write(&plain, b"x").unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"),
+        Path::new("-i"),
+        &plain,
+        Path::new("-o"),
+        &enc,
+        Path::new("--label"),
+        Path::new("Top Secret"),
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!enc.exists());
+}
+
+#[test]
+fn outdated_labeled_files_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let (enc, out) = (dir.path().join("old.hg"), dir.path().join("out"));
+    let (keys, policy) = (dir.path().join("work.keys"), dir.path().join("policy.toml"));
+    let key_manager = KeyManager::from_master_key(&[0x5A; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+    write_outdated(&enc, &key_manager);
+    fs::write(&policy, POLICY).unwrap();
+
+    let output = decrypt(&enc, &out, &keys, &[Path::new("--policy"), &policy]);
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("re-encryption within 365 days"));
+    assert!(!out.exists());
+    assert!(!dir.path().join("overrides.log").exists());
+
+    // Without a policy the label is only a record
+    let output = decrypt(&enc, &out, &keys, &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn overrides_need_a_reason_and_an_audit_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let (enc, out) = (dir.path().join("old.hg"), dir.path().join("out"));
+    let (keys, policy) = (dir.path().join("work.keys"), dir.path().join("policy.toml"));
+    let key_manager = KeyManager::from_master_key(&[0x5A; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+    write_outdated(&enc, &key_manager);
+
+    // A policy without an audit log cannot be overridden
+    fs::write(&policy, POLICY.replace("audit_log = \"overrides.log\"", "")).unwrap();
+    let reason = Path::new("legal hold review, ticket 4411");
+    let output = decrypt(&enc, &out, &keys, &[Path::new("--policy"), &policy, Path::new("--override-policy"), reason]);
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!out.exists());
+
+    fs::write(&policy, POLICY).unwrap();
+    let output = decrypt(&enc, &out, &keys, &[Path::new("--policy"), &policy, Path::new("--override-policy"), reason]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"quarterly figures");
+
+    let log = fs::read_to_string(dir.path().join("overrides.log")).unwrap();
+    assert_eq!(log.lines().count(), 1);
+    let record: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+    assert_eq!(record["label"], "confidential");
+    assert_eq!(record["reason"], "legal hold review, ticket 4411");
+    assert_eq!(record["file"], enc.to_str().unwrap());
+    assert!(record["requirements"][0].as_str().unwrap().contains("re-encryption within 365 days"));
+}
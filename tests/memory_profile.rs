@@ -0,0 +1,64 @@
+// Per-layer memory reports for a 10 MB input
+// Needs the counting allocator: cargo test --features memory-profile
+
+#![cfg(feature = "memory-profile")]
+
+use hybridguard::layers::layer3_noise::QuantumNoiseLayer;
+use hybridguard::layers::layer4_fhe::FHELayer;
+use hybridguard::layers::EncryptionLayer;
+use hybridguard::profiling::{self, Profiling, TrackingAllocator};
+use hybridguard::{HybridGuard, KeyManager};
+
+// The library leaves installing the counting allocator to its user
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+const INPUT_LEN: usize = 10 * 1024 * 1024;
+
+/// Allowance for KEM ciphertexts, padding and keystream scratch space
+const SLACK: usize = 256 * 1024;
+
+fn input() -> Vec<u8> {
+    (0..INPUT_LEN).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn pipeline_peaks_stay_within_bounds() {
+    let key_manager = KeyManager::from_master_key(&[0x4D; 32]).unwrap();
+    let hg = HybridGuard::builder(key_manager).with_profiling(Profiling::Memory).build().unwrap();
+
+    let (_, report) = hg.encrypt_with_report(&input()).unwrap();
+    let memory = report.memory.expect("profiling was on");
+    assert_eq!(memory.layers.len(), 4);
+
+    // Layers 1 and 2 copy their input and build a new output
+    for layer in &memory.layers[..2] {
+        assert!(layer.peak_bytes >= layer.output_bytes, "{:?}", layer);
+        assert!(layer.peak_bytes <= 2 * INPUT_LEN + SLACK, "{:?}", layer);
+    }
+    // Layers 3 and 4 reuse the buffer handed to them
+    for layer in &memory.layers[2..] {
+        assert!(layer.peak_bytes <= INPUT_LEN + SLACK, "{:?}", layer);
+    }
+    assert!(memory.peak() <= 2 * INPUT_LEN + SLACK);
+}
+
+#[test]
+fn in_place_paths_allocate_less() {
+    let key = [0x5E; 32];
+    for layer in [&QuantumNoiseLayer::new() as &dyn EncryptionLayer, &FHELayer::new()] {
+        let data = input();
+        let (_, copied) = profiling::measure(|| layer.encrypt(&data, &key).unwrap());
+        let (_, in_place) = profiling::measure(|| layer.encrypt_owned(data, &key).unwrap());
+        assert!(in_place < copied, "{}: {} in place vs {} copied", layer.name(), in_place, copied);
+        assert!(copied >= INPUT_LEN, "{}: {}", layer.name(), copied);
+    }
+}
+
+#[test]
+fn profiling_off_reports_nothing() {
+    let key_manager = KeyManager::from_master_key(&[0x4E; 32]).unwrap();
+    let hg = HybridGuard::builder(key_manager).build().unwrap();
+    let (_, report) = hg.encrypt_with_report(b"small").unwrap();
+    assert!(report.memory.is_none());
+}
@@ -0,0 +1,66 @@
+// `--lang` prints messages from a translation file, falling back to English
+// for ids it leaves out, and `--no-emoji` drops the icons
+
+mod support;
+
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn translated_messages_fall_back_to_english() {
+    let dir = tempfile::tempdir().unwrap();
+    let lang = dir.path().join("de.lang");
+    fs::write(
+        &lang,
+        "# German, partially\narchive-done = {entries} Einträge aus {input} archiviert\nerror-prefix = Fehler:\nerror-invalid-input = Ungültige Eingabe: {detail}\n",
+    )
+    .unwrap();
+    let input = dir.path().join("project");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("notes.txt"), "notes").unwrap();
+    let archive = dir.path().join("project.hga");
+
+    let result = hybridguard(&[Path::new("--lang"), &lang, Path::new("--no-emoji"), Path::new("archive"), Path::new("-i"), &input, Path::new("-o"), &archive]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("1 Einträge aus"), "{}", stderr);
+    assert!(!stderr.contains('📦'), "{}", stderr);
+
+    // extract-done is not translated, so it stays in English
+    let out = dir.path().join("out");
+    let result = hybridguard(&[Path::new("--lang"), &lang, Path::new("extract"), Path::new("-i"), &archive, Path::new("-o"), &out]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("Extracted 1 entries"), "{}", stderr);
+
+    let result = hybridguard(&[Path::new("--lang"), &lang, Path::new("--no-emoji"), Path::new("extract"), Path::new("-i"), &archive, Path::new("-o"), &out]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(2));
+    assert!(stderr.contains("Fehler: Ungültige Eingabe:"), "{}", stderr);
+    assert!(stderr.contains("is not empty"), "{}", stderr);
+}
+
+#[test]
+fn malformed_translation_file_is_a_usage_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let lang = dir.path().join("broken.lang");
+    fs::write(&lang, "archive-done\n").unwrap();
+    let result = hybridguard(&[Path::new("--lang"), &lang, Path::new("status")]);
+    assert_eq!(result.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("translation line 1"));
+}
+
+#[test]
+fn status_report_follows_lang_and_no_emoji() {
+    let dir = tempfile::tempdir().unwrap();
+    let lang = dir.path().join("de.lang");
+    fs::write(&lang, "status-title = HybridGuard Sicherheitsstatus\nstatus-operational = Alle Systeme betriebsbereit\n").unwrap();
+
+    let result = hybridguard(&[Path::new("--lang"), &lang, Path::new("--no-emoji"), Path::new("status")]);
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(stdout.contains("HybridGuard Sicherheitsstatus"), "{}", stdout);
+    assert!(stdout.contains("Alle Systeme betriebsbereit"), "{}", stdout);
+    assert!(stdout.contains("Encryption Layers:"), "{}", stdout);
+    assert!(!stdout.contains('✅') && !stdout.contains('🛡'), "{}", stdout);
+}
@@ -0,0 +1,115 @@
+// Application metadata: public entries anyone can read from the header,
+// private entries sealed under the file's key, both covered by the tag
+
+mod support;
+
+use hybridguard::crypto::metadata::{self, MetadataMap};
+use hybridguard::crypto::EncryptedData;
+use hybridguard::{EncryptOptions, HybridGuard, HybridGuardError, KeyManager};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn guard(fill: u8) -> HybridGuard {
+    HybridGuard::builder(KeyManager::from_master_key(&[fill; 32]).unwrap()).build().unwrap()
+}
+
+fn options() -> EncryptOptions {
+    EncryptOptions::default()
+        .with_metadata("tenant", "acme")
+        .with_metadata("doc.uuid", "6f1e2c90-0b7a-4c1d-9a43-35a8c1b0f6de")
+        .with_private_metadata("owner.email", "ops@example.com")
+}
+
+#[test]
+fn public_and_private_maps_round_trip() {
+    let hg = guard(0x61);
+    let encrypted = hg.encrypt_with_options(b"quarterly numbers", &options()).unwrap();
+    let parsed = EncryptedData::from_bytes(&encrypted.to_bytes().unwrap()).unwrap();
+
+    // Public entries need no keys; private ones only the file's
+    let extra = parsed.metadata().extra();
+    assert_eq!(extra.keys().collect::<Vec<_>>(), ["doc.uuid", "tenant"]);
+    assert_eq!(parsed.metadata().get("tenant"), Some(&b"acme"[..]));
+    assert!(parsed.metadata().get("owner.email").is_none());
+    let private = hg.open_metadata(&parsed).unwrap();
+    assert_eq!(private, MetadataMap::from([("owner.email".to_string(), b"ops@example.com".to_vec())]));
+    assert!(guard(0x62).open_metadata(&parsed).is_err());
+    assert_eq!(hg.decrypt(&parsed).unwrap(), b"quarterly numbers");
+
+    // Without metadata nothing is recorded
+    let plain = hg.encrypt(b"no metadata").unwrap();
+    assert!(plain.metadata().is_empty());
+    assert!(hg.open_metadata(&plain).unwrap().is_empty());
+}
+
+#[test]
+fn edited_metadata_fails_authentication() {
+    let hg = guard(0x63);
+    let bytes = hg.encrypt_with_options(b"payload", &options()).unwrap().to_bytes().unwrap();
+    let at = bytes.windows(4).position(|w| w == b"acme").unwrap();
+    let mut tampered = bytes.clone();
+    tampered[at] = b'A';
+
+    let tampered = EncryptedData::from_bytes(&tampered).unwrap();
+    assert_eq!(tampered.metadata().get("tenant"), Some(&b"Acme"[..]));
+    assert!(hg.decrypt(&tampered).is_err());
+    assert!(hg.open_metadata(&tampered).is_err());
+}
+
+#[test]
+fn invalid_metadata_is_refused_before_encrypting() {
+    let hg = guard(0x64);
+    let refused = |options: EncryptOptions| matches!(hg.encrypt_with_options(b"x", &options), Err(HybridGuardError::InvalidInput(_)));
+
+    assert!(refused(EncryptOptions::default().with_metadata("hg.version", "1")));
+    assert!(refused(EncryptOptions::default().with_metadata("has space", "1")));
+    assert!(refused(EncryptOptions::default().with_metadata("id", "1").with_private_metadata("id", "2")));
+    let big = vec![0u8; metadata::MAX_METADATA_LEN];
+    assert!(refused(EncryptOptions::default().with_metadata("blob", big.clone())));
+    assert!(!refused(EncryptOptions::default().with_metadata("blob", &big[..big.len() - 4])));
+}
+
+#[test]
+fn cli_records_and_inspects_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("work.keys");
+    KeyManager::from_master_key(&[0x65; 32]).unwrap().save(&key_file).unwrap();
+    let (plain, enc) = (dir.path().join("report.txt"), dir.path().join("report.hg"));
+    fs::write(&plain, b"report body").unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("-k"), &key_file,
+        Path::new("--meta"), Path::new("tenant=acme"), Path::new("--meta"), Path::new("note=say \"hi\""),
+        Path::new("--meta-private"), Path::new("owner=ops@example.com"),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Without keys the private map shows only as sealed bytes
+    for brief in [true, false] {
+        let mut args = vec![Path::new("inspect"), Path::new("-i"), &enc];
+        if brief {
+            args.push(Path::new("--brief"));
+        }
+        let output = hybridguard(&args);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("tenant = \"acme\""), "{}", stdout);
+        assert!(stdout.contains("note = \"say \\\"hi\\\"\""), "{}", stdout);
+        assert!(stdout.contains("private: sealed"), "{}", stdout);
+        assert!(!stdout.contains("ops@example.com"), "{}", stdout);
+    }
+    let output = hybridguard(&[Path::new("inspect"), Path::new("-i"), &enc, Path::new("-k"), &key_file]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("owner = \"ops@example.com\""));
+
+    // Reserved and malformed keys are usage errors, and nothing is written
+    let refused = dir.path().join("refused.hg");
+    for meta in ["hg.tenant=acme", "tenant"] {
+        let output = hybridguard(&[
+            Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &refused, Path::new("-k"), &key_file,
+            Path::new("--meta"), Path::new(meta),
+        ]);
+        assert_eq!(output.status.code(), Some(2), "{}", meta);
+        assert!(!refused.exists());
+    }
+}
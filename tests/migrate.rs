@@ -0,0 +1,86 @@
+// `hybridguard migrate` over a directory of mixed old, current and foreign files
+
+use hybridguard::crypto::{container, EncryptedData};
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::layers::{self, EncryptionLayer};
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn migrate(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .arg("migrate")
+        .args(args)
+        .output()
+        .expect("failed to run hybridguard")
+}
+
+/// Bare bincode of the original `EncryptedData`, all layers at format version 1
+fn v0_fixture(plaintext: &[u8], key_manager: &KeyManager) -> Vec<u8> {
+    let mut data = plaintext.to_vec();
+    for (layer, key) in layers::registry().iter().zip(key_manager.get_keys().unwrap().in_order()) {
+        data = layer.encrypt_version(&data, key, 1).unwrap();
+    }
+    let names: Vec<String> = layers::legacy_descriptors().into_iter().map(|d| d.name).collect();
+    bincode::serialize(&(data, names, "0.1.0", 1_700_000_000u64)).unwrap()
+}
+
+fn decrypt(bytes: &[u8], key_manager: &KeyManager) -> Vec<u8> {
+    let encrypted = EncryptedData::from_bytes(bytes).unwrap();
+    HybridGuardEncryptor::new().decrypt(&encrypted, &key_manager.keys_for(&encrypted).unwrap()).unwrap()
+}
+
+#[test]
+fn recursive_migration_skips_current_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let km = KeyManager::from_master_key(&[0x33; 32]).unwrap();
+    km.save(&keys).unwrap();
+
+    let old = dir.path().join("old");
+    fs::create_dir_all(old.join("nested")).unwrap();
+    let legacy = v0_fixture(b"legacy secret", &km);
+    fs::write(old.join("nested/legacy.hg"), &legacy).unwrap();
+    let current = HybridGuardEncryptor::new().encrypt(b"current", km.get_keys().unwrap()).unwrap().with_key_id(km.key_id());
+    fs::write(old.join("current.hg"), current.to_bytes().unwrap()).unwrap();
+    fs::write(old.join("notes.txt"), b"%PDF-1.7 not a ciphertext").unwrap();
+
+    let new = dir.path().join("new");
+    let output = migrate(&[Path::new("-r"), Path::new("-k"), &keys, Path::new("-i"), &old, Path::new("-o"), &new]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Migrated: 1"));
+    assert!(stderr.contains("already current: 1"));
+
+    let migrated = fs::read(new.join("nested/legacy.hg")).unwrap();
+    assert_eq!(container::format_version(&migrated).unwrap(), container::FORMAT_VERSION);
+    assert_eq!(decrypt(&migrated, &km), b"legacy secret");
+    assert!(!new.join("current.hg").exists());
+
+    // Inputs are untouched without --delete-old
+    assert_eq!(fs::read(old.join("nested/legacy.hg")).unwrap(), legacy);
+}
+
+#[test]
+fn delete_old_replaces_in_place() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let km = KeyManager::from_master_key(&[0x44; 32]).unwrap();
+    km.save(&keys).unwrap();
+
+    let file = dir.path().join("legacy.hg");
+    fs::write(&file, v0_fixture(b"in place", &km)).unwrap();
+
+    // Replacing the input needs --delete-old
+    let output = migrate(&[Path::new("-k"), &keys, Path::new("-i"), &file, Path::new("-o"), &file]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(container::format_version(&fs::read(&file).unwrap()).unwrap(), 0);
+
+    let output = migrate(&[Path::new("--delete-old"), Path::new("-k"), &keys, Path::new("-i"), &file, Path::new("-o"), &file]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let migrated = fs::read(&file).unwrap();
+    assert_eq!(container::format_version(&migrated).unwrap(), container::FORMAT_VERSION);
+    assert_eq!(decrypt(&migrated, &km), b"in place");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
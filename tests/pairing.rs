@@ -0,0 +1,64 @@
+// Two key holders agree on a shared key through the pair subcommands
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn offer_accept_finish_share_one_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let (alice, bob) = (dir.path().join("a.keys"), dir.path().join("b.keys"));
+    KeyManager::from_master_key(&[0xA7; 32]).unwrap().save(&alice).unwrap();
+    KeyManager::from_master_key(&[0xB7; 32]).unwrap().save(&bob).unwrap();
+    let (offer, reply) = (dir.path().join("offer.bin"), dir.path().join("accept.bin"));
+    let (alice_shared, bob_shared) = (dir.path().join("a-shared.keys"), dir.path().join("b-shared.keys"));
+
+    let output = hybridguard(&[Path::new("pair"), Path::new("offer"), Path::new("--key-file"), &alice]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    fs::write(&offer, &output.stdout).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("pair"), Path::new("accept"), Path::new("--key-file"), &bob, &offer, Path::new("-o"), &bob_shared,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    fs::write(&reply, &output.stdout).unwrap();
+
+    // Finishing with the wrong key file is a key error
+    let output = hybridguard(&[
+        Path::new("pair"), Path::new("finish"), Path::new("--key-file"), &bob, &reply, Path::new("-o"), &alice_shared,
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = hybridguard(&[
+        Path::new("pair"), Path::new("finish"), Path::new("--key-file"), &alice, &reply, Path::new("-o"), &alice_shared,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Fingerprint:"));
+
+    let (a, b) = (KeyManager::load(&alice_shared).unwrap(), KeyManager::load(&bob_shared).unwrap());
+    assert_eq!(a.key_id(), b.key_id());
+}
+
+#[test]
+fn accept_sends_no_reply_when_the_key_is_not_saved() {
+    let dir = tempfile::tempdir().unwrap();
+    let (alice, bob) = (dir.path().join("a.keys"), dir.path().join("b.keys"));
+    KeyManager::from_master_key(&[0xA8; 32]).unwrap().save(&alice).unwrap();
+    KeyManager::from_master_key(&[0xB8; 32]).unwrap().save(&bob).unwrap();
+    let offer = dir.path().join("offer.bin");
+    let output = hybridguard(&[Path::new("pair"), Path::new("offer"), Path::new("--key-file"), &alice]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    fs::write(&offer, &output.stdout).unwrap();
+
+    // The output's directory is a file, so the shared key cannot be saved
+    let blocker = dir.path().join("not-a-dir");
+    fs::write(&blocker, b"").unwrap();
+    let output = hybridguard(&[
+        Path::new("pair"), Path::new("accept"), Path::new("--key-file"), &bob, &offer, Path::new("-o"), &blocker.join("b-shared.keys"),
+    ]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty(), "a reply went out for a key that was not saved");
+}
@@ -0,0 +1,80 @@
+// `encrypt --passphrase-only` writes a container that the passphrase alone
+// opens: decrypt recognises it without a key file, and inspect shows the
+// Argon2id cost and salt it carries
+
+mod support;
+
+use hybridguard::simple;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+const PASSPHRASE: &str = "tangerine-Lathe-93-orbit";
+
+/// Run hybridguard with `args`, typing `passphrase` at the prompt
+fn hybridguard(args: &[&Path], passphrase: &str) -> Output {
+    support::hybridguard_with_input(args, format!("{}\n", passphrase).as_bytes())
+}
+
+#[test]
+fn passphrase_alone_decrypts() {
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, enc, out) = (dir.path().join("note.txt"), dir.path().join("note.hg"), dir.path().join("out.txt"));
+    fs::write(&plain, b"the crate is under the third stair").unwrap();
+
+    let args = [Path::new("encrypt"), Path::new("--passphrase-only"), Path::new("-i"), &plain, Path::new("-o"), &enc];
+    let output = hybridguard(&args, PASSPHRASE);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("only as strong as its passphrase"));
+
+    // Inspect shows what the recipient's machine will run
+    let output = hybridguard(&[Path::new("inspect"), Path::new("--brief"), &enc], "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Passphrase: Argon2id m=19 MiB, t=2, p=1, salt "), "{}", stdout);
+    let output = hybridguard(&[Path::new("inspect"), &enc], "");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Passphrase: Argon2id m=19 MiB"));
+
+    let wrong = hybridguard(&[Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out], "not the passphrase");
+    assert_eq!(wrong.status.code(), Some(3), "{}", String::from_utf8_lossy(&wrong.stderr));
+    assert!(!out.exists());
+
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out], PASSPHRASE);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"the crate is under the third stair");
+}
+
+#[test]
+fn library_and_cli_containers_interoperate() {
+    let dir = tempfile::tempdir().unwrap();
+    let (enc, out) = (dir.path().join("lib.hg"), dir.path().join("out"));
+    fs::write(&enc, simple::encrypt(PASSPHRASE, b"from the library").unwrap()).unwrap();
+
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out], PASSPHRASE);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"from the library");
+}
+
+#[test]
+fn passphrase_only_refuses_key_files_and_weak_passphrases() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("note.txt");
+    fs::write(&plain, b"data").unwrap();
+
+    let keyed = [
+        Path::new("encrypt"),
+        Path::new("--passphrase-only"),
+        Path::new("-k"),
+        Path::new("work.keys"),
+        Path::new("-i"),
+        &plain,
+        Path::new("-o"),
+        &dir.path().join("keyed.hg"),
+    ];
+    assert_eq!(hybridguard(&keyed, PASSPHRASE).status.code(), Some(2));
+
+    let weak = dir.path().join("weak.hg");
+    let args = [Path::new("encrypt"), Path::new("--passphrase-only"), Path::new("-i"), &plain, Path::new("-o"), &weak];
+    let output = hybridguard(&args, "password123");
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!weak.exists());
+}
@@ -0,0 +1,67 @@
+// `hybridguard keygen` password strength checks and organizational policy
+
+mod support;
+
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+/// Run `keygen` with `password` typed at the prompt
+fn keygen(args: &[&Path], password: &str) -> Output {
+    let args = [&[Path::new("keygen")][..], args].concat();
+    support::hybridguard_with_input(&args, format!("{}\n", password).as_bytes())
+}
+
+#[test]
+fn weak_password_is_refused_unless_allowed() {
+    let dir = tempfile::tempdir().unwrap();
+    let keystore = dir.path().join("keys");
+
+    let output = keygen(&[Path::new("-o"), &keystore], "password123");
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("too weak"));
+    assert!(!keystore.join("hybridguard.keys").exists());
+
+    let output = keygen(&[Path::new("-o"), &keystore, Path::new("--allow-weak-password")], "password123");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("weak password accepted"));
+    assert!(keystore.join("hybridguard.keys").exists());
+}
+
+#[test]
+fn strong_password_shows_crack_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let keystore = dir.path().join("keys");
+
+    let output = keygen(&[Path::new("-o"), &keystore], "plinth-Ocelot-47-marmalade");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("score 4/4, cracked offline in centuries"));
+
+    // A lower threshold admits a middling password
+    let output = keygen(&[Path::new("-o"), &keystore, Path::new("--min-password-score"), Path::new("1")], "Summer2024!");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn policy_file_denylist_cannot_be_overridden() {
+    let dir = tempfile::tempdir().unwrap();
+    let keystore = dir.path().join("keys");
+    let policy = dir.path().join("policy.json");
+    fs::write(&policy, r#"{"min_length": 16, "denylist": ["Plinth-Ocelot-47-Marmalade"]}"#).unwrap();
+
+    for password in ["plinth-ocelot-47-marmalade", "PLINTH-OCELOT-47-MARMALADE"] {
+        let output = keygen(
+            &[Path::new("-o"), &keystore, Path::new("--password-policy"), &policy, Path::new("--allow-weak-password")],
+            password,
+        );
+        assert_eq!(output.status.code(), Some(7));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("denylist"));
+    }
+
+    // Too short for the policy even though it scores 4
+    let output = keygen(&[Path::new("-o"), &keystore, Path::new("--password-policy"), &policy], "vT8#qLm2!xR9@wZ");
+    assert_eq!(output.status.code(), Some(7));
+
+    let output = keygen(&[Path::new("-o"), &keystore, Path::new("--password-policy"), &policy], "correct horse battery staple");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+}
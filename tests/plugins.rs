@@ -0,0 +1,63 @@
+// Out-of-tree layers loaded through the plugin ABI
+#![cfg(feature = "plugins")]
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::{DecryptErrorMode, HybridGuard, KeyManager};
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Build the example plugin crate and return the path of its library
+fn example_plugin() -> PathBuf {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("plugins/example-layer/Cargo.toml");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("example-layer");
+    let status = Command::new(env!("CARGO"))
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "building the example plugin failed");
+    target_dir.join("debug").join(format!("{}hg_example_layer{}", DLL_PREFIX, DLL_SUFFIX))
+}
+
+#[test]
+fn example_plugin_round_trips_as_an_extra_layer() {
+    let plugin = example_plugin();
+    let keys = || KeyManager::from_master_key(&[0x58; 32]).unwrap();
+    let guard = HybridGuard::builder(keys())
+        .with_decrypt_errors(DecryptErrorMode::Verbose)
+        .with_plugin(&plugin)
+        .unwrap()
+        .build().unwrap();
+
+    let names: Vec<String> = guard.descriptors().into_iter().map(|d| d.name).collect();
+    assert_eq!(names[2], "Example-XOR");
+
+    let encrypted = guard.encrypt(b"slotted in as layer 2.5").unwrap();
+    assert!(encrypted.descriptors().iter().any(|d| d.name == "Example-XOR" && d.version == 1));
+    assert_eq!(guard.decrypt(&encrypted).unwrap(), b"slotted in as layer 2.5");
+
+    // The container format round-trips the descriptor
+    let reread = hybridguard::crypto::EncryptedData::from_bytes(&encrypted.to_bytes().unwrap()).unwrap();
+    assert_eq!(guard.decrypt(&reread).unwrap(), b"slotted in as layer 2.5");
+
+    // Without the plugin the ciphertext is refused, not misdecrypted
+    let without = HybridGuard::builder(keys()).build().unwrap();
+    let err = without.decrypt(&reread).unwrap_err();
+    assert!(matches!(err, HybridGuardError::UnsupportedFormat(ref m) if m.contains("Example-XOR")), "{}", err);
+
+    // Nothing is loaded implicitly, and a second copy of the layer is refused
+    assert!(HybridGuard::builder(keys()).with_plugin(&plugin).unwrap().with_plugin(&plugin).is_err());
+}
+
+#[test]
+fn libraries_without_the_abi_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let fake = dir.path().join(format!("{}not_a_plugin{}", DLL_PREFIX, DLL_SUFFIX));
+    std::fs::write(&fake, b"not a shared library").unwrap();
+    let err = HybridGuard::builder(KeyManager::from_master_key(&[0x59; 32]).unwrap()).with_plugin(&fake).err().unwrap();
+    assert!(matches!(err, HybridGuardError::UnsupportedFormat(_)));
+}
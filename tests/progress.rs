@@ -0,0 +1,100 @@
+// --json-progress: encrypt and decrypt report their operation states in a legal order
+
+mod support;
+
+use hybridguard::error::exit_code;
+use hybridguard::progress::{self, Direction, OperationState};
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+/// The states on stderr, checked against the transition grammar as they come
+fn states(output: &Output) -> Vec<OperationState> {
+    let mut states: Vec<OperationState> = Vec::new();
+    for line in String::from_utf8_lossy(&output.stderr).lines().filter(|line| line.starts_with('{')) {
+        let state: OperationState = serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+        assert!(progress::may_follow(states.last(), &state), "{:?} after {:?}", state, states.last());
+        states.push(state);
+    }
+    states
+}
+
+fn layers(states: &[OperationState]) -> Vec<(usize, Direction)> {
+    states
+        .iter()
+        .filter_map(|state| match state {
+            OperationState::Layer { index, direction, .. } => Some((*index, *direction)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn file_operations_follow_the_state_grammar() {
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, enc, out) = (dir.path().join("plain"), dir.path().join("plain.hg"), dir.path().join("out"));
+    let keys = dir.path().join("work.keys");
+    KeyManager::from_master_key(&[0x3C; 32]).unwrap().save(&keys).unwrap();
+    fs::write(&plain, vec![0x42; 5000]).unwrap();
+
+    let json = Path::new("--json-progress");
+    let output = hybridguard(&[json, Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("-k"), &keys]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let encrypted = states(&output);
+    let enc_len = fs::metadata(&enc).unwrap().len();
+    assert_eq!(encrypted.first(), Some(&OperationState::ResolvingKeys));
+    assert!(encrypted.contains(&OperationState::Reading { bytes: 5000 }));
+    assert_eq!(layers(&encrypted), (1..=4).map(|index| (index, Direction::Encrypt)).collect::<Vec<_>>());
+    assert!(encrypted.contains(&OperationState::Writing { bytes: enc_len }));
+    match encrypted.last() {
+        Some(OperationState::Done(summary)) => {
+            assert_eq!((summary.direction, summary.bytes_in, summary.bytes_out), (Direction::Encrypt, 5000, enc_len))
+        }
+        other => panic!("encryption ended in {:?}", other),
+    }
+
+    let output = hybridguard(&[json, Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out, Path::new("-k"), &keys]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let decrypted = states(&output);
+    assert_eq!(layers(&decrypted), (1..=4).rev().map(|index| (index, Direction::Decrypt)).collect::<Vec<_>>());
+    assert!(matches!(decrypted.last(), Some(OperationState::Done(summary)) if summary.bytes_out == 5000));
+    assert_eq!(fs::read(&out).unwrap(), vec![0x42; 5000]);
+}
+
+#[test]
+fn failures_end_the_operation() {
+    let dir = tempfile::tempdir().unwrap();
+    let (plain, enc, out) = (dir.path().join("plain"), dir.path().join("plain.hg"), dir.path().join("out"));
+    let keys = dir.path().join("work.keys");
+    KeyManager::from_master_key(&[0x3C; 32]).unwrap().save(&keys).unwrap();
+    fs::write(&plain, vec![0x42; 5000]).unwrap();
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("-k"), &keys]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Flip a ciphertext byte: the container still parses but fails its tag
+    let mut bytes = fs::read(&enc).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0x01;
+    fs::write(&enc, bytes).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("--json-progress"),
+        Path::new("decrypt"),
+        Path::new("-i"),
+        &enc,
+        Path::new("-o"),
+        &out,
+        Path::new("-k"),
+        &keys,
+    ]);
+    assert_eq!(output.status.code(), Some(exit_code::INTEGRITY as i32));
+    let states = states(&output);
+    assert!(layers(&states).is_empty());
+    match states.last() {
+        Some(OperationState::Failed(error)) => assert_eq!(error.exit_code, exit_code::INTEGRITY),
+        other => panic!("decryption ended in {:?}", other),
+    }
+    assert!(!out.exists());
+}
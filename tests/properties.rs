@@ -0,0 +1,133 @@
+// Property-based tests across every registered layer and the full pipeline
+
+use hybridguard::crypto::hkdf::KeyDerivation;
+use hybridguard::crypto::{container, EncryptedData};
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::layers::registry;
+use proptest::prelude::*;
+
+/// Lengths around the 32-byte block boundaries plus arbitrary small sizes
+fn interesting_len() -> impl Strategy<Value = usize> {
+    prop_oneof![
+        Just(0usize),
+        Just(1usize),
+        Just(31usize),
+        Just(32usize),
+        Just(33usize),
+        Just(63usize),
+        Just(64usize),
+        Just(65usize),
+        0usize..2048,
+    ]
+}
+
+fn plaintext() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        interesting_len().prop_map(|len| vec![0x00u8; len]),
+        interesting_len().prop_map(|len| vec![0xFFu8; len]),
+        interesting_len().prop_flat_map(|len| prop::collection::vec(any::<u8>(), len)),
+    ]
+}
+
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 32..=64)
+}
+
+/// Inputs of at least 16 bytes, so a garbled decryption or a ciphertext
+/// matching the original by chance is negligible
+fn non_trivial_plaintext() -> impl Strategy<Value = Vec<u8>> {
+    plaintext().prop_filter("non-trivial", |d| d.len() >= 16)
+}
+
+/// Flip one bit of `data`, chosen by `selector`
+fn flip_bit(data: &mut [u8], selector: usize) {
+    let bit = selector % (data.len() * 8);
+    data[bit / 8] ^= 1 << (bit % 8);
+}
+
+/// Flip one ciphertext bit in the serialized container, whose body starts
+/// with the length-prefixed ciphertext
+fn flip_ciphertext_bit(encrypted: &EncryptedData, selector: usize) -> EncryptedData {
+    let mut bytes = encrypted.to_bytes().unwrap();
+    let start = container::PREFIX_LEN + 8;
+    flip_bit(&mut bytes[start..start + encrypted.ciphertext().len()], selector);
+    EncryptedData::from_bytes(&bytes).unwrap()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn layer_round_trip(data in plaintext(), key in key()) {
+        for layer in registry() {
+            let ciphertext = layer.encrypt(&data, &key).unwrap();
+            let decrypted = layer.decrypt(&ciphertext, &key).unwrap();
+            prop_assert_eq!(&decrypted, &data, "layer {}", layer.name());
+        }
+    }
+
+    #[test]
+    fn layer_ciphertext_differs(data in non_trivial_plaintext(), key in key()) {
+        for layer in registry() {
+            let ciphertext = layer.encrypt(&data, &key).unwrap();
+            prop_assert_ne!(&ciphertext, &data, "layer {}", layer.name());
+        }
+    }
+
+    #[test]
+    fn layer_bit_flip_never_returns_plaintext(
+        data in non_trivial_plaintext(),
+        key in key(),
+        selector in any::<usize>(),
+    ) {
+        for layer in registry() {
+            let mut ciphertext = layer.encrypt(&data, &key).unwrap();
+            flip_bit(&mut ciphertext, selector);
+            if let Ok(decrypted) = layer.decrypt(&ciphertext, &key) {
+                prop_assert_ne!(&decrypted, &data, "layer {}", layer.name());
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline_round_trip(data in plaintext(), master in key()) {
+        let keys = KeyDerivation::new(master).derive_all_keys().unwrap();
+        let encryptor = HybridGuardEncryptor::new();
+
+        let encrypted = encryptor.encrypt(&data, &keys).unwrap();
+        prop_assert_eq!(encryptor.decrypt(&encrypted, &keys).unwrap(), data);
+    }
+
+    #[test]
+    fn pipeline_bit_flip_never_returns_plaintext(
+        data in non_trivial_plaintext(),
+        master in key(),
+        selector in any::<usize>(),
+    ) {
+        let keys = KeyDerivation::new(master).derive_all_keys().unwrap();
+        let encryptor = HybridGuardEncryptor::new();
+
+        let encrypted = encryptor.encrypt(&data, &keys).unwrap();
+        let encrypted = flip_ciphertext_bit(&encrypted, selector);
+        if let Ok(decrypted) = encryptor.decrypt(&encrypted, &keys) {
+            prop_assert_ne!(decrypted, data);
+        }
+    }
+}
+
+#[test]
+#[cfg_attr(not(feature = "slow-tests"), ignore)]
+fn multi_megabyte_round_trip() {
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+    let key = vec![0x5Au8; 32];
+
+    for layer in registry() {
+        let ciphertext = layer.encrypt(&data, &key).unwrap();
+        assert_eq!(layer.decrypt(&ciphertext, &key).unwrap(), data, "layer {}", layer.name());
+    }
+
+    let keys = KeyDerivation::new(key).derive_all_keys().unwrap();
+    let encryptor = HybridGuardEncryptor::new();
+    let encrypted = encryptor.encrypt(&data, &keys).unwrap();
+    assert_eq!(encryptor.decrypt(&encrypted, &keys).unwrap(), data);
+}
@@ -0,0 +1,151 @@
+// --protector: key files sealed at rest by a password or an HMAC secret file
+
+mod support;
+
+use hybridguard::error::exit_code;
+use std::fs;
+use std::path::Path;
+use support::{hybridguard, hybridguard_with_input};
+
+fn protector(spec: &str) -> [&Path; 2] {
+    [Path::new("--protector"), Path::new(spec)]
+}
+
+#[test]
+fn hmac_file_protector_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.key");
+    fs::write(&master, [0x5Au8, 0xA5].repeat(16)).unwrap();
+    let secret = dir.path().join("token.secret");
+    fs::write(&secret, [0x17u8; 32]).unwrap();
+    let spec = format!("hmac-file:{}", secret.display());
+    let keys_dir = dir.path().join("keys");
+    let keys = keys_dir.join("hybridguard.keys");
+
+    let keygen: [&Path; 5] = [Path::new("keygen"), Path::new("-o"), &keys_dir, Path::new("--from-master-key-file"), &master];
+    let output = hybridguard(&[&keygen[..], &protector(&spec)].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!fs::read_to_string(&keys).unwrap().contains("layer1_key"));
+
+    let input = dir.path().join("plain.txt");
+    fs::write(&input, b"sealed at rest").unwrap();
+    let encrypted = dir.path().join("plain.hg");
+    let restored = dir.path().join("plain.out");
+    let encrypt: [&Path; 7] = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted];
+    let output = hybridguard(&[&encrypt[..], &protector(&spec)].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let decrypt: [&Path; 7] = [Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored];
+    let output = hybridguard(&[&decrypt[..], &protector(&spec)].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"sealed at rest");
+
+    // The wrong secret does not unlock it
+    let wrong = dir.path().join("wrong.secret");
+    fs::write(&wrong, [0x18u8; 32]).unwrap();
+    let output = hybridguard(&[&decrypt[..], &protector(&format!("hmac-file:{}", wrong.display()))].concat());
+    assert_eq!(output.status.code(), Some(exit_code::KEY as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("HMAC secret does not unlock"));
+
+    // Nor does leaving the protector out, or naming another one
+    let output = hybridguard(&decrypt);
+    assert_eq!(output.status.code(), Some(exit_code::KEY as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("protected by the hmac-file protector"));
+    let output = hybridguard_with_input(&[&decrypt[..], &protector("password")].concat(), b"guess\n");
+    assert_eq!(output.status.code(), Some(exit_code::KEY as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not password"));
+}
+
+#[test]
+fn password_protector_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.key");
+    fs::write(&master, [0x3Cu8, 0xC3].repeat(16)).unwrap();
+    let keys_dir = dir.path().join("keys");
+    let keys = keys_dir.join("hybridguard.keys");
+
+    let keygen: [&Path; 5] = [Path::new("keygen"), Path::new("-o"), &keys_dir, Path::new("--from-master-key-file"), &master];
+    let output = hybridguard_with_input(&[&keygen[..], &protector("password")].concat(), b"key file passphrase\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let input = dir.path().join("plain.txt");
+    fs::write(&input, b"password sealed").unwrap();
+    let encrypted = dir.path().join("plain.hg");
+    let encrypt: [&Path; 7] = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted];
+    let output = hybridguard_with_input(&[&encrypt[..], &protector("password")].concat(), b"wrong passphrase\n");
+    assert_eq!(output.status.code(), Some(exit_code::KEY as i32));
+    assert!(!encrypted.exists());
+
+    let output = hybridguard_with_input(&[&encrypt[..], &protector("password")].concat(), b"key file passphrase\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn tuned_parameters_stretch_the_password_protector() {
+    let dir = tempfile::tempdir().unwrap();
+    let params = dir.path().join("argon2.json");
+
+    // A 1 ms target on any machine settles on the floor, which is never undercut
+    let tune: [&Path; 9] = [
+        Path::new("key"), Path::new("tune"), Path::new("--target-ms"), Path::new("1"), Path::new("--min-memory-mib"),
+        Path::new("19"), Path::new("--json"), Path::new("--save"), &params,
+    ];
+    let output = hybridguard(&tune);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["params"]["memory_kib"], 19 * 1024);
+    assert!(report["measured_ms"].as_f64().unwrap() > 0.0);
+    let output = hybridguard(&[Path::new("key"), Path::new("tune"), Path::new("--min-memory-mib"), Path::new("8")]);
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+
+    let master = dir.path().join("master.key");
+    fs::write(&master, [0x6Bu8, 0xB6].repeat(16)).unwrap();
+    let keys_dir = dir.path().join("keys");
+    let keys = keys_dir.join("hybridguard.keys");
+    let keygen: [&Path; 7] = [
+        Path::new("keygen"), Path::new("-o"), &keys_dir, Path::new("--from-master-key-file"), &master, Path::new("--kdf-params"), &params,
+    ];
+    let output = hybridguard_with_input(&[&keygen[..], &protector("password")].concat(), b"key file passphrase\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let sealed: serde_json::Value = serde_json::from_slice(&fs::read(&keys).unwrap()).unwrap();
+    assert_eq!(sealed["passphrase"]["params"], report["params"]);
+
+    // Opening reads the parameters from the file; no flag is needed
+    let input = dir.path().join("plain.txt");
+    fs::write(&input, b"stretched").unwrap();
+    let encrypt: [&Path; 7] = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &dir.path().join("plain.hg")];
+    let output = hybridguard_with_input(&[&encrypt[..], &protector("password")].concat(), b"key file passphrase\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The parameters only apply to the password protector
+    let output = hybridguard(&keygen);
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--protector password"));
+}
+
+#[test]
+fn piped_password_is_not_asked_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.key");
+    fs::write(&master, [0x4Du8, 0xD4].repeat(16)).unwrap();
+    let keys_dir = dir.path().join("keys");
+    let keys = keys_dir.join("hybridguard.keys");
+    let keygen: [&Path; 5] = [Path::new("keygen"), Path::new("-o"), &keys_dir, Path::new("--from-master-key-file"), &master];
+    let output = hybridguard_with_input(&[&keygen[..], &protector("password")].concat(), b"key file passphrase\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Stdin is no terminal, so the right password on the next line is never read
+    let input = dir.path().join("plain.txt");
+    fs::write(&input, b"typo").unwrap();
+    let encrypt: [&Path; 7] = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &dir.path().join("plain.hg")];
+    let typed = b"key file passphrse\nkey file passphrase\n";
+    for attempts in ["3", "5"] {
+        let args = [&encrypt[..], &protector("password"), &[Path::new("--password-attempts"), Path::new(attempts)]].concat();
+        let output = hybridguard_with_input(&args, typed);
+        assert_eq!(output.status.code(), Some(exit_code::KEY as i32));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("Enter key file password").count(), 1, "{}", stderr);
+        assert!(!stderr.contains("attempt(s) left"), "{}", stderr);
+    }
+    let output = hybridguard_with_input(&[&encrypt[..], &protector("password"), &[Path::new("--password-attempts"), Path::new("0")]].concat(), typed);
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+}
@@ -0,0 +1,83 @@
+// File names that are not UTF-8 survive encryption, decryption and JSON output
+#![cfg(unix)]
+
+mod support;
+
+use hybridguard::pathname::JsonPath;
+use hybridguard::KeyManager;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use support::hybridguard;
+
+/// Latin-1 and stray bytes, as left behind by old tools and other locales
+const NAMES: [&[u8]; 3] = [b"caf\xe9.txt", b"\xff\xfe-raw", b"plain.txt"];
+
+fn sorted_names(dir: &Path) -> Vec<OsString> {
+    let mut names: Vec<OsString> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn raw_names_round_trip_through_a_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("raw.keys");
+    KeyManager::from_master_key(&[0x52; 32]).unwrap().save(&keys).unwrap();
+    let (plain, sealed, restored) = (dir.path().join("plain"), dir.path().join("sealed"), dir.path().join("restored"));
+    for sub in [&plain, &sealed, &restored] {
+        fs::create_dir(sub).unwrap();
+    }
+    let inputs: Vec<PathBuf> = NAMES.iter().map(|name| plain.join(OsStr::from_bytes(name))).collect();
+    for (input, name) in inputs.iter().zip(NAMES) {
+        fs::write(input, name).unwrap();
+    }
+
+    let mut args = vec![Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-o"), &sealed, Path::new("-i")];
+    args.extend(inputs.iter().map(PathBuf::as_path));
+    let output = hybridguard(&args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let encrypted: Vec<PathBuf> = sorted_names(&sealed).into_iter().map(|name| sealed.join(name)).collect();
+    let mut args = vec![Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-o"), &restored, Path::new("-i")];
+    args.extend(encrypted.iter().map(PathBuf::as_path));
+    let output = hybridguard(&args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(sorted_names(&restored), sorted_names(&plain));
+    for name in NAMES {
+        assert_eq!(fs::read(restored.join(OsStr::from_bytes(name))).unwrap(), name);
+    }
+}
+
+#[test]
+fn json_reports_flag_raw_names_instead_of_mangling_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join(OsStr::from_bytes(b"r\xe9sum\xe9"));
+    let output_file = dir.path().join(OsStr::from_bytes(b"r\xe9sum\xe9.hg"));
+    fs::write(&input, b"curriculum vitae").unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--dry-run"), Path::new("--json"), Path::new("-i"), &input, Path::new("-o"), &output_file,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let file = &plan["files"][0];
+    assert!(file["input"]["base64"].is_string());
+    assert_eq!(file["input"]["lossy"].as_str().unwrap(), input.to_string_lossy());
+    let parsed: JsonPath = serde_json::from_value(file["input"].clone()).unwrap();
+    assert_eq!(parsed.to_path_buf().unwrap(), input);
+    let parsed: JsonPath = serde_json::from_value(file["output"].clone()).unwrap();
+    assert_eq!(parsed.to_path_buf().unwrap(), output_file);
+
+    // Scan reports name the same way
+    fs::write(&output_file, b"HGRD but not really a container").unwrap();
+    let output = hybridguard(&[Path::new("scan"), Path::new("--root"), dir.path(), Path::new("--json")]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let listed = report["hits"].as_array().unwrap().iter().chain(report["unscanned"].as_array().unwrap());
+    let paths: Vec<PathBuf> = listed
+        .map(|entry| serde_json::from_value::<JsonPath>(entry["path"].clone()).unwrap().to_path_buf().unwrap())
+        .collect();
+    assert!(paths.contains(&output_file), "{}", String::from_utf8_lossy(&output.stdout));
+}
@@ -0,0 +1,56 @@
+// Encrypting with --redundancy survives damaged shards on disk
+
+mod support;
+
+use hybridguard::storage::erasure::{self, HEADER_LEN, SHARD_PREFIX_LEN};
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn damage(file: &Path, shards: &[usize]) {
+    let mut bytes = fs::read(file).unwrap();
+    let layout = erasure::read_layout(&bytes).unwrap();
+    for &index in shards {
+        let offset = HEADER_LEN + index * (SHARD_PREFIX_LEN + layout.shard_len) + SHARD_PREFIX_LEN;
+        bytes[offset] ^= 0x5A;
+    }
+    fs::write(file, bytes).unwrap();
+}
+
+#[test]
+fn decrypt_repairs_up_to_m_damaged_shards() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x66; 32]).unwrap().save(&keys).unwrap();
+
+    let plaintext: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+    let input = dir.path().join("archive.tar");
+    let encrypted = dir.path().join("archive.tar.hg");
+    fs::write(&input, &plaintext).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--redundancy"), Path::new("4+2"),
+        Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    damage(&encrypted, &[1, 4]);
+    let decrypted = dir.path().join("repaired.tar");
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &decrypted,
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1, 4"));
+    assert_eq!(fs::read(&decrypted).unwrap(), plaintext);
+
+    // One more damaged shard than parity is unrecoverable
+    damage(&encrypted, &[0]);
+    let lost = dir.path().join("lost.tar");
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &lost,
+    ]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("shards 0, 1, 4"));
+    assert!(!lost.exists());
+}
@@ -0,0 +1,55 @@
+// `rekey-plan` lists the files under an old key ID and `rekey-apply` moves
+// them to the new key file, recording progress in the plan
+
+mod support;
+
+use hybridguard::rekey::{EntryStatus, RekeyPlan};
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn plan_then_apply_moves_every_file_to_the_new_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = dir.path().join("data");
+    fs::create_dir(&data).unwrap();
+    let old_keys = dir.path().join("old.keys");
+    let new_keys = dir.path().join("new.keys");
+    let old = KeyManager::from_master_key(&[0x61; 32]).unwrap();
+    old.save(&old_keys).unwrap();
+    KeyManager::from_master_key(&[0x62; 32]).unwrap().save(&new_keys).unwrap();
+    let old_id = old.key_id().to_string();
+    let guard = HybridGuard::builder(old).build().unwrap();
+    for name in ["a.hg", "b.hg"] {
+        fs::write(data.join(name), guard.encrypt(name.as_bytes()).unwrap().to_bytes().unwrap()).unwrap();
+    }
+
+    let plan = dir.path().join("plan.json");
+    let output = hybridguard(&[
+        Path::new("rekey-plan"),
+        Path::new("--root"),
+        &data,
+        Path::new("--from"),
+        Path::new(&old_id),
+        Path::new("--to"),
+        &new_keys,
+        Path::new("--plan"),
+        &plan,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("a.hg"));
+    assert_eq!(RekeyPlan::load(&plan).unwrap().entries.len(), 2);
+
+    for _ in 0..2 {
+        let output = hybridguard(&[Path::new("rekey-apply"), Path::new("--plan"), &plan, Path::new("-k"), &old_keys]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    let applied = RekeyPlan::load(&plan).unwrap();
+    assert!(applied.entries.iter().all(|entry| entry.status == EntryStatus::Done));
+
+    let plaintext = dir.path().join("a.txt");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &new_keys, Path::new("-i"), &data.join("a.hg"), Path::new("-o"), &plaintext]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&plaintext).unwrap(), b"a.hg");
+}
@@ -0,0 +1,59 @@
+// --nice, --ionice and --max-threads lower a run's priority and report what took effect
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn dry_run_reports_effective_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("a.txt");
+    fs::write(&input, b"alpha").unwrap();
+    let output_file = dir.path().join("a.txt.hg");
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--dry-run"), Path::new("--json"), Path::new("--nice"), Path::new("10"),
+        Path::new("--max-threads"), Path::new("1"), Path::new("-i"), &input, Path::new("-o"), &output_file,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(plan["resources"]["max_threads"], 1);
+    // Lowering the priority needs no privilege
+    if cfg!(unix) {
+        assert_eq!(plan["resources"]["nice"], 10);
+    }
+
+    // Without the flags, the plan says nothing about resources
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("--dry-run"), Path::new("--json"), Path::new("-i"), &input, Path::new("-o"), &output_file]);
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(plan.get("resources").is_none());
+}
+
+#[test]
+fn niced_run_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x4E; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("backup.tar");
+    fs::write(&input, b"background job").unwrap();
+    let encrypted = dir.path().join("backup.hg");
+    let restored = dir.path().join("backup.out");
+
+    let limits: [&Path; 6] = [Path::new("--nice"), Path::new("19"), Path::new("--ionice"), Path::new("idle"), Path::new("--max-threads"), Path::new("2")];
+    let encrypt: [&Path; 7] = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted];
+    let output = hybridguard(&[&encrypt[..], &limits].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let decrypt: [&Path; 7] = [Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored];
+    let output = hybridguard(&[&decrypt[..], &limits].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"background job");
+
+    // Raising priority is refused at parse time, as is a zero thread cap
+    let output = hybridguard(&[&encrypt[..], &[Path::new("--force"), Path::new("--nice"), Path::new("-5")]].concat());
+    assert_eq!(output.status.code(), Some(2));
+    let output = hybridguard(&[&encrypt[..], &[Path::new("--force"), Path::new("--max-threads"), Path::new("0")]].concat());
+    assert_eq!(output.status.code(), Some(2));
+}
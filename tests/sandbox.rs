@@ -0,0 +1,127 @@
+// decrypt --sandbox parses and decrypts untrusted input with no way left to
+// open files, connect or run programs; elsewhere it falls back to strict limits
+
+mod support;
+
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use support::hybridguard;
+
+#[test]
+fn sandboxed_decrypt_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x5B; 32]).unwrap().save(&key_file).unwrap();
+    let (plain, enc, out) = (dir.path().join("upload.csv"), dir.path().join("upload.hg"), dir.path().join("upload.out"));
+    fs::write(&plain, b"id,amount\n1,20\n").unwrap();
+
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &enc, Path::new("-k"), &key_file]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let decrypt = |input: &Path, output: &Path| {
+        hybridguard(&[Path::new("decrypt"), Path::new("--sandbox"), Path::new("-i"), input, Path::new("-o"), output, Path::new("-k"), &key_file])
+    };
+    let output = decrypt(&enc, &out);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"id,amount\n1,20\n");
+
+    // A tampered container fails inside the sandbox and leaves no output
+    let mut bytes = fs::read(&enc).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 1;
+    let (tampered, refused) = (dir.path().join("tampered.hg"), dir.path().join("tampered.out"));
+    fs::write(&tampered, &bytes).unwrap();
+    let output = decrypt(&tampered, &refused);
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!refused.exists());
+
+    // One file per run
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("--sandbox"), Path::new("-i"), &enc, Path::new("-i"), &tampered,
+        Path::new("-o"), dir.path(), Path::new("-k"), &key_file,
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn dry_run_reports_the_sandbox() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("test.keys");
+    let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x5C; 32]).unwrap()).build().unwrap();
+    KeyManager::from_master_key(&[0x5C; 32]).unwrap().save(&key_file).unwrap();
+    let enc = dir.path().join("a.hg");
+    fs::write(&enc, hg.encrypt(b"alpha").unwrap().to_bytes().unwrap()).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("--sandbox"), Path::new("--dry-run"), Path::new("--json"), Path::new("-i"), &enc,
+        Path::new("-o"), &dir.path().join("a.out"), Path::new("-k"), &key_file,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(plan["sandbox"]["strict_limits"], true);
+    if !cfg!(target_os = "linux") {
+        assert_eq!(plan["sandbox"]["sandbox"], false);
+    }
+}
+
+/// Runs twice: as a test it encrypts a message and re-runs itself as a child,
+/// which activates the sandbox, decrypts the message and then tries to reach
+/// the parent's listener and open, rename or remove a file
+#[cfg(target_os = "linux")]
+#[test]
+fn nothing_opens_or_connects_once_sandboxed() {
+    use hybridguard::crypto::EncryptedData;
+    use hybridguard::sandbox::{self, SandboxOptions};
+    use std::io::ErrorKind;
+    use std::net::{TcpListener, TcpStream};
+
+    // Set in the child to the ciphertext it decrypts
+    const CHILD: &str = "HYBRIDGUARD_SANDBOX_CHILD";
+    // Exit code of a child that found no sandbox to test
+    const NO_SANDBOX: i32 = 77;
+
+    if let Some(ciphertext) = std::env::var_os(CHILD) {
+        let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x5D; 32]).unwrap()).build().unwrap();
+        let bytes = fs::read(&ciphertext).unwrap();
+        let port = std::env::var("HYBRIDGUARD_SANDBOX_PORT").unwrap();
+        sandbox::warm_up(&hg).unwrap();
+
+        let report = sandbox::activate(&SandboxOptions::default());
+        if !report.sandbox {
+            eprintln!("no sandbox here: {:?}", report.skipped);
+            std::process::exit(NO_SANDBOX);
+        }
+        let encrypted = EncryptedData::from_bytes(&bytes).unwrap();
+        assert_eq!(hg.decrypt(&encrypted).unwrap(), b"untrusted upload");
+        let connected = TcpStream::connect(format!("127.0.0.1:{}", port));
+        assert_eq!(connected.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs::File::open(&ciphertext).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let moved = Path::new(&ciphertext).with_extension("moved");
+        assert_eq!(fs::rename(&ciphertext, &moved).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs::hard_link(&ciphertext, &moved).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs::remove_file(&ciphertext).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let hg = HybridGuard::builder(KeyManager::from_master_key(&[0x5D; 32]).unwrap()).build().unwrap();
+    let ciphertext = dir.path().join("upload.hg");
+    fs::write(&ciphertext, hg.encrypt(b"untrusted upload").unwrap().to_bytes().unwrap()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "nothing_opens_or_connects_once_sandboxed", "--test-threads=1", "--nocapture"])
+        .env(CHILD, &ciphertext)
+        .env("HYBRIDGUARD_SANDBOX_PORT", listener.local_addr().unwrap().port().to_string())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.code() == Some(NO_SANDBOX) {
+        eprintln!("skipped: {}", stderr);
+        return;
+    }
+    assert!(output.status.success(), "{}\n{}", String::from_utf8_lossy(&output.stdout), stderr);
+    listener.set_nonblocking(true).unwrap();
+    assert!(listener.accept().is_err(), "the sandboxed child connected");
+}
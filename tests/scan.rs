@@ -0,0 +1,211 @@
+// `scan` finds HybridGuard files of every format by their headers, without keys,
+// and tolerates paths it cannot read
+
+mod support;
+
+use hybridguard::cancel::CancellationToken;
+use hybridguard::crypto::encoding::{self, ArmorWriter, Encoding};
+use hybridguard::crypto::EncryptedData;
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::layers::{self, EncryptionLayer};
+use hybridguard::scan::{self, Predicate};
+use hybridguard::storage::erasure;
+use hybridguard::streaming::chunked;
+use hybridguard::KeyManager;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use support::hybridguard;
+
+/// Bare bincode of the original `EncryptedData`, all layers at format version 1
+fn v0_fixture(plaintext: &[u8], key_manager: &KeyManager) -> Vec<u8> {
+    let mut data = plaintext.to_vec();
+    for (layer, key) in layers::registry().iter().zip(key_manager.get_keys().unwrap().in_order()) {
+        data = layer.encrypt_version(&data, key, 1).unwrap();
+    }
+    let names: Vec<String> = layers::legacy_descriptors().into_iter().map(|d| d.name).collect();
+    bincode::serialize(&(data, names, "0.1.0", 1_600_000_000u64)).unwrap()
+}
+
+fn current(plaintext: &[u8], key_manager: &KeyManager) -> EncryptedData {
+    HybridGuardEncryptor::new().encrypt(plaintext, key_manager.get_keys().unwrap()).unwrap().with_key_id(key_manager.key_id())
+}
+
+/// Two legacy containers (one nested), a current one and a text file
+fn tree(root: &Path, key_manager: &KeyManager) {
+    fs::create_dir_all(root.join("archive/2020")).unwrap();
+    fs::write(root.join("archive/old.hg"), v0_fixture(b"old", key_manager)).unwrap();
+    fs::write(root.join("archive/2020/older.hg"), v0_fixture(b"older", key_manager)).unwrap();
+    fs::write(root.join("current.hg"), current(b"current", key_manager).to_bytes().unwrap()).unwrap();
+    fs::write(root.join("notes.txt"), b"not a ciphertext, and long enough to look like one").unwrap();
+}
+
+/// Sorted hit paths relative to `root`, and the containers read
+fn hits(root: &Path, predicate: Predicate) -> (Vec<PathBuf>, usize) {
+    let mut found = scan::find(root, predicate);
+    let mut paths: Vec<PathBuf> = found.by_ref().map(|hit| hit.path.strip_prefix(root).unwrap().to_path_buf()).collect();
+    paths.sort();
+    assert!(found.unscanned().is_empty(), "{:?}", found.unscanned());
+    (paths, found.containers())
+}
+
+#[test]
+fn predicates_select_by_layer_version_and_date() {
+    let dir = tempfile::tempdir().unwrap();
+    let km = KeyManager::from_master_key(&[0x5C; 32]).unwrap();
+    tree(dir.path(), &km);
+    let legacy = vec![PathBuf::from("archive/2020/older.hg"), PathBuf::from("archive/old.hg")];
+
+    let (all, containers) = hits(dir.path(), Predicate::default());
+    assert_eq!((all.len(), containers), (3, 3));
+
+    let hqc_v1 = Predicate { uses_layer: Some("HQC".to_string()), layer_version: Some(1), ..Predicate::default() };
+    assert_eq!(hits(dir.path(), hqc_v1).0, legacy);
+    let before = Predicate { older_than: Some(scan::parse_date("2025-01-01").unwrap()), ..Predicate::default() };
+    assert_eq!(hits(dir.path(), before).0, legacy);
+    let old_format = Predicate { format_below: Some(1), ..Predicate::default() };
+    assert_eq!(hits(dir.path(), old_format).0, legacy);
+    let ml_kem = Predicate { uses_layer: Some("ML-KEM".to_string()), ..Predicate::default() };
+    assert_eq!(hits(dir.path(), ml_kem).0.len(), 3);
+    let unknown = Predicate { uses_layer: Some("Kyber".to_string()), ..Predicate::default() };
+    assert!(hits(dir.path(), unknown).0.is_empty());
+}
+
+/// A directory holding a container that this user cannot list, if the
+/// platform and user allow making one (root reads everything)
+#[cfg(unix)]
+fn locked_dir(root: &Path, key_manager: &KeyManager) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let locked = root.join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::write(locked.join("hidden.hg"), v0_fixture(b"hidden", key_manager)).unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    fs::read_dir(&locked).is_err().then_some(locked)
+}
+
+#[cfg(not(unix))]
+fn locked_dir(_root: &Path, _key_manager: &KeyManager) -> Option<PathBuf> {
+    None
+}
+
+#[test]
+fn unreadable_paths_are_collected_and_the_walk_goes_on() {
+    let dir = tempfile::tempdir().unwrap();
+    let km = KeyManager::from_master_key(&[0x5D; 32]).unwrap();
+    tree(dir.path(), &km);
+    // An armored container is decoded to reach its header; a damaged one is listed, not missed
+    let armored = encoding::encode(&current(b"armored", &km), Encoding::Armor).unwrap();
+    fs::write(dir.path().join("armored.hg.asc"), &armored).unwrap();
+    fs::write(dir.path().join("damaged.hg.asc"), &armored[..armored.len() / 2]).unwrap();
+
+    let locked = locked_dir(dir.path(), &km);
+
+    let mut found = scan::find(dir.path(), Predicate::default());
+    // Listed when root can read the locked directory anyway
+    let expected = if cfg!(unix) && locked.is_none() { 5 } else { 4 };
+    assert_eq!(found.by_ref().count(), expected);
+    let unscanned: Vec<&Path> = found.unscanned().iter().map(|u| u.path.as_path()).collect();
+    assert!(unscanned.contains(&dir.path().join("damaged.hg.asc").as_path()), "{:?}", found.unscanned());
+    if let Some(locked) = &locked {
+        assert!(unscanned.contains(&locked.as_path()), "{:?}", found.unscanned());
+        fs::set_permissions(locked, fs::metadata(dir.path()).unwrap().permissions()).unwrap();
+    }
+
+    // A missing root is reported, not a panic
+    let mut missing = scan::find(&dir.path().join("gone"), Predicate::default());
+    assert_eq!(missing.next(), None);
+    assert_eq!(missing.unscanned().len(), 1);
+}
+
+#[test]
+fn scan_command_reports_and_rekeys_matches() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("data");
+    let old = KeyManager::from_master_key(&[0x5E; 32]).unwrap();
+    let new = KeyManager::from_master_key(&[0x5F; 32]).unwrap();
+    tree(&root, &old);
+    let (old_keys, new_keys) = (dir.path().join("old.keys"), dir.path().join("new.keys"));
+    old.save(&old_keys).unwrap();
+    new.save(&new_keys).unwrap();
+
+    let scan: [&Path; 7] = [
+        Path::new("scan"), Path::new("--root"), &root, Path::new("--uses-layer"), Path::new("HQC"),
+        Path::new("--older-than"), Path::new("2025-01-01"),
+    ];
+    let output = hybridguard(&[&scan[..], &[Path::new("--json")]].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["containers"], 3);
+    assert_eq!(report["hits"].as_array().unwrap().len(), 2);
+    assert_eq!(report["hits"][0]["matched_layers"][0]["name"], "HQC");
+
+    let rekey: [&Path; 5] = [Path::new("--rekey-with"), &new_keys, Path::new("-k"), &old_keys, Path::new("--json")];
+    let output = hybridguard(&[&scan[..], &rekey].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["rekeyed"], 2);
+
+    // The rekeyed files are current, under the new keys; the other is untouched
+    let rekeyed = EncryptedData::from_bytes(&fs::read(root.join("archive/2020/older.hg")).unwrap()).unwrap();
+    assert_eq!(rekeyed.key_id(), Some(new.key_id()));
+    let plaintext = HybridGuardEncryptor::new().decrypt(&rekeyed, &new.keys_for(&rekeyed).unwrap()).unwrap();
+    assert_eq!(plaintext, b"older");
+    let untouched = EncryptedData::from_bytes(&fs::read(root.join("current.hg")).unwrap()).unwrap();
+    assert_eq!(untouched.key_id(), Some(old.key_id()));
+    let output = hybridguard(&[&scan[..], &[Path::new("--json")]].concat());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(report["hits"].as_array().unwrap().is_empty());
+
+    // --rekey-with needs the key file the matches were made with
+    let output = hybridguard(&[&scan[..], &[Path::new("--rekey-with"), &new_keys]].concat());
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn scan_finds_and_rekeys_every_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("data");
+    fs::create_dir(&root).unwrap();
+    let old = KeyManager::from_master_key(&[0x60; 32]).unwrap();
+    let new = KeyManager::from_master_key(&[0x61; 32]).unwrap();
+    let (old_keys, new_keys) = (dir.path().join("old.keys"), dir.path().join("new.keys"));
+    old.save(&old_keys).unwrap();
+    new.save(&new_keys).unwrap();
+
+    let cancel = CancellationToken::new();
+    let mut stream = Vec::new();
+    chunked::encrypt_into(&mut &b"chunked"[..], 7, &mut stream, &old, 1, &cancel).unwrap();
+    fs::write(root.join("stream.hg"), &stream).unwrap();
+    let mut armored = ArmorWriter::new(Vec::new()).unwrap();
+    armored.write_all(&stream).unwrap();
+    fs::write(root.join("stream.hg.asc"), armored.finish().unwrap()).unwrap();
+    fs::write(root.join("record.hg.asc"), encoding::encode(&current(b"armored", &old), Encoding::Armor).unwrap()).unwrap();
+    fs::write(root.join("record.json"), encoding::encode(&current(b"json", &old), Encoding::Json).unwrap()).unwrap();
+    let sharded = erasure::encode(&current(b"sharded", &old).to_bytes().unwrap(), erasure::Redundancy::new(4, 2).unwrap()).unwrap();
+    fs::write(root.join("record.shards"), sharded).unwrap();
+
+    let scan: [&Path; 4] = [Path::new("scan"), Path::new("--root"), &root, Path::new("--json")];
+    let output = hybridguard(&scan);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["containers"], 5);
+    let mut formats: Vec<String> = report["hits"].as_array().unwrap().iter().map(|hit| hit["format"].as_str().unwrap().to_string()).collect();
+    formats.sort();
+    assert_eq!(formats, ["armor", "armored-chunked", "chunked", "json", "sharded"]);
+
+    let output = hybridguard(&[&scan[..], &[Path::new("--rekey-with"), &new_keys, Path::new("-k"), &old_keys]].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["rekeyed"], 5);
+
+    // Each file keeps its format, now under the new key
+    for hit in report["hits"].as_array().unwrap() {
+        let path = PathBuf::from(hit["path"].as_str().unwrap());
+        let header = scan::read_header(&path).unwrap().unwrap();
+        assert_eq!(header.format.to_string(), hit["format"].as_str().unwrap());
+        assert_eq!(header.key_id.as_deref(), Some(new.key_id()), "{}", path.display());
+    }
+    let mut restored = Vec::new();
+    chunked::decrypt_stream(&mut fs::File::open(root.join("stream.hg")).unwrap(), &mut restored, &new, &cancel).unwrap();
+    assert_eq!(restored, b"chunked");
+}
@@ -0,0 +1,108 @@
+// `serve --stdio` handshake and capability checks over pipes
+
+use hybridguard::crypto::container;
+use hybridguard::serve::{codes, Server, PROTOCOL_VERSION};
+use hybridguard::{HybridGuard, KeyManager};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+/// Send each request as one line and read one response line for it
+fn exchange<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, requests: &[Value]) -> Vec<Value> {
+    requests
+        .iter()
+        .map(|request| {
+            writeln!(writer, "{}", request).unwrap();
+            writer.flush().unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+#[test]
+fn handshake_and_refused_capability_over_in_process_pipes() {
+    use std::os::unix::net::UnixStream;
+
+    let (client, server_end) = UnixStream::pair().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0x92; 32]).unwrap();
+    let key_id = key_manager.key_id().to_string();
+    let server = std::thread::spawn(move || {
+        let mut server = Server::new(HybridGuard::builder(key_manager).build().unwrap());
+        server.serve(BufReader::new(server_end.try_clone().unwrap()), server_end).unwrap();
+    });
+
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+    let mut writer = client;
+    let replies = exchange(
+        &mut reader,
+        &mut writer,
+        &[
+            json!({"id": "a", "op": "encrypt", "data": "aGk="}),
+            json!({"id": "b", "op": "hello", "max_protocol": PROTOCOL_VERSION + 5}),
+            json!({"id": "c", "op": "encrypt", "data": "aGk=", "requires": ["signing"]}),
+            json!({"id": "d", "op": "encrypt", "data": "aGk=", "encoding": "armor"}),
+        ],
+    );
+
+    assert_eq!(replies[0]["error"]["code"], json!(codes::HANDSHAKE_REQUIRED));
+
+    let hello = &replies[1]["result"];
+    assert_eq!(replies[1]["id"], json!("b"));
+    assert_eq!(hello["protocol"], json!(PROTOCOL_VERSION));
+    assert_eq!(hello["crate_version"], json!(env!("CARGO_PKG_VERSION")));
+    assert_eq!(hello["key_id"], json!(key_id));
+    assert_eq!(hello["layers"].as_array().unwrap().len(), 4);
+    assert_eq!(hello["layers"][0]["id"], json!("ML-KEM-768"));
+    assert_eq!(hello["container_versions"]["writes"], json!(container::FORMAT_VERSION));
+    assert_eq!(hello["features"], json!({"armor": true, "compression": false, "streaming": false, "signing": false}));
+    assert_eq!(hello["limits"]["max_concurrent_ops"], json!(1));
+
+    // Declared unavailable, so refused rather than attempted
+    assert_eq!(replies[2]["ok"], json!(false));
+    assert_eq!(replies[2]["error"]["code"], json!(codes::CAPABILITY_UNAVAILABLE));
+    assert_eq!(replies[3]["ok"], json!(true), "{}", replies[3]);
+
+    // The reader shares the socket, so only shutting down the write half ends the session
+    writer.shutdown(std::net::Shutdown::Write).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn cli_serves_on_stdio() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x93; 32]).unwrap().save(&keys).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args(["serve", "--stdio", "-k"])
+        .arg(&keys)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to run hybridguard");
+    let mut writer = child.stdin.take().unwrap();
+    let mut reader = BufReader::new(child.stdout.take().unwrap());
+
+    let replies = exchange(
+        &mut reader,
+        &mut writer,
+        &[
+            json!({"op": "hello", "max_protocol": 0}),
+            json!({"op": "hello", "max_protocol": 1}),
+            json!({"op": "encrypt", "data": "c2VjcmV0"}),
+        ],
+    );
+    assert_eq!(replies[0]["error"]["code"], json!(codes::PROTOCOL_UNSUPPORTED));
+    assert_eq!(replies[1]["result"]["protocol"], json!(1));
+
+    let sealed = replies[2]["result"]["data"].clone();
+    let replies = exchange(&mut reader, &mut writer, &[json!({"op": "decrypt", "data": sealed})]);
+    assert_eq!(replies[0]["result"]["data"], json!("c2VjcmV0"));
+
+    drop(writer);
+    assert!(child.wait().unwrap().success());
+}
@@ -0,0 +1,101 @@
+// `encrypt --shape` writes a constant-rate stream that `decrypt` reads back
+
+mod support;
+
+use hybridguard::streaming::shaping::{self, PREFIX_LEN};
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn shaped_files_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("shape.keys");
+    let (plain, shaped, restored) = (
+        dir.path().join("plain"),
+        dir.path().join("plain.hg"),
+        dir.path().join("restored"),
+    );
+    KeyManager::from_master_key(&[0x73; 32])
+        .unwrap()
+        .save(&keys)
+        .unwrap();
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i % 239) as u8).collect();
+    fs::write(&plain, &data).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"),
+        Path::new("-k"),
+        &keys,
+        Path::new("-i"),
+        &plain,
+        Path::new("-o"),
+        &shaped,
+        Path::new("--shape"),
+        Path::new("2048@1ms"),
+    ]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stream = fs::read(&shaped).unwrap();
+    assert!(shaping::is_shaped(&stream));
+    // Frames follow the prefix and the header whose length ends it
+    let header_len = u32::from_le_bytes(stream[PREFIX_LEN - 4..PREFIX_LEN].try_into().unwrap()) as usize;
+    assert_eq!((stream.len() - PREFIX_LEN - header_len) % 2048, 0);
+
+    let output = hybridguard(&[
+        Path::new("decrypt"),
+        Path::new("-k"),
+        &keys,
+        Path::new("-i"),
+        &shaped,
+        Path::new("-o"),
+        &restored,
+    ]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&restored).unwrap(), data);
+
+    // Other keys are refused by the key ID in the stream header
+    let other = dir.path().join("other.keys");
+    KeyManager::from_master_key(&[0x74; 32])
+        .unwrap()
+        .save(&other)
+        .unwrap();
+    let output = hybridguard(&[
+        Path::new("decrypt"),
+        Path::new("-k"),
+        &other,
+        Path::new("-i"),
+        &shaped,
+        Path::new("-o"),
+        &dir.path().join("wrong"),
+        Path::new("--force"),
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn bad_shapes_are_usage_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("plain");
+    fs::write(&plain, b"x").unwrap();
+    for shape in ["4096", "8@1ms", "4096@0ms"] {
+        let output = hybridguard(&[
+            Path::new("encrypt"),
+            Path::new("-i"),
+            &plain,
+            Path::new("-o"),
+            &dir.path().join("out"),
+            Path::new("--shape"),
+            Path::new(shape),
+        ]);
+        assert_eq!(output.status.code(), Some(2), "{}", shape);
+    }
+}
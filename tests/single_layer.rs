@@ -0,0 +1,174 @@
+// Standalone use of individual layers with keys managed by the caller
+// Doubles as usage examples and as a check that the framing documented on
+// each layer type matches what the layer actually writes
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::layers::{registry, EncryptionLayer, FHELayer, HqcLayer, MlKemLayer, QuantumNoiseLayer, SealedMessage, KEM_LENGTH_PREFIX};
+use sha3::{Digest, Sha3_256};
+
+/// A key the application manages itself, e.g. unwrapped from its own keystore
+fn external_key(label: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"application master secret");
+    hasher.update(label);
+    hasher.finalize().to_vec()
+}
+
+#[test]
+fn test_mlkem_standalone() {
+    let layer = MlKemLayer::new();
+    let key = external_key(b"mlkem");
+    let message = b"only the KEM piece, not the whole stack";
+
+    let sealed = layer.seal(message, &key).unwrap();
+    assert_eq!(sealed.kem_ct.len(), 1088);
+    assert_eq!(layer.overhead_bytes().unwrap(), KEM_LENGTH_PREFIX + 1088);
+    assert_eq!(sealed.sym_ct.len(), message.len());
+    assert_eq!(layer.open(&sealed, &key).unwrap(), message);
+
+    // Length prefix and pair make up exactly what `encrypt` produces and `decrypt` reads
+    let framed = sealed.clone().into_bytes();
+    assert_eq!(framed.len(), layer.output_len(message.len()).unwrap());
+    assert_eq!(&framed[..4], &1088u32.to_be_bytes());
+    assert_eq!(&framed[4..1092], &sealed.kem_ct[..]);
+    assert_eq!(layer.decrypt(&framed, &key).unwrap(), message);
+
+    let encrypted = layer.encrypt(message, &key).unwrap();
+    assert_eq!(layer.open(&layer.split(&encrypted).unwrap(), &key).unwrap(), message);
+}
+
+#[test]
+fn test_hqc_standalone() {
+    let layer = HqcLayer::new();
+    let key = external_key(b"hqc");
+    let message = b"code-based KEM on its own";
+
+    let sealed = layer.seal(message, &key).unwrap();
+    assert_eq!(KEM_LENGTH_PREFIX + sealed.kem_ct.len(), layer.overhead_bytes().unwrap());
+    assert_eq!(sealed.sym_ct.len(), message.len());
+    assert_eq!(layer.decrypt(&sealed.clone().into_bytes(), &key).unwrap(), message);
+
+    let encrypted = layer.encrypt(message, &key).unwrap();
+    assert_eq!(KEM_LENGTH_PREFIX + layer.split(&encrypted).unwrap().kem_ct.len(), layer.overhead_bytes().unwrap());
+    assert_eq!(layer.open(&layer.split(&encrypted).unwrap(), &key).unwrap(), message);
+}
+
+#[test]
+fn test_open_rejects_malformed_pairs() {
+    let layer = MlKemLayer::new();
+    let key = external_key(b"mlkem");
+    let mut sealed = layer.seal(b"payload", &key).unwrap();
+    sealed.kem_ct.pop();
+    assert!(layer.open(&sealed, &key).is_err());
+
+    assert!(layer.split(&[0u8; 100]).is_err());
+    assert!(SealedMessage::split(&[1, 2, 3], 4).is_err());
+    assert!(SealedMessage::parse(&[0, 0, 0, 4, 1, 2, 3], 4).is_err());
+    assert_eq!(SealedMessage::parse(&[0, 0, 0, 2, 1, 2, 3], 2).unwrap().sym_ct, vec![3]);
+}
+
+#[test]
+fn test_kem_length_prefix_is_checked() {
+    let key = external_key(b"prefix");
+    let kem_layers: [Box<dyn EncryptionLayer>; 2] = [Box::new(MlKemLayer::new()), Box::new(HqcLayer::new())];
+    for layer in &kem_layers {
+        let encrypted = layer.encrypt(b"framed payload", &key).unwrap();
+        let announced = u32::from_be_bytes(encrypted[..4].try_into().unwrap());
+
+        // Implausible lengths and lengths from another parameter set are both refused
+        for bad in [0, u32::MAX, announced + 1, announced - 1] {
+            let mut corrupted = encrypted.clone();
+            corrupted[..4].copy_from_slice(&bad.to_be_bytes());
+            let err = layer.decrypt(&corrupted, &key).unwrap_err();
+            assert!(matches!(err, HybridGuardError::DecryptionError(_)), "layer {}: {}", layer.name(), err);
+        }
+        assert!(layer.decrypt(&encrypted[..3], &key).is_err());
+    }
+}
+
+#[test]
+fn test_kem_layers_read_unframed_v2() {
+    let key = external_key(b"legacy");
+    let message = LEGACY_MESSAGE;
+    let kem_layers: [Box<dyn EncryptionLayer>; 2] = [Box::new(MlKemLayer::new()), Box::new(HqcLayer::new())];
+    for layer in &kem_layers {
+        let overhead = layer.overhead_bytes().unwrap();
+        let legacy = layer.encrypt_version(message, &key, 2).unwrap();
+        assert_eq!(legacy.len(), overhead - KEM_LENGTH_PREFIX + message.len());
+        assert_eq!(layer.decrypt_version(&legacy, &key, 2).unwrap(), message);
+
+        // Streaming follows the descriptor version too
+        let mut state = layer.begin_decrypt(&key, 2).unwrap();
+        let mut plaintext = Vec::new();
+        for chunk in legacy.chunks(1000) {
+            plaintext.extend(state.process_chunk(chunk).unwrap());
+        }
+        plaintext.extend(state.finish().unwrap());
+        assert_eq!(plaintext, message);
+
+        // Without the descriptor version, legacy bytes fail the prefix check
+        assert!(layer.decrypt(&legacy, &key).is_err());
+    }
+}
+
+/// Output of a KEM layer's `encrypt` from before the length prefix (format v2),
+/// recorded at the parent of the commit that added it for LEGACY_MESSAGE
+/// under `external_key(b"legacy")`; unlike `encrypt_version(.., 2)`, no code
+/// of the current format had a hand in it
+fn pre_framing_fixture(name: &str) -> Vec<u8> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("kem-v2-{}.bin", name));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}; record it with the pre-framing layer and commit it", path.display(), e))
+}
+
+const LEGACY_MESSAGE: &[u8] = b"written before KEM ciphertexts were length-prefixed";
+
+#[test]
+fn test_kem_layers_read_pre_framing_fixtures() {
+    let key = external_key(b"legacy");
+    let kem_layers: [(&str, Box<dyn EncryptionLayer>); 2] = [("mlkem", Box::new(MlKemLayer::new())), ("hqc", Box::new(HqcLayer::new()))];
+    for (name, layer) in &kem_layers {
+        let fixture = pre_framing_fixture(name);
+        assert_eq!(fixture.len(), layer.overhead_bytes().unwrap() - KEM_LENGTH_PREFIX + LEGACY_MESSAGE.len(), "{}", name);
+        assert_eq!(layer.decrypt_version(&fixture, &key, 2).unwrap(), LEGACY_MESSAGE, "{}", name);
+        assert!(layer.decrypt(&fixture, &key).is_err(), "{}", name);
+    }
+}
+
+#[test]
+fn test_keyed_layers_framing() {
+    let key = external_key(b"symmetric");
+    let message = vec![0xA5u8; 40];
+
+    // Noise: no header, same length
+    let noise = QuantumNoiseLayer::new();
+    let noisy = noise.encrypt(&message, &key).unwrap();
+    assert_eq!(noisy.len(), message.len());
+    assert_eq!(noise.overhead_bytes().unwrap(), 0);
+    assert_eq!(noise.decrypt(&noisy, &key).unwrap(), message);
+
+    // FHE: no header, padded to the next multiple of 32
+    let fhe = FHELayer::new();
+    for len in [0, 31, 32, 40] {
+        let out = fhe.encrypt(&message[..len], &key).unwrap();
+        assert_eq!(out.len() % 32, 0);
+        assert!(out.len() > len && out.len() - len <= 32);
+        assert_eq!(fhe.decrypt(&out, &key).unwrap(), &message[..len]);
+    }
+    assert!(fhe.encrypt(&message, &key[..16]).is_err());
+}
+
+#[test]
+fn test_overhead_bounds_every_layer() {
+    let key = external_key(b"bounds");
+    for layer in registry() {
+        let overhead = layer.overhead_bytes().unwrap();
+        let mut largest = 0;
+        for len in [0, 1, 31, 32, 1000] {
+            let out = layer.encrypt(&vec![7u8; len], &key).unwrap();
+            assert_eq!(out.len(), layer.output_len(len).unwrap(), "layer {}", layer.name());
+            largest = largest.max(out.len() - len);
+        }
+        // The bound is tight: some input size reaches it
+        assert_eq!(largest, overhead, "layer {}", layer.name());
+    }
+}
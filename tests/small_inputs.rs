@@ -0,0 +1,36 @@
+// Every length from 0 to 130 bytes survives the full pipeline, through both
+// the container round trip and the bare encryptor
+
+use hybridguard::crypto::EncryptedData;
+use hybridguard::encryptor::HybridGuardEncryptor;
+use hybridguard::{HybridGuard, KeyManager};
+
+fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 + len) as u8).collect()
+}
+
+#[test]
+fn small_inputs_round_trip_through_containers() {
+    let guard = HybridGuard::builder(KeyManager::from_master_key(&[0x0B; 32]).unwrap()).build().unwrap();
+    let overhead = HybridGuardEncryptor::new().estimate_output_size(0).unwrap();
+    for len in 0..=130 {
+        let data = message(len);
+        let encrypted = guard.encrypt(&data).unwrap();
+        assert!(encrypted.ciphertext().len() >= overhead, "{} bytes", len);
+        let bytes = encrypted.to_bytes().unwrap();
+        assert_eq!(guard.decrypt(&EncryptedData::from_bytes(&bytes).unwrap()).unwrap(), data, "{} bytes", len);
+    }
+}
+
+#[test]
+fn small_inputs_round_trip_through_the_encryptor() {
+    let encryptor = HybridGuardEncryptor::new();
+    let keys = KeyManager::from_master_key(&[0x0C; 32]).unwrap();
+    let keys = keys.get_keys().unwrap();
+    for len in 0..=130 {
+        let data = message(len);
+        let encrypted = encryptor.encrypt(&data, keys).unwrap();
+        assert_eq!(encrypted.ciphertext().len(), encryptor.estimate_output_size(len).unwrap(), "{} bytes", len);
+        assert_eq!(encryptor.decrypt(&encrypted, keys).unwrap(), data, "{} bytes", len);
+    }
+}
@@ -0,0 +1,95 @@
+// encrypt --source-cmd runs a producer such as pg_dump itself, encrypts its
+// stdout as a chunked file and fails, leaving no output, whenever the
+// producer fails
+#![cfg(unix)]
+
+mod support;
+
+use hybridguard::error::exit_code;
+use hybridguard::streaming::chunked;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use std::time::{Duration, Instant};
+use support::hybridguard;
+
+fn encrypt_from(source: &str, output: &Path, key_file: &Path, extra: &[&str]) -> Output {
+    let mut args = vec![
+        Path::new("encrypt"), Path::new("--source-cmd"), Path::new(source), Path::new("--max-input-bytes"), Path::new("1048576"),
+        Path::new("-o"), output, Path::new("-k"), key_file,
+    ];
+    args.extend(extra.iter().map(Path::new));
+    hybridguard(&args)
+}
+
+#[test]
+fn producer_output_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("backup.keys");
+    KeyManager::from_master_key(&[0x66; 32]).unwrap().save(&key_file).unwrap();
+    let (enc, out) = (dir.path().join("dump.hg"), dir.path().join("dump.sql"));
+
+    let output = encrypt_from("printf 'COPY t (id) FROM stdin;\\n1\\n'; echo 'pg_dump: dumping contents' >&2", &enc, &key_file, &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let header = chunked::read_header(&mut fs::File::open(&enc).unwrap()).unwrap();
+    assert_eq!(header.plaintext_len, 26);
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-i"), &enc, Path::new("-o"), &out, Path::new("-k"), &key_file]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), b"COPY t (id) FROM stdin;\n1\n");
+
+    // The producer's output needs a cap, and there is one output file
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--source-cmd"), Path::new("printf x"), Path::new("-o"), &enc, Path::new("-k"), &key_file,
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = encrypt_from("printf x", dir.path(), &key_file, &[]);
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn producer_failing_mid_stream_fails_the_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("backup.keys");
+    KeyManager::from_master_key(&[0x67; 32]).unwrap().save(&key_file).unwrap();
+    let enc = dir.path().join("dump.hg");
+
+    let source = "printf 'COPY t (id) FROM stdin;\\n'; echo 'pg_dump: error: connection to server lost' >&2; exit 3";
+    let output = encrypt_from(source, &enc, &key_file, &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("exited with status 3"), "{}", stderr);
+    assert!(stderr.contains("connection to server lost"), "{}", stderr);
+    assert!(!enc.exists());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "a staged file was left behind");
+}
+
+#[test]
+fn hanging_producer_is_killed_at_the_timeout() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("backup.keys");
+    KeyManager::from_master_key(&[0x68; 32]).unwrap().save(&key_file).unwrap();
+    let enc = dir.path().join("dump.hg");
+
+    let started = Instant::now();
+    let output = encrypt_from("printf 'COPY t'; sleep 60", &enc, &key_file, &["--source-timeout", "1"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("--source-timeout 1s"), "{}", stderr);
+    assert!(started.elapsed() < Duration::from_secs(30));
+    assert!(!enc.exists());
+}
+
+#[test]
+fn producer_writing_past_the_cap_leaves_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("backup.keys");
+    KeyManager::from_master_key(&[0x69; 32]).unwrap().save(&key_file).unwrap();
+    let enc = dir.path().join("dump.hg");
+
+    let output = encrypt_from("head -c 2097152 /dev/zero", &enc, &key_file, &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(exit_code::POLICY as i32), "{}", stderr);
+    assert!(!enc.exists());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "a staged file was left behind");
+}
@@ -0,0 +1,93 @@
+// Sparse encryption round-trips disk images without filling their holes
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use support::hybridguard;
+
+const MIB: u64 = 1024 * 1024;
+
+/// 64 MiB image with two small data regions; everything else is a hole
+fn disk_image(path: &Path) -> Vec<(u64, Vec<u8>)> {
+    let regions = vec![
+        (0, vec![0xEB; 4096]),
+        (40 * MIB, (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>()),
+    ];
+    let mut file = File::create(path).unwrap();
+    file.set_len(64 * MIB).unwrap();
+    for (offset, data) in &regions {
+        file.seek(SeekFrom::Start(*offset)).unwrap();
+        file.write_all(data).unwrap();
+    }
+    file.sync_all().unwrap();
+    regions
+}
+
+#[cfg(unix)]
+fn allocated(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).unwrap().blocks() * 512
+}
+
+#[test]
+fn sparse_round_trip_keeps_holes() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x5E; 32]).unwrap().save(&keys).unwrap();
+
+    let image = dir.path().join("disk.img");
+    let encrypted = dir.path().join("disk.img.hg");
+    let restored = dir.path().join("restored.img");
+    disk_image(&image);
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--sparse"),
+        Path::new("-k"), &keys, Path::new("-i"), &image, Path::new("-o"), &encrypted,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), fs::read(&image).unwrap());
+
+    // Only meaningful where the filesystem actually stored the holes
+    #[cfg(unix)]
+    if allocated(&image) < 8 * MIB {
+        assert!(fs::metadata(&encrypted).unwrap().len() < 8 * MIB);
+        assert!(allocated(&restored) <= allocated(&image) + MIB);
+    }
+}
+
+#[test]
+fn tampered_sparse_file_writes_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x5F; 32]).unwrap().save(&keys).unwrap();
+
+    let image = dir.path().join("disk.img");
+    let encrypted = dir.path().join("disk.img.hg");
+    let restored = dir.path().join("restored.img");
+    disk_image(&image);
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--sparse"),
+        Path::new("-k"), &keys, Path::new("-i"), &image, Path::new("-o"), &encrypted,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut bytes = fs::read(&encrypted).unwrap();
+    let last = bytes.len() - 100;
+    bytes[last] ^= 0x01;
+    fs::write(&encrypted, bytes).unwrap();
+
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored,
+    ]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(!restored.exists());
+}
@@ -0,0 +1,131 @@
+// Special inputs: FIFOs and character devices are only encrypted with
+// `--allow-special` and a `--max-input-bytes` cap, directories are archived
+// but refuse options that do not apply to them, and nothing hangs waiting on
+// an input that never ends
+#![cfg(unix)]
+
+mod support;
+
+use hybridguard::streaming::chunked;
+use hybridguard::KeyManager;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::thread;
+use support::hybridguard;
+
+fn encrypt(input: &Path, output: &Path, key_file: &Path, extra: &[&Path]) -> Output {
+    let mut args = vec![Path::new("encrypt"), Path::new("-i"), input, Path::new("-o"), output, Path::new("-k"), key_file];
+    args.extend_from_slice(extra);
+    hybridguard(&args)
+}
+
+fn keys(dir: &Path) -> PathBuf {
+    let path = dir.join("work.keys");
+    KeyManager::from_master_key(&[0x4D; 32]).unwrap().save(&path).unwrap();
+    path
+}
+
+fn mkfifo(path: &Path) {
+    let name = CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o600) }, 0, "mkfifo failed");
+}
+
+/// Write `data` into the FIFO at `path` once a reader opens it; the reader
+/// may stop early, so a broken pipe is expected
+fn feed(path: &Path, data: Vec<u8>) -> thread::JoinHandle<()> {
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        let mut fifo = OpenOptions::new().write(true).open(path).unwrap();
+        let _ = fifo.write_all(&data);
+    })
+}
+
+#[test]
+fn fifos_are_refused_without_allow_special() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = keys(dir.path());
+    let (fifo, out) = (dir.path().join("fifo"), dir.path().join("out.hg"));
+    mkfifo(&fifo);
+
+    // No writer ever opens the FIFO: the input is judged without reading it
+    let output = encrypt(&fifo, &out, &key_file, &[]);
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is a FIFO") && stderr.contains("--allow-special"), "{}", stderr);
+    assert!(!out.exists());
+
+    // The flag is not accepted without a cap
+    let output = encrypt(&fifo, &out, &key_file, &[Path::new("--allow-special")]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!out.exists());
+}
+
+#[test]
+fn fifo_input_is_capped() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = keys(dir.path());
+    let (fifo, out, back) = (dir.path().join("fifo"), dir.path().join("out.hg"), dir.path().join("back"));
+    mkfifo(&fifo);
+    let special = [Path::new("--allow-special"), Path::new("--max-input-bytes"), Path::new("4096")];
+
+    let writer = feed(&fifo, b"streamed through a pipe".to_vec());
+    let output = encrypt(&fifo, &out, &key_file, &special);
+    writer.join().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // Streamed through a spool into a chunked file, never held in memory whole
+    assert_eq!(&fs::read(&out).unwrap()[..chunked::MAGIC.len()], chunked::MAGIC);
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-i"), &out, Path::new("-o"), &back, Path::new("-k"), &key_file]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&back).unwrap(), b"streamed through a pipe");
+
+    let over = dir.path().join("over.hg");
+    let before = fs::read_dir(dir.path()).unwrap().count();
+    let writer = feed(&fifo, vec![0x5A; 64 * 1024]);
+    let output = encrypt(&fifo, &over, &key_file, &special);
+    writer.join().unwrap();
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("limit is 4096"));
+    assert!(!over.exists());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), before, "the spool was left behind");
+}
+
+#[test]
+fn endless_devices_stop_at_the_cap() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = keys(dir.path());
+    let out = dir.path().join("zero.hg");
+    let zero = Path::new("/dev/zero");
+
+    let output = encrypt(zero, &out, &key_file, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("character device"));
+
+    let output = encrypt(zero, &out, &key_file, &[Path::new("--allow-special"), Path::new("--max-input-bytes"), Path::new("1000")]);
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!out.exists());
+}
+
+#[test]
+fn directories_refuse_options_for_single_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = keys(dir.path());
+    let tree = dir.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    fs::write(tree.join("a.txt"), b"a").unwrap();
+
+    let output = encrypt(&tree, &dir.path().join("tree.hg"), &key_file, &[Path::new("--sparse")]);
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is a directory") && stderr.contains("--sparse does not apply"), "{}", stderr);
+    assert!(!dir.path().join("tree.hg").exists());
+
+    // Regular files over the cap are refused before anything is read
+    let big = dir.path().join("big");
+    fs::write(&big, vec![1u8; 2048]).unwrap();
+    let output = encrypt(&big, &dir.path().join("big.hg"), &key_file, &[Path::new("--max-input-bytes"), Path::new("1024")]);
+    assert_eq!(output.status.code(), Some(7));
+}
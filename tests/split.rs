@@ -0,0 +1,202 @@
+// Split outputs decrypt only as the complete set, in order, and a set that is
+// not complete is refused before anything is written, naming every bad part
+
+mod support;
+
+use hybridguard::error::HybridGuardError;
+use hybridguard::fsutil::WriteOptions;
+use hybridguard::streaming::split::{self, SplitSet};
+use hybridguard::{CancellationToken, KeyManager};
+use std::fs;
+use std::path::{Path, PathBuf};
+use support::hybridguard;
+
+const SPLIT_SIZE: u64 = 64 * 1024;
+
+/// A split set of 300 KB under `dir`, its base output path and the plaintext
+fn encrypted_set(dir: &Path, key_manager: &KeyManager) -> (PathBuf, Vec<u8>) {
+    let input = dir.join("backup.tar");
+    let output = dir.join("backup.hgd");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(&input, &data).unwrap();
+    let stats = split::encrypt_file(&input, &output, key_manager, SPLIT_SIZE, &CancellationToken::new(), &WriteOptions::default()).unwrap();
+    assert!(stats.parts >= 5, "{} parts", stats.parts);
+    assert_eq!(stats.manifest, split::manifest_path(&output));
+    (output, data)
+}
+
+fn decrypt(input: &Path, restored: &Path, key_manager: &KeyManager) -> Result<Vec<u8>, HybridGuardError> {
+    split::decrypt_file_with(input, restored, key_manager, &CancellationToken::new(), &WriteOptions::default())?;
+    Ok(fs::read(restored).unwrap())
+}
+
+fn integrity_message(result: Result<Vec<u8>, HybridGuardError>) -> String {
+    match result {
+        Err(HybridGuardError::Integrity(message)) => message,
+        other => panic!("expected an integrity error, got {:?}", other.map(|data| data.len())),
+    }
+}
+
+#[test]
+fn set_decrypts_from_the_manifest_or_any_part() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xD1; 32]).unwrap();
+    let (output, data) = encrypted_set(dir.path(), &key_manager);
+    let restored = dir.path().join("restored.tar");
+
+    let set = SplitSet::open(&split::manifest_path(&output)).unwrap();
+    for (index, part) in set.part_paths().unwrap().iter().enumerate() {
+        assert_eq!(*part, split::part_path(&output, index as u32));
+        assert!(fs::metadata(part).unwrap().len() <= SPLIT_SIZE);
+    }
+    assert!(set.survey().unwrap().is_empty());
+
+    for input in [split::manifest_path(&output), split::part_path(&output, 0), split::part_path(&output, 3)] {
+        assert_eq!(decrypt(&input, &restored, &key_manager).unwrap(), data, "{}", input.display());
+    }
+}
+
+#[test]
+fn shuffled_parts_are_named() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xD2; 32]).unwrap();
+    let (output, _) = encrypted_set(dir.path(), &key_manager);
+    let (one, two) = (split::part_path(&output, 1), split::part_path(&output, 2));
+    let swap = dir.path().join("swap");
+    fs::rename(&one, &swap).unwrap();
+    fs::rename(&two, &one).unwrap();
+    fs::rename(&swap, &two).unwrap();
+
+    let restored = dir.path().join("restored.tar");
+    let message = integrity_message(decrypt(&split::manifest_path(&output), &restored, &key_manager));
+    assert!(message.contains("2 of "), "{}", message);
+    assert!(message.contains(&format!("{} holds part 2 instead of part 1", one.display())), "{}", message);
+    assert!(message.contains(&format!("{} holds part 1 instead of part 2", two.display())), "{}", message);
+    assert!(!restored.exists());
+}
+
+#[test]
+fn dropped_part_is_reported_before_decrypting() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xD3; 32]).unwrap();
+    let (output, _) = encrypted_set(dir.path(), &key_manager);
+    let dropped = split::part_path(&output, 3);
+    fs::remove_file(&dropped).unwrap();
+
+    let set = SplitSet::open(&split::part_path(&output, 0)).unwrap();
+    let issues = set.survey().unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].index, 3);
+    assert_eq!(issues[0].problem, split::PartProblem::Missing);
+
+    let restored = dir.path().join("restored.tar");
+    let message = integrity_message(decrypt(&split::part_path(&output, 0), &restored, &key_manager));
+    assert!(message.contains(&format!("{} (part 3) is missing", dropped.display())), "{}", message);
+    assert!(!restored.exists());
+
+    // A truncated last part is as unusable as a missing one
+    let last = split::part_path(&output, set.manifest().parts.len() as u32 - 1);
+    let bytes = fs::read(&last).unwrap();
+    fs::write(&last, &bytes[..bytes.len() - 1]).unwrap();
+    let issues = set.survey().unwrap();
+    assert_eq!(issues.len(), 2);
+    assert!(matches!(issues[1].problem, split::PartProblem::WrongSize { .. }));
+}
+
+#[test]
+fn corrupted_part_is_named() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xD4; 32]).unwrap();
+    let (output, _) = encrypted_set(dir.path(), &key_manager);
+    let corrupted = split::part_path(&output, 2);
+    let mut bytes = fs::read(&corrupted).unwrap();
+    bytes[1000] ^= 0x01;
+    fs::write(&corrupted, bytes).unwrap();
+
+    // Sizes and headers still look right; only the hashes tell
+    let set = SplitSet::open(&split::manifest_path(&output)).unwrap();
+    assert!(set.survey().unwrap().is_empty());
+    let restored = dir.path().join("restored.tar");
+    let message = integrity_message(decrypt(&split::manifest_path(&output), &restored, &key_manager));
+    assert!(message.contains("1 of "), "{}", message);
+    assert!(message.contains(&format!("{} (part 2) is corrupted", corrupted.display())), "{}", message);
+    assert!(!restored.exists());
+}
+
+#[test]
+fn parts_of_another_set_are_foreign() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xD5; 32]).unwrap();
+    let (output, _) = encrypted_set(dir.path(), &key_manager);
+    let other = tempfile::tempdir().unwrap();
+    let (other_output, _) = encrypted_set(other.path(), &key_manager);
+    fs::copy(split::part_path(&other_output, 1), split::part_path(&output, 1)).unwrap();
+
+    let set = SplitSet::open(&split::manifest_path(&output)).unwrap();
+    let issues = set.survey().unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].problem, split::PartProblem::Foreign);
+}
+
+#[test]
+fn tampered_manifest_fails_authentication() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_manager = KeyManager::from_master_key(&[0xD6; 32]).unwrap();
+    let (output, _) = encrypted_set(dir.path(), &key_manager);
+    let manifest = split::manifest_path(&output);
+    let mut record: serde_json::Value = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+    record["split_size"] = serde_json::json!(SPLIT_SIZE * 2);
+    fs::write(&manifest, serde_json::to_vec_pretty(&record).unwrap()).unwrap();
+
+    let restored = dir.path().join("restored.tar");
+    let message = integrity_message(decrypt(&manifest, &restored, &key_manager));
+    assert!(message.contains("split manifest failed authentication"), "{}", message);
+
+    // and another key is refused as such
+    let other = KeyManager::from_master_key(&[0xD7; 32]).unwrap();
+    assert!(matches!(decrypt(&split::part_path(&output, 0), &restored, &other), Err(HybridGuardError::KeyMismatch(_))));
+}
+
+#[test]
+fn cli_splits_and_refuses_an_incomplete_set() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0xD8; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("backup.tar");
+    let output = dir.path().join("backup.hgd");
+    let restored = dir.path().join("restored.tar");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 233) as u8).collect();
+    fs::write(&input, &data).unwrap();
+
+    let output_run = hybridguard(&[
+        Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &output,
+        Path::new("--split-size"), Path::new("64K"),
+    ]);
+    assert!(output_run.status.success(), "{}", String::from_utf8_lossy(&output_run.stderr));
+    assert!(split::manifest_path(&output).exists());
+    assert!(!output.exists());
+
+    let decrypt = [
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &split::part_path(&output, 0), Path::new("-o"), &restored,
+    ];
+    let run = hybridguard(&decrypt);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), data);
+    fs::remove_file(&restored).unwrap();
+
+    fs::remove_file(split::part_path(&output, 1)).unwrap();
+    let run = hybridguard(&decrypt);
+    assert_eq!(run.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&run.stderr).contains("(part 1) is missing"), "{}", String::from_utf8_lossy(&run.stderr));
+    assert!(!restored.exists());
+
+    // A shorter set written over it leaves none of the old parts behind
+    fs::write(&input, &data[..1000]).unwrap();
+    let run = hybridguard(&[
+        Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &output,
+        Path::new("--split-size"), Path::new("64K"), Path::new("--force"),
+    ]);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    let parts = SplitSet::open(&split::manifest_path(&output)).unwrap().manifest().parts.len() as u32;
+    assert!(!split::part_path(&output, parts).exists());
+}
@@ -0,0 +1,135 @@
+// --stable-read: files written during encryption are reread or refused, never mixed
+
+mod support;
+
+use hybridguard::crypto::EncryptedData;
+use hybridguard::error::exit_code;
+use hybridguard::stable_read::{self, StableRead};
+use hybridguard::{HybridGuardError, KeyManager};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use support::hybridguard;
+
+/// Append to `path` every 100µs until the returned flag is set
+fn grow_in_background(path: &Path) -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut log = OpenOptions::new().append(true).open(path).unwrap();
+    let start_len = fs::metadata(path).unwrap().len();
+    let flag = stop.clone();
+    let writer = thread::spawn(move || {
+        while !flag.load(Ordering::Relaxed) {
+            log.write_all(&[b'+'; 512]).unwrap();
+            thread::sleep(Duration::from_micros(100));
+        }
+    });
+    // Only read once the writer is demonstrably running
+    while fs::metadata(path).unwrap().len() == start_len {
+        thread::yield_now();
+    }
+    (stop, writer)
+}
+
+#[test]
+fn growing_file_fails_without_retries() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.log");
+    fs::write(&path, vec![b'.'; 4 << 20]).unwrap();
+
+    let (stop, writer) = grow_in_background(&path);
+    for snapshot_copy in [false, true] {
+        let result = stable_read::read_stable(&path, &StableRead { retries: 0, snapshot_copy });
+        assert!(matches!(result, Err(HybridGuardError::SourceChangedDuringRead(_))), "{:?}", result.map(|r| r.1));
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+
+    // Once the writer stops, a retry takes a consistent snapshot
+    let (data, snapshot) = stable_read::read_stable(&path, &StableRead::default()).unwrap();
+    assert_eq!(data.len() as u64, snapshot.len);
+    assert_eq!(snapshot.len, fs::metadata(&path).unwrap().len());
+    assert!(data.ends_with(b"+"));
+}
+
+#[test]
+fn cli_records_the_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x94; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("app.log");
+    fs::write(&input, b"line one\nline two\n").unwrap();
+    let expected = stable_read::snapshot_of(&fs::metadata(&input).unwrap()).unwrap();
+
+    let encrypted = dir.path().join("app.hg");
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("-k"), &keys, Path::new("--stable-read"), Path::new("--snapshot-copy"), Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let container = EncryptedData::from_bytes(&fs::read(&encrypted).unwrap()).unwrap();
+    assert_eq!(container.source_snapshot(), Some(&expected));
+
+    let output = hybridguard(&[Path::new("inspect"), Path::new("--brief"), Path::new("-i"), &encrypted]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Source snapshot: 18 bytes, modified at "));
+
+    let restored = dir.path().join("app.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"line one\nline two\n");
+
+    // A plain encrypt records nothing
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("--force"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success());
+    assert_eq!(EncryptedData::from_bytes(&fs::read(&encrypted).unwrap()).unwrap().source_snapshot(), None);
+}
+
+#[test]
+fn cli_refuses_a_growing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x95; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("live.log");
+    fs::write(&input, vec![b'.'; 4 << 20]).unwrap();
+    let encrypted = dir.path().join("live.hg");
+
+    let (stop, writer) = grow_in_background(&input);
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("-k"), &keys, Path::new("--stable-read"), Path::new("--stable-read-retries"), Path::new("0"),
+        Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+
+    assert_eq!(output.status.code(), Some(exit_code::IO as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Source changed during read"));
+    assert!(!encrypted.exists());
+}
+
+#[test]
+fn cli_warns_when_a_file_changes_without_stable_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x96; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("live.log");
+    fs::write(&input, vec![b'.'; 4 << 20]).unwrap();
+    let encrypted = dir.path().join("live.hg");
+    let encrypt = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &encrypted];
+
+    let (stop, writer) = grow_in_background(&input);
+    let warned = hybridguard(&encrypt);
+    let denied = hybridguard(&[&encrypt[..], &[Path::new("--force"), Path::new("--deny-warnings")]].concat());
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+
+    // The ciphertext is written, with a warning that it may be mixed
+    let stderr = String::from_utf8_lossy(&warned.stderr);
+    assert!(warned.status.success(), "{}", stderr);
+    assert!(stderr.lines().any(|line| line.starts_with("warning[HG005]: ") && line.contains("changed while it was read")), "{}", stderr);
+
+    let stderr = String::from_utf8_lossy(&denied.stderr);
+    assert_eq!(denied.status.code(), Some(exit_code::POLICY as i32), "{}", stderr);
+    assert!(stderr.contains("HG005"), "{}", stderr);
+}
@@ -0,0 +1,90 @@
+// --stats-file counts encrypt and decrypt runs locally, across concurrent
+// processes, and never fails a run over a damaged stats file
+
+mod support;
+
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use support::hybridguard;
+
+fn show_json(stats: &Path) -> serde_json::Value {
+    let output = hybridguard(&[Path::new("--stats-file"), stats, Path::new("stats"), Path::new("show"), Path::new("--json")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn concurrent_runs_are_all_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("stats.keys");
+    let stats = dir.path().join("stats.json");
+    KeyManager::from_master_key(&[0x53; 32]).unwrap().save(&keys).unwrap();
+
+    let runs: Vec<_> = (0..6)
+        .map(|i| {
+            let (dir, keys, stats) = (dir.path().to_path_buf(), keys.clone(), stats.clone());
+            thread::spawn(move || {
+                let input = dir.join(format!("plain-{}", i));
+                let output = dir.join(format!("plain-{}.hg", i));
+                fs::write(&input, vec![i as u8; 1000 * (i + 1)]).unwrap();
+                let result = hybridguard(&[
+                    Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &output,
+                    Path::new("--stats-file"), &stats,
+                ]);
+                assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+                fs::metadata(&output).unwrap().len()
+            })
+        })
+        .collect();
+    let written: u64 = runs.into_iter().map(|run| run.join().unwrap()).sum();
+
+    // A decrypt of something that is not a ciphertext counts as a failure by exit code
+    let bogus = dir.path().join("bogus.hg");
+    fs::write(&bogus, b"not a container").unwrap();
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &bogus, Path::new("-o"), &dir.path().join("bogus.out"),
+        Path::new("--stats-file"), &stats,
+    ]);
+    let code = output.status.code().unwrap();
+    assert_ne!(code, 0);
+
+    let counts = show_json(&stats);
+    assert_eq!(counts["operations"]["encrypt"]["runs"], 6);
+    assert_eq!(counts["operations"]["encrypt"]["files"], 6);
+    assert_eq!(counts["operations"]["encrypt"]["bytes_in"], 21_000);
+    assert_eq!(counts["operations"]["encrypt"]["bytes_out"], written);
+    assert_eq!(counts["operations"]["decrypt"]["runs"], 1);
+    assert_eq!(counts["failures"][code.to_string()], 1);
+    let histogram: u64 = counts["input_sizes"].as_object().unwrap().values().map(|n| n.as_u64().unwrap()).sum();
+    assert_eq!(histogram, 6);
+    assert!(!dir.path().join("stats.json.lock").exists());
+
+    let output = hybridguard(&[Path::new("--stats-file"), &stats, Path::new("stats"), Path::new("show")]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("6 run(s)"));
+}
+
+#[test]
+fn corrupt_stats_file_restarts_without_failing_the_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("stats.keys");
+    let stats = dir.path().join("stats.json");
+    let input = dir.path().join("plain");
+    KeyManager::from_master_key(&[0x54; 32]).unwrap().save(&keys).unwrap();
+    fs::write(&input, b"counted after all").unwrap();
+    fs::write(&stats, b"\x00\x01 definitely not json").unwrap();
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &input, Path::new("-o"), &dir.path().join("plain.hg"),
+        Path::new("--stats-file"), &stats,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unreadable"));
+    assert_eq!(fs::read(dir.path().join("stats.json.corrupt")).unwrap(), b"\x00\x01 definitely not json");
+    assert_eq!(show_json(&stats)["operations"]["encrypt"]["bytes_in"], 17);
+
+    // Without a stats file, show refuses instead of guessing a path
+    let output = hybridguard(&[Path::new("stats"), Path::new("show")]);
+    assert_eq!(output.status.code(), Some(2));
+}
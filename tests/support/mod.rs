@@ -0,0 +1,70 @@
+// Helpers shared by integration tests: running the CLI and comparing bytes
+// Every test crate compiles its own copy and uses only part of it
+#![allow(dead_code)]
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// Bytes per hexdump row
+const ROW_LEN: usize = 16;
+
+/// One hexdump row: offset, hex bytes and printable ASCII
+fn row(marker: char, offset: usize, bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let ascii: String = bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+    format!("{} {:08x}  {:<47}  |{}|", marker, offset, hex.join(" "), ascii)
+}
+
+/// Hexdump of the rows where `expected` and `actual` differ, `-` for
+/// expected and `+` for actual, stopping after `max_rows` differing rows
+pub fn hexdump_diff(expected: &[u8], actual: &[u8], max_rows: usize) -> String {
+    let mut out = String::new();
+    if expected.len() != actual.len() {
+        let _ = writeln!(out, "length: expected {} bytes, got {}", expected.len(), actual.len());
+    }
+
+    let rows = expected.len().max(actual.len()).div_ceil(ROW_LEN);
+    let mut shown = 0;
+    for index in 0..rows {
+        let range = |bytes: &[u8]| {
+            let start = (index * ROW_LEN).min(bytes.len());
+            let end = ((index + 1) * ROW_LEN).min(bytes.len());
+            bytes[start..end].to_vec()
+        };
+        let (want, got) = (range(expected), range(actual));
+        if want == got {
+            continue;
+        }
+        if shown == max_rows {
+            let _ = writeln!(out, "... more rows differ");
+            break;
+        }
+        let _ = writeln!(out, "{}", row('-', index * ROW_LEN, &want));
+        let _ = writeln!(out, "{}", row('+', index * ROW_LEN, &got));
+        shown += 1;
+    }
+    out
+}
+
+/// Run the hybridguard binary with `args`
+pub fn hybridguard(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args(args)
+        .output()
+        .expect("failed to run hybridguard")
+}
+
+/// Run with `input` on stdin, where passwords and passphrases are read from
+pub fn hybridguard_with_input(args: &[&Path], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run hybridguard");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
@@ -0,0 +1,78 @@
+// Outputs are staged in owner-only temporary files that never outlive a failure
+
+mod support;
+
+use hybridguard::streaming::chunked;
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+fn entries(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn failed_decrypt_leaves_no_plaintext_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("data.bin");
+    let encrypted = dir.path().join("data.hg");
+    fs::write(&input, vec![0x3C; 300_000]).unwrap();
+    let key_manager = KeyManager::from_master_key(&[0x81; 32]).unwrap();
+    chunked::encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+
+    // Every segment is decrypted, then the output cannot be put in place
+    let out = dir.path().join("out");
+    fs::create_dir_all(out.join("occupied")).unwrap();
+    let target = out.join("occupied");
+    assert!(chunked::decrypt_file(&encrypted, &target, &key_manager).is_err());
+    assert_eq!(entries(&out), ["occupied"]);
+    assert!(entries(&target).is_empty());
+
+    chunked::decrypt_file(&encrypted, &out.join("data.out"), &key_manager).unwrap();
+    assert_eq!(entries(&out), ["data.out", "occupied"]);
+}
+
+#[test]
+fn encrypt_stages_in_temp_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let staging = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x82; 32]).unwrap().save(&keys).unwrap();
+    let input = dir.path().join("report.txt");
+    fs::write(&input, b"quarterly numbers").unwrap();
+    let encrypted = dir.path().join("report.hg");
+
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("-k"), &keys, Path::new("--temp-dir"), staging.path(), Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(entries(staging.path()).is_empty());
+
+    let restored = dir.path().join("report.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"quarterly numbers");
+    assert_eq!(entries(dir.path()), ["report.hg", "report.out", "report.txt", "test.keys"]);
+
+    #[cfg(unix)]
+    {
+        // The output keeps the mode of the temporary file it was renamed from
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&restored).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    // Decrypting never takes a temp dir: plaintext stays beside its output
+    let output = hybridguard(&[
+        Path::new("decrypt"), Path::new("-k"), &keys, Path::new("--temp-dir"), staging.path(), Path::new("-i"), &encrypted, Path::new("-o"), &restored,
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let missing = dir.path().join("missing");
+    let output = hybridguard(&[
+        Path::new("encrypt"), Path::new("--force"), Path::new("-k"), &keys, Path::new("--temp-dir"), &missing, Path::new("-i"), &input, Path::new("-o"), &encrypted,
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,77 @@
+// Output volume at each verbosity level, and stdout kept free of chatter
+
+use hybridguard::KeyManager;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn encrypt_with(flags: &[&str], dir: &Path, keys: &Path) -> Output {
+    let input = dir.join("notes.txt");
+    fs::write(&input, b"quarterly numbers").unwrap();
+    Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args(flags)
+        .arg("encrypt")
+        .arg("-k")
+        .arg(keys)
+        .arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(dir.join("notes.txt.hg"))
+        .arg("--force")
+        .env_remove("RUST_LOG")
+        .output()
+        .expect("failed to run hybridguard")
+}
+
+fn stderr_lines(output: &Output) -> usize {
+    String::from_utf8_lossy(&output.stderr).lines().count()
+}
+
+#[test]
+fn each_level_adds_output_on_stderr_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    KeyManager::from_master_key(&[0x17; 32]).unwrap().save(&keys).unwrap();
+
+    let quiet = encrypt_with(&["-q"], dir.path(), &keys);
+    let normal = encrypt_with(&[], dir.path(), &keys);
+    let verbose = encrypt_with(&["-v"], dir.path(), &keys);
+    let debug = encrypt_with(&["-vv"], dir.path(), &keys);
+
+    for output in [&quiet, &normal, &verbose, &debug] {
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(output.stdout.is_empty(), "stdout: {}", String::from_utf8_lossy(&output.stdout));
+    }
+
+    assert_eq!(stderr_lines(&quiet), 0);
+    assert_eq!(stderr_lines(&normal), 1);
+    assert!(String::from_utf8_lossy(&normal.stderr).contains("Encrypted"));
+
+    let verbose_text = String::from_utf8_lossy(&verbose.stderr);
+    assert!(verbose_text.contains("HybridGuard v"));
+    assert!(verbose_text.contains("Layer 1"));
+    assert!(stderr_lines(&verbose) > stderr_lines(&normal));
+
+    assert!(String::from_utf8_lossy(&debug.stderr).contains("Layer 1 (ML-KEM)"));
+    assert!(stderr_lines(&debug) > stderr_lines(&verbose));
+}
+
+#[test]
+fn quiet_still_reports_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("missing.keys");
+
+    let output = encrypt_with(&["-q"], dir.path(), &keys);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(stderr_lines(&output) >= 1);
+}
+
+#[test]
+fn quiet_and_verbose_conflict() {
+    let output = Command::new(env!("CARGO_BIN_EXE_hybridguard"))
+        .args(["-q", "-v", "status"])
+        .output()
+        .expect("failed to run hybridguard");
+    assert_eq!(output.status.code(), Some(2));
+}
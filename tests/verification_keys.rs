@@ -0,0 +1,108 @@
+// Verification keys: an auditor checks ciphertexts it cannot decrypt, and
+// every path that needs layer keys refuses the file up front
+
+mod support;
+
+use hybridguard::crypto::container;
+use hybridguard::key_manager::verification::VerificationKey;
+use hybridguard::streaming::chunked;
+use hybridguard::verify;
+use hybridguard::{CancellationToken, HybridGuard, HybridGuardError, KeyManager};
+use std::fs;
+use std::path::Path;
+use support::hybridguard;
+
+/// Flip one bit at `offset` of the file at `path`
+fn tamper(path: &Path, offset: usize) {
+    let mut bytes = fs::read(path).unwrap();
+    bytes[offset] ^= 0x01;
+    fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn verification_key_detects_tampering_it_cannot_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let full = KeyManager::from_master_key(&[0x6A; 32]).unwrap();
+    let vk_path = dir.path().join("audit.vk");
+    full.export_verification_key().unwrap().save(&vk_path).unwrap();
+    let key = VerificationKey::load(&vk_path).unwrap();
+    assert_eq!(key.key_id(), full.key_id());
+
+    let container = dir.path().join("ledger.hg");
+    let encrypted = HybridGuard::builder(KeyManager::from_master_key(&[0x6A; 32]).unwrap()).build().unwrap().encrypt(b"ledger rows").unwrap();
+    assert!(encrypted.has_verification_tag());
+    fs::write(&container, encrypted.to_bytes().unwrap()).unwrap();
+    let report = verify::verify_integrity(&container, &key, &CancellationToken::new()).unwrap();
+    assert!(report.is_intact() && report.plaintext_len.is_none());
+
+    // A bit of the ciphertext itself, past the prefix and its length
+    tamper(&container, container::PREFIX_LEN + 8 + 100);
+    let report = verify::verify_integrity(&container, &key, &CancellationToken::new()).unwrap();
+    assert!(matches!(report.into_result(), Err(HybridGuardError::Integrity(_))));
+
+    let plain = dir.path().join("big.bin");
+    fs::write(&plain, vec![0x42u8; 300 * 1024]).unwrap();
+    let chunked_path = dir.path().join("big.hgc");
+    chunked::encrypt_file(&plain, &chunked_path, &full, 1).unwrap();
+    assert!(verify::verify_integrity(&chunked_path, &key, &CancellationToken::new()).unwrap().is_intact());
+    tamper(&chunked_path, 200 * 1024);
+    let report = verify::verify_integrity(&chunked_path, &key, &CancellationToken::new()).unwrap();
+    assert!(!report.is_intact());
+
+    // Another profile's verification key is told apart from damage
+    let other = KeyManager::from_master_key(&[0x6B; 32]).unwrap().export_verification_key().unwrap();
+    let result = verify::verify_integrity(&chunked_path, &other, &CancellationToken::new());
+    assert!(matches!(result, Err(HybridGuardError::KeyMismatch(_))));
+}
+
+#[test]
+fn verification_key_cannot_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.vk");
+    KeyManager::from_master_key(&[0x6C; 32]).unwrap().export_verification_key().unwrap().save(&path).unwrap();
+
+    let file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(file["body"]["capabilities"], serde_json::json!(["verify"]));
+    assert!(file["body"].get("layer1_key").is_none());
+    assert!(matches!(KeyManager::load(&path), Err(HybridGuardError::CapabilityDenied(_))));
+}
+
+#[test]
+fn cli_export_verify_and_refused_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let master = dir.path().join("master.bin");
+    fs::write(&master, [0x6D; 32]).unwrap();
+    let keystore = dir.path().join("keys");
+    let output = hybridguard(&[Path::new("keygen"), Path::new("-o"), &keystore, Path::new("--from-master-key-file"), &master]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let full = keystore.join("hybridguard.keys");
+
+    let vk = dir.path().join("audit.vk");
+    let output = hybridguard(&[
+        Path::new("key"), Path::new("export"), Path::new("-k"), &full, Path::new("--verification-key"), Path::new("-o"), &vk,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let input = dir.path().join("report.txt");
+    fs::write(&input, b"quarterly figures").unwrap();
+    let encrypted = dir.path().join("report.hg");
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("-k"), &full, Path::new("-i"), &input, Path::new("-o"), &encrypted]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let verify_args = [Path::new("verify"), Path::new("--verification-key"), &vk, Path::new("-i"), &encrypted];
+    let output = hybridguard(&verify_args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Plaintext checks need the key file
+    let deep = [Path::new("verify"), Path::new("--verification-key"), &vk, Path::new("--deep"), Path::new("--root"), dir.path()];
+    assert_eq!(hybridguard(&deep).status.code(), Some(2));
+
+    let restored = dir.path().join("report.out");
+    let output = hybridguard(&[Path::new("decrypt"), Path::new("-k"), &vk, Path::new("-i"), &encrypted, Path::new("-o"), &restored]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Capability denied"));
+    assert!(!restored.exists());
+
+    tamper(&encrypted, container::PREFIX_LEN + 8 + 100);
+    assert_eq!(hybridguard(&verify_args).status.code(), Some(4));
+}
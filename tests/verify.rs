@@ -0,0 +1,167 @@
+// `verify` authenticates a file without writing its plaintext; `--quick`
+// checks only a chunked file's Merkle index, and `--deep` decrypts a tree
+
+mod support;
+
+use hybridguard::streaming::chunked;
+use hybridguard::{HybridGuard, KeyManager};
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use support::hybridguard;
+
+fn verify(input: &Path, keys: &Path, quick: bool) -> Output {
+    let mut args = vec![Path::new("verify"), Path::new("-k"), keys, Path::new("-i"), input];
+    if quick {
+        args.push(Path::new("--quick"));
+    }
+    hybridguard(&args)
+}
+
+#[test]
+fn quick_checks_the_index_and_full_checks_the_payload() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let key_manager = KeyManager::from_master_key(&[0xBE; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+
+    let input = dir.path().join("dataset.bin");
+    let encrypted = dir.path().join("dataset.hgd");
+    fs::write(&input, vec![0x3D; 200_000]).unwrap();
+    chunked::encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+    for quick in [true, false] {
+        let output = verify(&encrypted, &keys, quick);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    // A flipped payload byte leaves the index consistent
+    let mut bytes = fs::read(&encrypted).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 1;
+    fs::write(&encrypted, &bytes).unwrap();
+    assert!(verify(&encrypted, &keys, true).status.success());
+    assert_eq!(verify(&encrypted, &keys, false).status.code(), Some(4));
+
+    // A flipped root tag does not
+    bytes[middle] ^= 1;
+    let root_tag = bytes.len() - 40;
+    bytes[root_tag] ^= 1;
+    fs::write(&encrypted, &bytes).unwrap();
+    assert_eq!(verify(&encrypted, &keys, true).status.code(), Some(4));
+}
+
+#[test]
+fn single_containers_verify_in_full_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let key_manager = KeyManager::from_master_key(&[0xBF; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+    let single = dir.path().join("small.hg");
+    let encrypted = HybridGuard::builder(key_manager).build().unwrap().encrypt(b"a small file").unwrap();
+    fs::write(&single, encrypted.to_bytes().unwrap()).unwrap();
+
+    let output = verify(&single, &keys, false);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty());
+    let output = verify(&single, &keys, true);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no segment index"));
+}
+
+#[test]
+fn full_verify_lists_every_damaged_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("test.keys");
+    let key_manager = KeyManager::from_master_key(&[0xC1; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+    let input = dir.path().join("dataset.bin");
+    let encrypted = dir.path().join("dataset.hgd");
+    fs::write(&input, vec![0x3E; 200_000]).unwrap();
+    let stats = chunked::encrypt_file(&input, &encrypted, &key_manager, 1).unwrap();
+    assert_eq!(stats.segments, 4);
+
+    // Damage the first and the last segment
+    let mut bytes = fs::read(&encrypted).unwrap();
+    bytes[2000] ^= 1;
+    let last = bytes.len() - 3000;
+    bytes[last] ^= 1;
+    fs::write(&encrypted, &bytes).unwrap();
+
+    let output = verify(&encrypted, &keys, false);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("damaged segment 0 at bytes"), "{}", stderr);
+    assert!(stderr.contains("damaged segment 3 at bytes"), "{}", stderr);
+
+    let output = hybridguard(&[Path::new("verify"), Path::new("-k"), &keys, Path::new("-i"), &encrypted, Path::new("--max-report"), Path::new("1")]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(!stderr.contains("damaged segment 3"), "{}", stderr);
+    assert!(stderr.contains("1 more damaged region(s)"), "{}", stderr);
+}
+
+#[test]
+fn deep_verify_reports_every_file_and_fails_at_the_end() {
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("drill.keys");
+    let key_manager = KeyManager::from_master_key(&[0xC0; 32]).unwrap();
+    key_manager.save(&keys).unwrap();
+
+    // Two single containers, one chunked file, one plain file and one corrupted container
+    let root = dir.path().join("backups");
+    fs::create_dir_all(root.join("2025/q1")).unwrap();
+    let plain = dir.path().join("plain");
+    for (name, fill) in [("ledger.hg", 0x11), ("2025/payroll.hg", 0x22), ("2025/q1/broken.hg", 0x33)] {
+        fs::write(&plain, vec![fill; 5000]).unwrap();
+        let output = hybridguard(&[Path::new("encrypt"), Path::new("-i"), &plain, Path::new("-o"), &root.join(name), Path::new("-k"), &keys]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    fs::write(&plain, vec![0x44; 200_000]).unwrap();
+    chunked::encrypt_file(&plain, &root.join("2025/q1/dataset.hgd"), &key_manager, 1).unwrap();
+    fs::write(root.join("README.txt"), "not a backup").unwrap();
+    let broken = root.join("2025/q1/broken.hg");
+    let mut bytes = fs::read(&broken).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0x01;
+    fs::write(&broken, bytes).unwrap();
+
+    let report = dir.path().join("report.json");
+    let output = hybridguard(&[
+        Path::new("verify"), Path::new("--deep"), Path::new("--root"), &root, Path::new("--recursive"),
+        Path::new("--report"), &report, Path::new("--jobs"), Path::new("2"), Path::new("-k"), &keys,
+    ]);
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!((report["verified"].as_u64(), report["failed"].as_u64()), (Some(3), Some(1)));
+    let files = report["files"].as_array().unwrap();
+    let names: Vec<&str> = files.iter().map(|file| file["path"].as_str().unwrap()).collect();
+    assert_eq!(names.len(), 4, "{:?}", names);
+    assert!(names.iter().all(|name| !name.ends_with("README.txt")));
+
+    let failed: Vec<_> = files.iter().filter(|file| file["status"] == "failed").collect();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0]["path"].as_str().unwrap().ends_with("broken.hg"));
+    assert_eq!(failed[0]["exit_code"], 4);
+    assert!(failed[0]["error"].is_string());
+
+    let ledger = files.iter().find(|file| file["path"].as_str().unwrap().ends_with("ledger.hg")).unwrap();
+    assert_eq!(ledger["status"], "verified");
+    assert_eq!(ledger["plaintext_len"], 5000);
+    assert_eq!(ledger["plaintext_sha256"], hex(&Sha256::digest(vec![0x11; 5000])));
+    assert!(!ledger["layers"].as_array().unwrap().is_empty());
+    assert!(ledger["bytes_per_sec"].is_number() && ledger["duration_secs"].is_number());
+    let dataset = files.iter().find(|file| file["kind"] == "chunked").unwrap();
+    assert_eq!(dataset["plaintext_len"], 200_000);
+
+    // Without --recursive only the root's own files are decrypted
+    let output = hybridguard(&[Path::new("verify"), Path::new("--deep"), Path::new("--root"), &root, Path::new("-k"), &keys]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Verified: 1, failed: 0"));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
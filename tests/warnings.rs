@@ -0,0 +1,68 @@
+// Warnings carry a stable code, print as warning[HGxxx], and can be denied
+// with --deny-warnings or dropped with --allow
+#![cfg(unix)]
+
+mod support;
+
+use hybridguard::error::exit_code;
+use hybridguard::KeyManager;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use support::hybridguard;
+
+#[test]
+fn loose_key_file_warning_is_coded_and_can_be_denied() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("shared.keys");
+    KeyManager::from_master_key(&[0x74; 32]).unwrap().save(&keys).unwrap();
+    fs::set_permissions(&keys, fs::Permissions::from_mode(0o644)).unwrap();
+    let (plain, enc) = (dir.path().join("roster.csv"), dir.path().join("roster.hg"));
+    fs::write(&plain, b"name,shift\n").unwrap();
+    let encrypt = [Path::new("encrypt"), Path::new("-k"), &keys, Path::new("-i"), &plain, Path::new("-o"), &enc];
+
+    let output = hybridguard(&encrypt);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    // Lines start with the code, so scripts can grep for ^warning\[
+    assert!(stderr.lines().any(|line| line.starts_with("warning[HG004]: ")), "{}", stderr);
+    fs::remove_file(&enc).unwrap();
+
+    // Denied, the run stops before writing anything
+    let output = hybridguard(&[&encrypt[..], &[Path::new("--deny-warnings")]].concat());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(exit_code::POLICY as i32), "{}", stderr);
+    assert!(stderr.contains("HG004"), "{}", stderr);
+    assert!(!enc.exists());
+
+    // Allowed, in any case, it is neither printed nor denied
+    let output = hybridguard(&[&encrypt[..], &[Path::new("--deny-warnings"), Path::new("--allow"), Path::new("hg004")]].concat());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(!stderr.contains("HG004"), "{}", stderr);
+    assert!(enc.exists());
+
+    let output = hybridguard(&[&encrypt[..], &[Path::new("--force"), Path::new("--allow"), Path::new("HG999")]].concat());
+    assert_eq!(output.status.code(), Some(exit_code::USAGE as i32));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("known codes are HG001"));
+}
+
+#[test]
+fn json_progress_reports_warnings_as_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = dir.path().join("shared.keys");
+    KeyManager::from_master_key(&[0x75; 32]).unwrap().save(&keys).unwrap();
+    fs::set_permissions(&keys, fs::Permissions::from_mode(0o640)).unwrap();
+    let (plain, enc) = (dir.path().join("notes.txt"), dir.path().join("notes.hg"));
+    fs::write(&plain, b"standup").unwrap();
+
+    let output = hybridguard(&[Path::new("encrypt"), Path::new("--json-progress"), Path::new("-k"), &keys, Path::new("-i"), &plain, Path::new("-o"), &enc]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let warning = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find_map(|line| line.get("warning").cloned())
+        .expect("no warning line");
+    assert_eq!(warning["code"], "HG004");
+    assert_eq!(warning["context"]["path"], keys.display().to_string());
+}